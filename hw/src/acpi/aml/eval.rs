@@ -0,0 +1,1675 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The interpreter parses and executes AML in a single pass. Loading a definition
+//! block executes its term list at the root scope, which creates the named objects,
+//! method bodies are only parsed when invoked.
+
+use alloc::{string::String, vec::Vec, boxed::Box, format};
+use super::*;
+
+const ZERO_OP:        u8 = 0x00;
+const ONE_OP:         u8 = 0x01;
+const ALIAS_OP:       u8 = 0x06;
+const NAME_OP:        u8 = 0x08;
+const BYTE_PREFIX:    u8 = 0x0A;
+const WORD_PREFIX:    u8 = 0x0B;
+const DWORD_PREFIX:   u8 = 0x0C;
+const STRING_PREFIX:  u8 = 0x0D;
+const QWORD_PREFIX:   u8 = 0x0E;
+const SCOPE_OP:       u8 = 0x10;
+const BUFFER_OP:      u8 = 0x11;
+const PACKAGE_OP:     u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP:      u8 = 0x14;
+const EXTERNAL_OP:    u8 = 0x15;
+const DUAL_NAME:      u8 = 0x2E;
+const MULTI_NAME:     u8 = 0x2F;
+const EXT_PREFIX:     u8 = 0x5B;
+const ROOT_CHAR:      u8 = b'\\';
+const PARENT_CHAR:    u8 = b'^';
+const LOCAL0_OP:      u8 = 0x60;
+const LOCAL7_OP:      u8 = 0x67;
+const ARG0_OP:        u8 = 0x68;
+const ARG6_OP:        u8 = 0x6E;
+const STORE_OP:       u8 = 0x70;
+const REF_OF_OP:      u8 = 0x71;
+const ADD_OP:         u8 = 0x72;
+const CONCAT_OP:      u8 = 0x73;
+const SUBTRACT_OP:    u8 = 0x74;
+const INCREMENT_OP:   u8 = 0x75;
+const DECREMENT_OP:   u8 = 0x76;
+const MULTIPLY_OP:    u8 = 0x77;
+const DIVIDE_OP:      u8 = 0x78;
+const SHL_OP:         u8 = 0x79;
+const SHR_OP:         u8 = 0x7A;
+const AND_OP:         u8 = 0x7B;
+const NAND_OP:        u8 = 0x7C;
+const OR_OP:          u8 = 0x7D;
+const NOR_OP:         u8 = 0x7E;
+const XOR_OP:         u8 = 0x7F;
+const NOT_OP:         u8 = 0x80;
+const FSL_BIT_OP:     u8 = 0x81;
+const FSR_BIT_OP:     u8 = 0x82;
+const DEREF_OF_OP:    u8 = 0x83;
+const CONCAT_RES_OP:  u8 = 0x84;
+const MOD_OP:         u8 = 0x85;
+const NOTIFY_OP:      u8 = 0x86;
+const SIZE_OF_OP:     u8 = 0x87;
+const INDEX_OP:       u8 = 0x88;
+const MATCH_OP:       u8 = 0x89;
+const CREATE_DWORD:   u8 = 0x8A;
+const CREATE_WORD:    u8 = 0x8B;
+const CREATE_BYTE:    u8 = 0x8C;
+const CREATE_BIT:     u8 = 0x8D;
+const OBJECT_TYPE_OP: u8 = 0x8E;
+const CREATE_QWORD:   u8 = 0x8F;
+const LAND_OP:        u8 = 0x90;
+const LOR_OP:         u8 = 0x91;
+const LNOT_OP:        u8 = 0x92;
+const LEQUAL_OP:      u8 = 0x93;
+const LGREATER_OP:    u8 = 0x94;
+const LLESS_OP:       u8 = 0x95;
+const TO_BUFFER_OP:   u8 = 0x96;
+const TO_DEC_STR_OP:  u8 = 0x97;
+const TO_HEX_STR_OP:  u8 = 0x98;
+const TO_INTEGER_OP:  u8 = 0x99;
+const TO_STRING_OP:   u8 = 0x9C;
+const COPY_OBJECT_OP: u8 = 0x9D;
+const MID_OP:         u8 = 0x9E;
+const CONTINUE_OP:    u8 = 0x9F;
+const IF_OP:          u8 = 0xA0;
+const ELSE_OP:        u8 = 0xA1;
+const WHILE_OP:       u8 = 0xA2;
+const NOOP_OP:        u8 = 0xA3;
+const RETURN_OP:      u8 = 0xA4;
+const BREAK_OP:       u8 = 0xA5;
+const BREAKPOINT_OP:  u8 = 0xCC;
+const ONES_OP:        u8 = 0xFF;
+
+const EXT_MUTEX:        u8 = 0x01;
+const EXT_EVENT:        u8 = 0x02;
+const EXT_COND_REF_OF:  u8 = 0x12;
+const EXT_CREATE_FIELD: u8 = 0x13;
+const EXT_LOAD_TABLE:   u8 = 0x1F;
+const EXT_LOAD:         u8 = 0x20;
+const EXT_STALL:        u8 = 0x21;
+const EXT_SLEEP:        u8 = 0x22;
+const EXT_ACQUIRE:      u8 = 0x23;
+const EXT_SIGNAL:       u8 = 0x24;
+const EXT_WAIT:         u8 = 0x25;
+const EXT_RESET:        u8 = 0x26;
+const EXT_RELEASE:      u8 = 0x27;
+const EXT_FROM_BCD:     u8 = 0x28;
+const EXT_TO_BCD:       u8 = 0x29;
+const EXT_UNLOAD:       u8 = 0x2A;
+const EXT_REVISION:     u8 = 0x30;
+const EXT_DEBUG:        u8 = 0x31;
+const EXT_FATAL:        u8 = 0x32;
+const EXT_TIMER:        u8 = 0x33;
+const EXT_OP_REGION:    u8 = 0x80;
+const EXT_FIELD:        u8 = 0x81;
+const EXT_DEVICE:       u8 = 0x82;
+const EXT_PROCESSOR:    u8 = 0x83;
+const EXT_POWER_RES:    u8 = 0x84;
+const EXT_THERMAL_ZONE: u8 = 0x85;
+const EXT_INDEX_FIELD:  u8 = 0x86;
+const EXT_BANK_FIELD:   u8 = 0x87;
+const EXT_DATA_REGION:  u8 = 0x88;
+
+/// Revision reported by the `Revision` opcode
+const INTERPRETER_REVISION: u64 = 0x2023_0101;
+
+/// A cursor over a term list.
+#[derive(Clone)]
+struct Stream<'a> {
+	data: &'a [u8],
+	pos:  usize
+}
+
+impl<'a> Stream<'a> {
+	fn new(data: &'a [u8]) -> Self {
+		Self { data, pos: 0 }
+	}
+
+	fn is_empty(&self) -> bool {
+		self.pos >= self.data.len()
+	}
+
+	fn peek(&self) -> Result<u8> {
+		self.data.get(self.pos).copied().ok_or(AmlError::UnexpectedEnd)
+	}
+
+	fn peek_at(&self, off: usize) -> Result<u8> {
+		self.data.get(self.pos + off).copied().ok_or(AmlError::UnexpectedEnd)
+	}
+
+	fn byte(&mut self) -> Result<u8> {
+		let v = self.peek()?;
+		self.pos += 1;
+		Ok(v)
+	}
+
+	fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+		let v = self.data.get(self.pos..self.pos + n).ok_or(AmlError::UnexpectedEnd)?;
+		self.pos += n;
+		Ok(v)
+	}
+
+	fn word(&mut self) -> Result<u16> {
+		Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+	}
+
+	fn dword(&mut self) -> Result<u32> {
+		Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+	}
+
+	fn qword(&mut self) -> Result<u64> {
+		Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+	}
+
+	/// Decodes a PkgLength and returns its raw value.
+	fn pkg_length_raw(&mut self) -> Result<usize> {
+		let lead = self.byte()? as usize;
+		let count = lead >> 6;
+
+		if count == 0 {
+			return Ok(lead & 0x3F);
+		}
+
+		let mut len = lead & 0xF;
+		for i in 0..count {
+			len |= (self.byte()? as usize) << (4 + 8 * i);
+		}
+		Ok(len)
+	}
+
+	/// Decodes a PkgLength and returns a stream of the package contents following it,
+	/// the stream is advanced past the package.
+	fn package(&mut self) -> Result<Stream<'a>> {
+		let start = self.pos;
+		let end = start + self.pkg_length_raw()?;
+		if end > self.data.len() || end < self.pos {
+			return Err(AmlError::UnexpectedEnd);
+		}
+		let inner = Stream { data: &self.data[..end], pos: self.pos };
+		self.pos = end;
+		Ok(inner)
+	}
+
+	fn rest(&mut self) -> &'a [u8] {
+		let v = &self.data[self.pos.min(self.data.len())..];
+		self.pos = self.data.len();
+		v
+	}
+
+	fn name_seg(&mut self) -> Result<[u8; 4]> {
+		let seg: [u8; 4] = self.bytes(4)?.try_into().unwrap();
+		match seg[0] == b'_' || seg[0].is_ascii_uppercase()
+			&& seg[1..].iter().all(|c| *c == b'_' || c.is_ascii_uppercase() || c.is_ascii_digit()) {
+			true  => Ok(seg),
+			false => Err(AmlError::InvalidName)
+		}
+	}
+
+	fn is_name_start(&self) -> bool {
+		matches!(self.peek(), Ok(ROOT_CHAR | PARENT_CHAR | DUAL_NAME | MULTI_NAME | b'A'..=b'Z' | b'_'))
+	}
+
+	/// Decodes a NameString into ASL notation, e.g. `\_SB_.PCI0` or `^^FOO_`. The null
+	/// name decodes to an empty string.
+	fn name_string(&mut self) -> Result<String> {
+		let mut out = String::new();
+
+		match self.peek()? {
+			ROOT_CHAR => { self.pos += 1; out.push('\\'); }
+			PARENT_CHAR => while self.peek()? == PARENT_CHAR { self.pos += 1; out.push('^'); }
+			_ => ()
+		}
+
+		let count = match self.peek()? {
+			ZERO_OP    => { self.pos += 1; 0 }
+			DUAL_NAME  => { self.pos += 1; 2 }
+			MULTI_NAME => { self.pos += 1; self.byte()? as usize }
+			_          => 1
+		};
+
+		for i in 0..count {
+			if i > 0 { out.push('.'); }
+			out.push_str(core::str::from_utf8(&self.name_seg()?).unwrap());
+		}
+
+		Ok(out)
+	}
+}
+
+enum Flow<'a> {
+	Normal,
+	Return(Object<'a>),
+	Break,
+	Continue
+}
+
+struct Frame<'a> {
+	args:    [Object<'a>; 7],
+	locals:  [Object<'a>; 8],
+	/// Objects created by the method, they are deleted when it returns
+	created: Vec<String>
+}
+
+pub(super) struct Interpreter<'n, 'a> {
+	ns:      &'n mut Namespace<'a>,
+	handler: &'n mut dyn Handler,
+	frames:  Vec<Frame<'a>>,
+	scope:   String
+}
+
+impl<'n, 'a> Interpreter<'n, 'a> {
+	pub(super) fn new(ns: &'n mut Namespace<'a>, handler: &'n mut dyn Handler) -> Self {
+		Self { ns, handler, frames: Vec::new(), scope: String::from("\\") }
+	}
+
+	pub(super) fn load(&mut self, code: &'a AmlCode) -> Result<()> {
+		let mut s = Stream::new(code);
+		match self.term_list(&mut s)? {
+			Flow::Normal | Flow::Return(_) => Ok(()),
+			_ => Err(AmlError::InvalidOpcode(BREAK_OP as _))
+		}
+	}
+
+	pub(super) fn evaluate(&mut self, path: &str, args: Vec<Object<'a>>) -> Result<Object<'a>> {
+		match self.ns.objects.get(path).ok_or_else(|| AmlError::NotFound(String::from(path)))? {
+			Object::Method(_) => self.invoke(path, args),
+			_ => self.read_name(path)
+		}
+	}
+
+	fn ones(&self) -> u64 {
+		match self.ns.revision < 2 {
+			true  => u32::MAX as u64,
+			false => u64::MAX
+		}
+	}
+
+	fn int_bytes(&self) -> usize {
+		match self.ns.revision < 2 {
+			true  => 4,
+			false => 8
+		}
+	}
+
+	fn frame(&mut self) -> Result<&mut Frame<'a>> {
+		self.frames.last_mut().ok_or(AmlError::InvalidType)
+	}
+
+	// ---------------------------------------------------------------------------------------
+	// Names
+	// ---------------------------------------------------------------------------------------
+
+	/// Makes a name absolute without searching, used for objects that are created.
+	fn absolute(&self, name: &str) -> Result<String> {
+		let mut scope = self.scope.as_str();
+		let mut rest = name;
+
+		if let Some(v) = rest.strip_prefix('\\') {
+			scope = "\\";
+			rest = v;
+		}
+
+		while let Some(v) = rest.strip_prefix('^') {
+			scope = parent(scope).ok_or(AmlError::InvalidName)?;
+			rest = v;
+		}
+
+		Ok(match (scope, rest) {
+			(_, "") => String::from(scope),
+			("\\", _) => format!("\\{}", rest),
+			_ => format!("{}.{}", scope, rest)
+		})
+	}
+
+	/// Resolves a name to the path of an existing object. Single segment names are
+	/// searched for in the parent scopes.
+	fn resolve(&self, name: &str) -> Option<String> {
+		if name.starts_with('\\') || name.starts_with('^') || name.contains('.') {
+			return self.absolute(name).ok().filter(|v| self.ns.objects.contains_key(v));
+		}
+
+		let mut scope = Some(self.scope.as_str());
+		while let Some(s) = scope {
+			let path = match s {
+				"\\" => format!("\\{}", name),
+				_ => format!("{}.{}", s, name)
+			};
+			if self.ns.objects.contains_key(&path) {
+				return Some(path);
+			}
+			scope = parent(s);
+		}
+
+		None
+	}
+
+	fn resolve_existing(&self, name: &str) -> Result<String> {
+		self.resolve(name).ok_or_else(|| AmlError::NotFound(String::from(name)))
+	}
+
+	/// Follows aliases.
+	fn target_path(&self, path: String) -> String {
+		match self.ns.objects.get(&path) {
+			Some(Object::Alias(v)) => self.target_path(v.clone()),
+			_ => path
+		}
+	}
+
+	fn create(&mut self, name: &str, object: Object<'a>) -> Result<String> {
+		let path = self.absolute(name)?;
+		self.ns.insert(path.clone(), object)?;
+
+		if let Some(frame) = self.frames.last_mut() {
+			frame.created.push(path.clone());
+		}
+
+		Ok(path)
+	}
+
+	/// Runs `f` with `path` as the current scope.
+	fn with_scope<T>(&mut self, path: String, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+		let prev = core::mem::replace(&mut self.scope, path);
+		let result = f(self);
+		self.scope = prev;
+		result
+	}
+
+	// ---------------------------------------------------------------------------------------
+	// Terms
+	// ---------------------------------------------------------------------------------------
+
+	fn term_list(&mut self, s: &mut Stream<'a>) -> Result<Flow<'a>> {
+		while !s.is_empty() {
+			match self.term(s)? {
+				Flow::Normal => (),
+				flow => return Ok(flow)
+			}
+		}
+		Ok(Flow::Normal)
+	}
+
+	fn term(&mut self, s: &mut Stream<'a>) -> Result<Flow<'a>> {
+		let op = s.peek()?;
+
+		match op {
+			ALIAS_OP => {
+				s.pos += 1;
+				let source = s.name_string()?;
+				let alias = s.name_string()?;
+				let source = self.resolve(&source).unwrap_or(self.absolute(&source)?);
+				self.create(&alias, Object::Alias(source))?;
+			}
+			NAME_OP => {
+				s.pos += 1;
+				let name = s.name_string()?;
+				let value = self.data_ref_object(s)?;
+				self.create(&name, value)?;
+			}
+			SCOPE_OP => {
+				s.pos += 1;
+				let mut body = s.package()?;
+				let name = body.name_string()?;
+				let path = self.resolve(&name).map_or_else(|| self.absolute(&name), Ok)?;
+				let path = self.target_path(path);
+
+				if !self.ns.objects.contains_key(&path) {
+					self.create(&name, Object::Scope)?;
+				}
+
+				self.with_scope(path, |this| this.term_list(&mut body))?;
+			}
+			METHOD_OP => {
+				s.pos += 1;
+				let mut body = s.package()?;
+				let name = body.name_string()?;
+				let flags = body.byte()?;
+				self.create(&name, Object::Method(Method {
+					args:       flags & 0x7,
+					serialized: flags & 0x8 != 0,
+					sync_level: flags >> 4,
+					code:       MethodCode::Aml(body.rest())
+				}))?;
+			}
+			EXTERNAL_OP => {
+				s.pos += 1;
+				let name = s.name_string()?;
+				let ty = s.byte()?;
+				let args = s.byte()?;
+				let path = self.absolute(&name)?;
+				// the definition may come from a table loaded later
+				if self.resolve(&name).is_none() {
+					self.ns.insert(path, Object::External { ty, args })?;
+				}
+			}
+			CREATE_DWORD | CREATE_WORD | CREATE_BYTE | CREATE_BIT | CREATE_QWORD => {
+				s.pos += 1;
+				let source = self.buffer_location(s)?;
+				let index = self.integer_arg(s)?;
+				let name = s.name_string()?;
+				let (bit_offset, bit_len) = match op {
+					CREATE_BIT   => (index, 1),
+					CREATE_BYTE  => (index * 8, 8),
+					CREATE_WORD  => (index * 8, 16),
+					CREATE_DWORD => (index * 8, 32),
+					_            => (index * 8, 64)
+				};
+				self.create(&name, Object::BufferField { source, bit_offset, bit_len })?;
+			}
+			IF_OP => {
+				s.pos += 1;
+				let mut body = s.package()?;
+				let predicate = self.integer_arg(&mut body)? != 0;
+
+				let mut else_body = None;
+				if s.peek().ok() == Some(ELSE_OP) {
+					s.pos += 1;
+					else_body = Some(s.package()?);
+				}
+
+				return match (predicate, else_body) {
+					(true, _) => self.term_list(&mut body),
+					(false, Some(mut body)) => self.term_list(&mut body),
+					(false, None) => Ok(Flow::Normal)
+				};
+			}
+			ELSE_OP => {
+				// only valid after If, which consumes it
+				return Err(AmlError::InvalidOpcode(op as _));
+			}
+			WHILE_OP => {
+				s.pos += 1;
+				let body = s.package()?;
+
+				for _ in 0..MAX_LOOP_ITERATIONS {
+					let mut body = body.clone();
+					if self.integer_arg(&mut body)? == 0 {
+						return Ok(Flow::Normal);
+					}
+
+					match self.term_list(&mut body)? {
+						Flow::Break => return Ok(Flow::Normal),
+						Flow::Return(v) => return Ok(Flow::Return(v)),
+						Flow::Normal | Flow::Continue => ()
+					}
+				}
+
+				return Err(AmlError::LoopLimit);
+			}
+			NOOP_OP | BREAKPOINT_OP => s.pos += 1,
+			RETURN_OP => {
+				s.pos += 1;
+				let value = self.term_arg(s)?;
+				return Ok(Flow::Return(value));
+			}
+			BREAK_OP => {
+				s.pos += 1;
+				return Ok(Flow::Break);
+			}
+			CONTINUE_OP => {
+				s.pos += 1;
+				return Ok(Flow::Continue);
+			}
+			NOTIFY_OP => {
+				s.pos += 1;
+				let target = self.super_name(s)?;
+				let value = self.integer_arg(s)?;
+				if let Some(Reference::Name(path)) = target {
+					self.handler.notify(&path, value);
+				}
+			}
+			EXT_PREFIX => return self.ext_term(s),
+			_ => { self.term_arg(s)?; }
+		}
+
+		Ok(Flow::Normal)
+	}
+
+	fn ext_term(&mut self, s: &mut Stream<'a>) -> Result<Flow<'a>> {
+		let op = s.peek_at(1)?;
+
+		match op {
+			EXT_MUTEX => {
+				s.pos += 2;
+				let name = s.name_string()?;
+				let flags = s.byte()?;
+				self.create(&name, Object::Mutex { sync_level: flags & 0xF })?;
+			}
+			EXT_EVENT => {
+				s.pos += 2;
+				let name = s.name_string()?;
+				self.create(&name, Object::Event)?;
+			}
+			EXT_CREATE_FIELD => {
+				s.pos += 2;
+				let source = self.buffer_location(s)?;
+				let bit_offset = self.integer_arg(s)?;
+				let bit_len = self.integer_arg(s)?;
+				let name = s.name_string()?;
+				self.create(&name, Object::BufferField { source, bit_offset, bit_len })?;
+			}
+			EXT_LOAD | EXT_UNLOAD | EXT_LOAD_TABLE => return Err(AmlError::Unsupported("dynamic table loading")),
+			EXT_STALL => {
+				s.pos += 2;
+				let us = self.integer_arg(s)?;
+				self.handler.stall(us);
+			}
+			EXT_SLEEP => {
+				s.pos += 2;
+				let ms = self.integer_arg(s)?;
+				self.handler.sleep(ms);
+			}
+			EXT_SIGNAL | EXT_RESET | EXT_RELEASE => {
+				// there is only a single thread of execution, so events and mutexes are no-ops
+				s.pos += 2;
+				self.super_name(s)?;
+			}
+			EXT_FATAL => {
+				s.pos += 2;
+				let ty = s.byte()?;
+				let code = s.dword()?;
+				let arg = self.integer_arg(s)?;
+				return Err(AmlError::Fatal { ty, code, arg });
+			}
+			EXT_OP_REGION => {
+				s.pos += 2;
+				let name = s.name_string()?;
+				let space = RegionSpace::from(s.byte()?);
+				let offset = self.integer_arg(s)?;
+				let length = self.integer_arg(s)?;
+				let scope = self.scope.clone();
+				self.create(&name, Object::OperationRegion(Region { space, offset, length, scope }))?;
+			}
+			EXT_DATA_REGION => {
+				s.pos += 2;
+				let name = s.name_string()?;
+				self.term_arg(s)?;
+				self.term_arg(s)?;
+				self.term_arg(s)?;
+				// the table isn't known to the namespace, give it an empty region
+				let scope = self.scope.clone();
+				self.create(&name, Object::OperationRegion(Region { space: RegionSpace::SystemMemory, offset: 0, length: 0, scope }))?;
+			}
+			EXT_FIELD => {
+				s.pos += 2;
+				let mut body = s.package()?;
+				let region = body.name_string()?;
+				let region = self.target_path(self.resolve_existing(&region)?);
+				let flags = body.byte()?;
+				self.field_list(&mut body, FieldKind::Normal { region }, flags)?;
+			}
+			EXT_INDEX_FIELD => {
+				s.pos += 2;
+				let mut body = s.package()?;
+				let index = body.name_string()?;
+				let data = body.name_string()?;
+				let index = self.target_path(self.resolve_existing(&index)?);
+				let data = self.target_path(self.resolve_existing(&data)?);
+				let flags = body.byte()?;
+				self.field_list(&mut body, FieldKind::Index { index, data }, flags)?;
+			}
+			EXT_BANK_FIELD => {
+				s.pos += 2;
+				let mut body = s.package()?;
+				let region = body.name_string()?;
+				let bank = body.name_string()?;
+				let region = self.target_path(self.resolve_existing(&region)?);
+				let bank = self.target_path(self.resolve_existing(&bank)?);
+				let value = self.integer_arg(&mut body)?;
+				let flags = body.byte()?;
+				self.field_list(&mut body, FieldKind::Bank { region, bank, value }, flags)?;
+			}
+			EXT_DEVICE | EXT_THERMAL_ZONE => {
+				s.pos += 2;
+				let mut body = s.package()?;
+				let name = body.name_string()?;
+				let object = match op {
+					EXT_DEVICE => Object::Device,
+					_ => Object::ThermalZone
+				};
+				let path = self.create(&name, object)?;
+				self.with_scope(path, |this| this.term_list(&mut body))?;
+			}
+			EXT_PROCESSOR => {
+				s.pos += 2;
+				let mut body = s.package()?;
+				let name = body.name_string()?;
+				let id = body.byte()?;
+				let pblk_addr = body.dword()?;
+				let pblk_len = body.byte()?;
+				let path = self.create(&name, Object::Processor { id, pblk_addr, pblk_len })?;
+				self.with_scope(path, |this| this.term_list(&mut body))?;
+			}
+			EXT_POWER_RES => {
+				s.pos += 2;
+				let mut body = s.package()?;
+				let name = body.name_string()?;
+				let system_level = body.byte()?;
+				let resource_order = body.word()?;
+				let path = self.create(&name, Object::PowerResource { system_level, resource_order })?;
+				self.with_scope(path, |this| this.term_list(&mut body))?;
+			}
+			_ => { self.term_arg(s)?; }
+		}
+
+		Ok(Flow::Normal)
+	}
+
+	fn field_list(&mut self, s: &mut Stream<'a>, kind: FieldKind, mut flags: u8) -> Result<()> {
+		let mut bit_offset = 0;
+
+		while !s.is_empty() {
+			match s.peek()? {
+				// ReservedField
+				0x00 => {
+					s.pos += 1;
+					bit_offset += s.pkg_length_raw()? as u64;
+				}
+				// AccessField
+				0x01 => {
+					s.pos += 1;
+					let access = s.byte()?;
+					s.byte()?;
+					flags = (flags & 0xF0) | (access & 0xF);
+				}
+				// ConnectField
+				0x02 => {
+					s.pos += 1;
+					match s.peek()? {
+						BUFFER_OP => { self.term_arg(s)?; }
+						_ => { s.name_string()?; }
+					}
+				}
+				// ExtendedAccessField
+				0x03 => {
+					s.pos += 1;
+					let access = s.byte()?;
+					s.byte()?;
+					s.byte()?;
+					flags = (flags & 0xF0) | (access & 0xF);
+				}
+				_ => {
+					let name = String::from(core::str::from_utf8(&s.name_seg()?).unwrap());
+					let bit_len = s.pkg_length_raw()? as u64;
+					self.create(&name, Object::FieldUnit(FieldUnit { kind: kind.clone(), bit_offset, bit_len, flags }))?;
+					bit_offset += bit_len;
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	// ---------------------------------------------------------------------------------------
+	// Data objects
+	// ---------------------------------------------------------------------------------------
+
+	fn data_ref_object(&mut self, s: &mut Stream<'a>) -> Result<Object<'a>> {
+		self.term_arg(s)
+	}
+
+	fn buffer(&mut self, s: &mut Stream<'a>) -> Result<Object<'a>> {
+		let mut body = s.package()?;
+		let size = self.integer_arg(&mut body)? as usize;
+		let init = body.rest();
+		let mut buf = alloc::vec![0u8; size.max(init.len())];
+		buf[..init.len()].copy_from_slice(init);
+		Ok(Object::Buffer(buf))
+	}
+
+	fn package(&mut self, s: &mut Stream<'a>, var: bool) -> Result<Object<'a>> {
+		let mut body = s.package()?;
+		let count = match var {
+			false => body.byte()? as usize,
+			true  => self.integer_arg(&mut body)? as usize
+		};
+
+		let mut elements = Vec::with_capacity(count);
+		while !body.is_empty() {
+			let element = match body.is_name_start() {
+				// names in packages are references and are not evaluated
+				true => {
+					let name = body.name_string()?;
+					Object::Reference(Reference::Name(match self.resolve(&name) {
+						Some(path) => path,
+						None => self.absolute(&name)?
+					}))
+				}
+				false => self.term_arg(&mut body)?
+			};
+			elements.push(element);
+		}
+
+		if elements.len() > count {
+			return Err(AmlError::InvalidIndex);
+		}
+
+		elements.resize(count, Object::Uninitialized);
+		Ok(Object::Package(elements))
+	}
+
+	// ---------------------------------------------------------------------------------------
+	// Expressions
+	// ---------------------------------------------------------------------------------------
+
+	fn integer_arg(&mut self, s: &mut Stream<'a>) -> Result<u64> {
+		let v = self.term_arg(s)?;
+		self.convert_integer(v)
+	}
+
+	/// Evaluates a TermArg, references to named objects and fields are read.
+	fn term_arg(&mut self, s: &mut Stream<'a>) -> Result<Object<'a>> {
+		let op = s.peek()?;
+
+		if s.is_name_start() {
+			let name = s.name_string()?;
+			let path = self.target_path(self.resolve_existing(&name)?);
+
+			return match self.ns.objects.get(&path) {
+				Some(Object::Method(m)) => {
+					let mut args = Vec::with_capacity(m.args as usize);
+					for _ in 0..m.args {
+						args.push(self.term_arg(s)?);
+					}
+					self.invoke(&path, args)
+				}
+				_ => self.read_name(&path)
+			};
+		}
+
+		s.pos += 1;
+		Ok(match op {
+			ZERO_OP       => Object::Integer(0),
+			ONE_OP        => Object::Integer(1),
+			ONES_OP       => Object::Integer(self.ones()),
+			BYTE_PREFIX   => Object::Integer(s.byte()? as _),
+			WORD_PREFIX   => Object::Integer(s.word()? as _),
+			DWORD_PREFIX  => Object::Integer(s.dword()? as _),
+			QWORD_PREFIX  => Object::Integer(s.qword()? & self.ones()),
+			STRING_PREFIX => {
+				let start = s.pos;
+				while s.byte()? != 0 {}
+				Object::String(String::from_utf8_lossy(&s.data[start..s.pos - 1]).into_owned())
+			}
+			BUFFER_OP      => self.buffer(s)?,
+			PACKAGE_OP     => self.package(s, false)?,
+			VAR_PACKAGE_OP => self.package(s, true)?,
+			LOCAL0_OP..=LOCAL7_OP => {
+				let v = self.frame()?.locals[(op - LOCAL0_OP) as usize].clone();
+				v
+			}
+			ARG0_OP..=ARG6_OP => {
+				let v = self.frame()?.args[(op - ARG0_OP) as usize].clone();
+				v
+			}
+			STORE_OP => {
+				let value = self.term_arg(s)?;
+				let target = self.super_name(s)?;
+				self.store(target.as_ref(), value.clone())?;
+				value
+			}
+			REF_OF_OP => match self.super_name(s)? {
+				Some(r) => Object::Reference(r),
+				None => return Err(AmlError::InvalidType)
+			},
+			ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHL_OP | SHR_OP | AND_OP | NAND_OP | OR_OP | NOR_OP | XOR_OP | MOD_OP => {
+				let a = self.integer_arg(s)?;
+				let b = self.integer_arg(s)?;
+				let v = match op {
+					ADD_OP      => a.wrapping_add(b),
+					SUBTRACT_OP => a.wrapping_sub(b),
+					MULTIPLY_OP => a.wrapping_mul(b),
+					SHL_OP      => a.checked_shl(b as u32).unwrap_or(0),
+					SHR_OP      => a.checked_shr(b as u32).unwrap_or(0),
+					AND_OP      => a & b,
+					NAND_OP     => !(a & b),
+					OR_OP       => a | b,
+					NOR_OP      => !(a | b),
+					XOR_OP      => a ^ b,
+					_           => a.checked_rem(b).ok_or(AmlError::DivideByZero)?
+				};
+				self.store_result(s, Object::Integer(v & self.ones()))?
+			}
+			DIVIDE_OP => {
+				let a = self.integer_arg(s)?;
+				let b = self.integer_arg(s)?;
+				if b == 0 {
+					return Err(AmlError::DivideByZero);
+				}
+				self.store_result(s, Object::Integer(a % b))?;
+				self.store_result(s, Object::Integer(a / b))?
+			}
+			NOT_OP => {
+				let v = self.integer_arg(s)?;
+				self.store_result(s, Object::Integer(!v & self.ones()))?
+			}
+			FSL_BIT_OP | FSR_BIT_OP => {
+				let v = self.integer_arg(s)?;
+				let bit = match (v, op) {
+					(0, _) => 0,
+					(v, FSL_BIT_OP) => 64 - v.leading_zeros() as u64,
+					(v, _) => v.trailing_zeros() as u64 + 1
+				};
+				self.store_result(s, Object::Integer(bit))?
+			}
+			INCREMENT_OP | DECREMENT_OP => {
+				let target = self.super_name(s)?.ok_or(AmlError::InvalidType)?;
+				let v = self.read_ref(&target)?;
+				let v = self.convert_integer(v)?;
+				let v = match op {
+					INCREMENT_OP => v.wrapping_add(1),
+					_            => v.wrapping_sub(1)
+				} & self.ones();
+				self.store(Some(&target), Object::Integer(v))?;
+				Object::Integer(v)
+			}
+			CONCAT_OP => {
+				let a = self.term_arg(s)?;
+				let b = self.term_arg(s)?;
+				let v = self.concat(a, b)?;
+				self.store_result(s, v)?
+			}
+			CONCAT_RES_OP => {
+				let a = self.term_arg(s)?;
+				let b = self.term_arg(s)?;
+				let mut a = self.convert_buffer(a)?;
+				let b = self.convert_buffer(b)?;
+				// strip the end tag of the first template, the second provides it
+				if a.len() >= 2 && a[a.len() - 2] == 0x79 { a.truncate(a.len() - 2); }
+				a.extend_from_slice(&b);
+				self.store_result(s, Object::Buffer(a))?
+			}
+			DEREF_OF_OP => {
+				let v = self.term_arg(s)?;
+				match v {
+					Object::Reference(r) => self.read_ref(&r)?,
+					Object::String(name) => {
+						let path = self.target_path(self.resolve_existing(&name)?);
+						self.read_name(&path)?
+					}
+					_ => return Err(AmlError::InvalidType)
+				}
+			}
+			SIZE_OF_OP => {
+				let target = self.super_name(s)?.ok_or(AmlError::InvalidType)?;
+				let v = self.read_ref(&target)?;
+				Object::Integer(match self.deref(v)? {
+					Object::String(v) => v.len(),
+					Object::Buffer(v) => v.len(),
+					Object::Package(v) => v.len(),
+					_ => return Err(AmlError::InvalidType)
+				} as u64)
+			}
+			INDEX_OP => {
+				let source = self.index_source(s)?;
+				let index = self.integer_arg(s)? as usize;
+				let v = match source {
+					Ok(location) => {
+						let v = self.read_ref(&location)?;
+						let len = match self.deref(v)? {
+							Object::String(v) => v.len(),
+							Object::Buffer(v) => v.len(),
+							Object::Package(v) => v.len(),
+							_ => return Err(AmlError::InvalidType)
+						};
+						if index >= len {
+							return Err(AmlError::InvalidIndex);
+						}
+						Object::Reference(Reference::Index(Box::new(location), index))
+					}
+					Err(value) => element(value, index)?
+				};
+				self.store_result(s, v)?
+			}
+			MATCH_OP => {
+				let package = self.term_arg(s)?;
+				let Object::Package(package) = self.deref(package)? else { return Err(AmlError::InvalidType) };
+				let op1 = s.byte()?;
+				let v1 = self.term_arg(s)?;
+				let op2 = s.byte()?;
+				let v2 = self.term_arg(s)?;
+				let start = self.integer_arg(s)? as usize;
+
+				let mut found = self.ones();
+				for (i, e) in package.into_iter().enumerate().skip(start) {
+					let e = self.deref(e)?;
+					if !matches!(e, Object::Integer(_) | Object::String(_) | Object::Buffer(_)) {
+						continue;
+					}
+					if self.match_op(op1, &e, &v1)? && self.match_op(op2, &e, &v2)? {
+						found = i as u64;
+						break;
+					}
+				}
+				Object::Integer(found)
+			}
+			OBJECT_TYPE_OP => {
+				let target = self.super_name(s)?.ok_or(AmlError::InvalidType)?;
+				Object::Integer(match &target {
+					Reference::Name(path) => self.ns.objects.get(path).map_or(0, |v| v.type_id()),
+					Reference::Debug => 16,
+					r => self.read_ref(r)?.type_id()
+				})
+			}
+			LAND_OP | LOR_OP => {
+				let a = self.integer_arg(s)? != 0;
+				let b = self.integer_arg(s)? != 0;
+				self.bool(match op {
+					LAND_OP => a && b,
+					_       => a || b
+				})
+			}
+			LNOT_OP => {
+				let v = self.integer_arg(s)?;
+				self.bool(v == 0)
+			}
+			LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+				let a = self.term_arg(s)?;
+				let b = self.term_arg(s)?;
+				let ord = self.compare(a, b)?;
+				self.bool(match op {
+					LEQUAL_OP   => ord.is_eq(),
+					LGREATER_OP => ord.is_gt(),
+					_           => ord.is_lt()
+				})
+			}
+			TO_BUFFER_OP => {
+				let v = self.term_arg(s)?;
+				let v = Object::Buffer(self.convert_buffer(v)?);
+				self.store_result(s, v)?
+			}
+			TO_INTEGER_OP => {
+				let v = self.term_arg(s)?;
+				let v = match self.deref(v)? {
+					// explicit conversion also accepts decimal strings
+					Object::String(v) => Object::Integer(parse_integer(&v) & self.ones()),
+					v => Object::Integer(self.convert_integer(v)?)
+				};
+				self.store_result(s, v)?
+			}
+			TO_DEC_STR_OP | TO_HEX_STR_OP => {
+				let v = self.term_arg(s)?;
+				let hex = op == TO_HEX_STR_OP;
+				let v = Object::String(match self.deref(v)? {
+					Object::Integer(v) if hex => format!("0x{:X}", v),
+					Object::Integer(v) => format!("{}", v),
+					Object::Buffer(v) => v.iter()
+						.map(|b| if hex { format!("0x{:02X}", b) } else { format!("{}", b) })
+						.collect::<Vec<_>>()
+						.join(","),
+					Object::String(v) => v,
+					_ => return Err(AmlError::InvalidType)
+				});
+				self.store_result(s, v)?
+			}
+			TO_STRING_OP => {
+				let v = self.term_arg(s)?;
+				let v = self.convert_buffer(v)?;
+				let len = self.integer_arg(s)? as usize;
+				let end = v.iter().position(|b| *b == 0).unwrap_or(v.len()).min(len);
+				let v = Object::String(String::from_utf8_lossy(&v[..end]).into_owned());
+				self.store_result(s, v)?
+			}
+			COPY_OBJECT_OP => {
+				let v = self.term_arg(s)?;
+				match self.super_name(s)? {
+					Some(Reference::Name(path)) => { self.ns.objects.insert(path, v.clone()); }
+					Some(r) => *self.location_mut(&r)? = v.clone(),
+					None => ()
+				}
+				v
+			}
+			MID_OP => {
+				let v = self.term_arg(s)?;
+				let index = self.integer_arg(s)? as usize;
+				let len = self.integer_arg(s)? as usize;
+				let v = match self.deref(v)? {
+					Object::String(v) => {
+						let start = index.min(v.len());
+						Object::String(String::from(&v[start..(start + len).min(v.len())]))
+					}
+					v => {
+						let v = self.convert_buffer(v)?;
+						let start = index.min(v.len());
+						Object::Buffer(v[start..(start + len).min(v.len())].to_vec())
+					}
+				};
+				self.store_result(s, v)?
+			}
+			EXT_PREFIX => self.ext_term_arg(s)?,
+			op => return Err(AmlError::InvalidOpcode(op as _))
+		})
+	}
+
+	fn ext_term_arg(&mut self, s: &mut Stream<'a>) -> Result<Object<'a>> {
+		let op = s.byte()?;
+
+		Ok(match op {
+			EXT_COND_REF_OF => {
+				let found = match s.is_name_start() {
+					true => {
+						let name = s.name_string()?;
+						self.resolve(&name).map(|v| Reference::Name(self.target_path(v)))
+					}
+					false => self.super_name(s)?
+				};
+				let target = self.super_name(s)?;
+				match found {
+					Some(r) => {
+						self.store(target.as_ref(), Object::Reference(r))?;
+						Object::Integer(self.ones())
+					}
+					None => Object::Integer(0)
+				}
+			}
+			EXT_ACQUIRE => {
+				self.super_name(s)?;
+				s.word()?;
+				// acquired
+				Object::Integer(0)
+			}
+			EXT_WAIT => {
+				self.super_name(s)?;
+				self.term_arg(s)?;
+				Object::Integer(0)
+			}
+			EXT_FROM_BCD => {
+				let v = self.integer_arg(s)?;
+				let mut out = 0;
+				for i in (0..16).rev() {
+					out = out * 10 + ((v >> (i * 4)) & 0xF);
+				}
+				self.store_result(s, Object::Integer(out))?
+			}
+			EXT_TO_BCD => {
+				let mut v = self.integer_arg(s)?;
+				let mut out = 0;
+				let mut shift = 0;
+				while v != 0 && shift < 64 {
+					out |= (v % 10) << shift;
+					v /= 10;
+					shift += 4;
+				}
+				self.store_result(s, Object::Integer(out))?
+			}
+			EXT_REVISION => Object::Integer(INTERPRETER_REVISION),
+			EXT_DEBUG => Object::Reference(Reference::Debug),
+			EXT_TIMER => Object::Integer(self.handler.timer()),
+			op => return Err(AmlError::InvalidOpcode(((EXT_PREFIX as u16) << 8) | op as u16))
+		})
+	}
+
+	/// Decodes a SuperName, or `None` for the null name.
+	fn super_name(&mut self, s: &mut Stream<'a>) -> Result<Option<Reference>> {
+		let op = s.peek()?;
+
+		match op {
+			ZERO_OP => {
+				s.pos += 1;
+				Ok(None)
+			}
+			LOCAL0_OP..=LOCAL7_OP => {
+				s.pos += 1;
+				Ok(Some(Reference::Local(op - LOCAL0_OP)))
+			}
+			ARG0_OP..=ARG6_OP => {
+				s.pos += 1;
+				Ok(Some(Reference::Arg(op - ARG0_OP)))
+			}
+			EXT_PREFIX if s.peek_at(1)? == EXT_DEBUG => {
+				s.pos += 2;
+				Ok(Some(Reference::Debug))
+			}
+			_ if s.is_name_start() => {
+				let name = s.name_string()?;
+				Ok(Some(Reference::Name(self.target_path(self.resolve_existing(&name)?))))
+			}
+			// Type6Opcode: RefOf, DerefOf, Index or a method returning a reference
+			_ => match self.term_arg(s)? {
+				Object::Reference(r) => Ok(Some(r)),
+				_ => Err(AmlError::InvalidType)
+			}
+		}
+	}
+
+	/// Decodes the source of `Index`, which is either a location or a temporary value.
+	fn index_source(&mut self, s: &mut Stream<'a>) -> Result<core::result::Result<Reference, Object<'a>>> {
+		let op = s.peek()?;
+
+		if matches!(op, LOCAL0_OP..=LOCAL7_OP | ARG0_OP..=ARG6_OP) {
+			return Ok(Ok(self.super_name(s)?.unwrap()));
+		}
+
+		if s.is_name_start() {
+			let mut peek = s.clone();
+			let name = peek.name_string()?;
+			let path = self.target_path(self.resolve_existing(&name)?);
+			if !matches!(self.ns.objects.get(&path), Some(Object::Method(_) | Object::FieldUnit(_) | Object::BufferField { .. })) {
+				*s = peek;
+				return Ok(Ok(Reference::Name(path)));
+			}
+		}
+
+		Ok(match self.term_arg(s)? {
+			Object::Reference(r) => Ok(r),
+			v => Err(v)
+		})
+	}
+
+	/// Decodes the source buffer of the CreateField opcodes, which must be a location.
+	fn buffer_location(&mut self, s: &mut Stream<'a>) -> Result<Reference> {
+		self.index_source(s)?.map_err(|_| AmlError::InvalidType)
+	}
+
+	/// Decodes the target of an operator, stores the result and returns it.
+	fn store_result(&mut self, s: &mut Stream<'a>, v: Object<'a>) -> Result<Object<'a>> {
+		let target = self.super_name(s)?;
+		self.store(target.as_ref(), v.clone())?;
+		Ok(v)
+	}
+
+	fn bool(&self, v: bool) -> Object<'a> {
+		Object::Integer(if v { self.ones() } else { 0 })
+	}
+
+	fn match_op(&mut self, op: u8, e: &Object<'a>, v: &Object<'a>) -> Result<bool> {
+		if op == 0 {
+			return Ok(true);
+		}
+
+		let ord = self.compare(e.clone(), v.clone())?;
+		Ok(match op {
+			1 => ord.is_eq(),
+			2 => ord.is_le(),
+			3 => ord.is_lt(),
+			4 => ord.is_ge(),
+			5 => ord.is_gt(),
+			_ => return Err(AmlError::InvalidType)
+		})
+	}
+
+	/// Compares two objects, the second operand is converted to the type of the first.
+	fn compare(&mut self, a: Object<'a>, b: Object<'a>) -> Result<core::cmp::Ordering> {
+		Ok(match self.deref(a)? {
+			Object::String(a) => {
+				let b = self.convert_buffer(b)?;
+				let b = &b[..b.iter().position(|c| *c == 0).unwrap_or(b.len())];
+				a.as_bytes().cmp(b)
+			}
+			Object::Buffer(a) => a.cmp(&self.convert_buffer(b)?),
+			a => self.convert_integer(a)?.cmp(&self.convert_integer(b)?)
+		})
+	}
+
+	fn concat(&mut self, a: Object<'a>, b: Object<'a>) -> Result<Object<'a>> {
+		Ok(match self.deref(a)? {
+			Object::Integer(a) => {
+				let mut v = a.to_le_bytes()[..self.int_bytes()].to_vec();
+				let b = self.convert_integer(b)?;
+				v.extend_from_slice(&b.to_le_bytes()[..self.int_bytes()]);
+				Object::Buffer(v)
+			}
+			Object::String(mut a) => {
+				match self.deref(b)? {
+					Object::String(b) => a.push_str(&b),
+					Object::Integer(b) => a.push_str(&format!("{:X}", b)),
+					Object::Buffer(b) => a.push_str(&b.iter().map(|v| format!("{:02X}", v)).collect::<Vec<_>>().join(" ")),
+					_ => return Err(AmlError::InvalidType)
+				}
+				Object::String(a)
+			}
+			Object::Buffer(mut a) => {
+				a.extend_from_slice(&self.convert_buffer(b)?);
+				Object::Buffer(a)
+			}
+			_ => return Err(AmlError::InvalidType)
+		})
+	}
+
+	// ---------------------------------------------------------------------------------------
+	// Conversions
+	// ---------------------------------------------------------------------------------------
+
+	/// Resolves references to the referenced value.
+	fn deref(&mut self, v: Object<'a>) -> Result<Object<'a>> {
+		match v {
+			Object::Reference(Reference::Debug) => Ok(v),
+			Object::Reference(r) => {
+				let v = self.read_ref(&r)?;
+				self.deref(v)
+			}
+			v => Ok(v)
+		}
+	}
+
+	fn convert_integer(&mut self, v: Object<'a>) -> Result<u64> {
+		Ok(match self.deref(v)? {
+			Object::Integer(v) => v,
+			Object::Buffer(v) => {
+				let mut bytes = [0u8; 8];
+				let n = v.len().min(self.int_bytes());
+				bytes[..n].copy_from_slice(&v[..n]);
+				u64::from_le_bytes(bytes)
+			}
+			// implicit conversion of strings is always hexadecimal
+			Object::String(v) => u64::from_str_radix(
+				v.trim_start_matches("0x").trim_start_matches("0X").split(|c: char| !c.is_ascii_hexdigit()).next().unwrap_or(""),
+				16).unwrap_or(0) & self.ones(),
+			_ => return Err(AmlError::InvalidType)
+		})
+	}
+
+	fn convert_buffer(&mut self, v: Object<'a>) -> Result<Vec<u8>> {
+		Ok(match self.deref(v)? {
+			Object::Integer(v) => v.to_le_bytes()[..self.int_bytes()].to_vec(),
+			Object::Buffer(v) => v,
+			Object::String(v) => {
+				let mut v = v.into_bytes();
+				if !v.is_empty() { v.push(0); }
+				v
+			}
+			_ => return Err(AmlError::InvalidType)
+		})
+	}
+
+	// ---------------------------------------------------------------------------------------
+	// Reading and writing
+	// ---------------------------------------------------------------------------------------
+
+	fn read_name(&mut self, path: &str) -> Result<Object<'a>> {
+		let object = self.ns.objects.get(path).cloned().ok_or_else(|| AmlError::NotFound(String::from(path)))?;
+
+		match object {
+			Object::FieldUnit(field) => self.read_field(&field),
+			Object::BufferField { source, bit_offset, bit_len } => {
+				let buf = self.read_ref(&source)?;
+				let buf = self.convert_buffer(buf)?;
+				let bits = read_bits(&buf, bit_offset, bit_len);
+				Ok(self.field_value(bits, bit_len))
+			}
+			Object::Alias(path) => self.read_name(&path),
+			Object::Method(m) if m.args == 0 => self.invoke(path, Vec::new()),
+			v => Ok(v)
+		}
+	}
+
+	fn read_ref(&mut self, r: &Reference) -> Result<Object<'a>> {
+		match r {
+			Reference::Name(path) => self.read_name(path),
+			Reference::Local(i) => Ok(self.frame()?.locals[*i as usize].clone()),
+			Reference::Arg(i) => Ok(self.frame()?.args[*i as usize].clone()),
+			Reference::Index(inner, index) => {
+				let v = self.read_ref(inner)?;
+				let v = self.deref(v)?;
+				element(v, *index)
+			}
+			Reference::Debug => Ok(Object::Uninitialized)
+		}
+	}
+
+	/// Returns the object stored at a location, for writing.
+	fn location_mut(&mut self, r: &Reference) -> Result<&mut Object<'a>> {
+		match r {
+			Reference::Name(path) => self.ns.objects.get_mut(path).ok_or_else(|| AmlError::NotFound(path.clone())),
+			Reference::Local(i) => Ok(&mut self.frame()?.locals[*i as usize]),
+			Reference::Arg(i) => Ok(&mut self.frame()?.args[*i as usize]),
+			Reference::Index(inner, index) => match self.location_mut(inner)? {
+				Object::Package(v) => v.get_mut(*index).ok_or(AmlError::InvalidIndex),
+				_ => Err(AmlError::InvalidType)
+			},
+			Reference::Debug => Err(AmlError::InvalidType)
+		}
+	}
+
+	fn store(&mut self, target: Option<&Reference>, v: Object<'a>) -> Result<()> {
+		let Some(target) = target else { return Ok(()) };
+
+		match target {
+			Reference::Debug => {
+				self.handler.debug(&v);
+				Ok(())
+			}
+			Reference::Local(i) => {
+				self.frame()?.locals[*i as usize] = v;
+				Ok(())
+			}
+			Reference::Arg(i) => match self.frame()?.args[*i as usize].clone() {
+				// arguments passed by reference are written through
+				Object::Reference(r) => self.store(Some(&r), v),
+				_ => {
+					self.frame()?.args[*i as usize] = v;
+					Ok(())
+				}
+			},
+			Reference::Name(path) => {
+				let current = self.ns.objects.get(path).cloned().ok_or_else(|| AmlError::NotFound(path.clone()))?;
+				let new = match current {
+					Object::FieldUnit(field) => return self.write_field(&field, v),
+					Object::BufferField { source, bit_offset, bit_len } => {
+						let bits = self.convert_buffer(v)?;
+						return match self.location_mut(&source)? {
+							Object::Buffer(buf) => {
+								write_bits(buf, bit_offset, bit_len, &bits);
+								Ok(())
+							}
+							_ => Err(AmlError::InvalidType)
+						};
+					}
+					Object::Alias(path) => return self.store(Some(&Reference::Name(path)), v),
+					// stores convert to the type of the existing object
+					Object::Integer(_) => Object::Integer(self.convert_integer(v)?),
+					Object::String(_) => match v {
+						Object::Integer(v) => Object::String(format!("{:X}", v)),
+						Object::Buffer(v) => Object::String(String::from_utf8_lossy(
+							&v[..v.iter().position(|c| *c == 0).unwrap_or(v.len())]).into_owned()),
+						v => v
+					},
+					Object::Buffer(old) => {
+						let mut new = self.convert_buffer(v)?;
+						new.resize(old.len(), 0);
+						Object::Buffer(new)
+					}
+					Object::Method(_) | Object::Device | Object::Scope | Object::OperationRegion(_) => return Err(AmlError::InvalidType),
+					_ => v
+				};
+				self.ns.objects.insert(path.clone(), new);
+				Ok(())
+			}
+			Reference::Index(inner, index) => {
+				let byte = match &v {
+					Object::Integer(b) => Some(*b as u8),
+					_ => None
+				};
+				match self.location_mut(inner)? {
+					Object::Package(p) => *p.get_mut(*index).ok_or(AmlError::InvalidIndex)? = v,
+					Object::Buffer(b) => *b.get_mut(*index).ok_or(AmlError::InvalidIndex)? = byte.ok_or(AmlError::InvalidType)?,
+					Object::String(s) => {
+						let mut bytes = core::mem::take(s).into_bytes();
+						*bytes.get_mut(*index).ok_or(AmlError::InvalidIndex)? = byte.ok_or(AmlError::InvalidType)?;
+						*s = String::from_utf8_lossy(&bytes).into_owned();
+					}
+					_ => return Err(AmlError::InvalidType)
+				}
+				Ok(())
+			}
+		}
+	}
+
+	// ---------------------------------------------------------------------------------------
+	// Fields
+	// ---------------------------------------------------------------------------------------
+
+	fn field_value(&self, bits: Vec<u8>, bit_len: u64) -> Object<'a> {
+		match bit_len <= self.int_bytes() as u64 * 8 {
+			true => {
+				let mut bytes = [0u8; 8];
+				bytes[..bits.len().min(8)].copy_from_slice(&bits[..bits.len().min(8)]);
+				Object::Integer(u64::from_le_bytes(bytes))
+			}
+			false => Object::Buffer(bits)
+		}
+	}
+
+	/// Returns the access width of a field in bits.
+	fn access_width(field: &FieldUnit) -> u64 {
+		match field.access_type() {
+			FIELD_ACCESS_WORD  => 16,
+			FIELD_ACCESS_DWORD => 32,
+			FIELD_ACCESS_QWORD => 64,
+			FIELD_ACCESS_ANY   => {
+				// the smallest naturally aligned access that contains the whole field
+				let mut width = 8;
+				while width < 64 && field.bit_offset / width != (field.bit_offset + field.bit_len.max(1) - 1) / width {
+					width *= 2;
+				}
+				width
+			}
+			_ => 8
+		}
+	}
+
+	fn read_field(&mut self, field: &FieldUnit) -> Result<Object<'a>> {
+		let width = Self::access_width(field);
+		let first = field.bit_offset / width;
+		let last = (field.bit_offset + field.bit_len.max(1) - 1) / width;
+
+		let mut raw = Vec::with_capacity(((last - first + 1) * width / 8) as usize);
+		for unit in first..=last {
+			let v = self.unit_read(&field.kind, unit * width / 8, width as u8)?;
+			raw.extend_from_slice(&v.to_le_bytes()[..(width / 8) as usize]);
+		}
+
+		let bits = read_bits(&raw, field.bit_offset - first * width, field.bit_len);
+		Ok(self.field_value(bits, field.bit_len))
+	}
+
+	fn write_field(&mut self, field: &FieldUnit, v: Object<'a>) -> Result<()> {
+		let value = self.convert_buffer(v)?;
+		let width = Self::access_width(field);
+		let first = field.bit_offset / width;
+		let last = (field.bit_offset + field.bit_len.max(1) - 1) / width;
+
+		for unit in first..=last {
+			let unit_start = unit * width;
+			let lo = field.bit_offset.max(unit_start);
+			let hi = (field.bit_offset + field.bit_len).min(unit_start + width);
+			let mask = match hi - lo {
+				64 => u64::MAX,
+				n => ((1u64 << n) - 1) << (lo - unit_start)
+			};
+
+			let bits = read_bits(&value, lo - field.bit_offset, hi - lo);
+			let mut bytes = [0u8; 8];
+			bytes[..bits.len()].copy_from_slice(&bits);
+			let bits = u64::from_le_bytes(bytes) << (lo - unit_start);
+
+			let unit_mask = match width { 64 => u64::MAX, w => (1u64 << w) - 1 };
+			let old = match (mask == unit_mask, field.update_rule()) {
+				(true, _) => 0,
+				(false, FIELD_UPDATE_WRITE_AS_ONES) => unit_mask,
+				(false, FIELD_UPDATE_WRITE_AS_ZEROS) => 0,
+				(false, _) => self.unit_read(&field.kind, unit * width / 8, width as u8)?
+			};
+
+			self.unit_write(&field.kind, unit * width / 8, width as u8, (old & !mask) | (bits & mask))?;
+		}
+
+		Ok(())
+	}
+
+	fn unit_read(&mut self, kind: &FieldKind, offset: u64, width: u8) -> Result<u64> {
+		match kind {
+			FieldKind::Normal { region } => self.region_access(region, offset, width, None),
+			FieldKind::Bank { region, bank, value } => {
+				self.store(Some(&Reference::Name(bank.clone())), Object::Integer(*value))?;
+				self.region_access(region, offset, width, None)
+			}
+			FieldKind::Index { index, data } => {
+				self.store(Some(&Reference::Name(index.clone())), Object::Integer(offset))?;
+				let v = self.read_name(data)?;
+				self.convert_integer(v)
+			}
+		}
+	}
+
+	fn unit_write(&mut self, kind: &FieldKind, offset: u64, width: u8, value: u64) -> Result<()> {
+		match kind {
+			FieldKind::Normal { region } => self.region_access(region, offset, width, Some(value)).map(|_| ()),
+			FieldKind::Bank { region, bank, value: bank_value } => {
+				self.store(Some(&Reference::Name(bank.clone())), Object::Integer(*bank_value))?;
+				self.region_access(region, offset, width, Some(value)).map(|_| ())
+			}
+			FieldKind::Index { index, data } => {
+				self.store(Some(&Reference::Name(index.clone())), Object::Integer(offset))?;
+				self.store(Some(&Reference::Name(data.clone())), Object::Integer(value))
+			}
+		}
+	}
+
+	/// Reads from or writes to `offset` in an operation region.
+	fn region_access(&mut self, region: &str, offset: u64, width: u8, value: Option<u64>) -> Result<u64> {
+		let Some(Object::OperationRegion(region)) = self.ns.objects.get(region).cloned() else {
+			return Err(AmlError::InvalidRegion);
+		};
+
+		if offset + width as u64 / 8 > region.length {
+			return Err(AmlError::InvalidRegion);
+		}
+
+		let address = region.offset + offset;
+		Ok(match (region.space, value) {
+			(RegionSpace::SystemMemory, None) => self.handler.read_memory(address, width),
+			(RegionSpace::SystemMemory, Some(v)) => { self.handler.write_memory(address, width, v); 0 }
+			(RegionSpace::SystemIo, None) => self.handler.read_io(address as u16, width),
+			(RegionSpace::SystemIo, Some(v)) => { self.handler.write_io(address as u16, width, v); 0 }
+			(RegionSpace::PciConfig, v) => {
+				let pci = self.pci_address(&region.scope)?;
+				match v {
+					None => self.handler.read_pci(pci, address as u16, width),
+					Some(v) => { self.handler.write_pci(pci, address as u16, width, v); 0 }
+				}
+			}
+			_ => return Err(AmlError::InvalidRegion)
+		})
+	}
+
+	/// Determines the PCI function of the device at `scope`. The segment and bus are
+	/// taken from the closest ancestor with `_SEG` and `_BBN`, bridges in between
+	/// are not followed.
+	fn pci_address(&mut self, scope: &str) -> Result<PciAddress> {
+		let adr = self.optional_integer(&child(scope, "_ADR"))?.unwrap_or(0);
+		let mut address = PciAddress { segment: 0, bus: 0, device: (adr >> 16) as u8, function: adr as u8 };
+
+		let mut path = Some(scope);
+		let (mut seg, mut bbn) = (None, None);
+		while let Some(p) = path {
+			if seg.is_none() { seg = self.optional_integer(&child(p, "_SEG"))?; }
+			if bbn.is_none() { bbn = self.optional_integer(&child(p, "_BBN"))?; }
+			if seg.is_some() && bbn.is_some() { break; }
+			path = parent(p);
+		}
+
+		address.segment = seg.unwrap_or(0) as u16;
+		address.bus = bbn.unwrap_or(0) as u8;
+		Ok(address)
+	}
+
+	fn optional_integer(&mut self, path: &str) -> Result<Option<u64>> {
+		match self.ns.objects.contains_key(path) {
+			true => {
+				let v = self.evaluate(path, Vec::new())?;
+				self.convert_integer(v).map(Some)
+			}
+			false => Ok(None)
+		}
+	}
+
+	// ---------------------------------------------------------------------------------------
+	// Methods
+	// ---------------------------------------------------------------------------------------
+
+	fn invoke(&mut self, path: &str, args: Vec<Object<'a>>) -> Result<Object<'a>> {
+		let Some(Object::Method(method)) = self.ns.objects.get(path).cloned() else {
+			return Err(AmlError::NotFound(String::from(path)));
+		};
+
+		let code = match method.code {
+			MethodCode::Native(f) => return Ok(f(&args)),
+			MethodCode::Aml(code) => code
+		};
+
+		if self.frames.len() >= MAX_CALL_DEPTH {
+			return Err(AmlError::CallDepth);
+		}
+
+		let mut frame = Frame {
+			args:    Default::default(),
+			locals:  Default::default(),
+			created: Vec::new()
+		};
+		for (slot, arg) in frame.args.iter_mut().zip(args) {
+			*slot = arg;
+		}
+
+		self.frames.push(frame);
+		let result = self.with_scope(String::from(path), |this| match this.term_list(&mut Stream::new(code))? {
+			// references to locals and arguments can't outlive the method
+			Flow::Return(Object::Reference(r @ (Reference::Local(_) | Reference::Arg(_)))) => this.read_ref(&r),
+			Flow::Return(v) => Ok(v),
+			_ => Ok(Object::Integer(0))
+		});
+		let frame = self.frames.pop().unwrap();
+
+		for path in frame.created {
+			self.ns.objects.remove(&path);
+		}
+
+		result
+	}
+}
+
+fn element<'a>(v: Object<'a>, index: usize) -> Result<Object<'a>> {
+	match v {
+		Object::Package(v) => v.into_iter().nth(index).ok_or(AmlError::InvalidIndex),
+		Object::Buffer(v) => v.get(index).map(|v| Object::Integer(*v as _)).ok_or(AmlError::InvalidIndex),
+		Object::String(v) => v.as_bytes().get(index).map(|v| Object::Integer(*v as _)).ok_or(AmlError::InvalidIndex),
+		_ => Err(AmlError::InvalidType)
+	}
+}
+
+/// Parses an integer for `ToInteger`, which accepts decimal and `0x` prefixed strings.
+fn parse_integer(s: &str) -> u64 {
+	let s = s.trim();
+	match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+		Some(hex) => u64::from_str_radix(hex, 16).unwrap_or(0),
+		None => s.parse().unwrap_or(0)
+	}
+}
+
+/// Extracts `len` bits starting at bit `offset`, missing bits read as zero.
+fn read_bits(buf: &[u8], offset: u64, len: u64) -> Vec<u8> {
+	let mut out = alloc::vec![0u8; ((len + 7) / 8) as usize];
+	for i in 0..len {
+		let bit = offset + i;
+		if buf.get((bit / 8) as usize).map_or(false, |b| b >> (bit % 8) & 1 != 0) {
+			out[(i / 8) as usize] |= 1 << (i % 8);
+		}
+	}
+	out
+}
+
+fn write_bits(buf: &mut [u8], offset: u64, len: u64, value: &[u8]) {
+	for i in 0..len {
+		let bit = offset + i;
+		let Some(b) = buf.get_mut((bit / 8) as usize) else { return };
+		match value.get((i / 8) as usize).map_or(false, |v| v >> (i % 8) & 1 != 0) {
+			true  => *b |= 1 << (bit % 8),
+			false => *b &= !(1 << (bit % 8))
+		}
+	}
+}
@@ -0,0 +1,635 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! ACPI Machine Language
+//!
+//! The definition blocks of the DSDT and SSDTs are loaded into a [`Namespace`], which
+//! can then be queried for devices, their resources, PCI interrupt routing and sleep
+//! objects. Methods are evaluated by the interpreter in `eval`, accesses to operation
+//! regions are forwarded to a [`Handler`].
+
+use alloc::{string::String, vec::Vec, collections::BTreeMap, format};
+use super::{AmlCode, DescHeader};
+
+pub use crate::pcie::PciAddress;
+
+mod eval;
+pub mod resource;
+
+pub use resource::*;
+
+pub type Result<T> = core::result::Result<T, AmlError>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AmlError {
+	/// The definition block ended in the middle of a term
+	UnexpectedEnd,
+	/// Unknown opcode, extended opcodes are prefixed with `0x5B`
+	InvalidOpcode(u16),
+	InvalidName,
+	NotFound(String),
+	AlreadyExists(String),
+	/// The operand has the wrong type for the operation
+	InvalidType,
+	InvalidIndex,
+	/// Access outside of the operation region or to an unsupported address space
+	InvalidRegion,
+	DivideByZero,
+	/// The AML executed a `Fatal` opcode
+	Fatal { ty: u8, code: u32, arg: u64 },
+	/// A `While` loop exceeded [`MAX_LOOP_ITERATIONS`]
+	LoopLimit,
+	/// Method invocations nested deeper than [`MAX_CALL_DEPTH`]
+	CallDepth,
+	/// A valid opcode that isn't implemented, e.g. `Load`
+	Unsupported(&'static str)
+}
+
+pub const MAX_LOOP_ITERATIONS: usize = 0x10_0000;
+pub const MAX_CALL_DEPTH:      usize = 64;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Object<'a> {
+	#[default]
+	Uninitialized,
+	Integer(u64),
+	String(String),
+	Buffer(Vec<u8>),
+	Package(Vec<Object<'a>>),
+	Reference(Reference),
+	Method(Method<'a>),
+	Scope,
+	Device,
+	Processor { id: u8, pblk_addr: u32, pblk_len: u8 },
+	PowerResource { system_level: u8, resource_order: u16 },
+	ThermalZone,
+	OperationRegion(Region),
+	FieldUnit(FieldUnit),
+	BufferField { source: Reference, bit_offset: u64, bit_len: u64 },
+	Mutex { sync_level: u8 },
+	Event,
+	/// Absolute path of the aliased object
+	Alias(String),
+	/// An object declared with `External`, which is expected to be defined by another table
+	External { ty: u8, args: u8 }
+}
+
+impl<'a> Object<'a> {
+	/// The value returned by `ObjectType`.
+	pub fn type_id(&self) -> u64 {
+		match self {
+			Self::Uninitialized               => 0,
+			Self::Integer(_)                  => 1,
+			Self::String(_)                   => 2,
+			Self::Buffer(_)                   => 3,
+			Self::Package(_)                  => 4,
+			Self::FieldUnit(_)                => 5,
+			Self::Device                      => 6,
+			Self::Event                       => 7,
+			Self::Method(_)                   => 8,
+			Self::Mutex { .. }                => 9,
+			Self::OperationRegion(_)          => 10,
+			Self::PowerResource { .. }        => 11,
+			Self::Processor { .. }            => 12,
+			Self::ThermalZone                 => 13,
+			Self::BufferField { .. }          => 14,
+			Self::Reference(Reference::Debug) => 16,
+			Self::External { ty, .. }         => *ty as _,
+			Self::Scope | Self::Alias(_) | Self::Reference(_) => 0
+		}
+	}
+
+	pub fn as_integer(&self) -> Option<u64> {
+		match self {
+			Self::Integer(v) => Some(*v),
+			_ => None
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match self {
+			Self::String(v) => Some(v),
+			_ => None
+		}
+	}
+
+	pub fn as_buffer(&self) -> Option<&[u8]> {
+		match self {
+			Self::Buffer(v) => Some(v),
+			_ => None
+		}
+	}
+
+	pub fn as_package(&self) -> Option<&[Object<'a>]> {
+		match self {
+			Self::Package(v) => Some(v),
+			_ => None
+		}
+	}
+
+	/// Returns true for objects that only exist to open a scope in the namespace.
+	pub fn is_scope(&self) -> bool {
+		matches!(self, Self::Scope | Self::Device | Self::Processor { .. } | Self::PowerResource { .. } | Self::ThermalZone)
+	}
+}
+
+/// A storage location, as returned by `RefOf` and `Index` or used as a target.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Reference {
+	/// Absolute path of a named object
+	Name(String),
+	Local(u8),
+	Arg(u8),
+	/// Element of a package, or byte of a buffer or string
+	Index(alloc::boxed::Box<Reference>, usize),
+	Debug
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Method<'a> {
+	pub args:       u8,
+	pub serialized: bool,
+	pub sync_level: u8,
+	pub code:       MethodCode<'a>
+}
+
+#[derive(Clone, Debug)]
+pub enum MethodCode<'a> {
+	Aml(&'a [u8]),
+	/// Methods provided by the OS itself, e.g. `\_OSI`
+	Native(fn(&[Object<'a>]) -> Object<'a>)
+}
+
+impl PartialEq for MethodCode<'_> {
+	fn eq(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Self::Aml(a), Self::Aml(b)) => a.as_ptr() == b.as_ptr() && a.len() == b.len(),
+			(Self::Native(a), Self::Native(b)) => *a as usize == *b as usize,
+			_ => false
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RegionSpace {
+	SystemMemory,
+	SystemIo,
+	PciConfig,
+	EmbeddedControl,
+	SmBus,
+	SystemCmos,
+	PciBarTarget,
+	Ipmi,
+	GeneralPurposeIo,
+	GenericSerialBus,
+	Pcc,
+	Oem(u8)
+}
+
+impl From<u8> for RegionSpace {
+	fn from(v: u8) -> Self {
+		match v {
+			0x00 => Self::SystemMemory,
+			0x01 => Self::SystemIo,
+			0x02 => Self::PciConfig,
+			0x03 => Self::EmbeddedControl,
+			0x04 => Self::SmBus,
+			0x05 => Self::SystemCmos,
+			0x06 => Self::PciBarTarget,
+			0x07 => Self::Ipmi,
+			0x08 => Self::GeneralPurposeIo,
+			0x09 => Self::GenericSerialBus,
+			0x0A => Self::Pcc,
+			v    => Self::Oem(v)
+		}
+	}
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Region {
+	pub space:  RegionSpace,
+	pub offset: u64,
+	pub length: u64,
+	/// Absolute path of the scope the region was declared in, PCI config regions are
+	/// relative to the device of this scope.
+	pub scope:  String
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldUnit {
+	pub kind:       FieldKind,
+	pub bit_offset: u64,
+	pub bit_len:    u64,
+	/// Access type (bits 0-3), lock rule (bit 4) and update rule (bits 5-6)
+	pub flags:      u8
+}
+
+pub const FIELD_ACCESS_ANY:    u8 = 0;
+pub const FIELD_ACCESS_BYTE:   u8 = 1;
+pub const FIELD_ACCESS_WORD:   u8 = 2;
+pub const FIELD_ACCESS_DWORD:  u8 = 3;
+pub const FIELD_ACCESS_QWORD:  u8 = 4;
+pub const FIELD_ACCESS_BUFFER: u8 = 5;
+
+pub const FIELD_UPDATE_PRESERVE:        u8 = 0;
+pub const FIELD_UPDATE_WRITE_AS_ONES:   u8 = 1;
+pub const FIELD_UPDATE_WRITE_AS_ZEROS:  u8 = 2;
+
+impl FieldUnit {
+	pub fn access_type(&self) -> u8 {
+		self.flags & 0xF
+	}
+
+	pub fn update_rule(&self) -> u8 {
+		(self.flags >> 5) & 0x3
+	}
+}
+
+/// Field units refer to their region and index/bank registers by absolute path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FieldKind {
+	Normal { region: String },
+	Index { index: String, data: String },
+	Bank { region: String, bank: String, value: u64 }
+}
+
+/// Provides the interpreter with access to the hardware. Widths are in bits and
+/// always one of 8, 16, 32 or 64.
+pub trait Handler {
+	fn read_memory(&mut self, address: u64, width: u8) -> u64;
+
+	fn write_memory(&mut self, address: u64, width: u8, value: u64);
+
+	fn read_io(&mut self, port: u16, width: u8) -> u64;
+
+	fn write_io(&mut self, port: u16, width: u8, value: u64);
+
+	fn read_pci(&mut self, address: PciAddress, offset: u16, width: u8) -> u64;
+
+	fn write_pci(&mut self, address: PciAddress, offset: u16, width: u8, value: u64);
+
+	/// Busy waits for `us` microseconds.
+	fn stall(&mut self, _us: u64) {}
+
+	/// Sleeps for `ms` milliseconds.
+	fn sleep(&mut self, _ms: u64) {}
+
+	/// Returns a monotonic timestamp in units of 100ns.
+	fn timer(&mut self) -> u64 {
+		0
+	}
+
+	fn notify(&mut self, _path: &str, _value: u64) {}
+
+	/// Called for every store to the `Debug` object.
+	fn debug(&mut self, _value: &Object) {}
+}
+
+/// The ACPI namespace, objects are stored by their absolute path, e.g. `\_SB_.PCI0._CRS`.
+pub struct Namespace<'a> {
+	objects:  BTreeMap<String, Object<'a>>,
+	revision: u8
+}
+
+/// Strings `\_OSI` answers with true for, the same set Linux reports.
+pub const OSI_STRINGS: &[&str] = &[
+	"Windows 2000", "Windows 2001", "Windows 2001 SP1", "Windows 2001.1", "Windows 2001 SP2",
+	"Windows 2001.1 SP1", "Windows 2006", "Windows 2006.1", "Windows 2006 SP1", "Windows 2006 SP2",
+	"Windows 2009", "Windows 2012", "Windows 2013", "Windows 2015", "Windows 2016", "Windows 2017",
+	"Windows 2017.2", "Windows 2018", "Windows 2018.2", "Windows 2019", "Windows 2020", "Windows 2021",
+	"Windows 2022", "Module Device", "Processor Device", "3.0 Thermal Model", "3.0 _SCP Extensions",
+	"Processor Aggregator Device", "Extended Address Space Descriptor"
+];
+
+fn osi<'a>(args: &[Object<'a>]) -> Object<'a> {
+	Object::Integer(match args.first() {
+		Some(Object::String(s)) if OSI_STRINGS.contains(&s.as_str()) => u64::MAX,
+		_ => 0
+	})
+}
+
+impl<'a> Namespace<'a> {
+	/// Creates the namespace with the predefined root scopes. `revision` is the
+	/// revision of the DSDT, integers are 32 bits wide for revisions below 2.
+	pub fn new(revision: u8) -> Self {
+		let mut objects = BTreeMap::new();
+
+		for path in ["\\", "\\_GPE", "\\_PR_", "\\_SB_", "\\_SI_", "\\_TZ_"] {
+			objects.insert(String::from(path), Object::Scope);
+		}
+
+		objects.insert(String::from("\\_OS_"), Object::String(String::from("Microsoft Windows NT")));
+		objects.insert(String::from("\\_REV"), Object::Integer(2));
+		objects.insert(String::from("\\_GL_"), Object::Mutex { sync_level: 0 });
+		objects.insert(String::from("\\_OSI"), Object::Method(Method {
+			args:       1,
+			serialized: false,
+			sync_level: 0,
+			code:       MethodCode::Native(osi)
+		}));
+
+		Self { objects, revision }
+	}
+
+	pub fn revision(&self) -> u8 {
+		self.revision
+	}
+
+	/// Loads a definition block, i.e. the contents of the DSDT or an SSDT following
+	/// the table header. Code at the top level of the block is executed.
+	pub fn load(&mut self, code: &'a AmlCode, handler: &mut dyn Handler) -> Result<()> {
+		eval::Interpreter::new(self, handler).load(code)
+	}
+
+	/// Loads the definition block of a DSDT or SSDT.
+	pub fn load_table(&mut self, table: &'a DescHeader, handler: &mut dyn Handler) -> Result<()> {
+		let code = unsafe { (core::ptr::slice_from_raw_parts(
+			(table as *const DescHeader).add(1) as *const u8,
+			table.length as usize - core::mem::size_of::<DescHeader>()
+		) as *const AmlCode).as_ref().unwrap() };
+		self.load(code, handler)
+	}
+
+	pub fn get(&self, path: &str) -> Option<&Object<'a>> {
+		self.objects.get(&normalize(path)?)
+	}
+
+	pub fn contains(&self, path: &str) -> bool {
+		self.get(path).is_some()
+	}
+
+	/// Iterates over all objects in the namespace in path order.
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &Object<'a>)> {
+		self.objects.iter().map(|(k, v)| (k.as_str(), v))
+	}
+
+	/// Returns the absolute paths of all direct children of `path`.
+	pub fn children(&self, path: &str) -> Vec<String> {
+		let Some(path) = normalize(path) else { return Vec::new() };
+		let prefix = if path == "\\" { path } else { format!("{}.", path) };
+
+		self.objects.range(prefix.clone()..)
+			.take_while(|(k, _)| k.starts_with(&prefix))
+			.filter(|(k, _)| k.len() > prefix.len() && !k[prefix.len()..].contains('.'))
+			.map(|(k, _)| k.clone())
+			.collect()
+	}
+
+	/// Returns the absolute paths of all devices, processors and thermal zones in
+	/// path order.
+	pub fn devices(&self) -> Vec<String> {
+		self.objects.iter()
+			.filter(|(_, v)| matches!(v, Object::Device | Object::Processor { .. } | Object::ThermalZone))
+			.map(|(k, _)| k.clone())
+			.collect()
+	}
+
+	/// Evaluates the object at `path`, methods are invoked with `args`.
+	pub fn evaluate(&mut self, path: &str, args: Vec<Object<'a>>, handler: &mut dyn Handler) -> Result<Object<'a>> {
+		let path = normalize(path).ok_or(AmlError::InvalidName)?;
+		eval::Interpreter::new(self, handler).evaluate(&path, args)
+	}
+
+	/// Like [`Namespace::evaluate`] without arguments, but returns `None` if the object
+	/// does not exist.
+	pub fn evaluate_optional(&mut self, path: &str, handler: &mut dyn Handler) -> Result<Option<Object<'a>>> {
+		match self.contains(path) {
+			true  => self.evaluate(path, Vec::new(), handler).map(Some),
+			false => Ok(None)
+		}
+	}
+
+	/// Evaluates `_STA` of a device, devices without one are present and functioning.
+	pub fn device_status(&mut self, path: &str, handler: &mut dyn Handler) -> Result<u64> {
+		Ok(match self.evaluate_optional(&child(path, "_STA"), handler)? {
+			Some(Object::Integer(v)) => v,
+			Some(_) => return Err(AmlError::InvalidType),
+			None => STA_DEFAULT
+		})
+	}
+
+	/// Runs `\_SB._INI` and the `_INI` of all present devices, as the OS has to do
+	/// after loading all tables. Children of absent devices are skipped, unless the
+	/// device reports itself as functioning.
+	pub fn initialize(&mut self, handler: &mut dyn Handler) -> Result<()> {
+		if self.contains("\\_SB_._INI") {
+			self.evaluate("\\_SB_._INI", Vec::new(), handler)?;
+		}
+
+		let mut skip: Option<String> = None;
+		for path in self.devices() {
+			if let Some(prefix) = &skip {
+				if path.starts_with(prefix.as_str()) { continue; }
+				skip = None;
+			}
+
+			let sta = self.device_status(&path, handler)?;
+			if sta & STA_PRESENT != 0 && self.contains(&child(&path, "_INI")) {
+				self.evaluate(&child(&path, "_INI"), Vec::new(), handler)?;
+			}
+
+			if sta & (STA_PRESENT | STA_FUNCTIONING) == 0 {
+				skip = Some(format!("{}.", path));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Collects the identification objects of a device.
+	pub fn device_info(&mut self, path: &str, handler: &mut dyn Handler) -> Result<DeviceInfo<'a>> {
+		let path = normalize(path).ok_or(AmlError::InvalidName)?;
+		let hid = self.evaluate_optional(&child(&path, "_HID"), handler)?.and_then(|v| id_string(&v));
+		let cid = match self.evaluate_optional(&child(&path, "_CID"), handler)? {
+			Some(Object::Package(v)) => v.iter().filter_map(id_string).collect(),
+			Some(v) => id_string(&v).into_iter().collect(),
+			None => Vec::new()
+		};
+		let uid = self.evaluate_optional(&child(&path, "_UID"), handler)?;
+		let adr = self.evaluate_optional(&child(&path, "_ADR"), handler)?.and_then(|v| v.as_integer());
+		let status = self.device_status(&path, handler)?;
+		Ok(DeviceInfo { path, hid, cid, uid, adr, status })
+	}
+
+	/// Returns the paths of all devices whose `_HID` or `_CID` matches `id`, e.g. `PNP0A08`.
+	pub fn find_devices(&mut self, id: &str, handler: &mut dyn Handler) -> Result<Vec<String>> {
+		let mut found = Vec::new();
+		for path in self.devices() {
+			let info = self.device_info(&path, handler)?;
+			if info.hid.as_deref() == Some(id) || info.cid.iter().any(|v| v == id) {
+				found.push(path);
+			}
+		}
+		Ok(found)
+	}
+
+	/// Decodes the current resource settings (`_CRS`) of a device.
+	pub fn resources(&mut self, path: &str, handler: &mut dyn Handler) -> Result<Vec<Resource>> {
+		match self.evaluate(&child(path, "_CRS"), Vec::new(), handler)? {
+			Object::Buffer(v) => parse_resources(&v),
+			_ => Err(AmlError::InvalidType)
+		}
+	}
+
+	/// Decodes the PCI interrupt routing table (`_PRT`) of a PCI root bridge or bridge.
+	pub fn pci_routing(&mut self, path: &str, handler: &mut dyn Handler) -> Result<Vec<PciRoute>> {
+		let Object::Package(entries) = self.evaluate(&child(path, "_PRT"), Vec::new(), handler)? else {
+			return Err(AmlError::InvalidType);
+		};
+
+		entries.iter().map(|entry| {
+			let [Object::Integer(addr), Object::Integer(pin), source, Object::Integer(index)] = entry.as_package()
+				.ok_or(AmlError::InvalidType)? else { return Err(AmlError::InvalidType) };
+
+			Ok(PciRoute {
+				device: (*addr >> 16) as u16,
+				pin:    *pin as u8,
+				source: match source {
+					Object::Integer(0) => PciRouteSource::Gsi(*index as u32),
+					Object::Reference(Reference::Name(path)) => PciRouteSource::Link { path: path.clone(), index: *index as u32 },
+					Object::String(path) => PciRouteSource::Link { path: normalize(path).ok_or(AmlError::InvalidName)?, index: *index as u32 },
+					_ => return Err(AmlError::InvalidType)
+				}
+			})
+		}).collect()
+	}
+
+	/// Evaluates `\_Sx` and returns the `SLP_TYPa` and `SLP_TYPb` values for the sleep
+	/// state, or `None` if the state is not supported.
+	pub fn sleep_type(&mut self, state: u8, handler: &mut dyn Handler) -> Result<Option<(u8, u8)>> {
+		if state > 5 {
+			return Ok(None);
+		}
+
+		Ok(match self.evaluate_optional(&format!("\\_S{}_", state), handler)? {
+			Some(Object::Package(v)) => match v.as_slice() {
+				[Object::Integer(a), Object::Integer(b), ..] => Some((*a as u8, *b as u8)),
+				// some firmware packs both values into the first element
+				[Object::Integer(a)] => Some((*a as u8, (*a >> 8) as u8)),
+				_ => return Err(AmlError::InvalidType)
+			},
+			Some(_) => return Err(AmlError::InvalidType),
+			None => None
+		})
+	}
+
+	fn insert(&mut self, path: String, object: Object<'a>) -> Result<()> {
+		if self.objects.contains_key(&path) {
+			return Err(AmlError::AlreadyExists(path));
+		}
+		self.objects.insert(path, object);
+		Ok(())
+	}
+}
+
+impl core::fmt::Debug for Namespace<'_> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_map().entries(self.objects.iter()).finish()
+	}
+}
+
+pub const STA_PRESENT:     u64 = 0x01;
+pub const STA_ENABLED:     u64 = 0x02;
+pub const STA_SHOWN:       u64 = 0x04;
+pub const STA_FUNCTIONING: u64 = 0x08;
+pub const STA_BATTERY:     u64 = 0x10;
+pub const STA_DEFAULT:     u64 = 0x0F;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo<'a> {
+	pub path:   String,
+	/// Hardware ID, EISA IDs are decoded into their string form
+	pub hid:    Option<String>,
+	/// Compatible IDs
+	pub cid:    Vec<String>,
+	pub uid:    Option<Object<'a>>,
+	pub adr:    Option<u64>,
+	pub status: u64
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PciRoute {
+	pub device: u16,
+	/// 0 = INTA, 1 = INTB, ...
+	pub pin:    u8,
+	pub source: PciRouteSource
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PciRouteSource {
+	/// Hardwired to a global system interrupt
+	Gsi(u32),
+	/// Routed through a link device, `index` selects the interrupt in its resources
+	Link { path: String, index: u32 }
+}
+
+/// Decodes a compressed EISA ID, e.g. `0x080AD041` to `PNP0A08`.
+pub fn decode_eisa_id(id: u32) -> String {
+	let v = id.swap_bytes();
+	let c = |shift: u32| (((v >> shift) & 0x1F) as u8 + 0x40) as char;
+	format!("{}{}{}{:04X}", c(26), c(21), c(16), v & 0xFFFF)
+}
+
+fn id_string(v: &Object) -> Option<String> {
+	match v {
+		Object::Integer(v) => Some(decode_eisa_id(*v as u32)),
+		Object::String(v) => Some(v.clone()),
+		_ => None
+	}
+}
+
+/// Converts a path in ASL notation, like `\_SB.PCI0`, into an absolute namespace
+/// path with padded name segments.
+pub fn normalize(path: &str) -> Option<String> {
+	let rest = path.strip_prefix('\\')?;
+	let mut out = String::from("\\");
+
+	for (i, seg) in rest.split('.').filter(|s| !s.is_empty()).enumerate() {
+		if seg.len() > 4 || !seg.bytes().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == b'_') {
+			return None;
+		}
+		if i > 0 { out.push('.'); }
+		out.push_str(seg);
+		(seg.len()..4).for_each(|_| out.push('_'));
+	}
+
+	Some(out)
+}
+
+/// Appends a name segment to an absolute path.
+pub fn child(path: &str, seg: &str) -> String {
+	let path = normalize(path).unwrap_or_else(|| String::from(path));
+	let seg = format!("{:_<4}", seg);
+	match path.as_str() {
+		"\\" => format!("\\{}", seg),
+		_    => format!("{}.{}", path, seg)
+	}
+}
+
+/// Returns the parent scope of an absolute path.
+pub fn parent(path: &str) -> Option<&str> {
+	match path.rfind('.') {
+		Some(i) => Some(&path[..i]),
+		None if path.len() > 1 => Some("\\"),
+		None => None
+	}
+}
@@ -0,0 +1,254 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Resource descriptors, as returned by `_CRS`, `_PRS` and `_RES`
+
+use alloc::vec::Vec;
+use crate::acpi::GenericAddress;
+use super::{AmlError, Result};
+
+const SMALL_IRQ:         u8 = 0x04;
+const SMALL_DMA:         u8 = 0x05;
+const SMALL_START_DEP:   u8 = 0x06;
+const SMALL_END_DEP:     u8 = 0x07;
+const SMALL_IO:          u8 = 0x08;
+const SMALL_FIXED_IO:    u8 = 0x09;
+const SMALL_FIXED_DMA:   u8 = 0x0A;
+const SMALL_END:         u8 = 0x0F;
+
+const LARGE_MEMORY24:    u8 = 0x01;
+const LARGE_REGISTER:    u8 = 0x02;
+const LARGE_MEMORY32:    u8 = 0x05;
+const LARGE_FIXED_MEM32: u8 = 0x06;
+const LARGE_DWORD:       u8 = 0x07;
+const LARGE_WORD:        u8 = 0x08;
+const LARGE_EXT_IRQ:     u8 = 0x09;
+const LARGE_QWORD:       u8 = 0x0A;
+const LARGE_EXTENDED:    u8 = 0x0B;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Resource {
+	Irq(Irq),
+	Dma { channels: Vec<u8>, flags: u8 },
+	Io { decode16: bool, min: u16, max: u16, align: u16, len: u16 },
+	Memory { writable: bool, min: u64, max: u64, align: u64, len: u64 },
+	AddressRange(AddressRange),
+	Register(GenericAddress)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Irq {
+	/// IRQ numbers for the small descriptor, GSIs for the extended descriptor
+	pub interrupts: Vec<u32>,
+	pub edge:       bool,
+	pub active_low: bool,
+	pub shared:     bool,
+	pub wake:       bool
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AddressRangeKind {
+	Memory,
+	Io,
+	BusNumber,
+	Other(u8)
+}
+
+/// A Word, DWord, QWord or Extended address space descriptor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AddressRange {
+	pub kind:        AddressRangeKind,
+	/// The device produces the range for its children, e.g. a host bridge window
+	pub producer:    bool,
+	pub granularity: u64,
+	pub min:         u64,
+	pub max:         u64,
+	/// Offset from the secondary (child) side to the primary side
+	pub translation: u64,
+	pub len:         u64,
+	/// Type specific flags
+	pub flags:       u8
+}
+
+/// Decodes a resource template. Unknown descriptors are skipped, parsing stops at
+/// the end tag.
+pub fn parse_resources(buf: &[u8]) -> Result<Vec<Resource>> {
+	let mut resources = Vec::new();
+	let mut pos = 0;
+
+	while pos < buf.len() {
+		let tag = buf[pos];
+
+		if tag & 0x80 == 0 {
+			let len = (tag & 0x7) as usize;
+			// optional trailing bytes read as zero
+			let mut data = [0u8; 7];
+			data[..len].copy_from_slice(buf.get(pos + 1..pos + 1 + len).ok_or(AmlError::UnexpectedEnd)?);
+			pos += 1 + len;
+
+			match (tag >> 3) & 0xF {
+				SMALL_IRQ => {
+					let mask = u16::from_le_bytes([data[0], data[1]]);
+					// edge triggered, active high if the flags are omitted
+					let flags = if len < 3 { 0x1 } else { data[2] };
+					resources.push(Resource::Irq(Irq {
+						interrupts: (0..16).filter(|i| mask & (1 << i) != 0).collect(),
+						edge:       flags & 0x1 != 0,
+						active_low: flags & 0x8 != 0,
+						shared:     flags & 0x10 != 0,
+						wake:       flags & 0x20 != 0
+					}));
+				}
+				SMALL_DMA => resources.push(Resource::Dma {
+					channels: (0..8).filter(|i| data[0] & (1 << i) != 0).collect(),
+					flags:    data[1]
+				}),
+				SMALL_IO => {
+					let min = u16::from_le_bytes([data[1], data[2]]);
+					let max = u16::from_le_bytes([data[3], data[4]]);
+					resources.push(Resource::Io {
+						decode16: data[0] & 1 != 0,
+						min,
+						max,
+						align:    data[5] as _,
+						len:      data[6] as _
+					});
+				}
+				SMALL_FIXED_IO => {
+					let base = u16::from_le_bytes([data[0], data[1]]) & 0x3FF;
+					resources.push(Resource::Io { decode16: false, min: base, max: base, align: 1, len: data[2] as _ });
+				}
+				SMALL_FIXED_DMA => resources.push(Resource::Dma {
+					channels: alloc::vec![u16::from_le_bytes([data[2], data[3]]) as u8],
+					flags:    data[4]
+				}),
+				SMALL_END => break,
+				// dependent function descriptors are flattened
+				SMALL_START_DEP | SMALL_END_DEP => (),
+				_ => ()
+			}
+		} else {
+			let len = u16::from_le_bytes([
+				*buf.get(pos + 1).ok_or(AmlError::UnexpectedEnd)?,
+				*buf.get(pos + 2).ok_or(AmlError::UnexpectedEnd)?
+			]) as usize;
+			let data = buf.get(pos + 3..pos + 3 + len).ok_or(AmlError::UnexpectedEnd)?;
+			pos += 3 + len;
+
+			// the smallest large descriptor is the extended interrupt with a single interrupt
+			if data.len() < 6 {
+				return Err(AmlError::UnexpectedEnd);
+			}
+
+			match tag & 0x7F {
+				LARGE_MEMORY24 => resources.push(Resource::Memory {
+					writable: data[0] & 1 != 0,
+					min:      (u16le(data, 1) as u64) << 8,
+					max:      (u16le(data, 3) as u64) << 8,
+					align:    u16le(data, 5) as _,
+					len:      (u16le(data, 7) as u64) << 8
+				}),
+				LARGE_REGISTER => resources.push(Resource::Register(GenericAddress {
+					address_space: data[0],
+					bit_width:     data[1],
+					bit_offset:    data[2],
+					access_size:   data[3],
+					address:       u64le(data, 4)
+				})),
+				LARGE_MEMORY32 => resources.push(Resource::Memory {
+					writable: data[0] & 1 != 0,
+					min:      u32le(data, 1) as _,
+					max:      u32le(data, 5) as _,
+					align:    u32le(data, 9) as _,
+					len:      u32le(data, 13) as _
+				}),
+				LARGE_FIXED_MEM32 => {
+					let base = u32le(data, 1) as u64;
+					let len = u32le(data, 5) as u64;
+					resources.push(Resource::Memory {
+						writable: data[0] & 1 != 0,
+						min:      base,
+						max:      base,
+						align:    1,
+						len
+					});
+				}
+				LARGE_WORD => resources.push(address_space(data, 2, |d, i| u16le(d, i) as _)?),
+				LARGE_DWORD => resources.push(address_space(data, 4, |d, i| u32le(d, i) as _)?),
+				LARGE_QWORD => resources.push(address_space(data, 8, u64le)?),
+				// the extended descriptor has a revision and reserved byte before the fields
+				LARGE_EXTENDED => resources.push(address_space(data, 8, |d, i| u64le(d, i + 2))?),
+				LARGE_EXT_IRQ => {
+					let flags = data[0];
+					let count = data[1] as usize;
+					resources.push(Resource::Irq(Irq {
+						interrupts: (0..count).map(|i| u32le(data, 2 + i * 4)).collect(),
+						edge:       flags & 0x2 != 0,
+						active_low: flags & 0x4 != 0,
+						shared:     flags & 0x8 != 0,
+						wake:       flags & 0x10 != 0
+					}));
+				}
+				_ => ()
+			}
+		}
+	}
+
+	Ok(resources)
+}
+
+/// Decodes the common layout of the address space descriptors, `width` is the size
+/// of the granularity, min, max, translation and length fields.
+fn address_space(data: &[u8], width: usize, read: impl Fn(&[u8], usize) -> u64) -> Result<Resource> {
+	if data.len() < 3 + 5 * width {
+		return Err(AmlError::UnexpectedEnd);
+	}
+
+	let field = |i: usize| read(data, 3 + i * width);
+	Ok(Resource::AddressRange(AddressRange {
+		kind:        match data[0] {
+			0 => AddressRangeKind::Memory,
+			1 => AddressRangeKind::Io,
+			2 => AddressRangeKind::BusNumber,
+			v => AddressRangeKind::Other(v)
+		},
+		producer:    data[1] & 0x1 == 0,
+		granularity: field(0),
+		min:         field(1),
+		max:         field(2),
+		translation: field(3),
+		len:         field(4),
+		flags:       data[2]
+	}))
+}
+
+fn u16le(data: &[u8], i: usize) -> u16 {
+	data.get(i..i + 2).map_or(0, |v| u16::from_le_bytes(v.try_into().unwrap()))
+}
+
+fn u32le(data: &[u8], i: usize) -> u32 {
+	data.get(i..i + 4).map_or(0, |v| u32::from_le_bytes(v.try_into().unwrap()))
+}
+
+fn u64le(data: &[u8], i: usize) -> u64 {
+	data.get(i..i + 8).map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()))
+}
@@ -20,11 +20,12 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-#![allow(unaligned_references)]
-
 use core::{iter::IntoIterator, mem::size_of};
 use super::*;
 
+pub mod aml;
+pub mod power;
+
 #[repr(transparent)]
 pub struct AmlCode([u8]);
 
@@ -34,6 +35,14 @@ impl core::fmt::Debug for AmlCode {
 	}
 }
 
+impl core::ops::Deref for AmlCode {
+	type Target = [u8];
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
 pub trait TableTrait {
 	fn validate<'a>(ptr: *const Self) -> Option<&'a Self>;
 }
@@ -43,36 +52,47 @@ pub trait TableTrait {
 pub struct RSDP {
 	/// “RSD PTR ” (Notice that this signature must contain a trailing blank
 	/// character.)
-	signature:         [u8; 8],
+	pub signature:         [u8; 8],
 	/// This is the checksum of the fields defined in the ACPI 1.0
 	/// specification. This includes only the first 20 bytes of this table, bytes
 	/// 0 to 19, including the checksum field. These bytes must sum to
 	/// zero.
-	checksum:          u8,
+	pub checksum:          u8,
 	/// An OEM-supplied string that identifies the OEM.
-	oem_id:            [u8; 6],
+	pub oem_id:            [u8; 6],
 	/// The revision of this structure. Larger revision numbers are backward
 	/// compatible to lower revision numbers. The ACPI version 1.0
 	/// revision number of this table is zero. The ACPI version 1.0 RSDP
 	/// Structure only includes the first 20 bytes of this table, bytes 0 to 19.
 	/// It does not include the Length field and beyond. The current value
 	/// for this field is 2.
-	revision:          u8,
+	pub revision:          u8,
 	/// 32 bit physical address of the RSDT.
-	rsdt:              Ptr32<RSDT>,
+	pub rsdt:              Ptr32<RSDT>,
 	/// The length of the table, in bytes, including the header, starting from
 	/// offset 0. This field is used to record the size of the entire table. This
 	/// field is not available in the ACPI version 1.0 RSDP Structure.
-	length:            u32,
+	pub length:            u32,
 	/// 64 bit physical address of the XSDT.
-	xsdt:              Ptr64<XSDT>,
+	pub xsdt:              Ptr64<XSDT>,
 	/// This is a checksum of the entire table, including both checksum fields.
-	extended_checksum: u8,
+	pub extended_checksum: u8,
 	/// Reserved field
-	reserved:          [u8; 3]
+	pub _res0:             [u8; 3]
 }
 
 impl RSDP {
+	pub const SIGNATURE: [u8; 8] = *b"RSD PTR ";
+
+	/// Checks the signature and both checksums, the extended checksum is only present
+	/// since revision 2.
+	pub fn is_valid(&self) -> bool {
+		let ptr = self as *const Self as *const u8;
+		self.signature == Self::SIGNATURE
+			&& checksum(unsafe { core::slice::from_raw_parts(ptr, 20) })
+			&& (self.revision < 2 || checksum(unsafe { core::slice::from_raw_parts(ptr, self.length as usize) }))
+	}
+
 	pub fn get_rsdt(&self) -> Option<&RSDT> {
 		unsafe { self.rsdt.as_ref() }
 	}
@@ -82,12 +102,17 @@ impl RSDP {
 			return None;
 		}
 
-		unsafe { (self.rsdt.as_ptr() as usize as *const DescHeader).as_ref().map(|h| h.into()) }
+		unsafe { (self.xsdt.as_ptr() as usize as *const DescHeader).as_ref().map(|h| h.into()) }
 	}
 }
 
-impl super::Table for RSDP {
-	const GUID: u128 = super::ACPI_20_TABLE_GUID;
+/// Returns true if all bytes sum to zero, which is how every ACPI checksum is defined.
+pub fn checksum(bytes: &[u8]) -> bool {
+	bytes.iter().fold(0u8, |acc, v| acc.wrapping_add(*v)) == 0
+}
+
+impl crate::uefi::Table for RSDP {
+	const GUID: u128 = crate::uefi::ACPI_20_TABLE_GUID;
 }
 
 impl core::fmt::Debug for RSDP {
@@ -146,33 +171,33 @@ pub struct DescHeader {
 	/// OSPM ignores the entire table (it is not loaded into ACPI
 	/// namespace); OSPM ignores the table even though the values in the
 	/// Length and Checksum fields are correct.
-	signature:         u32,
+	pub signature:         u32,
 	/// The length of the table, in bytes, including the header, starting from
 	/// offset 0. This field is used to record the size of the entire table.
-	length:            u32,
+	pub length:            u32,
 	/// The revision of the structure corresponding to the signature field for
 	/// this table. Larger revision numbers are backward compatible to
 	/// lower revision numbers with the same signature.
-	revision:          u8,
+	pub revision:          u8,
 	/// The entire table, including the checksum field, must add to zero to
 	/// be considered valid.
-	checksum:          u8,
+	pub checksum:          u8,
 	/// An OEM-supplied string that identifies the OEM.
-	oem_id:            [u8; 6],
+	pub oem_id:            [u8; 6],
 	/// An OEM-supplied string that the OEM uses to identify the particular
 	/// data table. This field is particularly useful when defining a definition
 	/// block to distinguish definition block functions. The OEM assigns
 	/// each dissimilar table a new OEM Table ID.
-	oem_table_id:      [u8; 8],
+	pub oem_table_id:      [u8; 8],
 	/// An OEM-supplied revision number. Larger numbers are assumed to
 	/// be newer revisions.
-	oem_revision:      u32,
+	pub oem_revision:      u32,
 	/// Vendor ID of utility that created the table. For tables containing
 	/// Definition Blocks, this is the ID for the ASL Compiler.
-	creator_id:        u32,
+	pub creator_id:        u32,
 	/// Revision of utility that created the table. For tables containing
 	/// Definition Blocks, this is the revision for the ASL Compiler.
-	creator_revision:  u32
+	pub creator_revision:  u32
 }
 
 impl<'a> Into<Table<'a>> for &'a DescHeader {
@@ -185,6 +210,7 @@ impl<'a> Into<Table<'a>> for &'a DescHeader {
 				b"BERT" => Bert((ptr as *const BERT).as_ref().unwrap()),
 				b"BGRT" => Bgrt((ptr as *const BGRT).as_ref().unwrap()),
 				b"CPEP" => Cpep((ptr as *const CPEP).as_ref().unwrap()),
+				b"DSDT" => Dsdt((ptr as *const DSDT).as_ref().unwrap()),
 				b"ECDT" => Ecdt((ptr as *const ECDT).as_ref().unwrap()),
 				b"FACP" => Fadt((ptr as *const FADT).as_ref().unwrap()),
 				b"FACS" => Facs((ptr as *const FACS).as_ref().unwrap()),
@@ -192,6 +218,7 @@ impl<'a> Into<Table<'a>> for &'a DescHeader {
 				b"SBST" => Sbst((ptr as *const SBST).as_ref().unwrap()),
 				b"SLIT" => Slit((ptr as *const SLIT).as_ref().unwrap()),
 				b"SRAT" => Srat((ptr as *const SRAT).as_ref().unwrap()),
+				b"SSDT" => Ssdt((ptr as *const SSDT).as_ref().unwrap()),
 				b"XSDT" => Xsdt((ptr as *const XSDT).as_ref().unwrap()),
 				b"HPET" => Hpet((ptr as *const HPET).as_ref().unwrap()),
 				b"MCFG" => Mcfg((ptr as *const MCFG).as_ref().unwrap()),
@@ -203,6 +230,13 @@ impl<'a> Into<Table<'a>> for &'a DescHeader {
 	}
 }
 
+impl DescHeader {
+	/// Checks the checksum over the whole table, the table must be mapped up to `length`.
+	pub fn is_valid(&self) -> bool {
+		checksum(unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) })
+	}
+}
+
 impl core::fmt::Debug for DescHeader {
 	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
 		f.debug_struct("DescHeader")
@@ -247,7 +281,7 @@ pub enum Table<'a> {
 	//Sdev(&'a SDEV),
 	Slit(&'a SLIT),
 	Srat(&'a SRAT),
-	//Ssdt(&'a SSDT),
+	Ssdt(&'a SSDT),
 	Xsdt(&'a XSDT),
 	Boot,
 	//Csrt(&'a CSRT),
@@ -336,19 +370,21 @@ impl<'a> IntoIterator for &'a XSDT {
 	type IntoIter = XsdtEntryIter<'a>;
 
 	fn into_iter(self) -> Self::IntoIter {
-		XsdtEntryIter(self.entries[..(self.header.length as usize
-			- size_of::<DescHeader>()) / 8].iter())
+		// the entries are only 4-byte aligned, so they can't be borrowed as `[u64]`
+		XsdtEntryIter(unsafe { core::slice::from_raw_parts(
+			core::ptr::addr_of!(self.entries) as *const [u8; 8],
+			({ self.header.length } as usize - size_of::<DescHeader>()) / 8) }.iter())
 	}
 }
 
 #[derive(Clone)]
-pub struct XsdtEntryIter<'a>(<&'a [u64] as IntoIterator>::IntoIter);
+pub struct XsdtEntryIter<'a>(<&'a [[u8; 8]] as IntoIterator>::IntoIter);
 
 impl<'a> Iterator for XsdtEntryIter<'a> {
 	type Item = Table<'a>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		self.0.next().map(|ptr| unsafe { (*ptr as usize as *const DescHeader).as_ref().unwrap().into() })
+		self.0.next().map(|ptr| unsafe { (u64::from_le_bytes(*ptr) as usize as *const DescHeader).as_ref().unwrap().into() })
 	}
 }
 
@@ -429,24 +465,100 @@ pub struct FADT {
 }
 
 impl FADT {
+	pub const FLAG_WBINVD:               u32 = 1 << 0;
+	pub const FLAG_WBINVD_FLUSH:         u32 = 1 << 1;
+	pub const FLAG_PROC_C1:              u32 = 1 << 2;
+	pub const FLAG_P_LVL2_UP:            u32 = 1 << 3;
+	pub const FLAG_PWR_BUTTON:           u32 = 1 << 4;
+	pub const FLAG_SLP_BUTTON:           u32 = 1 << 5;
+	pub const FLAG_FIX_RTC:              u32 = 1 << 6;
+	pub const FLAG_RTC_S4:               u32 = 1 << 7;
+	pub const FLAG_TMR_VAL_EXT:          u32 = 1 << 8;
+	pub const FLAG_DCK_CAP:              u32 = 1 << 9;
+	pub const FLAG_RESET_REG_SUP:        u32 = 1 << 10;
+	pub const FLAG_SEALED_CASE:          u32 = 1 << 11;
+	pub const FLAG_HEADLESS:             u32 = 1 << 12;
+	pub const FLAG_CPU_SW_SLP:           u32 = 1 << 13;
+	pub const FLAG_PCI_EXP_WAK:          u32 = 1 << 14;
+	pub const FLAG_USE_PLATFORM_CLOCK:   u32 = 1 << 15;
+	pub const FLAG_S4_RTC_STS_VALID:     u32 = 1 << 16;
+	pub const FLAG_REMOTE_POWER_ON:      u32 = 1 << 17;
+	pub const FLAG_FORCE_APIC_CLUSTER:   u32 = 1 << 18;
+	pub const FLAG_FORCE_APIC_PHYS_DEST: u32 = 1 << 19;
+	pub const FLAG_HW_REDUCED_ACPI:      u32 = 1 << 20;
+	pub const FLAG_LOW_POWER_S0_IDLE:    u32 = 1 << 21;
+
 	pub fn firmware_ctrl(&self) -> Option<&FACS> {
-		unsafe {
-			if self.firmware_ctrl != 0 {
-				self.firmware_ctrl as usize as *const FACS
-			} else if self.x_firmware_ctrl != 0 && self.header.revision >= 2 {
-				self.x_firmware_ctrl as usize as *const FACS
-			} else {
-				return None
-			}.as_ref()
+		unsafe { self.firmware_ctrl_ptr().as_ref() }
+	}
+
+	/// Returns the address of the FACS for writing the waking vector, or null.
+	pub fn firmware_ctrl_ptr(&self) -> *mut FACS {
+		if self.firmware_ctrl != 0 {
+			self.firmware_ctrl as usize as *mut FACS
+		} else if self.x_firmware_ctrl != 0 && self.header.revision >= 2 {
+			self.x_firmware_ctrl as usize as *mut FACS
+		} else {
+			core::ptr::null_mut()
+		}
+	}
+
+	pub fn is_hw_reduced(&self) -> bool {
+		self.flags & Self::FLAG_HW_REDUCED_ACPI != 0
+	}
+
+	/// The reset register, if the platform supports resetting through it.
+	pub fn reset_register(&self) -> Option<GenericAddress> {
+		let reg = self.reset_reg;
+		match self.flags & Self::FLAG_RESET_REG_SUP != 0 && reg.address != 0 {
+			true  => Some(reg),
+			false => None
+		}
+	}
+
+	pub fn pm1a_evt_blk(&self) -> Option<GenericAddress> {
+		self.x_block(self.x_pm1a_evt_blk, self.pm1a_evt_blk, self.pm1_evt_len)
+	}
+
+	pub fn pm1b_evt_blk(&self) -> Option<GenericAddress> {
+		self.x_block(self.x_pm1b_evt_blk, self.pm1b_evt_blk, self.pm1_evt_len)
+	}
+
+	pub fn pm1a_cnt_blk(&self) -> Option<GenericAddress> {
+		self.x_block(self.x_pm1a_cnt_blk, self.pm1a_cnt_blk, self.pm1_cnt_len)
+	}
+
+	pub fn pm1b_cnt_blk(&self) -> Option<GenericAddress> {
+		self.x_block(self.x_pm1b_cnt_blk, self.pm1b_cnt_blk, self.pm1_cnt_len)
+	}
+
+	pub fn pm_tmr_blk(&self) -> Option<GenericAddress> {
+		self.x_block(self.x_pm_tmr_blk, self.pm_tmr_blk, self.pm_tmr_lem)
+	}
+
+	/// Returns the extended block if it is set, or the legacy IO port block otherwise.
+	fn x_block(&self, x: GenericAddress, legacy: u32, len: u8) -> Option<GenericAddress> {
+		if self.header.revision >= 2 && { x.address } != 0 {
+			Some(x)
+		} else if legacy != 0 {
+			Some(GenericAddress {
+				address_space: AddressSpace::SystemIoSpace as _,
+				bit_width:     len * 8,
+				bit_offset:    0,
+				access_size:   0,
+				address:       legacy as _
+			})
+		} else {
+			None
 		}
 	}
 
 	pub fn dsdt(&self) -> Option<&DSDT> {
 		unsafe {
-			if self.dsdt.0 != 0 {
-				self.dsdt.as_ref()
-			} else if self.x_dsdt.0 != 0 && self.header.revision >= 2 {
-				self.x_dsdt.as_ref()
+			if !{ self.dsdt }.as_ptr().is_null() {
+				{ self.dsdt }.as_ref()
+			} else if !{ self.x_dsdt }.as_ptr().is_null() && self.header.revision >= 2 {
+				{ self.x_dsdt }.as_ref()
 			} else {
 				None
 			}
@@ -782,44 +894,189 @@ pub struct SRAT {
 	pub header:    DescHeader,
 	_res0:         u32,
 	_res1:         u64,
-	static_resource_allocations: [StaticResourceAllocation; 0x1000]
+	static_resource_allocations: [u8; 0x1000]
+}
+
+impl<'a> IntoIterator for &'a SRAT {
+	type Item     = <SratEntryIter<'a> as Iterator>::Item;
+	type IntoIter = SratEntryIter<'a>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		SratEntryIter(&self.static_resource_allocations[..(self.header.length as usize
+			- size_of::<DescHeader>() - 12)])
+	}
 }
 
 impl core::fmt::Debug for SRAT {
 	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
 		f.debug_struct("SRAT")
 			.field("header", &self.header)
+			.field("static_resource_allocations", &self.into_iter())
 			.finish()
 	}
 }
 
-pub enum StaticResourceAllocation {
-	ProcessorLocalApicSapicAffinity {
+#[derive(Clone)]
+pub struct SratEntryIter<'a>(&'a [u8]);
 
-	},
-	MemoryAffinity {
+impl<'a> Iterator for SratEntryIter<'a> {
+	type Item = &'a StaticResourceAllocation;
 
-	},
-	ProcessorLocalX2ApicAffinity {
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let len = *self.0.get(1)? as usize;
+			if len < 2 || len > self.0.len() {
+				return None;
+			}
 
-	},
-	GiccAffinity {
+			let (entry, rest) = self.0.split_at(len);
+			self.0 = rest;
 
-	},
-	GicInterruptTranslationServiceAffinity {
+			// skip structures added by newer revisions, e.g. generic initiators
+			if entry[0] <= 4 {
+				return unsafe { (entry.as_ptr() as *const StaticResourceAllocation).as_ref() };
+			}
+		}
+	}
+}
 
+impl core::fmt::Debug for SratEntryIter<'_> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_list().entries(self.clone()).finish()
 	}
 }
 
-#[repr(C)]
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SratProcessorLocalApicSapicAffinity {
+	pub length:                    u8,
+	pub proximity_domain_lo:       u8,
+	pub apic_id:                   u8,
+	pub flags:                     u32,
+	pub local_sapic_eid:           u8,
+	pub proximity_domain_hi:       [u8; 3],
+	pub clock_domain:              u32
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SratMemoryAffinity {
+	pub length:                    u8,
+	pub proximity_domain:          u32,
+	pub _res0:                     u16,
+	pub base_address:              u64,
+	pub range_length:              u64,
+	pub _res1:                     u32,
+	pub flags:                     u32,
+	pub _res2:                     u64
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SratProcessorLocalX2ApicAffinity {
+	pub length:                    u8,
+	pub _res0:                     u16,
+	pub proximity_domain:          u32,
+	pub x2_apic_id:                u32,
+	pub flags:                     u32,
+	pub clock_domain:              u32,
+	pub _res1:                     u32
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SratGiccAffinity {
+	pub length:                    u8,
+	pub proximity_domain:          u32,
+	pub acpi_processor_uid:        u32,
+	pub flags:                     u32,
+	pub clock_domain:              u32
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SratGicInterruptTranslationServiceAffinity {
+	pub length:                    u8,
+	pub proximity_domain:          u32,
+	pub _res0:                     u16,
+	pub its_id:                    u32
+}
+
+#[repr(u8)]
+#[derive(Debug)]
+pub enum StaticResourceAllocation {
+	ProcessorLocalApicSapicAffinity(SratProcessorLocalApicSapicAffinity),
+	MemoryAffinity(SratMemoryAffinity),
+	ProcessorLocalX2ApicAffinity(SratProcessorLocalX2ApicAffinity),
+	GiccAffinity(SratGiccAffinity),
+	GicInterruptTranslationServiceAffinity(SratGicInterruptTranslationServiceAffinity)
+}
+
+impl StaticResourceAllocation {
+	/// Set for all structures if the entry is in use, processors and memory ranges
+	/// without it are to be ignored.
+	pub const FLAG_ENABLED:       u32 = 0x1;
+	pub const FLAG_HOT_PLUGGABLE: u32 = 0x2;
+	pub const FLAG_NON_VOLATILE:  u32 = 0x4;
+
+	pub fn proximity_domain(&self) -> u32 {
+		match self {
+			Self::ProcessorLocalApicSapicAffinity(v) => u32::from_le_bytes([
+				v.proximity_domain_lo, v.proximity_domain_hi[0], v.proximity_domain_hi[1], v.proximity_domain_hi[2]]),
+			Self::MemoryAffinity(v)                         => v.proximity_domain,
+			Self::ProcessorLocalX2ApicAffinity(v)           => v.proximity_domain,
+			Self::GiccAffinity(v)                           => v.proximity_domain,
+			Self::GicInterruptTranslationServiceAffinity(v) => v.proximity_domain
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		let flags = match self {
+			Self::ProcessorLocalApicSapicAffinity(v)        => v.flags,
+			Self::MemoryAffinity(v)                         => v.flags,
+			Self::ProcessorLocalX2ApicAffinity(v)           => v.flags,
+			Self::GiccAffinity(v)                           => v.flags,
+			Self::GicInterruptTranslationServiceAffinity(_) => Self::FLAG_ENABLED
+		};
+		flags & Self::FLAG_ENABLED != 0
+	}
+}
+
+/// System Locality Distance Information Table, a `localities` x `localities` matrix
+/// of relative distances between the proximity domains.
+#[repr(C, packed)]
 pub struct SLIT {
-	pub header:  DescHeader,
-	pub entries: [u8; 0x1000_0000]
+	pub header:     DescHeader,
+	pub localities: u64,
+	entries:        [u8; 0x1000_0000]
+}
+
+impl SLIT {
+	/// The distance of a locality to itself, remote localities are greater.
+	pub const LOCAL_DISTANCE:       u8 = 10;
+	/// The locality is unreachable from the other one.
+	pub const UNREACHABLE_DISTANCE: u8 = 0xFF;
+
+	pub fn localities(&self) -> usize {
+		let len = (self.header.length as usize).saturating_sub(size_of::<DescHeader>() + 8);
+		let mut n = (self.localities as usize).min(len);
+		while n * n > len { n -= 1; }
+		n
+	}
+
+	/// Returns the distance from locality `from` to `to`.
+	pub fn distance(&self, from: usize, to: usize) -> Option<u8> {
+		let n = self.localities();
+		(from < n && to < n).then(|| self.entries[from * n + to])
+	}
 }
 
 impl core::fmt::Debug for SLIT {
 	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-		f.debug_struct("SLIT").field("header", &self.header).finish()
+		f.debug_struct("SLIT")
+			.field("header", &{ self.header })
+			.field("localities", &{ self.localities })
+			.finish()
 	}
 }
 
@@ -848,10 +1105,58 @@ pub struct CpepProcessorStructure {
 	pub polling_interval: u32
 }
 
-#[repr(C)]
+/// Firmware ACPI Control Structure, unlike the other tables it has no `DescHeader`
+#[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct FACS {
-	pub header:  DescHeader,
+	pub signature:                u32,
+	pub length:                   u32,
+	pub hardware_signature:       u32,
+	/// Real mode address the firmware jumps to when waking from a sleep state
+	pub firmware_waking_vector:   u32,
+	pub global_lock:              u32,
+	pub flags:                    u32,
+	/// Used instead of `firmware_waking_vector` if non-zero, see `OSPM_FLAG_64BIT_WAKE`
+	pub x_firmware_waking_vector: u64,
+	pub version:                  u8,
+	pub _res0:                    [u8; 3],
+	pub ospm_flags:               u32,
+	pub _res1:                    [u8; 24]
+}
+
+impl FACS {
+	pub const SIGNATURE: [u8; 4] = *b"FACS";
+
+	pub const FLAG_S4BIOS:                u32 = 0x1;
+	pub const FLAG_64BIT_WAKE_SUPPORTED:  u32 = 0x2;
+
+	/// The waking vector is entered in 64-bit mode instead of protected mode
+	pub const OSPM_FLAG_64BIT_WAKE:       u32 = 0x1;
+
+	pub const GLOBAL_LOCK_PENDING:        u32 = 0x1;
+	pub const GLOBAL_LOCK_OWNED:          u32 = 0x2;
+
+	pub fn is_valid(&self) -> bool {
+		self.signature.to_le_bytes() == Self::SIGNATURE && self.length as usize >= size_of::<Self>()
+	}
+
+	/// Sets the real mode waking vector, the address must be below 1 MiB.
+	pub fn set_waking_vector(&mut self, vector: u32) {
+		self.firmware_waking_vector = vector;
+		if self.version >= 1 {
+			self.x_firmware_waking_vector = 0;
+		}
+	}
+
+	/// Sets the extended waking vector, which is entered in protected mode or, if
+	/// `long_mode` is set and the firmware supports it, in 64-bit mode.
+	pub fn set_x_waking_vector(&mut self, vector: u64, long_mode: bool) {
+		self.x_firmware_waking_vector = vector;
+		self.ospm_flags = match long_mode && self.flags & Self::FLAG_64BIT_WAKE_SUPPORTED != 0 {
+			true  => self.ospm_flags | Self::OSPM_FLAG_64BIT_WAKE,
+			false => self.ospm_flags & !Self::OSPM_FLAG_64BIT_WAKE
+		};
+	}
 }
 
 #[repr(C)]
@@ -871,6 +1176,24 @@ impl DSDT {
 	}
 }
 
+/// Secondary System Description Table, continues the namespace of the DSDT
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SSDT {
+	pub header:       DescHeader
+}
+
+impl SSDT {
+	pub fn definition_block(&self) -> &AmlCode {
+		unsafe  {
+			(core::ptr::slice_from_raw_parts(
+				(self as *const Self).add(1) as *const u8,
+				self.header.length as usize - size_of::<DescHeader>()
+			) as *const AmlCode).as_ref().unwrap()
+		}
+	}
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct HPET {
@@ -882,7 +1205,7 @@ pub struct HPET {
 	pub page_protecton_attr:  u8
 }
 
-#[repr(C)]
+#[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct MCFG {
 	pub header:  DescHeader,
@@ -903,7 +1226,7 @@ impl<'a> IntoIterator for &'a MCFG {
 impl core::fmt::Debug for MCFG {
 	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
 		f.debug_struct("MCFG")
-			.field("header", &self.header)
+			.field("header", &{ self.header })
 			.field("entries", &self.into_iter())
 			.finish()
 	}
@@ -926,7 +1249,7 @@ impl core::fmt::Debug for McfgEntryIter<'_> {
 	}
 }
 
-#[repr(C)]
+#[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct McfgEntry {
 	pub address:          Ptr64<()>,
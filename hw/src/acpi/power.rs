@@ -0,0 +1,286 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! ACPI power management
+//!
+//! Sleep states are entered by writing the `SLP_TYP` values from the `\_Sx` objects
+//! to the PM1 control blocks, or to the sleep control register on hardware-reduced
+//! platforms. S5 is the soft-off state, S3 suspends to RAM and resumes at the
+//! waking vector in the FACS.
+
+use alloc::vec;
+use super::{*, aml::{AmlError, Handler, Namespace, Object, PciAddress}};
+
+pub type Result<T> = core::result::Result<T, PowerError>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PowerError {
+	/// Evaluating `\_PTS`, `\_Sx` or `\_WAK` failed
+	Aml(AmlError),
+	/// The platform doesn't support the sleep state or has no reset register
+	Unsupported,
+	/// The FADT has no FACS, which is required for the waking vector
+	NoFacs,
+	/// A register is in an address space that can't be accessed
+	InvalidRegister,
+	/// The firmware did not hand over control to the OS
+	Timeout
+}
+
+impl From<AmlError> for PowerError {
+	fn from(e: AmlError) -> Self {
+		Self::Aml(e)
+	}
+}
+
+pub const PM1_STS_TMR:         u64 = 1 << 0;
+pub const PM1_STS_BM:          u64 = 1 << 4;
+pub const PM1_STS_GBL:         u64 = 1 << 5;
+pub const PM1_STS_PWRBTN:      u64 = 1 << 8;
+pub const PM1_STS_SLPBTN:      u64 = 1 << 9;
+pub const PM1_STS_RTC:         u64 = 1 << 10;
+pub const PM1_STS_WAK:         u64 = 1 << 15;
+
+pub const PM1_CNT_SCI_EN:      u64 = 1 << 0;
+pub const PM1_CNT_BM_RLD:      u64 = 1 << 1;
+pub const PM1_CNT_GBL_RLS:     u64 = 1 << 2;
+pub const PM1_CNT_SLP_TYP:     u64 = 0x7 << 10;
+pub const PM1_CNT_SLP_EN:      u64 = 1 << 13;
+
+pub const SLEEP_CNT_SLP_TYP:   u64 = 0x7 << 2;
+pub const SLEEP_CNT_SLP_EN:    u64 = 1 << 5;
+pub const SLEEP_STS_WAK:       u64 = 1 << 7;
+
+/// Number of times the SCI_EN bit is polled after requesting ACPI mode, 1ms apart
+const ENABLE_POLL_MS: usize = 3000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum SleepState {
+	S0 = 0,
+	S1,
+	S2,
+	S3,
+	S4,
+	S5
+}
+
+pub struct Power<'a> {
+	fadt: &'a FADT
+}
+
+impl<'a> Power<'a> {
+	pub fn new(fadt: &'a FADT) -> Self {
+		Self { fadt }
+	}
+
+	/// Switches the platform from legacy to ACPI mode, if it isn't already.
+	pub fn enable(&self, handler: &mut dyn Handler) -> Result<()> {
+		let (smi_cmd, acpi_enable) = ({ self.fadt.smi_cmd }, { self.fadt.acpi_enable });
+		if self.fadt.is_hw_reduced() || smi_cmd == 0 || acpi_enable == 0 || self.sci_enabled(handler)? {
+			return Ok(());
+		}
+
+		handler.write_io(smi_cmd as _, 8, acpi_enable as _);
+		for _ in 0..ENABLE_POLL_MS {
+			if self.sci_enabled(handler)? {
+				return Ok(());
+			}
+			handler.stall(1000);
+		}
+
+		Err(PowerError::Timeout)
+	}
+
+	fn sci_enabled(&self, handler: &mut dyn Handler) -> Result<bool> {
+		match self.fadt.pm1a_cnt_blk() {
+			Some(reg) => Ok(read_register(&reg, handler)? & PM1_CNT_SCI_EN != 0),
+			None => Ok(true)
+		}
+	}
+
+	/// Enters a sleep state. Returns once the platform woke up from S1 or S2, S3 resumes
+	/// at the waking vector instead and S4 and S5 don't return at all if successful.
+	///
+	/// `\_PTS` is evaluated before, devices must already be in a low power state.
+	pub fn sleep(&self, state: SleepState, ns: &mut Namespace<'_>, handler: &mut dyn Handler) -> Result<()> {
+		let (typ_a, typ_b) = ns.sleep_type(state as u8, handler)?.ok_or(PowerError::Unsupported)?;
+
+		if ns.contains("\\_PTS") {
+			ns.evaluate("\\_PTS", vec![Object::Integer(state as u64)], handler)?;
+		}
+
+		if self.fadt.is_hw_reduced() {
+			let (control, status) = (self.fadt.sleep_control_reg, self.fadt.sleep_status_reg);
+			if { control.address } == 0 {
+				return Err(PowerError::Unsupported);
+			}
+
+			if { status.address } != 0 {
+				write_register(&status, SLEEP_STS_WAK, handler)?;
+			}
+			write_register(&control, (((typ_a as u64) << 2) & SLEEP_CNT_SLP_TYP) | SLEEP_CNT_SLP_EN, handler)?;
+
+			return match { status.address } != 0 {
+				true  => wait(&status, SLEEP_STS_WAK, handler),
+				false => Ok(())
+			};
+		}
+
+		let pm1a = self.fadt.pm1a_cnt_blk().ok_or(PowerError::Unsupported)?;
+		let pm1b = self.fadt.pm1b_cnt_blk();
+
+		// the wake status bits are write-1-to-clear
+		for sts in [self.fadt.pm1a_evt_blk(), self.fadt.pm1b_evt_blk()].into_iter().flatten() {
+			write_register(&status_register(sts), PM1_STS_WAK, handler)?;
+		}
+
+		// SLP_TYP has to be written before SLP_EN
+		let value_a = (read_register(&pm1a, handler)? & !(PM1_CNT_SLP_TYP | PM1_CNT_SLP_EN)) | ((typ_a as u64) << 10);
+		let value_b = match &pm1b {
+			Some(reg) => (read_register(reg, handler)? & !(PM1_CNT_SLP_TYP | PM1_CNT_SLP_EN)) | ((typ_b as u64) << 10),
+			None => 0
+		};
+
+		write_register(&pm1a, value_a, handler)?;
+		if let Some(reg) = &pm1b { write_register(reg, value_b, handler)?; }
+		write_register(&pm1a, value_a | PM1_CNT_SLP_EN, handler)?;
+		if let Some(reg) = &pm1b { write_register(reg, value_b | PM1_CNT_SLP_EN, handler)?; }
+
+		match state {
+			SleepState::S1 | SleepState::S2 => wait(&status_register(self.fadt.pm1a_evt_blk()
+				.ok_or(PowerError::Unsupported)?), PM1_STS_WAK, handler),
+			_ => Ok(())
+		}
+	}
+
+	/// Enters the soft-off state S5.
+	pub fn shutdown(&self, ns: &mut Namespace<'_>, handler: &mut dyn Handler) -> Result<()> {
+		self.sleep(SleepState::S5, ns, handler)
+	}
+
+	/// Suspends to RAM. The firmware resumes execution in real mode at `waking_vector`,
+	/// which must be below 1 MiB, the caller has to save the context of all harts before.
+	pub fn suspend(&self, waking_vector: u32, ns: &mut Namespace<'_>, handler: &mut dyn Handler) -> Result<()> {
+		if ns.sleep_type(SleepState::S3 as u8, handler)?.is_none() {
+			return Err(PowerError::Unsupported);
+		}
+
+		// SAFETY: the FACS is only ever accessed here and by the firmware
+		let facs = unsafe { self.fadt.firmware_ctrl_ptr().as_mut() }.ok_or(PowerError::NoFacs)?;
+		if !facs.is_valid() {
+			return Err(PowerError::NoFacs);
+		}
+
+		facs.set_waking_vector(waking_vector);
+		self.sleep(SleepState::S3, ns, handler)
+	}
+
+	/// Returns from a sleep state, this has to be called after resuming at the waking
+	/// vector or after `sleep` returned.
+	pub fn wake(&self, state: SleepState, ns: &mut Namespace<'_>, handler: &mut dyn Handler) -> Result<()> {
+		if let Some(pm1a) = self.fadt.pm1a_cnt_blk().filter(|_| !self.fadt.is_hw_reduced()) {
+			let v = read_register(&pm1a, handler)?;
+			write_register(&pm1a, v & !(PM1_CNT_SLP_TYP | PM1_CNT_SLP_EN), handler)?;
+		}
+
+		if ns.contains("\\_WAK") {
+			ns.evaluate("\\_WAK", vec![Object::Integer(state as u64)], handler)?;
+		}
+
+		Ok(())
+	}
+
+	/// Resets the platform through the FADT reset register.
+	pub fn reset(&self, handler: &mut dyn Handler) -> Result<()> {
+		let reg = self.fadt.reset_register().ok_or(PowerError::Unsupported)?;
+		write_register(&reg, self.fadt.reset_value as _, handler)
+	}
+}
+
+/// The status register is the first half of a PM1 event block.
+fn status_register(mut evt: GenericAddress) -> GenericAddress {
+	evt.bit_width /= 2;
+	evt
+}
+
+fn wait(reg: &GenericAddress, bit: u64, handler: &mut dyn Handler) -> Result<()> {
+	for _ in 0..ENABLE_POLL_MS {
+		if read_register(reg, handler)? & bit != 0 {
+			return Ok(());
+		}
+		handler.stall(1000);
+	}
+	Err(PowerError::Timeout)
+}
+
+/// The width of an access to the register in bits.
+fn access_width(reg: &GenericAddress) -> u8 {
+	match reg.access_size {
+		1..=4 => 8 << (reg.access_size - 1),
+		_ => match reg.bit_offset + reg.bit_width {
+			0..=8   => 8,
+			9..=16  => 16,
+			17..=32 => 32,
+			_       => 64
+		}
+	}
+}
+
+fn register_mask(reg: &GenericAddress) -> u64 {
+	match reg.bit_width {
+		0 | 64.. => u64::MAX,
+		n => (1 << n) - 1
+	}
+}
+
+/// Reads a register described by a generic address.
+pub fn read_register(reg: &GenericAddress, handler: &mut dyn Handler) -> Result<u64> {
+	let width = access_width(reg);
+	let address = reg.address;
+	let raw = match reg.address_space {
+		0 => handler.read_memory(address, width),
+		1 => handler.read_io(address as _, width),
+		2 => handler.read_pci(pci_address(address), address as u16, width),
+		_ => return Err(PowerError::InvalidRegister)
+	};
+	Ok((raw >> reg.bit_offset) & register_mask(reg))
+}
+
+/// Writes a register described by a generic address, bits outside of the register
+/// in the access unit are written as zero.
+pub fn write_register(reg: &GenericAddress, value: u64, handler: &mut dyn Handler) -> Result<()> {
+	let width = access_width(reg);
+	let address = reg.address;
+	let value = (value & register_mask(reg)) << reg.bit_offset;
+	match reg.address_space {
+		0 => handler.write_memory(address, width, value),
+		1 => handler.write_io(address as _, width, value),
+		2 => handler.write_pci(pci_address(address), address as u16, width, value),
+		_ => return Err(PowerError::InvalidRegister)
+	}
+	Ok(())
+}
+
+/// PCI config registers encode the function in the address, on segment 0 and bus 0.
+fn pci_address(address: u64) -> PciAddress {
+	PciAddress { segment: 0, bus: 0, device: (address >> 32) as u8, function: (address >> 16) as u8 }
+}
@@ -0,0 +1,589 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {super::*, crate::dma::{Dma, Region, PAGE_SIZE}, alloc::vec::Vec, core::ptr::addr_of_mut};
+
+/// PRD entries of a command table, enough for a transfer of `MAX_TRANSFER` bytes that
+/// doesn't start at a page boundary
+pub const PRDS: usize = 33;
+/// Maximum bytes transferred by one command
+pub const MAX_TRANSFER: usize = (PRDS - 1) * PAGE_SIZE;
+/// How long to wait for a command to complete
+pub const COMMAND_TIMEOUT_MS: u64 = 30_000;
+/// How long a device gets to establish the link after spin-up or reset
+pub const LINK_TIMEOUT_MS: u64 = 1_000;
+
+/// Command tables are 128 byte aligned
+const TABLE_SIZE: usize = (0x80 + PRDS * core::mem::size_of::<AhciPrd>() + 0x7F) & !0x7F;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// The device didn't establish the link, become ready or complete a command in time
+	Timeout,
+	/// No ATA device is attached to the port
+	NoDevice,
+	/// The device aborted a command, `status` and `error` are its task file registers
+	Device { status: u8, error: u8 },
+	/// The buffer is misaligned, not a multiple of the sector size or the LBA range is
+	/// outside of the disk
+	InvalidArgument,
+	/// No DMA memory
+	NoMemory,
+	/// No free command slot, or a non-queued command has to wait for queued ones
+	Busy
+}
+
+impl From<Error> for crate::block::Error {
+	fn from(e: Error) -> Self {
+		match e {
+			Error::Timeout         => Self::Timeout,
+			Error::NoDevice        => Self::NoDevice,
+			Error::InvalidArgument => Self::InvalidArgument,
+			Error::NoMemory        => Self::NoMemory,
+			Error::Device { .. } | Error::Busy => Self::Io
+		}
+	}
+}
+
+/// The ATA device attached to a port.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Disk {
+	pub sectors:       u64,
+	pub sector_size:   u32,
+	/// Commands queued at once, one without NCQ
+	pub queue_depth:   u8,
+	pub write_cache:   bool,
+	pub serial_number: [u8; 20],
+	pub model_number:  [u8; 40],
+	flush_ext:         bool
+}
+
+impl Disk {
+	fn check(&self, lba: u64, len: usize) -> Result<u16, Error> {
+		let sectors = len / self.sector_size as usize;
+		match len % self.sector_size as usize == 0 && sectors > 0 && len <= MAX_TRANSFER
+			&& lba.checked_add(sectors as u64).map_or(false, |end| end <= self.sectors) {
+			true  => Ok(sectors as u16),
+			false => Err(Error::InvalidArgument)
+		}
+	}
+}
+
+/// A change of the devices attached to the controller.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+	Attached(usize),
+	Detached(usize)
+}
+
+/// An implemented port with a device attached.
+struct Port {
+	regs:      *mut HbaPort,
+	list:      Region,
+	fis:       Region,
+	/// A command table for each slot
+	tables:    Region,
+	disk:      Disk,
+	ncq:       bool,
+	/// Slots with a command in flight
+	busy:      u32,
+	/// A non-queued command is in flight, it has to complete before others are issued
+	exclusive: bool,
+	/// Slots that completed while waiting for another one
+	done:      Vec<(u8, Result<(), Error>)>
+}
+
+impl Port {
+	unsafe fn read(&self, reg: fn(*mut HbaPort) -> *mut u32) -> u32 {
+		reg(self.regs).read_volatile()
+	}
+
+	fn free(self, dma: &mut impl Dma) {
+		self.list.free(dma);
+		self.fis.free(dma);
+		self.tables.free(dma);
+	}
+}
+
+/// Clears `ST` and `FRE` and waits until the port stopped processing commands and
+/// receiving FISes.
+unsafe fn stop(regs: *mut HbaPort, dma: &mut impl Dma) -> Result<(), Error> {
+	let cmd = addr_of_mut!((*regs).command_status);
+	cmd.write_volatile(cmd.read_volatile() & !PXCMD_ST);
+	wait(dma, 500, || cmd.read_volatile() & PXCMD_CR == 0)?;
+	cmd.write_volatile(cmd.read_volatile() & !PXCMD_FRE);
+	wait(dma, 500, || cmd.read_volatile() & PXCMD_FR == 0)
+}
+
+/// Polls `f` every millisecond.
+fn wait(dma: &mut impl Dma, ms: u64, mut f: impl FnMut() -> bool) -> Result<(), Error> {
+	for _ in 0..ms {
+		if f() {
+			return Ok(());
+		}
+		dma.stall(1000);
+	}
+	match f() {
+		true  => Ok(()),
+		false => Err(Error::Timeout)
+	}
+}
+
+pub struct Controller<D: Dma> {
+	regs:             *mut Hba,
+	dma:              D,
+	pub capabilities: u32,
+	/// Command slots per port
+	pub slots:        u32,
+	ports:            Vec<Option<Port>>
+}
+
+impl<D: Dma> Controller<D> {
+	/// Resets the HBA, brings up the links of all implemented ports and identifies the
+	/// attached disks. Ports without a disk report an `Event::Attached` once one is
+	/// plugged in.
+	///
+	/// # Safety
+	///
+	/// `regs` must map the ABAR (BAR5) of the controller.
+	pub unsafe fn new(regs: *mut u8, dma: D) -> Result<Self, Error> {
+		let hba = regs as *mut Hba;
+		let ghc = addr_of_mut!((*hba).generic_gost_control.global_host_control);
+		ghc.write_volatile(ghc.read_volatile() | GHC_AE);
+		ghc.write_volatile(ghc.read_volatile() | GHC_HR);
+
+		let mut ctrl = Self { regs: hba, dma, capabilities: 0, slots: 0, ports: (0..32).map(|_| None).collect() };
+		wait(&mut ctrl.dma, 1000, || ghc.read_volatile() & GHC_HR == 0)?;
+		ghc.write_volatile(GHC_AE);
+
+		let cap = addr_of_mut!((*hba).generic_gost_control.host_capabilities).read_volatile();
+		ctrl.capabilities = cap;
+		ctrl.slots = ((cap & CAP_NCS_MASK) >> CAP_NCS_SHIFT) + 1;
+
+		for i in ctrl.implemented() {
+			let regs = ctrl.port_regs(i);
+			addr_of_mut!((*regs).interrupt_enable).write_volatile(PXIS_PCS | PXIS_PRCS);
+			match ctrl.attach(i) {
+				Ok(()) | Err(Error::NoDevice) | Err(Error::Timeout) => (),
+				Err(e) => return Err(e)
+			}
+		}
+
+		addr_of_mut!((*hba).generic_gost_control.interrupt_status).write_volatile(!0);
+		ghc.write_volatile(GHC_AE | GHC_IE);
+		Ok(ctrl)
+	}
+
+	fn implemented(&self) -> impl Iterator<Item = usize> {
+		let pi = unsafe { addr_of_mut!((*self.regs).generic_gost_control.ports_implemented).read_volatile() };
+		(0..32).filter(move |i| pi & 1 << i != 0)
+	}
+
+	fn port_regs(&self, i: usize) -> *mut HbaPort {
+		unsafe { addr_of_mut!((*self.regs).ports[i]) }
+	}
+
+	/// Sets up the command list of a port and identifies its device.
+	fn attach(&mut self, i: usize) -> Result<(), Error> {
+		let regs = self.port_regs(i);
+		unsafe { stop(regs, &mut self.dma)? };
+
+		let list = Region::alloc(&mut self.dma, 32 * core::mem::size_of::<AhciCommandHeader>(), 1024).ok_or(Error::NoMemory)?;
+		let (fis, tables) = match (Region::alloc(&mut self.dma, 256, 256), Region::alloc(&mut self.dma, self.slots as usize * TABLE_SIZE, 128)) {
+			(Some(fis), Some(tables)) => (fis, tables),
+			(fis, tables) => {
+				list.free(&mut self.dma);
+				for region in [fis, tables].into_iter().flatten() {
+					region.free(&mut self.dma);
+				}
+				return Err(Error::NoMemory);
+			}
+		};
+
+		unsafe {
+			for slot in 0..self.slots as usize {
+				let table = tables.phys + (slot * TABLE_SIZE) as u64;
+				let header = list.as_ptr::<AhciCommandHeader>().add(slot);
+				addr_of_mut!((*header).ctd_base_address).write_volatile(table as u32);
+				addr_of_mut!((*header).ctd_base_address2).write_volatile((table >> 32) as u32);
+			}
+			addr_of_mut!((*regs).command_list_bar).write_volatile(Ptr64::new(list.phys as usize as *mut _));
+			addr_of_mut!((*regs).fis_bar).write_volatile(Ptr64::new(fis.phys as usize as *mut _));
+		}
+
+		let port = Port {
+			regs,
+			list,
+			fis,
+			tables,
+			disk:      Disk { sectors: 0, sector_size: 512, queue_depth: 1, write_cache: false, serial_number: [0; 20], model_number: [0; 40], flush_ext: false },
+			ncq:       false,
+			busy:      0,
+			exclusive: false,
+			done:      Vec::new()
+		};
+
+		match unsafe { self.link_up(&port) } {
+			Ok(()) => (),
+			Err(e) => {
+				port.free(&mut self.dma);
+				return Err(e);
+			}
+		}
+
+		let depth = self.slots.min(32) as u8;
+		self.ports[i] = Some(port);
+		match self.identify(i) {
+			Ok(id) => {
+				let port = self.ports[i].as_mut().unwrap();
+				port.ncq = self.capabilities & CAP_SNCQ != 0 && id.queue_depth() > 0;
+				port.disk = Disk {
+					sectors:       id.sectors(),
+					sector_size:   id.sector_size(),
+					queue_depth:   if port.ncq { id.queue_depth().min(depth) } else { 1 },
+					write_cache:   id.write_cache_enabled(),
+					serial_number: id.serial_number(),
+					model_number:  id.model_number(),
+					flush_ext:     id.supports_flush_ext()
+				};
+				Ok(())
+			}
+			Err(e) => {
+				self.detach(i);
+				Err(e)
+			}
+		}
+	}
+
+	/// Spins up the device, waits for the link and starts the command list.
+	unsafe fn link_up(&mut self, port: &Port) -> Result<(), Error> {
+		let regs = port.regs;
+		let cmd = addr_of_mut!((*regs).command_status);
+		let is = addr_of_mut!((*regs).interrupt_status);
+		addr_of_mut!((*regs).sata_error).write_volatile(!0);
+		is.write_volatile(is.read_volatile());
+		cmd.write_volatile(cmd.read_volatile() | PXCMD_FRE);
+		if self.capabilities & CAP_SSS != 0 {
+			cmd.write_volatile(cmd.read_volatile() | PXCMD_SUD | PXCMD_POD);
+		}
+
+		let ssts = addr_of_mut!((*regs).sata_status);
+		if wait(&mut self.dma, LINK_TIMEOUT_MS, || ssts.read_volatile() & PXSSTS_DET_MASK == PXSSTS_DET_PRESENT).is_err() {
+			return Err(Error::NoDevice);
+		}
+
+		let tfd = addr_of_mut!((*regs).task_file_data);
+		wait(&mut self.dma, COMMAND_TIMEOUT_MS, || tfd.read_volatile() & (PXTFD_STS_BSY | PXTFD_STS_DRQ) == 0)?;
+		if addr_of_mut!((*regs).signature).read_volatile() != SIG_ATA {
+			return Err(Error::NoDevice);
+		}
+
+		addr_of_mut!((*regs).sata_error).write_volatile(!0);
+		is.write_volatile(is.read_volatile());
+		addr_of_mut!((*regs).interrupt_enable).write_volatile(PXIS_DHRS | PXIS_PSS | PXIS_SDBS | PXIS_PCS | PXIS_PRCS | PXIS_ERRORS);
+		cmd.write_volatile(cmd.read_volatile() | PXCMD_ST);
+		Ok(())
+	}
+
+	fn identify(&mut self, i: usize) -> Result<IdentifyDevice, Error> {
+		let buf = Region::alloc(&mut self.dma, 512, 2).ok_or(Error::NoMemory)?;
+		let fis = FisRegH2d { device: 0, ..FisRegH2d::new(FisRegH2d::ATA_IDENTIFY_DEVICE, 0, 0) };
+		let result = self.issue(i, fis, buf.virt, 512, false, false)
+			.and_then(|slot| self.wait(i, slot));
+
+		let mut id = IdentifyDevice([0; 256]);
+		unsafe { core::ptr::copy_nonoverlapping(buf.virt as *const u16, id.0.as_mut_ptr(), 256) };
+		buf.free(&mut self.dma);
+		result.map(|_| id)
+	}
+
+	fn detach(&mut self, i: usize) {
+		if let Some(port) = self.ports[i].take() {
+			let _ = unsafe { stop(port.regs, &mut self.dma) };
+			port.free(&mut self.dma);
+		}
+	}
+
+	pub fn disk(&self, port: usize) -> Option<&Disk> {
+		self.ports.get(port)?.as_ref().map(|p| &p.disk)
+	}
+
+	/// The ports with a disk attached.
+	pub fn disks(&self) -> impl Iterator<Item = (usize, &Disk)> {
+		self.ports.iter().enumerate().filter_map(|(i, p)| p.as_ref().map(|p| (i, &p.disk)))
+	}
+
+	/// Builds the command table of a free slot and issues the command, returns the slot.
+	fn issue(&mut self, i: usize, fis: FisRegH2d, buf: *const u8, len: usize, write: bool, queued: bool) -> Result<u8, Error> {
+		let port = self.ports.get_mut(i).and_then(Option::as_mut).ok_or(Error::NoDevice)?;
+		if port.exclusive || (!queued && port.busy != 0) {
+			return Err(Error::Busy);
+		}
+		let depth = if queued { port.disk.queue_depth as u32 } else { 1 };
+		let slot = (0..depth).find(|s| port.busy & 1 << s == 0).ok_or(Error::Busy)? as u8;
+
+		let fis = match queued {
+			true  => FisRegH2d { count: slot << 3, ..fis },
+			false => fis
+		};
+
+		// split the buffer into physically contiguous runs
+		let table = unsafe { port.tables.virt.add(slot as usize * TABLE_SIZE) };
+		let prds = unsafe { table.add(0x80) as *mut AhciPrd };
+		let mut count = 0;
+		let mut off = 0;
+		let mut run: Option<(u64, usize)> = None;
+		while off < len {
+			let phys = self.dma.phys(unsafe { buf.add(off) });
+			let n = (PAGE_SIZE - phys as usize % PAGE_SIZE).min(len - off);
+			if phys & 1 != 0 || n % 2 != 0 {
+				return Err(Error::InvalidArgument);
+			}
+			run = match run {
+				Some((start, size)) if start + size as u64 == phys => Some((start, size + n)),
+				Some(prev) => {
+					if count == PRDS {
+						return Err(Error::InvalidArgument);
+					}
+					unsafe { prds.add(count).write_volatile(prd(prev)) };
+					count += 1;
+					Some((phys, n))
+				}
+				None => Some((phys, n))
+			};
+			off += n;
+		}
+		if let Some(last) = run {
+			if count == PRDS {
+				return Err(Error::InvalidArgument);
+			}
+			unsafe { prds.add(count).write_volatile(prd(last)) };
+			count += 1;
+		}
+
+		unsafe {
+			core::ptr::write_bytes(table, 0, 0x80);
+			(table as *mut FisRegH2d).write_volatile(fis);
+			let header = port.list.as_ptr::<AhciCommandHeader>().add(slot as usize);
+			let flags = (core::mem::size_of::<FisRegH2d>() / 4) as u16 | if write { CMD_HEADER_FLAG_W } else { 0 };
+			addr_of_mut!((*header).flags).write_volatile(flags);
+			addr_of_mut!((*header).prd_table_length).write_volatile(count as u16);
+			addr_of_mut!((*header).prs_byte_count).write_volatile(0);
+
+			if queued {
+				addr_of_mut!((*port.regs).sata_active).write_volatile(1 << slot);
+			}
+			addr_of_mut!((*port.regs).command_issue).write_volatile(1 << slot);
+		}
+
+		port.busy |= 1 << slot;
+		port.exclusive = !queued;
+		Ok(slot)
+	}
+
+	/// Moves the commands that finished to `done`. After an error all commands in flight
+	/// fail, as the port has to be restarted.
+	fn reap(&mut self, i: usize) {
+		let port = match self.ports.get_mut(i).and_then(Option::as_mut) {
+			Some(port) => port,
+			None => return
+		};
+
+		unsafe {
+			let is = port.read(|p| addr_of_mut!((*p).interrupt_status));
+			if is & PXIS_ERRORS != 0 {
+				let tfd = port.read(|p| addr_of_mut!((*p).task_file_data));
+				let error = Error::Device { status: tfd as u8, error: (tfd >> 8) as u8 };
+				for slot in (0..32).filter(|s| port.busy & 1 << s != 0) {
+					port.done.push((slot, Err(error)));
+				}
+				port.busy = 0;
+				port.exclusive = false;
+
+				// restart the command list, which clears the outstanding commands
+				let regs = port.regs;
+				addr_of_mut!((*regs).interrupt_status).write_volatile(is & PXIS_ERRORS);
+				let cmd = addr_of_mut!((*regs).command_status);
+				cmd.write_volatile(cmd.read_volatile() & !PXCMD_ST);
+				let _ = wait(&mut self.dma, 500, || cmd.read_volatile() & PXCMD_CR == 0);
+				addr_of_mut!((*regs).sata_error).write_volatile(!0);
+				cmd.write_volatile(cmd.read_volatile() | PXCMD_ST);
+				return;
+			}
+
+			let ack = is & (PXIS_DHRS | PXIS_PSS | PXIS_SDBS);
+			if ack != 0 {
+				addr_of_mut!((*port.regs).interrupt_status).write_volatile(ack);
+			}
+
+			let active = port.read(|p| addr_of_mut!((*p).command_issue)) | port.read(|p| addr_of_mut!((*p).sata_active));
+			let finished = port.busy & !active;
+			for slot in (0..32).filter(|s| finished & 1 << s != 0) {
+				port.done.push((slot, Ok(())));
+			}
+			port.busy &= !finished;
+			if port.busy == 0 {
+				port.exclusive = false;
+			}
+		}
+	}
+
+	fn wait(&mut self, i: usize, slot: u8) -> Result<(), Error> {
+		for _ in 0..COMMAND_TIMEOUT_MS * 10 {
+			self.reap(i);
+			let port = self.ports.get_mut(i).and_then(Option::as_mut).ok_or(Error::NoDevice)?;
+			if let Some(pos) = port.done.iter().position(|(s, _)| *s == slot) {
+				return port.done.swap_remove(pos).1;
+			}
+			self.dma.stall(100);
+		}
+		Err(Error::Timeout)
+	}
+
+	fn submit_rw(&mut self, i: usize, lba: u64, buf: *const u8, len: usize, write: bool) -> Result<u8, Error> {
+		let port = self.ports.get(i).and_then(Option::as_ref).ok_or(Error::NoDevice)?;
+		let count = port.disk.check(lba, len)?;
+		let fis = match (port.ncq, write) {
+			(true, false)  => FisRegH2d::queued(FisRegH2d::ATA_READ_FPDMA_QUEUED, lba, count, 0),
+			(true, true)   => FisRegH2d::queued(FisRegH2d::ATA_WRITE_FPDMA_QUEUED, lba, count, 0),
+			(false, false) => FisRegH2d::new(FisRegH2d::ATA_READ_DMA_EXT, lba, count),
+			(false, true)  => FisRegH2d::new(FisRegH2d::ATA_WRITE_DMA_EXT, lba, count)
+		};
+		let ncq = port.ncq;
+		self.issue(i, fis, buf, len, write, ncq)
+	}
+
+	/// Submits a read of `buf.len()` bytes at `lba`, returns the slot. With NCQ up to
+	/// `Disk::queue_depth` reads and writes are in flight at once.
+	pub fn submit_read(&mut self, port: usize, lba: u64, buf: &mut [u8]) -> Result<u8, Error> {
+		self.submit_rw(port, lba, buf.as_ptr(), buf.len(), false)
+	}
+
+	/// Submits a write of `buf` to `lba`, returns the slot.
+	pub fn submit_write(&mut self, port: usize, lba: u64, buf: &[u8]) -> Result<u8, Error> {
+		self.submit_rw(port, lba, buf.as_ptr(), buf.len(), true)
+	}
+
+	/// Returns a finished command of a port, called from the interrupt handler until it
+	/// returns `None`.
+	pub fn complete(&mut self, port: usize) -> Option<(u8, Result<(), Error>)> {
+		self.reap(port);
+		self.ports.get_mut(port)?.as_mut()?.done.pop()
+	}
+
+	/// Reads `buf.len()` bytes at `lba`, waiting for completion.
+	pub fn read(&mut self, port: usize, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+		let size = self.disk(port).ok_or(Error::NoDevice)?.sector_size as usize;
+		let step = MAX_TRANSFER / size * size;
+		for (i, chunk) in buf.chunks_mut(step).enumerate() {
+			let slot = self.submit_read(port, lba + (i * step / size) as u64, chunk)?;
+			self.wait(port, slot)?;
+		}
+		Ok(())
+	}
+
+	/// Writes `buf` to `lba`, waiting for completion.
+	pub fn write(&mut self, port: usize, lba: u64, buf: &[u8]) -> Result<(), Error> {
+		let size = self.disk(port).ok_or(Error::NoDevice)?.sector_size as usize;
+		let step = MAX_TRANSFER / size * size;
+		for (i, chunk) in buf.chunks(step).enumerate() {
+			let slot = self.submit_write(port, lba + (i * step / size) as u64, chunk)?;
+			self.wait(port, slot)?;
+		}
+		Ok(())
+	}
+
+	/// Flushes the write cache, waiting for completion. Fails with `Busy` while queued
+	/// commands are in flight.
+	pub fn flush(&mut self, port: usize) -> Result<(), Error> {
+		let disk = self.disk(port).ok_or(Error::NoDevice)?;
+		let command = if disk.flush_ext { FisRegH2d::ATA_FLUSH_CACHE_EXT } else { FisRegH2d::ATA_FLUSH_CACHE };
+		let slot = self.issue(port, FisRegH2d::new(command, 0, 0), core::ptr::null(), 0, false, false)?;
+		self.wait(port, slot)
+	}
+
+	/// Acknowledges the interrupt of the HBA, attaches devices that were plugged in and
+	/// detaches removed ones. Completions are left to `complete`.
+	pub fn interrupt(&mut self) -> Vec<Event> {
+		let mut events = Vec::new();
+		let is = unsafe { addr_of_mut!((*self.regs).generic_gost_control.interrupt_status) };
+		let pending = unsafe { is.read_volatile() };
+
+		for i in (0..32).filter(|i| pending & 1 << i != 0) {
+			let regs = self.port_regs(i);
+			let status = unsafe { addr_of_mut!((*regs).interrupt_status).read_volatile() };
+			if status & (PXIS_PCS | PXIS_PRCS) == 0 {
+				// errors are cleared by `reap` when it fails the commands
+				unsafe { addr_of_mut!((*regs).interrupt_status).write_volatile(status & !PXIS_ERRORS) };
+				continue;
+			}
+
+			unsafe {
+				addr_of_mut!((*regs).sata_error).write_volatile(PXSERR_DIAG_X | PXSERR_DIAG_N);
+				addr_of_mut!((*regs).interrupt_status).write_volatile(status & (PXIS_PCS | PXIS_PRCS));
+			}
+			let present = unsafe { addr_of_mut!((*regs).sata_status).read_volatile() } & PXSSTS_DET_MASK == PXSSTS_DET_PRESENT;
+			match (present, self.ports[i].is_some()) {
+				(true, false) if self.attach(i).is_ok() => events.push(Event::Attached(i)),
+				(false, true) => {
+					self.detach(i);
+					events.push(Event::Detached(i));
+				}
+				_ => ()
+			}
+		}
+
+		unsafe { is.write_volatile(pending) };
+		events
+	}
+}
+
+fn prd((phys, len): (u64, usize)) -> AhciPrd {
+	AhciPrd {
+		data_base_addr0: phys as u32,
+		data_base_addr1: (phys >> 32) as u32,
+		_res0:           0,
+		descriptor_info: (len as u32 - 1) & PRD_INFO_DBC_MASK
+	}
+}
+
+impl<D: Dma> Drop for Controller<D> {
+	fn drop(&mut self) {
+		unsafe {
+			let ghc = addr_of_mut!((*self.regs).generic_gost_control.global_host_control);
+			ghc.write_volatile(ghc.read_volatile() & !GHC_IE);
+		}
+		for i in 0..self.ports.len() {
+			self.detach(i);
+		}
+	}
+}
+
+impl<D: Dma> core::fmt::Debug for Controller<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Controller")
+			.field("capabilities", &format_args!("{:#x}", self.capabilities))
+			.field("slots", &self.slots)
+			.field("disks", &self.disks().count())
+			.finish()
+	}
+}
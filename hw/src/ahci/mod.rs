@@ -20,8 +20,14 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+//! Advanced Host Controller Interface, SATA host bus adapters.
+
 use crate::Ptr64;
 
+mod controller;
+
+pub use controller::*;
+
 /// Supports 64-bit Addressing
 pub const CAP_S64A:  u32 = 1 << 31;
 /// Supports Native Command Queuing
@@ -59,7 +65,7 @@ pub const CAP_SSC:      u32 = 1 << 14;
 /// Partial State Capable
 pub const CAP_PSC:      u32 = 1 << 13;
 /// Number of Command Slots
-pub const CAP_NCS_MASK:  u32 = 0b1111 << CAP_NCS_SHIFT;
+pub const CAP_NCS_MASK:  u32 = 0b11111 << CAP_NCS_SHIFT;
 pub const CAP_NCS_SHIFT: u32 = 8;
 /// Command Completion Coalescing Supported
 pub const CAP_CCCS:     u32 = 1 << 7;
@@ -68,7 +74,80 @@ pub const CAP_EMS:      u32 = 1 << 6;
 /// Supports External SATA
 pub const CAP_SXS:      u32 = 1 << 5;
 /// Number of Ports
-pub const CAP_NP_MASK:  u32 = 0b1111;
+pub const CAP_NP_MASK:  u32 = 0b11111;
+
+/// AHCI Enable
+pub const GHC_AE: u32 = 1 << 31;
+/// Interrupt Enable
+pub const GHC_IE: u32 = 1 << 1;
+/// HBA Reset
+pub const GHC_HR: u32 = 1 << 0;
+
+/// Interface Communication Control
+pub const PXCMD_ICC_MASK:   u32 = 0b1111 << 28;
+/// Command List Running
+pub const PXCMD_CR:  u32 = 1 << 15;
+/// FIS Receive Running
+pub const PXCMD_FR:  u32 = 1 << 14;
+/// FIS Receive Enable
+pub const PXCMD_FRE: u32 = 1 << 4;
+/// Command List Override
+pub const PXCMD_CLO: u32 = 1 << 3;
+/// Power On Device
+pub const PXCMD_POD: u32 = 1 << 2;
+/// Spin-Up Device
+pub const PXCMD_SUD: u32 = 1 << 1;
+/// Start
+pub const PXCMD_ST:  u32 = 1 << 0;
+
+/// Task File Error Status
+pub const PXIS_TFES: u32 = 1 << 30;
+/// Host Bus Fatal Error Status
+pub const PXIS_HBFS: u32 = 1 << 29;
+/// Host Bus Data Error Status
+pub const PXIS_HBDS: u32 = 1 << 28;
+/// Interface Fatal Error Status
+pub const PXIS_IFS:  u32 = 1 << 27;
+/// PhyRdy Change Status
+pub const PXIS_PRCS: u32 = 1 << 22;
+/// Port Connect Change Status
+pub const PXIS_PCS:  u32 = 1 << 6;
+/// Set Device Bits Interrupt
+pub const PXIS_SDBS: u32 = 1 << 3;
+/// PIO Setup FIS Interrupt
+pub const PXIS_PSS:  u32 = 1 << 1;
+/// Device to Host Register FIS Interrupt
+pub const PXIS_DHRS: u32 = 1 << 0;
+/// Errors that stop the command list
+pub const PXIS_ERRORS: u32 = PXIS_TFES | PXIS_HBFS | PXIS_HBDS | PXIS_IFS;
+
+/// The error register of the task file
+pub const PXTFD_ERR_MASK: u32 = 0xFF << 8;
+pub const PXTFD_STS_BSY:  u32 = 1 << 7;
+pub const PXTFD_STS_DRQ:  u32 = 1 << 3;
+pub const PXTFD_STS_ERR:  u32 = 1 << 0;
+
+/// Device detection
+pub const PXSSTS_DET_MASK:    u32 = 0xF;
+/// Device present and communication established
+pub const PXSSTS_DET_PRESENT: u32 = 3;
+/// Device detection initialization, sends COMRESET while set
+pub const PXSCTL_DET_INIT:    u32 = 1;
+
+/// Exchanged bit, a device was attached or detached
+pub const PXSERR_DIAG_X: u32 = 1 << 26;
+/// PhyRdy change
+pub const PXSERR_DIAG_N: u32 = 1 << 16;
+
+pub const SIG_ATA:   u32 = 0x0000_0101;
+pub const SIG_ATAPI: u32 = 0xEB14_0101;
+
+/// Write, the data flows from the host to the device
+pub const CMD_HEADER_FLAG_W: u16 = 1 << 6;
+/// Prefetchable
+pub const CMD_HEADER_FLAG_P: u16 = 1 << 7;
+/// Clear busy upon R_OK
+pub const CMD_HEADER_FLAG_C: u16 = 1 << 10;
 
 pub const PRD_INFO_INT_ON_COMPLETION: u32 = 1 << 31;
 pub const PRD_INFO_DBC_MASK:          u32 = !(!0 << 22);
@@ -108,13 +187,21 @@ pub struct HbaPort {
 	pub signature:        u32,
 	pub sata_status:      u32,
 	pub sata_control:     u32,
-	pub sata_error:       u32
+	pub sata_error:       u32,
+	pub sata_active:      u32,
+	pub command_issue:    u32,
+	pub sata_notification: u32,
+	pub fis_switching_control: u32,
+	pub device_sleep:     u32,
+	pub _res1:            [u32; 10],
+	pub vendor_specific:  [u32; 4]
 }
 
 #[repr(C)]
 pub struct AhciCommandHeader {
-	pub prd_table_length:  u16,
+	/// Command FIS length in dwords and `CMD_HEADER_FLAG_*`
 	pub flags:             u16,
+	pub prd_table_length:  u16,
 	pub prs_byte_count:    u32,
 	pub ctd_base_address:  u32,
 	pub ctd_base_address2: u32,
@@ -135,4 +222,136 @@ pub struct AhciPrd {
 	pub data_base_addr1: u32,
 	pub _res0:           u32,
 	pub descriptor_info: u32
-}
\ No newline at end of file
+}
+/// Register FIS, host to device
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FisRegH2d {
+	pub fis_type:     u8,
+	/// Port multiplier and the command bit
+	pub flags:        u8,
+	pub command:      u8,
+	pub features:     u8,
+	pub lba0:         u8,
+	pub lba1:         u8,
+	pub lba2:         u8,
+	pub device:       u8,
+	pub lba3:         u8,
+	pub lba4:         u8,
+	pub lba5:         u8,
+	pub features_exp: u8,
+	pub count:        u8,
+	pub count_exp:    u8,
+	pub icc:          u8,
+	pub control:      u8,
+	pub _res0:        [u8; 4]
+}
+
+impl FisRegH2d {
+	pub const TYPE:         u8 = 0x27;
+	/// The FIS updates the command register
+	pub const FLAG_COMMAND: u8 = 0x80;
+	/// LBA addressing
+	pub const DEVICE_LBA:   u8 = 0x40;
+
+	pub const ATA_IDENTIFY_DEVICE:       u8 = 0xEC;
+	pub const ATA_READ_DMA_EXT:          u8 = 0x25;
+	pub const ATA_WRITE_DMA_EXT:         u8 = 0x35;
+	pub const ATA_READ_FPDMA_QUEUED:     u8 = 0x60;
+	pub const ATA_WRITE_FPDMA_QUEUED:    u8 = 0x61;
+	pub const ATA_FLUSH_CACHE:           u8 = 0xE7;
+	pub const ATA_FLUSH_CACHE_EXT:       u8 = 0xEA;
+
+	pub fn new(command: u8, lba: u64, count: u16) -> Self {
+		Self {
+			fis_type:  Self::TYPE,
+			flags:     Self::FLAG_COMMAND,
+			command,
+			lba0:      lba as u8,
+			lba1:      (lba >> 8) as u8,
+			lba2:      (lba >> 16) as u8,
+			device:    Self::DEVICE_LBA,
+			lba3:      (lba >> 24) as u8,
+			lba4:      (lba >> 32) as u8,
+			lba5:      (lba >> 40) as u8,
+			count:     count as u8,
+			count_exp: (count >> 8) as u8,
+			..Self::default()
+		}
+	}
+
+	/// A queued command, the sector count goes into the features and the tag into
+	/// the count register.
+	pub fn queued(command: u8, lba: u64, count: u16, tag: u8) -> Self {
+		Self {
+			features:     count as u8,
+			features_exp: (count >> 8) as u8,
+			count:        tag << 3,
+			count_exp:    0,
+			..Self::new(command, lba, 0)
+		}
+	}
+
+	pub fn lba(&self) -> u64 {
+		u64::from_le_bytes([self.lba0, self.lba1, self.lba2, self.lba3, self.lba4, self.lba5, 0, 0])
+	}
+}
+
+/// The words of the IDENTIFY DEVICE data the driver uses.
+pub struct IdentifyDevice(pub [u16; 256]);
+
+impl IdentifyDevice {
+	/// ATA strings are stored with the bytes of each word swapped.
+	fn string<const N: usize>(&self, words: core::ops::Range<usize>) -> [u8; N] {
+		let mut s = [0; N];
+		for (i, w) in self.0[words].iter().enumerate() {
+			s[2 * i..2 * i + 2].copy_from_slice(&w.to_be_bytes());
+		}
+		s
+	}
+
+	pub fn serial_number(&self) -> [u8; 20] {
+		self.string(10..20)
+	}
+
+	pub fn model_number(&self) -> [u8; 40] {
+		self.string(27..47)
+	}
+
+	/// Maximum queue depth, zero if NCQ is unsupported.
+	pub fn queue_depth(&self) -> u8 {
+		match self.0[76] & 1 << 8 != 0 {
+			true  => (self.0[75] & 0x1F) as u8 + 1,
+			false => 0
+		}
+	}
+
+	pub fn supports_lba48(&self) -> bool {
+		self.0[83] & 1 << 10 != 0
+	}
+
+	pub fn supports_flush_ext(&self) -> bool {
+		self.0[83] & 1 << 13 != 0
+	}
+
+	pub fn write_cache_enabled(&self) -> bool {
+		self.0[85] & 1 << 5 != 0
+	}
+
+	/// User addressable sectors
+	pub fn sectors(&self) -> u64 {
+		match self.supports_lba48() {
+			true  => self.0[100] as u64 | (self.0[101] as u64) << 16 | (self.0[102] as u64) << 32 | (self.0[103] as u64) << 48,
+			false => self.0[60] as u64 | (self.0[61] as u64) << 16
+		}
+	}
+
+	/// Logical sector size in bytes
+	pub fn sector_size(&self) -> u32 {
+		let w = self.0[106];
+		match w & 0xC000 == 0x4000 && w & 1 << 12 != 0 {
+			true  => 2 * (self.0[117] as u32 | (self.0[118] as u32) << 16),
+			false => 512
+		}
+	}
+}
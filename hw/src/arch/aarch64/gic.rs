@@ -175,4 +175,145 @@ impl GICv2 {
 	pub unsafe fn complete(&mut self, id: usize) {
 		(&mut (*self.gicc).eoi as *mut u32).write_volatile(id as _)
 	}
-}
\ No newline at end of file
+}
+/// Interrupt Translation Service of a GICv3/4, translates MSI writes to `GITS_TRANSLATER`
+/// (see `MsiMessage::gic_its`) into LPIs delivered to the redistributor of a collection.
+pub struct GicIts {
+	base:   *mut u8,
+	queue:  *mut [u64; 4],
+	/// Number of commands in the queue
+	size:   usize,
+	write:  usize
+}
+
+impl GicIts {
+	const CTLR:    usize = 0x0;
+	const TYPER:   usize = 0x8;
+	const CBASER:  usize = 0x80;
+	const CWRITER: usize = 0x88;
+	const CREADR:  usize = 0x90;
+
+	const CMD_MOVI:    u64 = 0x01;
+	const CMD_SYNC:    u64 = 0x05;
+	const CMD_MAPD:    u64 = 0x08;
+	const CMD_MAPC:    u64 = 0x09;
+	const CMD_MAPTI:   u64 = 0x0A;
+	const CMD_INV:     u64 = 0x0C;
+	const CMD_DISCARD: u64 = 0x0F;
+
+	/// The first LPI INTID
+	pub const LPI_BASE: u32 = 8192;
+
+	/// Initializes the ITS with a command queue of 4 KiB pages at `queue`, whose physical
+	/// address is `queue_phys`. The device and collection tables must already be set up
+	/// in `GITS_BASER<n>`.
+	///
+	/// # Safety
+	///
+	/// `base` must map the ITS control frame and `queue` `pages` pages of memory only
+	/// used by the ITS.
+	pub unsafe fn new(base: *mut u8, queue: *mut u8, queue_phys: u64, pages: usize) -> Self {
+		let its = Self { base, queue: queue as _, size: pages * 4096 / 32, write: 0 };
+		its.reg(Self::CTLR).write_volatile(its.reg(Self::CTLR).read_volatile() & !1);
+		core::ptr::write_bytes(queue, 0, pages * 4096);
+		// valid, inner shareable, normal inner write-back cacheable memory
+		its.reg(Self::CBASER).write_volatile(1 << 63 | 7 << 59 | 1 << 10
+			| queue_phys & 0xF_FFFF_FFFF_F000 | (pages as u64 - 1) & 0xFF);
+		its.reg(Self::CWRITER).write_volatile(0);
+		its.reg(Self::CTLR).write_volatile(its.reg(Self::CTLR).read_volatile() | 1);
+		its
+	}
+
+	unsafe fn reg(&self, offset: usize) -> *mut u64 {
+		self.base.add(offset) as *mut u64
+	}
+
+	/// Whether collections target redistributors by physical address instead of
+	/// processor number.
+	pub fn physical_target(&self) -> bool {
+		unsafe { self.reg(Self::TYPER).read_volatile() & 1 << 19 != 0 }
+	}
+
+	unsafe fn command(&mut self, cmd: [u64; 4]) {
+		let next = (self.write + 1) % self.size;
+		while (self.reg(Self::CREADR).read_volatile() as usize & 0xF_FFE0) / 32 == next {
+			core::hint::spin_loop();
+		}
+
+		self.queue.add(self.write).write_volatile(cmd);
+		self.write = next;
+		self.reg(Self::CWRITER).write_volatile((next * 32) as u64);
+	}
+
+	/// Maps a device to its interrupt translation table at the physical address `itt`,
+	/// which must hold `2^event_bits` entries of `GITS_TYPER.ITT_entry_size` bytes.
+	pub unsafe fn map_device(&mut self, device_id: u32, itt: u64, event_bits: u8) {
+		self.command([
+			Self::CMD_MAPD | (device_id as u64) << 32,
+			(event_bits.max(1) - 1) as u64 & 0x1F,
+			1 << 63 | itt & 0xF_FFFF_FFFF_FF00,
+			0
+		]);
+	}
+
+	pub unsafe fn unmap_device(&mut self, device_id: u32) {
+		self.command([Self::CMD_MAPD | (device_id as u64) << 32, 0, 0, 0]);
+	}
+
+	/// Maps a collection to a redistributor, `target` is either its physical address
+	/// or processor number, see `physical_target`.
+	pub unsafe fn map_collection(&mut self, collection: u16, target: u64) {
+		let target = match self.physical_target() {
+			true  => target >> 16,
+			false => target
+		};
+		self.command([Self::CMD_MAPC, 0, 1 << 63 | (target & 0xF_FFFF_FFFF) << 16 | collection as u64, 0]);
+	}
+
+	/// Maps an event of a device to an LPI delivered to a collection.
+	pub unsafe fn map_event(&mut self, device_id: u32, event_id: u32, intid: u32, collection: u16) {
+		self.command([
+			Self::CMD_MAPTI | (device_id as u64) << 32,
+			event_id as u64 | (intid as u64) << 32,
+			collection as u64,
+			0
+		]);
+	}
+
+	/// Moves an event to another collection.
+	pub unsafe fn move_event(&mut self, device_id: u32, event_id: u32, collection: u16) {
+		self.command([Self::CMD_MOVI | (device_id as u64) << 32, event_id as u64, collection as u64, 0]);
+	}
+
+	/// Removes the mapping of an event and clears its pending state.
+	pub unsafe fn discard(&mut self, device_id: u32, event_id: u32) {
+		self.command([Self::CMD_DISCARD | (device_id as u64) << 32, event_id as u64, 0, 0]);
+	}
+
+	/// Makes the redistributor reload the configuration of an event's LPI.
+	pub unsafe fn invalidate(&mut self, device_id: u32, event_id: u32) {
+		self.command([Self::CMD_INV | (device_id as u64) << 32, event_id as u64, 0, 0]);
+	}
+
+	/// Waits until all previous commands targeting the redistributor have completed.
+	pub unsafe fn sync(&mut self, target: u64) {
+		let target = match self.physical_target() {
+			true  => target >> 16,
+			false => target
+		};
+		self.command([Self::CMD_SYNC, 0, (target & 0xF_FFFF_FFFF) << 16, 0]);
+		while self.reg(Self::CREADR).read_volatile() as usize & 0xF_FFE0 != self.write * 32 {
+			core::hint::spin_loop();
+		}
+	}
+}
+
+/// Sets the priority and enable bit of an LPI in the LPI configuration table referenced
+/// by `GICR_PROPBASER`, followed by `GicIts::invalidate` to take effect.
+///
+/// # Safety
+///
+/// `table` must point to the configuration table and hold an entry for `intid`.
+pub unsafe fn lpi_configure(table: *mut u8, intid: u32, priority: u8, enabled: bool) {
+	table.add((intid - GicIts::LPI_BASE) as usize).write_volatile(priority & 0xFC | 0x2 | enabled as u8);
+}
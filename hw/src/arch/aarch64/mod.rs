@@ -108,6 +108,8 @@ def_reg!(
 	HCR_EL2,
 	HPFAR_EL2,
 	HSTR_EL2,
+	ICC_EOIR1_EL1,
+	ICC_IAR1_EL1,
 	ID_AA64AFR0_EL1,
 	ID_AA64AFR1_EL1,
 	ID_AA64DFR0_EL1,
@@ -423,6 +425,21 @@ pub unsafe fn r#yield() {
 	llvm_asm!("yield"::::"volatile")
 }
 
+/// Whether the processor has FEAT_RNG, ID_AA64ISAR0_EL1.RNDR
+pub fn has_rndr() -> bool {
+	(ID_AA64ISAR0_EL1.read() >> 60) & 0xF >= 1
+}
+
+/// A random number from the RNDR register, None if it had none ready. Requires
+/// `has_rndr`.
+#[inline]
+pub fn rndr() -> Option<u64> {
+	let val: u64;
+	let ok: u64;
+	unsafe { llvm_asm!("mrs $0, s3_3_c2_c4_0\n cset $1, ne" : "=r"(val), "=r"(ok) :: "cc" : "volatile"); }
+	(ok != 0).then_some(val)
+}
+
 /// see ARMv8 ARM, chapter D1
 pub mod interrupts {
 	pub type ExceptionVector = [u64; 0x80];
@@ -274,12 +274,12 @@ impl RFLAGS {
 	pub const AC: u64 = 1 << 18;
 
 	pub fn set(&self, val: u64) {
-		unsafe { asm!("pushq {0}; popfq", in(reg) val) }
+		unsafe { asm!("push {0}; popfq", in(reg) val) }
 	}
 
 	pub fn get(&self) -> u64 {
 		let val;
-        unsafe { asm!("pushfq; popq {0}", out(reg) val); }
+        unsafe { asm!("pushfq; pop {0}", out(reg) val); }
 		val
 	}
 }
@@ -323,7 +323,7 @@ pub unsafe fn int3() {
 
 
 #[inline]
-pub fn sysret() -> ! {
+pub unsafe fn sysret() -> ! {
     asm!("sysret");
 	core::hint::unreachable_unchecked();
 }
@@ -361,11 +361,47 @@ pub unsafe fn lidt(idt: &DescriptorTablePointer) {
     asm!("lidt [{0}]", in(reg) idt);
 }
 
+#[inline]
+pub unsafe fn inb(port: u16) -> u8 {
+	let val;
+	asm!("in al, dx", out("al") val, in("dx") port);
+	val
+}
+
+#[inline]
+pub unsafe fn inw(port: u16) -> u16 {
+	let val;
+	asm!("in ax, dx", out("ax") val, in("dx") port);
+	val
+}
+
+#[inline]
+pub unsafe fn inl(port: u16) -> u32 {
+	let val;
+	asm!("in eax, dx", out("eax") val, in("dx") port);
+	val
+}
+
+#[inline]
+pub unsafe fn outb(port: u16, val: u8) {
+	asm!("out dx, al", in("dx") port, in("al") val);
+}
+
+#[inline]
+pub unsafe fn outw(port: u16, val: u16) {
+	asm!("out dx, ax", in("dx") port, in("ax") val);
+}
+
+#[inline]
+pub unsafe fn outl(port: u16, val: u32) {
+	asm!("out dx, eax", in("dx") port, in("eax") val);
+}
+
 #[inline]
 pub fn rdtsc32() -> (u32, u32) {
     let eax: u32;
     let edx: u32;
-	asm!("rdtsc", out("eax") eax, out("edx") edx);
+	unsafe { asm!("rdtsc", out("eax") eax, out("edx") edx); }
     (eax, edx)
 }
 
@@ -375,6 +411,41 @@ pub fn rdtsc64() -> u64 {
     eax as u64 | ((edx as u64) << 32)
 }
 
+/// Whether the processor has `rdrand`, CPUID.01H:ECX.RDRAND[bit 30]
+#[allow(unused_unsafe)]
+pub fn has_rdrand() -> bool {
+	unsafe { core::arch::x86_64::__cpuid(1).ecx & (1 << 30) != 0 }
+}
+
+/// Whether the processor has `rdseed`, CPUID.(EAX=07H, ECX=0H):EBX.RDSEED[bit 18]
+#[allow(unused_unsafe)]
+pub fn has_rdseed() -> bool {
+	unsafe {
+		core::arch::x86_64::__cpuid(0).eax >= 7
+			&& core::arch::x86_64::__cpuid_count(7, 0).ebx & (1 << 18) != 0
+	}
+}
+
+/// A random number from the DRBG of the processor, None if it had none ready.
+/// Requires `has_rdrand`.
+#[inline]
+pub fn rdrand() -> Option<u64> {
+	let val: u64;
+	let ok: u8;
+	unsafe { asm!("rdrand {0}", "setc {1}", out(reg) val, out(reg_byte) ok); }
+	(ok != 0).then_some(val)
+}
+
+/// A random number straight from the entropy source of the processor, None if it had
+/// none ready. Requires `has_rdseed`.
+#[inline]
+pub fn rdseed() -> Option<u64> {
+	let val: u64;
+	let ok: u8;
+	unsafe { asm!("rdseed {0}", "setc {1}", out(reg) val, out(reg_byte) ok); }
+	(ok != 0).then_some(val)
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 #[repr(align(16))]
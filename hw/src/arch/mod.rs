@@ -73,3 +73,20 @@ impl<T> WO<T> {
 		unsafe { (&mut self.0 as *mut T).write_volatile(val); }
 	}
 }
+
+pub trait PageTableEntry {
+	fn set_read(&mut self, v: bool);
+	fn get_read(&self) -> bool;
+	fn set_write(&mut self, v: bool);
+	fn get_write(&self) -> bool;
+	fn set_exec(&mut self, v: bool);
+	fn get_exec(&self) -> bool;
+	fn set_user(&mut self, v: bool);
+	fn get_user(&self) -> bool;
+	fn set_global(&mut self, v: bool);
+	fn get_global(&self) -> bool;
+	fn set_valid(&mut self, v: bool);
+	fn get_valid(&self) -> bool;
+	fn set_ppn(&mut self, v: usize);
+	fn get_ppn(&self) -> usize;
+}
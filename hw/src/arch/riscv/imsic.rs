@@ -0,0 +1,113 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Incoming Message-Signaled Interrupt Controller of the Advanced Interrupt Architecture.
+//!
+//! Each hart has a supervisor interrupt file, a device signals identity `n` by writing
+//! `n` to the `seteipnum_le` register at the start of the hart's file, see
+//! `MsiMessage::imsic`. The file itself is accessed indirectly via `siselect`/`sireg`.
+
+use super::{siselect, sireg, stopei};
+
+/// Size of an interrupt file in the IMSIC's memory region
+pub const INTERRUPT_FILE_SIZE: u64 = 0x1000;
+
+const EIDELIVERY:  u64 = 0x70;
+const EITHRESHOLD: u64 = 0x72;
+const EIP0:        u64 = 0x80;
+const EIE0:        u64 = 0xC0;
+
+/// The supervisor interrupt file of the current hart.
+pub struct Imsic {
+	/// Number of identities the file implements, at most 2047
+	identities: u32
+}
+
+impl Imsic {
+	/// Returns the address of a hart's interrupt file, as the device tree's
+	/// `riscv,imsics` node places the files of consecutive harts `stride` bytes apart.
+	pub fn interrupt_file(base: u64, stride: u64, hart_index: usize) -> u64 {
+		base + stride.max(INTERRUPT_FILE_SIZE) * hart_index as u64
+	}
+
+	/// Enables interrupt delivery of the file with all identities disabled.
+	///
+	/// # Safety
+	///
+	/// Must be called on the hart the file belongs to with `Ssaia` present.
+	pub unsafe fn init(identities: u32) -> Self {
+		let file = Self { identities: identities.min(2047) };
+		for reg in (0..=file.identities / 64).map(|i| i as u64 * 2) {
+			Self::write(EIE0 + reg, 0);
+			Self::write(EIP0 + reg, 0);
+		}
+		Self::write(EITHRESHOLD, 0);
+		Self::write(EIDELIVERY, 1);
+		file
+	}
+
+	fn read(reg: u64) -> u64 {
+		siselect.write(reg);
+		sireg.read()
+	}
+
+	fn write(reg: u64, val: u64) {
+		siselect.write(reg);
+		sireg.write(val);
+	}
+
+	/// On RV64 only the even `eip`/`eie` registers exist, each covering 64 identities.
+	fn locate(&self, identity: u32) -> (u64, u64) {
+		assert!(identity != 0 && identity <= self.identities, "invalid IMSIC identity {}", identity);
+		((identity / 64) as u64 * 2, 1 << (identity % 64))
+	}
+
+	pub fn enable(&mut self, identity: u32) {
+		let (reg, bit) = self.locate(identity);
+		Self::write(EIE0 + reg, Self::read(EIE0 + reg) | bit);
+	}
+
+	pub fn disable(&mut self, identity: u32) {
+		let (reg, bit) = self.locate(identity);
+		Self::write(EIE0 + reg, Self::read(EIE0 + reg) & !bit);
+	}
+
+	pub fn is_pending(&self, identity: u32) -> bool {
+		let (reg, bit) = self.locate(identity);
+		Self::read(EIP0 + reg) & bit != 0
+	}
+
+	/// Identities at or above `threshold` are not delivered, zero disables the threshold.
+	pub fn set_threshold(&mut self, threshold: u32) {
+		Self::write(EITHRESHOLD, threshold as u64);
+	}
+
+	/// Claims the highest priority pending and enabled identity, clearing its pending bit.
+	pub fn claim() -> Option<u32> {
+		let val = stopei.read();
+		stopei.write(0);
+		match (val >> 16) as u32 & 0x7FF {
+			0 => None,
+			id => Some(id)
+		}
+	}
+}
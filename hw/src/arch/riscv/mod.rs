@@ -25,6 +25,7 @@ pub use atp::*;
 pub mod atp;
 pub mod plic;
 pub mod clint;
+pub mod imsic;
 
 macro_rules! def_reg {
 	( $( #[$outer:meta] )* $reg:ident) => {
@@ -177,6 +178,12 @@ def_csr!(
 	stval,
 	/// Supervisor Address Translation and Protection Register
 	satp,
+	/// Supervisor Indirect Register Select (`Smaia`/`Ssaia` extension)
+	siselect,
+	/// Supervisor Indirect Register Alias (`Smaia`/`Ssaia` extension)
+	sireg,
+	/// Supervisor Top External Interrupt (`Smaia`/`Ssaia` extension)
+	stopei,
 	/// User Status Register (`N` User-level interrupts extension)
 	ustatus,
 	/// User Trap Vector Base Address Register (`N` User-level interrupts extension)
@@ -357,6 +364,36 @@ pub unsafe fn wfi() {
 	llvm_asm!("wfi"::::"volatile")
 }
 
+/// The `time` CSR, a read-only shadow of `mtime`
+#[inline]
+pub fn rdtime() -> u64 {
+	let val: u64;
+	unsafe { llvm_asm!("rdtime $0" : "=r"(val) ::: "volatile"); }
+	val
+}
+
+/// The entropy source of Zkr, the `seed` CSR, has to be read with a write.
+#[inline]
+pub unsafe fn seed() -> u64 {
+	let val: u64;
+	llvm_asm!("csrrw $0, 0x015, x0" : "=r"(val) ::: "volatile");
+	val
+}
+
+pub const SEED_OPST_MASK: u64 = 0b11 << 30;
+pub const SEED_OPST_BIST: u64 = 0b00 << 30;
+pub const SEED_OPST_WAIT: u64 = 0b01 << 30;
+pub const SEED_OPST_ES16: u64 = 0b10 << 30;
+pub const SEED_OPST_DEAD: u64 = 0b11 << 30;
+
+/// 16 bits of entropy from the `seed` CSR, None if it had none ready or the source
+/// failed. Traps if Zkr is missing or `mseccfg` doesn't allow access.
+#[inline]
+pub unsafe fn seed16() -> Option<u16> {
+	let val = seed();
+	(val & SEED_OPST_MASK == SEED_OPST_ES16).then_some(val as u16)
+}
+
 #[inline]
 pub unsafe fn sfence_vma(vaddr: usize, asid: usize) {
 	llvm_asm!("sfence.vma $0, $1" :: "r"(vaddr), "r"(asid));
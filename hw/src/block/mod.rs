@@ -0,0 +1,256 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Block devices.
+//!
+//! Storage drivers implement `BlockDevice`, partition tables, RAID arrays and file systems
+//! are generic over it and thus stack on any driver or on each other. `Queue` batches
+//! requests to a device, merging adjacent ones and ordering them with a `Scheduler`.
+
+mod queue;
+
+pub use queue::*;
+
+use {alloc::{boxed::Box, rc::Rc, vec::Vec}, core::cell::RefCell};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// The buffer is not a multiple of the block size or the range is outside of the device
+	InvalidArgument,
+	/// The device can't be written to
+	ReadOnly,
+	/// The device failed the operation
+	Io,
+	/// The device was removed
+	NoDevice,
+	/// No memory for buffers or device queues
+	NoMemory,
+	/// The device didn't complete the operation in time
+	Timeout,
+	/// The data read from the device doesn't match its checksum or is malformed
+	Corrupted,
+	/// The operation is not supported by the device or the on-disk format
+	Unsupported
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// A device addressed in fixed size blocks.
+///
+/// The length of all buffers must be a multiple of `block_size`, devices may require a
+/// stricter alignment of their buffers, which callers get by allocating buffers with
+/// `alloc_buffer`.
+pub trait BlockDevice {
+	/// Size of a block in bytes
+	fn block_size(&self) -> usize;
+
+	/// Size of the device in blocks
+	fn blocks(&self) -> u64;
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<()>;
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> Result<()>;
+
+	/// Commits the device's volatile write cache.
+	fn flush(&mut self) -> Result<()> {
+		Ok(())
+	}
+
+	/// Tells the device the blocks are unused, their contents are undefined afterwards.
+	fn discard(&mut self, lba: u64, blocks: u64) -> Result<()> {
+		check_blocks(self, lba, blocks)
+	}
+
+	fn is_read_only(&self) -> bool {
+		false
+	}
+
+	/// The most blocks the device transfers in one command, larger requests are split by
+	/// the driver. `Queue` doesn't merge requests beyond this.
+	fn max_blocks(&self) -> u64 {
+		u64::MAX
+	}
+
+	/// Reads consecutive blocks into several buffers.
+	fn read_vectored(&mut self, mut lba: u64, bufs: &mut [&mut [u8]]) -> Result<()> {
+		for buf in bufs {
+			self.read(lba, buf)?;
+			lba += (buf.len() / self.block_size()) as u64;
+		}
+		Ok(())
+	}
+
+	/// Writes several buffers to consecutive blocks.
+	fn write_vectored(&mut self, mut lba: u64, bufs: &[&[u8]]) -> Result<()> {
+		for buf in bufs {
+			self.write(lba, buf)?;
+			lba += (buf.len() / self.block_size()) as u64;
+		}
+		Ok(())
+	}
+}
+
+/// Checks that `len` bytes at `lba` are whole blocks inside of the device.
+pub fn check_range<D: BlockDevice + ?Sized>(dev: &D, lba: u64, len: usize) -> Result<()> {
+	let size = dev.block_size();
+	match size != 0 && len % size == 0 {
+		true  => check_blocks(dev, lba, (len / size) as u64),
+		false => Err(Error::InvalidArgument)
+	}
+}
+
+/// Checks that `blocks` blocks at `lba` are inside of the device.
+pub fn check_blocks<D: BlockDevice + ?Sized>(dev: &D, lba: u64, blocks: u64) -> Result<()> {
+	match lba.checked_add(blocks) {
+		Some(end) if end <= dev.blocks() => Ok(()),
+		_ => Err(Error::InvalidArgument)
+	}
+}
+
+/// Allocates a zeroed buffer of `blocks` blocks of the device.
+pub fn alloc_buffer<D: BlockDevice + ?Sized>(dev: &D, blocks: usize) -> Result<Vec<u8>> {
+	let len = blocks.checked_mul(dev.block_size()).ok_or(Error::InvalidArgument)?;
+	let mut buf = Vec::new();
+	buf.try_reserve_exact(len).map_err(|_| Error::NoMemory)?;
+	buf.resize(len, 0);
+	Ok(buf)
+}
+
+macro_rules! forward {
+	() => {
+		fn block_size(&self) -> usize { (**self).block_size() }
+		fn blocks(&self) -> u64 { (**self).blocks() }
+		fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> { (**self).read(lba, buf) }
+		fn write(&mut self, lba: u64, buf: &[u8]) -> Result<()> { (**self).write(lba, buf) }
+		fn flush(&mut self) -> Result<()> { (**self).flush() }
+		fn discard(&mut self, lba: u64, blocks: u64) -> Result<()> { (**self).discard(lba, blocks) }
+		fn is_read_only(&self) -> bool { (**self).is_read_only() }
+		fn max_blocks(&self) -> u64 { (**self).max_blocks() }
+		fn read_vectored(&mut self, lba: u64, bufs: &mut [&mut [u8]]) -> Result<()> { (**self).read_vectored(lba, bufs) }
+		fn write_vectored(&mut self, lba: u64, bufs: &[&[u8]]) -> Result<()> { (**self).write_vectored(lba, bufs) }
+	};
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for &mut T {
+	forward!();
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for Box<T> {
+	forward!();
+}
+
+/// Devices shared by several users on one hart, e.g. the partitions of a disk.
+impl<T: BlockDevice + ?Sized> BlockDevice for Rc<RefCell<T>> {
+	fn block_size(&self) -> usize { self.borrow().block_size() }
+	fn blocks(&self) -> u64 { self.borrow().blocks() }
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> { self.borrow_mut().read(lba, buf) }
+	fn write(&mut self, lba: u64, buf: &[u8]) -> Result<()> { self.borrow_mut().write(lba, buf) }
+	fn flush(&mut self) -> Result<()> { self.borrow_mut().flush() }
+	fn discard(&mut self, lba: u64, blocks: u64) -> Result<()> { self.borrow_mut().discard(lba, blocks) }
+	fn is_read_only(&self) -> bool { self.borrow().is_read_only() }
+	fn max_blocks(&self) -> u64 { self.borrow().max_blocks() }
+	fn read_vectored(&mut self, lba: u64, bufs: &mut [&mut [u8]]) -> Result<()> { self.borrow_mut().read_vectored(lba, bufs) }
+	fn write_vectored(&mut self, lba: u64, bufs: &[&[u8]]) -> Result<()> { self.borrow_mut().write_vectored(lba, bufs) }
+}
+
+/// A block device in memory, used for RAM disks and to test the layers above drivers.
+#[derive(Clone, Debug)]
+pub struct RamDisk {
+	block_size: usize,
+	data:       Vec<u8>,
+	read_only:  bool
+}
+
+impl RamDisk {
+	pub fn new(block_size: usize, blocks: usize) -> Self {
+		Self { block_size, data: alloc::vec![0; block_size * blocks], read_only: false }
+	}
+
+	/// Wraps a disk image, the image is truncated to whole blocks.
+	pub fn from_vec(block_size: usize, mut data: Vec<u8>) -> Self {
+		data.truncate(data.len() - data.len() % block_size);
+		Self { block_size, data, read_only: false }
+	}
+
+	pub fn set_read_only(&mut self, read_only: bool) {
+		self.read_only = read_only;
+	}
+
+	pub fn as_slice(&self) -> &[u8] {
+		&self.data
+	}
+
+	pub fn as_mut_slice(&mut self) -> &mut [u8] {
+		&mut self.data
+	}
+
+	pub fn into_vec(self) -> Vec<u8> {
+		self.data
+	}
+
+	fn range(&self, lba: u64, len: usize) -> Result<core::ops::Range<usize>> {
+		check_range(self, lba, len)?;
+		let start = lba as usize * self.block_size;
+		Ok(start..start + len)
+	}
+}
+
+impl BlockDevice for RamDisk {
+	fn block_size(&self) -> usize {
+		self.block_size
+	}
+
+	fn blocks(&self) -> u64 {
+		(self.data.len() / self.block_size) as u64
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> {
+		let range = self.range(lba, buf.len())?;
+		buf.copy_from_slice(&self.data[range]);
+		Ok(())
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+		if self.read_only {
+			return Err(Error::ReadOnly);
+		}
+		let range = self.range(lba, buf.len())?;
+		self.data[range].copy_from_slice(buf);
+		Ok(())
+	}
+
+	fn discard(&mut self, lba: u64, blocks: u64) -> Result<()> {
+		if self.read_only {
+			return Err(Error::ReadOnly);
+		}
+		let len = usize::try_from(blocks).ok()
+			.and_then(|b| b.checked_mul(self.block_size))
+			.ok_or(Error::InvalidArgument)?;
+		let range = self.range(lba, len)?;
+		self.data[range].fill(0);
+		Ok(())
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+}
@@ -0,0 +1,289 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The request queue of a block device.
+//!
+//! Requests are dispatched in batches: the scheduler picks one request and every queued
+//! request of the same kind that continues it on the device is merged into one vectored
+//! command. Flushes are barriers, nothing is moved across them, and requests that
+//! overlap an earlier write or discard keep their order.
+
+use {super::*, alloc::vec::Vec};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+	Read,
+	Write,
+	Flush,
+	Discard
+}
+
+impl Op {
+	fn modifies(self) -> bool {
+		matches!(self, Self::Write | Self::Discard)
+	}
+}
+
+#[derive(Debug)]
+pub struct Request {
+	pub id:        u64,
+	pub op:        Op,
+	pub lba:       u64,
+	pub blocks:    u64,
+	/// The data to write or the buffer the blocks are read into, empty otherwise
+	pub data:      Vec<u8>,
+	/// Identifies the submitter, e.g. the context the request is charged to
+	pub owner:     usize,
+	/// Submission time, in the unit of the `now` arguments
+	pub submitted: u64
+}
+
+impl Request {
+	fn end(&self) -> u64 {
+		self.lba + self.blocks
+	}
+
+	fn overlaps(&self, other: &Self) -> bool {
+		self.lba < other.end() && other.lba < self.end()
+	}
+}
+
+#[derive(Debug)]
+pub struct Completion {
+	pub request: Request,
+	pub result:  Result<()>,
+	/// Number of requests dispatched in the same device command
+	pub batch:   usize
+}
+
+/// Orders the requests of a queue.
+pub trait Scheduler {
+	/// Returns the index of the next request in `eligible`, which is never empty and in
+	/// submission order. `head` is the block following the last dispatched request.
+	fn pick(&mut self, eligible: &[&Request], head: u64, now: u64) -> usize;
+}
+
+impl<T: Scheduler + ?Sized> Scheduler for Box<T> {
+	fn pick(&mut self, eligible: &[&Request], head: u64, now: u64) -> usize {
+		(**self).pick(eligible, head, now)
+	}
+}
+
+/// Dispatches requests in submission order, for devices without seek penalty.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Noop;
+
+impl Scheduler for Noop {
+	fn pick(&mut self, _: &[&Request], _: u64, _: u64) -> usize {
+		0
+	}
+}
+
+/// Sweeps the device in ascending block order and wraps around (C-SCAN), but dispatches
+/// requests queued longer than their expiry time first, oldest deadline first.
+#[derive(Copy, Clone, Debug)]
+pub struct Deadline {
+	pub read_expire:  u64,
+	pub write_expire: u64
+}
+
+impl Deadline {
+	fn deadline(&self, request: &Request) -> u64 {
+		request.submitted.saturating_add(match request.op {
+			Op::Read => self.read_expire,
+			_        => self.write_expire
+		})
+	}
+}
+
+impl Scheduler for Deadline {
+	fn pick(&mut self, eligible: &[&Request], head: u64, now: u64) -> usize {
+		let expired = eligible.iter()
+			.enumerate()
+			.filter(|(_, r)| self.deadline(r) <= now)
+			.min_by_key(|(_, r)| self.deadline(r));
+
+		if let Some((i, _)) = expired {
+			return i;
+		}
+
+		eligible.iter()
+			.enumerate()
+			.min_by_key(|(_, r)| (r.lba < head, r.lba))
+			.map_or(0, |(i, _)| i)
+	}
+}
+
+pub struct Queue<S> {
+	scheduler: S,
+	pending:   Vec<Request>,
+	next_id:   u64,
+	head:      u64
+}
+
+impl<S: Scheduler> Queue<S> {
+	pub fn new(scheduler: S) -> Self {
+		Self { scheduler, pending: Vec::new(), next_id: 1, head: 0 }
+	}
+
+	pub fn scheduler(&mut self) -> &mut S {
+		&mut self.scheduler
+	}
+
+	pub fn len(&self) -> usize {
+		self.pending.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.pending.is_empty()
+	}
+
+	/// Queues a read of `blocks` blocks, returns the request id.
+	pub fn read<D: BlockDevice + ?Sized>(&mut self, dev: &D, lba: u64, blocks: u64, owner: usize, now: u64) -> Result<u64> {
+		check_blocks(dev, lba, blocks)?;
+		let data = alloc_buffer(dev, usize::try_from(blocks).map_err(|_| Error::InvalidArgument)?)?;
+		Ok(self.push(Op::Read, lba, blocks, data, owner, now))
+	}
+
+	pub fn write<D: BlockDevice + ?Sized>(&mut self, dev: &D, lba: u64, data: Vec<u8>, owner: usize, now: u64) -> Result<u64> {
+		if dev.is_read_only() {
+			return Err(Error::ReadOnly);
+		}
+		check_range(dev, lba, data.len())?;
+		let blocks = (data.len() / dev.block_size()) as u64;
+		Ok(self.push(Op::Write, lba, blocks, data, owner, now))
+	}
+
+	pub fn discard<D: BlockDevice + ?Sized>(&mut self, dev: &D, lba: u64, blocks: u64, owner: usize, now: u64) -> Result<u64> {
+		if dev.is_read_only() {
+			return Err(Error::ReadOnly);
+		}
+		check_blocks(dev, lba, blocks)?;
+		Ok(self.push(Op::Discard, lba, blocks, Vec::new(), owner, now))
+	}
+
+	/// Queues a flush, it completes after all requests queued before it.
+	pub fn flush(&mut self, owner: usize, now: u64) -> u64 {
+		self.push(Op::Flush, 0, 0, Vec::new(), owner, now)
+	}
+
+	fn push(&mut self, op: Op, lba: u64, blocks: u64, data: Vec<u8>, owner: usize, now: u64) -> u64 {
+		let id = self.next_id;
+		self.next_id += 1;
+		self.pending.push(Request { id, op, lba, blocks, data, owner, submitted: now });
+		id
+	}
+
+	/// Issues the next device command, returns the requests it completed.
+	pub fn dispatch<D: BlockDevice + ?Sized>(&mut self, dev: &mut D, now: u64) -> Vec<Completion> {
+		if self.pending.is_empty() {
+			return Vec::new();
+		}
+
+		if self.pending[0].op == Op::Flush {
+			let request = self.pending.remove(0);
+			return alloc::vec![Completion { request, result: dev.flush(), batch: 1 }];
+		}
+
+		let eligible = self.eligible();
+		let refs = eligible.iter().map(|&i| &self.pending[i]).collect::<Vec<_>>();
+		let first = eligible[self.scheduler.pick(&refs, self.head, now).min(eligible.len() - 1)];
+
+		// extend the picked request in both directions with eligible requests of the same kind
+		let (op, max) = (self.pending[first].op, dev.max_blocks());
+		let (mut start, mut end) = (self.pending[first].lba, self.pending[first].end());
+		let mut batch = alloc::vec![first];
+		loop {
+			let next = eligible.iter().copied().find(|&i| {
+				let r = &self.pending[i];
+				!batch.contains(&i) && r.op == op && end - start + r.blocks <= max
+					&& (r.lba == end || r.end() == start)
+			});
+			match next {
+				Some(i) => {
+					let r = &self.pending[i];
+					match r.lba == end {
+						true  => end = r.end(),
+						false => start = r.lba
+					}
+					batch.push(i);
+				}
+				None => break
+			}
+		}
+
+		// take the batch out of the queue, in block order
+		batch.sort_unstable_by(|a, b| b.cmp(a));
+		let mut requests = batch.into_iter().map(|i| self.pending.remove(i)).collect::<Vec<_>>();
+		requests.sort_unstable_by_key(|r| r.lba);
+
+		let result = match op {
+			Op::Read => dev.read_vectored(start, &mut requests.iter_mut()
+				.map(|r| r.data.as_mut_slice())
+				.collect::<Vec<_>>()),
+			Op::Write => dev.write_vectored(start, &requests.iter()
+				.map(|r| r.data.as_slice())
+				.collect::<Vec<_>>()),
+			Op::Discard => dev.discard(start, end - start),
+			Op::Flush => unreachable!()
+		};
+		self.head = end;
+
+		let batch = requests.len();
+		requests.into_iter()
+			.map(|request| Completion { request, result, batch })
+			.collect()
+	}
+
+	/// Dispatches requests until the queue is empty.
+	pub fn run<D: BlockDevice + ?Sized>(&mut self, dev: &mut D, now: u64) -> Vec<Completion> {
+		let mut completions = Vec::new();
+		while !self.pending.is_empty() {
+			completions.extend(self.dispatch(dev, now));
+		}
+		completions
+	}
+
+	/// Indices of the requests that may be dispatched next: those before the first flush
+	/// that don't overlap an earlier request where either of both modifies the blocks.
+	fn eligible(&self) -> Vec<usize> {
+		let barrier = self.pending.iter()
+			.position(|r| r.op == Op::Flush)
+			.unwrap_or(self.pending.len());
+		let pending = &self.pending[..barrier];
+
+		(0..barrier)
+			.filter(|&i| pending[..i].iter()
+				.all(|r| !(r.op.modifies() || pending[i].op.modifies()) || !r.overlaps(&pending[i])))
+			.collect()
+	}
+}
+
+impl<S: core::fmt::Debug> core::fmt::Debug for Queue<S> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Queue")
+			.field("scheduler", &self.scheduler)
+			.field("pending", &self.pending.len())
+			.field("head", &self.head)
+			.finish()
+	}
+}
@@ -0,0 +1,119 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Mapping of logical addresses to device offsets.
+
+use {super::*, alloc::{collections::BTreeMap, vec::Vec}};
+
+/// A chunk, i.e. a range of logical addresses and the device ranges that store it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChunkMapping {
+	pub logical:    u64,
+	pub length:     u64,
+	/// `BLOCK_GROUP_*` type and profile
+	pub kind:       u64,
+	pub stripe_len: u64,
+	/// Device id and offset of each stripe
+	pub stripes:    Vec<(u64, u64)>
+}
+
+impl ChunkMapping {
+	/// Whether every stripe holds a full copy of the chunk, which is true for the SINGLE,
+	/// DUP and RAID1 profiles.
+	pub fn is_mirrored(&self) -> bool {
+		self.kind & BLOCK_GROUP_PROFILE_MASK & !(BLOCK_GROUP_DUP | BLOCK_GROUP_RAID1
+			| BLOCK_GROUP_RAID1C3 | BLOCK_GROUP_RAID1C4) == 0
+	}
+}
+
+/// The chunks of a file system, by logical address.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkMap {
+	chunks: BTreeMap<u64, ChunkMapping>
+}
+
+impl ChunkMap {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Reads the system chunks from the superblock's `sys_chunk_array`.
+	pub fn from_sys_chunk_array(sb: &Superblock) -> Result<Self> {
+		let array = sb.sys_chunk_array;
+		let array = array.get(..sb.sys_chunk_array_size as usize).ok_or(Error::Corrupted)?;
+		let mut map = Self::new();
+		let mut off = 0;
+		while off < array.len() {
+			let key = Key::from(read_struct::<DiskKey>(array, off)?);
+			if key.kind != CHUNK_ITEM_KEY {
+				return Err(Error::Corrupted);
+			}
+			off += core::mem::size_of::<DiskKey>();
+			off += map.insert(key.offset, &array[off..])?;
+		}
+		Ok(map)
+	}
+
+	/// Adds the chunk item at the start of `data`, returns the item's size.
+	pub fn insert(&mut self, logical: u64, data: &[u8]) -> Result<usize> {
+		let chunk = read_struct::<Chunk>(data, 0)?;
+		let size = core::mem::size_of::<Chunk>() + chunk.num_stripes as usize * core::mem::size_of::<Stripe>();
+		if chunk.num_stripes == 0 || chunk.length == 0 || size > data.len() {
+			return Err(Error::Corrupted);
+		}
+
+		let stripes = (0..chunk.num_stripes as usize)
+			.map(|i| read_struct::<Stripe>(data, core::mem::size_of::<Chunk>() + i * core::mem::size_of::<Stripe>()))
+			.map(|s| s.map(|s| (s.devid, s.offset)))
+			.collect::<Result<Vec<_>>>()?;
+		self.chunks.insert(logical, ChunkMapping {
+			logical,
+			length:     chunk.length,
+			kind:       chunk.kind,
+			stripe_len: chunk.stripe_len,
+			stripes
+		});
+		Ok(size)
+	}
+
+	/// The chunk containing the logical address.
+	pub fn get(&self, logical: u64) -> Option<&ChunkMapping> {
+		self.chunks.range(..=logical).next_back()
+			.map(|(_, chunk)| chunk)
+			.filter(|chunk| logical - chunk.logical < chunk.length)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &ChunkMapping> {
+		self.chunks.values()
+	}
+
+	/// Device offsets of all copies of `len` bytes at the logical address, which must be
+	/// within one chunk. Striped profiles aren't supported.
+	pub fn map(&self, logical: u64, len: u64) -> Result<Vec<u64>> {
+		let chunk = self.get(logical).ok_or(Error::Corrupted)?;
+		if logical - chunk.logical + len > chunk.length {
+			return Err(Error::Corrupted);
+		} else if !chunk.is_mirrored() {
+			return Err(Error::Unsupported);
+		}
+		Ok(chunk.stripes.iter().map(|(_, offset)| offset + logical - chunk.logical).collect())
+	}
+}
@@ -0,0 +1,442 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Allocation of extents and their back references in the extent tree, the free space
+//! tree and the checksums of data extents.
+//!
+//! Allocating or freeing a tree block happens while a path is being copied, when the
+//! extent and free space trees can't be modified. These changes are queued as `Pending`
+//! and applied at the end of each operation, which may copy more nodes and queue more
+//! changes until none are left. Space freed in a transaction is pinned until the commit,
+//! as the last committed trees may still refer to it.
+
+use {
+	super::*,
+	crate::block::BlockDevice,
+	alloc::{collections::BTreeMap, vec::Vec}
+};
+
+const EXTENT_ITEM_SIZE: usize = core::mem::size_of::<ExtentItem>();
+const DATA_REF_SIZE: usize = core::mem::size_of::<ExtentDataRef>();
+
+/// A block group, i.e. the allocation state of a chunk.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) struct BlockGroup {
+	pub start:  u64,
+	pub length: u64,
+	pub flags:  u64
+}
+
+/// A change to the extent and free space trees that hasn't been applied yet.
+#[derive(Copy, Clone, Debug)]
+pub(super) enum Pending {
+	AddTreeBlock { bytenr: u64, level: u8, owner: u64 },
+	FreeTreeBlock { bytenr: u64, level: u8 },
+	/// A new data extent with one reference
+	AddData { bytenr: u64, len: u64, root: u64, inode: u64, offset: u64 },
+	/// Adds or removes a reference of a file extent item to a data extent, the extent is
+	/// freed with its last reference
+	DataRef { bytenr: u64, len: u64, root: u64, inode: u64, offset: u64, add: bool }
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+	/// Fails if a tree block is shared, e.g. with a snapshot, as copying it would have to
+	/// add references to its children.
+	pub(super) fn check_exclusive(&mut self, bytenr: u64, level: u8) -> Result<()> {
+		let item = self.search(EXTENT_TREE_OBJECTID, Key::new(bytenr, METADATA_ITEM_KEY, level as u64))?
+			.ok_or(Error::Corrupted)?;
+		let extent = read_struct::<ExtentItem>(&item, 0)?;
+		match extent.refs == 1 && extent.flags & BLOCK_FLAG_FULL_BACKREF == 0 {
+			true  => Ok(()),
+			false => Err(Error::Unsupported)
+		}
+	}
+
+	/// Allocates a block for a node of the tree.
+	pub(super) fn alloc_tree_block(&mut self, tree: u64, level: u8) -> Result<u64> {
+		let kind = match tree {
+			CHUNK_TREE_OBJECTID => BLOCK_GROUP_SYSTEM,
+			_                   => BLOCK_GROUP_METADATA
+		};
+		let size = self.nodesize as u64;
+		let (bytenr, _) = self.find_free(kind, size, size, size)?;
+		self.reserved.insert(bytenr, size);
+		self.pending.push_back(Pending::AddTreeBlock { bytenr, level, owner: tree });
+		Ok(bytenr)
+	}
+
+	pub(super) fn free_tree_block(&mut self, bytenr: u64, level: u8) {
+		self.dirty.remove(&bytenr);
+		self.pinned.insert(bytenr, self.nodesize as u64);
+		self.pending.push_back(Pending::FreeTreeBlock { bytenr, level });
+	}
+
+	/// Allocates a data extent of at least one sector and at most `len` bytes and
+	/// references it from the file extent item at `(inode, offset)`.
+	pub(super) fn alloc_data(&mut self, len: u64, inode: u64, offset: u64) -> Result<(u64, u64)> {
+		let (bytenr, len) = self.find_free(BLOCK_GROUP_DATA, self.sectorsize, len, self.sectorsize)?;
+		self.reserved.insert(bytenr, len);
+		self.pending.push_back(Pending::AddData { bytenr, len, root: FS_TREE_OBJECTID, inode, offset });
+		Ok((bytenr, len))
+	}
+
+	/// Finds a free range of `min..=max` bytes in a block group of the type, which isn't
+	/// reserved or pinned in the current transaction.
+	fn find_free(&mut self, kind: u64, min: u64, max: u64, align: u64) -> Result<(u64, u64)> {
+		let groups = self.block_groups.iter().filter(|bg| bg.flags & kind != 0).copied().collect::<Vec<_>>();
+		let mut best = None;
+		for bg in groups {
+			let items = self.range(
+				FREE_SPACE_TREE_OBJECTID,
+				Key::new(bg.start, 0, 0),
+				Key::new(bg.start + bg.length - 1, u8::MAX, u64::MAX)
+			)?;
+			for (key, data) in items {
+				match key.kind {
+					FREE_SPACE_INFO_KEY if read_struct::<FreeSpaceInfo>(&data, 0)?.flags & FREE_SPACE_USING_BITMAPS != 0 =>
+						return Err(Error::Unsupported),
+					FREE_SPACE_EXTENT_KEY => (),
+					FREE_SPACE_BITMAP_KEY => return Err(Error::Unsupported),
+					_                     => continue
+				}
+
+				let end = key.objectid + key.offset;
+				let mut start = align_up(key.objectid, align);
+				while start + min <= end {
+					let limit = end.min(start + max);
+					match self.busy(start, limit - start) {
+						Some(busy_end) => start = align_up(busy_end, align),
+						None => {
+							let len = (limit - start) / align * align;
+							if len == max {
+								return Ok((start, len));
+							} else if best.map_or(true, |(_, l)| len > l) {
+								best = Some((start, len));
+							}
+							break;
+						}
+					}
+				}
+			}
+		}
+		best.ok_or(Error::NoSpace)
+	}
+
+	/// If part of the range is reserved or pinned, the end of the first such part.
+	fn busy(&self, start: u64, len: u64) -> Option<u64> {
+		[&self.reserved, &self.pinned].iter()
+			.filter_map(|map| overlap(map, start, len))
+			.min()
+	}
+
+	/// Applies the pending changes to the extent and free space trees.
+	pub(super) fn flush_pending(&mut self) -> Result<()> {
+		while let Some(op) = self.pending.pop_front() {
+			match op {
+				Pending::AddTreeBlock { bytenr, level, owner } => {
+					let mut item = struct_bytes(&ExtentItem {
+						refs:       1,
+						generation: self.transid,
+						flags:      EXTENT_FLAG_TREE_BLOCK
+					}).to_vec();
+					item.push(TREE_BLOCK_REF_KEY);
+					item.extend_from_slice(&owner.to_le_bytes());
+					self.insert(EXTENT_TREE_OBJECTID, Key::new(bytenr, METADATA_ITEM_KEY, level as u64), &item)?;
+					self.allocated(bytenr, self.nodesize as u64)?;
+				}
+				Pending::FreeTreeBlock { bytenr, level } => {
+					self.delete(EXTENT_TREE_OBJECTID, Key::new(bytenr, METADATA_ITEM_KEY, level as u64))?;
+					self.freed(bytenr, self.nodesize as u64)?;
+				}
+				Pending::AddData { bytenr, len, root, inode, offset } => {
+					let mut item = struct_bytes(&ExtentItem {
+						refs:       1,
+						generation: self.transid,
+						flags:      EXTENT_FLAG_DATA
+					}).to_vec();
+					item.push(EXTENT_DATA_REF_KEY);
+					item.extend_from_slice(struct_bytes(&ExtentDataRef { root, objectid: inode, offset, count: 1 }));
+					self.insert(EXTENT_TREE_OBJECTID, Key::new(bytenr, EXTENT_ITEM_KEY, len), &item)?;
+					self.allocated(bytenr, len)?;
+				}
+				Pending::DataRef { bytenr, len, root, inode, offset, add } =>
+					self.update_data_ref(bytenr, len, root, inode, offset, add)?
+			}
+		}
+		Ok(())
+	}
+
+	fn update_data_ref(&mut self, bytenr: u64, len: u64, root: u64, inode: u64, offset: u64, add: bool) -> Result<()> {
+		let key = Key::new(bytenr, EXTENT_ITEM_KEY, len);
+		let mut item = self.search(EXTENT_TREE_OBJECTID, key)?.ok_or(Error::Corrupted)?;
+		let mut extent = read_struct::<ExtentItem>(&item, 0)?;
+		let hash = extent_data_ref_hash(root, inode, offset);
+
+		// find the inline reference, or where to insert it
+		let mut pos = EXTENT_ITEM_SIZE;
+		let mut insert_at = None;
+		let mut found = None;
+		while pos < item.len() {
+			let size = match item[pos] {
+				TREE_BLOCK_REF_KEY | SHARED_BLOCK_REF_KEY => 9,
+				EXTENT_DATA_REF_KEY                       => 1 + DATA_REF_SIZE,
+				SHARED_DATA_REF_KEY                       => 13,
+				_                                         => return Err(Error::Corrupted)
+			};
+			if item[pos] == EXTENT_DATA_REF_KEY {
+				let r = read_struct::<ExtentDataRef>(&item, pos + 1)?;
+				if r.root == root && r.objectid == inode && r.offset == offset {
+					found = Some(pos);
+					break;
+				} else if insert_at.is_none() && extent_data_ref_hash(r.root, r.objectid, r.offset) < hash {
+					insert_at = Some(pos);
+				}
+			} else if item[pos] > EXTENT_DATA_REF_KEY && insert_at.is_none() {
+				insert_at = Some(pos);
+			}
+			pos += size;
+		}
+
+		match (found, add) {
+			(Some(pos), _) => {
+				let mut r = read_struct::<ExtentDataRef>(&item, pos + 1)?;
+				r.count = match add {
+					true  => r.count + 1,
+					false => r.count.checked_sub(1).ok_or(Error::Corrupted)?
+				};
+				match r.count {
+					0 => drop(item.drain(pos..pos + 1 + DATA_REF_SIZE)),
+					_ => item[pos + 1..pos + 1 + DATA_REF_SIZE].copy_from_slice(struct_bytes(&r))
+				}
+			}
+			(None, true) => {
+				let pos = insert_at.unwrap_or(item.len());
+				let r = ExtentDataRef { root, objectid: inode, offset, count: 1 };
+				let bytes = [&[EXTENT_DATA_REF_KEY][..], struct_bytes(&r)].concat();
+				item.splice(pos..pos, bytes);
+			}
+			(None, false) => {
+				// a reference in its own item
+				let refs = self.range(
+					EXTENT_TREE_OBJECTID,
+					Key::new(bytenr, EXTENT_DATA_REF_KEY, 0),
+					Key::new(bytenr, EXTENT_DATA_REF_KEY, u64::MAX)
+				)?;
+				let (ref_key, data) = refs.into_iter()
+					.find(|(_, data)| read_struct::<ExtentDataRef>(data, 0)
+						.map_or(false, |r| r.root == root && r.objectid == inode && r.offset == offset))
+					.ok_or(Error::Corrupted)?;
+				let mut r = read_struct::<ExtentDataRef>(&data, 0)?;
+				r.count -= 1;
+				match r.count {
+					0 => self.delete(EXTENT_TREE_OBJECTID, ref_key)?,
+					_ => self.update(EXTENT_TREE_OBJECTID, ref_key, struct_bytes(&r))?
+				}
+			}
+		}
+
+		extent.refs = match add {
+			true  => extent.refs + 1,
+			false => extent.refs.checked_sub(1).ok_or(Error::Corrupted)?
+		};
+		if extent.refs == 0 {
+			self.delete(EXTENT_TREE_OBJECTID, key)?;
+			self.delete_csums(bytenr, len)?;
+			self.pinned.insert(bytenr, len);
+			return self.freed(bytenr, len);
+		}
+		item[..EXTENT_ITEM_SIZE].copy_from_slice(struct_bytes(&extent));
+		self.update(EXTENT_TREE_OBJECTID, key, &item)
+	}
+
+	/// Accounts for an allocated extent and removes it from the free space tree.
+	fn allocated(&mut self, bytenr: u64, len: u64) -> Result<()> {
+		self.account(bytenr, len, true)?;
+		let (key, _) = self.prev(FREE_SPACE_TREE_OBJECTID, Key::new(bytenr, FREE_SPACE_EXTENT_KEY, u64::MAX))?
+			.ok_or(Error::Corrupted)?;
+		if key.kind != FREE_SPACE_EXTENT_KEY || key.objectid + key.offset < bytenr + len {
+			return Err(Error::Corrupted);
+		}
+
+		self.delete(FREE_SPACE_TREE_OBJECTID, key)?;
+		let mut extents = -1;
+		if key.objectid < bytenr {
+			self.insert(FREE_SPACE_TREE_OBJECTID, Key::new(key.objectid, FREE_SPACE_EXTENT_KEY, bytenr - key.objectid), &[])?;
+			extents += 1;
+		}
+		if bytenr + len < key.objectid + key.offset {
+			let end = key.objectid + key.offset;
+			self.insert(FREE_SPACE_TREE_OBJECTID, Key::new(bytenr + len, FREE_SPACE_EXTENT_KEY, end - bytenr - len), &[])?;
+			extents += 1;
+		}
+		self.count_free_extents(bytenr, extents)
+	}
+
+	/// Accounts for a freed extent and adds it to the free space tree, merged with its
+	/// neighbours.
+	fn freed(&mut self, bytenr: u64, len: u64) -> Result<()> {
+		self.account(bytenr, len, false)?;
+		let bg = self.block_group(bytenr)?;
+		let (mut start, mut end) = (bytenr, bytenr + len);
+		let mut extents = 1;
+
+		if let Some((key, _)) = self.prev(FREE_SPACE_TREE_OBJECTID, Key::new(bytenr, FREE_SPACE_EXTENT_KEY, 0))? {
+			if key.kind == FREE_SPACE_EXTENT_KEY && key.objectid >= bg.start && key.objectid + key.offset >= bytenr {
+				if key.objectid + key.offset > bytenr {
+					return Err(Error::Corrupted);
+				}
+				self.delete(FREE_SPACE_TREE_OBJECTID, key)?;
+				start = key.objectid;
+				extents -= 1;
+			}
+		}
+		if end < bg.start + bg.length {
+			if let Some((key, _)) = self.prev(FREE_SPACE_TREE_OBJECTID, Key::new(end, FREE_SPACE_EXTENT_KEY, u64::MAX))? {
+				if key.kind == FREE_SPACE_EXTENT_KEY && key.objectid == end {
+					self.delete(FREE_SPACE_TREE_OBJECTID, key)?;
+					end += key.offset;
+					extents -= 1;
+				}
+			}
+		}
+
+		self.insert(FREE_SPACE_TREE_OBJECTID, Key::new(start, FREE_SPACE_EXTENT_KEY, end - start), &[])?;
+		self.count_free_extents(bytenr, extents)
+	}
+
+	fn count_free_extents(&mut self, bytenr: u64, delta: i32) -> Result<()> {
+		if delta == 0 {
+			return Ok(());
+		}
+		let bg = self.block_group(bytenr)?;
+		let key = Key::new(bg.start, FREE_SPACE_INFO_KEY, bg.length);
+		let data = self.search(FREE_SPACE_TREE_OBJECTID, key)?.ok_or(Error::Corrupted)?;
+		let mut info = read_struct::<FreeSpaceInfo>(&data, 0)?;
+		info.extent_count = (info.extent_count as i64 + delta as i64) as u32;
+		self.update(FREE_SPACE_TREE_OBJECTID, key, struct_bytes(&info))
+	}
+
+	/// Updates the used bytes of the block group and the superblock.
+	fn account(&mut self, bytenr: u64, len: u64, alloc: bool) -> Result<()> {
+		let bg = self.block_group(bytenr)?;
+		let key = Key::new(bg.start, BLOCK_GROUP_ITEM_KEY, bg.length);
+		let data = self.search(self.block_group_tree, key)?.ok_or(Error::Corrupted)?;
+		let mut item = read_struct::<BlockGroupItem>(&data, 0)?;
+		let used = match alloc {
+			true  => Some(item.used + len).filter(|used| *used <= bg.length),
+			false => item.used.checked_sub(len)
+		};
+		item.used = used.ok_or(Error::Corrupted)?;
+		self.sb.bytes_used = match alloc {
+			true  => self.sb.bytes_used + len,
+			false => self.sb.bytes_used - len
+		};
+		self.update(self.block_group_tree, key, struct_bytes(&item))
+	}
+
+	fn block_group(&self, bytenr: u64) -> Result<BlockGroup> {
+		self.block_groups.iter()
+			.find(|bg| bytenr >= bg.start && bytenr - bg.start < bg.length)
+			.copied()
+			.ok_or(Error::Corrupted)
+	}
+
+	/// The checksums of the sectors of a data range, `None` for sectors without one.
+	pub(super) fn data_csums(&mut self, start: u64, len: u64) -> Result<Vec<Option<Vec<u8>>>> {
+		let mut csums = alloc::vec![None; (len / self.sectorsize) as usize];
+		for (key, data) in self.csum_items(start, len)? {
+			for (i, csum) in data.chunks_exact(self.csum_size).enumerate() {
+				let sector = key.offset + i as u64 * self.sectorsize;
+				if sector >= start && sector < start + len {
+					csums[((sector - start) / self.sectorsize) as usize] = Some(csum.to_vec());
+				}
+			}
+		}
+		Ok(csums)
+	}
+
+	/// Adds the checksums of data written to a new extent.
+	pub(super) fn add_csums(&mut self, start: u64, data: &[u8]) -> Result<()> {
+		// at most half a leaf per item
+		let per_item = ((self.nodesize - core::mem::size_of::<Header>()) / 2 - core::mem::size_of::<Item>())
+			/ self.csum_size;
+		for (i, chunk) in data.chunks(per_item * self.sectorsize as usize).enumerate() {
+			let item = chunk.chunks(self.sectorsize as usize)
+				.flat_map(|sector| checksum(self.csum_type, sector)[..self.csum_size].to_vec())
+				.collect::<Vec<_>>();
+			let offset = start + (i * per_item) as u64 * self.sectorsize;
+			self.insert(CSUM_TREE_OBJECTID, Key::new(EXTENT_CSUM_OBJECTID, EXTENT_CSUM_KEY, offset), &item)?;
+		}
+		Ok(())
+	}
+
+	/// Removes the checksums of a freed data range, items are split if it covers only
+	/// part of them.
+	fn delete_csums(&mut self, start: u64, len: u64) -> Result<()> {
+		let end = start + len;
+		for (key, data) in self.csum_items(start, len)? {
+			self.delete(CSUM_TREE_OBJECTID, key)?;
+			let item_end = key.offset + (data.len() / self.csum_size) as u64 * self.sectorsize;
+			if key.offset < start {
+				let keep = ((start - key.offset) / self.sectorsize) as usize * self.csum_size;
+				self.insert(CSUM_TREE_OBJECTID, key, &data[..keep])?;
+			}
+			if item_end > end {
+				let skip = ((end - key.offset) / self.sectorsize) as usize * self.csum_size;
+				self.insert(CSUM_TREE_OBJECTID, Key::new(EXTENT_CSUM_OBJECTID, EXTENT_CSUM_KEY, end), &data[skip..])?;
+			}
+		}
+		Ok(())
+	}
+
+	/// The checksum items overlapping a data range.
+	fn csum_items(&mut self, start: u64, len: u64) -> Result<Vec<(Key, Vec<u8>)>> {
+		let mut items = Vec::new();
+		if let Some((key, data)) = self.prev(CSUM_TREE_OBJECTID, Key::new(EXTENT_CSUM_OBJECTID, EXTENT_CSUM_KEY, start))? {
+			let item_end = key.offset + (data.len() / self.csum_size) as u64 * self.sectorsize;
+			if key.objectid == EXTENT_CSUM_OBJECTID && key.kind == EXTENT_CSUM_KEY && item_end > start {
+				items.push((key, data));
+			}
+		}
+		if len > 1 {
+			items.extend(self.range(
+				CSUM_TREE_OBJECTID,
+				Key::new(EXTENT_CSUM_OBJECTID, EXTENT_CSUM_KEY, start + 1),
+				Key::new(EXTENT_CSUM_OBJECTID, EXTENT_CSUM_KEY, start + len - 1)
+			)?);
+		}
+		Ok(items)
+	}
+}
+
+/// If a range of the map overlaps `start..start + len`, the end of the first one.
+fn overlap(map: &BTreeMap<u64, u64>, start: u64, len: u64) -> Option<u64> {
+	map.range(..start + len)
+		.rev()
+		.take_while(|(s, l)| **s + **l > start || **s >= start)
+		.filter(|(s, l)| **s + **l > start)
+		.map(|(s, l)| s + l)
+		.min()
+}
+
+pub(super) fn align_up(value: u64, align: u64) -> u64 {
+	(value + align - 1) / align * align
+}
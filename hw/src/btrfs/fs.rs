@@ -0,0 +1,1060 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use {
+	super::{*, extent::{align_up, BlockGroup, Pending}, tree::{verify, Root}},
+	crate::block::{self, BlockDevice},
+	alloc::{collections::{BTreeMap, VecDeque}, string::String, vec, vec::Vec}
+};
+
+/// Largest file size, the kernel limits offsets to `i64`
+pub const MAX_FILE_SIZE: u64 = i64::MAX as u64;
+const SUPER_FLAG_SEEDING: u64 = 1 << 32;
+const FILE_EXTENT_SIZE: usize = core::mem::size_of::<FileExtentItem>();
+const DIR_ITEM_SIZE: usize = core::mem::size_of::<DirItem>();
+const INODE_REF_SIZE: usize = core::mem::size_of::<InodeRef>();
+
+/// An entry of a directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+	pub name:  String,
+	pub inode: u64,
+	/// `FT_*`
+	pub kind:  u8,
+	pub size:  u64,
+	pub mode:  u32,
+	pub nlink: u32,
+	pub uid:   u32,
+	pub gid:   u32,
+	/// `INODE_*` flags
+	pub flags: u64,
+	pub ctime: Timespec,
+	pub mtime: Timespec,
+	pub otime: Timespec
+}
+
+impl Entry {
+	pub fn is_dir(&self) -> bool {
+		self.kind == FT_DIR
+	}
+
+	fn new(name: &[u8], inode: u64, item: &InodeItem) -> Self {
+		Self {
+			name:  String::from_utf8_lossy(name).into(),
+			inode,
+			kind:  file_type(item.mode),
+			size:  item.size,
+			mode:  item.mode,
+			nlink: item.nlink,
+			uid:   item.uid,
+			gid:   item.gid,
+			flags: item.flags,
+			ctime: item.ctime,
+			mtime: item.mtime,
+			otime: item.otime
+		}
+	}
+}
+
+/// A Btrfs file system on a device.
+///
+/// Changes are made in a transaction, which `sync` commits. Data is written to new
+/// extents immediately, changed tree nodes are kept in memory until the commit and the
+/// changes are lost if the file system is dropped without it. After a failed write the
+/// transaction may be incomplete and shouldn't be committed.
+pub struct FileSystem<D> {
+	pub(super) dev:              D,
+	pub(super) sb:               Superblock,
+	pub(super) chunks:           ChunkMap,
+	pub(super) csum_type:        u16,
+	pub(super) csum_size:        usize,
+	pub(super) nodesize:         usize,
+	pub(super) sectorsize:       u64,
+	/// The fsid in the headers of tree nodes
+	pub(super) metadata_fsid:    [u8; 16],
+	/// Current roots of the trees, by object id
+	pub(super) roots:            BTreeMap<u64, Root>,
+	/// Roots of the trees at the last commit
+	committed:                   BTreeMap<u64, Root>,
+	pub(super) block_groups:     Vec<BlockGroup>,
+	/// The tree containing the block group items
+	pub(super) block_group_tree: u64,
+	pub(super) writable:         bool,
+	/// Id of the current transaction
+	pub(super) transid:          u64,
+	/// Nodes changed in the current transaction
+	pub(super) dirty:            BTreeMap<u64, Node>,
+	pub(super) cache:            BTreeMap<u64, Node>,
+	/// Extents allocated and freed in the current transaction, by start and length
+	pub(super) reserved:         BTreeMap<u64, u64>,
+	pub(super) pinned:           BTreeMap<u64, u64>,
+	pub(super) pending:          VecDeque<Pending>,
+	/// Inode of the root directory
+	root_dir:                    u64,
+	label:                       String,
+	time:                        Timespec
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+	/// Reads the superblock, falling back to its mirrors if it is corrupted, the chunk
+	/// tree and the roots of the other trees.
+	pub fn open(mut dev: D) -> Result<Self> {
+		let size = dev.blocks() * dev.block_size() as u64;
+		let mut buf = [0u8; SUPER_SIZE];
+		let mut sb = None;
+		for offset in SUPER_MIRRORS.iter().copied().filter(|offset| offset + SUPER_SIZE as u64 <= size) {
+			read_bytes(&mut dev, offset, &mut buf)?;
+			let s = read_struct::<Superblock>(&buf, 0)?;
+			if s.magic == MAGIC && s.bytenr == offset
+				&& checksum_size(s.csum_type).map_or(false, |len| verify(&buf, s.csum_type, len)) {
+				sb = Some(s);
+				break;
+			}
+		}
+
+		let sb = sb.ok_or(Error::NotBtrfs)?;
+		let (sectorsize, nodesize) = (sb.sectorsize as u64, sb.nodesize as usize);
+		if !sectorsize.is_power_of_two() || !(4096..=65536).contains(&sectorsize)
+			|| !nodesize.is_power_of_two() || !(sectorsize as usize..=65536).contains(&nodesize) {
+			return Err(Error::Corrupted);
+		} else if sb.incompat_flags & !INCOMPAT_SUPPORTED != 0 || sb.num_devices != 1 {
+			return Err(Error::Unsupported);
+		}
+
+		let mut fs = Self {
+			dev,
+			sb,
+			chunks:           ChunkMap::from_sys_chunk_array(&sb)?,
+			csum_type:        sb.csum_type,
+			csum_size:        checksum_size(sb.csum_type).unwrap(),
+			nodesize,
+			sectorsize,
+			metadata_fsid:    match sb.incompat_flags & INCOMPAT_METADATA_UUID {
+				0 => sb.fsid,
+				_ => sb.metadata_uuid
+			},
+			roots:            BTreeMap::new(),
+			committed:        BTreeMap::new(),
+			block_groups:     Vec::new(),
+			block_group_tree: EXTENT_TREE_OBJECTID,
+			writable:         false,
+			transid:          sb.generation + 1,
+			dirty:            BTreeMap::new(),
+			cache:            BTreeMap::new(),
+			reserved:         BTreeMap::new(),
+			pinned:           BTreeMap::new(),
+			pending:          VecDeque::new(),
+			root_dir:         FIRST_FREE_OBJECTID,
+			label:            String::new(),
+			time:             Timespec::default()
+		};
+
+		fs.roots.insert(CHUNK_TREE_OBJECTID, Root { bytenr: sb.chunk_root, level: sb.chunk_root_level });
+		fs.roots.insert(ROOT_TREE_OBJECTID, Root { bytenr: sb.root, level: sb.root_level });
+		let chunks = fs.range(
+			CHUNK_TREE_OBJECTID,
+			Key::new(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY, 0),
+			Key::new(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY, u64::MAX)
+		)?;
+		for (key, data) in chunks {
+			fs.chunks.insert(key.offset, &data)?;
+		}
+
+		let roots = fs.range(ROOT_TREE_OBJECTID, Key::MIN, Key::new(FIRST_FREE_OBJECTID - 1, u8::MAX, u64::MAX))?;
+		for (key, data) in roots.into_iter().filter(|(key, _)| key.kind == ROOT_ITEM_KEY) {
+			let item = root_item(&data)?;
+			fs.roots.insert(key.objectid, Root { bytenr: item.bytenr, level: item.level });
+			if key.objectid == FS_TREE_OBJECTID {
+				fs.root_dir = item.root_dirid;
+			}
+		}
+		for tree in [EXTENT_TREE_OBJECTID, FS_TREE_OBJECTID, CSUM_TREE_OBJECTID] {
+			fs.root(tree)?;
+		}
+
+		if sb.compat_ro_flags & COMPAT_RO_BLOCK_GROUP_TREE != 0 {
+			fs.root(BLOCK_GROUP_TREE_OBJECTID)?;
+			fs.block_group_tree = BLOCK_GROUP_TREE_OBJECTID;
+		}
+		for (start, length) in fs.chunks.iter().map(|c| (c.logical, c.length)).collect::<Vec<_>>() {
+			let data = fs.search(fs.block_group_tree, Key::new(start, BLOCK_GROUP_ITEM_KEY, length))?
+				.ok_or(Error::Corrupted)?;
+			let flags = read_struct::<BlockGroupItem>(&data, 0)?.flags;
+			fs.block_groups.push(BlockGroup { start, length, flags });
+		}
+
+		let fst = COMPAT_RO_FREE_SPACE_TREE | COMPAT_RO_FREE_SPACE_TREE_VALID;
+		fs.writable = sb.incompat_flags & INCOMPAT_SKINNY_METADATA != 0
+			&& sb.compat_ro_flags & fst == fst
+			&& sb.compat_ro_flags & !(fst | COMPAT_RO_BLOCK_GROUP_TREE) == 0
+			&& sb.flags & SUPER_FLAG_SEEDING == 0
+			&& sb.log_root == 0
+			&& fs.roots.contains_key(&FREE_SPACE_TREE_OBJECTID)
+			&& !fs.roots.contains_key(&QUOTA_TREE_OBJECTID)
+			&& fs.chunks.iter().all(ChunkMapping::is_mirrored);
+		fs.committed = fs.roots.clone();
+
+		let label = sb.label;
+		let len = label.iter().position(|c| *c == 0).unwrap_or(label.len());
+		fs.label = String::from_utf8_lossy(&label[..len]).into();
+		Ok(fs)
+	}
+
+	/// The label, empty if there is none.
+	pub fn label(&self) -> &str {
+		&self.label
+	}
+
+	pub fn fsid(&self) -> [u8; 16] {
+		self.sb.fsid
+	}
+
+	/// Transaction id of the last commit.
+	pub fn generation(&self) -> u64 {
+		self.sb.generation
+	}
+
+	pub fn sector_size(&self) -> usize {
+		self.sectorsize as usize
+	}
+
+	pub fn node_size(&self) -> usize {
+		self.nodesize
+	}
+
+	pub fn total_bytes(&self) -> u64 {
+		self.sb.total_bytes
+	}
+
+	/// Bytes used by extents, including the current transaction.
+	pub fn bytes_used(&self) -> u64 {
+		self.sb.bytes_used
+	}
+
+	/// Whether the file system can be written to, see the module documentation.
+	pub fn is_writable(&self) -> bool {
+		self.writable
+	}
+
+	/// Sets the time stamp of files created or modified from now on.
+	pub fn set_time(&mut self, time: Timespec) {
+		self.time = time;
+	}
+
+	/// The entries of the directory at `path`, in the order they were created.
+	pub fn read_dir(&mut self, path: &str) -> Result<Vec<Entry>> {
+		let (dir, inode) = self.lookup(path)?;
+		if file_type(inode.mode) != FT_DIR {
+			return Err(Error::NotADirectory);
+		}
+
+		let items = self.range(
+			FS_TREE_OBJECTID,
+			Key::new(dir, DIR_INDEX_KEY, 0),
+			Key::new(dir, DIR_INDEX_KEY, u64::MAX)
+		)?;
+		let mut entries = Vec::new();
+		for (_, data) in items {
+			let (item, name, _) = dir_items(&data).next().ok_or(Error::Corrupted)??;
+			let location = Key::from(item.location);
+			let inode = match location.kind {
+				INODE_ITEM_KEY => self.inode(location.objectid)?,
+				// the top directory of a subvolume, which is in another tree
+				_ => InodeItem { mode: S_IFDIR | 0o755, nlink: 1, ..InodeItem::default() }
+			};
+			entries.push(Entry::new(name, location.objectid, &inode));
+		}
+		Ok(entries)
+	}
+
+	/// The entry at `path`, the root directory has an entry without name.
+	pub fn metadata(&mut self, path: &str) -> Result<Entry> {
+		let (inode, item) = self.lookup(path)?;
+		Ok(Entry::new(split(path).1.as_bytes(), inode, &item))
+	}
+
+	/// Reads from the file at `path`, returns the number of bytes read, which is less than
+	/// the buffer's length at the end of the file.
+	pub fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+		let (inode, item) = self.file(path)?;
+		if offset >= item.size {
+			return Ok(0);
+		}
+
+		let len = buf.len().min((item.size - offset) as usize);
+		self.read_inode(inode, &item, offset, &mut buf[..len])?;
+		Ok(len)
+	}
+
+	/// Writes to the file at `path`, which is extended if the data goes beyond its end.
+	/// The data is stored uncompressed in new extents.
+	pub fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize> {
+		self.check_writable()?;
+		let (inode, mut item) = self.file(path)?;
+		let end = offset.checked_add(data.len() as u64).filter(|end| *end <= MAX_FILE_SIZE).ok_or(Error::NoSpace)?;
+		if data.is_empty() {
+			return Ok(0);
+		}
+
+		self.uninline(inode, &mut item)?;
+		let start = offset / self.sectorsize * self.sectorsize;
+		let mut buf = vec![0u8; (align_up(end, self.sectorsize) - start) as usize];
+		// keep the data of partially written sectors
+		let last = buf.len() - self.sectorsize as usize;
+		if start < offset {
+			self.read_inode_clamped(inode, &item, start, &mut buf[..self.sectorsize as usize])?;
+		}
+		if end % self.sectorsize != 0 && (last != 0 || start == offset) {
+			self.read_inode_clamped(inode, &item, start + last as u64, &mut buf[last..])?;
+		}
+		buf[(offset - start) as usize..][..data.len()].copy_from_slice(data);
+
+		self.fill_hole(inode, &item, start)?;
+		self.drop_extents(inode, &mut item, start, start + buf.len() as u64)?;
+		self.write_extents(inode, &mut item, start, &buf)?;
+		item.size = item.size.max(end);
+		self.touch(&mut item);
+		self.put_inode(inode, &item)?;
+		self.flush_pending()?;
+		Ok(data.len())
+	}
+
+	/// Sets the size of the file at `path`, a file that grows is filled with zeros.
+	pub fn truncate(&mut self, path: &str, size: u64) -> Result<()> {
+		self.check_writable()?;
+		let (inode, mut item) = self.file(path)?;
+		if size > MAX_FILE_SIZE {
+			return Err(Error::NoSpace);
+		}
+
+		self.uninline(inode, &mut item)?;
+		if size < item.size {
+			let end = align_up(size, self.sectorsize);
+			if size != end {
+				// zero the rest of the last sector, it becomes part of the file if it grows
+				let start = end - self.sectorsize;
+				let mut buf = vec![0u8; self.sectorsize as usize];
+				self.read_inode_clamped(inode, &item, start, &mut buf[..(size - start) as usize])?;
+				self.drop_extents(inode, &mut item, start, end)?;
+				self.write_extents(inode, &mut item, start, &buf)?;
+			}
+			self.drop_extents(inode, &mut item, end, u64::MAX)?;
+		} else {
+			self.fill_hole(inode, &item, align_up(size, self.sectorsize))?;
+		}
+
+		item.size = size;
+		self.touch(&mut item);
+		self.put_inode(inode, &item)?;
+		self.flush_pending()
+	}
+
+	/// Creates an empty file.
+	pub fn create_file(&mut self, path: &str) -> Result<Entry> {
+		self.create(path, S_IFREG | 0o644)
+	}
+
+	/// Creates an empty directory.
+	pub fn create_dir(&mut self, path: &str) -> Result<Entry> {
+		self.create(path, S_IFDIR | 0o755)
+	}
+
+	/// Removes a file or an empty directory, the file's extents are freed with its last
+	/// link.
+	pub fn remove(&mut self, path: &str) -> Result<()> {
+		self.check_writable()?;
+		let (parent_path, name) = split(path);
+		if name.is_empty() {
+			return Err(Error::InvalidName);
+		}
+		let (parent, mut parent_item) = self.dir(parent_path)?;
+		let entry = self.find_entry(parent, name.as_bytes())?.ok_or(Error::NotFound)?;
+		let location = Key::from(entry.location);
+		if location.kind != INODE_ITEM_KEY {
+			return Err(Error::Unsupported);
+		}
+
+		let inode = location.objectid;
+		let mut item = self.inode(inode)?;
+		let is_dir = file_type(item.mode) == FT_DIR;
+		if is_dir && !self.range(
+			FS_TREE_OBJECTID,
+			Key::new(inode, DIR_INDEX_KEY, 0),
+			Key::new(inode, DIR_INDEX_KEY, u64::MAX)
+		)?.is_empty() {
+			return Err(Error::NotEmpty);
+		}
+
+		// the inode reference holds the index of the entry
+		let ref_key = Key::new(inode, INODE_REF_KEY, parent);
+		let mut refs = self.search(FS_TREE_OBJECTID, ref_key)?.ok_or(Error::Unsupported)?;
+		let mut pos = 0;
+		let index = loop {
+			let r = read_struct::<InodeRef>(&refs, pos)?;
+			let end = pos + INODE_REF_SIZE + r.name_len as usize;
+			if refs.get(pos + INODE_REF_SIZE..end).ok_or(Error::Corrupted)? == name.as_bytes() {
+				refs.drain(pos..end);
+				break r.index;
+			}
+			pos = end;
+		};
+		match refs.is_empty() {
+			true  => self.delete(FS_TREE_OBJECTID, ref_key)?,
+			false => self.update(FS_TREE_OBJECTID, ref_key, &refs)?
+		}
+
+		let dir_key = Key::new(parent, DIR_ITEM_KEY, name_hash(name.as_bytes()));
+		let mut items = self.search(FS_TREE_OBJECTID, dir_key)?.ok_or(Error::Corrupted)?;
+		let range = dir_items(&items)
+			.find(|e| e.as_ref().map_or(true, |(_, n, _)| *n == name.as_bytes()))
+			.ok_or(Error::Corrupted)??.2;
+		items.drain(range);
+		match items.is_empty() {
+			true  => self.delete(FS_TREE_OBJECTID, dir_key)?,
+			false => self.update(FS_TREE_OBJECTID, dir_key, &items)?
+		}
+		self.delete(FS_TREE_OBJECTID, Key::new(parent, DIR_INDEX_KEY, index))?;
+
+		item.nlink = item.nlink.saturating_sub(1);
+		if item.nlink == 0 || is_dir {
+			let items = self.range(FS_TREE_OBJECTID, Key::new(inode, 0, 0), Key::new(inode, u8::MAX, u64::MAX))?;
+			for (key, data) in items {
+				if key.kind == EXTENT_DATA_KEY {
+					let extent = file_extent(&data)?;
+					if extent.kind != FILE_EXTENT_INLINE && extent.disk_bytenr != 0 {
+						self.pending.push_back(data_ref(inode, key, &extent, false));
+					}
+				}
+				self.delete(FS_TREE_OBJECTID, key)?;
+			}
+		} else {
+			item.transid = self.transid;
+			item.ctime = self.time;
+			self.put_inode(inode, &item)?;
+		}
+
+		parent_item.size = parent_item.size.saturating_sub(2 * name.len() as u64);
+		self.touch(&mut parent_item);
+		self.put_inode(parent, &parent_item)?;
+		self.flush_pending()
+	}
+
+	/// Commits the transaction: writes the changed nodes, then the superblocks, and
+	/// flushes the device.
+	pub fn sync(&mut self) -> Result<()> {
+		if self.dirty.is_empty() && self.pending.is_empty() {
+			return Ok(self.dev.flush()?);
+		}
+
+		// updating the root items changes the root tree, which changes the extent tree
+		loop {
+			self.flush_pending()?;
+			let mut changed = false;
+			for (tree, root) in self.roots.clone() {
+				if matches!(tree, ROOT_TREE_OBJECTID | CHUNK_TREE_OBJECTID) {
+					continue;
+				}
+				let key = Key::new(tree, ROOT_ITEM_KEY, 0);
+				let mut data = self.search(ROOT_TREE_OBJECTID, key)?.ok_or(Error::Corrupted)?;
+				let mut item = root_item(&data)?;
+				if item.bytenr == root.bytenr && item.level == root.level {
+					continue;
+				}
+				item.bytenr = root.bytenr;
+				item.level = root.level;
+				item.generation = self.transid;
+				item.generation_v2 = self.transid;
+				if tree == FS_TREE_OBJECTID {
+					item.ctransid = self.transid;
+					item.ctime = self.time;
+				}
+				data.resize(data.len().max(core::mem::size_of::<RootItem>()), 0);
+				data[..core::mem::size_of::<RootItem>()].copy_from_slice(struct_bytes(&item));
+				self.update(ROOT_TREE_OBJECTID, key, &data)?;
+				changed = true;
+			}
+			if !changed && self.pending.is_empty() {
+				break;
+			}
+		}
+
+		let nodes = core::mem::take(&mut self.dirty);
+		for (bytenr, node) in &nodes {
+			let bytes = node.to_bytes(self.nodesize, self.csum_type);
+			for offset in self.chunks.map(*bytenr, self.nodesize as u64)? {
+				self.write_bytes(offset, &bytes)?;
+			}
+		}
+		self.dev.flush()?;
+		self.write_super()?;
+		self.dev.flush()?;
+
+		self.committed = self.roots.clone();
+		self.cache.clear();
+		self.cache.extend(nodes.into_iter().take(64));
+		self.reserved.clear();
+		self.pinned.clear();
+		self.transid += 1;
+		Ok(())
+	}
+
+	/// Returns the device without committing.
+	pub fn into_inner(self) -> D {
+		self.dev
+	}
+
+	fn create(&mut self, path: &str, mode: u32) -> Result<Entry> {
+		self.check_writable()?;
+		let (parent_path, name) = split(path);
+		if name.is_empty() || name.len() > NAME_MAX || name.contains('\0') || name == "." || name == ".." {
+			return Err(Error::InvalidName);
+		}
+		let (parent, mut parent_item) = self.dir(parent_path)?;
+		if self.find_entry(parent, name.as_bytes())?.is_some() {
+			return Err(Error::Exists);
+		}
+
+		let inode = match self.prev(FS_TREE_OBJECTID, Key::new(LAST_FREE_OBJECTID, u8::MAX, u64::MAX))? {
+			Some((key, _)) => key.objectid.max(FIRST_FREE_OBJECTID) + 1,
+			None           => FIRST_FREE_OBJECTID + 1
+		};
+		let index = match self.prev(FS_TREE_OBJECTID, Key::new(parent, DIR_INDEX_KEY, u64::MAX))? {
+			Some((key, _)) if key.objectid == parent && key.kind == DIR_INDEX_KEY => key.offset + 1,
+			_ => 2
+		};
+
+		let item = InodeItem {
+			generation: self.transid,
+			transid:    self.transid,
+			nlink:      1,
+			mode,
+			sequence:   1,
+			atime:      self.time,
+			ctime:      self.time,
+			mtime:      self.time,
+			otime:      self.time,
+			..InodeItem::default()
+		};
+		self.put_new_inode(inode, &item)?;
+
+		let name = name.as_bytes();
+		let inode_ref = [struct_bytes(&InodeRef { index, name_len: name.len() as u16 }), name].concat();
+		self.insert(FS_TREE_OBJECTID, Key::new(inode, INODE_REF_KEY, parent), &inode_ref)?;
+
+		let dir_item = [struct_bytes(&DirItem {
+			location: Key::new(inode, INODE_ITEM_KEY, 0).into(),
+			transid:  self.transid,
+			data_len: 0,
+			name_len: name.len() as u16,
+			kind:     file_type(mode)
+		}), name].concat();
+		let dir_key = Key::new(parent, DIR_ITEM_KEY, name_hash(name));
+		match self.search(FS_TREE_OBJECTID, dir_key)? {
+			// names with the same hash share the item
+			Some(items) => self.update(FS_TREE_OBJECTID, dir_key, &[&items, &dir_item[..]].concat())?,
+			None        => self.insert(FS_TREE_OBJECTID, dir_key, &dir_item)?
+		}
+		self.insert(FS_TREE_OBJECTID, Key::new(parent, DIR_INDEX_KEY, index), &dir_item)?;
+
+		parent_item.size += 2 * name.len() as u64;
+		self.touch(&mut parent_item);
+		self.put_inode(parent, &parent_item)?;
+		self.flush_pending()?;
+		Ok(Entry::new(name, inode, &item))
+	}
+
+	/// Resolves a path to an inode in the top level subvolume.
+	fn lookup(&mut self, path: &str) -> Result<(u64, InodeItem)> {
+		let mut inode = self.root_dir;
+		let mut item = self.inode(inode)?;
+		for name in path.split('/').filter(|c| !c.is_empty()) {
+			if file_type(item.mode) != FT_DIR {
+				return Err(Error::NotADirectory);
+			}
+			let entry = self.find_entry(inode, name.as_bytes())?.ok_or(Error::NotFound)?;
+			let location = Key::from(entry.location);
+			if location.kind != INODE_ITEM_KEY {
+				return Err(Error::Unsupported);
+			}
+			inode = location.objectid;
+			item = self.inode(inode)?;
+		}
+		Ok((inode, item))
+	}
+
+	fn dir(&mut self, path: &str) -> Result<(u64, InodeItem)> {
+		let (inode, item) = self.lookup(path)?;
+		match file_type(item.mode) {
+			FT_DIR => Ok((inode, item)),
+			_      => Err(Error::NotADirectory)
+		}
+	}
+
+	fn file(&mut self, path: &str) -> Result<(u64, InodeItem)> {
+		let (inode, item) = self.lookup(path)?;
+		match file_type(item.mode) {
+			FT_DIR => Err(Error::IsADirectory),
+			_      => Ok((inode, item))
+		}
+	}
+
+	fn inode(&mut self, inode: u64) -> Result<InodeItem> {
+		let data = self.search(FS_TREE_OBJECTID, Key::new(inode, INODE_ITEM_KEY, 0))?.ok_or(Error::Corrupted)?;
+		read_struct(&data, 0)
+	}
+
+	fn put_inode(&mut self, inode: u64, item: &InodeItem) -> Result<()> {
+		self.update(FS_TREE_OBJECTID, Key::new(inode, INODE_ITEM_KEY, 0), struct_bytes(item))
+	}
+
+	fn put_new_inode(&mut self, inode: u64, item: &InodeItem) -> Result<()> {
+		self.insert(FS_TREE_OBJECTID, Key::new(inode, INODE_ITEM_KEY, 0), struct_bytes(item))
+	}
+
+	fn touch(&self, item: &mut InodeItem) {
+		item.transid = self.transid;
+		item.sequence += 1;
+		item.ctime = self.time;
+		item.mtime = self.time;
+	}
+
+	fn check_writable(&self) -> Result<()> {
+		match self.writable {
+			true  => Ok(()),
+			false => Err(Error::Unsupported)
+		}
+	}
+
+	/// The directory item of the name in the directory.
+	fn find_entry(&mut self, dir: u64, name: &[u8]) -> Result<Option<DirItem>> {
+		let data = match self.search(FS_TREE_OBJECTID, Key::new(dir, DIR_ITEM_KEY, name_hash(name)))? {
+			Some(data) => data,
+			None       => return Ok(None)
+		};
+		for entry in dir_items(&data) {
+			let (item, n, _) = entry?;
+			if n == name {
+				return Ok(Some(item));
+			}
+		}
+		Ok(None)
+	}
+
+	/// Reads a range of a file, reading past the size returns zeros or the content of
+	/// the last extent.
+	fn read_inode(&mut self, inode: u64, item: &InodeItem, offset: u64, buf: &mut [u8]) -> Result<()> {
+		buf.fill(0);
+		let end = offset + buf.len() as u64;
+		let check = item.flags & INODE_NODATASUM == 0;
+		for (key, data) in self.extents(inode, offset, end)? {
+			let extent = file_extent(&data)?;
+			if extent.encryption != 0 || extent.other_encoding != 0 {
+				return Err(Error::Unsupported);
+			}
+
+			if extent.kind == FILE_EXTENT_INLINE {
+				let content = match extent.compression {
+					COMPRESS_NONE => data[FileExtentItem::INLINE_SIZE..].to_vec(),
+					c             => decompress(c, &data[FileExtentItem::INLINE_SIZE..], extent.ram_bytes)?
+				};
+				copy_overlap(buf, offset, &content, key.offset);
+				continue;
+			} else if extent.disk_bytenr == 0 || extent.kind == FILE_EXTENT_PREALLOC {
+				continue;
+			}
+
+			// the part of the file covered by the extent and the buffer
+			let start = key.offset.max(offset);
+			let stop = (key.offset + extent.num_bytes).min(end);
+			if start >= stop {
+				continue;
+			}
+
+			if extent.compression != COMPRESS_NONE {
+				let raw = self.read_data(extent.disk_bytenr, extent.disk_num_bytes, check)?;
+				let content = decompress(extent.compression, &raw, extent.ram_bytes)?;
+				let content = content.get(extent.offset as usize..).unwrap_or(&[]);
+				let len = content.len().min(extent.num_bytes as usize);
+				copy_overlap(buf, offset, &content[..len], key.offset);
+			} else {
+				let disk = extent.disk_bytenr + extent.offset + (start - key.offset);
+				let disk_start = disk / self.sectorsize * self.sectorsize;
+				let disk_end = align_up(disk + (stop - start), self.sectorsize);
+				let raw = self.read_data(disk_start, disk_end - disk_start, check)?;
+				let skip = (disk - disk_start) as usize;
+				buf[(start - offset) as usize..(stop - offset) as usize]
+					.copy_from_slice(&raw[skip..skip + (stop - start) as usize]);
+			}
+		}
+		Ok(())
+	}
+
+	/// Like `read_inode`, but only reads up to the size of the file.
+	fn read_inode_clamped(&mut self, inode: u64, item: &InodeItem, offset: u64, buf: &mut [u8]) -> Result<()> {
+		buf.fill(0);
+		let len = item.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+		match len {
+			0 => Ok(()),
+			_ => self.read_inode(inode, item, offset, &mut buf[..len])
+		}
+	}
+
+	/// The file extent items overlapping `start..end`.
+	fn extents(&mut self, inode: u64, start: u64, end: u64) -> Result<Vec<(Key, Vec<u8>)>> {
+		let mut items = Vec::new();
+		if let Some((key, data)) = self.prev(FS_TREE_OBJECTID, Key::new(inode, EXTENT_DATA_KEY, start))? {
+			if key.objectid == inode && key.kind == EXTENT_DATA_KEY {
+				items.push((key, data));
+			}
+		}
+		if end > start + 1 {
+			items.extend(self.range(
+				FS_TREE_OBJECTID,
+				Key::new(inode, EXTENT_DATA_KEY, start + 1),
+				Key::new(inode, EXTENT_DATA_KEY, end - 1)
+			)?);
+		}
+		Ok(items)
+	}
+
+	/// Reads a range of a data extent, verifying its checksums and using the next copy if
+	/// one is corrupted.
+	fn read_data(&mut self, logical: u64, len: u64, check: bool) -> Result<Vec<u8>> {
+		let csums = match check {
+			true  => self.data_csums(logical, len)?,
+			false => Vec::new()
+		};
+		let mut buf = vec![0u8; len as usize];
+		for offset in self.chunks.map(logical, len)? {
+			self.read_bytes(offset, &mut buf)?;
+			let valid = buf.chunks(self.sectorsize as usize)
+				.zip(&csums)
+				.all(|(sector, csum)| csum.as_ref()
+					.map_or(true, |csum| checksum(self.csum_type, sector)[..self.csum_size] == csum[..]));
+			if valid {
+				return Ok(buf);
+			}
+		}
+		Err(Error::Corrupted)
+	}
+
+	/// Converts an inline extent to a regular one before the file is modified.
+	fn uninline(&mut self, inode: u64, item: &mut InodeItem) -> Result<()> {
+		let key = Key::new(inode, EXTENT_DATA_KEY, 0);
+		let extent = match self.search(FS_TREE_OBJECTID, key)? {
+			Some(data) => file_extent(&data)?,
+			None       => return Ok(())
+		};
+		if extent.kind != FILE_EXTENT_INLINE {
+			return Ok(());
+		}
+
+		let mut buf = vec![0u8; self.sectorsize as usize];
+		self.read_inode_clamped(inode, item, 0, &mut buf)?;
+		self.delete(FS_TREE_OBJECTID, key)?;
+		item.nbytes = item.nbytes.saturating_sub(extent.ram_bytes);
+		if item.size != 0 {
+			self.write_extents(inode, item, 0, &buf)?;
+		}
+		Ok(())
+	}
+
+	/// Removes the file extent items in `start..end`, items partially in the range are
+	/// trimmed and keep referencing their extent.
+	fn drop_extents(&mut self, inode: u64, item: &mut InodeItem, start: u64, end: u64) -> Result<()> {
+		for (key, data) in self.extents(inode, start, end)? {
+			let extent = file_extent(&data)?;
+			let extent_end = key.offset + extent.num_bytes;
+			if extent.kind == FILE_EXTENT_INLINE {
+				return Err(Error::Corrupted);
+			} else if extent_end <= start || key.offset >= end {
+				continue;
+			}
+
+			let hole = extent.disk_bytenr == 0;
+			self.delete(FS_TREE_OBJECTID, key)?;
+			if key.offset < start {
+				let head = FileExtentItem { num_bytes: start - key.offset, ..extent };
+				self.insert(FS_TREE_OBJECTID, key, &struct_bytes(&head)[..FILE_EXTENT_SIZE])?;
+				if !hole {
+					item.nbytes += head.num_bytes;
+					self.pending.push_back(data_ref(inode, key, &head, true));
+				}
+			}
+			if extent_end > end {
+				let tail = FileExtentItem {
+					offset:    extent.offset + (end - key.offset),
+					num_bytes: extent_end - end,
+					..extent
+				};
+				let tail_key = Key::new(inode, EXTENT_DATA_KEY, end);
+				self.insert(FS_TREE_OBJECTID, tail_key, struct_bytes(&tail))?;
+				if !hole {
+					item.nbytes += tail.num_bytes;
+					self.pending.push_back(data_ref(inode, tail_key, &tail, true));
+				}
+			}
+			if !hole {
+				item.nbytes = item.nbytes.saturating_sub(extent.num_bytes);
+				self.pending.push_back(data_ref(inode, key, &extent, false));
+			}
+		}
+		Ok(())
+	}
+
+	/// Writes data at a sector aligned file offset to new extents.
+	fn write_extents(&mut self, inode: u64, item: &mut InodeItem, offset: u64, data: &[u8]) -> Result<()> {
+		let mut pos = 0;
+		while pos < data.len() {
+			let want = ((data.len() - pos) as u64).min(MAX_EXTENT_SIZE);
+			let (bytenr, len) = self.alloc_data(want, inode, offset + pos as u64)?;
+			let chunk = &data[pos..pos + len as usize];
+			for disk in self.chunks.map(bytenr, len)? {
+				self.write_bytes(disk, chunk)?;
+			}
+			if item.flags & INODE_NODATASUM == 0 {
+				self.add_csums(bytenr, chunk)?;
+			}
+
+			let extent = FileExtentItem {
+				generation:     self.transid,
+				ram_bytes:      len,
+				kind:           FILE_EXTENT_REG,
+				disk_bytenr:    bytenr,
+				disk_num_bytes: len,
+				num_bytes:      len,
+				..FileExtentItem::default()
+			};
+			self.insert(FS_TREE_OBJECTID, Key::new(inode, EXTENT_DATA_KEY, offset + pos as u64), struct_bytes(&extent))?;
+			item.nbytes += len;
+			pos += len as usize;
+		}
+		Ok(())
+	}
+
+	/// Adds an explicit hole from the end of the file to `offset`, unless the file system
+	/// leaves holes implicit.
+	fn fill_hole(&mut self, inode: u64, item: &InodeItem, offset: u64) -> Result<()> {
+		let start = align_up(item.size, self.sectorsize);
+		if self.sb.incompat_flags & INCOMPAT_NO_HOLES != 0 || start >= offset {
+			return Ok(());
+		}
+		let hole = FileExtentItem {
+			generation: self.transid,
+			ram_bytes:  offset - start,
+			kind:       FILE_EXTENT_REG,
+			num_bytes:  offset - start,
+			..FileExtentItem::default()
+		};
+		self.insert(FS_TREE_OBJECTID, Key::new(inode, EXTENT_DATA_KEY, start), struct_bytes(&hole))
+	}
+
+	/// Writes the superblock with the current roots and a backup of them to all mirrors.
+	fn write_super(&mut self) -> Result<()> {
+		let root = self.root(ROOT_TREE_OBJECTID)?;
+		let mut sb = self.sb;
+		let slot = sb.super_roots.iter()
+			.position(|backup| backup.tree_root_gen == sb.generation)
+			.map_or(0, |i| (i + 1) % sb.super_roots.len());
+
+		sb.generation = self.transid;
+		sb.root = root.bytenr;
+		sb.root_level = root.level;
+		let mut backup = sb.super_roots[slot];
+		backup.tree_root = root.bytenr;
+		backup.tree_root_gen = self.transid;
+		backup.tree_root_level = root.level;
+		backup.chunk_root = sb.chunk_root;
+		backup.chunk_root_gen = sb.chunk_root_generation;
+		backup.chunk_root_level = sb.chunk_root_level;
+		let mut roots = [(0, 0, 0); 4];
+		for (i, tree) in [EXTENT_TREE_OBJECTID, FS_TREE_OBJECTID, DEV_TREE_OBJECTID, CSUM_TREE_OBJECTID].into_iter().enumerate() {
+			let data = self.search(ROOT_TREE_OBJECTID, Key::new(tree, ROOT_ITEM_KEY, 0))?.ok_or(Error::Corrupted)?;
+			let item = root_item(&data)?;
+			roots[i] = (item.bytenr, item.generation, item.level);
+		}
+		(backup.extent_root, backup.extent_root_gen, backup.extent_root_level) = roots[0];
+		(backup.fs_root, backup.fs_root_gen, backup.fs_root_level) = roots[1];
+		(backup.dev_root, backup.dev_root_gen, backup.dev_root_level) = roots[2];
+		(backup.csum_root, backup.csum_root_gen, backup.csum_root_level) = roots[3];
+		backup.total_bytes = sb.total_bytes;
+		backup.bytes_used = sb.bytes_used;
+		backup.num_devices = sb.num_devices;
+		sb.super_roots[slot] = backup;
+
+		let size = self.dev.blocks() * self.dev.block_size() as u64;
+		for offset in SUPER_MIRRORS.iter().copied().filter(|offset| offset + SUPER_SIZE as u64 <= size) {
+			sb.bytenr = offset;
+			let mut buf = struct_bytes(&sb).to_vec();
+			let csum = checksum(self.csum_type, &buf[CSUM_SIZE..]);
+			buf[..CSUM_SIZE].copy_from_slice(&csum);
+			self.write_bytes(offset, &buf)?;
+		}
+		sb.bytenr = SUPER_OFFSET;
+		self.sb = sb;
+		Ok(())
+	}
+
+	pub(super) fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+		read_bytes(&mut self.dev, offset, buf)
+	}
+
+	fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+		let block_size = self.dev.block_size();
+		let lba = offset / block_size as u64;
+		let skip = (offset % block_size as u64) as usize;
+		let blocks = (skip + buf.len() + block_size - 1) / block_size;
+
+		// read the partially written first and last block
+		let mut tmp = block::alloc_buffer(&self.dev, blocks)?;
+		if skip != 0 {
+			self.dev.read(lba, &mut tmp[..block_size])?;
+		}
+		if (skip + buf.len()) % block_size != 0 && (blocks > 1 || skip == 0) {
+			self.dev.read(lba + blocks as u64 - 1, &mut tmp[(blocks - 1) * block_size..])?;
+		}
+		tmp[skip..skip + buf.len()].copy_from_slice(buf);
+		Ok(self.dev.write(lba, &tmp)?)
+	}
+}
+
+impl<D> core::fmt::Debug for FileSystem<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("FileSystem")
+			.field("label", &self.label)
+			.field("generation", &{ self.sb.generation })
+			.field("nodesize", &self.nodesize)
+			.field("sectorsize", &self.sectorsize)
+			.field("writable", &self.writable)
+			.finish()
+	}
+}
+
+/// A directory item with its name and its range in the item data.
+type DirEntry<'a> = (DirItem, &'a [u8], core::ops::Range<usize>);
+
+/// The directory items packed into the data of a `DIR_ITEM_KEY` item.
+fn dir_items(data: &[u8]) -> impl Iterator<Item = Result<DirEntry<'_>>> {
+	let mut pos = 0;
+	core::iter::from_fn(move || {
+		if pos >= data.len() {
+			return None;
+		}
+		let start = pos;
+		let item = match read_struct::<DirItem>(data, pos) {
+			Ok(item) => item,
+			Err(e)   => return Some(Err(e))
+		};
+		let name = start + DIR_ITEM_SIZE..start + DIR_ITEM_SIZE + item.name_len as usize;
+		pos = name.end + item.data_len as usize;
+		match data.get(name).filter(|_| pos <= data.len()) {
+			Some(name) => Some(Ok((item, name, start..pos))),
+			None       => {
+				pos = data.len();
+				Some(Err(Error::Corrupted))
+			}
+		}
+	})
+}
+
+/// Parses a file extent item, inline extents only have the fields before their data.
+fn file_extent(data: &[u8]) -> Result<FileExtentItem> {
+	let mut buf = [0u8; FILE_EXTENT_SIZE];
+	let len = data.len().min(FILE_EXTENT_SIZE);
+	buf[..len].copy_from_slice(&data[..len]);
+	let extent = read_struct::<FileExtentItem>(&buf, 0)?;
+	match (extent.kind, data.len()) {
+		(FILE_EXTENT_INLINE, FileExtentItem::INLINE_SIZE..) | (_, FILE_EXTENT_SIZE) => Ok(extent),
+		_ => Err(Error::Corrupted)
+	}
+}
+
+/// Parses a root item, older versions of the item are shorter.
+fn root_item(data: &[u8]) -> Result<RootItem> {
+	let mut buf = [0u8; core::mem::size_of::<RootItem>()];
+	let len = data.len().min(buf.len());
+	buf[..len].copy_from_slice(&data[..len]);
+	read_struct(&buf, 0)
+}
+
+/// The back reference change for a file extent item.
+fn data_ref(inode: u64, key: Key, extent: &FileExtentItem, add: bool) -> Pending {
+	Pending::DataRef {
+		bytenr: extent.disk_bytenr,
+		len:    extent.disk_num_bytes,
+		root:   FS_TREE_OBJECTID,
+		inode,
+		offset: key.offset - extent.offset,
+		add
+	}
+}
+
+fn decompress(compression: u8, data: &[u8], len: u64) -> Result<Vec<u8>> {
+	match compression {
+		COMPRESS_ZLIB => Ok(compress::zlib_decompress(data, len as usize)?),
+		COMPRESS_ZSTD => Ok(compress::zstd_decompress_frame(data, len as usize)?),
+		COMPRESS_LZO  => Err(Error::Unsupported),
+		_             => Err(Error::Corrupted)
+	}
+}
+
+/// Copies the part of `src`, which starts at file offset `src_offset`, that overlaps the
+/// buffer at file offset `offset`.
+fn copy_overlap(buf: &mut [u8], offset: u64, src: &[u8], src_offset: u64) {
+	let start = src_offset.max(offset);
+	let end = (src_offset + src.len() as u64).min(offset + buf.len() as u64);
+	if start < end {
+		buf[(start - offset) as usize..(end - offset) as usize]
+			.copy_from_slice(&src[(start - src_offset) as usize..(end - src_offset) as usize]);
+	}
+}
+
+fn file_type(mode: u32) -> u8 {
+	match mode & S_IFMT {
+		S_IFDIR  => FT_DIR,
+		S_IFREG  => FT_REG_FILE,
+		S_IFLNK  => FT_SYMLINK,
+		0o020000 => FT_CHRDEV,
+		0o060000 => FT_BLKDEV,
+		0o010000 => FT_FIFO,
+		0o140000 => FT_SOCK,
+		_        => FT_UNKNOWN
+	}
+}
+
+/// Splits a path into the parent directory and the last component.
+fn split(path: &str) -> (&str, &str) {
+	let path = path.trim_end_matches('/');
+	match path.rfind('/') {
+		Some(i) => (&path[..i], &path[i + 1..]),
+		None    => ("", path)
+	}
+}
+
+fn read_bytes<D: BlockDevice>(dev: &mut D, offset: u64, buf: &mut [u8]) -> Result<()> {
+	let block_size = dev.block_size();
+	let skip = (offset % block_size as u64) as usize;
+	let blocks = (skip + buf.len() + block_size - 1) / block_size;
+	let mut tmp = block::alloc_buffer(dev, blocks)?;
+	dev.read(offset / block_size as u64, &mut tmp)?;
+	buf.copy_from_slice(&tmp[skip..skip + buf.len()]);
+	Ok(())
+}
@@ -0,0 +1,643 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Btrfs, the copy-on-write B-tree file system of Linux.
+//!
+//! `FileSystem` gives access to the files of the top level subvolume by their path. All
+//! metadata lives in B-trees whose nodes are addressed by logical addresses, which the
+//! chunk tree maps to device offsets. Reads verify the checksums of nodes and data and
+//! fall back to the second copy of DUP and RAID1 chunks. Writes never overwrite a block
+//! the last committed transaction refers to, `FileSystem::sync` commits the changes by
+//! writing the superblock.
+//!
+//! Only file systems on a single device are supported. Writes additionally require skinny
+//! metadata, the free space tree and no quota or log tree, which is what current versions
+//! of `mkfs.btrfs` create.
+
+mod chunk;
+mod tree;
+mod extent;
+mod fs;
+
+pub use {chunk::*, tree::*, fs::*};
+
+use crate::{block, compress};
+
+/// Device offset of the primary superblock, mirrors follow at 64 MiB and 256 GiB
+pub const SUPER_OFFSET: u64 = 0x1_0000;
+pub const SUPER_MIRRORS: [u64; 3] = [SUPER_OFFSET, 0x400_0000, 0x40_0000_0000];
+pub const SUPER_SIZE: usize = 4096;
+/// `_BHRfS_M`
+pub const MAGIC: u64 = 0x4D5F_5366_5248_425F;
+
+pub const CSUM_SIZE: usize = 32;
+pub const CSUM_CRC32C:   u16 = 0;
+pub const CSUM_XXHASH:   u16 = 1;
+pub const CSUM_SHA256:   u16 = 2;
+pub const CSUM_BLAKE2:   u16 = 3;
+
+pub const INCOMPAT_MIXED_BACKREF:     u64 = 1 << 0;
+pub const INCOMPAT_DEFAULT_SUBVOL:    u64 = 1 << 1;
+pub const INCOMPAT_MIXED_GROUPS:      u64 = 1 << 2;
+pub const INCOMPAT_COMPRESS_LZO:      u64 = 1 << 3;
+pub const INCOMPAT_COMPRESS_ZSTD:     u64 = 1 << 4;
+pub const INCOMPAT_BIG_METADATA:      u64 = 1 << 5;
+pub const INCOMPAT_EXTENDED_IREF:     u64 = 1 << 6;
+pub const INCOMPAT_RAID56:            u64 = 1 << 7;
+pub const INCOMPAT_SKINNY_METADATA:   u64 = 1 << 8;
+pub const INCOMPAT_NO_HOLES:          u64 = 1 << 9;
+pub const INCOMPAT_METADATA_UUID:     u64 = 1 << 10;
+pub const INCOMPAT_RAID1C34:          u64 = 1 << 11;
+pub const INCOMPAT_ZONED:             u64 = 1 << 12;
+pub const INCOMPAT_EXTENT_TREE_V2:    u64 = 1 << 13;
+pub const INCOMPAT_RAID_STRIPE_TREE:  u64 = 1 << 14;
+pub const INCOMPAT_SIMPLE_QUOTA:      u64 = 1 << 16;
+/// Incompatible features this implementation understands
+pub const INCOMPAT_SUPPORTED: u64 = INCOMPAT_MIXED_BACKREF | INCOMPAT_DEFAULT_SUBVOL
+	| INCOMPAT_COMPRESS_LZO | INCOMPAT_COMPRESS_ZSTD | INCOMPAT_BIG_METADATA
+	| INCOMPAT_EXTENDED_IREF | INCOMPAT_RAID56 | INCOMPAT_SKINNY_METADATA | INCOMPAT_NO_HOLES
+	| INCOMPAT_METADATA_UUID | INCOMPAT_RAID1C34;
+
+pub const COMPAT_RO_FREE_SPACE_TREE:       u64 = 1 << 0;
+pub const COMPAT_RO_FREE_SPACE_TREE_VALID: u64 = 1 << 1;
+pub const COMPAT_RO_VERITY:                u64 = 1 << 2;
+pub const COMPAT_RO_BLOCK_GROUP_TREE:      u64 = 1 << 3;
+
+// tree object ids
+pub const ROOT_TREE_OBJECTID:        u64 = 1;
+pub const EXTENT_TREE_OBJECTID:      u64 = 2;
+pub const CHUNK_TREE_OBJECTID:       u64 = 3;
+pub const DEV_TREE_OBJECTID:         u64 = 4;
+pub const FS_TREE_OBJECTID:          u64 = 5;
+pub const ROOT_TREE_DIR_OBJECTID:    u64 = 6;
+pub const CSUM_TREE_OBJECTID:        u64 = 7;
+pub const QUOTA_TREE_OBJECTID:       u64 = 8;
+pub const UUID_TREE_OBJECTID:        u64 = 9;
+pub const FREE_SPACE_TREE_OBJECTID:  u64 = 10;
+pub const BLOCK_GROUP_TREE_OBJECTID: u64 = 11;
+pub const TREE_LOG_OBJECTID:         u64 = -6i64 as u64;
+pub const EXTENT_CSUM_OBJECTID:      u64 = -10i64 as u64;
+/// First object id of inodes and subvolumes
+pub const FIRST_FREE_OBJECTID:       u64 = 256;
+pub const LAST_FREE_OBJECTID:        u64 = -256i64 as u64;
+/// Object id of the chunk items in the chunk tree
+pub const FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+/// Object id of the device items in the chunk tree
+pub const DEV_ITEMS_OBJECTID:        u64 = 1;
+
+// key types
+pub const INODE_ITEM_KEY:        u8 = 1;
+pub const INODE_REF_KEY:         u8 = 12;
+pub const INODE_EXTREF_KEY:      u8 = 13;
+pub const XATTR_ITEM_KEY:        u8 = 24;
+pub const ORPHAN_ITEM_KEY:       u8 = 48;
+pub const DIR_ITEM_KEY:          u8 = 84;
+pub const DIR_INDEX_KEY:         u8 = 96;
+pub const EXTENT_DATA_KEY:       u8 = 108;
+pub const EXTENT_CSUM_KEY:       u8 = 128;
+pub const ROOT_ITEM_KEY:         u8 = 132;
+pub const ROOT_BACKREF_KEY:      u8 = 144;
+pub const ROOT_REF_KEY:          u8 = 156;
+pub const EXTENT_ITEM_KEY:       u8 = 168;
+pub const METADATA_ITEM_KEY:     u8 = 169;
+pub const TREE_BLOCK_REF_KEY:    u8 = 176;
+pub const EXTENT_DATA_REF_KEY:   u8 = 178;
+pub const SHARED_BLOCK_REF_KEY:  u8 = 182;
+pub const SHARED_DATA_REF_KEY:   u8 = 184;
+pub const BLOCK_GROUP_ITEM_KEY:  u8 = 192;
+pub const FREE_SPACE_INFO_KEY:   u8 = 198;
+pub const FREE_SPACE_EXTENT_KEY: u8 = 199;
+pub const FREE_SPACE_BITMAP_KEY: u8 = 200;
+pub const DEV_EXTENT_KEY:        u8 = 204;
+pub const DEV_ITEM_KEY:          u8 = 216;
+pub const CHUNK_ITEM_KEY:        u8 = 228;
+
+// block group and chunk types
+pub const BLOCK_GROUP_DATA:     u64 = 1 << 0;
+pub const BLOCK_GROUP_SYSTEM:   u64 = 1 << 1;
+pub const BLOCK_GROUP_METADATA: u64 = 1 << 2;
+pub const BLOCK_GROUP_RAID0:    u64 = 1 << 3;
+pub const BLOCK_GROUP_RAID1:    u64 = 1 << 4;
+pub const BLOCK_GROUP_DUP:      u64 = 1 << 5;
+pub const BLOCK_GROUP_RAID10:   u64 = 1 << 6;
+pub const BLOCK_GROUP_RAID5:    u64 = 1 << 7;
+pub const BLOCK_GROUP_RAID6:    u64 = 1 << 8;
+pub const BLOCK_GROUP_RAID1C3:  u64 = 1 << 9;
+pub const BLOCK_GROUP_RAID1C4:  u64 = 1 << 10;
+pub const BLOCK_GROUP_TYPE_MASK: u64 = BLOCK_GROUP_DATA | BLOCK_GROUP_SYSTEM | BLOCK_GROUP_METADATA;
+pub const BLOCK_GROUP_PROFILE_MASK: u64 = BLOCK_GROUP_RAID0 | BLOCK_GROUP_RAID1 | BLOCK_GROUP_DUP
+	| BLOCK_GROUP_RAID10 | BLOCK_GROUP_RAID5 | BLOCK_GROUP_RAID6 | BLOCK_GROUP_RAID1C3
+	| BLOCK_GROUP_RAID1C4;
+
+pub const HEADER_FLAG_WRITTEN: u64 = 1 << 0;
+pub const HEADER_FLAG_RELOC:   u64 = 1 << 1;
+/// `Header::flags` bits 56 to 63 hold the back reference revision
+pub const MIXED_BACKREF_REV:   u64 = 1 << 56;
+
+pub const EXTENT_FLAG_DATA:       u64 = 1 << 0;
+pub const EXTENT_FLAG_TREE_BLOCK: u64 = 1 << 1;
+pub const BLOCK_FLAG_FULL_BACKREF: u64 = 1 << 8;
+
+pub const FREE_SPACE_USING_BITMAPS: u32 = 1 << 0;
+
+pub const FILE_EXTENT_INLINE:   u8 = 0;
+pub const FILE_EXTENT_REG:      u8 = 1;
+pub const FILE_EXTENT_PREALLOC: u8 = 2;
+
+pub const COMPRESS_NONE: u8 = 0;
+pub const COMPRESS_ZLIB: u8 = 1;
+pub const COMPRESS_LZO:  u8 = 2;
+pub const COMPRESS_ZSTD: u8 = 3;
+
+// `DirItem::kind`
+pub const FT_UNKNOWN:  u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR:      u8 = 2;
+pub const FT_CHRDEV:   u8 = 3;
+pub const FT_BLKDEV:   u8 = 4;
+pub const FT_FIFO:     u8 = 5;
+pub const FT_SOCK:     u8 = 6;
+pub const FT_SYMLINK:  u8 = 7;
+pub const FT_XATTR:    u8 = 8;
+
+// `InodeItem::flags`
+pub const INODE_NODATASUM:  u64 = 1 << 0;
+pub const INODE_NODATACOW:  u64 = 1 << 1;
+pub const INODE_READONLY:   u64 = 1 << 2;
+pub const INODE_NOCOMPRESS: u64 = 1 << 3;
+pub const INODE_PREALLOC:   u64 = 1 << 4;
+pub const INODE_COMPRESS:   u64 = 1 << 11;
+
+pub const S_IFMT:  u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+/// Largest name of a directory entry
+pub const NAME_MAX: usize = 255;
+/// Largest extent the kernel creates, longer writes are split
+pub const MAX_EXTENT_SIZE: u64 = 128 << 20;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// Reading or writing the device failed
+	Block(block::Error),
+	/// The device doesn't contain a valid superblock
+	NotBtrfs,
+	/// A checksum doesn't match or a tree is malformed
+	Corrupted,
+	/// The file system uses a feature that isn't implemented, or one that prevents writes
+	Unsupported,
+	NotFound,
+	NotADirectory,
+	IsADirectory,
+	Exists,
+	/// The directory to remove isn't empty
+	NotEmpty,
+	/// The name is empty, too long or contains `/` or NUL
+	InvalidName,
+	NoSpace
+}
+
+impl From<block::Error> for Error {
+	fn from(e: block::Error) -> Self {
+		Self::Block(e)
+	}
+}
+
+impl From<compress::Error> for Error {
+	fn from(e: compress::Error) -> Self {
+		match e {
+			compress::Error::Unsupported => Self::Unsupported,
+			_                            => Self::Corrupted
+		}
+	}
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The key of an item, items are sorted by object id, type and offset.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Key {
+	pub objectid: u64,
+	pub kind:     u8,
+	pub offset:   u64
+}
+
+impl Key {
+	pub const MIN: Self = Self::new(0, 0, 0);
+	pub const MAX: Self = Self::new(u64::MAX, u8::MAX, u64::MAX);
+
+	pub const fn new(objectid: u64, kind: u8, offset: u64) -> Self {
+		Self { objectid, kind, offset }
+	}
+}
+
+impl From<DiskKey> for Key {
+	fn from(key: DiskKey) -> Self {
+		Self::new(key.objectid, key.kind, key.offset)
+	}
+}
+
+impl From<Key> for DiskKey {
+	fn from(key: Key) -> Self {
+		Self { objectid: key.objectid, kind: key.kind, offset: key.offset }
+	}
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DiskKey {
+	pub objectid: u64,
+	pub kind:     u8,
+	pub offset:   u64
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Timespec {
+	pub sec:  u64,
+	pub nsec: u32
+}
+
+/// The superblock, its checksum covers everything after `csum`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct Superblock {
+	pub csum:                  [u8; CSUM_SIZE],
+	pub fsid:                  [u8; 16],
+	/// Device offset of this copy
+	pub bytenr:                u64,
+	pub flags:                 u64,
+	pub magic:                 u64,
+	/// Transaction id of the last commit
+	pub generation:            u64,
+	/// Logical address of the root tree root
+	pub root:                  u64,
+	pub chunk_root:            u64,
+	pub log_root:              u64,
+	pub _unused_log_root_transid: u64,
+	pub total_bytes:           u64,
+	pub bytes_used:            u64,
+	pub root_dir_objectid:     u64,
+	pub num_devices:           u64,
+	pub sectorsize:            u32,
+	pub nodesize:              u32,
+	pub _unused_leafsize:      u32,
+	pub stripesize:            u32,
+	/// Length of the used part of `sys_chunk_array`
+	pub sys_chunk_array_size:  u32,
+	pub chunk_root_generation: u64,
+	pub compat_flags:          u64,
+	pub compat_ro_flags:       u64,
+	pub incompat_flags:        u64,
+	pub csum_type:             u16,
+	pub root_level:            u8,
+	pub chunk_root_level:      u8,
+	pub log_root_level:        u8,
+	pub dev_item:              DevItem,
+	pub label:                 [u8; 256],
+	pub cache_generation:      u64,
+	pub uuid_tree_generation:  u64,
+	/// The fsid in tree block headers if `INCOMPAT_METADATA_UUID` is set
+	pub metadata_uuid:         [u8; 16],
+	pub nr_global_roots:       u64,
+	pub _res0:                 [u64; 27],
+	/// Key and chunk item pairs of the system chunks, needed to read the chunk tree
+	pub sys_chunk_array:       [u8; 2048],
+	pub super_roots:           [RootBackup; 4],
+	pub _res1:                 [u8; 565]
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct DevItem {
+	pub devid:        u64,
+	pub total_bytes:  u64,
+	pub bytes_used:   u64,
+	pub io_align:     u32,
+	pub io_width:     u32,
+	pub sector_size:  u32,
+	pub kind:         u64,
+	pub generation:   u64,
+	pub start_offset: u64,
+	pub dev_group:    u32,
+	pub seek_speed:   u8,
+	pub bandwidth:    u8,
+	pub uuid:         [u8; 16],
+	pub fsid:         [u8; 16]
+}
+
+/// Tree roots of one of the last four commits.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct RootBackup {
+	pub tree_root:          u64,
+	pub tree_root_gen:      u64,
+	pub chunk_root:         u64,
+	pub chunk_root_gen:     u64,
+	pub extent_root:        u64,
+	pub extent_root_gen:    u64,
+	pub fs_root:            u64,
+	pub fs_root_gen:        u64,
+	pub dev_root:           u64,
+	pub dev_root_gen:       u64,
+	pub csum_root:          u64,
+	pub csum_root_gen:      u64,
+	pub total_bytes:        u64,
+	pub bytes_used:         u64,
+	pub num_devices:        u64,
+	pub _res0:              [u64; 4],
+	pub tree_root_level:    u8,
+	pub chunk_root_level:   u8,
+	pub extent_root_level:  u8,
+	pub fs_root_level:      u8,
+	pub dev_root_level:     u8,
+	pub csum_root_level:    u8,
+	pub _res1:              [u8; 10]
+}
+
+/// Header of every tree node, its checksum covers the rest of the node.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Header {
+	pub csum:            [u8; CSUM_SIZE],
+	pub fsid:            [u8; 16],
+	/// Logical address of the node
+	pub bytenr:          u64,
+	pub flags:           u64,
+	pub chunk_tree_uuid: [u8; 16],
+	pub generation:      u64,
+	/// Object id of the tree the node belongs to
+	pub owner:           u64,
+	pub nritems:         u32,
+	/// Zero for leaves
+	pub level:           u8
+}
+
+/// An item of a leaf, follows the header. `offset` is relative to the end of the header,
+/// the data of the first item is at the end of the node.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Item {
+	pub key:    DiskKey,
+	pub offset: u32,
+	pub size:   u32
+}
+
+/// A child pointer of an internal node, `key` is the first key of the child.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KeyPtr {
+	pub key:        DiskKey,
+	pub blockptr:   u64,
+	pub generation: u64
+}
+
+/// Key `(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY, logical)`, `num_stripes` `Stripe`s follow.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Chunk {
+	pub length:      u64,
+	pub owner:       u64,
+	pub stripe_len:  u64,
+	/// `BLOCK_GROUP_*` type and profile
+	pub kind:        u64,
+	pub io_align:    u32,
+	pub io_width:    u32,
+	pub sector_size: u32,
+	pub num_stripes: u16,
+	pub sub_stripes: u16
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stripe {
+	pub devid:    u64,
+	/// Device offset
+	pub offset:   u64,
+	pub dev_uuid: [u8; 16]
+}
+
+/// Key `(tree, ROOT_ITEM_KEY, 0)` in the root tree.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct RootItem {
+	pub inode:         InodeItem,
+	pub generation:    u64,
+	/// Inode of the top directory of a subvolume
+	pub root_dirid:    u64,
+	pub bytenr:        u64,
+	pub byte_limit:    u64,
+	pub bytes_used:    u64,
+	pub last_snapshot: u64,
+	pub flags:         u64,
+	pub refs:          u32,
+	pub drop_progress: DiskKey,
+	pub drop_level:    u8,
+	pub level:         u8,
+	/// Equals `generation` if the fields after it are valid
+	pub generation_v2: u64,
+	pub uuid:          [u8; 16],
+	pub parent_uuid:   [u8; 16],
+	pub received_uuid: [u8; 16],
+	pub ctransid:      u64,
+	pub otransid:      u64,
+	pub stransid:      u64,
+	pub rtransid:      u64,
+	pub ctime:         Timespec,
+	pub otime:         Timespec,
+	pub stime:         Timespec,
+	pub rtime:         Timespec,
+	pub _res0:         [u64; 8]
+}
+
+/// Key `(inode, INODE_ITEM_KEY, 0)`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct InodeItem {
+	pub generation:  u64,
+	pub transid:     u64,
+	pub size:        u64,
+	/// Bytes of the extents referenced by the file
+	pub nbytes:      u64,
+	pub block_group: u64,
+	pub nlink:       u32,
+	pub uid:         u32,
+	pub gid:         u32,
+	pub mode:        u32,
+	pub rdev:        u64,
+	pub flags:       u64,
+	pub sequence:    u64,
+	pub _res0:       [u64; 4],
+	pub atime:       Timespec,
+	pub ctime:       Timespec,
+	pub mtime:       Timespec,
+	pub otime:       Timespec
+}
+
+/// Key `(inode, INODE_REF_KEY, parent)`, the name follows.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct InodeRef {
+	/// Offset of the `DIR_INDEX_KEY` item of the entry
+	pub index:    u64,
+	pub name_len: u16
+}
+
+/// Key `(parent, DIR_ITEM_KEY, name_hash(name))` or `(parent, DIR_INDEX_KEY, index)`, the
+/// name and `data_len` bytes of data follow. Entries with colliding hashes share an item.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DirItem {
+	/// Key of the inode item, or of the root item of a subvolume
+	pub location: DiskKey,
+	pub transid:  u64,
+	pub data_len: u16,
+	pub name_len: u16,
+	/// `FT_*`
+	pub kind:     u8
+}
+
+/// Key `(inode, EXTENT_DATA_KEY, file offset)`. Inline extents store their data after
+/// `kind` instead of the remaining fields.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FileExtentItem {
+	pub generation:     u64,
+	/// Size of the decompressed extent
+	pub ram_bytes:      u64,
+	pub compression:    u8,
+	pub encryption:     u8,
+	pub other_encoding: u16,
+	pub kind:           u8,
+	/// Logical address of the extent, zero for holes
+	pub disk_bytenr:    u64,
+	pub disk_num_bytes: u64,
+	/// Offset into the decompressed extent
+	pub offset:         u64,
+	/// Bytes of the file covered by the item
+	pub num_bytes:      u64
+}
+
+impl FileExtentItem {
+	/// Size of the fields stored for inline extents
+	pub const INLINE_SIZE: usize = 21;
+}
+
+/// Key `(bytenr, EXTENT_ITEM_KEY, length)` or, for tree blocks with skinny metadata,
+/// `(bytenr, METADATA_ITEM_KEY, level)`. Inline back references follow.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ExtentItem {
+	pub refs:       u64,
+	pub generation: u64,
+	pub flags:      u64
+}
+
+/// An inline `EXTENT_DATA_REF_KEY` back reference, also the data of the keyed item.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ExtentDataRef {
+	pub root:     u64,
+	pub objectid: u64,
+	/// File offset the extent starts at, i.e. key offset minus extent offset
+	pub offset:   u64,
+	pub count:    u32
+}
+
+/// Key `(logical, BLOCK_GROUP_ITEM_KEY, length)`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BlockGroupItem {
+	pub used:           u64,
+	pub chunk_objectid: u64,
+	pub flags:          u64
+}
+
+/// Key `(block group, FREE_SPACE_INFO_KEY, length)`, followed by the block group's
+/// `(start, FREE_SPACE_EXTENT_KEY, length)` items, which have no data.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FreeSpaceInfo {
+	pub extent_count: u32,
+	pub flags:        u32
+}
+
+/// Key `(devid, DEV_EXTENT_KEY, device offset)`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DevExtent {
+	pub chunk_tree:      u64,
+	pub chunk_objectid:  u64,
+	pub chunk_offset:    u64,
+	pub length:          u64,
+	pub chunk_tree_uuid: [u8; 16]
+}
+
+/// Hash of a name in the offset of `DIR_ITEM_KEY`, CRC32C seeded with `!1` and without
+/// the final inversion.
+pub fn name_hash(name: &[u8]) -> u64 {
+	!crate::crc::crc32c_update(1, name) as u64
+}
+
+/// Hash of an `ExtentDataRef` in the offset of `EXTENT_DATA_REF_KEY`, inline references are
+/// sorted by it in descending order.
+pub fn extent_data_ref_hash(root: u64, objectid: u64, offset: u64) -> u64 {
+	let raw = |crc: u32, v: u64| !crate::crc::crc32c_update(!crc, &v.to_le_bytes());
+	let high = raw(!0, root);
+	let low = raw(raw(!0, objectid), offset);
+	((high as u64) << 31) ^ low as u64
+}
+
+/// Checksum of `data` as stored in nodes, superblocks and the checksum tree.
+pub fn checksum(kind: u16, data: &[u8]) -> [u8; CSUM_SIZE] {
+	let mut csum = [0u8; CSUM_SIZE];
+	match kind {
+		CSUM_CRC32C => csum[..4].copy_from_slice(&crate::crc::crc32c(data).to_le_bytes()),
+		CSUM_XXHASH => csum[..8].copy_from_slice(&crate::crc::xxhash64(data, 0).to_le_bytes()),
+		_           => unreachable!()
+	}
+	csum
+}
+
+/// Bytes of a checksum of the type.
+pub fn checksum_size(kind: u16) -> Option<usize> {
+	match kind {
+		CSUM_CRC32C => Some(4),
+		CSUM_XXHASH => Some(8),
+		_           => None
+	}
+}
+
+pub(crate) fn read_struct<T: Copy>(bytes: &[u8], offset: usize) -> Result<T> {
+	if offset + core::mem::size_of::<T>() > bytes.len() {
+		return Err(Error::Corrupted);
+	}
+	// SAFETY: the structs are packed and the range was checked
+	Ok(unsafe { (bytes.as_ptr().add(offset) as *const T).read_unaligned() })
+}
+
+pub(crate) fn struct_bytes<T: Copy>(value: &T) -> &[u8] {
+	// SAFETY: the structs are packed and have no padding
+	unsafe { core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>()) }
+}
+
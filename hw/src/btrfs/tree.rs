@@ -0,0 +1,433 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Tree nodes, searching trees and copy-on-write modification of trees.
+
+use {
+	super::*,
+	crate::block::BlockDevice,
+	alloc::{vec, vec::Vec}
+};
+
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+const ITEM_SIZE: usize = core::mem::size_of::<Item>();
+const PTR_SIZE: usize = core::mem::size_of::<KeyPtr>();
+/// Clean nodes kept in memory, the cache is emptied when it's full
+const CACHE_NODES: usize = 256;
+
+/// A parsed tree node.
+#[derive(Clone, Debug)]
+pub struct Node {
+	pub header: Header,
+	/// Keys and data of the items of a leaf
+	pub items:  Vec<(Key, Vec<u8>)>,
+	/// Keys, addresses and generations of the children of an internal node
+	pub ptrs:   Vec<(Key, u64, u64)>
+}
+
+impl Node {
+	/// Parses a node, the checksum must have been verified.
+	pub fn parse(bytes: &[u8]) -> Result<Self> {
+		let header = read_struct::<Header>(bytes, 0)?;
+		let count = header.nritems as usize;
+		let mut node = Self { header, items: Vec::new(), ptrs: Vec::new() };
+		if header.level == 0 {
+			let data_start = HEADER_SIZE + count * ITEM_SIZE;
+			for i in 0..count {
+				let item = read_struct::<Item>(bytes, HEADER_SIZE + i * ITEM_SIZE)?;
+				let start = HEADER_SIZE + item.offset as usize;
+				let end = start + item.size as usize;
+				if start < data_start || end > bytes.len() {
+					return Err(Error::Corrupted);
+				}
+				node.items.push((item.key.into(), bytes[start..end].to_vec()));
+			}
+		} else {
+			if count == 0 || HEADER_SIZE + count * PTR_SIZE > bytes.len() {
+				return Err(Error::Corrupted);
+			}
+			for i in 0..count {
+				let ptr = read_struct::<KeyPtr>(bytes, HEADER_SIZE + i * PTR_SIZE)?;
+				node.ptrs.push((ptr.key.into(), ptr.blockptr, ptr.generation));
+			}
+		}
+
+		let sorted = match header.level {
+			0 => node.items.windows(2).all(|w| w[0].0 < w[1].0),
+			_ => node.ptrs.windows(2).all(|w| w[0].0 < w[1].0)
+		};
+		match sorted {
+			true  => Ok(node),
+			false => Err(Error::Corrupted)
+		}
+	}
+
+	/// Serializes the node and computes its checksum.
+	pub fn to_bytes(&self, nodesize: usize, csum_type: u16) -> Vec<u8> {
+		let mut buf = vec![0u8; nodesize];
+		let mut header = self.header;
+		if self.is_leaf() {
+			header.nritems = self.items.len() as u32;
+			let mut end = nodesize;
+			for (i, (key, data)) in self.items.iter().enumerate() {
+				end -= data.len();
+				buf[end..end + data.len()].copy_from_slice(data);
+				let item = Item { key: (*key).into(), offset: (end - HEADER_SIZE) as u32, size: data.len() as u32 };
+				buf[HEADER_SIZE + i * ITEM_SIZE..][..ITEM_SIZE].copy_from_slice(struct_bytes(&item));
+			}
+		} else {
+			header.nritems = self.ptrs.len() as u32;
+			for (i, (key, blockptr, generation)) in self.ptrs.iter().enumerate() {
+				let ptr = KeyPtr { key: (*key).into(), blockptr: *blockptr, generation: *generation };
+				buf[HEADER_SIZE + i * PTR_SIZE..][..PTR_SIZE].copy_from_slice(struct_bytes(&ptr));
+			}
+		}
+		buf[..HEADER_SIZE].copy_from_slice(struct_bytes(&header));
+		let csum = checksum(csum_type, &buf[CSUM_SIZE..]);
+		buf[..CSUM_SIZE].copy_from_slice(&csum);
+		buf
+	}
+
+	pub fn is_leaf(&self) -> bool {
+		self.header.level == 0
+	}
+
+	pub fn len(&self) -> usize {
+		match self.is_leaf() {
+			true  => self.items.len(),
+			false => self.ptrs.len()
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub fn first_key(&self) -> Option<Key> {
+		match self.is_leaf() {
+			true  => self.items.first().map(|(key, _)| *key),
+			false => self.ptrs.first().map(|(key, ..)| *key)
+		}
+	}
+
+	/// Bytes used after the header.
+	pub fn used(&self) -> usize {
+		match self.is_leaf() {
+			true  => self.items.iter().map(|(_, data)| ITEM_SIZE + data.len()).sum(),
+			false => self.ptrs.len() * PTR_SIZE
+		}
+	}
+
+	/// Index of the child whose key range contains the key, if any.
+	fn child(&self, key: &Key) -> Option<usize> {
+		self.ptrs.partition_point(|(k, ..)| k <= key).checked_sub(1)
+	}
+
+	/// Splits a node that doesn't fit into `nodesize` into nodes of about equal size.
+	fn split(mut self, nodesize: usize) -> Vec<Node> {
+		let capacity = nodesize - HEADER_SIZE;
+		let used = self.used();
+		if used <= capacity {
+			return vec![self];
+		}
+
+		let parts = (used + capacity - 1) / capacity + 1;
+		let limit = (used + parts - 1) / parts;
+		let mut nodes = Vec::new();
+		let empty = Node { header: self.header, items: Vec::new(), ptrs: Vec::new() };
+		let mut current = empty.clone();
+		let mut size = 0;
+		if self.is_leaf() {
+			for (key, data) in self.items.drain(..) {
+				let cost = ITEM_SIZE + data.len();
+				if size != 0 && size + cost > limit {
+					nodes.push(core::mem::replace(&mut current, empty.clone()));
+					size = 0;
+				}
+				size += cost;
+				current.items.push((key, data));
+			}
+		} else {
+			for ptr in self.ptrs.drain(..) {
+				if size != 0 && size + PTR_SIZE > limit {
+					nodes.push(core::mem::replace(&mut current, empty.clone()));
+					size = 0;
+				}
+				size += PTR_SIZE;
+				current.ptrs.push(ptr);
+			}
+		}
+		nodes.push(current);
+		nodes
+	}
+}
+
+/// Verifies the checksum of a node or superblock.
+pub(super) fn verify(bytes: &[u8], csum_type: u16, csum_size: usize) -> bool {
+	checksum(csum_type, &bytes[CSUM_SIZE..])[..csum_size] == bytes[..csum_size]
+}
+
+/// The root node of a tree.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) struct Root {
+	pub bytenr: u64,
+	pub level:  u8
+}
+
+enum Op<'a> {
+	Insert(&'a [u8]),
+	Update(&'a [u8]),
+	Delete
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+	/// Reads the node at the logical address and verifies it, using the next copy if one
+	/// is corrupted. Nodes changed in the current transaction are returned from memory.
+	pub(super) fn node(&mut self, bytenr: u64) -> Result<Node> {
+		if let Some(node) = self.dirty.get(&bytenr).or_else(|| self.cache.get(&bytenr)) {
+			return Ok(node.clone());
+		}
+
+		let mut buf = vec![0u8; self.nodesize];
+		for offset in self.chunks.map(bytenr, self.nodesize as u64)? {
+			self.read_bytes(offset, &mut buf)?;
+			let header = read_struct::<Header>(&buf, 0)?;
+			if !verify(&buf, self.csum_type, self.csum_size)
+				|| { header.bytenr } != bytenr
+				|| header.fsid != self.metadata_fsid {
+				continue;
+			}
+			if let Ok(node) = Node::parse(&buf) {
+				if self.cache.len() >= CACHE_NODES {
+					self.cache.clear();
+				}
+				self.cache.insert(bytenr, node.clone());
+				return Ok(node);
+			}
+		}
+		Err(Error::Corrupted)
+	}
+
+	pub(super) fn root(&self, tree: u64) -> Result<Root> {
+		self.roots.get(&tree).copied().ok_or(Error::Corrupted)
+	}
+
+	/// Reads a child of an internal node and checks its level.
+	fn child_node(&mut self, parent: &Node, index: usize) -> Result<Node> {
+		let node = self.node(parent.ptrs[index].1)?;
+		match node.header.level + 1 == parent.header.level {
+			true  => Ok(node),
+			false => Err(Error::Corrupted)
+		}
+	}
+
+	fn root_node(&mut self, tree: u64) -> Result<Node> {
+		let root = self.root(tree)?;
+		let node = self.node(root.bytenr)?;
+		match node.header.level == root.level {
+			true  => Ok(node),
+			false => Err(Error::Corrupted)
+		}
+	}
+
+	/// The data of the item with the key.
+	pub(super) fn search(&mut self, tree: u64, key: Key) -> Result<Option<Vec<u8>>> {
+		let mut node = self.root_node(tree)?;
+		while !node.is_leaf() {
+			match node.child(&key) {
+				Some(i) => node = self.child_node(&node, i)?,
+				None    => return Ok(None)
+			}
+		}
+		Ok(node.items.binary_search_by(|(k, _)| k.cmp(&key)).ok().map(|i| node.items.swap_remove(i).1))
+	}
+
+	/// The item with the largest key less than or equal to `key`.
+	pub(super) fn prev(&mut self, tree: u64, key: Key) -> Result<Option<(Key, Vec<u8>)>> {
+		let mut node = self.root_node(tree)?;
+		while !node.is_leaf() {
+			match node.child(&key) {
+				Some(i) => node = self.child_node(&node, i)?,
+				None    => return Ok(None)
+			}
+		}
+		let i = node.items.partition_point(|(k, _)| *k <= key);
+		Ok(i.checked_sub(1).map(|i| node.items.swap_remove(i)))
+	}
+
+	/// The items with keys in `min..=max`, in order.
+	pub(super) fn range(&mut self, tree: u64, min: Key, max: Key) -> Result<Vec<(Key, Vec<u8>)>> {
+		let mut items = Vec::new();
+		let mut stack = vec![self.root_node(tree)?];
+		while let Some(mut node) = stack.pop() {
+			if node.is_leaf() {
+				let start = node.items.partition_point(|(k, _)| *k < min);
+				let end = node.items.partition_point(|(k, _)| *k <= max);
+				items.extend(node.items.drain(start..end.max(start)));
+				continue;
+			}
+
+			let start = node.child(&min).unwrap_or(0);
+			let end = node.ptrs.partition_point(|(k, ..)| *k <= max);
+			for i in (start..end).rev() {
+				stack.push(self.child_node(&node, i)?);
+			}
+		}
+		Ok(items)
+	}
+
+	pub(super) fn insert(&mut self, tree: u64, key: Key, data: &[u8]) -> Result<()> {
+		self.modify(tree, key, Op::Insert(data))
+	}
+
+	/// Replaces the data of an item, its size may change.
+	pub(super) fn update(&mut self, tree: u64, key: Key, data: &[u8]) -> Result<()> {
+		self.modify(tree, key, Op::Update(data))
+	}
+
+	pub(super) fn delete(&mut self, tree: u64, key: Key) -> Result<()> {
+		self.modify(tree, key, Op::Delete)
+	}
+
+	fn modify(&mut self, tree: u64, key: Key, op: Op) -> Result<()> {
+		if !self.writable {
+			return Err(Error::Unsupported);
+		}
+		if let Op::Insert(data) | Op::Update(data) = op {
+			if ITEM_SIZE + data.len() > self.nodesize - HEADER_SIZE {
+				return Err(Error::NoSpace);
+			}
+		}
+
+		let path = self.cow_path(tree, key)?;
+		let leaf = self.dirty.get_mut(path.last().unwrap()).unwrap();
+		match (op, leaf.items.binary_search_by(|(k, _)| k.cmp(&key))) {
+			(Op::Insert(data), Err(i)) => leaf.items.insert(i, (key, data.to_vec())),
+			(Op::Update(data), Ok(i))  => leaf.items[i].1 = data.to_vec(),
+			(Op::Delete, Ok(i))        => drop(leaf.items.remove(i)),
+			(Op::Insert(_), Ok(_))     => return Err(Error::Exists),
+			(_, Err(_))                => return Err(Error::NotFound)
+		}
+		self.balance(tree, &path)
+	}
+
+	/// Copies the nodes from the root to the leaf that contains or would contain the key,
+	/// returns their new addresses.
+	fn cow_path(&mut self, tree: u64, key: Key) -> Result<Vec<u64>> {
+		let root = self.root(tree)?;
+		let mut bytenr = self.cow(tree, root.bytenr)?;
+		self.roots.insert(tree, Root { bytenr, ..root });
+
+		let mut path = vec![bytenr];
+		loop {
+			let node = &self.dirty[&bytenr];
+			if node.is_leaf() {
+				return Ok(path);
+			}
+
+			let i = node.child(&key).unwrap_or(0);
+			let child = self.cow(tree, node.ptrs[i].1)?;
+			let node = self.dirty.get_mut(&bytenr).unwrap();
+			node.ptrs[i].1 = child;
+			node.ptrs[i].2 = self.transid;
+			path.push(child);
+			bytenr = child;
+		}
+	}
+
+	/// Copies a node of the last transaction to a new block, returns its address.
+	fn cow(&mut self, tree: u64, bytenr: u64) -> Result<u64> {
+		if self.dirty.contains_key(&bytenr) {
+			return Ok(bytenr);
+		}
+
+		let mut node = self.node(bytenr)?;
+		self.check_exclusive(bytenr, node.header.level)?;
+		let new = self.alloc_tree_block(tree, node.header.level)?;
+		self.free_tree_block(bytenr, node.header.level);
+		node.header.bytenr = new;
+		node.header.generation = self.transid;
+		node.header.owner = tree;
+		node.header.flags = HEADER_FLAG_WRITTEN | MIXED_BACKREF_REV;
+		self.cache.remove(&bytenr);
+		self.dirty.insert(new, node);
+		Ok(new)
+	}
+
+	/// Splits overflowing nodes, removes empty ones and updates the keys of the parents
+	/// after the leaf at the end of the path changed.
+	fn balance(&mut self, tree: u64, path: &[u64]) -> Result<()> {
+		for depth in (0..path.len()).rev() {
+			let bytenr = path[depth];
+			let parent = depth.checked_sub(1).map(|d| path[d]);
+			let slot = parent.map(|p| self.dirty[&p].ptrs.iter().position(|ptr| ptr.1 == bytenr).unwrap());
+			let node = self.dirty.remove(&bytenr).unwrap();
+			let level = node.header.level;
+
+			if node.is_empty() {
+				match (parent, slot) {
+					(Some(parent), Some(slot)) => {
+						self.dirty.get_mut(&parent).unwrap().ptrs.remove(slot);
+						self.free_tree_block(bytenr, level);
+					}
+					_ => {
+						// an empty root is a leaf
+						let mut node = node;
+						node.header.level = 0;
+						node.ptrs.clear();
+						self.dirty.insert(bytenr, node);
+						self.roots.insert(tree, Root { bytenr, level: 0 });
+					}
+				}
+				continue;
+			}
+
+			let mut nodes = node.split(self.nodesize).into_iter();
+			let first = nodes.next().unwrap();
+			let mut ptrs = vec![(first.first_key().unwrap(), bytenr, self.transid)];
+			self.dirty.insert(bytenr, first);
+			for mut node in nodes {
+				let new = self.alloc_tree_block(tree, level)?;
+				node.header.bytenr = new;
+				ptrs.push((node.first_key().unwrap(), new, self.transid));
+				self.dirty.insert(new, node);
+			}
+
+			match (parent, slot) {
+				(Some(parent), Some(slot)) => {
+					self.dirty.get_mut(&parent).unwrap().ptrs.splice(slot..slot + 1, ptrs);
+				}
+				_ if ptrs.len() > 1 => {
+					let new = self.alloc_tree_block(tree, level + 1)?;
+					let mut header = self.dirty[&bytenr].header;
+					header.bytenr = new;
+					header.level = level + 1;
+					self.dirty.insert(new, Node { header, items: Vec::new(), ptrs });
+					self.roots.insert(tree, Root { bytenr: new, level: level + 1 });
+					// the new root may itself be too large
+					return self.balance(tree, &[new]);
+				}
+				_ => ()
+			}
+		}
+		Ok(())
+	}
+}
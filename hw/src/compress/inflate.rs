@@ -0,0 +1,289 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! DEFLATE (RFC 1951) and the zlib container (RFC 1950).
+
+use {super::*, alloc::vec::Vec};
+
+const MAX_BITS: usize = 15;
+const LENGTH_BASE: [u16; 29] = [
+	3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+	1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+	6145, 8193, 12289, 16385, 24577
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order of the code length code lengths of a dynamic block
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decompresses a zlib stream, i.e. a DEFLATE stream with a header and an Adler-32
+/// checksum, to at most `limit` bytes.
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+	let (cmf, flg) = match data {
+		[cmf, flg, ..] => (*cmf, *flg),
+		_              => return Err(Error::Corrupted)
+	};
+	if cmf & 0xF != 8 || cmf >> 4 > 7 || (cmf as u16 * 256 + flg as u16) % 31 != 0 {
+		return Err(Error::Corrupted);
+	} else if flg & 0x20 != 0 {
+		return Err(Error::Unsupported);
+	}
+
+	let mut out = Vec::new();
+	let len = inflate_into(&data[2..], &mut out, limit)?;
+	let adler = data.get(2 + len..6 + len).ok_or(Error::Corrupted)?;
+	match u32::from_be_bytes(adler.try_into().unwrap()) == adler32(&out) {
+		true  => Ok(out),
+		false => Err(Error::Corrupted)
+	}
+}
+
+/// Decompresses a raw DEFLATE stream to at most `limit` bytes.
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+	let mut out = Vec::new();
+	inflate_into(data, &mut out, limit)?;
+	Ok(out)
+}
+
+/// Appends the decompressed stream to `out`, returns the number of bytes consumed.
+fn inflate_into(data: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<usize> {
+	let mut bits = Bits { data, pos: 0, bit: 0 };
+	loop {
+		let last = bits.read(1)? == 1;
+		match bits.read(2)? {
+			0 => {
+				bits.align();
+				let header = bits.bytes(4)?;
+				let len = u16::from_le_bytes([header[0], header[1]]);
+				if len != !u16::from_le_bytes([header[2], header[3]]) {
+					return Err(Error::Corrupted);
+				}
+				let stored = bits.bytes(len as usize)?;
+				if out.len() + stored.len() > limit {
+					return Err(Error::TooLarge);
+				}
+				out.extend_from_slice(stored);
+			}
+			1 => {
+				let mut lengths = [0; 288 + 30];
+				lengths[..144].fill(8);
+				lengths[144..256].fill(9);
+				lengths[256..280].fill(7);
+				lengths[280..288].fill(8);
+				lengths[288..].fill(5);
+				codes(&mut bits, out, limit, &Huffman::new(&lengths[..288])?, &Huffman::new(&lengths[288..])?)?;
+			}
+			2 => {
+				let (lit, dist) = dynamic_tables(&mut bits)?;
+				codes(&mut bits, out, limit, &lit, &dist)?;
+			}
+			_ => return Err(Error::Corrupted)
+		}
+		if last {
+			bits.align();
+			return Ok(bits.pos);
+		}
+	}
+}
+
+fn dynamic_tables(bits: &mut Bits) -> Result<(Huffman, Huffman)> {
+	let nlen = bits.read(5)? as usize + 257;
+	let ndist = bits.read(5)? as usize + 1;
+	let ncode = bits.read(4)? as usize + 4;
+	if nlen > 286 || ndist > 30 {
+		return Err(Error::Corrupted);
+	}
+
+	let mut clens = [0; 19];
+	for i in CLEN_ORDER.iter().take(ncode) {
+		clens[*i] = bits.read(3)? as u8;
+	}
+	let clen = Huffman::new(&clens)?;
+
+	let mut lengths = [0u8; 286 + 30];
+	let mut i = 0;
+	while i < nlen + ndist {
+		let (value, repeat) = match clen.decode(bits)? {
+			sym @ 0..=15 => (sym as u8, 1),
+			16 => (*lengths[..i].last().ok_or(Error::Corrupted)?, 3 + bits.read(2)?),
+			17 => (0, 3 + bits.read(3)?),
+			18 => (0, 11 + bits.read(7)?),
+			_  => return Err(Error::Corrupted)
+		};
+		let end = i + repeat as usize;
+		lengths.get_mut(i..end).ok_or(Error::Corrupted)?.fill(value);
+		i = end;
+	}
+	if i != nlen + ndist || lengths[256] == 0 {
+		return Err(Error::Corrupted);
+	}
+	Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..nlen + ndist])?))
+}
+
+/// Decodes the literals and matches of a compressed block.
+fn codes(bits: &mut Bits, out: &mut Vec<u8>, limit: usize, lit: &Huffman, dist: &Huffman) -> Result<()> {
+	loop {
+		let sym = lit.decode(bits)? as usize;
+		if sym < 256 {
+			if out.len() == limit {
+				return Err(Error::TooLarge);
+			}
+			out.push(sym as u8);
+			continue;
+		} else if sym == 256 {
+			return Ok(());
+		}
+
+		let sym = sym - 257;
+		if sym >= 29 {
+			return Err(Error::Corrupted);
+		}
+		let len = LENGTH_BASE[sym] as usize + bits.read(LENGTH_EXTRA[sym])? as usize;
+		let sym = dist.decode(bits)? as usize;
+		if sym >= 30 {
+			return Err(Error::Corrupted);
+		}
+		let distance = DIST_BASE[sym] as usize + bits.read(DIST_EXTRA[sym])? as usize;
+		if distance > out.len() {
+			return Err(Error::Corrupted);
+		} else if out.len() + len > limit {
+			return Err(Error::TooLarge);
+		}
+		copy_match(out, distance, len);
+	}
+}
+
+/// Appends `len` bytes starting `distance` bytes before the end, the ranges may overlap.
+pub(super) fn copy_match(out: &mut Vec<u8>, distance: usize, len: usize) {
+	let start = out.len() - distance;
+	if distance >= len {
+		out.extend_from_within(start..start + len);
+	} else {
+		for i in 0..len {
+			out.push(out[start + i]);
+		}
+	}
+}
+
+fn adler32(data: &[u8]) -> u32 {
+	let (mut a, mut b) = (1u32, 0u32);
+	for chunk in data.chunks(5552) {
+		for byte in chunk {
+			a += *byte as u32;
+			b += a;
+		}
+		a %= 65521;
+		b %= 65521;
+	}
+	b << 16 | a
+}
+
+/// Reads a stream least significant bit first.
+struct Bits<'a> {
+	data: &'a [u8],
+	pos:  usize,
+	bit:  u8
+}
+
+impl<'a> Bits<'a> {
+	fn read(&mut self, count: u8) -> Result<u32> {
+		let mut value = 0;
+		for i in 0..count {
+			let byte = *self.data.get(self.pos).ok_or(Error::Corrupted)?;
+			value |= ((byte >> self.bit & 1) as u32) << i;
+			self.bit += 1;
+			if self.bit == 8 {
+				self.bit = 0;
+				self.pos += 1;
+			}
+		}
+		Ok(value)
+	}
+
+	fn align(&mut self) {
+		if self.bit != 0 {
+			self.bit = 0;
+			self.pos += 1;
+		}
+	}
+
+	fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+		let bytes = self.data.get(self.pos..self.pos + len).ok_or(Error::Corrupted)?;
+		self.pos += len;
+		Ok(bytes)
+	}
+}
+
+/// A canonical prefix code, decoded one bit at a time.
+struct Huffman {
+	/// Number of codes of each length
+	counts:  [u16; MAX_BITS + 1],
+	/// Symbols ordered by code
+	symbols: Vec<u16>
+}
+
+impl Huffman {
+	fn new(lengths: &[u8]) -> Result<Self> {
+		let mut counts = [0u16; MAX_BITS + 1];
+		for len in lengths {
+			counts[*len as usize] += 1;
+		}
+
+		// reject over-subscribed codes, incomplete ones are allowed
+		let mut left = 1i32;
+		for count in &counts[1..] {
+			left = (left << 1) - *count as i32;
+			if left < 0 {
+				return Err(Error::Corrupted);
+			}
+		}
+
+		let mut offsets = [0u16; MAX_BITS + 2];
+		for len in 1..=MAX_BITS {
+			offsets[len + 1] = offsets[len] + counts[len];
+		}
+		let mut symbols = alloc::vec![0; lengths.len()];
+		for (sym, len) in lengths.iter().enumerate().filter(|(_, len)| **len != 0) {
+			symbols[offsets[*len as usize] as usize] = sym as u16;
+			offsets[*len as usize] += 1;
+		}
+		counts[0] = 0;
+		Ok(Self { counts, symbols })
+	}
+
+	fn decode(&self, bits: &mut Bits) -> Result<u16> {
+		let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+		for count in &self.counts[1..] {
+			code |= bits.read(1)? as i32;
+			let count = *count as i32;
+			if code - first < count {
+				return Ok(self.symbols[(index + code - first) as usize]);
+			}
+			index += count;
+			first = (first + count) << 1;
+			code <<= 1;
+		}
+		Err(Error::Corrupted)
+	}
+}
@@ -0,0 +1,43 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Decompressors for the formats file systems store data in.
+//!
+//! All of them decompress a whole stream into a `Vec`, the caller passes the expected size,
+//! which is known from the on-disk metadata, as a limit.
+
+mod inflate;
+mod zstd;
+
+pub use {inflate::*, zstd::*};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// The stream is malformed or its checksum doesn't match
+	Corrupted,
+	/// The data decompresses to more than the limit
+	TooLarge,
+	/// The stream uses a feature that isn't implemented, e.g. a zstd dictionary
+	Unsupported
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
@@ -0,0 +1,617 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Zstandard frames (RFC 8878), without dictionaries.
+
+use {super::*, crate::crc::xxhash64, alloc::{vec, vec::Vec}};
+
+const MAGIC: u32 = 0xFD2F_B528;
+/// Magic of skippable frames, the low four bits are user defined
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const MAX_BLOCK_SIZE: usize = 128 << 10;
+
+const LL_BASE: [u32; 36] = [
+	0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64, 128, 256, 512,
+	1024, 2048, 4096, 8192, 16384, 32768, 65536
+];
+const LL_BITS: [u8; 36] = [
+	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16
+];
+const ML_BASE: [u32; 53] = [
+	3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+	33, 34, 35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027, 2051, 4099, 8195, 16387, 32771, 65539
+];
+const ML_BITS: [u8; 53] = [
+	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2,
+	2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16
+];
+
+/// The predefined distributions of the literal length, match length and offset codes
+const LL_DEFAULT: (u8, [i16; 36]) = (6, [
+	4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1, -1, -1, -1, -1
+]);
+const ML_DEFAULT: (u8, [i16; 53]) = (6, [
+	1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+	1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1
+]);
+const OF_DEFAULT: (u8, [i16; 29]) = (5, [
+	1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1
+]);
+
+/// Decompresses the zstd frames in `data` to at most `limit` bytes.
+pub fn zstd_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+	let mut out = Vec::new();
+	let mut pos = 0;
+	while pos < data.len() {
+		let magic = u32::from_le_bytes(data.get(pos..pos + 4).ok_or(Error::Corrupted)?.try_into().unwrap());
+		if magic & !0xF == SKIPPABLE_MAGIC {
+			let len = u32::from_le_bytes(data.get(pos + 4..pos + 8).ok_or(Error::Corrupted)?.try_into().unwrap());
+			pos += 8 + len as usize;
+		} else if magic == MAGIC {
+			pos += 4 + frame(&data[pos + 4..], &mut out, limit)?;
+		} else {
+			return Err(Error::Corrupted);
+		}
+	}
+	match pos == data.len() {
+		true  => Ok(out),
+		false => Err(Error::Corrupted)
+	}
+}
+
+/// Decompresses the first zstd frame in `data` to at most `limit` bytes and ignores what
+/// follows it, e.g. the zero padding of a compressed Btrfs extent.
+pub fn zstd_decompress_frame(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+	let mut out = Vec::new();
+	match data.get(..4) {
+		Some(magic) if u32::from_le_bytes(magic.try_into().unwrap()) == MAGIC => {
+			frame(&data[4..], &mut out, limit)?;
+			Ok(out)
+		}
+		_ => Err(Error::Corrupted)
+	}
+}
+
+/// Decodes a frame after its magic, returns the number of bytes consumed.
+fn frame(data: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<usize> {
+	let desc = *data.first().ok_or(Error::Corrupted)?;
+	let single_segment = desc & 0x20 != 0;
+	let checksum = desc & 0x04 != 0;
+	if desc & 0x08 != 0 {
+		return Err(Error::Corrupted);
+	}
+
+	let mut pos = 1 + !single_segment as usize;
+	let dict_len = [0, 1, 2, 4][(desc & 3) as usize];
+	let dict = data.get(pos..pos + dict_len).ok_or(Error::Corrupted)?;
+	if dict.iter().any(|b| *b != 0) {
+		return Err(Error::Unsupported);
+	}
+	pos += dict_len;
+
+	let size_len = [single_segment as usize, 2, 4, 8][(desc >> 6) as usize];
+	let size = data.get(pos..pos + size_len).ok_or(Error::Corrupted)?;
+	let size = size.iter().rev().fold(0u64, |v, b| v << 8 | *b as u64) + if size_len == 2 { 256 } else { 0 };
+	if size_len != 0 && size > (limit - out.len()) as u64 {
+		return Err(Error::TooLarge);
+	}
+	pos += size_len;
+
+	let start = out.len();
+	let mut state = State { rep: [1, 4, 8], huffman: None, ll: None, of: None, ml: None };
+	loop {
+		let header = data.get(pos..pos + 3).ok_or(Error::Corrupted)?;
+		let header = header[0] as usize | (header[1] as usize) << 8 | (header[2] as usize) << 16;
+		let (last, kind, len) = (header & 1 != 0, header >> 1 & 3, header >> 3);
+		pos += 3;
+
+		let block = data.get(pos..pos + if kind == 1 { 1 } else { len }).ok_or(Error::Corrupted)?;
+		if len > MAX_BLOCK_SIZE {
+			return Err(Error::Corrupted);
+		}
+		if kind != 2 && out.len() + len > limit {
+			return Err(Error::TooLarge);
+		}
+		match kind {
+			0 => out.extend_from_slice(block),
+			1 => out.resize(out.len() + len, block[0]),
+			2 => compressed_block(block, &mut state, out, start, limit)?,
+			_ => return Err(Error::Corrupted)
+		}
+		pos += block.len();
+		if last {
+			break;
+		}
+	}
+
+	if size_len != 0 && (out.len() - start) as u64 != size {
+		return Err(Error::Corrupted);
+	}
+	if checksum {
+		let sum = data.get(pos..pos + 4).ok_or(Error::Corrupted)?;
+		if u32::from_le_bytes(sum.try_into().unwrap()) != xxhash64(&out[start..], 0) as u32 {
+			return Err(Error::Corrupted);
+		}
+		pos += 4;
+	}
+	Ok(pos)
+}
+
+/// Tables and offsets that later blocks of a frame may refer to
+struct State {
+	rep:     [usize; 3],
+	huffman: Option<Huffman>,
+	ll:      Option<Fse>,
+	of:      Option<Fse>,
+	ml:      Option<Fse>
+}
+
+fn compressed_block(data: &[u8], state: &mut State, out: &mut Vec<u8>, start: usize, limit: usize) -> Result<()> {
+	let (literals, len) = literals(data, state)?;
+	let data = &data[len..];
+
+	// the number of sequences
+	let (count, mut pos) = match *data.first().ok_or(Error::Corrupted)? {
+		0           => (0, 1),
+		b @ 1..=127 => (b as usize, 1),
+		b @ 128..=254 => (((b as usize - 128) << 8) + *data.get(1).ok_or(Error::Corrupted)? as usize, 2),
+		255 => {
+			let b = data.get(1..3).ok_or(Error::Corrupted)?;
+			(b[0] as usize + ((b[1] as usize) << 8) + 0x7F00, 3)
+		}
+	};
+	if count == 0 {
+		if out.len() + literals.len() > limit {
+			return Err(Error::TooLarge);
+		}
+		out.extend_from_slice(&literals);
+		return Ok(());
+	}
+
+	let modes = *data.get(pos).ok_or(Error::Corrupted)?;
+	if modes & 3 != 0 {
+		return Err(Error::Corrupted);
+	}
+	pos += 1;
+	pos += table(&data[pos..], modes >> 6, &mut state.ll, &LL_DEFAULT.1, LL_DEFAULT.0, 9)?;
+	pos += table(&data[pos..], modes >> 4 & 3, &mut state.of, &OF_DEFAULT.1, OF_DEFAULT.0, 8)?;
+	pos += table(&data[pos..], modes >> 2 & 3, &mut state.ml, &ML_DEFAULT.1, ML_DEFAULT.0, 9)?;
+	let (ll, of, ml) = (state.ll.as_ref().unwrap(), state.of.as_ref().unwrap(), state.ml.as_ref().unwrap());
+
+	let mut bits = BackBits::new(&data[pos..])?;
+	let mut ll_state = bits.read(ll.log) as usize;
+	let mut of_state = bits.read(of.log) as usize;
+	let mut ml_state = bits.read(ml.log) as usize;
+	let mut lit = 0;
+	for i in 0..count {
+		let (ll_code, of_code, ml_code) = (ll.table[ll_state].symbol as usize, of.table[of_state].symbol, ml.table[ml_state].symbol as usize);
+		if ll_code >= LL_BASE.len() || ml_code >= ML_BASE.len() || of_code > 31 {
+			return Err(Error::Corrupted);
+		}
+
+		let value = (1usize << of_code) + bits.read(of_code) as usize;
+		let match_len = ML_BASE[ml_code] as usize + bits.read(ML_BITS[ml_code]) as usize;
+		let lit_len = LL_BASE[ll_code] as usize + bits.read(LL_BITS[ll_code]) as usize;
+
+		let rep = &mut state.rep;
+		let offset = match value {
+			4.. => {
+				*rep = [value - 3, rep[0], rep[1]];
+				rep[0]
+			}
+			_ => match value - (lit_len != 0) as usize {
+				0 => rep[0],
+				1 => {
+					*rep = [rep[1], rep[0], rep[2]];
+					rep[0]
+				}
+				2 => {
+					*rep = [rep[2], rep[0], rep[1]];
+					rep[0]
+				}
+				_ => {
+					*rep = [rep[0].checked_sub(1).filter(|o| *o != 0).ok_or(Error::Corrupted)?, rep[0], rep[1]];
+					rep[0]
+				}
+			}
+		};
+
+		if i + 1 < count {
+			ll_state = ll.next(ll_state, &mut bits);
+			ml_state = ml.next(ml_state, &mut bits);
+			of_state = of.next(of_state, &mut bits);
+		}
+		if bits.overflowed() {
+			return Err(Error::Corrupted);
+		}
+
+		let literal = literals.get(lit..lit + lit_len).ok_or(Error::Corrupted)?;
+		if out.len() + lit_len + match_len > limit {
+			return Err(Error::TooLarge);
+		}
+		out.extend_from_slice(literal);
+		lit += lit_len;
+		if offset > out.len() - start {
+			return Err(Error::Corrupted);
+		}
+		super::inflate::copy_match(out, offset, match_len);
+	}
+
+	if !bits.is_empty() {
+		return Err(Error::Corrupted);
+	}
+	if out.len() + literals.len() - lit > limit {
+		return Err(Error::TooLarge);
+	}
+	out.extend_from_slice(&literals[lit..]);
+	Ok(())
+}
+
+/// Decodes the literals section, returns the literals and the size of the section.
+fn literals(data: &[u8], state: &mut State) -> Result<(Vec<u8>, usize)> {
+	let header = *data.first().ok_or(Error::Corrupted)?;
+	let kind = header & 3;
+	let format = header >> 2 & 3;
+	let byte = |i: usize| data.get(i).map(|b| *b as usize).ok_or(Error::Corrupted);
+
+	if kind < 2 {
+		let (size, pos) = match format {
+			0 | 2 => (header as usize >> 3, 1),
+			1     => ((header as usize >> 4) + (byte(1)? << 4), 2),
+			_     => ((header as usize >> 4) + (byte(1)? << 4) + (byte(2)? << 12), 3)
+		};
+		if size > MAX_BLOCK_SIZE {
+			return Err(Error::Corrupted);
+		}
+		return match kind {
+			0 => Ok((data.get(pos..pos + size).ok_or(Error::Corrupted)?.to_vec(), pos + size)),
+			_ => Ok((vec![*data.get(pos).ok_or(Error::Corrupted)?; size], pos + 1))
+		};
+	}
+
+	let (streams, regenerated, compressed, mut pos) = match format {
+		0 | 1 => {
+			let v = header as usize >> 4 | byte(1)? << 4 | byte(2)? << 12;
+			(if format == 0 { 1 } else { 4 }, v & 0x3FF, v >> 10, 3)
+		}
+		2 => {
+			let v = header as usize >> 4 | byte(1)? << 4 | byte(2)? << 12 | byte(3)? << 20;
+			(4, v & 0x3FFF, v >> 14, 4)
+		}
+		_ => {
+			let v = header as usize >> 4 | byte(1)? << 4 | byte(2)? << 12 | byte(3)? << 20 | byte(4)? << 28;
+			(4, v & 0x3FFFF, v >> 18, 5)
+		}
+	};
+	if regenerated > MAX_BLOCK_SIZE {
+		return Err(Error::Corrupted);
+	}
+
+	let end = pos + compressed;
+	let data = data.get(..end).ok_or(Error::Corrupted)?;
+	if kind == 2 {
+		let (huffman, len) = Huffman::read(&data[pos..])?;
+		state.huffman = Some(huffman);
+		pos += len;
+	}
+	let huffman = state.huffman.as_ref().ok_or(Error::Corrupted)?;
+
+	let mut literals = Vec::with_capacity(regenerated);
+	if streams == 1 {
+		huffman.decode(&data[pos..], regenerated, &mut literals)?;
+	} else {
+		let jump = data.get(pos..pos + 6).ok_or(Error::Corrupted)?;
+		let sizes = [0, 2, 4].map(|i| u16::from_le_bytes([jump[i], jump[i + 1]]) as usize);
+		let per_stream = (regenerated + 3) / 4;
+		let mut start = pos + 6;
+		for (i, size) in sizes.iter().copied().chain([usize::MAX]).enumerate() {
+			let stream = match i {
+				3 => data.get(start..).ok_or(Error::Corrupted)?,
+				_ => data.get(start..start + size).ok_or(Error::Corrupted)?
+			};
+			let count = match i {
+				3 => regenerated.checked_sub(3 * per_stream).ok_or(Error::Corrupted)?,
+				_ => per_stream
+			};
+			huffman.decode(stream, count, &mut literals)?;
+			start += stream.len();
+		}
+	}
+	Ok((literals, end))
+}
+
+/// Reads the table of a sequence code with the given mode, returns the bytes consumed.
+fn table(data: &[u8], mode: u8, table: &mut Option<Fse>, default: &[i16], default_log: u8, max_log: u8) -> Result<usize> {
+	let (fse, len) = match mode {
+		0 => (Fse::new(default, default_log)?, 0),
+		1 => (Fse { log: 0, table: vec![FseEntry { symbol: *data.first().ok_or(Error::Corrupted)?, bits: 0, base: 0 }] }, 1),
+		2 => Fse::read(data, max_log, default.len() - 1)?,
+		_ => return table.is_some().then_some(0).ok_or(Error::Corrupted)
+	};
+	*table = Some(fse);
+	Ok(len)
+}
+
+#[derive(Copy, Clone, Default)]
+struct FseEntry {
+	symbol: u8,
+	bits:   u8,
+	base:   u16
+}
+
+/// A finite state entropy decoding table
+struct Fse {
+	log:   u8,
+	table: Vec<FseEntry>
+}
+
+impl Fse {
+	/// Reads a table description, returns the table and the bytes consumed.
+	fn read(data: &[u8], max_log: u8, max_symbol: usize) -> Result<(Self, usize)> {
+		let mut bits = Bits { data, pos: 0 };
+		let log = bits.read(4)? as u8 + 5;
+		if log > max_log {
+			return Err(Error::Corrupted);
+		}
+
+		let mut counts = Vec::new();
+		let mut remaining = (1i32 << log) + 1;
+		let mut threshold = 1i32 << log;
+		let mut width = log + 1;
+		while remaining > 1 {
+			if counts.len() > max_symbol {
+				return Err(Error::Corrupted);
+			}
+
+			// values below `max` are one bit shorter
+			let max = 2 * threshold - 1 - remaining;
+			let low = bits.peek(width - 1)? as i32;
+			let mut count = if low & (threshold - 1) < max {
+				bits.skip(width - 1);
+				low & (threshold - 1)
+			} else {
+				let value = bits.read(width)? as i32 & (2 * threshold - 1);
+				if value >= threshold { value - max } else { value }
+			};
+			count -= 1;
+			remaining -= count.abs();
+			counts.push(count as i16);
+
+			if count == 0 {
+				loop {
+					let repeat = bits.read(2)?;
+					counts.extend(core::iter::repeat(0).take(repeat as usize));
+					if repeat != 3 {
+						break;
+					}
+				}
+			}
+			while remaining < threshold && threshold > 1 {
+				width -= 1;
+				threshold >>= 1;
+			}
+		}
+		if remaining != 1 || counts.len() > max_symbol + 1 {
+			return Err(Error::Corrupted);
+		}
+		Ok((Self::new(&counts, log)?, (bits.pos + 7) / 8))
+	}
+
+	/// Builds the decoding table from the normalized counts, -1 stands for a probability
+	/// below one.
+	fn new(counts: &[i16], log: u8) -> Result<Self> {
+		let size = 1usize << log;
+		let mut table = vec![FseEntry::default(); size];
+		let mut next = vec![0u16; counts.len()];
+		let mut high = size;
+		for (symbol, count) in counts.iter().enumerate() {
+			if *count == -1 {
+				high = high.checked_sub(1).ok_or(Error::Corrupted)?;
+				table[high].symbol = symbol as u8;
+				next[symbol] = 1;
+			} else {
+				next[symbol] = *count as u16;
+			}
+		}
+
+		let step = (size >> 1) + (size >> 3) + 3;
+		let mut pos = 0;
+		for (symbol, count) in counts.iter().enumerate() {
+			for _ in 0..(*count).max(0) {
+				table[pos].symbol = symbol as u8;
+				pos = (pos + step) & (size - 1);
+				while pos >= high {
+					pos = (pos + step) & (size - 1);
+				}
+			}
+		}
+		if pos != 0 {
+			return Err(Error::Corrupted);
+		}
+
+		for entry in table.iter_mut() {
+			let state = next[entry.symbol as usize];
+			next[entry.symbol as usize] += 1;
+			entry.bits = log - (15 - state.leading_zeros() as u8);
+			entry.base = ((state as usize) << entry.bits).checked_sub(size).ok_or(Error::Corrupted)? as u16;
+		}
+		Ok(Self { log, table })
+	}
+
+	fn next(&self, state: usize, bits: &mut BackBits) -> usize {
+		let entry = self.table[state];
+		entry.base as usize + bits.read(entry.bits) as usize
+	}
+}
+
+/// A prefix code of the literals
+struct Huffman {
+	max_bits: u8,
+	/// Symbol and code length, indexed by the next `max_bits` bits
+	table:    Vec<(u8, u8)>
+}
+
+impl Huffman {
+	/// Reads a tree description, returns the table and the bytes consumed.
+	fn read(data: &[u8]) -> Result<(Self, usize)> {
+		let header = *data.first().ok_or(Error::Corrupted)? as usize;
+		let (mut weights, len) = if header >= 128 {
+			let count = header - 127;
+			let bytes = data.get(1..1 + (count + 1) / 2).ok_or(Error::Corrupted)?;
+			let weights = (0..count).map(|i| match i % 2 {
+				0 => bytes[i / 2] >> 4,
+				_ => bytes[i / 2] & 0xF
+			}).collect::<Vec<_>>();
+			(weights, 1 + bytes.len())
+		} else {
+			let data = data.get(1..1 + header).ok_or(Error::Corrupted)?;
+			let (fse, len) = Fse::read(data, 6, 15)?;
+			let mut bits = BackBits::new(&data[len..])?;
+			let mut states = [bits.read(fse.log) as usize, bits.read(fse.log) as usize];
+			let mut weights = Vec::new();
+
+			// two interleaved states, the other one's symbol follows when the stream ends
+			let mut i = 0;
+			loop {
+				weights.push(fse.table[states[i]].symbol);
+				states[i] = fse.next(states[i], &mut bits);
+				if bits.overflowed() {
+					weights.push(fse.table[states[i ^ 1]].symbol);
+					break;
+				}
+				i ^= 1;
+				if weights.len() > 255 {
+					return Err(Error::Corrupted);
+				}
+			}
+			(weights, 1 + header)
+		};
+
+		if weights.len() > 255 || weights.iter().any(|w| *w > 11) {
+			return Err(Error::Corrupted);
+		}
+		let total = weights.iter().filter(|w| **w != 0).map(|w| 1u32 << (w - 1)).sum::<u32>();
+		if total == 0 {
+			return Err(Error::Corrupted);
+		}
+		let max_bits = (32 - total.leading_zeros()) as u8;
+		let rest = (1 << max_bits) - total;
+		if !rest.is_power_of_two() || max_bits > 11 {
+			return Err(Error::Corrupted);
+		}
+		weights.push(rest.trailing_zeros() as u8 + 1);
+
+		let mut table = Vec::with_capacity(1 << max_bits);
+		for weight in 1..=max_bits {
+			for (symbol, _) in weights.iter().enumerate().filter(|(_, w)| **w == weight) {
+				let entry = (symbol as u8, max_bits + 1 - weight);
+				table.extend(core::iter::repeat(entry).take(1 << (weight - 1)));
+			}
+		}
+		Ok((Self { max_bits, table }, len))
+	}
+
+	fn decode(&self, stream: &[u8], count: usize, out: &mut Vec<u8>) -> Result<()> {
+		let mut bits = BackBits::new(stream)?;
+		for _ in 0..count {
+			let (symbol, len) = self.table[bits.peek(self.max_bits) as usize];
+			bits.skip(len);
+			out.push(symbol);
+		}
+		match bits.is_empty() {
+			true  => Ok(()),
+			false => Err(Error::Corrupted)
+		}
+	}
+}
+
+/// Reads a stream least significant bit first.
+struct Bits<'a> {
+	data: &'a [u8],
+	pos:  usize
+}
+
+impl Bits<'_> {
+	fn peek(&self, count: u8) -> Result<u32> {
+		(0..count as usize).try_fold(0, |value, i| {
+			let pos = self.pos + i;
+			let byte = self.data.get(pos / 8).ok_or(Error::Corrupted)?;
+			Ok(value | ((byte >> (pos % 8) & 1) as u32) << i)
+		})
+	}
+
+	fn skip(&mut self, count: u8) {
+		self.pos += count as usize;
+	}
+
+	fn read(&mut self, count: u8) -> Result<u32> {
+		let value = self.peek(count)?;
+		self.skip(count);
+		Ok(value)
+	}
+}
+
+/// Reads a stream from its end, most significant bit first. The last byte's highest set bit
+/// marks the start, reading beyond the beginning yields zeros.
+struct BackBits<'a> {
+	data: &'a [u8],
+	/// Number of bits not read yet, negative after reading beyond the beginning
+	pos:  isize
+}
+
+impl<'a> BackBits<'a> {
+	fn new(data: &'a [u8]) -> Result<Self> {
+		match data.last() {
+			Some(b) if *b != 0 => Ok(Self { data, pos: data.len() as isize * 8 - b.leading_zeros() as isize - 1 }),
+			_                  => Err(Error::Corrupted)
+		}
+	}
+
+	fn peek(&self, count: u8) -> u64 {
+		(1..=count as isize).fold(0, |value, i| {
+			let pos = self.pos - i;
+			let bit = match pos >= 0 {
+				true  => self.data[pos as usize / 8] >> (pos % 8) & 1,
+				false => 0
+			};
+			value << 1 | bit as u64
+		})
+	}
+
+	fn skip(&mut self, count: u8) {
+		self.pos -= count as isize;
+	}
+
+	fn read(&mut self, count: u8) -> u64 {
+		let value = self.peek(count);
+		self.skip(count);
+		value
+	}
+
+	fn overflowed(&self) -> bool {
+		self.pos < 0
+	}
+
+	fn is_empty(&self) -> bool {
+		self.pos == 0
+	}
+}
@@ -0,0 +1,452 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Text console on a linear framebuffer, rendering the glyphs of `font::FONT`.
+//!
+//! Output is interpreted like a terminal: `\n` starts a new line, `\r`, `\t` and
+//! backspace move the cursor, long lines wrap and the screen scrolls up once the cursor
+//! passes the last row. Of the escape sequences, SGR colors and attributes (`ESC[...m`,
+//! including 256 and 24 bit colors), cursor movement (`A` to `D`, `H`) and erasing
+//! (`J`, `K`) are supported, others are consumed and ignored.
+//!
+//! The console records the area it changed, a device that has to be told about updates
+//! takes it with `Console::take_dirty`.
+
+use crate::font::{self, FONT};
+
+pub const CELL_WIDTH:  usize = font::LENGTH as usize;
+pub const CELL_HEIGHT: usize = font::HEIGHT as usize;
+/// Columns a tab advances to a multiple of
+pub const TAB_WIDTH:   usize = 8;
+
+/// The 16 colors of the palette as 0xRRGGBB, the 8 normal ones and their bright variants.
+pub const PALETTE: [u32; 16] = [
+	0x000000, 0xAA0000, 0x00AA00, 0xAA5500, 0x0000AA, 0xAA00AA, 0x00AAAA, 0xAAAAAA,
+	0x555555, 0xFF5555, 0x55FF55, 0xFFFF55, 0x5555FF, 0xFF55FF, 0x55FFFF, 0xFFFFFF
+];
+pub const DEFAULT_FG: u32 = PALETTE[7];
+pub const DEFAULT_BG: u32 = PALETTE[0];
+
+const MAX_PARAMS: usize = 16;
+const REPLACEMENT: usize = 0x5E;
+
+/// Where the color channels are in a 32 bit pixel.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PixelFormat {
+	/// Red in the lowest byte
+	Rgbx,
+	/// Blue in the lowest byte
+	Bgrx
+}
+
+impl PixelFormat {
+	/// Converts a 0xRRGGBB color to a pixel.
+	pub fn pixel(self, rgb: u32) -> u32 {
+		match self {
+			Self::Bgrx => rgb & 0xFF_FFFF,
+			Self::Rgbx => (rgb >> 16 & 0xFF) | (rgb & 0xFF00) | (rgb & 0xFF) << 16
+		}
+	}
+}
+
+/// An area of the framebuffer, in pixels.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rect {
+	pub x:      u32,
+	pub y:      u32,
+	pub width:  u32,
+	pub height: u32
+}
+
+impl Rect {
+	pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+		Self { x, y, width, height }
+	}
+
+	/// The smallest rectangle containing both.
+	pub fn union(self, other: Self) -> Self {
+		let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+		let right = (self.x + self.width).max(other.x + other.width);
+		let bottom = (self.y + self.height).max(other.y + other.height);
+		Self { x, y, width: right - x, height: bottom - y }
+	}
+}
+
+/// A linear framebuffer of 32 bit pixels.
+pub struct Framebuffer<'a> {
+	pub pixels: &'a mut [u32],
+	pub width:  usize,
+	pub height: usize,
+	/// Pixels from the start of a line to the start of the next
+	pub stride: usize,
+	pub format: PixelFormat
+}
+
+impl<'a> Framebuffer<'a> {
+	/// A framebuffer over `pixels`, none if they don't hold `height` lines of `stride`.
+	pub fn new(pixels: &'a mut [u32], width: usize, height: usize, stride: usize, format: PixelFormat) -> Option<Self> {
+		match stride >= width && pixels.len() >= stride * height {
+			true  => Some(Self { pixels, width, height, stride, format }),
+			false => None
+		}
+	}
+
+	pub fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, pixel: u32) {
+		for line in y..(y + height).min(self.height) {
+			let start = line * self.stride + x.min(self.width);
+			let end = line * self.stride + (x + width).min(self.width);
+			self.pixels[start..end].fill(pixel);
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+	Ground,
+	Escape,
+	/// In a control sequence, after `ESC[`
+	Csi
+}
+
+pub struct Console<'a> {
+	fb:      Framebuffer<'a>,
+	cols:    usize,
+	rows:    usize,
+	col:     usize,
+	row:     usize,
+	fg:      u32,
+	bg:      u32,
+	bold:    bool,
+	/// The foreground is a palette color that bold makes bright
+	fg_base: Option<usize>,
+	state:   State,
+	params:  [u16; MAX_PARAMS],
+	nparams: usize,
+	dirty:   Option<Rect>
+}
+
+impl<'a> Console<'a> {
+	/// A console filling the framebuffer, which is cleared.
+	pub fn new(fb: Framebuffer<'a>) -> Self {
+		let mut console = Self {
+			cols:    (fb.width / CELL_WIDTH).max(1),
+			rows:    (fb.height / CELL_HEIGHT).max(1),
+			fb,
+			col:     0,
+			row:     0,
+			fg:      DEFAULT_FG,
+			bg:      DEFAULT_BG,
+			bold:    false,
+			fg_base: Some(7),
+			state:   State::Ground,
+			params:  [0; MAX_PARAMS],
+			nparams: 0,
+			dirty:   None
+		};
+		console.clear();
+		console
+	}
+
+	/// Columns and rows.
+	pub fn size(&self) -> (usize, usize) {
+		(self.cols, self.rows)
+	}
+
+	/// Column and row of the cursor.
+	pub fn cursor(&self) -> (usize, usize) {
+		(self.col, self.row)
+	}
+
+	pub fn framebuffer(&self) -> &Framebuffer<'a> {
+		&self.fb
+	}
+
+	/// The area changed since the last call.
+	pub fn take_dirty(&mut self) -> Option<Rect> {
+		self.dirty.take()
+	}
+
+	fn mark(&mut self, x: usize, y: usize, width: usize, height: usize) {
+		let rect = Rect::new(x as u32, y as u32, width as u32, height as u32);
+		self.dirty = Some(self.dirty.map_or(rect, |d| d.union(rect)));
+	}
+
+	/// Clears the screen with the background color and moves the cursor home.
+	pub fn clear(&mut self) {
+		let (w, h) = (self.fb.width, self.fb.height);
+		self.fb.fill(0, 0, w, h, self.fb.format.pixel(self.bg));
+		self.mark(0, 0, w, h);
+		self.col = 0;
+		self.row = 0;
+	}
+
+	pub fn write(&mut self, s: &str) {
+		for c in s.chars() {
+			self.put(c);
+		}
+	}
+
+	fn put(&mut self, c: char) {
+		match self.state {
+			State::Ground => match c {
+				'\x1B' => self.state = State::Escape,
+				'\n'   => self.newline(),
+				'\r'   => self.col = 0,
+				'\t'   => self.col = ((self.col / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols - 1),
+				'\x08' => self.col = self.col.saturating_sub(1),
+				c if c < ' ' || c == '\x7F' => (),
+				c => self.glyph(c)
+			},
+			State::Escape => match c {
+				'[' => {
+					self.state = State::Csi;
+					self.params = [0; MAX_PARAMS];
+					self.nparams = 0;
+				}
+				'c' => {
+					self.reset_attributes();
+					self.state = State::Ground;
+					self.clear();
+				}
+				_ => self.state = State::Ground
+			},
+			State::Csi => match c {
+				'0'..='9' => {
+					let i = self.nparams.max(1) - 1;
+					self.nparams = self.nparams.max(1);
+					self.params[i] = self.params[i].saturating_mul(10).saturating_add(c as u16 - b'0' as u16);
+				}
+				';' => if self.nparams < MAX_PARAMS {
+					// an empty first parameter counts as well
+					self.nparams = self.nparams.max(1) + 1;
+				},
+				// private and intermediate characters
+				'?' | '>' | '<' | '=' | ' '..='/' => (),
+				'@'..='~' => {
+					self.state = State::Ground;
+					self.csi(c);
+				}
+				_ => self.state = State::Ground
+			}
+		}
+	}
+
+	/// A parameter of the current sequence, `default` if it is missing or zero.
+	fn param(&self, i: usize, default: u16) -> usize {
+		match self.params[i] {
+			0 => default as usize,
+			p => p as usize
+		}
+	}
+
+	fn csi(&mut self, c: char) {
+		match c {
+			'm' => self.sgr(),
+			'A' => self.row = self.row.saturating_sub(self.param(0, 1)),
+			'B' => self.row = (self.row + self.param(0, 1)).min(self.rows - 1),
+			'C' => self.col = (self.col + self.param(0, 1)).min(self.cols - 1),
+			'D' => self.col = self.col.saturating_sub(self.param(0, 1)),
+			'H' | 'f' => {
+				self.row = (self.param(0, 1) - 1).min(self.rows - 1);
+				self.col = (self.param(1, 1) - 1).min(self.cols - 1);
+			}
+			'J' => match self.params[0] {
+				0 => {
+					self.erase_line(self.col, self.cols);
+					self.erase_rows(self.row + 1, self.rows);
+				}
+				1 => {
+					self.erase_rows(0, self.row);
+					self.erase_line(0, self.col + 1);
+				}
+				_ => self.erase_rows(0, self.rows)
+			},
+			'K' => match self.params[0] {
+				0 => self.erase_line(self.col, self.cols),
+				1 => self.erase_line(0, self.col + 1),
+				_ => self.erase_line(0, self.cols)
+			},
+			_ => ()
+		}
+	}
+
+	fn reset_attributes(&mut self) {
+		self.fg = DEFAULT_FG;
+		self.bg = DEFAULT_BG;
+		self.bold = false;
+		self.fg_base = Some(7);
+	}
+
+	fn set_fg(&mut self, index: usize) {
+		self.fg_base = Some(index);
+		self.fg = PALETTE[index + if self.bold { 8 } else { 0 }];
+	}
+
+	/// Select graphic rendition, the parameters of `ESC[...m`.
+	fn sgr(&mut self) {
+		let mut i = 0;
+		loop {
+			let p = self.params[i] as usize;
+			match p {
+				0 => self.reset_attributes(),
+				1 => {
+					self.bold = true;
+					if let Some(base) = self.fg_base {
+						self.set_fg(base);
+					}
+				}
+				22 => {
+					self.bold = false;
+					if let Some(base) = self.fg_base {
+						self.set_fg(base);
+					}
+				}
+				30..=37 => self.set_fg(p - 30),
+				39 => self.set_fg(7),
+				40..=47 => self.bg = PALETTE[p - 40],
+				49 => self.bg = DEFAULT_BG,
+				90..=97 => {
+					self.fg_base = None;
+					self.fg = PALETTE[p - 90 + 8];
+				}
+				100..=107 => self.bg = PALETTE[p - 100 + 8],
+				38 | 48 => {
+					let (color, used) = self.extended_color(i + 1);
+					if let Some(color) = color {
+						match p {
+							38 => {
+								self.fg_base = None;
+								self.fg = color;
+							}
+							_ => self.bg = color
+						}
+					}
+					i += used;
+				}
+				_ => ()
+			}
+			i += 1;
+			if i >= self.nparams {
+				return;
+			}
+		}
+	}
+
+	/// A 256 color (`5;n`) or 24 bit color (`2;r;g;b`) starting at parameter `i`, and the
+	/// number of parameters it takes.
+	fn extended_color(&self, i: usize) -> (Option<u32>, usize) {
+		let p = |j: usize| self.params.get(i + j).copied().unwrap_or(0) as u32;
+		match p(0) {
+			5 => (Some(match p(1) {
+				n @ 0..=15 => PALETTE[n as usize],
+				n @ 16..=231 => {
+					let level = |v: u32| if v == 0 { 0 } else { 55 + v * 40 };
+					let n = n - 16;
+					level(n / 36) << 16 | level(n / 6 % 6) << 8 | level(n % 6)
+				}
+				n => {
+					let v = 8 + (n.min(255) - 232) * 10;
+					v << 16 | v << 8 | v
+				}
+			}), 2),
+			2 => (Some((p(1) & 0xFF) << 16 | (p(2) & 0xFF) << 8 | p(3) & 0xFF), 4),
+			_ => (None, 0)
+		}
+	}
+
+	fn newline(&mut self) {
+		self.col = 0;
+		if self.row + 1 < self.rows {
+			self.row += 1;
+		} else {
+			self.scroll();
+		}
+	}
+
+	/// Moves all rows up by one and clears the last.
+	fn scroll(&mut self) {
+		let stride = self.fb.stride;
+		let line = CELL_HEIGHT * stride;
+		let used = self.rows * line;
+		self.fb.pixels.copy_within(line..used, 0);
+		self.erase_rows(self.rows - 1, self.rows);
+		self.mark(0, 0, self.cols * CELL_WIDTH, self.rows * CELL_HEIGHT);
+	}
+
+	fn erase_rows(&mut self, from: usize, to: usize) {
+		if from < to {
+			let bg = self.fb.format.pixel(self.bg);
+			let width = self.cols * CELL_WIDTH;
+			self.fb.fill(0, from * CELL_HEIGHT, width, (to - from) * CELL_HEIGHT, bg);
+			self.mark(0, from * CELL_HEIGHT, width, (to - from) * CELL_HEIGHT);
+		}
+	}
+
+	fn erase_line(&mut self, from: usize, to: usize) {
+		let to = to.min(self.cols);
+		if from < to {
+			let bg = self.fb.format.pixel(self.bg);
+			let (x, y) = (from * CELL_WIDTH, self.row * CELL_HEIGHT);
+			self.fb.fill(x, y, (to - from) * CELL_WIDTH, CELL_HEIGHT, bg);
+			self.mark(x, y, (to - from) * CELL_WIDTH, CELL_HEIGHT);
+		}
+	}
+
+	/// Draws a character at the cursor and advances it, wrapping at the end of the line.
+	fn glyph(&mut self, c: char) {
+		if self.col >= self.cols {
+			self.newline();
+		}
+		let (x, y) = (self.col * CELL_WIDTH, self.row * CELL_HEIGHT);
+		let format = self.fb.format;
+		let (fg, bg) = (self.fg, self.bg);
+		let glyph = match c {
+			' ' => None,
+			'!'..='~' => Some(&FONT[c as usize - 0x21]),
+			_ => Some(&FONT[REPLACEMENT])
+		};
+
+		for row in 0..CELL_HEIGHT.min(self.fb.height - y) {
+			let line = &mut self.fb.pixels[(y + row) * self.fb.stride + x..][..CELL_WIDTH.min(self.fb.width - x)];
+			for (col, pixel) in line.iter_mut().enumerate() {
+				let alpha = glyph.map_or(0, |g| g[col][row]);
+				*pixel = format.pixel(blend(fg, bg, alpha));
+			}
+		}
+		self.mark(x, y, CELL_WIDTH, CELL_HEIGHT);
+		self.col += 1;
+	}
+}
+
+impl core::fmt::Write for Console<'_> {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		self.write(s);
+		Ok(())
+	}
+}
+
+/// Mixes two 0xRRGGBB colors, `alpha` is the weight of `fg`.
+pub fn blend(fg: u32, bg: u32, alpha: u8) -> u32 {
+	let (a, b) = (alpha as u32, 255 - alpha as u32);
+	let channel = |shift: u32| (((fg >> shift & 0xFF) * a + (bg >> shift & 0xFF) * b + 127) / 255) << shift;
+	channel(16) | channel(8) | channel(0)
+}
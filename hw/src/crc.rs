@@ -0,0 +1,128 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Checksums of on-disk structures.
+
+/// CRC-32 (IEEE 802.3) as used by GPT, zlib and UEFI
+pub fn crc32(data: &[u8]) -> u32 {
+	crc32_update(0, data)
+}
+
+/// Continues a CRC-32 over more data, `crc` is the result for the preceding data.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+	!data.iter().fold(!crc, |crc, b| CRC32_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ crc >> 8)
+}
+
+const CRC32_TABLE: [u32; 256] = table(0xEDB8_8320);
+
+/// Lookup table of a reflected CRC-32 with the given reversed polynomial.
+const fn table(poly: u32) -> [u32; 256] {
+	let mut table = [0; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = match crc & 1 {
+				1 => crc >> 1 ^ poly,
+				_ => crc >> 1
+			};
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+}
+
+/// CRC-32C (Castagnoli) as used by Btrfs, iSCSI and ext4
+pub fn crc32c(data: &[u8]) -> u32 {
+	crc32c_update(0, data)
+}
+
+/// Continues a CRC-32C over more data, `crc` is the result for the preceding data.
+pub fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+	!data.iter().fold(!crc, |crc, b| CRC32C_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ crc >> 8)
+}
+
+const CRC32C_TABLE: [u32; 256] = table(0x82F6_3B78);
+
+const XXH_PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const XXH_PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const XXH_PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const XXH_PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const XXH_PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// XXH64 as used by zstd frames and Btrfs
+pub fn xxhash64(data: &[u8], seed: u64) -> u64 {
+	fn round(acc: u64, input: u64) -> u64 {
+		acc.wrapping_add(input.wrapping_mul(XXH_PRIME64_2)).rotate_left(31).wrapping_mul(XXH_PRIME64_1)
+	}
+
+	fn merge(acc: u64, val: u64) -> u64 {
+		(acc ^ round(0, val)).wrapping_mul(XXH_PRIME64_1).wrapping_add(XXH_PRIME64_4)
+	}
+
+	let u64_at = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+	let mut i = 0;
+	let mut hash = if data.len() >= 32 {
+		let mut v = [
+			seed.wrapping_add(XXH_PRIME64_1).wrapping_add(XXH_PRIME64_2),
+			seed.wrapping_add(XXH_PRIME64_2),
+			seed,
+			seed.wrapping_sub(XXH_PRIME64_1)
+		];
+		while i + 32 <= data.len() {
+			for (j, v) in v.iter_mut().enumerate() {
+				*v = round(*v, u64_at(i + j * 8));
+			}
+			i += 32;
+		}
+		let hash = v[0].rotate_left(1)
+			.wrapping_add(v[1].rotate_left(7))
+			.wrapping_add(v[2].rotate_left(12))
+			.wrapping_add(v[3].rotate_left(18));
+		v.iter().fold(hash, |hash, v| merge(hash, *v))
+	} else {
+		seed.wrapping_add(XXH_PRIME64_5)
+	};
+
+	hash = hash.wrapping_add(data.len() as u64);
+	while i + 8 <= data.len() {
+		hash = (hash ^ round(0, u64_at(i))).rotate_left(27).wrapping_mul(XXH_PRIME64_1).wrapping_add(XXH_PRIME64_4);
+		i += 8;
+	}
+	if i + 4 <= data.len() {
+		let v = u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) as u64;
+		hash = (hash ^ v.wrapping_mul(XXH_PRIME64_1)).rotate_left(23).wrapping_mul(XXH_PRIME64_2).wrapping_add(XXH_PRIME64_3);
+		i += 4;
+	}
+	for b in &data[i..] {
+		hash = (hash ^ (*b as u64).wrapping_mul(XXH_PRIME64_5)).rotate_left(11).wrapping_mul(XXH_PRIME64_1);
+	}
+
+	hash ^= hash >> 33;
+	hash = hash.wrapping_mul(XXH_PRIME64_2);
+	hash ^= hash >> 29;
+	hash = hash.wrapping_mul(XXH_PRIME64_3);
+	hash ^ hash >> 32
+}
@@ -317,7 +317,9 @@ impl Into<Option<u64>> for FdtValue<'_> {
 
 impl<'a, T: Copy> Into<Option<&'a [T]>> for FdtValue<'a> {
 	fn into(self) -> Option<&'a [T]> {
-		(!self.0.is_empty() && self.0.len() % size_of::<T>() == 0).then(||
+		// properties are only 4-byte aligned
+		(!self.0.is_empty() && self.0.len() % size_of::<T>() == 0
+			&& self.0.as_ptr() as usize % core::mem::align_of::<T>() == 0).then(||
 			unsafe { core::slice::from_raw_parts(self.0.as_ptr() as *const T, self.len() / size_of::<T>()) })
 	}
 }
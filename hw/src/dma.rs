@@ -0,0 +1,86 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The environment the device drivers in this crate run in.
+//!
+//! Drivers program devices with physical addresses, they get memory the device can
+//! access and the physical address of caller provided buffers through `Dma`.
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub trait Dma {
+	/// Allocates zeroed, physically contiguous memory aligned to at least `align`,
+	/// returns its virtual and physical address.
+	fn alloc(&mut self, size: usize, align: usize) -> Option<(*mut u8, u64)>;
+
+	/// Frees memory returned by `alloc`.
+	fn free(&mut self, virt: *mut u8, size: usize);
+
+	/// Returns the physical address of a byte in a buffer, buffers passed to a driver
+	/// must stay mapped until the operation completes.
+	fn phys(&mut self, virt: *const u8) -> u64;
+
+	/// Busy waits for `us` microseconds.
+	fn stall(&mut self, us: u64);
+}
+
+impl<T: Dma + ?Sized> Dma for &mut T {
+	fn alloc(&mut self, size: usize, align: usize) -> Option<(*mut u8, u64)> {
+		(**self).alloc(size, align)
+	}
+
+	fn free(&mut self, virt: *mut u8, size: usize) {
+		(**self).free(virt, size)
+	}
+
+	fn phys(&mut self, virt: *const u8) -> u64 {
+		(**self).phys(virt)
+	}
+
+	fn stall(&mut self, us: u64) {
+		(**self).stall(us)
+	}
+}
+
+/// A region of DMA memory, owners free it with `Region::free` as it doesn't keep the
+/// allocator.
+#[derive(Debug)]
+pub struct Region {
+	pub virt: *mut u8,
+	pub phys: u64,
+	pub size: usize
+}
+
+impl Region {
+	pub fn alloc(dma: &mut impl Dma, size: usize, align: usize) -> Option<Self> {
+		let (virt, phys) = dma.alloc(size, align)?;
+		Some(Self { virt, phys, size })
+	}
+
+	pub fn free(self, dma: &mut impl Dma) {
+		dma.free(self.virt, self.size)
+	}
+
+	pub fn as_ptr<T>(&self) -> *mut T {
+		self.virt as _
+	}
+}
@@ -0,0 +1,286 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Directory entries and names.
+
+use {super::*, alloc::{string::String, vec::Vec}};
+
+/// Size of a directory entry
+pub const ENTRY_SIZE: usize = 32;
+/// Longest long name, in UTF-16 code units
+pub const MAX_NAME: usize = 255;
+
+/// A file or directory as listed in its parent directory.
+#[derive(Clone, Debug)]
+pub struct Entry {
+	/// The long name, or the short name if there is none
+	pub name:       String,
+	/// The 8.3 name, e.g. `ALONGF~1.TXT`
+	pub short_name: String,
+	pub attributes: u8,
+	pub size:       u32,
+	/// First cluster, zero for empty files
+	pub cluster:    u32,
+	pub created:    DateTime,
+	pub modified:   DateTime,
+	/// Device offsets of the entry's long name entries followed by its short name entry
+	pub(crate) slots: Vec<u64>
+}
+
+impl Entry {
+	pub fn is_dir(&self) -> bool {
+		self.attributes & ATTR_DIRECTORY != 0
+	}
+
+	/// Device offset of the short name entry
+	pub(crate) fn slot(&self) -> u64 {
+		*self.slots.last().unwrap()
+	}
+
+	/// Whether `name` refers to this entry, by its long or its short name.
+	pub fn matches(&self, name: &str) -> bool {
+		eq_ignore_case(&self.name, name) || eq_ignore_case(&self.short_name, name)
+	}
+}
+
+/// Compares names the way FAT does, ignoring case.
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+	a.chars().flat_map(char::to_uppercase).eq(b.chars().flat_map(char::to_uppercase))
+}
+
+pub(crate) fn read_entry(raw: &[u8; ENTRY_SIZE]) -> DirEntry {
+	// SAFETY: both are 32 bytes and `DirEntry` is packed
+	unsafe { (raw.as_ptr() as *const DirEntry).read_unaligned() }
+}
+
+pub(crate) fn entry_bytes<T: Copy>(entry: &T) -> [u8; ENTRY_SIZE] {
+	debug_assert_eq!(core::mem::size_of::<T>(), ENTRY_SIZE);
+	// SAFETY: the entry structs are packed and 32 bytes
+	unsafe { (entry as *const T as *const [u8; ENTRY_SIZE]).read_unaligned() }
+}
+
+/// Collects the entries of a directory from its raw entries and their device offsets.
+/// Deleted entries, the volume label and the `.` and `..` entries are skipped, long names
+/// whose sequence or checksum is broken are ignored in favour of the short name.
+pub(crate) fn parse(raw: &[(u64, [u8; ENTRY_SIZE])]) -> Vec<Entry> {
+	let mut entries = Vec::new();
+	let mut lfn = Vec::<(u64, LfnEntry)>::new();
+
+	for (offset, bytes) in raw {
+		let entry = read_entry(bytes);
+		match entry.name[0] {
+			ENTRY_END => break,
+			ENTRY_DELETED => {
+				lfn.clear();
+				continue;
+			}
+			_ => ()
+		}
+
+		if entry.is_long_name() {
+			// SAFETY: see `read_entry`
+			let long = unsafe { (bytes.as_ptr() as *const LfnEntry).read_unaligned() };
+			let follows = lfn.last().map_or(false, |(_, prev)| {
+				prev.ord & !LFN_LAST == long.ord + 1 && prev.checksum == long.checksum
+			});
+			if long.ord & LFN_LAST != 0 {
+				lfn.clear();
+				lfn.push((*offset, long));
+			} else if follows {
+				lfn.push((*offset, long));
+			} else {
+				lfn.clear();
+			}
+			continue;
+		}
+
+		let long = core::mem::take(&mut lfn);
+		if entry.attributes & ATTR_VOLUME_ID != 0 || entry.name[0] == b'.' {
+			continue;
+		}
+
+		let short_name = short_name_str(&entry);
+		let complete = long.last().map_or(false, |(_, e)| e.ord & !LFN_LAST == 1)
+			&& long.iter().all(|(_, e)| e.checksum == short_name_checksum(&entry.name));
+		let (name, mut slots) = match complete {
+			true => {
+				let units = long.iter().rev()
+					.flat_map(|(_, e)| e.units())
+					.take_while(|u| *u != 0 && *u != 0xFFFF)
+					.collect::<Vec<_>>();
+				let name = char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect();
+				(name, long.iter().map(|(o, _)| *o).collect::<Vec<_>>())
+			}
+			false => (short_name.clone(), Vec::new())
+		};
+		slots.push(*offset);
+
+		entries.push(Entry {
+			name,
+			short_name,
+			attributes: entry.attributes,
+			size:       entry.size,
+			cluster:    entry.cluster(),
+			created:    DateTime::from_fat(entry.created_date, entry.created_time),
+			modified:   DateTime::from_fat(entry.modified_date, entry.modified_time),
+			slots
+		});
+	}
+	entries
+}
+
+/// The short name as displayed, e.g. `README.TXT`, or `readme.txt` if the NT case flags
+/// are set.
+pub(crate) fn short_name_str(entry: &DirEntry) -> String {
+	let mut name = entry.name;
+	if name[0] == 0x05 {
+		name[0] = ENTRY_DELETED;
+	}
+
+	let part = |bytes: &[u8], lower: bool| bytes.iter()
+		.rev().skip_while(|b| **b == b' ').collect::<Vec<_>>().into_iter().rev()
+		.map(|b| match lower {
+			true  => b.to_ascii_lowercase() as char,
+			false => *b as char
+		})
+		.collect::<String>();
+
+	let base = part(&name[..8], entry.nt_res & NT_LOWER_BASE != 0);
+	let ext = part(&name[8..], entry.nt_res & NT_LOWER_EXT != 0);
+	match ext.is_empty() {
+		true  => base,
+		false => base + "." + &ext
+	}
+}
+
+/// Checks a name for a new entry.
+pub(crate) fn check_name(name: &str) -> Result<()> {
+	let valid = !name.is_empty()
+		&& name != "." && name != ".."
+		&& !name.ends_with('.') && !name.ends_with(' ')
+		&& name.encode_utf16().count() <= MAX_NAME
+		&& name.chars().all(|c| c >= ' ' && !"\"*/:<>?\\|".contains(c));
+	match valid {
+		true  => Ok(()),
+		false => Err(Error::InvalidName)
+	}
+}
+
+fn is_short_char(c: u8) -> bool {
+	c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// The short name and NT case flags of a name that fits into a short name entry, which
+/// is a name of the 8.3 form whose base and extension are each in a single case.
+pub(crate) fn short_name_of(name: &str) -> Option<([u8; 11], u8)> {
+	let (base, ext) = match name.rfind('.') {
+		Some(i) => (&name[..i], &name[i + 1..]),
+		None    => (name, "")
+	};
+	if base.is_empty() || base.len() > 8 || ext.len() > 3 || (ext.is_empty() && name.ends_with('.')) {
+		return None;
+	}
+
+	let mut flags = 0;
+	for (part, flag) in [(base, NT_LOWER_BASE), (ext, NT_LOWER_EXT)] {
+		let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+		let upper = part.bytes().any(|c| c.is_ascii_uppercase());
+		if lower && upper {
+			return None;
+		} else if lower {
+			flags |= flag;
+		}
+	}
+
+	let mut short = [b' '; 11];
+	for (i, c) in base.bytes().enumerate() {
+		short[i] = c.to_ascii_uppercase();
+	}
+	for (i, c) in ext.bytes().enumerate() {
+		short[8 + i] = c.to_ascii_uppercase();
+	}
+	match short.iter().take(base.len()).chain(&short[8..8 + ext.len()]).all(|c| is_short_char(*c)) {
+		true => {
+			if short[0] == ENTRY_DELETED {
+				short[0] = 0x05;
+			}
+			Some((short, flags))
+		}
+		false => None
+	}
+}
+
+/// The short name `BASE~N.EXT` generated for a long name, `BASE` is shortened so the name
+/// fits.
+pub(crate) fn numbered_short_name(name: &str, n: u32) -> [u8; 11] {
+	let clean = |s: &str| s.chars()
+		.filter(|c| *c != ' ' && *c != '.')
+		.map(|c| match c.is_ascii() && is_short_char(c.to_ascii_uppercase() as u8) {
+			true  => c.to_ascii_uppercase() as u8,
+			false => b'_'
+		})
+		.collect::<Vec<_>>();
+
+	let trimmed = name.trim_start_matches('.');
+	let (base, ext) = match trimmed.rfind('.') {
+		Some(i) => (clean(&trimmed[..i]), clean(&trimmed[i + 1..])),
+		None    => (clean(trimmed), Vec::new())
+	};
+
+	let tail = alloc::format!("~{}", n);
+	let len = base.len().min(8 - tail.len()).max(1);
+	let mut short = [b' '; 11];
+	for (i, c) in base.iter().chain(b"_".iter()).take(len).enumerate() {
+		short[i] = *c;
+	}
+	short[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+	for (i, c) in ext.iter().take(3).enumerate() {
+		short[8 + i] = *c;
+	}
+	short
+}
+
+/// The long name entries of `name` in the order they are stored, followed by the short
+/// name entry with the checksum `checksum`.
+pub(crate) fn long_name_entries(name: &str, checksum: u8) -> Vec<LfnEntry> {
+	let mut units = name.encode_utf16().collect::<Vec<_>>();
+	if units.len() % 13 != 0 {
+		units.push(0);
+	}
+	while units.len() % 13 != 0 {
+		units.push(0xFFFF);
+	}
+
+	let count = units.len() / 13;
+	(0..count).rev().map(|i| {
+		let mut entry = LfnEntry {
+			ord:        (i as u8 + 1) | if i + 1 == count { LFN_LAST } else { 0 },
+			attributes: ATTR_LONG_NAME,
+			checksum,
+			..LfnEntry::default()
+		};
+		let mut chunk = [0; 13];
+		chunk.copy_from_slice(&units[i * 13..(i + 1) * 13]);
+		entry.set_units(&chunk);
+		entry
+	}).collect()
+}
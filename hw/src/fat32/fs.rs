@@ -0,0 +1,828 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {
+	super::*,
+	crate::block::{self, BlockDevice},
+	alloc::{collections::{BTreeMap, BTreeSet}, string::String, vec, vec::Vec}
+};
+
+/// Largest file size
+pub const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+/// Largest directory size, the specification limits directories to 65536 entries
+const MAX_DIR_SIZE: u64 = 65536 * ENTRY_SIZE as u64;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const FAT32_MASK: u32 = 0x0FFF_FFFF;
+
+/// A FAT file system on a device.
+///
+/// Directory entries and file data are written through, changes to the FAT are written
+/// at the end of each operation. The free cluster hints in the FAT32 `FsInfo` sector are
+/// only written by `sync`, which should be called before the device is released.
+pub struct FileSystem<D> {
+	dev:          D,
+	kind:         FatType,
+	label:        String,
+	sector_size:  u64,
+	cluster_size: u64,
+	/// Device offset of the first FAT
+	fat_offset:   u64,
+	/// Size of a FAT in bytes
+	fat_size:     u64,
+	fats:         u8,
+	/// The only FAT read and written if mirroring is disabled
+	active_fat:   Option<u8>,
+	/// Device offset and entries of the fixed root directory of FAT12 and FAT16
+	root_offset:  u64,
+	root_entries: u32,
+	/// First cluster of the root directory of FAT32
+	root_cluster: u32,
+	/// Device offset of cluster 2, the first data cluster
+	data_offset:  u64,
+	/// Number of data clusters
+	clusters:     u32,
+	/// Device offset of the `FsInfo` sector
+	fs_info:      Option<u64>,
+	free:         Option<u32>,
+	next_free:    u32,
+	/// Sectors of the FAT read so far, by their index in the FAT
+	fat:          BTreeMap<u64, Vec<u8>>,
+	dirty:        BTreeSet<u64>,
+	time:         DateTime
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+	/// Reads the boot sector and determines the FAT type from the number of clusters.
+	pub fn open(mut dev: D) -> Result<Self> {
+		let mut boot = [0u8; 512];
+		read_bytes(&mut dev, 0, &mut boot)?;
+		let bpb = read_struct::<Bpb>(&boot, 0);
+		let ebpb32 = read_struct::<Ebpb32>(&boot, core::mem::size_of::<Bpb>());
+
+		let sector_size = bpb.bytes_per_sector as u64;
+		if boot[510..] != BOOT_SIGNATURE
+			|| !matches!(boot[0], 0xEB | 0xE9)
+			|| !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+			|| !bpb.sectors_per_cluster.is_power_of_two()
+			|| bpb.reserved_sectors == 0
+			|| bpb.fats == 0 {
+			return Err(Error::NotFat);
+		}
+
+		let fat_sectors = match bpb.fat_size_16 {
+			0 => ebpb32.fat_size_32 as u64,
+			n => n as u64
+		};
+		let total_sectors = match bpb.total_sectors_16 {
+			0 => bpb.total_sectors_32 as u64,
+			n => n as u64
+		};
+		let root_sectors = (bpb.root_entries as u64 * ENTRY_SIZE as u64 + sector_size - 1) / sector_size;
+		let data_sector = bpb.reserved_sectors as u64 + bpb.fats as u64 * fat_sectors + root_sectors;
+		if fat_sectors == 0 || total_sectors <= data_sector {
+			return Err(Error::NotFat);
+		}
+		if total_sectors * sector_size > dev.blocks() * dev.block_size() as u64 {
+			return Err(Error::Corrupted);
+		}
+
+		let clusters = ((total_sectors - data_sector) / bpb.sectors_per_cluster as u64) as u32;
+		let kind = match clusters {
+			0..=4084     => FatType::Fat12,
+			4085..=65524 => FatType::Fat16,
+			_            => FatType::Fat32
+		};
+		let entry_bits = match kind {
+			FatType::Fat12 => 12,
+			FatType::Fat16 => 16,
+			FatType::Fat32 => 32
+		};
+		if kind == FatType::Fat32 && (bpb.fat_size_16 != 0 || bpb.root_entries != 0)
+			|| kind != FatType::Fat32 && bpb.root_entries == 0 {
+			return Err(Error::NotFat);
+		}
+		if fat_sectors * sector_size * 8 < (clusters as u64 + 2) * entry_bits {
+			return Err(Error::Corrupted);
+		}
+
+		let mut fs = Self {
+			dev,
+			kind,
+			label:        String::new(),
+			sector_size,
+			cluster_size: sector_size * bpb.sectors_per_cluster as u64,
+			fat_offset:   bpb.reserved_sectors as u64 * sector_size,
+			fat_size:     fat_sectors * sector_size,
+			fats:         bpb.fats,
+			active_fat:   None,
+			root_offset:  (data_sector - root_sectors) * sector_size,
+			root_entries: bpb.root_entries as u32,
+			root_cluster: 0,
+			data_offset:  data_sector * sector_size,
+			clusters,
+			fs_info:      None,
+			free:         None,
+			next_free:    2,
+			fat:          BTreeMap::new(),
+			dirty:        BTreeSet::new(),
+			time:         DateTime::EPOCH
+		};
+
+		if kind == FatType::Fat32 {
+			if !fs.is_cluster(ebpb32.root_cluster) {
+				return Err(Error::Corrupted);
+			}
+			fs.root_cluster = ebpb32.root_cluster;
+			if ebpb32.ext_flags & Ebpb32::EXT_FLAGS_NO_MIRRORING != 0 {
+				fs.active_fat = Some((ebpb32.ext_flags & 0xF) as u8).filter(|f| *f < bpb.fats);
+			}
+
+			if ebpb32.fs_info != 0 && ebpb32.fs_info < bpb.reserved_sectors {
+				let offset = ebpb32.fs_info as u64 * sector_size;
+				let mut sector = [0u8; 512];
+				fs.read_bytes(offset, &mut sector)?;
+				let info = read_struct::<FsInfo>(&sector, 0);
+				if info.is_valid() {
+					fs.fs_info = Some(offset);
+					fs.free = Some(info.free_count).filter(|n| *n <= clusters);
+					fs.next_free = Some(info.next_free).filter(|c| fs.is_cluster(*c)).unwrap_or(2);
+				}
+			}
+		}
+
+		let raw = fs.read_dir_raw(0)?;
+		let volume = raw.iter()
+			.map(|(_, bytes)| read_entry(bytes))
+			.take_while(|e| e.name[0] != ENTRY_END)
+			.find(|e| e.name[0] != ENTRY_DELETED && !e.is_long_name() && e.attributes & ATTR_VOLUME_ID != 0);
+		let label = match (volume, kind) {
+			(Some(e), _)              => e.name,
+			(None, FatType::Fat32)    => ebpb32.volume_label,
+			(None, _)                 => read_struct::<Ebpb16>(&boot, core::mem::size_of::<Bpb>()).volume_label
+		};
+		if &label != b"NO NAME    " {
+			fs.label = label.iter().map(|c| *c as char).collect::<String>().trim_end().into();
+		}
+		Ok(fs)
+	}
+
+	pub fn kind(&self) -> FatType {
+		self.kind
+	}
+
+	/// The volume label, empty if there is none.
+	pub fn label(&self) -> &str {
+		&self.label
+	}
+
+	pub fn cluster_size(&self) -> usize {
+		self.cluster_size as usize
+	}
+
+	/// Number of data clusters.
+	pub fn clusters(&self) -> u32 {
+		self.clusters
+	}
+
+	/// Number of free clusters, counted once if the file system doesn't record it.
+	pub fn free_clusters(&mut self) -> Result<u32> {
+		if let Some(free) = self.free {
+			return Ok(free);
+		}
+
+		let mut free = 0;
+		for cluster in 2..self.clusters + 2 {
+			if self.next(cluster)? == 0 {
+				free += 1;
+			}
+		}
+		self.free = Some(free);
+		Ok(free)
+	}
+
+	/// Sets the time stamp of files created or modified from now on.
+	pub fn set_time(&mut self, time: DateTime) {
+		self.time = time;
+	}
+
+	/// The entries of the directory at `path`.
+	pub fn read_dir(&mut self, path: &str) -> Result<Vec<Entry>> {
+		let dir = self.dir(path)?;
+		self.read_dir_entries(dir)
+	}
+
+	/// The entry at `path`, the root directory has an entry without name.
+	pub fn metadata(&mut self, path: &str) -> Result<Entry> {
+		Ok(self.find(path)?.unwrap_or_else(|| Entry {
+			name:       String::new(),
+			short_name: String::new(),
+			attributes: ATTR_DIRECTORY,
+			size:       0,
+			cluster:    0,
+			created:    DateTime::EPOCH,
+			modified:   DateTime::EPOCH,
+			slots:      Vec::new()
+		}))
+	}
+
+	/// Reads from the file at `path`, returns the number of bytes read, which is less than
+	/// the buffer's length at the end of the file.
+	pub fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+		let entry = self.file(path)?;
+		if offset >= entry.size as u64 {
+			return Ok(0);
+		}
+
+		let len = buf.len().min((entry.size as u64 - offset) as usize);
+		let chain = self.chain(entry.cluster)?;
+		if (chain.len() as u64) * self.cluster_size < entry.size as u64 {
+			return Err(Error::Corrupted);
+		}
+		self.data_io(&chain, offset, Data::Read(&mut buf[..len]))?;
+		Ok(len)
+	}
+
+	/// Writes to the file at `path`, which is extended if the data goes beyond its end.
+	pub fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize> {
+		let mut entry = self.file(path)?;
+		let end = offset + data.len() as u64;
+		if end > MAX_FILE_SIZE {
+			return Err(Error::NoSpace);
+		}
+
+		let result = (|| {
+			if end > entry.size as u64 {
+				self.resize(&mut entry, end)?;
+			}
+			let chain = self.chain(entry.cluster)?;
+			self.data_io(&chain, offset, Data::Write(data))
+		})();
+		self.update_entry(&entry)?;
+		self.flush_fat()?;
+		result.map(|_| data.len())
+	}
+
+	/// Sets the size of the file at `path`, a file that grows is filled with zeros.
+	pub fn truncate(&mut self, path: &str, size: u64) -> Result<()> {
+		let mut entry = self.file(path)?;
+		if size > MAX_FILE_SIZE {
+			return Err(Error::NoSpace);
+		}
+
+		let result = self.resize(&mut entry, size);
+		self.update_entry(&entry)?;
+		self.flush_fat()?;
+		result
+	}
+
+	/// Creates an empty file.
+	pub fn create_file(&mut self, path: &str) -> Result<Entry> {
+		self.create(path, ATTR_ARCHIVE)
+	}
+
+	/// Creates an empty directory.
+	pub fn create_dir(&mut self, path: &str) -> Result<Entry> {
+		self.create(path, ATTR_DIRECTORY)
+	}
+
+	/// Removes a file or an empty directory.
+	pub fn remove(&mut self, path: &str) -> Result<()> {
+		let entry = self.find(path)?.ok_or(Error::InvalidName)?;
+		if entry.is_dir() && (entry.cluster == 0 || !self.read_dir_entries(entry.cluster)?.is_empty()) {
+			return Err(Error::NotEmpty);
+		}
+
+		for slot in &entry.slots {
+			self.write_bytes(*slot, &[ENTRY_DELETED])?;
+		}
+		if entry.cluster != 0 {
+			let chain = self.chain(entry.cluster)?;
+			self.free_clusters_of(&chain)?;
+		}
+		self.flush_fat()
+	}
+
+	/// Writes the FAT and the free cluster hints and flushes the device.
+	pub fn sync(&mut self) -> Result<()> {
+		self.flush_fat()?;
+		if let Some(offset) = self.fs_info {
+			let mut sector = [0u8; 512];
+			self.read_bytes(offset, &mut sector)?;
+			let mut info = read_struct::<FsInfo>(&sector, 0);
+			info.free_count = self.free.unwrap_or(FsInfo::UNKNOWN);
+			info.next_free = self.next_free;
+			// SAFETY: `FsInfo` is packed and 512 bytes
+			sector = unsafe { core::mem::transmute::<FsInfo, [u8; 512]>(info) };
+			self.write_bytes(offset, &sector)?;
+		}
+		Ok(self.dev.flush()?)
+	}
+
+	/// Returns the device without syncing.
+	pub fn into_inner(self) -> D {
+		self.dev
+	}
+
+	fn create(&mut self, path: &str, attributes: u8) -> Result<Entry> {
+		let (parent, name) = split(path);
+		check_name(name)?;
+		let dir = self.dir(parent)?;
+		let raw = self.read_dir_raw(dir)?;
+		let entries = parse(&raw);
+		if entries.iter().any(|e| e.matches(name)) {
+			return Err(Error::Exists);
+		}
+
+		let (short, nt_res, long) = match short_name_of(name) {
+			Some((short, nt_res)) => (short, nt_res, Vec::new()),
+			None => {
+				let taken = raw.iter()
+					.map(|(_, bytes)| read_entry(bytes))
+					.take_while(|e| e.name[0] != ENTRY_END)
+					.filter(|e| e.name[0] != ENTRY_DELETED && !e.is_long_name())
+					.map(|e| e.name)
+					.collect::<BTreeSet<_>>();
+				let short = (1..1_000_000)
+					.map(|n| numbered_short_name(name, n))
+					.find(|short| !taken.contains(short))
+					.ok_or(Error::Exists)?;
+				(short, 0, long_name_entries(name, short_name_checksum(&short)))
+			}
+		};
+
+		let slots = self.alloc_slots(dir, long.len() + 1)?;
+		let cluster = match attributes & ATTR_DIRECTORY != 0 {
+			true  => self.alloc(1, None)?[0],
+			false => 0
+		};
+
+		let (date, time) = self.time.to_fat();
+		let mut entry = DirEntry {
+			name: short,
+			attributes,
+			nt_res,
+			created_time:  time,
+			created_date:  date,
+			accessed_date: date,
+			modified_time: time,
+			modified_date: date,
+			..DirEntry::default()
+		};
+		entry.set_cluster(cluster);
+
+		if cluster != 0 {
+			let dot = DirEntry { name: *b".          ", nt_res: 0, ..entry };
+			let mut dotdot = DirEntry { name: *b"..         ", ..dot };
+			dotdot.set_cluster(dir);
+			let offset = self.cluster_offset(cluster);
+			self.write_bytes(offset, &entry_bytes(&dot))?;
+			self.write_bytes(offset + ENTRY_SIZE as u64, &entry_bytes(&dotdot))?;
+		}
+		for (slot, long) in slots.iter().zip(&long) {
+			self.write_bytes(*slot, &entry_bytes(long))?;
+		}
+		self.write_bytes(*slots.last().unwrap(), &entry_bytes(&entry))?;
+		self.flush_fat()?;
+
+		Ok(Entry {
+			name: name.into(),
+			short_name: short_name_str(&entry),
+			attributes,
+			size: 0,
+			cluster,
+			created: self.time,
+			modified: self.time,
+			slots
+		})
+	}
+
+	/// The entry at `path` or `None` for the root directory.
+	fn find(&mut self, path: &str) -> Result<Option<Entry>> {
+		let mut found: Option<Entry> = None;
+		for name in path.split('/').filter(|c| !c.is_empty()) {
+			let dir = match &found {
+				None                    => 0,
+				Some(e) if e.is_dir()   => e.cluster,
+				Some(_)                 => return Err(Error::NotADirectory)
+			};
+			found = Some(self.read_dir_entries(dir)?.into_iter().find(|e| e.matches(name)).ok_or(Error::NotFound)?);
+		}
+		Ok(found)
+	}
+
+	/// The first cluster of the directory at `path`, zero for the root directory.
+	fn dir(&mut self, path: &str) -> Result<u32> {
+		match self.find(path)? {
+			None                  => Ok(0),
+			Some(e) if e.is_dir() => Ok(e.cluster),
+			Some(_)               => Err(Error::NotADirectory)
+		}
+	}
+
+	fn file(&mut self, path: &str) -> Result<Entry> {
+		match self.find(path)? {
+			Some(e) if !e.is_dir() => Ok(e),
+			_                      => Err(Error::IsADirectory)
+		}
+	}
+
+	fn read_dir_entries(&mut self, dir: u32) -> Result<Vec<Entry>> {
+		Ok(parse(&self.read_dir_raw(dir)?))
+	}
+
+	/// All entries of a directory, including free ones, with their device offsets.
+	fn read_dir_raw(&mut self, dir: u32) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>> {
+		let regions = match (dir, self.kind) {
+			(0, FatType::Fat12 | FatType::Fat16) => vec![(self.root_offset, self.root_entries as u64 * ENTRY_SIZE as u64)],
+			_ => {
+				let start = if dir == 0 { self.root_cluster } else { dir };
+				self.chain(start)?.into_iter().map(|c| (self.cluster_offset(c), self.cluster_size)).collect()
+			}
+		};
+
+		let mut raw = Vec::new();
+		let mut buf = Vec::new();
+		for (offset, len) in regions {
+			buf.resize(len as usize, 0);
+			self.read_bytes(offset, &mut buf)?;
+			for (i, chunk) in buf.chunks_exact(ENTRY_SIZE).enumerate() {
+				let mut bytes = [0; ENTRY_SIZE];
+				bytes.copy_from_slice(chunk);
+				raw.push((offset + (i * ENTRY_SIZE) as u64, bytes));
+			}
+		}
+		Ok(raw)
+	}
+
+	/// Finds `count` consecutive free entries in a directory, which is extended if there
+	/// are none.
+	fn alloc_slots(&mut self, dir: u32, count: usize) -> Result<Vec<u64>> {
+		let mut run = Vec::new();
+		let mut end = false;
+		for (offset, bytes) in self.read_dir_raw(dir)? {
+			end |= bytes[0] == ENTRY_END;
+			if end || bytes[0] == ENTRY_DELETED {
+				run.push(offset);
+				if run.len() == count {
+					return Ok(run);
+				}
+			} else {
+				run.clear();
+			}
+		}
+
+		if dir == 0 && self.kind != FatType::Fat32 {
+			return Err(Error::NoSpace);
+		}
+		let start = if dir == 0 { self.root_cluster } else { dir };
+		let chain = self.chain(start)?;
+		let needed = ((count - run.len()) * ENTRY_SIZE) as u64;
+		let clusters = (needed + self.cluster_size - 1) / self.cluster_size;
+		if (chain.len() as u64 + clusters) * self.cluster_size > MAX_DIR_SIZE {
+			return Err(Error::NoSpace);
+		}
+
+		for cluster in self.alloc(clusters as usize, chain.last().copied())? {
+			let offset = self.cluster_offset(cluster);
+			run.extend((0..self.cluster_size).step_by(ENTRY_SIZE).map(|i| offset + i));
+		}
+		run.truncate(count);
+		Ok(run)
+	}
+
+	/// Writes the size, first cluster and modification time of an entry to its short name
+	/// entry.
+	fn update_entry(&mut self, entry: &Entry) -> Result<()> {
+		let mut bytes = [0; ENTRY_SIZE];
+		self.read_bytes(entry.slot(), &mut bytes)?;
+		let mut raw = read_entry(&bytes);
+		let (date, time) = self.time.to_fat();
+		raw.size = entry.size;
+		raw.set_cluster(entry.cluster);
+		raw.attributes |= ATTR_ARCHIVE;
+		raw.modified_date = date;
+		raw.modified_time = time;
+		raw.accessed_date = date;
+		self.write_bytes(entry.slot(), &entry_bytes(&raw))
+	}
+
+	/// Grows or shrinks the cluster chain of a file to fit `size`, the bytes between the
+	/// old and the new size are zeroed.
+	fn resize(&mut self, entry: &mut Entry, size: u64) -> Result<()> {
+		let mut chain = self.chain(entry.cluster)?;
+		let old = entry.size as u64;
+		let needed = ((size + self.cluster_size - 1) / self.cluster_size) as usize;
+
+		if needed > chain.len() {
+			let new = self.alloc(needed - chain.len(), chain.last().copied())?;
+			if chain.is_empty() {
+				entry.cluster = new[0];
+			}
+			chain.extend(new);
+		} else if needed < chain.len() {
+			match needed {
+				0 => entry.cluster = 0,
+				n => self.set_next(chain[n - 1], self.eoc())?
+			}
+			self.free_clusters_of(&chain[needed..])?;
+			chain.truncate(needed);
+		}
+
+		// clusters are zeroed when allocated, zero the rest of the previous last cluster
+		let allocated = (chain.len() as u64 * self.cluster_size).min(size);
+		if old < allocated {
+			let end = allocated.min((old / self.cluster_size + 1) * self.cluster_size);
+			self.data_io(&chain, old, Data::Write(&vec![0; (end - old) as usize]))?;
+		}
+		entry.size = size as u32;
+		Ok(())
+	}
+
+	fn data_io(&mut self, chain: &[u32], mut offset: u64, mut data: Data) -> Result<()> {
+		let mut done = 0;
+		while done < data.len() {
+			let cluster = *chain.get((offset / self.cluster_size) as usize).ok_or(Error::Corrupted)?;
+			let within = offset % self.cluster_size;
+			let len = ((self.cluster_size - within) as usize).min(data.len() - done);
+			let dev_offset = self.cluster_offset(cluster) + within;
+			match &mut data {
+				Data::Read(buf)  => self.read_bytes(dev_offset, &mut buf[done..done + len])?,
+				Data::Write(buf) => self.write_bytes(dev_offset, &buf[done..done + len])?
+			}
+			done += len;
+			offset += len as u64;
+		}
+		Ok(())
+	}
+
+	fn cluster_offset(&self, cluster: u32) -> u64 {
+		self.data_offset + (cluster as u64 - 2) * self.cluster_size
+	}
+
+	fn is_cluster(&self, cluster: u32) -> bool {
+		cluster >= 2 && cluster < self.clusters + 2
+	}
+
+	/// The value marking the end of a chain.
+	fn eoc(&self) -> u32 {
+		match self.kind {
+			FatType::Fat12 => 0xFFF,
+			FatType::Fat16 => 0xFFFF,
+			FatType::Fat32 => FAT32_MASK
+		}
+	}
+
+	fn is_eoc(&self, value: u32) -> bool {
+		value >= match self.kind {
+			FatType::Fat12 => FAT12_EOC,
+			FatType::Fat16 => FAT16_EOC,
+			FatType::Fat32 => FAT32_EOC
+		}
+	}
+
+	/// The clusters of a chain, empty if `start` is zero.
+	fn chain(&mut self, start: u32) -> Result<Vec<u32>> {
+		let mut chain = Vec::new();
+		let mut cluster = start;
+		if start == 0 {
+			return Ok(chain);
+		}
+		loop {
+			// a chain longer than the number of clusters has a loop
+			if !self.is_cluster(cluster) || chain.len() >= self.clusters as usize {
+				return Err(Error::Corrupted);
+			}
+			chain.push(cluster);
+			cluster = self.next(cluster)?;
+			if self.is_eoc(cluster) {
+				break;
+			}
+		}
+		Ok(chain)
+	}
+
+	/// Allocates and zeroes `count` clusters and links them to a chain, which is appended
+	/// to `prev` if given.
+	fn alloc(&mut self, count: usize, prev: Option<u32>) -> Result<Vec<u32>> {
+		let mut clusters = Vec::with_capacity(count);
+		let mut cluster = self.next_free;
+		for _ in 0..self.clusters {
+			if clusters.len() == count {
+				break;
+			}
+			if self.next(cluster)? == 0 {
+				clusters.push(cluster);
+			}
+			cluster = match cluster + 1 < self.clusters + 2 {
+				true  => cluster + 1,
+				false => 2
+			};
+		}
+		if clusters.len() < count {
+			return Err(Error::NoSpace);
+		}
+
+		let zero = vec![0; self.cluster_size as usize];
+		for (i, c) in clusters.iter().enumerate() {
+			self.write_bytes(self.cluster_offset(*c), &zero)?;
+			let next = clusters.get(i + 1).copied().unwrap_or(self.eoc());
+			self.set_next(*c, next)?;
+		}
+		if let (Some(prev), Some(first)) = (prev, clusters.first()) {
+			self.set_next(prev, *first)?;
+		}
+
+		self.next_free = cluster;
+		self.free = self.free.map(|n| n.saturating_sub(count as u32));
+		Ok(clusters)
+	}
+
+	fn free_clusters_of(&mut self, clusters: &[u32]) -> Result<()> {
+		for cluster in clusters {
+			self.set_next(*cluster, 0)?;
+		}
+		self.free = self.free.map(|n| (n + clusters.len() as u32).min(self.clusters));
+		Ok(())
+	}
+
+	/// The FAT entry of a cluster.
+	fn next(&mut self, cluster: u32) -> Result<u32> {
+		let c = cluster as u64;
+		Ok(match self.kind {
+			FatType::Fat12 => {
+				let mut b = [0; 2];
+				self.fat_io(c + c / 2, &mut b, false)?;
+				match cluster & 1 {
+					0 => u16::from_le_bytes(b) as u32 & 0xFFF,
+					_ => u16::from_le_bytes(b) as u32 >> 4
+				}
+			}
+			FatType::Fat16 => {
+				let mut b = [0; 2];
+				self.fat_io(c * 2, &mut b, false)?;
+				u16::from_le_bytes(b) as u32
+			}
+			FatType::Fat32 => {
+				let mut b = [0; 4];
+				self.fat_io(c * 4, &mut b, false)?;
+				u32::from_le_bytes(b) & FAT32_MASK
+			}
+		})
+	}
+
+	fn set_next(&mut self, cluster: u32, value: u32) -> Result<()> {
+		let c = cluster as u64;
+		match self.kind {
+			FatType::Fat12 => {
+				let mut b = [0; 2];
+				self.fat_io(c + c / 2, &mut b, false)?;
+				let old = u16::from_le_bytes(b);
+				let new = match cluster & 1 {
+					0 => old & 0xF000 | value as u16 & 0xFFF,
+					_ => old & 0x000F | (value as u16) << 4
+				};
+				self.fat_io(c + c / 2, &mut new.to_le_bytes(), true)
+			}
+			FatType::Fat16 => self.fat_io(c * 2, &mut (value as u16).to_le_bytes(), true),
+			FatType::Fat32 => {
+				// the upper four bits are reserved and have to be preserved
+				let mut b = [0; 4];
+				self.fat_io(c * 4, &mut b, false)?;
+				let new = u32::from_le_bytes(b) & !FAT32_MASK | value & FAT32_MASK;
+				self.fat_io(c * 4, &mut new.to_le_bytes(), true)
+			}
+		}
+	}
+
+	/// Reads or writes bytes of the cached FAT.
+	fn fat_io(&mut self, offset: u64, buf: &mut [u8], write: bool) -> Result<()> {
+		for (i, byte) in buf.iter_mut().enumerate() {
+			let pos = offset + i as u64;
+			let index = pos / self.sector_size;
+			if !self.fat.contains_key(&index) {
+				let mut sector = vec![0; self.sector_size as usize];
+				let fat = self.active_fat.unwrap_or(0) as u64;
+				self.read_bytes(self.fat_offset + fat * self.fat_size + index * self.sector_size, &mut sector)?;
+				self.fat.insert(index, sector);
+			}
+
+			let sector = self.fat.get_mut(&index).unwrap();
+			let pos = (pos % self.sector_size) as usize;
+			match write {
+				true => {
+					sector[pos] = *byte;
+					self.dirty.insert(index);
+				}
+				false => *byte = sector[pos]
+			}
+		}
+		Ok(())
+	}
+
+	/// Writes the changed FAT sectors to all FATs, or only to the active one.
+	fn flush_fat(&mut self) -> Result<()> {
+		let fats = match self.active_fat {
+			Some(fat) => fat..fat + 1,
+			None      => 0..self.fats
+		};
+		for index in core::mem::take(&mut self.dirty) {
+			let sector = self.fat[&index].clone();
+			for fat in fats.clone() {
+				self.write_bytes(self.fat_offset + fat as u64 * self.fat_size + index * self.sector_size, &sector)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+		read_bytes(&mut self.dev, offset, buf)
+	}
+
+	fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+		let block_size = self.dev.block_size();
+		let lba = offset / block_size as u64;
+		let skip = (offset % block_size as u64) as usize;
+		let blocks = (skip + buf.len() + block_size - 1) / block_size;
+
+		// read the partially written first and last block
+		let mut tmp = block::alloc_buffer(&self.dev, blocks)?;
+		if skip != 0 {
+			self.dev.read(lba, &mut tmp[..block_size])?;
+		}
+		if (skip + buf.len()) % block_size != 0 && (blocks > 1 || skip == 0) {
+			self.dev.read(lba + blocks as u64 - 1, &mut tmp[(blocks - 1) * block_size..])?;
+		}
+		tmp[skip..skip + buf.len()].copy_from_slice(buf);
+		Ok(self.dev.write(lba, &tmp)?)
+	}
+}
+
+impl<D> core::fmt::Debug for FileSystem<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("FileSystem")
+			.field("kind", &self.kind)
+			.field("label", &self.label)
+			.field("cluster_size", &self.cluster_size)
+			.field("clusters", &self.clusters)
+			.field("free", &self.free)
+			.finish()
+	}
+}
+
+enum Data<'a> {
+	Read(&'a mut [u8]),
+	Write(&'a [u8])
+}
+
+impl Data<'_> {
+	fn len(&self) -> usize {
+		match self {
+			Self::Read(buf)  => buf.len(),
+			Self::Write(buf) => buf.len()
+		}
+	}
+}
+
+/// Splits a path into the parent directory and the last component.
+fn split(path: &str) -> (&str, &str) {
+	let path = path.trim_end_matches('/');
+	match path.rfind('/') {
+		Some(i) => (&path[..i], &path[i + 1..]),
+		None    => ("", path)
+	}
+}
+
+fn read_struct<T: Copy>(bytes: &[u8], offset: usize) -> T {
+	assert!(offset + core::mem::size_of::<T>() <= bytes.len());
+	// SAFETY: the structs are packed and the range was checked
+	unsafe { (bytes.as_ptr().add(offset) as *const T).read_unaligned() }
+}
+
+fn read_bytes<D: BlockDevice>(dev: &mut D, offset: u64, buf: &mut [u8]) -> Result<()> {
+	let block_size = dev.block_size();
+	let skip = (offset % block_size as u64) as usize;
+	let blocks = (skip + buf.len() + block_size - 1) / block_size;
+	let mut tmp = block::alloc_buffer(dev, blocks)?;
+	dev.read(offset / block_size as u64, &mut tmp)?;
+	buf.copy_from_slice(&tmp[skip..skip + buf.len()]);
+	Ok(())
+}
@@ -0,0 +1,307 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! FAT12, FAT16 and FAT32 file systems with long file names.
+//!
+//! `FileSystem` addresses files by their path, components are separated by `/` and
+//! compared case-insensitively like on Windows. The on-disk structures below follow the
+//! Microsoft FAT specification.
+
+mod dir;
+mod fs;
+
+pub use {dir::*, fs::*};
+
+use crate::block;
+
+/// Cluster values at or above this mark the end of a chain, after masking to the FAT width
+pub const FAT12_EOC: u32 = 0xFF8;
+pub const FAT16_EOC: u32 = 0xFFF8;
+pub const FAT32_EOC: u32 = 0x0FFF_FFF8;
+/// Cluster values at or above this, but below the end of chain mark, are bad clusters
+pub const FAT12_BAD: u32 = 0xFF7;
+pub const FAT16_BAD: u32 = 0xFFF7;
+pub const FAT32_BAD: u32 = 0x0FFF_FFF7;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN:    u8 = 0x02;
+pub const ATTR_SYSTEM:    u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE:   u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// `DirEntry::nt_res` flags of Windows NT and Linux, the base name or extension of a short
+/// name without a long name is displayed in lower case
+pub const NT_LOWER_BASE: u8 = 0x08;
+pub const NT_LOWER_EXT:  u8 = 0x10;
+
+/// First name byte of a free entry, all following entries are free as well
+pub const ENTRY_END:     u8 = 0x00;
+/// First name byte of a deleted entry
+pub const ENTRY_DELETED: u8 = 0xE5;
+/// `LfnEntry::ord` flag of the last, i.e. first stored, entry of a long name
+pub const LFN_LAST:      u8 = 0x40;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FatType {
+	Fat12,
+	Fat16,
+	Fat32
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// Reading or writing the device failed
+	Block(block::Error),
+	/// The boot sector doesn't describe a FAT file system
+	NotFat,
+	/// A cluster chain or directory is malformed
+	Corrupted,
+	NotFound,
+	NotADirectory,
+	IsADirectory,
+	Exists,
+	/// The directory to remove isn't empty
+	NotEmpty,
+	/// The name is empty, too long or contains characters FAT doesn't allow
+	InvalidName,
+	/// No free clusters, no room in the fixed root directory or the file would exceed 4 GiB
+	NoSpace
+}
+
+impl From<block::Error> for Error {
+	fn from(e: block::Error) -> Self {
+		Self::Block(e)
+	}
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The BIOS parameter block shared by all FAT types, at the start of the boot sector.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct Bpb {
+	pub jump:                [u8; 3],
+	pub oem_name:            [u8; 8],
+	pub bytes_per_sector:    u16,
+	/// A power of two
+	pub sectors_per_cluster: u8,
+	/// Sectors before the first FAT, including the boot sector
+	pub reserved_sectors:    u16,
+	pub fats:                u8,
+	/// Entries of the fixed root directory, zero on FAT32
+	pub root_entries:        u16,
+	/// Zero if the count doesn't fit, then `total_sectors_32` is used
+	pub total_sectors_16:    u16,
+	pub media:               u8,
+	/// Sectors per FAT, zero on FAT32
+	pub fat_size_16:         u16,
+	pub sectors_per_track:   u16,
+	pub heads:               u16,
+	pub hidden_sectors:      u32,
+	pub total_sectors_32:    u32
+}
+
+/// The extended BIOS parameter block of FAT12 and FAT16, follows `Bpb`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct Ebpb16 {
+	pub drive_number: u8,
+	pub _res0:        u8,
+	/// 0x29 if the following fields are valid
+	pub boot_sig:     u8,
+	pub volume_id:    u32,
+	pub volume_label: [u8; 11],
+	/// Informational only, e.g. `FAT12   `
+	pub fs_type:      [u8; 8]
+}
+
+/// The extended BIOS parameter block of FAT32, follows `Bpb`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct Ebpb32 {
+	pub fat_size_32:  u32,
+	/// Bits 0-3 are the active FAT if bit 7 is set, otherwise all FATs are mirrored
+	pub ext_flags:    u16,
+	pub fs_version:   u16,
+	pub root_cluster: u32,
+	/// Sector of the `FsInfo` structure
+	pub fs_info:      u16,
+	/// Sector of the copy of the boot sectors
+	pub backup_boot:  u16,
+	pub _res0:        [u8; 12],
+	pub drive_number: u8,
+	pub _res1:        u8,
+	pub boot_sig:     u8,
+	pub volume_id:    u32,
+	pub volume_label: [u8; 11],
+	pub fs_type:      [u8; 8]
+}
+
+impl Ebpb32 {
+	pub const EXT_FLAGS_NO_MIRRORING: u16 = 0x80;
+}
+
+/// Free cluster hints of FAT32, both may be `UNKNOWN`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct FsInfo {
+	pub lead_sig:   u32,
+	pub _res0:      [u8; 480],
+	pub struct_sig: u32,
+	pub free_count: u32,
+	/// The cluster to start searching for free clusters at
+	pub next_free:  u32,
+	pub _res1:      [u8; 12],
+	pub trail_sig:  u32
+}
+
+impl FsInfo {
+	pub const LEAD_SIG:   u32 = 0x4161_5252;
+	pub const STRUCT_SIG: u32 = 0x6141_7272;
+	pub const TRAIL_SIG:  u32 = 0xAA55_0000;
+	pub const UNKNOWN:    u32 = 0xFFFF_FFFF;
+
+	pub fn is_valid(&self) -> bool {
+		self.lead_sig == Self::LEAD_SIG && self.struct_sig == Self::STRUCT_SIG && self.trail_sig == Self::TRAIL_SIG
+	}
+}
+
+/// A short name directory entry.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DirEntry {
+	/// Space padded 8.3 name without the dot, the first byte is 0x05 if the name starts
+	/// with 0xE5
+	pub name:          [u8; 11],
+	pub attributes:    u8,
+	pub nt_res:        u8,
+	/// Tenths of a second, 0-199
+	pub created_tenth: u8,
+	pub created_time:  u16,
+	pub created_date:  u16,
+	pub accessed_date: u16,
+	/// Zero on FAT12 and FAT16
+	pub cluster_hi:    u16,
+	pub modified_time: u16,
+	pub modified_date: u16,
+	pub cluster_lo:    u16,
+	pub size:          u32
+}
+
+impl DirEntry {
+	pub fn cluster(&self) -> u32 {
+		(self.cluster_hi as u32) << 16 | self.cluster_lo as u32
+	}
+
+	pub fn set_cluster(&mut self, cluster: u32) {
+		self.cluster_hi = (cluster >> 16) as u16;
+		self.cluster_lo = cluster as u16;
+	}
+
+	pub fn is_long_name(&self) -> bool {
+		self.attributes & 0x3F == ATTR_LONG_NAME
+	}
+}
+
+/// A long name entry, up to 20 precede a short name entry and each holds 13 UTF-16 code
+/// units of the name, the entry with the highest ordinal first.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LfnEntry {
+	/// The position of the entry in the name, starting at 1, or'ed with `LFN_LAST`
+	pub ord:        u8,
+	pub name1:      [u16; 5],
+	/// Always `ATTR_LONG_NAME`
+	pub attributes: u8,
+	pub kind:       u8,
+	/// `short_name_checksum` of the short name entry
+	pub checksum:   u8,
+	pub name2:      [u16; 6],
+	pub cluster:    u16,
+	pub name3:      [u16; 2]
+}
+
+impl LfnEntry {
+	/// The 13 code units of the entry.
+	pub fn units(&self) -> [u16; 13] {
+		let (n1, n2, n3) = (self.name1, self.name2, self.name3);
+		let mut units = [0; 13];
+		units[..5].copy_from_slice(&n1);
+		units[5..11].copy_from_slice(&n2);
+		units[11..].copy_from_slice(&n3);
+		units
+	}
+
+	pub fn set_units(&mut self, units: &[u16; 13]) {
+		let (mut n1, mut n2, mut n3) = ([0; 5], [0; 6], [0; 2]);
+		n1.copy_from_slice(&units[..5]);
+		n2.copy_from_slice(&units[5..11]);
+		n3.copy_from_slice(&units[11..]);
+		self.name1 = n1;
+		self.name2 = n2;
+		self.name3 = n3;
+	}
+}
+
+/// The checksum of a short name stored in its long name entries.
+pub fn short_name_checksum(name: &[u8; 11]) -> u8 {
+	name.iter().fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+/// A timestamp as stored in directory entries, local time with two second resolution.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub struct DateTime {
+	/// 1980-2107
+	pub year:   u16,
+	pub month:  u8,
+	pub day:    u8,
+	pub hour:   u8,
+	pub minute: u8,
+	pub second: u8
+}
+
+impl DateTime {
+	/// The earliest representable time, used when no clock is set
+	pub const EPOCH: Self = Self { year: 1980, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+
+	pub fn from_fat(date: u16, time: u16) -> Self {
+		Self {
+			year:   1980 + (date >> 9),
+			month:  (date >> 5 & 0xF) as u8,
+			day:    (date & 0x1F) as u8,
+			hour:   (time >> 11) as u8,
+			minute: (time >> 5 & 0x3F) as u8,
+			second: (time & 0x1F) as u8 * 2
+		}
+	}
+
+	/// The date and time fields.
+	pub fn to_fat(&self) -> (u16, u16) {
+		let year = self.year.clamp(1980, 2107) - 1980;
+		(
+			year << 9 | (self.month as u16 & 0xF) << 5 | self.day as u16 & 0x1F,
+			(self.hour as u16) << 11 | (self.minute as u16 & 0x3F) << 5 | (self.second as u16 / 2)
+		)
+	}
+}
@@ -20,21 +20,30 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use super::Guid;
+//! GUID partition tables.
+//!
+//! `Gpt` reads and edits the table of a `BlockDevice`, `Partition` exposes a partition as a
+//! block device of its own.
+
+mod table;
+
+pub use table::*;
+
+use {crate::uefi::Guid, alloc::string::String};
 
 pub type LBA = u64;
 
 pub const SIGNATURE: u64 = 0x5452415020494645;
 
 pub const UNUSED_ENTRY_GUID:         Guid = 0x00000000000000000000000000000000;
-pub const EFI_SYSTEM_PARTITION_GUID: Guid = 0xC12A7328F81F11D2BA4B00A0C93EC93B;
-pub const LEGACY_MBR_PARTITION_GUID: Guid = 0x024DEE4133E711D39D690008C781F39F;
+pub const EFI_SYSTEM_PARTITION_GUID: Guid = 0x3BC93EC9A0004BBA11D2F81FC12A7328;
+pub const LEGACY_MBR_PARTITION_GUID: Guid = 0x9FF381C70800699D11D333E7024DEE41;
 
 pub const PARTITION_ENTRY_REQUIRED:             u64 = 0x1;
 pub const PARTITION_ENTRY_NO_BLOCK_IO_PROTOCOL: u64 = 0x2;
-pub const PARTITION_ENTRY_LEGACY_BIOS_BOOTABLE: u64 = 0x3;
+pub const PARTITION_ENTRY_LEGACY_BIOS_BOOTABLE: u64 = 0x4;
 
-#[repr(C)]
+#[repr(C, packed)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Header {
 	/// Identifies EFI-compatible partition table header.
@@ -94,12 +103,63 @@ pub struct Header {
 }
 
 impl Header {
+	pub const REVISION: u32 = 0x10000;
+
 	pub fn is_valid(&self) -> bool {
 		self.signature == SIGNATURE
 	}
+
+	/// The CRC32 of the header with `header_crc32` cleared, `bytes` is the sector holding it
+	/// as the header may be larger than this struct.
+	pub fn checksum(&self, bytes: &[u8]) -> u32 {
+		let len = (self.header_size as usize).min(bytes.len());
+		let crc = crate::crc::crc32_update(0, &bytes[..16]);
+		let crc = crate::crc::crc32_update(crc, &[0; 4]);
+		crate::crc::crc32_update(crc, bytes.get(20..len).unwrap_or(&[]))
+	}
+}
+
+impl PartitionEntry {
+	pub fn is_used(&self) -> bool {
+		self.partition_type_guid != UNUSED_ENTRY_GUID
+	}
+
+	/// Size in blocks
+	pub fn blocks(&self) -> u64 {
+		self.ending_lba - self.starting_lba + 1
+	}
+
+	/// The name, which is stored as null-terminated UTF-16.
+	pub fn name(&self) -> String {
+		let units = self.partition_name.chunks(2)
+			.map(|c| u16::from_le_bytes([c[0], c[1]]))
+			.take_while(|c| *c != 0);
+		char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+	}
+
+	/// Sets the name, truncated to 36 UTF-16 code units.
+	pub fn set_name(&mut self, name: &str) {
+		self.partition_name = [0; 72];
+		for (i, unit) in name.encode_utf16().take(36).enumerate() {
+			self.partition_name[i * 2..i * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+		}
+	}
+}
+
+impl Default for PartitionEntry {
+	fn default() -> Self {
+		Self {
+			partition_type_guid:   UNUSED_ENTRY_GUID,
+			unique_partition_guid: 0,
+			starting_lba:          0,
+			ending_lba:            0,
+			attributes:            0,
+			partition_name:        [0; 72]
+		}
+	}
 }
 
-#[repr(C)]
+#[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct PartitionEntry {
 	/// Unique ID that defines the purpose and
@@ -136,11 +196,11 @@ impl core::fmt::Debug for PartitionEntry {
 		while i < self.partition_name.len() && self.partition_name[i] != 0 { i += 1; }
 		
 		f.debug_struct("PartitionEntry")
-			.field("partition_type_guid", &self.partition_type_guid)
-			.field("unique_partition_guid", &self.unique_partition_guid)
-			.field("starting_lba", &self.starting_lba)
-			.field("ending_lba", &self.ending_lba)
-			.field("attributes", &self.attributes)
+			.field("partition_type_guid", &{ self.partition_type_guid })
+			.field("unique_partition_guid", &{ self.unique_partition_guid })
+			.field("starting_lba", &{ self.starting_lba })
+			.field("ending_lba", &{ self.ending_lba })
+			.field("attributes", &{ self.attributes })
 			.field("partition_name", unsafe { &core::str::from_utf8_unchecked(&self.partition_name[..i]) })
 			.finish()
 	}
@@ -0,0 +1,455 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {super::*, crate::block::{self, BlockDevice}, alloc::vec::Vec, core::mem::size_of};
+
+/// Entries of a new table, the minimum the specification allows
+pub const DEFAULT_ENTRIES: u32 = 128;
+/// Largest entry array accepted when reading a table, in bytes
+const MAX_ARRAY_SIZE: usize = 1 << 20;
+
+const MBR_PARTITION_TABLE: usize = 446;
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// Reading or writing the device failed
+	Block(block::Error),
+	/// Neither the primary nor the backup table is valid
+	NoTable,
+	/// The blocks are outside of the usable range, overlap another partition or the device
+	/// is too small for a table
+	InvalidRange,
+	/// A GUID is zero or another partition already has the unique GUID
+	InvalidGuid,
+	/// All entries are in use
+	Full,
+	/// No partition has the unique GUID
+	NotFound
+}
+
+impl From<block::Error> for Error {
+	fn from(e: block::Error) -> Self {
+		Self::Block(e)
+	}
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The partition table of a device.
+///
+/// Changes are written to both copies of the table and the protective MBR right away.
+/// A table that was read from its backup, or whose backup is damaged, stays so until
+/// `repair` or another change rewrites it.
+pub struct Gpt<D> {
+	dev:     D,
+	primary: Header,
+	backup:  Header,
+	/// The entry array, entries may be larger than `PartitionEntry`
+	entries: Vec<u8>,
+	/// Whether the primary and the backup table were valid when read
+	valid:   (bool, bool)
+}
+
+impl<D: BlockDevice> Gpt<D> {
+	/// Reads the table, from the backup at the last block if the primary one is corrupt.
+	pub fn open(mut dev: D) -> Result<Self> {
+		let last = dev.blocks().checked_sub(1).ok_or(Error::NoTable)?;
+		let primary = read_table(&mut dev, 1)?;
+
+		// the backup is where the primary says, which isn't the last block if the disk grew
+		let backup_lba = primary.as_ref()
+			.map(|(h, _)| h.alternate_lba)
+			.filter(|lba| *lba > 1 && *lba <= last)
+			.unwrap_or(last);
+		let backup = read_table(&mut dev, backup_lba)?;
+		let valid = (primary.is_some(), backup.is_some());
+
+		let (header, entries) = primary.or(backup).ok_or(Error::NoTable)?;
+		let array_blocks = array_blocks(&header, dev.block_size());
+		let primary = Header {
+			my_lba:              1,
+			alternate_lba:       backup_lba,
+			partition_entry_lba: match valid.0 {
+				true  => header.partition_entry_lba,
+				false => 2
+			},
+			..header
+		};
+		let backup = Header {
+			my_lba:              backup_lba,
+			alternate_lba:       1,
+			partition_entry_lba: backup_lba - array_blocks,
+			..header
+		};
+		Ok(Self { dev, primary, backup, entries, valid })
+	}
+
+	/// Writes an empty table with `DEFAULT_ENTRIES` entries.
+	pub fn create(dev: D, disk_guid: Guid) -> Result<Self> {
+		let block_size = dev.block_size();
+		let last = dev.blocks().checked_sub(1).ok_or(Error::InvalidRange)?;
+		if disk_guid == UNUSED_ENTRY_GUID {
+			return Err(Error::InvalidGuid);
+		}
+
+		let entries = alloc::vec![0; DEFAULT_ENTRIES as usize * size_of::<PartitionEntry>()];
+		let array_blocks = ((entries.len() + block_size - 1) / block_size) as u64;
+		let first_usable = 2 + array_blocks;
+		let last_usable = last.checked_sub(array_blocks + 1).filter(|l| *l >= first_usable).ok_or(Error::InvalidRange)?;
+
+		let primary = Header {
+			signature:                   SIGNATURE,
+			revision:                    Header::REVISION,
+			header_size:                 size_of::<Header>() as u32,
+			header_crc32:                0,
+			_res0:                       0,
+			my_lba:                      1,
+			alternate_lba:               last,
+			first_usable_lba:            first_usable,
+			last_usable_lba:             last_usable,
+			disk_guid,
+			partition_entry_lba:         2,
+			number_of_partition_entries: DEFAULT_ENTRIES,
+			size_of_partition_entry:     size_of::<PartitionEntry>() as u32,
+			partition_entry_array_crc32: 0
+		};
+		let backup = Header { my_lba: last, alternate_lba: 1, partition_entry_lba: last_usable + 1, ..primary };
+
+		let mut gpt = Self { dev, primary, backup, entries, valid: (false, false) };
+		gpt.write()?;
+		Ok(gpt)
+	}
+
+	/// The primary header, `header_crc32` is only current after the table was written.
+	pub fn header(&self) -> &Header {
+		&self.primary
+	}
+
+	pub fn disk_guid(&self) -> Guid {
+		self.primary.disk_guid
+	}
+
+	/// Whether either copy of the table was found corrupt.
+	pub fn needs_repair(&self) -> bool {
+		!(self.valid.0 && self.valid.1)
+	}
+
+	/// Rewrites both copies of the table.
+	pub fn repair(&mut self) -> Result<()> {
+		self.write()
+	}
+
+	/// Number of entries, used or not
+	pub fn len(&self) -> usize {
+		self.primary.number_of_partition_entries as usize
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub fn entry(&self, index: usize) -> Option<PartitionEntry> {
+		let size = self.primary.size_of_partition_entry as usize;
+		(index < self.len()).then(|| {
+			// SAFETY: the array holds `len` entries of at least `size_of::<PartitionEntry>()` bytes
+			unsafe { (self.entries.as_ptr().add(index * size) as *const PartitionEntry).read_unaligned() }
+		})
+	}
+
+	fn set_entry(&mut self, index: usize, entry: PartitionEntry) {
+		let size = self.primary.size_of_partition_entry as usize;
+		let bytes = &mut self.entries[index * size..(index + 1) * size];
+		bytes.fill(0);
+		// SAFETY: see `entry`
+		unsafe { (bytes.as_mut_ptr() as *mut PartitionEntry).write_unaligned(entry) }
+	}
+
+	/// The used entries and their indices.
+	pub fn partitions(&self) -> impl Iterator<Item = (usize, PartitionEntry)> + '_ {
+		(0..self.len()).filter_map(|i| self.entry(i).filter(PartitionEntry::is_used).map(|e| (i, e)))
+	}
+
+	/// The partition with the unique GUID.
+	pub fn find(&self, guid: Guid) -> Option<(usize, PartitionEntry)> {
+		self.partitions().find(|(_, e)| { e.unique_partition_guid } == guid)
+	}
+
+	/// The first block of the lowest gap that holds `blocks` blocks starting at a multiple
+	/// of `align`.
+	pub fn find_free(&self, blocks: u64, align: u64) -> Option<LBA> {
+		let align = align.max(1);
+		let mut used = self.partitions().map(|(_, e)| (e.starting_lba, e.ending_lba)).collect::<Vec<_>>();
+		used.sort_unstable();
+
+		let mut start = self.primary.first_usable_lba;
+		for (first, last) in used.into_iter().chain(core::iter::once((self.primary.last_usable_lba + 1, 0))) {
+			let aligned = (start + align - 1) / align * align;
+			if aligned.checked_add(blocks).map_or(false, |end| end <= first) {
+				return Some(aligned);
+			}
+			start = start.max(last + 1);
+		}
+		None
+	}
+
+	/// Adds a partition from `first` to `last`, inclusive, returns its index.
+	pub fn add(&mut self, type_guid: Guid, guid: Guid, first: LBA, last: LBA, name: &str) -> Result<usize> {
+		if type_guid == UNUSED_ENTRY_GUID || guid == UNUSED_ENTRY_GUID || self.find(guid).is_some() {
+			return Err(Error::InvalidGuid);
+		}
+		self.check_range(first, last, None)?;
+
+		let index = (0..self.len()).find(|i| self.entry(*i).map_or(false, |e| !e.is_used())).ok_or(Error::Full)?;
+		let mut entry = PartitionEntry {
+			partition_type_guid:   type_guid,
+			unique_partition_guid: guid,
+			starting_lba:          first,
+			ending_lba:            last,
+			..PartitionEntry::default()
+		};
+		entry.set_name(name);
+		self.set_entry(index, entry);
+		self.write()?;
+		Ok(index)
+	}
+
+	/// Moves the end of a partition to `last`, its contents aren't touched.
+	pub fn resize(&mut self, guid: Guid, last: LBA) -> Result<()> {
+		let (index, mut entry) = self.find(guid).ok_or(Error::NotFound)?;
+		self.check_range(entry.starting_lba, last, Some(index))?;
+		entry.ending_lba = last;
+		self.set_entry(index, entry);
+		self.write()
+	}
+
+	/// Removes a partition, its contents aren't touched.
+	pub fn remove(&mut self, guid: Guid) -> Result<PartitionEntry> {
+		let (index, entry) = self.find(guid).ok_or(Error::NotFound)?;
+		self.set_entry(index, PartitionEntry::default());
+		self.write()?;
+		Ok(entry)
+	}
+
+	/// A block device of the partition with the unique GUID, sharing the device.
+	pub fn partition(&self, guid: Guid) -> Option<Partition<D>> where D: Clone {
+		self.find(guid).map(|(_, e)| Partition::new(self.dev.clone(), &e))
+	}
+
+	pub fn device(&mut self) -> &mut D {
+		&mut self.dev
+	}
+
+	pub fn into_inner(self) -> D {
+		self.dev
+	}
+
+	fn check_range(&self, first: LBA, last: LBA, except: Option<usize>) -> Result<()> {
+		let usable = first >= self.primary.first_usable_lba && first <= last && last <= self.primary.last_usable_lba;
+		let overlaps = self.partitions()
+			.any(|(i, e)| Some(i) != except && first <= e.ending_lba && e.starting_lba <= last);
+		match usable && !overlaps {
+			true  => Ok(()),
+			false => Err(Error::InvalidRange)
+		}
+	}
+
+	/// Writes the protective MBR and both copies of the table, the backup first so one copy
+	/// is always valid.
+	fn write(&mut self) -> Result<()> {
+		let crc = crate::crc::crc32(&self.entries);
+		let block_size = self.dev.block_size();
+
+		let mut mbr = block::alloc_buffer(&self.dev, 1)?;
+		self.dev.read(0, &mut mbr)?;
+		protective_mbr(&mut mbr, self.dev.blocks());
+		self.dev.write(0, &mbr)?;
+
+		let mut entries = self.entries.clone();
+		entries.resize(array_blocks(&self.primary, block_size) as usize * block_size, 0);
+
+		for header in [&mut self.backup, &mut self.primary] {
+			header.partition_entry_array_crc32 = crc;
+			header.header_size = size_of::<Header>() as u32;
+			header.header_crc32 = 0;
+
+			let mut block = block::alloc_buffer(&self.dev, 1)?;
+			// SAFETY: a block is at least as large as the header
+			unsafe { (block.as_mut_ptr() as *mut Header).write_unaligned(*header) };
+			header.header_crc32 = header.checksum(&block);
+			unsafe { (block.as_mut_ptr() as *mut Header).write_unaligned(*header) };
+
+			self.dev.write(header.partition_entry_lba, &entries)?;
+			self.dev.write(header.my_lba, &block)?;
+		}
+
+		self.dev.flush()?;
+		self.valid = (true, true);
+		Ok(())
+	}
+}
+
+impl<D> core::fmt::Debug for Gpt<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Gpt")
+			.field("disk_guid", &{ self.primary.disk_guid })
+			.field("entries", &{ self.primary.number_of_partition_entries })
+			.field("valid", &self.valid)
+			.finish()
+	}
+}
+
+/// Blocks of the entry array of a table
+fn array_blocks(header: &Header, block_size: usize) -> u64 {
+	let len = header.number_of_partition_entries as u64 * header.size_of_partition_entry as u64;
+	(len + block_size as u64 - 1) / block_size as u64
+}
+
+/// Reads the header at `lba` and its entry array, returns `None` if either is invalid.
+fn read_table<D: BlockDevice>(dev: &mut D, lba: LBA) -> Result<Option<(Header, Vec<u8>)>> {
+	let block_size = dev.block_size();
+	if block_size < size_of::<Header>() || lba >= dev.blocks() {
+		return Ok(None);
+	}
+
+	let mut block = block::alloc_buffer(dev, 1)?;
+	dev.read(lba, &mut block)?;
+	// SAFETY: the block is at least as large as the header
+	let header = unsafe { (block.as_ptr() as *const Header).read_unaligned() };
+
+	let entry_size = header.size_of_partition_entry as usize;
+	let len = header.number_of_partition_entries as usize * entry_size;
+	let blocks = array_blocks(&header, block_size);
+	let valid = header.is_valid()
+		&& header.header_size as usize >= size_of::<Header>()
+		&& header.header_size as usize <= block_size
+		&& header.header_crc32 == header.checksum(&block)
+		&& header.my_lba == lba
+		&& header.first_usable_lba <= header.last_usable_lba
+		&& header.last_usable_lba < dev.blocks()
+		&& entry_size >= size_of::<PartitionEntry>()
+		&& entry_size % 8 == 0
+		&& len <= MAX_ARRAY_SIZE
+		&& header.partition_entry_lba.checked_add(blocks).map_or(false, |end| end <= dev.blocks());
+	if !valid {
+		return Ok(None);
+	}
+
+	let mut entries = block::alloc_buffer(dev, blocks as usize)?;
+	dev.read(header.partition_entry_lba, &mut entries)?;
+	entries.truncate(len);
+
+	Ok((crate::crc::crc32(&entries) == header.partition_entry_array_crc32).then_some((header, entries)))
+}
+
+/// Replaces the partition records of an MBR with one covering the disk, the boot code
+/// is kept.
+fn protective_mbr(mbr: &mut [u8], blocks: u64) {
+	let size = u32::try_from(blocks - 1).unwrap_or(u32::MAX);
+	let record = &mut mbr[MBR_PARTITION_TABLE..MBR_PARTITION_TABLE + 64];
+	record.fill(0);
+	// start CHS 0/0/2, end CHS at its maximum
+	record[..16].copy_from_slice(&[
+		0x00, 0x00, 0x02, 0x00, MBR_TYPE_PROTECTIVE, 0xFF, 0xFF, 0xFF,
+		1, 0, 0, 0, size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8
+	]);
+	mbr[510] = 0x55;
+	mbr[511] = 0xAA;
+}
+
+/// A partition as a block device, addressed relative to its first block.
+#[derive(Clone, Debug)]
+pub struct Partition<D> {
+	dev:           D,
+	pub guid:      Guid,
+	pub type_guid: Guid,
+	first:         LBA,
+	blocks:        u64
+}
+
+impl<D> Partition<D> {
+	pub fn new(dev: D, entry: &PartitionEntry) -> Self {
+		Self {
+			dev,
+			guid:      entry.unique_partition_guid,
+			type_guid: entry.partition_type_guid,
+			first:     entry.starting_lba,
+			blocks:    entry.blocks()
+		}
+	}
+
+	/// The block of the device the partition starts at.
+	pub fn first_lba(&self) -> LBA {
+		self.first
+	}
+
+	pub fn into_inner(self) -> D {
+		self.dev
+	}
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+	fn block_size(&self) -> usize {
+		self.dev.block_size()
+	}
+
+	fn blocks(&self) -> u64 {
+		self.blocks
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		self.dev.read(self.first + lba, buf)
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		self.dev.write(self.first + lba, buf)
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		self.dev.flush()
+	}
+
+	fn discard(&mut self, lba: u64, blocks: u64) -> block::Result<()> {
+		block::check_blocks(self, lba, blocks)?;
+		self.dev.discard(self.first + lba, blocks)
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.dev.is_read_only()
+	}
+
+	fn max_blocks(&self) -> u64 {
+		self.dev.max_blocks()
+	}
+
+	fn read_vectored(&mut self, lba: u64, bufs: &mut [&mut [u8]]) -> block::Result<()> {
+		block::check_range(self, lba, bufs.iter().map(|b| b.len()).sum())?;
+		self.dev.read_vectored(self.first + lba, bufs)
+	}
+
+	fn write_vectored(&mut self, lba: u64, bufs: &[&[u8]]) -> block::Result<()> {
+		block::check_range(self, lba, bufs.iter().map(|b| b.len()).sum())?;
+		self.dev.write_vectored(self.first + lba, bufs)
+	}
+}
@@ -0,0 +1,331 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {super::*, alloc::vec::Vec};
+
+/// Output pins in order of preference
+const OUTPUT_DEVICES: [u32; 3] = [DEVICE_LINE_OUT, DEVICE_SPEAKER, DEVICE_HEADPHONE];
+/// Input pins in order of preference
+const INPUT_DEVICES: [u32; 3] = [DEVICE_MIC, DEVICE_LINE_IN, DEVICE_AUX];
+/// Mixers and selectors a path may pass through
+const MAX_DEPTH: usize = 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WidgetType {
+	/// Output converter, a DAC
+	Output,
+	/// Input converter, an ADC
+	Input,
+	Mixer,
+	Selector,
+	Pin,
+	Power,
+	VolumeKnob,
+	Beep,
+	Vendor,
+	Reserved(u8)
+}
+
+impl From<u32> for WidgetType {
+	fn from(caps: u32) -> Self {
+		match ((caps & WCAP_TYPE_MASK) >> WCAP_TYPE_SHIFT) as u8 {
+			0x0 => Self::Output,
+			0x1 => Self::Input,
+			0x2 => Self::Mixer,
+			0x3 => Self::Selector,
+			0x4 => Self::Pin,
+			0x5 => Self::Power,
+			0x6 => Self::VolumeKnob,
+			0x7 => Self::Beep,
+			0xF => Self::Vendor,
+			v   => Self::Reserved(v)
+		}
+	}
+}
+
+/// A widget of an audio function group, capabilities the widget doesn't override are
+/// those of the function group.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Widget {
+	pub node:        u8,
+	/// `WCAP_*`
+	pub caps:        u32,
+	/// `PINCAP_*`, zero for other widgets
+	pub pin_caps:    u32,
+	/// Configuration default, zero for other widgets
+	pub config:      u32,
+	/// `PARAM_PCM` of converters
+	pub pcm:         u32,
+	pub in_amp:      u32,
+	pub out_amp:     u32,
+	/// The widgets whose output this widget takes as input
+	pub connections: Vec<u8>
+}
+
+impl Widget {
+	pub fn kind(&self) -> WidgetType {
+		self.caps.into()
+	}
+
+	/// The default device of a pin, `DEVICE_*`.
+	pub fn device(&self) -> u32 {
+		(self.config & CONFIG_DEVICE_MASK) >> CONFIG_DEVICE_SHIFT
+	}
+
+	/// Whether a pin is connected to a jack or built-in device.
+	pub fn is_connected(&self) -> bool {
+		self.config & CONFIG_PORT_MASK != CONFIG_PORT_NONE
+	}
+
+	pub fn is_digital(&self) -> bool {
+		self.caps & WCAP_DIGITAL != 0
+	}
+}
+
+/// The widgets a stream passes through, in the direction of the signal: from the
+/// converter to the pin for output, from the pin to the converter for input.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Path {
+	pub direction: Direction,
+	pub widgets:   Vec<u8>
+}
+
+impl Path {
+	pub fn converter(&self) -> u8 {
+		match self.direction {
+			Direction::Output => self.widgets[0],
+			Direction::Input  => self.widgets[self.widgets.len() - 1]
+		}
+	}
+
+	pub fn pin(&self) -> u8 {
+		match self.direction {
+			Direction::Output => self.widgets[self.widgets.len() - 1],
+			Direction::Input  => self.widgets[0]
+		}
+	}
+}
+
+/// A codec with an audio function group.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Codec {
+	pub address:   u8,
+	pub vendor_id: u32,
+	pub revision:  u32,
+	/// Node of the audio function group
+	pub afg:       u8,
+	pub widgets:   Vec<Widget>,
+	pub output:    Option<Path>,
+	pub input:     Option<Path>
+}
+
+impl Codec {
+	/// Powers up the audio function group of the codec and reads its widgets, `verb` sends
+	/// a verb to a node and returns the response. Fails with `Error::Unsupported` if the codec
+	/// has no audio function group, e.g. a modem.
+	pub fn enumerate(address: u8, mut verb: impl FnMut(u8, u32, u32) -> Result<u32, Error>) -> Result<Self, Error> {
+		let vendor_id = parameter(&mut verb, 0, PARAM_VENDOR_ID)?;
+		let revision = parameter(&mut verb, 0, PARAM_REVISION_ID)?;
+		let (start, count) = nodes(parameter(&mut verb, 0, PARAM_NODE_COUNT)?);
+
+		let mut afg = None;
+		for node in start..start.saturating_add(count) {
+			if parameter(&mut verb, node, PARAM_FUNCTION_GROUP)? & 0xFF == FUNCTION_GROUP_AUDIO {
+				afg = Some(node);
+				break;
+			}
+		}
+		let afg = afg.ok_or(Error::Unsupported)?;
+
+		verb(afg, VERB_SET_POWER_STATE, POWER_D0)?;
+		let pcm = parameter(&mut verb, afg, PARAM_PCM)?;
+		let in_amp = parameter(&mut verb, afg, PARAM_IN_AMP_CAPS)?;
+		let out_amp = parameter(&mut verb, afg, PARAM_OUT_AMP_CAPS)?;
+		let (start, count) = nodes(parameter(&mut verb, afg, PARAM_NODE_COUNT)?);
+
+		let mut widgets = Vec::with_capacity(count as usize);
+		for node in start..start.saturating_add(count) {
+			widgets.push(widget(node, &mut verb, pcm, in_amp, out_amp)?);
+		}
+
+		let mut codec = Self { address, vendor_id, revision, afg, widgets, output: None, input: None };
+		codec.output = codec.find_path(Direction::Output);
+		codec.input = codec.find_path(Direction::Input);
+		Ok(codec)
+	}
+
+	pub fn widget(&self, node: u8) -> Option<&Widget> {
+		self.widgets.iter().find(|w| w.node == node)
+	}
+
+	pub fn path(&self, direction: Direction) -> Option<&Path> {
+		match direction {
+			Direction::Output => self.output.as_ref(),
+			Direction::Input  => self.input.as_ref()
+		}
+	}
+
+	/// Finds a path between an analog converter and a connected pin, preferring line out,
+	/// speakers and headphones for output and microphones, line in and aux for input. Other
+	/// connected pins are the last resort.
+	pub fn find_path(&self, direction: Direction) -> Option<Path> {
+		let (devices, pin_cap) = match direction {
+			Direction::Output => (OUTPUT_DEVICES, PINCAP_OUTPUT),
+			Direction::Input  => (INPUT_DEVICES, PINCAP_INPUT)
+		};
+		let pins = self.widgets.iter()
+			.filter(|w| w.kind() == WidgetType::Pin && w.pin_caps & pin_cap != 0 && w.is_connected() && !w.is_digital())
+			.collect::<Vec<_>>();
+		let preferred = devices.iter()
+			.flat_map(|device| pins.iter().filter(move |w| w.device() == *device))
+			.chain(pins.iter().filter(|w| !devices.contains(&w.device())));
+
+		for pin in preferred {
+			// routes are followed against the signal, from a widget to its inputs
+			let route = match direction {
+				Direction::Output => self.route(pin.node, &mut |w| w.kind() == WidgetType::Output && !w.is_digital(), 0),
+				Direction::Input  => self.widgets.iter()
+					.filter(|w| w.kind() == WidgetType::Input && !w.is_digital())
+					.find_map(|adc| self.route(adc.node, &mut |w| w.node == pin.node, 0))
+			};
+			if let Some(mut widgets) = route {
+				widgets.reverse();
+				return Some(Path { direction, widgets });
+			}
+		}
+		None
+	}
+
+	/// The widgets from `node` back to one accepted by `target`.
+	fn route(&self, node: u8, target: &mut impl FnMut(&Widget) -> bool, depth: usize) -> Option<Vec<u8>> {
+		let widget = self.widget(node)?;
+		for source in widget.connections.iter().filter_map(|n| self.widget(*n)) {
+			if target(source) {
+				return Some([node, source.node].to_vec());
+			}
+			if depth < MAX_DEPTH && matches!(source.kind(), WidgetType::Mixer | WidgetType::Selector) {
+				if let Some(mut route) = self.route(source.node, target, depth + 1) {
+					route.insert(0, node);
+					return Some(route);
+				}
+			}
+		}
+		None
+	}
+
+	/// Powers up the widgets of a path, selects the connections along it, unmutes its
+	/// amplifiers at 0 dB and enables the pin.
+	pub fn enable(&self, path: &Path, mut verb: impl FnMut(u8, u32, u32) -> Result<u32, Error>) -> Result<(), Error> {
+		for (i, &node) in path.widgets.iter().enumerate() {
+			let widget = self.widget(node).ok_or(Error::NoPath)?;
+			if widget.caps & WCAP_POWER != 0 {
+				verb(node, VERB_SET_POWER_STATE, POWER_D0)?;
+			}
+
+			// the input carrying the signal, pins at the start of an input path have one input amp
+			let input = i.checked_sub(1).and_then(|j| widget.connections.iter().position(|n| *n == path.widgets[j]));
+			if let Some(index) = input {
+				if widget.kind() != WidgetType::Mixer && widget.connections.len() > 1 {
+					verb(node, VERB_SET_CONNECTION_SELECT, index as u32)?;
+				}
+			}
+			if let Some(index) = input.or((widget.kind() == WidgetType::Pin).then_some(0)) {
+				if widget.caps & WCAP_IN_AMP != 0 {
+					verb(node, VERB_SET_AMP_GAIN_MUTE, AMP_SET_INPUT | AMP_SET_LEFT | AMP_SET_RIGHT
+						| (index as u32) << AMP_SET_INDEX_SHIFT | widget.in_amp & AMPCAP_OFFSET_MASK)?;
+				}
+			}
+			if widget.caps & WCAP_OUT_AMP != 0 {
+				verb(node, VERB_SET_AMP_GAIN_MUTE, AMP_SET_OUTPUT | AMP_SET_LEFT | AMP_SET_RIGHT
+					| widget.out_amp & AMPCAP_OFFSET_MASK)?;
+			}
+
+			if widget.kind() == WidgetType::Pin {
+				let control = match path.direction {
+					Direction::Output if widget.device() == DEVICE_HEADPHONE && widget.pin_caps & PINCAP_HEADPHONE != 0
+						=> PINCTL_OUT_ENABLE | PINCTL_HP_ENABLE,
+					Direction::Output => PINCTL_OUT_ENABLE,
+					Direction::Input  => PINCTL_IN_ENABLE
+				};
+				verb(node, VERB_SET_PIN_CONTROL, control)?;
+				if widget.pin_caps & PINCAP_EAPD != 0 {
+					verb(node, VERB_SET_EAPD_BTL, EAPD_BTL_EAPD)?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+fn parameter(verb: &mut impl FnMut(u8, u32, u32) -> Result<u32, Error>, node: u8, param: u8) -> Result<u32, Error> {
+	verb(node, VERB_GET_PARAMETER, param as u32)
+}
+
+/// Start and count of `PARAM_NODE_COUNT`
+fn nodes(count: u32) -> (u8, u8) {
+	((count >> 16) as u8, count as u8)
+}
+
+fn widget(node: u8, verb: &mut impl FnMut(u8, u32, u32) -> Result<u32, Error>, pcm: u32, in_amp: u32, out_amp: u32) -> Result<Widget, Error> {
+	let mut param = |param: u8| parameter(verb, node, param);
+	let caps = param(PARAM_WIDGET_CAPS)?;
+	let mut widget = Widget { node, caps, pin_caps: 0, config: 0, pcm: 0, in_amp: 0, out_amp: 0, connections: Vec::new() };
+
+	if matches!(widget.kind(), WidgetType::Output | WidgetType::Input) {
+		widget.pcm = if caps & WCAP_FORMAT_OVERRIDE != 0 { param(PARAM_PCM)? } else { pcm };
+	}
+	if caps & WCAP_IN_AMP != 0 {
+		widget.in_amp = if caps & WCAP_AMP_OVERRIDE != 0 { param(PARAM_IN_AMP_CAPS)? } else { in_amp };
+	}
+	if caps & WCAP_OUT_AMP != 0 {
+		widget.out_amp = if caps & WCAP_AMP_OVERRIDE != 0 { param(PARAM_OUT_AMP_CAPS)? } else { out_amp };
+	}
+	if widget.kind() == WidgetType::Pin {
+		widget.pin_caps = param(PARAM_PIN_CAPS)?;
+	}
+
+	let length = match caps & WCAP_CONN_LIST != 0 {
+		true  => param(PARAM_CONNECTION_LENGTH)?,
+		false => 0
+	};
+	if widget.kind() == WidgetType::Pin {
+		widget.config = verb(node, VERB_GET_CONFIG_DEFAULT, 0)?;
+	}
+
+	// short form entries are 8 bits, four per response, long form ones 16 bits; the top bit
+	// of an entry makes it the end of a range starting at the previous one
+	let (bits, per_response) = if length & CONNLEN_LONG != 0 { (16, 2) } else { (8, 4) };
+	let count = (length & CONNLEN_MASK) as usize;
+	let (mask, range) = ((1u32 << bits) - 1, 1u32 << (bits - 1));
+	for offset in (0..count).step_by(per_response) {
+		let entries = verb(node, VERB_GET_CONNECTION_LIST, offset as u32)?;
+		for i in 0..per_response.min(count - offset) {
+			let entry = entries >> (i * bits) & mask;
+			let target = (entry & !range) as u8;
+			match (entry & range != 0, widget.connections.last().copied()) {
+				(true, Some(prev)) if prev < target => widget.connections.extend(prev + 1..=target),
+				_ => widget.connections.push(target)
+			}
+		}
+	}
+	Ok(widget)
+}
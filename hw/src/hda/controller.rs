@@ -0,0 +1,574 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {super::*, crate::dma::{Dma, Region}, alloc::vec::Vec, core::ptr::addr_of_mut};
+
+/// How long a codec gets to respond to a verb
+pub const COMMAND_TIMEOUT_US: u64 = 100_000;
+/// How long the link gets to enter or leave reset
+pub const RESET_TIMEOUT_US: u64 = 100_000;
+/// Periods in the buffer of a stream, the controller interrupts after each
+pub const PERIODS: usize = 4;
+/// Length of a period
+pub const PERIOD_MS: usize = 20;
+
+/// Buffers and buffer descriptor lists are 128 byte aligned
+const ALIGN: usize = 128;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// The link didn't leave reset or a codec didn't respond in time
+	Timeout,
+	/// No codec with an audio function group is attached to the link
+	NoCodec,
+	/// The codec has no path in the requested direction
+	NoPath,
+	/// The codec has no audio function group or doesn't support the format
+	Unsupported,
+	/// No such codec or stream
+	InvalidArgument,
+	/// No DMA memory
+	NoMemory,
+	/// The converter is in use or no stream descriptor or tag is left
+	Busy
+}
+
+/// An open stream.
+struct Stream {
+	direction: Direction,
+	codec:     usize,
+	converter: u8,
+	tag:       u8,
+	format:    Format,
+	buffer:    Region,
+	bdl:       Region,
+	period:    usize,
+	/// Where the next write or read starts, bytes into the buffer
+	position:  usize,
+	/// Bytes from the link position to `position`, written but not yet played or captured
+	/// but not yet read
+	fill:      usize,
+	/// The link position at the last update
+	link:      usize,
+	/// Bytes past the link position the DMA engine may already have fetched
+	guard:     usize
+}
+
+impl Stream {
+	fn size(&self) -> usize {
+		self.buffer.size
+	}
+
+	/// Copies `len` bytes between the buffer at `position` and `buf`, wrapping around the
+	/// end of the buffer.
+	unsafe fn copy(&self, position: usize, len: usize, mut f: impl FnMut(*mut u8, usize, usize)) {
+		let first = len.min(self.size() - position);
+		f(self.buffer.virt.add(position), 0, first);
+		if first < len {
+			f(self.buffer.virt, first, len - first);
+		}
+	}
+
+	fn free(self, dma: &mut impl Dma) {
+		self.buffer.free(dma);
+		self.bdl.free(dma);
+	}
+}
+
+/// Polls `f` every 10 microseconds.
+fn wait(dma: &mut impl Dma, us: u64, mut f: impl FnMut() -> bool) -> Result<(), Error> {
+	for _ in 0..us / 10 {
+		if f() {
+			return Ok(());
+		}
+		dma.stall(10);
+	}
+	match f() {
+		true  => Ok(()),
+		false => Err(Error::Timeout)
+	}
+}
+
+/// Selects the largest ring size the controller supports, returns the number of entries.
+unsafe fn ring_size(reg: *mut u8) -> usize {
+	let caps = reg.read_volatile();
+	let (size, entries) = match () {
+		_ if caps & RINGSIZE_CAP_256 != 0 => (RINGSIZE_256, 256),
+		_ if caps & RINGSIZE_CAP_16 != 0  => (RINGSIZE_16, 16),
+		_                                 => (RINGSIZE_2, 2)
+	};
+	reg.write_volatile(caps & !RINGSIZE_MASK | size);
+	entries
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+	match b {
+		0 => a,
+		b => gcd(b, a % b)
+	}
+}
+
+pub struct Controller<D: Dma> {
+	regs:             *mut Registers,
+	dma:              D,
+	pub capabilities: u16,
+	corb:             Region,
+	corb_entries:     usize,
+	corb_write:       usize,
+	rirb:             Region,
+	rirb_entries:     usize,
+	rirb_read:        usize,
+	codecs:           Vec<Codec>,
+	/// Streams by descriptor
+	streams:          Vec<Option<Stream>>,
+	/// Input, output and bidirectional descriptors
+	descriptors:      (usize, usize, usize),
+	/// Unsolicited responses by codec address
+	unsolicited:      Vec<(u8, u32)>
+}
+
+impl<D: Dma> Controller<D> {
+	/// Resets the controller and the link, sets up the CORB and RIRB and enumerates the
+	/// codecs with an audio function group.
+	///
+	/// # Safety
+	///
+	/// `regs` must map the register BAR (BAR0) of the controller.
+	pub unsafe fn new(regs: *mut u8, mut dma: D) -> Result<Self, Error> {
+		let hda = regs as *mut Registers;
+		let gctl = addr_of_mut!((*hda).global_control);
+		let corbctl = addr_of_mut!((*hda).corb_control);
+		let rirbctl = addr_of_mut!((*hda).rirb_control);
+
+		// firmware may have left the rings running
+		corbctl.write_volatile(0);
+		rirbctl.write_volatile(0);
+		wait(&mut dma, RESET_TIMEOUT_US, || corbctl.read_volatile() & CORBCTL_RUN == 0
+			&& rirbctl.read_volatile() & RIRBCTL_DMAEN == 0)?;
+
+		gctl.write_volatile(gctl.read_volatile() & !GCTL_CRST);
+		wait(&mut dma, RESET_TIMEOUT_US, || gctl.read_volatile() & GCTL_CRST == 0)?;
+		dma.stall(100);
+		gctl.write_volatile(gctl.read_volatile() | GCTL_CRST);
+		wait(&mut dma, RESET_TIMEOUT_US, || gctl.read_volatile() & GCTL_CRST != 0)?;
+		// codecs request a state change within 521 us after the link left reset
+		dma.stall(1000);
+
+		let statests = addr_of_mut!((*hda).state_change_status);
+		let present = statests.read_volatile() & 0x7FFF;
+		statests.write_volatile(present);
+		if present == 0 {
+			return Err(Error::NoCodec);
+		}
+
+		let capabilities = addr_of_mut!((*hda).capabilities).read_volatile();
+		let descriptors = (
+			((capabilities & GCAP_ISS_MASK) >> GCAP_ISS_SHIFT) as usize,
+			((capabilities & GCAP_OSS_MASK) >> GCAP_OSS_SHIFT) as usize,
+			((capabilities & GCAP_BSS_MASK) >> GCAP_BSS_SHIFT) as usize
+		);
+
+		let corb = Region::alloc(&mut dma, 256 * 4, ALIGN).ok_or(Error::NoMemory)?;
+		let rirb = match Region::alloc(&mut dma, 256 * 8, ALIGN) {
+			Some(rirb) => rirb,
+			None => {
+				corb.free(&mut dma);
+				return Err(Error::NoMemory);
+			}
+		};
+
+		let mut ctrl = Self {
+			regs: hda,
+			dma,
+			capabilities,
+			corb,
+			corb_entries: 0,
+			corb_write:   0,
+			rirb,
+			rirb_entries: 0,
+			rirb_read:    0,
+			codecs:       Vec::new(),
+			streams:      (0..descriptors.0 + descriptors.1 + descriptors.2).map(|_| None).collect(),
+			descriptors,
+			unsolicited:  Vec::new()
+		};
+		ctrl.start_rings()?;
+
+		for address in (0..15).filter(|i| present & 1 << i != 0) {
+			match Codec::enumerate(address, |node, verb, payload| ctrl.verb(address, node, verb, payload)) {
+				Ok(codec) => ctrl.codecs.push(codec),
+				Err(Error::Unsupported | Error::Timeout) => (),
+				Err(e) => return Err(e)
+			}
+		}
+		if ctrl.codecs.is_empty() {
+			return Err(Error::NoCodec);
+		}
+
+		gctl.write_volatile(gctl.read_volatile() | GCTL_UNSOL);
+		addr_of_mut!((*hda).interrupt_control).write_volatile(INTCTL_GIE | INTCTL_CIE);
+		Ok(ctrl)
+	}
+
+	unsafe fn start_rings(&mut self) -> Result<(), Error> {
+		let hda = self.regs;
+		self.corb_entries = ring_size(addr_of_mut!((*hda).corb_size));
+		addr_of_mut!((*hda).corb_base).write_volatile(self.corb.phys as u32);
+		addr_of_mut!((*hda).corb_base_upper).write_volatile((self.corb.phys >> 32) as u32);
+		addr_of_mut!((*hda).corb_write_pointer).write_volatile(0);
+		self.corb_write = 0;
+
+		// some controllers report the reset by setting the bit, others clear it right away
+		let corbrp = addr_of_mut!((*hda).corb_read_pointer);
+		corbrp.write_volatile(CORBRP_RST);
+		let _ = wait(&mut self.dma, 1000, || corbrp.read_volatile() & CORBRP_RST != 0);
+		corbrp.write_volatile(0);
+		wait(&mut self.dma, RESET_TIMEOUT_US, || corbrp.read_volatile() == 0)?;
+
+		self.rirb_entries = ring_size(addr_of_mut!((*hda).rirb_size));
+		addr_of_mut!((*hda).rirb_base).write_volatile(self.rirb.phys as u32);
+		addr_of_mut!((*hda).rirb_base_upper).write_volatile((self.rirb.phys >> 32) as u32);
+		addr_of_mut!((*hda).rirb_write_pointer).write_volatile(RIRBWP_RST);
+		addr_of_mut!((*hda).response_interrupt_count).write_volatile(1);
+		addr_of_mut!((*hda).rirb_status).write_volatile(RIRBSTS_RINTFL | RIRBSTS_OIS);
+		self.rirb_read = 0;
+
+		addr_of_mut!((*hda).corb_control).write_volatile(CORBCTL_RUN);
+		addr_of_mut!((*hda).rirb_control).write_volatile(RIRBCTL_DMAEN | RIRBCTL_RINTCTL);
+		Ok(())
+	}
+
+	fn stream_regs(&self, descriptor: usize) -> *mut StreamDescriptor {
+		unsafe { addr_of_mut!((*self.regs).streams[descriptor]) }
+	}
+
+	/// Sends a verb to a node of a codec and waits for the response.
+	pub fn verb(&mut self, codec: u8, node: u8, verb: u32, payload: u32) -> Result<u32, Error> {
+		let hda = self.regs;
+		self.corb_write = (self.corb_write + 1) % self.corb_entries;
+		unsafe {
+			self.corb.as_ptr::<u32>().add(self.corb_write).write_volatile(command(codec, node, verb, payload));
+			addr_of_mut!((*hda).corb_write_pointer).write_volatile(self.corb_write as u16);
+		}
+
+		for _ in 0..COMMAND_TIMEOUT_US / 10 {
+			if let Some(response) = self.receive(Some(codec)) {
+				return Ok(response);
+			}
+			self.dma.stall(10);
+		}
+		self.receive(Some(codec)).ok_or(Error::Timeout)
+	}
+
+	/// Takes the responses the controller wrote to the RIRB, unsolicited ones are kept for
+	/// `unsolicited`. Returns the solicited response of `codec`.
+	fn receive(&mut self, codec: Option<u8>) -> Option<u32> {
+		let hda = self.regs;
+		let mut solicited = None;
+		unsafe {
+			let write = addr_of_mut!((*hda).rirb_write_pointer).read_volatile() as usize & 0xFF;
+			while self.rirb_read != write % self.rirb_entries {
+				self.rirb_read = (self.rirb_read + 1) % self.rirb_entries;
+				let entry = self.rirb.as_ptr::<u64>().add(self.rirb_read).read_volatile();
+				let (response, extended) = (entry as u32, (entry >> 32) as u32);
+				let address = (extended & RIRB_EX_CODEC_MASK) as u8;
+				match extended & RIRB_EX_UNSOL != 0 {
+					true  => self.unsolicited.push((address, response)),
+					false if Some(address) == codec => solicited = Some(response),
+					false => ()
+				}
+			}
+			addr_of_mut!((*hda).rirb_status).write_volatile(RIRBSTS_RINTFL | RIRBSTS_OIS);
+		}
+		solicited
+	}
+
+	pub fn codecs(&self) -> &[Codec] {
+		&self.codecs
+	}
+
+	/// Takes the unsolicited responses received so far, with the address of their codec.
+	pub fn unsolicited(&mut self) -> impl Iterator<Item = (u8, u32)> + '_ {
+		self.unsolicited.drain(..)
+	}
+
+	/// A free descriptor for the direction, bidirectional ones are the last resort.
+	fn free_descriptor(&self, direction: Direction) -> Option<usize> {
+		let (input, output, bidirectional) = self.descriptors;
+		let range = match direction {
+			Direction::Input  => 0..input,
+			Direction::Output => input..input + output
+		};
+		range.chain(input + output..input + output + bidirectional).find(|i| self.streams[*i].is_none())
+	}
+
+	/// Opens a stream to or from the converter at the end of the codec's path in `direction`
+	/// and starts it. The buffer holds `PERIODS` periods of `PERIOD_MS`. Returns the stream's
+	/// descriptor.
+	pub fn open(&mut self, codec: usize, direction: Direction, format: Format) -> Result<usize, Error> {
+		let encoded = format.encode().ok_or(Error::Unsupported)?;
+		let codec_ref = self.codecs.get(codec).ok_or(Error::InvalidArgument)?.clone();
+		let path = codec_ref.path(direction).ok_or(Error::NoPath)?;
+		let converter = path.converter();
+		if !format.supported_by(codec_ref.widget(converter).map_or(0, |w| w.pcm)) {
+			return Err(Error::Unsupported);
+		}
+		if self.streams.iter().flatten().any(|s| s.codec == codec && s.converter == converter) {
+			return Err(Error::Busy);
+		}
+		let descriptor = self.free_descriptor(direction).ok_or(Error::Busy)?;
+		let tag = (1..16u8).find(|tag| !self.streams.iter().flatten().any(|s| s.direction == direction && s.tag == *tag))
+			.ok_or(Error::Busy)?;
+
+		// periods are whole frames and keep the buffers of the descriptors aligned
+		let frame = format.frame_size();
+		let unit = frame / gcd(frame, ALIGN) * ALIGN;
+		let period = (format.bytes_per_second() * PERIOD_MS / 1000 + unit - 1) / unit * unit;
+		let buffer = Region::alloc(&mut self.dma, PERIODS * period, ALIGN).ok_or(Error::NoMemory)?;
+		let bdl = match Region::alloc(&mut self.dma, PERIODS * core::mem::size_of::<BufferDescriptor>(), ALIGN) {
+			Some(bdl) => bdl,
+			None => {
+				buffer.free(&mut self.dma);
+				return Err(Error::NoMemory);
+			}
+		};
+		for i in 0..PERIODS {
+			let entry = BufferDescriptor { address: buffer.phys + (i * period) as u64, length: period as u32, flags: BDL_IOC };
+			unsafe { bdl.as_ptr::<BufferDescriptor>().add(i).write_volatile(entry) };
+		}
+
+		let mut stream = Stream {
+			direction, codec, converter, tag, format, buffer, bdl, period,
+			position: 0,
+			fill:     0,
+			link:     0,
+			guard:    0
+		};
+		let sd = self.stream_regs(descriptor);
+		let result = unsafe { self.reset_stream(sd) }.and_then(|_| {
+			let bidirectional = descriptor >= self.descriptors.0 + self.descriptors.1;
+			unsafe {
+				addr_of_mut!((*sd).control2).write_volatile(tag << SDCTL2_STRM_SHIFT
+					| if bidirectional && direction == Direction::Output { SDCTL2_DIR } else { 0 });
+				addr_of_mut!((*sd).buffer_length).write_volatile(stream.size() as u32);
+				addr_of_mut!((*sd).last_index).write_volatile(PERIODS as u16 - 1);
+				addr_of_mut!((*sd).format).write_volatile(encoded);
+				addr_of_mut!((*sd).bdl_base).write_volatile(stream.bdl.phys as u32);
+				addr_of_mut!((*sd).bdl_base_upper).write_volatile((stream.bdl.phys >> 32) as u32);
+				addr_of_mut!((*sd).status).write_volatile(SDSTS_BCIS | SDSTS_ERRORS);
+			}
+
+			let address = codec_ref.address;
+			codec_ref.enable(path, |node, verb, payload| self.verb(address, node, verb, payload))?;
+			self.verb(address, converter, VERB_SET_CONVERTER_FORMAT, encoded as u32)?;
+			self.verb(address, converter, VERB_SET_STREAM_CHANNEL, (tag as u32) << 4)
+		});
+		if let Err(e) = result {
+			stream.free(&mut self.dma);
+			return Err(e);
+		}
+
+		unsafe {
+			let fifo = addr_of_mut!((*sd).fifo_size).read_volatile() as usize + 1;
+			stream.guard = (fifo + frame - 1) / frame * frame;
+			if direction == Direction::Output {
+				stream.position = stream.guard;
+				stream.fill = stream.guard;
+			}
+			let intctl = addr_of_mut!((*self.regs).interrupt_control);
+			intctl.write_volatile(intctl.read_volatile() | 1 << descriptor);
+			addr_of_mut!((*sd).control).write_volatile(SDCTL_RUN | SDCTL_IOCE | SDCTL_FEIE | SDCTL_DEIE);
+		}
+		self.streams[descriptor] = Some(stream);
+		Ok(descriptor)
+	}
+
+	/// Stops the stream and puts the descriptor into reset.
+	unsafe fn reset_stream(&mut self, sd: *mut StreamDescriptor) -> Result<(), Error> {
+		let control = addr_of_mut!((*sd).control);
+		control.write_volatile(control.read_volatile() & !SDCTL_RUN);
+		wait(&mut self.dma, RESET_TIMEOUT_US, || control.read_volatile() & SDCTL_RUN == 0)?;
+		control.write_volatile(SDCTL_SRST);
+		wait(&mut self.dma, RESET_TIMEOUT_US, || control.read_volatile() & SDCTL_SRST != 0)?;
+		control.write_volatile(0);
+		wait(&mut self.dma, RESET_TIMEOUT_US, || control.read_volatile() & SDCTL_SRST == 0)
+	}
+
+	/// Stops and frees a stream.
+	pub fn close(&mut self, descriptor: usize) -> Result<(), Error> {
+		let stream = self.streams.get_mut(descriptor).and_then(Option::take).ok_or(Error::InvalidArgument)?;
+		let sd = self.stream_regs(descriptor);
+		unsafe {
+			let intctl = addr_of_mut!((*self.regs).interrupt_control);
+			intctl.write_volatile(intctl.read_volatile() & !(1 << descriptor));
+		}
+		let result = unsafe { self.reset_stream(sd) };
+		if let Some(address) = self.codecs.get(stream.codec).map(|c| c.address) {
+			let _ = self.verb(address, stream.converter, VERB_SET_STREAM_CHANNEL, 0);
+		}
+		stream.free(&mut self.dma);
+		result
+	}
+
+	/// Advances the stream to the link position. Played bytes are replaced by silence, so
+	/// an underrun doesn't repeat old samples. Must be called at least once per buffer, the
+	/// interrupt after each period takes care of that.
+	fn update(&mut self, descriptor: usize) -> Result<&mut Stream, Error> {
+		let sd = self.stream_regs(descriptor);
+		let stream = self.streams.get_mut(descriptor).and_then(Option::as_mut).ok_or(Error::InvalidArgument)?;
+		let size = stream.size();
+		let link = unsafe { addr_of_mut!((*sd).position).read_volatile() } as usize % size;
+		let delta = (link + size - stream.link) % size;
+
+		match stream.direction {
+			Direction::Output => {
+				unsafe { stream.copy(stream.link, delta, |ptr, _, len| ptr.write_bytes(0, len)) };
+				match delta + stream.guard > stream.fill {
+					true => {
+						stream.position = (link + stream.guard) % size;
+						stream.fill = stream.guard;
+					}
+					false => stream.fill -= delta
+				}
+			}
+			Direction::Input => {
+				stream.fill += delta;
+				// the oldest period was overwritten
+				if stream.fill + stream.guard > size {
+					stream.position = (link + stream.period) % size;
+					stream.fill = size - stream.period;
+				}
+			}
+		}
+		stream.link = link;
+		Ok(stream)
+	}
+
+	/// Writes whole frames into the buffer of an output stream, as many as fit. Returns the
+	/// number of bytes written, zero if the buffer is full.
+	pub fn write(&mut self, descriptor: usize, buf: &[u8]) -> Result<usize, Error> {
+		let stream = self.update(descriptor)?;
+		if stream.direction != Direction::Output {
+			return Err(Error::InvalidArgument);
+		}
+
+		let frame = stream.format.frame_size();
+		let len = buf.len().min(stream.size() - stream.fill) / frame * frame;
+		unsafe { stream.copy(stream.position, len, |ptr, offset, len| ptr.copy_from_nonoverlapping(buf[offset..].as_ptr(), len)) };
+		stream.position = (stream.position + len) % stream.size();
+		stream.fill += len;
+		Ok(len)
+	}
+
+	/// Reads whole captured frames from an input stream. Returns the number of bytes read,
+	/// zero if nothing was captured since the last read.
+	pub fn read(&mut self, descriptor: usize, buf: &mut [u8]) -> Result<usize, Error> {
+		let stream = self.update(descriptor)?;
+		if stream.direction != Direction::Input {
+			return Err(Error::InvalidArgument);
+		}
+
+		let frame = stream.format.frame_size();
+		let len = buf.len().min(stream.fill) / frame * frame;
+		unsafe { stream.copy(stream.position, len, |ptr, offset, len| ptr.copy_to_nonoverlapping(buf[offset..].as_mut_ptr(), len)) };
+		stream.position = (stream.position + len) % stream.size();
+		stream.fill -= len;
+		Ok(len)
+	}
+
+	/// Bytes that can be written to an output stream or read from an input stream without
+	/// waiting.
+	pub fn available(&mut self, descriptor: usize) -> Result<usize, Error> {
+		let stream = self.update(descriptor)?;
+		Ok(match stream.direction {
+			Direction::Output => stream.size() - stream.fill,
+			Direction::Input  => stream.fill
+		})
+	}
+
+	/// Bytes written to an output stream that weren't played yet.
+	pub fn queued(&mut self, descriptor: usize) -> Result<usize, Error> {
+		let stream = self.update(descriptor)?;
+		Ok(match stream.direction {
+			Direction::Output => stream.fill - stream.guard,
+			Direction::Input  => 0
+		})
+	}
+
+	pub fn format(&self, descriptor: usize) -> Option<Format> {
+		self.streams.get(descriptor)?.as_ref().map(|s| s.format)
+	}
+
+	/// Bytes per period of a stream.
+	pub fn period(&self, descriptor: usize) -> Option<usize> {
+		self.streams.get(descriptor)?.as_ref().map(|s| s.period)
+	}
+
+	/// Handles an interrupt, takes responses from the RIRB and advances the streams that
+	/// completed a period. Returns a bit for each of these streams' descriptors.
+	pub fn interrupt(&mut self) -> u32 {
+		let hda = self.regs;
+		let status = unsafe { addr_of_mut!((*hda).interrupt_status).read_volatile() };
+		if status & INTSTS_CIS != 0 {
+			self.receive(None);
+		}
+
+		let mut completed = 0;
+		for i in (0..self.streams.len()).filter(|i| status & 1 << i != 0) {
+			let sd = self.stream_regs(i);
+			unsafe {
+				let sts = addr_of_mut!((*sd).status);
+				sts.write_volatile(sts.read_volatile());
+			}
+			if self.update(i).is_ok() {
+				completed |= 1 << i;
+			}
+		}
+		completed
+	}
+}
+
+impl<D: Dma> Drop for Controller<D> {
+	fn drop(&mut self) {
+		for i in 0..self.streams.len() {
+			let _ = self.close(i);
+		}
+		unsafe {
+			addr_of_mut!((*self.regs).interrupt_control).write_volatile(0);
+			addr_of_mut!((*self.regs).corb_control).write_volatile(0);
+			addr_of_mut!((*self.regs).rirb_control).write_volatile(0);
+		}
+		let empty = || Region { virt: core::ptr::null_mut(), phys: 0, size: 0 };
+		core::mem::replace(&mut self.corb, empty()).free(&mut self.dma);
+		core::mem::replace(&mut self.rirb, empty()).free(&mut self.dma);
+	}
+}
+
+impl<D: Dma> core::fmt::Debug for Controller<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Controller")
+			.field("capabilities", &format_args!("{:#x}", self.capabilities))
+			.field("streams", &self.descriptors)
+			.field("codecs", &self.codecs.iter().map(|c| c.vendor_id).collect::<Vec<_>>())
+			.finish()
+	}
+}
@@ -0,0 +1,429 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Intel High Definition Audio controllers and their codecs.
+//!
+//! The controller sends verbs to the codecs through the CORB and receives their responses
+//! through the RIRB. Each codec's audio function group is walked to find a path from an
+//! output converter to an output pin and from an input pin to an input converter, streams
+//! move PCM between memory and these converters.
+
+mod codec;
+mod controller;
+
+pub use {codec::*, controller::*};
+
+/// 64 Bit Address Supported
+pub const GCAP_64OK:      u16 = 1 << 0;
+/// Number of Serial Data Out Signals
+pub const GCAP_NSDO_MASK: u16 = 0b11 << 1;
+/// Number of Bidirectional Streams Supported
+pub const GCAP_BSS_MASK:  u16 = 0b11111 << GCAP_BSS_SHIFT;
+pub const GCAP_BSS_SHIFT: u16 = 3;
+/// Number of Input Streams Supported
+pub const GCAP_ISS_MASK:  u16 = 0b1111 << GCAP_ISS_SHIFT;
+pub const GCAP_ISS_SHIFT: u16 = 8;
+/// Number of Output Streams Supported
+pub const GCAP_OSS_MASK:  u16 = 0b1111 << GCAP_OSS_SHIFT;
+pub const GCAP_OSS_SHIFT: u16 = 12;
+
+/// Controller Reset, the link is in reset while clear
+pub const GCTL_CRST:   u32 = 1 << 0;
+/// Flush Control
+pub const GCTL_FCNTRL: u32 = 1 << 1;
+/// Accept Unsolicited Response Enable
+pub const GCTL_UNSOL:  u32 = 1 << 8;
+
+/// Global Interrupt Enable
+pub const INTCTL_GIE: u32 = 1 << 31;
+/// Controller Interrupt Enable, CORB and RIRB interrupts
+pub const INTCTL_CIE: u32 = 1 << 30;
+/// Global Interrupt Status
+pub const INTSTS_GIS: u32 = 1 << 31;
+/// Controller Interrupt Status
+pub const INTSTS_CIS: u32 = 1 << 30;
+/// Stream Interrupt Status, a bit per stream descriptor
+pub const INTSTS_SIS_MASK: u32 = !(!0 << 30);
+
+/// Read Pointer Reset
+pub const CORBRP_RST:     u16 = 1 << 15;
+/// Memory Error Interrupt Enable
+pub const CORBCTL_CMEIE:  u8 = 1 << 0;
+/// Enable CORB DMA Engine
+pub const CORBCTL_RUN:    u8 = 1 << 1;
+/// Write Pointer Reset
+pub const RIRBWP_RST:     u16 = 1 << 15;
+/// Response Interrupt Control
+pub const RIRBCTL_RINTCTL: u8 = 1 << 0;
+/// RIRB DMA Enable
+pub const RIRBCTL_DMAEN:  u8 = 1 << 1;
+/// Response Overrun Interrupt Control
+pub const RIRBCTL_OIC:    u8 = 1 << 2;
+/// Response Interrupt
+pub const RIRBSTS_RINTFL: u8 = 1 << 0;
+/// Response Overrun Interrupt Status
+pub const RIRBSTS_OIS:    u8 = 1 << 2;
+/// The ring sizes the controller supports, in the CORBSIZE and RIRBSIZE registers
+pub const RINGSIZE_CAP_2:   u8 = 1 << 4;
+pub const RINGSIZE_CAP_16:  u8 = 1 << 5;
+pub const RINGSIZE_CAP_256: u8 = 1 << 6;
+/// The ring size in use
+pub const RINGSIZE_MASK:    u8 = 0b11;
+pub const RINGSIZE_2:       u8 = 0b00;
+pub const RINGSIZE_16:      u8 = 0b01;
+pub const RINGSIZE_256:     u8 = 0b10;
+
+/// The response is unsolicited, in the extended response of a RIRB entry
+pub const RIRB_EX_UNSOL:     u32 = 1 << 4;
+/// The codec that sent the response
+pub const RIRB_EX_CODEC_MASK: u32 = 0xF;
+
+/// Stream Reset
+pub const SDCTL_SRST: u16 = 1 << 0;
+/// Stream Run
+pub const SDCTL_RUN:  u16 = 1 << 1;
+/// Interrupt On Completion Enable
+pub const SDCTL_IOCE: u16 = 1 << 2;
+/// FIFO Error Interrupt Enable
+pub const SDCTL_FEIE: u16 = 1 << 3;
+/// Descriptor Error Interrupt Enable
+pub const SDCTL_DEIE: u16 = 1 << 4;
+/// Stream Number, the tag the converters listen to, in the upper control byte
+pub const SDCTL2_STRM_SHIFT: u8 = 4;
+/// Bidirectional Direction Control, set for output
+pub const SDCTL2_DIR: u8 = 1 << 3;
+/// Buffer Completion Interrupt Status
+pub const SDSTS_BCIS:    u8 = 1 << 2;
+/// FIFO Error
+pub const SDSTS_FIFOE:   u8 = 1 << 3;
+/// Descriptor Error
+pub const SDSTS_DESE:    u8 = 1 << 4;
+/// FIFO Ready
+pub const SDSTS_FIFORDY: u8 = 1 << 5;
+pub const SDSTS_ERRORS:  u8 = SDSTS_FIFOE | SDSTS_DESE;
+
+/// Interrupt On Completion, in the flags of a buffer descriptor
+pub const BDL_IOC: u32 = 1 << 0;
+
+/// Verbs with a 12 bit identifier and an 8 bit payload
+pub const VERB_GET_PARAMETER:          u32 = 0xF00;
+pub const VERB_GET_CONNECTION_SELECT:  u32 = 0xF01;
+pub const VERB_SET_CONNECTION_SELECT:  u32 = 0x701;
+pub const VERB_GET_CONNECTION_LIST:    u32 = 0xF02;
+pub const VERB_GET_POWER_STATE:        u32 = 0xF05;
+pub const VERB_SET_POWER_STATE:        u32 = 0x705;
+pub const VERB_GET_STREAM_CHANNEL:     u32 = 0xF06;
+pub const VERB_SET_STREAM_CHANNEL:     u32 = 0x706;
+pub const VERB_GET_PIN_CONTROL:        u32 = 0xF07;
+pub const VERB_SET_PIN_CONTROL:        u32 = 0x707;
+pub const VERB_GET_UNSOLICITED:        u32 = 0xF08;
+pub const VERB_SET_UNSOLICITED:        u32 = 0x708;
+pub const VERB_GET_PIN_SENSE:          u32 = 0xF09;
+pub const VERB_GET_EAPD_BTL:           u32 = 0xF0C;
+pub const VERB_SET_EAPD_BTL:           u32 = 0x70C;
+pub const VERB_GET_CONFIG_DEFAULT:     u32 = 0xF1C;
+pub const VERB_FUNCTION_RESET:         u32 = 0x7FF;
+/// Verbs with a 4 bit identifier and a 16 bit payload
+pub const VERB_SET_CONVERTER_FORMAT:   u32 = 0x2;
+pub const VERB_SET_AMP_GAIN_MUTE:      u32 = 0x3;
+pub const VERB_GET_CONVERTER_FORMAT:   u32 = 0xA;
+pub const VERB_GET_AMP_GAIN_MUTE:      u32 = 0xB;
+
+/// Parameters read with `VERB_GET_PARAMETER`
+pub const PARAM_VENDOR_ID:         u8 = 0x00;
+pub const PARAM_REVISION_ID:       u8 = 0x02;
+pub const PARAM_NODE_COUNT:        u8 = 0x04;
+pub const PARAM_FUNCTION_GROUP:    u8 = 0x05;
+pub const PARAM_AUDIO_FG_CAPS:     u8 = 0x08;
+pub const PARAM_WIDGET_CAPS:       u8 = 0x09;
+pub const PARAM_PCM:               u8 = 0x0A;
+pub const PARAM_STREAM_FORMATS:    u8 = 0x0B;
+pub const PARAM_PIN_CAPS:          u8 = 0x0C;
+pub const PARAM_IN_AMP_CAPS:       u8 = 0x0D;
+pub const PARAM_CONNECTION_LENGTH: u8 = 0x0E;
+pub const PARAM_POWER_STATES:      u8 = 0x0F;
+pub const PARAM_OUT_AMP_CAPS:      u8 = 0x12;
+
+/// Function group type of an audio function group
+pub const FUNCTION_GROUP_AUDIO: u32 = 0x01;
+
+/// Widget type
+pub const WCAP_TYPE_MASK:     u32 = 0xF << WCAP_TYPE_SHIFT;
+pub const WCAP_TYPE_SHIFT:    u32 = 20;
+/// Stereo
+pub const WCAP_STEREO:        u32 = 1 << 0;
+/// Input Amplifier Present
+pub const WCAP_IN_AMP:        u32 = 1 << 1;
+/// Output Amplifier Present
+pub const WCAP_OUT_AMP:       u32 = 1 << 2;
+/// Amplifier Parameter Override, the widget has amplifier capabilities of its own
+pub const WCAP_AMP_OVERRIDE:  u32 = 1 << 3;
+/// Format Override, the widget has PCM and format capabilities of its own
+pub const WCAP_FORMAT_OVERRIDE: u32 = 1 << 4;
+/// Unsolicited Capable
+pub const WCAP_UNSOL:         u32 = 1 << 7;
+/// Connection List
+pub const WCAP_CONN_LIST:     u32 = 1 << 8;
+/// Digital
+pub const WCAP_DIGITAL:       u32 = 1 << 9;
+/// Power Control
+pub const WCAP_POWER:         u32 = 1 << 10;
+
+/// Presence Detect Capable
+pub const PINCAP_PRESENCE:    u32 = 1 << 2;
+/// Headphone Drive Capable
+pub const PINCAP_HEADPHONE:   u32 = 1 << 3;
+/// Output Capable
+pub const PINCAP_OUTPUT:      u32 = 1 << 4;
+/// Input Capable
+pub const PINCAP_INPUT:       u32 = 1 << 5;
+/// EAPD Capable
+pub const PINCAP_EAPD:        u32 = 1 << 16;
+
+/// Pin control
+pub const PINCTL_IN_ENABLE:   u32 = 1 << 5;
+pub const PINCTL_OUT_ENABLE:  u32 = 1 << 6;
+pub const PINCTL_HP_ENABLE:   u32 = 1 << 7;
+
+/// External amplifier power down, enables the amplifier when set
+pub const EAPD_BTL_EAPD:      u32 = 1 << 1;
+
+/// The long form of connection list entries has 16 bit entries
+pub const CONNLEN_LONG:       u32 = 1 << 7;
+pub const CONNLEN_MASK:       u32 = 0x7F;
+
+/// Amplifier capabilities: the gain step of 0 dB, the number of steps and mute
+pub const AMPCAP_OFFSET_MASK: u32 = 0x7F;
+pub const AMPCAP_STEPS_MASK:  u32 = 0x7F << AMPCAP_STEPS_SHIFT;
+pub const AMPCAP_STEPS_SHIFT: u32 = 8;
+pub const AMPCAP_MUTE:        u32 = 1 << 31;
+
+/// Payload of `VERB_SET_AMP_GAIN_MUTE`
+pub const AMP_SET_OUTPUT:     u32 = 1 << 15;
+pub const AMP_SET_INPUT:      u32 = 1 << 14;
+pub const AMP_SET_LEFT:       u32 = 1 << 13;
+pub const AMP_SET_RIGHT:      u32 = 1 << 12;
+pub const AMP_SET_INDEX_SHIFT: u32 = 8;
+pub const AMP_SET_MUTE:       u32 = 1 << 7;
+
+/// Power state D0, fully on
+pub const POWER_D0: u32 = 0;
+
+/// Port connectivity in the configuration default of a pin
+pub const CONFIG_PORT_MASK:   u32 = 0b11 << 30;
+pub const CONFIG_PORT_JACK:   u32 = 0b00 << 30;
+pub const CONFIG_PORT_NONE:   u32 = 0b01 << 30;
+pub const CONFIG_PORT_FIXED:  u32 = 0b10 << 30;
+pub const CONFIG_PORT_BOTH:   u32 = 0b11 << 30;
+/// Default device
+pub const CONFIG_DEVICE_MASK:  u32 = 0xF << CONFIG_DEVICE_SHIFT;
+pub const CONFIG_DEVICE_SHIFT: u32 = 20;
+
+/// Supported sample sizes of `PARAM_PCM`
+pub const PCM_BITS_8:  u32 = 1 << 16;
+pub const PCM_BITS_16: u32 = 1 << 17;
+pub const PCM_BITS_20: u32 = 1 << 18;
+pub const PCM_BITS_24: u32 = 1 << 19;
+pub const PCM_BITS_32: u32 = 1 << 20;
+/// Supported sample rates of `PARAM_PCM`, a bit per entry
+pub const PCM_RATES: [u32; 12] = [8000, 11025, 16000, 22050, 32000, 44100, 48000, 88200, 96000, 176400, 192000, 384000];
+/// PCM in `PARAM_STREAM_FORMATS`
+pub const STREAM_FORMAT_PCM: u32 = 1 << 0;
+
+/// The controller registers.
+#[repr(C)]
+pub struct Registers {
+	pub capabilities:          u16,
+	pub minor_version:         u8,
+	pub major_version:         u8,
+	pub output_payload:        u16,
+	pub input_payload:         u16,
+	pub global_control:        u32,
+	pub wake_enable:           u16,
+	pub state_change_status:   u16,
+	pub global_status:         u16,
+	pub _res0:                 [u16; 3],
+	pub output_stream_payload: u16,
+	pub input_stream_payload:  u16,
+	pub _res1:                 u32,
+	pub interrupt_control:     u32,
+	pub interrupt_status:      u32,
+	pub _res2:                 [u32; 2],
+	pub wall_clock:            u32,
+	pub _res3:                 u32,
+	pub stream_sync:           u32,
+	pub _res4:                 u32,
+	pub corb_base:             u32,
+	pub corb_base_upper:       u32,
+	pub corb_write_pointer:    u16,
+	pub corb_read_pointer:     u16,
+	pub corb_control:          u8,
+	pub corb_status:           u8,
+	pub corb_size:             u8,
+	pub _res5:                 u8,
+	pub rirb_base:             u32,
+	pub rirb_base_upper:       u32,
+	pub rirb_write_pointer:    u16,
+	pub response_interrupt_count: u16,
+	pub rirb_control:          u8,
+	pub rirb_status:           u8,
+	pub rirb_size:             u8,
+	pub _res6:                 u8,
+	pub immediate_command:     u32,
+	pub immediate_response:    u32,
+	pub immediate_status:      u16,
+	pub _res7:                 [u16; 3],
+	pub position_base:         u32,
+	pub position_base_upper:   u32,
+	pub _res8:                 [u32; 2],
+	/// Input streams, then output streams, then bidirectional streams
+	pub streams:               [StreamDescriptor; 30]
+}
+
+#[repr(C)]
+pub struct StreamDescriptor {
+	/// The lower 16 bits of the control register, `SDCTL_*`
+	pub control:         u16,
+	/// The upper 8 bits of the control register, `SDCTL2_*`
+	pub control2:        u8,
+	pub status:          u8,
+	/// Link Position in Buffer
+	pub position:        u32,
+	/// Cyclic Buffer Length
+	pub buffer_length:   u32,
+	/// Last Valid Index of the buffer descriptor list
+	pub last_index:      u16,
+	pub _res0:           u16,
+	pub fifo_size:       u16,
+	pub format:          u16,
+	pub _res1:           u32,
+	pub bdl_base:        u32,
+	pub bdl_base_upper:  u32
+}
+
+/// An entry of a buffer descriptor list.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BufferDescriptor {
+	pub address: u64,
+	pub length:  u32,
+	/// `BDL_IOC`
+	pub flags:   u32
+}
+
+/// Builds a command for the CORB.
+pub fn command(codec: u8, node: u8, verb: u32, payload: u32) -> u32 {
+	let verb = match verb <= 0xF {
+		true  => verb << 16 | payload & 0xFFFF,
+		false => verb << 8 | payload & 0xFF
+	};
+	(codec as u32) << 28 | (node as u32) << 20 | verb
+}
+
+/// Splits a CORB command into codec address, node, verb and payload.
+pub fn split_command(command: u32) -> (u8, u8, u32, u32) {
+	let (codec, node, verb) = ((command >> 28) as u8, (command >> 20) as u8, command & 0xF_FFFF);
+	match verb >> 16 {
+		0x7 | 0xF => (codec, node, verb >> 8, verb & 0xFF),
+		v         => (codec, node, v, verb & 0xFFFF)
+	}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+	Output,
+	Input
+}
+
+/// A PCM stream format.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Format {
+	pub rate:     u32,
+	/// Bits per sample, samples take 1, 2 or 4 bytes in memory
+	pub bits:     u8,
+	pub channels: u8
+}
+
+impl Format {
+	/// 48 kHz, 16 bit stereo, which every codec supports.
+	pub const DEFAULT: Self = Self { rate: 48000, bits: 16, channels: 2 };
+
+	/// The value of the stream format register and `VERB_SET_CONVERTER_FORMAT`, `None` if
+	/// the rate can't be derived from 48 or 44.1 kHz or the sample size is unsupported.
+	pub fn encode(&self) -> Option<u16> {
+		let bits = match self.bits {
+			8  => 0b000,
+			16 => 0b001,
+			20 => 0b010,
+			24 => 0b011,
+			32 => 0b100,
+			_  => return None
+		};
+		if !(1..=16).contains(&self.channels) {
+			return None;
+		}
+
+		let (base, mult, div) = [(48000, 0u16), (44100, 1)].into_iter()
+			.flat_map(|(rate, base)| (1..=4).flat_map(move |mult| (1..=8).map(move |div| (rate, base, mult, div))))
+			.find(|(rate, _, mult, div)| rate * mult == self.rate * div)
+			.map(|(_, base, mult, div)| (base, mult as u16 - 1, div as u16 - 1))?;
+		Some(base << 14 | mult << 11 | div << 8 | bits << 4 | (self.channels as u16 - 1))
+	}
+
+	/// Bytes per frame, a sample of each channel.
+	pub fn frame_size(&self) -> usize {
+		let sample = match self.bits {
+			0..=8   => 1,
+			9..=16  => 2,
+			_       => 4
+		};
+		sample * self.channels as usize
+	}
+
+	pub fn bytes_per_second(&self) -> usize {
+		self.frame_size() * self.rate as usize
+	}
+
+	/// Whether `PARAM_PCM` capabilities include the rate and sample size.
+	pub fn supported_by(&self, pcm: u32) -> bool {
+		let bits = match self.bits {
+			8  => PCM_BITS_8,
+			16 => PCM_BITS_16,
+			20 => PCM_BITS_20,
+			24 => PCM_BITS_24,
+			32 => PCM_BITS_32,
+			_  => return false
+		};
+		pcm & bits != 0 && PCM_RATES.iter().position(|r| *r == self.rate).map_or(false, |i| pcm & 1 << i != 0)
+	}
+}
+
+/// Default devices in the configuration default of a pin
+pub const DEVICE_LINE_OUT:  u32 = 0x0;
+pub const DEVICE_SPEAKER:   u32 = 0x1;
+pub const DEVICE_HEADPHONE: u32 = 0x2;
+pub const DEVICE_CD:        u32 = 0x3;
+pub const DEVICE_SPDIF_OUT: u32 = 0x4;
+pub const DEVICE_LINE_IN:   u32 = 0x8;
+pub const DEVICE_AUX:       u32 = 0x9;
+pub const DEVICE_MIC:       u32 = 0xA;
+pub const DEVICE_SPDIF_IN:  u32 = 0xC;
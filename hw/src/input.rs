@@ -0,0 +1,298 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Device independent key and pointer events.
+//!
+//! Keys are identified by the key codes of Linux' evdev interface, which virtio input
+//! devices report as they are; USB usages are converted with `from_hid_usage`. A
+//! `Keyboard` tracks the modifiers of the keys it sees and adds the character a key
+//! press produces on its layout. Events are passed on as records of `RECORD_LEN` bytes.
+
+pub const KEY_ESC:        u16 = 1;
+pub const KEY_BACKSPACE:  u16 = 14;
+pub const KEY_TAB:        u16 = 15;
+pub const KEY_ENTER:      u16 = 28;
+pub const KEY_LEFTCTRL:   u16 = 29;
+pub const KEY_A:          u16 = 30;
+pub const KEY_LEFTSHIFT:  u16 = 42;
+pub const KEY_RIGHTSHIFT: u16 = 54;
+pub const KEY_LEFTALT:    u16 = 56;
+pub const KEY_SPACE:      u16 = 57;
+pub const KEY_CAPSLOCK:   u16 = 58;
+pub const KEY_102ND:      u16 = 86;
+pub const KEY_KPENTER:    u16 = 96;
+pub const KEY_RIGHTCTRL:  u16 = 97;
+pub const KEY_RIGHTALT:   u16 = 100;
+pub const KEY_UP:         u16 = 103;
+pub const KEY_LEFT:       u16 = 105;
+pub const KEY_RIGHT:      u16 = 106;
+pub const KEY_DOWN:       u16 = 108;
+pub const KEY_LEFTMETA:   u16 = 125;
+pub const KEY_RIGHTMETA:  u16 = 126;
+
+/// Codes from here on are buttons
+pub const BTN_MISC:   u16 = 0x100;
+pub const BTN_LEFT:   u16 = 0x110;
+pub const BTN_RIGHT:  u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+/// Modifiers held down, and the lock keys that are on
+pub const MOD_LEFT_SHIFT:  u16 = 1 << 0;
+pub const MOD_RIGHT_SHIFT: u16 = 1 << 1;
+pub const MOD_LEFT_CTRL:   u16 = 1 << 2;
+pub const MOD_RIGHT_CTRL:  u16 = 1 << 3;
+pub const MOD_ALT:         u16 = 1 << 4;
+/// The right alt key, it selects the third level of a layout
+pub const MOD_ALTGR:       u16 = 1 << 5;
+pub const MOD_LEFT_META:   u16 = 1 << 6;
+pub const MOD_RIGHT_META:  u16 = 1 << 7;
+pub const MOD_CAPS_LOCK:   u16 = 1 << 8;
+pub const MOD_SHIFT: u16 = MOD_LEFT_SHIFT | MOD_RIGHT_SHIFT;
+pub const MOD_CTRL:  u16 = MOD_LEFT_CTRL | MOD_RIGHT_CTRL;
+pub const MOD_META:  u16 = MOD_LEFT_META | MOD_RIGHT_META;
+
+/// Absolute positions are scaled to `0..=POSITION_MAX`
+pub const POSITION_MAX: u32 = 0xFFFF;
+
+/// Record kinds
+pub const KIND_KEY:      u16 = 1;
+pub const KIND_BUTTON:   u16 = 2;
+pub const KIND_MOTION:   u16 = 3;
+pub const KIND_POSITION: u16 = 4;
+pub const KIND_WHEEL:    u16 = 5;
+
+/// Size of a record: the kind and code as u16, two i32 values and a u32, little endian
+pub const RECORD_LEN: usize = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+	/// A key below `BTN_MISC`, the character is only set for presses
+	Key { code: u16, pressed: bool, char: Option<char>, modifiers: u16 },
+	Button { button: u16, pressed: bool },
+	/// Relative movement of a mouse
+	Motion { dx: i32, dy: i32 },
+	/// Position of a tablet or touchscreen
+	Position { x: u32, y: u32 },
+	/// Wheel steps, positive `dy` scrolls up
+	Wheel { dx: i32, dy: i32 }
+}
+
+impl Event {
+	/// Record fields: `code` and `value` are the key and the state, the modifiers are in
+	/// `value2` and the character in `extra`, zero if there is none.
+	pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+		let (kind, code, value, value2, extra) = match *self {
+			Self::Key { code, pressed, char, modifiers } =>
+				(KIND_KEY, code, pressed as i32, modifiers as i32, char.map_or(0, |c| c as u32)),
+			Self::Button { button, pressed } => (KIND_BUTTON, button, pressed as i32, 0, 0),
+			Self::Motion { dx, dy }          => (KIND_MOTION, 0, dx, dy, 0),
+			Self::Position { x, y }          => (KIND_POSITION, 0, x as i32, y as i32, 0),
+			Self::Wheel { dx, dy }           => (KIND_WHEEL, 0, dx, dy, 0)
+		};
+
+		let mut buf = [0; RECORD_LEN];
+		buf[0..2].copy_from_slice(&kind.to_le_bytes());
+		buf[2..4].copy_from_slice(&code.to_le_bytes());
+		buf[4..8].copy_from_slice(&value.to_le_bytes());
+		buf[8..12].copy_from_slice(&value2.to_le_bytes());
+		buf[12..16].copy_from_slice(&extra.to_le_bytes());
+		buf
+	}
+
+	pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+		let buf = buf.get(..RECORD_LEN)?;
+		let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+		let i32_at = |i: usize| i32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+		let (code, value, value2) = (u16_at(2), i32_at(4), i32_at(8));
+
+		Some(match u16_at(0) {
+			KIND_KEY => Self::Key {
+				code,
+				pressed:   value != 0,
+				char:      char::from_u32(i32_at(12) as u32).filter(|&c| c != '\0'),
+				modifiers: value2 as u16
+			},
+			KIND_BUTTON   => Self::Button { button: code, pressed: value != 0 },
+			KIND_MOTION   => Self::Motion { dx: value, dy: value2 },
+			KIND_POSITION => Self::Position { x: value as u32, y: value2 as u32 },
+			KIND_WHEEL    => Self::Wheel { dx: value, dy: value2 },
+			_ => return None
+		})
+	}
+}
+
+/// Keys producing the same characters on all layouts, the keypad always enters digits.
+const COMMON: &[(u16, &str)] = &[
+	(1, "\x1b"), (14, "\x08"), (15, "\t"), (28, "\n"), (57, " "), (96, "\n"),
+	(55, "*"), (74, "-"), (78, "+"), (98, "/"), (83, "."),
+	(71, "7"), (72, "8"), (73, "9"), (75, "4"), (76, "5"), (77, "6"), (79, "1"), (80, "2"), (81, "3"), (82, "0")
+];
+
+/// The characters of a key without modifiers, with shift and with AltGr.
+const US: &[(u16, &str)] = &[
+	(2, "1!"), (3, "2@"), (4, "3#"), (5, "4$"), (6, "5%"), (7, "6^"), (8, "7&"), (9, "8*"), (10, "9("), (11, "0)"),
+	(12, "-_"), (13, "=+"),
+	(16, "qQ"), (17, "wW"), (18, "eE"), (19, "rR"), (20, "tT"), (21, "yY"), (22, "uU"), (23, "iI"), (24, "oO"), (25, "pP"),
+	(26, "[{"), (27, "]}"),
+	(30, "aA"), (31, "sS"), (32, "dD"), (33, "fF"), (34, "gG"), (35, "hH"), (36, "jJ"), (37, "kK"), (38, "lL"),
+	(39, ";:"), (40, "'\""), (41, "`~"), (43, "\\|"),
+	(44, "zZ"), (45, "xX"), (46, "cC"), (47, "vV"), (48, "bB"), (49, "nN"), (50, "mM"),
+	(51, ",<"), (52, ".>"), (53, "/?"), (86, "\\|")
+];
+
+const DE: &[(u16, &str)] = &[
+	(2, "1!"), (3, "2\"²"), (4, "3§³"), (5, "4$"), (6, "5%"), (7, "6&"), (8, "7/{"), (9, "8(["), (10, "9)]"), (11, "0=}"),
+	(12, "ß?\\"), (13, "´`"),
+	(16, "qQ@"), (17, "wW"), (18, "eE€"), (19, "rR"), (20, "tT"), (21, "zZ"), (22, "uU"), (23, "iI"), (24, "oO"), (25, "pP"),
+	(26, "üÜ"), (27, "+*~"),
+	(30, "aA"), (31, "sS"), (32, "dD"), (33, "fF"), (34, "gG"), (35, "hH"), (36, "jJ"), (37, "kK"), (38, "lL"),
+	(39, "öÖ"), (40, "äÄ"), (41, "^°"), (43, "#'"),
+	(44, "yY"), (45, "xX"), (46, "cC"), (47, "vV"), (48, "bB"), (49, "nN"), (50, "mMµ"),
+	(51, ",;"), (52, ".:"), (53, "-_"), (86, "<>|")
+];
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Layout {
+	#[default]
+	Us,
+	De
+}
+
+impl Layout {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"us" => Some(Self::Us),
+			"de" => Some(Self::De),
+			_    => None
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Us => "us",
+			Self::De => "de"
+		}
+	}
+
+	/// The character a key produces with the modifiers, `MOD_*`. Caps lock shifts
+	/// letters, control turns ASCII letters into control characters.
+	pub fn char(self, code: u16, modifiers: u16) -> Option<char> {
+		let table = match self {
+			Self::Us => US,
+			Self::De => DE
+		};
+		let (_, chars) = COMMON.iter().chain(table).find(|(c, _)| *c == code)?;
+		let mut chars = chars.chars();
+		let (normal, shifted, altgr) = (chars.next()?, chars.next(), chars.next());
+
+		if let (true, Some(c)) = (modifiers & MOD_ALTGR != 0, altgr) {
+			return Some(c);
+		}
+		let shift = (modifiers & MOD_SHIFT != 0) ^ (modifiers & MOD_CAPS_LOCK != 0 && normal.is_alphabetic());
+		let c = match shift {
+			true  => shifted.unwrap_or(normal),
+			false => normal
+		};
+		match modifiers & MOD_CTRL != 0 && c.is_ascii_alphabetic() {
+			true  => Some((c as u8 & 0x1F) as char),
+			false => Some(c)
+		}
+	}
+}
+
+/// Tracks the modifiers of key events and adds the characters of presses.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Keyboard {
+	layout:    Layout,
+	modifiers: u16
+}
+
+impl Keyboard {
+	pub const fn new(layout: Layout) -> Self {
+		Self { layout, modifiers: 0 }
+	}
+
+	pub fn layout(&self) -> Layout {
+		self.layout
+	}
+
+	pub fn set_layout(&mut self, layout: Layout) {
+		self.layout = layout;
+	}
+
+	/// The modifiers held down, `MOD_*`.
+	pub fn modifiers(&self) -> u16 {
+		self.modifiers
+	}
+
+	/// The event of a key press or release. Presses that repeat while the key is held
+	/// down are presses as well.
+	pub fn key(&mut self, code: u16, pressed: bool) -> Event {
+		let modifier = match code {
+			KEY_LEFTSHIFT  => MOD_LEFT_SHIFT,
+			KEY_RIGHTSHIFT => MOD_RIGHT_SHIFT,
+			KEY_LEFTCTRL   => MOD_LEFT_CTRL,
+			KEY_RIGHTCTRL  => MOD_RIGHT_CTRL,
+			KEY_LEFTALT    => MOD_ALT,
+			KEY_RIGHTALT   => MOD_ALTGR,
+			KEY_LEFTMETA   => MOD_LEFT_META,
+			KEY_RIGHTMETA  => MOD_RIGHT_META,
+			_              => 0
+		};
+		match (code, pressed) {
+			(KEY_CAPSLOCK, true) => self.modifiers ^= MOD_CAPS_LOCK,
+			(_, true)  => self.modifiers |= modifier,
+			(_, false) => self.modifiers &= !modifier
+		}
+
+		let char = match pressed && modifier == 0 {
+			true  => self.layout.char(code, self.modifiers),
+			false => None
+		};
+		Event::Key { code, pressed, char, modifiers: self.modifiers }
+	}
+}
+
+/// Key codes of the usages 0x04 to 0x65 of the keyboard/keypad page.
+const HID_USAGES: [u8; 0x62] = [
+	                30,  48,  46,  32,  18,  33,  34,  35,  23,  36,  37,  38,
+	 50,  49,  24,  25,  16,  19,  31,  20,  22,  47,  17,  45,  21,  44,   2,   3,
+	  4,   5,   6,   7,   8,   9,  10,  11,  28,   1,  14,  15,  57,  12,  13,  26,
+	 27,  43,  43,  39,  40,  41,  51,  52,  53,  58,  59,  60,  61,  62,  63,  64,
+	 65,  66,  67,  68,  87,  88,  99,  70, 119, 110, 102, 104, 111, 107, 109, 106,
+	105, 108, 103,  69,  98,  55,  74,  78,  96,  79,  80,  81,  75,  76,  77,  71,
+	 72,  73,  82,  83,  86, 127
+];
+
+/// Key codes of the modifier usages 0xE0 to 0xE7.
+const HID_MODIFIERS: [u16; 8] = [
+	KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_LEFTALT, KEY_LEFTMETA, KEY_RIGHTCTRL, KEY_RIGHTSHIFT, KEY_RIGHTALT, KEY_RIGHTMETA
+];
+
+/// The key code of a usage of the keyboard/keypad page.
+pub fn from_hid_usage(usage: u8) -> Option<u16> {
+	match usage {
+		0x04..=0x65 => Some(HID_USAGES[usage as usize - 0x04] as u16),
+		0xE0..=0xE7 => Some(HID_MODIFIERS[usage as usize - 0xE0]),
+		_ => None
+	}
+}
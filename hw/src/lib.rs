@@ -1,8 +1,15 @@
-#![feature(abi_efiapi)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
 
 use utils::*;
 
+pub mod utils;
 pub mod arch;
+pub mod dma;
+pub mod block;
+pub mod crc;
+pub mod compress;
 pub mod devtree;
 pub mod pcie;
 pub mod uefi;
@@ -16,7 +23,11 @@ pub mod raid;
 pub mod gpt;
 pub mod btrfs;
 pub mod fat32;
+pub mod net;
 pub mod smbios;
-pub mod utils;
 pub mod uart_ns16550a;
-pub mod font;
\ No newline at end of file
+pub mod font;
+pub mod console;
+pub mod input;
+pub mod random;
+pub mod p9;
@@ -0,0 +1,74 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Address Resolution Protocol for IPv4 over Ethernet.
+
+use super::Mac;
+
+pub const PACKET_LEN: usize = 28;
+
+pub const HTYPE_ETHERNET: u16 = 1;
+
+pub const OP_REQUEST: u16 = 1;
+pub const OP_REPLY:   u16 = 2;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Packet {
+	pub op:         u16,
+	pub sender_mac: Mac,
+	pub sender_ip:  [u8; 4],
+	pub target_mac: Mac,
+	pub target_ip:  [u8; 4]
+}
+
+impl Packet {
+	/// Parses a packet, only Ethernet and IPv4 addresses are supported.
+	pub fn parse(buf: &[u8]) -> Option<Self> {
+		if buf.len() < PACKET_LEN
+			|| u16::from_be_bytes([buf[0], buf[1]]) != HTYPE_ETHERNET
+			|| u16::from_be_bytes([buf[2], buf[3]]) != super::ethernet::TYPE_IPV4
+			|| buf[4] != 6 || buf[5] != 4 {
+			return None;
+		}
+		Some(Self {
+			op:         u16::from_be_bytes([buf[6], buf[7]]),
+			sender_mac: buf[8..14].try_into().unwrap(),
+			sender_ip:  buf[14..18].try_into().unwrap(),
+			target_mac: buf[18..24].try_into().unwrap(),
+			target_ip:  buf[24..28].try_into().unwrap()
+		})
+	}
+
+	pub fn to_bytes(&self) -> [u8; PACKET_LEN] {
+		let mut buf = [0; PACKET_LEN];
+		buf[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+		buf[2..4].copy_from_slice(&super::ethernet::TYPE_IPV4.to_be_bytes());
+		buf[4] = 6;
+		buf[5] = 4;
+		buf[6..8].copy_from_slice(&self.op.to_be_bytes());
+		buf[8..14].copy_from_slice(&self.sender_mac);
+		buf[14..18].copy_from_slice(&self.sender_ip);
+		buf[18..24].copy_from_slice(&self.target_mac);
+		buf[24..28].copy_from_slice(&self.target_ip);
+		buf
+	}
+}
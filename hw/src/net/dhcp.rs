@@ -0,0 +1,372 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Dynamic Host Configuration Protocol client.
+//!
+//! The `Client` discovers a server, requests the offered address and renews the lease at
+//! half of its time, first from the server that granted it, then from any. Messages are
+//! retransmitted with an exponential backoff until the server answers.
+
+use {super::Mac, alloc::vec::Vec};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+pub const OP_REQUEST: u8 = 1;
+pub const OP_REPLY:   u8 = 2;
+
+pub const MSG_DISCOVER: u8 = 1;
+pub const MSG_OFFER:    u8 = 2;
+pub const MSG_REQUEST:  u8 = 3;
+pub const MSG_DECLINE:  u8 = 4;
+pub const MSG_ACK:      u8 = 5;
+pub const MSG_NAK:      u8 = 6;
+pub const MSG_RELEASE:  u8 = 7;
+
+pub const OPTION_PAD:         u8 = 0;
+pub const OPTION_SUBNET_MASK: u8 = 1;
+pub const OPTION_ROUTER:      u8 = 3;
+pub const OPTION_DNS:         u8 = 6;
+pub const OPTION_REQUESTED:   u8 = 50;
+pub const OPTION_LEASE_TIME:  u8 = 51;
+pub const OPTION_MSG_TYPE:    u8 = 53;
+pub const OPTION_SERVER_ID:   u8 = 54;
+pub const OPTION_PARAMETERS:  u8 = 55;
+pub const OPTION_RENEWAL:     u8 = 58;
+pub const OPTION_REBINDING:   u8 = 59;
+pub const OPTION_END:         u8 = 255;
+
+const MAGIC: [u8; 4] = [99, 130, 83, 99];
+/// Offset of the options, after the fixed fields and the magic cookie
+const OPTIONS_OFFSET: usize = 240;
+/// Flag asking the server to broadcast its reply, as the client has no address yet
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const RETRANSMIT_MIN_MS: u64 = 4000;
+const RETRANSMIT_MAX_MS: u64 = 64_000;
+/// Requests for an offered address before discovering again
+const MAX_REQUESTS: u32 = 4;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Message {
+	pub op:         u8,
+	pub xid:        u32,
+	pub ciaddr:     [u8; 4],
+	pub yiaddr:     [u8; 4],
+	pub siaddr:     [u8; 4],
+	pub chaddr:     Mac,
+	pub ty:         u8,
+	pub server:     Option<[u8; 4]>,
+	pub requested:  Option<[u8; 4]>,
+	pub mask:       Option<[u8; 4]>,
+	pub router:     Option<[u8; 4]>,
+	pub dns:        Vec<[u8; 4]>,
+	/// In seconds
+	pub lease_time: Option<u32>,
+	pub renewal:    Option<u32>,
+	pub rebinding:  Option<u32>
+}
+
+impl Message {
+	pub fn parse(buf: &[u8]) -> Option<Self> {
+		if buf.len() < OPTIONS_OFFSET || buf[1] != 1 || buf[2] != 6 || buf[236..240] != MAGIC {
+			return None;
+		}
+		let addr = |i: usize| -> [u8; 4] { buf[i..i + 4].try_into().unwrap() };
+		let mut msg = Self {
+			op:     buf[0],
+			xid:    u32::from_be_bytes(addr(4)),
+			ciaddr: addr(12),
+			yiaddr: addr(16),
+			siaddr: addr(20),
+			chaddr: buf[28..34].try_into().unwrap(),
+			..Self::default()
+		};
+
+		let mut options = &buf[OPTIONS_OFFSET..];
+		while let Some(&code) = options.first() {
+			match code {
+				OPTION_PAD => {
+					options = &options[1..];
+					continue;
+				}
+				OPTION_END => break,
+				_ => ()
+			}
+			let len = *options.get(1)? as usize;
+			let data = options.get(2..2 + len)?;
+			let addr = || data.get(..4).and_then(|a| a.try_into().ok());
+			let secs = || data.get(..4).map(|a| u32::from_be_bytes(a.try_into().unwrap()));
+			match code {
+				OPTION_MSG_TYPE if len == 1 => msg.ty = data[0],
+				OPTION_SERVER_ID            => msg.server = addr(),
+				OPTION_REQUESTED            => msg.requested = addr(),
+				OPTION_SUBNET_MASK          => msg.mask = addr(),
+				OPTION_ROUTER               => msg.router = addr(),
+				OPTION_DNS                  => msg.dns = data.chunks_exact(4).map(|a| a.try_into().unwrap()).collect(),
+				OPTION_LEASE_TIME           => msg.lease_time = secs(),
+				OPTION_RENEWAL              => msg.renewal = secs(),
+				OPTION_REBINDING            => msg.rebinding = secs(),
+				_ => ()
+			}
+			options = &options[2 + len..];
+		}
+		(msg.ty != 0).then_some(msg)
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = alloc::vec![0; OPTIONS_OFFSET];
+		buf[0] = self.op;
+		buf[1] = 1;
+		buf[2] = 6;
+		buf[4..8].copy_from_slice(&self.xid.to_be_bytes());
+		if self.op == OP_REQUEST && self.ciaddr == [0; 4] {
+			buf[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+		}
+		buf[12..16].copy_from_slice(&self.ciaddr);
+		buf[16..20].copy_from_slice(&self.yiaddr);
+		buf[20..24].copy_from_slice(&self.siaddr);
+		buf[28..34].copy_from_slice(&self.chaddr);
+		buf[236..240].copy_from_slice(&MAGIC);
+
+		let mut option = |code: u8, data: &[u8]| {
+			buf.extend_from_slice(&[code, data.len() as u8]);
+			buf.extend_from_slice(data);
+		};
+		option(OPTION_MSG_TYPE, &[self.ty]);
+		let addrs = [(OPTION_SERVER_ID, self.server), (OPTION_REQUESTED, self.requested),
+			(OPTION_SUBNET_MASK, self.mask), (OPTION_ROUTER, self.router)];
+		for (code, addr) in addrs {
+			if let Some(addr) = addr {
+				option(code, &addr);
+			}
+		}
+		if !self.dns.is_empty() {
+			option(OPTION_DNS, &self.dns.concat());
+		}
+		for (code, secs) in [(OPTION_LEASE_TIME, self.lease_time), (OPTION_RENEWAL, self.renewal), (OPTION_REBINDING, self.rebinding)] {
+			if let Some(secs) = secs {
+				option(code, &secs.to_be_bytes());
+			}
+		}
+		if self.op == OP_REQUEST {
+			option(OPTION_PARAMETERS, &[OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS, OPTION_LEASE_TIME]);
+		}
+		buf.push(OPTION_END);
+		buf
+	}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+	Init,
+	Selecting,
+	Requesting,
+	Bound,
+	Renewing,
+	Rebinding
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lease {
+	pub addr:      [u8; 4],
+	pub prefix:    u8,
+	pub router:    Option<[u8; 4]>,
+	pub dns:       Vec<[u8; 4]>,
+	pub server:    [u8; 4],
+	/// Times in milliseconds
+	pub renew_at:  u64,
+	pub rebind_at: u64,
+	pub expires:   u64
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+	/// A lease was granted or renewed
+	Bound(Lease),
+	/// The lease expired or the server revoked it, the address mustn't be used anymore
+	Expired
+}
+
+/// A message to send, to the server or broadcast.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Outgoing {
+	pub msg:    Vec<u8>,
+	pub server: Option<[u8; 4]>
+}
+
+#[derive(Clone, Debug)]
+pub struct Client {
+	mac:      Mac,
+	state:    State,
+	xid:      u32,
+	/// When to send the next message
+	timer:    u64,
+	interval: u64,
+	retries:  u32,
+	offer:    Option<Message>,
+	lease:    Option<Lease>
+}
+
+impl Client {
+	pub fn new(mac: Mac, xid: u32) -> Self {
+		Self { mac, state: State::Init, xid, timer: 0, interval: RETRANSMIT_MIN_MS, retries: 0, offer: None, lease: None }
+	}
+
+	pub fn state(&self) -> State {
+		self.state
+	}
+
+	pub fn lease(&self) -> Option<&Lease> {
+		self.lease.as_ref()
+	}
+
+	fn message(&self, ty: u8) -> Message {
+		Message { op: OP_REQUEST, xid: self.xid, chaddr: self.mac, ty, ..Message::default() }
+	}
+
+	/// Sets the time of the next retransmission, doubling the interval.
+	fn backoff(&mut self, now: u64) {
+		self.timer = now + self.interval;
+		self.interval = (self.interval * 2).min(RETRANSMIT_MAX_MS);
+	}
+
+	fn enter(&mut self, state: State, now: u64) {
+		self.state = state;
+		self.timer = now;
+		self.interval = RETRANSMIT_MIN_MS;
+		self.retries = 0;
+	}
+
+	/// The message to send at `now`, and whether the lease expired.
+	pub fn poll(&mut self, now: u64) -> (Option<Outgoing>, Option<Event>) {
+		let mut event = None;
+		if let Some(lease) = &self.lease {
+			match self.state {
+				State::Bound if now >= lease.renew_at => self.enter(State::Renewing, now),
+				State::Renewing if now >= lease.rebind_at => self.enter(State::Rebinding, now),
+				State::Rebinding if now >= lease.expires => {
+					self.lease = None;
+					self.xid = self.xid.wrapping_add(1);
+					self.enter(State::Init, now);
+					event = Some(Event::Expired);
+				}
+				_ => ()
+			}
+		}
+		if now < self.timer || self.state == State::Bound {
+			return (None, event);
+		}
+
+		let msg = match self.state {
+			State::Init | State::Selecting => {
+				self.state = State::Selecting;
+				self.backoff(now);
+				Outgoing { msg: self.message(MSG_DISCOVER).to_bytes(), server: None }
+			}
+			State::Requesting => {
+				let Some(offer) = &self.offer else {
+					self.enter(State::Init, now);
+					return (None, event);
+				};
+				let msg = Message { requested: Some(offer.yiaddr), server: offer.server, ..self.message(MSG_REQUEST) };
+				self.retries += 1;
+				if self.retries > MAX_REQUESTS {
+					self.offer = None;
+					self.enter(State::Init, now);
+					return (None, event);
+				}
+				self.backoff(now);
+				Outgoing { msg: msg.to_bytes(), server: None }
+			}
+			State::Renewing | State::Rebinding => {
+				let lease = self.lease.as_ref().unwrap();
+				let msg = Message { ciaddr: lease.addr, ..self.message(MSG_REQUEST) };
+				// half of the time left until the next state, at least a minute
+				let until = match self.state {
+					State::Renewing => lease.rebind_at,
+					_               => lease.expires
+				};
+				let server = (self.state == State::Renewing).then_some(lease.server);
+				self.timer = now + (until.saturating_sub(now) / 2).max(60_000);
+				Outgoing { msg: msg.to_bytes(), server }
+			}
+			State::Bound => unreachable!()
+		};
+		(Some(msg), event)
+	}
+
+	/// Processes a message received on the client port.
+	pub fn receive(&mut self, buf: &[u8], now: u64) -> Option<Event> {
+		let msg = Message::parse(buf)?;
+		if msg.op != OP_REPLY || msg.xid != self.xid || msg.chaddr != self.mac {
+			return None;
+		}
+
+		match (self.state, msg.ty) {
+			(State::Selecting, MSG_OFFER) if msg.server.is_some() => {
+				self.offer = Some(msg);
+				self.enter(State::Requesting, now);
+				None
+			}
+			(State::Requesting | State::Renewing | State::Rebinding, MSG_ACK) => {
+				let addr = match self.state {
+					State::Requesting => msg.yiaddr,
+					_ => self.lease.as_ref().map_or(msg.yiaddr, |l| l.addr)
+				};
+				let server = msg.server.or_else(|| self.offer.as_ref().and_then(|o| o.server)).unwrap_or(msg.siaddr);
+				let secs = msg.lease_time.unwrap_or(3600) as u64;
+				let lease = Lease {
+					addr,
+					prefix:    msg.mask.and_then(super::ipv4::prefix).unwrap_or(24),
+					router:    msg.router,
+					dns:       msg.dns,
+					server,
+					renew_at:  now + msg.renewal.map_or(secs / 2, |s| s as u64) * 1000,
+					rebind_at: now + msg.rebinding.map_or(secs * 7 / 8, |s| s as u64) * 1000,
+					expires:   now + secs * 1000
+				};
+				self.lease = Some(lease.clone());
+				self.offer = None;
+				self.enter(State::Bound, now);
+				Some(Event::Bound(lease))
+			}
+			(State::Requesting | State::Renewing | State::Rebinding, MSG_NAK) => {
+				let expired = self.lease.take().map(|_| Event::Expired);
+				self.offer = None;
+				self.xid = self.xid.wrapping_add(1);
+				self.enter(State::Init, now);
+				expired
+			}
+			_ => None
+		}
+	}
+
+	/// The message releasing the lease, the client starts over afterwards.
+	pub fn release(&mut self, now: u64) -> Option<Outgoing> {
+		let lease = self.lease.take()?;
+		let msg = Message { ciaddr: lease.addr, server: Some(lease.server), ..self.message(MSG_RELEASE) };
+		self.xid = self.xid.wrapping_add(1);
+		self.enter(State::Init, now);
+		Some(Outgoing { msg: msg.to_bytes(), server: Some(lease.server) })
+	}
+}
@@ -0,0 +1,64 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Ethernet II frames.
+
+use {super::Mac, alloc::vec::Vec};
+
+pub const HEADER_LEN: usize = 14;
+
+pub const TYPE_IPV4: u16 = 0x0800;
+pub const TYPE_ARP:  u16 = 0x0806;
+pub const TYPE_IPV6: u16 = 0x86DD;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+	pub dst: Mac,
+	pub src: Mac,
+	pub ty:  u16
+}
+
+impl Header {
+	/// The header and the payload of a frame.
+	pub fn parse(frame: &[u8]) -> Option<(Self, &[u8])> {
+		if frame.len() < HEADER_LEN {
+			return None;
+		}
+		let header = Self {
+			dst: frame[0..6].try_into().unwrap(),
+			src: frame[6..12].try_into().unwrap(),
+			ty:  u16::from_be_bytes([frame[12], frame[13]])
+		};
+		Some((header, &frame[HEADER_LEN..]))
+	}
+
+	pub fn write(&self, buf: &mut Vec<u8>) {
+		buf.extend_from_slice(&self.dst);
+		buf.extend_from_slice(&self.src);
+		buf.extend_from_slice(&self.ty.to_be_bytes());
+	}
+}
+
+/// Whether the address is a group address, including the broadcast address.
+pub fn is_multicast(mac: &Mac) -> bool {
+	mac[0] & 1 != 0
+}
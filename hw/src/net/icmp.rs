@@ -0,0 +1,257 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Internet Control Message Protocol for IPv4 and IPv6, echo messages and neighbor
+//! discovery.
+//!
+//! Messages are built with a zero checksum, `finish_v4` and `finish_v6` fill it in.
+
+use {super::{IpAddr, Mac}, alloc::{collections::VecDeque, vec::Vec}};
+
+pub const V4_ECHO_REPLY:       u8 = 0;
+pub const V4_DEST_UNREACHABLE: u8 = 3;
+pub const V4_ECHO_REQUEST:     u8 = 8;
+
+pub const V6_DEST_UNREACHABLE: u8 = 1;
+pub const V6_ECHO_REQUEST:     u8 = 128;
+pub const V6_ECHO_REPLY:       u8 = 129;
+pub const V6_ROUTER_SOLICIT:   u8 = 133;
+pub const V6_ROUTER_ADVERT:    u8 = 134;
+pub const V6_NEIGHBOR_SOLICIT: u8 = 135;
+pub const V6_NEIGHBOR_ADVERT:  u8 = 136;
+
+/// Neighbor discovery options
+pub const OPTION_SOURCE_LL:   u8 = 1;
+pub const OPTION_TARGET_LL:   u8 = 2;
+pub const OPTION_PREFIX_INFO: u8 = 3;
+pub const OPTION_MTU:         u8 = 5;
+
+/// The advertisement answers a solicitation
+pub const ADVERT_SOLICITED: u8 = 0x40;
+/// The advertisement replaces the cached link-layer address
+pub const ADVERT_OVERRIDE:  u8 = 0x20;
+
+/// The prefix is on the link
+pub const PREFIX_ON_LINK:    u8 = 0x80;
+/// Addresses may be configured for the prefix
+pub const PREFIX_AUTONOMOUS: u8 = 0x40;
+
+/// An echo request or reply.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Echo<'a> {
+	pub ty:    u8,
+	pub ident: u16,
+	pub seq:   u16,
+	pub data:  &'a [u8]
+}
+
+impl<'a> Echo<'a> {
+	pub fn parse(msg: &'a [u8]) -> Option<Self> {
+		(msg.len() >= 8).then(|| Self {
+			ty:    msg[0],
+			ident: u16::from_be_bytes([msg[4], msg[5]]),
+			seq:   u16::from_be_bytes([msg[6], msg[7]]),
+			data:  &msg[8..]
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut msg = Vec::with_capacity(8 + self.data.len());
+		msg.extend_from_slice(&[self.ty, 0, 0, 0]);
+		msg.extend_from_slice(&self.ident.to_be_bytes());
+		msg.extend_from_slice(&self.seq.to_be_bytes());
+		msg.extend_from_slice(self.data);
+		msg
+	}
+}
+
+/// Most replies a socket holds before dropping new ones
+pub const RX_QUEUE_LEN: usize = 64;
+
+/// A socket sending echo requests to `remote`, it receives the replies with its
+/// identifier.
+#[derive(Clone, Debug)]
+pub struct Socket {
+	pub remote: IpAddr,
+	pub ident:  u16,
+	/// Sequence number of the next request
+	pub seq:    u16,
+	/// Sequence numbers and data of the received replies
+	pub rx:     VecDeque<(u16, Vec<u8>)>
+}
+
+impl Socket {
+	pub fn new(remote: IpAddr, ident: u16) -> Self {
+		Self { remote, ident, seq: 0, rx: VecDeque::new() }
+	}
+}
+
+/// A neighbor solicitation or advertisement.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Neighbor {
+	pub ty:     u8,
+	/// `ADVERT_*` flags of an advertisement
+	pub flags:  u8,
+	pub target: [u8; 16],
+	/// The source link-layer address of a solicitation, the target link-layer address
+	/// of an advertisement
+	pub mac:    Option<Mac>
+}
+
+impl Neighbor {
+	pub fn parse(msg: &[u8]) -> Option<Self> {
+		if msg.len() < 24 || msg[1] != 0 {
+			return None;
+		}
+		let ty = msg[0];
+		let option = match ty {
+			V6_NEIGHBOR_SOLICIT => OPTION_SOURCE_LL,
+			_                   => OPTION_TARGET_LL
+		};
+		Some(Self {
+			ty,
+			flags:  msg[4],
+			target: msg[8..24].try_into().unwrap(),
+			mac:    options(&msg[24..]).find(|(t, _)| *t == option).and_then(|(_, o)| o.get(..6)?.try_into().ok())
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut msg = Vec::with_capacity(32);
+		msg.extend_from_slice(&[self.ty, 0, 0, 0, self.flags, 0, 0, 0]);
+		msg.extend_from_slice(&self.target);
+		if let Some(mac) = self.mac {
+			let option = match self.ty {
+				V6_NEIGHBOR_SOLICIT => OPTION_SOURCE_LL,
+				_                   => OPTION_TARGET_LL
+			};
+			msg.extend_from_slice(&[option, 1]);
+			msg.extend_from_slice(&mac);
+		}
+		msg
+	}
+}
+
+/// A prefix of a router advertisement.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Prefix {
+	pub prefix:    [u8; 16],
+	pub len:       u8,
+	/// `PREFIX_*` flags
+	pub flags:     u8,
+	/// Lifetimes in seconds
+	pub valid:     u32,
+	pub preferred: u32
+}
+
+/// A router advertisement.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RouterAdvert {
+	/// How long the router is a default router, in seconds, zero if it isn't one
+	pub lifetime: u16,
+	pub mac:      Option<Mac>,
+	pub mtu:      Option<u32>,
+	pub prefixes: Vec<Prefix>
+}
+
+impl RouterAdvert {
+	pub fn parse(msg: &[u8]) -> Option<Self> {
+		if msg.len() < 16 || msg[0] != V6_ROUTER_ADVERT || msg[1] != 0 {
+			return None;
+		}
+		let mut advert = Self { lifetime: u16::from_be_bytes([msg[6], msg[7]]), mac: None, mtu: None, prefixes: Vec::new() };
+		for (ty, option) in options(&msg[16..]) {
+			match ty {
+				OPTION_SOURCE_LL if option.len() >= 6 => advert.mac = option[..6].try_into().ok(),
+				OPTION_MTU if option.len() >= 6 => advert.mtu = Some(u32::from_be_bytes(option[2..6].try_into().unwrap())),
+				OPTION_PREFIX_INFO if option.len() >= 30 => advert.prefixes.push(Prefix {
+					len:       option[0],
+					flags:     option[1],
+					valid:     u32::from_be_bytes(option[2..6].try_into().unwrap()),
+					preferred: u32::from_be_bytes(option[6..10].try_into().unwrap()),
+					prefix:    option[14..30].try_into().unwrap()
+				}),
+				_ => ()
+			}
+		}
+		Some(advert)
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut msg = Vec::new();
+		msg.extend_from_slice(&[V6_ROUTER_ADVERT, 0, 0, 0, 64, 0]);
+		msg.extend_from_slice(&self.lifetime.to_be_bytes());
+		msg.extend_from_slice(&[0; 8]);
+		if let Some(mac) = self.mac {
+			msg.extend_from_slice(&[OPTION_SOURCE_LL, 1]);
+			msg.extend_from_slice(&mac);
+		}
+		if let Some(mtu) = self.mtu {
+			msg.extend_from_slice(&[OPTION_MTU, 1, 0, 0]);
+			msg.extend_from_slice(&mtu.to_be_bytes());
+		}
+		for prefix in &self.prefixes {
+			msg.extend_from_slice(&[OPTION_PREFIX_INFO, 4, prefix.len, prefix.flags]);
+			msg.extend_from_slice(&prefix.valid.to_be_bytes());
+			msg.extend_from_slice(&prefix.preferred.to_be_bytes());
+			msg.extend_from_slice(&[0; 4]);
+			msg.extend_from_slice(&prefix.prefix);
+		}
+		msg
+	}
+}
+
+/// A router solicitation.
+pub fn router_solicit(mac: Option<Mac>) -> Vec<u8> {
+	let mut msg = alloc::vec![V6_ROUTER_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+	if let Some(mac) = mac {
+		msg.extend_from_slice(&[OPTION_SOURCE_LL, 1]);
+		msg.extend_from_slice(&mac);
+	}
+	msg
+}
+
+/// The type and body of neighbor discovery options, stops at a malformed one.
+pub fn options(mut buf: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+	core::iter::from_fn(move || {
+		let len = *buf.get(1)? as usize * 8;
+		if len == 0 || len > buf.len() {
+			return None;
+		}
+		let option = (buf[0], &buf[2..len]);
+		buf = &buf[len..];
+		Some(option)
+	})
+}
+
+/// Fills in the checksum of an ICMPv4 message.
+pub fn finish_v4(msg: &mut [u8]) {
+	let sum = super::checksum(msg);
+	msg[2..4].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Fills in the checksum of an ICMPv6 message, which covers the pseudo header.
+pub fn finish_v6(msg: &mut [u8], src: [u8; 16], dst: [u8; 16]) {
+	let sum = super::pseudo_header(IpAddr::V6(src), IpAddr::V6(dst), super::ipv6::NEXT_ICMPV6, msg.len());
+	let sum = !super::checksum_fold(super::checksum_add(sum, msg));
+	msg[2..4].copy_from_slice(&sum.to_be_bytes());
+}
@@ -0,0 +1,904 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {
+	super::{*, ethernet::Header as EthernetHeader},
+	alloc::{collections::BTreeMap, vec::Vec}
+};
+
+/// A socket of an interface.
+pub type Handle = usize;
+
+/// How long a resolved neighbor is used before it is resolved again
+pub const NEIGHBOR_TTL_MS:     u64 = 300_000;
+/// Interval between solicitations of an unresolved neighbor
+pub const RESOLVE_INTERVAL_MS: u64 = 1000;
+/// Solicitations before an unresolved neighbor and the packets held for it are dropped
+pub const RESOLVE_TRIES:       u32 = 3;
+/// Most packets held for a neighbor until it is resolved
+pub const PENDING_MAX:         usize = 16;
+
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Ipv4Config {
+	pub addr:    [u8; 4],
+	pub prefix:  u8,
+	pub gateway: Option<[u8; 4]>
+}
+
+/// An IP packet, with where the device completes its checksum.
+struct Packet {
+	ty:       u16,
+	data:     Vec<u8>,
+	checksum: Option<Checksum>
+}
+
+struct Neighbor {
+	mac:        Option<Mac>,
+	/// When the address expires, or the next solicitation is due while it isn't resolved
+	timer:      u64,
+	tries:      u32,
+	pending:    Vec<Packet>
+}
+
+enum Socket {
+	Udp(udp::Socket),
+	Tcp(tcp::Socket),
+	Icmp(icmp::Socket)
+}
+
+struct Slot {
+	socket:   Socket,
+	/// The handle was closed, the connection is still shutting down
+	released: bool
+}
+
+/// The addresses of a device and the sockets bound to them.
+pub struct Interface<D: Device> {
+	device:    D,
+	mac:       Mac,
+	ipv4:      Option<Ipv4Config>,
+	/// The link-local address first, then those configured from router advertisements
+	ipv6:      Vec<[u8; 16]>,
+	/// On-link prefixes and the default router, from router advertisements
+	prefixes:  Vec<([u8; 16], u8)>,
+	router6:   Option<[u8; 16]>,
+	dns:       Vec<IpAddr>,
+	neighbors: BTreeMap<IpAddr, Neighbor>,
+	sockets:   Vec<Option<Slot>>,
+	dhcp:      Option<dhcp::Client>,
+	random:    Random,
+	next_port: u16,
+	ip_id:     u16,
+	now:       u64,
+	/// A router solicitation is due
+	solicit:   bool
+}
+
+impl<D: Device> Interface<D> {
+	/// An interface with a link-local IPv6 address, `now` seeds sequence numbers.
+	pub fn new(device: D, now: u64) -> Self {
+		let mac = device.mac();
+		let mut random = Random::new(now ^ u64::from_be_bytes([0, 0, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]]));
+		let next_port = EPHEMERAL_PORTS.start() + (random.next_u32() % EPHEMERAL_PORTS.len() as u32) as u16;
+		Self {
+			device,
+			mac,
+			ipv4:      None,
+			ipv6:      alloc::vec![ipv6::link_local(mac)],
+			prefixes:  Vec::new(),
+			router6:   None,
+			dns:       Vec::new(),
+			neighbors: BTreeMap::new(),
+			sockets:   Vec::new(),
+			dhcp:      None,
+			random,
+			next_port,
+			ip_id:     0,
+			now,
+			solicit:   true
+		}
+	}
+
+	pub fn device(&self) -> &D {
+		&self.device
+	}
+
+	pub fn device_mut(&mut self) -> &mut D {
+		&mut self.device
+	}
+
+	pub fn mac(&self) -> Mac {
+		self.mac
+	}
+
+	pub fn ipv4(&self) -> Option<Ipv4Config> {
+		self.ipv4
+	}
+
+	/// Configures the IPv4 address, use `start_dhcp` to get one from a server instead.
+	pub fn set_ipv4(&mut self, config: Option<Ipv4Config>) {
+		if self.ipv4 != config {
+			self.neighbors.retain(|addr, _| addr.is_v6());
+		}
+		self.ipv4 = config;
+	}
+
+	pub fn ipv6(&self) -> &[[u8; 16]] {
+		&self.ipv6
+	}
+
+	pub fn add_ipv6(&mut self, addr: [u8; 16]) {
+		if !self.ipv6.contains(&addr) {
+			self.ipv6.push(addr);
+		}
+	}
+
+	/// The default IPv6 router, if one advertised itself.
+	pub fn router6(&self) -> Option<[u8; 16]> {
+		self.router6
+	}
+
+	/// Name servers, from DHCP or configured.
+	pub fn dns(&self) -> &[IpAddr] {
+		&self.dns
+	}
+
+	pub fn set_dns(&mut self, dns: Vec<IpAddr>) {
+		self.dns = dns;
+	}
+
+	/// Configures IPv4 with DHCP, starting with the next `poll`.
+	pub fn start_dhcp(&mut self) {
+		let xid = self.random.next_u32();
+		self.dhcp = Some(dhcp::Client::new(self.mac, xid));
+	}
+
+	/// Releases the lease, the address is removed.
+	pub fn stop_dhcp(&mut self) {
+		let Some(mut client) = self.dhcp.take() else { return };
+		if let (Some(out), Some(config)) = (client.release(self.now), self.ipv4) {
+			self.send_dhcp(IpAddr::V4(config.addr), out);
+			self.set_ipv4(None);
+		}
+	}
+
+	pub fn dhcp_state(&self) -> Option<dhcp::State> {
+		self.dhcp.as_ref().map(|c| c.state())
+	}
+
+	/// Processes received frames and timers, returns whether a frame was received.
+	pub fn poll(&mut self, now: u64) -> bool {
+		self.now = now;
+		let mut received = false;
+		while let Some(frame) = self.device.receive() {
+			self.receive(&frame);
+			received = true;
+		}
+
+		if let Some(client) = &mut self.dhcp {
+			let (out, event) = client.poll(now);
+			let src = match client.state() {
+				dhcp::State::Renewing | dhcp::State::Rebinding => self.ipv4.map_or(IpAddr::UNSPECIFIED_V4, |c| IpAddr::V4(c.addr)),
+				_ => IpAddr::UNSPECIFIED_V4
+			};
+			if let Some(event) = event {
+				self.apply_dhcp(event);
+			}
+			if let Some(out) = out {
+				self.send_dhcp(src, out);
+			}
+		}
+
+		if core::mem::take(&mut self.solicit) {
+			let mut msg = icmp::router_solicit(Some(self.mac));
+			icmp::finish_v6(&mut msg, self.ipv6[0], ipv6::ALL_ROUTERS);
+			let _ = self.send_ip(IpAddr::V6(self.ipv6[0]), IpAddr::V6(ipv6::ALL_ROUTERS), ipv6::NEXT_ICMPV6, msg, None);
+		}
+
+		self.resolve();
+
+		for i in 0..self.sockets.len() {
+			self.dispatch(i);
+			if let Some(Slot { socket: Socket::Tcp(s), released: true }) = &self.sockets[i] {
+				if s.state == tcp::State::Closed {
+					self.sockets[i] = None;
+				}
+			}
+		}
+		received
+	}
+
+	/// Solicits unresolved neighbors again, drops those that didn't answer and expired ones.
+	fn resolve(&mut self) {
+		let now = self.now;
+		let mut due = Vec::new();
+		self.neighbors.retain(|addr, n| match (n.mac, now >= n.timer) {
+			(_, false)                        => true,
+			(Some(_), true)                   => false,
+			(None, true) if n.tries >= RESOLVE_TRIES => false,
+			(None, true) => {
+				due.push(*addr);
+				true
+			}
+		});
+		for addr in due {
+			self.solicit(addr);
+		}
+	}
+
+	fn apply_dhcp(&mut self, event: dhcp::Event) {
+		self.dns.retain(IpAddr::is_v6);
+		match event {
+			dhcp::Event::Bound(lease) => {
+				self.set_ipv4(Some(Ipv4Config { addr: lease.addr, prefix: lease.prefix, gateway: lease.router }));
+				self.dns.extend(lease.dns.iter().map(|a| IpAddr::V4(*a)));
+			}
+			dhcp::Event::Expired => self.set_ipv4(None)
+		}
+	}
+
+	fn send_dhcp(&mut self, src: IpAddr, out: dhcp::Outgoing) {
+		let dst = out.server.map_or(IpAddr::BROADCAST_V4, IpAddr::V4);
+		let _ = self.send_udp(Endpoint::new(src, dhcp::CLIENT_PORT), Endpoint::new(dst, dhcp::SERVER_PORT), &out.msg);
+	}
+
+	fn receive(&mut self, frame: &Frame) {
+		let Some((eth, payload)) = EthernetHeader::parse(&frame.data) else { return };
+		if eth.dst != self.mac && !ethernet::is_multicast(&eth.dst) {
+			return;
+		}
+		match eth.ty {
+			ethernet::TYPE_ARP  => self.receive_arp(payload),
+			ethernet::TYPE_IPV4 => self.receive_ipv4(payload, frame.checksum_valid),
+			ethernet::TYPE_IPV6 => self.receive_ipv6(payload, frame.checksum_valid),
+			_ => ()
+		}
+	}
+
+	fn receive_arp(&mut self, payload: &[u8]) {
+		let (Some(packet), Some(config)) = (arp::Packet::parse(payload), self.ipv4) else { return };
+		let sender = IpAddr::V4(packet.sender_ip);
+		if packet.target_ip == config.addr || self.neighbors.contains_key(&sender) {
+			self.learn(sender, packet.sender_mac);
+		}
+		if packet.op == arp::OP_REQUEST && packet.target_ip == config.addr {
+			let reply = arp::Packet {
+				op:         arp::OP_REPLY,
+				sender_mac: self.mac,
+				sender_ip:  config.addr,
+				target_mac: packet.sender_mac,
+				target_ip:  packet.sender_ip
+			};
+			self.transmit(packet.sender_mac, ethernet::TYPE_ARP, &reply.to_bytes(), None);
+		}
+	}
+
+	fn receive_ipv4(&mut self, packet: &[u8], checked: bool) {
+		let Some((ip, payload)) = ipv4::Header::parse(packet) else { return };
+		let unicast = self.ipv4.map_or(false, |c| ip.dst == c.addr);
+		let broadcast = ip.dst == [0xFF; 4] || self.ipv4.map_or(false, |c| ip.dst == ipv4::broadcast(c.addr, c.prefix));
+		// replies to the DHCP client may be sent to the offered address
+		let dhcp = self.dhcp.is_some() && ip.protocol == ipv4::PROTOCOL_UDP;
+		if !unicast && !broadcast && !dhcp {
+			return;
+		}
+
+		let (src, dst) = (IpAddr::V4(ip.src), IpAddr::V4(ip.dst));
+		match ip.protocol {
+			ipv4::PROTOCOL_ICMP if unicast => self.receive_icmpv4(ip.src, ip.dst, payload),
+			ipv4::PROTOCOL_UDP => self.receive_udp(src, dst, payload, checked, unicast || broadcast),
+			ipv4::PROTOCOL_TCP if unicast => self.receive_tcp(src, dst, payload, checked),
+			_ => ()
+		}
+	}
+
+	fn receive_icmpv4(&mut self, src: [u8; 4], dst: [u8; 4], msg: &[u8]) {
+		if checksum(msg) != 0 {
+			return;
+		}
+		let Some(echo) = icmp::Echo::parse(msg) else { return };
+		match echo.ty {
+			icmp::V4_ECHO_REQUEST => {
+				let mut reply = icmp::Echo { ty: icmp::V4_ECHO_REPLY, ..echo }.to_bytes();
+				icmp::finish_v4(&mut reply);
+				let _ = self.send_ip(IpAddr::V4(dst), IpAddr::V4(src), ipv4::PROTOCOL_ICMP, reply, None);
+			}
+			icmp::V4_ECHO_REPLY => self.receive_echo_reply(IpAddr::V4(src), &echo),
+			_ => ()
+		}
+	}
+
+	fn receive_ipv6(&mut self, packet: &[u8], checked: bool) {
+		let Some((ip, payload)) = ipv6::Header::parse(packet) else { return };
+		let unicast = self.ipv6.contains(&ip.dst);
+		let multicast = ip.dst == ipv6::ALL_NODES || self.ipv6.iter().any(|a| ipv6::solicited_node(a) == ip.dst);
+		if !unicast && !multicast {
+			return;
+		}
+
+		let (src, dst) = (IpAddr::V6(ip.src), IpAddr::V6(ip.dst));
+		match ip.next {
+			ipv6::NEXT_ICMPV6 => self.receive_icmpv6(&ip, payload),
+			ipv6::NEXT_UDP => self.receive_udp(src, dst, payload, checked, true),
+			ipv6::NEXT_TCP if unicast => self.receive_tcp(src, dst, payload, checked),
+			_ => ()
+		}
+	}
+
+	fn receive_icmpv6(&mut self, ip: &ipv6::Header, msg: &[u8]) {
+		let sum = pseudo_header(IpAddr::V6(ip.src), IpAddr::V6(ip.dst), ipv6::NEXT_ICMPV6, msg.len());
+		if msg.len() < 4 || checksum_fold(checksum_add(sum, msg)) != 0xFFFF {
+			return;
+		}
+		// neighbor discovery messages from off the link are forged
+		let on_link = ip.hop_limit == 255;
+
+		match msg[0] {
+			icmp::V6_NEIGHBOR_SOLICIT if on_link => {
+				let Some(ns) = icmp::Neighbor::parse(msg) else { return };
+				if !self.ipv6.contains(&ns.target) {
+					return;
+				}
+				let unspecified = ip.src == [0; 16];
+				if let (Some(mac), false) = (ns.mac, unspecified) {
+					self.learn(IpAddr::V6(ip.src), mac);
+				}
+				let (dst, flags) = match unspecified {
+					true  => (ipv6::ALL_NODES, icmp::ADVERT_OVERRIDE),
+					false => (ip.src, icmp::ADVERT_SOLICITED | icmp::ADVERT_OVERRIDE)
+				};
+				let mut advert = icmp::Neighbor { ty: icmp::V6_NEIGHBOR_ADVERT, flags, target: ns.target, mac: Some(self.mac) }.to_bytes();
+				icmp::finish_v6(&mut advert, ns.target, dst);
+				let _ = self.send_ip(IpAddr::V6(ns.target), IpAddr::V6(dst), ipv6::NEXT_ICMPV6, advert, None);
+			}
+			icmp::V6_NEIGHBOR_ADVERT if on_link => {
+				if let Some(icmp::Neighbor { target, mac: Some(mac), .. }) = icmp::Neighbor::parse(msg) {
+					if self.neighbors.contains_key(&IpAddr::V6(target)) {
+						self.learn(IpAddr::V6(target), mac);
+					}
+				}
+			}
+			icmp::V6_ROUTER_ADVERT if on_link && ipv6::is_link_local(&ip.src) => {
+				let Some(advert) = icmp::RouterAdvert::parse(msg) else { return };
+				if let Some(mac) = advert.mac {
+					self.learn(IpAddr::V6(ip.src), mac);
+				}
+				self.router6 = match advert.lifetime {
+					0 if self.router6 == Some(ip.src) => None,
+					0 => self.router6,
+					_ => Some(ip.src)
+				};
+				for prefix in advert.prefixes.iter().filter(|p| p.valid > 0 && !ipv6::is_link_local(&p.prefix)) {
+					if prefix.flags & icmp::PREFIX_ON_LINK != 0 && !self.prefixes.contains(&(prefix.prefix, prefix.len)) {
+						self.prefixes.push((prefix.prefix, prefix.len));
+					}
+					if prefix.flags & icmp::PREFIX_AUTONOMOUS != 0 && prefix.len == 64 {
+						self.add_ipv6(ipv6::with_prefix(prefix.prefix[..8].try_into().unwrap(), self.mac));
+					}
+				}
+			}
+			icmp::V6_ECHO_REQUEST if self.ipv6.contains(&ip.dst) => {
+				let Some(echo) = icmp::Echo::parse(msg) else { return };
+				let mut reply = icmp::Echo { ty: icmp::V6_ECHO_REPLY, ..echo }.to_bytes();
+				icmp::finish_v6(&mut reply, ip.dst, ip.src);
+				let _ = self.send_ip(IpAddr::V6(ip.dst), IpAddr::V6(ip.src), ipv6::NEXT_ICMPV6, reply, None);
+			}
+			icmp::V6_ECHO_REPLY => if let Some(echo) = icmp::Echo::parse(msg) {
+				self.receive_echo_reply(IpAddr::V6(ip.src), &echo);
+			},
+			_ => ()
+		}
+	}
+
+	fn receive_echo_reply(&mut self, src: IpAddr, echo: &icmp::Echo) {
+		let socket = self.sockets.iter_mut().flatten().find_map(|slot| match &mut slot.socket {
+			Socket::Icmp(s) if s.remote == src && s.ident == echo.ident => Some(s),
+			_ => None
+		});
+		if let Some(socket) = socket {
+			if socket.rx.len() < icmp::RX_QUEUE_LEN {
+				socket.rx.push_back((echo.seq, echo.data.to_vec()));
+			}
+		}
+	}
+
+	fn receive_udp(&mut self, src: IpAddr, dst: IpAddr, datagram: &[u8], checked: bool, ours: bool) {
+		let Some((udp, data)) = udp::Header::parse(datagram, src, dst, checked) else { return };
+		if udp.dst_port == dhcp::CLIENT_PORT && udp.src_port == dhcp::SERVER_PORT {
+			if let Some(event) = self.dhcp.as_mut().and_then(|c| c.receive(data, self.now)) {
+				self.apply_dhcp(event);
+			}
+			return;
+		} else if !ours {
+			return;
+		}
+
+		let (src, dst) = (Endpoint::new(src, udp.src_port), Endpoint::new(dst, udp.dst_port));
+		// connected sockets take precedence
+		let mut sockets = self.sockets.iter_mut().flatten().filter_map(|slot| match &mut slot.socket {
+			Socket::Udp(s) if s.accepts(src, dst) => Some(s),
+			_ => None
+		}).collect::<Vec<_>>();
+		sockets.sort_by_key(|s| s.remote.is_none());
+		if let Some(socket) = sockets.into_iter().next() {
+			socket.push(src, data);
+		}
+	}
+
+	fn receive_tcp(&mut self, src: IpAddr, dst: IpAddr, buf: &[u8], checked: bool) {
+		let Some((segment, payload)) = tcp::Segment::parse(buf, src, dst, checked) else { return };
+		let (src, dst) = (Endpoint::new(src, segment.src_port), Endpoint::new(dst, segment.dst_port));
+
+		let connection = self.sockets.iter().position(|slot| matches!(slot, Some(Slot { socket: Socket::Tcp(s), .. }) if s.matches(src, dst)));
+		if let Some(i) = connection {
+			let Some(Slot { socket: Socket::Tcp(s), .. }) = &mut self.sockets[i] else { unreachable!() };
+			if let Some(reset) = s.process(&segment, payload, self.now) {
+				let _ = self.send_tcp(dst, src, reset, &[]);
+			}
+			self.dispatch(i);
+			return;
+		}
+
+		let listener = self.sockets.iter().position(|slot| matches!(slot, Some(Slot { socket: Socket::Tcp(s), released: false })
+			if s.state == tcp::State::Listen && s.local.port == dst.port && (s.local.addr.is_unspecified() || s.local.addr == dst.addr)));
+		match listener {
+			Some(l) if segment.flags & (tcp::FLAG_SYN | tcp::FLAG_ACK | tcp::FLAG_RST) == tcp::FLAG_SYN => {
+				let Some(Slot { socket: Socket::Tcp(listener), .. }) = &self.sockets[l] else { unreachable!() };
+				let pending = self.sockets.iter().flatten().filter(|slot| matches!(&slot.socket, Socket::Tcp(s) if s.parent == Some(l))).count();
+				// the peer retries once connections were accepted
+				if pending >= listener.backlog {
+					return;
+				}
+				let iss = self.random.next_u32();
+				let socket = tcp::Socket::accept(dst, src, &segment, iss, self.mss(src.addr), l);
+				let i = self.insert(Socket::Tcp(socket));
+				self.dispatch(i);
+			}
+			Some(_) if segment.flags & tcp::FLAG_RST != 0 => (),
+			_ => if let Some(reset) = segment.reset_reply(payload.len()) {
+				let _ = self.send_tcp(dst, src, reset, &[]);
+			}
+		}
+	}
+
+	/// Records the link-layer address of a neighbor and sends the packets held for it.
+	fn learn(&mut self, addr: IpAddr, mac: Mac) {
+		let neighbor = self.neighbors.entry(addr).or_insert(Neighbor { mac: None, timer: 0, tries: 0, pending: Vec::new() });
+		neighbor.mac = Some(mac);
+		neighbor.timer = self.now + NEIGHBOR_TTL_MS;
+		neighbor.tries = 0;
+		for packet in core::mem::take(&mut neighbor.pending) {
+			self.transmit(mac, packet.ty, &packet.data, packet.checksum);
+		}
+	}
+
+	/// Sends an ARP request or a neighbor solicitation for `addr`.
+	fn solicit(&mut self, addr: IpAddr) {
+		if let Some(n) = self.neighbors.get_mut(&addr) {
+			n.tries += 1;
+			n.timer = self.now + RESOLVE_INTERVAL_MS;
+		}
+		match addr {
+			IpAddr::V4(target_ip) => {
+				let Some(config) = self.ipv4 else { return };
+				let request = arp::Packet { op: arp::OP_REQUEST, sender_mac: self.mac, sender_ip: config.addr, target_mac: [0; 6], target_ip };
+				self.transmit(BROADCAST, ethernet::TYPE_ARP, &request.to_bytes(), None);
+			}
+			IpAddr::V6(target) => {
+				let Some(IpAddr::V6(src)) = self.source(addr) else { return };
+				let dst = ipv6::solicited_node(&target);
+				let mut msg = icmp::Neighbor { ty: icmp::V6_NEIGHBOR_SOLICIT, flags: 0, target, mac: Some(self.mac) }.to_bytes();
+				icmp::finish_v6(&mut msg, src, dst);
+				let _ = self.send_ip(IpAddr::V6(src), IpAddr::V6(dst), ipv6::NEXT_ICMPV6, msg, None);
+			}
+		}
+	}
+
+	/// The address to send to `dst` from.
+	fn source(&self, dst: IpAddr) -> Option<IpAddr> {
+		match dst {
+			IpAddr::V4(_) => self.ipv4.map(|c| IpAddr::V4(c.addr)),
+			IpAddr::V6(d) if ipv6::is_link_local(&d) || d[0] == 0xFF => Some(IpAddr::V6(self.ipv6[0])),
+			IpAddr::V6(_) => Some(IpAddr::V6(*self.ipv6.get(1).unwrap_or(&self.ipv6[0])))
+		}
+	}
+
+	/// The maximum segment size of TCP connections to `addr`.
+	fn mss(&self, addr: IpAddr) -> u16 {
+		let headers = match addr {
+			IpAddr::V4(_) => ipv4::HEADER_LEN + tcp::HEADER_LEN,
+			IpAddr::V6(_) => ipv6::HEADER_LEN + tcp::HEADER_LEN
+		};
+		self.device.mtu().saturating_sub(headers).min(0xFFFF) as u16
+	}
+
+	fn send_udp(&mut self, src: Endpoint, dst: Endpoint, data: &[u8]) -> Result<()> {
+		let offload = self.device.checksum_offload();
+		let mut datagram = Vec::with_capacity(udp::HEADER_LEN + data.len());
+		udp::Header { src_port: src.port, dst_port: dst.port, len: data.len() as u16 }
+			.write(&mut datagram, data, src.addr, dst.addr, offload);
+		self.send_ip(src.addr, dst.addr, ipv4::PROTOCOL_UDP, datagram, offload.then_some(udp::CHECKSUM_OFFSET))
+	}
+
+	fn send_tcp(&mut self, src: Endpoint, dst: Endpoint, segment: tcp::Segment, data: &[u8]) -> Result<()> {
+		let offload = self.device.checksum_offload();
+		let mut buf = Vec::with_capacity(tcp::HEADER_LEN + 4 + data.len());
+		segment.write(&mut buf, data, src.addr, dst.addr, offload);
+		self.send_ip(src.addr, dst.addr, ipv4::PROTOCOL_TCP, buf, offload.then_some(tcp::CHECKSUM_OFFSET))
+	}
+
+	/// Sends a packet, `checksum` is the offset of the checksum the device completes in
+	/// the payload.
+	fn send_ip(&mut self, src: IpAddr, dst: IpAddr, protocol: u8, payload: Vec<u8>, checksum: Option<u16>) -> Result<()> {
+		let mut data = Vec::with_capacity(ipv6::HEADER_LEN + payload.len());
+		let (ty, header_len) = match (src, dst) {
+			(IpAddr::V4(src), IpAddr::V4(dst)) => {
+				self.ip_id = self.ip_id.wrapping_add(1);
+				ipv4::Header { src, dst, protocol, ttl: ipv4::DEFAULT_TTL, id: self.ip_id, len: payload.len() as u16 }.write(&mut data);
+				(ethernet::TYPE_IPV4, ipv4::HEADER_LEN)
+			}
+			(IpAddr::V6(src), IpAddr::V6(dst)) => {
+				// neighbor discovery requires the maximum hop limit
+				let hop_limit = match protocol {
+					ipv6::NEXT_ICMPV6 => 255,
+					_                 => ipv6::DEFAULT_HOP_LIMIT
+				};
+				ipv6::Header { src, dst, next: protocol, hop_limit, len: payload.len() as u16 }.write(&mut data);
+				(ethernet::TYPE_IPV6, ipv6::HEADER_LEN)
+			}
+			_ => return Err(Error::InvalidArgument)
+		};
+		data.extend_from_slice(&payload);
+		let checksum = checksum.map(|offset| Checksum { start: header_len as u16, offset });
+		self.route(dst, Packet { ty, data, checksum })
+	}
+
+	/// Sends a packet to the next hop towards `dst`, or holds it back until the next hop
+	/// is resolved.
+	fn route(&mut self, dst: IpAddr, packet: Packet) -> Result<()> {
+		let hop = match dst {
+			IpAddr::V4(d) if d == [0xFF; 4] || self.ipv4.map_or(false, |c| d == ipv4::broadcast(c.addr, c.prefix)) => {
+				self.transmit(BROADCAST, packet.ty, &packet.data, packet.checksum);
+				return Ok(());
+			}
+			IpAddr::V4(d) if dst.is_multicast() => {
+				self.transmit([1, 0, 0x5E, d[1] & 0x7F, d[2], d[3]], packet.ty, &packet.data, packet.checksum);
+				return Ok(());
+			}
+			IpAddr::V4(d) => {
+				let config = self.ipv4.ok_or(Error::NoRoute)?;
+				match ipv4::same_network(d, config.addr, config.prefix) {
+					true  => dst,
+					false => IpAddr::V4(config.gateway.ok_or(Error::NoRoute)?)
+				}
+			}
+			IpAddr::V6(d) if dst.is_multicast() => {
+				self.transmit(ipv6::multicast_mac(&d), packet.ty, &packet.data, packet.checksum);
+				return Ok(());
+			}
+			IpAddr::V6(d) if ipv6::is_link_local(&d) || self.prefixes.iter().any(|(p, len)| ipv6::same_prefix(p, &d, *len)) => dst,
+			IpAddr::V6(_) => IpAddr::V6(self.router6.ok_or(Error::NoRoute)?)
+		};
+
+		let neighbor = self.neighbors.entry(hop).or_insert(Neighbor { mac: None, timer: 0, tries: 0, pending: Vec::new() });
+		match neighbor.mac {
+			Some(mac) => {
+				self.transmit(mac, packet.ty, &packet.data, packet.checksum);
+				Ok(())
+			}
+			None => {
+				let new = neighbor.tries == 0;
+				if neighbor.pending.len() < PENDING_MAX {
+					neighbor.pending.push(packet);
+				}
+				if new {
+					self.solicit(hop);
+				}
+				Ok(())
+			}
+		}
+	}
+
+	fn transmit(&mut self, dst: Mac, ty: u16, payload: &[u8], checksum: Option<Checksum>) {
+		let mut frame = Vec::with_capacity(ethernet::HEADER_LEN + payload.len());
+		EthernetHeader { dst, src: self.mac, ty }.write(&mut frame);
+		frame.extend_from_slice(payload);
+		let checksum = checksum.map(|c| Checksum { start: c.start + ethernet::HEADER_LEN as u16, ..c });
+		// lost frames are retransmitted by TCP or the application
+		let _ = self.device.transmit(&frame, checksum);
+	}
+
+	/// Sends the segments a TCP socket has to send.
+	fn dispatch(&mut self, i: Handle) {
+		let Some(Some(Slot { socket: Socket::Tcp(s), .. })) = self.sockets.get_mut(i) else { return };
+		let (local, remote) = (s.local, s.remote);
+		for (segment, data) in s.dispatch(self.now) {
+			let _ = self.send_tcp(local, remote, segment, &data);
+		}
+	}
+
+	fn insert(&mut self, socket: Socket) -> Handle {
+		let slot = Some(Slot { socket, released: false });
+		match self.sockets.iter().position(Option::is_none) {
+			Some(i) => {
+				self.sockets[i] = slot;
+				i
+			}
+			None => {
+				self.sockets.push(slot);
+				self.sockets.len() - 1
+			}
+		}
+	}
+
+	fn socket(&mut self, handle: Handle) -> Result<&mut Socket> {
+		match self.sockets.get_mut(handle) {
+			Some(Some(Slot { socket, released: false })) => Ok(socket),
+			_ => Err(Error::InvalidArgument)
+		}
+	}
+
+	fn tcp(&mut self, handle: Handle) -> Result<&mut tcp::Socket> {
+		match self.socket(handle)? {
+			Socket::Tcp(s) => Ok(s),
+			_ => Err(Error::InvalidArgument)
+		}
+	}
+
+	/// Whether a socket of the same protocol is bound to the port.
+	fn port_used(&self, tcp: bool, port: u16) -> bool {
+		self.sockets.iter().flatten().any(|slot| match &slot.socket {
+			Socket::Udp(s) => !tcp && s.local.port == port,
+			Socket::Tcp(s) => tcp && s.local.port == port && (s.state == tcp::State::Listen || s.state != tcp::State::Closed),
+			Socket::Icmp(_) => false
+		})
+	}
+
+	fn ephemeral_port(&mut self, tcp: bool) -> Result<u16> {
+		for _ in 0..EPHEMERAL_PORTS.len() {
+			let port = self.next_port;
+			self.next_port = match port {
+				p if p == *EPHEMERAL_PORTS.end() => *EPHEMERAL_PORTS.start(),
+				p => p + 1
+			};
+			if !self.port_used(tcp, port) {
+				return Ok(port);
+			}
+		}
+		Err(Error::AddressInUse)
+	}
+
+	/// Closes a socket, TCP connections are shut down gracefully in the background and
+	/// connections that weren't accepted yet are reset.
+	pub fn close(&mut self, handle: Handle) -> Result<()> {
+		self.socket(handle)?;
+		let Some(Slot { socket, released }) = &mut self.sockets[handle] else { unreachable!() };
+		match socket {
+			Socket::Tcp(s) if s.state == tcp::State::Listen => {
+				self.sockets[handle] = None;
+				let children = (0..self.sockets.len())
+					.filter(|&i| matches!(&self.sockets[i], Some(Slot { socket: Socket::Tcp(s), .. }) if s.parent == Some(handle)))
+					.collect::<Vec<_>>();
+				for i in children {
+					let Some(Slot { socket: Socket::Tcp(mut s), .. }) = self.sockets[i].take() else { unreachable!() };
+					if let Some(reset) = s.abort() {
+						let _ = self.send_tcp(s.local, s.remote, reset, &[]);
+					}
+				}
+			}
+			Socket::Tcp(s) => {
+				s.close();
+				*released = true;
+				self.dispatch(handle);
+			}
+			_ => self.sockets[handle] = None
+		}
+		Ok(())
+	}
+
+	/// The local and, if connected, the remote endpoint of a socket.
+	pub fn endpoints(&mut self, handle: Handle) -> Result<(Endpoint, Option<Endpoint>)> {
+		Ok(match self.socket(handle)? {
+			Socket::Udp(s)  => (s.local, s.remote),
+			Socket::Tcp(s)  => (s.local, (s.state != tcp::State::Listen).then_some(s.remote)),
+			Socket::Icmp(s) => (Endpoint::new(s.remote, 0), None)
+		})
+	}
+
+	/// Binds a UDP socket to `local`, an ephemeral port if its port is zero. A connected
+	/// socket only receives from `remote`.
+	pub fn udp_bind(&mut self, local: Endpoint, remote: Option<Endpoint>) -> Result<Handle> {
+		let port = match local.port {
+			0 => self.ephemeral_port(false)?,
+			p if self.port_used(false, p) => return Err(Error::AddressInUse),
+			p => p
+		};
+		Ok(self.insert(Socket::Udp(udp::Socket::new(Endpoint::new(local.addr, port), remote))))
+	}
+
+	/// Sends a datagram to `dst`, or the socket's remote if it is connected. Datagrams
+	/// larger than the MTU are rejected.
+	pub fn udp_send(&mut self, handle: Handle, dst: Option<Endpoint>, data: &[u8]) -> Result<()> {
+		let Socket::Udp(s) = self.socket(handle)? else { return Err(Error::InvalidArgument) };
+		let (local, remote) = (s.local, s.remote);
+		let dst = dst.or(remote).ok_or(Error::NotConnected)?;
+		let src = match local.addr.is_unspecified() || local.addr.is_v6() != dst.addr.is_v6() {
+			true  => self.source(dst.addr).ok_or(Error::NoRoute)?,
+			false => local.addr
+		};
+		let headers = match dst.addr {
+			IpAddr::V4(_) => ipv4::HEADER_LEN + udp::HEADER_LEN,
+			IpAddr::V6(_) => ipv6::HEADER_LEN + udp::HEADER_LEN
+		};
+		if data.len() + headers > self.device.mtu() {
+			return Err(Error::InvalidArgument);
+		}
+		self.send_udp(Endpoint::new(src, local.port), dst, data)
+	}
+
+	/// The next received datagram and its sender.
+	pub fn udp_recv(&mut self, handle: Handle) -> Result<(Endpoint, Vec<u8>)> {
+		match self.socket(handle)? {
+			Socket::Udp(s) => s.rx.pop_front().ok_or(Error::WouldBlock),
+			_ => Err(Error::InvalidArgument)
+		}
+	}
+
+	/// Opens a connection, the SYN is sent immediately. The socket is connected once its
+	/// state is `Established`.
+	pub fn tcp_connect(&mut self, remote: Endpoint) -> Result<Handle> {
+		if remote.port == 0 || remote.addr.is_unspecified() || remote.addr.is_multicast() {
+			return Err(Error::InvalidArgument);
+		}
+		let src = self.source(remote.addr).ok_or(Error::NoRoute)?;
+		let local = Endpoint::new(src, self.ephemeral_port(true)?);
+		let socket = tcp::Socket::connect(local, remote, self.random.next_u32(), self.mss(remote.addr));
+		let handle = self.insert(Socket::Tcp(socket));
+		self.dispatch(handle);
+		Ok(handle)
+	}
+
+	/// Accepts connections to `local`, at most `backlog` of them wait to be accepted.
+	pub fn tcp_listen(&mut self, local: Endpoint, backlog: usize) -> Result<Handle> {
+		if local.port == 0 {
+			return Err(Error::InvalidArgument);
+		} else if self.port_used(true, local.port) {
+			return Err(Error::AddressInUse);
+		}
+		Ok(self.insert(Socket::Tcp(tcp::Socket::listen(local, backlog))))
+	}
+
+	/// Takes an established connection from the backlog of a listening socket.
+	pub fn tcp_accept(&mut self, handle: Handle) -> Result<Handle> {
+		if self.tcp(handle)?.state != tcp::State::Listen {
+			return Err(Error::InvalidArgument);
+		}
+		let connection = self.sockets.iter_mut().enumerate().find_map(|(i, slot)| match slot {
+			Some(Slot { socket: Socket::Tcp(s), .. }) if s.parent == Some(handle) && s.state.is_synchronized() => {
+				s.parent = None;
+				Some(i)
+			}
+			_ => None
+		});
+		connection.ok_or(Error::WouldBlock)
+	}
+
+	/// Queues data and sends what the window allows, returns how much was queued.
+	pub fn tcp_send(&mut self, handle: Handle, data: &[u8]) -> Result<usize> {
+		let n = self.tcp(handle)?.send(data)?;
+		self.dispatch(handle);
+		Ok(n)
+	}
+
+	/// Reads received data, zero once the peer closed the connection.
+	pub fn tcp_recv(&mut self, handle: Handle, buf: &mut [u8]) -> Result<usize> {
+		let n = self.tcp(handle)?.recv(buf)?;
+		// announces a window that opened again
+		self.dispatch(handle);
+		Ok(n)
+	}
+
+	/// Closes our side of a connection, data can still be received.
+	pub fn tcp_shutdown(&mut self, handle: Handle) -> Result<()> {
+		self.tcp(handle)?.close();
+		self.dispatch(handle);
+		Ok(())
+	}
+
+	/// Closes a connection immediately with a reset.
+	pub fn tcp_abort(&mut self, handle: Handle) -> Result<()> {
+		let s = self.tcp(handle)?;
+		let (local, remote) = (s.local, s.remote);
+		if let Some(reset) = s.abort() {
+			self.send_tcp(local, remote, reset, &[])?;
+		}
+		Ok(())
+	}
+
+	pub fn tcp_state(&mut self, handle: Handle) -> Result<tcp::State> {
+		Ok(self.tcp(handle)?.state)
+	}
+
+	/// Why a connection was closed, if it didn't close normally.
+	pub fn tcp_error(&mut self, handle: Handle) -> Result<Option<Error>> {
+		Ok(self.tcp(handle)?.error)
+	}
+
+	/// Whether `tcp_recv` returns data or the end of the stream.
+	pub fn tcp_can_recv(&mut self, handle: Handle) -> Result<bool> {
+		Ok(self.tcp(handle)?.can_recv())
+	}
+
+	/// Opens a socket sending echo requests to `remote`.
+	pub fn icmp_open(&mut self, remote: IpAddr) -> Result<Handle> {
+		if remote.is_unspecified() {
+			return Err(Error::InvalidArgument);
+		}
+		let ident = self.random.next_u32() as u16;
+		Ok(self.insert(Socket::Icmp(icmp::Socket::new(remote, ident))))
+	}
+
+	/// Sends an echo request with `data`, returns its sequence number.
+	pub fn icmp_send(&mut self, handle: Handle, data: &[u8]) -> Result<u16> {
+		let Socket::Icmp(s) = self.socket(handle)? else { return Err(Error::InvalidArgument) };
+		let (remote, ident, seq) = (s.remote, s.ident, s.seq);
+		s.seq = s.seq.wrapping_add(1);
+
+		let src = self.source(remote).ok_or(Error::NoRoute)?;
+		match (src, remote) {
+			(IpAddr::V4(_), IpAddr::V4(_)) => {
+				let mut msg = icmp::Echo { ty: icmp::V4_ECHO_REQUEST, ident, seq, data }.to_bytes();
+				icmp::finish_v4(&mut msg);
+				self.send_ip(src, remote, ipv4::PROTOCOL_ICMP, msg, None)?;
+			}
+			(IpAddr::V6(s), IpAddr::V6(d)) => {
+				let mut msg = icmp::Echo { ty: icmp::V6_ECHO_REQUEST, ident, seq, data }.to_bytes();
+				icmp::finish_v6(&mut msg, s, d);
+				self.send_ip(src, remote, ipv6::NEXT_ICMPV6, msg, None)?;
+			}
+			_ => return Err(Error::NoRoute)
+		}
+		Ok(seq)
+	}
+
+	/// The sequence number and data of the next received echo reply.
+	pub fn icmp_recv(&mut self, handle: Handle) -> Result<(u16, Vec<u8>)> {
+		match self.socket(handle)? {
+			Socket::Icmp(s) => s.rx.pop_front().ok_or(Error::WouldBlock),
+			_ => Err(Error::InvalidArgument)
+		}
+	}
+}
+
+impl<D: Device> core::fmt::Debug for Interface<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Interface")
+			.field("mac", &self.mac)
+			.field("ipv4", &self.ipv4)
+			.field("ipv6", &self.ipv6.iter().map(|a| IpAddr::V6(*a)).collect::<Vec<_>>())
+			.field("dns", &self.dns)
+			.field("sockets", &self.sockets.iter().flatten().count())
+			.finish()
+	}
+}
@@ -0,0 +1,116 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Internet Protocol version 4 headers, without options.
+
+use alloc::vec::Vec;
+
+pub const HEADER_LEN: usize = 20;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP:  u8 = 6;
+pub const PROTOCOL_UDP:  u8 = 17;
+
+pub const DEFAULT_TTL: u8 = 64;
+
+/// Don't Fragment
+const FLAG_DF: u16 = 0x4000;
+/// More Fragments
+const FLAG_MF: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+	pub src:      [u8; 4],
+	pub dst:      [u8; 4],
+	pub protocol: u8,
+	pub ttl:      u8,
+	pub id:       u16,
+	/// Length of the payload
+	pub len:      u16
+}
+
+impl Header {
+	/// The header and the payload of a packet. Packets with a wrong checksum and
+	/// fragments are rejected, options are skipped.
+	pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+		if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+			return None;
+		}
+		let header_len = (packet[0] & 0xF) as usize * 4;
+		let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+		let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+		if header_len < HEADER_LEN || total_len < header_len || total_len > packet.len()
+			|| super::checksum(&packet[..header_len]) != 0
+			|| fragment & (FLAG_MF | FRAGMENT_OFFSET_MASK) != 0 {
+			return None;
+		}
+
+		let header = Self {
+			src:      packet[12..16].try_into().unwrap(),
+			dst:      packet[16..20].try_into().unwrap(),
+			protocol: packet[9],
+			ttl:      packet[8],
+			id:       u16::from_be_bytes([packet[4], packet[5]]),
+			len:      (total_len - header_len) as u16
+		};
+		Some((header, &packet[header_len..total_len]))
+	}
+
+	pub fn write(&self, buf: &mut Vec<u8>) {
+		let start = buf.len();
+		buf.extend_from_slice(&[0x45, 0]);
+		buf.extend_from_slice(&(HEADER_LEN as u16 + self.len).to_be_bytes());
+		buf.extend_from_slice(&self.id.to_be_bytes());
+		buf.extend_from_slice(&FLAG_DF.to_be_bytes());
+		buf.extend_from_slice(&[self.ttl, self.protocol, 0, 0]);
+		buf.extend_from_slice(&self.src);
+		buf.extend_from_slice(&self.dst);
+		let sum = super::checksum(&buf[start..]);
+		buf[start + 10..start + 12].copy_from_slice(&sum.to_be_bytes());
+	}
+}
+
+/// The network mask of a prefix length.
+pub fn mask(prefix: u8) -> [u8; 4] {
+	match prefix {
+		0 => [0; 4],
+		_ => (!0u32 << (32 - prefix.min(32) as u32)).to_be_bytes()
+	}
+}
+
+/// The prefix length of a network mask, the mask's set bits must be contiguous.
+pub fn prefix(mask: [u8; 4]) -> Option<u8> {
+	let mask = u32::from_be_bytes(mask);
+	(mask.leading_ones() + mask.trailing_zeros() == 32 || mask == 0).then(|| mask.leading_ones() as u8)
+}
+
+/// Whether `a` and `b` are on the same network.
+pub fn same_network(a: [u8; 4], b: [u8; 4], prefix: u8) -> bool {
+	let mask = u32::from_be_bytes(mask(prefix));
+	u32::from_be_bytes(a) & mask == u32::from_be_bytes(b) & mask
+}
+
+/// The directed broadcast address of a network.
+pub fn broadcast(addr: [u8; 4], prefix: u8) -> [u8; 4] {
+	(u32::from_be_bytes(addr) | !u32::from_be_bytes(mask(prefix))).to_be_bytes()
+}
@@ -0,0 +1,111 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Internet Protocol version 6 headers, extension headers aren't supported.
+
+use {super::Mac, alloc::vec::Vec};
+
+pub const HEADER_LEN: usize = 40;
+
+pub const NEXT_TCP:    u8 = 6;
+pub const NEXT_UDP:    u8 = 17;
+pub const NEXT_ICMPV6: u8 = 58;
+
+pub const DEFAULT_HOP_LIMIT: u8 = 64;
+
+/// All nodes on the link, `ff02::1`
+pub const ALL_NODES:   [u8; 16] = [0xFF, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+/// All routers on the link, `ff02::2`
+pub const ALL_ROUTERS: [u8; 16] = [0xFF, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+	pub src:       [u8; 16],
+	pub dst:       [u8; 16],
+	pub next:      u8,
+	pub hop_limit: u8,
+	/// Length of the payload
+	pub len:       u16
+}
+
+impl Header {
+	/// The header and the payload of a packet.
+	pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+		if packet.len() < HEADER_LEN || packet[0] >> 4 != 6 {
+			return None;
+		}
+		let len = u16::from_be_bytes([packet[4], packet[5]]);
+		if HEADER_LEN + len as usize > packet.len() {
+			return None;
+		}
+
+		let header = Self {
+			src:       packet[8..24].try_into().unwrap(),
+			dst:       packet[24..40].try_into().unwrap(),
+			next:      packet[6],
+			hop_limit: packet[7],
+			len
+		};
+		Some((header, &packet[HEADER_LEN..HEADER_LEN + len as usize]))
+	}
+
+	pub fn write(&self, buf: &mut Vec<u8>) {
+		buf.extend_from_slice(&[0x60, 0, 0, 0]);
+		buf.extend_from_slice(&self.len.to_be_bytes());
+		buf.extend_from_slice(&[self.next, self.hop_limit]);
+		buf.extend_from_slice(&self.src);
+		buf.extend_from_slice(&self.dst);
+	}
+}
+
+/// The link-local address with the modified EUI-64 interface identifier of `mac`.
+pub fn link_local(mac: Mac) -> [u8; 16] {
+	with_prefix(&[0xFE, 0x80, 0, 0, 0, 0, 0, 0], mac)
+}
+
+/// An address of a /64 prefix with the modified EUI-64 interface identifier of `mac`.
+pub fn with_prefix(prefix: &[u8; 8], mac: Mac) -> [u8; 16] {
+	let mut addr = [0; 16];
+	addr[..8].copy_from_slice(prefix);
+	addr[8..].copy_from_slice(&[mac[0] ^ 2, mac[1], mac[2], 0xFF, 0xFE, mac[3], mac[4], mac[5]]);
+	addr
+}
+
+pub fn is_link_local(addr: &[u8; 16]) -> bool {
+	addr[0] == 0xFE && addr[1] & 0xC0 == 0x80
+}
+
+/// The solicited-node multicast address neighbor solicitations for `addr` are sent to.
+pub fn solicited_node(addr: &[u8; 16]) -> [u8; 16] {
+	[0xFF, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xFF, addr[13], addr[14], addr[15]]
+}
+
+/// The Ethernet address a multicast address maps to.
+pub fn multicast_mac(addr: &[u8; 16]) -> Mac {
+	[0x33, 0x33, addr[12], addr[13], addr[14], addr[15]]
+}
+
+/// Whether the first `len` bits of `a` and `b` are equal.
+pub fn same_prefix(a: &[u8; 16], b: &[u8; 16], len: u8) -> bool {
+	let (bytes, bits) = (len.min(128) as usize / 8, len % 8);
+	a[..bytes] == b[..bytes] && (bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0)
+}
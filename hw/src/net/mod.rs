@@ -0,0 +1,333 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Internet protocol suite on top of Ethernet devices.
+//!
+//! An `Interface` owns a device and the sockets bound to its addresses. `Interface::poll`
+//! processes received frames, answers ARP, neighbor solicitations and echo requests and
+//! drives the timers of TCP and DHCP; the time is passed in by the caller, in
+//! milliseconds. Packets to neighbors whose link-layer address isn't known yet are held
+//! back until it is resolved.
+//!
+//! IPv4 fragments are dropped and the stack sends none, segments are sized to the MTU.
+
+pub mod ethernet;
+pub mod arp;
+pub mod ipv4;
+pub mod ipv6;
+pub mod icmp;
+pub mod udp;
+pub mod tcp;
+pub mod dhcp;
+mod interface;
+
+pub use interface::*;
+
+use alloc::{boxed::Box, vec::Vec};
+
+/// An Ethernet address
+pub type Mac = [u8; 6];
+
+pub const BROADCAST: Mac = [0xFF; 6];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// Malformed address, unknown socket or an operation the socket doesn't support
+	InvalidArgument,
+	/// Another socket is bound to the port
+	AddressInUse,
+	/// The socket isn't connected yet or anymore
+	NotConnected,
+	/// The peer answered the connection request with a reset
+	ConnectionRefused,
+	/// The peer reset the connection
+	ConnectionReset,
+	/// The peer stopped acknowledging data, or the neighbor didn't answer
+	Timeout,
+	/// The operation can't complete before the next `poll`
+	WouldBlock,
+	/// No address or route to the destination
+	NoRoute,
+	/// The socket's buffers are full
+	NoMemory,
+	/// The device was removed or its link is down
+	NoDevice,
+	/// The device failed to transmit the frame
+	Device
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum IpAddr {
+	V4([u8; 4]),
+	V6([u8; 16])
+}
+
+impl IpAddr {
+	pub const UNSPECIFIED_V4: Self = Self::V4([0; 4]);
+	pub const BROADCAST_V4:   Self = Self::V4([0xFF; 4]);
+	pub const UNSPECIFIED_V6: Self = Self::V6([0; 16]);
+
+	pub fn is_unspecified(&self) -> bool {
+		match self {
+			Self::V4(a) => a == &[0; 4],
+			Self::V6(a) => a == &[0; 16]
+		}
+	}
+
+	pub fn is_multicast(&self) -> bool {
+		match self {
+			Self::V4(a) => a[0] & 0xF0 == 0xE0,
+			Self::V6(a) => a[0] == 0xFF
+		}
+	}
+
+	pub fn is_v6(&self) -> bool {
+		matches!(self, Self::V6(_))
+	}
+
+	/// Parses the dotted decimal and the colon hexadecimal notation.
+	pub fn parse(s: &str) -> Option<Self> {
+		match s.contains(':') {
+			true  => parse_v6(s).map(Self::V6),
+			false => parse_v4(s).map(Self::V4)
+		}
+	}
+}
+
+fn parse_v4(s: &str) -> Option<[u8; 4]> {
+	let mut addr = [0; 4];
+	let mut parts = s.split('.');
+	for byte in &mut addr {
+		let part = parts.next()?;
+		if part.is_empty() || part.len() > 3 || !part.bytes().all(|c| c.is_ascii_digit()) {
+			return None;
+		}
+		*byte = part.parse().ok()?;
+	}
+	parts.next().is_none().then_some(addr)
+}
+
+fn parse_v6(s: &str) -> Option<[u8; 16]> {
+	let groups = |s: &str| -> Option<Vec<u16>> {
+		match s.is_empty() {
+			true  => Some(Vec::new()),
+			false => s.split(':').map(|g| match g.len() {
+				1..=4 => u16::from_str_radix(g, 16).ok(),
+				_     => None
+			}).collect()
+		}
+	};
+
+	let (head, tail) = match s.find("::") {
+		Some(i) => (groups(&s[..i])?, Some(groups(&s[i + 2..])?)),
+		None    => (groups(s)?, None)
+	};
+	let words = match tail {
+		Some(tail) if head.len() + tail.len() < 8 => head.iter().copied()
+			.chain(core::iter::repeat(0).take(8 - head.len() - tail.len()))
+			.chain(tail)
+			.collect::<Vec<_>>(),
+		None if head.len() == 8 => head,
+		_ => return None
+	};
+
+	let mut addr = [0; 16];
+	for (i, word) in words.iter().enumerate() {
+		addr[2 * i..2 * i + 2].copy_from_slice(&word.to_be_bytes());
+	}
+	Some(addr)
+}
+
+impl core::fmt::Display for IpAddr {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::V4(a) => write!(f, "{}.{}.{}.{}", a[0], a[1], a[2], a[3]),
+			Self::V6(a) => {
+				let words = (0..8).map(|i| u16::from_be_bytes([a[2 * i], a[2 * i + 1]])).collect::<Vec<_>>();
+				// the longest run of at least two zero words is shortened to `::`
+				let (mut zeros, mut run) = ((0, 0), (0, 0));
+				for (i, &w) in words.iter().enumerate() {
+					run = match w {
+						0 => (if run.1 == 0 { i } else { run.0 }, run.1 + 1),
+						_ => (0, 0)
+					};
+					if run.1 > zeros.1 {
+						zeros = run;
+					}
+				}
+
+				let join = |f: &mut core::fmt::Formatter<'_>, words: &[u16]| -> core::fmt::Result {
+					for (i, w) in words.iter().enumerate() {
+						write!(f, "{}{:x}", if i > 0 { ":" } else { "" }, w)?;
+					}
+					Ok(())
+				};
+				match zeros.1 {
+					0 | 1 => join(f, &words),
+					_ => {
+						join(f, &words[..zeros.0])?;
+						f.write_str("::")?;
+						join(f, &words[zeros.0 + zeros.1..])
+					}
+				}
+			}
+		}
+	}
+}
+
+/// An address and port.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Endpoint {
+	pub addr: IpAddr,
+	pub port: u16
+}
+
+impl Endpoint {
+	pub fn new(addr: IpAddr, port: u16) -> Self {
+		Self { addr, port }
+	}
+
+	/// Parses `<ipv4>:<port>` and `[<ipv6>]:<port>`.
+	pub fn parse(s: &str) -> Option<Self> {
+		let (addr, port) = s.rsplit_once(':')?;
+		let addr = match addr.strip_prefix('[').and_then(|a| a.strip_suffix(']')) {
+			Some(addr) => IpAddr::V6(parse_v6(addr)?),
+			None       => IpAddr::V4(parse_v4(addr)?)
+		};
+		Some(Self { addr, port: port.parse().ok()? })
+	}
+}
+
+impl core::fmt::Display for Endpoint {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self.addr {
+			IpAddr::V4(_) => write!(f, "{}:{}", self.addr, self.port),
+			IpAddr::V6(_) => write!(f, "[{}]:{}", self.addr, self.port)
+		}
+	}
+}
+
+/// Where the device inserts the checksum of an outgoing packet: the ones' complement sum
+/// from `start` to the end of the frame is added to the 16 bits at `start + offset`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Checksum {
+	pub start:  u16,
+	pub offset: u16
+}
+
+/// A received frame, without the device's header.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Frame {
+	pub data:           Vec<u8>,
+	/// The device verified the checksums of the packet
+	pub checksum_valid: bool
+}
+
+/// An Ethernet device.
+pub trait Device {
+	fn mac(&self) -> Mac;
+
+	/// Largest payload of a frame
+	fn mtu(&self) -> usize {
+		1500
+	}
+
+	fn link_up(&mut self) -> bool {
+		true
+	}
+
+	/// Whether `transmit` completes checksums, otherwise they are computed before.
+	fn checksum_offload(&self) -> bool {
+		false
+	}
+
+	/// Sends a frame, including the Ethernet header.
+	fn transmit(&mut self, frame: &[u8], checksum: Option<Checksum>) -> Result<()>;
+
+	/// The next received frame, if any.
+	fn receive(&mut self) -> Option<Frame>;
+}
+
+impl<D: Device + ?Sized> Device for Box<D> {
+	fn mac(&self) -> Mac { (**self).mac() }
+	fn mtu(&self) -> usize { (**self).mtu() }
+	fn link_up(&mut self) -> bool { (**self).link_up() }
+	fn checksum_offload(&self) -> bool { (**self).checksum_offload() }
+	fn transmit(&mut self, frame: &[u8], checksum: Option<Checksum>) -> Result<()> { (**self).transmit(frame, checksum) }
+	fn receive(&mut self) -> Option<Frame> { (**self).receive() }
+}
+
+/// Adds `data` to a ones' complement sum, as 16 bit big endian words.
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+	let mut chunks = data.chunks_exact(2);
+	for word in &mut chunks {
+		sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+	}
+	if let [last] = chunks.remainder() {
+		sum += (*last as u32) << 8;
+	}
+	// fold early so long buffers don't overflow
+	(sum & 0xFFFF) + (sum >> 16)
+}
+
+/// Folds a sum to 16 bits, without complementing it.
+pub fn checksum_fold(mut sum: u32) -> u16 {
+	while sum > 0xFFFF {
+		sum = (sum & 0xFFFF) + (sum >> 16);
+	}
+	sum as u16
+}
+
+/// The Internet checksum of `data`.
+pub fn checksum(data: &[u8]) -> u16 {
+	!checksum_fold(checksum_add(0, data))
+}
+
+/// The sum of the pseudo header of a TCP or UDP packet, or of an ICMPv6 message.
+pub fn pseudo_header(src: IpAddr, dst: IpAddr, protocol: u8, len: usize) -> u32 {
+	let sum = match (src, dst) {
+		(IpAddr::V4(s), IpAddr::V4(d)) => checksum_add(checksum_add(0, &s), &d),
+		(IpAddr::V6(s), IpAddr::V6(d)) => checksum_add(checksum_add(0, &s), &d),
+		_ => 0
+	};
+	checksum_add(sum, &[0, protocol]) + checksum_add(0, &(len as u32).to_be_bytes())
+}
+
+/// Sequence numbers and identifiers, `Interface` seeds it with the time of its creation.
+#[derive(Clone, Debug)]
+pub struct Random(u64);
+
+impl Random {
+	pub fn new(seed: u64) -> Self {
+		Self(seed ^ 0x9E37_79B9_7F4A_7C15)
+	}
+
+	/// xorshift64*
+	pub fn next_u32(&mut self) -> u32 {
+		self.0 ^= self.0 >> 12;
+		self.0 ^= self.0 << 25;
+		self.0 ^= self.0 >> 27;
+		(self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32
+	}
+}
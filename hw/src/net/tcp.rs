@@ -0,0 +1,611 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Transmission Control Protocol.
+//!
+//! A `Socket` is the state machine of one connection: `process` takes the segments the
+//! interface received for it and `dispatch` returns the segments it has to send, new data
+//! as well as retransmissions. Unacknowledged data is retransmitted go-back-n after the
+//! retransmission timeout, which doubles on every retry. Segments that arrive out of
+//! order are dropped and acknowledged with the next expected sequence number, the peer
+//! retransmits them.
+
+use {
+	super::{Endpoint, Error, IpAddr},
+	alloc::{collections::VecDeque, vec::Vec}
+};
+
+pub const HEADER_LEN: usize = 20;
+
+/// Offset of the checksum in the header
+pub const CHECKSUM_OFFSET: u16 = 16;
+
+pub const FLAG_FIN: u8 = 0x01;
+pub const FLAG_SYN: u8 = 0x02;
+pub const FLAG_RST: u8 = 0x04;
+pub const FLAG_PSH: u8 = 0x08;
+pub const FLAG_ACK: u8 = 0x10;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Maximum segment size assumed if the peer doesn't announce one
+pub const DEFAULT_MSS: u16 = 536;
+
+/// Size of the send and the receive buffer of a connection
+pub const BUFFER_SIZE: usize = 0xFFFF;
+
+pub const RTO_INITIAL_MS: u64 = 1000;
+pub const RTO_MAX_MS:     u64 = 60_000;
+/// Retransmissions of a segment before the connection is given up
+pub const MAX_RETRIES:    u32 = 8;
+/// Twice the maximum segment lifetime
+pub const TIME_WAIT_MS:   u64 = 60_000;
+
+/// `a < b` in sequence space
+pub fn seq_lt(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) < 0
+}
+
+/// `a <= b` in sequence space
+pub fn seq_le(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) <= 0
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Segment {
+	pub src_port: u16,
+	pub dst_port: u16,
+	pub seq:      u32,
+	pub ack:      u32,
+	pub flags:    u8,
+	pub window:   u16,
+	/// The maximum segment size option, only in SYN segments
+	pub mss:      Option<u16>
+}
+
+impl Segment {
+	/// The header and the payload of a segment, the checksum is verified unless
+	/// `checked`.
+	pub fn parse(buf: &[u8], src: IpAddr, dst: IpAddr, checked: bool) -> Option<(Self, &[u8])> {
+		if buf.len() < HEADER_LEN {
+			return None;
+		}
+		let header_len = (buf[12] >> 4) as usize * 4;
+		if header_len < HEADER_LEN || header_len > buf.len() {
+			return None;
+		} else if !checked {
+			let sum = super::pseudo_header(src, dst, super::ipv4::PROTOCOL_TCP, buf.len());
+			if super::checksum_fold(super::checksum_add(sum, buf)) != 0xFFFF {
+				return None;
+			}
+		}
+
+		let mut segment = Self {
+			src_port: u16::from_be_bytes([buf[0], buf[1]]),
+			dst_port: u16::from_be_bytes([buf[2], buf[3]]),
+			seq:      u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+			ack:      u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+			flags:    buf[13],
+			window:   u16::from_be_bytes([buf[14], buf[15]]),
+			mss:      None
+		};
+
+		let mut options = &buf[HEADER_LEN..header_len];
+		while let Some(&kind) = options.first() {
+			match kind {
+				OPTION_END => break,
+				OPTION_NOP => options = &options[1..],
+				_ => {
+					let len = *options.get(1)? as usize;
+					if len < 2 || len > options.len() {
+						return None;
+					} else if kind == OPTION_MSS && len == 4 {
+						segment.mss = Some(u16::from_be_bytes([options[2], options[3]]));
+					}
+					options = &options[len..];
+				}
+			}
+		}
+		Some((segment, &buf[header_len..]))
+	}
+
+	/// Writes the header and the payload. The checksum is computed, or with `offload`
+	/// set to the sum of the pseudo header for the device to complete.
+	pub fn write(&self, buf: &mut Vec<u8>, payload: &[u8], src: IpAddr, dst: IpAddr, offload: bool) {
+		let start = buf.len();
+		let header_len = HEADER_LEN + if self.mss.is_some() { 4 } else { 0 };
+		buf.extend_from_slice(&self.src_port.to_be_bytes());
+		buf.extend_from_slice(&self.dst_port.to_be_bytes());
+		buf.extend_from_slice(&self.seq.to_be_bytes());
+		buf.extend_from_slice(&self.ack.to_be_bytes());
+		buf.extend_from_slice(&[(header_len / 4) as u8 * 16, self.flags]);
+		buf.extend_from_slice(&self.window.to_be_bytes());
+		buf.extend_from_slice(&[0; 4]);
+		if let Some(mss) = self.mss {
+			buf.extend_from_slice(&[OPTION_MSS, 4]);
+			buf.extend_from_slice(&mss.to_be_bytes());
+		}
+		buf.extend_from_slice(payload);
+
+		let sum = super::pseudo_header(src, dst, super::ipv4::PROTOCOL_TCP, buf.len() - start);
+		let sum = match offload {
+			true  => super::checksum_fold(sum),
+			false => !super::checksum_fold(super::checksum_add(sum, &buf[start..]))
+		};
+		buf[start + 16..start + 18].copy_from_slice(&sum.to_be_bytes());
+	}
+
+	/// Length in sequence space, SYN and FIN take one each.
+	pub fn seq_len(&self, payload: usize) -> u32 {
+		payload as u32 + (self.flags & FLAG_SYN != 0) as u32 + (self.flags & FLAG_FIN != 0) as u32
+	}
+
+	/// The reset answering a segment that doesn't belong to a connection, none for a reset.
+	pub fn reset_reply(&self, payload: usize) -> Option<Self> {
+		let reply = Self { src_port: self.dst_port, dst_port: self.src_port, ..Self::default() };
+		match (self.flags & FLAG_RST != 0, self.flags & FLAG_ACK != 0) {
+			(true, _)  => None,
+			(_, true)  => Some(Self { seq: self.ack, flags: FLAG_RST, ..reply }),
+			(_, false) => Some(Self { ack: self.seq.wrapping_add(self.seq_len(payload)), flags: FLAG_RST | FLAG_ACK, ..reply })
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+	Closed,
+	Listen,
+	SynSent,
+	SynReceived,
+	Established,
+	FinWait1,
+	FinWait2,
+	CloseWait,
+	Closing,
+	LastAck,
+	TimeWait
+}
+
+impl State {
+	/// Whether the handshake completed and the connection isn't closed.
+	pub fn is_synchronized(&self) -> bool {
+		!matches!(self, Self::Closed | Self::Listen | Self::SynSent | Self::SynReceived)
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct Socket {
+	pub state:      State,
+	pub local:      Endpoint,
+	/// The peer, unspecified for listening sockets
+	pub remote:     Endpoint,
+	/// Why the connection was closed, if it didn't close normally
+	pub error:      Option<Error>,
+	/// The listening socket a connection was accepted by, until it is taken from its
+	/// backlog
+	pub parent:     Option<usize>,
+	/// Most connections a listening socket holds until they are accepted
+	pub backlog:    usize,
+	iss:            u32,
+	snd_una:        u32,
+	snd_nxt:        u32,
+	snd_wnd:        u32,
+	rcv_nxt:        u32,
+	/// Unacknowledged and unsent data, `tx_seq` is the sequence number of its first byte
+	tx:             VecDeque<u8>,
+	tx_seq:         u32,
+	rx:             VecDeque<u8>,
+	/// Our maximum segment size and the one used, the smaller of ours and the peer's
+	local_mss:      u16,
+	mss:            u16,
+	/// `close` was called, a FIN follows the data
+	closing:        bool,
+	/// Sequence number of our FIN, once it was sent
+	fin_seq:        Option<u32>,
+	fin_received:   bool,
+	ack_pending:    bool,
+	rto:            u64,
+	retransmit_at:  Option<u64>,
+	retries:        u32,
+	time_wait_until: u64
+}
+
+impl Socket {
+	fn new(state: State, local: Endpoint, remote: Endpoint, iss: u32, mss: u16) -> Self {
+		Self {
+			state,
+			local,
+			remote,
+			error:           None,
+			parent:          None,
+			backlog:         0,
+			iss,
+			snd_una:         iss,
+			snd_nxt:         iss,
+			snd_wnd:         0,
+			rcv_nxt:         0,
+			tx:              VecDeque::new(),
+			tx_seq:          iss.wrapping_add(1),
+			rx:              VecDeque::new(),
+			local_mss:       mss,
+			mss:             mss.min(DEFAULT_MSS),
+			closing:         false,
+			fin_seq:         None,
+			fin_received:    false,
+			ack_pending:     false,
+			rto:             RTO_INITIAL_MS,
+			retransmit_at:   None,
+			retries:         0,
+			time_wait_until: 0
+		}
+	}
+
+	/// A socket accepting connections to `local`.
+	pub fn listen(local: Endpoint, backlog: usize) -> Self {
+		Self { backlog: backlog.max(1), ..Self::new(State::Listen, local, Endpoint::new(local.addr, 0), 0, DEFAULT_MSS) }
+	}
+
+	/// A connection to `remote`, the SYN is sent by the next `dispatch`.
+	pub fn connect(local: Endpoint, remote: Endpoint, iss: u32, mss: u16) -> Self {
+		Self::new(State::SynSent, local, remote, iss, mss)
+	}
+
+	/// A connection requested by a SYN to a listening socket, the SYN-ACK is sent by the
+	/// next `dispatch`.
+	pub fn accept(local: Endpoint, remote: Endpoint, syn: &Segment, iss: u32, mss: u16, parent: usize) -> Self {
+		let mut socket = Self::new(State::SynReceived, local, remote, iss, mss);
+		socket.rcv_nxt = syn.seq.wrapping_add(1);
+		socket.snd_wnd = syn.window as u32;
+		socket.mss = mss.min(syn.mss.unwrap_or(DEFAULT_MSS));
+		socket.parent = Some(parent);
+		socket
+	}
+
+	/// Whether a segment from `src` to `dst` belongs to this connection.
+	pub fn matches(&self, src: Endpoint, dst: Endpoint) -> bool {
+		self.state != State::Listen && self.state != State::Closed && self.local == dst && self.remote == src
+	}
+
+	/// Whether there is data to read, or the peer closed its side.
+	pub fn can_recv(&self) -> bool {
+		!self.rx.is_empty() || self.fin_received || self.state == State::Closed
+	}
+
+	/// Free space in the send buffer, zero once the socket is closing.
+	pub fn send_capacity(&self) -> usize {
+		match self.closing || !matches!(self.state, State::SynSent | State::SynReceived | State::Established | State::CloseWait) {
+			true  => 0,
+			false => BUFFER_SIZE - self.tx.len()
+		}
+	}
+
+	/// Queues data to be sent, returns how much fit into the send buffer.
+	pub fn send(&mut self, data: &[u8]) -> Result<usize, Error> {
+		if self.send_capacity() == 0 {
+			return Err(match (self.error, self.closing || self.state == State::Closed) {
+				(Some(e), _) => e,
+				(None, true) => Error::NotConnected,
+				(None, false) => Error::WouldBlock
+			});
+		}
+		let n = data.len().min(self.send_capacity());
+		self.tx.extend(&data[..n]);
+		Ok(n)
+	}
+
+	/// Reads received data, returns zero once the peer closed its side and all data was
+	/// read.
+	pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		if !self.rx.is_empty() {
+			let n = buf.len().min(self.rx.len());
+			for (dst, src) in buf.iter_mut().zip(self.rx.drain(..n)) {
+				*dst = src;
+			}
+			// a window that opens again is announced
+			if self.rx.len() + n >= BUFFER_SIZE - self.mss as usize && self.state.is_synchronized() {
+				self.ack_pending = true;
+			}
+			return Ok(n);
+		}
+		match (self.error, self.fin_received, self.state) {
+			(Some(e), _, _)          => Err(e),
+			(None, true, _)          => Ok(0),
+			(None, _, State::Closed) => Err(Error::NotConnected),
+			_                        => Err(Error::WouldBlock)
+		}
+	}
+
+	/// Closes our side of the connection once all data was sent.
+	pub fn close(&mut self) {
+		match self.state {
+			State::Listen | State::SynSent => self.state = State::Closed,
+			State::SynReceived | State::Established | State::CloseWait => self.closing = true,
+			_ => ()
+		}
+	}
+
+	/// Closes the connection immediately, returns the reset to send to the peer.
+	pub fn abort(&mut self) -> Option<Segment> {
+		let synchronized = self.state.is_synchronized() || self.state == State::SynReceived;
+		self.state = State::Closed;
+		synchronized.then(|| self.segment(self.snd_nxt, FLAG_RST))
+	}
+
+	fn close_with(&mut self, error: Error) {
+		self.state = State::Closed;
+		self.error = Some(error);
+		self.retransmit_at = None;
+	}
+
+	fn segment(&self, seq: u32, flags: u8) -> Segment {
+		Segment {
+			src_port: self.local.port,
+			dst_port: self.remote.port,
+			seq,
+			ack:      match flags & FLAG_ACK { 0 => 0, _ => self.rcv_nxt },
+			flags,
+			window:   (BUFFER_SIZE - self.rx.len()).min(0xFFFF) as u16,
+			mss:      None
+		}
+	}
+
+	/// Whether a segment overlaps the receive window.
+	fn acceptable(&self, seq: u32, len: u32) -> bool {
+		let window = (BUFFER_SIZE - self.rx.len()) as u32;
+		let end = self.rcv_nxt.wrapping_add(window);
+		let inside = |s: u32| seq_le(self.rcv_nxt, s) && seq_lt(s, end);
+		match (len, window) {
+			(0, 0) => seq == self.rcv_nxt,
+			(0, _) => inside(seq),
+			(_, 0) => false,
+			_      => inside(seq) || inside(seq.wrapping_add(len - 1))
+		}
+	}
+
+	/// Takes acknowledged data and SYN or FIN off the send queue.
+	fn acknowledge(&mut self, ack: u32, now: u64) {
+		if seq_lt(self.tx_seq, ack) {
+			let n = (ack.wrapping_sub(self.tx_seq) as usize).min(self.tx.len());
+			self.tx.drain(..n);
+			self.tx_seq = self.tx_seq.wrapping_add(n as u32);
+		}
+		self.snd_una = ack;
+		self.retries = 0;
+		self.rto = RTO_INITIAL_MS;
+		self.retransmit_at = (self.snd_una != self.snd_nxt).then_some(now + self.rto);
+	}
+
+	/// Processes a received segment, returns a reset to answer it with.
+	pub fn process(&mut self, seg: &Segment, payload: &[u8], now: u64) -> Option<Segment> {
+		match self.state {
+			State::Closed | State::Listen => return None,
+			State::SynSent => {
+				let ack = seg.flags & FLAG_ACK != 0;
+				if ack && (seq_le(seg.ack, self.iss) || seq_lt(self.snd_nxt, seg.ack)) {
+					return seg.reset_reply(payload.len());
+				} else if seg.flags & FLAG_RST != 0 {
+					if ack {
+						self.close_with(Error::ConnectionRefused);
+					}
+					return None;
+				} else if seg.flags & FLAG_SYN != 0 {
+					self.rcv_nxt = seg.seq.wrapping_add(1);
+					self.snd_wnd = seg.window as u32;
+					self.mss = self.local_mss.min(seg.mss.unwrap_or(DEFAULT_MSS));
+					self.ack_pending = true;
+					match ack {
+						true => {
+							self.acknowledge(seg.ack, now);
+							self.state = State::Established;
+						}
+						// simultaneous open, the SYN is sent again with an ACK
+						false => {
+							self.state = State::SynReceived;
+							self.snd_nxt = self.iss;
+							self.retransmit_at = None;
+						}
+					}
+				}
+				return None;
+			}
+			_ => ()
+		}
+
+		if !self.acceptable(seg.seq, seg.seq_len(payload.len())) {
+			if seg.flags & FLAG_RST == 0 {
+				self.ack_pending = true;
+			}
+			return None;
+		} else if seg.flags & FLAG_RST != 0 {
+			self.close_with(match self.state {
+				State::SynReceived => Error::ConnectionRefused,
+				_                  => Error::ConnectionReset
+			});
+			return None;
+		} else if seg.flags & FLAG_SYN != 0 {
+			self.close_with(Error::ConnectionReset);
+			return Some(self.segment(self.snd_nxt, FLAG_RST));
+		} else if seg.flags & FLAG_ACK == 0 {
+			return None;
+		}
+
+		let ack_valid = seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_nxt);
+		if self.state == State::SynReceived {
+			match ack_valid {
+				true  => self.state = State::Established,
+				false => return seg.reset_reply(payload.len())
+			}
+		}
+		if ack_valid {
+			self.acknowledge(seg.ack, now);
+		} else if seq_lt(self.snd_nxt, seg.ack) {
+			// acknowledges something not sent yet
+			self.ack_pending = true;
+			return None;
+		}
+		if seq_le(self.snd_una, seg.ack) {
+			self.snd_wnd = seg.window as u32;
+			if self.snd_wnd == 0 {
+				// the peer answered a window probe
+				self.retries = 0;
+			}
+		}
+
+		let fin_acked = self.fin_seq.map_or(false, |fin| seq_lt(fin, self.snd_una));
+		match self.state {
+			State::FinWait1 if fin_acked => self.state = State::FinWait2,
+			State::Closing if fin_acked => {
+				self.state = State::TimeWait;
+				self.time_wait_until = now + TIME_WAIT_MS;
+			}
+			State::LastAck if fin_acked => {
+				self.state = State::Closed;
+				return None;
+			}
+			_ => ()
+		}
+
+		// data that was already received is skipped, data beyond `rcv_nxt` dropped
+		let mut data = payload;
+		let skip = self.rcv_nxt.wrapping_sub(seg.seq) as usize;
+		if seq_lt(seg.seq, self.rcv_nxt) {
+			data = &data[skip.min(data.len())..];
+		}
+		if !data.is_empty() && matches!(self.state, State::Established | State::FinWait1 | State::FinWait2) {
+			if seq_le(seg.seq, self.rcv_nxt) {
+				let n = data.len().min(BUFFER_SIZE - self.rx.len());
+				self.rx.extend(&data[..n]);
+				self.rcv_nxt = self.rcv_nxt.wrapping_add(n as u32);
+			}
+			self.ack_pending = true;
+		}
+
+		if seg.flags & FLAG_FIN != 0 && seg.seq.wrapping_add(payload.len() as u32) == self.rcv_nxt && !self.fin_received {
+			self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+			self.fin_received = true;
+			self.ack_pending = true;
+			self.state = match self.state {
+				State::Established => State::CloseWait,
+				State::FinWait1    => State::Closing,
+				State::FinWait2    => State::TimeWait,
+				state              => state
+			};
+			if self.state == State::TimeWait {
+				self.time_wait_until = now + TIME_WAIT_MS;
+			}
+		}
+		None
+	}
+
+	/// The segments to send at `now`, with their payload.
+	pub fn dispatch(&mut self, now: u64) -> Vec<(Segment, Vec<u8>)> {
+		let mut out = Vec::new();
+		match self.state {
+			State::Closed | State::Listen => return out,
+			State::TimeWait => {
+				if now >= self.time_wait_until {
+					self.state = State::Closed;
+				} else if core::mem::take(&mut self.ack_pending) {
+					out.push((self.segment(self.snd_nxt, FLAG_ACK), Vec::new()));
+				}
+				return out;
+			}
+			_ => ()
+		}
+
+		let mut probe = false;
+		if self.retransmit_at.map_or(false, |at| now >= at) {
+			if self.retries >= MAX_RETRIES {
+				let reset = self.segment(self.snd_nxt, FLAG_RST);
+				self.close_with(Error::Timeout);
+				out.push((reset, Vec::new()));
+				return out;
+			}
+			self.retries += 1;
+			self.rto = (self.rto * 2).min(RTO_MAX_MS);
+			self.snd_nxt = self.snd_una;
+			self.retransmit_at = None;
+			probe = self.snd_wnd == 0;
+		}
+
+		if matches!(self.state, State::SynSent | State::SynReceived) {
+			if self.snd_nxt == self.iss {
+				let flags = match self.state {
+					State::SynSent => FLAG_SYN,
+					_              => FLAG_SYN | FLAG_ACK
+				};
+				let syn = Segment { mss: Some(self.local_mss), ..self.segment(self.iss, flags) };
+				out.push((syn, Vec::new()));
+				self.snd_nxt = self.iss.wrapping_add(1);
+				self.retransmit_at.get_or_insert(now + self.rto);
+				self.ack_pending = false;
+			}
+			return out;
+		}
+
+		loop {
+			let offset = self.snd_nxt.wrapping_sub(self.tx_seq) as usize;
+			let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+			let window = match probe && in_flight == 0 {
+				true  => 1,
+				false => self.snd_wnd
+			};
+			let n = self.tx.len().saturating_sub(offset)
+				.min(self.mss as usize)
+				.min(window.saturating_sub(in_flight) as usize);
+			let fin = self.closing && offset + n == self.tx.len()
+				&& self.fin_seq.map_or(true, |fin| fin == self.snd_nxt.wrapping_add(n as u32));
+			if n == 0 && !fin {
+				// a closed window is probed once the timer fires
+				if self.tx.len() > offset && self.retransmit_at.is_none() {
+					self.retransmit_at = Some(now + self.rto);
+				}
+				break;
+			}
+
+			let flags = FLAG_ACK | if n > 0 { FLAG_PSH } else { 0 } | if fin { FLAG_FIN } else { 0 };
+			let data = self.tx.range(offset..offset + n).copied().collect::<Vec<_>>();
+			out.push((self.segment(self.snd_nxt, flags), data));
+			self.snd_nxt = self.snd_nxt.wrapping_add(n as u32);
+			self.retransmit_at.get_or_insert(now + self.rto);
+			self.ack_pending = false;
+
+			if fin {
+				self.fin_seq = Some(self.snd_nxt);
+				self.snd_nxt = self.snd_nxt.wrapping_add(1);
+				self.state = match self.state {
+					State::Established => State::FinWait1,
+					State::CloseWait   => State::LastAck,
+					state              => state
+				};
+				break;
+			}
+		}
+
+		if core::mem::take(&mut self.ack_pending) {
+			out.push((self.segment(self.snd_nxt, FLAG_ACK), Vec::new()));
+		}
+		out
+	}
+}
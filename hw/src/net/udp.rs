@@ -0,0 +1,124 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! User Datagram Protocol.
+
+use {super::{Endpoint, IpAddr}, alloc::{collections::VecDeque, vec::Vec}};
+
+pub const HEADER_LEN: usize = 8;
+
+/// Offset of the checksum in the header
+pub const CHECKSUM_OFFSET: u16 = 6;
+
+/// Most datagrams a socket holds before dropping new ones
+pub const RX_QUEUE_LEN: usize = 64;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+	pub src_port: u16,
+	pub dst_port: u16,
+	/// Length of the payload
+	pub len:      u16
+}
+
+impl Header {
+	/// The header and the payload of a datagram, the checksum is verified if it is set
+	/// or required by IPv6, unless `checked`.
+	pub fn parse(datagram: &[u8], src: IpAddr, dst: IpAddr, checked: bool) -> Option<(Self, &[u8])> {
+		if datagram.len() < HEADER_LEN {
+			return None;
+		}
+		let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+		let sum = u16::from_be_bytes([datagram[6], datagram[7]]);
+		if len < HEADER_LEN || len > datagram.len() {
+			return None;
+		} else if !checked && (sum != 0 || src.is_v6()) {
+			let sum = super::pseudo_header(src, dst, super::ipv4::PROTOCOL_UDP, len);
+			if super::checksum_fold(super::checksum_add(sum, &datagram[..len])) != 0xFFFF {
+				return None;
+			}
+		}
+
+		let header = Self {
+			src_port: u16::from_be_bytes([datagram[0], datagram[1]]),
+			dst_port: u16::from_be_bytes([datagram[2], datagram[3]]),
+			len:      (len - HEADER_LEN) as u16
+		};
+		Some((header, &datagram[HEADER_LEN..len]))
+	}
+
+	/// Writes the header and the payload. The checksum is computed, or with `offload`
+	/// set to the sum of the pseudo header for the device to complete.
+	pub fn write(&self, buf: &mut Vec<u8>, payload: &[u8], src: IpAddr, dst: IpAddr, offload: bool) {
+		let start = buf.len();
+		let len = HEADER_LEN + payload.len();
+		buf.extend_from_slice(&self.src_port.to_be_bytes());
+		buf.extend_from_slice(&self.dst_port.to_be_bytes());
+		buf.extend_from_slice(&(len as u16).to_be_bytes());
+		buf.extend_from_slice(&[0, 0]);
+		buf.extend_from_slice(payload);
+
+		let sum = super::pseudo_header(src, dst, super::ipv4::PROTOCOL_UDP, len);
+		let sum = match offload {
+			true  => super::checksum_fold(sum),
+			// zero means no checksum, it is sent as all ones instead
+			false => match !super::checksum_fold(super::checksum_add(sum, &buf[start..])) {
+				0   => 0xFFFF,
+				sum => sum
+			}
+		};
+		buf[start + 6..start + 8].copy_from_slice(&sum.to_be_bytes());
+	}
+}
+
+/// A bound socket, it receives the datagrams to its port from `remote`, or from
+/// anywhere if it isn't connected.
+#[derive(Clone, Debug)]
+pub struct Socket {
+	pub local:  Endpoint,
+	pub remote: Option<Endpoint>,
+	pub rx:     VecDeque<(Endpoint, Vec<u8>)>
+}
+
+impl Socket {
+	pub fn new(local: Endpoint, remote: Option<Endpoint>) -> Self {
+		Self { local, remote, rx: VecDeque::new() }
+	}
+
+	/// Whether the socket takes a datagram from `src` to `dst`.
+	pub fn accepts(&self, src: Endpoint, dst: Endpoint) -> bool {
+		self.local.port == dst.port
+			&& (self.local.addr.is_unspecified() || self.local.addr == dst.addr)
+			&& self.remote.map_or(true, |r| r == src)
+	}
+
+	/// Queues a received datagram, returns false if the queue is full.
+	pub fn push(&mut self, src: Endpoint, data: &[u8]) -> bool {
+		match self.rx.len() < RX_QUEUE_LEN {
+			true => {
+				self.rx.push_back((src, data.to_vec()));
+				true
+			}
+			false => false
+		}
+	}
+}
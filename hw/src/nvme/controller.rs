@@ -0,0 +1,689 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {super::*, crate::dma::{Dma, Region, PAGE_SIZE}, alloc::vec::Vec, core::ptr::addr_of_mut};
+
+/// Entries of the admin queues
+pub const ADMIN_QUEUE_SIZE: u16 = 32;
+/// Entries of each I/O queue, every entry has a page for its PRP list
+pub const IO_QUEUE_SIZE:    u16 = 64;
+/// How long to wait for a command to complete
+pub const COMMAND_TIMEOUT_MS: u64 = 30_000;
+
+/// PRP entries in a list page, the driver doesn't chain lists
+const PRP_LIST_LEN: usize = PAGE_SIZE / 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// The controller didn't change its ready state or complete a command in time
+	Timeout,
+	/// The controller reported a fatal status
+	Fatal,
+	/// A command completed with an error, see `Completion::code`
+	Command { opcode: u8, status: u16 },
+	/// The controller doesn't support the NVM command set or 4 KiB pages
+	Unsupported,
+	/// The buffer is misaligned, not a multiple of the block size or the LBA range is
+	/// outside of the namespace
+	InvalidArgument,
+	/// No DMA memory or no free entry in the submission queue
+	NoMemory
+}
+
+impl From<Error> for crate::block::Error {
+	fn from(e: Error) -> Self {
+		match e {
+			Error::Timeout         => Self::Timeout,
+			Error::Unsupported     => Self::Unsupported,
+			Error::InvalidArgument => Self::InvalidArgument,
+			Error::NoMemory        => Self::NoMemory,
+			Error::Fatal | Error::Command { .. } => Self::Io
+		}
+	}
+}
+
+/// An active namespace of a controller.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Namespace {
+	pub id:         u32,
+	/// Size in logical blocks
+	pub blocks:     u64,
+	pub block_size: u32
+}
+
+impl Namespace {
+	fn check(&self, lba: u64, len: usize) -> Result<u64, Error> {
+		let blocks = (len / self.block_size as usize) as u64;
+		match len % self.block_size as usize == 0 && lba.checked_add(blocks).map_or(false, |end| end <= self.blocks) {
+			true  => Ok(blocks),
+			false => Err(Error::InvalidArgument)
+		}
+	}
+}
+
+/// A submission queue and the completion queue it posts to.
+struct Queue {
+	id:          u16,
+	size:        u16,
+	sq:          Region,
+	cq:          Region,
+	/// A PRP list page for each submission queue entry
+	prp_lists:   Region,
+	sq_tail:     u16,
+	sq_head:     u16,
+	cq_head:     u16,
+	phase:       bool,
+	sq_doorbell: *mut u32,
+	cq_doorbell: *mut u32,
+	/// Completions reaped while waiting for another command
+	done:        Vec<Completion>
+}
+
+impl Queue {
+	fn new(dma: &mut impl Dma, regs: *mut u8, stride: usize, id: u16, size: u16, prp_lists: bool) -> Result<Self, Error> {
+		let sq = Region::alloc(dma, size as usize * core::mem::size_of::<Command>(), PAGE_SIZE).ok_or(Error::NoMemory)?;
+		let cq = match Region::alloc(dma, size as usize * core::mem::size_of::<Completion>(), PAGE_SIZE) {
+			Some(v) => v,
+			None => {
+				sq.free(dma);
+				return Err(Error::NoMemory);
+			}
+		};
+		let lists = match Region::alloc(dma, if prp_lists { size as usize * PAGE_SIZE } else { PAGE_SIZE }, PAGE_SIZE) {
+			Some(v) => v,
+			None => {
+				sq.free(dma);
+				cq.free(dma);
+				return Err(Error::NoMemory);
+			}
+		};
+
+		let doorbell = |i: usize| unsafe { regs.add(Nvme::DOORBELLS + i * stride) as *mut u32 };
+		Ok(Self {
+			id,
+			size,
+			sq,
+			cq,
+			prp_lists:   lists,
+			sq_tail:     0,
+			sq_head:     0,
+			cq_head:     0,
+			phase:       true,
+			sq_doorbell: doorbell(2 * id as usize),
+			cq_doorbell: doorbell(2 * id as usize + 1),
+			done:        Vec::new()
+		})
+	}
+
+	fn free(&self, dma: &mut impl Dma) {
+		for region in [&self.sq, &self.cq, &self.prp_lists] {
+			dma.free(region.virt, region.size);
+		}
+	}
+
+	fn is_full(&self) -> bool {
+		(self.sq_tail + 1) % self.size == self.sq_head
+	}
+
+	/// The PRP list page of the entry the next command is placed in.
+	fn prp_list(&self) -> (*mut u64, u64) {
+		let offset = match self.prp_lists.size >= self.size as usize * PAGE_SIZE {
+			true  => self.sq_tail as usize * PAGE_SIZE,
+			false => 0
+		};
+		(unsafe { self.prp_lists.virt.add(offset) as *mut u64 }, self.prp_lists.phys + offset as u64)
+	}
+
+	/// Places a command in the queue, its id is the index of its entry.
+	fn submit(&mut self, mut cmd: Command) -> Result<u16, Error> {
+		if self.is_full() {
+			return Err(Error::NoMemory);
+		}
+
+		let cid = self.sq_tail;
+		cmd.command_id = cid;
+		unsafe {
+			(self.sq.as_ptr::<Command>()).add(cid as usize).write_volatile(cmd);
+			self.sq_tail = (self.sq_tail + 1) % self.size;
+			core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+			self.sq_doorbell.write_volatile(self.sq_tail as u32);
+		}
+		Ok(cid)
+	}
+
+	/// Returns the next completion, if the controller posted one.
+	fn complete(&mut self) -> Option<Completion> {
+		self.done.pop().or_else(|| self.reap())
+	}
+
+	/// Takes the next entry off the completion queue.
+	fn reap(&mut self) -> Option<Completion> {
+		let entry = unsafe { (self.cq.as_ptr::<Completion>()).add(self.cq_head as usize).read_volatile() };
+		if entry.phase() != self.phase {
+			return None;
+		}
+
+		self.cq_head = (self.cq_head + 1) % self.size;
+		if self.cq_head == 0 {
+			self.phase = !self.phase;
+		}
+		self.sq_head = entry.sq_head % self.size;
+		unsafe { self.cq_doorbell.write_volatile(self.cq_head as u32) };
+		Some(entry)
+	}
+}
+
+/// Waits for the completion of command `cid` in `q`, polling the queue every 100 µs.
+fn wait(q: &mut Queue, regs: *mut Nvme, dma: &mut impl Dma, cid: u16, opcode: u8) -> Result<u32, Error> {
+	for _ in 0..COMMAND_TIMEOUT_MS * 10 {
+		let completion = match q.done.iter().position(|c| c.command_id == cid) {
+			Some(i) => Some(q.done.swap_remove(i)),
+			None => loop {
+				match q.reap() {
+					Some(c) if c.command_id == cid => break Some(c),
+					Some(c) => q.done.push(c),
+					None => break None
+				}
+			}
+		};
+
+		if let Some(c) = completion {
+			return match c.code() {
+				0 => Ok(c.result),
+				status => Err(Error::Command { opcode, status })
+			};
+		}
+
+		if unsafe { addr_of_mut!((*regs).controller_status).read_volatile() } & Nvme::CSTS_CFS != 0 {
+			return Err(Error::Fatal);
+		}
+		dma.stall(100);
+	}
+	Err(Error::Timeout)
+}
+
+/// An I/O queue pair. The controller owns its queues, `Controller::take_io_queues` hands
+/// them out so each can be used under its own lock, they must be given back with
+/// `Controller::return_io_queues` before the controller is shut down or dropped.
+pub struct IoQueue<D: Dma> {
+	queue:        Queue,
+	regs:         *mut Nvme,
+	dma:          D,
+	max_transfer: usize,
+	trim:         bool,
+	write_cache:  bool
+}
+
+impl<D: Dma> IoQueue<D> {
+	/// The queue id, 1 for the first I/O queue pair.
+	pub fn id(&self) -> u16 {
+		self.queue.id
+	}
+
+	pub fn supports_trim(&self) -> bool {
+		self.trim
+	}
+
+	/// Sets up the data pointers of a transfer, larger than two pages use the PRP list
+	/// page of the entry the command is placed in.
+	fn prps(&mut self, cmd: &mut Command, buf: *const u8, len: usize) -> Result<(), Error> {
+		let first = self.dma.phys(buf);
+		if first & 0x3 != 0 || len > self.max_transfer {
+			return Err(Error::InvalidArgument);
+		}
+
+		cmd.prp1 = first;
+		let first_len = PAGE_SIZE - (first as usize % PAGE_SIZE);
+		if len <= first_len {
+			return Ok(());
+		}
+
+		let pages = (len - first_len + PAGE_SIZE - 1) / PAGE_SIZE;
+		if pages == 1 {
+			cmd.prp2 = self.dma.phys(unsafe { buf.add(first_len) });
+			return Ok(());
+		}
+
+		if pages > PRP_LIST_LEN {
+			return Err(Error::InvalidArgument);
+		}
+		let (list, phys) = self.queue.prp_list();
+		for i in 0..pages {
+			let page = self.dma.phys(unsafe { buf.add(first_len + i * PAGE_SIZE) });
+			unsafe { list.add(i).write_volatile(page) };
+		}
+		cmd.prp2 = phys;
+		Ok(())
+	}
+
+	fn submit_rw(&mut self, opcode: u8, ns: &Namespace, lba: u64, buf: *const u8, len: usize) -> Result<u16, Error> {
+		let blocks = ns.check(lba, len)?;
+		if blocks == 0 || blocks > 0x1_0000 {
+			return Err(Error::InvalidArgument);
+		}
+
+		let mut cmd = Command {
+			opcode,
+			nsid:  ns.id,
+			cdw10: lba as u32,
+			cdw11: (lba >> 32) as u32,
+			cdw12: blocks as u32 - 1,
+			..Command::default()
+		};
+		self.prps(&mut cmd, buf, len)?;
+		self.queue.submit(cmd)
+	}
+
+	fn wait(&mut self, cid: u16, opcode: u8) -> Result<u32, Error> {
+		wait(&mut self.queue, self.regs, &mut self.dma, cid, opcode)
+	}
+
+	/// Submits a read of `buf.len()` bytes at `lba`, returns the command id. The buffer
+	/// must not be accessed until the command completed.
+	pub fn submit_read(&mut self, ns: &Namespace, lba: u64, buf: &mut [u8]) -> Result<u16, Error> {
+		self.submit_rw(Command::NVM_READ, ns, lba, buf.as_ptr(), buf.len())
+	}
+
+	/// Submits a write of `buf` to `lba`, returns the command id.
+	pub fn submit_write(&mut self, ns: &Namespace, lba: u64, buf: &[u8]) -> Result<u16, Error> {
+		self.submit_rw(Command::NVM_WRITE, ns, lba, buf.as_ptr(), buf.len())
+	}
+
+	/// Submits a flush of the volatile write cache, returns the command id.
+	pub fn submit_flush(&mut self, ns: &Namespace) -> Result<u16, Error> {
+		self.queue.submit(Command { opcode: Command::NVM_FLUSH, nsid: ns.id, ..Command::default() })
+	}
+
+	/// Submits a deallocation of up to 256 ranges of `(lba, blocks)`, returns the command id.
+	pub fn submit_trim(&mut self, ns: &Namespace, ranges: &[(u64, u32)]) -> Result<u16, Error> {
+		if ranges.is_empty() || ranges.len() > PAGE_SIZE / core::mem::size_of::<DsmRange>() || !self.trim {
+			return Err(Error::InvalidArgument);
+		}
+
+		let (list, phys) = self.queue.prp_list();
+		for (i, (lba, blocks)) in ranges.iter().enumerate() {
+			ns.check(*lba, *blocks as usize * ns.block_size as usize)?;
+			let range = DsmRange { attributes: 0, length: *blocks, start_lba: *lba };
+			unsafe { (list as *mut DsmRange).add(i).write_volatile(range) };
+		}
+
+		self.queue.submit(Command {
+			opcode: Command::NVM_DATASET_MGMT,
+			nsid:   ns.id,
+			prp1:   phys,
+			cdw10:  ranges.len() as u32 - 1,
+			cdw11:  Command::DSM_DEALLOCATE,
+			..Command::default()
+		})
+	}
+
+	/// Reaps a completion, called from the queue's interrupt handler until it returns
+	/// `None`.
+	pub fn complete(&mut self) -> Option<Completion> {
+		self.queue.complete()
+	}
+
+	/// Reads `buf.len()` bytes at `lba`, waiting for completion.
+	pub fn read(&mut self, ns: &Namespace, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+		let step = self.max_transfer / ns.block_size as usize * ns.block_size as usize;
+		for (i, chunk) in buf.chunks_mut(step.max(ns.block_size as usize)).enumerate() {
+			let lba = lba + (i * step / ns.block_size as usize) as u64;
+			let cid = self.submit_read(ns, lba, chunk)?;
+			self.wait(cid, Command::NVM_READ)?;
+		}
+		Ok(())
+	}
+
+	/// Writes `buf` to `lba`, waiting for completion.
+	pub fn write(&mut self, ns: &Namespace, lba: u64, buf: &[u8]) -> Result<(), Error> {
+		let step = self.max_transfer / ns.block_size as usize * ns.block_size as usize;
+		for (i, chunk) in buf.chunks(step.max(ns.block_size as usize)).enumerate() {
+			let lba = lba + (i * step / ns.block_size as usize) as u64;
+			let cid = self.submit_write(ns, lba, chunk)?;
+			self.wait(cid, Command::NVM_WRITE)?;
+		}
+		Ok(())
+	}
+
+	/// Flushes the volatile write cache, a no-op if the controller has none.
+	pub fn flush(&mut self, ns: &Namespace) -> Result<(), Error> {
+		if !self.write_cache {
+			return Ok(());
+		}
+		let cid = self.submit_flush(ns)?;
+		self.wait(cid, Command::NVM_FLUSH).map(|_| ())
+	}
+
+	/// Deallocates ranges of `(lba, blocks)`, waiting for completion.
+	pub fn trim(&mut self, ns: &Namespace, ranges: &[(u64, u32)]) -> Result<(), Error> {
+		for chunk in ranges.chunks(PAGE_SIZE / core::mem::size_of::<DsmRange>()) {
+			let cid = self.submit_trim(ns, chunk)?;
+			self.wait(cid, Command::NVM_DATASET_MGMT)?;
+		}
+		Ok(())
+	}
+}
+
+/// Driver of an NVMe controller, `regs` maps BAR0.
+///
+/// The admin queue is only used during initialization. I/O is submitted to one of the
+/// I/O queue pairs created by `create_io_queues`, usually one per hart, which either
+/// completes synchronously (`read`, `write`, ...) or through the interrupt of the
+/// queue (`submit_*` and `complete`). Drivers that use the queues from several harts at
+/// once take them out with `take_io_queues` and lock each on its own.
+pub struct Controller<D: Dma> {
+	regs:          *mut Nvme,
+	dma:           D,
+	stride:        usize,
+	/// Timeout for the ready state to change
+	ready_timeout: u64,
+	admin:         Queue,
+	io:            Vec<IoQueue<D>>,
+	/// Maximum bytes per command
+	max_transfer:  usize,
+	pub serial_number: [u8; 20],
+	pub model_number:  [u8; 40],
+	pub firmware:      [u8; 8],
+	pub namespaces:    u32,
+	pub optional_nvm_command_support: u16,
+	pub volatile_write_cache: bool
+}
+
+impl<D: Dma> Controller<D> {
+	/// Resets and enables the controller and identifies it.
+	///
+	/// # Safety
+	///
+	/// `regs` must map BAR0 of the controller, including the doorbells.
+	pub unsafe fn new(regs: *mut u8, mut dma: D) -> Result<Self, Error> {
+		let nvme = regs as *mut Nvme;
+		let cap = addr_of_mut!((*nvme).controller_capabilities).read_volatile();
+		if cap & Nvme::CAP_CSS_NVM == 0 || (cap >> Nvme::CAP_MPSMIN_SHIFT) & 0xF != 0 {
+			return Err(Error::Unsupported);
+		}
+
+		let stride = 4 << ((cap >> Nvme::CAP_DSTRD_SHIFT) & 0xF);
+		let ready_timeout = ((cap >> Nvme::CAP_TO_SHIFT) & 0xFF).max(1) * 500;
+		let admin = Queue::new(&mut dma, regs, stride, 0, ADMIN_QUEUE_SIZE, false)?;
+
+		let mut ctrl = Self {
+			regs: nvme,
+			dma,
+			stride,
+			ready_timeout,
+			admin,
+			io:            Vec::new(),
+			max_transfer:  PRP_LIST_LEN * PAGE_SIZE,
+			serial_number: [0; 20],
+			model_number:  [0; 40],
+			firmware:      [0; 8],
+			namespaces:    0,
+			optional_nvm_command_support: 0,
+			volatile_write_cache: false
+		};
+
+		ctrl.disable()?;
+		let aqa = (ADMIN_QUEUE_SIZE as u32 - 1) << 16 | (ADMIN_QUEUE_SIZE as u32 - 1);
+		addr_of_mut!((*nvme).admin_queue_attributes).write_volatile(aqa);
+		addr_of_mut!((*nvme).admin_submission_queue).write_volatile(Ptr64::new(ctrl.admin.sq.phys as usize as *mut u8));
+		addr_of_mut!((*nvme).admin_completion_queue).write_volatile(Ptr64::new(ctrl.admin.cq.phys as usize as *mut u8));
+		addr_of_mut!((*nvme).interrupt_mask_set).write_volatile(!0);
+		addr_of_mut!((*nvme).controller_config).write_volatile(Nvme::CC_CSS_NVM
+			| 6 << Nvme::CC_IOSQES_SHIFT | 4 << Nvme::CC_IOCQES_SHIFT | Nvme::CC_EN);
+		ctrl.wait_ready(true)?;
+		ctrl.identify_controller()?;
+		Ok(ctrl)
+	}
+
+	fn status(&self) -> u32 {
+		unsafe { addr_of_mut!((*self.regs).controller_status).read_volatile() }
+	}
+
+	fn disable(&mut self) -> Result<(), Error> {
+		let cc = unsafe { addr_of_mut!((*self.regs).controller_config) };
+		unsafe { cc.write_volatile(cc.read_volatile() & !Nvme::CC_EN) };
+		self.wait_ready(false)
+	}
+
+	fn wait_ready(&mut self, ready: bool) -> Result<(), Error> {
+		for _ in 0..self.ready_timeout {
+			let status = self.status();
+			if status & Nvme::CSTS_CFS != 0 && ready {
+				return Err(Error::Fatal);
+			} else if (status & Nvme::CSTS_RDY != 0) == ready {
+				return Ok(());
+			}
+			self.dma.stall(1000);
+		}
+		Err(Error::Timeout)
+	}
+
+	fn admin(&mut self, cmd: Command) -> Result<u32, Error> {
+		let cid = self.admin.submit(cmd)?;
+		wait(&mut self.admin, self.regs, &mut self.dma, cid, cmd.opcode)
+	}
+
+	/// Runs an identify command, the data is in the admin queue's scratch page.
+	fn identify(&mut self, cns: u32, nsid: u32) -> Result<*const u8, Error> {
+		let (page, phys) = self.admin.prp_list();
+		unsafe { core::ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE) };
+		self.admin(Command { opcode: Command::ADMIN_IDENTIFY, nsid, prp1: phys, cdw10: cns, ..Command::default() })?;
+		Ok(page as *const u8)
+	}
+
+	fn identify_controller(&mut self) -> Result<(), Error> {
+		let id = unsafe { &*(self.identify(Command::IDENTIFY_CONTROLLER, 0)? as *const IdentifyController) };
+		self.serial_number = id.serial_number;
+		self.model_number = id.model_number;
+		self.firmware = id.firmware_revision;
+		self.namespaces = id.namespaces;
+		self.optional_nvm_command_support = id.optional_nvm_command_support;
+		self.volatile_write_cache = id.volatile_write_cache & IdentifyController::VWC_PRESENT != 0;
+		if id.max_data_transfer_size != 0 {
+			self.max_transfer = self.max_transfer.min(PAGE_SIZE << id.max_data_transfer_size.min(20));
+		}
+		Ok(())
+	}
+
+	/// Maximum bytes transferred by one command, `read` and `write` split larger buffers.
+	pub fn max_transfer(&self) -> usize {
+		self.max_transfer
+	}
+
+	pub fn supports_trim(&self) -> bool {
+		self.optional_nvm_command_support & IdentifyController::ONCS_DATASET_MGMT != 0
+	}
+
+	/// Returns the active namespaces.
+	pub fn identify_namespaces(&mut self) -> Result<Vec<Namespace>, Error> {
+		// NVMe 1.0 controllers don't report the active namespace list
+		let ids = match self.identify(Command::IDENTIFY_ACTIVE_NSIDS, 0) {
+			Ok(list) => unsafe { core::slice::from_raw_parts(list as *const u32, PAGE_SIZE / 4) }
+				.iter().copied().take_while(|id| *id != 0).collect::<Vec<_>>(),
+			Err(Error::Command { .. }) => (1..=self.namespaces).collect(),
+			Err(e) => return Err(e)
+		};
+
+		let mut namespaces = Vec::new();
+		for id in ids {
+			let ns = unsafe { &*(self.identify(Command::IDENTIFY_NAMESPACE, id)? as *const IdentifyNamespace) };
+			let (size, format) = (ns.size, ns.lba_format());
+			if size != 0 && (9..=16).contains(&format.lba_data_size) && format.metadata_size == 0 {
+				namespaces.push(Namespace { id, blocks: size, block_size: 1 << format.lba_data_size });
+			}
+		}
+		Ok(namespaces)
+	}
+
+	/// Creates an I/O queue pair for each entry of `vectors`, completions of a queue
+	/// signal its MSI-X vector or are polled if it is `None`. The controller may grant
+	/// fewer queues, returns the number created.
+	pub fn create_io_queues(&mut self, vectors: &[Option<u16>]) -> Result<usize, Error> where D: Clone {
+		if vectors.is_empty() || !self.io.is_empty() {
+			return Err(Error::InvalidArgument);
+		}
+
+		let requested = vectors.len().min(0xFFFF) as u32 - 1;
+		let granted = self.admin(Command {
+			opcode: Command::ADMIN_SET_FEATURES,
+			cdw10:  Command::FEATURE_NUMBER_OF_QUEUES,
+			cdw11:  requested << 16 | requested,
+			..Command::default()
+		})?;
+		let count = ((granted & 0xFFFF).min(granted >> 16).min(requested) + 1) as usize;
+
+		let cap = unsafe { addr_of_mut!((*self.regs).controller_capabilities).read_volatile() };
+		let size = IO_QUEUE_SIZE.min((cap & Nvme::CAP_MQES_MASK) as u16 + 1);
+
+		for (i, vector) in vectors.iter().take(count).enumerate() {
+			let id = i as u16 + 1;
+			let queue = Queue::new(&mut self.dma, self.regs as *mut u8, self.stride, id, size, true)?;
+			let interrupts = vector.map_or(0, |v| (v as u32) << 16 | Command::QUEUE_IEN);
+			let cdw10 = (size as u32 - 1) << 16 | id as u32;
+
+			let r = self.admin(Command { opcode: Command::ADMIN_CREATE_CQ, prp1: queue.cq.phys, cdw10, cdw11: interrupts | Command::QUEUE_PC, ..Command::default() })
+				.and_then(|_| self.admin(Command { opcode: Command::ADMIN_CREATE_SQ, prp1: queue.sq.phys, cdw10, cdw11: (id as u32) << 16 | Command::QUEUE_PC, ..Command::default() }));
+			if let Err(e) = r {
+				queue.free(&mut self.dma);
+				return Err(e);
+			}
+			self.io.push(IoQueue {
+				queue,
+				regs:         self.regs,
+				dma:          self.dma.clone(),
+				max_transfer: self.max_transfer,
+				trim:         self.supports_trim(),
+				write_cache:  self.volatile_write_cache
+			});
+		}
+		Ok(self.io.len())
+	}
+
+	pub fn io_queues(&self) -> usize {
+		self.io.len()
+	}
+
+	/// Hands out the I/O queues, in the order they were created.
+	pub fn take_io_queues(&mut self) -> Vec<IoQueue<D>> {
+		core::mem::take(&mut self.io)
+	}
+
+	/// Takes back the queues handed out by `take_io_queues`.
+	pub fn return_io_queues(&mut self, queues: Vec<IoQueue<D>>) {
+		self.io.extend(queues);
+		self.io.sort_by_key(|q| q.queue.id);
+	}
+
+	fn queue(&mut self, queue: usize) -> Result<&mut IoQueue<D>, Error> {
+		self.io.get_mut(queue).ok_or(Error::InvalidArgument)
+	}
+
+	/// Submits a read of `buf.len()` bytes at `lba`, returns the command id. The buffer
+	/// must not be accessed until the command completed.
+	pub fn submit_read(&mut self, queue: usize, ns: &Namespace, lba: u64, buf: &mut [u8]) -> Result<u16, Error> {
+		self.queue(queue)?.submit_read(ns, lba, buf)
+	}
+
+	/// Submits a write of `buf` to `lba`, returns the command id.
+	pub fn submit_write(&mut self, queue: usize, ns: &Namespace, lba: u64, buf: &[u8]) -> Result<u16, Error> {
+		self.queue(queue)?.submit_write(ns, lba, buf)
+	}
+
+	/// Submits a flush of the volatile write cache, returns the command id.
+	pub fn submit_flush(&mut self, queue: usize, ns: &Namespace) -> Result<u16, Error> {
+		self.queue(queue)?.submit_flush(ns)
+	}
+
+	/// Submits a deallocation of up to 256 ranges of `(lba, blocks)`, returns the command id.
+	pub fn submit_trim(&mut self, queue: usize, ns: &Namespace, ranges: &[(u64, u32)]) -> Result<u16, Error> {
+		self.queue(queue)?.submit_trim(ns, ranges)
+	}
+
+	/// Reaps a completion of a queue, called from the queue's interrupt handler until
+	/// it returns `None`.
+	pub fn complete(&mut self, queue: usize) -> Option<Completion> {
+		self.io.get_mut(queue)?.complete()
+	}
+
+	/// Reads `buf.len()` bytes at `lba`, waiting for completion.
+	pub fn read(&mut self, queue: usize, ns: &Namespace, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+		self.queue(queue)?.read(ns, lba, buf)
+	}
+
+	/// Writes `buf` to `lba`, waiting for completion.
+	pub fn write(&mut self, queue: usize, ns: &Namespace, lba: u64, buf: &[u8]) -> Result<(), Error> {
+		self.queue(queue)?.write(ns, lba, buf)
+	}
+
+	/// Flushes the volatile write cache, a no-op if the controller has none.
+	pub fn flush(&mut self, queue: usize, ns: &Namespace) -> Result<(), Error> {
+		self.queue(queue)?.flush(ns)
+	}
+
+	/// Deallocates ranges of `(lba, blocks)`, waiting for completion.
+	pub fn trim(&mut self, queue: usize, ns: &Namespace, ranges: &[(u64, u32)]) -> Result<(), Error> {
+		self.queue(queue)?.trim(ns, ranges)
+	}
+
+	/// Deletes the I/O queues and performs a normal shutdown.
+	pub fn shutdown(&mut self) -> Result<(), Error> {
+		for id in self.io.iter().rev().map(|q| q.queue.id as u32).collect::<Vec<_>>() {
+			self.admin(Command { opcode: Command::ADMIN_DELETE_SQ, cdw10: id, ..Command::default() })?;
+			self.admin(Command { opcode: Command::ADMIN_DELETE_CQ, cdw10: id, ..Command::default() })?;
+		}
+		while let Some(q) = self.io.pop() {
+			q.queue.free(&mut self.dma);
+		}
+
+		let cc = unsafe { addr_of_mut!((*self.regs).controller_config) };
+		unsafe { cc.write_volatile(cc.read_volatile() | Nvme::CC_SHN_NORMAL) };
+		for _ in 0..self.ready_timeout {
+			if self.status() & Nvme::CSTS_SHST_MASK == Nvme::CSTS_SHST_DONE {
+				return Ok(());
+			}
+			self.dma.stall(1000);
+		}
+		Err(Error::Timeout)
+	}
+}
+
+impl<D: Dma> Drop for Controller<D> {
+	fn drop(&mut self) {
+		// the controller must stop accessing the queues before they are freed
+		let _ = self.disable();
+		while let Some(q) = self.io.pop() {
+			q.queue.free(&mut self.dma);
+		}
+		self.admin.free(&mut self.dma);
+	}
+}
+
+impl<D: Dma> core::fmt::Debug for Controller<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Controller")
+			.field("model_number", &core::str::from_utf8(&self.model_number).unwrap_or("").trim_end())
+			.field("serial_number", &core::str::from_utf8(&self.serial_number).unwrap_or("").trim_end())
+			.field("namespaces", &self.namespaces)
+			.field("io_queues", &self.io.len())
+			.finish()
+	}
+}
@@ -20,8 +20,14 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+//! NVM Express controllers, see `Controller` for the driver.
+
 use crate::Ptr64;
 
+mod controller;
+
+pub use controller::*;
+
 #[repr(C)]
 pub struct Nvme {
 	pub controller_capabilities:                                  u64,
@@ -41,13 +47,199 @@ pub struct Nvme {
 	pub boot_partition_read_select:                               u32,
 	pub boot_partition_memory_buffer_location:                    Ptr64<u8>,
 	pub controller_memory_buffer_status:                          u32,
-	pub _res1:                                                    [u32; 0x369],
+	pub _res1:                                                    [u32; 0x36B],
 	pub persistent_memory_capabilities:                           u32,
 	pub persistent_memory_region_control:                         u32,
 	pub persistent_memory_region_status:                          u32,
 	pub persistent_memory_region_elasticity_buffer_size:          u32,
 	pub persistent_memory_region_sustained_write_throughput:      u32,
 	pub persistent_memory_region_controller_memory_space_control: u32,
-	pub _res2:                                                    [u32; 0x1E4],
+	pub _res2:                                                    [u32; 0x7A],
 	pub doorbells_base:                                           [u32; 0]
-}
\ No newline at end of file
+}
+impl Nvme {
+	/// Offset of the first doorbell register
+	pub const DOORBELLS: usize = 0x1000;
+
+	/// Maximum queue entries supported, zero based
+	pub const CAP_MQES_MASK:   u64 = 0xFFFF;
+	/// Timeout for `CSTS_RDY` to change in units of 500 ms
+	pub const CAP_TO_SHIFT:    u64 = 24;
+	/// Doorbell stride, `4 << DSTRD` bytes
+	pub const CAP_DSTRD_SHIFT: u64 = 32;
+	/// NVM command set supported
+	pub const CAP_CSS_NVM:     u64 = 1 << 37;
+	/// Minimum memory page size, `4096 << MPSMIN` bytes
+	pub const CAP_MPSMIN_SHIFT: u64 = 48;
+
+	pub const CC_EN:           u32 = 1 << 0;
+	pub const CC_CSS_NVM:      u32 = 0 << 4;
+	pub const CC_MPS_SHIFT:    u32 = 7;
+	pub const CC_SHN_NORMAL:   u32 = 1 << 14;
+	pub const CC_IOSQES_SHIFT: u32 = 16;
+	pub const CC_IOCQES_SHIFT: u32 = 20;
+
+	pub const CSTS_RDY:        u32 = 1 << 0;
+	/// Controller fatal status
+	pub const CSTS_CFS:        u32 = 1 << 1;
+	pub const CSTS_SHST_MASK:  u32 = 3 << 2;
+	pub const CSTS_SHST_DONE:  u32 = 2 << 2;
+}
+
+/// Submission queue entry, the layout of command dwords 10-15 depends on the opcode.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Command {
+	pub opcode:     u8,
+	/// Fused operation and PRP/SGL selection, zero for PRPs
+	pub flags:      u8,
+	pub command_id: u16,
+	pub nsid:       u32,
+	pub _res0:      u64,
+	pub metadata:   u64,
+	pub prp1:       u64,
+	pub prp2:       u64,
+	pub cdw10:      u32,
+	pub cdw11:      u32,
+	pub cdw12:      u32,
+	pub cdw13:      u32,
+	pub cdw14:      u32,
+	pub cdw15:      u32
+}
+
+impl Command {
+	pub const ADMIN_DELETE_SQ:      u8 = 0x00;
+	pub const ADMIN_CREATE_SQ:      u8 = 0x01;
+	pub const ADMIN_GET_LOG_PAGE:   u8 = 0x02;
+	pub const ADMIN_DELETE_CQ:      u8 = 0x04;
+	pub const ADMIN_CREATE_CQ:      u8 = 0x05;
+	pub const ADMIN_IDENTIFY:       u8 = 0x06;
+	pub const ADMIN_ABORT:          u8 = 0x08;
+	pub const ADMIN_SET_FEATURES:   u8 = 0x09;
+	pub const ADMIN_GET_FEATURES:   u8 = 0x0A;
+
+	pub const NVM_FLUSH:            u8 = 0x00;
+	pub const NVM_WRITE:            u8 = 0x01;
+	pub const NVM_READ:             u8 = 0x02;
+	pub const NVM_DATASET_MGMT:     u8 = 0x09;
+
+	pub const IDENTIFY_NAMESPACE:   u32 = 0x00;
+	pub const IDENTIFY_CONTROLLER:  u32 = 0x01;
+	pub const IDENTIFY_ACTIVE_NSIDS: u32 = 0x02;
+
+	pub const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+	/// Physically contiguous queue
+	pub const QUEUE_PC:             u32 = 1 << 0;
+	/// Completion queue interrupts enabled
+	pub const QUEUE_IEN:            u32 = 1 << 1;
+
+	/// Deallocate the ranges of a dataset management command
+	pub const DSM_DEALLOCATE:       u32 = 1 << 2;
+}
+
+/// Completion queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Completion {
+	/// Command specific result
+	pub result:     u32,
+	pub _res0:      u32,
+	pub sq_head:    u16,
+	pub sq_id:      u16,
+	pub command_id: u16,
+	/// Phase tag in bit 0, status code in bits 1-8, status code type in bits 9-11
+	pub status:     u16
+}
+
+impl Completion {
+	pub const STATUS_PHASE: u16 = 1;
+
+	pub fn phase(&self) -> bool {
+		self.status & Self::STATUS_PHASE != 0
+	}
+
+	/// Status code type in the upper byte and status code in the lower one, zero on success.
+	pub fn code(&self) -> u16 {
+		(self.status >> 1) & 0x7FF
+	}
+}
+
+/// A range of a dataset management command.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DsmRange {
+	pub attributes: u32,
+	/// Number of logical blocks
+	pub length:     u32,
+	pub start_lba:  u64
+}
+
+#[repr(C, packed)]
+pub struct IdentifyController {
+	pub vendor_id:                      u16,
+	pub subsystem_vendor_id:            u16,
+	pub serial_number:                  [u8; 20],
+	pub model_number:                   [u8; 40],
+	pub firmware_revision:              [u8; 8],
+	pub recommended_arbitration_burst:  u8,
+	pub ieee_oui:                       [u8; 3],
+	pub multipath_capabilities:         u8,
+	/// Maximum transfer size as power of two of the minimum page size, zero if unlimited
+	pub max_data_transfer_size:         u8,
+	pub controller_id:                  u16,
+	pub version:                        u32,
+	pub _res0:                          [u8; 172],
+	pub optional_admin_command_support: u16,
+	pub _res1:                          [u8; 254],
+	/// Required and maximum submission queue entry size as powers of two
+	pub sq_entry_size:                  u8,
+	pub cq_entry_size:                  u8,
+	pub max_commands:                   u16,
+	pub namespaces:                     u32,
+	pub optional_nvm_command_support:   u16,
+	pub fused_operation_support:        u16,
+	pub format_nvm_attributes:          u8,
+	pub volatile_write_cache:           u8,
+	pub _res2:                          [u8; 3570]
+}
+
+impl IdentifyController {
+	pub const ONCS_DATASET_MGMT:   u16 = 1 << 2;
+	pub const VWC_PRESENT:         u8 = 1 << 0;
+}
+
+#[repr(C, packed)]
+pub struct IdentifyNamespace {
+	/// Size in logical blocks
+	pub size:                   u64,
+	pub capacity:               u64,
+	pub utilization:            u64,
+	pub features:               u8,
+	/// Number of LBA formats, zero based
+	pub lba_formats_len:        u8,
+	/// Index of the LBA format in use
+	pub formatted_lba_size:     u8,
+	pub metadata_capabilities:  u8,
+	pub _res0:                  [u8; 100],
+	pub lba_formats:            [LbaFormat; 64],
+	pub _res1:                  [u8; 3712]
+}
+
+impl IdentifyNamespace {
+	pub fn lba_format(&self) -> LbaFormat {
+		let flbas = self.formatted_lba_size;
+		let i = (flbas & 0xF | (flbas >> 1) & 0x30) as usize;
+		let formats = self.lba_formats;
+		formats[i.min(formats.len() - 1)]
+	}
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LbaFormat {
+	pub metadata_size:      u16,
+	/// Logical block size as power of two
+	pub lba_data_size:      u8,
+	pub relative_performance: u8
+}
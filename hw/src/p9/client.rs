@@ -0,0 +1,403 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {super::*, alloc::vec};
+
+/// A file of the server, as the client refers to it.
+pub type Fid = u32;
+
+/// Largest message the client negotiates
+pub const MAX_MSIZE: usize = 64 * 1024;
+/// Opened files kept for reads and writes by path, the least recently used is clunked
+pub const OPEN_FIDS: usize = 16;
+pub const ROOT: Fid = 0;
+
+/// Requests are sent one at a time, all with the same tag
+const TAG: u16 = 1;
+
+/// A client of a single share.
+pub struct Client<C: Channel> {
+	channel:  C,
+	msize:    usize,
+	/// Fids `clunk` gave back, and the next one never used
+	free:     Vec<Fid>,
+	next:     Fid,
+	/// Opened files by path, the most recently used last
+	open:     Vec<(String, Fid)>,
+	response: Vec<u8>
+}
+
+impl<C: Channel> Client<C> {
+	/// Negotiates the version and the message size and attaches to the share `aname` as
+	/// `uname` with `uid`, the share's root is `ROOT`.
+	pub fn new(channel: C, uname: &str, aname: &str, uid: u32) -> Result<Self> {
+		let msize = channel.max_size().min(MAX_MSIZE);
+		if msize <= IO_HEADER_LEN {
+			return Err(Error::InvalidArgument);
+		}
+		let mut client = Self { channel, msize, free: Vec::new(), next: ROOT + 1, open: Vec::new(), response: vec![0; msize] };
+
+		let mut r = client.call(Writer::new(TVERSION, NOTAG).u32(msize as u32).str(VERSION))?;
+		let (msize, version) = (r.u32()? as usize, r.str()?);
+		if version != VERSION {
+			return Err(Error::Unsupported);
+		} else if msize <= IO_HEADER_LEN {
+			return Err(Error::Protocol);
+		}
+		client.msize = client.msize.min(msize);
+
+		client.call(Writer::new(TATTACH, TAG).u32(ROOT).u32(NOFID).str(uname).str(aname).u32(uid))?.qid()?;
+		Ok(client)
+	}
+
+	pub fn channel(&self) -> &C {
+		&self.channel
+	}
+
+	pub fn channel_mut(&mut self) -> &mut C {
+		&mut self.channel
+	}
+
+	/// The negotiated message size.
+	pub fn msize(&self) -> usize {
+		self.msize
+	}
+
+	/// Fids in use, including `ROOT`.
+	pub fn fids(&self) -> usize {
+		self.next as usize - self.free.len()
+	}
+
+	/// Sends a request, returns the fields of the response after its header.
+	fn call(&mut self, request: Writer) -> Result<Reader<'_>> {
+		let request = request.finish();
+		if request.len() > self.msize {
+			return Err(Error::InvalidArgument);
+		}
+		let len = self.channel.request(&request, &mut self.response)?;
+		let mut r = Reader::new(self.response.get(..len).ok_or(Error::Protocol)?);
+		let (size, ty, tag) = (r.u32()? as usize, r.u8()?, r.u16()?);
+		if size != len || tag.to_le_bytes() != request[5..7] {
+			return Err(Error::Protocol);
+		}
+		match ty {
+			RLERROR => Err(Error::Remote(r.u32()?)),
+			ty if ty == request[4] + 1 => Ok(r),
+			_ => Err(Error::Protocol)
+		}
+	}
+
+	fn alloc(&mut self) -> Result<Fid> {
+		match self.free.pop() {
+			Some(fid) => Ok(fid),
+			None if self.next == NOFID => Err(Error::NoFid),
+			None => {
+				self.next += 1;
+				Ok(self.next - 1)
+			}
+		}
+	}
+
+	/// Walks from `fid` along `names` to a new fid, a clone of `fid` without names.
+	pub fn walk(&mut self, fid: Fid, names: &[&str]) -> Result<Fid> {
+		if names.iter().any(|n| n.is_empty() || n.len() > u16::MAX as usize || n.contains('/')) {
+			return Err(Error::InvalidArgument);
+		}
+		let newfid = self.alloc()?;
+		let mut from = fid;
+		for chunk in names.chunks(MAXWELEM).chain(names.is_empty().then_some(&[][..])) {
+			let request = chunk.iter().fold(Writer::new(TWALK, TAG).u32(from).u32(newfid).u16(chunk.len() as u16), |w, n| w.str(n));
+			// the new fid only exists if all names were walked
+			let walked = self.call(request).and_then(|mut r| r.u16().map(|n| n as usize));
+			let error = match walked {
+				Ok(n) if n == chunk.len() => {
+					from = newfid;
+					continue;
+				}
+				Ok(_) => Error::Remote(ENOENT),
+				Err(e) => e
+			};
+			match from == newfid {
+				true  => {
+					let _ = self.clunk(newfid);
+				}
+				false => self.free.push(newfid)
+			}
+			return Err(error);
+		}
+		Ok(newfid)
+	}
+
+	/// Opens a walked fid with `O_*` flags.
+	pub fn open(&mut self, fid: Fid, flags: u32) -> Result<Qid> {
+		self.call(Writer::new(TLOPEN, TAG).u32(fid).u32(flags))?.qid()
+	}
+
+	/// Creates and opens a file in the directory `fid`, which then refers to the file.
+	pub fn create(&mut self, fid: Fid, name: &str, flags: u32, mode: u32, gid: u32) -> Result<Qid> {
+		if name.is_empty() || name.contains('/') {
+			return Err(Error::InvalidArgument);
+		}
+		self.call(Writer::new(TLCREATE, TAG).u32(fid).str(name).u32(flags).u32(mode).u32(gid))?.qid()
+	}
+
+	pub fn mkdir(&mut self, fid: Fid, name: &str, mode: u32, gid: u32) -> Result<Qid> {
+		if name.is_empty() || name.contains('/') {
+			return Err(Error::InvalidArgument);
+		}
+		self.call(Writer::new(TMKDIR, TAG).u32(fid).str(name).u32(mode).u32(gid))?.qid()
+	}
+
+	/// Reads from an opened fid until `buf` is full or the end of the file.
+	pub fn read(&mut self, fid: Fid, offset: u64, buf: &mut [u8]) -> Result<usize> {
+		let mut done = 0;
+		while done < buf.len() {
+			let count = (buf.len() - done).min(self.msize - IO_HEADER_LEN);
+			let mut r = self.call(Writer::new(TREAD, TAG).u32(fid).u64(offset + done as u64).u32(count as u32))?;
+			let n = r.u32()? as usize;
+			if n > count {
+				return Err(Error::Protocol);
+			}
+			buf[done..done + n].copy_from_slice(r.bytes(n)?);
+			done += n;
+			if n < count {
+				break;
+			}
+		}
+		Ok(done)
+	}
+
+	/// Writes to an opened fid, returns how much the server took.
+	pub fn write(&mut self, fid: Fid, offset: u64, data: &[u8]) -> Result<usize> {
+		let mut done = 0;
+		while done < data.len() {
+			let count = (data.len() - done).min(self.msize - IO_HEADER_LEN);
+			let request = Writer::new(TWRITE, TAG).u32(fid).u64(offset + done as u64).u32(count as u32).bytes(&data[done..done + count]);
+			let n = self.call(request)?.u32()? as usize;
+			if n > count {
+				return Err(Error::Protocol);
+			}
+			done += n;
+			if n < count {
+				break;
+			}
+		}
+		Ok(done)
+	}
+
+	/// The entries of an opened directory after `offset`, as many as fit into a response.
+	pub fn readdir(&mut self, fid: Fid, offset: u64) -> Result<Vec<DirEntry>> {
+		let count = (self.msize - IO_HEADER_LEN) as u32;
+		let mut r = self.call(Writer::new(TREADDIR, TAG).u32(fid).u64(offset).u32(count))?;
+		let len = r.u32()? as usize;
+		let mut r = Reader::new(r.bytes(len)?);
+		let mut entries = Vec::new();
+		while r.remaining() > 0 {
+			entries.push(DirEntry { qid: r.qid()?, offset: r.u64()?, ty: r.u8()?, name: r.str()?.into() });
+		}
+		Ok(entries)
+	}
+
+	/// All entries of an opened directory.
+	pub fn read_dir(&mut self, fid: Fid) -> Result<Vec<DirEntry>> {
+		let mut entries = Vec::new();
+		loop {
+			let offset = entries.last().map_or(0, |e: &DirEntry| e.offset);
+			let more = self.readdir(fid, offset)?;
+			if more.is_empty() {
+				return Ok(entries);
+			}
+			entries.extend(more);
+		}
+	}
+
+	pub fn getattr(&mut self, fid: Fid) -> Result<Attr> {
+		let mut r = self.call(Writer::new(TGETATTR, TAG).u32(fid).u64(GETATTR_BASIC))?;
+		let valid = r.u64()?;
+		let qid = r.qid()?;
+		let (mode, uid, gid) = (r.u32()?, r.u32()?, r.u32()?);
+		let (nlink, rdev, size, blksize, blocks) = (r.u64()?, r.u64()?, r.u64()?, r.u64()?, r.u64()?);
+		let (atime, mtime, ctime) = ((r.u64()?, r.u64()?), (r.u64()?, r.u64()?), (r.u64()?, r.u64()?));
+		Ok(Attr { valid, qid, mode, uid, gid, nlink, rdev, size, blksize, blocks, atime, mtime, ctime })
+	}
+
+	pub fn setattr(&mut self, fid: Fid, attr: &SetAttr) -> Result<()> {
+		self.call(Writer::new(TSETATTR, TAG).u32(fid).u32(attr.valid).u32(attr.mode).u32(attr.uid).u32(attr.gid)
+			.u64(attr.size).u64(attr.atime.0).u64(attr.atime.1).u64(attr.mtime.0).u64(attr.mtime.1))?;
+		Ok(())
+	}
+
+	pub fn fsync(&mut self, fid: Fid) -> Result<()> {
+		self.call(Writer::new(TFSYNC, TAG).u32(fid).u32(0))?;
+		Ok(())
+	}
+
+	/// Removes `name` from the directory `fid`, a directory with `AT_REMOVEDIR`.
+	pub fn unlink(&mut self, fid: Fid, name: &str, flags: u32) -> Result<()> {
+		self.call(Writer::new(TUNLINKAT, TAG).u32(fid).str(name).u32(flags))?;
+		Ok(())
+	}
+
+	/// Releases a fid, it is gone even if the server fails the request.
+	pub fn clunk(&mut self, fid: Fid) -> Result<()> {
+		let result = self.call(Writer::new(TCLUNK, TAG).u32(fid)).map(drop);
+		self.free.push(fid);
+		result
+	}
+
+	/// Runs `f` on a fid walked to `path`, which is clunked afterwards.
+	fn with_path<T>(&mut self, path: &str, f: impl FnOnce(&mut Self, Fid) -> Result<T>) -> Result<T> {
+		let fid = self.walk(ROOT, &components(path)?)?;
+		let result = f(self, fid);
+		let _ = self.clunk(fid);
+		result
+	}
+
+	pub fn metadata(&mut self, path: &str) -> Result<Attr> {
+		self.with_path(path, |c, fid| c.getattr(fid))
+	}
+
+	/// An opened fid of a file, for reads and writes. Files are opened for writing if
+	/// the server allows it.
+	fn opened(&mut self, path: &str) -> Result<Fid> {
+		if let Some(i) = self.open.iter().position(|(p, _)| p == path) {
+			let entry = self.open.remove(i);
+			let fid = entry.1;
+			self.open.push(entry);
+			return Ok(fid);
+		}
+
+		let fid = self.walk(ROOT, &components(path)?)?;
+		let opened = match self.open(fid, O_RDWR) {
+			Err(Error::Remote(EACCES | EROFS | EPERM)) => self.open(fid, O_RDONLY),
+			r => r
+		};
+		if let Err(e) = opened {
+			let _ = self.clunk(fid);
+			return Err(e);
+		}
+		if self.open.len() == OPEN_FIDS {
+			let (_, old) = self.open.remove(0);
+			let _ = self.clunk(old);
+		}
+		self.open.push((path.into(), fid));
+		Ok(fid)
+	}
+
+	/// Clunks the opened fids of `path` and the files below it.
+	fn forget(&mut self, path: &str) {
+		let path = path.trim_end_matches('/');
+		let (forgotten, kept) = core::mem::take(&mut self.open).into_iter()
+			.partition::<Vec<_>, _>(|(p, _)| p.strip_prefix(path).map_or(false, |rest| rest.is_empty() || rest.starts_with('/')));
+		self.open = kept;
+		for (_, fid) in forgotten {
+			let _ = self.clunk(fid);
+		}
+	}
+
+	pub fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+		let fid = self.opened(path)?;
+		self.read(fid, offset, buf)
+	}
+
+	pub fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize> {
+		let fid = self.opened(path)?;
+		self.write(fid, offset, data)
+	}
+
+	pub fn truncate(&mut self, path: &str, size: u64) -> Result<()> {
+		self.with_path(path, |c, fid| c.setattr(fid, &SetAttr { valid: SETATTR_SIZE, size, ..SetAttr::default() }))
+	}
+
+	/// Creates an empty file or a directory, owned by the group of its directory.
+	pub fn create_path(&mut self, path: &str, dir: bool) -> Result<()> {
+		let mut names = components(path)?;
+		let name = names.pop().ok_or(Error::InvalidArgument)?;
+		let fid = self.walk(ROOT, &names)?;
+		let result = match dir {
+			true  => self.mkdir(fid, name, 0o755, 0),
+			false => self.create(fid, name, O_RDWR | O_CREAT | O_EXCL, 0o644, 0)
+		};
+		let _ = self.clunk(fid);
+		result.map(drop)
+	}
+
+	/// Removes a file or an empty directory.
+	pub fn remove(&mut self, path: &str) -> Result<()> {
+		let mut names = components(path)?;
+		let name = names.pop().ok_or(Error::InvalidArgument)?;
+		self.forget(path);
+		let fid = self.walk(ROOT, &names)?;
+		let result = match self.unlink(fid, name, 0) {
+			Err(Error::Remote(EISDIR)) => self.unlink(fid, name, AT_REMOVEDIR),
+			r => r
+		};
+		let _ = self.clunk(fid);
+		result
+	}
+
+	/// The entries of a directory, without `.` and `..`.
+	pub fn list(&mut self, path: &str) -> Result<Vec<DirEntry>> {
+		self.with_path(path, |c, fid| {
+			c.open(fid, O_RDONLY | O_DIRECTORY)?;
+			let mut entries = c.read_dir(fid)?;
+			entries.retain(|e| e.name != "." && e.name != "..");
+			Ok(entries)
+		})
+	}
+
+	/// Syncs the opened files.
+	pub fn sync(&mut self) -> Result<()> {
+		let fids = self.open.iter().map(|(_, fid)| *fid).collect::<Vec<_>>();
+		fids.into_iter().try_for_each(|fid| self.fsync(fid))
+	}
+}
+
+impl<C: Channel> Drop for Client<C> {
+	fn drop(&mut self) {
+		for (_, fid) in core::mem::take(&mut self.open) {
+			let _ = self.clunk(fid);
+		}
+		let _ = self.clunk(ROOT);
+	}
+}
+
+impl<C: Channel> core::fmt::Debug for Client<C> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Client")
+			.field("msize", &self.msize)
+			.field("fids", &self.fids())
+			.field("open", &self.open.len())
+			.finish()
+	}
+}
+
+/// The names of a path relative to the root of the share, `..` isn't allowed.
+pub fn components(path: &str) -> Result<Vec<&str>> {
+	path.split('/')
+		.filter(|n| !n.is_empty() && *n != ".")
+		.map(|n| match n {
+			".." => Err(Error::InvalidArgument),
+			n    => Ok(n)
+		})
+		.collect()
+}
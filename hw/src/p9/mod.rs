@@ -0,0 +1,295 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! 9P2000.L, the file protocol QEMU shares host directories with.
+//!
+//! A `Client` sends one request at a time over a `Channel`, e.g. a virtio 9P transport,
+//! and waits for the response. Files are referred to by fids the client allocates:
+//! walking from a fid yields a new one, which is opened, read, written and finally
+//! clunked. The messages are little-endian, strings are prefixed with their 16 bit
+//! length. Errors of the server are Linux error numbers.
+
+mod client;
+
+pub use client::*;
+
+use alloc::{string::String, vec::Vec};
+
+pub const VERSION: &str = "9P2000.L";
+
+/// Message types, each response is the type of its request plus one
+pub const RLERROR:   u8 = 7;
+pub const TLOPEN:    u8 = 12;
+pub const TLCREATE:  u8 = 14;
+pub const TGETATTR:  u8 = 24;
+pub const TSETATTR:  u8 = 26;
+pub const TREADDIR:  u8 = 40;
+pub const TFSYNC:    u8 = 50;
+pub const TMKDIR:    u8 = 72;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION:  u8 = 100;
+pub const TATTACH:   u8 = 104;
+pub const TWALK:     u8 = 110;
+pub const TREAD:     u8 = 116;
+pub const TWRITE:    u8 = 118;
+pub const TCLUNK:    u8 = 120;
+
+/// Size, type and tag
+pub const HEADER_LEN: usize = 7;
+/// The tag of `TVERSION`
+pub const NOTAG: u16 = 0xFFFF;
+pub const NOFID: u32 = 0xFFFF_FFFF;
+/// Most names of one `TWALK`
+pub const MAXWELEM: usize = 16;
+/// Header, fid, offset and count of `TWRITE`, the overhead of a write or read
+pub const IO_HEADER_LEN: usize = HEADER_LEN + 4 + 8 + 4;
+
+pub const QID_DIR:     u8 = 0x80;
+pub const QID_SYMLINK: u8 = 0x02;
+pub const QID_FILE:    u8 = 0x00;
+
+/// `TLOPEN` and `TLCREATE` flags, those of Linux
+pub const O_RDONLY:    u32 = 0;
+pub const O_WRONLY:    u32 = 1;
+pub const O_RDWR:      u32 = 2;
+pub const O_CREAT:     u32 = 0o100;
+pub const O_EXCL:      u32 = 0o200;
+pub const O_TRUNC:     u32 = 0o1000;
+pub const O_DIRECTORY: u32 = 0o200000;
+
+/// `TGETATTR` request mask of the mode through the block count
+pub const GETATTR_BASIC: u64 = 0x7FF;
+
+/// `TSETATTR` fields to set
+pub const SETATTR_MODE:  u32 = 0x1;
+pub const SETATTR_UID:   u32 = 0x2;
+pub const SETATTR_GID:   u32 = 0x4;
+pub const SETATTR_SIZE:  u32 = 0x8;
+pub const SETATTR_ATIME: u32 = 0x10;
+pub const SETATTR_MTIME: u32 = 0x20;
+
+/// `TUNLINKAT` flag to remove a directory
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+/// Linux error numbers the server answers with
+pub const EPERM:     u32 = 1;
+pub const ENOENT:    u32 = 2;
+pub const EIO:       u32 = 5;
+pub const EACCES:    u32 = 13;
+pub const EEXIST:    u32 = 17;
+pub const ENOTDIR:   u32 = 20;
+pub const EISDIR:    u32 = 21;
+pub const EINVAL:    u32 = 22;
+pub const ENOSPC:    u32 = 28;
+pub const EROFS:     u32 = 30;
+pub const ENOTEMPTY: u32 = 39;
+
+/// Mode bits of the file type
+pub const S_IFMT:  u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// The server failed the request with a Linux error number
+	Remote(u32),
+	/// A malformed or unexpected response
+	Protocol,
+	/// The server doesn't speak 9P2000.L
+	Unsupported,
+	/// The channel failed to deliver the request or the response
+	Transport,
+	/// All fids are in use
+	NoFid,
+	/// An empty or oversized name, or a path that leaves the share
+	InvalidArgument
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The server's identity of a file.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Qid {
+	pub ty:      u8,
+	pub version: u32,
+	pub path:    u64
+}
+
+impl Qid {
+	pub fn is_dir(&self) -> bool {
+		self.ty & QID_DIR != 0
+	}
+}
+
+/// The attributes `TGETATTR` returns, times are in seconds and nanoseconds.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Attr {
+	pub valid:   u64,
+	pub qid:     Qid,
+	pub mode:    u32,
+	pub uid:     u32,
+	pub gid:     u32,
+	pub nlink:   u64,
+	pub rdev:    u64,
+	pub size:    u64,
+	pub blksize: u64,
+	pub blocks:  u64,
+	pub atime:   (u64, u64),
+	pub mtime:   (u64, u64),
+	pub ctime:   (u64, u64)
+}
+
+impl Attr {
+	pub fn is_dir(&self) -> bool {
+		self.mode & S_IFMT == S_IFDIR
+	}
+}
+
+/// The attributes `TSETATTR` sets, those in `valid`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SetAttr {
+	pub valid: u32,
+	pub mode:  u32,
+	pub uid:   u32,
+	pub gid:   u32,
+	pub size:  u64,
+	pub atime: (u64, u64),
+	pub mtime: (u64, u64)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+	pub qid:    Qid,
+	/// Where `TREADDIR` continues after the entry
+	pub offset: u64,
+	/// `DT_*` of Linux
+	pub ty:     u8,
+	pub name:   String
+}
+
+/// Builds a message, the size is filled in by `finish`.
+pub struct Writer(Vec<u8>);
+
+impl Writer {
+	pub fn new(ty: u8, tag: u16) -> Self {
+		let mut buf = Vec::with_capacity(64);
+		buf.extend_from_slice(&[0; 4]);
+		buf.push(ty);
+		buf.extend_from_slice(&tag.to_le_bytes());
+		Self(buf)
+	}
+
+	pub fn u8(mut self, v: u8) -> Self {
+		self.0.push(v);
+		self
+	}
+
+	pub fn u16(mut self, v: u16) -> Self {
+		self.0.extend_from_slice(&v.to_le_bytes());
+		self
+	}
+
+	pub fn u32(mut self, v: u32) -> Self {
+		self.0.extend_from_slice(&v.to_le_bytes());
+		self
+	}
+
+	pub fn u64(mut self, v: u64) -> Self {
+		self.0.extend_from_slice(&v.to_le_bytes());
+		self
+	}
+
+	pub fn str(self, s: &str) -> Self {
+		self.u16(s.len() as u16).bytes(s.as_bytes())
+	}
+
+	pub fn bytes(mut self, b: &[u8]) -> Self {
+		self.0.extend_from_slice(b);
+		self
+	}
+
+	pub fn qid(self, q: &Qid) -> Self {
+		self.u8(q.ty).u32(q.version).u64(q.path)
+	}
+
+	pub fn finish(mut self) -> Vec<u8> {
+		let len = self.0.len() as u32;
+		self.0[..4].copy_from_slice(&len.to_le_bytes());
+		self.0
+	}
+}
+
+/// Reads the fields of a message, all fail with `Error::Protocol` past its end.
+pub struct Reader<'a> {
+	buf: &'a [u8],
+	pos: usize
+}
+
+impl<'a> Reader<'a> {
+	pub fn new(buf: &'a [u8]) -> Self {
+		Self { buf, pos: 0 }
+	}
+
+	pub fn remaining(&self) -> usize {
+		self.buf.len() - self.pos
+	}
+
+	pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+		let b = self.buf.get(self.pos..self.pos + len).ok_or(Error::Protocol)?;
+		self.pos += len;
+		Ok(b)
+	}
+
+	pub fn u8(&mut self) -> Result<u8> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	pub fn u16(&mut self) -> Result<u16> {
+		Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+	}
+
+	pub fn u32(&mut self) -> Result<u32> {
+		Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+	}
+
+	pub fn u64(&mut self) -> Result<u64> {
+		Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+	}
+
+	pub fn str(&mut self) -> Result<&'a str> {
+		let len = self.u16()? as usize;
+		core::str::from_utf8(self.bytes(len)?).map_err(|_| Error::Protocol)
+	}
+
+	pub fn qid(&mut self) -> Result<Qid> {
+		Ok(Qid { ty: self.u8()?, version: self.u32()?, path: self.u64()? })
+	}
+}
+
+/// Carries requests to a server.
+pub trait Channel {
+	/// Largest message, request or response, the channel carries
+	fn max_size(&self) -> usize;
+
+	/// Sends a request and waits for the response, returns its length.
+	fn request(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize>;
+}
@@ -0,0 +1,532 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Bus enumeration and resource assignment.
+//!
+//! `enumerate` walks a segment depth first, numbering the buses behind bridges as it
+//! goes, sizes the BARs and collects the capabilities. `assign` then places all BARs
+//! and bridge windows in the host bridge's windows, firmware assignments are not kept.
+
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use crate::acpi::aml::{AddressRangeKind, Resource};
+use super::{ConfigSpace, ExtCapabilityHeader, PciAddress, vendors};
+
+const REG_ID:             u16 = 0x00;
+const REG_COMMAND:        u16 = 0x04;
+const REG_CLASS:          u16 = 0x08;
+const REG_HEADER:         u16 = 0x0C;
+const REG_BAR0:           u16 = 0x10;
+const REG_SUBSYSTEM:      u16 = 0x2C;
+const REG_CAPS:           u16 = 0x34;
+const REG_INTERRUPT:      u16 = 0x3C;
+const REG_BUS_NUMBERS:    u16 = 0x18;
+const REG_IO_WINDOW:      u16 = 0x1C;
+const REG_MEMORY_WINDOW:  u16 = 0x20;
+const REG_PREF_WINDOW:    u16 = 0x24;
+const REG_PREF_BASE_U:    u16 = 0x28;
+const REG_PREF_LIMIT_U:   u16 = 0x2C;
+const REG_IO_WINDOW_U:    u16 = 0x30;
+const REG_EXT_CAPS:       u16 = 0x100;
+
+pub const COMMAND_IO_SPACE:     u16 = 0x1;
+pub const COMMAND_MEMORY_SPACE: u16 = 0x2;
+pub const COMMAND_BUS_MASTER:   u16 = 0x4;
+pub const COMMAND_INT_DISABLE:  u16 = 0x400;
+
+pub const STATUS_CAPABILITIES:  u16 = 0x10;
+
+pub const HEADER_TYPE_MASK:     u8 = 0x7F;
+pub const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+/// Bridges decode I/O in 4 KiB and memory in 1 MiB granules
+const IO_WINDOW_ALIGN:     u64 = 0x1000;
+const MEMORY_WINDOW_ALIGN: u64 = 0x10_0000;
+
+/// Guards against malformed, circular capability lists
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BarKind {
+	Io,
+	Memory32,
+	Memory64
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Bar {
+	pub kind:         BarKind,
+	pub prefetchable: bool,
+	/// Zero until assigned
+	pub address:      u64,
+	pub size:         u64
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CapabilityRef {
+	pub id:       u16,
+	/// Offset of the capability in the configuration space
+	pub offset:   u16,
+	/// Located in the extended configuration space
+	pub extended: bool
+}
+
+/// An inclusive range of addresses.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Window {
+	pub base:  u64,
+	pub limit: u64
+}
+
+impl Window {
+	pub fn size(&self) -> u64 {
+		self.limit - self.base + 1
+	}
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BridgeWindows {
+	pub secondary_bus:   u8,
+	pub subordinate_bus: u8,
+	pub io:              Option<Window>,
+	pub memory:          Option<Window>,
+	pub prefetchable:    Option<Window>
+}
+
+#[derive(Clone, Debug)]
+pub struct Device {
+	pub address:          PciAddress,
+	pub vendor_id:        u16,
+	pub device_id:        u16,
+	pub vendor_name:      &'static str,
+	pub class_code:       u8,
+	pub subclass:         u8,
+	pub prog_if:          u8,
+	pub revision_id:      u8,
+	pub header_type:      u8,
+	pub subsys_vendor_id: u16,
+	pub subsys_id:        u16,
+	/// INTA# to INTD# as 1 to 4, zero if the function doesn't use a pin
+	pub interrupt_pin:    u8,
+	/// The upper half of a 64-bit BAR occupies the following slot, which is `None`
+	pub bars:             [Option<Bar>; 6],
+	pub capabilities:     Vec<CapabilityRef>,
+	/// Only set for PCI-to-PCI bridges
+	pub bridge:           Option<BridgeWindows>,
+	/// The functions on the secondary bus of a bridge
+	pub children:         Vec<Device>
+}
+
+impl Device {
+	pub fn is_bridge(&self) -> bool {
+		self.bridge.is_some()
+	}
+
+	/// Returns the offset of the first capability with the given id.
+	pub fn capability(&self, id: u8) -> Option<u16> {
+		self.capabilities.iter().find(|c| !c.extended && c.id == id as u16).map(|c| c.offset)
+	}
+
+	/// Returns the offset of the first extended capability with the given id.
+	pub fn ext_capability(&self, id: u16) -> Option<u16> {
+		self.capabilities.iter().find(|c| c.extended && c.id == id).map(|c| c.offset)
+	}
+
+	/// Iterates over this function and all functions behind it, depth first.
+	pub fn iter(&self) -> DeviceIter<'_> {
+		DeviceIter(alloc::vec![core::slice::from_ref(self)])
+	}
+}
+
+/// Iterates depth first over a tree of devices.
+pub struct DeviceIter<'a>(Vec<&'a [Device]>);
+
+impl<'a> DeviceIter<'a> {
+	pub fn new(devices: &'a [Device]) -> Self {
+		Self(alloc::vec![devices])
+	}
+}
+
+impl<'a> Iterator for DeviceIter<'a> {
+	type Item = &'a Device;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let level = self.0.last_mut()?;
+			match level.split_first() {
+				Some((device, rest)) => {
+					*level = rest;
+					if !device.children.is_empty() {
+						self.0.push(&device.children);
+					}
+					return Some(device);
+				}
+				None => { self.0.pop(); }
+			}
+		}
+	}
+}
+
+/// The windows of a host bridge that BARs and bridge windows are allocated from.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Resources {
+	pub io:           Option<Window>,
+	/// Below 4 GiB, used for all non-prefetchable BARs
+	pub memory:       Option<Window>,
+	/// Used for prefetchable BARs, falls back to `memory` if there is none
+	pub prefetchable: Option<Window>
+}
+
+impl Resources {
+	/// Picks the largest I/O window, the largest memory window below 4 GiB and the
+	/// largest one above from the current resource settings (`_CRS`) of a host bridge.
+	pub fn from_resources(resources: &[Resource]) -> Self {
+		let mut v = Self::default();
+
+		for r in resources {
+			let (kind, window) = match r {
+				Resource::AddressRange(r) if r.len > 0 =>
+					(r.kind, Window { base: r.min + r.translation, limit: r.min + r.translation + r.len - 1 }),
+				_ => continue
+			};
+
+			let slot = match kind {
+				AddressRangeKind::Io                                       => &mut v.io,
+				AddressRangeKind::Memory if window.limit <= u32::MAX as u64 => &mut v.memory,
+				AddressRangeKind::Memory                                   => &mut v.prefetchable,
+				_ => continue
+			};
+
+			if slot.map_or(true, |w| w.size() < window.size()) {
+				*slot = Some(window);
+			}
+		}
+
+		v
+	}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AssignError {
+	/// A BAR didn't fit into the host bridge's windows, it is left unassigned
+	Exhausted { address: PciAddress, bar: usize }
+}
+
+/// Enumerates the functions on `buses` of a segment. Buses behind bridges are numbered
+/// consecutively from the first bus on.
+pub fn enumerate(cfg: &mut impl ConfigSpace, segment: u16, buses: RangeInclusive<u8>) -> Vec<Device> {
+	let mut next = *buses.start();
+	scan_bus(cfg, segment, *buses.start(), *buses.end(), &mut next)
+}
+
+fn scan_bus(cfg: &mut impl ConfigSpace, segment: u16, bus: u8, last: u8, next: &mut u8) -> Vec<Device> {
+	let mut devices = Vec::new();
+
+	for device in 0..32 {
+		for function in 0..8 {
+			let address = PciAddress { segment, bus, device, function };
+			let id = cfg.read(address, REG_ID);
+
+			if id as u16 == 0xFFFF {
+				if function == 0 { break; } else { continue; }
+			}
+
+			let header = (cfg.read(address, REG_HEADER) >> 16) as u8;
+			devices.push(probe(cfg, address, id, header, last, next));
+
+			if function == 0 && header & HEADER_MULTIFUNCTION == 0 {
+				break;
+			}
+		}
+	}
+
+	devices
+}
+
+fn probe(cfg: &mut impl ConfigSpace, address: PciAddress, id: u32, header: u8, last: u8, next: &mut u8) -> Device {
+	let class = cfg.read(address, REG_CLASS);
+	let is_bridge = header & HEADER_TYPE_MASK == 1;
+	let subsystem = if is_bridge { 0 } else { cfg.read(address, REG_SUBSYSTEM) };
+
+	let mut device = Device {
+		address,
+		vendor_id:        id as u16,
+		device_id:        (id >> 16) as u16,
+		vendor_name:      vendors::get_name(id as u16),
+		class_code:       (class >> 24) as u8,
+		subclass:         (class >> 16) as u8,
+		prog_if:          (class >> 8) as u8,
+		revision_id:      class as u8,
+		header_type:      header,
+		subsys_vendor_id: subsystem as u16,
+		subsys_id:        (subsystem >> 16) as u16,
+		interrupt_pin:    (cfg.read(address, REG_INTERRUPT) >> 8) as u8,
+		bars:             [None; 6],
+		capabilities:     Vec::new(),
+		bridge:           None,
+		children:         Vec::new()
+	};
+
+	size_bars(cfg, &mut device, if is_bridge { 2 } else { 6 });
+	device.capabilities = capabilities(cfg, address);
+
+	if is_bridge && *next < last {
+		*next += 1;
+		let secondary = *next;
+
+		// let the bridge forward all remaining buses until the ones behind it are known
+		let numbers = cfg.read(address, REG_BUS_NUMBERS) & 0xFF00_0000;
+		cfg.write(address, REG_BUS_NUMBERS, numbers | (last as u32) << 16 | (secondary as u32) << 8 | address.bus as u32);
+		device.children = scan_bus(cfg, address.segment, secondary, last, next);
+		cfg.write(address, REG_BUS_NUMBERS, numbers | (*next as u32) << 16 | (secondary as u32) << 8 | address.bus as u32);
+
+		device.bridge = Some(BridgeWindows { secondary_bus: secondary, subordinate_bus: *next, ..BridgeWindows::default() });
+	} else if is_bridge {
+		device.bridge = Some(BridgeWindows::default());
+	}
+
+	device
+}
+
+fn size_bars(cfg: &mut impl ConfigSpace, device: &mut Device, count: usize) {
+	let address = device.address;
+
+	// decoding has to be off while the BARs hold the sizing pattern
+	let command = cfg.read(address, REG_COMMAND);
+	cfg.write(address, REG_COMMAND, command & !((COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE) as u32));
+
+	let mut i = 0;
+	while i < count {
+		let reg = REG_BAR0 + i as u16 * 4;
+		let orig = cfg.read(address, reg);
+		cfg.write(address, reg, !0);
+		let mask = cfg.read(address, reg);
+		cfg.write(address, reg, orig);
+
+		let bar = if orig & 1 == 1 {
+			// I/O BARs may only implement the lower 16 bits
+			let mask = mask & !0x3 | if mask >> 16 == 0 { 0xFFFF_0000 } else { 0 };
+			(mask & !0x3 != 0).then(|| Bar { kind: BarKind::Io, prefetchable: false, address: 0, size: (!mask).wrapping_add(1) as u64 })
+		} else if orig & 0x6 == 0x4 && i + 1 < count {
+			let orig_u = cfg.read(address, reg + 4);
+			cfg.write(address, reg + 4, !0);
+			let mask = (cfg.read(address, reg + 4) as u64) << 32 | (mask & !0xF) as u64;
+			cfg.write(address, reg + 4, orig_u);
+
+			i += 1;
+			(mask != 0).then(|| Bar { kind: BarKind::Memory64, prefetchable: orig & 0x8 != 0, address: 0, size: (!mask).wrapping_add(1) })
+		} else {
+			let mask = mask & !0xF;
+			(mask != 0).then(|| Bar { kind: BarKind::Memory32, prefetchable: orig & 0x8 != 0, address: 0, size: (!mask).wrapping_add(1) as u64 })
+		};
+
+		let slot = if matches!(bar, Some(Bar { kind: BarKind::Memory64, .. })) { i - 1 } else { i };
+		device.bars[slot] = bar;
+		i += 1;
+	}
+
+	cfg.write(address, REG_COMMAND, command);
+}
+
+fn capabilities(cfg: &mut impl ConfigSpace, address: PciAddress) -> Vec<CapabilityRef> {
+	let mut caps = Vec::new();
+
+	if (cfg.read(address, REG_COMMAND) >> 16) as u16 & STATUS_CAPABILITIES != 0 {
+		let mut ptr = cfg.read(address, REG_CAPS) as u8 & 0xFC;
+
+		while ptr >= 0x40 && caps.len() < MAX_CAPABILITIES {
+			let header = cfg.read(address, ptr as u16);
+			caps.push(CapabilityRef { id: header as u8 as u16, offset: ptr as u16, extended: false });
+			ptr = (header >> 8) as u8 & 0xFC;
+		}
+	}
+
+	// conventional PCI functions and mechanisms without the extended space read all ones or zero
+	let mut ptr = REG_EXT_CAPS;
+	let mut n   = 0;
+
+	while ptr >= REG_EXT_CAPS && n < MAX_CAPABILITIES {
+		let header = cfg.read(address, ptr);
+		if header == 0 || header == !0 {
+			break;
+		}
+
+		let header = ExtCapabilityHeader(header);
+		caps.push(CapabilityRef { id: header.get_id() as u16, offset: ptr, extended: true });
+		ptr = header.get_next() as u16 & 0xFFC;
+		n += 1;
+	}
+
+	caps
+}
+
+/// Assigns addresses to all BARs and bridge windows and enables decoding. Bridges
+/// additionally get bus mastering enabled so their downstream functions can DMA,
+/// for all other functions that is left to the drivers.
+///
+/// BARs that don't fit are left unassigned, the first of them is returned as error.
+pub fn assign(cfg: &mut impl ConfigSpace, devices: &mut [Device], resources: &Resources) -> Result<(), AssignError> {
+	let mut alloc = Allocators {
+		io:           resources.io.map(Allocator::new),
+		memory:       resources.memory.map(Allocator::new),
+		prefetchable: resources.prefetchable.map(Allocator::new),
+		error:        None
+	};
+
+	alloc.bus(cfg, devices);
+	alloc.error.map_or(Ok(()), Err)
+}
+
+#[derive(Copy, Clone)]
+struct Allocator {
+	next:  u64,
+	limit: u64
+}
+
+impl Allocator {
+	fn new(window: Window) -> Self {
+		Self { next: window.base, limit: window.limit }
+	}
+
+	fn alloc(&mut self, size: u64, align: u64) -> Option<u64> {
+		let base = self.next.checked_add(align - 1)? & !(align - 1);
+		let end = base.checked_add(size)?;
+		(end - 1 <= self.limit).then(|| { self.next = end; base })
+	}
+
+	/// Rounds the next address up to `align`, returns the window allocated since `start`.
+	fn close(&mut self, start: u64, align: u64) -> Option<Window> {
+		if self.next == start {
+			return None;
+		}
+
+		self.next = ((self.next + align - 1) & !(align - 1)).min(self.limit.saturating_add(1));
+		Some(Window { base: start, limit: self.next - 1 })
+	}
+
+	fn open(&mut self, align: u64) -> u64 {
+		self.next = (self.next + align - 1) & !(align - 1);
+		self.next
+	}
+}
+
+struct Allocators {
+	io:           Option<Allocator>,
+	memory:       Option<Allocator>,
+	prefetchable: Option<Allocator>,
+	error:        Option<AssignError>
+}
+
+impl Allocators {
+	fn bus(&mut self, cfg: &mut impl ConfigSpace, devices: &mut [Device]) {
+		// bridges first, their windows are the most aligned
+		for device in devices.iter_mut().filter(|d| d.is_bridge()) {
+			self.bridge(cfg, device);
+		}
+
+		// then the largest BARs first to keep the gaps small
+		let mut bars = devices.iter().enumerate()
+			.flat_map(|(i, d)| d.bars.iter().enumerate().filter_map(move |(j, b)| b.map(|b| (i, j, b.size))))
+			.collect::<Vec<_>>();
+		bars.sort_by_key(|(i, j, size)| (core::cmp::Reverse(*size), *i, *j));
+
+		for (i, j, _) in bars {
+			self.bar(cfg, &mut devices[i], j);
+		}
+
+		for device in devices.iter() {
+			let mut command = (cfg.read(device.address, REG_COMMAND) & 0xFFFF) as u16;
+
+			for bar in device.bars.iter().flatten().filter(|b| b.address != 0) {
+				command |= if bar.kind == BarKind::Io { COMMAND_IO_SPACE } else { COMMAND_MEMORY_SPACE };
+			}
+
+			if let Some(bridge) = &device.bridge {
+				if bridge.io.is_some() { command |= COMMAND_IO_SPACE; }
+				if bridge.memory.is_some() || bridge.prefetchable.is_some() { command |= COMMAND_MEMORY_SPACE; }
+				command |= COMMAND_BUS_MASTER;
+			}
+
+			let status = cfg.read(device.address, REG_COMMAND) & 0xFFFF_0000;
+			cfg.write(device.address, REG_COMMAND, status | command as u32);
+		}
+	}
+
+	fn bar(&mut self, cfg: &mut impl ConfigSpace, device: &mut Device, i: usize) {
+		let bar = device.bars[i].as_mut().unwrap();
+		let alloc = match bar.kind {
+			BarKind::Io => self.io.as_mut(),
+			// 32-bit BARs can't use a window above 4 GiB
+			_ if bar.prefetchable && (bar.kind == BarKind::Memory64 || self.prefetchable.map_or(false, |a| a.limit <= u32::MAX as u64))
+				=> self.prefetchable.as_mut().or(self.memory.as_mut()),
+			_ => self.memory.as_mut()
+		};
+
+		match alloc.and_then(|a| a.alloc(bar.size, bar.size)) {
+			Some(address) => bar.address = address,
+			None => {
+				self.error.get_or_insert(AssignError::Exhausted { address: device.address, bar: i });
+				return;
+			}
+		}
+
+		let reg = REG_BAR0 + i as u16 * 4;
+		let flags = cfg.read(device.address, reg) & if bar.kind == BarKind::Io { 0x3 } else { 0xF };
+		cfg.write(device.address, reg, bar.address as u32 | flags);
+		if bar.kind == BarKind::Memory64 {
+			cfg.write(device.address, reg + 4, (bar.address >> 32) as u32);
+		}
+	}
+
+	fn bridge(&mut self, cfg: &mut impl ConfigSpace, device: &mut Device) {
+		let io_start   = self.io.as_mut().map(|a| a.open(IO_WINDOW_ALIGN));
+		let mem_start  = self.memory.as_mut().map(|a| a.open(MEMORY_WINDOW_ALIGN));
+		let pref_start = self.prefetchable.as_mut().map(|a| a.open(MEMORY_WINDOW_ALIGN));
+
+		self.bus(cfg, &mut device.children);
+
+		let io   = self.io.as_mut().zip(io_start).and_then(|(a, s)| a.close(s, IO_WINDOW_ALIGN));
+		let mem  = self.memory.as_mut().zip(mem_start).and_then(|(a, s)| a.close(s, MEMORY_WINDOW_ALIGN));
+		let pref = self.prefetchable.as_mut().zip(pref_start).and_then(|(a, s)| a.close(s, MEMORY_WINDOW_ALIGN));
+
+		// an empty window is disabled by programming its base above its limit
+		let address = device.address;
+		let (io_base, io_limit) = io.map_or((0xF000, 0), |w| (w.base, w.limit));
+		let secondary_status = cfg.read(address, REG_IO_WINDOW) & 0xFFFF_0000;
+		cfg.write(address, REG_IO_WINDOW, secondary_status | (io_limit as u32 >> 8 & 0xF0) << 8 | (io_base as u32 >> 8 & 0xF0));
+		cfg.write(address, REG_IO_WINDOW_U, (io_limit as u32 >> 16) << 16 | (io_base as u32 >> 16));
+
+		let (mem_base, mem_limit) = mem.map_or((0xFFF0_0000, 0), |w| (w.base, w.limit));
+		cfg.write(address, REG_MEMORY_WINDOW, (mem_limit as u32 >> 16 & 0xFFF0) << 16 | (mem_base as u32 >> 16 & 0xFFF0));
+
+		let (pref_base, pref_limit) = pref.map_or((0xFFF0_0000, 0), |w| (w.base, w.limit));
+		cfg.write(address, REG_PREF_WINDOW, (pref_limit as u32 >> 16 & 0xFFF0) << 16 | (pref_base as u32 >> 16 & 0xFFF0) | 0x1_0001);
+		cfg.write(address, REG_PREF_BASE_U, (pref_base >> 32) as u32);
+		cfg.write(address, REG_PREF_LIMIT_U, (pref_limit >> 32) as u32);
+
+		if let Some(bridge) = device.bridge.as_mut() {
+			bridge.io           = io;
+			bridge.memory       = mem;
+			bridge.prefetchable = pref;
+		}
+	}
+}
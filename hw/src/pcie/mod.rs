@@ -2,9 +2,92 @@
 use super::*;
 
 pub mod vendors;
+mod enumerate;
+mod msi;
+
+pub use {enumerate::*, msi::*};
 
 pub type PciEcam = [[[[u8; 0x1000]; 8]; 32]; 256];
 
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PciAddress {
+	pub segment:  u16,
+	pub bus:      u8,
+	pub device:   u8,
+	pub function: u8
+}
+
+impl core::fmt::Display for PciAddress {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+	}
+}
+
+/// Access to the configuration space of the functions in a segment group. Offsets are
+/// dword aligned, reads from functions that don't exist return all ones.
+pub trait ConfigSpace {
+	fn read(&mut self, address: PciAddress, offset: u16) -> u32;
+
+	fn write(&mut self, address: PciAddress, offset: u16, value: u32);
+}
+
+/// Enhanced configuration access mechanism, the memory mapped configuration space of
+/// a segment group as listed in the MCFG.
+#[derive(Debug)]
+pub struct Ecam {
+	base:      *mut u8,
+	segment:   u16,
+	start_bus: u8,
+	end_bus:   u8
+}
+
+impl Ecam {
+	/// `base` is where the window starting at the configuration space of bus 0 is
+	/// mapped, even if the segment starts at a later bus.
+	///
+	/// # Safety
+	///
+	/// The configuration space of all buses in the range must be mapped.
+	pub unsafe fn new(base: *mut u8, segment: u16, start_bus: u8, end_bus: u8) -> Self {
+		Self { base, segment, start_bus, end_bus }
+	}
+
+	/// # Safety
+	///
+	/// See `new`, `base` maps `entry.address`.
+	pub unsafe fn from_mcfg(entry: &crate::acpi::McfgEntry, base: *mut u8) -> Self {
+		Self::new(base, entry.pci_segment, entry.start_bus_number, entry.end_bus_number)
+	}
+
+	pub fn segment(&self) -> u16 {
+		self.segment
+	}
+
+	pub fn buses(&self) -> core::ops::RangeInclusive<u8> {
+		self.start_bus..=self.end_bus
+	}
+
+	fn ptr(&self, address: PciAddress, offset: u16) -> Option<*mut u32> {
+		(address.segment == self.segment && self.buses().contains(&address.bus)
+			&& address.device < 32 && address.function < 8 && offset < 0x1000).then(|| unsafe {
+			self.base.add((address.bus as usize) << 20 | (address.device as usize) << 15
+				| (address.function as usize) << 12 | (offset & !3) as usize) as *mut u32
+		})
+	}
+}
+
+impl ConfigSpace for Ecam {
+	fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+		self.ptr(address, offset).map_or(!0, |ptr| unsafe { ptr.read_volatile() })
+	}
+
+	fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+		if let Some(ptr) = self.ptr(address, offset) {
+			unsafe { ptr.write_volatile(value) }
+		}
+	}
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub union ConfigurationSpaceHeader {
@@ -49,15 +132,18 @@ pub struct ConfigurationSpaceHeaderType0 {
 	pub _pad1:       u8,
 	pub header_type: u8,
 	pub bist:        u8,
-	pub bar0:        u64,
-	pub bar1:        u64,
-	pub bar2:        u64,
+	pub bars:        [u32; 6],
 	pub cardbus_cis_ptr: u32,
 	pub subsys_vendor_id: u16,
 	pub subsys_id:   u16,
-	pub  ext_rom_base_addr: u32,
+	pub ext_rom_base_addr: u32,
+	pub caps_ptr:    u8,
 	pub _pad3:       [u8; 3],
-	pub _pad4:       [u32; 2]
+	pub _pad4:       u32,
+	pub interrupt_line: u8,
+	pub interrupt_pin: u8,
+	pub min_grant:   u8,
+	pub max_latency: u8
 }
 
 #[repr(C)]
@@ -75,7 +161,7 @@ pub struct ConfigurationSpaceHeaderType1 {
 	pub _pad1:                       u8,
 	pub header_type:                 u8,
 	pub bist:                        u8,
-	pub bar0:                        u64,
+	pub bars:                        [u32; 2],
 	pub primary_bus_number:          u8,
 	pub secondary_bus_number:        u8,
 	pub subordinate_bus_number:      u8,
@@ -93,8 +179,10 @@ pub struct ConfigurationSpaceHeaderType1 {
 	pub io_limit_u:                  u16,
 	pub caps_ptr:                    u8,
 	pub _pad3:                       [u8; 3],
+	pub ext_rom_base_addr:           u32,
+	pub interrupt_line:              u8,
+	pub interrupt_pin:               u8,
 	pub bridge_control:              u16,
-	pub _pad4:                       [u8; 2],
 }
 
 #[repr(C)]
@@ -116,8 +204,29 @@ pub struct CapabilityHeader {
 }
 
 impl CapabilityHeader {
-	const ID_MSI: u8 = 0x05;
-	const ID_MSI_X: u8 = 0x11;
+	pub const ID_POWER_MANAGEMENT: u8 = 0x01;
+	pub const ID_MSI:              u8 = 0x05;
+	pub const ID_VENDOR_SPECIFIC:  u8 = 0x09;
+	pub const ID_PCIE:             u8 = 0x10;
+	pub const ID_MSI_X:            u8 = 0x11;
+}
+
+/// Header of the capabilities in the extended configuration space, starting at offset
+/// 0x100.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ExtCapabilityHeader(pub u32);
+
+impl ExtCapabilityHeader {
+	pub const ID_AER:            u16 = 0x0001;
+	pub const ID_SERIAL_NUMBER:  u16 = 0x0003;
+	pub const ID_SRIOV:          u16 = 0x0010;
+
+	define_bits!(
+		RO 0..15,  get_id;
+		RO 16..19, get_version;
+		RO 20..31, get_next;
+	);
 }
 
 #[repr(C)]
@@ -169,7 +278,7 @@ pub struct MsiCapabilityMessageControl(u16);
 impl MsiCapabilityMessageControl {
 	define_bits!(
 		RW 0,    get_msi_enable, set_msi_enable;
-		RO 1..3, get_multiple_message_capable, set_multiple_message_capable;
+		RO 1..3, get_multiple_message_capable;
 		RW 4..6, get_multiple_message_enable, set_multiple_message_enable;
 		RO 7,    get_64bit_address_capable;
 		RO 8,    get_per_vector_masking_capable;
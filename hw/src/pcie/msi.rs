@@ -0,0 +1,312 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Programming of the MSI and MSI-X capabilities.
+//!
+//! The message written by the function is composed by the interrupt controller of the
+//! target hart, see the constructors of `MsiMessage`.
+
+use super::{CapabilityHeader, ConfigSpace, Device, MsiXTableEntry, PciAddress, COMMAND_INT_DISABLE};
+
+const REG_COMMAND: u16 = 0x04;
+
+const MSI_CONTROL_ENABLE:     u16 = 0x1;
+const MSI_CONTROL_MMC_SHIFT:  u16 = 1;
+const MSI_CONTROL_MME_SHIFT:  u16 = 4;
+const MSI_CONTROL_MME_MASK:   u16 = 0x70;
+const MSI_CONTROL_64BIT:      u16 = 0x80;
+const MSI_CONTROL_PER_VECTOR: u16 = 0x100;
+
+const MSIX_CONTROL_SIZE_MASK: u16 = 0x7FF;
+const MSIX_CONTROL_MASK_ALL:  u16 = 0x4000;
+const MSIX_CONTROL_ENABLE:    u16 = 0x8000;
+
+const MSIX_VECTOR_MASKED:     u32 = 0x1;
+
+/// The address/data pair a function writes to signal an interrupt.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MsiMessage {
+	pub address: u64,
+	pub data:    u32
+}
+
+impl MsiMessage {
+	/// Offset of `GITS_TRANSLATER` in the ITS register frame
+	pub const GITS_TRANSLATER: u64 = 0x1_0040;
+
+	/// Fixed, edge triggered delivery of `vector` to the local APIC `apic_id` in physical
+	/// destination mode. IDs above 255 use the extended destination ID in address bits
+	/// 11:5, IDs it can't encode require interrupt remapping and return `None`, as do
+	/// the reserved vectors below 16.
+	pub fn x2apic(apic_id: u32, vector: u8) -> Option<Self> {
+		(apic_id < 0x8000 && vector >= 0x10).then_some(Self {
+			address: 0xFEE0_0000 | ((apic_id & 0xFF) << 12 | (apic_id >> 8) << 5) as u64,
+			data:    vector as u32
+		})
+	}
+
+	/// Writes `event_id` to the translation register of a GICv3 ITS. The ITS maps the
+	/// event of the requester to an LPI and the collection of the target hart, see
+	/// `GicIts::map`.
+	pub fn gic_its(its_base: u64, event_id: u32) -> Self {
+		Self { address: its_base + Self::GITS_TRANSLATER, data: event_id }
+	}
+
+	/// Sets the interrupt `identity` in the supervisor interrupt file of a RISC-V
+	/// IMSIC, the file's `seteipnum_le` register is at its base.
+	pub fn imsic(interrupt_file: u64, identity: u32) -> Self {
+		Self { address: interrupt_file, data: identity }
+	}
+}
+
+/// The MSI capability of a function, all vectors share one message whose data is
+/// incremented for each vector.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Msi {
+	pub address: PciAddress,
+	pub offset:  u16
+}
+
+impl Msi {
+	pub fn new(device: &Device) -> Option<Self> {
+		device.capability(CapabilityHeader::ID_MSI).map(|offset| Self { address: device.address, offset })
+	}
+
+	fn control(&self, cfg: &mut impl ConfigSpace) -> u16 {
+		(cfg.read(self.address, self.offset) >> 16) as u16
+	}
+
+	fn set_control(&self, cfg: &mut impl ConfigSpace, control: u16) {
+		let header = cfg.read(self.address, self.offset) & 0xFFFF;
+		cfg.write(self.address, self.offset, header | (control as u32) << 16);
+	}
+
+	/// Offsets of the data, mask and pending registers, which depend on the address size.
+	fn regs(&self, control: u16) -> (u16, u16, u16) {
+		match control & MSI_CONTROL_64BIT != 0 {
+			true  => (self.offset + 0xC, self.offset + 0x10, self.offset + 0x14),
+			false => (self.offset + 0x8, self.offset + 0xC, self.offset + 0x10)
+		}
+	}
+
+	/// The number of vectors the function requests, a power of two up to 32.
+	pub fn vectors(&self, cfg: &mut impl ConfigSpace) -> u8 {
+		1 << ((self.control(cfg) >> MSI_CONTROL_MMC_SHIFT) & 0x7).min(5)
+	}
+
+	pub fn is_64bit(&self, cfg: &mut impl ConfigSpace) -> bool {
+		self.control(cfg) & MSI_CONTROL_64BIT != 0
+	}
+
+	/// Enables `count` vectors, which is rounded down to a power of two the function
+	/// supports. Vector `i` sends `message.data + i`, so the data has to be aligned to
+	/// the count. Returns the number of enabled vectors, zero if the address doesn't
+	/// fit into a 32-bit capability. INTx is disabled.
+	pub fn enable(&self, cfg: &mut impl ConfigSpace, message: MsiMessage, count: u8) -> u8 {
+		let control = self.control(cfg);
+		if count == 0 || message.address >> 32 != 0 && control & MSI_CONTROL_64BIT == 0 {
+			return 0;
+		}
+
+		let count = (1 << (7 - count.leading_zeros())).min(self.vectors(cfg));
+		let (data, ..) = self.regs(control);
+
+		self.set_control(cfg, control & !MSI_CONTROL_ENABLE);
+		cfg.write(self.address, self.offset + 4, message.address as u32);
+		if control & MSI_CONTROL_64BIT != 0 {
+			cfg.write(self.address, self.offset + 8, (message.address >> 32) as u32);
+		}
+
+		let reg = cfg.read(self.address, data) & 0xFFFF_0000;
+		cfg.write(self.address, data, reg | message.data & 0xFFFF);
+
+		disable_intx(cfg, self.address);
+		self.set_control(cfg, control & !MSI_CONTROL_MME_MASK
+			| (count.trailing_zeros() as u16) << MSI_CONTROL_MME_SHIFT | MSI_CONTROL_ENABLE);
+		count
+	}
+
+	pub fn disable(&self, cfg: &mut impl ConfigSpace) {
+		let control = self.control(cfg);
+		self.set_control(cfg, control & !MSI_CONTROL_ENABLE);
+	}
+
+	/// Masks or unmasks a vector, returns false if the function doesn't support
+	/// per-vector masking.
+	pub fn mask(&self, cfg: &mut impl ConfigSpace, vector: u8, masked: bool) -> bool {
+		let control = self.control(cfg);
+		if control & MSI_CONTROL_PER_VECTOR == 0 || vector >= 32 {
+			return false;
+		}
+
+		let (_, mask, _) = self.regs(control);
+		let bits = cfg.read(self.address, mask);
+		cfg.write(self.address, mask, if masked { bits | 1 << vector } else { bits & !(1 << vector) });
+		true
+	}
+
+	pub fn is_pending(&self, cfg: &mut impl ConfigSpace, vector: u8) -> bool {
+		let control = self.control(cfg);
+		let (.., pending) = self.regs(control);
+		control & MSI_CONTROL_PER_VECTOR != 0 && vector < 32 && cfg.read(self.address, pending) & 1 << vector != 0
+	}
+}
+
+/// The MSI-X capability of a function, each vector has its own message in a table
+/// located in one of the function's memory BARs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MsiX {
+	pub address:      PciAddress,
+	pub offset:       u16,
+	/// Number of vectors
+	pub size:         u16,
+	pub table_bar:    u8,
+	pub table_offset: u32,
+	pub pba_bar:      u8,
+	pub pba_offset:   u32
+}
+
+impl MsiX {
+	pub fn new(cfg: &mut impl ConfigSpace, device: &Device) -> Option<Self> {
+		let offset = device.capability(CapabilityHeader::ID_MSI_X)?;
+		let control = (cfg.read(device.address, offset) >> 16) as u16;
+		let table = cfg.read(device.address, offset + 4);
+		let pba = cfg.read(device.address, offset + 8);
+
+		Some(Self {
+			address:      device.address,
+			offset,
+			size:         (control & MSIX_CONTROL_SIZE_MASK) + 1,
+			table_bar:    (table & 0x7) as u8,
+			table_offset: table & !0x7,
+			pba_bar:      (pba & 0x7) as u8,
+			pba_offset:   pba & !0x7
+		})
+	}
+
+	fn set_control(&self, cfg: &mut impl ConfigSpace, set: u16, clear: u16) {
+		let reg = cfg.read(self.address, self.offset);
+		let control = ((reg >> 16) as u16 | set) & !clear;
+		cfg.write(self.address, self.offset, reg & 0xFFFF | (control as u32) << 16);
+	}
+
+	/// Enables MSI-X with all vectors masked by the function mask, which `unmask_all`
+	/// lifts once the table is set up. INTx is disabled.
+	pub fn enable(&self, cfg: &mut impl ConfigSpace) {
+		disable_intx(cfg, self.address);
+		self.set_control(cfg, MSIX_CONTROL_ENABLE | MSIX_CONTROL_MASK_ALL, 0);
+	}
+
+	pub fn disable(&self, cfg: &mut impl ConfigSpace) {
+		self.set_control(cfg, 0, MSIX_CONTROL_ENABLE);
+	}
+
+	pub fn mask_all(&self, cfg: &mut impl ConfigSpace) {
+		self.set_control(cfg, MSIX_CONTROL_MASK_ALL, 0);
+	}
+
+	pub fn unmask_all(&self, cfg: &mut impl ConfigSpace) {
+		self.set_control(cfg, 0, MSIX_CONTROL_MASK_ALL);
+	}
+
+	/// Returns the vector table given where the BARs it resides in are mapped.
+	///
+	/// # Safety
+	///
+	/// `table_bar` and `pba_bar` must map the BARs `self.table_bar` and `self.pba_bar`.
+	pub unsafe fn table(&self, table_bar: *mut u8, pba_bar: *mut u8) -> MsiXTable {
+		MsiXTable {
+			entries: table_bar.add(self.table_offset as usize) as *mut MsiXTableEntry,
+			pba:     pba_bar.add(self.pba_offset as usize) as *const u64,
+			size:    self.size
+		}
+	}
+}
+
+/// The memory mapped vector table and pending bit array of an MSI-X capability.
+#[derive(Debug)]
+pub struct MsiXTable {
+	entries: *mut MsiXTableEntry,
+	pba:     *const u64,
+	size:    u16
+}
+
+impl MsiXTable {
+	pub fn len(&self) -> usize {
+		self.size as usize
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.size == 0
+	}
+
+	fn entry(&self, vector: u16) -> *mut u32 {
+		assert!(vector < self.size, "MSI-X vector {} out of range", vector);
+		unsafe { self.entries.add(vector as usize) as *mut u32 }
+	}
+
+	/// Sets the message of a vector, the vector is masked while it is updated and
+	/// remains masked.
+	pub fn set(&mut self, vector: u16, message: MsiMessage) {
+		let entry = self.entry(vector);
+		unsafe {
+			let control = entry.add(3).read_volatile();
+			entry.add(3).write_volatile(control | MSIX_VECTOR_MASKED);
+			entry.add(0).write_volatile(message.address as u32);
+			entry.add(1).write_volatile((message.address >> 32) as u32);
+			entry.add(2).write_volatile(message.data);
+		}
+	}
+
+	pub fn get(&self, vector: u16) -> MsiMessage {
+		let entry = self.entry(vector);
+		unsafe {
+			MsiMessage {
+				address: entry.add(0).read_volatile() as u64 | (entry.add(1).read_volatile() as u64) << 32,
+				data:    entry.add(2).read_volatile()
+			}
+		}
+	}
+
+	pub fn mask(&mut self, vector: u16, masked: bool) {
+		let entry = self.entry(vector);
+		unsafe {
+			let control = entry.add(3).read_volatile();
+			entry.add(3).write_volatile(if masked { control | MSIX_VECTOR_MASKED } else { control & !MSIX_VECTOR_MASKED });
+		}
+	}
+
+	pub fn is_masked(&self, vector: u16) -> bool {
+		unsafe { self.entry(vector).add(3).read_volatile() & MSIX_VECTOR_MASKED != 0 }
+	}
+
+	pub fn is_pending(&self, vector: u16) -> bool {
+		assert!(vector < self.size, "MSI-X vector {} out of range", vector);
+		unsafe { self.pba.add(vector as usize / 64).read_volatile() & 1 << (vector % 64) != 0 }
+	}
+}
+
+fn disable_intx(cfg: &mut impl ConfigSpace, address: PciAddress) {
+	let command = cfg.read(address, REG_COMMAND);
+	cfg.write(address, REG_COMMAND, command & 0xFFFF | COMMAND_INT_DISABLE as u32);
+}
@@ -12,7 +12,8 @@ pub fn get_name(id: u16) -> &'static str {
 
 static VENDOR_UNKNOWN: &str = "UNKNOWN VENDOR";
 
-static VENDORS: [(u16, &str); 978] = [
+// sorted by id, `try_get_name` relies on it
+static VENDORS: [(u16, &str); 959] = [
 	(0x0014, "Loongson Technology Corporation Limited"),
 	(0x001C, "PEAK-System Technik GmbH"),
 	(0x00B0, "Blue Origin, LLC"),
@@ -20,84 +21,31 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x0123, "General Dynamics Mission Systems, Inc."),
 	(0x012E, "OLZETEK"),
 	(0x01B8, "Sintela Ltd"),
+	(0x01DE, "Oxide Computer Company"),
 	(0x0222, "Not for Radio, LLC"),
 	(0x0303, "DAQ System"),
-	(0x2782, "Emdoor Digital Technology Co., Ltd"),
-	(0x27D1, "Angelbird Technologies GmbH"),
 	(0x0488, "Cirque Corporation"),
-	(0x301F, "WOLF Advanced Technology"),
 	(0x04DB, "CLEVER INFORMATION INC."),
-	(0x3100, "Dynabook Inc."),
-	(0x3264, "RnD Center \"ELVEES\", JSC"),
 	(0x0526, "Unicompute Technology Co., Ltd."),
-	(0x3442, "Bihl + Wiedemann GmbH"),
-	(0x3475, "Arista Networks, Inc."),
-	(0x3842, "EVGA Corporation"),
-	(0x38EF, "4Links Limited"),
 	(0x060E, "Lightelligence, Inc."),
-	(0x3DEE, "SunRise Memory Corp."),
-	(0x4144, "Alpha Data Parallel Systems Ltd."),
-	(0x4149, "AIMOTIVE Kft."),
-	(0x4153, "Active Silicon, Ltd."),
-	(0x434E, "Cornelis Networks, Inc."),
-	(0x4453, "dSPACE GmbH"),
-	(0x4547, "Salland Engineering (Europe) b.v"),
 	(0x0731, "Changsha Jingjia Microelectronics Co., Ltd."),
-	(0x4C54, "Nanjing Lisuan Technology Co., Ltd."),
-	(0x4D54, "Microtechnica Co., Ltd."),
-	(0x4D56, "Matrix Vision GmbH"),
-	(0x1BEE, "HMS Technology Center Ravensburg GmbH"),
-	(0x1C24, "Elma Electronic Inc"),
-	(0x4E58, "Nutanix, Inc."),
-	(0x4EEB, "Nebulon Inc"),
 	(0x0800, "Hirota Seisakusho Limited"),
-	(0x50C1, "Socionext Inc."),
 	(0x0823, "Hangzhou Hongjun Microelectronics Co., Ltd."),
-	(0x5353, "iodyne"),
-	(0x5554, "Applied Research Laboratories, The University of Texas at Austin"),
-	(0x5658, "VX Instruments GmbH"),
-	(0x5743, "WALTON DIGI-TECH INDUSTRIES LIMITED"),
-	(0x5845, "Extreme Engineering Solutions"),
-	(0x5853, "Citrix Systems UK Ltd"),
-	(0x5942, "Yellowbrick Data"),
-	(0x5959, "Jiangsu Yunyong Electronics and Technology Co., Ltd."),
 	(0x0909, "Intelligence Draw Microelectronics(Nanjing) Co., Ltd."),
-	(0x6766, "Glenfly Tech Co., Ltd."),
-	(0x6899, "ZT Systems"),
 	(0x0A7C, "Aurora Innovation Opco, Inc."),
-	(0x6D5E, "Guntermann & Drunck GmbH"),
-	(0x7053, "Synology Inc."),
-	(0x7377, "Shenzhen Colorful Yugong Technology and Development Co., Ltd."),
 	(0x0BAE, "Bachmann electronic GmbH"),
-	(0x7548, "INUITIVE LTD."),
-	(0x78C0, "Herrick Technology Laboratories (HTL), Inc."),
 	(0x0CCD, "Preferred Networks, Inc."),
-	(0x8009, "Shengli Technologies Co., Ltd"),
-	(0x8080, "StoreSwift Technology Co., Ltd."),
-	(0x8086, "Intel Corporation"),
-	(0x8088, "Beijing Wangxun Technology Co., Ltd."),
-	(0x8510, "Xi'an Sietium Semicoductor CO., LTD"),
-	(0x8686, "SAP SE"),
-	(0x9000, "C-Core Technology Co., Ltd."),
-	(0x9588, "Beijing JCZ Technology Co., Ltd"),
-	(0x9753, "PEZY Computing K.K."),
-	(0x9999, "MetaX Integrated Circuits (Shanghai) Co., Ltd."),
-	(0x9D32, "Beijing Starblaze Technology Co., LTD"),
-	(0x9D68, "Brite Semiconductor (Shanghai) Corporation, Ltd."),
 	(0x1013, "Cirrus Logic, Inc."),
 	(0x1014, "IBM"),
 	(0x1018, "Unisys Corporation"),
 	(0x1019, "Elitegroup Computer Systems Inc."),
 	(0x101E, "American Megatrends Incorporated"),
-	(0x101E, "AMI US Holdings, Inc."),
 	(0x1021, "Oki Electric Industry Co., Ltd."),
 	(0x1022, "Advanced Micro Devices, Inc."),
 	(0x1024, "Beijing Dajia Internet Information Technology Co., Ltd."),
 	(0x1028, "Dell Computer Corporation"),
 	(0x102B, "Matrox Graphics Inc."),
-	(0xA23B, "Silicon Integrated Systems"),
 	(0x103C, "HP Inc."),
-	(0x103C, "Hewlett Packard"),
 	(0x1043, "Asustek Computer Inc."),
 	(0x104A, "STMicroelectronics International NV"),
 	(0x104C, "Texas Instruments"),
@@ -112,7 +60,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x107D, "Leadtek Research Inc."),
 	(0x108E, "Oracle"),
 	(0x1093, "National Instruments Corporation"),
-	(0x1093, "National Instruments"),
 	(0x1096, "Alacron"),
 	(0x10A0, "Meidensha Corporation"),
 	(0x10AC, "Honeywell Inc."),
@@ -135,17 +82,13 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x110A, "Siemens AG"),
 	(0x1113, "Accton Technology Corporation"),
 	(0x112B, "Heidelberger Druckmaschinen AG"),
-	(0xABCD, "Vadatech Inc."),
 	(0x1131, "NXP Semiconductors"),
 	(0x1134, "Mercury Computer Systems, Inc."),
 	(0x1135, "FUJIFILM Business Innovation Corp."),
 	(0x1137, "Cisco Systems, Inc."),
 	(0x1147, "Interface Corporation"),
-	(0xAD00, "Alta Data Technologies"),
-	(0xAD10, "New Wave Design and Verification, LLC"),
 	(0x114F, "Digi International Inc."),
 	(0x1154, "Buffalo Inc."),
-	(0xAD5A, "ADVA Optical Networking SE"),
 	(0x115C, "Photron Limited"),
 	(0x1165, "Foresight Imaging LLC"),
 	(0x1168, "Thine Electronics, Inc"),
@@ -157,7 +100,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1195, "Ratoc Systems Inc."),
 	(0x119A, "Mindshare, Inc."),
 	(0x119D, "B.U.G. MORI SEIKI CO., LTD."),
-	(0x119D, "DMG MORI B.U.G. CO., LTD."),
 	(0x119F, "BULL SAS"),
 	(0x11A1, "Hamamatsu Photonics K.K."),
 	(0x11AC, "Canon, Inc."),
@@ -174,7 +116,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1217, "BayHub Technology Inc"),
 	(0x1221, "Contec Co., Ltd."),
 	(0x1227, "Tech Source Inc."),
-	(0x1227, "EIZO Rugged Solutions"),
 	(0x1235, "SMART Modular Technologies"),
 	(0x123C, "CENTURY SYSTEMS Co.,Ltd."),
 	(0x123D, "Engineering Design Team, Inc."),
@@ -193,16 +134,13 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1297, "Shuttle Inc"),
 	(0x12A0, "Rockwell Automation Inc. (Allen-Bradley)"),
 	(0x12A4, "NTT Electronics Corporation"),
-	(0x01DE, "Oxide Computer Company"),
 	(0x12AF, "TDK Corporation"),
 	(0x12B7, "Cognex Corporation"),
 	(0x12C4, "Connect Tech Inc."),
 	(0x12D6, "Analogic Corporation"),
 	(0x12D8, "Diodes Incorporated"),
-	(0x12D8, "Pericom Semiconductor"),
 	(0x12DB, "Annapolis Micro Systems, Inc."),
 	(0x12E1, "Nintendo Co., Ltd."),
-	(0xBDBD, "Blackmagic Design Pty Ltd"),
 	(0x12FE, "esd electronics gmbh"),
 	(0x1304, "Juniper Networks"),
 	(0x130F, "Advanet, Inc."),
@@ -226,7 +164,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x13B5, "ARM Ltd."),
 	(0x13B8, "Nokia Solutions and Networks Oy"),
 	(0x13B9, "ELECOM CO LTD"),
-	(0xC5EC, "Citadel Securities LLC"),
 	(0x13CC, "Barco, Inc."),
 	(0x13D6, "K.I. Technology Co., Ltd"),
 	(0x13DE, "ABB AB"),
@@ -242,7 +179,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1433, "Eltec Elektronik AG"),
 	(0x1435, "RTD Embedded Technologies, Inc."),
 	(0x1442, "Phoenix Contact Electronics GmbH"),
-	(0xCABC, "Cambricon Technologies Corporation Limited"),
 	(0x1447, "AIM GmbH"),
 	(0x144A, "ADLINK Technology"),
 	(0x144B, "Verint"),
@@ -251,23 +187,17 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1461, "AVerMedia Technologies, Inc."),
 	(0x1462, "Micro-Star International Co., Ltd."),
 	(0x1463, "Fast Corporation"),
-	(0xCC53, "ScaleFlux Inc."),
-	(0xCCDE, "Code Construct Pty Ltd"),
-	(0xCCEC, "Curtiss-Wright Controls Embedded Computing"),
 	(0x148A, "OPTO 22"),
 	(0x148C, "Tul Corporation"),
 	(0x1498, "TEWS Technologies GmbH"),
-	(0xCDFA, "NextSilicon Ltd"),
 	(0x14A0, "Softing AG"),
 	(0x14A4, "Lite-On Technology Corporation"),
 	(0x14A9, "Hivertec, Inc."),
-	(0xCEBA, "KEBA AG"),
 	(0x14C0, "Compal Electronics, Inc."),
 	(0x14C3, "MediaTek Incorporation"),
 	(0x14CD, "Universal Global Scientific Industrial Co., Ltd"),
 	(0x14D2, "TITAN Electronics Inc."),
 	(0x14D6, "Accusys Storage LTD."),
-	(0xD063, "SolidRun Ltd"),
 	(0x14E4, "Broadcom Limited"),
 	(0x14EB, "Seiko Epson Corporation"),
 	(0x14FF, "Twinhead International Corporation"),
@@ -290,24 +220,19 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x15AD, "Vmware, Inc."),
 	(0x15B8, "ADDI-DATA Gmbh"),
 	(0x15BB, "Portwell Inc"),
-	(0x15BB, "Portwell, Inc."),
 	(0x15BC, "Keysight Technologies"),
 	(0x15BD, "DFI Inc."),
 	(0x15CF, "Hilscher Gesellschaft fuer Systemautomation mbH"),
 	(0x15D7, "Collins Aerospace"),
 	(0x15D9, "Super Micro Computer Inc."),
-	(0xDA7A, "Speedata Inc."),
 	(0x15E0, "Blue Coat Systems"),
 	(0x15E7, "GET Engineering Corporation"),
 	(0x160C, "OMS Motion, Inc."),
-	(0x160C, "Pro-Dex, Oregon Micro Systems"),
 	(0x160D, "AAEON Electronics, Inc."),
-	(0xDD01, "Digital Devices"),
 	(0x1629, "Kongsberg Defence & Aerospace AS - Spacetec"),
 	(0x162F, "ROHDE & SCHWARZ GmbH & Co. KG"),
 	(0x163F, "Renishaw plc."),
 	(0x1642, "Shenzhen Bitland Information Technology Co., Ltd"),
-	(0xDEDA, "XIMEA"),
 	(0x165C, "Gidel Ltd."),
 	(0x1679, "Tokyo Electron Device Ltd."),
 	(0x1683, "StepTechnica Co., Ltd."),
@@ -316,7 +241,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x16C8, "Octasic Inc."),
 	(0x16CB, "Konica Minolta Holdings Inc."),
 	(0x16CE, "Roland Corporation"),
-	(0xE4BF, "EKF Elektronik GmbH"),
 	(0x16E2, "Marvin Test Solutions, Inc."),
 	(0x16EA, "Fuji Electric Co., Ltd."),
 	(0x16F2, "Bosch Rexroth AG"),
@@ -329,11 +253,9 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x174F, "SAXA, Inc."),
 	(0x175C, "AudioScience, Inc."),
 	(0x1761, "Pickering Interfaces Ltd."),
-	(0xEA50, "Emerson Automation Solutions"),
 	(0x1771, "InnoVision Multimedia, Ltd."),
 	(0x1775, "General Electric"),
 	(0x1778, "For-A Company Limited"),
-	(0xEACE, "Endace Technology Ltd."),
 	(0x177C, "EBRAINS, INC."),
 	(0x1784, "Lockheed Martin"),
 	(0x1792, "Artiza Networks, Inc."),
@@ -341,7 +263,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1796, "Forschungszentrum Jülich GmbH"),
 	(0x17A0, "Genesys Logic, Inc."),
 	(0x17AA, "Lenovo"),
-	(0xECAB, "GM Cruise LLC"),
 	(0x17C0, "Wistron Corporation"),
 	(0x17C3, "Protogate, Inc."),
 	(0x17CB, "Qualcomm Incorporated"),
@@ -352,17 +273,12 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x17E8, "Chrontel, Inc."),
 	(0x17ED, "ARBOR Technology Corp"),
 	(0x17F3, "RDC Semiconductor Co., Ltd."),
-	(0xEFAB, "Enfabrica Corporation"),
 	(0x17F9, "GemTek Technology Co., Ltd."),
 	(0x1805, "Euresys s.a."),
 	(0x1808, "nVent, Schroff GmbH"),
 	(0x180C, "IEI Integration Corp."),
 	(0x1817, "JAE"),
-	(0xF111, "Framework Computer Inc."),
-	(0xF117, "Rockport Networks Inc."),
 	(0x181D, "eInfochips, Inc."),
-	(0xF15E, "SiFive, Inc."),
-	(0xF1D0, "AJA Video"),
 	(0x1830, "Cohu, Inc."),
 	(0x184B, "SYSTEC Corporation"),
 	(0x184C, "Hirose Electric USA Inc."),
@@ -373,7 +289,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x188B, "Faraday Technology Corporation"),
 	(0x1893, "Kyocera Corporation"),
 	(0x1895, "Flextronics International"),
-	(0xF5F5, "F5 Networks, Inc."),
 	(0x18B2, "K.K. Rocky"),
 	(0x18B4, "Yamaichi Electronics"),
 	(0x18D1, "ULVAC-PHI, Inc."),
@@ -384,7 +299,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x18EF, "Avery Design systems, Inc."),
 	(0x18F1, "Spectrum Instrumentation GmbH"),
 	(0x18F2, "Dai-Ichi Seiko Co., Ltd(I-PEX)"),
-	(0x18F2, "I-PEX (Dai-ichi Seiko)"),
 	(0x18F4, "Napatech AS"),
 	(0x18F8, "Amphenol Corp."),
 	(0x18FD, "Digital Media Professionals, Inc."),
@@ -401,10 +315,7 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1954, "One Stop Systems, Inc."),
 	(0x1960, "REJ Co., Ltd."),
 	(0x1962, "U.S. Patent & Trademark Office"),
-	(0xFDEF, "Fermionic Design Private Limited"),
-	(0xFE19, "TenaFe Inc."),
 	(0x1974, "STAR Electronics GmbH & Co. KG"),
-	(0x1974, "Eberspächer Electronics GmbH & Co. KG"),
 	(0x1977, "Etion Create (Pty) Ltd."),
 	(0x197B, "JMicron Technology Corporation"),
 	(0x197D, "Cap Co., Ltd."),
@@ -445,7 +356,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1A6E, "International Game Technology"),
 	(0x1A82, "Samtec"),
 	(0x1A83, "Gopher Inc."),
-	(0x1A83, "ARKUS Inc."),
 	(0x1A8A, "Star Bridge, Inc."),
 	(0x1A9D, "QSC Audio Products, LLC"),
 	(0x1AA1, "Aristocrat Technologies Australia Pty Ltd."),
@@ -457,16 +367,14 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1AC1, "Global Unichip Corp."),
 	(0x1AC2, "Avalue Technology Inc."),
 	(0x1ACD, "Airbus DS Electronics and Border Security GmbH"),
-	(0x1ACD, "Hensoldt Sensors GmbH"),
-	(0x1ACD, "Cassidian"),
 	(0x1AE0, "Google, Inc."),
 	(0x1AE8, "Basler AG"),
+	(0x1AF4, "Red Hat, Inc."),
 	(0x1AF8, "Parade Technologies, Inc."),
 	(0x1B00, "Montage Technology Co., Ltd."),
 	(0x1B05, "Benchmark Electronics, Inc."),
 	(0x1B07, "NEXTCHIP Co,Ltd"),
 	(0x1B08, "Avnet Embedded GmbH"),
-	(0x1B08, "MSC Vertriebs GmbH"),
 	(0x1B0A, "Pegatron Corporation"),
 	(0x1B0C, "Northrop Grumman Corp., Electronic Systems"),
 	(0x1B12, "Analogix Semiconductor"),
@@ -474,8 +382,8 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1B26, "INVEA-TECH a.s."),
 	(0x1B2A, "Keyence Corporation"),
 	(0x1B2E, "Riverbed Technology, Inc."),
+	(0x1B36, "Red Hat, Inc."),
 	(0x1B37, "SP Devices"),
-	(0x1B37, "Teledyne SP Devices"),
 	(0x1B42, "Ceremorphic, Inc"),
 	(0x1B45, "StarTech.com Ltd."),
 	(0x1B49, "ALLDIS Computersystem GmbH"),
@@ -519,7 +427,7 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1BE9, "The MathWorks, Inc."),
 	(0x1BEB, "SignalCore, Inc."),
 	(0x1BEC, "SECO S.P.A."),
-	(0x1BEE, "IXXAT Automation GmbH"),
+	(0x1BEE, "HMS Technology Center Ravensburg GmbH"),
 	(0x1BF5, "Greenliant"),
 	(0x1BFA, "Daiichi Jitsugyo Viswill Co., Ltd"),
 	(0x1BFB, "Analog Bits"),
@@ -530,19 +438,16 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1C0B, "Alazar Technologies, Inc."),
 	(0x1C18, "ASSET InterTech, Inc."),
 	(0x1C1A, "UNH InterOperability Laboratory"),
-	(0x1C1A, "UNH InterOperability Laboratory (UNH-IOL)"),
 	(0x1C1B, "Netlist, Inc."),
-	(0x1C24, "Elma Bustronic Corp."),
+	(0x1C24, "Elma Electronic Inc"),
 	(0x1C2A, "Acromag, Inc"),
 	(0x1C2E, "Schneider Electric Japan Holdings Ltd."),
 	(0x1C2F, "Beckhoff Automation GmbH & Co. KG"),
 	(0x1C33, "Daktronics, Inc."),
-	(0x1C33, "Daktronics"),
 	(0x1C37, "Shikino High-Tech Co., Ltd"),
 	(0x1C46, "Cosmotechs Co., Ltd."),
 	(0x1C4E, "Techway"),
 	(0x1C4F, "Bruker Corporation"),
-	(0x7248, "BizLink Technology, Inc."),
 	(0x1C55, "Hexagon Metrology S.P.A."),
 	(0x1C5B, "XJTAG Ltd."),
 	(0x1C5C, "SK Hynix"),
@@ -622,7 +527,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1D47, "Ciena Corporation"),
 	(0x1D4B, "Elektrosfera LTD."),
 	(0x1D4C, "Diamanti, Inc."),
-	(0x1D4C, "Datawise Systems, Inc."),
 	(0x1D4D, "Integrated Design Tools, Inc."),
 	(0x1D58, "Atech Flash Technology, Inc"),
 	(0x1D61, "Technobox, Inc."),
@@ -978,7 +882,6 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x1FAA, "Hexaflake (Shanghai) Information Technology Co., Ltd."),
 	(0x1FAB, "UniFabriX Ltd."),
 	(0x1FCB, "PerkinElmer"),
-	(0x1FCB, "Varex Imaging Deutschland AG"),
 	(0x1FCD, "Hitachi High-Tech Corporation"),
 	(0x1FD4, "Sunix Co., Ltd."),
 	(0x1FD9, "Neuchips Inc."),
@@ -990,5 +893,84 @@ static VENDORS: [(u16, &str); 978] = [
 	(0x22DB, "Missing Link Electronics, Inc."),
 	(0x2337, "Macronix International Co., Ltd."),
 	(0x23E2, "streamcomputing"),
-	(0x2646, "Kingston Technology Company ")
-];
\ No newline at end of file
+	(0x2646, "Kingston Technology Company "),
+	(0x2782, "Emdoor Digital Technology Co., Ltd"),
+	(0x27D1, "Angelbird Technologies GmbH"),
+	(0x301F, "WOLF Advanced Technology"),
+	(0x3100, "Dynabook Inc."),
+	(0x3264, "RnD Center \"ELVEES\", JSC"),
+	(0x3442, "Bihl + Wiedemann GmbH"),
+	(0x3475, "Arista Networks, Inc."),
+	(0x3842, "EVGA Corporation"),
+	(0x38EF, "4Links Limited"),
+	(0x3DEE, "SunRise Memory Corp."),
+	(0x4144, "Alpha Data Parallel Systems Ltd."),
+	(0x4149, "AIMOTIVE Kft."),
+	(0x4153, "Active Silicon, Ltd."),
+	(0x434E, "Cornelis Networks, Inc."),
+	(0x4453, "dSPACE GmbH"),
+	(0x4547, "Salland Engineering (Europe) b.v"),
+	(0x4C54, "Nanjing Lisuan Technology Co., Ltd."),
+	(0x4D54, "Microtechnica Co., Ltd."),
+	(0x4D56, "Matrix Vision GmbH"),
+	(0x4E58, "Nutanix, Inc."),
+	(0x4EEB, "Nebulon Inc"),
+	(0x50C1, "Socionext Inc."),
+	(0x5353, "iodyne"),
+	(0x5554, "Applied Research Laboratories, The University of Texas at Austin"),
+	(0x5658, "VX Instruments GmbH"),
+	(0x5743, "WALTON DIGI-TECH INDUSTRIES LIMITED"),
+	(0x5845, "Extreme Engineering Solutions"),
+	(0x5853, "Citrix Systems UK Ltd"),
+	(0x5942, "Yellowbrick Data"),
+	(0x5959, "Jiangsu Yunyong Electronics and Technology Co., Ltd."),
+	(0x6766, "Glenfly Tech Co., Ltd."),
+	(0x6899, "ZT Systems"),
+	(0x6D5E, "Guntermann & Drunck GmbH"),
+	(0x7053, "Synology Inc."),
+	(0x7248, "BizLink Technology, Inc."),
+	(0x7377, "Shenzhen Colorful Yugong Technology and Development Co., Ltd."),
+	(0x7548, "INUITIVE LTD."),
+	(0x78C0, "Herrick Technology Laboratories (HTL), Inc."),
+	(0x8009, "Shengli Technologies Co., Ltd"),
+	(0x8080, "StoreSwift Technology Co., Ltd."),
+	(0x8086, "Intel Corporation"),
+	(0x8088, "Beijing Wangxun Technology Co., Ltd."),
+	(0x8510, "Xi'an Sietium Semicoductor CO., LTD"),
+	(0x8686, "SAP SE"),
+	(0x9000, "C-Core Technology Co., Ltd."),
+	(0x9588, "Beijing JCZ Technology Co., Ltd"),
+	(0x9753, "PEZY Computing K.K."),
+	(0x9999, "MetaX Integrated Circuits (Shanghai) Co., Ltd."),
+	(0x9D32, "Beijing Starblaze Technology Co., LTD"),
+	(0x9D68, "Brite Semiconductor (Shanghai) Corporation, Ltd."),
+	(0xA23B, "Silicon Integrated Systems"),
+	(0xABCD, "Vadatech Inc."),
+	(0xAD00, "Alta Data Technologies"),
+	(0xAD10, "New Wave Design and Verification, LLC"),
+	(0xAD5A, "ADVA Optical Networking SE"),
+	(0xBDBD, "Blackmagic Design Pty Ltd"),
+	(0xC5EC, "Citadel Securities LLC"),
+	(0xCABC, "Cambricon Technologies Corporation Limited"),
+	(0xCC53, "ScaleFlux Inc."),
+	(0xCCDE, "Code Construct Pty Ltd"),
+	(0xCCEC, "Curtiss-Wright Controls Embedded Computing"),
+	(0xCDFA, "NextSilicon Ltd"),
+	(0xCEBA, "KEBA AG"),
+	(0xD063, "SolidRun Ltd"),
+	(0xDA7A, "Speedata Inc."),
+	(0xDD01, "Digital Devices"),
+	(0xDEDA, "XIMEA"),
+	(0xE4BF, "EKF Elektronik GmbH"),
+	(0xEA50, "Emerson Automation Solutions"),
+	(0xEACE, "Endace Technology Ltd."),
+	(0xECAB, "GM Cruise LLC"),
+	(0xEFAB, "Enfabrica Corporation"),
+	(0xF111, "Framework Computer Inc."),
+	(0xF117, "Rockport Networks Inc."),
+	(0xF15E, "SiFive, Inc."),
+	(0xF1D0, "AJA Video"),
+	(0xF5F5, "F5 Networks, Inc."),
+	(0xFDEF, "Fermionic Design Private Limited"),
+	(0xFE19, "TenaFe Inc.")
+];
@@ -0,0 +1,1194 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Assembly, I/O and background work of arrays.
+
+use {
+	super::{*, parity::Block},
+	crate::block::{self, BlockDevice},
+	alloc::{string::String, vec::Vec},
+	core::mem::size_of
+};
+
+/// Bytes rebuilt between the superblock updates that record the progress
+const CHECKPOINT: u64 = 16 << 20;
+/// Offset of `sb_csum` in the superblock
+const CSUM_OFFSET: usize = 0xD8;
+
+/// How a member takes part in the array
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+	/// The member holds current data
+	InSync,
+	/// The member is being rebuilt, its blocks below the offset are current
+	Rebuilding(u64),
+	/// The member failed and is no longer used
+	Faulty,
+	/// The slot has no device
+	Missing
+}
+
+/// Background work of an array
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+	Idle,
+	/// Makes the redundancy consistent after an unclean shutdown or the creation
+	Resync,
+	/// Reconstructs the contents of rebuilding members
+	Rebuild,
+	/// Counts the blocks whose redundancy is inconsistent
+	Check,
+	/// Counts and rewrites the blocks whose redundancy is inconsistent
+	Repair
+}
+
+/// Parameters of a new array
+#[derive(Clone, Debug)]
+pub struct Config {
+	pub level:       Level,
+	pub uuid:        u128,
+	/// At most 32 bytes, longer names are truncated
+	pub name:        String,
+	/// Chunk size in bytes, unused by RAID1
+	pub chunk_size:  u64,
+	/// Copies of each chunk, RAID10 only
+	pub copies:      u32,
+	/// Offset of the data from the start of the members in bytes
+	pub data_offset: u64,
+	/// Creation time in seconds since the epoch
+	pub time:        u64
+}
+
+impl Config {
+	/// The defaults of `mdadm`: 512 KiB chunks, two copies and the data 1 MiB into the members.
+	pub fn new(level: Level, uuid: u128) -> Self {
+		Self { level, uuid, name: String::new(), chunk_size: 512 << 10, copies: 2, data_offset: 1 << 20, time: 0 }
+	}
+}
+
+struct Member<D> {
+	dev:       Option<D>,
+	/// Unused for spares, except that failed spares are `Faulty`
+	state:     State,
+	/// Index in the role table
+	number:    u32,
+	uuid:      u128,
+	/// First block of the data
+	offset:    u64,
+	corrected: u32
+}
+
+impl<D> Member<D> {
+	fn missing() -> Self {
+		Self { dev: None, state: State::Missing, number: u32::MAX, uuid: 0, offset: 0, corrected: 0 }
+	}
+}
+
+/// An md array on its members' devices.
+pub struct Array<D> {
+	/// The array's fields of the superblock, the member's ones are filled in when writing it
+	sb:         Superblock,
+	roles:      Vec<u16>,
+	level:      Level,
+	/// Members by slot
+	members:    Vec<Member<D>>,
+	spares:     Vec<Member<D>>,
+	block_size: usize,
+	/// Blocks of each member used for data
+	size:       u64,
+	/// Chunk size in blocks, unused by RAID1
+	chunk:      u64,
+	/// Copies of each chunk, RAID10 only
+	copies:     usize,
+	/// Whether the superblocks mark the array active, i.e. writes may be incomplete
+	dirty:      bool,
+	/// Position of the resync, if the redundancy may be inconsistent
+	resync:     Option<u64>,
+	/// Whether the running scrub repairs and its position
+	scrub:      Option<(bool, u64)>,
+	mismatches: u64,
+	read_only:  bool
+}
+
+/// Reads the superblock and the role table of a member.
+pub fn probe<D: BlockDevice>(dev: &mut D) -> Result<(Superblock, Vec<u16>)> {
+	let size = dev.block_size();
+	if size == 0 || size % SECTOR_SIZE as usize != 0 || SUPER_SIZE % size != 0 {
+		return Err(Error::Unsupported);
+	}
+	let lba = SUPER_OFFSET * SECTOR_SIZE / size as u64;
+	if dev.blocks() < lba + (SUPER_SIZE / size) as u64 {
+		return Err(Error::NoSuperblock);
+	}
+
+	let mut buf = block::alloc_buffer(dev, SUPER_SIZE / size)?;
+	dev.read(lba, &mut buf)?;
+	// SAFETY: the buffer is larger than the superblock, which consists of integers
+	let sb = unsafe { (buf.as_ptr() as *const Superblock).read_unaligned() };
+	if !sb.is_valid() {
+		return Err(Error::NoSuperblock);
+	}
+	let end = size_of::<Superblock>() + sb.max_dev as usize * 2;
+	if end > SUPER_SIZE || sb.checksum(&buf) != sb.sb_csum || sb.super_offset != SUPER_OFFSET {
+		return Err(Error::Corrupted);
+	}
+	let roles = buf[size_of::<Superblock>()..end].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+	Ok((sb, roles))
+}
+
+impl<D: BlockDevice> Array<D> {
+	/// Writes the superblocks of a new array, members are in the order of the devices. The
+	/// redundancy of the new array is made consistent by a resync.
+	pub fn create(devices: Vec<D>, config: &Config) -> Result<Self> {
+		let n = devices.len();
+		let block_size = devices.first().ok_or(Error::InvalidArgument)?.block_size();
+		let (bs, spb) = (block_size as u64, block_size as u64 / SECTOR_SIZE);
+		let copies = match config.level {
+			Level::Raid10 => config.copies as usize,
+			_             => 1
+		};
+		let valid = n >= min_members(config.level, copies) && (2..=n).contains(&copies.max(2))
+			&& size_of::<Superblock>() + n * 2 <= SUPER_SIZE
+			&& devices.iter().all(|d| d.block_size() == block_size)
+			&& block_size % SECTOR_SIZE as usize == 0 && SUPER_SIZE % block_size == 0
+			&& config.data_offset >= SUPER_OFFSET * SECTOR_SIZE + SUPER_SIZE as u64 && config.data_offset % bs == 0
+			&& (config.level == Level::Raid1 || (config.chunk_size != 0 && config.chunk_size % bs == 0));
+		if !valid {
+			return Err(Error::InvalidArgument);
+		}
+
+		let offset = config.data_offset / bs;
+		let chunk = config.chunk_size / bs;
+		let mut size = devices.iter().map(|d| d.blocks().saturating_sub(offset)).min().unwrap_or(0);
+		if config.level != Level::Raid1 {
+			size -= size % chunk;
+		}
+		if size == 0 {
+			return Err(Error::InvalidArgument);
+		}
+
+		let mut set_name = [0; 32];
+		let name = &config.name.as_bytes()[..config.name.len().min(32)];
+		set_name[..name.len()].copy_from_slice(name);
+		let sb = Superblock {
+			magic:         MAGIC,
+			major_version: MAJOR_VERSION,
+			set_uuid:      config.uuid,
+			set_name,
+			ctime:         config.time,
+			level:         config.level.md(),
+			layout:        match config.level {
+				Level::Raid5 | Level::Raid6 => LAYOUT_LEFT_SYMMETRIC,
+				Level::Raid10               => 1 << LAYOUT_FAR_SHIFT | copies as u32,
+				_                           => 0
+			},
+			size:          size * spb,
+			chunksize:     match config.level {
+				Level::Raid1 => 0,
+				_            => (chunk * spb) as u32
+			},
+			raid_disks:    n as u32,
+			super_offset:  SUPER_OFFSET,
+			utime:         config.time,
+			resync_offset: MAX_SECTOR,
+			..Superblock::default()
+		};
+
+		let mut array = Self::new(sb, (0..n as u16).collect(), block_size, size)?;
+		for (slot, dev) in devices.into_iter().enumerate() {
+			array.members[slot] = Member {
+				dev:       Some(dev),
+				state:     State::InSync,
+				number:    slot as u32,
+				uuid:      device_uuid(config.uuid, slot as u32),
+				offset,
+				corrected: 0
+			};
+		}
+		array.read_only = array.members.iter().any(|m| m.dev.as_ref().map_or(false, |d| d.is_read_only()));
+		if array.read_only {
+			return Err(Error::Block(block::Error::ReadOnly));
+		}
+		array.resync = config.level.is_redundant().then_some(0);
+		array.write_superblocks()?;
+		Ok(array)
+	}
+
+	/// Assembles the array the devices belong to, the superblock with the most events
+	/// describes it. Stale members are rebuilt, spares take the slots of missing members.
+	///
+	/// Arrays with a write intent bitmap are assembled read-only.
+	pub fn assemble(devices: Vec<D>) -> Result<Self> {
+		let mut found = Vec::new();
+		for mut dev in devices {
+			let (sb, roles) = probe(&mut dev)?;
+			found.push((dev, sb, roles));
+		}
+
+		let (dev, sb, roles) = found.iter().max_by_key(|(_, sb, _)| sb.events).ok_or(Error::InvalidArgument)?;
+		let (sb, roles, block_size) = (*sb, roles.clone(), dev.block_size());
+		let spb = block_size as u64 / SECTOR_SIZE;
+		if found.iter().any(|(dev, s, _)| s.set_uuid != sb.set_uuid || dev.block_size() != block_size) {
+			return Err(Error::Mismatch);
+		}
+
+		// RAID0 superblocks may not record the size
+		let size = match sb.size {
+			0    => found.iter().map(|(_, s, _)| s.data_size).min().unwrap_or(0),
+			size => size
+		} / spb;
+		let mut array = Self::new(sb, roles, block_size, size)?;
+
+		let mut stale = false;
+		for (dev, s, _) in found {
+			let offset = s.data_offset / spb;
+			if s.data_offset % spb != 0 || dev.blocks() < offset + array.size {
+				return Err(Error::Corrupted);
+			}
+			array.read_only |= dev.is_read_only();
+
+			let role = array.roles.get(s.dev_number as usize).copied().unwrap_or(ROLE_SPARE) as usize;
+			let state = match role < array.members.len() {
+				true if s.events < sb.events                           => State::Rebuilding(0),
+				true if s.feature_map & FEATURE_RECOVERY_OFFSET != 0 => State::Rebuilding(s.recovery_offset / spb),
+				_                                                      => State::InSync
+			};
+			stale |= s.events < sb.events;
+			let member = Member { dev: Some(dev), state, number: s.dev_number, uuid: s.device_uuid, offset, corrected: s.cnt_corrected_read };
+			match array.members.get_mut(role) {
+				Some(slot) if slot.dev.is_some() => return Err(Error::Mismatch),
+				Some(slot)                       => *slot = member,
+				None                             => array.spares.push(Member { state: State::Missing, ..member })
+			}
+		}
+
+		array.read_only |= sb.feature_map & FEATURE_BITMAP_OFFSET != 0;
+		array.resync = (sb.resync_offset != MAX_SECTOR && array.level.is_redundant())
+			.then(|| (sb.resync_offset / spb).min(array.sync_total()));
+		if !array.can_run() {
+			return Err(Error::Failed);
+		}
+		if !array.read_only && (array.activate_spares() || stale) {
+			array.write_superblocks()?;
+		}
+		Ok(array)
+	}
+
+	/// An array without members, the geometry checked.
+	fn new(sb: Superblock, roles: Vec<u16>, block_size: usize, size: u64) -> Result<Self> {
+		let level = Level::from_md(sb.level).ok_or(Error::Unsupported)?;
+		let spb = block_size as u64 / SECTOR_SIZE;
+		let n = sb.raid_disks as usize;
+		if sb.feature_map & !FEATURES_SUPPORTED != 0 || (level.has_parity() && sb.layout != LAYOUT_LEFT_SYMMETRIC) {
+			return Err(Error::Unsupported);
+		}
+
+		// only the near layout, i.e. one far copy and no offset layout
+		let copies = match level {
+			Level::Raid10 if sb.layout >> LAYOUT_FAR_SHIFT != 1 => return Err(Error::Unsupported),
+			Level::Raid10                                       => (sb.layout & 0xFF) as usize,
+			_                                                   => 1
+		};
+		let chunk = match level {
+			Level::Raid1                              => 0,
+			_ if sb.chunksize as u64 % spb != 0 => return Err(Error::Unsupported),
+			_                                         => sb.chunksize as u64 / spb
+		};
+		let valid = n >= min_members(level, copies) && n <= roles.len() && copies != 0 && copies <= n
+			&& (level == Level::Raid1 || chunk != 0);
+		if !valid {
+			return Err(Error::Corrupted);
+		}
+
+		let size = match level {
+			Level::Raid1 => size,
+			_            => size - size % chunk
+		};
+		Ok(Self {
+			sb,
+			roles,
+			level,
+			members:    (0..n).map(|_| Member::missing()).collect(),
+			spares:     Vec::new(),
+			block_size,
+			size,
+			chunk,
+			copies,
+			dirty:      false,
+			resync:     None,
+			scrub:      None,
+			mismatches: 0,
+			read_only:  false
+		})
+	}
+
+	pub fn level(&self) -> Level {
+		self.level
+	}
+
+	pub fn uuid(&self) -> u128 {
+		self.sb.set_uuid
+	}
+
+	pub fn name(&self) -> &str {
+		self.sb.name()
+	}
+
+	/// Number of slots
+	pub fn members(&self) -> usize {
+		self.members.len()
+	}
+
+	pub fn spares(&self) -> usize {
+		self.spares.iter().filter(|m| m.state != State::Faulty).count()
+	}
+
+	pub fn state(&self, slot: usize) -> Option<State> {
+		self.members.get(slot).map(|m| m.state)
+	}
+
+	/// Number of slots without an in-sync member
+	pub fn degraded(&self) -> usize {
+		self.members.iter().filter(|m| m.state != State::InSync).count()
+	}
+
+	/// Chunk size in bytes, zero for RAID1
+	pub fn chunk_size(&self) -> u64 {
+		self.chunk * self.block_size as u64
+	}
+
+	pub fn events(&self) -> u64 {
+		self.sb.events
+	}
+
+	/// Whether no writes are outstanding and the redundancy is consistent.
+	pub fn is_clean(&self) -> bool {
+		!self.dirty && self.resync.is_none()
+	}
+
+	/// Blocks whose redundancy the last scrub found inconsistent
+	pub fn mismatches(&self) -> u64 {
+		self.mismatches
+	}
+
+	/// Sets the time recorded in the superblocks, in seconds since the epoch.
+	pub fn set_time(&mut self, time: u64) {
+		self.sb.utime = time;
+	}
+
+	/// The background work `step` does next.
+	pub fn action(&self) -> Action {
+		match (self.rebuilding(), self.resync, self.scrub) {
+			(Some(_), _, _)              => Action::Rebuild,
+			(_, Some(_), _)              => Action::Resync,
+			(_, _, Some((true, _)))  => Action::Repair,
+			(_, _, Some((false, _))) => Action::Check,
+			_                            => Action::Idle
+		}
+	}
+
+	/// Blocks done and total blocks of the current background work, per member for
+	/// rebuilds and for RAID1, RAID5 and RAID6, of the array for RAID10.
+	pub fn progress(&self) -> (u64, u64) {
+		match (self.rebuilding(), self.resync, self.scrub) {
+			(Some(slot), _, _) => match self.members[slot].state {
+				State::Rebuilding(offset) => (offset, self.size),
+				_                         => (0, self.size)
+			},
+			(_, Some(pos), _) | (_, _, Some((_, pos))) => (pos, self.sync_total()),
+			_ => (0, 0)
+		}
+	}
+
+	/// Starts or stops a scrub, i.e. `Action::Check`, `Action::Repair` or `Action::Idle`.
+	/// Resyncs and rebuilds start by themselves and can't be stopped.
+	pub fn start(&mut self, action: Action) -> Result<()> {
+		match action {
+			Action::Check | Action::Repair if !self.level.is_redundant() => Err(Error::Unsupported),
+			Action::Repair if self.read_only => Err(Error::Block(block::Error::ReadOnly)),
+			Action::Check | Action::Repair | Action::Idle => match self.action() {
+				Action::Resync | Action::Rebuild => Err(Error::Busy),
+				_ => {
+					self.scrub = (action != Action::Idle).then_some((action == Action::Repair, 0));
+					self.mismatches = match action {
+						Action::Idle => self.mismatches,
+						_            => 0
+					};
+					Ok(())
+				}
+			},
+			_ => Err(Error::InvalidArgument)
+		}
+	}
+
+	/// Fails the member in the slot, a spare takes its place.
+	pub fn fail(&mut self, slot: usize) -> Result<()> {
+		match self.members.get_mut(slot) {
+			Some(m) if m.dev.is_some() => m.state = State::Faulty,
+			_ => return Err(Error::InvalidArgument)
+		}
+		self.activate_spares();
+		self.write_superblocks()
+	}
+
+	/// Removes the device of a failed member.
+	pub fn remove(&mut self, slot: usize) -> Option<D> {
+		let member = self.members.get_mut(slot).filter(|m| m.state == State::Faulty)?;
+		member.state = State::Missing;
+		member.dev.take()
+	}
+
+	/// Adds a device, which is rebuilt in the first slot without a working member or
+	/// otherwise becomes a spare. The device of a failed member is dropped when a spare
+	/// takes its slot.
+	pub fn add(&mut self, dev: D) -> Result<()> {
+		if !self.level.is_redundant() {
+			return Err(Error::Unsupported);
+		} else if self.read_only {
+			return Err(Error::Block(block::Error::ReadOnly));
+		}
+		let offset = self.members.iter().chain(&self.spares).find(|m| m.dev.is_some()).map(|m| m.offset).ok_or(Error::Failed)?;
+		if dev.block_size() != self.block_size || dev.blocks() < offset + self.size {
+			return Err(Error::InvalidArgument);
+		}
+
+		let number = self.free_number();
+		self.spares.push(Member {
+			dev:       Some(dev),
+			state:     State::Missing,
+			number,
+			uuid:      device_uuid(self.sb.set_uuid, number) ^ self.sb.events as u128,
+			offset,
+			corrected: 0
+		});
+		self.activate_spares();
+		self.write_superblocks()
+	}
+
+	/// Advances the background work by up to `blocks` blocks: rebuilds members, otherwise
+	/// resyncs and otherwise scrubs. Returns whether work is left.
+	pub fn step(&mut self, blocks: u64) -> Result<bool> {
+		let blocks = blocks.max(1);
+		match self.action() {
+			Action::Idle                                 => return Ok(false),
+			_ if self.read_only && self.scrub.is_none() => return Ok(false),
+			Action::Rebuild => {
+				let slot = self.rebuilding().ok_or(Error::Failed)?;
+				self.rebuild(slot, blocks)?;
+			}
+			Action::Resync => {
+				let pos = self.resync.unwrap_or(0);
+				let (done, _) = self.sync(pos, blocks, true)?;
+				self.resync = Some(pos + done).filter(|pos| *pos < self.sync_total());
+				if self.resync.is_none() && !self.dirty {
+					self.write_superblocks()?;
+				}
+			}
+			Action::Check | Action::Repair => {
+				let (repair, pos) = self.scrub.unwrap_or((false, 0));
+				let (done, mismatch) = self.sync(pos, blocks, repair && !self.read_only)?;
+				if mismatch {
+					self.mismatches += done;
+				}
+				self.scrub = Some((repair, pos + done)).filter(|(_, pos)| *pos < self.sync_total());
+			}
+		}
+		Ok(self.action() != Action::Idle)
+	}
+
+	/// The devices of the members and spares.
+	pub fn into_inner(self) -> Vec<D> {
+		self.members.into_iter().chain(self.spares).filter_map(|m| m.dev).collect()
+	}
+
+	/// Blocks of the array
+	fn capacity(&self) -> u64 {
+		let n = self.members.len() as u64;
+		match self.level {
+			Level::Raid0  => self.size * n,
+			Level::Raid1  => self.size,
+			Level::Raid5  => self.size * (n - 1),
+			Level::Raid6  => self.size * (n - 2),
+			Level::Raid10 => self.size / self.chunk * n / self.copies as u64 * self.chunk
+		}
+	}
+
+	/// Blocks a resync or scrub goes through, of each member for RAID1, RAID5 and RAID6 and
+	/// of the array for RAID10, like md does.
+	fn sync_total(&self) -> u64 {
+		match self.level {
+			Level::Raid10 => self.capacity(),
+			_             => self.size
+		}
+	}
+
+	/// Whether the in-sync members hold all data.
+	fn can_run(&self) -> bool {
+		let n = self.members.len();
+		let up = |slot: usize| self.members[slot].dev.is_some() && self.members[slot].state == State::InSync;
+		let failed = (0..n).filter(|s| !up(*s)).count();
+		match self.level {
+			Level::Raid0  => failed == 0,
+			Level::Raid1  => failed < n,
+			Level::Raid5  => failed <= 1,
+			Level::Raid6  => failed <= 2,
+			// the copies of every chunk start at a multiple of `copies` modulo `n`
+			Level::Raid10 => (0..n).all(|k| (0..self.copies).any(|j| up((k * self.copies + j) % n)))
+		}
+	}
+
+	/// The rebuilding member that is furthest behind.
+	fn rebuilding(&self) -> Option<usize> {
+		(0..self.members.len())
+			.filter(|s| self.members[*s].dev.is_some())
+			.filter_map(|s| match self.members[s].state {
+				State::Rebuilding(offset) => Some((offset, s)),
+				_                         => None
+			})
+			.min()
+			.map(|(_, s)| s)
+	}
+
+	/// Moves spares into the slots without a working member. Returns whether any moved.
+	fn activate_spares(&mut self) -> bool {
+		let mut moved = false;
+		for slot in 0..self.members.len() {
+			if !matches!(self.members[slot].state, State::Missing | State::Faulty) {
+				continue;
+			}
+			if let Some(i) = self.spares.iter().position(|m| m.state != State::Faulty) {
+				let mut member = self.spares.remove(i);
+				member.state = State::Rebuilding(0);
+				self.members[slot] = member;
+				moved = true;
+			}
+		}
+		moved
+	}
+
+	/// The lowest device number no present device and no slot refers to.
+	fn free_number(&self) -> u32 {
+		let n = self.members.len();
+		let used = |i: usize| self.members.iter().chain(&self.spares).any(|m| m.number as usize == i && m.dev.is_some())
+			|| (self.roles[i] as usize) < n;
+		(0..self.roles.len()).find(|i| !used(*i)).unwrap_or(self.roles.len()) as u32
+	}
+
+	/// Records the slots of the present devices in the role table.
+	fn update_roles(&mut self) {
+		for (slot, m) in self.members.iter().enumerate().filter(|(_, m)| m.dev.is_some()) {
+			let number = m.number as usize;
+			if number >= self.roles.len() {
+				self.roles.resize(number + 1, ROLE_SPARE);
+			}
+			// devices the slot belonged to before
+			for role in self.roles.iter_mut().filter(|r| **r as usize == slot) {
+				*role = ROLE_FAULTY;
+			}
+			self.roles[number] = match m.state {
+				State::Faulty => ROLE_FAULTY,
+				_             => slot as u16
+			};
+		}
+		for m in self.spares.iter().filter(|m| m.dev.is_some()) {
+			let number = m.number as usize;
+			if number >= self.roles.len() {
+				self.roles.resize(number + 1, ROLE_SPARE);
+			}
+			self.roles[number] = match m.state {
+				State::Faulty => ROLE_FAULTY,
+				_             => ROLE_SPARE
+			};
+		}
+	}
+
+	/// Writes the superblocks of all members and spares with the next event count. Members
+	/// that can't be written fail.
+	fn write_superblocks(&mut self) -> Result<()> {
+		if self.read_only {
+			return Ok(());
+		}
+
+		let lba = SUPER_OFFSET * SECTOR_SIZE / self.block_size as u64;
+		loop {
+			self.sb.events += 1;
+			self.update_roles();
+			let mut failed = false;
+			for i in 0..self.members.len() + self.spares.len() {
+				let member = match i < self.members.len() {
+					true  => &self.members[i],
+					false => &self.spares[i - self.members.len()]
+				};
+				if member.dev.is_none() || member.state == State::Faulty || member.state == State::Missing && i < self.members.len() {
+					continue;
+				}
+				let buf = self.superblock(member)?;
+				let member = match i < self.members.len() {
+					true  => &mut self.members[i],
+					false => &mut self.spares[i - self.members.len()]
+				};
+				if let Some(Err(_)) = member.dev.as_mut().map(|d| d.write(lba, &buf)) {
+					member.state = State::Faulty;
+					failed = true;
+				}
+			}
+			if !failed {
+				break;
+			}
+			self.activate_spares();
+		}
+
+		match self.can_run() {
+			true  => Ok(()),
+			false => Err(Error::Failed)
+		}
+	}
+
+	/// The superblock and role table of a member.
+	fn superblock(&self, member: &Member<D>) -> Result<Vec<u8>> {
+		let dev = member.dev.as_ref().ok_or(Error::InvalidArgument)?;
+		let spb = self.block_size as u64 / SECTOR_SIZE;
+		let mut sb = self.sb;
+		sb.feature_map &= !FEATURE_RECOVERY_OFFSET;
+		sb.recovery_offset = 0;
+		if let State::Rebuilding(offset) = member.state {
+			sb.feature_map |= FEATURE_RECOVERY_OFFSET;
+			sb.recovery_offset = offset * spb;
+		}
+		sb.data_offset = member.offset * spb;
+		sb.data_size = (dev.blocks() - member.offset) * spb;
+		sb.dev_number = member.number;
+		sb.device_uuid = member.uuid;
+		sb.cnt_corrected_read = member.corrected;
+		sb.resync_offset = match (self.dirty, self.resync) {
+			(true, _)         => 0,
+			(false, Some(pos)) => pos * spb,
+			(false, None)      => MAX_SECTOR
+		};
+		sb.max_dev = self.roles.len() as u32;
+		sb.sb_csum = 0;
+
+		let mut buf = block::alloc_buffer(dev, SUPER_SIZE / self.block_size)?;
+		// SAFETY: the buffer is larger than the superblock
+		unsafe { (buf.as_mut_ptr() as *mut Superblock).write_unaligned(sb) };
+		for (i, role) in self.roles.iter().enumerate() {
+			let off = size_of::<Superblock>() + i * 2;
+			buf[off..off + 2].copy_from_slice(&role.to_le_bytes());
+		}
+		let csum = sb.checksum(&buf);
+		buf[CSUM_OFFSET..CSUM_OFFSET + 4].copy_from_slice(&csum.to_le_bytes());
+		Ok(buf)
+	}
+
+	/// Marks the array active before the first write after it was clean.
+	fn mark_dirty(&mut self) -> block::Result<()> {
+		if !self.dirty && self.level.is_redundant() {
+			self.dirty = true;
+			self.write_superblocks().map_err(block_error)?;
+		}
+		Ok(())
+	}
+
+	fn flush_members(&mut self) {
+		for slot in 0..self.members.len() {
+			if self.writable(slot) && self.members[slot].dev.as_mut().map_or(false, |d| d.flush().is_err()) {
+				self.fail_member(slot);
+			}
+		}
+	}
+
+	/// Whether the member's blocks are current.
+	fn readable(&self, slot: usize, moff: u64, blocks: u64) -> bool {
+		let member = &self.members[slot];
+		member.dev.is_some() && match member.state {
+			State::InSync             => true,
+			State::Rebuilding(offset) => moff + blocks <= offset,
+			_                         => false
+		}
+	}
+
+	fn writable(&self, slot: usize) -> bool {
+		let member = &self.members[slot];
+		member.dev.is_some() && matches!(member.state, State::InSync | State::Rebuilding(_))
+	}
+
+	fn read_member(&mut self, slot: usize, moff: u64, buf: &mut [u8]) -> block::Result<()> {
+		let member = &mut self.members[slot];
+		member.dev.as_mut().ok_or(block::Error::NoDevice)?.read(member.offset + moff, buf)
+	}
+
+	/// Writes to a member, which fails if that doesn't work. Returns whether it worked.
+	fn write_member(&mut self, slot: usize, moff: u64, buf: &[u8]) -> bool {
+		let member = &mut self.members[slot];
+		let ok = member.dev.as_mut().map_or(false, |d| d.write(member.offset + moff, buf).is_ok());
+		if !ok {
+			self.fail_member(slot);
+		}
+		ok
+	}
+
+	/// Rewrites blocks that failed to read with the data from the redundancy, like md does.
+	/// The member fails if that doesn't work either.
+	fn correct(&mut self, slot: usize, moff: u64, buf: &[u8]) {
+		if self.write_member(slot, moff, buf) {
+			self.members[slot].corrected = self.members[slot].corrected.saturating_add(1);
+		}
+	}
+
+	fn fail_member(&mut self, slot: usize) {
+		self.members[slot].state = State::Faulty;
+		self.activate_spares();
+		// the array fails if too many members failed, which the I/O reports itself
+		let _ = self.write_superblocks();
+	}
+
+	/// Slots and member blocks holding the array block and the blocks up to the end of its
+	/// chunk, for RAID1 and RAID10.
+	fn copies(&self, lba: u64) -> (Vec<(usize, u64)>, u64) {
+		let n = self.members.len() as u64;
+		match self.level {
+			Level::Raid10 => {
+				let (k, off) = (lba / self.chunk, lba % self.chunk);
+				let copies = (0..self.copies as u64)
+					.map(|j| k * self.copies as u64 + j)
+					.map(|i| ((i % n) as usize, i / n * self.chunk + off))
+					.collect();
+				(copies, self.chunk - off)
+			}
+			_ => ((0..n as usize).map(|slot| (slot, lba)).collect(), u64::MAX)
+		}
+	}
+
+	fn data_disks(&self) -> usize {
+		self.members.len() - match self.level {
+			Level::Raid6 => 2,
+			_            => 1
+		}
+	}
+
+	/// Slots of the data blocks in syndrome order, of P and of Q of a stripe in the
+	/// left-symmetric layout.
+	fn stripe_slots(&self, stripe: u64) -> (Vec<usize>, usize, Option<usize>) {
+		let n = self.members.len();
+		let p = n - 1 - (stripe % n as u64) as usize;
+		let q = (self.level == Level::Raid6).then_some((p + 1) % n);
+		let first = p + 1 + q.is_some() as usize;
+		((0..self.data_disks()).map(|i| (first + i) % n).collect(), p, q)
+	}
+
+	/// Reads `blocks` blocks from `off` within the chunks of a stripe, the blocks of
+	/// unavailable members are reconstructed. Returns the data in syndrome order, P and Q.
+	#[allow(clippy::type_complexity)]
+	fn read_stripe(&mut self, stripe: u64, off: u64, blocks: u64) -> block::Result<(Vec<Vec<u8>>, Vec<u8>, Option<Vec<u8>>)> {
+		let (data, p, q) = self.stripe_slots(stripe);
+		let moff = stripe * self.chunk + off;
+		let order = data.iter().enumerate()
+			.map(|(i, slot)| (*slot, Block::Data(i)))
+			.chain(Some((p, Block::P)))
+			.chain(q.map(|q| (q, Block::Q)))
+			.collect::<Vec<_>>();
+
+		let (mut bufs, mut missing, mut bad) = (Vec::new(), Vec::new(), Vec::new());
+		for &(slot, which) in &order {
+			let mut buf = block::alloc_buffer(self, blocks as usize)?;
+			if !self.readable(slot, moff, blocks) {
+				missing.push(which);
+			} else if self.read_member(slot, moff, &mut buf).is_err() {
+				missing.push(which);
+				bad.push((slot, which));
+			}
+			bufs.push(buf);
+		}
+		if missing.len() > self.members.len() - self.data_disks() {
+			return Err(block::Error::Io);
+		}
+
+		let mut q = q.and_then(|_| bufs.pop());
+		let mut p = bufs.pop().unwrap_or_default();
+		if !missing.is_empty() {
+			parity::recover(&mut bufs, &mut p, q.as_deref_mut(), &missing);
+		}
+		for (slot, which) in bad {
+			let buf = match which {
+				Block::Data(i) => bufs[i].clone(),
+				Block::P       => p.clone(),
+				Block::Q       => q.clone().unwrap_or_default()
+			};
+			self.correct(slot, moff, &buf);
+		}
+		Ok((bufs, p, q))
+	}
+
+	/// Reads up to the end of a chunk, returns the blocks read.
+	fn read_chunk(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<u64> {
+		let blocks = (buf.len() / self.block_size) as u64;
+		match self.level {
+			Level::Raid0 => {
+				let n = self.members.len() as u64;
+				let (k, off) = (lba / self.chunk, lba % self.chunk);
+				let (slot, moff, blocks) = ((k % n) as usize, k / n * self.chunk + off, blocks.min(self.chunk - off));
+				if !self.readable(slot, moff, blocks) {
+					return Err(block::Error::Io);
+				}
+				self.read_member(slot, moff, &mut buf[..blocks as usize * self.block_size])?;
+				Ok(blocks)
+			}
+			Level::Raid1 | Level::Raid10 => {
+				let (copies, left) = self.copies(lba);
+				let blocks = blocks.min(left);
+				let buf = &mut buf[..blocks as usize * self.block_size];
+				let mut bad = Vec::new();
+				for (slot, moff) in copies {
+					if !self.readable(slot, moff, blocks) {
+						continue;
+					} else if self.read_member(slot, moff, buf).is_err() {
+						bad.push((slot, moff));
+						continue;
+					}
+					for (slot, moff) in bad {
+						self.correct(slot, moff, buf);
+					}
+					return Ok(blocks);
+				}
+				Err(block::Error::Io)
+			}
+			Level::Raid5 | Level::Raid6 => {
+				let dd = self.data_disks() as u64;
+				let (k, off) = (lba / self.chunk, lba % self.chunk);
+				let (stripe, i) = (k / dd, (k % dd) as usize);
+				let blocks = blocks.min(self.chunk - off);
+				let buf = &mut buf[..blocks as usize * self.block_size];
+				let (moff, slot) = (stripe * self.chunk + off, self.stripe_slots(stripe).0[i]);
+				if !self.readable(slot, moff, blocks) || self.read_member(slot, moff, buf).is_err() {
+					let (data, _, _) = self.read_stripe(stripe, off, blocks)?;
+					buf.copy_from_slice(&data[i]);
+				}
+				Ok(blocks)
+			}
+		}
+	}
+
+	/// Writes up to the end of a chunk, or for RAID5 and RAID6 of a stripe, returns the
+	/// blocks written.
+	fn write_chunk(&mut self, lba: u64, buf: &[u8]) -> block::Result<u64> {
+		let (bs, blocks) = (self.block_size, (buf.len() / self.block_size) as u64);
+		match self.level {
+			Level::Raid0 => {
+				let n = self.members.len() as u64;
+				let (k, off) = (lba / self.chunk, lba % self.chunk);
+				let (slot, moff, blocks) = ((k % n) as usize, k / n * self.chunk + off, blocks.min(self.chunk - off));
+				match self.writable(slot) && self.write_member(slot, moff, &buf[..blocks as usize * bs]) {
+					true  => Ok(blocks),
+					false => Err(block::Error::Io)
+				}
+			}
+			Level::Raid1 | Level::Raid10 => {
+				let (copies, left) = self.copies(lba);
+				let blocks = blocks.min(left);
+				let mut written = false;
+				for (slot, moff) in copies {
+					if self.writable(slot) {
+						written |= self.write_member(slot, moff, &buf[..blocks as usize * bs]);
+					}
+				}
+				match written {
+					true  => Ok(blocks),
+					false => Err(block::Error::Io)
+				}
+			}
+			Level::Raid5 | Level::Raid6 => self.write_stripe(lba, buf)
+		}
+	}
+
+	/// Writes the part of a stripe starting at `lba` and recomputes its parity from the
+	/// new data and the data of the stripe that isn't written.
+	fn write_stripe(&mut self, lba: u64, buf: &[u8]) -> block::Result<u64> {
+		let bs = self.block_size;
+		let width = self.chunk * self.data_disks() as u64;
+		let (stripe, base) = (lba / width, lba / width * width);
+		let end = (lba + (buf.len() / bs) as u64).min(base + width);
+		let (first, last) = ((lba - base) / self.chunk, (end - 1 - base) / self.chunk);
+		// the range within the chunks that changes
+		let (lo, hi) = match first == last {
+			true  => ((lba - base) % self.chunk, (end - 1 - base) % self.chunk + 1),
+			false => (0, self.chunk)
+		};
+
+		let (mut data, mut p, mut q) = match lba == base && end == base + width {
+			true => {
+				let data = (0..self.data_disks()).map(|_| block::alloc_buffer(self, (hi - lo) as usize)).collect::<block::Result<Vec<_>>>()?;
+				let q = match self.level {
+					Level::Raid6 => Some(block::alloc_buffer(self, (hi - lo) as usize)?),
+					_            => None
+				};
+				(data, block::alloc_buffer(self, (hi - lo) as usize)?, q)
+			}
+			false => self.read_stripe(stripe, lo, hi - lo)?
+		};
+
+		let slots = self.stripe_slots(stripe);
+		let mut writes = Vec::new();
+		for i in first..=last {
+			let start = base + i * self.chunk;
+			let (s, e) = (lba.max(start), end.min(start + self.chunk));
+			let src = &buf[(s - lba) as usize * bs..(e - lba) as usize * bs];
+			let dst = (s - start - lo) as usize * bs;
+			data[i as usize][dst..dst + src.len()].copy_from_slice(src);
+			writes.push((slots.0[i as usize], stripe * self.chunk + s - start, i as usize, dst..dst + src.len()));
+		}
+
+		let refs = data.iter().map(|d| d.as_slice()).collect::<Vec<_>>();
+		parity::gen_p(&refs, &mut p);
+		if let Some(q) = q.as_mut() {
+			parity::gen_q(&refs, q);
+		}
+
+		let moff = stripe * self.chunk + lo;
+		for (slot, moff, i, range) in writes {
+			if self.writable(slot) {
+				self.write_member(slot, moff, &data[i][range]);
+			}
+		}
+		if self.writable(slots.1) {
+			self.write_member(slots.1, moff, &p);
+		}
+		if let (Some(slot), Some(q)) = (slots.2, q) {
+			if self.writable(slot) {
+				self.write_member(slot, moff, &q);
+			}
+		}
+		match self.can_run() {
+			true  => Ok(end - lba),
+			false => Err(block::Error::Io)
+		}
+	}
+
+	/// Reconstructs up to `blocks` blocks of a rebuilding member.
+	fn rebuild(&mut self, slot: usize, blocks: u64) -> Result<()> {
+		let offset = match self.members[slot].state {
+			State::Rebuilding(offset) => offset,
+			_                         => return Ok(())
+		};
+		let n = self.members.len() as u64;
+		let blocks = match self.level {
+			Level::Raid1 => blocks.min(self.size - offset),
+			_            => blocks.min(self.size - offset).min(self.chunk - offset % self.chunk)
+		};
+
+		let data = match self.level {
+			Level::Raid5 | Level::Raid6 => {
+				let stripe = offset / self.chunk;
+				let (slots, p, _) = self.stripe_slots(stripe);
+				let (data, pb, qb) = self.read_stripe(stripe, offset % self.chunk, blocks)?;
+				match slots.iter().position(|s| *s == slot) {
+					Some(i)            => Some(data[i].clone()),
+					None if slot == p => Some(pb),
+					None               => qb
+				}
+			}
+			_ => {
+				// the array block the member's block is a copy of
+				let lba = match self.level {
+					Level::Raid10 => (offset / self.chunk * n + slot as u64) / self.copies as u64 * self.chunk + offset % self.chunk,
+					_             => offset
+				};
+				match lba < self.capacity() {
+					true  => {
+						let mut buf = block::alloc_buffer(self, blocks as usize)?;
+						self.read_chunk(lba, &mut buf)?;
+						Some(buf)
+					}
+					// the end of RAID10 members may hold no chunk
+					false => None
+				}
+			}
+		};
+
+		if let Some(data) = data {
+			if !self.write_member(slot, offset, &data) {
+				return Ok(());
+			}
+		}
+		let end = offset + blocks;
+		self.members[slot].state = match end < self.size {
+			true  => State::Rebuilding(end),
+			false => State::InSync
+		};
+		let bs = self.block_size as u64;
+		if end >= self.size || end * bs / CHECKPOINT != offset * bs / CHECKPOINT {
+			self.write_superblocks()?;
+		}
+		Ok(())
+	}
+
+	/// Compares and, if `repair` is set, rewrites the redundancy of up to `blocks` blocks at
+	/// a resync position. Returns the blocks done and whether they were inconsistent.
+	fn sync(&mut self, pos: u64, blocks: u64, repair: bool) -> Result<(u64, bool)> {
+		match self.level {
+			Level::Raid5 | Level::Raid6 => {
+				let (stripe, off) = (pos / self.chunk, pos % self.chunk);
+				let blocks = blocks.min(self.chunk - off).min(self.size - pos);
+				let (data, p, q) = self.read_stripe(stripe, off, blocks)?;
+				let refs = data.iter().map(|d| d.as_slice()).collect::<Vec<_>>();
+				let (_, p_slot, q_slot) = self.stripe_slots(stripe);
+				let moff = stripe * self.chunk + off;
+
+				let mut expected = block::alloc_buffer(self, blocks as usize)?;
+				parity::gen_p(&refs, &mut expected);
+				let mut mismatch = p != expected;
+				if p != expected && repair && self.writable(p_slot) {
+					self.write_member(p_slot, moff, &expected);
+				}
+				if let (Some(slot), Some(q)) = (q_slot, q) {
+					parity::gen_q(&refs, &mut expected);
+					mismatch |= q != expected;
+					if q != expected && repair && self.writable(slot) {
+						self.write_member(slot, moff, &expected);
+					}
+				}
+				Ok((blocks, mismatch))
+			}
+			_ => {
+				let (copies, left) = self.copies(pos);
+				let blocks = blocks.min(left).min(self.sync_total() - pos);
+				let mut first: Option<Vec<u8>> = None;
+				let mut differ = Vec::new();
+				for (slot, moff) in copies {
+					if !self.readable(slot, moff, blocks) {
+						continue;
+					}
+					let mut buf = block::alloc_buffer(self, blocks as usize)?;
+					let ok = self.read_member(slot, moff, &mut buf).is_ok();
+					match &first {
+						None if ok                         => first = Some(buf),
+						Some(first) if ok && *first == buf => (),
+						_                                  => differ.push((slot, moff, ok))
+					}
+				}
+
+				let first = first.ok_or(Error::Block(block::Error::Io))?;
+				let mismatch = differ.iter().any(|(_, _, ok)| *ok);
+				for (slot, moff, ok) in differ {
+					// unreadable blocks are always rewritten
+					if repair || !ok {
+						self.write_member(slot, moff, &first);
+					}
+				}
+				Ok((blocks, mismatch))
+			}
+		}
+	}
+}
+
+impl<D: BlockDevice> BlockDevice for Array<D> {
+	fn block_size(&self) -> usize {
+		self.block_size
+	}
+
+	fn blocks(&self) -> u64 {
+		self.capacity()
+	}
+
+	fn read(&mut self, mut lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		let mut done = 0;
+		while done < buf.len() {
+			let blocks = self.read_chunk(lba, &mut buf[done..])?;
+			done += blocks as usize * self.block_size;
+			lba += blocks;
+		}
+		Ok(())
+	}
+
+	fn write(&mut self, mut lba: u64, buf: &[u8]) -> block::Result<()> {
+		if self.read_only {
+			return Err(block::Error::ReadOnly);
+		}
+		block::check_range(self, lba, buf.len())?;
+		self.mark_dirty()?;
+		let mut done = 0;
+		while done < buf.len() {
+			let blocks = self.write_chunk(lba, &buf[done..])?;
+			done += blocks as usize * self.block_size;
+			lba += blocks;
+		}
+		Ok(())
+	}
+
+	/// Flushes the members and marks the array clean.
+	fn flush(&mut self) -> block::Result<()> {
+		if self.read_only {
+			return Ok(());
+		}
+		self.flush_members();
+		if self.dirty {
+			self.dirty = false;
+			self.write_superblocks().map_err(block_error)?;
+			self.flush_members();
+		}
+		match self.can_run() {
+			true  => Ok(()),
+			false => Err(block::Error::Io)
+		}
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+}
+
+impl<D> core::fmt::Debug for Array<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Array")
+			.field("uuid", &{ self.sb.set_uuid })
+			.field("level", &self.level)
+			.field("members", &self.members.iter().map(|m| m.state).collect::<Vec<_>>())
+			.field("spares", &self.spares.len())
+			.field("size", &self.size)
+			.field("chunk", &self.chunk)
+			.field("dirty", &self.dirty)
+			.field("resync", &self.resync)
+			.field("read_only", &self.read_only)
+			.finish()
+	}
+}
+
+fn min_members(level: Level, copies: usize) -> usize {
+	match level {
+		Level::Raid0 | Level::Raid1 => 1,
+		Level::Raid5                => 2,
+		Level::Raid6                => 4,
+		Level::Raid10               => copies.max(2)
+	}
+}
+
+/// A device UUID derived from the array's, there is no source of randomness.
+fn device_uuid(uuid: u128, number: u32) -> u128 {
+	uuid.rotate_left(64) ^ ((number as u128 + 1) * 0x9E37_79B9_7F4A_7C15)
+}
+
+fn block_error(e: Error) -> block::Error {
+	match e {
+		Error::Block(e) => e,
+		_               => block::Error::Io
+	}
+}
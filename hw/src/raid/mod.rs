@@ -0,0 +1,226 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Software RAID in the format of Linux' md.
+//!
+//! `Array` stacks on the block devices of its members and is a `BlockDevice` itself. Each
+//! member carries an md version 1.2 superblock 4 KiB from its start, so arrays created by
+//! `mdadm` can be assembled and the other way around. RAID0, RAID1, RAID5 and RAID6 with the
+//! left-symmetric layout and RAID10 with the near layout are supported.
+//!
+//! Redundant arrays keep working while members are missing or failed. Resyncing after an
+//! unclean shutdown, rebuilding members and scrubbing happen in the background, i.e. in the
+//! steps `Array::step` is called for.
+
+mod array;
+mod parity;
+
+pub use array::*;
+
+use crate::block;
+
+pub const MAGIC:         u32 = 0xA92B4EFC;
+pub const MAJOR_VERSION: u32 = 1;
+
+/// md addresses everything in 512 byte sectors
+pub const SECTOR_SIZE:  u64 = 512;
+/// The version 1.2 superblock starts 4 KiB into the member
+pub const SUPER_OFFSET: u64 = 8;
+/// Bytes read and written for the superblock and the role table following it
+pub const SUPER_SIZE:   usize = 4096;
+/// `resync_offset` of arrays that are in sync
+pub const MAX_SECTOR:   u64 = !0;
+
+pub const FEATURE_BITMAP_OFFSET:     u32 = 0x0001;
+pub const FEATURE_RECOVERY_OFFSET:   u32 = 0x0002;
+pub const FEATURE_RESHAPE_ACTIVE:    u32 = 0x0004;
+pub const FEATURE_BAD_BLOCKS:        u32 = 0x0008;
+pub const FEATURE_REPLACEMENT:       u32 = 0x0010;
+pub const FEATURE_RESHAPE_BACKWARDS: u32 = 0x0020;
+pub const FEATURE_NEW_OFFSET:        u32 = 0x0040;
+pub const FEATURE_RECOVERY_BITMAP:   u32 = 0x0080;
+pub const FEATURE_CLUSTERED:         u32 = 0x0100;
+pub const FEATURE_JOURNAL:           u32 = 0x0200;
+pub const FEATURE_PPL:               u32 = 0x0400;
+pub const FEATURE_MULTIPLE_PPLS:     u32 = 0x0800;
+pub const FEATURE_RAID0_LAYOUT:      u32 = 0x1000;
+/// Features arrays may have. The write intent bitmap isn't updated, arrays with one are
+/// assembled read-only.
+pub const FEATURES_SUPPORTED:        u32 = FEATURE_BITMAP_OFFSET | FEATURE_RECOVERY_OFFSET
+	| FEATURE_BAD_BLOCKS | FEATURE_RAID0_LAYOUT;
+
+pub const ROLE_SPARE:   u16 = 0xFFFF;
+pub const ROLE_FAULTY:  u16 = 0xFFFE;
+pub const ROLE_JOURNAL: u16 = 0xFFFD;
+
+pub const LAYOUT_LEFT_SYMMETRIC: u32 = 2;
+/// RAID10 layouts are the near copies plus the far copies shifted by this
+pub const LAYOUT_FAR_SHIFT:      u32 = 8;
+pub const LAYOUT_OFFSET:         u32 = 0x10000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// Reading or writing a member failed
+	Block(block::Error),
+	/// The device has no md superblock
+	NoSuperblock,
+	/// The superblock's checksum is wrong or its fields are inconsistent
+	Corrupted,
+	/// The level, layout or a feature of the array isn't supported
+	Unsupported,
+	/// The devices belong to different arrays or claim the same slot
+	Mismatch,
+	/// Too many members are missing or failed for the array to work
+	Failed,
+	/// The devices or arguments don't make up a valid array or operation
+	InvalidArgument,
+	/// Background work that can't be interrupted is running
+	Busy
+}
+
+impl From<block::Error> for Error {
+	fn from(e: block::Error) -> Self {
+		Self::Block(e)
+	}
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Level {
+	Raid0,
+	Raid1,
+	Raid5,
+	Raid6,
+	Raid10
+}
+
+impl Level {
+	pub fn from_md(level: u32) -> Option<Self> {
+		Some(match level {
+			0  => Self::Raid0,
+			1  => Self::Raid1,
+			5  => Self::Raid5,
+			6  => Self::Raid6,
+			10 => Self::Raid10,
+			_  => return None
+		})
+	}
+
+	pub fn md(self) -> u32 {
+		match self {
+			Self::Raid0  => 0,
+			Self::Raid1  => 1,
+			Self::Raid5  => 5,
+			Self::Raid6  => 6,
+			Self::Raid10 => 10
+		}
+	}
+
+	/// Whether the level stores parity, i.e. is RAID5 or RAID6.
+	pub fn has_parity(self) -> bool {
+		matches!(self, Self::Raid5 | Self::Raid6)
+	}
+
+	/// Whether the array survives failed members.
+	pub fn is_redundant(self) -> bool {
+		self != Self::Raid0
+	}
+}
+
+/// The md version 1 superblock, followed by the `u16` role of each device number.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Superblock {
+	pub magic:              u32,
+	pub major_version:      u32,
+	pub feature_map:        u32,
+	pub _pad0:              u32,
+	pub set_uuid:           u128,
+	pub set_name:           [u8; 32],
+	/// Creation time, seconds in the low 40 bits
+	pub ctime:              u64,
+	pub level:              u32,
+	pub layout:             u32,
+	/// Sectors of each member used by the array
+	pub size:               u64,
+	/// Chunk size in sectors
+	pub chunksize:          u32,
+	pub raid_disks:         u32,
+	pub bitmap_offset:      u32,
+	pub new_level:          u32,
+	pub reshape_position:   u64,
+	pub delta_disks:        u32,
+	pub new_layout:         u32,
+	pub new_chunk:          u32,
+	pub new_offset:         u32,
+	/// First sector of the data on this member
+	pub data_offset:        u64,
+	/// Sectors available for data on this member
+	pub data_size:          u64,
+	pub super_offset:       u64,
+	/// Sectors of this member rebuilt so far, if `FEATURE_RECOVERY_OFFSET` is set
+	pub recovery_offset:    u64,
+	/// Index of this member in the role table
+	pub dev_number:         u32,
+	pub cnt_corrected_read: u32,
+	pub device_uuid:        u128,
+	pub devflags:           u8,
+	pub bblog_shift:        u8,
+	pub bblog_size:         u16,
+	pub bblog_offset:       u32,
+	/// Time of the last update, seconds in the low 40 bits
+	pub utime:              u64,
+	/// Incremented with every update, members with fewer events are stale
+	pub events:             u64,
+	/// Sector up to which the array is in sync, `MAX_SECTOR` if it is clean
+	pub resync_offset:      u64,
+	pub sb_csum:            u32,
+	/// Number of entries in the role table
+	pub max_dev:            u32,
+	pub _pad3:              [u8; 32]
+}
+
+impl Superblock {
+	pub fn is_valid(&self) -> bool {
+		self.magic == MAGIC && self.major_version == MAJOR_VERSION
+	}
+
+	/// The checksum of the superblock and the role table with `sb_csum` cleared, `bytes`
+	/// holds both.
+	pub fn checksum(&self, bytes: &[u8]) -> u32 {
+		let len = (core::mem::size_of::<Self>() + self.max_dev as usize * 2).min(bytes.len());
+		let sum = bytes[..len].chunks(4).enumerate()
+			.filter(|(i, _)| *i != 0xD8 / 4)
+			.map(|(_, c)| match c.len() {
+				4 => u32::from_le_bytes([c[0], c[1], c[2], c[3]]) as u64,
+				_ => u16::from_le_bytes([c[0], c[1]]) as u64
+			})
+			.sum::<u64>();
+		((sum & 0xFFFF_FFFF) + (sum >> 32)) as u32
+	}
+
+	pub fn name(&self) -> &str {
+		let len = self.set_name.iter().position(|b| *b == 0).unwrap_or(32);
+		core::str::from_utf8(&self.set_name[..len]).unwrap_or("")
+	}
+}
@@ -0,0 +1,142 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Parity of RAID5 and RAID6 stripes.
+//!
+//! P is the XOR of the data blocks, Q the sum of `g^i * D_i` in GF(2^8) with the generator
+//! `g = 2` and the polynomial `x^8 + x^4 + x^3 + x^2 + 1`, as in Linux' `raid6` library.
+
+use alloc::vec::Vec;
+
+const fn tables() -> ([u8; 512], [u8; 256]) {
+	let (mut exp, mut log) = ([0u8; 512], [0u8; 256]);
+	let (mut i, mut x) = (0, 1u16);
+	while i < 255 {
+		exp[i] = x as u8;
+		exp[i + 255] = x as u8;
+		log[x as usize] = i as u8;
+		x <<= 1;
+		if x & 0x100 != 0 {
+			x ^= 0x11D;
+		}
+		i += 1;
+	}
+	(exp, log)
+}
+
+const TABLES: ([u8; 512], [u8; 256]) = tables();
+const EXP: [u8; 512] = TABLES.0;
+const LOG: [u8; 256] = TABLES.1;
+
+fn mul(a: u8, b: u8) -> u8 {
+	match a == 0 || b == 0 {
+		true  => 0,
+		false => EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+	}
+}
+
+/// `g^n`
+fn pow(n: usize) -> u8 {
+	EXP[n % 255]
+}
+
+fn inv(a: u8) -> u8 {
+	EXP[255 - LOG[a as usize] as usize]
+}
+
+fn xor(dst: &mut [u8], src: &[u8]) {
+	dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= s);
+}
+
+/// `dst ^= c * src`
+fn mul_xor(dst: &mut [u8], src: &[u8], c: u8) {
+	dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= mul(c, *s));
+}
+
+/// P of the data blocks.
+pub fn gen_p(data: &[&[u8]], p: &mut [u8]) {
+	p.fill(0);
+	data.iter().for_each(|d| xor(p, d));
+}
+
+/// Q of the data blocks.
+pub fn gen_q(data: &[&[u8]], q: &mut [u8]) {
+	q.fill(0);
+	data.iter().enumerate().for_each(|(i, d)| mul_xor(q, d, pow(i)));
+}
+
+/// A block of a stripe
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Block {
+	/// The data block with the index in syndrome order
+	Data(usize),
+	P,
+	Q
+}
+
+/// Recomputes the missing blocks of a stripe. `data` are the data blocks in syndrome order,
+/// `q` is `None` for RAID5. At most two blocks may be missing, one for RAID5.
+pub fn recover(data: &mut [Vec<u8>], p: &mut [u8], q: Option<&mut [u8]>, missing: &[Block]) {
+	let lost = missing.iter().filter_map(|b| match b { Block::Data(i) => Some(*i), _ => None }).collect::<Vec<_>>();
+	let (p_lost, q_lost) = (missing.contains(&Block::P), missing.contains(&Block::Q));
+
+	match (lost.as_slice(), q.as_deref()) {
+		// P recovers a single data block if it is intact
+		(&[x], _) if !p_lost => {
+			let mut d = p.to_vec();
+			data.iter().enumerate().filter(|(i, _)| *i != x).for_each(|(_, b)| xor(&mut d, b));
+			data[x] = d;
+		}
+		(&[x], Some(q)) => {
+			let mut d = q.to_vec();
+			data.iter().enumerate().filter(|(i, _)| *i != x).for_each(|(i, b)| mul_xor(&mut d, b, pow(i)));
+			let c = inv(pow(x));
+			d.iter_mut().for_each(|b| *b = mul(*b, c));
+			data[x] = d;
+		}
+		(&[x, y], Some(q)) => {
+			let (x, y) = (x.min(y), x.max(y));
+			let (mut pxy, mut qxy) = (p.to_vec(), q.to_vec());
+			for (i, b) in data.iter().enumerate().filter(|(i, _)| *i != x && *i != y) {
+				xor(&mut pxy, b);
+				mul_xor(&mut qxy, b, pow(i));
+			}
+			// D_x = (g^(y-x) * Pxy + g^-x * Qxy) / (g^(y-x) + 1), D_y = Pxy + D_x
+			let gyx = pow(y - x);
+			let denom = inv(gyx ^ 1);
+			let (a, b) = (mul(gyx, denom), mul(inv(pow(x)), denom));
+			let dx = pxy.iter().zip(&qxy).map(|(p, q)| mul(a, *p) ^ mul(b, *q)).collect::<Vec<_>>();
+			xor(&mut pxy, &dx);
+			data[x] = dx;
+			data[y] = pxy;
+		}
+		_ => ()
+	}
+
+	let refs = data.iter().map(|d| d.as_slice()).collect::<Vec<_>>();
+	if p_lost {
+		gen_p(&refs, p);
+	}
+	if let (true, Some(q)) = (q_lost, q) {
+		gen_q(&refs, q);
+	}
+}
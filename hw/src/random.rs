@@ -0,0 +1,180 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A ChaCha20 based entropy pool.
+//!
+//! Entropy is mixed into a 256 bit key: each 32 byte chunk of the input is XORed into
+//! the key, which is then replaced by the first half of a ChaCha20 block keyed with it.
+//! Output is the ChaCha20 key stream of the current key; after every request the key
+//! is replaced by a block of the stream that was not handed out (fast key erasure), so
+//! earlier output can't be reconstructed from the state.
+//!
+//! The pool counts the bits of entropy credited by the sources and is seeded once it
+//! got `SEED_BITS`, output before that is only as good as what was mixed in.
+
+/// Bits of entropy the pool needs before its output is considered secure
+pub const SEED_BITS: usize = 256;
+/// Bytes of the key stream produced per rekey, larger requests are split
+pub const MAX_REQUEST: usize = 64 * 1024;
+
+const BLOCK_LEN: usize = 64;
+/// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+/// First nonce word when mixing input, to separate it from output
+const NONCE_MIX: u32 = 1;
+const NONCE_OUTPUT: u32 = 2;
+
+#[inline(always)]
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+	s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+	s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+	s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+	s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+}
+
+/// The ChaCha20 block function of RFC 8439.
+pub fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; BLOCK_LEN] {
+	let mut input = [0u32; 16];
+	input[..4].copy_from_slice(&SIGMA);
+	input[4..12].copy_from_slice(key);
+	input[12] = counter;
+	input[13..].copy_from_slice(nonce);
+
+	let mut s = input;
+	for _ in 0..10 {
+		quarter_round(&mut s, 0, 4,  8, 12);
+		quarter_round(&mut s, 1, 5,  9, 13);
+		quarter_round(&mut s, 2, 6, 10, 14);
+		quarter_round(&mut s, 3, 7, 11, 15);
+		quarter_round(&mut s, 0, 5, 10, 15);
+		quarter_round(&mut s, 1, 6, 11, 12);
+		quarter_round(&mut s, 2, 7,  8, 13);
+		quarter_round(&mut s, 3, 4,  9, 14);
+	}
+
+	let mut out = [0; BLOCK_LEN];
+	for (i, (w, x)) in s.iter().zip(input.iter()).enumerate() {
+		out[4 * i..4 * i + 4].copy_from_slice(&w.wrapping_add(*x).to_le_bytes());
+	}
+	out
+}
+
+fn key_from(bytes: &[u8]) -> [u32; 8] {
+	let mut key = [0; 8];
+	for (k, b) in key.iter_mut().zip(bytes.chunks_exact(4)) {
+		*k = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+	}
+	key
+}
+
+#[derive(Clone)]
+pub struct Pool {
+	key:        [u32; 8],
+	/// Rekeys so far, part of the nonce so no two requests share a key stream
+	generation: u64,
+	entropy:    usize,
+	seeded:     bool
+}
+
+impl Pool {
+	pub const fn new() -> Self {
+		Self { key: [0; 8], generation: 0, entropy: 0, seeded: false }
+	}
+
+	/// Mixes `data` into the key and credits `bits` of entropy, at most 8 per byte.
+	pub fn add(&mut self, data: &[u8], bits: usize) {
+		for chunk in data.chunks(32) {
+			let mut buf = [0; 32];
+			buf[..chunk.len()].copy_from_slice(chunk);
+			for (k, w) in self.key.iter_mut().zip(key_from(&buf).iter()) {
+				*k ^= w;
+			}
+			let nonce = [NONCE_MIX, chunk.len() as u32, self.generation as u32];
+			self.key = key_from(&chacha20_block(&self.key, 0, &nonce)[..32]);
+			self.generation = self.generation.wrapping_add(1);
+		}
+
+		self.entropy = self.entropy.saturating_add(bits.min(8 * data.len())).min(SEED_BITS);
+		self.seeded |= self.entropy >= SEED_BITS;
+	}
+
+	/// Credits `bits` of entropy for input added before without credit, e.g. because it
+	/// was only judged afterwards.
+	pub fn credit(&mut self, bits: usize) {
+		self.entropy = self.entropy.saturating_add(bits).min(SEED_BITS);
+		self.seeded |= self.entropy >= SEED_BITS;
+	}
+
+	/// Bits of entropy credited so far, up to `SEED_BITS`.
+	pub fn entropy(&self) -> usize {
+		self.entropy
+	}
+
+	pub fn is_seeded(&self) -> bool {
+		self.seeded
+	}
+
+	/// Fills `buf` with the key stream and rekeys, regardless of whether the pool is
+	/// seeded yet.
+	pub fn fill(&mut self, buf: &mut [u8]) {
+		for request in buf.chunks_mut(MAX_REQUEST) {
+			let nonce = [NONCE_OUTPUT, self.generation as u32, (self.generation >> 32) as u32];
+			for (i, chunk) in request.chunks_mut(BLOCK_LEN).enumerate() {
+				let block = chacha20_block(&self.key, i as u32 + 1, &nonce);
+				chunk.copy_from_slice(&block[..chunk.len()]);
+			}
+			// block 0 is never handed out, it becomes the next key
+			self.key = key_from(&chacha20_block(&self.key, 0, &nonce)[..32]);
+			self.generation = self.generation.wrapping_add(1);
+		}
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		let mut buf = [0; 8];
+		self.fill(&mut buf);
+		u64::from_le_bytes(buf)
+	}
+}
+
+impl Default for Pool {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl core::fmt::Debug for Pool {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Pool")
+			.field("entropy", &self.entropy)
+			.field("seeded", &self.seeded)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Estimates the entropy of timer samples taken around work of varying duration,
+/// conservatively, as one bit per 8 changes in the time between samples. Returns 0 if
+/// the timer is too coarse to show any jitter.
+pub fn jitter_bits(samples: &[u64]) -> usize {
+	samples.windows(3)
+		.filter(|w| w[1].wrapping_sub(w[0]) != w[2].wrapping_sub(w[1]))
+		.count() / 8
+}
@@ -20,29 +20,45 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-#[repr(C)]
-#[derive(Debug)]
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
 pub struct SmBios2EntryPoint {
 	pub anchor:                  [u8; 4],
 	pub checksum:                u8,
 	pub length:                  u8,
 	pub major_version:           u8,
 	pub minor_version:           u8,
-	pub max_struct_size:         u32,
+	pub max_struct_size:         u16,
 	pub revision:                u8,
 	pub formatted_area:          [u8; 5],
 	pub intermediate_anchor:     [u8; 5],
 	pub intermediate_checksum:   u8,
-	pub structure_table_length:  u32,
-	pub structure_table_address: u64,
-	pub number_of_structures:    u32,
+	pub structure_table_length:  u16,
+	pub structure_table_address: u32,
+	pub number_of_structures:    u16,
 	pub bcd_revision:            u8
 }
 
-#[repr(C)]
-#[derive(Debug)]
+impl SmBios2EntryPoint {
+	pub const ANCHOR:              [u8; 4] = *b"_SM_";
+	pub const INTERMEDIATE_ANCHOR: [u8; 5] = *b"_DMI_";
+
+	/// Checks both anchors, the checksum over `length` bytes and the intermediate
+	/// checksum over the 15 bytes starting at the intermediate anchor.
+	pub fn is_valid(&self) -> bool {
+		let bytes = unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) };
+		self.anchor == Self::ANCHOR
+			&& self.intermediate_anchor == Self::INTERMEDIATE_ANCHOR
+			&& bytes.len() >= 0x1F
+			&& checksum(bytes)
+			&& checksum(&bytes[0x10..0x1F])
+	}
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
 pub struct SmBios3EntryPoint {
-	pub anchor:                   [u8; 4],
+	pub anchor:                   [u8; 5],
 	pub checksum:                 u8,
 	pub length:                   u8,
 	pub major_version:            u8,
@@ -50,6 +66,20 @@ pub struct SmBios3EntryPoint {
 	pub docrev:                   u8,
 	pub revision:                 u8,
 	pub _reserved0:               u8,
-	pub structure_table_max_size: u64,
-	pub structure_table_address:  u128
-}
\ No newline at end of file
+	pub structure_table_max_size: u32,
+	pub structure_table_address:  u64
+}
+
+impl SmBios3EntryPoint {
+	pub const ANCHOR: [u8; 5] = *b"_SM3_";
+
+	/// Checks the anchor and the checksum over `length` bytes.
+	pub fn is_valid(&self) -> bool {
+		let bytes = unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) };
+		self.anchor == Self::ANCHOR && bytes.len() >= 0x18 && checksum(bytes)
+	}
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+	bytes.iter().fold(0u8, |acc, v| acc.wrapping_add(*v)) == 0
+}
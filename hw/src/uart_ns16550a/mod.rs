@@ -20,7 +20,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use hw::arch::*;
+use crate::arch::*;
 
 const DIVISOR: u16 = 592;
 
@@ -21,7 +21,7 @@
 // SOFTWARE.
 
 use core::{ptr::{null, null_mut}, mem::size_of};
-use crate::utils::DbgBin;
+use crate::{utils::DbgBin, acpi, smbios};
 
 pub type Status = isize;
 pub type Handle = *mut u8;
@@ -368,13 +368,13 @@ pub struct BootServices {
 }
 
 impl BootServices {
-	pub fn get_memory_map(&self, size: &mut usize, memory_map: *mut u8, map_key: &mut usize, descriptor_size: &mut usize, descriptor_version: &mut usize) -> Status {
+	pub fn get_memory_map(&self, size: &mut usize, memory_map: *mut u8, map_key: &mut usize, descriptor_size: &mut usize, descriptor_version: &mut u32) -> Status {
         (self.get_memory_map)(
-            &mut size,
+            size,
 			memory_map,
-			&mut map_key,
-			&mut descriptor_size,
-			&mut descriptor_version
+			map_key,
+			descriptor_size,
+			descriptor_version
 		)
     }
 
@@ -478,7 +478,7 @@ impl<'a, const N: usize> IntoIterator for &'a MemoryMap<N> {
 
     fn into_iter(self) -> Self::IntoIter {
         MemoryMapIter {
-            buf: &self.buf[self.size],
+            buf: &self.buf[..self.size],
             len: self.desc,
         }
     }
@@ -1,33 +1,8 @@
-
-#[derive(Copy, Clone, Debug, Default)]
-pub struct Ptr32<T> {
-    ptr:    u32,
-    marker: PhantomData<T>,
-}
-
-impl<T> Ptr32<T> {
-    pub fn get(self) -> *mut T {
-        self.ptr as usize as _
-    }
-}
-
-#[derive(Copy, Clone, Debug, Default)]
-pub struct Ptr64<T> {
-    ptr:    u64,
-	marker: PhantomData<T>,
-}
-
-impl<T> Ptr64<T> {
-    pub fn get(self) -> *mut T {
-        self.ptr as usize as _
-    }
-}
-
 #[macro_export]
 macro_rules! define_ro_bit {
     ( $getter_name:ident, $bit:expr ) => {
 		pub fn $getter_name(&self) -> bool {
-			self.0 & !(1 << $bit) != 0
+			self.0 & (1 << $bit) != 0
 		}
 	};
 }
@@ -39,7 +14,7 @@ macro_rules! define_wo_bit {
 			if v {
 				self.0 |= 1 << $bit;
 			} else {
-				self.0 &= 1 << !$bit;
+				self.0 &= !(1 << $bit);
 			}
 		}
 	};
@@ -48,41 +23,42 @@ macro_rules! define_wo_bit {
 #[macro_export]
 macro_rules! define_rw_bit {
     ( $getter_name:ident, $setter_name:ident, $bit:expr ) => {
-		define_ro_bit!($getter_name, $bit);
-		define_wo_bit!($setter_name, $bit);
+		$crate::define_ro_bit!($getter_name, $bit);
+		$crate::define_wo_bit!($setter_name, $bit);
 	};
 }
 
+/// Defines getters and setters for single bits and inclusive bit ranges of a newtype
+/// wrapping an integer, e.g. `RW 0, get_enable, set_enable; RO 1..3, get_count;`.
+#[macro_export]
 macro_rules! define_bits {
-    ( $( $tt:tt );* ) => {
-		$( define_bits!( $tt ); )*
-	};
-	( RO $bit:expr:$end:expr, $getter:ident ) => {
-		pub fn $getter(&self) -> bool {
-			self.0 & !(1 << $bit) != 0
+    () => {};
+	( RO $lo:literal..$hi:literal, $getter:ident; $( $rest:tt )* ) => {
+		pub fn $getter(&self) -> u32 {
+			(self.0 as u32 >> $lo) & !(!0u32 << ($hi - $lo + 1))
 		}
+
+		$crate::define_bits!( $( $rest )* );
 	};
-	( WO $bit:expr, $setter:ident ) => {
-		pub fn $setter(&self) -> bool {
-			self.0 & !(1 << $bit) != 0
-		}
+	( RO $bit:literal, $getter:ident; $( $rest:tt )* ) => {
+		$crate::define_ro_bit!($getter, $bit);
+		$crate::define_bits!( $( $rest )* );
 	};
-    ( RW $bit:expr, $getter:ident, $setter:ident ) => {
-		define_bits!(RO $bit, $getter);
-		define_bits!(WO $bit, $setter);
-	};( RO $bit:expr, $getter:ident ) => {
-		pub fn $getter(&self) -> bool {
-			self.0 & !(1 << $bit) != 0
+	( RW $lo:literal..$hi:literal, $getter:ident, $setter:ident; $( $rest:tt )* ) => {
+		pub fn $getter(&self) -> u32 {
+			(self.0 as u32 >> $lo) & !(!0u32 << ($hi - $lo + 1))
 		}
-	};
-	( WO $bit:expr, $setter:ident ) => {
-		pub fn $setter(&self) -> bool {
-			self.0 & !(1 << $bit) != 0
+
+		pub fn $setter(&mut self, v: u32) {
+			let mask = !(!0u32 << ($hi - $lo + 1)) << $lo;
+			self.0 = ((self.0 as u32 & !mask) | (v << $lo & mask)) as _;
 		}
+
+		$crate::define_bits!( $( $rest )* );
 	};
-    ( RW $bit:expr, $getter:ident, $setter:ident ) => {
-		define_bits!(RO $bit, $getter);
-		define_bits!(WO $bit, $setter);
+	( RW $bit:literal, $getter:ident, $setter:ident; $( $rest:tt )* ) => {
+		$crate::define_rw_bit!($getter, $setter, $bit);
+		$crate::define_bits!( $( $rest )* );
 	};
 }
 
@@ -0,0 +1,154 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! 9P transport.
+//!
+//! The device shares a directory of the host, named by the mount tag in the
+//! configuration. It has a single request queue: the driver posts a 9P request followed
+//! by a writable buffer for the response, one request at a time. The protocol itself is
+//! spoken by `p9::Client`, which the device serves as its `p9::Channel`.
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, FEATURE_RING_PACKED},
+	crate::{dma::{Dma, Region, PAGE_SIZE}, p9},
+	alloc::string::String,
+};
+
+/// The configuration contains the mount tag
+pub const FEATURE_MOUNT_TAG: u64 = 1 << 0;
+
+const CONFIG_TAG_LEN: usize = 0x0;
+const CONFIG_TAG:     usize = 0x2;
+
+const QUEUE_SIZE: u16 = 4;
+/// Largest message in either direction
+const MSIZE: usize = p9::MAX_MSIZE;
+const REQUEST_TIMEOUT_US: u64 = 5_000_000;
+
+pub struct Virtio9p<T: Transport, D: Dma> {
+	transport: T,
+	dma:       D,
+	vq:        Option<Virtqueue>,
+	/// The request followed by the response
+	buffers:   Option<Region>,
+	features:  u64,
+	tag:       String
+}
+
+impl<T: Transport, D: Dma> Virtio9p<T, D> {
+	pub fn new(mut transport: T, mut dma: D) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::_9pTransport as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, FEATURE_MOUNT_TAG | FEATURE_RING_PACKED)?;
+		let mut tag = String::new();
+		if features & FEATURE_MOUNT_TAG != 0 {
+			let mut buf = [0u8; 256];
+			let len = (transport.read_config_u16(CONFIG_TAG_LEN) as usize).min(buf.len());
+			transport.read_config_bytes(CONFIG_TAG, &mut buf[..len]);
+			tag = String::from_utf8_lossy(&buf[..len]).trim_end_matches('\0').into();
+		}
+		let mut dev = Self { transport, dma, vq: None, buffers: None, features, tag };
+
+		let mut vq = Virtqueue::new(&mut dev.transport, &mut dev.dma, 0, QUEUE_SIZE, features & FEATURE_RING_PACKED != 0)?;
+		vq.set_interrupts(false);
+		dev.vq = Some(vq);
+		dev.buffers = Some(Region::alloc(&mut dev.dma, 2 * MSIZE, PAGE_SIZE).ok_or(Error::NoMemory)?);
+		dev.transport.driver_ok();
+		Ok(dev)
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	/// The mount tag naming the share, empty if the device has none.
+	pub fn tag(&self) -> &str {
+		&self.tag
+	}
+
+	/// Sends a request and waits for the response, returns its length.
+	pub fn transfer(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+		let (Some(vq), Some(buffers)) = (self.vq.as_mut(), self.buffers.as_ref()) else { return Err(Error::NoQueue) };
+		if request.len() > MSIZE {
+			return Err(Error::InvalidArgument);
+		}
+		let len = response.len().min(MSIZE);
+
+		unsafe { core::ptr::copy_nonoverlapping(request.as_ptr(), buffers.virt, request.len()); }
+		let token = vq.push(&[
+			Buffer::read(buffers.phys, request.len() as u32),
+			Buffer::write(buffers.phys + MSIZE as u64, len as u32)
+		])?;
+		vq.notify(&mut self.transport);
+
+		for _ in 0..REQUEST_TIMEOUT_US / 10 {
+			match vq.pop() {
+				Some((t, written)) if t == token => {
+					let written = (written as usize).min(len);
+					unsafe { core::ptr::copy_nonoverlapping(buffers.virt.add(MSIZE), response.as_mut_ptr(), written); }
+					return Ok(written);
+				}
+				Some(_) => (),
+				None => self.dma.stall(10)
+			}
+		}
+		Err(Error::Timeout)
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`.
+	pub fn interrupt(&mut self) -> u32 {
+		self.transport.ack_interrupt()
+	}
+}
+
+impl<T: Transport, D: Dma> p9::Channel for Virtio9p<T, D> {
+	fn max_size(&self) -> usize {
+		MSIZE
+	}
+
+	fn request(&mut self, request: &[u8], response: &mut [u8]) -> p9::Result<usize> {
+		self.transfer(request, response).map_err(|_| p9::Error::Transport)
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Virtio9p<T, D> {
+	fn drop(&mut self) {
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		if let Some(vq) = self.vq.take() {
+			vq.free(&mut self.dma);
+		}
+		if let Some(buffers) = self.buffers.take() {
+			buffers.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Virtio9p<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Virtio9p")
+			.field("features", &self.features)
+			.field("tag", &self.tag)
+			.finish()
+	}
+}
@@ -0,0 +1,470 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Block devices, disks of 512 byte sectors.
+//!
+//! A request is a header, the data and a status byte the device writes. Each queue has
+//! one request in flight at a time, its header, status and indirect descriptor table
+//! are in a page that belongs to the queue. Requests are polled, the queues don't
+//! signal used buffers.
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, MAX_QUEUE_SIZE, FEATURE_INDIRECT_DESC, FEATURE_RING_PACKED},
+	crate::dma::{Dma, Region, PAGE_SIZE},
+	alloc::vec::Vec
+};
+
+/// Maximum size of a segment in `size_max`
+pub const FEATURE_SIZE_MAX:     u64 = 1 << 1;
+/// Maximum number of segments of a request in `seg_max`
+pub const FEATURE_SEG_MAX:      u64 = 1 << 2;
+/// Read-only device
+pub const FEATURE_RO:           u64 = 1 << 5;
+/// Logical block size in `blk_size`
+pub const FEATURE_BLK_SIZE:     u64 = 1 << 6;
+/// Cache flush requests
+pub const FEATURE_FLUSH:        u64 = 1 << 9;
+/// Number of request queues in `num_queues`
+pub const FEATURE_MQ:           u64 = 1 << 12;
+/// Discard requests, limits in `max_discard_*`
+pub const FEATURE_DISCARD:      u64 = 1 << 13;
+/// Write zeroes requests, limits in `max_write_zeroes_*`
+pub const FEATURE_WRITE_ZEROES: u64 = 1 << 14;
+
+pub const REQ_IN:           u32 = 0;
+pub const REQ_OUT:          u32 = 1;
+pub const REQ_FLUSH:        u32 = 4;
+pub const REQ_GET_ID:       u32 = 8;
+pub const REQ_DISCARD:      u32 = 11;
+pub const REQ_WRITE_ZEROES: u32 = 13;
+
+pub const STATUS_OK:     u8 = 0;
+pub const STATUS_IOERR:  u8 = 1;
+pub const STATUS_UNSUPP: u8 = 2;
+
+/// Write zeroes may deallocate the blocks
+pub const SEGMENT_FLAG_UNMAP: u32 = 1 << 0;
+
+/// Offsets of the fields of the device configuration
+pub const CONFIG_CAPACITY:                 usize = 0;
+pub const CONFIG_SIZE_MAX:                 usize = 8;
+pub const CONFIG_SEG_MAX:                  usize = 12;
+pub const CONFIG_BLK_SIZE:                 usize = 20;
+pub const CONFIG_NUM_QUEUES:               usize = 34;
+pub const CONFIG_MAX_DISCARD_SECTORS:      usize = 36;
+pub const CONFIG_DISCARD_ALIGNMENT:        usize = 44;
+pub const CONFIG_MAX_WRITE_ZEROES_SECTORS: usize = 48;
+
+/// Sector numbers and the capacity are in 512 byte units whatever the block size
+pub const SECTOR_SIZE: usize = 512;
+/// Data segments of a request, the most the indirect table holds
+pub const MAX_SEGMENTS: usize = 128;
+/// Longer chains go through the indirect table
+pub const INDIRECT_THRESHOLD: usize = 4;
+
+const SUPPORTED: u64 = FEATURE_SIZE_MAX | FEATURE_SEG_MAX | FEATURE_RO | FEATURE_BLK_SIZE | FEATURE_FLUSH
+	| FEATURE_MQ | FEATURE_DISCARD | FEATURE_WRITE_ZEROES | FEATURE_INDIRECT_DESC | FEATURE_RING_PACKED;
+const REQUEST_TIMEOUT_US: u64 = 30_000_000;
+
+/// Layout of the request page of a queue
+const PAGE_STATUS:  usize = 16;
+const PAGE_SEGMENT: usize = 32;
+const PAGE_TABLE:   usize = 1024;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RequestHeader {
+	pub ty:       u32,
+	pub reserved: u32,
+	pub sector:   u64
+}
+
+/// The data of discard and write zeroes requests.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DiscardSegment {
+	pub sector:  u64,
+	pub sectors: u32,
+	pub flags:   u32
+}
+
+struct Queue {
+	vq:    Virtqueue,
+	page:  Region,
+	/// A request timed out and may still be in flight
+	stuck: bool
+}
+
+pub struct Disk<T: Transport, D: Dma> {
+	transport:         T,
+	dma:               D,
+	queues:            Vec<Queue>,
+	features:          u64,
+	sectors:           u64,
+	block_size:        u32,
+	/// Data segments of a request and their maximum size
+	max_segments:      usize,
+	segment_size:      u32,
+	/// In sectors, zero if not supported
+	max_discard:       u32,
+	discard_alignment: u32,
+	max_write_zeroes:  u32
+}
+
+impl<T: Transport, D: Dma> Disk<T, D> {
+	/// Initializes the device with up to `queues` request queues.
+	pub fn new(mut transport: T, mut dma: D, queues: u16) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::BlockDevice as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, SUPPORTED)?;
+		let has = |f: u64| features & f != 0;
+		let count = match has(FEATURE_MQ) {
+			true  => transport.read_config_u16(CONFIG_NUM_QUEUES).max(1),
+			false => 1
+		};
+
+		let mut disk = Self {
+			sectors:           transport.read_config_u64(CONFIG_CAPACITY),
+			block_size:        match has(FEATURE_BLK_SIZE) {
+				true  => transport.read_config_u32(CONFIG_BLK_SIZE),
+				false => SECTOR_SIZE as u32
+			},
+			max_segments:      match has(FEATURE_SEG_MAX) {
+				true  => (transport.read_config_u32(CONFIG_SEG_MAX) as usize).clamp(1, MAX_SEGMENTS),
+				false => MAX_SEGMENTS
+			},
+			segment_size:      match has(FEATURE_SIZE_MAX) {
+				true  => transport.read_config_u32(CONFIG_SIZE_MAX).max(SECTOR_SIZE as u32),
+				false => u32::MAX
+			},
+			max_discard:       match has(FEATURE_DISCARD) {
+				true  => transport.read_config_u32(CONFIG_MAX_DISCARD_SECTORS),
+				false => 0
+			},
+			discard_alignment: match has(FEATURE_DISCARD) {
+				true  => transport.read_config_u32(CONFIG_DISCARD_ALIGNMENT),
+				false => 0
+			},
+			max_write_zeroes:  match has(FEATURE_WRITE_ZEROES) {
+				true  => transport.read_config_u32(CONFIG_MAX_WRITE_ZEROES_SECTORS),
+				false => 0
+			},
+			transport,
+			dma,
+			queues:            Vec::new(),
+			features
+		};
+
+		if disk.block_size < SECTOR_SIZE as u32 || !disk.block_size.is_power_of_two() {
+			disk.transport.fail();
+			return Err(Error::Unsupported);
+		}
+
+		for i in 0..queues.clamp(1, count) {
+			let mut vq = match Virtqueue::new(&mut disk.transport, &mut disk.dma, i, MAX_QUEUE_SIZE, has(FEATURE_RING_PACKED)) {
+				Ok(vq) => vq,
+				// at least one queue is needed
+				Err(e) if i == 0 => return Err(e),
+				Err(_) => break
+			};
+			let page = match Region::alloc(&mut disk.dma, PAGE_SIZE, PAGE_SIZE) {
+				Some(page) => page,
+				None => {
+					vq.free(&mut disk.dma);
+					match i {
+						0 => return Err(Error::NoMemory),
+						_ => break
+					}
+				}
+			};
+			vq.set_interrupts(false);
+			disk.queues.push(Queue { vq, page, stuck: false });
+		}
+
+		// without indirect descriptors, the header and status take queue entries as well
+		if !has(FEATURE_INDIRECT_DESC) {
+			let size = disk.queues.iter().map(|q| q.vq.size() as usize).min().unwrap_or(0);
+			disk.max_segments = disk.max_segments.min(size.saturating_sub(2));
+		}
+		if disk.max_transfer() < disk.block_size as usize {
+			return Err(Error::Unsupported);
+		}
+
+		disk.transport.driver_ok();
+		Ok(disk)
+	}
+
+	pub fn queues(&self) -> usize {
+		self.queues.len()
+	}
+
+	pub fn block_size(&self) -> u32 {
+		self.block_size
+	}
+
+	pub fn blocks(&self) -> u64 {
+		self.sectors / (self.block_size as usize / SECTOR_SIZE) as u64
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	pub fn is_read_only(&self) -> bool {
+		self.features & FEATURE_RO != 0
+	}
+
+	pub fn supports_flush(&self) -> bool {
+		self.features & FEATURE_FLUSH != 0
+	}
+
+	pub fn supports_discard(&self) -> bool {
+		self.max_discard != 0
+	}
+
+	pub fn supports_write_zeroes(&self) -> bool {
+		self.max_write_zeroes != 0
+	}
+
+	/// The most bytes a request transfers, however the buffer is laid out in physical
+	/// memory. Segments end at page boundaries unless pages are contiguous.
+	pub fn max_transfer(&self) -> usize {
+		let per_page = (PAGE_SIZE + self.segment_size.min(PAGE_SIZE as u32) as usize - 1)
+			/ self.segment_size.min(PAGE_SIZE as u32) as usize;
+		let pages = (self.max_segments / per_page).saturating_sub(1);
+		pages * PAGE_SIZE / self.block_size as usize * self.block_size as usize
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`.
+	pub fn interrupt(&mut self) -> u32 {
+		self.transport.ack_interrupt()
+	}
+
+	fn sector(&self, lba: u64) -> u64 {
+		lba * (self.block_size as usize / SECTOR_SIZE) as u64
+	}
+
+	/// Checks that the queue exists and `blocks` blocks at `lba` are inside of the device.
+	fn check(&self, queue: usize, lba: u64, blocks: u64) -> Result<(), Error> {
+		match queue < self.queues.len() && lba.checked_add(blocks).map_or(false, |end| end <= self.blocks()) {
+			true  => Ok(()),
+			false => Err(Error::InvalidArgument)
+		}
+	}
+
+	/// Like `check`, for a buffer of whole blocks.
+	fn check_buf(&self, queue: usize, lba: u64, len: usize) -> Result<(), Error> {
+		match len % self.block_size as usize {
+			0 => self.check(queue, lba, (len / self.block_size as usize) as u64),
+			_ => Err(Error::InvalidArgument)
+		}
+	}
+
+	/// Reads whole blocks starting at `lba` on a queue.
+	pub fn read(&mut self, queue: usize, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+		self.check_buf(queue, lba, buf.len())?;
+		self.transfer(queue, REQ_IN, lba, buf.as_mut_ptr(), buf.len())
+	}
+
+	/// Writes whole blocks starting at `lba` on a queue.
+	pub fn write(&mut self, queue: usize, lba: u64, buf: &[u8]) -> Result<(), Error> {
+		self.check_buf(queue, lba, buf.len())?;
+		if self.is_read_only() {
+			return Err(Error::ReadOnly);
+		}
+		self.transfer(queue, REQ_OUT, lba, buf.as_ptr() as *mut u8, buf.len())
+	}
+
+	/// Commits the volatile write cache, devices without one complete writes once they
+	/// are persistent.
+	pub fn flush(&mut self, queue: usize) -> Result<(), Error> {
+		self.check(queue, 0, 0)?;
+		match self.supports_flush() {
+			true  => self.request(queue, REQ_FLUSH, 0, &[]),
+			false => Ok(())
+		}
+	}
+
+	/// Tells the device the blocks are unused. Discarding is a hint, devices without
+	/// discard ignore it.
+	pub fn discard(&mut self, queue: usize, lba: u64, blocks: u64) -> Result<(), Error> {
+		self.check(queue, lba, blocks)?;
+		if self.is_read_only() {
+			return Err(Error::ReadOnly);
+		} else if !self.supports_discard() {
+			return Ok(());
+		}
+		self.ranges(queue, REQ_DISCARD, lba, blocks, self.max_discard, 0)
+	}
+
+	/// Zeroes blocks, `unmap` allows the device to deallocate them.
+	pub fn write_zeroes(&mut self, queue: usize, lba: u64, blocks: u64, unmap: bool) -> Result<(), Error> {
+		self.check(queue, lba, blocks)?;
+		if self.is_read_only() {
+			return Err(Error::ReadOnly);
+		} else if !self.supports_write_zeroes() {
+			return Err(Error::Unsupported);
+		}
+		let flags = if unmap { SEGMENT_FLAG_UNMAP } else { 0 };
+		self.ranges(queue, REQ_WRITE_ZEROES, lba, blocks, self.max_write_zeroes, flags)
+	}
+
+	/// Issues discard or write zeroes requests of a single segment of at most `max`
+	/// sectors each.
+	fn ranges(&mut self, queue: usize, ty: u32, lba: u64, blocks: u64, max: u32, flags: u32) -> Result<(), Error> {
+		let per_block = (self.block_size as usize / SECTOR_SIZE) as u64;
+		// whole blocks, aligned to the discard granularity where possible
+		let mut step = (max as u64 / per_block).max(1) * per_block;
+		if ty == REQ_DISCARD && self.discard_alignment as u64 > per_block {
+			let alignment = self.discard_alignment as u64 / per_block * per_block;
+			step = (step / alignment).max(1) * alignment;
+		}
+
+		let (mut sector, end) = (self.sector(lba), self.sector(lba + blocks));
+		while sector < end {
+			let sectors = (end - sector).min(step);
+			let page = &self.queues[queue].page;
+			unsafe { (page.virt.add(PAGE_SEGMENT) as *mut DiscardSegment).write_volatile(DiscardSegment { sector, sectors: sectors as u32, flags }) };
+			let data = [Buffer::read(page.phys + PAGE_SEGMENT as u64, core::mem::size_of::<DiscardSegment>() as u32)];
+			self.request(queue, ty, 0, &data)?;
+			sector += sectors;
+		}
+		Ok(())
+	}
+
+	/// Splits a transfer into requests of at most `max_transfer` bytes.
+	fn transfer(&mut self, queue: usize, ty: u32, lba: u64, buf: *mut u8, len: usize) -> Result<(), Error> {
+		let step = self.max_transfer();
+		let mut offset = 0;
+		while offset < len {
+			let n = (len - offset).min(step);
+			let data = self.segments(unsafe { buf.add(offset) }, n, ty == REQ_IN);
+			let lba = lba + (offset / self.block_size as usize) as u64;
+			self.request(queue, ty, self.sector(lba), &data)?;
+			offset += n;
+		}
+		Ok(())
+	}
+
+	/// The physically contiguous parts of a buffer, up to `segment_size` long.
+	fn segments(&mut self, buf: *mut u8, len: usize, writable: bool) -> Vec<Buffer> {
+		let mut segments: Vec<Buffer> = Vec::new();
+		let mut offset = 0;
+		while offset < len {
+			let phys = self.dma.phys(unsafe { buf.add(offset) });
+			let n = (PAGE_SIZE - phys as usize % PAGE_SIZE).min(len - offset).min(self.segment_size as usize) as u32;
+			match segments.last_mut() {
+				Some(last) if last.phys + last.len as u64 == phys && last.len as u64 + n as u64 <= self.segment_size as u64 =>
+					last.len += n,
+				_ => segments.push(Buffer { phys, len: n, writable })
+			}
+			offset += n as usize;
+		}
+		segments
+	}
+
+	/// Issues a request and waits for its completion.
+	fn request(&mut self, queue: usize, ty: u32, sector: u64, data: &[Buffer]) -> Result<(), Error> {
+		if self.queues[queue].stuck {
+			return Err(Error::Timeout);
+		} else if data.len() > self.max_segments.max(1) {
+			return Err(Error::InvalidArgument);
+		}
+
+		let indirect = self.features & FEATURE_INDIRECT_DESC != 0 && data.len() + 2 > INDIRECT_THRESHOLD;
+		let q = &mut self.queues[queue];
+		let (virt, phys) = (q.page.virt, q.page.phys);
+		unsafe {
+			(virt as *mut RequestHeader).write_volatile(RequestHeader { ty, reserved: 0, sector });
+			virt.add(PAGE_STATUS).write_volatile(0xFF);
+		}
+
+		let mut chain = Vec::with_capacity(data.len() + 2);
+		chain.push(Buffer::read(phys, core::mem::size_of::<RequestHeader>() as u32));
+		chain.extend_from_slice(data);
+		chain.push(Buffer::write(phys + PAGE_STATUS as u64, 1));
+
+		let token = match indirect {
+			true => {
+				let table = Region { virt: unsafe { virt.add(PAGE_TABLE) }, phys: phys + PAGE_TABLE as u64, size: PAGE_SIZE - PAGE_TABLE };
+				q.vq.push_indirect(&table, &chain)?
+			}
+			false => q.vq.push(&chain)?
+		};
+		q.vq.notify(&mut self.transport);
+
+		for _ in 0..REQUEST_TIMEOUT_US / 10 {
+			match q.vq.pop() {
+				Some((t, _)) if t == token => return match unsafe { virt.add(PAGE_STATUS).read_volatile() } {
+					STATUS_OK     => Ok(()),
+					STATUS_UNSUPP => Err(Error::Unsupported),
+					_             => Err(Error::Device)
+				},
+				// only one request is in flight
+				Some(_) => (),
+				None if self.transport.needs_reset() => return Err(Error::Device),
+				None => self.dma.stall(10)
+			}
+		}
+
+		q.stuck = true;
+		Err(Error::Timeout)
+	}
+
+	/// The serial number of the device, up to 20 bytes.
+	pub fn id(&mut self, queue: usize) -> Result<Vec<u8>, Error> {
+		self.check(queue, 0, 0)?;
+		let page = &self.queues[queue].page;
+		let data = [Buffer::write(page.phys + PAGE_SEGMENT as u64, 20)];
+		let virt = page.virt;
+		unsafe { core::ptr::write_bytes(virt.add(PAGE_SEGMENT), 0, 20) };
+		self.request(queue, REQ_GET_ID, 0, &data)?;
+		let id = unsafe { core::slice::from_raw_parts(virt.add(PAGE_SEGMENT), 20) };
+		Ok(id[..id.iter().position(|&c| c == 0).unwrap_or(20)].to_vec())
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Disk<T, D> {
+	fn drop(&mut self) {
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		for q in self.queues.drain(..) {
+			q.vq.free(&mut self.dma);
+			q.page.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Disk<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Disk")
+			.field("blocks", &self.blocks())
+			.field("block_size", &self.block_size)
+			.field("queues", &self.queues.len())
+			.field("read_only", &self.is_read_only())
+			.field("flush", &self.supports_flush())
+			.field("discard", &self.supports_discard())
+			.field("write_zeroes", &self.supports_write_zeroes())
+			.finish()
+	}
+}
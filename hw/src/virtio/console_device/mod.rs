@@ -0,0 +1,453 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Console devices with one or more ports.
+//!
+//! Without `FEATURE_MULTIPORT` the device has a single port, 0, which is the console.
+//! With it, the device announces its ports on the control queue once the driver is
+//! ready: the driver sets up the queues of all ports in advance and accepts ports as
+//! they are added, the host names them, marks console ports and tells when its side
+//! is opened or closed. `Console::poll` handles those messages and returns what
+//! changed. Writes are queued without waiting, reads return what was received.
+//!
+//! The size of the console is in the configuration, it is read again when the
+//! configuration changes; console ports other than 0 are resized by control messages.
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, FEATURE_RING_PACKED, INTERRUPT_CONFIG},
+	crate::dma::{Dma, Region},
+	alloc::{collections::VecDeque, string::String, vec::Vec}
+};
+
+pub const FEATURE_SIZE:        u64 = 1 << 0;
+pub const FEATURE_MULTIPORT:   u64 = 1 << 1;
+pub const FEATURE_EMERG_WRITE: u64 = 1 << 2;
+
+const SUPPORTED: u64 = FEATURE_SIZE | FEATURE_MULTIPORT | FEATURE_EMERG_WRITE | FEATURE_RING_PACKED;
+
+/// Offsets of the fields of the device configuration
+pub const CONFIG_COLS:         usize = 0;
+pub const CONFIG_ROWS:         usize = 2;
+pub const CONFIG_MAX_NR_PORTS: usize = 4;
+pub const CONFIG_EMERG_WR:     usize = 8;
+
+/// Events of control messages
+pub const CTRL_DEVICE_READY:  u16 = 0;
+pub const CTRL_DEVICE_ADD:    u16 = 1;
+pub const CTRL_DEVICE_REMOVE: u16 = 2;
+pub const CTRL_PORT_READY:    u16 = 3;
+pub const CTRL_CONSOLE_PORT:  u16 = 4;
+pub const CTRL_RESIZE:        u16 = 5;
+pub const CTRL_PORT_OPEN:     u16 = 6;
+pub const CTRL_PORT_NAME:     u16 = 7;
+
+/// Size of the header of a control message: the port as u32, the event and a value as
+/// u16
+pub const CTRL_LEN: usize = 8;
+
+/// Ports the driver sets up queues for
+pub const MAX_PORTS: u32 = 16;
+pub const BUFFER_SIZE: usize = 512;
+const QUEUE_SIZE: u16 = 8;
+
+/// A change reported by `Console::poll`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+	Added(u32),
+	Removed(u32),
+	Named(u32),
+	/// The port is a console
+	Console(u32),
+	/// The host opened or closed its side of the port
+	Opened(u32),
+	Closed(u32),
+	Resized { port: u32, cols: u16, rows: u16 }
+}
+
+/// A port the device added.
+#[derive(Clone, Debug, Default)]
+pub struct Port {
+	pub id:        u32,
+	pub name:      Option<String>,
+	pub console:   bool,
+	pub host_open: bool,
+	/// Received and not read yet
+	input:         VecDeque<u8>
+}
+
+/// A queue and its buffers.
+struct Queue {
+	vq:      Virtqueue,
+	buffers: Region,
+	/// The buffer each token refers to
+	slots:   Vec<u16>,
+	/// Buffers not in the queue, only used for transmit queues
+	free:    Vec<u16>
+}
+
+impl Queue {
+	fn new(transport: &mut impl Transport, dma: &mut impl Dma, index: u16, packed: bool) -> Result<Self, Error> {
+		let vq = Virtqueue::new(transport, dma, index, QUEUE_SIZE, packed)?;
+		let size = vq.size();
+		match Region::alloc(dma, size as usize * BUFFER_SIZE, BUFFER_SIZE) {
+			Some(buffers) => Ok(Self { vq, buffers, slots: alloc::vec![0; size as usize], free: (0..size).collect() }),
+			None => {
+				vq.free(dma);
+				Err(Error::NoMemory)
+			}
+		}
+	}
+
+	fn buffer(&self, slot: u16) -> *mut u8 {
+		unsafe { self.buffers.virt.add(slot as usize * BUFFER_SIZE) }
+	}
+
+	/// Makes all buffers of a receive queue available to the device.
+	fn post_all(&mut self) -> Result<(), Error> {
+		while let Some(slot) = self.free.pop() {
+			self.post(slot)?;
+		}
+		Ok(())
+	}
+
+	fn post(&mut self, slot: u16) -> Result<(), Error> {
+		let phys = self.buffers.phys + (slot as usize * BUFFER_SIZE) as u64;
+		let token = self.vq.push(&[Buffer::write(phys, BUFFER_SIZE as u32)])?;
+		self.slots[token as usize] = slot;
+		Ok(())
+	}
+
+	/// The next received buffer, which is posted again.
+	fn receive(&mut self) -> Option<Vec<u8>> {
+		let (token, len) = self.vq.pop()?;
+		let slot = self.slots[token as usize];
+		let data = unsafe { core::slice::from_raw_parts(self.buffer(slot), (len as usize).min(BUFFER_SIZE)) }.to_vec();
+		let _ = self.post(slot);
+		Some(data)
+	}
+
+	/// Queues data of at most `BUFFER_SIZE` bytes, false if no buffer is free.
+	fn send(&mut self, data: &[u8]) -> Result<bool, Error> {
+		while let Some((token, _)) = self.vq.pop() {
+			self.free.push(self.slots[token as usize]);
+		}
+		let Some(slot) = self.free.pop() else { return Ok(false) };
+		unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), self.buffer(slot), data.len()) };
+		let phys = self.buffers.phys + (slot as usize * BUFFER_SIZE) as u64;
+		match self.vq.push(&[Buffer::read(phys, data.len() as u32)]) {
+			Ok(token) => {
+				self.slots[token as usize] = slot;
+				Ok(true)
+			}
+			Err(e) => {
+				self.free.push(slot);
+				Err(e)
+			}
+		}
+	}
+
+	fn free(self, dma: &mut impl Dma) {
+		self.vq.free(dma);
+		self.buffers.free(dma);
+	}
+}
+
+pub struct Console<T: Transport, D: Dma> {
+	transport: T,
+	dma:       D,
+	/// The receive and transmit queues of the ports, by id
+	queues:    Vec<(Queue, Queue)>,
+	ctrl:      Option<(Queue, Queue)>,
+	ports:     Vec<Port>,
+	size:      Option<(u16, u16)>,
+	/// Changes found while handling an interrupt
+	pending:   Vec<Event>,
+	features:  u64
+}
+
+impl<T: Transport, D: Dma> Console<T, D> {
+	pub fn new(mut transport: T, mut dma: D) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::ConsoleDevice as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, SUPPORTED)?;
+		let packed = features & FEATURE_RING_PACKED != 0;
+		let multiport = features & FEATURE_MULTIPORT != 0;
+		let max_ports = match multiport {
+			true  => transport.read_config_u32(CONFIG_MAX_NR_PORTS).clamp(1, MAX_PORTS),
+			false => 1
+		};
+		let size = (features & FEATURE_SIZE != 0)
+			.then(|| (transport.read_config_u16(CONFIG_COLS), transport.read_config_u16(CONFIG_ROWS)));
+
+		let mut dev = Self { transport, dma, queues: Vec::new(), ctrl: None, ports: Vec::new(), size, pending: Vec::new(), features };
+		// port 0 uses the queues 0 and 1, the control queues come next, then the other ports
+		for id in 0..max_ports as u16 {
+			let index = match id {
+				0 => 0,
+				_ => 2 * id + 2
+			};
+			let (rx, tx) = dev.queue_pair(index, packed)?;
+			dev.queues.push((rx, tx));
+			if id == 0 && multiport {
+				dev.ctrl = Some(dev.queue_pair(2, packed)?);
+			}
+		}
+
+		for (rx, _) in &mut dev.queues {
+			rx.post_all()?;
+		}
+		if let Some((rx, _)) = &mut dev.ctrl {
+			rx.post_all()?;
+		}
+		dev.transport.driver_ok();
+		for (rx, _) in &dev.queues {
+			rx.vq.notify(&mut dev.transport);
+		}
+
+		match &dev.ctrl {
+			Some((rx, _)) => {
+				rx.vq.notify(&mut dev.transport);
+				dev.control(0, CTRL_DEVICE_READY, 1)?;
+			}
+			None => dev.ports.push(Port { id: 0, console: true, host_open: true, ..Port::default() })
+		}
+		Ok(dev)
+	}
+
+	fn queue_pair(&mut self, index: u16, packed: bool) -> Result<(Queue, Queue), Error> {
+		let rx = Queue::new(&mut self.transport, &mut self.dma, index, packed)?;
+		match Queue::new(&mut self.transport, &mut self.dma, index + 1, packed) {
+			Ok(tx) => Ok((rx, tx)),
+			Err(e) => {
+				rx.free(&mut self.dma);
+				Err(e)
+			}
+		}
+	}
+
+	/// Sends a control message.
+	fn control(&mut self, port: u32, event: u16, value: u16) -> Result<(), Error> {
+		let (_, tx) = self.ctrl.as_mut().ok_or(Error::Unsupported)?;
+		let mut msg = [0; CTRL_LEN];
+		msg[0..4].copy_from_slice(&port.to_le_bytes());
+		msg[4..6].copy_from_slice(&event.to_le_bytes());
+		msg[6..8].copy_from_slice(&value.to_le_bytes());
+		match tx.send(&msg)? {
+			true => {
+				tx.vq.notify(&mut self.transport);
+				Ok(())
+			}
+			false => Err(Error::NoMemory)
+		}
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	/// The ports the device added.
+	pub fn ports(&self) -> &[Port] {
+		&self.ports
+	}
+
+	pub fn port(&self, id: u32) -> Option<&Port> {
+		self.ports.iter().find(|p| p.id == id)
+	}
+
+	fn port_mut(&mut self, id: u32) -> Option<&mut Port> {
+		self.ports.iter_mut().find(|p| p.id == id)
+	}
+
+	/// Columns and rows of the console, with `FEATURE_SIZE`.
+	pub fn size(&self) -> Option<(u16, u16)> {
+		self.size
+	}
+
+	/// Handles the control messages and receives the data of all ports, returns the
+	/// changes since the last call.
+	pub fn poll(&mut self) -> Vec<Event> {
+		let mut events = core::mem::take(&mut self.pending);
+		let mut messages = Vec::new();
+		if let Some((rx, _)) = &mut self.ctrl {
+			while let Some(msg) = rx.receive() {
+				messages.push(msg);
+			}
+			if !messages.is_empty() {
+				rx.vq.notify(&mut self.transport);
+			}
+		}
+		for msg in messages {
+			self.message(&msg, &mut events);
+		}
+
+		for port in &mut self.ports {
+			let (rx, _) = &mut self.queues[port.id as usize];
+			let mut received = false;
+			while let Some(data) = rx.receive() {
+				port.input.extend(data);
+				received = true;
+			}
+			if received {
+				rx.vq.notify(&mut self.transport);
+			}
+		}
+		events
+	}
+
+	fn message(&mut self, msg: &[u8], events: &mut Vec<Event>) {
+		if msg.len() < CTRL_LEN {
+			return;
+		}
+		let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+		let event = u16::from_le_bytes([msg[4], msg[5]]);
+		let value = u16::from_le_bytes([msg[6], msg[7]]);
+		let known = self.port(id).is_some();
+
+		match event {
+			CTRL_DEVICE_ADD if known => (),
+			CTRL_DEVICE_ADD => {
+				let ok = (id as usize) < self.queues.len();
+				if ok {
+					self.ports.push(Port { id, ..Port::default() });
+					events.push(Event::Added(id));
+				}
+				let _ = self.control(id, CTRL_PORT_READY, ok as u16);
+			}
+			_ if !known => (),
+			CTRL_DEVICE_REMOVE => {
+				self.ports.retain(|p| p.id != id);
+				events.push(Event::Removed(id));
+			}
+			CTRL_CONSOLE_PORT => {
+				self.port_mut(id).unwrap().console = true;
+				events.push(Event::Console(id));
+				// console ports are always open on the driver's side
+				let _ = self.control(id, CTRL_PORT_OPEN, 1);
+			}
+			CTRL_PORT_OPEN => {
+				self.port_mut(id).unwrap().host_open = value != 0;
+				events.push(match value {
+					0 => Event::Closed(id),
+					_ => Event::Opened(id)
+				});
+			}
+			CTRL_PORT_NAME => {
+				let name = &msg[CTRL_LEN..];
+				let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(name.len())];
+				self.port_mut(id).unwrap().name = Some(String::from_utf8_lossy(name).into_owned());
+				events.push(Event::Named(id));
+			}
+			CTRL_RESIZE if msg.len() >= CTRL_LEN + 4 => {
+				let cols = u16::from_le_bytes([msg[8], msg[9]]);
+				let rows = u16::from_le_bytes([msg[10], msg[11]]);
+				events.push(Event::Resized { port: id, cols, rows });
+			}
+			_ => ()
+		}
+	}
+
+	/// Takes received data of a port, returns its length.
+	pub fn read(&mut self, port: u32, buf: &mut [u8]) -> Result<usize, Error> {
+		let port = self.port_mut(port).ok_or(Error::InvalidArgument)?;
+		let n = port.input.len().min(buf.len());
+		for (b, c) in buf.iter_mut().zip(port.input.drain(..n)) {
+			*b = c;
+		}
+		Ok(n)
+	}
+
+	/// Queues data on a port, returns how much of it fit into the free buffers.
+	pub fn write(&mut self, port: u32, data: &[u8]) -> Result<usize, Error> {
+		if self.port(port).is_none() {
+			return Err(Error::InvalidArgument);
+		}
+		let (_, tx) = &mut self.queues[port as usize];
+		let mut written = 0;
+		for chunk in data.chunks(BUFFER_SIZE) {
+			if !tx.send(chunk)? {
+				break;
+			}
+			written += chunk.len();
+		}
+		if written > 0 {
+			tx.vq.notify(&mut self.transport);
+		}
+		Ok(written)
+	}
+
+	/// Tells the host the driver's side of a port was opened or closed.
+	pub fn set_open(&mut self, port: u32, open: bool) -> Result<(), Error> {
+		if self.port(port).is_none() {
+			return Err(Error::InvalidArgument);
+		}
+		self.control(port, CTRL_PORT_OPEN, open as u16)
+	}
+
+	/// Writes to the console through the configuration, without queues, for when the
+	/// driver can't use them anymore. False without `FEATURE_EMERG_WRITE`.
+	pub fn emergency_write(&mut self, data: &[u8]) -> bool {
+		if self.features & FEATURE_EMERG_WRITE == 0 {
+			return false;
+		}
+		for &c in data {
+			self.transport.write_config(CONFIG_EMERG_WR, 4, c as u32);
+		}
+		true
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`. A new size
+	/// of port 0 is reported by the next `poll`.
+	pub fn interrupt(&mut self) -> u32 {
+		let isr = self.transport.ack_interrupt();
+		if isr & INTERRUPT_CONFIG != 0 && self.features & FEATURE_SIZE != 0 {
+			let size = (self.transport.read_config_u16(CONFIG_COLS), self.transport.read_config_u16(CONFIG_ROWS));
+			if self.size != Some(size) {
+				self.size = Some(size);
+				self.pending.push(Event::Resized { port: 0, cols: size.0, rows: size.1 });
+			}
+		}
+		isr
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Console<T, D> {
+	fn drop(&mut self) {
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		for (rx, tx) in self.queues.drain(..).chain(self.ctrl.take()) {
+			rx.free(&mut self.dma);
+			tx.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Console<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Console")
+			.field("ports", &self.ports.iter().map(|p| (p.id, p.name.as_deref())).collect::<Vec<_>>())
+			.field("max_ports", &self.queues.len())
+			.field("size", &self.size)
+			.field("emerg_write", &(self.features & FEATURE_EMERG_WRITE != 0))
+			.finish()
+	}
+}
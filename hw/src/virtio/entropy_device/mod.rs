@@ -0,0 +1,136 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Entropy devices.
+//!
+//! The device has a single request queue and no configuration: the driver posts
+//! writable buffers and the device fills them with random bytes, possibly fewer than
+//! asked for. Requests are polled one at a time, the bytes are meant to seed
+//! `random::Pool` rather than be used directly.
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, FEATURE_RING_PACKED},
+	crate::dma::{Dma, Region, PAGE_SIZE},
+};
+
+const QUEUE_SIZE: u16 = 4;
+const REQUEST_TIMEOUT_US: u64 = 1_000_000;
+
+pub struct Rng<T: Transport, D: Dma> {
+	transport: T,
+	dma:       D,
+	vq:        Option<Virtqueue>,
+	page:      Option<Region>,
+	features:  u64
+}
+
+impl<T: Transport, D: Dma> Rng<T, D> {
+	pub fn new(mut transport: T, mut dma: D) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::EntropyDevice as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, FEATURE_RING_PACKED)?;
+		let mut rng = Self { transport, dma, vq: None, page: None, features };
+
+		let mut vq = Virtqueue::new(&mut rng.transport, &mut rng.dma, 0, QUEUE_SIZE, features & FEATURE_RING_PACKED != 0)?;
+		vq.set_interrupts(false);
+		rng.vq = Some(vq);
+		rng.page = Some(Region::alloc(&mut rng.dma, PAGE_SIZE, PAGE_SIZE).ok_or(Error::NoMemory)?);
+		rng.transport.driver_ok();
+		Ok(rng)
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	/// Asks the device for up to a page of random bytes and returns how many it wrote
+	/// to the start of `buf`. This may be less than the length of `buf`, even 0.
+	pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		let (Some(vq), Some(page)) = (self.vq.as_mut(), self.page.as_ref()) else { return Err(Error::NoQueue) };
+		let len = buf.len().min(PAGE_SIZE);
+		if len == 0 {
+			return Ok(0);
+		}
+
+		let token = vq.push(&[Buffer::write(page.phys, len as u32)])?;
+		vq.notify(&mut self.transport);
+
+		for _ in 0..REQUEST_TIMEOUT_US / 10 {
+			match vq.pop() {
+				Some((t, written)) if t == token => {
+					let written = (written as usize).min(len);
+					unsafe { core::ptr::copy_nonoverlapping(page.virt, buf.as_mut_ptr(), written); }
+					unsafe { page.virt.write_bytes(0, written); }
+					return Ok(written);
+				}
+				Some(_) => (),
+				None => self.dma.stall(10)
+			}
+		}
+		Err(Error::Timeout)
+	}
+
+	/// Fills `buf` completely, asking the device as often as needed. Fails with
+	/// `Error::Timeout` if the device keeps returning nothing.
+	pub fn fill(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+		let mut offset = 0;
+		let mut empty = 0;
+		while offset < buf.len() {
+			match self.read(&mut buf[offset..])? {
+				0 if empty >= REQUEST_TIMEOUT_US / 10 => return Err(Error::Timeout),
+				0 => {
+					empty += 1;
+					self.dma.stall(10);
+				}
+				n => offset += n
+			}
+		}
+		Ok(())
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`.
+	pub fn interrupt(&mut self) -> u32 {
+		self.transport.ack_interrupt()
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Rng<T, D> {
+	fn drop(&mut self) {
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		if let Some(vq) = self.vq.take() {
+			vq.free(&mut self.dma);
+		}
+		if let Some(page) = self.page.take() {
+			page.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Rng<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Rng")
+			.field("features", &self.features)
+			.finish()
+	}
+}
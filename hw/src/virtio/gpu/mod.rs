@@ -0,0 +1,337 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! GPU devices, 2D mode.
+//!
+//! The driver creates a resource of the size of a scanout, backs it with a DMA buffer
+//! and shows it on the scanout. Drawing happens in the buffer, `Gpu::flush` transfers
+//! the changed area to the host and updates the display. Commands are sent on the
+//! control queue one at a time and polled, the cursor queue isn't used.
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, FEATURE_RING_PACKED},
+	crate::{console::{PixelFormat, Rect}, dma::{Dma, Region, PAGE_SIZE}},
+	alloc::vec::Vec
+};
+
+/// Offsets of the fields of the device configuration
+pub const CONFIG_EVENTS_READ:  usize = 0;
+pub const CONFIG_EVENTS_CLEAR: usize = 4;
+pub const CONFIG_NUM_SCANOUTS: usize = 8;
+
+/// The display configuration changed, `Gpu::displays` returns the new one
+pub const EVENT_DISPLAY: u32 = 1 << 0;
+
+pub const CMD_GET_DISPLAY_INFO:        u32 = 0x0100;
+pub const CMD_RESOURCE_CREATE_2D:      u32 = 0x0101;
+pub const CMD_RESOURCE_UNREF:          u32 = 0x0102;
+pub const CMD_SET_SCANOUT:             u32 = 0x0103;
+pub const CMD_RESOURCE_FLUSH:          u32 = 0x0104;
+pub const CMD_TRANSFER_TO_HOST_2D:     u32 = 0x0105;
+pub const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+pub const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+pub const RESP_OK_NODATA:       u32 = 0x1100;
+pub const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+pub const RESP_ERR_UNSPEC:      u32 = 0x1200;
+pub const RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+pub const RESP_ERR_INVALID_SCANOUT_ID:  u32 = 0x1202;
+pub const RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+pub const RESP_ERR_INVALID_PARAMETER:   u32 = 0x1205;
+
+pub const FORMAT_B8G8R8A8_UNORM: u32 = 1;
+pub const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+pub const FORMAT_A8R8G8B8_UNORM: u32 = 3;
+pub const FORMAT_X8R8G8B8_UNORM: u32 = 4;
+pub const FORMAT_R8G8B8A8_UNORM: u32 = 67;
+pub const FORMAT_X8B8G8R8_UNORM: u32 = 68;
+pub const FORMAT_A8B8G8R8_UNORM: u32 = 121;
+pub const FORMAT_R8G8B8X8_UNORM: u32 = 134;
+
+pub const MAX_SCANOUTS: usize = 16;
+pub const HEADER_LEN:   usize = 24;
+/// Size of the response to `CMD_GET_DISPLAY_INFO`
+pub const DISPLAY_INFO_LEN: usize = HEADER_LEN + MAX_SCANOUTS * 24;
+
+const QUEUE_SIZE: u16 = 16;
+const COMMAND_TIMEOUT_US: u64 = 5_000_000;
+/// The resource of the scanout
+const RESOURCE_ID: u32 = 1;
+
+/// Layout of the command page
+const PAGE_RESPONSE: usize = 2048;
+
+/// A scanout, as the device reports it.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Display {
+	pub scanout: u32,
+	pub rect:    Rect,
+	pub enabled: bool
+}
+
+/// The resource shown on a scanout.
+struct Scanout {
+	id:     u32,
+	width:  u32,
+	height: u32,
+	buffer: Region
+}
+
+pub struct Gpu<T: Transport, D: Dma> {
+	transport: T,
+	dma:       D,
+	vq:        Option<Virtqueue>,
+	page:      Option<Region>,
+	scanout:   Option<Scanout>,
+	scanouts:  u32,
+	features:  u64
+}
+
+/// Appends little endian words to a command.
+fn words(buf: &mut Vec<u8>, words: &[u32]) {
+	for w in words {
+		buf.extend_from_slice(&w.to_le_bytes());
+	}
+}
+
+fn header(ty: u32) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(64);
+	words(&mut buf, &[ty, 0, 0, 0, 0, 0]);
+	buf
+}
+
+impl<T: Transport, D: Dma> Gpu<T, D> {
+	pub fn new(mut transport: T, mut dma: D) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::Gpu as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, FEATURE_RING_PACKED)?;
+		let scanouts = transport.read_config_u32(CONFIG_NUM_SCANOUTS).min(MAX_SCANOUTS as u32);
+		let mut gpu = Self { transport, dma, vq: None, page: None, scanout: None, scanouts, features };
+
+		let mut vq = Virtqueue::new(&mut gpu.transport, &mut gpu.dma, 0, QUEUE_SIZE, features & FEATURE_RING_PACKED != 0)?;
+		vq.set_interrupts(false);
+		gpu.vq = Some(vq);
+		gpu.page = Some(Region::alloc(&mut gpu.dma, PAGE_SIZE, PAGE_SIZE).ok_or(Error::NoMemory)?);
+		gpu.transport.driver_ok();
+		Ok(gpu)
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	pub fn scanouts(&self) -> u32 {
+		self.scanouts
+	}
+
+	/// Sends a command, returns the type and the body of the response.
+	fn command(&mut self, cmd: &[u8], response_len: usize) -> Result<(u32, Vec<u8>), Error> {
+		let (Some(vq), Some(page)) = (self.vq.as_mut(), self.page.as_ref()) else { return Err(Error::NoQueue) };
+		if cmd.len() > PAGE_RESPONSE || response_len > PAGE_SIZE - PAGE_RESPONSE {
+			return Err(Error::InvalidArgument);
+		}
+		unsafe {
+			core::ptr::copy_nonoverlapping(cmd.as_ptr(), page.virt, cmd.len());
+			page.virt.add(PAGE_RESPONSE).write_bytes(0, response_len);
+		}
+
+		let token = vq.push(&[
+			Buffer::read(page.phys, cmd.len() as u32),
+			Buffer::write(page.phys + PAGE_RESPONSE as u64, response_len as u32)
+		])?;
+		vq.notify(&mut self.transport);
+
+		for _ in 0..COMMAND_TIMEOUT_US / 10 {
+			match vq.pop() {
+				Some((t, _)) if t == token => {
+					let response = unsafe { core::slice::from_raw_parts(page.virt.add(PAGE_RESPONSE), response_len) };
+					let ty = u32::from_le_bytes(response[0..4].try_into().unwrap());
+					return Ok((ty, response[HEADER_LEN..].to_vec()));
+				}
+				Some(_) => (),
+				None => self.dma.stall(10)
+			}
+		}
+		Err(Error::Timeout)
+	}
+
+	/// Sends a command that is answered without data.
+	fn command_nodata(&mut self, cmd: &[u8]) -> Result<(), Error> {
+		match self.command(cmd, HEADER_LEN)?.0 {
+			RESP_OK_NODATA => Ok(()),
+			RESP_ERR_OUT_OF_MEMORY => Err(Error::NoMemory),
+			RESP_ERR_INVALID_SCANOUT_ID | RESP_ERR_INVALID_RESOURCE_ID | RESP_ERR_INVALID_PARAMETER => Err(Error::InvalidArgument),
+			_ => Err(Error::Device)
+		}
+	}
+
+	/// The scanouts and their preferred sizes.
+	pub fn displays(&mut self) -> Result<Vec<Display>, Error> {
+		let (ty, body) = self.command(&header(CMD_GET_DISPLAY_INFO), DISPLAY_INFO_LEN)?;
+		if ty != RESP_OK_DISPLAY_INFO {
+			return Err(Error::Device);
+		}
+		let word = |i: usize| u32::from_le_bytes(body[4 * i..4 * i + 4].try_into().unwrap());
+		Ok((0..self.scanouts as usize).map(|i| Display {
+			scanout: i as u32,
+			rect:    Rect::new(word(6 * i), word(6 * i + 1), word(6 * i + 2), word(6 * i + 3)),
+			enabled: word(6 * i + 4) != 0
+		}).collect())
+	}
+
+	/// Pending configuration events, `EVENT_*`, which are acknowledged.
+	pub fn events(&mut self) -> u32 {
+		let events = self.transport.read_config_u32(CONFIG_EVENTS_READ);
+		if events != 0 {
+			self.transport.write_config(CONFIG_EVENTS_CLEAR, 4, events);
+		}
+		events
+	}
+
+	/// Shows a new framebuffer of `width` by `height` pixels on a scanout, the previous
+	/// one is released.
+	pub fn set_mode(&mut self, scanout: u32, width: u32, height: u32) -> Result<(), Error> {
+		if scanout >= self.scanouts || width == 0 || height == 0 {
+			return Err(Error::InvalidArgument);
+		}
+		self.release()?;
+
+		let size = width as usize * height as usize * 4;
+		let buffer = Region::alloc(&mut self.dma, size, PAGE_SIZE).ok_or(Error::NoMemory)?;
+		let id = RESOURCE_ID;
+
+		let mut cmd = header(CMD_RESOURCE_CREATE_2D);
+		words(&mut cmd, &[id, FORMAT_B8G8R8X8_UNORM, width, height]);
+		if let Err(e) = self.command_nodata(&cmd) {
+			buffer.free(&mut self.dma);
+			return Err(e);
+		}
+		self.scanout = Some(Scanout { id, width, height, buffer });
+
+		let buffer = &self.scanout.as_ref().unwrap().buffer;
+		let mut cmd = header(CMD_RESOURCE_ATTACH_BACKING);
+		words(&mut cmd, &[id, 1]);
+		cmd.extend_from_slice(&buffer.phys.to_le_bytes());
+		words(&mut cmd, &[size as u32, 0]);
+		self.command_nodata(&cmd)?;
+
+		let mut cmd = header(CMD_SET_SCANOUT);
+		words(&mut cmd, &[0, 0, width, height, scanout, id]);
+		self.command_nodata(&cmd)?;
+		self.flush(Rect::new(0, 0, width, height))
+	}
+
+	/// Detaches the framebuffer from the scanouts and releases it.
+	fn release(&mut self) -> Result<(), Error> {
+		let Some(s) = self.scanout.take() else { return Ok(()) };
+		let result = (|| {
+			for scanout in 0..self.scanouts {
+				let mut cmd = header(CMD_SET_SCANOUT);
+				words(&mut cmd, &[0, 0, 0, 0, scanout, 0]);
+				self.command_nodata(&cmd)?;
+			}
+			let mut cmd = header(CMD_RESOURCE_DETACH_BACKING);
+			words(&mut cmd, &[s.id, 0]);
+			self.command_nodata(&cmd)?;
+			let mut cmd = header(CMD_RESOURCE_UNREF);
+			words(&mut cmd, &[s.id, 0]);
+			self.command_nodata(&cmd)
+		})();
+		// the device may still access the buffer if it didn't detach it, it is kept until
+		// the device is reset
+		match result {
+			Ok(()) => s.buffer.free(&mut self.dma),
+			Err(_) => self.scanout = Some(s)
+		}
+		result
+	}
+
+	/// Size of the framebuffer.
+	pub fn mode(&self) -> Option<(u32, u32)> {
+		self.scanout.as_ref().map(|s| (s.width, s.height))
+	}
+
+	pub fn format(&self) -> PixelFormat {
+		PixelFormat::Bgrx
+	}
+
+	/// The pixels of the framebuffer, lines of `width` pixels.
+	pub fn framebuffer(&mut self) -> Option<&mut [u32]> {
+		let s = self.scanout.as_ref()?;
+		Some(unsafe { core::slice::from_raw_parts_mut(s.buffer.virt as *mut u32, (s.width * s.height) as usize) })
+	}
+
+	/// Shows a changed area of the framebuffer.
+	pub fn flush(&mut self, rect: Rect) -> Result<(), Error> {
+		let s = self.scanout.as_ref().ok_or(Error::InvalidArgument)?;
+		let (id, width, height) = (s.id, s.width, s.height);
+		let x = rect.x.min(width);
+		let y = rect.y.min(height);
+		let rect = Rect::new(x, y, rect.width.min(width - x), rect.height.min(height - y));
+		if rect.width == 0 || rect.height == 0 {
+			return Ok(());
+		}
+
+		let mut cmd = header(CMD_TRANSFER_TO_HOST_2D);
+		words(&mut cmd, &[rect.x, rect.y, rect.width, rect.height]);
+		let offset = (rect.y as u64 * width as u64 + rect.x as u64) * 4;
+		cmd.extend_from_slice(&offset.to_le_bytes());
+		words(&mut cmd, &[id, 0]);
+		self.command_nodata(&cmd)?;
+
+		let mut cmd = header(CMD_RESOURCE_FLUSH);
+		words(&mut cmd, &[rect.x, rect.y, rect.width, rect.height, id, 0]);
+		self.command_nodata(&cmd)
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`.
+	pub fn interrupt(&mut self) -> u32 {
+		self.transport.ack_interrupt()
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Gpu<T, D> {
+	fn drop(&mut self) {
+		let _ = self.release();
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		if let Some(s) = self.scanout.take() {
+			s.buffer.free(&mut self.dma);
+		}
+		if let Some(vq) = self.vq.take() {
+			vq.free(&mut self.dma);
+		}
+		if let Some(page) = self.page.take() {
+			page.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Gpu<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Gpu")
+			.field("scanouts", &self.scanouts)
+			.field("mode", &self.mode())
+			.finish()
+	}
+}
@@ -0,0 +1,360 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Input devices: keyboards, mice and tablets.
+//!
+//! The configuration is queried by writing a selector and reading what the device puts
+//! into the data field, the driver reads the name, the ids, the supported events and
+//! the ranges of the absolute axes. The device reports evdev events on the event queue
+//! into buffers that are posted again once read. `Translator` groups them by
+//! `SYN_REPORT` into `input::Event`s; the status queue, which sets LEDs, isn't used.
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, FEATURE_RING_PACKED},
+	crate::{dma::{Dma, Region, PAGE_SIZE}, input::{self, BTN_MISC, POSITION_MAX}},
+	alloc::{string::String, vec::Vec}
+};
+
+/// Offsets of the fields of the device configuration
+pub const CONFIG_SELECT: usize = 0;
+pub const CONFIG_SUBSEL: usize = 1;
+pub const CONFIG_SIZE:   usize = 2;
+pub const CONFIG_DATA:   usize = 8;
+pub const CONFIG_DATA_LEN: usize = 128;
+
+/// Selectors of the configuration
+pub const CFG_UNSET:     u8 = 0x00;
+pub const CFG_ID_NAME:   u8 = 0x01;
+pub const CFG_ID_SERIAL: u8 = 0x02;
+pub const CFG_ID_DEVIDS: u8 = 0x03;
+pub const CFG_PROP_BITS: u8 = 0x10;
+/// The subselector is the event type
+pub const CFG_EV_BITS:   u8 = 0x11;
+/// The subselector is the axis
+pub const CFG_ABS_INFO:  u8 = 0x12;
+
+/// Event types
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_MSC: u16 = 0x04;
+pub const EV_LED: u16 = 0x11;
+pub const EV_REP: u16 = 0x14;
+
+pub const SYN_REPORT:  u16 = 0;
+/// Events were lost, the state is only consistent again after the next report
+pub const SYN_DROPPED: u16 = 3;
+
+pub const REL_X:      u16 = 0x00;
+pub const REL_Y:      u16 = 0x01;
+pub const REL_HWHEEL: u16 = 0x06;
+pub const REL_WHEEL:  u16 = 0x08;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+
+pub const EVENT_LEN: usize = 8;
+
+const QUEUE_SIZE: u16 = 64;
+
+/// An event as the device reports it.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RawEvent {
+	pub ty:    u16,
+	pub code:  u16,
+	pub value: u32
+}
+
+impl RawEvent {
+	pub fn parse(buf: &[u8]) -> Option<Self> {
+		let buf = buf.get(..EVENT_LEN)?;
+		Some(Self {
+			ty:    u16::from_le_bytes([buf[0], buf[1]]),
+			code:  u16::from_le_bytes([buf[2], buf[3]]),
+			value: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]])
+		})
+	}
+}
+
+/// The range of an absolute axis.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct AbsInfo {
+	pub min:  i32,
+	pub max:  i32,
+	pub fuzz: i32,
+	pub flat: i32,
+	pub res:  i32
+}
+
+impl AbsInfo {
+	/// Scales a value to `0..=POSITION_MAX`.
+	pub fn scale(&self, value: i32) -> u32 {
+		let range = (self.max as i64 - self.min as i64).max(1);
+		let value = (value as i64).clamp(self.min as i64, self.max as i64) - self.min as i64;
+		(value * POSITION_MAX as i64 / range) as u32
+	}
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DevIds {
+	pub bustype: u16,
+	pub vendor:  u16,
+	pub product: u16,
+	pub version: u16
+}
+
+/// Turns the events of a device into `input::Event`s. Keys and buttons are passed on
+/// right away, movements are summed up until the next report.
+#[derive(Clone, Debug, Default)]
+pub struct Translator {
+	abs:      [Option<AbsInfo>; 2],
+	motion:   (i32, i32),
+	wheel:    (i32, i32),
+	position: (u32, u32),
+	moved:    bool,
+	dropped:  bool
+}
+
+impl Translator {
+	/// `abs` are the ranges of `ABS_X` and `ABS_Y`, if the device has them.
+	pub fn new(abs: [Option<AbsInfo>; 2]) -> Self {
+		Self { abs, ..Self::default() }
+	}
+
+	pub fn translate(&mut self, events: &[RawEvent]) -> Vec<input::Event> {
+		let mut out = Vec::new();
+		for e in events {
+			let value = e.value as i32;
+			match (e.ty, e.code) {
+				(EV_SYN, SYN_DROPPED) => self.dropped = true,
+				(EV_SYN, SYN_REPORT) if core::mem::take(&mut self.dropped) => self.reset(),
+				(EV_SYN, SYN_REPORT) => self.report(&mut out),
+				_ if self.dropped => (),
+				// a value of 2 is a key held down repeating
+				(EV_KEY, code) if code < BTN_MISC => out.push(input::Event::Key { code, pressed: value != 0, char: None, modifiers: 0 }),
+				(EV_KEY, button) => out.push(input::Event::Button { button, pressed: value != 0 }),
+				(EV_REL, REL_X)      => self.motion.0 += value,
+				(EV_REL, REL_Y)      => self.motion.1 += value,
+				(EV_REL, REL_HWHEEL) => self.wheel.0 += value,
+				(EV_REL, REL_WHEEL)  => self.wheel.1 += value,
+				(EV_ABS, axis @ (ABS_X | ABS_Y)) => {
+					let scaled = match self.abs[axis as usize] {
+						Some(info) => info.scale(value),
+						None       => value.clamp(0, POSITION_MAX as i32) as u32
+					};
+					match axis {
+						ABS_X => self.position.0 = scaled,
+						_     => self.position.1 = scaled
+					}
+					self.moved = true;
+				}
+				_ => ()
+			}
+		}
+		out
+	}
+
+	fn report(&mut self, out: &mut Vec<input::Event>) {
+		if self.motion != (0, 0) {
+			out.push(input::Event::Motion { dx: self.motion.0, dy: self.motion.1 });
+		}
+		if self.wheel != (0, 0) {
+			out.push(input::Event::Wheel { dx: self.wheel.0, dy: self.wheel.1 });
+		}
+		if self.moved {
+			out.push(input::Event::Position { x: self.position.0, y: self.position.1 });
+		}
+		self.reset();
+	}
+
+	fn reset(&mut self) {
+		self.motion = (0, 0);
+		self.wheel = (0, 0);
+		self.moved = false;
+	}
+}
+
+/// Reads a configuration field, an empty result means the device doesn't have it.
+fn query(transport: &mut impl Transport, select: u8, subsel: u8) -> Vec<u8> {
+	transport.write_config(CONFIG_SELECT, 1, select as u32);
+	transport.write_config(CONFIG_SUBSEL, 1, subsel as u32);
+	let size = (transport.read_config_u8(CONFIG_SIZE) as usize).min(CONFIG_DATA_LEN);
+	let mut data = alloc::vec![0; size];
+	transport.read_config_bytes(CONFIG_DATA, &mut data);
+	data
+}
+
+pub struct Input<T: Transport, D: Dma> {
+	transport:  T,
+	dma:        D,
+	vq:         Option<Virtqueue>,
+	buffers:    Option<Region>,
+	/// The buffer each token refers to
+	slots:      Vec<u16>,
+	name:       String,
+	ids:        DevIds,
+	/// Bitmaps of the supported codes of `EV_KEY`, `EV_REL` and `EV_ABS`
+	bits:       [Vec<u8>; 3],
+	abs:        [Option<AbsInfo>; 2],
+	translator: Translator,
+	features:   u64
+}
+
+impl<T: Transport, D: Dma> Input<T, D> {
+	pub fn new(mut transport: T, mut dma: D) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::Input as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, FEATURE_RING_PACKED)?;
+		let name = String::from_utf8_lossy(&query(&mut transport, CFG_ID_NAME, 0)).into_owned();
+		let ids = match query(&mut transport, CFG_ID_DEVIDS, 0) {
+			d if d.len() >= 8 => DevIds {
+				bustype: u16::from_le_bytes([d[0], d[1]]),
+				vendor:  u16::from_le_bytes([d[2], d[3]]),
+				product: u16::from_le_bytes([d[4], d[5]]),
+				version: u16::from_le_bytes([d[6], d[7]])
+			},
+			_ => DevIds::default()
+		};
+		let bits = [EV_KEY, EV_REL, EV_ABS].map(|ty| query(&mut transport, CFG_EV_BITS, ty as u8));
+		let abs = [ABS_X, ABS_Y].map(|axis| {
+			if bits[2].get(axis as usize / 8).map_or(true, |b| b & 1 << (axis % 8) == 0) {
+				return None;
+			}
+			let d = query(&mut transport, CFG_ABS_INFO, axis as u8);
+			let word = |i: usize| i32::from_le_bytes(d[4 * i..4 * i + 4].try_into().unwrap());
+			(d.len() >= 20).then(|| AbsInfo { min: word(0), max: word(1), fuzz: word(2), flat: word(3), res: word(4) })
+		});
+		transport.write_config(CONFIG_SELECT, 1, CFG_UNSET as u32);
+
+		let mut dev = Self { transport, dma, vq: None, buffers: None, slots: Vec::new(), name, ids, bits, abs,
+			translator: Translator::new(abs), features };
+		let vq = Virtqueue::new(&mut dev.transport, &mut dev.dma, 0, QUEUE_SIZE, features & FEATURE_RING_PACKED != 0)?;
+		let size = vq.size();
+		dev.vq = Some(vq);
+		dev.buffers = Some(Region::alloc(&mut dev.dma, size as usize * EVENT_LEN, PAGE_SIZE).ok_or(Error::NoMemory)?);
+		dev.slots = alloc::vec![0; size as usize];
+		for slot in 0..size {
+			dev.post(slot)?;
+		}
+		dev.transport.driver_ok();
+		dev.vq.as_ref().unwrap().notify(&mut dev.transport);
+		Ok(dev)
+	}
+
+	/// Makes an event buffer available to the device.
+	fn post(&mut self, slot: u16) -> Result<(), Error> {
+		let (Some(vq), Some(buffers)) = (self.vq.as_mut(), self.buffers.as_ref()) else { return Err(Error::NoQueue) };
+		let token = vq.push(&[Buffer::write(buffers.phys + (slot as usize * EVENT_LEN) as u64, EVENT_LEN as u32)])?;
+		self.slots[token as usize] = slot;
+		Ok(())
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	pub fn ids(&self) -> DevIds {
+		self.ids
+	}
+
+	/// Reads a configuration field, `CFG_*`.
+	pub fn config(&mut self, select: u8, subsel: u8) -> Vec<u8> {
+		let data = query(&mut self.transport, select, subsel);
+		self.transport.write_config(CONFIG_SELECT, 1, CFG_UNSET as u32);
+		data
+	}
+
+	/// Whether the device reports the code of an event type, `EV_KEY`, `EV_REL` or
+	/// `EV_ABS`.
+	pub fn supports(&self, ty: u16, code: u16) -> bool {
+		let bits = match ty {
+			EV_KEY => &self.bits[0],
+			EV_REL => &self.bits[1],
+			EV_ABS => &self.bits[2],
+			_      => return false
+		};
+		bits.get(code as usize / 8).map_or(false, |b| b & 1 << (code % 8) != 0)
+	}
+
+	/// The range of `ABS_X` or `ABS_Y`.
+	pub fn abs_info(&self, axis: u16) -> Option<AbsInfo> {
+		*self.abs.get(axis as usize)?
+	}
+
+	/// The events the device reported since the last call.
+	pub fn events(&mut self) -> Vec<RawEvent> {
+		let mut events = Vec::new();
+		while let Some((token, _)) = self.vq.as_mut().and_then(|vq| vq.pop()) {
+			let slot = self.slots[token as usize];
+			let buffers = self.buffers.as_ref().unwrap();
+			let buf = unsafe { core::slice::from_raw_parts(buffers.virt.add(slot as usize * EVENT_LEN), EVENT_LEN) };
+			events.extend(RawEvent::parse(buf));
+			let _ = self.post(slot);
+		}
+		if !events.is_empty() {
+			self.vq.as_ref().unwrap().notify(&mut self.transport);
+		}
+		events
+	}
+
+	/// The reported events, translated.
+	pub fn poll(&mut self) -> Vec<input::Event> {
+		let events = self.events();
+		self.translator.translate(&events)
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`.
+	pub fn interrupt(&mut self) -> u32 {
+		self.transport.ack_interrupt()
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Input<T, D> {
+	fn drop(&mut self) {
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		if let Some(vq) = self.vq.take() {
+			vq.free(&mut self.dma);
+		}
+		if let Some(buffers) = self.buffers.take() {
+			buffers.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Input<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Input")
+			.field("name", &self.name)
+			.field("ids", &self.ids)
+			.field("keys", &self.supports(EV_KEY, input::KEY_A))
+			.field("rel", &self.supports(EV_REL, REL_X))
+			.field("abs", &self.abs)
+			.finish()
+	}
+}
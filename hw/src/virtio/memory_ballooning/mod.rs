@@ -0,0 +1,298 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Traditional memory balloon devices.
+//!
+//! The host sets the number of pages it wants the guest to give up in the configuration.
+//! The driver inflates the balloon by taking pages from its allocator and sending their
+//! page frame numbers on the inflate queue, and deflates it by sending them on the deflate
+//! queue before using the pages again. `actual` tells the host how many pages the balloon
+//! holds.
+//!
+//! With `FEATURE_STATS_VQ` the driver sends memory statistics once after start and again
+//! every time the device returns the last ones. With `FEATURE_PAGE_REPORTING` the driver
+//! reports free blocks of memory, which the host may reclaim until they are allocated
+//! again. Queues of features that weren't negotiated are left out, later queues move up.
+//! Requests are polled one at a time.
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, FEATURE_RING_PACKED},
+	crate::dma::{Dma, Region, PAGE_SIZE}
+};
+
+/// Pages must not be used before the host was told about their deflation
+pub const FEATURE_MUST_TELL_HOST: u64 = 1 << 0;
+pub const FEATURE_STATS_VQ:       u64 = 1 << 1;
+/// The balloon may be deflated when the guest runs out of memory
+pub const FEATURE_DEFLATE_ON_OOM: u64 = 1 << 2;
+pub const FEATURE_FREE_PAGE_HINT: u64 = 1 << 3;
+pub const FEATURE_PAGE_POISON:    u64 = 1 << 4;
+pub const FEATURE_PAGE_REPORTING: u64 = 1 << 5;
+
+const SUPPORTED: u64 = FEATURE_MUST_TELL_HOST | FEATURE_STATS_VQ | FEATURE_DEFLATE_ON_OOM
+	| FEATURE_PAGE_REPORTING | FEATURE_RING_PACKED;
+
+/// Offsets of the fields of the device configuration
+pub const CONFIG_NUM_PAGES:             usize = 0;
+pub const CONFIG_ACTUAL:                usize = 4;
+pub const CONFIG_FREE_PAGE_HINT_CMD_ID: usize = 8;
+pub const CONFIG_POISON_VAL:            usize = 12;
+
+/// Page frame numbers always refer to 4 KiB pages
+pub const PFN_SHIFT: u32 = 12;
+/// Most page frame numbers sent in one request
+pub const PFNS_PER_REQUEST: usize = 256;
+/// Most blocks reported in one request
+pub const REPORT_CAPACITY: usize = 32;
+
+/// Tags of the statistics
+pub const STAT_SWAP_IN:      u16 = 0;
+pub const STAT_SWAP_OUT:     u16 = 1;
+pub const STAT_MAJFLT:       u16 = 2;
+pub const STAT_MINFLT:       u16 = 3;
+pub const STAT_MEMFREE:      u16 = 4;
+pub const STAT_MEMTOT:       u16 = 5;
+pub const STAT_AVAIL:        u16 = 6;
+pub const STAT_CACHES:       u16 = 7;
+pub const STAT_HTLB_PGALLOC: u16 = 8;
+pub const STAT_HTLB_PGFAIL:  u16 = 9;
+
+/// Size of a statistic: the tag as u16 and the value as u64, packed
+pub const STAT_LEN: usize = 10;
+pub const MAX_STATS: usize = 10;
+
+const QUEUE_SIZE: u16 = 64;
+const REQUEST_TIMEOUT_US: u64 = 1_000_000;
+/// The statistics are sent from the second half of the page, the PFNs from the first
+const PAGE_STATS: usize = PAGE_SIZE / 2;
+
+pub struct Balloon<T: Transport, D: Dma> {
+	transport: T,
+	dma:       D,
+	inflate:   Option<Virtqueue>,
+	deflate:   Option<Virtqueue>,
+	stats:     Option<Virtqueue>,
+	reporting: Option<Virtqueue>,
+	page:      Option<Region>,
+	/// Pages in the balloon
+	actual:    u32,
+	/// The device holds the last statistics
+	stats_out: bool,
+	features:  u64
+}
+
+impl<T: Transport, D: Dma> Balloon<T, D> {
+	pub fn new(mut transport: T, mut dma: D) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::MemoryBallooning as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, SUPPORTED)?;
+		let mut balloon = Self {
+			transport, dma, inflate: None, deflate: None, stats: None, reporting: None, page: None,
+			actual: 0, stats_out: false, features
+		};
+
+		balloon.inflate = Some(balloon.queue(0)?);
+		balloon.deflate = Some(balloon.queue(1)?);
+		let mut index = 2;
+		if features & FEATURE_STATS_VQ != 0 {
+			balloon.stats = Some(balloon.queue(index)?);
+			index += 1;
+		}
+		if features & FEATURE_PAGE_REPORTING != 0 {
+			balloon.reporting = Some(balloon.queue(index)?);
+		}
+		balloon.page = Some(Region::alloc(&mut balloon.dma, PAGE_SIZE, PAGE_SIZE).ok_or(Error::NoMemory)?);
+		balloon.transport.driver_ok();
+		balloon.transport.write_config(CONFIG_ACTUAL, 4, 0);
+		Ok(balloon)
+	}
+
+	fn queue(&mut self, index: u16) -> Result<Virtqueue, Error> {
+		let packed = self.features & FEATURE_RING_PACKED != 0;
+		let mut vq = Virtqueue::new(&mut self.transport, &mut self.dma, index, QUEUE_SIZE, packed)?;
+		vq.set_interrupts(false);
+		Ok(vq)
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	/// Pages the host wants in the balloon.
+	pub fn target(&mut self) -> u32 {
+		self.transport.read_config_u32(CONFIG_NUM_PAGES)
+	}
+
+	/// Pages in the balloon.
+	pub fn actual(&self) -> u32 {
+		self.actual
+	}
+
+	/// Pages to add to the balloon, negative if pages should be taken out.
+	pub fn delta(&mut self) -> i64 {
+		self.target() as i64 - self.actual as i64
+	}
+
+	/// Whether deflated pages may only be used after `deflate` returned.
+	pub fn must_tell_host(&self) -> bool {
+		self.features & FEATURE_MUST_TELL_HOST != 0
+	}
+
+	/// Whether the balloon may be deflated beyond the target when the guest runs out of
+	/// memory.
+	pub fn deflate_on_oom(&self) -> bool {
+		self.features & FEATURE_DEFLATE_ON_OOM != 0
+	}
+
+	/// Sends a request and waits until the device is done with it.
+	fn request(transport: &mut T, dma: &mut D, vq: &mut Virtqueue, buffers: &[Buffer]) -> Result<(), Error> {
+		let token = vq.push(buffers)?;
+		vq.notify(transport);
+		for _ in 0..REQUEST_TIMEOUT_US / 10 {
+			match vq.pop() {
+				Some((t, _)) if t == token => return Ok(()),
+				Some(_) => (),
+				None => dma.stall(10)
+			}
+		}
+		Err(Error::Timeout)
+	}
+
+	/// Sends page frame numbers on the inflate or deflate queue.
+	fn send_pfns(&mut self, inflate: bool, pfns: &[u32]) -> Result<(), Error> {
+		if pfns.len() > PFNS_PER_REQUEST {
+			return Err(Error::InvalidArgument);
+		} else if pfns.is_empty() {
+			return Ok(());
+		}
+
+		let vq = match inflate {
+			true  => self.inflate.as_mut(),
+			false => self.deflate.as_mut()
+		};
+		let (Some(vq), Some(page)) = (vq, self.page.as_ref()) else { return Err(Error::NoQueue) };
+		for (i, pfn) in pfns.iter().enumerate() {
+			unsafe { (page.virt as *mut u32).add(i).write_unaligned(pfn.to_le()); }
+		}
+		Self::request(&mut self.transport, &mut self.dma, vq, &[Buffer::read(page.phys, 4 * pfns.len() as u32)])
+	}
+
+	/// Gives the pages to the host, at most `PFNS_PER_REQUEST` at a time. The pages must
+	/// not be accessed until they were deflated again.
+	pub fn inflate(&mut self, pfns: &[u32]) -> Result<(), Error> {
+		self.send_pfns(true, pfns)?;
+		self.actual += pfns.len() as u32;
+		self.transport.write_config(CONFIG_ACTUAL, 4, self.actual);
+		Ok(())
+	}
+
+	/// Takes pages back from the host, at most `PFNS_PER_REQUEST` at a time. Their content
+	/// is undefined.
+	pub fn deflate(&mut self, pfns: &[u32]) -> Result<(), Error> {
+		self.send_pfns(false, pfns)?;
+		self.actual = self.actual.saturating_sub(pfns.len() as u32);
+		self.transport.write_config(CONFIG_ACTUAL, 4, self.actual);
+		Ok(())
+	}
+
+	/// Whether the device returned the last statistics, i.e. wants new ones. The first
+	/// ones are sent unasked.
+	pub fn stats_requested(&mut self) -> bool {
+		let Some(vq) = self.stats.as_mut() else { return false };
+		while vq.pop().is_some() {
+			self.stats_out = false;
+		}
+		!self.stats_out
+	}
+
+	/// Sends statistics, `(STAT_*, value)`, memory sizes are in bytes. Returns false if
+	/// the device still holds the last ones.
+	pub fn send_stats(&mut self, stats: &[(u16, u64)]) -> Result<bool, Error> {
+		if stats.len() > MAX_STATS {
+			return Err(Error::InvalidArgument);
+		} else if !self.stats_requested() {
+			return Ok(false);
+		}
+
+		let (Some(vq), Some(page)) = (self.stats.as_mut(), self.page.as_ref()) else { return Err(Error::Unsupported) };
+		let buf = unsafe { core::slice::from_raw_parts_mut(page.virt.add(PAGE_STATS), STAT_LEN * MAX_STATS) };
+		for ((tag, value), entry) in stats.iter().zip(buf.chunks_exact_mut(STAT_LEN)) {
+			entry[..2].copy_from_slice(&tag.to_le_bytes());
+			entry[2..].copy_from_slice(&value.to_le_bytes());
+		}
+		vq.push(&[Buffer::read(page.phys + PAGE_STATS as u64, (STAT_LEN * stats.len()) as u32)])?;
+		vq.notify(&mut self.transport);
+		self.stats_out = true;
+		Ok(true)
+	}
+
+	/// Blocks that can be reported at once, 0 without `FEATURE_PAGE_REPORTING`.
+	pub fn report_capacity(&self) -> usize {
+		self.reporting.as_ref().map_or(0, |vq| (vq.size() as usize).min(REPORT_CAPACITY))
+	}
+
+	/// Reports free blocks of memory, `(physical address, length in bytes)`. The host
+	/// may discard their content, they must not be allocated until this returns.
+	pub fn report(&mut self, blocks: &[(u64, u32)]) -> Result<(), Error> {
+		if blocks.len() > self.report_capacity() {
+			return Err(if self.reporting.is_none() { Error::Unsupported } else { Error::InvalidArgument });
+		} else if blocks.is_empty() {
+			return Ok(());
+		}
+
+		let buffers = blocks.iter().map(|(phys, len)| Buffer::write(*phys, *len)).collect::<alloc::vec::Vec<_>>();
+		let vq = self.reporting.as_mut().ok_or(Error::Unsupported)?;
+		Self::request(&mut self.transport, &mut self.dma, vq, &buffers)
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`. A configuration
+	/// change means a new target.
+	pub fn interrupt(&mut self) -> u32 {
+		self.transport.ack_interrupt()
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Balloon<T, D> {
+	fn drop(&mut self) {
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		for vq in [self.inflate.take(), self.deflate.take(), self.stats.take(), self.reporting.take()].into_iter().flatten() {
+			vq.free(&mut self.dma);
+		}
+		if let Some(page) = self.page.take() {
+			page.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Balloon<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Balloon")
+			.field("actual", &self.actual)
+			.field("stats", &self.stats.is_some())
+			.field("reporting", &self.reporting.is_some())
+			.field("must_tell_host", &self.must_tell_host())
+			.field("deflate_on_oom", &self.deflate_on_oom())
+			.finish()
+	}
+}
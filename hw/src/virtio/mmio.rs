@@ -0,0 +1,262 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The memory mapped transport, version 2. Platforms without PCI list the devices in
+//! the device tree as `virtio,mmio` nodes.
+
+use {
+	super::{Error, Transport},
+	crate::devtree::{FdtHeader, FdtStructureToken},
+	alloc::vec::Vec,
+	core::ptr::addr_of_mut
+};
+
+/// "virt"
+pub const MAGIC: u32 = 0x74726976;
+/// The legacy interface is version 1
+pub const VERSION: u32 = 2;
+/// Offset of the device configuration
+pub const CONFIG_OFFSET: usize = 0x100;
+
+#[repr(C)]
+pub struct Registers {
+	pub magic:               u32,
+	pub version:             u32,
+	pub device_id:           u32,
+	pub vendor_id:           u32,
+	pub device_features:     u32,
+	pub device_features_sel: u32,
+	_reserved0:              [u32; 2],
+	pub driver_features:     u32,
+	pub driver_features_sel: u32,
+	_reserved1:              [u32; 2],
+	pub queue_sel:           u32,
+	pub queue_num_max:       u32,
+	pub queue_num:           u32,
+	_reserved2:              [u32; 2],
+	pub queue_ready:         u32,
+	_reserved3:              [u32; 2],
+	pub queue_notify:        u32,
+	_reserved4:              [u32; 3],
+	pub interrupt_status:    u32,
+	pub interrupt_ack:       u32,
+	_reserved5:              [u32; 2],
+	pub status:              u32,
+	_reserved6:              [u32; 3],
+	pub queue_desc_low:      u32,
+	pub queue_desc_high:     u32,
+	_reserved7:              [u32; 2],
+	pub queue_driver_low:    u32,
+	pub queue_driver_high:   u32,
+	_reserved8:              [u32; 2],
+	pub queue_device_low:    u32,
+	pub queue_device_high:   u32,
+	_reserved9:              [u32; 1],
+	pub shm_sel:             u32,
+	pub shm_len_low:         u32,
+	pub shm_len_high:        u32,
+	pub shm_base_low:        u32,
+	pub shm_base_high:       u32,
+	pub queue_reset:         u32,
+	_reserved10:             [u32; 14],
+	pub config_generation:   u32
+}
+
+/// A device in the device tree, the registers are at `base`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Node {
+	pub base:       u64,
+	pub size:       u64,
+	/// The interrupt specifier, in the format of the interrupt parent
+	pub interrupts: Vec<u32>
+}
+
+/// Finds the `virtio,mmio` nodes anywhere in the tree, in the order of the tree. Assumes
+/// two address and two size cells, as on all supported platforms.
+pub fn find(fdt: &FdtHeader) -> Vec<Node> {
+	// whether each open node is compatible, and what it has of its reg and interrupts
+	let mut stack: Vec<(bool, Node)> = Vec::new();
+	let mut nodes = Vec::new();
+
+	for token in fdt.structure_block() {
+		match token {
+			FdtStructureToken::BeginNone { .. } => stack.push((false, Node::default())),
+			FdtStructureToken::Prop { name, value } => if let Some((compatible, node)) = stack.last_mut() {
+				let cells = value.chunks_exact(4).map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]])).collect::<Vec<_>>();
+				match name {
+					"compatible" => *compatible = value.split(|&c| c == 0).any(|s| s == b"virtio,mmio"),
+					"reg" if cells.len() >= 4 => {
+						node.base = (cells[0] as u64) << 32 | cells[1] as u64;
+						node.size = (cells[2] as u64) << 32 | cells[3] as u64;
+					}
+					"interrupts" => node.interrupts = cells,
+					_ => ()
+				}
+			}
+			FdtStructureToken::EndNode => if let Some((true, node)) = stack.pop() {
+				if node.size != 0 {
+					nodes.push(node);
+				}
+			}
+		}
+	}
+
+	nodes
+}
+
+pub struct Mmio {
+	regs: *mut Registers
+}
+
+impl Mmio {
+	/// Checks the registers at `base`, mapped uncached, for a version 2 device. The
+	/// platform creates all slots, those without a device have a device id of zero.
+	///
+	/// # Safety
+	///
+	/// `base` must point to the registers and stay mapped while the transport is used.
+	pub unsafe fn new(base: *mut u8) -> Result<Self, Error> {
+		let regs = base as *mut Registers;
+		if addr_of_mut!((*regs).magic).read_volatile() != MAGIC {
+			return Err(Error::NoDevice);
+		} else if addr_of_mut!((*regs).version).read_volatile() != VERSION {
+			return Err(Error::Unsupported);
+		} else if addr_of_mut!((*regs).device_id).read_volatile() == 0 {
+			return Err(Error::NoDevice);
+		}
+		Ok(Self { regs })
+	}
+
+	pub fn vendor_id(&self) -> u32 {
+		unsafe { addr_of_mut!((*self.regs).vendor_id).read_volatile() }
+	}
+
+	fn select(&mut self, queue: u16) {
+		unsafe { addr_of_mut!((*self.regs).queue_sel).write_volatile(queue as u32) };
+	}
+}
+
+impl Transport for Mmio {
+	fn device_id(&self) -> u32 {
+		unsafe { addr_of_mut!((*self.regs).device_id).read_volatile() }
+	}
+
+	fn status(&self) -> u8 {
+		unsafe { addr_of_mut!((*self.regs).status).read_volatile() as u8 }
+	}
+
+	fn set_status(&mut self, status: u8) {
+		unsafe { addr_of_mut!((*self.regs).status).write_volatile(status as u32) }
+	}
+
+	fn device_features(&mut self) -> u64 {
+		let mut features = 0;
+		for i in 0..2 {
+			unsafe {
+				addr_of_mut!((*self.regs).device_features_sel).write_volatile(i);
+				features |= (addr_of_mut!((*self.regs).device_features).read_volatile() as u64) << (32 * i);
+			}
+		}
+		features
+	}
+
+	fn set_driver_features(&mut self, features: u64) {
+		for i in 0..2 {
+			unsafe {
+				addr_of_mut!((*self.regs).driver_features_sel).write_volatile(i);
+				addr_of_mut!((*self.regs).driver_features).write_volatile((features >> (32 * i)) as u32);
+			}
+		}
+	}
+
+	fn max_queue_size(&mut self, queue: u16) -> u16 {
+		self.select(queue);
+		unsafe { addr_of_mut!((*self.regs).queue_num_max).read_volatile().min(u16::MAX as u32) as u16 }
+	}
+
+	fn queue_enabled(&mut self, queue: u16) -> bool {
+		self.select(queue);
+		unsafe { addr_of_mut!((*self.regs).queue_ready).read_volatile() != 0 }
+	}
+
+	fn enable_queue(&mut self, queue: u16, size: u16, desc: u64, driver: u64, device: u64) {
+		self.select(queue);
+		unsafe {
+			addr_of_mut!((*self.regs).queue_num).write_volatile(size as u32);
+			addr_of_mut!((*self.regs).queue_desc_low).write_volatile(desc as u32);
+			addr_of_mut!((*self.regs).queue_desc_high).write_volatile((desc >> 32) as u32);
+			addr_of_mut!((*self.regs).queue_driver_low).write_volatile(driver as u32);
+			addr_of_mut!((*self.regs).queue_driver_high).write_volatile((driver >> 32) as u32);
+			addr_of_mut!((*self.regs).queue_device_low).write_volatile(device as u32);
+			addr_of_mut!((*self.regs).queue_device_high).write_volatile((device >> 32) as u32);
+			addr_of_mut!((*self.regs).queue_ready).write_volatile(1);
+		}
+	}
+
+	fn notify(&mut self, queue: u16) {
+		unsafe { addr_of_mut!((*self.regs).queue_notify).write_volatile(queue as u32) }
+	}
+
+	fn ack_interrupt(&mut self) -> u32 {
+		unsafe {
+			let status = addr_of_mut!((*self.regs).interrupt_status).read_volatile();
+			addr_of_mut!((*self.regs).interrupt_ack).write_volatile(status);
+			status
+		}
+	}
+
+	fn config_generation(&self) -> u32 {
+		unsafe { addr_of_mut!((*self.regs).config_generation).read_volatile() }
+	}
+
+	fn read_config(&self, offset: usize, width: usize) -> u32 {
+		let ptr = unsafe { (self.regs as *mut u8).add(CONFIG_OFFSET + offset) };
+		unsafe {
+			match width {
+				1 => ptr.read_volatile() as u32,
+				2 => (ptr as *mut u16).read_volatile() as u32,
+				_ => (ptr as *mut u32).read_volatile()
+			}
+		}
+	}
+
+	fn write_config(&mut self, offset: usize, width: usize, value: u32) {
+		let ptr = unsafe { (self.regs as *mut u8).add(CONFIG_OFFSET + offset) };
+		unsafe {
+			match width {
+				1 => ptr.write_volatile(value as u8),
+				2 => (ptr as *mut u16).write_volatile(value as u16),
+				_ => (ptr as *mut u32).write_volatile(value)
+			}
+		}
+	}
+}
+
+impl core::fmt::Debug for Mmio {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Mmio")
+			.field("regs", &self.regs)
+			.field("device_id", &self.device_id())
+			.field("status", &self.status())
+			.finish()
+	}
+}
@@ -1,4 +1,36 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
 
+//! Virtual I/O devices.
+//!
+//! A device is reached through a `Transport`, memory mapped (`mmio`) or a PCI function
+//! (`pci`). `init` resets it and negotiates features, the driver of the device type
+//! then sets up its `Virtqueue`s, split or packed depending on the features, and calls
+//! `Transport::driver_ok`. `DeviceType` maps the device id to the module implementing
+//! the device type.
+
+mod queue;
+pub mod mmio;
+pub mod pci;
 pub mod network_device;
 pub mod block_device;
 pub mod console_device;
@@ -20,4 +52,304 @@ pub mod crypto;
 pub mod signal_distribution;
 pub mod pstore;
 pub mod iommu;
-pub mod memory;
\ No newline at end of file
+pub mod memory;
+
+pub use queue::*;
+
+use crate::dma::Dma;
+
+/// The guest noticed the device
+pub const STATUS_ACKNOWLEDGE:  u8 = 1 << 0;
+/// The guest knows how to drive the device
+pub const STATUS_DRIVER:       u8 = 1 << 1;
+/// The driver is set up and ready to drive the device
+pub const STATUS_DRIVER_OK:    u8 = 1 << 2;
+/// The driver acknowledged the features it understands, feature negotiation is complete
+pub const STATUS_FEATURES_OK:  u8 = 1 << 3;
+/// The device experienced an error it can't recover from without a reset
+pub const STATUS_NEEDS_RESET:  u8 = 1 << 6;
+/// The guest gave up on the device
+pub const STATUS_FAILED:       u8 = 1 << 7;
+
+/// Descriptors may refer to a table of further descriptors
+pub const FEATURE_INDIRECT_DESC:   u64 = 1 << 28;
+/// Used and available event fields for notification suppression
+pub const FEATURE_EVENT_IDX:       u64 = 1 << 29;
+/// Compliance with version 1 of the specification, legacy devices don't offer it
+pub const FEATURE_VERSION_1:       u64 = 1 << 32;
+/// The device accesses memory through an IOMMU
+pub const FEATURE_ACCESS_PLATFORM: u64 = 1 << 33;
+/// Packed virtqueue layout
+pub const FEATURE_RING_PACKED:     u64 = 1 << 34;
+/// Buffers are used in the order they are made available
+pub const FEATURE_IN_ORDER:        u64 = 1 << 35;
+
+/// Used buffer notification
+pub const INTERRUPT_QUEUE:  u32 = 1 << 0;
+/// Configuration change notification
+pub const INTERRUPT_CONFIG: u32 = 1 << 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// No device behind the transport, or a transport or device the driver doesn't know
+	NoDevice,
+	/// A legacy device, the device didn't accept the features or doesn't support a request
+	Unsupported,
+	/// The device doesn't have the queue or it is already enabled
+	NoQueue,
+	/// No DMA memory or no free descriptors in the queue
+	NoMemory,
+	/// Empty or oversized buffer chains, device-readable after device-writable buffers, or
+	/// a request outside of the device
+	InvalidArgument,
+	/// The device can't be written to
+	ReadOnly,
+	/// The device didn't complete the reset in time
+	Timeout,
+	/// The device needs a reset or reported an error for a request
+	Device
+}
+
+impl From<Error> for crate::block::Error {
+	fn from(e: Error) -> Self {
+		match e {
+			Error::NoDevice        => Self::NoDevice,
+			Error::Unsupported | Error::NoQueue => Self::Unsupported,
+			Error::NoMemory        => Self::NoMemory,
+			Error::InvalidArgument => Self::InvalidArgument,
+			Error::ReadOnly        => Self::ReadOnly,
+			Error::Timeout         => Self::Timeout,
+			Error::Device          => Self::Io
+		}
+	}
+}
+
+impl From<Error> for crate::net::Error {
+	fn from(e: Error) -> Self {
+		match e {
+			Error::NoDevice        => Self::NoDevice,
+			Error::NoMemory        => Self::NoMemory,
+			Error::InvalidArgument => Self::InvalidArgument,
+			Error::Timeout         => Self::Timeout,
+			_                      => Self::Device
+		}
+	}
+}
+
+/// Device types by their id, each has a module in `hw::virtio`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum DeviceType {
+	NetworkDevice       = 1,
+	BlockDevice         = 2,
+	ConsoleDevice       = 3,
+	EntropyDevice       = 4,
+	MemoryBallooning    = 5,
+	IoMemory            = 6,
+	Rpmsg               = 7,
+	ScsiHost            = 8,
+	_9pTransport        = 9,
+	Mac80211Wlan        = 10,
+	RprocSerial         = 11,
+	Caif                = 12,
+	MemoryBalloon       = 13,
+	Gpu                 = 16,
+	TimerClock          = 17,
+	Input               = 18,
+	Socket              = 19,
+	Crypto              = 20,
+	SignalDistribution  = 21,
+	Pstore              = 22,
+	Iommu               = 23,
+	Memory              = 24
+}
+
+impl DeviceType {
+	pub fn from_id(id: u32) -> Option<Self> {
+		Some(match id {
+			1  => Self::NetworkDevice,
+			2  => Self::BlockDevice,
+			3  => Self::ConsoleDevice,
+			4  => Self::EntropyDevice,
+			5  => Self::MemoryBallooning,
+			6  => Self::IoMemory,
+			7  => Self::Rpmsg,
+			8  => Self::ScsiHost,
+			9  => Self::_9pTransport,
+			10 => Self::Mac80211Wlan,
+			11 => Self::RprocSerial,
+			12 => Self::Caif,
+			13 => Self::MemoryBalloon,
+			16 => Self::Gpu,
+			17 => Self::TimerClock,
+			18 => Self::Input,
+			19 => Self::Socket,
+			20 => Self::Crypto,
+			21 => Self::SignalDistribution,
+			22 => Self::Pstore,
+			23 => Self::Iommu,
+			24 => Self::Memory,
+			_  => return None
+		})
+	}
+
+	/// The name of the module implementing the device type.
+	pub fn module(self) -> &'static str {
+		match self {
+			Self::NetworkDevice      => "network_device",
+			Self::BlockDevice        => "block_device",
+			Self::ConsoleDevice      => "console_device",
+			Self::EntropyDevice      => "entropy_device",
+			Self::MemoryBallooning   => "memory_ballooning",
+			Self::IoMemory           => "io_memory",
+			Self::Rpmsg              => "rpmsg",
+			Self::ScsiHost           => "scsi_host",
+			Self::_9pTransport       => "_9p_transport",
+			Self::Mac80211Wlan       => "mac80211wlan",
+			Self::RprocSerial        => "rproc_serial",
+			Self::Caif               => "caif",
+			Self::MemoryBalloon      => "memory_balloon",
+			Self::Gpu                => "gpu",
+			Self::TimerClock         => "timer_clock",
+			Self::Input              => "input",
+			Self::Socket             => "socket",
+			Self::Crypto             => "crypto",
+			Self::SignalDistribution => "signal_distribution",
+			Self::Pstore             => "pstore",
+			Self::Iommu              => "iommu",
+			Self::Memory             => "memory"
+		}
+	}
+}
+
+/// Access to a device independent of how it is attached.
+pub trait Transport {
+	/// The device id, see `DeviceType`.
+	fn device_id(&self) -> u32;
+
+	fn status(&self) -> u8;
+
+	fn set_status(&mut self, status: u8);
+
+	fn device_features(&mut self) -> u64;
+
+	fn set_driver_features(&mut self, features: u64);
+
+	/// The maximum size of a queue, zero if the device doesn't have it.
+	fn max_queue_size(&mut self, queue: u16) -> u16;
+
+	/// Whether the queue is enabled.
+	fn queue_enabled(&mut self, queue: u16) -> bool;
+
+	/// Hands the physical addresses of the descriptor, driver and device areas of a queue
+	/// with `size` entries to the device and enables it.
+	fn enable_queue(&mut self, queue: u16, size: u16, desc: u64, driver: u64, device: u64);
+
+	/// Tells the device there are new buffers in the queue.
+	fn notify(&mut self, queue: u16);
+
+	/// Reads and acknowledges the pending interrupts, `INTERRUPT_*`.
+	fn ack_interrupt(&mut self) -> u32;
+
+	/// Changes whenever the device changes its configuration.
+	fn config_generation(&self) -> u32;
+
+	/// Reads a field of `width` 1, 2 or 4 bytes from the device configuration.
+	fn read_config(&self, offset: usize, width: usize) -> u32;
+
+	fn write_config(&mut self, offset: usize, width: usize, value: u32);
+
+	fn read_config_u8(&self, offset: usize) -> u8 {
+		self.read_config(offset, 1) as u8
+	}
+
+	fn read_config_u16(&self, offset: usize) -> u16 {
+		self.read_config(offset, 2) as u16
+	}
+
+	fn read_config_u32(&self, offset: usize) -> u32 {
+		self.read_config(offset, 4)
+	}
+
+	/// Reads a 64-bit field as two halves, again if the configuration changed in between.
+	fn read_config_u64(&self, offset: usize) -> u64 {
+		loop {
+			let generation = self.config_generation();
+			let value = self.read_config(offset, 4) as u64 | (self.read_config(offset + 4, 4) as u64) << 32;
+			if generation == self.config_generation() {
+				return value;
+			}
+		}
+	}
+
+	/// Reads a byte array, again if the configuration changed in between.
+	fn read_config_bytes(&self, offset: usize, buf: &mut [u8]) {
+		loop {
+			let generation = self.config_generation();
+			for (i, v) in buf.iter_mut().enumerate() {
+				*v = self.read_config(offset + i, 1) as u8;
+			}
+			if generation == self.config_generation() {
+				return;
+			}
+		}
+	}
+
+	/// Completes the initialization once the queues are set up.
+	fn driver_ok(&mut self) {
+		let status = self.status();
+		self.set_status(status | STATUS_DRIVER_OK);
+	}
+
+	/// Tells the device the driver gave up on it.
+	fn fail(&mut self) {
+		let status = self.status();
+		self.set_status(status | STATUS_FAILED);
+	}
+
+	fn needs_reset(&self) -> bool {
+		self.status() & STATUS_NEEDS_RESET != 0
+	}
+}
+
+/// How long a device may take to complete a reset.
+const RESET_TIMEOUT_US: u64 = 1_000_000;
+
+/// Resets the device, which stops it from using the queues.
+pub fn reset(transport: &mut impl Transport, dma: &mut impl Dma) -> Result<(), Error> {
+	transport.set_status(0);
+	for _ in 0..RESET_TIMEOUT_US / 10 {
+		if transport.status() == 0 {
+			return Ok(());
+		}
+		dma.stall(10);
+	}
+	match transport.status() {
+		0 => Ok(()),
+		_ => Err(Error::Timeout)
+	}
+}
+
+/// Resets the device and negotiates features, of the device features the driver accepts
+/// those in `supported`. Version 1 compliance is required, legacy devices are rejected.
+/// Returns the negotiated features, the driver then sets up its queues and calls
+/// `Transport::driver_ok`.
+pub fn init(transport: &mut impl Transport, dma: &mut impl Dma, supported: u64) -> Result<u64, Error> {
+	reset(transport, dma)?;
+	transport.set_status(STATUS_ACKNOWLEDGE);
+	transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+	let features = transport.device_features() & (supported | FEATURE_VERSION_1);
+	if features & FEATURE_VERSION_1 == 0 {
+		transport.fail();
+		return Err(Error::Unsupported);
+	}
+
+	transport.set_driver_features(features);
+	transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+	if transport.status() & STATUS_FEATURES_OK == 0 {
+		transport.fail();
+		return Err(Error::Unsupported);
+	}
+	Ok(features)
+}
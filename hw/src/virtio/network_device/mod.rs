@@ -1,3 +1,462 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Network devices, Ethernet interfaces.
+//!
+//! The device has a receive and a transmit queue per queue pair and spreads received
+//! packets over the pairs once more than one is enabled through the control queue.
+//! Receive queues are kept filled with buffers of `BUFFER_SIZE`, with mergeable receive
+//! buffers a packet may span several of them. Frames are copied out of and into the
+//! buffers, the queues are polled.
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, FEATURE_RING_PACKED},
+	crate::{dma::{Dma, Region, PAGE_SIZE}, net},
+	alloc::vec::Vec
+};
 
 pub const PCI_DEVICE_ID:              u16 = 0x1041;
-pub const PCI_DEVICE_ID_TRANSITIONAL: u16 = 0x1000;
\ No newline at end of file
+pub const PCI_DEVICE_ID_TRANSITIONAL: u16 = 0x1000;
+
+/// The device completes the checksums of sent packets
+pub const FEATURE_CSUM:       u64 = 1 << 0;
+/// The device passes received packets with a partial or already verified checksum
+pub const FEATURE_GUEST_CSUM: u64 = 1 << 1;
+/// Maximum MTU in `mtu`
+pub const FEATURE_MTU:        u64 = 1 << 3;
+/// The device's address is in `mac`
+pub const FEATURE_MAC:        u64 = 1 << 5;
+/// Received packets may span multiple buffers
+pub const FEATURE_MRG_RXBUF:  u64 = 1 << 15;
+/// The link status is in `status`
+pub const FEATURE_STATUS:     u64 = 1 << 16;
+/// Control queue
+pub const FEATURE_CTRL_VQ:    u64 = 1 << 17;
+/// Multiple queue pairs, `max_virtqueue_pairs` of them
+pub const FEATURE_MQ:         u64 = 1 << 22;
+
+/// Offsets of the fields of the device configuration
+pub const CONFIG_MAC:          usize = 0;
+pub const CONFIG_STATUS:       usize = 6;
+pub const CONFIG_MAX_VQ_PAIRS: usize = 8;
+pub const CONFIG_MTU:          usize = 10;
+
+pub const STATUS_LINK_UP: u16 = 1 << 0;
+
+/// The checksum from `csum_start` to the end of the packet is to be added at
+/// `csum_start + csum_offset`
+pub const HDR_F_NEEDS_CSUM: u8 = 1 << 0;
+/// The device verified the checksums of a received packet
+pub const HDR_F_DATA_VALID: u8 = 1 << 1;
+pub const HDR_GSO_NONE:     u8 = 0;
+
+/// Control queue commands, classes and their commands
+pub const CTRL_MQ:               u8 = 4;
+pub const CTRL_MQ_VQ_PAIRS_SET:  u8 = 0;
+pub const CTRL_OK:               u8 = 0;
+
+/// Size of the buffers, a packet of the largest MTU and its header fit into one
+pub const BUFFER_SIZE: usize = 2048;
+/// Entries of the receive and the transmit queues
+pub const QUEUE_SIZE:  u16 = 128;
+/// Largest MTU, frames are sent from a single buffer
+pub const MAX_MTU:     usize = BUFFER_SIZE - HEADER_LEN - net::ethernet::HEADER_LEN;
+
+pub const HEADER_LEN: usize = core::mem::size_of::<Header>();
+
+const SUPPORTED: u64 = FEATURE_CSUM | FEATURE_GUEST_CSUM | FEATURE_MTU | FEATURE_MAC | FEATURE_MRG_RXBUF
+	| FEATURE_STATUS | FEATURE_CTRL_VQ | FEATURE_MQ | FEATURE_RING_PACKED;
+const COMMAND_TIMEOUT_US: u64 = 1_000_000;
+
+/// Layout of the control page
+const CTRL_DATA: usize = 16;
+const CTRL_ACK:  usize = 64;
+
+/// The header preceding each packet in the buffers.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Header {
+	pub flags:       u8,
+	pub gso_type:    u8,
+	pub hdr_len:     u16,
+	pub gso_size:    u16,
+	pub csum_start:  u16,
+	pub csum_offset: u16,
+	/// Buffers a received packet spans, with `FEATURE_MRG_RXBUF`
+	pub num_buffers: u16
+}
+
+/// A queue and the buffers it uses.
+struct Queue {
+	vq:      Virtqueue,
+	buffers: Region,
+	/// The buffer each token refers to
+	slots:   Vec<u16>,
+	/// Buffers not in the queue, only used for transmit queues
+	free:    Vec<u16>
+}
+
+impl Queue {
+	fn new(transport: &mut impl Transport, dma: &mut impl Dma, index: u16, packed: bool) -> Result<Self, Error> {
+		let vq = Virtqueue::new(transport, dma, index, QUEUE_SIZE, packed)?;
+		let size = vq.size();
+		match Region::alloc(dma, size as usize * BUFFER_SIZE, PAGE_SIZE) {
+			Some(buffers) => Ok(Self { vq, buffers, slots: alloc::vec![0; size as usize], free: (0..size).collect() }),
+			None => {
+				vq.free(dma);
+				Err(Error::NoMemory)
+			}
+		}
+	}
+
+	fn buffer(&self, slot: u16) -> *mut u8 {
+		unsafe { self.buffers.virt.add(slot as usize * BUFFER_SIZE) }
+	}
+
+	/// Makes a receive buffer available to the device.
+	fn post(&mut self, slot: u16) -> Result<(), Error> {
+		let phys = self.buffers.phys + (slot as usize * BUFFER_SIZE) as u64;
+		let token = self.vq.push(&[Buffer::write(phys, BUFFER_SIZE as u32)])?;
+		self.slots[token as usize] = slot;
+		Ok(())
+	}
+
+	fn free(self, dma: &mut impl Dma) {
+		self.vq.free(dma);
+		self.buffers.free(dma);
+	}
+}
+
+struct Pair {
+	rx: Queue,
+	tx: Queue
+}
+
+pub struct Net<T: Transport, D: Dma> {
+	transport: T,
+	dma:       D,
+	pairs:     Vec<Pair>,
+	ctrl:      Option<(Virtqueue, Region)>,
+	features:  u64,
+	mac:       net::Mac,
+	mtu:       usize,
+	/// The receive queue `receive` looks at first
+	next_rx:   usize
+}
+
+impl<T: Transport, D: Dma> Net<T, D> {
+	/// Initializes the device with up to `pairs` queue pairs.
+	pub fn new(mut transport: T, mut dma: D, pairs: u16) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::NetworkDevice as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, SUPPORTED)?;
+		let has = |f: u64| features & f != 0;
+		let packed = has(FEATURE_RING_PACKED);
+		// the number of pairs is set through the control queue
+		let max_pairs = match has(FEATURE_MQ) && has(FEATURE_CTRL_VQ) {
+			true  => transport.read_config_u16(CONFIG_MAX_VQ_PAIRS).max(1),
+			false => 1
+		};
+
+		let mut mac = [0; 6];
+		if has(FEATURE_MAC) {
+			for (i, b) in mac.iter_mut().enumerate() {
+				*b = transport.read_config_u8(CONFIG_MAC + i);
+			}
+		}
+		let mtu = match has(FEATURE_MTU) {
+			true  => (transport.read_config_u16(CONFIG_MTU) as usize).min(MAX_MTU),
+			false => 1500
+		};
+
+		let mut dev = Self { transport, dma, pairs: Vec::new(), ctrl: None, features, mac, mtu, next_rx: 0 };
+		for i in 0..pairs.clamp(1, max_pairs) {
+			let rx = match Queue::new(&mut dev.transport, &mut dev.dma, 2 * i, packed) {
+				Ok(rx) => rx,
+				Err(e) if i == 0 => return Err(e),
+				Err(_) => break
+			};
+			let tx = match Queue::new(&mut dev.transport, &mut dev.dma, 2 * i + 1, packed) {
+				Ok(tx) => tx,
+				Err(e) => {
+					rx.free(&mut dev.dma);
+					match i {
+						0 => return Err(e),
+						_ => break
+					}
+				}
+			};
+			dev.pairs.push(Pair { rx, tx });
+		}
+
+		if has(FEATURE_CTRL_VQ) {
+			let vq = Virtqueue::new(&mut dev.transport, &mut dev.dma, 2 * max_pairs, 8, packed)?;
+			match Region::alloc(&mut dev.dma, PAGE_SIZE, PAGE_SIZE) {
+				Some(page) => dev.ctrl = Some((vq, page)),
+				None => {
+					vq.free(&mut dev.dma);
+					return Err(Error::NoMemory);
+				}
+			}
+		}
+
+		// a device without an address gets a locally administered one, unique as its buffers are
+		if !has(FEATURE_MAC) {
+			let id = (dev.pairs[0].rx.buffers.phys / PAGE_SIZE as u64).to_be_bytes();
+			mac = [0x02, id[3], id[4], id[5], id[6], id[7]];
+			dev.mac = mac;
+		}
+
+		for pair in &mut dev.pairs {
+			pair.rx.vq.set_interrupts(false);
+			pair.tx.vq.set_interrupts(false);
+			for slot in 0..pair.rx.vq.size() {
+				pair.rx.post(slot)?;
+			}
+			pair.rx.free.clear();
+		}
+
+		dev.transport.driver_ok();
+		for pair in &dev.pairs {
+			pair.rx.vq.notify(&mut dev.transport);
+		}
+
+		if dev.pairs.len() > 1 {
+			let pairs = (dev.pairs.len() as u16).to_le_bytes();
+			if dev.command(CTRL_MQ, CTRL_MQ_VQ_PAIRS_SET, &pairs).is_err() {
+				// the device keeps using the first pair
+				while dev.pairs.len() > 1 {
+					let pair = dev.pairs.pop().unwrap();
+					pair.rx.free(&mut dev.dma);
+					pair.tx.free(&mut dev.dma);
+				}
+			}
+		}
+		Ok(dev)
+	}
+
+	pub fn pairs(&self) -> usize {
+		self.pairs.len()
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	pub fn mac(&self) -> net::Mac {
+		self.mac
+	}
+
+	pub fn mtu(&self) -> usize {
+		self.mtu
+	}
+
+	pub fn link_up(&self) -> bool {
+		match self.features & FEATURE_STATUS {
+			0 => true,
+			_ => self.transport.read_config_u16(CONFIG_STATUS) & STATUS_LINK_UP != 0
+		}
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`.
+	pub fn interrupt(&mut self) -> u32 {
+		self.transport.ack_interrupt()
+	}
+
+	/// Sends a command on the control queue and waits for its acknowledgement.
+	fn command(&mut self, class: u8, command: u8, data: &[u8]) -> Result<(), Error> {
+		let (vq, page) = self.ctrl.as_mut().ok_or(Error::Unsupported)?;
+		if data.len() > CTRL_ACK - CTRL_DATA {
+			return Err(Error::InvalidArgument);
+		}
+		unsafe {
+			page.virt.write_volatile(class);
+			page.virt.add(1).write_volatile(command);
+			core::ptr::copy_nonoverlapping(data.as_ptr(), page.virt.add(CTRL_DATA), data.len());
+			page.virt.add(CTRL_ACK).write_volatile(0xFF);
+		}
+
+		let token = vq.push(&[
+			Buffer::read(page.phys, 2),
+			Buffer::read(page.phys + CTRL_DATA as u64, data.len() as u32),
+			Buffer::write(page.phys + CTRL_ACK as u64, 1)
+		])?;
+		vq.notify(&mut self.transport);
+
+		for _ in 0..COMMAND_TIMEOUT_US / 10 {
+			match vq.pop() {
+				Some((t, _)) if t == token => return match unsafe { page.virt.add(CTRL_ACK).read_volatile() } {
+					CTRL_OK => Ok(()),
+					_       => Err(Error::Unsupported)
+				},
+				Some(_) => (),
+				None => self.dma.stall(10)
+			}
+		}
+		Err(Error::Timeout)
+	}
+
+	/// Sends a frame on a queue pair, `checksum` is completed by the device if it
+	/// supports `FEATURE_CSUM`, otherwise before it is sent.
+	pub fn transmit(&mut self, pair: usize, frame: &[u8], checksum: Option<net::Checksum>) -> Result<(), Error> {
+		if frame.is_empty() || frame.len() + HEADER_LEN > BUFFER_SIZE || pair >= self.pairs.len() {
+			return Err(Error::InvalidArgument);
+		}
+		let offload = self.features & FEATURE_CSUM != 0;
+		let q = &mut self.pairs[pair].tx;
+		while let Some((token, _)) = q.vq.pop() {
+			q.free.push(q.slots[token as usize]);
+		}
+		let slot = q.free.pop().ok_or(Error::NoMemory)?;
+
+		let mut header = Header { num_buffers: 0, ..Header::default() };
+		let buf = q.buffer(slot);
+		unsafe { core::ptr::copy_nonoverlapping(frame.as_ptr(), buf.add(HEADER_LEN), frame.len()) };
+		if let Some(c) = checksum {
+			let (start, offset) = (c.start as usize, c.start as usize + c.offset as usize);
+			if offset + 2 > frame.len() {
+				q.free.push(slot);
+				return Err(Error::InvalidArgument);
+			}
+			match offload {
+				true => {
+					header.flags = HDR_F_NEEDS_CSUM;
+					header.csum_start = c.start;
+					header.csum_offset = c.offset;
+				}
+				// the field holds the sum of the pseudo header, which is part of the sum
+				false => unsafe {
+					let sum = !net::checksum_fold(net::checksum_add(0, &frame[start..]));
+					core::ptr::copy_nonoverlapping(sum.to_be_bytes().as_ptr(), buf.add(HEADER_LEN + offset), 2);
+				}
+			}
+		}
+		unsafe { (buf as *mut Header).write_volatile(header) };
+
+		let phys = q.buffers.phys + (slot as usize * BUFFER_SIZE) as u64;
+		match q.vq.push(&[Buffer::read(phys, (HEADER_LEN + frame.len()) as u32)]) {
+			Ok(token) => q.slots[token as usize] = slot,
+			Err(e) => {
+				q.free.push(slot);
+				return Err(e);
+			}
+		}
+		q.vq.notify(&mut self.transport);
+		Ok(())
+	}
+
+	/// The next frame received on a queue pair, the buffers are handed back to the device.
+	pub fn receive(&mut self, pair: usize) -> Option<net::Frame> {
+		let mergeable = self.features & FEATURE_MRG_RXBUF != 0;
+		let q = &mut self.pairs.get_mut(pair)?.rx;
+		let (token, len) = q.vq.pop()?;
+		let slot = q.slots[token as usize];
+		let buf = q.buffer(slot);
+		let header = unsafe { (buf as *const Header).read_volatile() };
+		let len = (len as usize).clamp(HEADER_LEN, BUFFER_SIZE);
+		let mut data = unsafe { core::slice::from_raw_parts(buf.add(HEADER_LEN), len - HEADER_LEN) }.to_vec();
+		let _ = q.post(slot);
+
+		let count = match mergeable {
+			true  => header.num_buffers.max(1),
+			false => 1
+		};
+		for _ in 1..count {
+			// the device makes all buffers of a packet used at once
+			let Some((token, len)) = q.vq.pop() else { break };
+			let slot = q.slots[token as usize];
+			data.extend_from_slice(unsafe { core::slice::from_raw_parts(q.buffer(slot), (len as usize).min(BUFFER_SIZE)) });
+			let _ = q.post(slot);
+		}
+		q.vq.notify(&mut self.transport);
+
+		Some(net::Frame { data, checksum_valid: header.flags & (HDR_F_DATA_VALID | HDR_F_NEEDS_CSUM) != 0 })
+	}
+
+	/// The next frame received on any queue pair.
+	pub fn receive_any(&mut self) -> Option<net::Frame> {
+		for i in 0..self.pairs.len() {
+			let pair = (self.next_rx + i) % self.pairs.len();
+			if let Some(frame) = self.receive(pair) {
+				self.next_rx = (pair + 1) % self.pairs.len();
+				return Some(frame);
+			}
+		}
+		None
+	}
+}
+
+/// As a `net::Device`, frames are sent on the first queue pair and received on all.
+impl<T: Transport, D: Dma> net::Device for Net<T, D> {
+	fn mac(&self) -> net::Mac {
+		self.mac
+	}
+
+	fn mtu(&self) -> usize {
+		self.mtu
+	}
+
+	fn link_up(&mut self) -> bool {
+		Net::link_up(self)
+	}
+
+	fn checksum_offload(&self) -> bool {
+		self.features & FEATURE_CSUM != 0
+	}
+
+	fn transmit(&mut self, frame: &[u8], checksum: Option<net::Checksum>) -> net::Result<()> {
+		Ok(Net::transmit(self, 0, frame, checksum)?)
+	}
+
+	fn receive(&mut self) -> Option<net::Frame> {
+		self.receive_any()
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Net<T, D> {
+	fn drop(&mut self) {
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		for pair in self.pairs.drain(..) {
+			pair.rx.free(&mut self.dma);
+			pair.tx.free(&mut self.dma);
+		}
+		if let Some((vq, page)) = self.ctrl.take() {
+			vq.free(&mut self.dma);
+			page.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Net<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		let m = self.mac;
+		f.debug_struct("Net")
+			.field("mac", &format_args!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5]))
+			.field("mtu", &self.mtu)
+			.field("pairs", &self.pairs.len())
+			.field("csum", &(self.features & FEATURE_CSUM != 0))
+			.field("mrg_rxbuf", &(self.features & FEATURE_MRG_RXBUF != 0))
+			.finish()
+	}
+}
@@ -0,0 +1,353 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The modern PCI transport. Vendor-specific capabilities locate the common, notify,
+//! ISR and device configuration structures in the BARs of the function.
+
+use {
+	super::{Error, Transport},
+	crate::pcie::{CapabilityHeader, ConfigSpace, Device},
+	alloc::vec::Vec,
+	core::ptr::addr_of_mut
+};
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+/// Modern functions have the device id `DEVICE_ID_BASE` plus the virtio device id,
+/// transitional ones a device id below it and the virtio device id as subsystem id
+pub const DEVICE_ID_BASE: u16 = 0x1040;
+pub const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+
+/// Capability `cfg_type`s
+pub const CAP_COMMON_CFG:    u8 = 1;
+pub const CAP_NOTIFY_CFG:    u8 = 2;
+pub const CAP_ISR_CFG:       u8 = 3;
+pub const CAP_DEVICE_CFG:    u8 = 4;
+pub const CAP_PCI_CFG:       u8 = 5;
+pub const CAP_SHARED_MEMORY: u8 = 8;
+
+/// No MSI-X vector, interrupts are signalled through INTx and the ISR status
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+#[repr(C)]
+pub struct CommonConfig {
+	pub device_feature_select: u32,
+	pub device_feature:        u32,
+	pub driver_feature_select: u32,
+	pub driver_feature:        u32,
+	pub config_msix_vector:    u16,
+	pub num_queues:            u16,
+	pub device_status:         u8,
+	pub config_generation:     u8,
+	pub queue_select:          u16,
+	pub queue_size:            u16,
+	pub queue_msix_vector:     u16,
+	pub queue_enable:          u16,
+	pub queue_notify_off:      u16,
+	pub queue_desc_low:        u32,
+	pub queue_desc_high:       u32,
+	pub queue_driver_low:      u32,
+	pub queue_driver_high:     u32,
+	pub queue_device_low:      u32,
+	pub queue_device_high:     u32,
+	pub queue_notify_data:     u16,
+	pub queue_reset:           u16
+}
+
+/// Where a configuration structure is located.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Structure {
+	pub bar:    u8,
+	pub offset: u32,
+	pub length: u32
+}
+
+/// The configuration structures of a function, the first capability of each type.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Capabilities {
+	pub common:            Option<Structure>,
+	pub notify:            Option<Structure>,
+	/// Queue notify addresses are `queue_notify_off` times this apart
+	pub notify_multiplier: u32,
+	pub isr:               Option<Structure>,
+	pub device:            Option<Structure>
+}
+
+impl Capabilities {
+	pub fn read(cfg: &mut impl ConfigSpace, device: &Device) -> Self {
+		let mut caps = Self::default();
+
+		for cap in device.capabilities.iter().filter(|c| !c.extended && c.id == CapabilityHeader::ID_VENDOR_SPECIFIC as u16) {
+			let header = cfg.read(device.address, cap.offset);
+			let bar = cfg.read(device.address, cap.offset + 4) as u8;
+			// bars past the sixth are reserved
+			if ((header >> 16) as u8) < 16 || bar > 5 {
+				continue;
+			}
+
+			let structure = Some(Structure {
+				bar,
+				offset: cfg.read(device.address, cap.offset + 8),
+				length: cfg.read(device.address, cap.offset + 12)
+			});
+
+			let slot = match (header >> 24) as u8 {
+				CAP_COMMON_CFG => &mut caps.common,
+				CAP_NOTIFY_CFG => &mut caps.notify,
+				CAP_ISR_CFG    => &mut caps.isr,
+				CAP_DEVICE_CFG => &mut caps.device,
+				_ => continue
+			};
+
+			if slot.is_none() {
+				*slot = structure;
+				if (header >> 24) as u8 == CAP_NOTIFY_CFG {
+					caps.notify_multiplier = cfg.read(device.address, cap.offset + 16);
+				}
+			}
+		}
+
+		caps
+	}
+
+	/// The BARs the structures are in, each once.
+	pub fn bars(&self) -> Vec<u8> {
+		let mut bars = [self.common, self.notify, self.isr, self.device].iter()
+			.flatten()
+			.map(|s| s.bar)
+			.collect::<Vec<_>>();
+		bars.sort_unstable();
+		bars.dedup();
+		bars
+	}
+}
+
+/// The virtio device id of a function, see `super::DeviceType`.
+pub fn device_id(device: &Device) -> Option<u32> {
+	match device.device_id {
+		_ if device.vendor_id != VENDOR_ID => None,
+		id if TRANSITIONAL_DEVICE_IDS.contains(&id) => Some(device.subsys_id as u32),
+		id if (DEVICE_ID_BASE..DEVICE_ID_BASE + 0x40).contains(&id) => Some((id - DEVICE_ID_BASE) as u32),
+		_ => None
+	}
+}
+
+pub struct Pci {
+	common:         *mut CommonConfig,
+	notify:         *mut u8,
+	multiplier:     u32,
+	isr:            *mut u8,
+	/// Null if the device type has no configuration
+	device:         *mut u8,
+	device_id:      u32,
+	/// MSI-X vectors for the queues, see `set_vectors`
+	vectors:        u16,
+	notify_offsets: Vec<u16>
+}
+
+impl Pci {
+	/// Locates the configuration structures in the BARs, `bars` holds where each BAR of
+	/// the function is mapped, or null.
+	///
+	/// # Safety
+	///
+	/// The BARs listed by `Capabilities::bars` must be mapped uncached and stay mapped
+	/// while the transport is used.
+	pub unsafe fn new(device: &Device, caps: &Capabilities, bars: &[*mut u8; 6]) -> Result<Self, Error> {
+		let device_id = self::device_id(device).ok_or(Error::NoDevice)?;
+
+		let map = |s: Option<Structure>, min: usize| -> Result<*mut u8, Error> {
+			let s = s.ok_or(Error::NoDevice)?;
+			let size = device.bars[s.bar as usize].map_or(0, |bar| bar.size);
+			match bars[s.bar as usize] {
+				base if !base.is_null() && (s.length as usize) >= min && s.offset as u64 + s.length as u64 <= size =>
+					Ok(base.add(s.offset as usize)),
+				_ => Err(Error::NoDevice)
+			}
+		};
+
+		let common = map(caps.common, core::mem::size_of::<CommonConfig>())? as *mut CommonConfig;
+		let notify = map(caps.notify, 2)?;
+		let isr = map(caps.isr, 1)?;
+		let device = match caps.device {
+			Some(_) => map(caps.device, 0)?,
+			None => core::ptr::null_mut()
+		};
+
+		Ok(Self { common, notify, multiplier: caps.notify_multiplier, isr, device, device_id, vectors: 0,
+			notify_offsets: Vec::new() })
+	}
+
+	pub fn num_queues(&self) -> u16 {
+		unsafe { addr_of_mut!((*self.common).num_queues).read_volatile() }
+	}
+
+	/// Routes configuration changes to MSI-X vector 0 and spreads the queues enabled
+	/// afterwards over the remaining `vectors - 1`, or also vector 0 if it is the only
+	/// one. Zero switches back to INTx. Returns false if the device has no room for the
+	/// vectors.
+	pub fn set_vectors(&mut self, vectors: u16) -> bool {
+		self.vectors = vectors;
+		let vector = match vectors {
+			0 => NO_VECTOR,
+			_ => 0
+		};
+		unsafe {
+			addr_of_mut!((*self.common).config_msix_vector).write_volatile(vector);
+			addr_of_mut!((*self.common).config_msix_vector).read_volatile() == vector
+		}
+	}
+
+	fn queue_vector(&self, queue: u16) -> u16 {
+		match self.vectors {
+			0 => NO_VECTOR,
+			1 => 0,
+			n => 1 + queue % (n - 1)
+		}
+	}
+
+	fn select(&mut self, queue: u16) {
+		unsafe { addr_of_mut!((*self.common).queue_select).write_volatile(queue) };
+	}
+}
+
+impl Transport for Pci {
+	fn device_id(&self) -> u32 {
+		self.device_id
+	}
+
+	fn status(&self) -> u8 {
+		unsafe { addr_of_mut!((*self.common).device_status).read_volatile() }
+	}
+
+	fn set_status(&mut self, status: u8) {
+		unsafe { addr_of_mut!((*self.common).device_status).write_volatile(status) }
+	}
+
+	fn device_features(&mut self) -> u64 {
+		let mut features = 0;
+		for i in 0..2 {
+			unsafe {
+				addr_of_mut!((*self.common).device_feature_select).write_volatile(i);
+				features |= (addr_of_mut!((*self.common).device_feature).read_volatile() as u64) << (32 * i);
+			}
+		}
+		features
+	}
+
+	fn set_driver_features(&mut self, features: u64) {
+		for i in 0..2 {
+			unsafe {
+				addr_of_mut!((*self.common).driver_feature_select).write_volatile(i);
+				addr_of_mut!((*self.common).driver_feature).write_volatile((features >> (32 * i)) as u32);
+			}
+		}
+	}
+
+	fn max_queue_size(&mut self, queue: u16) -> u16 {
+		if queue >= self.num_queues() {
+			return 0;
+		}
+		self.select(queue);
+		unsafe { addr_of_mut!((*self.common).queue_size).read_volatile() }
+	}
+
+	fn queue_enabled(&mut self, queue: u16) -> bool {
+		self.select(queue);
+		unsafe { addr_of_mut!((*self.common).queue_enable).read_volatile() != 0 }
+	}
+
+	fn enable_queue(&mut self, queue: u16, size: u16, desc: u64, driver: u64, device: u64) {
+		let vector = self.queue_vector(queue);
+		self.select(queue);
+		let offset = unsafe {
+			addr_of_mut!((*self.common).queue_size).write_volatile(size);
+			addr_of_mut!((*self.common).queue_msix_vector).write_volatile(vector);
+			addr_of_mut!((*self.common).queue_desc_low).write_volatile(desc as u32);
+			addr_of_mut!((*self.common).queue_desc_high).write_volatile((desc >> 32) as u32);
+			addr_of_mut!((*self.common).queue_driver_low).write_volatile(driver as u32);
+			addr_of_mut!((*self.common).queue_driver_high).write_volatile((driver >> 32) as u32);
+			addr_of_mut!((*self.common).queue_device_low).write_volatile(device as u32);
+			addr_of_mut!((*self.common).queue_device_high).write_volatile((device >> 32) as u32);
+			let offset = addr_of_mut!((*self.common).queue_notify_off).read_volatile();
+			addr_of_mut!((*self.common).queue_enable).write_volatile(1);
+			offset
+		};
+
+		if self.notify_offsets.len() <= queue as usize {
+			self.notify_offsets.resize(queue as usize + 1, 0);
+		}
+		self.notify_offsets[queue as usize] = offset;
+	}
+
+	fn notify(&mut self, queue: u16) {
+		let offset = self.notify_offsets.get(queue as usize).copied().unwrap_or(0) as usize * self.multiplier as usize;
+		unsafe { (self.notify.add(offset) as *mut u16).write_volatile(queue) }
+	}
+
+	/// Reading the ISR status clears it.
+	fn ack_interrupt(&mut self) -> u32 {
+		unsafe { self.isr.read_volatile() as u32 }
+	}
+
+	fn config_generation(&self) -> u32 {
+		unsafe { addr_of_mut!((*self.common).config_generation).read_volatile() as u32 }
+	}
+
+	fn read_config(&self, offset: usize, width: usize) -> u32 {
+		if self.device.is_null() {
+			return 0;
+		}
+		let ptr = unsafe { self.device.add(offset) };
+		unsafe {
+			match width {
+				1 => ptr.read_volatile() as u32,
+				2 => (ptr as *mut u16).read_volatile() as u32,
+				_ => (ptr as *mut u32).read_volatile()
+			}
+		}
+	}
+
+	fn write_config(&mut self, offset: usize, width: usize, value: u32) {
+		if self.device.is_null() {
+			return;
+		}
+		let ptr = unsafe { self.device.add(offset) };
+		unsafe {
+			match width {
+				1 => ptr.write_volatile(value as u8),
+				2 => (ptr as *mut u16).write_volatile(value as u16),
+				_ => (ptr as *mut u32).write_volatile(value)
+			}
+		}
+	}
+}
+
+impl core::fmt::Debug for Pci {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Pci")
+			.field("common", &self.common)
+			.field("device_id", &self.device_id)
+			.field("status", &self.status())
+			.field("vectors", &self.vectors)
+			.finish()
+	}
+}
@@ -0,0 +1,440 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {
+	super::{Error, Transport},
+	crate::dma::{Dma, Region},
+	alloc::vec::Vec,
+	core::{ptr::addr_of_mut, sync::atomic::{fence, Ordering}}
+};
+
+/// The buffer continues in the next descriptor
+pub const DESC_F_NEXT:     u16 = 1 << 0;
+/// The device writes the buffer
+pub const DESC_F_WRITE:    u16 = 1 << 1;
+/// The buffer holds a table of descriptors
+pub const DESC_F_INDIRECT: u16 = 1 << 2;
+/// Packed descriptor available, matches the driver's wrap counter
+pub const DESC_F_AVAIL:    u16 = 1 << 7;
+/// Packed descriptor used, matches the device's wrap counter
+pub const DESC_F_USED:     u16 = 1 << 15;
+
+/// The driver doesn't want used buffer notifications
+pub const AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// The device doesn't want available buffer notifications
+pub const USED_F_NO_NOTIFY:     u16 = 1;
+
+/// Event suppression flags of packed queues
+pub const EVENT_FLAGS_ENABLE:  u16 = 0;
+pub const EVENT_FLAGS_DISABLE: u16 = 1;
+pub const EVENT_FLAGS_DESC:    u16 = 2;
+
+/// The most entries a queue gets, queues of block and network devices are usually 256
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+/// A descriptor of a split queue.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Descriptor {
+	pub addr:  u64,
+	pub len:   u32,
+	pub flags: u16,
+	pub next:  u16
+}
+
+/// A descriptor of a packed queue.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PackedDescriptor {
+	pub addr:  u64,
+	pub len:   u32,
+	pub id:    u16,
+	pub flags: u16
+}
+
+/// An entry of the used ring of a split queue.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct UsedElem {
+	pub id:  u32,
+	pub len: u32
+}
+
+/// A part of a request, the device reads buffers in order and then writes the writable
+/// ones.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Buffer {
+	pub phys:     u64,
+	pub len:      u32,
+	pub writable: bool
+}
+
+impl Buffer {
+	/// A buffer the device reads.
+	pub fn read(phys: u64, len: u32) -> Self {
+		Self { phys, len, writable: false }
+	}
+
+	/// A buffer the device writes.
+	pub fn write(phys: u64, len: u32) -> Self {
+		Self { phys, len, writable: true }
+	}
+}
+
+enum Layout {
+	/// Descriptor table, available ring and used ring at the given offsets. Free
+	/// descriptors are linked through `next`.
+	Split {
+		avail:     usize,
+		used:      usize,
+		free_head: u16,
+		avail_idx: u16,
+		last_used: u16
+	},
+	/// Descriptor ring, followed by the driver and device event suppression structures.
+	/// Buffer ids are handed out separately from descriptors, with the number of
+	/// descriptors each one occupies.
+	Packed {
+		ids:        Vec<u16>,
+		chains:     Vec<u16>,
+		next_avail: u16,
+		avail_wrap: bool,
+		next_used:  u16,
+		used_wrap:  bool
+	}
+}
+
+/// A queue of buffer chains the driver makes available to the device, which returns
+/// them once used. `push` returns a token that `pop` returns with the chain.
+pub struct Virtqueue {
+	index:  u16,
+	size:   u16,
+	region: Region,
+	free:   u16,
+	layout: Layout
+}
+
+impl Virtqueue {
+	/// Allocates queue `index` with at most `size` entries and enables it, the layout is
+	/// packed if `FEATURE_RING_PACKED` was negotiated.
+	pub fn new(transport: &mut impl Transport, dma: &mut impl Dma, index: u16, size: u16, packed: bool) -> Result<Self, Error> {
+		let max = transport.max_queue_size(index);
+		if max == 0 || transport.queue_enabled(index) {
+			return Err(Error::NoQueue);
+		}
+
+		let size = size.min(max).clamp(1, 1 << 15);
+		let desc = size as usize * 16;
+		let (size, layout, len) = match packed {
+			true => (size, Layout::Packed {
+				ids:        (0..size).rev().collect(),
+				chains:     alloc::vec![0; size as usize],
+				next_avail: 0,
+				avail_wrap: true,
+				next_used:  0,
+				used_wrap:  true
+			}, desc + 8),
+			false => {
+				// split queues are a power of two in size
+				let size = 1 << (15 - size.leading_zeros());
+				let desc = size as usize * 16;
+				let used = (desc + 6 + 2 * size as usize + 3) & !3;
+				(size, Layout::Split { avail: desc, used, free_head: 0, avail_idx: 0, last_used: 0 },
+					used + 6 + 8 * size as usize)
+			}
+		};
+
+		let region = Region::alloc(dma, len, 16).ok_or(Error::NoMemory)?;
+		let queue = Self { index, size, region, free: size, layout };
+
+		let (driver, device) = match queue.layout {
+			Layout::Split { avail, used, .. } => {
+				for i in 0..size {
+					unsafe { addr_of_mut!((*queue.desc(i)).next).write_volatile(i.wrapping_add(1)) };
+				}
+				(avail, used)
+			}
+			Layout::Packed { .. } => (desc, desc + 4)
+		};
+
+		transport.enable_queue(index, size, queue.region.phys, queue.region.phys + driver as u64,
+			queue.region.phys + device as u64);
+		Ok(queue)
+	}
+
+	pub fn index(&self) -> u16 {
+		self.index
+	}
+
+	pub fn size(&self) -> u16 {
+		self.size
+	}
+
+	pub fn is_packed(&self) -> bool {
+		matches!(self.layout, Layout::Packed { .. })
+	}
+
+	/// Descriptors not in use, a chain takes one per buffer.
+	pub fn free_descriptors(&self) -> u16 {
+		self.free
+	}
+
+	fn desc(&self, i: u16) -> *mut Descriptor {
+		unsafe { self.region.as_ptr::<Descriptor>().add(i as usize) }
+	}
+
+	fn packed_desc(&self, i: u16) -> *mut PackedDescriptor {
+		unsafe { self.region.as_ptr::<PackedDescriptor>().add(i as usize) }
+	}
+
+	fn field<T>(&self, offset: usize) -> *mut T {
+		unsafe { self.region.virt.add(offset) as *mut T }
+	}
+
+	/// Makes a chain of buffers available to the device, device-readable buffers have to
+	/// come first. Returns the token `pop` returns once the device used the chain.
+	pub fn push(&mut self, buffers: &[Buffer]) -> Result<u16, Error> {
+		if buffers.is_empty() || buffers.len() > self.size as usize || !ordered(buffers) {
+			return Err(Error::InvalidArgument);
+		}
+		self.place(buffers.len(), |i| (buffers[i].phys, buffers[i].len, match buffers[i].writable {
+			true  => DESC_F_WRITE,
+			false => 0
+		}))
+	}
+
+	/// Like `push`, but places the chain in `table` and makes it available as a single
+	/// indirect descriptor, which takes one entry of the queue however long the chain
+	/// is. `table` must hold 16 bytes per buffer and stay allocated until the chain is
+	/// used. Requires `FEATURE_INDIRECT_DESC`.
+	pub fn push_indirect(&mut self, table: &Region, buffers: &[Buffer]) -> Result<u16, Error> {
+		if buffers.is_empty() || table.size < buffers.len() * 16 || !ordered(buffers) {
+			return Err(Error::InvalidArgument);
+		}
+
+		for (i, b) in buffers.iter().enumerate() {
+			let flags = if b.writable { DESC_F_WRITE } else { 0 };
+			unsafe {
+				match self.layout {
+					// the device follows the chain through `next`
+					Layout::Split { .. } => table.as_ptr::<Descriptor>().add(i).write_volatile(Descriptor {
+						addr:  b.phys,
+						len:   b.len,
+						flags: flags | if i + 1 < buffers.len() { DESC_F_NEXT } else { 0 },
+						next:  i as u16 + 1
+					}),
+					// the table is read in order
+					Layout::Packed { .. } => table.as_ptr::<PackedDescriptor>().add(i).write_volatile(PackedDescriptor {
+						addr:  b.phys,
+						len:   b.len,
+						id:    0,
+						flags
+					})
+				}
+			}
+		}
+
+		self.place(1, |_| (table.phys, (buffers.len() * 16) as u32, DESC_F_INDIRECT))
+	}
+
+	/// Writes a chain of `count` descriptors given by `desc` as address, length and flags
+	/// other than `DESC_F_NEXT`, and makes it available.
+	fn place(&mut self, count: usize, desc: impl Fn(usize) -> (u64, u32, u16)) -> Result<u16, Error> {
+		if count > self.free as usize {
+			return Err(Error::NoMemory);
+		}
+
+		let flags = |i: usize, f: u16| f | if i + 1 < count { DESC_F_NEXT } else { 0 };
+
+		let token = match self.layout {
+			Layout::Split { avail, ref mut free_head, ref mut avail_idx, .. } => {
+				let head = *free_head;
+				let mut i = head;
+				for j in 0..count {
+					let (addr, len, f) = desc(j);
+					let ptr = unsafe { self.region.as_ptr::<Descriptor>().add(i as usize) };
+					unsafe {
+						let next = addr_of_mut!((*ptr).next).read_volatile();
+						addr_of_mut!((*ptr).addr).write_volatile(addr);
+						addr_of_mut!((*ptr).len).write_volatile(len);
+						addr_of_mut!((*ptr).flags).write_volatile(flags(j, f));
+						if j + 1 < count {
+							i = next;
+						} else {
+							*free_head = next;
+						}
+					}
+				}
+
+				let ring = unsafe { self.region.virt.add(avail) as *mut u16 };
+				unsafe {
+					ring.add(2 + (*avail_idx % self.size) as usize).write_volatile(head);
+					*avail_idx = avail_idx.wrapping_add(1);
+					fence(Ordering::Release);
+					ring.add(1).write_volatile(*avail_idx);
+				}
+				head
+			}
+			Layout::Packed { ref mut ids, ref mut chains, ref mut next_avail, ref mut avail_wrap, .. } => {
+				let id = ids.pop().ok_or(Error::NoMemory)?;
+				chains[id as usize] = count as u16;
+
+				let first = *next_avail;
+				let mut first_flags = 0;
+				for j in 0..count {
+					let (addr, len, f) = desc(j);
+					let ptr = unsafe { self.region.as_ptr::<PackedDescriptor>().add(*next_avail as usize) };
+					let flags = flags(j, f) | match *avail_wrap {
+						true  => DESC_F_AVAIL,
+						false => DESC_F_USED
+					};
+					unsafe {
+						addr_of_mut!((*ptr).addr).write_volatile(addr);
+						addr_of_mut!((*ptr).len).write_volatile(len);
+						addr_of_mut!((*ptr).id).write_volatile(id);
+						// the device may consume the chain as soon as the first descriptor is available
+						match j {
+							0 => first_flags = flags,
+							_ => addr_of_mut!((*ptr).flags).write_volatile(flags)
+						}
+					}
+
+					*next_avail += 1;
+					if *next_avail == self.size {
+						*next_avail = 0;
+						*avail_wrap = !*avail_wrap;
+					}
+				}
+
+				fence(Ordering::Release);
+				unsafe { addr_of_mut!((*self.packed_desc(first)).flags).write_volatile(first_flags) };
+				id
+			}
+		};
+
+		self.free -= count as u16;
+		Ok(token)
+	}
+
+	/// Notifies the device of new buffers, unless it suppressed notifications.
+	pub fn notify(&self, transport: &mut impl Transport) {
+		fence(Ordering::SeqCst);
+		let suppressed = match self.layout {
+			Layout::Split { used, .. } => unsafe { self.field::<u16>(used).read_volatile() & USED_F_NO_NOTIFY != 0 },
+			Layout::Packed { .. } => unsafe { self.field::<u16>(self.size as usize * 16 + 6).read_volatile() == EVENT_FLAGS_DISABLE }
+		};
+
+		if !suppressed {
+			transport.notify(self.index);
+		}
+	}
+
+	/// Takes the next chain the device used, returns its token and the number of bytes
+	/// the device wrote.
+	pub fn pop(&mut self) -> Option<(u16, u32)> {
+		let (token, len, count) = match self.layout {
+			Layout::Split { used, ref mut free_head, ref mut last_used, .. } => {
+				let ring = unsafe { self.region.virt.add(used) as *mut u16 };
+				if unsafe { ring.add(1).read_volatile() } == *last_used {
+					return None;
+				}
+				fence(Ordering::Acquire);
+
+				let elem = unsafe { (ring.add(2) as *mut UsedElem).add((*last_used % self.size) as usize).read_volatile() };
+				*last_used = last_used.wrapping_add(1);
+
+				let head = elem.id as u16;
+				let (mut i, mut count) = (head, 1);
+				loop {
+					let desc = unsafe { self.region.as_ptr::<Descriptor>().add(i as usize) };
+					if unsafe { addr_of_mut!((*desc).flags).read_volatile() } & DESC_F_NEXT == 0 {
+						unsafe { addr_of_mut!((*desc).next).write_volatile(*free_head) };
+						break;
+					}
+					i = unsafe { addr_of_mut!((*desc).next).read_volatile() };
+					count += 1;
+				}
+				*free_head = head;
+				(head, elem.len, count)
+			}
+			Layout::Packed { ref mut ids, ref chains, ref mut next_used, ref mut used_wrap, .. } => {
+				let desc = unsafe { self.region.as_ptr::<PackedDescriptor>().add(*next_used as usize) };
+				let flags = unsafe { addr_of_mut!((*desc).flags).read_volatile() };
+				if (flags & DESC_F_AVAIL != 0) != *used_wrap || (flags & DESC_F_USED != 0) != *used_wrap {
+					return None;
+				}
+				fence(Ordering::Acquire);
+
+				let id = unsafe { addr_of_mut!((*desc).id).read_volatile() };
+				let len = unsafe { addr_of_mut!((*desc).len).read_volatile() };
+				let count = chains[id as usize];
+				*next_used += count;
+				if *next_used >= self.size {
+					*next_used -= self.size;
+					*used_wrap = !*used_wrap;
+				}
+				ids.push(id);
+				(id, len, count)
+			}
+		};
+
+		self.free += count;
+		Some((token, len))
+	}
+
+	/// Enables or disables used buffer notifications, the device may still send some
+	/// after they are disabled.
+	pub fn set_interrupts(&mut self, enabled: bool) {
+		match self.layout {
+			Layout::Split { avail, .. } => unsafe { self.field::<u16>(avail).write_volatile(match enabled {
+				true  => 0,
+				false => AVAIL_F_NO_INTERRUPT
+			}) },
+			Layout::Packed { .. } => unsafe { self.field::<u16>(self.size as usize * 16 + 2).write_volatile(match enabled {
+				true  => EVENT_FLAGS_ENABLE,
+				false => EVENT_FLAGS_DISABLE
+			}) }
+		}
+		fence(Ordering::SeqCst);
+	}
+
+	/// Frees the rings, the device must not use the queue anymore, see `super::reset`.
+	pub fn free(&self, dma: &mut impl Dma) {
+		dma.free(self.region.virt, self.region.size)
+	}
+}
+
+/// Whether no device-readable buffer follows a device-writable one.
+fn ordered(buffers: &[Buffer]) -> bool {
+	!buffers.windows(2).any(|w| w[0].writable && !w[1].writable)
+}
+
+impl core::fmt::Debug for Virtqueue {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Virtqueue")
+			.field("index", &self.index)
+			.field("size", &self.size)
+			.field("packed", &self.is_packed())
+			.field("free", &self.free)
+			.finish()
+	}
+}
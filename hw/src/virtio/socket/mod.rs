@@ -0,0 +1,360 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Socket devices, connections between the guest and the host without a network.
+//!
+//! Packets are a `Header` followed by the payload, the device addresses the guest by the
+//! context ID in its configuration and the host by `HOST_CID`. The receive queue is kept
+//! filled with buffers of `BUFFER_SIZE`, a packet and its header always fit into one.
+//! The event queue tells of transport resets, after which the context ID may have
+//! changed and all connections are gone. `Streams` implements stream sockets on top of
+//! the packets of a `Device`.
+
+mod stream;
+
+pub use stream::*;
+
+use {
+	super::{Buffer, Error, Transport, Virtqueue, DeviceType, FEATURE_RING_PACKED},
+	crate::dma::{Dma, Region, PAGE_SIZE},
+	alloc::vec::Vec
+};
+
+pub const PCI_DEVICE_ID: u16 = 0x1053;
+
+/// Stream sockets, implied if the device offers no socket type
+pub const FEATURE_STREAM:    u64 = 1 << 0;
+pub const FEATURE_SEQPACKET: u64 = 1 << 1;
+
+/// Offsets of the fields of the device configuration
+pub const CONFIG_GUEST_CID: usize = 0;
+
+/// Reserved context IDs, the host is always `HOST_CID`
+pub const HYPERVISOR_CID: u64 = 0;
+pub const LOCAL_CID:      u64 = 1;
+pub const HOST_CID:       u64 = 2;
+
+pub const TYPE_STREAM:    u16 = 1;
+pub const TYPE_SEQPACKET: u16 = 2;
+
+pub const OP_INVALID:        u16 = 0;
+/// Connection request, the response or a reset
+pub const OP_REQUEST:        u16 = 1;
+pub const OP_RESPONSE:       u16 = 2;
+pub const OP_RST:            u16 = 3;
+/// The sender won't receive or send anymore, `SHUTDOWN_*` in `flags`
+pub const OP_SHUTDOWN:       u16 = 4;
+/// Data
+pub const OP_RW:             u16 = 5;
+/// Tells the peer the free space of the receive buffer, or asks for it
+pub const OP_CREDIT_UPDATE:  u16 = 6;
+pub const OP_CREDIT_REQUEST: u16 = 7;
+
+pub const SHUTDOWN_RCV:  u32 = 1 << 0;
+pub const SHUTDOWN_SEND: u32 = 1 << 1;
+
+pub const EVENT_TRANSPORT_RESET: u32 = 0;
+
+pub const HEADER_LEN:  usize = 44;
+/// Size of the buffers, a packet and its header fit into one
+pub const BUFFER_SIZE: usize = PAGE_SIZE;
+pub const MAX_PAYLOAD: usize = BUFFER_SIZE - HEADER_LEN;
+/// Entries of the receive and the transmit queue
+pub const QUEUE_SIZE:  u16 = 128;
+/// Buffers in the event queue
+pub const EVENTS:      u16 = 8;
+
+const SUPPORTED: u64 = FEATURE_STREAM | FEATURE_RING_PACKED;
+const EVENT_LEN: usize = 4;
+
+/// The header preceding each packet, little-endian on the wire.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Header {
+	pub src_cid:   u64,
+	pub dst_cid:   u64,
+	pub src_port:  u32,
+	pub dst_port:  u32,
+	/// Length of the payload
+	pub len:       u32,
+	pub ty:        u16,
+	pub op:        u16,
+	pub flags:     u32,
+	/// Size of the sender's receive buffer
+	pub buf_alloc: u32,
+	/// Bytes the sender took from its receive buffer
+	pub fwd_cnt:   u32
+}
+
+impl Header {
+	pub fn parse(bytes: &[u8]) -> Option<Self> {
+		if bytes.len() < HEADER_LEN {
+			return None;
+		}
+		let u16 = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+		let u32 = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+		let u64 = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+		Some(Self {
+			src_cid:   u64(0),
+			dst_cid:   u64(8),
+			src_port:  u32(16),
+			dst_port:  u32(20),
+			len:       u32(24),
+			ty:        u16(28),
+			op:        u16(30),
+			flags:     u32(32),
+			buf_alloc: u32(36),
+			fwd_cnt:   u32(40)
+		})
+	}
+
+	pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+		let mut bytes = [0; HEADER_LEN];
+		bytes[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+		bytes[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+		bytes[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+		bytes[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+		bytes[24..28].copy_from_slice(&self.len.to_le_bytes());
+		bytes[28..30].copy_from_slice(&self.ty.to_le_bytes());
+		bytes[30..32].copy_from_slice(&self.op.to_le_bytes());
+		bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+		bytes[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+		bytes[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+		bytes
+	}
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Packet {
+	pub header: Header,
+	pub data:   Vec<u8>
+}
+
+/// What `Streams` needs of a socket device.
+pub trait Device {
+	fn guest_cid(&self) -> u64;
+
+	/// Sends a packet, fails with `Error::NoMemory` while the device is busy.
+	fn transmit(&mut self, header: &Header, data: &[u8]) -> Result<(), Error>;
+
+	fn receive(&mut self) -> Option<Packet>;
+
+	/// Whether the transport was reset since the last call.
+	fn reset(&mut self) -> bool;
+}
+
+/// A queue and the buffers it uses.
+struct Queue {
+	vq:      Virtqueue,
+	buffers: Region,
+	/// The buffer each token refers to
+	slots:   Vec<u16>,
+	/// Buffers not in the queue, only used for the transmit queue
+	free:    Vec<u16>
+}
+
+impl Queue {
+	fn new(transport: &mut impl Transport, dma: &mut impl Dma, index: u16, size: u16, len: usize, packed: bool) -> Result<Self, Error> {
+		let mut vq = Virtqueue::new(transport, dma, index, size, packed)?;
+		vq.set_interrupts(false);
+		let size = vq.size();
+		match Region::alloc(dma, size as usize * len, PAGE_SIZE) {
+			Some(buffers) => Ok(Self { vq, buffers, slots: alloc::vec![0; size as usize], free: (0..size).collect() }),
+			None => {
+				vq.free(dma);
+				Err(Error::NoMemory)
+			}
+		}
+	}
+
+	/// Makes a buffer of `len` available to the device to write to.
+	fn post(&mut self, slot: u16, len: usize) -> Result<(), Error> {
+		let phys = self.buffers.phys + (slot as usize * len) as u64;
+		let token = self.vq.push(&[Buffer::write(phys, len as u32)])?;
+		self.slots[token as usize] = slot;
+		Ok(())
+	}
+
+	fn free(self, dma: &mut impl Dma) {
+		self.vq.free(dma);
+		self.buffers.free(dma);
+	}
+}
+
+pub struct Vsock<T: Transport, D: Dma> {
+	transport: T,
+	dma:       D,
+	rx:        Option<Queue>,
+	tx:        Option<Queue>,
+	events:    Option<Queue>,
+	guest_cid: u64,
+	/// A transport reset event arrived since the last `reset`
+	reset:     bool,
+	features:  u64
+}
+
+impl<T: Transport, D: Dma> Vsock<T, D> {
+	pub fn new(mut transport: T, mut dma: D) -> Result<Self, Error> {
+		if transport.device_id() != DeviceType::Socket as u32 {
+			return Err(Error::NoDevice);
+		}
+
+		let features = super::init(&mut transport, &mut dma, SUPPORTED)?;
+		let packed = features & FEATURE_RING_PACKED != 0;
+		let mut dev = Self { transport, dma, rx: None, tx: None, events: None, guest_cid: 0, reset: false, features };
+		dev.rx = Some(Queue::new(&mut dev.transport, &mut dev.dma, 0, QUEUE_SIZE, BUFFER_SIZE, packed)?);
+		dev.tx = Some(Queue::new(&mut dev.transport, &mut dev.dma, 1, QUEUE_SIZE, BUFFER_SIZE, packed)?);
+		dev.events = Some(Queue::new(&mut dev.transport, &mut dev.dma, 2, EVENTS, EVENT_LEN, packed)?);
+
+		for (q, len) in [(dev.rx.as_mut().unwrap(), BUFFER_SIZE), (dev.events.as_mut().unwrap(), EVENT_LEN)] {
+			for slot in 0..q.vq.size() {
+				q.post(slot, len)?;
+			}
+			q.free.clear();
+		}
+		dev.guest_cid = dev.transport.read_config_u64(CONFIG_GUEST_CID);
+		dev.transport.driver_ok();
+		dev.rx.as_ref().unwrap().vq.notify(&mut dev.transport);
+		dev.events.as_ref().unwrap().vq.notify(&mut dev.transport);
+		Ok(dev)
+	}
+
+	pub fn features(&self) -> u64 {
+		self.features
+	}
+
+	/// The context ID of the guest, read again after a transport reset.
+	pub fn guest_cid(&self) -> u64 {
+		self.guest_cid
+	}
+
+	/// Reads and acknowledges the pending interrupts, `super::INTERRUPT_*`.
+	pub fn interrupt(&mut self) -> u32 {
+		self.transport.ack_interrupt()
+	}
+
+	/// Sends a packet, the payload must fit into a buffer with the header.
+	pub fn transmit(&mut self, header: &Header, data: &[u8]) -> Result<(), Error> {
+		if data.len() > MAX_PAYLOAD || header.len as usize != data.len() {
+			return Err(Error::InvalidArgument);
+		}
+		let q = self.tx.as_mut().unwrap();
+		while let Some((token, _)) = q.vq.pop() {
+			q.free.push(q.slots[token as usize]);
+		}
+		let slot = q.free.pop().ok_or(Error::NoMemory)?;
+
+		let offset = slot as usize * BUFFER_SIZE;
+		unsafe {
+			let buf = q.buffers.virt.add(offset);
+			core::ptr::copy_nonoverlapping(header.to_bytes().as_ptr(), buf, HEADER_LEN);
+			core::ptr::copy_nonoverlapping(data.as_ptr(), buf.add(HEADER_LEN), data.len());
+		}
+		match q.vq.push(&[Buffer::read(q.buffers.phys + offset as u64, (HEADER_LEN + data.len()) as u32)]) {
+			Ok(token) => q.slots[token as usize] = slot,
+			Err(e) => {
+				q.free.push(slot);
+				return Err(e);
+			}
+		}
+		q.vq.notify(&mut self.transport);
+		Ok(())
+	}
+
+	/// The next received packet, the buffer is handed back to the device. Packets too
+	/// short for their header are dropped.
+	pub fn receive(&mut self) -> Option<Packet> {
+		let q = self.rx.as_mut().unwrap();
+		loop {
+			let (token, len) = q.vq.pop()?;
+			let slot = q.slots[token as usize];
+			let bytes = unsafe {
+				core::slice::from_raw_parts(q.buffers.virt.add(slot as usize * BUFFER_SIZE), (len as usize).min(BUFFER_SIZE))
+			};
+			let packet = Header::parse(bytes)
+				.filter(|h| HEADER_LEN + h.len as usize <= bytes.len())
+				.map(|header| Packet { header, data: bytes[HEADER_LEN..HEADER_LEN + header.len as usize].to_vec() });
+			let _ = q.post(slot, BUFFER_SIZE);
+			q.vq.notify(&mut self.transport);
+			if packet.is_some() {
+				return packet;
+			}
+		}
+	}
+
+	/// Handles the events the device sent, returns whether the transport was reset.
+	pub fn poll_events(&mut self) -> bool {
+		let q = self.events.as_mut().unwrap();
+		let (mut events, mut reset) = (0, false);
+		while let Some((token, _)) = q.vq.pop() {
+			let slot = q.slots[token as usize];
+			let id = unsafe { (q.buffers.virt.add(slot as usize * EVENT_LEN) as *const u32).read_volatile() };
+			reset |= u32::from_le(id) == EVENT_TRANSPORT_RESET;
+			let _ = q.post(slot, EVENT_LEN);
+			events += 1;
+		}
+		if events > 0 {
+			q.vq.notify(&mut self.transport);
+		}
+		if reset {
+			self.guest_cid = self.transport.read_config_u64(CONFIG_GUEST_CID);
+			self.reset = true;
+		}
+		reset
+	}
+}
+
+impl<T: Transport, D: Dma> Device for Vsock<T, D> {
+	fn guest_cid(&self) -> u64 {
+		self.guest_cid
+	}
+
+	fn transmit(&mut self, header: &Header, data: &[u8]) -> Result<(), Error> {
+		Vsock::transmit(self, header, data)
+	}
+
+	fn receive(&mut self) -> Option<Packet> {
+		Vsock::receive(self)
+	}
+
+	fn reset(&mut self) -> bool {
+		self.poll_events();
+		core::mem::replace(&mut self.reset, false)
+	}
+}
+
+impl<T: Transport, D: Dma> Drop for Vsock<T, D> {
+	fn drop(&mut self) {
+		let _ = super::reset(&mut self.transport, &mut self.dma);
+		for q in [self.rx.take(), self.tx.take(), self.events.take()].into_iter().flatten() {
+			q.free(&mut self.dma);
+		}
+	}
+}
+
+impl<T: Transport, D: Dma> core::fmt::Debug for Vsock<T, D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Vsock")
+			.field("guest_cid", &self.guest_cid)
+			.field("features", &format_args!("{:#x}", self.features))
+			.finish()
+	}
+}
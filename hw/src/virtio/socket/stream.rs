@@ -0,0 +1,517 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Stream sockets over the packets of a socket device.
+//!
+//! A connection is identified by its local port and the peer's context ID and port, a
+//! listening socket by its port alone. Flow control is credit based: every packet tells
+//! the peer the size of the sender's receive buffer and how much of it was consumed,
+//! data is only sent while the peer has room for it. Closing a connection shuts it down
+//! in both directions and waits for the peer's reset, `CLOSE_TIMEOUT_MS` at most. The
+//! time is passed in by the caller, in milliseconds.
+
+use {
+	super::*,
+	crate::net::{Error, Result},
+	alloc::collections::VecDeque
+};
+
+/// A socket of `Streams`.
+pub type Handle = usize;
+
+/// Size of the receive and the send buffer of a connection
+pub const BUF_ALLOC:          u32 = 256 * 1024;
+pub const CONNECT_TIMEOUT_MS: u64 = 2000;
+pub const CLOSE_TIMEOUT_MS:   u64 = 8000;
+/// Consumed bytes after which the peer is told of the free space, without waiting for
+/// data to send
+pub const CREDIT_UPDATE_THRESHOLD: u32 = BUF_ALLOC / 4;
+
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u32> = 49152..=65535;
+/// `VMADDR_PORT_ANY`
+const PORT_ANY: u32 = u32::MAX;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+	Listen,
+	/// The request was sent, the response is outstanding
+	Connecting,
+	Connected,
+	/// Shut down in both directions, waiting for the peer's reset
+	Closing,
+	Closed
+}
+
+struct Socket {
+	state:            State,
+	port:             u32,
+	/// The context ID and port of the peer
+	peer:             (u64, u32),
+	/// The listener of a connection that wasn't accepted yet
+	parent:           Option<Handle>,
+	backlog:          usize,
+	rx:               VecDeque<u8>,
+	tx:               VecDeque<u8>,
+	/// Bytes taken from `rx`, and the count the peer was told last
+	fwd_cnt:          u32,
+	told_cnt:         u32,
+	/// Bytes sent
+	tx_cnt:           u32,
+	/// The peer's receive buffer and how much of it the peer consumed
+	peer_alloc:       u32,
+	peer_fwd:         u32,
+	/// `SHUTDOWN_*` of the peer, ours and those of ours the peer was told
+	peer_shutdown:    u32,
+	shutdown:         u32,
+	shutdown_sent:    u32,
+	credit_requested: bool,
+	/// When connecting or closing times out
+	timer:            u64,
+	error:            Option<Error>,
+	/// The handle was closed, the connection is still shutting down
+	released:         bool
+}
+
+impl Socket {
+	fn new(state: State, port: u32, peer: (u64, u32)) -> Self {
+		Self {
+			state, port, peer, parent: None, backlog: 0, rx: VecDeque::new(), tx: VecDeque::new(),
+			fwd_cnt: 0, told_cnt: 0, tx_cnt: 0, peer_alloc: 0, peer_fwd: 0, peer_shutdown: 0,
+			shutdown: 0, shutdown_sent: 0, credit_requested: false, timer: 0, error: None, released: false
+		}
+	}
+
+	/// Bytes the peer has room for.
+	fn credit(&self) -> usize {
+		self.peer_alloc.saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd)) as usize
+	}
+
+	fn close_with(&mut self, error: Error) {
+		self.state = State::Closed;
+		self.error = Some(error);
+		self.tx.clear();
+	}
+
+	fn send(&mut self, data: &[u8]) -> Result<usize> {
+		match self.state {
+			State::Listen => return Err(Error::InvalidArgument),
+			State::Connecting => return Err(Error::WouldBlock),
+			State::Closed => return Err(self.error.unwrap_or(Error::NotConnected)),
+			_ if self.shutdown & SHUTDOWN_SEND != 0 || self.peer_shutdown & SHUTDOWN_RCV != 0 => return Err(Error::NotConnected),
+			_ => ()
+		}
+		let n = data.len().min(BUF_ALLOC as usize - self.tx.len());
+		if n == 0 && !data.is_empty() {
+			return Err(Error::WouldBlock);
+		}
+		self.tx.extend(&data[..n]);
+		Ok(n)
+	}
+
+	fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+		if !self.rx.is_empty() {
+			let n = buf.len().min(self.rx.len());
+			for (dst, src) in buf.iter_mut().zip(self.rx.drain(..n)) {
+				*dst = src;
+			}
+			self.fwd_cnt = self.fwd_cnt.wrapping_add(n as u32);
+			return Ok(n);
+		}
+		match (self.state, self.error) {
+			(State::Listen, _) => Err(Error::InvalidArgument),
+			(State::Connecting, _) => Err(Error::WouldBlock),
+			(_, Some(e)) => Err(e),
+			(State::Closed, _) => Ok(0),
+			_ if self.peer_shutdown & SHUTDOWN_SEND != 0 => Ok(0),
+			_ => Err(Error::WouldBlock)
+		}
+	}
+
+	fn can_recv(&self) -> bool {
+		!self.rx.is_empty() || self.peer_shutdown & SHUTDOWN_SEND != 0 || self.state == State::Closed
+	}
+}
+
+/// Sends a packet of a connection, it carries the credit of the connection.
+fn transmit(device: &mut impl Device, s: &mut Socket, op: u16, flags: u32, data: &[u8]) -> Result<()> {
+	let header = Header {
+		src_cid:   device.guest_cid(),
+		dst_cid:   s.peer.0,
+		src_port:  s.port,
+		dst_port:  s.peer.1,
+		len:       data.len() as u32,
+		ty:        TYPE_STREAM,
+		op,
+		flags,
+		buf_alloc: BUF_ALLOC,
+		fwd_cnt:   s.fwd_cnt
+	};
+	device.transmit(&header, data)?;
+	s.told_cnt = s.fwd_cnt;
+	Ok(())
+}
+
+/// The stream sockets of a device.
+pub struct Streams<D: Device> {
+	device:    D,
+	sockets:   Vec<Option<Socket>>,
+	next_port: u32,
+	now:       u64
+}
+
+impl<D: Device> Streams<D> {
+	/// `now` picks the first ephemeral port.
+	pub fn new(device: D, now: u64) -> Self {
+		let next_port = EPHEMERAL_PORTS.start() + (now % (EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start() + 1) as u64) as u32;
+		Self { device, sockets: Vec::new(), next_port, now }
+	}
+
+	pub fn device(&self) -> &D {
+		&self.device
+	}
+
+	pub fn device_mut(&mut self) -> &mut D {
+		&mut self.device
+	}
+
+	pub fn guest_cid(&self) -> u64 {
+		self.device.guest_cid()
+	}
+
+	/// Processes received packets, sends data the peers have room for and times out
+	/// connections. Returns whether a packet arrived or a connection timed out.
+	pub fn poll(&mut self, now: u64) -> bool {
+		self.now = now;
+		let mut active = false;
+		if self.device.reset() {
+			active = true;
+			for slot in &mut self.sockets {
+				match slot {
+					Some(s) if s.state == State::Listen => (),
+					Some(s) if s.released => *slot = None,
+					Some(s) => s.close_with(Error::ConnectionReset),
+					None => ()
+				}
+			}
+		}
+
+		while let Some(packet) = self.device.receive() {
+			active = true;
+			self.process(packet);
+		}
+
+		for i in 0..self.sockets.len() {
+			let Some(s) = &mut self.sockets[i] else { continue };
+			match s.state {
+				State::Connecting | State::Closing if now >= s.timer => {
+					active = true;
+					let _ = transmit(&mut self.device, s, OP_RST, 0, &[]);
+					match s.state {
+						State::Connecting => s.close_with(Error::Timeout),
+						_ => self.sockets[i] = None
+					}
+				}
+				_ => self.dispatch(i)
+			}
+		}
+		active
+	}
+
+	fn process(&mut self, packet: Packet) {
+		let h = packet.header;
+		if h.ty != TYPE_STREAM || h.dst_cid != self.device.guest_cid() {
+			return self.reset(&h);
+		}
+
+		let found = self.sockets.iter().position(|s| matches!(s,
+			Some(s) if s.state != State::Listen && s.port == h.dst_port && s.peer == (h.src_cid, h.src_port)));
+		let Some(i) = found else {
+			if h.op == OP_REQUEST {
+				if let Some(listener) = self.listener(h.dst_port) {
+					let pending = self.sockets.iter().filter(|s| matches!(s, Some(s) if s.parent == Some(listener))).count();
+					if pending < self.sockets[listener].as_ref().unwrap().backlog {
+						let mut s = Socket::new(State::Connected, h.dst_port, (h.src_cid, h.src_port));
+						(s.parent, s.peer_alloc, s.peer_fwd) = (Some(listener), h.buf_alloc, h.fwd_cnt);
+						if transmit(&mut self.device, &mut s, OP_RESPONSE, 0, &[]).is_ok() {
+							self.insert(s);
+						}
+						return;
+					}
+				}
+			}
+			return self.reset(&h);
+		};
+
+		let s = self.sockets[i].as_mut().unwrap();
+		// asked again only once the credit changed
+		if (s.peer_alloc, s.peer_fwd) != (h.buf_alloc, h.fwd_cnt) {
+			(s.peer_alloc, s.peer_fwd, s.credit_requested) = (h.buf_alloc, h.fwd_cnt, false);
+		}
+		match (s.state, h.op) {
+			(State::Closing, OP_RST) => self.sockets[i] = None,
+			(_, OP_RST) if s.released => self.sockets[i] = None,
+			(State::Closed, OP_RST) => (),
+			(State::Connecting, OP_RST) => s.close_with(Error::ConnectionRefused),
+			(_, OP_RST) if s.peer_shutdown == SHUTDOWN_RCV | SHUTDOWN_SEND => s.state = State::Closed,
+			(_, OP_RST) => s.close_with(Error::ConnectionReset),
+			(State::Closed, _) => {
+				let _ = transmit(&mut self.device, s, OP_RST, 0, &[]);
+			}
+			(State::Connecting, OP_RESPONSE) => s.state = State::Connected,
+			(State::Connecting, _) => {
+				let _ = transmit(&mut self.device, s, OP_RST, 0, &[]);
+				s.close_with(Error::ConnectionReset);
+			}
+			(State::Connected | State::Closing, OP_RW) => match s.released || s.shutdown & SHUTDOWN_RCV != 0 {
+				// dropped, but the peer gets the credit back
+				true => s.fwd_cnt = s.fwd_cnt.wrapping_add(packet.data.len() as u32),
+				false if s.rx.len() + packet.data.len() > BUF_ALLOC as usize => {
+					let _ = transmit(&mut self.device, s, OP_RST, 0, &[]);
+					s.close_with(Error::ConnectionReset);
+				}
+				false => s.rx.extend(&packet.data)
+			},
+			(_, OP_CREDIT_REQUEST) => {
+				let _ = transmit(&mut self.device, s, OP_CREDIT_UPDATE, 0, &[]);
+			}
+			(_, OP_SHUTDOWN) => {
+				s.peer_shutdown |= h.flags & (SHUTDOWN_RCV | SHUTDOWN_SEND);
+				if s.peer_shutdown == SHUTDOWN_RCV | SHUTDOWN_SEND {
+					let _ = transmit(&mut self.device, s, OP_RST, 0, &[]);
+					match s.released {
+						true  => self.sockets[i] = None,
+						false => s.state = State::Closed
+					}
+				}
+			}
+			_ => ()
+		}
+		self.dispatch(i);
+	}
+
+	/// Answers a packet that belongs to no connection with a reset.
+	fn reset(&mut self, h: &Header) {
+		if h.op == OP_RST {
+			return;
+		}
+		let _ = self.device.transmit(&Header {
+			src_cid:  self.device.guest_cid(),
+			dst_cid:  h.src_cid,
+			src_port: h.dst_port,
+			dst_port: h.src_port,
+			ty:       TYPE_STREAM,
+			op:       OP_RST,
+			..Header::default()
+		}, &[]);
+	}
+
+	/// Sends the queued data the peer has room for, then a pending shutdown, and tells
+	/// the peer of the space that was freed.
+	fn dispatch(&mut self, i: Handle) {
+		let Some(s) = self.sockets.get_mut(i).and_then(Option::as_mut) else { return };
+		if s.state != State::Connected {
+			return;
+		}
+		if s.peer_shutdown & SHUTDOWN_RCV != 0 {
+			s.tx.clear();
+		}
+		while !s.tx.is_empty() {
+			let n = s.tx.len().min(s.credit()).min(MAX_PAYLOAD);
+			if n == 0 {
+				if !s.credit_requested {
+					s.credit_requested = transmit(&mut self.device, s, OP_CREDIT_REQUEST, 0, &[]).is_ok();
+				}
+				break;
+			}
+			let data = s.tx.iter().take(n).copied().collect::<Vec<_>>();
+			if transmit(&mut self.device, s, OP_RW, 0, &data).is_err() {
+				break;
+			}
+			s.tx.drain(..n);
+			s.tx_cnt = s.tx_cnt.wrapping_add(n as u32);
+		}
+
+		if s.tx.is_empty() && s.shutdown & !s.shutdown_sent != 0 && transmit(&mut self.device, s, OP_SHUTDOWN, s.shutdown, &[]).is_ok() {
+			s.shutdown_sent = s.shutdown;
+			if s.shutdown == SHUTDOWN_RCV | SHUTDOWN_SEND {
+				s.state = State::Closing;
+				s.timer = self.now + CLOSE_TIMEOUT_MS;
+			}
+		}
+		if s.state == State::Connected && s.fwd_cnt.wrapping_sub(s.told_cnt) >= CREDIT_UPDATE_THRESHOLD {
+			let _ = transmit(&mut self.device, s, OP_CREDIT_UPDATE, 0, &[]);
+		}
+	}
+
+	fn insert(&mut self, socket: Socket) -> Handle {
+		match self.sockets.iter().position(Option::is_none) {
+			Some(i) => {
+				self.sockets[i] = Some(socket);
+				i
+			}
+			None => {
+				self.sockets.push(Some(socket));
+				self.sockets.len() - 1
+			}
+		}
+	}
+
+	fn listener(&self, port: u32) -> Option<Handle> {
+		self.sockets.iter().position(|s| matches!(s, Some(s) if s.state == State::Listen && s.port == port))
+	}
+
+	fn ephemeral_port(&mut self) -> Result<u32> {
+		for _ in EPHEMERAL_PORTS {
+			let port = self.next_port;
+			self.next_port = match port == *EPHEMERAL_PORTS.end() {
+				true  => *EPHEMERAL_PORTS.start(),
+				false => port + 1
+			};
+			if !self.sockets.iter().any(|s| matches!(s, Some(s) if s.port == port)) {
+				return Ok(port);
+			}
+		}
+		Err(Error::AddressInUse)
+	}
+
+	fn socket(&mut self, handle: Handle) -> Result<&mut Socket> {
+		match self.sockets.get_mut(handle) {
+			Some(Some(s)) if !s.released => Ok(s),
+			_ => Err(Error::InvalidArgument)
+		}
+	}
+
+	/// Opens a connection to a port of `cid`, the request is sent immediately. The socket
+	/// is connected once its state is `Connected`.
+	pub fn connect(&mut self, cid: u64, port: u32) -> Result<Handle> {
+		if cid == self.device.guest_cid() || cid == LOCAL_CID || port == PORT_ANY {
+			return Err(Error::InvalidArgument);
+		}
+		let mut s = Socket::new(State::Connecting, self.ephemeral_port()?, (cid, port));
+		s.timer = self.now + CONNECT_TIMEOUT_MS;
+		transmit(&mut self.device, &mut s, OP_REQUEST, 0, &[])?;
+		Ok(self.insert(s))
+	}
+
+	/// Accepts connections to `port`, at most `backlog` of them wait to be accepted.
+	pub fn listen(&mut self, port: u32, backlog: usize) -> Result<Handle> {
+		if port == PORT_ANY {
+			return Err(Error::InvalidArgument);
+		} else if self.listener(port).is_some() {
+			return Err(Error::AddressInUse);
+		}
+		let mut s = Socket::new(State::Listen, port, (0, 0));
+		s.backlog = backlog;
+		Ok(self.insert(s))
+	}
+
+	/// Takes a connection from the backlog of a listening socket.
+	pub fn accept(&mut self, handle: Handle) -> Result<Handle> {
+		if self.socket(handle)?.state != State::Listen {
+			return Err(Error::InvalidArgument);
+		}
+		let connection = self.sockets.iter_mut().position(|s| match s {
+			Some(s) if s.parent == Some(handle) => {
+				s.parent = None;
+				true
+			}
+			_ => false
+		});
+		connection.ok_or(Error::WouldBlock)
+	}
+
+	/// Queues data and sends what the peer has room for, returns how much was queued.
+	pub fn send(&mut self, handle: Handle, data: &[u8]) -> Result<usize> {
+		let n = self.socket(handle)?.send(data)?;
+		self.dispatch(handle);
+		Ok(n)
+	}
+
+	/// Reads received data, zero once the peer won't send anymore.
+	pub fn recv(&mut self, handle: Handle, buf: &mut [u8]) -> Result<usize> {
+		let n = self.socket(handle)?.recv(buf)?;
+		self.dispatch(handle);
+		Ok(n)
+	}
+
+	/// Closes our side of a connection once the queued data was sent, data can still be
+	/// received.
+	pub fn shutdown(&mut self, handle: Handle) -> Result<()> {
+		match self.socket(handle)? {
+			s if s.state == State::Listen => return Err(Error::InvalidArgument),
+			s => s.shutdown |= SHUTDOWN_SEND
+		}
+		self.dispatch(handle);
+		Ok(())
+	}
+
+	/// Closes a socket, connections are shut down in the background and connections
+	/// that weren't accepted yet are reset.
+	pub fn close(&mut self, handle: Handle) -> Result<()> {
+		let state = self.socket(handle)?.state;
+		let s = self.sockets[handle].as_mut().unwrap();
+		match state {
+			State::Listen => {
+				self.sockets[handle] = None;
+				for i in 0..self.sockets.len() {
+					if let Some(s) = self.sockets[i].as_mut().filter(|s| s.parent == Some(handle)) {
+						let _ = transmit(&mut self.device, s, OP_RST, 0, &[]);
+						self.sockets[i] = None;
+					}
+				}
+			}
+			State::Connecting => {
+				let _ = transmit(&mut self.device, s, OP_RST, 0, &[]);
+				self.sockets[handle] = None;
+			}
+			State::Closed => self.sockets[handle] = None,
+			State::Connected | State::Closing => {
+				s.released = true;
+				s.shutdown = SHUTDOWN_RCV | SHUTDOWN_SEND;
+				s.fwd_cnt = s.fwd_cnt.wrapping_add(s.rx.len() as u32);
+				s.rx.clear();
+				self.dispatch(handle);
+			}
+		}
+		Ok(())
+	}
+
+	pub fn state(&mut self, handle: Handle) -> Result<State> {
+		Ok(self.socket(handle)?.state)
+	}
+
+	/// Why a connection was closed, if it didn't close normally.
+	pub fn error(&mut self, handle: Handle) -> Result<Option<Error>> {
+		Ok(self.socket(handle)?.error)
+	}
+
+	/// Whether `recv` returns data or the end of the stream.
+	pub fn can_recv(&mut self, handle: Handle) -> Result<bool> {
+		Ok(self.socket(handle)?.can_recv())
+	}
+
+	/// The local port and, if connected, the context ID and port of the peer.
+	pub fn endpoints(&mut self, handle: Handle) -> Result<(u32, Option<(u64, u32)>)> {
+		let s = self.socket(handle)?;
+		Ok((s.port, (s.state != State::Listen).then_some(s.peer)))
+	}
+}
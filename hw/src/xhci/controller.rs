@@ -0,0 +1,921 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {super::*, crate::dma::{Dma, Region, PAGE_SIZE}, alloc::vec::Vec, core::ptr::addr_of_mut};
+
+/// How long the controller gets to halt, reset or start
+pub const RESET_TIMEOUT_US: u64 = 100_000;
+/// How long a port gets to finish a reset
+pub const PORT_RESET_TIMEOUT_US: u64 = 100_000;
+/// How long a command may take
+pub const COMMAND_TIMEOUT_US: u64 = 500_000;
+/// How long a control or bulk transfer may take
+pub const TRANSFER_TIMEOUT_US: u64 = 5_000_000;
+/// Largest transfer on a bulk endpoint, one bounce buffer
+pub const MAX_TRANSFER: usize = 0x10000;
+
+/// Completion code of stopped endpoints
+const COMPLETION_STOPPED: u8 = 26;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+	/// The controller, a command or a transfer didn't complete in time
+	Timeout,
+	/// No DMA memory
+	NoMemory,
+	/// No device is connected to the port or the slot isn't in use
+	NoDevice,
+	/// The controller has no device slot left
+	NoSlots,
+	/// No such endpoint or the transfer doesn't fit
+	InvalidArgument,
+	/// The endpoint stalled, the request isn't supported or the endpoint halted
+	Stall,
+	/// A command or transfer completed with this completion code
+	Completion(u8),
+	/// The device returned malformed descriptors or status
+	Protocol,
+	/// A mass storage command failed with this sense data
+	Sense { key: u8, asc: u8, ascq: u8 }
+}
+
+impl From<Error> for crate::block::Error {
+	fn from(e: Error) -> Self {
+		match e {
+			Error::Timeout         => Self::Timeout,
+			Error::NoMemory        => Self::NoMemory,
+			Error::NoDevice        => Self::NoDevice,
+			Error::InvalidArgument => Self::InvalidArgument,
+			Error::Sense { key: storage::SENSE_DATA_PROTECT, .. } => Self::ReadOnly,
+			Error::NoSlots | Error::Stall | Error::Completion(_) | Error::Protocol
+				| Error::Sense { .. } => Self::Io
+		}
+	}
+}
+
+/// A device addressed and configured by the controller.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Device {
+	pub slot:          u8,
+	/// Root hub port, starting at 1
+	pub port:          u8,
+	/// `SPEED_*`
+	pub speed:         u8,
+	pub descriptor:    DeviceDescriptor,
+	/// The first configuration, the one the device is in
+	pub configuration: ConfigurationDescriptor
+}
+
+impl Device {
+	/// Default settings of the interfaces.
+	pub fn interfaces(&self) -> impl Iterator<Item = &InterfaceDescriptor> {
+		self.configuration.interfaces.iter().filter(|i| i.alternate == 0)
+	}
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+	/// A device was connected and configured
+	Attached(u8),
+	/// The device in the slot was disconnected, the slot is free again
+	Detached { slot: u8, port: u8 },
+	/// An interrupt IN endpoint polled with `start_reports` returned data
+	Report { slot: u8, endpoint: u8, data: Vec<u8> }
+}
+
+/// A transfer ring and the buffer its transfers go through.
+struct Endpoint {
+	descriptor: EndpointDescriptor,
+	ring:       Ring,
+	bounce:     Region,
+	/// The transfer queued on a polled interrupt endpoint
+	pending:    Option<u64>
+}
+
+impl Endpoint {
+	fn free(self, dma: &mut impl Dma) {
+		self.ring.free(dma);
+		self.bounce.free(dma);
+	}
+}
+
+struct Slot {
+	device:    Device,
+	/// Device context, owned by the controller
+	output:    Region,
+	/// Input context of commands
+	input:     Region,
+	/// Endpoints by DCI
+	endpoints: Vec<Option<Endpoint>>
+}
+
+impl Slot {
+	fn free(self, dma: &mut impl Dma) {
+		self.output.free(dma);
+		self.input.free(dma);
+		self.endpoints.into_iter().flatten().for_each(|ep| ep.free(dma));
+	}
+}
+
+/// Polls `f` every 10 microseconds.
+fn wait(dma: &mut impl Dma, us: u64, mut f: impl FnMut() -> bool) -> Result<(), Error> {
+	for _ in 0..us / 10 {
+		if f() {
+			return Ok(());
+		}
+		dma.stall(10);
+	}
+	match f() {
+		true  => Ok(()),
+		false => Err(Error::Timeout)
+	}
+}
+
+/// A context entry, 32 or 64 bytes depending on the controller.
+unsafe fn context(region: &Region, size: usize, index: usize) -> *mut u32 {
+	region.virt.add(index * size) as *mut u32
+}
+
+/// Endpoint context interval, in 2^n * 125 us.
+fn interval(speed: u8, ep: &EndpointDescriptor) -> u32 {
+	match (ep.transfer_type(), speed) {
+		// bInterval is in frames of 1 ms
+		(ENDPOINT_INTERRUPT, SPEED_LOW | SPEED_FULL) => (ep.interval.max(1) as u32 * 8).ilog2(),
+		(ENDPOINT_INTERRUPT | ENDPOINT_ISOCH, _) => ep.interval.clamp(1, 16) as u32 - 1,
+		_ => 0
+	}
+}
+
+pub struct Controller<D: Dma> {
+	op:         *mut OperationalRegisters,
+	runtime:    *mut RuntimeRegisters,
+	doorbells:  *mut u32,
+	dma:        D,
+	pub ports:  u8,
+	/// Bytes per context entry
+	context:    usize,
+	dcbaa:      Region,
+	/// Scratchpad buffer array and the buffers
+	scratchpad: Option<(Region, Region)>,
+	commands:   Ring,
+	events:     EventRing,
+	/// Slots by ID, 0 is unused
+	slots:      Vec<Option<Slot>>,
+	/// Transfer and command completions not yet waited for
+	completions: Vec<Trb>,
+	/// Reports not yet returned by `interrupt`
+	reports:    Vec<Event>,
+	/// Ports with a status change not yet handled by `interrupt`
+	changed:    Vec<u8>
+}
+
+impl<D: Dma> Controller<D> {
+	/// Resets the controller, sets up the command and event ring and enumerates the devices
+	/// connected to the root hub.
+	///
+	/// # Safety
+	///
+	/// `regs` must map the register BAR (BAR0) of the controller.
+	pub unsafe fn new(regs: *mut u8, mut dma: D) -> Result<Self, Error> {
+		let cap = regs as *mut CapabilityRegisters;
+		let op = regs.add(addr_of_mut!((*cap).length).read_volatile() as usize) as *mut OperationalRegisters;
+		let runtime = regs.add(addr_of_mut!((*cap).runtime_offset).read_volatile() as usize & !0x1F) as *mut RuntimeRegisters;
+		let doorbells = regs.add(addr_of_mut!((*cap).doorbell_offset).read_volatile() as usize & !0x3) as *mut u32;
+		let usbcmd = addr_of_mut!((*op).command);
+		let usbsts = addr_of_mut!((*op).status);
+
+		// firmware may have left the controller running
+		usbcmd.write_volatile(usbcmd.read_volatile() & !USBCMD_RS);
+		wait(&mut dma, RESET_TIMEOUT_US, || usbsts.read_volatile() & USBSTS_HCH != 0)?;
+		usbcmd.write_volatile(USBCMD_HCRST);
+		wait(&mut dma, RESET_TIMEOUT_US, || usbcmd.read_volatile() & USBCMD_HCRST == 0
+			&& usbsts.read_volatile() & USBSTS_CNR == 0)?;
+
+		let params1 = addr_of_mut!((*cap).structural_params1).read_volatile();
+		let params2 = addr_of_mut!((*cap).structural_params2).read_volatile();
+		let caps = addr_of_mut!((*cap).capability_params1).read_volatile();
+		let max_slots = (params1 & HCSPARAMS1_SLOTS_MASK) as usize;
+		let ports = (params1 >> HCSPARAMS1_PORTS_SHIFT) as u8;
+		let scratchpads = ((params2 >> HCSPARAMS2_SCRATCH_HI_SHIFT) & 0x1F) << 5 | (params2 >> HCSPARAMS2_SCRATCH_LO_SHIFT) & 0x1F;
+		let context = if caps & HCCPARAMS1_CSZ != 0 { 64 } else { 32 };
+
+		let dcbaa = Region::alloc(&mut dma, (max_slots + 1) * 8, 64);
+		let scratchpad = match scratchpads as usize {
+			0 => Some(None),
+			n => match (Region::alloc(&mut dma, n * 8, 64), Region::alloc(&mut dma, n * PAGE_SIZE, PAGE_SIZE)) {
+				(Some(array), Some(pages)) => Some(Some((array, pages))),
+				(array, pages) => {
+					array.into_iter().chain(pages).for_each(|r| r.free(&mut dma));
+					None
+				}
+			}
+		};
+		let commands = Ring::new(&mut dma);
+		let events = EventRing::new(&mut dma);
+		let (dcbaa, scratchpad, commands, events) = match (dcbaa, scratchpad, commands, events) {
+			(Some(dcbaa), Some(scratchpad), Some(commands), Some(events)) => (dcbaa, scratchpad, commands, events),
+			(dcbaa, scratchpad, commands, events) => {
+				dcbaa.into_iter().for_each(|r| r.free(&mut dma));
+				scratchpad.flatten().into_iter().for_each(|(array, pages)| {
+					array.free(&mut dma);
+					pages.free(&mut dma);
+				});
+				commands.iter().for_each(|r| r.free(&mut dma));
+				events.iter().for_each(|r| r.free(&mut dma));
+				return Err(Error::NoMemory);
+			}
+		};
+
+		if let Some((array, pages)) = &scratchpad {
+			for i in 0..scratchpads as usize {
+				array.as_ptr::<u64>().add(i).write_volatile(pages.phys + (i * PAGE_SIZE) as u64);
+			}
+			dcbaa.as_ptr::<u64>().write_volatile(array.phys);
+		}
+
+		addr_of_mut!((*op).config).write_volatile(max_slots as u32);
+		write64(addr_of_mut!((*op).dcbaa), dcbaa.phys);
+		write64(addr_of_mut!((*op).command_ring), commands.phys() | CRCR_RCS);
+
+		let interrupter = addr_of_mut!((*runtime).interrupters[0]);
+		addr_of_mut!((*interrupter).erst_size).write_volatile(1);
+		write64(addr_of_mut!((*interrupter).dequeue), events.dequeue_pointer());
+		write64(addr_of_mut!((*interrupter).erst_base), events.table_phys());
+		// at most one interrupt per millisecond
+		addr_of_mut!((*interrupter).moderation).write_volatile(4000);
+		addr_of_mut!((*interrupter).management).write_volatile(IMAN_IP | IMAN_IE);
+
+		let mut ctrl = Self {
+			op,
+			runtime,
+			doorbells,
+			dma,
+			ports,
+			context,
+			dcbaa,
+			scratchpad,
+			commands,
+			events,
+			slots:       (0..=max_slots).map(|_| None).collect(),
+			completions: Vec::new(),
+			reports:     Vec::new(),
+			changed:     Vec::new()
+		};
+
+		usbcmd.write_volatile(USBCMD_RS | USBCMD_INTE | USBCMD_HSEE);
+		wait(&mut ctrl.dma, RESET_TIMEOUT_US, || usbsts.read_volatile() & USBSTS_HCH == 0)?;
+
+		if caps & HCCPARAMS1_PPC != 0 {
+			for port in 1..=ports {
+				let portsc = ctrl.port(port);
+				portsc.write_volatile(Self::neutral(portsc.read_volatile()) | PORTSC_PP);
+			}
+			// power on to power good
+			ctrl.dma.stall(20_000);
+		}
+
+		for port in 1..=ports {
+			match ctrl.attach(port) {
+				Ok(_) | Err(Error::NoDevice) => (),
+				Err(Error::NoMemory) => return Err(Error::NoMemory),
+				// the device stays unusable until it is connected again
+				Err(_) => ()
+			}
+		}
+		Ok(ctrl)
+	}
+
+	fn port(&self, port: u8) -> *mut u32 {
+		unsafe { addr_of_mut!((*self.op).ports[port as usize - 1].status) }
+	}
+
+	/// A PORTSC value that changes nothing when written: the read-only and preserved bits
+	/// without the change bits, Port Enabled or Port Reset.
+	fn neutral(portsc: u32) -> u32 {
+		portsc & PORTSC_PRESERVE
+	}
+
+	fn ring_doorbell(&mut self, slot: u8, target: u8) {
+		unsafe { self.doorbells.add(slot as usize).write_volatile(target as u32) };
+	}
+
+	fn alloc(&mut self, size: usize, align: usize) -> Result<Region, Error> {
+		Region::alloc(&mut self.dma, size, align).ok_or(Error::NoMemory)
+	}
+
+	/// Takes the events of the event ring, completions are kept for `command` and `transfer`.
+	fn process_events(&mut self) {
+		let mut any = false;
+		while let Some(trb) = self.events.pop() {
+			any = true;
+			match trb.kind() {
+				TRB_PORT_STATUS_CHANGE => {
+					let port = (trb.parameter >> 24) as u8;
+					if !self.changed.contains(&port) {
+						self.changed.push(port);
+					}
+				}
+				TRB_TRANSFER_EVENT if self.is_report(&trb) => self.report(trb),
+				TRB_TRANSFER_EVENT | TRB_COMMAND_COMPLETION => self.completions.push(trb),
+				_ => ()
+			}
+		}
+		if any {
+			unsafe {
+				let interrupter = addr_of_mut!((*self.runtime).interrupters[0]);
+				write64(addr_of_mut!((*interrupter).dequeue), self.events.dequeue_pointer() | ERDP_EHB);
+			}
+		}
+	}
+
+	fn endpoint(&mut self, slot: u8, dci: u8) -> Result<&mut Endpoint, Error> {
+		self.slots.get_mut(slot as usize)
+			.and_then(Option::as_mut)
+			.ok_or(Error::NoDevice)?
+			.endpoints.get_mut(dci as usize)
+			.and_then(Option::as_mut)
+			.ok_or(Error::InvalidArgument)
+	}
+
+	fn is_report(&mut self, trb: &Trb) -> bool {
+		matches!(self.endpoint(trb.slot(), trb.endpoint()), Ok(ep) if ep.pending == Some(trb.parameter))
+	}
+
+	/// Returns the data of a completed report and polls the endpoint again, polling stops
+	/// when the endpoint fails.
+	fn report(&mut self, trb: Trb) {
+		let (slot, dci) = (trb.slot(), trb.endpoint());
+		let Ok(ep) = self.endpoint(slot, dci) else { return };
+		ep.pending = None;
+		if !matches!(trb.completion(), COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET) {
+			return;
+		}
+		let len = (ep.descriptor.max_packet_size as usize).saturating_sub((trb.status & 0xFF_FFFF) as usize);
+		let mut data = alloc::vec![0; len];
+		unsafe { core::ptr::copy_nonoverlapping(ep.bounce.virt, data.as_mut_ptr(), len) };
+		let endpoint = ep.descriptor.address;
+		self.reports.push(Event::Report { slot, endpoint, data });
+		let _ = self.queue_report(slot, dci);
+	}
+
+	fn queue_report(&mut self, slot: u8, dci: u8) -> Result<(), Error> {
+		let ep = self.endpoint(slot, dci)?;
+		let len = ep.descriptor.max_packet_size as u32;
+		let phys = ep.ring.push(Trb::new(TRB_NORMAL, ep.bounce.phys, len, TRB_ISP | TRB_IOC));
+		ep.pending = Some(phys);
+		self.ring_doorbell(slot, dci);
+		Ok(())
+	}
+
+	/// Runs a command, returns its completion event.
+	fn command(&mut self, trb: Trb) -> Result<Trb, Error> {
+		let phys = self.commands.push(trb);
+		self.ring_doorbell(0, 0);
+		for _ in 0..COMMAND_TIMEOUT_US / 10 {
+			self.process_events();
+			let i = self.completions.iter()
+				.position(|e| e.kind() == TRB_COMMAND_COMPLETION && e.parameter == phys);
+			if let Some(i) = i {
+				let event = self.completions.remove(i);
+				return match event.completion() {
+					COMPLETION_SUCCESS  => Ok(event),
+					COMPLETION_NO_SLOTS => Err(Error::NoSlots),
+					code                => Err(Error::Completion(code))
+				};
+			}
+			self.dma.stall(10);
+		}
+		Err(Error::Timeout)
+	}
+
+	/// Waits for the TD made of `trbs`, their addresses and lengths. A control transfer ends
+	/// with its status stage, other transfers end with the first short packet. Returns the
+	/// bytes transferred.
+	fn wait_transfer(&mut self, slot: u8, dci: u8, trbs: &[(u64, usize)]) -> Result<usize, Error> {
+		let total = trbs.iter().map(|(_, len)| len).sum::<usize>();
+		let last = trbs.last().map_or(0, |(phys, _)| *phys);
+		let mut transferred = None;
+		for _ in 0..TRANSFER_TIMEOUT_US / 10 {
+			self.process_events();
+			while let Some(i) = self.completions.iter()
+				.position(|e| e.kind() == TRB_TRANSFER_EVENT && e.slot() == slot && e.endpoint() == dci) {
+				let event = self.completions.remove(i);
+				// events of earlier, aborted transfers
+				let Some(index) = trbs.iter().position(|(phys, _)| *phys == event.parameter) else { continue };
+				match event.completion() {
+					COMPLETION_SHORT_PACKET => {
+						let residual = (event.status & 0xFF_FFFF) as usize;
+						let before = trbs[..index].iter().map(|(_, len)| len).sum::<usize>();
+						let n = before + trbs[index].1.saturating_sub(residual);
+						if dci != 1 || event.parameter == last {
+							return Ok(n);
+						}
+						transferred = Some(n);
+					}
+					COMPLETION_SUCCESS if event.parameter == last => return Ok(transferred.unwrap_or(total)),
+					COMPLETION_SUCCESS => (),
+					COMPLETION_STALL => return Err(Error::Stall),
+					code => return Err(Error::Completion(code))
+				}
+			}
+			self.dma.stall(10);
+		}
+		Err(Error::Timeout)
+	}
+
+	/// Runs a transfer and recovers the endpoint when it fails.
+	fn transfer(&mut self, slot: u8, dci: u8, trbs: &[(u64, usize)]) -> Result<usize, Error> {
+		self.ring_doorbell(slot, dci);
+		match self.wait_transfer(slot, dci, trbs) {
+			Ok(n) => Ok(n),
+			Err(Error::Stall) => {
+				self.reset_endpoint(slot, dci)?;
+				Err(Error::Stall)
+			}
+			Err(e) => {
+				self.abort(slot, dci);
+				Err(e)
+			}
+		}
+	}
+
+	/// Recovers a halted endpoint, the transfer ring continues after the failed TD.
+	fn reset_endpoint(&mut self, slot: u8, dci: u8) -> Result<(), Error> {
+		let id = (slot as u32) << TRB_SLOT_SHIFT | (dci as u32) << TRB_EP_SHIFT;
+		self.command(Trb::new(TRB_RESET_EP, 0, 0, id))?;
+		let dequeue = self.endpoint(slot, dci)?.ring.enqueue_pointer();
+		self.command(Trb::new(TRB_SET_DEQUEUE, dequeue, 0, id))?;
+		Ok(())
+	}
+
+	/// Stops an endpoint and skips the TDs still on its ring.
+	fn abort(&mut self, slot: u8, dci: u8) {
+		let id = (slot as u32) << TRB_SLOT_SHIFT | (dci as u32) << TRB_EP_SHIFT;
+		let _ = self.command(Trb::new(TRB_STOP_EP, 0, 0, id));
+		if let Ok(ep) = self.endpoint(slot, dci) {
+			let dequeue = ep.ring.enqueue_pointer();
+			let _ = self.command(Trb::new(TRB_SET_DEQUEUE, dequeue, 0, id));
+		}
+		self.completions.retain(|e| !(e.kind() == TRB_TRANSFER_EVENT && e.slot() == slot && e.endpoint() == dci
+			&& e.completion() == COMPLETION_STOPPED));
+	}
+
+	/// Runs a control transfer on the default endpoint. `data` is sent for OUT requests and
+	/// receives the data of IN requests, the setup packet's length is clamped to it.
+	/// Returns the bytes transferred.
+	pub fn control(&mut self, slot: u8, setup: SetupPacket, data: &mut [u8]) -> Result<usize, Error> {
+		let len = data.len().min(setup.length as usize);
+		let setup = SetupPacket { length: len as u16, ..setup };
+		let ep = self.endpoint(slot, 1)?;
+		if len > ep.bounce.size {
+			return Err(Error::InvalidArgument);
+		}
+		let is_in = setup.is_in();
+		if !is_in {
+			unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), ep.bounce.virt, len) };
+		}
+
+		let (trt, dir) = match (len, is_in) {
+			(0, _)     => (TRB_TRT_NO_DATA, 0),
+			(_, true)  => (TRB_TRT_IN, TRB_DIR_IN),
+			(_, false) => (TRB_TRT_OUT, 0)
+		};
+		let mut trbs = Vec::with_capacity(3);
+		trbs.push((ep.ring.push(Trb::new(TRB_SETUP, setup.to_u64(), 8, TRB_IDT | trt)), 0));
+		if len > 0 {
+			trbs.push((ep.ring.push(Trb::new(TRB_DATA, ep.bounce.phys, len as u32, TRB_ISP | dir)), len));
+		}
+		// the status stage goes the other way, IN when there is no data stage
+		let status = match (len, is_in) {
+			(0, _) | (_, false) => TRB_DIR_IN,
+			_                   => 0
+		};
+		trbs.push((ep.ring.push(Trb::new(TRB_STATUS, 0, 0, TRB_IOC | status)), 0));
+
+		let n = self.transfer(slot, 1, &trbs)?;
+		if is_in {
+			let ep = self.endpoint(slot, 1)?;
+			unsafe { core::ptr::copy_nonoverlapping(ep.bounce.virt, data.as_mut_ptr(), n) };
+		}
+		Ok(n)
+	}
+
+	/// Queues one TD of `len` bytes from the endpoint's bounce buffer, split where the
+	/// buffer crosses 64 KiB.
+	fn bulk(&mut self, slot: u8, dci: u8, len: usize) -> Result<usize, Error> {
+		let ep = self.endpoint(slot, dci)?;
+		let isp = if ep.descriptor.is_in() { TRB_ISP } else { 0 };
+		let mut trbs = Vec::new();
+		let mut off = 0;
+		while off < len || trbs.is_empty() {
+			let phys = ep.bounce.phys + off as u64;
+			let n = (len - off).min(0x10000 - (phys as usize & 0xFFFF));
+			let flags = if off + n < len { TRB_CH } else { TRB_IOC };
+			trbs.push((ep.ring.push(Trb::new(TRB_NORMAL, phys, n as u32, flags | isp)), n));
+			off += n;
+		}
+		self.transfer(slot, dci, &trbs)
+	}
+
+	fn bulk_endpoint(&mut self, slot: u8, address: u8) -> Result<u8, Error> {
+		let dci = dci(address);
+		match self.endpoint(slot, dci)?.descriptor.transfer_type() {
+			ENDPOINT_BULK | ENDPOINT_INTERRUPT => Ok(dci),
+			_ => Err(Error::InvalidArgument)
+		}
+	}
+
+	/// Reads from a bulk or interrupt IN endpoint, returns the bytes read. The transfer ends
+	/// early with a short packet.
+	pub fn bulk_in(&mut self, slot: u8, endpoint: u8, buf: &mut [u8]) -> Result<usize, Error> {
+		let dci = self.bulk_endpoint(slot, endpoint | ENDPOINT_IN)?;
+		let mut off = 0;
+		loop {
+			let len = (buf.len() - off).min(MAX_TRANSFER);
+			let n = self.bulk(slot, dci, len)?;
+			let ep = self.endpoint(slot, dci)?;
+			unsafe { core::ptr::copy_nonoverlapping(ep.bounce.virt, buf[off..].as_mut_ptr(), n) };
+			off += n;
+			if n < len || off == buf.len() {
+				return Ok(off);
+			}
+		}
+	}
+
+	/// Writes to a bulk or interrupt OUT endpoint, returns the bytes written.
+	pub fn bulk_out(&mut self, slot: u8, endpoint: u8, buf: &[u8]) -> Result<usize, Error> {
+		let dci = self.bulk_endpoint(slot, endpoint & !ENDPOINT_IN)?;
+		let mut off = 0;
+		loop {
+			let len = (buf.len() - off).min(MAX_TRANSFER);
+			let ep = self.endpoint(slot, dci)?;
+			unsafe { core::ptr::copy_nonoverlapping(buf[off..].as_ptr(), ep.bounce.virt, len) };
+			let n = self.bulk(slot, dci, len)?;
+			off += n;
+			if n < len || off == buf.len() {
+				return Ok(off);
+			}
+		}
+	}
+
+	/// Recovers a halted endpoint and clears the halt on the device.
+	pub fn clear_halt(&mut self, slot: u8, endpoint: u8) -> Result<(), Error> {
+		let dci = dci(endpoint);
+		self.endpoint(slot, dci)?;
+		if let Err(e) = self.reset_endpoint(slot, dci) {
+			// the endpoint wasn't halted on the controller's side
+			if !matches!(e, Error::Completion(_)) {
+				return Err(e);
+			}
+		}
+		match dci {
+			1 => Ok(()),
+			_ => self.control(slot, SetupPacket {
+				request_type: REQUEST_TYPE_ENDPOINT,
+				request:      REQUEST_CLEAR_FEATURE,
+				value:        FEATURE_ENDPOINT_HALT,
+				index:        endpoint as u16,
+				length:       0
+			}, &mut []).map(|_| ())
+		}
+	}
+
+	/// Polls an interrupt IN endpoint, its data arrives as `Event::Report`.
+	pub fn start_reports(&mut self, slot: u8, endpoint: u8) -> Result<(), Error> {
+		let dci = dci(endpoint);
+		let ep = self.endpoint(slot, dci)?;
+		if ep.descriptor.transfer_type() != ENDPOINT_INTERRUPT || !ep.descriptor.is_in() {
+			return Err(Error::InvalidArgument);
+		}
+		if ep.pending.is_some() {
+			return Ok(());
+		}
+		self.queue_report(slot, dci)
+	}
+
+	pub fn device(&self, slot: u8) -> Option<&Device> {
+		self.slots.get(slot as usize)?.as_ref().map(|s| &s.device)
+	}
+
+	pub fn devices(&self) -> impl Iterator<Item = &Device> {
+		self.slots.iter().flatten().map(|s| &s.device)
+	}
+
+	/// Handles an interrupt: returns reports and the devices attached or detached since the
+	/// last call.
+	pub fn interrupt(&mut self) -> Vec<Event> {
+		unsafe {
+			let interrupter = addr_of_mut!((*self.runtime).interrupters[0]);
+			let iman = addr_of_mut!((*interrupter).management);
+			iman.write_volatile(iman.read_volatile() | IMAN_IP);
+			addr_of_mut!((*self.op).status).write_volatile(USBSTS_EINT | USBSTS_PCD);
+		}
+		self.process_events();
+
+		let mut events = core::mem::take(&mut self.reports);
+		while !self.changed.is_empty() {
+			let port = self.changed.remove(0);
+			if port == 0 || port > self.ports {
+				continue;
+			}
+			let portsc = unsafe { self.port(port).read_volatile() };
+			let slot = self.devices().find(|d| d.port == port).map(|d| d.slot);
+			match (slot, portsc & PORTSC_CCS != 0) {
+				(None, true) => if let Ok(slot) = self.attach(port) {
+					events.push(Event::Attached(slot));
+				},
+				(Some(slot), false) => {
+					self.release(slot);
+					events.push(Event::Detached { slot, port });
+				}
+				_ => self.clear_changes(port)
+			}
+		}
+		events.append(&mut self.reports);
+		events
+	}
+
+	fn clear_changes(&mut self, port: u8) {
+		let portsc = self.port(port);
+		unsafe {
+			let v = portsc.read_volatile();
+			portsc.write_volatile(Self::neutral(v) | v & PORTSC_CHANGES);
+		}
+	}
+
+	/// Resets the port if it isn't enabled yet, then addresses and configures the device.
+	fn attach(&mut self, port: u8) -> Result<u8, Error> {
+		let portsc = self.port(port);
+		self.clear_changes(port);
+		let v = unsafe { portsc.read_volatile() };
+		if v & PORTSC_CCS == 0 {
+			return Err(Error::NoDevice);
+		}
+		// USB 3 ports enable themselves after connecting
+		if v & PORTSC_PED == 0 {
+			unsafe { portsc.write_volatile(Self::neutral(v) | PORTSC_PR) };
+			wait(&mut self.dma, PORT_RESET_TIMEOUT_US, || unsafe { portsc.read_volatile() } & PORTSC_PRC != 0)?;
+			self.clear_changes(port);
+			// reset recovery
+			self.dma.stall(10_000);
+		}
+		let v = unsafe { portsc.read_volatile() };
+		if v & (PORTSC_CCS | PORTSC_PED) != PORTSC_CCS | PORTSC_PED {
+			return Err(Error::NoDevice);
+		}
+		let speed = ((v & PORTSC_SPEED_MASK) >> PORTSC_SPEED_SHIFT) as u8;
+
+		let slot = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?.slot();
+		match self.setup(slot, port, speed) {
+			Ok(()) => Ok(slot),
+			Err(e) => {
+				self.release(slot);
+				Err(e)
+			}
+		}
+	}
+
+	/// Frees a slot and its memory, the device is gone or failed to enumerate.
+	fn release(&mut self, slot: u8) {
+		let _ = self.command(Trb::new(TRB_DISABLE_SLOT, 0, 0, (slot as u32) << TRB_SLOT_SHIFT));
+		unsafe { self.dcbaa.as_ptr::<u64>().add(slot as usize).write_volatile(0) };
+		if let Some(s) = self.slots.get_mut(slot as usize).and_then(Option::take) {
+			s.free(&mut self.dma);
+		}
+		self.completions.retain(|e| e.kind() != TRB_TRANSFER_EVENT || e.slot() != slot);
+	}
+
+	fn new_endpoint(&mut self, descriptor: EndpointDescriptor, bounce: usize) -> Result<Endpoint, Error> {
+		let ring = Ring::new(&mut self.dma).ok_or(Error::NoMemory)?;
+		match self.alloc(bounce, PAGE_SIZE) {
+			Ok(bounce) => Ok(Endpoint { descriptor, ring, bounce, pending: None }),
+			Err(e) => {
+				ring.free(&mut self.dma);
+				Err(e)
+			}
+		}
+	}
+
+	/// Addresses the device, reads its descriptors and selects the first configuration.
+	fn setup(&mut self, slot: u8, port: u8, speed: u8) -> Result<(), Error> {
+		let cs = self.context;
+		let output = self.alloc(32 * cs, 64)?;
+		let input = match self.alloc(33 * cs, 64) {
+			Ok(input) => input,
+			Err(e) => {
+				output.free(&mut self.dma);
+				return Err(e);
+			}
+		};
+		let mps: u16 = match speed {
+			SPEED_LOW | SPEED_FULL => 8,
+			SPEED_HIGH             => 64,
+			_                      => 512
+		};
+		let ep0 = EndpointDescriptor { address: 0, attributes: ENDPOINT_CONTROL, max_packet_size: mps, interval: 0 };
+		let ep0 = match self.new_endpoint(ep0, PAGE_SIZE) {
+			Ok(ep0) => ep0,
+			Err(e) => {
+				output.free(&mut self.dma);
+				input.free(&mut self.dma);
+				return Err(e);
+			}
+		};
+		let ring = ep0.ring.enqueue_pointer();
+		let (output_phys, input_phys) = (output.phys, input.phys);
+		unsafe { self.dcbaa.as_ptr::<u64>().add(slot as usize).write_volatile(output_phys) };
+
+		let mut endpoints = (0..32).map(|_| None).collect::<Vec<_>>();
+		endpoints[1] = Some(ep0);
+		self.slots[slot as usize] = Some(Slot {
+			device: Device { slot, port, speed, descriptor: Default::default(), configuration: Default::default() },
+			output,
+			input,
+			endpoints
+		});
+
+		let s = self.slots[slot as usize].as_mut().unwrap();
+		unsafe {
+			s.input.virt.write_bytes(0, s.input.size);
+			// add the slot and the default endpoint
+			context(&s.input, cs, 0).add(1).write_volatile(0b11);
+			let slot_ctx = context(&s.input, cs, 1);
+			slot_ctx.write_volatile((speed as u32) << 20 | 1 << 27);
+			slot_ctx.add(1).write_volatile((port as u32) << 16);
+			let ep = context(&s.input, cs, 2);
+			ep.add(1).write_volatile(3 << 1 | EP_TYPE_CONTROL << 3 | (mps as u32) << 16);
+			ep.add(2).write_volatile(ring as u32);
+			ep.add(3).write_volatile((ring >> 32) as u32);
+			ep.add(4).write_volatile(8);
+		}
+		self.command(Trb::new(TRB_ADDRESS_DEVICE, input_phys, 0, (slot as u32) << TRB_SLOT_SHIFT))?;
+
+		let mut buf = [0; DeviceDescriptor::SIZE];
+		self.control(slot, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 8), &mut buf[..8])?;
+		let actual = match speed {
+			SPEED_SUPER.. => 1u16.checked_shl(buf[7] as u32).unwrap_or(0),
+			_ => buf[7] as u16
+		};
+		if actual == 0 {
+			return Err(Error::Protocol);
+		}
+		if actual != mps {
+			let s = self.slots[slot as usize].as_mut().unwrap();
+			s.endpoints[1].as_mut().unwrap().descriptor.max_packet_size = actual;
+			unsafe {
+				s.input.virt.write_bytes(0, s.input.size);
+				context(&s.input, cs, 0).add(1).write_volatile(0b10);
+				let ep = context(&s.input, cs, 2);
+				ep.add(1).write_volatile(3 << 1 | EP_TYPE_CONTROL << 3 | (actual as u32) << 16);
+			}
+			self.command(Trb::new(TRB_EVALUATE_CONTEXT, input_phys, 0, (slot as u32) << TRB_SLOT_SHIFT))?;
+		}
+
+		if self.control(slot, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, buf.len() as u16), &mut buf)? < buf.len() {
+			return Err(Error::Protocol);
+		}
+		let descriptor = DeviceDescriptor::parse(&buf).ok_or(Error::Protocol)?;
+
+		let mut head = [0; ConfigurationDescriptor::SIZE];
+		self.control(slot, SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, head.len() as u16), &mut head)?;
+		let total = u16::from_le_bytes([head[2], head[3]]) as usize;
+		if !(ConfigurationDescriptor::SIZE..=PAGE_SIZE).contains(&total) {
+			return Err(Error::Protocol);
+		}
+		let mut buf = alloc::vec![0; total];
+		let n = self.control(slot, SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, total as u16), &mut buf)?;
+		let configuration = ConfigurationDescriptor::parse(&buf[..n]).ok_or(Error::Protocol)?;
+
+		self.control(slot, SetupPacket {
+			request_type: 0,
+			request:      REQUEST_SET_CONFIGURATION,
+			value:        configuration.value as u16,
+			index:        0,
+			length:       0
+		}, &mut [])?;
+		self.configure_endpoints(slot, speed, &configuration)?;
+
+		let s = self.slots[slot as usize].as_mut().unwrap();
+		s.device.descriptor = descriptor;
+		s.device.configuration = configuration;
+		Ok(())
+	}
+
+	/// Adds transfer rings for the endpoints of the interfaces' default settings,
+	/// isochronous endpoints aren't supported.
+	fn configure_endpoints(&mut self, slot: u8, speed: u8, config: &ConfigurationDescriptor) -> Result<(), Error> {
+		let descriptors = config.interfaces.iter()
+			.filter(|i| i.alternate == 0)
+			.flat_map(|i| i.endpoints.iter().copied())
+			.filter(|e| matches!(e.transfer_type(), ENDPOINT_BULK | ENDPOINT_INTERRUPT) && e.max_packet_size > 0)
+			.collect::<Vec<_>>();
+		if descriptors.is_empty() {
+			return Ok(());
+		}
+
+		for d in &descriptors {
+			let bounce = match d.transfer_type() {
+				ENDPOINT_BULK => MAX_TRANSFER,
+				_             => PAGE_SIZE
+			};
+			let ep = self.new_endpoint(*d, bounce)?;
+			let s = self.slots[slot as usize].as_mut().unwrap();
+			if let Some(old) = s.endpoints[d.dci() as usize].replace(ep) {
+				old.free(&mut self.dma);
+			}
+		}
+
+		let cs = self.context;
+		let s = self.slots[slot as usize].as_mut().unwrap();
+		let last = descriptors.iter().map(EndpointDescriptor::dci).max().unwrap_or(1) as u32;
+		unsafe {
+			s.input.virt.write_bytes(0, s.input.size);
+			let add = descriptors.iter().fold(1, |add, d| add | 1 << d.dci());
+			context(&s.input, cs, 0).add(1).write_volatile(add);
+			let (slot_ctx, current) = (context(&s.input, cs, 1), context(&s.output, cs, 0));
+			for i in 0..4 {
+				slot_ctx.add(i).write_volatile(current.add(i).read_volatile());
+			}
+			slot_ctx.write_volatile(slot_ctx.read_volatile() & !(0x1F << 27) | last << 27);
+			// the slot state of the output context is reserved in input contexts
+			slot_ctx.add(3).write_volatile(0);
+
+			for d in &descriptors {
+				let ep = s.endpoints[d.dci() as usize].as_ref().unwrap();
+				let kind = match (d.transfer_type(), d.is_in()) {
+					(ENDPOINT_BULK, false) => EP_TYPE_BULK_OUT,
+					(ENDPOINT_BULK, true)  => EP_TYPE_BULK_IN,
+					(_, false)             => EP_TYPE_INTERRUPT_OUT,
+					(_, true)              => EP_TYPE_INTERRUPT_IN
+				};
+				let ring = ep.ring.enqueue_pointer();
+				let ctx = context(&s.input, cs, d.dci() as usize + 1);
+				ctx.write_volatile(interval(speed, d) << 16);
+				ctx.add(1).write_volatile(3 << 1 | kind << 3 | (d.max_packet_size as u32) << 16);
+				ctx.add(2).write_volatile(ring as u32);
+				ctx.add(3).write_volatile((ring >> 32) as u32);
+				let esit = match d.transfer_type() {
+					ENDPOINT_INTERRUPT => d.max_packet_size as u32,
+					_                  => 0
+				};
+				ctx.add(4).write_volatile(esit << 16 | d.max_packet_size as u32);
+			}
+		}
+		let input = s.input.phys;
+		self.command(Trb::new(TRB_CONFIGURE_EP, input, 0, (slot as u32) << TRB_SLOT_SHIFT))?;
+		Ok(())
+	}
+}
+
+impl<D: Dma> Drop for Controller<D> {
+	fn drop(&mut self) {
+		for slot in 0..self.slots.len() as u8 {
+			if self.slots[slot as usize].is_some() {
+				self.release(slot);
+			}
+		}
+		unsafe {
+			let usbcmd = addr_of_mut!((*self.op).command);
+			let usbsts = addr_of_mut!((*self.op).status);
+			usbcmd.write_volatile(0);
+			let _ = wait(&mut self.dma, RESET_TIMEOUT_US, || usbsts.read_volatile() & USBSTS_HCH != 0);
+		}
+		self.dma.free(self.dcbaa.virt, self.dcbaa.size);
+		if let Some((array, pages)) = self.scratchpad.take() {
+			array.free(&mut self.dma);
+			pages.free(&mut self.dma);
+		}
+		self.commands.free(&mut self.dma);
+		self.events.free(&mut self.dma);
+	}
+}
+
+impl<D: Dma> core::fmt::Debug for Controller<D> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Controller")
+			.field("ports", &self.ports)
+			.field("slots", &(self.slots.len() - 1))
+			.field("devices", &self.devices().map(|d| (d.slot, d.port, d.descriptor.vendor_id, d.descriptor.product_id))
+				.collect::<Vec<_>>())
+			.finish()
+	}
+}
@@ -0,0 +1,237 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! HID boot protocol keyboards and mice.
+//!
+//! Boot protocol reports have a fixed layout, so no report descriptor is parsed. A
+//! keyboard report holds the modifiers and up to six pressed keys, `Keyboard` turns
+//! consecutive reports into presses and releases.
+
+use {super::*, crate::dma::Dma, alloc::vec::Vec};
+
+pub const SUBCLASS_BOOT:     u8 = 1;
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+pub const PROTOCOL_MOUSE:    u8 = 2;
+
+/// Class requests
+pub const REQUEST_GET_REPORT:   u8 = 0x01;
+pub const REQUEST_SET_REPORT:   u8 = 0x09;
+pub const REQUEST_SET_IDLE:     u8 = 0x0A;
+pub const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+/// `SET_PROTOCOL` values
+pub const PROTOCOL_BOOT:   u16 = 0;
+pub const PROTOCOL_REPORT: u16 = 1;
+/// Report type of output reports, in the high byte of `SET_REPORT` values
+pub const REPORT_OUTPUT: u16 = 2;
+
+/// Modifier bits of keyboard reports, usages 0xE0 to 0xE7
+pub const MOD_LEFT_CTRL:   u8 = 1 << 0;
+pub const MOD_LEFT_SHIFT:  u8 = 1 << 1;
+pub const MOD_LEFT_ALT:    u8 = 1 << 2;
+pub const MOD_LEFT_GUI:    u8 = 1 << 3;
+pub const MOD_RIGHT_CTRL:  u8 = 1 << 4;
+pub const MOD_RIGHT_SHIFT: u8 = 1 << 5;
+pub const MOD_RIGHT_ALT:   u8 = 1 << 6;
+pub const MOD_RIGHT_GUI:   u8 = 1 << 7;
+pub const MOD_SHIFT: u8 = MOD_LEFT_SHIFT | MOD_RIGHT_SHIFT;
+pub const MOD_CTRL:  u8 = MOD_LEFT_CTRL | MOD_RIGHT_CTRL;
+
+/// Keyboard LEDs of output reports
+pub const LED_NUM_LOCK:    u8 = 1 << 0;
+pub const LED_CAPS_LOCK:   u8 = 1 << 1;
+pub const LED_SCROLL_LOCK: u8 = 1 << 2;
+
+/// Usage of the first modifier key
+pub const USAGE_LEFT_CTRL: u8 = 0xE0;
+/// Reported in every key slot when too many keys are pressed
+pub const USAGE_ROLLOVER:  u8 = 0x01;
+
+/// Mouse buttons
+pub const BUTTON_LEFT:   u8 = 1 << 0;
+pub const BUTTON_RIGHT:  u8 = 1 << 1;
+pub const BUTTON_MIDDLE: u8 = 1 << 2;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Kind {
+	Keyboard,
+	Mouse
+}
+
+/// A boot protocol interface of a device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Interface {
+	pub kind:      Kind,
+	pub interface: u8,
+	/// The interrupt IN endpoint reports arrive on
+	pub endpoint:  u8
+}
+
+/// Finds the boot protocol keyboard and mouse interfaces of a device.
+pub fn find(device: &Device) -> Vec<Interface> {
+	device.interfaces()
+		.filter(|i| i.class == CLASS_HID && i.subclass == SUBCLASS_BOOT)
+		.filter_map(|i| {
+			let kind = match i.protocol {
+				PROTOCOL_KEYBOARD => Kind::Keyboard,
+				PROTOCOL_MOUSE    => Kind::Mouse,
+				_                 => return None
+			};
+			let ep = i.endpoints.iter().find(|e| e.transfer_type() == ENDPOINT_INTERRUPT && e.is_in())?;
+			Some(Interface { kind, interface: i.number, endpoint: ep.address })
+		})
+		.collect()
+}
+
+fn class_request(request: u8, value: u16, interface: u8) -> SetupPacket {
+	SetupPacket {
+		request_type: REQUEST_TYPE_CLASS | REQUEST_TYPE_INTERFACE,
+		request,
+		value,
+		index:        interface as u16,
+		length:       0
+	}
+}
+
+/// Switches the interface to the boot protocol and starts polling it, reports arrive as
+/// `Event::Report` of the interface's endpoint.
+pub fn start<D: Dma>(ctrl: &mut Controller<D>, slot: u8, interface: &Interface) -> Result<(), Error> {
+	ctrl.control(slot, class_request(REQUEST_SET_PROTOCOL, PROTOCOL_BOOT, interface.interface), &mut [])?;
+	// only report changes, mice may not support it
+	match ctrl.control(slot, class_request(REQUEST_SET_IDLE, 0, interface.interface), &mut []) {
+		Ok(_) | Err(Error::Stall) => (),
+		Err(e) => return Err(e)
+	}
+	ctrl.start_reports(slot, interface.endpoint)
+}
+
+/// Sets the keyboard LEDs, `LED_*`.
+pub fn set_leds<D: Dma>(ctrl: &mut Controller<D>, slot: u8, interface: &Interface, leds: u8) -> Result<(), Error> {
+	let setup = SetupPacket { length: 1, ..class_request(REQUEST_SET_REPORT, REPORT_OUTPUT << 8, interface.interface) };
+	ctrl.control(slot, setup, &mut [leds]).map(|_| ())
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Key {
+	/// Usage ID of the keyboard/keypad page
+	pub usage:   u8,
+	pub pressed: bool
+}
+
+/// Turns boot keyboard reports into key presses and releases.
+#[derive(Clone, Debug, Default)]
+pub struct Keyboard {
+	modifiers: u8,
+	keys:      [u8; 6]
+}
+
+impl Keyboard {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The modifiers held down, `MOD_*`.
+	pub fn modifiers(&self) -> u8 {
+		self.modifiers
+	}
+
+	/// Compares a report with the previous one, returns the keys released and pressed in
+	/// between. Modifiers are keys with the usages 0xE0 to 0xE7, reports with a rollover
+	/// error are ignored.
+	pub fn report(&mut self, report: &[u8]) -> Vec<Key> {
+		let mut changes = Vec::new();
+		if report.len() < 8 || report[2..8].contains(&USAGE_ROLLOVER) {
+			return changes;
+		}
+
+		let modifiers = report[0];
+		let mut keys = [0; 6];
+		keys.copy_from_slice(&report[2..8]);
+
+		for bit in 0..8 {
+			let (old, new) = (self.modifiers & 1 << bit != 0, modifiers & 1 << bit != 0);
+			if old != new {
+				changes.push(Key { usage: USAGE_LEFT_CTRL + bit, pressed: new });
+			}
+		}
+		for &usage in self.keys.iter().filter(|&&k| k != 0 && !keys.contains(&k)) {
+			changes.push(Key { usage, pressed: false });
+		}
+		for &usage in keys.iter().filter(|&&k| k != 0 && !self.keys.contains(&k)) {
+			changes.push(Key { usage, pressed: true });
+		}
+
+		self.modifiers = modifiers;
+		self.keys = keys;
+		changes
+	}
+}
+
+/// The character a key produces on a US layout.
+pub fn to_char(usage: u8, shift: bool) -> Option<char> {
+	const SHIFTED_DIGITS: &[u8; 10] = b"!@#$%^&*()";
+	const SYMBOLS: &[(u8, u8, u8)] = &[
+		(0x2D, b'-', b'_'), (0x2E, b'=', b'+'), (0x2F, b'[', b'{'), (0x30, b']', b'}'),
+		(0x31, b'\\', b'|'), (0x33, b';', b':'), (0x34, b'\'', b'"'), (0x35, b'`', b'~'),
+		(0x36, b',', b'<'), (0x37, b'.', b'>'), (0x38, b'/', b'?')
+	];
+
+	Some(match usage {
+		0x04..=0x1D if shift => (b'A' + usage - 0x04) as char,
+		0x04..=0x1D => (b'a' + usage - 0x04) as char,
+		0x1E..=0x27 if shift => SHIFTED_DIGITS[(usage - 0x1E) as usize] as char,
+		0x1E..=0x26 => (b'1' + usage - 0x1E) as char,
+		0x27 => '0',
+		0x28 => '\n',
+		0x29 => '\x1b',
+		0x2A => '\x08',
+		0x2B => '\t',
+		0x2C => ' ',
+		_ => SYMBOLS.iter()
+			.find(|(u, ..)| *u == usage)
+			.map(|&(_, c, s)| if shift { s } else { c } as char)?
+	})
+}
+
+/// A boot mouse report.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MouseReport {
+	/// `BUTTON_*`
+	pub buttons: u8,
+	pub x:       i8,
+	pub y:       i8,
+	/// Many mice report a wheel after the boot fields
+	pub wheel:   i8
+}
+
+impl MouseReport {
+	pub fn parse(report: &[u8]) -> Option<Self> {
+		if report.len() < 3 {
+			return None;
+		}
+		Some(Self {
+			buttons: report[0],
+			x:       report[1] as i8,
+			y:       report[2] as i8,
+			wheel:   report.get(3).map_or(0, |&w| w as i8)
+		})
+	}
+}
@@ -0,0 +1,509 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! eXtensible Host Controller Interface, USB host controllers.
+//!
+//! The controller takes commands through the command ring and moves data through a
+//! transfer ring per endpoint, completions and port changes arrive on the event ring.
+//! Devices are enumerated when their port connects, `hid` and `storage` implement the
+//! boot protocol of keyboards and mice and Bulk-Only mass storage on top.
+
+mod ring;
+mod controller;
+pub mod hid;
+pub mod storage;
+
+pub use {ring::*, controller::*};
+
+use alloc::vec::Vec;
+
+/// Number of Device Slots
+pub const HCSPARAMS1_SLOTS_MASK:  u32 = 0xFF;
+/// Number of Ports
+pub const HCSPARAMS1_PORTS_SHIFT: u32 = 24;
+/// Max Scratchpad Buffers, split into a high and a low part
+pub const HCSPARAMS2_SCRATCH_HI_SHIFT: u32 = 21;
+pub const HCSPARAMS2_SCRATCH_LO_SHIFT: u32 = 27;
+/// 64-bit Addressing Capability
+pub const HCCPARAMS1_AC64: u32 = 1 << 0;
+/// Context Size, contexts are 64 bytes instead of 32
+pub const HCCPARAMS1_CSZ:  u32 = 1 << 2;
+/// Port Power Control
+pub const HCCPARAMS1_PPC:  u32 = 1 << 3;
+
+/// Run/Stop
+pub const USBCMD_RS:    u32 = 1 << 0;
+/// Host Controller Reset
+pub const USBCMD_HCRST: u32 = 1 << 1;
+/// Interrupter Enable
+pub const USBCMD_INTE:  u32 = 1 << 2;
+/// Host System Error Enable
+pub const USBCMD_HSEE:  u32 = 1 << 3;
+
+/// HC Halted
+pub const USBSTS_HCH:  u32 = 1 << 0;
+/// Host System Error
+pub const USBSTS_HSE:  u32 = 1 << 2;
+/// Event Interrupt
+pub const USBSTS_EINT: u32 = 1 << 3;
+/// Port Change Detect
+pub const USBSTS_PCD:  u32 = 1 << 4;
+/// Controller Not Ready
+pub const USBSTS_CNR:  u32 = 1 << 11;
+/// Host Controller Error
+pub const USBSTS_HCE:  u32 = 1 << 12;
+
+/// Ring Cycle State
+pub const CRCR_RCS: u64 = 1 << 0;
+
+/// Current Connect Status
+pub const PORTSC_CCS: u32 = 1 << 0;
+/// Port Enabled, writing one disables the port
+pub const PORTSC_PED: u32 = 1 << 1;
+/// Over-current Active
+pub const PORTSC_OCA: u32 = 1 << 3;
+/// Port Reset
+pub const PORTSC_PR:  u32 = 1 << 4;
+/// Port Link State
+pub const PORTSC_PLS_MASK: u32 = 0xF << 5;
+/// Port Power
+pub const PORTSC_PP:  u32 = 1 << 9;
+/// Port Speed
+pub const PORTSC_SPEED_MASK:  u32 = 0xF << PORTSC_SPEED_SHIFT;
+pub const PORTSC_SPEED_SHIFT: u32 = 10;
+/// Connect Status Change
+pub const PORTSC_CSC: u32 = 1 << 17;
+/// Port Enabled/Disabled Change
+pub const PORTSC_PEC: u32 = 1 << 18;
+/// Warm Port Reset Change
+pub const PORTSC_WRC: u32 = 1 << 19;
+/// Over-current Change
+pub const PORTSC_OCC: u32 = 1 << 20;
+/// Port Reset Change
+pub const PORTSC_PRC: u32 = 1 << 21;
+/// Port Link State Change
+pub const PORTSC_PLC: u32 = 1 << 22;
+/// Port Config Error Change
+pub const PORTSC_CEC: u32 = 1 << 23;
+/// Port Indicator Control
+pub const PORTSC_PIC_MASK: u32 = 0x3 << 14;
+/// Wake on Connect, Disconnect and Over-current Enable
+pub const PORTSC_WAKE_MASK: u32 = 0x7 << 25;
+/// Device Removable
+pub const PORTSC_DR:  u32 = 1 << 30;
+/// The bits a write has to preserve, all others are cleared by writing one or are
+/// reserved
+pub const PORTSC_PRESERVE: u32 = PORTSC_CCS | PORTSC_OCA | PORTSC_PP | PORTSC_SPEED_MASK | PORTSC_PIC_MASK
+	| PORTSC_WAKE_MASK | PORTSC_DR;
+/// The change bits, cleared by writing one
+pub const PORTSC_CHANGES: u32 = PORTSC_CSC | PORTSC_PEC | PORTSC_WRC | PORTSC_OCC | PORTSC_PRC | PORTSC_PLC | PORTSC_CEC;
+
+/// Interrupt Pending
+pub const IMAN_IP: u32 = 1 << 0;
+/// Interrupt Enable
+pub const IMAN_IE: u32 = 1 << 1;
+/// Event Handler Busy, in the event ring dequeue pointer
+pub const ERDP_EHB: u64 = 1 << 3;
+
+/// Port speeds
+pub const SPEED_FULL:  u8 = 1;
+pub const SPEED_LOW:   u8 = 2;
+pub const SPEED_HIGH:  u8 = 3;
+pub const SPEED_SUPER: u8 = 4;
+
+/// TRB types
+pub const TRB_NORMAL:           u32 = 1;
+pub const TRB_SETUP:            u32 = 2;
+pub const TRB_DATA:             u32 = 3;
+pub const TRB_STATUS:           u32 = 4;
+pub const TRB_LINK:             u32 = 6;
+pub const TRB_NOOP:             u32 = 8;
+pub const TRB_ENABLE_SLOT:      u32 = 9;
+pub const TRB_DISABLE_SLOT:     u32 = 10;
+pub const TRB_ADDRESS_DEVICE:   u32 = 11;
+pub const TRB_CONFIGURE_EP:     u32 = 12;
+pub const TRB_EVALUATE_CONTEXT: u32 = 13;
+pub const TRB_RESET_EP:         u32 = 14;
+pub const TRB_STOP_EP:          u32 = 15;
+pub const TRB_SET_DEQUEUE:      u32 = 16;
+pub const TRB_NOOP_COMMAND:     u32 = 23;
+pub const TRB_TRANSFER_EVENT:   u32 = 32;
+pub const TRB_COMMAND_COMPLETION: u32 = 33;
+pub const TRB_PORT_STATUS_CHANGE: u32 = 34;
+pub const TRB_HOST_CONTROLLER:  u32 = 37;
+
+/// Cycle bit
+pub const TRB_CYCLE: u32 = 1 << 0;
+/// Toggle Cycle of link TRBs
+pub const TRB_TC:    u32 = 1 << 1;
+/// Interrupt on Short Packet
+pub const TRB_ISP:   u32 = 1 << 2;
+/// Chain bit
+pub const TRB_CH:    u32 = 1 << 4;
+/// Interrupt On Completion
+pub const TRB_IOC:   u32 = 1 << 5;
+/// Immediate Data, the parameter holds the data
+pub const TRB_IDT:   u32 = 1 << 6;
+/// Block Set Address Request of Address Device commands
+pub const TRB_BSR:   u32 = 1 << 9;
+pub const TRB_TYPE_SHIFT: u32 = 10;
+/// Direction of data and status stages, set for IN
+pub const TRB_DIR_IN: u32 = 1 << 16;
+/// Transfer Type of setup stages
+pub const TRB_TRT_NO_DATA: u32 = 0 << 16;
+pub const TRB_TRT_OUT:     u32 = 2 << 16;
+pub const TRB_TRT_IN:      u32 = 3 << 16;
+pub const TRB_EP_SHIFT:   u32 = 16;
+pub const TRB_SLOT_SHIFT: u32 = 24;
+
+/// Completion codes
+pub const COMPLETION_SUCCESS:      u8 = 1;
+pub const COMPLETION_TRANSACTION:  u8 = 4;
+pub const COMPLETION_TRB:          u8 = 5;
+pub const COMPLETION_STALL:        u8 = 6;
+pub const COMPLETION_NO_SLOTS:     u8 = 9;
+pub const COMPLETION_SHORT_PACKET: u8 = 13;
+
+/// Endpoint types of endpoint contexts
+pub const EP_TYPE_ISOCH_OUT:     u32 = 1;
+pub const EP_TYPE_BULK_OUT:      u32 = 2;
+pub const EP_TYPE_INTERRUPT_OUT: u32 = 3;
+pub const EP_TYPE_CONTROL:       u32 = 4;
+pub const EP_TYPE_ISOCH_IN:      u32 = 5;
+pub const EP_TYPE_BULK_IN:       u32 = 6;
+pub const EP_TYPE_INTERRUPT_IN:  u32 = 7;
+
+/// Standard requests
+pub const REQUEST_GET_STATUS:        u8 = 0x00;
+pub const REQUEST_CLEAR_FEATURE:     u8 = 0x01;
+pub const REQUEST_SET_FEATURE:       u8 = 0x03;
+pub const REQUEST_GET_DESCRIPTOR:    u8 = 0x06;
+pub const REQUEST_GET_CONFIGURATION: u8 = 0x08;
+pub const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+pub const REQUEST_SET_INTERFACE:     u8 = 0x0B;
+/// Feature selector of `REQUEST_CLEAR_FEATURE` on endpoints
+pub const FEATURE_ENDPOINT_HALT:     u16 = 0;
+
+/// `request_type` bits: direction, type and recipient
+pub const REQUEST_TYPE_IN:        u8 = 0x80;
+pub const REQUEST_TYPE_CLASS:     u8 = 0x20;
+pub const REQUEST_TYPE_INTERFACE: u8 = 0x01;
+pub const REQUEST_TYPE_ENDPOINT:  u8 = 0x02;
+
+/// Descriptor types
+pub const DESCRIPTOR_DEVICE:        u8 = 0x01;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+pub const DESCRIPTOR_STRING:        u8 = 0x03;
+pub const DESCRIPTOR_INTERFACE:     u8 = 0x04;
+pub const DESCRIPTOR_ENDPOINT:      u8 = 0x05;
+pub const DESCRIPTOR_HID:           u8 = 0x21;
+
+/// Endpoint transfer types in `bmAttributes`
+pub const ENDPOINT_CONTROL:   u8 = 0;
+pub const ENDPOINT_ISOCH:     u8 = 1;
+pub const ENDPOINT_BULK:      u8 = 2;
+pub const ENDPOINT_INTERRUPT: u8 = 3;
+/// IN endpoints have this bit set in their address
+pub const ENDPOINT_IN: u8 = 0x80;
+
+/// Interface classes
+pub const CLASS_HID:          u8 = 0x03;
+pub const CLASS_MASS_STORAGE: u8 = 0x08;
+pub const CLASS_HUB:          u8 = 0x09;
+
+#[repr(C)]
+pub struct CapabilityRegisters {
+	pub length:             u8,
+	pub _res0:              u8,
+	pub version:            u16,
+	pub structural_params1: u32,
+	pub structural_params2: u32,
+	pub structural_params3: u32,
+	pub capability_params1: u32,
+	pub doorbell_offset:    u32,
+	pub runtime_offset:     u32,
+	pub capability_params2: u32
+}
+
+/// 64-bit registers are split into two halves, the low one is written first.
+#[repr(C)]
+pub struct OperationalRegisters {
+	pub command:             u32,
+	pub status:              u32,
+	pub page_size:           u32,
+	pub _res0:               [u32; 2],
+	pub device_notification: u32,
+	pub command_ring:        [u32; 2],
+	pub _res1:               [u32; 4],
+	pub dcbaa:               [u32; 2],
+	pub config:              u32,
+	pub _res2:               [u32; 241],
+	pub ports:               [PortRegisters; 255]
+}
+
+#[repr(C)]
+pub struct PortRegisters {
+	pub status:    u32,
+	pub power:     u32,
+	pub link_info: u32,
+	pub lpm:       u32
+}
+
+#[repr(C)]
+pub struct Interrupter {
+	pub management:      u32,
+	pub moderation:      u32,
+	pub erst_size:       u32,
+	pub _res0:           u32,
+	pub erst_base:       [u32; 2],
+	pub dequeue:         [u32; 2]
+}
+
+#[repr(C)]
+pub struct RuntimeRegisters {
+	pub microframe_index: u32,
+	pub _res0:            [u32; 7],
+	pub interrupters:     [Interrupter; 1024]
+}
+
+/// Writes a 64-bit register as two halves, the low one first.
+///
+/// # Safety
+///
+/// `reg` must point to a 64-bit register.
+pub unsafe fn write64(reg: *mut [u32; 2], value: u64) {
+	let reg = reg as *mut u32;
+	reg.write_volatile(value as u32);
+	reg.add(1).write_volatile((value >> 32) as u32);
+}
+
+/// # Safety
+///
+/// `reg` must point to a 64-bit register.
+pub unsafe fn read64(reg: *mut [u32; 2]) -> u64 {
+	let reg = reg as *mut u32;
+	reg.read_volatile() as u64 | (reg.add(1).read_volatile() as u64) << 32
+}
+
+/// Transfer Request Block, the entries of all rings.
+#[repr(C, align(16))]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Trb {
+	pub parameter: u64,
+	pub status:    u32,
+	pub control:   u32
+}
+
+impl Trb {
+	pub fn new(kind: u32, parameter: u64, status: u32, flags: u32) -> Self {
+		Self { parameter, status, control: kind << TRB_TYPE_SHIFT | flags }
+	}
+
+	pub fn kind(&self) -> u32 {
+		(self.control >> TRB_TYPE_SHIFT) & 0x3F
+	}
+
+	pub fn cycle(&self) -> bool {
+		self.control & TRB_CYCLE != 0
+	}
+
+	/// Completion code of events
+	pub fn completion(&self) -> u8 {
+		(self.status >> 24) as u8
+	}
+
+	/// Slot ID of events and commands
+	pub fn slot(&self) -> u8 {
+		(self.control >> TRB_SLOT_SHIFT) as u8
+	}
+
+	/// Endpoint ID of transfer events
+	pub fn endpoint(&self) -> u8 {
+		((self.control >> TRB_EP_SHIFT) & 0x1F) as u8
+	}
+}
+
+/// The setup stage of a control transfer.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SetupPacket {
+	pub request_type: u8,
+	pub request:      u8,
+	pub value:        u16,
+	pub index:        u16,
+	pub length:       u16
+}
+
+impl SetupPacket {
+	pub fn get_descriptor(kind: u8, index: u8, length: u16) -> Self {
+		Self { request_type: REQUEST_TYPE_IN, request: REQUEST_GET_DESCRIPTOR, value: (kind as u16) << 8 | index as u16, index: 0, length }
+	}
+
+	/// The packet as immediate data of a setup TRB.
+	pub fn to_u64(&self) -> u64 {
+		self.request_type as u64 | (self.request as u64) << 8 | (self.value as u64) << 16
+			| (self.index as u64) << 32 | (self.length as u64) << 48
+	}
+
+	pub fn from_u64(v: u64) -> Self {
+		Self { request_type: v as u8, request: (v >> 8) as u8, value: (v >> 16) as u16, index: (v >> 32) as u16, length: (v >> 48) as u16 }
+	}
+
+	pub fn is_in(&self) -> bool {
+		self.request_type & REQUEST_TYPE_IN != 0
+	}
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceDescriptor {
+	pub usb_version:     u16,
+	pub class:           u8,
+	pub subclass:        u8,
+	pub protocol:        u8,
+	pub max_packet_size: u8,
+	pub vendor_id:       u16,
+	pub product_id:      u16,
+	pub device_version:  u16,
+	pub configurations:  u8
+}
+
+impl DeviceDescriptor {
+	pub const SIZE: usize = 18;
+
+	pub fn parse(b: &[u8]) -> Option<Self> {
+		if b.len() < Self::SIZE || b[1] != DESCRIPTOR_DEVICE {
+			return None;
+		}
+		let word = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+		Some(Self {
+			usb_version:     word(2),
+			class:           b[4],
+			subclass:        b[5],
+			protocol:        b[6],
+			max_packet_size: b[7],
+			vendor_id:       word(8),
+			product_id:      word(10),
+			device_version:  word(12),
+			configurations:  b[17]
+		})
+	}
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct EndpointDescriptor {
+	pub address:         u8,
+	pub attributes:      u8,
+	pub max_packet_size: u16,
+	pub interval:        u8
+}
+
+impl EndpointDescriptor {
+	pub fn is_in(&self) -> bool {
+		self.address & ENDPOINT_IN != 0
+	}
+
+	/// `ENDPOINT_*` transfer type
+	pub fn transfer_type(&self) -> u8 {
+		self.attributes & 0b11
+	}
+
+	/// Device Context Index of the endpoint: 1 for the default control endpoint, twice
+	/// the number for OUT and one more for IN endpoints.
+	pub fn dci(&self) -> u8 {
+		dci(self.address)
+	}
+}
+
+/// Device Context Index of an endpoint address.
+pub fn dci(address: u8) -> u8 {
+	match address & 0xF {
+		0 => 1,
+		n => 2 * n + (address & ENDPOINT_IN != 0) as u8
+	}
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InterfaceDescriptor {
+	pub number:    u8,
+	pub alternate: u8,
+	pub class:     u8,
+	pub subclass:  u8,
+	pub protocol:  u8,
+	pub endpoints: Vec<EndpointDescriptor>
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConfigurationDescriptor {
+	pub total_length: u16,
+	pub value:        u8,
+	pub attributes:   u8,
+	pub max_power:    u8,
+	pub interfaces:   Vec<InterfaceDescriptor>
+}
+
+impl ConfigurationDescriptor {
+	pub const SIZE: usize = 9;
+
+	/// Parses a configuration descriptor with the interface and endpoint descriptors that
+	/// follow it, other descriptors are skipped.
+	pub fn parse(b: &[u8]) -> Option<Self> {
+		if b.len() < Self::SIZE || b[1] != DESCRIPTOR_CONFIGURATION {
+			return None;
+		}
+		let mut config = Self {
+			total_length: u16::from_le_bytes([b[2], b[3]]),
+			value:        b[5],
+			attributes:   b[7],
+			max_power:    b[8],
+			interfaces:   Vec::new()
+		};
+
+		let mut off = b[0] as usize;
+		while off + 2 <= b.len() {
+			let (len, kind) = (b[off] as usize, b[off + 1]);
+			if len < 2 || off + len > b.len() {
+				break;
+			}
+			let d = &b[off..off + len];
+			match kind {
+				DESCRIPTOR_INTERFACE if len >= 9 => config.interfaces.push(InterfaceDescriptor {
+					number:    d[2],
+					alternate: d[3],
+					class:     d[5],
+					subclass:  d[6],
+					protocol:  d[7],
+					endpoints: Vec::new()
+				}),
+				DESCRIPTOR_ENDPOINT if len >= 7 => if let Some(interface) = config.interfaces.last_mut() {
+					interface.endpoints.push(EndpointDescriptor {
+						address:         d[2],
+						attributes:      d[3],
+						max_packet_size: u16::from_le_bytes([d[4], d[5]]) & 0x7FF,
+						interval:        d[6]
+					});
+				},
+				_ => ()
+			}
+			off += len;
+		}
+		Some(config)
+	}
+}
@@ -0,0 +1,145 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use {super::*, crate::dma::{Dma, Region}, core::{ptr::addr_of_mut, sync::atomic::{fence, Ordering}}};
+
+/// TRBs per ring segment
+pub const RING_TRBS: usize = 256;
+
+/// A ring software produces TRBs on, the command ring or a transfer ring. It is a single
+/// segment whose last TRB links back to the start and toggles the cycle state.
+pub struct Ring {
+	region:  Region,
+	enqueue: usize,
+	cycle:   bool
+}
+
+impl Ring {
+	pub fn new(dma: &mut impl Dma) -> Option<Self> {
+		let region = Region::alloc(dma, RING_TRBS * core::mem::size_of::<Trb>(), 64)?;
+		let ring = Self { region, enqueue: 0, cycle: true };
+		unsafe { ring.trb(RING_TRBS - 1).write_volatile(Trb::new(TRB_LINK, ring.region.phys, 0, TRB_TC)) };
+		Some(ring)
+	}
+
+	fn trb(&self, i: usize) -> *mut Trb {
+		unsafe { self.region.as_ptr::<Trb>().add(i) }
+	}
+
+	pub fn phys(&self) -> u64 {
+		self.region.phys
+	}
+
+	/// Where the next TRB goes and the cycle state it gets, for Set TR Dequeue Pointer.
+	pub fn enqueue_pointer(&self) -> u64 {
+		(self.region.phys + (self.enqueue * core::mem::size_of::<Trb>()) as u64) | self.cycle as u64
+	}
+
+	/// Hands a TRB to the controller, returns its physical address. The cycle bit is
+	/// written last, once the rest of the TRB is visible.
+	pub fn push(&mut self, trb: Trb) -> u64 {
+		let ptr = self.trb(self.enqueue);
+		let phys = self.region.phys + (self.enqueue * core::mem::size_of::<Trb>()) as u64;
+		unsafe {
+			addr_of_mut!((*ptr).parameter).write_volatile(trb.parameter);
+			addr_of_mut!((*ptr).status).write_volatile(trb.status);
+			fence(Ordering::Release);
+			addr_of_mut!((*ptr).control).write_volatile(trb.control & !TRB_CYCLE | self.cycle as u32);
+		}
+
+		self.enqueue += 1;
+		if self.enqueue == RING_TRBS - 1 {
+			// a TD continues across the link when its TRB is chained
+			let link = self.trb(RING_TRBS - 1);
+			unsafe {
+				fence(Ordering::Release);
+				addr_of_mut!((*link).control).write_volatile(TRB_LINK << TRB_TYPE_SHIFT | TRB_TC
+					| trb.control & TRB_CH | self.cycle as u32);
+			}
+			self.enqueue = 0;
+			self.cycle = !self.cycle;
+		}
+		phys
+	}
+
+	pub fn free(&self, dma: &mut impl Dma) {
+		dma.free(self.region.virt, self.region.size)
+	}
+}
+
+/// The event ring of an interrupter, a single segment the controller produces events on.
+pub struct EventRing {
+	segment: Region,
+	/// The event ring segment table
+	table:   Region,
+	dequeue: usize,
+	cycle:   bool
+}
+
+impl EventRing {
+	pub fn new(dma: &mut impl Dma) -> Option<Self> {
+		let segment = Region::alloc(dma, RING_TRBS * core::mem::size_of::<Trb>(), 64)?;
+		let table = match Region::alloc(dma, 16, 64) {
+			Some(table) => table,
+			None => {
+				segment.free(dma);
+				return None;
+			}
+		};
+		unsafe {
+			table.as_ptr::<u64>().write_volatile(segment.phys);
+			table.as_ptr::<u32>().add(2).write_volatile(RING_TRBS as u32);
+		}
+		Some(Self { segment, table, dequeue: 0, cycle: true })
+	}
+
+	pub fn table_phys(&self) -> u64 {
+		self.table.phys
+	}
+
+	/// The TRB software processes next, for the interrupter's dequeue pointer.
+	pub fn dequeue_pointer(&self) -> u64 {
+		self.segment.phys + (self.dequeue * core::mem::size_of::<Trb>()) as u64
+	}
+
+	/// Takes the next event, if the controller produced one.
+	pub fn pop(&mut self) -> Option<Trb> {
+		let ptr = unsafe { self.segment.as_ptr::<Trb>().add(self.dequeue) };
+		if unsafe { addr_of_mut!((*ptr).control).read_volatile() } & TRB_CYCLE != self.cycle as u32 {
+			return None;
+		}
+		fence(Ordering::Acquire);
+		let trb = unsafe { ptr.read_volatile() };
+		self.dequeue += 1;
+		if self.dequeue == RING_TRBS {
+			self.dequeue = 0;
+			self.cycle = !self.cycle;
+		}
+		Some(trb)
+	}
+
+	pub fn free(&self, dma: &mut impl Dma) {
+		for region in [&self.segment, &self.table] {
+			dma.free(region.virt, region.size);
+		}
+	}
+}
@@ -0,0 +1,430 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! USB mass storage with the Bulk-Only Transport and SCSI commands.
+//!
+//! Every command is a command block wrapper on the bulk OUT endpoint, the data stage in
+//! either direction and a command status wrapper on the bulk IN endpoint. `Transport`
+//! moves the wrappers, so `Disk` works with any way to reach the endpoints.
+
+use {super::*, crate::{block::{self, BlockDevice}, dma::Dma}};
+
+pub const SUBCLASS_SCSI:      u8 = 0x06;
+pub const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+/// Class requests
+pub const REQUEST_RESET:       u8 = 0xFF;
+pub const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+
+pub const CBW_SIGNATURE: u32 = 0x4342_5355;
+pub const CSW_SIGNATURE: u32 = 0x5342_5355;
+pub const CBW_SIZE: usize = 31;
+pub const CSW_SIZE: usize = 13;
+/// Data-In in the flags of command block wrappers
+pub const CBW_FLAG_IN: u8 = 0x80;
+
+/// Command status wrapper status
+pub const CSW_PASSED:      u8 = 0;
+pub const CSW_FAILED:      u8 = 1;
+pub const CSW_PHASE_ERROR: u8 = 2;
+
+/// SCSI operation codes
+pub const SCSI_TEST_UNIT_READY:  u8 = 0x00;
+pub const SCSI_REQUEST_SENSE:    u8 = 0x03;
+pub const SCSI_INQUIRY:          u8 = 0x12;
+pub const SCSI_MODE_SENSE_6:     u8 = 0x1A;
+pub const SCSI_READ_CAPACITY_10: u8 = 0x25;
+pub const SCSI_READ_10:          u8 = 0x28;
+pub const SCSI_WRITE_10:         u8 = 0x2A;
+pub const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+pub const SCSI_READ_16:          u8 = 0x88;
+pub const SCSI_WRITE_16:         u8 = 0x8A;
+/// SERVICE ACTION IN(16), READ CAPACITY(16) is its service action 0x10
+pub const SCSI_SERVICE_ACTION_IN_16: u8 = 0x9E;
+pub const SCSI_READ_CAPACITY_16: u8 = 0x10;
+
+/// Sense keys
+pub const SENSE_NO_SENSE:        u8 = 0x0;
+pub const SENSE_NOT_READY:       u8 = 0x2;
+pub const SENSE_MEDIUM_ERROR:    u8 = 0x3;
+pub const SENSE_ILLEGAL_REQUEST: u8 = 0x5;
+pub const SENSE_UNIT_ATTENTION:  u8 = 0x6;
+pub const SENSE_DATA_PROTECT:    u8 = 0x7;
+
+/// Write Protect in the device-specific parameter of mode parameter headers
+const MODE_WP: u8 = 0x80;
+/// TEST UNIT READY attempts while the unit reports a unit attention or becoming ready
+const READY_RETRIES: usize = 20;
+
+/// Moves data between the host and the bulk endpoints of a mass storage interface.
+pub trait Transport {
+	fn bulk_out(&mut self, data: &[u8]) -> Result<usize, Error>;
+
+	fn bulk_in(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+
+	/// Clears the halt of the bulk IN or OUT endpoint.
+	fn clear_halt(&mut self, is_in: bool) -> Result<(), Error>;
+
+	/// Sends a Bulk-Only Mass Storage Reset to the interface.
+	fn reset(&mut self) -> Result<(), Error>;
+
+	/// Waits between attempts of a command.
+	fn stall(&mut self, _us: u64) {}
+}
+
+/// Recovers from a phase error or an invalid command status wrapper.
+fn reset_recovery(t: &mut impl Transport) -> Result<(), Error> {
+	t.reset()?;
+	t.clear_halt(true)?;
+	t.clear_halt(false)
+}
+
+/// The Bulk-Only interface of a device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Interface {
+	pub interface: u8,
+	pub bulk_in:   u8,
+	pub bulk_out:  u8
+}
+
+/// Finds the SCSI Bulk-Only interface of a device.
+pub fn find(device: &Device) -> Option<Interface> {
+	device.interfaces()
+		.filter(|i| i.class == CLASS_MASS_STORAGE && i.subclass == SUBCLASS_SCSI && i.protocol == PROTOCOL_BULK_ONLY)
+		.find_map(|i| {
+			let bulk = |is_in| i.endpoints.iter()
+				.find(|e| e.transfer_type() == ENDPOINT_BULK && e.is_in() == is_in)
+				.map(|e| e.address);
+			Some(Interface { interface: i.number, bulk_in: bulk(true)?, bulk_out: bulk(false)? })
+		})
+}
+
+/// The transport of an interface on a controller.
+pub struct BulkOnly<'a, D: Dma> {
+	pub ctrl:      &'a mut Controller<D>,
+	pub slot:      u8,
+	pub interface: Interface
+}
+
+impl<D: Dma> BulkOnly<'_, D> {
+	/// The highest LUN of the device, devices with a single LUN may stall the request.
+	pub fn max_lun(&mut self) -> Result<u8, Error> {
+		let mut lun = [0];
+		let setup = SetupPacket {
+			request_type: REQUEST_TYPE_IN | REQUEST_TYPE_CLASS | REQUEST_TYPE_INTERFACE,
+			request:      REQUEST_GET_MAX_LUN,
+			value:        0,
+			index:        self.interface.interface as u16,
+			length:       1
+		};
+		match self.ctrl.control(self.slot, setup, &mut lun) {
+			Ok(1) => Ok(lun[0].min(15)),
+			Ok(_) | Err(Error::Stall) => Ok(0),
+			Err(e) => Err(e)
+		}
+	}
+}
+
+impl<D: Dma> Transport for BulkOnly<'_, D> {
+	fn bulk_out(&mut self, data: &[u8]) -> Result<usize, Error> {
+		self.ctrl.bulk_out(self.slot, self.interface.bulk_out, data)
+	}
+
+	fn bulk_in(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		self.ctrl.bulk_in(self.slot, self.interface.bulk_in, buf)
+	}
+
+	fn clear_halt(&mut self, is_in: bool) -> Result<(), Error> {
+		let endpoint = match is_in {
+			true  => self.interface.bulk_in,
+			false => self.interface.bulk_out
+		};
+		self.ctrl.clear_halt(self.slot, endpoint)
+	}
+
+	fn reset(&mut self) -> Result<(), Error> {
+		let setup = SetupPacket {
+			request_type: REQUEST_TYPE_CLASS | REQUEST_TYPE_INTERFACE,
+			request:      REQUEST_RESET,
+			value:        0,
+			index:        self.interface.interface as u16,
+			length:       0
+		};
+		self.ctrl.control(self.slot, setup, &mut []).map(|_| ())
+	}
+}
+
+/// The data stage of a command.
+pub enum Data<'a> {
+	None,
+	In(&'a mut [u8]),
+	Out(&'a [u8])
+}
+
+impl Data<'_> {
+	fn len(&self) -> usize {
+		match self {
+			Self::None   => 0,
+			Self::In(b)  => b.len(),
+			Self::Out(b) => b.len()
+		}
+	}
+}
+
+/// A logical unit, a SCSI direct access block device.
+pub struct Disk<T: Transport> {
+	pub transport: T,
+	pub lun:       u8,
+	pub vendor:    [u8; 8],
+	pub product:   [u8; 16],
+	block_size:    usize,
+	blocks:        u64,
+	read_only:     bool,
+	tag:           u32
+}
+
+impl<T: Transport> Disk<T> {
+	/// Identifies the unit, waits for it to become ready and reads its capacity.
+	pub fn new(transport: T, lun: u8) -> Result<Self, Error> {
+		let mut disk = Self {
+			transport,
+			lun,
+			vendor:     [0; 8],
+			product:    [0; 16],
+			block_size: 0,
+			blocks:     0,
+			read_only:  false,
+			tag:        0
+		};
+
+		let mut inquiry = [0; 36];
+		disk.command(&[SCSI_INQUIRY, 0, 0, 0, inquiry.len() as u8, 0], Data::In(&mut inquiry))?;
+		// peripheral qualifier and device type: a connected direct access device
+		if inquiry[0] != 0 {
+			return Err(Error::NoDevice);
+		}
+		disk.vendor.copy_from_slice(&inquiry[8..16]);
+		disk.product.copy_from_slice(&inquiry[16..32]);
+
+		let mut attempt = 0;
+		loop {
+			match disk.command(&[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], Data::None) {
+				Ok(_) => break,
+				Err(Error::Sense { key: SENSE_UNIT_ATTENTION | SENSE_NOT_READY, .. }) if attempt < READY_RETRIES => {
+					attempt += 1;
+					disk.transport.stall(100_000);
+				}
+				Err(e) => return Err(e)
+			}
+		}
+
+		let mut capacity = [0; 8];
+		disk.command(&[SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], Data::In(&mut capacity))?;
+		let last = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]) as u64;
+		disk.block_size = u32::from_be_bytes([capacity[4], capacity[5], capacity[6], capacity[7]]) as usize;
+		disk.blocks = last + 1;
+		if last == u32::MAX as u64 {
+			let mut capacity = [0; 32];
+			let mut cdb = [0; 16];
+			cdb[0] = SCSI_SERVICE_ACTION_IN_16;
+			cdb[1] = SCSI_READ_CAPACITY_16;
+			cdb[13] = capacity.len() as u8;
+			disk.command(&cdb, Data::In(&mut capacity))?;
+			let mut last = [0; 8];
+			last.copy_from_slice(&capacity[..8]);
+			disk.blocks = u64::from_be_bytes(last) + 1;
+			disk.block_size = u32::from_be_bytes([capacity[8], capacity[9], capacity[10], capacity[11]]) as usize;
+		}
+		if disk.block_size == 0 || !disk.block_size.is_power_of_two() || disk.block_size > MAX_TRANSFER {
+			return Err(Error::Protocol);
+		}
+
+		// not all devices implement MODE SENSE, they are assumed to be writable
+		let mut header = [0; 4];
+		if disk.command(&[SCSI_MODE_SENSE_6, 0, 0x3F, 0, header.len() as u8, 0], Data::In(&mut header)).is_ok() {
+			disk.read_only = header[2] & MODE_WP != 0;
+		}
+		Ok(disk)
+	}
+
+	/// Runs a SCSI command, returns the bytes transferred in the data stage. A failed
+	/// command returns its sense data.
+	pub fn command(&mut self, cdb: &[u8], mut data: Data) -> Result<usize, Error> {
+		match self.transfer(cdb, &mut data)? {
+			(n, CSW_PASSED) => Ok(n),
+			(_, _) => Err(self.sense())
+		}
+	}
+
+	fn sense(&mut self) -> Error {
+		let mut sense = [0; 18];
+		match self.transfer(&[SCSI_REQUEST_SENSE, 0, 0, 0, sense.len() as u8, 0], &mut Data::In(&mut sense)) {
+			Ok((n, CSW_PASSED)) if n >= 14 => Error::Sense { key: sense[2] & 0xF, asc: sense[12], ascq: sense[13] },
+			Ok(_) => Error::Protocol,
+			Err(e) => e
+		}
+	}
+
+	/// Sends the command block wrapper, transfers the data and receives the status,
+	/// returns the bytes transferred and the status.
+	fn transfer(&mut self, cdb: &[u8], data: &mut Data) -> Result<(usize, u8), Error> {
+		if cdb.is_empty() || cdb.len() > 16 {
+			return Err(Error::InvalidArgument);
+		}
+		self.tag = self.tag.wrapping_add(1);
+		let len = data.len();
+		let mut cbw = [0; CBW_SIZE];
+		cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+		cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+		cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+		cbw[12] = if let Data::In(_) = data { CBW_FLAG_IN } else { 0 };
+		cbw[13] = self.lun;
+		cbw[14] = cdb.len() as u8;
+		cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+
+		if let Err(e) = self.transport.bulk_out(&cbw) {
+			// the device didn't accept the command block
+			if e == Error::Stall {
+				let _ = reset_recovery(&mut self.transport);
+			}
+			return Err(e);
+		}
+
+		// a stalled data stage ends it early, the status follows
+		let n = match data {
+			Data::None    => Ok(0),
+			Data::In(b)   => self.transport.bulk_in(b),
+			Data::Out(b)  => self.transport.bulk_out(b)
+		};
+		let n = match n {
+			Ok(n) => n,
+			Err(Error::Stall) => {
+				self.transport.clear_halt(matches!(data, Data::In(_)))?;
+				0
+			}
+			Err(e) => return Err(e)
+		};
+
+		let mut csw = [0; CSW_SIZE];
+		let received = match self.transport.bulk_in(&mut csw) {
+			Err(Error::Stall) => {
+				self.transport.clear_halt(true)?;
+				self.transport.bulk_in(&mut csw)
+			}
+			r => r
+		}?;
+		let signature = u32::from_le_bytes([csw[0], csw[1], csw[2], csw[3]]);
+		let tag = u32::from_le_bytes([csw[4], csw[5], csw[6], csw[7]]);
+		if received != CSW_SIZE || signature != CSW_SIGNATURE || tag != self.tag || csw[12] == CSW_PHASE_ERROR {
+			let _ = reset_recovery(&mut self.transport);
+			return Err(Error::Protocol);
+		}
+		Ok((n, csw[12]))
+	}
+
+	/// READ or WRITE of the 10 or 16 byte variant, whichever fits the range.
+	fn rw_cdb(&self, write: bool, lba: u64, blocks: u32) -> ([u8; 16], usize) {
+		let mut cdb = [0; 16];
+		match lba + blocks as u64 <= u32::MAX as u64 + 1 && blocks <= u16::MAX as u32 {
+			true => {
+				cdb[0] = if write { SCSI_WRITE_10 } else { SCSI_READ_10 };
+				cdb[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+				cdb[7..9].copy_from_slice(&(blocks as u16).to_be_bytes());
+				(cdb, 10)
+			}
+			false => {
+				cdb[0] = if write { SCSI_WRITE_16 } else { SCSI_READ_16 };
+				cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+				cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+				(cdb, 16)
+			}
+		}
+	}
+}
+
+impl<T: Transport> BlockDevice for Disk<T> {
+	fn block_size(&self) -> usize {
+		self.block_size
+	}
+
+	fn blocks(&self) -> u64 {
+		self.blocks
+	}
+
+	fn read(&mut self, mut lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		for chunk in buf.chunks_mut(MAX_TRANSFER) {
+			let blocks = (chunk.len() / self.block_size) as u32;
+			let (cdb, len) = self.rw_cdb(false, lba, blocks);
+			if self.command(&cdb[..len], Data::In(chunk))? != chunk.len() {
+				return Err(block::Error::Io);
+			}
+			lba += blocks as u64;
+		}
+		Ok(())
+	}
+
+	fn write(&mut self, mut lba: u64, buf: &[u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		if self.read_only {
+			return Err(block::Error::ReadOnly);
+		}
+		for chunk in buf.chunks(MAX_TRANSFER) {
+			let blocks = (chunk.len() / self.block_size) as u32;
+			let (cdb, len) = self.rw_cdb(true, lba, blocks);
+			if self.command(&cdb[..len], Data::Out(chunk))? != chunk.len() {
+				return Err(block::Error::Io);
+			}
+			lba += blocks as u64;
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		match self.command(&[SCSI_SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], Data::None) {
+			// devices without a write cache
+			Ok(_) | Err(Error::Sense { key: SENSE_ILLEGAL_REQUEST, .. }) => Ok(()),
+			Err(e) => Err(e.into())
+		}
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	fn max_blocks(&self) -> u64 {
+		(MAX_TRANSFER / self.block_size) as u64
+	}
+}
+
+impl<T: Transport> core::fmt::Debug for Disk<T> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Disk")
+			.field("lun", &self.lun)
+			.field("vendor", &core::str::from_utf8(&self.vendor).unwrap_or("").trim_end())
+			.field("product", &core::str::from_utf8(&self.product).unwrap_or("").trim_end())
+			.field("block_size", &self.block_size)
+			.field("blocks", &self.blocks)
+			.field("read_only", &self.read_only)
+			.finish()
+	}
+}
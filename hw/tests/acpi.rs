@@ -0,0 +1,307 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use common::AcpiWindow;
+use hw::acpi::*;
+
+fn tables(window: &AcpiWindow) -> Vec<Table<'static>> {
+	match window.rsdp().get_xsdt().expect("no XSDT") {
+		Table::Xsdt(xsdt) => xsdt.into_iter().collect(),
+		t => panic!("expected XSDT, got {:?}", t)
+	}
+}
+
+fn fadt(window: &AcpiWindow) -> &'static FADT {
+	tables(window).into_iter().find_map(|t| match t { Table::Fadt(v) => Some(v), _ => None }).expect("no FADT")
+}
+
+fn madt(window: &AcpiWindow) -> &'static MADT {
+	tables(window).into_iter().find_map(|t| match t { Table::Apic(v) => Some(v), _ => None }).expect("no MADT")
+}
+
+fn mcfg(window: &AcpiWindow) -> &'static MCFG {
+	tables(window).into_iter().find_map(|t| match t { Table::Mcfg(v) => Some(v), _ => None }).expect("no MCFG")
+}
+
+/// Loads a single table as dumped by the kernel under `/sys/firmware/acpi/tables`.
+fn raw(name: &str) -> &'static DescHeader {
+	let table = unsafe { (common::load(name).as_ptr() as *const DescHeader).as_ref() }.unwrap();
+	assert!(table.is_valid(), "{}", name);
+	table
+}
+
+#[test]
+fn rsdp() {
+	for name in ["acpi/q35.bin", "acpi/virt.bin"] {
+		let window = AcpiWindow::load(name);
+		let rsdp = window.rsdp();
+		assert!(rsdp.is_valid(), "{}", name);
+		assert_eq!({ rsdp.revision }, 2);
+		assert_eq!({ rsdp.length }, 36);
+		assert_eq!(&rsdp.oem_id, b"BOCHS ");
+	}
+
+	let window = AcpiWindow::load("acpi/q35.bin");
+	window.mem[10] ^= 0xFF;
+	assert!(!window.rsdp().is_valid());
+}
+
+#[test]
+fn rsdt() {
+	let window = AcpiWindow::load("acpi/q35.bin");
+	let rsdt = window.header(window.rsdt);
+	assert_eq!(&{ rsdt.signature }.to_le_bytes(), b"RSDT");
+	assert!(rsdt.is_valid());
+
+	let entries = ({ rsdt.length } as usize - 36) / 4;
+	let signatures = (0..entries)
+		.map(|i| common::read::<u32>(window.mem, window.offset(window.rsdt) + 36 + i * 4) as u64)
+		.map(|addr| { window.header(addr).signature }.to_le_bytes())
+		.collect::<Vec<_>>();
+	assert_eq!(signatures, [*b"FACP", *b"APIC", *b"HPET", *b"MCFG"]);
+
+	assert_eq!(AcpiWindow::load("acpi/virt.bin").rsdt, 0);
+}
+
+#[test]
+fn xsdt() {
+	let window = AcpiWindow::load("acpi/q35.bin");
+	assert!(matches!(tables(&window)[..], [Table::Fadt(_), Table::Apic(_), Table::Hpet(_), Table::Mcfg(_)]));
+
+	let window = AcpiWindow::load("acpi/virt.bin");
+	let entries = tables(&window);
+	assert!(matches!(entries[..], [Table::Fadt(_), Table::Apic(_), Table::Mcfg(_)]));
+
+	for t in entries {
+		let header = match t {
+			Table::Fadt(v) => v as *const FADT as *const DescHeader,
+			Table::Apic(v) => v as *const MADT as *const DescHeader,
+			Table::Mcfg(v) => v as *const MCFG as *const DescHeader,
+			_ => unreachable!()
+		};
+		let header = unsafe { &*header };
+		assert!(header.is_valid());
+	}
+}
+
+#[test]
+fn fadt_q35() {
+	let window = AcpiWindow::load("acpi/q35.bin");
+	let fadt = fadt(&window);
+	assert_eq!({ fadt.header.revision }, 3);
+	assert_eq!({ fadt.header.length }, 244);
+	assert_eq!({ fadt.sci_int }, 9);
+	assert_eq!({ fadt.smi_cmd }, 0xB2);
+	assert_eq!({ fadt.pm1a_evt_blk }, 0x600);
+	assert_eq!({ fadt.pm1a_cnt_blk }, 0x604);
+	assert_eq!({ fadt.pm_tmr_blk }, 0x608);
+	assert_eq!({ fadt.flags }, 0x84A5);
+	assert_eq!({ fadt.reset_reg.address_space }, 1);
+	assert_eq!({ fadt.reset_reg.address }, 0xCF9);
+	assert_eq!({ fadt.reset_value }, 0x0F);
+	assert_eq!({ fadt.x_pm1a_cnt_blk.address }, 0x604);
+	assert_eq!({ fadt.x_gpe0_blk.address }, 0x620);
+
+	let dsdt = fadt.dsdt().expect("no DSDT");
+	assert_eq!(&{ dsdt.header.signature }.to_le_bytes(), b"DSDT");
+	assert!(dsdt.header.is_valid());
+	assert_eq!(dsdt.definition_block().len(), { dsdt.header.length } as usize - 36);
+}
+
+#[test]
+fn fadt_virt() {
+	let window = AcpiWindow::load("acpi/virt.bin");
+	let fadt = fadt(&window);
+	assert_eq!({ fadt.header.revision }, 6);
+	assert_eq!({ fadt.flags } & (1 << 20), 1 << 20);
+	assert_eq!({ fadt.arm_boot_arch }, 3);
+	assert!(fadt.firmware_ctrl().is_none());
+	assert!(fadt.dsdt().is_some());
+}
+
+#[test]
+fn madt_q35() {
+	let window = AcpiWindow::load("acpi/q35.bin");
+	let madt = madt(&window);
+	assert_eq!(madt.local_interrupt_controller_addr.as_ptr() as usize, 0xFEE00000);
+
+	let entries = madt.into_iter().collect::<Vec<_>>();
+	assert_eq!(entries.len(), 4 + 1 + 5 + 1);
+
+	for (i, e) in entries[..4].iter().enumerate() {
+		match e {
+			MadtInterruptController::ProcessorLocalApic(v) => {
+				assert_eq!(v.apic_id as usize, i);
+				assert_eq!({ v.flags }, 1);
+			}
+			e => panic!("expected a local APIC, got {:?}", e)
+		}
+	}
+
+	match entries[4] {
+		MadtInterruptController::IoApic(v) => {
+			assert_eq!({ v.io_apic_addr }.as_ptr() as usize, 0xFEC00000);
+			assert_eq!({ v.global_system_int_base }, 0);
+		}
+		e => panic!("expected an I/O APIC, got {:?}", e)
+	}
+
+	let overrides = entries[5..10].iter().map(|e| match e {
+		MadtInterruptController::InterruptSourceOverride(v) => (v.source, { v.global_system_int }, { v.flags }),
+		e => panic!("expected an interrupt source override, got {:?}", e)
+	}).collect::<Vec<_>>();
+	assert_eq!(overrides, [(0, 2, 0), (5, 5, 0xD), (9, 9, 0xD), (10, 10, 0xD), (11, 11, 0xD)]);
+
+	assert!(matches!(entries[10], MadtInterruptController::LocalApicNmi(v) if v.acpi_processor_uid == 0xFF && v.local_apic_lint == 1));
+}
+
+#[test]
+fn madt_virt() {
+	let window = AcpiWindow::load("acpi/virt.bin");
+	let entries = madt(&window).into_iter().collect::<Vec<_>>();
+	assert_eq!(entries.len(), 1 + 4 + 1 + 1);
+
+	assert!(matches!(entries[0], MadtInterruptController::GicDistributorInterface(v)
+		if { v.physical_base_addr } == 0x08000000 && v.gic_version == 3));
+
+	for (i, e) in entries[1..5].iter().enumerate() {
+		match e {
+			MadtInterruptController::GicCpuInterface(v) => {
+				assert_eq!({ v.cpu_interface_number } as usize, i);
+				assert_eq!({ v.mpidr } as usize, i);
+				assert_eq!({ v.performance_int_gisv }, 23);
+				assert_eq!({ v.vgic_maintenance_int }, 25);
+			}
+			e => panic!("expected a GICC, got {:?}", e)
+		}
+	}
+
+	assert!(matches!(entries[5], MadtInterruptController::GicRedistributor(v)
+		if { v.discovery_range_base_addr } == 0x080A0000 && { v.discovery_range_length } == 0x00F60000));
+	assert!(matches!(entries[6], MadtInterruptController::GicInterruptTranslationService(v)
+		if { v.physical_base_addr } == 0x08080000));
+}
+
+#[test]
+fn hpet() {
+	let window = AcpiWindow::load("acpi/q35.bin");
+	let hpet = tables(&window).into_iter().find_map(|t| match t { Table::Hpet(v) => Some(v), _ => None }).expect("no HPET");
+	assert!(unsafe { &*(hpet as *const HPET as *const DescHeader) }.is_valid());
+	assert_eq!({ hpet.event_timer_block_id }, 0x8086A201);
+	assert_eq!({ hpet.base_address.address }, 0xFED00000);
+}
+
+#[test]
+fn mcfg_entries() {
+	for (name, addr) in [("acpi/q35.bin", 0xB0000000), ("acpi/virt.bin", 0x4010000000)] {
+		let window = AcpiWindow::load(name);
+		let entries = mcfg(&window).into_iter().copied().collect::<Vec<_>>();
+		assert_eq!(entries.len(), 1, "{}", name);
+		assert_eq!({ entries[0].address }.as_ptr() as usize, addr);
+		assert_eq!({ entries[0].pci_segment }, 0);
+		assert_eq!(entries[0].start_bus_number, 0);
+		assert_eq!(entries[0].end_bus_number, 0xFF);
+	}
+}
+
+#[test]
+fn srat() {
+	let window = AcpiWindow::load("acpi/q35-numa.bin");
+	let srat = tables(&window).into_iter().find_map(|t| match t { Table::Srat(v) => Some(v), _ => None }).expect("no SRAT");
+	assert!(srat.header.is_valid());
+
+	// the generic initiator is skipped
+	let entries = srat.into_iter().collect::<Vec<_>>();
+	assert_eq!(entries.len(), 5 + 5 + 1);
+
+	let cpus = entries[..5].iter().map(|e| match e {
+		StaticResourceAllocation::ProcessorLocalApicSapicAffinity(v) => (v.apic_id, e.proximity_domain(), e.is_enabled()),
+		e => panic!("expected a local APIC affinity, got {:?}", e)
+	}).collect::<Vec<_>>();
+	assert_eq!(cpus, [(0, 0, true), (1, 0, true), (2, 1, true), (3, 1, true), (4, 1, false)]);
+
+	let mem = entries[5..10].iter().map(|e| match e {
+		StaticResourceAllocation::MemoryAffinity(v) => ({ v.base_address }, { v.range_length }, e.proximity_domain(), { v.flags }),
+		e => panic!("expected a memory affinity, got {:?}", e)
+	}).collect::<Vec<_>>();
+	assert_eq!(mem, [
+		(0x0, 0xA0000, 0, 1),
+		(0x100000, 0x7FF00000, 0, 1),
+		(0x80000000, 0x80000000, 1, 1),
+		(0x100000000, 0x80000000, 1, 1),
+		(0x180000000, 0x100000000, 1, StaticResourceAllocation::FLAG_ENABLED | StaticResourceAllocation::FLAG_HOT_PLUGGABLE)
+	]);
+
+	assert!(matches!(entries[10], StaticResourceAllocation::ProcessorLocalX2ApicAffinity(v)
+		if { v.x2_apic_id } == 0x100 && { v.proximity_domain } == 1));
+}
+
+#[test]
+fn slit() {
+	let window = AcpiWindow::load("acpi/q35-numa.bin");
+	let slit = tables(&window).into_iter().find_map(|t| match t { Table::Slit(v) => Some(v), _ => None }).expect("no SLIT");
+	assert_eq!(slit.localities(), 2);
+	assert_eq!(slit.distance(0, 0), Some(SLIT::LOCAL_DISTANCE));
+	assert_eq!(slit.distance(0, 1), Some(21));
+	assert_eq!(slit.distance(1, 0), Some(21));
+	assert_eq!(slit.distance(1, 1), Some(SLIT::LOCAL_DISTANCE));
+	assert_eq!(slit.distance(2, 0), None);
+
+	// a locality count exceeding the table is clamped
+	let offset = slit as *const SLIT as usize - window.mem.as_ptr() as usize;
+	window.mem[offset + 36] = 3;
+	assert_eq!(slit.localities(), 2);
+}
+
+/// The tables of a Firecracker guest, dumped rather than generated.
+#[test]
+fn firecracker() {
+	let Table::Fadt(fadt) = raw("acpi/firecracker/facp.aml").into() else { panic!("expected FADT") };
+	assert_eq!({ fadt.header.revision }, 6);
+	assert_eq!({ fadt.fadt_minor_version }, 5);
+	assert!(fadt.is_hw_reduced());
+	assert_eq!({ fadt.flags }, FADT::FLAG_HW_REDUCED_ACPI | FADT::FLAG_PWR_BUTTON | FADT::FLAG_SLP_BUTTON);
+	assert_eq!({ fadt.dsdt }.as_ptr() as usize, 0);
+	assert_eq!({ fadt.x_dsdt }.as_ptr() as usize, 0x9FD30);
+	assert_eq!(fadt.reset_register(), None);
+	assert_eq!(fadt.pm1a_cnt_blk(), None);
+	assert_eq!(fadt.pm_tmr_blk(), None);
+	assert_eq!(&{ fadt.hypervisor_vendor_id }.to_le_bytes(), b"FIRECKVM");
+
+	let Table::Apic(madt) = raw("acpi/firecracker/apic.aml").into() else { panic!("expected MADT") };
+	assert_eq!(madt.local_interrupt_controller_addr.as_ptr() as usize, 0xFEE00000);
+	let entries = madt.into_iter().collect::<Vec<_>>();
+	assert_eq!(entries.len(), 2);
+	assert!(matches!(entries[0], MadtInterruptController::IoApic(v)
+		if { v.io_apic_addr }.as_ptr() as usize == 0xFEC00000 && { v.global_system_int_base } == 0));
+	assert!(matches!(entries[1], MadtInterruptController::ProcessorLocalApic(v) if v.apic_id == 0 && { v.flags } == 1));
+
+	let Table::Mcfg(mcfg) = raw("acpi/firecracker/mcfg.aml").into() else { panic!("expected MCFG") };
+	let entries = mcfg.into_iter().copied().collect::<Vec<_>>();
+	assert_eq!(entries.len(), 1);
+	assert_eq!({ entries[0].address }.as_ptr() as usize, 0xEEC00000);
+	assert_eq!((entries[0].start_bus_number, entries[0].end_bus_number), (0, 0));
+
+	assert!(matches!(raw("acpi/firecracker/dsdt.aml").into(), Table::Dsdt(_)));
+}
@@ -0,0 +1,411 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{alloc::Layout, cell::RefCell, collections::BTreeMap, rc::Rc};
+use hw::{dma::{Dma, PAGE_SIZE}, ahci::*};
+
+const PORTS: usize = 4;
+const MODEL: &[u8; 40] = b"QEMU HARDDISK                           ";
+
+struct MockDisk {
+	data:   Vec<u8>,
+	ncq:    bool,
+	/// Commands touching this LBA fail with IDNF
+	bad:    Option<u64>
+}
+
+#[derive(Default)]
+struct MockPort {
+	disk:      Option<MockDisk>,
+	ci:        u32,
+	sact:      u32,
+	is:        u32,
+	failed:    bool,
+	/// Commands in the order they were executed
+	commands:  Vec<u8>,
+	/// Most queued commands in flight at once
+	max_queued: u32
+}
+
+/// Emulates an HBA with `PORTS` ports. The device executes commands whenever the driver
+/// stalls, unless `hold` is set.
+#[derive(Default)]
+struct State {
+	regs:   Vec<u32>,
+	ports:  Vec<MockPort>,
+	allocs: BTreeMap<usize, Layout>,
+	hold:   bool
+}
+
+#[derive(Clone)]
+struct Mock(Rc<RefCell<State>>);
+
+fn port_reg(i: usize, off: usize) -> usize {
+	(0x100 + i * 0x80 + off) / 4
+}
+
+impl Mock {
+	fn new(cap: u32) -> Self {
+		let mut state = State { regs: vec![0; 0x1100 / 4], ..State::default() };
+		state.regs[0] = cap | (PORTS as u32 - 1);
+		state.regs[3] = (1 << PORTS) - 1;
+		state.regs[4] = 0x0001_0300;
+		state.ports = (0..PORTS).map(|_| MockPort::default()).collect();
+		state.ports[0].disk = Some(MockDisk { data: vec![0; 512 * 1024], ncq: true, bad: None });
+		state.ports[1].disk = Some(MockDisk { data: vec![0; 512 * 256], ncq: false, bad: None });
+		Self(Rc::new(RefCell::new(state)))
+	}
+
+	fn regs(&self) -> *mut u8 {
+		self.0.borrow_mut().regs.as_mut_ptr() as *mut u8
+	}
+
+	/// Plugs a disk into or pulls it out of a port.
+	fn plug(&self, i: usize, disk: Option<MockDisk>) {
+		let mut state = self.0.borrow_mut();
+		state.ports[i].disk = disk;
+		state.ports[i].is |= PXIS_PCS | PXIS_PRCS;
+		state.process();
+	}
+}
+
+fn identify(disk: &MockDisk) -> [u16; 256] {
+	let mut id = [0u16; 256];
+	for (i, c) in MODEL.chunks(2).enumerate() {
+		id[27 + i] = u16::from_be_bytes([c[0], c[1]]);
+	}
+	for (i, c) in b"QM00001             ".chunks(2).enumerate() {
+		id[10 + i] = u16::from_be_bytes([c[0], c[1]]);
+	}
+	let sectors = (disk.data.len() / 512) as u64;
+	id[60] = sectors as u16;
+	id[61] = (sectors >> 16) as u16;
+	id[75] = 31;
+	id[76] = if disk.ncq { 1 << 8 } else { 0 };
+	id[83] = 1 << 10 | 1 << 13;
+	id[85] = 1 << 5;
+	for i in 0..4 {
+		id[100 + i] = (sectors >> (16 * i)) as u16;
+	}
+	id
+}
+
+impl State {
+	fn process(&mut self) {
+		if self.regs[1] & GHC_HR != 0 {
+			self.regs[1] = 0;
+		}
+
+		let mut pending = 0;
+		for i in 0..PORTS {
+			// registers the driver wrote since the last time
+			let is = self.regs[port_reg(i, 0x10)];
+			if is != self.ports[i].is {
+				self.ports[i].is &= !is;
+			}
+			let port = &mut self.ports[i];
+			for (off, shadow) in [(0x34, &mut port.sact), (0x38, &mut port.ci)] {
+				let v = self.regs[port_reg(i, off)];
+				if v != *shadow {
+					*shadow |= v;
+				}
+			}
+
+			let cmd = self.regs[port_reg(i, 0x18)];
+			let started = cmd & PXCMD_ST != 0;
+			self.regs[port_reg(i, 0x18)] = cmd & !(PXCMD_CR | PXCMD_FR)
+				| if started { PXCMD_CR } else { 0 } | if cmd & PXCMD_FRE != 0 { PXCMD_FR } else { 0 };
+			if !started {
+				let port = &mut self.ports[i];
+				port.ci = 0;
+				port.sact = 0;
+				port.failed = false;
+			}
+
+			let present = self.ports[i].disk.is_some();
+			self.regs[port_reg(i, 0x28)] = if present { 0x113 } else { 0 };
+			self.regs[port_reg(i, 0x24)] = if present { SIG_ATA } else { !0 };
+
+			if started && !self.hold {
+				self.execute(i);
+			}
+
+			let port = &self.ports[i];
+			self.regs[port_reg(i, 0x20)] = match (present, port.failed) {
+				(true, true)   => 0x51 | 0x10 << 8,
+				(true, false)  => 0x50,
+				(false, _)     => 0x7F
+			};
+			self.regs[port_reg(i, 0x10)] = port.is;
+			self.regs[port_reg(i, 0x34)] = port.sact;
+			self.regs[port_reg(i, 0x38)] = port.ci;
+			if port.is & self.regs[port_reg(i, 0x14)] != 0 {
+				pending |= 1 << i;
+			}
+		}
+		self.regs[2] = if self.regs[1] & GHC_IE != 0 { pending } else { 0 };
+	}
+
+	fn execute(&mut self, i: usize) {
+		let clb = self.regs[port_reg(i, 0)] as u64 | (self.regs[port_reg(i, 4)] as u64) << 32;
+		let port = &mut self.ports[i];
+		port.max_queued = port.max_queued.max(port.sact.count_ones());
+
+		while !port.failed && port.ci != 0 {
+			let slot = port.ci.trailing_zeros();
+			let header = unsafe { (clb as *mut u32).add(slot as usize * 8) };
+			let (dw0, ctba) = unsafe { (header.read(), header.add(2).read() as u64 | (header.add(3).read() as u64) << 32) };
+			let fis = unsafe { (ctba as *const FisRegH2d).read() };
+			let prds = (0..dw0 >> 16).map(|p| unsafe {
+				let prd = (ctba as *const u32).add(0x20 + p as usize * 4);
+				(prd.read() as u64 | (prd.add(1).read() as u64) << 32, (prd.add(3).read() & PRD_INFO_DBC_MASK) as usize + 1)
+			}).collect::<Vec<_>>();
+			port.commands.push(fis.command);
+
+			let disk = port.disk.as_mut().unwrap();
+			let queued = matches!(fis.command, FisRegH2d::ATA_READ_FPDMA_QUEUED | FisRegH2d::ATA_WRITE_FPDMA_QUEUED);
+			let count = match queued {
+				true  => fis.features as usize | (fis.features_exp as usize) << 8,
+				false => fis.count as usize | (fis.count_exp as usize) << 8
+			};
+			let range = fis.lba() as usize * 512..(fis.lba() as usize + count) * 512;
+			assert!(!queued || (fis.count >> 3) as u32 == slot && port.sact & 1 << slot != 0);
+			assert_eq!(dw0 & CMD_HEADER_FLAG_W as u32 != 0, matches!(fis.command, 0x35 | 0x61));
+
+			let ok = match fis.command {
+				FisRegH2d::ATA_IDENTIFY_DEVICE => {
+					let id = identify(disk);
+					unsafe { std::ptr::copy_nonoverlapping(id.as_ptr() as *const u8, prds[0].0 as *mut u8, 512) };
+					true
+				}
+				_ if range.end > disk.data.len() || disk.bad.map_or(false, |b| range.contains(&(b as usize * 512))) => false,
+				FisRegH2d::ATA_READ_DMA_EXT | FisRegH2d::ATA_READ_FPDMA_QUEUED => {
+					let mut off = range.start;
+					for (addr, len) in &prds {
+						unsafe { std::ptr::copy_nonoverlapping(disk.data.as_ptr().add(off), *addr as *mut u8, *len) };
+						off += len;
+					}
+					off == range.end
+				}
+				FisRegH2d::ATA_WRITE_DMA_EXT | FisRegH2d::ATA_WRITE_FPDMA_QUEUED => {
+					let mut off = range.start;
+					for (addr, len) in &prds {
+						unsafe { std::ptr::copy_nonoverlapping(*addr as *const u8, disk.data.as_mut_ptr().add(off), *len) };
+						off += len;
+					}
+					off == range.end
+				}
+				FisRegH2d::ATA_FLUSH_CACHE_EXT => true,
+				_ => false
+			};
+
+			if !ok {
+				port.failed = true;
+				port.is |= PXIS_TFES;
+				break;
+			}
+			port.ci &= !(1 << slot);
+			match queued {
+				true  => {
+					port.sact &= !(1 << slot);
+					port.is |= PXIS_SDBS;
+				}
+				false => port.is |= PXIS_DHRS
+			}
+		}
+	}
+}
+
+impl Dma for Mock {
+	fn alloc(&mut self, size: usize, align: usize) -> Option<(*mut u8, u64)> {
+		let layout = Layout::from_size_align(size, align).unwrap();
+		let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+		self.0.borrow_mut().allocs.insert(ptr as usize, layout);
+		Some((ptr, ptr as u64))
+	}
+
+	fn free(&mut self, virt: *mut u8, _size: usize) {
+		let layout = self.0.borrow_mut().allocs.remove(&(virt as usize)).expect("double free");
+		unsafe { std::alloc::dealloc(virt, layout) };
+	}
+
+	fn phys(&mut self, virt: *const u8) -> u64 {
+		virt as u64
+	}
+
+	fn stall(&mut self, _us: u64) {
+		self.0.borrow_mut().process();
+	}
+}
+
+fn controller() -> (Mock, Controller<Mock>) {
+	let mock = Mock::new(CAP_S64A | CAP_SNCQ | CAP_SSS | 31 << CAP_NCS_SHIFT);
+	let ctrl = unsafe { Controller::new(mock.regs(), mock.clone()) }.unwrap();
+	(mock, ctrl)
+}
+
+/// A buffer starting `offset` bytes into a page.
+fn buffer(len: usize, offset: usize) -> (Vec<u8>, usize) {
+	let buf = vec![0u8; len + 2 * PAGE_SIZE];
+	let start = (PAGE_SIZE - buf.as_ptr() as usize % PAGE_SIZE) % PAGE_SIZE + offset;
+	(buf, start)
+}
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<HbaPort>(), 0x80);
+	assert_eq!(core::mem::size_of::<Hba>(), 0x1100);
+	assert_eq!(core::mem::size_of::<AhciCommandHeader>(), 32);
+	assert_eq!(core::mem::size_of::<AhciPrd>(), 16);
+	assert_eq!(core::mem::size_of::<FisRegH2d>(), 20);
+
+	let fis = FisRegH2d::queued(FisRegH2d::ATA_READ_FPDMA_QUEUED, 0x0102_0304_0506, 0x0180, 5);
+	assert_eq!((fis.features, fis.features_exp, fis.count, fis.lba()), (0x80, 0x01, 5 << 3, 0x0102_0304_0506));
+}
+
+#[test]
+fn identify_disks() {
+	let (mock, ctrl) = controller();
+	assert_eq!(ctrl.slots, 32);
+
+	let disks = ctrl.disks().map(|(i, d)| (i, *d)).collect::<Vec<_>>();
+	assert_eq!(disks.len(), 2);
+	assert_eq!(disks[0].0, 0);
+	assert_eq!(disks[0].1.sectors, 1024);
+	assert_eq!(disks[0].1.sector_size, 512);
+	assert_eq!(disks[0].1.queue_depth, 32);
+	assert_eq!(&disks[0].1.model_number, MODEL);
+	assert_eq!(&disks[0].1.serial_number[..7], b"QM00001");
+	assert!(disks[0].1.write_cache);
+	assert_eq!((disks[1].0, disks[1].1.sectors, disks[1].1.queue_depth), (1, 256, 1));
+	assert!(ctrl.disk(2).is_none());
+
+	let state = mock.0.borrow();
+	assert_eq!(state.regs[1], GHC_AE | GHC_IE);
+	assert_eq!(state.regs[port_reg(0, 0x18)] & (PXCMD_ST | PXCMD_FRE | PXCMD_SUD), PXCMD_ST | PXCMD_FRE | PXCMD_SUD);
+	assert_eq!(state.regs[port_reg(2, 0x14)], PXIS_PCS | PXIS_PRCS);
+}
+
+#[test]
+fn read_write() {
+	let (mock, mut ctrl) = controller();
+
+	for port in [0, 1] {
+		let (mut buf, start) = buffer(5 * PAGE_SIZE, 512);
+		let data = &mut buf[start..start + 5 * PAGE_SIZE];
+		data.iter_mut().enumerate().for_each(|(i, b)| *b = (i * 3 + port) as u8);
+		ctrl.write(port, 10, data).unwrap();
+		assert_eq!(&mock.0.borrow().ports[port].disk.as_ref().unwrap().data[10 * 512..][..5 * PAGE_SIZE], &*data);
+
+		let (mut out, out_start) = buffer(5 * PAGE_SIZE, 1024);
+		ctrl.read(port, 10, &mut out[out_start..out_start + 5 * PAGE_SIZE]).unwrap();
+		assert_eq!(&out[out_start..out_start + 5 * PAGE_SIZE], &*data);
+	}
+
+	let state = mock.0.borrow();
+	assert_eq!(&state.ports[0].commands[1..], [FisRegH2d::ATA_WRITE_FPDMA_QUEUED, FisRegH2d::ATA_READ_FPDMA_QUEUED]);
+	assert_eq!(&state.ports[1].commands[1..], [FisRegH2d::ATA_WRITE_DMA_EXT, FisRegH2d::ATA_READ_DMA_EXT]);
+}
+
+#[test]
+fn split_transfers() {
+	let (mock, mut ctrl) = controller();
+	let (mut buf, start) = buffer(300 * 1024, 0);
+	buf[start..start + 300 * 1024].iter_mut().enumerate().for_each(|(i, b)| *b = (i / 512) as u8);
+	ctrl.write(0, 0, &buf[start..start + 300 * 1024]).unwrap();
+
+	let state = mock.0.borrow();
+	assert_eq!(state.ports[0].commands[1..], [FisRegH2d::ATA_WRITE_FPDMA_QUEUED; 3]);
+	assert_eq!(&state.ports[0].disk.as_ref().unwrap().data[..300 * 1024], &buf[start..start + 300 * 1024]);
+}
+
+#[test]
+fn ncq() {
+	let (mock, mut ctrl) = controller();
+	mock.0.borrow_mut().ports[0].disk.as_mut().unwrap().data.iter_mut().enumerate().for_each(|(i, b)| *b = (i / 512) as u8);
+	mock.0.borrow_mut().hold = true;
+
+	let mut bufs = (0..3).map(|_| buffer(512, 0)).collect::<Vec<_>>();
+	let mut slots = Vec::new();
+	for (i, (buf, start)) in bufs.iter_mut().enumerate() {
+		slots.push(ctrl.submit_read(0, 7 + i as u64, &mut buf[*start..*start + 512]).unwrap());
+		mock.0.borrow_mut().process();
+	}
+	assert_eq!(slots, [0, 1, 2]);
+	assert_eq!(mock.0.borrow().ports[0].sact, 0b111);
+	assert_eq!(ctrl.flush(0), Err(Error::Busy));
+	assert_eq!(ctrl.complete(0), None);
+
+	mock.0.borrow_mut().hold = false;
+	mock.0.borrow_mut().process();
+	assert_eq!(mock.0.borrow().ports[0].max_queued, 3);
+	assert_eq!(mock.0.borrow().regs[2] & 1, 1);
+	let mut done = std::iter::from_fn(|| ctrl.complete(0)).collect::<Vec<_>>();
+	done.sort_by_key(|(slot, _)| *slot);
+	assert_eq!(done, [(0, Ok(())), (1, Ok(())), (2, Ok(()))]);
+	for (i, (buf, start)) in bufs.iter().enumerate() {
+		assert!(buf[*start..*start + 512].iter().all(|b| *b == 7 + i as u8));
+	}
+
+	ctrl.flush(0).unwrap();
+	assert_eq!(mock.0.borrow().ports[0].commands.last(), Some(&FisRegH2d::ATA_FLUSH_CACHE_EXT));
+}
+
+#[test]
+fn errors() {
+	let (mock, mut ctrl) = controller();
+	let (mut buf, start) = buffer(4096, 0);
+
+	assert_eq!(ctrl.read(0, 1020, &mut buf[start..start + 4096]), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.read(0, 0, &mut buf[start..start + 100]), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.read(0, 0, &mut buf[start + 1..start + 513]), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.read(3, 0, &mut buf[start..start + 512]), Err(Error::NoDevice));
+	assert_eq!(mock.0.borrow().ports[0].commands.len(), 1);
+
+	// the port is restarted after the device aborted a command
+	mock.0.borrow_mut().ports[0].disk.as_mut().unwrap().bad = Some(5);
+	assert_eq!(ctrl.read(0, 4, &mut buf[start..start + 1024]), Err(Error::Device { status: 0x51, error: 0x10 }));
+	assert_eq!(ctrl.read(0, 6, &mut buf[start..start + 1024]), Ok(()));
+}
+
+#[test]
+fn hot_plug() {
+	let (mock, mut ctrl) = controller();
+	assert_eq!(ctrl.interrupt(), []);
+
+	mock.plug(2, Some(MockDisk { data: vec![0x5A; 512 * 64], ncq: true, bad: None }));
+	assert_ne!(mock.0.borrow().regs[2] & 1 << 2, 0);
+	assert_eq!(ctrl.interrupt(), [Event::Attached(2)]);
+	assert_eq!(ctrl.disk(2).map(|d| d.sectors), Some(64));
+
+	let (mut buf, start) = buffer(512, 0);
+	ctrl.read(2, 63, &mut buf[start..start + 512]).unwrap();
+	assert!(buf[start..start + 512].iter().all(|b| *b == 0x5A));
+
+	mock.plug(0, None);
+	assert_eq!(ctrl.interrupt(), [Event::Detached(0)]);
+	assert_eq!(ctrl.read(0, 0, &mut buf[start..start + 512]), Err(Error::NoDevice));
+	assert_eq!(ctrl.disks().map(|(i, _)| i).collect::<Vec<_>>(), [1, 2]);
+
+	drop(ctrl);
+	assert!(mock.0.borrow().allocs.is_empty());
+}
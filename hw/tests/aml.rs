@@ -0,0 +1,279 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use common::{AcpiWindow, MockHandler};
+use hw::acpi::{*, aml::*};
+
+fn dsdt(name: &str, handler: &mut MockHandler) -> Namespace<'static> {
+	let window = AcpiWindow::load(name);
+	let fadt = match window.rsdp().get_xsdt().expect("no XSDT") {
+		Table::Xsdt(xsdt) => xsdt.into_iter().find_map(|t| match t { Table::Fadt(v) => Some(v), _ => None }),
+		_ => None
+	}.expect("no FADT");
+	let dsdt = fadt.dsdt().expect("no DSDT");
+
+	let mut ns = Namespace::new(dsdt.header.revision);
+	ns.load(dsdt.definition_block(), handler).unwrap();
+	ns
+}
+
+fn interp(handler: &mut MockHandler) -> Namespace<'static> {
+	let table = unsafe { (common::load("acpi/interp.aml").as_ptr() as *const DescHeader).as_ref() }.unwrap();
+	assert!(table.is_valid());
+
+	let Table::Ssdt(ssdt) = table.into() else { panic!("expected SSDT") };
+	let mut ns = Namespace::new(table.revision);
+	ns.load(ssdt.definition_block(), handler).unwrap();
+	ns
+}
+
+fn call(ns: &mut Namespace<'static>, handler: &mut MockHandler, path: &str, args: &[u64]) -> aml::Result<Object<'static>> {
+	ns.evaluate(path, args.iter().map(|v| Object::Integer(*v)).collect(), handler)
+}
+
+#[test]
+fn eisa_id() {
+	assert_eq!(decode_eisa_id(0x080AD041), "PNP0A08");
+	assert_eq!(decode_eisa_id(0x0105D041), "PNP0501");
+}
+
+#[test]
+fn paths() {
+	assert_eq!(normalize("\\_SB.PCI0").as_deref(), Some("\\_SB_.PCI0"));
+	assert_eq!(normalize("\\").as_deref(), Some("\\"));
+	assert_eq!(normalize("_SB.PCI0"), None);
+	assert_eq!(normalize("\\_SB.PCIE0"), None);
+	assert_eq!(child("\\_SB", "_CRS"), "\\_SB_._CRS");
+	assert_eq!(parent("\\_SB_.PCI0"), Some("\\_SB_"));
+	assert_eq!(parent("\\_SB_"), Some("\\"));
+	assert_eq!(parent("\\"), None);
+}
+
+#[test]
+fn q35_devices() {
+	let mut handler = MockHandler::default();
+	let mut ns = dsdt("acpi/q35.bin", &mut handler);
+	ns.initialize(&mut handler).unwrap();
+
+	assert_eq!(ns.devices(), ["\\_SB_.PCI0", "\\_SB_.PCI0.SF8_", "\\_SB_.PCI0.SF8_.COM1"]);
+	assert_eq!(ns.find_devices("PNP0A08", &mut handler).unwrap(), ["\\_SB_.PCI0"]);
+	assert_eq!(ns.find_devices("PNP0A03", &mut handler).unwrap(), ["\\_SB_.PCI0"]);
+	assert_eq!(ns.children("\\_SB.PCI0.SF8"), ["\\_SB_.PCI0.SF8_.COM1", "\\_SB_.PCI0.SF8_._ADR"]);
+
+	let info = ns.device_info("\\_SB.PCI0.SF8.COM1", &mut handler).unwrap();
+	assert_eq!(info.hid.as_deref(), Some("PNP0501"));
+	assert!(info.cid.is_empty());
+	assert_eq!(info.uid, Some(Object::Integer(1)));
+	assert_eq!(info.status, STA_DEFAULT);
+
+	let info = ns.device_info("\\_SB.PCI0.SF8", &mut handler).unwrap();
+	assert_eq!(info.hid, None);
+	assert_eq!(info.adr, Some(0x1F0000));
+}
+
+#[test]
+fn q35_resources() {
+	let mut handler = MockHandler::default();
+	let mut ns = dsdt("acpi/q35.bin", &mut handler);
+
+	let crs = ns.resources("\\_SB.PCI0", &mut handler).unwrap();
+	assert_eq!(crs.len(), 7);
+	assert_eq!(crs[0], Resource::AddressRange(AddressRange {
+		kind:        AddressRangeKind::BusNumber,
+		producer:    true,
+		granularity: 0,
+		min:         0,
+		max:         0xFF,
+		translation: 0,
+		len:         0x100,
+		flags:       0
+	}));
+	assert_eq!(crs[1], Resource::Io { decode16: true, min: 0xCF8, max: 0xCF8, align: 1, len: 8 });
+	let windows = crs[2..].iter().map(|r| match r {
+		Resource::AddressRange(v) => (v.kind, v.min, v.len),
+		r => panic!("unexpected resource {:?}", r)
+	}).collect::<Vec<_>>();
+	assert_eq!(windows, [
+		(AddressRangeKind::Io, 0, 0xCF8),
+		(AddressRangeKind::Io, 0xD00, 0xF300),
+		(AddressRangeKind::Memory, 0xA0000, 0x20000),
+		(AddressRangeKind::Memory, 0x80000000, 0x30000000),
+		(AddressRangeKind::Memory, 0x800000000, 0x800000000)
+	]);
+
+	let crs = ns.resources("\\_SB.PCI0.SF8.COM1", &mut handler).unwrap();
+	assert_eq!(crs, [
+		Resource::Io { decode16: true, min: 0x3F8, max: 0x3F8, align: 0, len: 8 },
+		Resource::Irq(Irq { interrupts: vec![4], edge: true, active_low: false, shared: false, wake: false })
+	]);
+}
+
+#[test]
+fn q35_routing() {
+	let mut handler = MockHandler::default();
+	let mut ns = dsdt("acpi/q35.bin", &mut handler);
+
+	let routes = ns.pci_routing("\\_SB.PCI0", &mut handler).unwrap();
+	assert_eq!(routes.len(), 12);
+	assert_eq!(routes[0], PciRoute { device: 1, pin: 0, source: PciRouteSource::Gsi(17) });
+	assert_eq!(routes[11], PciRoute { device: 3, pin: 3, source: PciRouteSource::Gsi(18) });
+}
+
+/// The DSDT of a Firecracker guest, dumped rather than generated: hardware-reduced, no
+/// sleep states, PCI slots without `_CRS`.
+#[test]
+fn firecracker() {
+	let table = unsafe { (common::load("acpi/firecracker/dsdt.aml").as_ptr() as *const DescHeader).as_ref() }.unwrap();
+	assert!(table.is_valid());
+	let mut handler = MockHandler::default();
+	let mut ns = Namespace::new(table.revision);
+	ns.load_table(table, &mut handler).unwrap();
+
+	let devices = ns.devices();
+	assert_eq!(devices.len(), 3 + 32 + 3);
+	assert_eq!(ns.find_devices("PNP0A08", &mut handler).unwrap(), ["\\_SB_.PC00"]);
+	assert_eq!(ns.find_devices("VMGENCTR", &mut handler).unwrap(), ["\\_SB_.VGEN"]);
+	assert_eq!(ns.find_devices("PNP0303", &mut handler).unwrap(), ["\\_SB_.PS2_"]);
+	assert_eq!(ns.device_info("\\_SB.PC00.S031", &mut handler).unwrap().adr, Some(0x1F0000));
+
+	let info = ns.device_info("\\_SB.VCLK", &mut handler).unwrap();
+	assert_eq!(info.hid.as_deref(), Some("AMZNC10C"));
+	assert_eq!(info.cid, ["VMCLOCK"]);
+
+	assert_eq!(ns.resources("\\_SB.COM1", &mut handler).unwrap(), [
+		Resource::Irq(Irq { interrupts: vec![4], edge: true, active_low: false, shared: false, wake: false }),
+		Resource::Io { decode16: true, min: 0x3F8, max: 0x3F8, align: 1, len: 8 }
+	]);
+	let windows = ns.resources("\\_SB.PC00", &mut handler).unwrap().into_iter().filter_map(|r| match r {
+		Resource::AddressRange(v) => Some((v.kind, v.min, v.len)),
+		_ => None
+	}).collect::<Vec<_>>();
+	assert_eq!(windows, [
+		(AddressRangeKind::BusNumber, 0, 1),
+		(AddressRangeKind::Memory, 0xC0001000, 0x2EBFF000),
+		(AddressRangeKind::Memory, 0x4000000000, 0x4000000000),
+		(AddressRangeKind::Io, 0, 0xCF8),
+		(AddressRangeKind::Io, 0xD00, 0xF300)
+	]);
+	assert!(matches!(ns.resources("\\_SB.PC00.S000", &mut handler), Err(AmlError::NotFound(_))));
+
+	assert_eq!(ns.sleep_type(5, &mut handler), Ok(None));
+}
+
+#[test]
+fn q35_sleep() {
+	let mut handler = MockHandler::default();
+	let mut ns = dsdt("acpi/q35.bin", &mut handler);
+	assert_eq!(ns.sleep_type(5, &mut handler).unwrap(), Some((0, 0)));
+	assert_eq!(ns.sleep_type(3, &mut handler).unwrap(), None);
+}
+
+#[test]
+fn virt_devices() {
+	let mut handler = MockHandler::default();
+	let mut ns = dsdt("acpi/virt.bin", &mut handler);
+
+	assert_eq!(ns.find_devices("ARMH0011", &mut handler).unwrap(), ["\\_SB_.COM0"]);
+	assert_eq!(ns.resources("\\_SB.COM0", &mut handler).unwrap(), [
+		Resource::Memory { writable: true, min: 0x09000000, max: 0x09000000, align: 1, len: 0x1000 },
+		Resource::Irq(Irq { interrupts: vec![33], edge: true, active_low: false, shared: true, wake: false })
+	]);
+	assert_eq!(ns.sleep_type(5, &mut handler).unwrap(), None);
+}
+
+#[test]
+fn methods() {
+	let mut handler = MockHandler::default();
+	let mut ns = interp(&mut handler);
+
+	assert_eq!(call(&mut ns, &mut handler, "\\ADD2", &[2, 3]), Ok(Object::Integer(5)));
+	assert_eq!(call(&mut ns, &mut handler, "\\LOOP", &[10]), Ok(Object::Integer(45)));
+	assert_eq!(call(&mut ns, &mut handler, "\\FIB", &[15]), Ok(Object::Integer(610)));
+	assert_eq!(call(&mut ns, &mut handler, "\\CAT", &[]), Ok(Object::String(String::from("abcdef"))));
+	assert_eq!(call(&mut ns, &mut handler, "\\EQ", &[7, 7]), Ok(Object::Integer(u64::MAX)));
+	assert_eq!(call(&mut ns, &mut handler, "\\EQ", &[7, 8]), Ok(Object::Integer(0)));
+
+	// objects created by a method don't survive it
+	assert_eq!(call(&mut ns, &mut handler, "\\TEMP", &[]), Ok(Object::Integer(5)));
+	assert_eq!(call(&mut ns, &mut handler, "\\TEMP", &[]), Ok(Object::Integer(5)));
+	assert!(!ns.contains("\\TEMP.TMP"));
+
+	let osi = |ns: &mut Namespace<'static>, handler: &mut MockHandler, s: &str|
+		ns.evaluate("\\OSI", vec![Object::String(String::from(s))], handler);
+	assert_eq!(osi(&mut ns, &mut handler, "Windows 2015"), Ok(Object::Integer(u64::MAX)));
+	assert_eq!(osi(&mut ns, &mut handler, "Linux"), Ok(Object::Integer(0)));
+}
+
+#[test]
+fn packages_and_buffers() {
+	let mut handler = MockHandler::default();
+	let mut ns = interp(&mut handler);
+
+	assert_eq!(call(&mut ns, &mut handler, "\\SIZE", &[]), Ok(Object::Integer(3)));
+	assert_eq!(call(&mut ns, &mut handler, "\\IDX", &[1]), Ok(Object::String(String::from("x"))));
+	assert_eq!(call(&mut ns, &mut handler, "\\IDX", &[3]), Err(AmlError::InvalidIndex));
+
+	call(&mut ns, &mut handler, "\\SETP", &[2]).unwrap();
+	assert_eq!(call(&mut ns, &mut handler, "\\IDX", &[2]), Ok(Object::Integer(99)));
+
+	assert_eq!(ns.evaluate("\\BDW", Vec::new(), &mut handler), Ok(Object::Integer(0x55443322)));
+	call(&mut ns, &mut handler, "\\WBDW", &[0xAABBCCDD]).unwrap();
+	assert_eq!(ns.get("\\BUF"), Some(&Object::Buffer(vec![0x11, 0xDD, 0xCC, 0xBB, 0xAA])));
+}
+
+#[test]
+fn fields() {
+	let mut handler = MockHandler::default();
+	let mut ns = interp(&mut handler);
+
+	handler.write_memory(0x1000, 64, 0x1234_5678_0000_A5C3);
+	assert_eq!(ns.evaluate("\\F0", Vec::new(), &mut handler), Ok(Object::Integer(0xC3)));
+	assert_eq!(ns.evaluate("\\F1", Vec::new(), &mut handler), Ok(Object::Integer(0x5)));
+	assert_eq!(ns.evaluate("\\F2", Vec::new(), &mut handler), Ok(Object::Integer(0xA)));
+	assert_eq!(call(&mut ns, &mut handler, "\\RDF3", &[]), Ok(Object::Integer(0x1234_5678)));
+
+	// the neighbouring bits of the access unit are preserved
+	call(&mut ns, &mut handler, "\\WRF1", &[0xF]).unwrap();
+	assert_eq!(handler.read_memory(0x1000, 32), 0x0000_AFC3);
+
+	handler.write_memory(0x1008, 64, 0xDEAD_BEEF_0BAD_F00D);
+	assert_eq!(ns.evaluate("\\F4", Vec::new(), &mut handler), Ok(Object::Integer(0xDEAD_BEEF_0BAD_F00D)));
+
+	call(&mut ns, &mut handler, "\\POST", &[0x42]).unwrap();
+	assert_eq!(handler.io, [(0x80, 8, 0x42)]);
+}
+
+#[test]
+fn errors() {
+	let mut handler = MockHandler::default();
+	let mut ns = interp(&mut handler);
+
+	assert_eq!(call(&mut ns, &mut handler, "\\SPIN", &[]), Err(AmlError::LoopLimit));
+	assert_eq!(call(&mut ns, &mut handler, "\\DIV0", &[0]), Err(AmlError::DivideByZero));
+	assert_eq!(call(&mut ns, &mut handler, "\\NOPE", &[]), Err(AmlError::NotFound(String::from("\\NOPE"))));
+
+	// loading the same definition block twice redefines every object
+	let table = unsafe { (common::load("acpi/interp.aml").as_ptr() as *const DescHeader).as_ref() }.unwrap();
+	assert_eq!(ns.load_table(table, &mut handler), Err(AmlError::AlreadyExists(String::from("\\ADD2"))));
+}
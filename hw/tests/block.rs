@@ -0,0 +1,238 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{cell::RefCell, rc::Rc};
+use hw::block::*;
+
+/// Records the device commands issued to a RAM disk.
+struct Recorder {
+	disk:     RamDisk,
+	max:      u64,
+	commands: Vec<(Op, u64, u64)>
+}
+
+impl Recorder {
+	fn new(blocks: usize) -> Self {
+		Self { disk: RamDisk::new(512, blocks), max: u64::MAX, commands: Vec::new() }
+	}
+}
+
+impl BlockDevice for Recorder {
+	fn block_size(&self) -> usize { self.disk.block_size() }
+	fn blocks(&self) -> u64 { self.disk.blocks() }
+	fn max_blocks(&self) -> u64 { self.max }
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> {
+		self.commands.push((Op::Read, lba, buf.len() as u64 / 512));
+		self.disk.read(lba, buf)
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+		self.commands.push((Op::Write, lba, buf.len() as u64 / 512));
+		self.disk.write(lba, buf)
+	}
+
+	fn read_vectored(&mut self, lba: u64, bufs: &mut [&mut [u8]]) -> Result<()> {
+		let blocks = bufs.iter().map(|b| b.len() as u64 / 512).sum();
+		self.commands.push((Op::Read, lba, blocks));
+		self.disk.read_vectored(lba, bufs)
+	}
+
+	fn write_vectored(&mut self, lba: u64, bufs: &[&[u8]]) -> Result<()> {
+		let blocks = bufs.iter().map(|b| b.len() as u64 / 512).sum();
+		self.commands.push((Op::Write, lba, blocks));
+		self.disk.write_vectored(lba, bufs)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.commands.push((Op::Flush, 0, 0));
+		Ok(())
+	}
+
+	fn discard(&mut self, lba: u64, blocks: u64) -> Result<()> {
+		self.commands.push((Op::Discard, lba, blocks));
+		self.disk.discard(lba, blocks)
+	}
+}
+
+fn ids(completions: &[Completion]) -> Vec<u64> {
+	completions.iter().map(|c| c.request.id).collect()
+}
+
+#[test]
+fn ram_disk() {
+	let mut disk = RamDisk::new(512, 16);
+	assert_eq!((disk.block_size(), disk.blocks()), (512, 16));
+
+	disk.write(2, &[0xAB; 1024]).unwrap();
+	let mut buf = [0; 512];
+	disk.read(3, &mut buf).unwrap();
+	assert_eq!(buf, [0xAB; 512]);
+
+	assert_eq!(disk.read(16, &mut buf), Err(Error::InvalidArgument));
+	assert_eq!(disk.read(15, &mut [0; 1024]), Err(Error::InvalidArgument));
+	assert_eq!(disk.read(0, &mut [0; 100]), Err(Error::InvalidArgument));
+	assert_eq!(disk.read(u64::MAX, &mut buf), Err(Error::InvalidArgument));
+
+	disk.discard(3, 1).unwrap();
+	disk.read(3, &mut buf).unwrap();
+	assert_eq!(buf, [0; 512]);
+
+	disk.set_read_only(true);
+	assert_eq!(disk.write(0, &buf), Err(Error::ReadOnly));
+	assert_eq!(Queue::new(Noop).write(&disk, 0, vec![0; 512], 0, 0), Err(Error::ReadOnly));
+
+	let image = RamDisk::from_vec(512, vec![1; 1300]);
+	assert_eq!(image.blocks(), 2);
+}
+
+#[test]
+fn shared() {
+	let disk = Rc::new(RefCell::new(RamDisk::new(512, 8)));
+	let (mut a, mut b) = (disk.clone(), disk.clone());
+	a.write(1, &[7; 512]).unwrap();
+
+	let mut buf = vec![0; 512];
+	b.read(1, &mut buf).unwrap();
+	assert_eq!(buf, [7; 512]);
+
+	let mut boxed: Box<dyn BlockDevice> = Box::new(disk.clone());
+	assert_eq!(boxed.blocks(), 8);
+	boxed.read_vectored(0, &mut [&mut [0; 512], &mut buf]).unwrap();
+	assert_eq!(buf, [7; 512]);
+}
+
+#[test]
+fn merging() {
+	let mut dev = Recorder::new(64);
+	let mut queue = Queue::new(Noop);
+
+	let w0 = queue.write(&dev, 4, vec![1; 1024], 1, 0).unwrap();
+	let w1 = queue.write(&dev, 6, vec![2; 512], 2, 0).unwrap();
+	let w2 = queue.write(&dev, 2, vec![3; 1024], 1, 0).unwrap();
+	let w3 = queue.write(&dev, 20, vec![4; 512], 1, 0).unwrap();
+	assert_eq!(queue.len(), 4);
+
+	let completions = queue.dispatch(&mut dev, 0);
+	assert_eq!(ids(&completions), [w2, w0, w1]);
+	assert!(completions.iter().all(|c| c.batch == 3 && c.result.is_ok()));
+	assert_eq!(completions[2].request.owner, 2);
+	assert_eq!(dev.commands, [(Op::Write, 2, 5)]);
+
+	assert_eq!(ids(&queue.run(&mut dev, 0)), [w3]);
+	assert!(queue.is_empty());
+
+	let r0 = queue.read(&dev, 3, 2, 0, 0).unwrap();
+	let r1 = queue.read(&dev, 5, 2, 0, 0).unwrap();
+	let completions = queue.run(&mut dev, 0);
+	assert_eq!(ids(&completions), [r0, r1]);
+	assert_eq!(completions[0].request.data, [[3; 512], [1; 512]].concat());
+	assert_eq!(completions[1].request.data, [[1; 512], [2; 512]].concat());
+	assert_eq!(dev.commands[2..], [(Op::Read, 3, 4)]);
+
+	// merges stop at the device's transfer limit
+	dev.max = 4;
+	dev.commands.clear();
+	for lba in 0..6 {
+		queue.discard(&dev, lba, 1, 0, 0).unwrap();
+	}
+	queue.run(&mut dev, 0);
+	assert_eq!(dev.commands, [(Op::Discard, 0, 4), (Op::Discard, 4, 2)]);
+}
+
+#[test]
+fn ordering() {
+	let mut dev = Recorder::new(64);
+	let mut queue = Queue::new(Noop);
+
+	// a read after an overlapping write sees the written data
+	let w = queue.write(&dev, 8, vec![5; 512], 0, 0).unwrap();
+	let r = queue.read(&dev, 8, 1, 0, 0).unwrap();
+	let completions = queue.run(&mut dev, 0);
+	assert_eq!(ids(&completions), [w, r]);
+	assert_eq!(completions[1].request.data, [5; 512]);
+
+	// nothing is merged or reordered across a flush
+	dev.commands.clear();
+	queue.write(&dev, 0, vec![0; 512], 0, 0).unwrap();
+	let f = queue.flush(0, 0);
+	queue.write(&dev, 1, vec![0; 512], 0, 0).unwrap();
+	let completions = queue.run(&mut dev, 0);
+	assert_eq!(completions[1].request.id, f);
+	assert_eq!(dev.commands, [(Op::Write, 0, 1), (Op::Flush, 0, 0), (Op::Write, 1, 1)]);
+
+	// the write to block 0 waits for the read of block 0, the read of block 1 doesn't
+	dev.commands.clear();
+	let mut queue = Queue::new(Deadline { read_expire: 100, write_expire: 100 });
+	queue.read(&dev, 9, 1, 0, 0).unwrap();
+	queue.read(&dev, 0, 1, 0, 0).unwrap();
+	queue.write(&dev, 0, vec![1; 512], 0, 0).unwrap();
+	queue.read(&dev, 1, 1, 0, 0).unwrap();
+	queue.run(&mut dev, 0);
+	assert_eq!(dev.commands, [(Op::Read, 0, 2), (Op::Read, 9, 1), (Op::Write, 0, 1)]);
+}
+
+#[test]
+fn deadline() {
+	let mut dev = Recorder::new(1024);
+	let mut queue = Queue::new(Deadline { read_expire: 10, write_expire: 50 });
+
+	// sweep upwards from the head, then wrap around
+	queue.read(&dev, 100, 1, 0, 0).unwrap();
+	queue.dispatch(&mut dev, 0);
+	for lba in [50, 300, 10, 200] {
+		queue.read(&dev, lba, 1, 0, 0).unwrap();
+	}
+	queue.run(&mut dev, 0);
+	let order = dev.commands.iter().map(|c| c.1).collect::<Vec<_>>();
+	assert_eq!(order, [100, 200, 300, 10, 50]);
+
+	// expired requests go first, reads expire sooner than writes
+	dev.commands.clear();
+	queue.write(&dev, 900, vec![0; 512], 0, 0).unwrap();
+	queue.read(&dev, 800, 1, 0, 5).unwrap();
+	queue.read(&dev, 0, 1, 0, 55).unwrap();
+	queue.dispatch(&mut dev, 20);
+	queue.dispatch(&mut dev, 60);
+	queue.dispatch(&mut dev, 60);
+	let order = dev.commands.iter().map(|c| c.1).collect::<Vec<_>>();
+	assert_eq!(order, [800, 900, 0]);
+}
+
+#[test]
+fn errors() {
+	let mut dev = Recorder::new(8);
+	let mut queue = Queue::new(Box::new(Noop) as Box<dyn Scheduler>);
+
+	assert_eq!(queue.read(&dev, 8, 1, 0, 0), Err(Error::InvalidArgument));
+	assert_eq!(queue.write(&dev, 0, vec![0; 500], 0, 0), Err(Error::InvalidArgument));
+	assert_eq!(queue.discard(&dev, 7, 2, 0, 0), Err(Error::InvalidArgument));
+	assert!(queue.is_empty());
+
+	// a failing command fails every request merged into it
+	queue.read(&dev, 0, 1, 0, 0).unwrap();
+	queue.read(&dev, 1, 1, 0, 0).unwrap();
+	dev.disk = RamDisk::new(512, 1);
+	let completions = queue.run(&mut dev, 0);
+	assert_eq!(completions.len(), 2);
+	assert!(completions.iter().all(|c| c.result == Err(Error::InvalidArgument)));
+}
@@ -0,0 +1,295 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+mod common;
+
+use hw::{block::RamDisk, btrfs::*};
+
+const WORDS: [&[u8]; 16] = [
+	b"the", b"quick", b"brown", b"fox", b"jumps", b"over", b"lazy", b"dog", b"block", b"device", b"extent",
+	b"tree", b"node", b"leaf", b"kernel", b"page"
+];
+
+fn image() -> Vec<u8> {
+	common::load_sparse("btrfs/btrfs.img")
+}
+
+fn open(image: Vec<u8>) -> FileSystem<RamDisk> {
+	FileSystem::open(RamDisk::from_vec(512, image)).unwrap()
+}
+
+/// Reopens the file system from the committed state of the device.
+fn reopen(fs: FileSystem<RamDisk>) -> FileSystem<RamDisk> {
+	open(fs.into_inner().into_vec())
+}
+
+/// The same text as `text` in `fixtures/generate.py`.
+fn text(len: usize, seed: u32) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut x = seed;
+	while out.len() < len {
+		x = x.wrapping_mul(1103515245).wrapping_add(12345) & 0x7FFF_FFFF;
+		out.extend_from_slice(WORDS[(x >> 16 & 15) as usize]);
+		out.push(if x & 0x300 == 0 { b'\n' } else { b' ' });
+	}
+	out.truncate(len);
+	out
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+	(0..len).map(|i| (i * 7 + seed as usize) as u8).collect()
+}
+
+fn read_all(fs: &mut FileSystem<RamDisk>, path: &str) -> Vec<u8> {
+	let mut buf = vec![0; fs.metadata(path).unwrap().size as usize + 10];
+	let len = fs.read(path, 0, &mut buf).unwrap();
+	buf.truncate(len);
+	buf
+}
+
+fn names(fs: &mut FileSystem<RamDisk>, path: &str) -> Vec<String> {
+	fs.read_dir(path).unwrap().into_iter().map(|e| e.name).collect()
+}
+
+fn plain() -> Vec<u8> {
+	let mut data = vec![0; 45000];
+	data[..16384].copy_from_slice(&pattern(16384, 5));
+	data[32768..40960].copy_from_slice(&pattern(16384, 9)[4096..12288]);
+	data
+}
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<Superblock>(), SUPER_SIZE);
+	assert_eq!(core::mem::size_of::<DevItem>(), 98);
+	assert_eq!(core::mem::size_of::<RootBackup>(), 168);
+	assert_eq!(core::mem::size_of::<Header>(), 101);
+	assert_eq!(core::mem::size_of::<Item>(), 25);
+	assert_eq!(core::mem::size_of::<KeyPtr>(), 33);
+	assert_eq!(core::mem::size_of::<Chunk>(), 48);
+	assert_eq!(core::mem::size_of::<RootItem>(), 439);
+	assert_eq!(core::mem::size_of::<InodeItem>(), 160);
+	assert_eq!(core::mem::size_of::<DirItem>(), 30);
+	assert_eq!(core::mem::size_of::<FileExtentItem>(), 53);
+	assert_eq!(core::mem::size_of::<ExtentDataRef>(), 28);
+	assert_eq!(core::mem::size_of::<DevExtent>(), 48);
+	// `btrfs_name_hash` of the kernel
+	assert_eq!(name_hash(b"default"), 0x8dbfc2d2);
+}
+
+#[test]
+fn open_image() {
+	let fs = open(image());
+	assert_eq!(fs.label(), "fixture");
+	assert_eq!(fs.generation(), 7);
+	assert_eq!(fs.node_size(), 4096);
+	assert_eq!(fs.sector_size(), 4096);
+	assert!(fs.is_writable());
+
+	let mut zeros = image();
+	zeros[0x10000..0x11000].fill(0);
+	assert_eq!(FileSystem::open(RamDisk::from_vec(512, zeros)).unwrap_err(), Error::NotBtrfs);
+}
+
+#[test]
+fn enumerate() {
+	let mut fs = open(image());
+	assert_eq!(names(&mut fs, ""), ["hello.txt", "inline.txt", "zlib.bin", "zstd.bin", "plain.bin", "dir", "many"]);
+	assert_eq!(names(&mut fs, "dir/sub"), ["nested.txt"]);
+
+	let many = fs.read_dir("/many/").unwrap();
+	assert_eq!(many.len(), 150);
+	assert!(many.iter().enumerate().all(|(i, e)| e.name == format!("file-{:03}.txt", i) && e.kind == FT_REG_FILE));
+
+	let root = fs.metadata("").unwrap();
+	assert!(root.is_dir());
+	assert_eq!(root.inode, 256);
+	let entry = fs.metadata("zstd.bin").unwrap();
+	assert_eq!((entry.size, entry.kind, entry.mode), (200000, FT_REG_FILE, 0o100644));
+
+	assert_eq!(fs.metadata("missing").unwrap_err(), Error::NotFound);
+	assert_eq!(fs.read_dir("hello.txt").unwrap_err(), Error::NotADirectory);
+	assert_eq!(fs.metadata("hello.txt/x").unwrap_err(), Error::NotADirectory);
+	assert_eq!(fs.read("dir", 0, &mut [0; 4]).unwrap_err(), Error::IsADirectory);
+}
+
+#[test]
+fn read() {
+	let mut fs = open(image());
+	assert_eq!(read_all(&mut fs, "hello.txt"), b"Hello, Btrfs!\n");
+	assert_eq!(read_all(&mut fs, "inline.txt"), text(3000, 7));
+	assert_eq!(read_all(&mut fs, "zlib.bin"), text(100000, 3));
+	assert_eq!(read_all(&mut fs, "zstd.bin"), text(200000, 4));
+	assert_eq!(read_all(&mut fs, "plain.bin"), plain());
+	assert_eq!(read_all(&mut fs, "dir/sub/nested.txt"), b"nested\n");
+	assert_eq!(read_all(&mut fs, "many/file-149.txt"), b"149\n");
+
+	// ranges across extents and holes
+	let mut buf = [0; 300];
+	assert_eq!(fs.read("zstd.bin", (128 << 10) - 100, &mut buf).unwrap(), 300);
+	assert_eq!(buf[..], text(200000, 4)[(128 << 10) - 100..][..300]);
+	assert_eq!(fs.read("plain.bin", 16000, &mut buf).unwrap(), 300);
+	assert_eq!(buf[..], plain()[16000..16300]);
+	assert_eq!(fs.read("plain.bin", 44900, &mut buf).unwrap(), 100);
+	assert_eq!(fs.read("plain.bin", 45000, &mut buf).unwrap(), 0);
+}
+
+#[test]
+fn checksums() {
+	let find = |image: &[u8], needle: &[u8]| image.windows(needle.len()).position(|w| w == needle).unwrap();
+
+	// the first copy of the DUP metadata is corrupted, the second is used
+	let mut corrupted = image();
+	let leaf = find(&corrupted, b"hello.txt");
+	assert!(leaf < 7 << 20);
+	corrupted[leaf] ^= 1;
+	let mut fs = open(corrupted);
+	assert_eq!(read_all(&mut fs, "hello.txt"), b"Hello, Btrfs!\n");
+
+	// SINGLE data has no second copy
+	let mut corrupted = image();
+	let data = find(&corrupted, &pattern(4096, 5));
+	corrupted[data + 5000] ^= 1;
+	let mut fs = open(corrupted);
+	assert_eq!(fs.read("plain.bin", 4096, &mut [0; 100]).unwrap_err(), Error::Corrupted);
+	assert_eq!(fs.read("plain.bin", 0, &mut [0; 100]).unwrap(), 100);
+}
+
+#[test]
+fn modify() {
+	let mut fs = open(image());
+	let used = fs.bytes_used();
+	fs.set_time(Timespec { sec: 1_700_000_000, nsec: 0 });
+
+	// a new file across several sectors
+	fs.create_file("new.bin").unwrap();
+	let data = pattern(10000, 3);
+	assert_eq!(fs.write("new.bin", 100, &data).unwrap(), 10000);
+	assert_eq!(fs.create_file("new.bin").unwrap_err(), Error::Exists);
+
+	// overwriting the middle of an extent keeps both ends
+	let mut expected = plain();
+	fs.write("plain.bin", 5000, b"overwritten").unwrap();
+	expected[5000..5011].copy_from_slice(b"overwritten");
+	fs.write("plain.bin", 20000, &[0xAA; 100]).unwrap();
+	expected[20000..20100].fill(0xAA);
+	assert_eq!(read_all(&mut fs, "plain.bin"), expected);
+
+	// inline and compressed files become regular ones
+	fs.write("hello.txt", 14, b"More text\n").unwrap();
+	fs.truncate("zlib.bin", 5000).unwrap();
+	fs.truncate("zlib.bin", 9000).unwrap();
+	fs.write("zstd.bin", 140000, b"zstd").unwrap();
+	let mut zstd = text(200000, 4);
+	zstd[140000..140004].copy_from_slice(b"zstd");
+
+	// enough entries to split leaves
+	fs.create_dir("more").unwrap();
+	for i in 0..300 {
+		fs.create_file(&format!("more/entry with a longer name {}", i)).unwrap();
+	}
+	fs.remove("many/file-000.txt").unwrap();
+	fs.remove("dir/sub/nested.txt").unwrap();
+	fs.remove("dir/sub").unwrap();
+	assert_eq!(fs.remove("dir/sub").unwrap_err(), Error::NotFound);
+	assert_eq!(fs.remove("more").unwrap_err(), Error::NotEmpty);
+	fs.sync().unwrap();
+	assert!(fs.bytes_used() > used);
+
+	let mut fs = reopen(fs);
+	assert_eq!(fs.generation(), 8);
+	assert_eq!(read_all(&mut fs, "new.bin"), [&[0; 100][..], &data].concat());
+	assert_eq!(read_all(&mut fs, "plain.bin"), expected);
+	assert_eq!(read_all(&mut fs, "hello.txt"), b"Hello, Btrfs!\nMore text\n");
+	assert_eq!(read_all(&mut fs, "zlib.bin"), [&text(5000, 3)[..], &[0; 4000]].concat());
+	assert_eq!(read_all(&mut fs, "zstd.bin"), zstd);
+	assert_eq!(read_all(&mut fs, "inline.txt"), text(3000, 7));
+	assert_eq!(fs.read_dir("more").unwrap().len(), 300);
+	assert_eq!(fs.metadata("more").unwrap().size, 2 * (0..300).map(|i| format!("entry with a longer name {}", i).len() as u64).sum::<u64>());
+	assert_eq!(fs.read_dir("many").unwrap()[0].name, "file-001.txt");
+	assert!(fs.read_dir("dir").unwrap().is_empty());
+	assert_eq!(fs.metadata("new.bin").unwrap().mtime, Timespec { sec: 1_700_000_000, nsec: 0 });
+
+	// removing everything that was added frees its space again
+	for i in 0..300 {
+		fs.remove(&format!("more/entry with a longer name {}", i)).unwrap();
+	}
+	fs.remove("more").unwrap();
+	fs.remove("new.bin").unwrap();
+	fs.remove("zstd.bin").unwrap();
+	fs.sync().unwrap();
+	let mut fs = reopen(fs);
+	assert_eq!(names(&mut fs, ""), ["hello.txt", "inline.txt", "zlib.bin", "plain.bin", "dir", "many"]);
+	assert_eq!(read_all(&mut fs, "plain.bin"), expected);
+	assert!(fs.bytes_used() < used + 64 * 1024);
+}
+
+#[test]
+fn uncommitted() {
+	let mut fs = open(image());
+	fs.create_file("lost.txt").unwrap();
+	fs.write("lost.txt", 0, b"lost").unwrap();
+	assert_eq!(read_all(&mut fs, "lost.txt"), b"lost");
+
+	let mut fs = reopen(fs);
+	assert_eq!(fs.generation(), 7);
+	assert_eq!(fs.metadata("lost.txt").unwrap_err(), Error::NotFound);
+	assert_eq!(fs.create_file("a/b").unwrap_err(), Error::NotFound);
+	assert_eq!(fs.create_file("hello.txt/b").unwrap_err(), Error::NotADirectory);
+	assert_eq!(fs.create_file("").unwrap_err(), Error::InvalidName);
+}
+
+/// Formatted by `mkfs.btrfs --rootdir --compress`, `generate.py` only writes them where
+/// btrfs-progs 6.13 or later is installed.
+#[test]
+#[ignore = "the images are not checked in, run generate.py where btrfs-progs 6.13 is installed"]
+fn mkfs_images() {
+	for name in ["btrfs/mkfs-zlib.img", "btrfs/mkfs-zstd.img"] {
+		let mut fs = open(common::load_sparse(name));
+		assert_eq!(fs.label(), "fixture", "{}", name);
+		assert_eq!(fs.node_size(), 4096);
+
+		let mut root = names(&mut fs, "/");
+		root.sort();
+		assert_eq!(root, ["dir", "hello.txt", "many", "plain.bin", "text.txt"], "{}", name);
+		assert_eq!(names(&mut fs, "many").len(), 150);
+		assert_eq!(read_all(&mut fs, "hello.txt"), b"Hello, Btrfs!\n");
+		assert_eq!(read_all(&mut fs, "dir/sub/nested.txt"), b"nested\n");
+		assert_eq!(read_all(&mut fs, "many/file-149.txt"), b"149\n");
+		assert_eq!(read_all(&mut fs, "plain.bin"), pattern(40000, 5));
+
+		// the text is only that small on disk if its extents are compressed
+		let text = text(1 << 20, 3);
+		assert_eq!(read_all(&mut fs, "text.txt"), text, "{}", name);
+		assert!(fs.bytes_used() < text.len() as u64 / 2, "{}: {} bytes used", name, fs.bytes_used());
+		let mut buf = [0; 300];
+		assert_eq!(fs.read("text.txt", 200000, &mut buf).unwrap(), 300);
+		assert_eq!(buf[..], text[200000..200300]);
+
+		// and a write in the middle of a compressed extent survives a commit
+		fs.write("text.txt", 70000, b"rewritten").unwrap();
+		fs.sync().unwrap();
+		let mut fs = reopen(fs);
+		let mut expected = text;
+		expected[70000..70009].copy_from_slice(b"rewritten");
+		assert_eq!(read_all(&mut fs, "text.txt"), expected, "{}", name);
+	}
+}
@@ -0,0 +1,208 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Fixture loading shared by the integration tests. The fixtures are produced by
+//! `fixtures/generate.py`.
+
+#![allow(dead_code)]
+
+pub mod virtio;
+pub mod p9;
+
+use std::collections::BTreeMap;
+use hw::acpi::{self, DescHeader, RSDP, aml::{Handler, PciAddress}};
+
+pub fn path(name: &str) -> String {
+	format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Loads a fixture into a leaked, page aligned buffer, the parsers are handed
+/// references into it that have to outlive the test.
+pub fn load(name: &str) -> &'static mut [u8] {
+	let data = std::fs::read(path(name)).unwrap_or_else(|e| panic!("failed to read fixture `{}`: {}", name, e));
+	let buf = alloc(data.len());
+	buf.copy_from_slice(&data);
+	buf
+}
+
+/// Loads a fixture stored as its non-zero chunks, which keeps large disk images small.
+pub fn load_sparse(name: &str) -> Vec<u8> {
+	let data = std::fs::read(path(name)).unwrap_or_else(|e| panic!("failed to read fixture `{}`: {}", name, e));
+	assert_eq!(&data[..8], b"SPARSE\0\0", "fixture `{}` isn't sparse", name);
+	let mut image = vec![0; read::<u64>(&data, 8) as usize];
+	let mut off = 16;
+	while off < data.len() {
+		let (start, len) = (read::<u64>(&data, off) as usize, read::<u32>(&data, off + 8) as usize);
+		image[start..start + len].copy_from_slice(&data[off + 12..off + 12 + len]);
+		off += 12 + len;
+	}
+	image
+}
+
+fn alloc(len: usize) -> &'static mut [u8] {
+	let layout = std::alloc::Layout::from_size_align(len.max(1), 0x1000).unwrap();
+	unsafe {
+		let ptr = std::alloc::alloc_zeroed(layout);
+		assert!(!ptr.is_null());
+		core::slice::from_raw_parts_mut(ptr, len)
+	}
+}
+
+pub fn read<T: Copy>(buf: &[u8], off: usize) -> T {
+	assert!(off + core::mem::size_of::<T>() <= buf.len());
+	unsafe { (buf.as_ptr().add(off) as *const T).read_unaligned() }
+}
+
+fn write<T: Copy>(buf: &mut [u8], off: usize, v: T) {
+	assert!(off + core::mem::size_of::<T>() <= buf.len());
+	unsafe { (buf.as_mut_ptr().add(off) as *mut T).write_unaligned(v) }
+}
+
+/// A window of guest physical memory containing the ACPI tables, with the RSDP at
+/// its start.
+///
+/// The host can't follow the guest's physical pointers, so all 64-bit pointers the
+/// parsers dereference are relocated into the window. 32-bit pointers can't hold a
+/// host address and are cleared, which makes the parsers fall back to the 64-bit
+/// ones. The checksums of all patched tables are recomputed.
+pub struct AcpiWindow {
+	/// Guest physical address of the window
+	pub base: u64,
+	/// Guest physical address of the RSDT before it was cleared
+	pub rsdt: u64,
+	pub mem:  &'static mut [u8]
+}
+
+impl AcpiWindow {
+	pub fn load(name: &str) -> Self {
+		// the window follows the 8 byte base address, copy it so it's page aligned again
+		let raw = load(name);
+		let base = read::<u64>(raw, 0);
+		let mem = alloc(raw.len() - 8);
+		mem.copy_from_slice(&raw[8..]);
+
+		let mut window = Self { base, rsdt: read::<u32>(mem, 16) as u64, mem };
+		window.relocate();
+		window
+	}
+
+	pub fn offset(&self, addr: u64) -> usize {
+		assert!(addr >= self.base && addr < self.base + self.mem.len() as u64,
+			"address {:#x} outside of the fixture window", addr);
+		(addr - self.base) as usize
+	}
+
+	/// Translates a guest physical address into a host pointer.
+	pub fn host(&self, addr: u64) -> *const u8 {
+		unsafe { self.mem.as_ptr().add(self.offset(addr)) }
+	}
+
+	pub fn header(&self, addr: u64) -> &'static DescHeader {
+		unsafe { (self.host(addr) as *const DescHeader).as_ref() }.unwrap()
+	}
+
+	pub fn rsdp(&self) -> &'static RSDP {
+		unsafe { (self.mem.as_ptr() as *const RSDP).as_ref() }.unwrap()
+	}
+
+	fn relocate(&mut self) {
+		let host = self.mem.as_ptr() as u64;
+		let to_host = |base: u64, addr: u64| if addr == 0 { 0 } else { addr - base + host };
+
+		// RSDP: clear the RSDT, relocate the XSDT
+		let xsdt = read::<u64>(self.mem, 24);
+		write::<u32>(self.mem, 16, 0);
+		write::<u64>(self.mem, 24, to_host(self.base, xsdt));
+		self.mem[8] = 0;
+		self.mem[8] = fix(&self.mem[..20]);
+		self.mem[32] = 0;
+		self.mem[32] = fix(&self.mem[..36]);
+
+		// XSDT entries
+		let xsdt = self.offset(xsdt);
+		let len = read::<u32>(self.mem, xsdt + 4) as usize;
+		for entry in (xsdt + 36..xsdt + len).step_by(8) {
+			let addr = read::<u64>(self.mem, entry);
+			let table = self.offset(addr);
+			write::<u64>(self.mem, entry, to_host(self.base, addr));
+
+			if &self.mem[table..table + 4] == b"FACP" {
+				let len = read::<u32>(self.mem, table + 4) as usize;
+				write::<u32>(self.mem, table + 36, 0);
+				write::<u32>(self.mem, table + 40, 0);
+				let x_firmware_ctrl = read::<u64>(self.mem, table + 132);
+				let x_dsdt = read::<u64>(self.mem, table + 140);
+				write::<u64>(self.mem, table + 132, to_host(self.base, x_firmware_ctrl));
+				write::<u64>(self.mem, table + 140, to_host(self.base, x_dsdt));
+				fix_table(&mut self.mem[table..table + len]);
+			}
+		}
+
+		fix_table(&mut self.mem[xsdt..xsdt + len]);
+	}
+}
+
+/// Returns the byte that makes `bytes` sum to zero, assuming the checksum byte in it is zero.
+fn fix(bytes: &[u8]) -> u8 {
+	0u8.wrapping_sub(bytes.iter().fold(0u8, |acc, v| acc.wrapping_add(*v)))
+}
+
+fn fix_table(table: &mut [u8]) {
+	table[9] = 0;
+	table[9] = fix(table);
+	assert!(acpi::checksum(table));
+}
+
+/// Backs operation regions with a sparse byte array, ports read the values in `ports`
+/// and port writes are recorded in `io`.
+#[derive(Default)]
+pub struct MockHandler {
+	pub memory: BTreeMap<u64, u8>,
+	pub ports:  BTreeMap<u16, u64>,
+	pub io:     Vec<(u16, u8, u64)>
+}
+
+impl Handler for MockHandler {
+	fn read_memory(&mut self, address: u64, width: u8) -> u64 {
+		(0..width as u64 / 8).fold(0, |v, i| v | (*self.memory.get(&(address + i)).unwrap_or(&0) as u64) << (i * 8))
+	}
+
+	fn write_memory(&mut self, address: u64, width: u8, value: u64) {
+		for i in 0..width as u64 / 8 {
+			self.memory.insert(address + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	fn read_io(&mut self, port: u16, _width: u8) -> u64 {
+		self.ports.get(&port).copied().unwrap_or(0)
+	}
+
+	fn write_io(&mut self, port: u16, width: u8, value: u64) {
+		self.io.push((port, width, value));
+	}
+
+	fn read_pci(&mut self, _address: PciAddress, _offset: u16, _width: u8) -> u64 {
+		u64::MAX
+	}
+
+	fn write_pci(&mut self, _address: PciAddress, _offset: u16, _width: u8, _value: u64) {}
+}
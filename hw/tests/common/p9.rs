@@ -0,0 +1,260 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! An in-memory 9P2000.L server.
+
+use std::{cell::RefCell, collections::{BTreeMap, HashMap}, rc::Rc};
+use hw::p9::*;
+
+const EBADF: u32 = 9;
+
+pub struct Node {
+	pub qid:      u64,
+	pub dir:      bool,
+	pub data:     Vec<u8>,
+	/// Fails opens for writing with `EACCES`
+	pub readonly: bool
+}
+
+pub struct Server {
+	/// Files by path, the root is ""
+	pub nodes:   BTreeMap<String, Node>,
+	/// Paths of the fids, and the flags they were opened with
+	pub fids:    HashMap<u32, (String, Option<u32>)>,
+	pub msize:   usize,
+	pub version: &'static str,
+	/// Types of the requests served
+	pub log:     Vec<u8>,
+	/// Answers with another tag
+	pub bad_tag: bool,
+	next:        u64
+}
+
+impl Server {
+	pub fn new(msize: usize) -> Self {
+		let mut server = Self { nodes: BTreeMap::new(), fids: HashMap::new(), msize, version: VERSION,
+			log: Vec::new(), bad_tag: false, next: 0 };
+		server.add("", true, &[]);
+		server
+	}
+
+	pub fn add(&mut self, path: &str, dir: bool, data: &[u8]) {
+		self.next += 1;
+		self.nodes.insert(path.into(), Node { qid: self.next, dir, data: data.to_vec(), readonly: false });
+	}
+
+	fn qid(&self, path: &str) -> Qid {
+		let node = &self.nodes[path];
+		Qid { ty: if node.dir { QID_DIR } else { QID_FILE }, version: 0, path: node.qid }
+	}
+
+	fn children(&self, path: &str) -> Vec<String> {
+		self.nodes.keys()
+			.filter(|p| !p.is_empty() && parent(p) == path)
+			.map(|p| p.rsplit('/').next().unwrap().to_string())
+			.collect()
+	}
+
+	fn path(&self, fid: u32) -> std::result::Result<String, u32> {
+		self.fids.get(&fid).map(|(p, _)| p.clone()).ok_or(EBADF)
+	}
+
+	/// Serves a request, returns the response.
+	pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+		let mut r = Reader::new(request);
+		let (size, ty, tag) = (r.u32().unwrap() as usize, r.u8().unwrap(), r.u16().unwrap());
+		assert_eq!(size, request.len());
+		self.log.push(ty);
+		let tag = if self.bad_tag { tag.wrapping_add(1) } else { tag };
+		match self.serve(ty, &mut r, Writer::new(ty + 1, tag)) {
+			Ok(w) => w.finish(),
+			Err(e) => Writer::new(RLERROR, tag).u32(e).finish()
+		}
+	}
+
+	fn serve(&mut self, ty: u8, r: &mut Reader, w: Writer) -> std::result::Result<Writer, u32> {
+		match ty {
+			TVERSION => {
+				let msize = r.u32().unwrap() as usize;
+				assert_eq!(r.str().unwrap(), VERSION);
+				Ok(w.u32(msize.min(self.msize) as u32).str(self.version))
+			}
+			TATTACH => {
+				let (fid, afid) = (r.u32().unwrap(), r.u32().unwrap());
+				assert_eq!(afid, NOFID);
+				self.fids.insert(fid, (String::new(), None));
+				Ok(w.qid(&self.qid("")))
+			}
+			TWALK => {
+				let (fid, newfid, n) = (r.u32().unwrap(), r.u32().unwrap(), r.u16().unwrap() as usize);
+				assert!(n <= MAXWELEM);
+				if newfid != fid && self.fids.contains_key(&newfid) {
+					return Err(EBADF);
+				}
+				let mut path = self.path(fid)?;
+				let mut qids = Vec::new();
+				for i in 0..n {
+					let next = join(&path, r.str().unwrap());
+					if !self.nodes.contains_key(&next) {
+						if i == 0 {
+							return Err(ENOENT);
+						}
+						break;
+					}
+					qids.push(self.qid(&next));
+					path = next;
+				}
+				if qids.len() == n {
+					self.fids.insert(newfid, (path, None));
+				}
+				Ok(qids.iter().fold(w.u16(qids.len() as u16), |w, q| w.qid(q)))
+			}
+			TLOPEN => {
+				let (fid, flags) = (r.u32().unwrap(), r.u32().unwrap());
+				let path = self.path(fid)?;
+				if self.nodes[&path].readonly && flags & 3 != O_RDONLY {
+					return Err(EACCES);
+				} else if self.nodes[&path].dir && flags & 3 != O_RDONLY {
+					return Err(EISDIR);
+				}
+				self.fids.get_mut(&fid).unwrap().1 = Some(flags);
+				Ok(w.qid(&self.qid(&path)).u32(0))
+			}
+			TLCREATE | TMKDIR => {
+				let (fid, name) = (r.u32().unwrap(), r.str().unwrap());
+				let flags = if ty == TLCREATE { r.u32().unwrap() } else { 0 };
+				let dir = self.path(fid)?;
+				let path = join(&dir, name);
+				if self.nodes.contains_key(&path) {
+					return Err(EEXIST);
+				}
+				self.add(&path, ty == TMKDIR, &[]);
+				if ty == TMKDIR {
+					return Ok(w.qid(&self.qid(&path)));
+				}
+				self.fids.insert(fid, (path.clone(), Some(flags)));
+				Ok(w.qid(&self.qid(&path)).u32(0))
+			}
+			TREAD => {
+				let (fid, offset, count) = (r.u32().unwrap(), r.u64().unwrap() as usize, r.u32().unwrap() as usize);
+				assert!(IO_HEADER_LEN + count <= self.msize);
+				let Some((path, Some(_))) = self.fids.get(&fid) else { return Err(EBADF) };
+				let data = &self.nodes[path].data;
+				let data = &data[offset.min(data.len())..(offset + count).min(data.len())];
+				Ok(w.u32(data.len() as u32).bytes(data))
+			}
+			TWRITE => {
+				let (fid, offset, count) = (r.u32().unwrap(), r.u64().unwrap() as usize, r.u32().unwrap() as usize);
+				let data = r.bytes(count).unwrap();
+				let Some((path, Some(flags))) = self.fids.get(&fid) else { return Err(EBADF) };
+				if flags & 3 == O_RDONLY {
+					return Err(EBADF);
+				}
+				let file = &mut self.nodes.get_mut(path).unwrap().data;
+				if file.len() < offset + count {
+					file.resize(offset + count, 0);
+				}
+				file[offset..offset + count].copy_from_slice(data);
+				Ok(w.u32(count as u32))
+			}
+			TREADDIR => {
+				let (fid, offset, count) = (r.u32().unwrap(), r.u64().unwrap() as usize, r.u32().unwrap() as usize);
+				let Some((path, Some(_))) = self.fids.get(&fid) else { return Err(EBADF) };
+				let path = path.clone();
+				let names = [".".to_string(), "..".to_string()].into_iter().chain(self.children(&path));
+				let mut entries = Vec::new();
+				for (i, name) in names.enumerate().skip(offset) {
+					let qid = match name.as_str() {
+						"." | ".." => self.qid(&path),
+						name => self.qid(&join(&path, name))
+					};
+					let entry = Writer::new(0, 0).qid(&qid).u64(i as u64 + 1).u8(if qid.ty == QID_DIR { 4 } else { 8 }).str(&name).finish();
+					if entries.len() + entry.len() - HEADER_LEN > count {
+						break;
+					}
+					entries.extend_from_slice(&entry[HEADER_LEN..]);
+				}
+				Ok(w.u32(entries.len() as u32).bytes(&entries))
+			}
+			TGETATTR => {
+				let path = self.path(r.u32().unwrap())?;
+				let node = &self.nodes[&path];
+				let mode = if node.dir { S_IFDIR | 0o755 } else { S_IFREG | 0o644 };
+				Ok(w.u64(GETATTR_BASIC).qid(&self.qid(&path)).u32(mode).u32(1000).u32(1000).u64(1).u64(0)
+					.u64(node.data.len() as u64).u64(4096).u64((node.data.len() as u64 + 511) / 512)
+					.u64(1).u64(2).u64(3).u64(4).u64(5).u64(6).u64(0).u64(0).u64(0).u64(0))
+			}
+			TSETATTR => {
+				let path = self.path(r.u32().unwrap())?;
+				let valid = r.u32().unwrap();
+				let (_mode, _uid, _gid, size) = (r.u32().unwrap(), r.u32().unwrap(), r.u32().unwrap(), r.u64().unwrap());
+				if valid & SETATTR_SIZE != 0 {
+					self.nodes.get_mut(&path).unwrap().data.resize(size as usize, 0);
+				}
+				Ok(w)
+			}
+			TFSYNC => self.path(r.u32().unwrap()).map(|_| w),
+			TUNLINKAT => {
+				let (dir, name, flags) = (self.path(r.u32().unwrap())?, r.str().unwrap(), r.u32().unwrap());
+				let path = join(&dir, name);
+				match self.nodes.get(&path) {
+					None => return Err(ENOENT),
+					Some(n) if n.dir && flags & AT_REMOVEDIR == 0 => return Err(EISDIR),
+					Some(n) if n.dir && !self.children(&path).is_empty() => return Err(ENOTEMPTY),
+					Some(_) => ()
+				}
+				self.nodes.remove(&path);
+				Ok(w)
+			}
+			TCLUNK => self.fids.remove(&r.u32().unwrap()).map(|_| w).ok_or(EBADF),
+			_ => Err(EINVAL)
+		}
+	}
+}
+
+fn join(dir: &str, name: &str) -> String {
+	match dir {
+		"" => name.into(),
+		dir => format!("{}/{}", dir, name)
+	}
+}
+
+fn parent(path: &str) -> &str {
+	path.rsplit_once('/').map_or("", |(p, _)| p)
+}
+
+/// A channel to a shared server, requests larger than `max` fail.
+#[derive(Clone)]
+pub struct Fake(pub Rc<RefCell<Server>>, pub usize);
+
+impl Channel for Fake {
+	fn max_size(&self) -> usize {
+		self.1
+	}
+
+	fn request(&mut self, request: &[u8], response: &mut [u8]) -> hw::p9::Result<usize> {
+		assert!(request.len() <= self.1);
+		let r = self.0.borrow_mut().handle(request);
+		response.get_mut(..r.len()).ok_or(Error::Transport)?.copy_from_slice(&r);
+		Ok(r.len())
+	}
+}
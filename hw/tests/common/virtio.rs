@@ -0,0 +1,370 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A virtio device behind a mock transport, shared by the tests of the device types.
+
+use std::{alloc::Layout, cell::RefCell, collections::BTreeMap, rc::Rc};
+use hw::{dma::Dma, virtio::*};
+
+/// Queues of the mock device
+pub const QUEUES: u16 = 2;
+
+/// The device side of an enabled queue.
+pub struct DeviceQueue {
+	pub size:      u16,
+	pub desc:      u64,
+	pub driver:    u64,
+	pub device:    u64,
+	/// Split: the next available ring entry. Packed: the next descriptor to read and
+	/// its wrap counter.
+	pub next:      u16,
+	pub wrap:      bool,
+	/// Split: the used ring index. Packed: the next descriptor to write and its wrap
+	/// counter.
+	pub used:      u16,
+	pub used_wrap: bool
+}
+
+#[derive(Default)]
+pub struct State {
+	pub allocs:     BTreeMap<usize, Layout>,
+	pub id:         u32,
+	pub status:     u8,
+	/// The status isn't cleared on reset
+	pub stuck:      bool,
+	pub features:   u64,
+	pub accepted:   Option<u64>,
+	/// The device refuses FEATURES_OK
+	pub refuse:     bool,
+	pub max:        u16,
+	/// Queues the device has, `QUEUES` by default
+	pub count:      u16,
+	pub queues:     BTreeMap<u16, DeviceQueue>,
+	pub notified:   Vec<u16>,
+	pub isr:        u32,
+	pub config:     Vec<u8>,
+	/// Configuration reads after which the device changes the configuration
+	pub changes:    Vec<usize>,
+	pub reads:      usize,
+	pub generation: u32,
+	/// Chains that went through an indirect table
+	pub indirect:   usize,
+	/// Serves the chains of all queues while the driver waits, see `Mock::process`
+	pub handler:    Option<Rc<Handler>>,
+	/// Queues the handler serves, all if empty
+	pub serve:      Vec<u16>,
+	/// Updates the configuration after the driver wrote to the offset
+	pub on_write:   Option<Rc<ConfigWrite>>
+}
+
+pub type Handler = dyn Fn(u16, &[u8], usize) -> Vec<u8>;
+pub type ConfigWrite = dyn Fn(&mut Vec<u8>, usize);
+
+/// A device behind a transport that serves its queues from the memory the driver
+/// allocated, physical addresses are virtual addresses.
+#[derive(Clone)]
+pub struct Mock(pub Rc<RefCell<State>>);
+
+pub unsafe fn rd<T: Copy>(addr: u64) -> T {
+	(addr as *const T).read_volatile()
+}
+
+pub unsafe fn wr<T: Copy>(addr: u64, v: T) {
+	(addr as *mut T).write_volatile(v)
+}
+
+impl Mock {
+	/// A device with a 32 byte configuration of bytes counting up.
+	pub fn new(ty: DeviceType, features: u64) -> Self {
+		Self(Rc::new(RefCell::new(State { id: ty as u32, features, max: 16, count: QUEUES, config: (0..32).collect(), ..State::default() })))
+	}
+
+	/// Walks a chain, returns the readable bytes, the writable buffers, the number of
+	/// descriptors in the ring and the buffer id of a packed chain. Packed chains
+	/// continue in the following descriptors.
+	fn chain(q: &DeviceQueue, packed: bool, head: u16, indirect: &mut usize) -> (Vec<u8>, Vec<(u64, u32)>, u16, u16) {
+		let (mut data, mut writable) = (Vec::new(), Vec::new());
+		let (count, link) = Self::walk(q.desc, q.size, packed, true, head, &mut data, &mut writable, indirect);
+		(data, writable, count, link)
+	}
+
+	/// Walks the descriptors of a ring or of an indirect table, packed tables are read in
+	/// order.
+	#[allow(clippy::too_many_arguments)]
+	fn walk(base: u64, size: u16, packed: bool, ring: bool, head: u16, data: &mut Vec<u8>,
+		writable: &mut Vec<(u64, u32)>, indirect: &mut usize) -> (u16, u16) {
+		let (mut i, mut count) = (head, 0);
+		loop {
+			let d = base + i as u64 * 16;
+			let (addr, len) = unsafe { (rd::<u64>(d), rd::<u32>(d + 8)) };
+			let (flags, link) = match packed {
+				true  => unsafe { (rd::<u16>(d + 14), rd::<u16>(d + 12)) },
+				false => unsafe { (rd::<u16>(d + 12), rd::<u16>(d + 14)) }
+			};
+			count += 1;
+
+			if flags & DESC_F_INDIRECT != 0 {
+				assert!(ring, "nested indirect table");
+				assert_eq!(flags & DESC_F_NEXT, 0, "indirect descriptor in a chain");
+				*indirect += 1;
+				Self::walk(addr, (len / 16) as u16, packed, false, 0, data, writable, indirect);
+			} else if flags & DESC_F_WRITE != 0 {
+				writable.push((addr, len));
+			} else {
+				assert!(writable.is_empty(), "readable after writable buffer");
+				data.extend_from_slice(unsafe { std::slice::from_raw_parts(addr as *const u8, len as usize) });
+			}
+
+			let last = match packed && !ring {
+				true  => i + 1 == size,
+				false => flags & DESC_F_NEXT == 0
+			};
+			if last {
+				return (count, link);
+			}
+			i = match packed {
+				true  => (i + 1) % size,
+				false => link
+			};
+		}
+	}
+
+	/// Uses all available chains of a queue, in reverse order if `reverse`. `f` gets the
+	/// readable bytes and the size of the writable buffers, its response is written to
+	/// the writable buffers. Returns the number of chains.
+	pub fn process(&self, queue: u16, reverse: bool, f: impl Fn(&[u8], usize) -> Vec<u8>) -> usize {
+		self.process_some(queue, reverse, usize::MAX, f)
+	}
+
+	/// Like `process`, but uses at most `limit` chains.
+	pub fn process_some(&self, queue: u16, reverse: bool, limit: usize, f: impl Fn(&[u8], usize) -> Vec<u8>) -> usize {
+		let mut s = self.0.borrow_mut();
+		let packed = s.accepted.unwrap_or(0) & FEATURE_RING_PACKED != 0;
+		let q = s.queues.get_mut(&queue).expect("queue not enabled");
+		let (mut done, mut indirect) = (Vec::new(), 0);
+
+		let respond = |data: Vec<u8>, writable: Vec<(u64, u32)>| {
+			let response = f(&data, writable.iter().map(|(_, len)| *len as usize).sum());
+			let mut response = &response[..];
+			let mut written = 0;
+			for (addr, len) in writable {
+				let n = response.len().min(len as usize);
+				unsafe { std::ptr::copy_nonoverlapping(response.as_ptr(), addr as *mut u8, n) };
+				response = &response[n..];
+				written += n as u32;
+			}
+			written
+		};
+
+		match packed {
+			false => while done.len() < limit && q.next != unsafe { rd::<u16>(q.driver + 2) } {
+				let head = unsafe { rd::<u16>(q.driver + 4 + 2 * (q.next % q.size) as u64) };
+				q.next = q.next.wrapping_add(1);
+				let (data, writable, count, _) = Self::chain(q, false, head, &mut indirect);
+				done.push((head, respond(data, writable), count));
+			}
+			true => while done.len() < limit {
+				let flags = unsafe { rd::<u16>(q.desc + q.next as u64 * 16 + 14) };
+				if (flags & DESC_F_AVAIL != 0) != q.wrap || (flags & DESC_F_USED != 0) == q.wrap {
+					break;
+				}
+				let (data, writable, count, id) = Self::chain(q, true, q.next, &mut indirect);
+				done.push((id, respond(data, writable), count));
+				q.next += count;
+				if q.next >= q.size {
+					q.next -= q.size;
+					q.wrap = !q.wrap;
+				}
+			}
+		}
+
+		if reverse {
+			done.reverse();
+		}
+
+		let chains = done.len();
+		for (id, len, count) in done {
+			match packed {
+				false => unsafe {
+					let elem = q.device + 4 + 8 * (q.used % q.size) as u64;
+					wr(elem, id as u32);
+					wr(elem + 4, len);
+					q.used = q.used.wrapping_add(1);
+					wr(q.device + 2, q.used);
+				}
+				true => unsafe {
+					let d = q.desc + q.used as u64 * 16;
+					wr(d + 8, len);
+					wr(d + 12, id);
+					wr(d + 14, if q.used_wrap { DESC_F_AVAIL | DESC_F_USED } else { 0 });
+					q.used += count;
+					if q.used >= q.size {
+						q.used -= q.size;
+						q.used_wrap = !q.used_wrap;
+					}
+				}
+			}
+		}
+		s.indirect += indirect;
+		chains
+	}
+
+	/// Asks the driver not to notify the device about a queue.
+	pub fn suppress(&self, queue: u16, suppress: bool) {
+		let s = self.0.borrow();
+		let q = &s.queues[&queue];
+		let packed = s.accepted.unwrap_or(0) & FEATURE_RING_PACKED != 0;
+		unsafe {
+			match packed {
+				false => wr::<u16>(q.device, if suppress { 1 } else { 0 }),
+				true  => wr::<u16>(q.device + 2, if suppress { EVENT_FLAGS_DISABLE } else { EVENT_FLAGS_ENABLE })
+			}
+		}
+	}
+
+	/// The flags the driver set to suppress interrupts.
+	pub fn interrupts_suppressed(&self, queue: u16) -> bool {
+		let s = self.0.borrow();
+		let q = &s.queues[&queue];
+		let packed = s.accepted.unwrap_or(0) & FEATURE_RING_PACKED != 0;
+		unsafe {
+			match packed {
+				false => rd::<u16>(q.driver) & 1 != 0,
+				true  => rd::<u16>(q.driver + 2) == EVENT_FLAGS_DISABLE
+			}
+		}
+	}
+
+	pub fn check_freed(&self) {
+		assert!(self.0.borrow().allocs.is_empty(), "leaked DMA memory");
+	}
+}
+
+impl Dma for Mock {
+	fn alloc(&mut self, size: usize, align: usize) -> Option<(*mut u8, u64)> {
+		let layout = Layout::from_size_align(size, align).unwrap();
+		let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+		self.0.borrow_mut().allocs.insert(ptr as usize, layout);
+		Some((ptr, ptr as u64))
+	}
+
+	fn free(&mut self, virt: *mut u8, _size: usize) {
+		let layout = self.0.borrow_mut().allocs.remove(&(virt as usize)).expect("double free");
+		unsafe { std::alloc::dealloc(virt, layout) };
+	}
+
+	fn phys(&mut self, virt: *const u8) -> u64 {
+		virt as u64
+	}
+
+	fn stall(&mut self, _us: u64) {
+		let handler = self.0.borrow().handler.clone();
+		if let Some(handler) = handler {
+			let s = self.0.borrow();
+			let queues = s.queues.keys().copied().filter(|q| s.serve.is_empty() || s.serve.contains(q)).collect::<Vec<_>>();
+			drop(s);
+			for queue in queues {
+				self.process(queue, false, |data, len| handler(queue, data, len));
+			}
+		}
+	}
+}
+
+impl Transport for Mock {
+	fn device_id(&self) -> u32 {
+		self.0.borrow().id
+	}
+
+	fn status(&self) -> u8 {
+		self.0.borrow().status
+	}
+
+	fn set_status(&mut self, status: u8) {
+		let mut s = self.0.borrow_mut();
+		match status {
+			0 if s.stuck => (),
+			0 => {
+				s.status = 0;
+				s.accepted = None;
+				s.queues.clear();
+			}
+			_ if s.refuse => s.status = status & !STATUS_FEATURES_OK,
+			_ => s.status = status
+		}
+	}
+
+	fn device_features(&mut self) -> u64 {
+		self.0.borrow().features
+	}
+
+	fn set_driver_features(&mut self, features: u64) {
+		self.0.borrow_mut().accepted = Some(features);
+	}
+
+	fn max_queue_size(&mut self, queue: u16) -> u16 {
+		match queue < self.0.borrow().count {
+			true  => self.0.borrow().max,
+			false => 0
+		}
+	}
+
+	fn queue_enabled(&mut self, queue: u16) -> bool {
+		self.0.borrow().queues.contains_key(&queue)
+	}
+
+	fn enable_queue(&mut self, queue: u16, size: u16, desc: u64, driver: u64, device: u64) {
+		self.0.borrow_mut().queues.insert(queue, DeviceQueue {
+			size, desc, driver, device, next: 0, wrap: true, used: 0, used_wrap: true
+		});
+	}
+
+	fn notify(&mut self, queue: u16) {
+		self.0.borrow_mut().notified.push(queue);
+	}
+
+	fn ack_interrupt(&mut self) -> u32 {
+		std::mem::take(&mut self.0.borrow_mut().isr)
+	}
+
+	fn config_generation(&self) -> u32 {
+		self.0.borrow().generation
+	}
+
+	fn read_config(&self, offset: usize, width: usize) -> u32 {
+		let mut s = self.0.borrow_mut();
+		let value = s.config[offset..offset + width].iter().rev().fold(0, |v, &b| v << 8 | b as u32);
+		s.reads += 1;
+		if s.changes.contains(&s.reads) {
+			s.generation += 1;
+			s.config.iter_mut().for_each(|b| *b = !*b);
+		}
+		value
+	}
+
+	fn write_config(&mut self, offset: usize, width: usize, value: u32) {
+		let mut s = self.0.borrow_mut();
+		s.config[offset..offset + width].copy_from_slice(&value.to_le_bytes()[..width]);
+		if let Some(f) = s.on_write.clone() {
+			f(&mut s.config, offset);
+		}
+	}
+}
@@ -0,0 +1,110 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use hw::{compress::*, crc};
+
+const WORDS: [&[u8]; 16] = [
+	b"the", b"quick", b"brown", b"fox", b"jumps", b"over", b"lazy", b"dog", b"block", b"device", b"extent", b"tree",
+	b"node", b"leaf", b"kernel", b"page"
+];
+
+/// The text `fixtures/generate.py` compresses.
+fn text(n: usize) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut x = 1u32;
+	while out.len() < n {
+		x = x.wrapping_mul(1103515245).wrapping_add(12345) & 0x7FFF_FFFF;
+		out.extend_from_slice(WORDS[(x >> 16 & 15) as usize]);
+		out.push(if x & 0x300 == 0 { b'\n' } else { b' ' });
+	}
+	out.truncate(n);
+	out
+}
+
+fn noise(n: usize) -> Vec<u8> {
+	let mut x = 1u32;
+	(0..n).map(|_| {
+		x = x.wrapping_mul(1103515245).wrapping_add(12345) & 0x7FFF_FFFF;
+		(x >> 16) as u8
+	}).collect()
+}
+
+#[test]
+fn checksums() {
+	assert_eq!(crc::crc32(b"123456789"), 0xCBF4_3926);
+	assert_eq!(crc::crc32c(b"123456789"), 0xE306_9283);
+	assert_eq!(crc::crc32c_update(crc::crc32c(b"1234"), b"56789"), 0xE306_9283);
+	assert_eq!(crc::xxhash64(b"", 0), 0xEF46_DB37_51D8_E999);
+	assert_eq!(crc::xxhash64(b"abc", 0), 0x44BC_2CF5_AD77_0999);
+	assert_eq!(crc::xxhash64(&text(1000), 0), crc::xxhash64(&text(1000), 0));
+	assert_ne!(crc::xxhash64(b"abc", 1), crc::xxhash64(b"abc", 0));
+}
+
+#[test]
+fn zlib() {
+	let text = text(300000);
+	assert_eq!(zlib_decompress(common::load("compress/text.zlib"), text.len()).unwrap(), text);
+	assert_eq!(zlib_decompress(common::load("compress/short.zlib"), 40).unwrap(), text[..40]);
+	assert_eq!(zlib_decompress(common::load("compress/stored.zlib"), 5000).unwrap(), text[..5000]);
+
+	let data = common::load("compress/text.zlib");
+	assert_eq!(zlib_decompress(data, text.len() - 1), Err(Error::TooLarge));
+	let last = data.len() - 1;
+	data[last] ^= 1;
+	assert_eq!(zlib_decompress(data, text.len()), Err(Error::Corrupted));
+	assert_eq!(zlib_decompress(&[0x78, 0x9C], 10), Err(Error::Corrupted));
+	assert_eq!(inflate(&[0x03, 0x00], 10).unwrap(), b"");
+}
+
+#[test]
+fn zstd() {
+	let text = text(300000);
+	assert_eq!(zstd_decompress(common::load("compress/text-1.zst"), text.len()).unwrap(), text);
+	assert_eq!(zstd_decompress(common::load("compress/text-19.zst"), text.len()).unwrap(), text);
+
+	let mixed = [&noise(50000)[..], &[0; 100000], &text[..50000]].concat();
+	assert_eq!(zstd_decompress(common::load("compress/mixed.zst"), mixed.len()).unwrap(), mixed);
+	assert_eq!(zstd_decompress(common::load("compress/mixed.zst"), mixed.len() - 1), Err(Error::TooLarge));
+
+	// the content checksum covers the frame
+	let data = common::load("compress/text-1.zst");
+	let last = data.len() - 1;
+	data[last] ^= 1;
+	assert_eq!(zstd_decompress(data, text.len()), Err(Error::Corrupted));
+	assert_eq!(zstd_decompress(b"not zstd", 100), Err(Error::Corrupted));
+
+	// a skippable frame followed by a frame with a raw and an RLE block
+	let frame = [
+		&[0x50, 0x2A, 0x4D, 0x18, 2, 0, 0, 0, 0xAA, 0xBB][..],
+		&[0x28, 0xB5, 0x2F, 0xFD, 0x20, 5],
+		&[3 << 3, 0, 0], b"abc",
+		&[1 | 1 << 1 | 2 << 3, 0, 0], b"z"
+	].concat();
+	assert_eq!(zstd_decompress(&frame, 5).unwrap(), b"abczz");
+
+	// padding after a single frame
+	let padded = [&frame[10..], &[0; 7]].concat();
+	assert_eq!(zstd_decompress(&padded, 5), Err(Error::Corrupted));
+	assert_eq!(zstd_decompress_frame(&padded, 5).unwrap(), b"abczz");
+}
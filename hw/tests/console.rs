@@ -0,0 +1,159 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use hw::{console::*, font::FONT};
+
+const W: usize = CELL_WIDTH;
+const H: usize = CELL_HEIGHT;
+
+/// The pixels of a cell, line by line.
+fn cell(fb: &Framebuffer, col: usize, row: usize) -> Vec<u32> {
+	(0..H).flat_map(|y| {
+		let start = (row * H + y) * fb.stride + col * W;
+		fb.pixels[start..start + W].to_vec()
+	}).collect()
+}
+
+/// The pixels of a character drawn in `fg` on `bg`.
+fn glyph(c: char, fg: u32, bg: u32, format: PixelFormat) -> Vec<u32> {
+	(0..H).flat_map(|y| (0..W).map(move |x| {
+		let alpha = match c {
+			' ' => 0,
+			c => FONT[c as usize - 0x21][x][y]
+		};
+		format.pixel(blend(fg, bg, alpha))
+	})).collect()
+}
+
+fn check(console: &Console, col: usize, row: usize, c: char, fg: u32, bg: u32) {
+	let fb = console.framebuffer();
+	assert!(cell(fb, col, row) == glyph(c, fg, bg, fb.format), "cell {},{} isn't {:?} in {:06x} on {:06x}", col, row, c, fg, bg);
+}
+
+#[test]
+fn framebuffer() {
+	let mut pixels = vec![0; 100];
+	assert!(Framebuffer::new(&mut pixels, 10, 11, 10, PixelFormat::Rgbx).is_none());
+	assert!(Framebuffer::new(&mut pixels, 11, 9, 10, PixelFormat::Rgbx).is_none());
+	let mut fb = Framebuffer::new(&mut pixels, 8, 10, 10, PixelFormat::Rgbx).unwrap();
+	fb.fill(6, 8, 5, 5, 1);
+	assert_eq!(pixels.iter().filter(|&&p| p == 1).count(), 4);
+	assert_eq!((pixels[86], pixels[88], pixels[96]), (1, 0, 1));
+
+	assert_eq!(PixelFormat::Rgbx.pixel(0x123456), 0x563412);
+	assert_eq!(PixelFormat::Bgrx.pixel(0x123456), 0x123456);
+	assert_eq!((blend(0xFFFFFF, 0, 255), blend(0xFFFFFF, 0, 0), blend(0xFF0000, 0x0000FF, 128)), (0xFFFFFF, 0, 0x80007F));
+	assert_eq!(Rect::new(1, 2, 3, 4).union(Rect::new(0, 5, 2, 2)), Rect::new(0, 2, 4, 5));
+}
+
+#[test]
+fn text() {
+	// the stride leaves room that isn't drawn on
+	let (width, stride) = (5 * W + 3, 6 * W);
+	let mut pixels = vec![0xDEAD; stride * 3 * H];
+	let mut console = Console::new(Framebuffer::new(&mut pixels, width, 3 * H, stride, PixelFormat::Bgrx).unwrap());
+	assert_eq!(console.size(), (5, 3));
+	assert_eq!(console.take_dirty(), Some(Rect::new(0, 0, width as u32, 3 * H as u32)));
+
+	console.write("Hi!");
+	assert_eq!(console.cursor(), (3, 0));
+	check(&console, 0, 0, 'H', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 2, 0, '!', DEFAULT_FG, DEFAULT_BG);
+	assert_eq!(console.take_dirty(), Some(Rect::new(0, 0, 3 * W as u32, H as u32)));
+	assert_eq!(console.take_dirty(), None);
+
+	// wraps, tabs and backspaces stay on the line
+	console.write("abc\tx\x08y");
+	check(&console, 3, 0, 'a', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 0, 1, 'c', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 4, 1, 'y', DEFAULT_FG, DEFAULT_BG);
+	console.write("\rz\n");
+	check(&console, 0, 1, 'z', DEFAULT_FG, DEFAULT_BG);
+	assert_eq!(console.cursor(), (0, 2));
+
+	// characters outside of the font are drawn as the replacement glyph
+	console.write("\u{e4}");
+	let fb = console.framebuffer();
+	assert!(cell(fb, 0, 2) != glyph(' ', DEFAULT_FG, DEFAULT_BG, fb.format));
+	assert!(pixels.chunks(stride).all(|line| line[width..].iter().all(|&p| p == 0xDEAD)));
+}
+
+#[test]
+fn scrolling() {
+	let mut pixels = vec![0; 4 * W * 2 * H];
+	let mut console = Console::new(Framebuffer::new(&mut pixels, 4 * W, 2 * H, 4 * W, PixelFormat::Rgbx).unwrap());
+	console.write("a\nb\nc");
+	check(&console, 0, 0, 'b', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 0, 1, 'c', DEFAULT_FG, DEFAULT_BG);
+	assert_eq!(console.cursor(), (1, 1));
+	assert_eq!(console.take_dirty(), Some(Rect::new(0, 0, 4 * W as u32, 2 * H as u32)));
+
+	// the new line gets the current background
+	console.write("\x1b[42m\n");
+	check(&console, 0, 0, 'c', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 0, 1, ' ', DEFAULT_FG, PALETTE[2]);
+}
+
+#[test]
+fn colors() {
+	let mut pixels = vec![0; 8 * W * H];
+	let mut console = Console::new(Framebuffer::new(&mut pixels, 8 * W, H, 8 * W, PixelFormat::Rgbx).unwrap());
+	console.write("\x1b[31mA\x1b[1mB\x1b[22;44mC\x1b[0mD\x1b[93;101mE\x1b[38;5;196;48;5;21mF\x1b[38;2;1;2;3mG\x1b[39;49mH");
+	check(&console, 0, 0, 'A', PALETTE[1], DEFAULT_BG);
+	check(&console, 1, 0, 'B', PALETTE[9], DEFAULT_BG);
+	check(&console, 2, 0, 'C', PALETTE[1], PALETTE[4]);
+	check(&console, 3, 0, 'D', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 4, 0, 'E', PALETTE[11], PALETTE[9]);
+	check(&console, 5, 0, 'F', 0xFF0000, 0x0000FF);
+	check(&console, 6, 0, 'G', 0x010203, 0x0000FF);
+	check(&console, 7, 0, 'H', DEFAULT_FG, DEFAULT_BG);
+}
+
+#[test]
+fn control_sequences() {
+	let mut pixels = vec![0; 4 * W * 3 * H];
+	let mut console = Console::new(Framebuffer::new(&mut pixels, 4 * W, 3 * H, 4 * W, PixelFormat::Rgbx).unwrap());
+	console.write("abcd\nefgh\nijkl");
+
+	console.write("\x1b[2;3H");
+	assert_eq!(console.cursor(), (2, 1));
+	console.write("\x1b[K");
+	check(&console, 1, 1, 'f', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 2, 1, ' ', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 3, 1, ' ', DEFAULT_FG, DEFAULT_BG);
+
+	console.write("\x1b[A\x1b[2D\x1b[1K");
+	assert_eq!(console.cursor(), (0, 0));
+	check(&console, 0, 0, ' ', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 1, 0, 'b', DEFAULT_FG, DEFAULT_BG);
+
+	// unknown and private sequences are consumed
+	console.write("\x1b[?25l\x1b[5B\x1b[9C\x1b[3x");
+	assert_eq!(console.cursor(), (3, 2));
+	console.write("\x1b[J");
+	check(&console, 2, 2, 'k', DEFAULT_FG, DEFAULT_BG);
+	check(&console, 3, 2, ' ', DEFAULT_FG, DEFAULT_BG);
+
+	console.write("\x1b[2J\x1b[H");
+	assert_eq!(console.cursor(), (0, 0));
+	assert!(pixels.iter().all(|&p| p == 0));
+}
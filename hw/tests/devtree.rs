@@ -0,0 +1,170 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use hw::devtree::*;
+
+fn load(name: &str) -> &'static FdtHeader {
+	unsafe { (common::load(name).as_ptr() as *const FdtHeader).as_ref() }.unwrap()
+}
+
+fn prop<'a>(fdt: &'a FdtHeader, path: &'a [&'a str]) -> FdtValue<'a> {
+	match fdt.get(path).next() {
+		Some(FdtStructureToken::Prop { value, .. }) => value,
+		v => panic!("expected property {:?}, got {:?}", path, v)
+	}
+}
+
+fn cells(value: FdtValue) -> Vec<u32> {
+	value.chunks(4).map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]])).collect()
+}
+
+#[test]
+fn header() {
+	for name in ["dtb/riscv64-virt.dtb", "dtb/aarch64-virt.dtb"] {
+		let fdt = load(name);
+		assert!(fdt.is_valid(), "{}", name);
+		assert_eq!(fdt.version.to_be(), 17);
+		assert_eq!(fdt.last_comp_version.to_be(), 16);
+	}
+
+	let buf = common::load("dtb/riscv64-virt.dtb");
+	buf[0] = 0;
+	assert!(!unsafe { (buf.as_ptr() as *const FdtHeader).as_ref() }.unwrap().is_valid());
+}
+
+#[test]
+fn memory_reservation_block() {
+	assert_eq!(load("dtb/riscv64-virt.dtb").memory_reservation_block().count(), 0);
+
+	let entries = load("dtb/aarch64-virt.dtb").memory_reservation_block_slice();
+	assert_eq!(entries.len(), 1);
+	assert_eq!(entries[0].address.to_be(), 0x40000000);
+	assert_eq!(entries[0].size.to_be(), 0x200000);
+}
+
+#[test]
+fn structure_block() {
+	let fdt = load("dtb/riscv64-virt.dtb");
+	let mut depth = 0usize;
+	let mut nodes = 0;
+
+	for token in fdt.structure_block() {
+		match token {
+			FdtStructureToken::BeginNone { .. } => { depth += 1; nodes += 1; }
+			FdtStructureToken::EndNode          => depth -= 1,
+			FdtStructureToken::Prop { .. }      => assert!(depth > 0)
+		}
+	}
+
+	// root, chosen, memory, cpus, 4 cpus with an interrupt controller each, soc with 12 devices
+	assert_eq!(depth, 0);
+	assert_eq!(nodes, 1 + 1 + 1 + 1 + 4 * 2 + 1 + 12);
+
+	match fdt.structure_block().next() {
+		Some(FdtStructureToken::BeginNone { name, .. }) => assert_eq!(name, ""),
+		v => panic!("expected the root node, got {:?}", v)
+	}
+}
+
+#[test]
+fn path_riscv() {
+	let fdt = load("dtb/riscv64-virt.dtb");
+	assert_eq!(Into::<Option<&str>>::into(prop(fdt, &["", "model"])), Some("riscv-virtio,qemu"));
+	assert_eq!(cells(prop(fdt, &["", "memory", "reg"])), [0, 0x80000000, 1, 0]);
+	assert_eq!(Into::<Option<u32>>::into(prop(fdt, &["", "cpus", "timebase-frequency"])), Some(10000000));
+	assert_eq!(fdt.get(&["", "cpus", "cpu"]).count(), 4);
+	assert_eq!(fdt.get(&["", "soc", "virtio_mmio"]).count(), 8);
+	assert_eq!(Into::<Option<&str>>::into(prop(fdt, &["", "chosen", "stdout-path"])), Some("/soc/serial@10000000"));
+
+	let regs = fdt.get(&["", "soc", "virtio_mmio", "reg"])
+		.map(|v| match v {
+			FdtStructureToken::Prop { value, .. } => cells(value)[1],
+			v => panic!("expected a property, got {:?}", v)
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(regs, (1..=8).rev().map(|i| 0x10000000 + i * 0x1000).collect::<Vec<_>>());
+
+	assert!(prop(fdt, &["", "soc", "pci", "dma-coherent"]).is_empty());
+	assert_eq!(fdt.get(&["", "soc", "missing"]).count(), 0);
+}
+
+#[test]
+fn path_aarch64() {
+	let fdt = load("dtb/aarch64-virt.dtb");
+	assert_eq!(cells(prop(fdt, &["", "memory", "reg"])), [0, 0x40000000, 1, 0]);
+	assert_eq!(cells(prop(fdt, &["", "pcie", "reg"])), [0x40, 0x10000000, 0, 0x10000000]);
+	assert_eq!(cells(prop(fdt, &["", "intc", "its", "reg"])), [0, 0x08080000, 0, 0x20000]);
+	assert_eq!(Into::<Option<&str>>::into(prop(fdt, &["", "psci", "method"])), Some("hvc"));
+	assert_eq!(fdt.get(&["", "virtio_mmio"]).count(), 4);
+	assert_eq!(fdt.get(&["", "cpus", "cpu"]).count(), 4);
+
+	// the string list is returned as a whole
+	assert_eq!(&*prop(fdt, &["", "pl011", "compatible"]), b"arm,pl011\0arm,primecell\0");
+}
+
+#[test]
+fn values() {
+	let fdt = load("dtb/riscv64-virt.dtb");
+	let reg = prop(fdt, &["", "memory", "reg"]);
+	assert_eq!(Into::<Option<u32>>::into(reg), None);
+	assert_eq!(reg.as_slice::<u32>().map(|v| v.iter().map(|v| v.to_be()).collect::<Vec<_>>()), Some(vec![0, 0x80000000, 1, 0]));
+	assert_eq!(reg.as_slice::<[u8; 3]>(), None);
+	assert_eq!(Into::<Option<u64>>::into(prop(fdt, &["", "cpus", "cpu", "reg"])), None);
+	assert_eq!(Into::<Option<u32>>::into(prop(fdt, &["", "cpus", "cpu", "reg"])), Some(0));
+}
+
+/// Returns the value of the property `name` among the direct properties of a node.
+fn node_prop<'a>(tokens: FdtStructureIter<'a>, name: &str) -> Option<FdtValue<'a>> {
+	tokens.take_while(|t| !matches!(t, FdtStructureToken::BeginNone { .. })).find_map(|t| match t {
+		FdtStructureToken::Prop { name: n, value } if n == name => Some(value),
+		_ => None
+	})
+}
+
+#[test]
+fn numa() {
+	let fdt = load("dtb/riscv64-virt-numa.dtb");
+
+	let memory = fdt.get(&["", "memory"]).map(|t| match t {
+		FdtStructureToken::BeginNone { tokens, .. } => (
+			cells(node_prop(tokens, "reg").unwrap())[1],
+			node_prop(tokens, "numa-node-id").and_then(Into::<Option<u32>>::into)),
+		t => panic!("expected a node, got {:?}", t)
+	}).collect::<Vec<_>>();
+	assert_eq!(memory, [(0xC0000000, Some(1)), (0x80000000, Some(0))]);
+
+	let cpus = fdt.get(&["", "cpus", "cpu"]).map(|t| match t {
+		FdtStructureToken::BeginNone { tokens, .. } => node_prop(tokens, "numa-node-id").and_then(Into::<Option<u32>>::into),
+		t => panic!("expected a node, got {:?}", t)
+	}).collect::<Vec<_>>();
+	assert_eq!(cpus, [Some(0), Some(0), Some(1), Some(1)]);
+
+	assert_eq!(Into::<Option<&str>>::into(prop(fdt, &["", "distance-map", "compatible"])), Some("numa-distance-map-v1"));
+	let matrix = cells(prop(fdt, &["", "distance-map", "distance-matrix"]));
+	assert_eq!(matrix.chunks(3).map(|v| (v[0], v[1], v[2])).collect::<Vec<_>>(),
+		[(0, 0, 10), (0, 1, 20), (1, 0, 20), (1, 1, 10)]);
+
+	assert_eq!(fdt.get(&["", "distance-map"]).count(), 1);
+	assert!(load("dtb/riscv64-virt.dtb").get(&["", "distance-map"]).next().is_none());
+}
@@ -0,0 +1,276 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use hw::{block::RamDisk, fat32::*};
+
+const IMAGES: [(&str, FatType, usize); 3] = [
+	("fat/fat12.img", FatType::Fat12, 2048),
+	("fat/fat16.img", FatType::Fat16, 512),
+	("fat/fat32.img", FatType::Fat32, 512)
+];
+
+/// Formatted by `mkfs.fat` and filled by mtools, `generate.py` only writes them where
+/// both are installed.
+const MKFS_IMAGES: [(&str, FatType, usize); 3] = [
+	("fat/mkfs-fat12.img", FatType::Fat12, 2048),
+	("fat/mkfs-fat16.img", FatType::Fat16, 512),
+	("fat/mkfs-fat32.img", FatType::Fat32, 512)
+];
+
+fn open(name: &str) -> FileSystem<RamDisk> {
+	FileSystem::open(RamDisk::from_vec(512, common::load_sparse(name))).unwrap()
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+	(0..len).map(|i| (i * 7 + seed as usize) as u8).collect()
+}
+
+fn read_all(fs: &mut FileSystem<RamDisk>, path: &str) -> Vec<u8> {
+	let mut buf = vec![0; fs.metadata(path).unwrap().size as usize + 10];
+	let len = fs.read(path, 0, &mut buf).unwrap();
+	buf.truncate(len);
+	buf
+}
+
+fn names(fs: &mut FileSystem<RamDisk>, path: &str) -> Vec<String> {
+	fs.read_dir(path).unwrap().into_iter().map(|e| e.name).collect()
+}
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<Bpb>(), 36);
+	assert_eq!(core::mem::size_of::<Ebpb16>(), 26);
+	assert_eq!(core::mem::size_of::<Ebpb32>(), 54);
+	assert_eq!(core::mem::size_of::<FsInfo>(), 512);
+	assert_eq!(core::mem::size_of::<DirEntry>(), 32);
+	assert_eq!(core::mem::size_of::<LfnEntry>(), 32);
+	assert_eq!(short_name_checksum(b"ALONGF~1TXT"), 0x02);
+	assert_eq!(DateTime::from_fat(0x58B1, 0x645C), DateTime { year: 2024, month: 5, day: 17, hour: 12, minute: 34, second: 56 });
+	assert_eq!(DateTime::from_fat(0x58B1, 0x645C).to_fat(), (0x58B1, 0x645C));
+}
+
+#[test]
+fn open_images() {
+	for (name, kind, cluster_size) in IMAGES {
+		let mut fs = open(name);
+		assert_eq!(fs.kind(), kind, "{}", name);
+		assert_eq!(fs.label(), "TESTVOL");
+		assert_eq!(fs.cluster_size(), cluster_size);
+		assert!(fs.free_clusters().unwrap() < fs.clusters());
+	}
+
+	let mut disk = common::load_sparse("fat/fat16.img");
+	disk[510] = 0;
+	assert_eq!(FileSystem::open(RamDisk::from_vec(512, disk)).err(), Some(Error::NotFat));
+}
+
+#[test]
+fn enumerate() {
+	for (name, ..) in IMAGES {
+		let mut fs = open(name);
+		assert_eq!(names(&mut fs, "/"), ["EFI", "readme.txt", "A long file name.txt", "empty.dat", "many"], "{}", name);
+		assert_eq!(names(&mut fs, "/EFI"), ["BOOT"]);
+		assert_eq!(names(&mut fs, "efi/boot/"), ["BOOTX64.EFI"]);
+
+		let root = fs.read_dir("").unwrap();
+		assert_eq!(root[2].short_name, "ALONGF~1.TXT");
+		assert_eq!(root[1].short_name, "readme.txt");
+		assert!(root[0].is_dir() && !root[1].is_dir());
+		assert_eq!(root[3].cluster, 0);
+		assert_eq!(root[1].modified, DateTime { year: 2024, month: 5, day: 17, hour: 12, minute: 34, second: 56 });
+
+		let many = names(&mut fs, "many");
+		assert_eq!(many.len(), 40);
+		assert!(many.iter().enumerate().all(|(i, n)| *n == format!("file {:02}.txt", i)));
+
+		assert!(fs.metadata("/").unwrap().is_dir());
+		assert_eq!(fs.read_dir("nothing").err(), Some(Error::NotFound));
+		assert_eq!(fs.read_dir("readme.txt").err(), Some(Error::NotADirectory));
+		assert_eq!(fs.metadata("readme.txt/x").err(), Some(Error::NotADirectory));
+	}
+}
+
+#[test]
+fn read() {
+	for (name, _, cluster_size) in IMAGES {
+		let mut fs = open(name);
+		assert_eq!(read_all(&mut fs, "EFI/BOOT/BOOTX64.EFI"), pattern(3000, 1), "{}", name);
+		assert_eq!(read_all(&mut fs, "README.TXT"), b"Hello from the ESP\n");
+		assert_eq!(read_all(&mut fs, "empty.dat"), b"");
+		assert_eq!(read_all(&mut fs, "many/FILE 39.TXT"), b"39\n");
+
+		// the file's clusters are fragmented
+		let long = pattern(2 * cluster_size + 100, 2);
+		assert_eq!(read_all(&mut fs, "a long file name.txt"), long);
+		assert_eq!(read_all(&mut fs, "ALONGF~1.TXT"), long);
+		let mut buf = [0; 200];
+		assert_eq!(fs.read("A long file name.txt", cluster_size as u64 - 50, &mut buf).unwrap(), 200);
+		assert_eq!(buf[..], long[cluster_size - 50..cluster_size + 150]);
+		assert_eq!(fs.read("A long file name.txt", long.len() as u64 - 10, &mut buf).unwrap(), 10);
+		assert_eq!(fs.read("A long file name.txt", long.len() as u64, &mut buf).unwrap(), 0);
+
+		assert_eq!(fs.read("EFI", 0, &mut buf).err(), Some(Error::IsADirectory));
+		assert_eq!(fs.read("deleted file.bin", 0, &mut buf).err(), Some(Error::NotFound));
+	}
+}
+
+#[test]
+fn modify() {
+	for (name, ..) in IMAGES {
+		let mut fs = open(name);
+		let free = fs.free_clusters().unwrap();
+		let time = DateTime { year: 2025, month: 1, day: 2, hour: 3, minute: 4, second: 6 };
+		fs.set_time(time);
+
+		let data = pattern(10000, 3);
+		let entry = fs.create_file("EFI/BOOT/grubx64 config.cfg").unwrap();
+		assert_eq!(entry.short_name, "GRUBX6~1.CFG");
+		assert_eq!(fs.write("EFI/BOOT/grubx64 config.cfg", 0, &data).unwrap(), data.len());
+		assert_eq!(fs.write("EFI/BOOT/grubx64 config.cfg", 20000, b"end").unwrap(), 3);
+		fs.create_dir("Kernels").unwrap();
+		fs.create_file("kernels/VMLINUZ").unwrap();
+		fs.write("kernels/VMLINUZ", 0, &data[..100]).unwrap();
+		assert_eq!(fs.create_file("A Long File Name.TXT").err(), Some(Error::Exists));
+		assert_eq!(fs.create_file("A long file name 2.txt").unwrap().short_name, "ALONGF~2.TXT");
+		assert_eq!(fs.create_file("bad?name").err(), Some(Error::InvalidName));
+		assert_eq!(fs.create_file("kernels/x y").unwrap().short_name, "XY~1");
+		fs.remove("kernels/X Y").unwrap();
+		assert_eq!(fs.create_file("nothing/file").err(), Some(Error::NotFound));
+		fs.truncate("A long file name.txt", 10).unwrap();
+		assert_eq!(fs.remove("many").err(), Some(Error::NotEmpty));
+		fs.remove("many/file 07.txt").unwrap();
+		fs.remove("empty.dat").unwrap();
+		assert!(fs.free_clusters().unwrap() < free);
+		fs.sync().unwrap();
+
+		// everything is on the disk
+		let mut fs = FileSystem::open(fs.into_inner()).unwrap();
+		let config = read_all(&mut fs, "efi/boot/GRUBX6~1.CFG");
+		assert_eq!(config.len(), 20003, "{}", name);
+		assert_eq!(config[..10000], data[..]);
+		assert!(config[10000..20000].iter().all(|b| *b == 0));
+		assert_eq!(config[20000..], *b"end");
+		assert_eq!(fs.metadata("efi/boot/grubx64 config.cfg").unwrap().modified, time);
+		assert_eq!(read_all(&mut fs, "Kernels/vmlinuz"), data[..100]);
+		assert_eq!(names(&mut fs, "kernels"), ["VMLINUZ"]);
+		assert_eq!(read_all(&mut fs, "A long file name.txt"), pattern(10, 2));
+		assert_eq!(names(&mut fs, "many").len(), 39);
+		assert_eq!(names(&mut fs, "/"), ["EFI", "readme.txt", "A long file name.txt", "Kernels", "many", "A long file name 2.txt"]);
+
+		// growing zeroes the bytes beyond the old end
+		fs.truncate("A long file name.txt", 5).unwrap();
+		fs.truncate("A long file name.txt", 8).unwrap();
+		assert_eq!(read_all(&mut fs, "A long file name.txt"), [&pattern(5, 2)[..], &[0; 3]].concat());
+
+		// removing everything again frees the clusters
+		for path in ["EFI/BOOT/grubx64 config.cfg", "kernels/vmlinuz", "kernels", "A long file name 2.txt"] {
+			fs.remove(path).unwrap();
+		}
+		fs.truncate("A long file name.txt", 0).unwrap();
+		let long = fs.metadata("A long file name.txt").unwrap();
+		assert_eq!((long.size, long.cluster), (0, 0));
+		fs.sync().unwrap();
+		let mut fs = FileSystem::open(fs.into_inner()).unwrap();
+		assert_eq!(fs.free_clusters().unwrap(), free + 4);
+	}
+}
+
+#[test]
+fn free_space() {
+	let mut fs = open("fat/fat32.img");
+	let free = fs.free_clusters().unwrap();
+	fs.create_file("big").unwrap();
+	fs.truncate("big", 100 * 512).unwrap();
+	assert_eq!(fs.free_clusters().unwrap(), free - 100);
+	fs.sync().unwrap();
+
+	// FSInfo keeps the count
+	let disk = fs.into_inner().into_vec();
+	let info = common::read::<FsInfo>(&disk, 512);
+	assert!(info.is_valid());
+	assert_eq!({ info.free_count }, free - 100);
+
+	// the FATs are mirrored
+	let bpb = common::read::<Bpb>(&disk, 0);
+	let fat_size = common::read::<Ebpb32>(&disk, 36).fat_size_32 as usize * 512;
+	let fat = bpb.reserved_sectors as usize * 512;
+	assert!(disk[fat..fat + fat_size] == disk[fat + fat_size..fat + 2 * fat_size]);
+
+	let mut fs = FileSystem::open(RamDisk::from_vec(512, disk)).unwrap();
+	assert_eq!(fs.free_clusters().unwrap(), free - 100);
+	assert_eq!(fs.truncate("big", fs.clusters() as u64 * 512).err(), Some(Error::NoSpace));
+	assert_eq!(fs.metadata("big").unwrap().size, 100 * 512);
+}
+
+#[test]
+fn fixed_root() {
+	// the FAT16 root directory has room for 512 entries
+	let mut fs = open("fat/fat16.img");
+	let used = 1 + 1 + 1 + 2 + 1 + 1 + 1;
+	for i in 0..512 - used {
+		fs.create_file(&format!("F{}", i)).unwrap();
+	}
+	assert_eq!(fs.create_file("full").err(), Some(Error::NoSpace));
+	fs.remove("F0").unwrap();
+	fs.create_file("full").unwrap();
+
+	// subdirectories grow
+	for i in 0..200 {
+		fs.create_file(&format!("many/more {}", i)).unwrap();
+	}
+	assert_eq!(fs.read_dir("many").unwrap().len(), 240);
+}
+
+#[test]
+#[ignore = "the images are not checked in, run generate.py where dosfstools and mtools are installed"]
+fn mkfs_images() {
+	for (name, kind, cluster_size) in MKFS_IMAGES {
+		let mut fs = open(name);
+		assert_eq!(fs.kind(), kind, "{}", name);
+		assert_eq!(fs.label(), "TESTVOL");
+		assert_eq!(fs.cluster_size(), cluster_size);
+
+		let mut root = names(&mut fs, "/");
+		root.sort();
+		assert_eq!(root, ["A long file name.txt", "EFI", "empty.dat", "many", "readme.txt"], "{}", name);
+		assert_eq!(fs.read_dir("/").unwrap().iter().find(|e| e.name == "A long file name.txt").unwrap().short_name,
+			"ALONGF~1.TXT");
+		assert_eq!(names(&mut fs, "many").len(), 40);
+
+		assert_eq!(read_all(&mut fs, "EFI/BOOT/BOOTX64.EFI"), pattern(3000, 1), "{}", name);
+		assert_eq!(read_all(&mut fs, "readme.txt"), b"Hello from the ESP\n");
+		assert_eq!(read_all(&mut fs, "empty.dat"), b"");
+		assert_eq!(read_all(&mut fs, "many/file 39.txt"), b"39\n");
+		assert_eq!(read_all(&mut fs, "a long file name.txt"), pattern(2 * cluster_size + 100, 2));
+
+		// what the tools wrote stays readable after a change
+		let free = fs.free_clusters().unwrap();
+		fs.create_file("new file.txt").unwrap();
+		fs.write("new file.txt", 0, b"written").unwrap();
+		fs.remove("many/file 00.txt").unwrap();
+		assert_eq!(read_all(&mut fs, "new file.txt"), b"written");
+		assert_eq!(fs.free_clusters().unwrap(), free);
+		assert_eq!(names(&mut fs, "many").len(), 39);
+	}
+}
@@ -0,0 +1,1671 @@
+#!/usr/bin/env python3
+#
+# Regenerates the binary fixtures used by the host tests of the `hw` crate.
+#
+# The images mirror what QEMU hands to a guest: the ACPI tables of the `q35` and
+# arm64 `virt` machines as they appear in guest physical memory, the flattened
+# device trees of the riscv64 and aarch64 `virt` machines, a small GPT disk,
+# SMBIOS entry points and a UEFI memory map as returned by OVMF. The FAT images are
+# laid out like `mkfs.fat` formats them; the `mkfs-*` FAT and Btrfs images are
+# formatted by the real tools where they are installed, as are the `mdadm-*` RAID
+# members. Run it from any directory; the files are written next to this script.
+
+import os
+import shutil
+import struct
+import subprocess
+import tempfile
+import zlib
+
+OUT = os.path.dirname(os.path.abspath(__file__))
+
+
+def write(path, data):
+	path = os.path.join(OUT, path)
+	os.makedirs(os.path.dirname(path), exist_ok=True)
+	with open(path, "wb") as f:
+		f.write(data)
+
+
+def checksum(data):
+	return (-sum(data)) & 0xFF
+
+
+def align(data, n):
+	return data + b"\0" * (-len(data) % n)
+
+
+# ------------------------------------------------------------------------------------------------
+# ACPI
+# ------------------------------------------------------------------------------------------------
+
+def sdt(signature, revision, body, oem_table_id=b"BXPC    "):
+	length = 36 + len(body)
+	hdr = struct.pack("<4sIBB6s8sI4sI", signature, length, revision, 0, b"BOCHS ", oem_table_id, 1, b"BXPC", 1)
+	data = bytearray(hdr + body)
+	data[9] = checksum(data)
+	return bytes(data)
+
+
+def gas(space, width, offset, access, address):
+	return struct.pack("<BBBBQ", space, width, offset, access, address)
+
+
+def rsdp(rsdt, xsdt):
+	data = bytearray(struct.pack("<8sB6sBIIQB3s", b"RSD PTR ", 0, b"BOCHS ", 2, rsdt, 36, xsdt, 0, b"\0\0\0"))
+	data[8] = checksum(data[:20])
+	data[32] = checksum(data)
+	return bytes(data)
+
+
+def fadt(revision, dsdt, facs, fields):
+	f = dict(
+		sci_int=0, smi_cmd=0, acpi_enable=0, acpi_disable=0, pm1a_evt_blk=0, pm1a_cnt_blk=0, pm_tmr_blk=0,
+		gpe0_blk=0, pm1_evt_len=0, pm1_cnt_len=0, pm_tmr_len=0, gpe0_blk_len=0, p_lvl2_lat=0, p_lvl3_lat=0,
+		century=0, iapc_boot_arch=0, flags=0, reset_reg=gas(0, 0, 0, 0, 0), reset_value=0, arm_boot_arch=0,
+		minor=0, x_pm1a_evt_blk=gas(0, 0, 0, 0, 0), x_pm1a_cnt_blk=gas(0, 0, 0, 0, 0),
+		x_pm_tmr_blk=gas(0, 0, 0, 0, 0), x_gpe0_blk=gas(0, 0, 0, 0, 0),
+	)
+	f.update(fields)
+	body = struct.pack("<IIBBHIBBBBIIIIIIIIBBBBBBBBHHHHBBBBBHBI",
+		facs, dsdt, 0, 0, f["sci_int"], f["smi_cmd"], f["acpi_enable"], f["acpi_disable"], 0, 0,
+		f["pm1a_evt_blk"], 0, f["pm1a_cnt_blk"], 0, 0, f["pm_tmr_blk"], f["gpe0_blk"], 0,
+		f["pm1_evt_len"], f["pm1_cnt_len"], 0, f["pm_tmr_len"], f["gpe0_blk_len"], 0, 0, 0,
+		f["p_lvl2_lat"], f["p_lvl3_lat"], 0, 0, 0, 0, 0, 0, f["century"], f["iapc_boot_arch"], 0, f["flags"])
+	body += f["reset_reg"] + struct.pack("<BHB", f["reset_value"], f["arm_boot_arch"], f["minor"])
+	body += struct.pack("<QQ", facs, dsdt)
+	body += f["x_pm1a_evt_blk"] + gas(0, 0, 0, 0, 0) + f["x_pm1a_cnt_blk"] + gas(0, 0, 0, 0, 0)
+	body += gas(0, 0, 0, 0, 0) + f["x_pm_tmr_blk"] + f["x_gpe0_blk"] + gas(0, 0, 0, 0, 0)
+	if revision >= 5:
+		body += gas(0, 0, 0, 0, 0) * 2
+	if revision >= 6:
+		body += struct.pack("<Q", 0)
+	return sdt(b"FACP", revision, body)
+
+
+def facs():
+	return struct.pack("<4sIIIIIQBBBBI24s", b"FACS", 64, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, b"\0" * 24)
+
+
+def dsdt(aml):
+	return sdt(b"DSDT", 1, aml)
+
+
+class AcpiImage:
+	"""Lays out tables in a window of guest physical memory starting at `base`."""
+
+	def __init__(self, base):
+		self.base = base
+		self.data = bytearray()
+
+	def place(self, table, alignment=16):
+		self.data += b"\0" * (-len(self.data) % alignment)
+		addr = self.base + len(self.data)
+		self.data += table
+		return addr
+
+	def reserve(self, size, alignment=16):
+		self.data += b"\0" * (-len(self.data) % alignment)
+		addr = self.base + len(self.data)
+		self.data += b"\0" * size
+		return addr
+
+	def patch(self, addr, table):
+		off = addr - self.base
+		self.data[off:off + len(table)] = table
+
+
+# AML is assembled by hand since the fixtures must not depend on iasl.
+
+def pkg_length(n):
+	# the length includes the encoding itself
+	for size in range(1, 5):
+		total = n + size
+		if size == 1 and total < 0x40:
+			return bytes([total])
+		if size > 1 and total < (1 << (4 + 8 * (size - 1))):
+			out = [((size - 1) << 6) | (total & 0xF)]
+			total >>= 4
+			for _ in range(size - 1):
+				out.append(total & 0xFF)
+				total >>= 8
+			return bytes(out)
+	raise ValueError(n)
+
+
+def name_seg(s):
+	return s.encode().ljust(4, b"_")
+
+
+def name_string(path):
+	out = b""
+	if path.startswith("\\"):
+		out += b"\\"
+		path = path[1:]
+	while path.startswith("^"):
+		out += b"^"
+		path = path[1:]
+	segs = [name_seg(s) for s in path.split(".")] if path else []
+	if len(segs) == 0:
+		return out + b"\0"
+	if len(segs) == 1:
+		return out + segs[0]
+	if len(segs) == 2:
+		return out + b"\x2E" + b"".join(segs)
+	return out + b"\x2F" + bytes([len(segs)]) + b"".join(segs)
+
+
+def integer(v):
+	if v == 0:
+		return b"\x00"
+	if v == 1:
+		return b"\x01"
+	if v == 0xFFFFFFFFFFFFFFFF:
+		return b"\xFF"
+	if v <= 0xFF:
+		return b"\x0A" + struct.pack("<B", v)
+	if v <= 0xFFFF:
+		return b"\x0B" + struct.pack("<H", v)
+	if v <= 0xFFFFFFFF:
+		return b"\x0C" + struct.pack("<I", v)
+	return b"\x0E" + struct.pack("<Q", v)
+
+
+def string(s):
+	return b"\x0D" + s.encode() + b"\0"
+
+
+def buffer(data):
+	body = integer(len(data)) + data
+	return b"\x11" + pkg_length(len(body)) + body
+
+
+def package(*elems):
+	body = bytes([len(elems)]) + b"".join(elems)
+	return b"\x12" + pkg_length(len(body)) + body
+
+
+def name(path, obj):
+	return b"\x08" + name_string(path) + obj
+
+
+def scope(path, *terms):
+	body = name_string(path) + b"".join(terms)
+	return b"\x10" + pkg_length(len(body)) + body
+
+
+def device(path, *terms):
+	body = name_string(path) + b"".join(terms)
+	return b"\x5B\x82" + pkg_length(len(body)) + body
+
+
+def method(path, args, serialized, *terms):
+	body = name_string(path) + bytes([args | (0x8 if serialized else 0)]) + b"".join(terms)
+	return b"\x14" + pkg_length(len(body)) + body
+
+
+def ret(obj):
+	return b"\xA4" + obj
+
+
+def field_length(n):
+	# unlike PkgLength, the lengths in field lists don't include the encoding
+	if n < 0x40:
+		return bytes([n])
+	return bytes([0x40 | (n & 0xF), n >> 4])
+
+
+def local(i):
+	return bytes([0x60 + i])
+
+
+def arg(i):
+	return bytes([0x68 + i])
+
+
+def op(code, *operands):
+	return bytes([code]) + b"".join(operands)
+
+
+def store(src, dst):
+	return op(0x70, src, dst)
+
+
+def if_(pred, *terms, else_=None):
+	body = pred + b"".join(terms)
+	out = b"\xA0" + pkg_length(len(body)) + body
+	if else_ is not None:
+		body = b"".join(else_)
+		out += b"\xA1" + pkg_length(len(body)) + body
+	return out
+
+
+def while_(pred, *terms):
+	body = pred + b"".join(terms)
+	return b"\xA2" + pkg_length(len(body)) + body
+
+
+def call(path, *args):
+	return name_string(path) + b"".join(args)
+
+
+def op_region(path, space, offset, length):
+	return b"\x5B\x80" + name_string(path) + bytes([space]) + integer(offset) + integer(length)
+
+
+def field(region, flags, *units):
+	# units are (name, bits), reserved bits have no name
+	body = name_string(region) + bytes([flags])
+	for unit, bits in units:
+		body += (name_seg(unit) if unit else b"\0") + field_length(bits)
+	return b"\x5B\x81" + pkg_length(len(body)) + body
+
+
+def eisa_id(s):
+	c = [ord(x) - 0x40 for x in s[:3]]
+	v = (c[0] << 26) | (c[1] << 21) | (c[2] << 16) | int(s[3:], 16)
+	return integer(struct.unpack(">I", struct.pack("<I", v))[0])
+
+
+def resource_template(*descs):
+	return buffer(b"".join(descs) + b"\x79\x00")
+
+
+def res_io(min_, max_, align_, length):
+	return struct.pack("<BBHHBB", 0x47, 1, min_, max_, align_, length)
+
+
+def res_irq(*irqs):
+	mask = 0
+	for irq in irqs:
+		mask |= 1 << irq
+	return struct.pack("<BH", 0x22, mask)
+
+
+def res_word_bus(min_, max_):
+	return struct.pack("<BHBBBHHHHH", 0x88, 13, 2, 0x0C, 0, 0, min_, max_, 0, max_ - min_ + 1)
+
+
+def res_dword_io(min_, max_):
+	return struct.pack("<BHBBBIIIII", 0x87, 23, 1, 0x0C, 3, 0, min_, max_, 0, max_ - min_ + 1)
+
+
+def res_dword_mem(min_, max_):
+	return struct.pack("<BHBBBIIIII", 0x87, 23, 0, 0x0C, 1, 0, min_, max_, 0, max_ - min_ + 1)
+
+
+def res_qword_mem(min_, max_):
+	return struct.pack("<BHBBBQQQQQ", 0x8A, 43, 0, 0x0C, 1, 0, min_, max_, 0, max_ - min_ + 1)
+
+
+def res_mem32_fixed(base, length):
+	return struct.pack("<BHBII", 0x86, 9, 1, base, length)
+
+
+def res_ext_irq(*irqs):
+	return struct.pack("<BHBB", 0x89, 2 + 4 * len(irqs), 0x01 | 0x02 | 0x08, len(irqs)) + b"".join(struct.pack("<I", i) for i in irqs)
+
+
+def q35_dsdt(s3):
+	prt = []
+	for slot in range(1, 4):
+		for pin in range(4):
+			prt.append(package(integer((slot << 16) | 0xFFFF), integer(pin), integer(0), integer(16 + (slot + pin) % 4)))
+	return (scope("\\_SB",
+			device("PCI0",
+				name("_HID", eisa_id("PNP0A08")),
+				name("_CID", eisa_id("PNP0A03")),
+				name("_ADR", integer(0)),
+				name("_UID", integer(0)),
+				name("_SEG", integer(0)),
+				name("_BBN", integer(0)),
+				name("_CRS", resource_template(
+					res_word_bus(0x00, 0xFF),
+					res_io(0xCF8, 0xCF8, 1, 8),
+					res_dword_io(0x0000, 0x0CF7),
+					res_dword_io(0x0D00, 0xFFFF),
+					res_dword_mem(0x000A0000, 0x000BFFFF),
+					res_dword_mem(0x80000000, 0xAFFFFFFF),
+					res_qword_mem(0x800000000, 0xFFFFFFFFF))),
+				name("_PRT", package(*prt)),
+				device("SF8",
+					name("_ADR", integer(0x001F0000)),
+					device("COM1",
+						name("_HID", eisa_id("PNP0501")),
+						name("_UID", integer(1)),
+						method("_STA", 0, False, ret(integer(0x0F))),
+						name("_CRS", resource_template(res_io(0x3F8, 0x3F8, 0, 8), res_irq(4)))))))
+		+ (name("_S3", package(integer(1), integer(1), integer(0), integer(0))) if s3 else b"")
+		+ name("_S5", package(integer(0), integer(0), integer(0), integer(0)))
+	)
+
+
+def virt_dsdt():
+	return (scope("\\_SB",
+			device("COM0",
+				name("_HID", string("ARMH0011")),
+				name("_UID", integer(0)),
+				name("_CRS", resource_template(res_mem32_fixed(0x09000000, 0x1000), res_ext_irq(33)))),
+			device("PCI0",
+				name("_HID", eisa_id("PNP0A08")),
+				name("_CID", eisa_id("PNP0A03")),
+				name("_SEG", integer(0)),
+				name("_BBN", integer(0)),
+				name("_CRS", resource_template(
+					res_word_bus(0x00, 0xFF),
+					res_dword_mem(0x10000000, 0x3EFEFFFF),
+					res_qword_mem(0x8000000000, 0xFFFFFFFFFF))))))
+
+
+def interp_ssdt():
+	add, sub, inc, lless, lequal = 0x72, 0x74, 0x75, 0x95, 0x93
+	return sdt(b"SSDT", 2, b"".join((
+		method("ADD2", 2, False, ret(op(add, arg(0), arg(1), b"\0"))),
+		# sum of 0..Arg0
+		method("LOOP", 1, False,
+			store(integer(0), local(0)),
+			store(integer(0), local(1)),
+			while_(op(lless, local(0), arg(0)),
+				store(op(add, local(1), local(0), b"\0"), local(1)),
+				op(inc, local(0))),
+			ret(local(1))),
+		method("FIB", 1, False,
+			if_(op(lless, arg(0), integer(2)), ret(arg(0)),
+				else_=[ret(op(add, call("FIB", op(sub, arg(0), integer(1), b"\0")), call("FIB", op(sub, arg(0), integer(2), b"\0")), b"\0"))])),
+		method("CAT", 0, False, ret(op(0x73, string("abc"), string("def"), b"\0"))),
+		name("PKG", package(integer(10), string("x"), integer(30))),
+		method("IDX", 1, False, ret(op(0x83, op(0x88, name_string("PKG"), arg(0), b"\0")))),
+		method("SIZE", 0, False, ret(op(0x87, name_string("PKG")))),
+		# Index into a named package returns a reference that writes through
+		method("SETP", 1, False, store(integer(99), op(0x88, name_string("PKG"), arg(0), b"\0"))),
+		name("BUF", buffer(bytes([0x11, 0x22, 0x33, 0x44, 0x55]))),
+		op(0x8A, name_string("BUF"), integer(1), name_seg("BDW")),
+		method("WBDW", 1, False, store(arg(0), name_string("BDW"))),
+		op_region("MEM", 0, 0x1000, 0x10),
+		field("MEM", 0x03, ("F0", 8), ("F1", 4), ("F2", 4), (None, 16), ("F3", 32), ("F4", 64)),
+		op_region("IOP", 1, 0x80, 2),
+		field("IOP", 0x01, ("P80", 8), ("P81", 8)),
+		method("RDF3", 0, False, ret(name_string("F3"))),
+		method("WRF1", 1, False, store(arg(0), name_string("F1"))),
+		method("POST", 1, False, store(arg(0), name_string("P80"))),
+		# named objects created by a method are deleted when it returns
+		method("TEMP", 0, False, name("TMP", integer(5)), ret(name_string("TMP"))),
+		method("OSI", 1, False, ret(call("\\_OSI", arg(0)))),
+		method("SPIN", 0, False, while_(integer(1))),
+		method("DIV0", 1, False, ret(op(0x78, integer(1), arg(0), b"\0", b"\0"))),
+		method("EQ", 2, False, ret(op(lequal, arg(0), arg(1)))),
+	)))
+
+
+def q35(path="acpi/q35.bin", s3=False):
+	# QEMU leaves out _S3 with -global ICH9-LPC.disable_s3=1, the power tests use the S3 variant
+	img = AcpiImage(0x7FB7_E000)
+	rsdp_addr = img.reserve(36)
+	xsdt_addr = img.reserve(36 + 8 * 4)
+	rsdt_addr = img.reserve(36 + 4 * 4)
+	facs_addr = img.place(facs(), 64)
+	dsdt_addr = img.place(dsdt(q35_dsdt(s3)))
+	fadt_addr = img.place(fadt(3, dsdt_addr, facs_addr, dict(
+		sci_int=9, smi_cmd=0xB2, acpi_enable=0x02, acpi_disable=0x03,
+		pm1a_evt_blk=0x600, pm1a_cnt_blk=0x604, pm_tmr_blk=0x608, gpe0_blk=0x620,
+		pm1_evt_len=4, pm1_cnt_len=2, pm_tmr_len=4, gpe0_blk_len=16,
+		p_lvl2_lat=0xFFF, p_lvl3_lat=0xFFF, century=0x32, iapc_boot_arch=0x0002, flags=0x000084A5,
+		reset_reg=gas(1, 8, 0, 0, 0xCF9), reset_value=0x0F,
+		x_pm1a_evt_blk=gas(1, 32, 0, 0, 0x600), x_pm1a_cnt_blk=gas(1, 16, 0, 0, 0x604),
+		x_pm_tmr_blk=gas(1, 32, 0, 0, 0x608), x_gpe0_blk=gas(1, 128, 0, 0, 0x620))))
+
+	madt = struct.pack("<II", 0xFEE00000, 1)
+	for i in range(4):
+		madt += struct.pack("<BBBBI", 0, 8, i, i, 1)
+	madt += struct.pack("<BBBBII", 1, 12, 0, 0, 0xFEC00000, 0)
+	for src, gsi, flags in ((0, 2, 0x0), (5, 5, 0xD), (9, 9, 0xD), (10, 10, 0xD), (11, 11, 0xD)):
+		madt += struct.pack("<BBBBIH", 2, 10, 0, src, gsi, flags)
+	madt += struct.pack("<BBBHB", 4, 6, 0xFF, 0, 1)
+	madt_addr = img.place(sdt(b"APIC", 1, madt))
+
+	hpet = struct.pack("<I", 0x8086A201) + gas(0, 0, 0, 0, 0xFED00000) + struct.pack("<BHB", 0, 0, 0)
+	hpet_addr = img.place(sdt(b"HPET", 1, hpet))
+
+	mcfg = struct.pack("<Q", 0) + struct.pack("<QHBBI", 0xB0000000, 0, 0, 0xFF, 0)
+	mcfg_addr = img.place(sdt(b"MCFG", 1, mcfg))
+
+	entries = (fadt_addr, madt_addr, hpet_addr, mcfg_addr)
+	img.patch(xsdt_addr, sdt(b"XSDT", 1, b"".join(struct.pack("<Q", e) for e in entries)))
+	img.patch(rsdt_addr, sdt(b"RSDT", 1, b"".join(struct.pack("<I", e) for e in entries)))
+	img.patch(rsdp_addr, rsdp(rsdt_addr, xsdt_addr))
+	write(path, struct.pack("<Q", img.base) + bytes(img.data))
+
+
+def virt():
+	img = AcpiImage(0x5C3F_0000)
+	rsdp_addr = img.reserve(36)
+	xsdt_addr = img.reserve(36 + 8 * 3)
+	dsdt_addr = img.place(dsdt(virt_dsdt()))
+	fadt_addr = img.place(fadt(6, dsdt_addr, 0, dict(flags=1 << 20, arm_boot_arch=0x3, minor=1)))
+
+	madt = struct.pack("<II", 0, 0)
+	madt += struct.pack("<BBHIQIB3s", 0xC, 24, 0, 0, 0x08000000, 0, 3, b"\0\0\0")
+	for i in range(4):
+		madt += struct.pack("<BBHIIIIIQQQQIQQB3s", 0xB, 80, 0, i, i, 1, 0, 23, 0, 0, 0, 0, 25, 0, i, 0, b"\0\0\0")
+	madt += struct.pack("<BBHQI", 0xE, 16, 0, 0x080A0000, 0x00F60000)
+	madt += struct.pack("<BBHIQI", 0xF, 20, 0, 0, 0x08080000, 0)
+	madt_addr = img.place(sdt(b"APIC", 4, madt))
+
+	mcfg = struct.pack("<Q", 0) + struct.pack("<QHBBI", 0x4010000000, 0, 0, 0xFF, 0)
+	mcfg_addr = img.place(sdt(b"MCFG", 1, mcfg))
+
+	entries = (fadt_addr, madt_addr, mcfg_addr)
+	img.patch(xsdt_addr, sdt(b"XSDT", 1, b"".join(struct.pack("<Q", e) for e in entries)))
+	img.patch(rsdp_addr, rsdp(0, xsdt_addr))
+	write("acpi/virt.bin", struct.pack("<Q", img.base) + bytes(img.data))
+
+
+def q35_numa():
+	# -smp 4 -m 4G,slots=2,maxmem=8G -numa node,nodeid=0,cpus=0-1 -numa node,nodeid=1,cpus=2-3
+	# -numa dist,src=0,dst=1,val=21, with a possible but absent fifth cpu and a generic initiator
+	img = AcpiImage(0x7FB7_E000)
+	rsdp_addr = img.reserve(36)
+	xsdt_addr = img.reserve(36 + 8 * 2)
+
+	srat = struct.pack("<IQ", 1, 0)
+	for apic_id, domain, flags in ((0, 0, 1), (1, 0, 1), (2, 1, 1), (3, 1, 1), (4, 1, 0)):
+		srat += struct.pack("<BBBBIB3sI", 0, 16, domain, apic_id, flags, 0, b"\0\0\0", 0)
+	for base, length, domain, flags in (
+			(0x0, 0xA0000, 0, 1),
+			(0x100000, 0x7FF00000, 0, 1),
+			(0x80000000, 0x80000000, 1, 1),
+			(0x100000000, 0x80000000, 1, 1),
+			(0x180000000, 0x100000000, 1, 3)):
+		srat += struct.pack("<BBIHQQIIQ", 1, 40, domain, 0, base, length, 0, flags, 0)
+	srat += struct.pack("<BBBBI", 5, 32, 0, 0, 1) + b"\0" * 16 + struct.pack("<II", 1, 0)
+	srat += struct.pack("<BBHIIIII", 2, 24, 0, 1, 0x100, 1, 0, 0)
+	srat_addr = img.place(sdt(b"SRAT", 1, srat))
+
+	slit = struct.pack("<Q", 2) + bytes([10, 21, 21, 10])
+	slit_addr = img.place(sdt(b"SLIT", 1, slit))
+
+	entries = (srat_addr, slit_addr)
+	img.patch(xsdt_addr, sdt(b"XSDT", 1, b"".join(struct.pack("<Q", e) for e in entries)))
+	img.patch(rsdp_addr, rsdp(0, xsdt_addr))
+	write("acpi/q35-numa.bin", struct.pack("<Q", img.base) + bytes(img.data))
+
+
+# ------------------------------------------------------------------------------------------------
+# Flattened device tree
+# ------------------------------------------------------------------------------------------------
+
+def cells(*vals):
+	return b"".join(struct.pack(">I", v) for v in vals)
+
+
+def strs(*vals):
+	return b"".join(v.encode() + b"\0" for v in vals)
+
+
+class Node:
+	def __init__(self, name, props=(), children=()):
+		self.name = name
+		self.props = list(props)
+		self.children = list(children)
+
+
+def fdt(root, rsvmap=(), boot_cpuid=0):
+	strings = bytearray()
+	offsets = {}
+	struct_block = bytearray()
+
+	def string_off(s):
+		if s not in offsets:
+			offsets[s] = len(strings)
+			strings.extend(s.encode() + b"\0")
+		return offsets[s]
+
+	def emit(node):
+		struct_block.extend(struct.pack(">I", 1) + align(node.name.encode() + b"\0", 4))
+		for key, val in node.props:
+			struct_block.extend(struct.pack(">III", 3, len(val), string_off(key)) + align(val, 4))
+		for child in node.children:
+			emit(child)
+		struct_block.extend(struct.pack(">I", 2))
+
+	emit(root)
+	struct_block.extend(struct.pack(">I", 9))
+
+	rsv = b"".join(struct.pack(">QQ", a, s) for a, s in rsvmap) + struct.pack(">QQ", 0, 0)
+	off_rsv = 40
+	off_struct = off_rsv + len(rsv)
+	off_strings = off_struct + len(struct_block)
+	total = off_strings + len(strings)
+	header = struct.pack(">IIIIIIIIII", 0xD00DFEED, total, off_struct, off_strings, off_rsv, 17, 16,
+		boot_cpuid, len(strings), len(struct_block))
+	return header + rsv + bytes(struct_block) + bytes(strings)
+
+
+def riscv_virt():
+	phandle = 1
+	cpus = []
+	for i in range(4):
+		cpus.append(Node(f"cpu@{i}", [
+			("phandle", cells(phandle + 1)),
+			("device_type", strs("cpu")),
+			("reg", cells(i)),
+			("status", strs("okay")),
+			("compatible", strs("riscv")),
+			("riscv,isa", strs("rv64imafdch_zicsr_zifencei_zihintpause_zba_zbb_zbc_zbs_sstc")),
+			("mmu-type", strs("riscv,sv57")),
+		], [Node("interrupt-controller", [
+			("#interrupt-cells", cells(1)),
+			("interrupt-controller", b""),
+			("compatible", strs("riscv,cpu-intc")),
+			("phandle", cells(phandle)),
+		])]))
+		phandle += 2
+
+	plic = phandle
+	soc = [Node("serial@10000000", [
+		("interrupts", cells(10)),
+		("interrupt-parent", cells(plic)),
+		("clock-frequency", cells(0x384000)),
+		("reg", cells(0, 0x10000000, 0, 0x100)),
+		("compatible", strs("ns16550a")),
+	])]
+	for i in range(8, 0, -1):
+		soc.append(Node(f"virtio_mmio@1000{i}000", [
+			("interrupts", cells(i)),
+			("interrupt-parent", cells(plic)),
+			("reg", cells(0, 0x10000000 + i * 0x1000, 0, 0x1000)),
+			("compatible", strs("virtio,mmio")),
+		]))
+	soc += [
+		Node("plic@c000000", [
+			("phandle", cells(plic)),
+			("riscv,ndev", cells(0x5F)),
+			("reg", cells(0, 0x0C000000, 0, 0x600000)),
+			("interrupts-extended", cells(*sum(([1 + 2 * i, 11, 1 + 2 * i, 9] for i in range(4)), []))),
+			("interrupt-controller", b""),
+			("compatible", strs("sifive,plic-1.0.0", "riscv,plic0")),
+			("#address-cells", cells(0)),
+			("#interrupt-cells", cells(1)),
+		]),
+		Node("clint@2000000", [
+			("interrupts-extended", cells(*sum(([1 + 2 * i, 3, 1 + 2 * i, 7] for i in range(4)), []))),
+			("reg", cells(0, 0x02000000, 0, 0x10000)),
+			("compatible", strs("sifive,clint0", "riscv,clint0")),
+		]),
+		Node("pci@30000000", [
+			("interrupt-map-mask", cells(0x1800, 0, 0, 7)),
+			("#interrupt-cells", cells(1)),
+			("ranges", cells(0x01000000, 0, 0, 0, 0x03000000, 0, 0x10000,
+				0x02000000, 0, 0x40000000, 0, 0x40000000, 0, 0x40000000,
+				0x03000000, 0x4, 0, 0x4, 0, 0x4, 0)),
+			("reg", cells(0, 0x30000000, 0, 0x10000000)),
+			("dma-coherent", b""),
+			("bus-range", cells(0, 0xFF)),
+			("linux,pci-domain", cells(0)),
+			("device_type", strs("pci")),
+			("compatible", strs("pci-host-ecam-generic")),
+			("#size-cells", cells(2)),
+			("#address-cells", cells(3)),
+		]),
+	]
+
+	root = Node("", [
+		("#address-cells", cells(2)),
+		("#size-cells", cells(2)),
+		("compatible", strs("riscv-virtio")),
+		("model", strs("riscv-virtio,qemu")),
+	], [
+		Node("chosen", [
+			("bootargs", strs("")),
+			("stdout-path", strs("/soc/serial@10000000")),
+		]),
+		Node("memory@80000000", [
+			("device_type", strs("memory")),
+			("reg", cells(0, 0x80000000, 1, 0)),
+		]),
+		Node("cpus", [
+			("#address-cells", cells(1)),
+			("#size-cells", cells(0)),
+			("timebase-frequency", cells(10000000)),
+		], cpus),
+		Node("soc", [
+			("#address-cells", cells(2)),
+			("#size-cells", cells(2)),
+			("compatible", strs("simple-bus")),
+			("ranges", b""),
+		], soc),
+	])
+	write("dtb/riscv64-virt.dtb", fdt(root))
+
+
+def aarch64_virt():
+	gic = 0x8002
+	cpus = [Node(f"cpu@{i}", [
+		("phandle", cells(0x8003 + i)),
+		("reg", cells(i)),
+		("enable-method", strs("psci")),
+		("compatible", strs("arm,cortex-a57")),
+		("device_type", strs("cpu")),
+	]) for i in range(4)]
+
+	virtio = [Node(f"virtio_mmio@{0x0A000000 + i * 0x200:x}", [
+		("dma-coherent", b""),
+		("interrupts", cells(0, 0x10 + i, 1)),
+		("reg", cells(0, 0x0A000000 + i * 0x200, 0, 0x200)),
+		("compatible", strs("virtio,mmio")),
+	]) for i in range(3, -1, -1)]
+
+	root = Node("", [
+		("interrupt-parent", cells(gic)),
+		("#size-cells", cells(2)),
+		("#address-cells", cells(2)),
+		("compatible", strs("linux,dummy-virt")),
+	], [
+		Node("psci", [
+			("migrate", cells(0xC4000005)),
+			("cpu_on", cells(0xC4000003)),
+			("cpu_off", cells(0x84000002)),
+			("cpu_suspend", cells(0xC4000001)),
+			("method", strs("hvc")),
+			("compatible", strs("arm,psci-1.0", "arm,psci-0.2", "arm,psci")),
+		]),
+		Node("memory@40000000", [
+			("reg", cells(0, 0x40000000, 1, 0)),
+			("device_type", strs("memory")),
+		]),
+		Node("pl011@9000000", [
+			("clock-names", strs("uartclk", "apb_pclk")),
+			("clocks", cells(0x8000, 0x8000)),
+			("interrupts", cells(0, 1, 4)),
+			("reg", cells(0, 0x09000000, 0, 0x1000)),
+			("compatible", strs("arm,pl011", "arm,primecell")),
+		]),
+		*virtio,
+		Node("pcie@10000000", [
+			("interrupt-map-mask", cells(0x1800, 0, 0, 7)),
+			("#interrupt-cells", cells(1)),
+			("ranges", cells(0x01000000, 0, 0, 0, 0x3EFF0000, 0, 0x10000,
+				0x02000000, 0, 0x10000000, 0, 0x10000000, 0, 0x2EFF0000,
+				0x03000000, 0x80, 0, 0x80, 0, 0x80, 0)),
+			("reg", cells(0x40, 0x10000000, 0, 0x10000000)),
+			("msi-map", cells(0, 0x8004, 0, 0x10000)),
+			("dma-coherent", b""),
+			("bus-range", cells(0, 0xFF)),
+			("linux,pci-domain", cells(0)),
+			("#size-cells", cells(2)),
+			("#address-cells", cells(3)),
+			("device_type", strs("pci")),
+			("compatible", strs("pci-host-ecam-generic")),
+		]),
+		Node("intc@8000000", [
+			("phandle", cells(gic)),
+			("reg", cells(0, 0x08000000, 0, 0x10000, 0, 0x080A0000, 0, 0x00F60000)),
+			("#redistributor-regions", cells(1)),
+			("compatible", strs("arm,gic-v3")),
+			("ranges", b""),
+			("#size-cells", cells(2)),
+			("#address-cells", cells(2)),
+			("interrupt-controller", b""),
+			("#interrupt-cells", cells(3)),
+		], [Node("its@8080000", [
+			("phandle", cells(0x8004)),
+			("reg", cells(0, 0x08080000, 0, 0x20000)),
+			("#msi-cells", cells(1)),
+			("msi-controller", b""),
+			("compatible", strs("arm,gic-v3-its")),
+		])]),
+		Node("cpus", [
+			("#size-cells", cells(0)),
+			("#address-cells", cells(1)),
+		], cpus),
+		Node("chosen", [
+			("stdout-path", strs("/pl011@9000000")),
+		]),
+	])
+	# QEMU reserves nothing itself, firmware usually adds its own image
+	write("dtb/aarch64-virt.dtb", fdt(root, rsvmap=[(0x40000000, 0x200000)]))
+
+
+def riscv_virt_numa():
+	# -smp 4 -m 2G -numa node,nodeid=0,cpus=0-1,mem=1G -numa node,nodeid=1,cpus=2-3,mem=1G
+	# -numa dist,src=0,dst=1,val=20
+	cpus = [Node(f"cpu@{i}", [
+		("numa-node-id", cells(i // 2)),
+		("device_type", strs("cpu")),
+		("reg", cells(i)),
+		("status", strs("okay")),
+		("compatible", strs("riscv")),
+	]) for i in range(4)]
+
+	root = Node("", [
+		("#size-cells", cells(2)),
+		("#address-cells", cells(2)),
+		("compatible", strs("riscv-virtio")),
+		("model", strs("riscv-virtio,qemu")),
+	], [
+		Node("distance-map", [
+			("distance-matrix", cells(0, 0, 10, 0, 1, 20, 1, 0, 20, 1, 1, 10)),
+			("compatible", strs("numa-distance-map-v1")),
+		]),
+		Node("memory@c0000000", [
+			("numa-node-id", cells(1)),
+			("reg", cells(0, 0xC0000000, 0, 0x40000000)),
+			("device_type", strs("memory")),
+		]),
+		Node("memory@80000000", [
+			("numa-node-id", cells(0)),
+			("reg", cells(0, 0x80000000, 0, 0x40000000)),
+			("device_type", strs("memory")),
+		]),
+		Node("cpus", [
+			("#size-cells", cells(0)),
+			("#address-cells", cells(1)),
+			("timebase-frequency", cells(10000000)),
+		], cpus),
+	])
+	write("dtb/riscv64-virt-numa.dtb", fdt(root))
+
+
+# ------------------------------------------------------------------------------------------------
+# GPT
+# ------------------------------------------------------------------------------------------------
+
+def guid(s):
+	a, b, c, d, e = s.split("-")
+	return struct.pack("<IHH", int(a, 16), int(b, 16), int(c, 16)) + bytes.fromhex(d + e)
+
+
+def gpt_disk(sectors=128, sector=512):
+	entries = bytearray(128 * 128)
+	parts = [
+		("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "2B6F3E1A-52C4-4F1D-9B52-6C1A2E1F0A01", 34, 63, 0, "EFI system partition"),
+		("0FC63DAF-8483-4772-8E79-3D69D8477DE4", "9D8E5F4C-7A2B-4C1E-8F3D-2A1B0C9E8D02", 64, 94, 1 << 2, "root"),
+	]
+	for i, (ty, uniq, first, last, attrs, label) in enumerate(parts):
+		entries[i * 128:(i + 1) * 128] = guid(ty) + guid(uniq) + struct.pack("<QQQ", first, last, attrs) \
+			+ label.encode("utf-16-le").ljust(72, b"\0")
+
+	entries_crc = zlib.crc32(entries)
+	disk_guid = guid("5A3B2C1D-0E9F-4A8B-B7C6-D5E4F3A2B1C0")
+
+	def header(my_lba, alt_lba, entries_lba):
+		h = bytearray(struct.pack("<8sIIIIQQQQ16sQIII", b"EFI PART", 0x10000, 92, 0, 0, my_lba, alt_lba, 34,
+			sectors - 34, disk_guid, entries_lba, 128, 128, entries_crc))
+		h[16:20] = struct.pack("<I", zlib.crc32(h))
+		return bytes(h).ljust(sector, b"\0")
+
+	mbr = bytearray(sector)
+	mbr[446:462] = struct.pack("<BBBBBBBBII", 0, 0, 2, 0, 0xEE, 0xFF, 0xFF, 0xFF, 1, sectors - 1)
+	mbr[510:512] = b"\x55\xAA"
+
+	disk = bytearray(sectors * sector)
+	disk[0:sector] = mbr
+	disk[sector:2 * sector] = header(1, sectors - 1, 2)
+	disk[2 * sector:34 * sector] = entries
+	disk[(sectors - 33) * sector:(sectors - 1) * sector] = entries
+	disk[(sectors - 1) * sector:] = header(sectors - 1, 1, sectors - 33)
+	write("gpt/disk.img", bytes(disk))
+
+	# the same disk with a corrupted primary header, used to test the fallback to the backup
+	disk[sector + 24] ^= 0xFF
+	write("gpt/disk-bad-primary.img", bytes(disk))
+
+
+# ------------------------------------------------------------------------------------------------
+# Compression
+# ------------------------------------------------------------------------------------------------
+
+WORDS = [b"the", b"quick", b"brown", b"fox", b"jumps", b"over", b"lazy", b"dog", b"block", b"device",
+	b"extent", b"tree", b"node", b"leaf", b"kernel", b"page"]
+
+
+def text(n, seed=1):
+	# pseudo-random words, `tests/compress.rs` generates the same text
+	out = bytearray()
+	x = seed
+	while len(out) < n:
+		x = (x * 1103515245 + 12345) & 0x7FFFFFFF
+		out += WORDS[x >> 16 & 15] + (b"\n" if x & 0x300 == 0 else b" ")
+	return bytes(out[:n])
+
+
+def noise(n, seed=1):
+	out = bytearray()
+	x = seed
+	for _ in range(n):
+		x = (x * 1103515245 + 12345) & 0x7FFFFFFF
+		out.append(x >> 16 & 0xFF)
+	return bytes(out)
+
+
+def zstd(data, *args):
+	# needs the `zstd` command line tool, the frames don't depend on its version in ways
+	# the tests care about
+	return subprocess.run(["zstd", "-q", "-c", *args], input=data, stdout=subprocess.PIPE, check=True).stdout
+
+
+def compressed():
+	write("compress/text.zlib", zlib.compress(text(300000), 9))
+	write("compress/short.zlib", zlib.compress(text(40), 6))
+	write("compress/stored.zlib", zlib.compress(text(5000), 0))
+	write("compress/text-1.zst", zstd(text(300000), "-1", "--check"))
+	write("compress/text-19.zst", zstd(text(300000), "-19", "--no-check"))
+	write("compress/mixed.zst", zstd(noise(50000) + bytes(100000) + text(50000), "-3", "--check"))
+
+
+# ------------------------------------------------------------------------------------------------
+# FAT
+# ------------------------------------------------------------------------------------------------
+
+def sparse(data, chunk=512):
+	# images are stored as their non-zero chunks, `common::load_sparse` expands them
+	out = b"SPARSE\0\0" + struct.pack("<Q", len(data))
+	zero = bytes(chunk)
+	i = 0
+	while i < len(data):
+		if data[i:i + chunk] == zero:
+			i += chunk
+			continue
+		j = i
+		while j < len(data) and data[j:j + chunk] != zero:
+			j += chunk
+		out += struct.pack("<QI", i, j - i) + bytes(data[i:j])
+		i = j
+	return out
+
+
+def pattern(n, seed):
+	return bytes((i * 7 + seed) & 0xFF for i in range(n))
+
+
+FAT_DATE = (2024 - 1980) << 9 | 5 << 5 | 17
+FAT_TIME = 12 << 11 | 34 << 5 | 56 // 2
+
+
+def short_entry(name, attr, cluster=0, size=0, nt=0):
+	return struct.pack("<11sBBBHHHHHHHI", name, attr, nt, 0, FAT_TIME, FAT_DATE, FAT_DATE, cluster >> 16,
+		FAT_TIME, FAT_DATE, cluster & 0xFFFF, size)
+
+
+def lfn_entries(name, short):
+	csum = 0
+	for c in short:
+		csum = (((csum & 1) << 7) + (csum >> 1) + c) & 0xFF
+	units = list(struct.unpack("<%dH" % len(name), name.encode("utf-16-le")))
+	if len(units) % 13:
+		units.append(0)
+	units += [0xFFFF] * (-len(units) % 13)
+	entries = []
+	for i in range(len(units) // 13):
+		u = units[i * 13:(i + 1) * 13]
+		ordinal = i + 1 | (0x40 if (i + 1) * 13 == len(units) else 0)
+		entries.append(struct.pack("<B5HBBB6HH2H", ordinal, *u[:5], 0x0F, 0, csum, *u[5:11], 0, *u[11:]))
+	return entries[::-1]
+
+
+class Fat:
+	# a file system laid out like `mkfs.fat` 4.2 does, files are allocated in order
+	def __init__(self, bits, sectors, spc, reserved, root_entries, label, volume_id):
+		self.bits, self.spc, self.reserved = bits, spc, reserved
+		self.root_entries = root_entries
+		self.root_sectors = root_entries * 32 // 512
+		self.fat_sectors = 1
+		while True:
+			data = sectors - reserved - 2 * self.fat_sectors - self.root_sectors
+			self.clusters = data // spc
+			if self.fat_sectors * 512 * 8 >= (self.clusters + 2) * bits:
+				break
+			self.fat_sectors += 1
+		self.data = reserved + 2 * self.fat_sectors + self.root_sectors
+		self.img = bytearray(sectors * 512)
+		self.fat = [0] * (self.clusters + 2)
+		self.fat[0] = (0xFFFFFF00 | 0xF8) & ((1 << bits) - 1) & 0x0FFFFFFF
+		self.fat[1] = self.eoc()
+		self.next = 2
+
+		boot = bytearray(512)
+		boot[0:36] = struct.pack("<3s8sHBHBHHBHHHII", b"\xEB\x58\x90" if bits == 32 else b"\xEB\x3C\x90", b"mkfs.fat",
+			512, spc, reserved, 2, root_entries, sectors if sectors < 0x10000 and bits != 32 else 0, 0xF8,
+			self.fat_sectors if bits != 32 else 0, 32, 64, 0, sectors if sectors >= 0x10000 or bits == 32 else 0)
+		ebpb = struct.pack("<BBBI11s8s", 0x80, 0, 0x29, volume_id, label.ljust(11), b"FAT%d   " % bits)
+		if bits == 32:
+			ebpb = struct.pack("<IHHIHH12x", self.fat_sectors, 0, 0, 2, 1, 6) + ebpb
+		boot[36:36 + len(ebpb)] = ebpb
+		boot[510:512] = b"\x55\xAA"
+		self.boot = boot
+
+		self.root = []
+		if bits == 32:
+			self.root_cluster = self.alloc(1)[0]
+		self.root.append(short_entry(label.ljust(11), 0x08))
+		self.dirs = [(None, self.root)]
+
+	def eoc(self):
+		return {12: 0xFFF, 16: 0xFFFF, 32: 0x0FFFFFFF}[self.bits]
+
+	def alloc(self, n):
+		clusters = list(range(self.next, self.next + n))
+		self.next += n
+		for c in clusters:
+			self.fat[c] = self.eoc()
+		return clusters
+
+	def link(self, clusters):
+		for a, b in zip(clusters, clusters[1:]):
+			self.fat[a] = b
+		self.fat[clusters[-1]] = self.eoc()
+
+	def offset(self, cluster):
+		return (self.data + (cluster - 2) * self.spc) * 512
+
+	def store(self, data, clusters=None):
+		size = self.spc * 512
+		if clusters is None:
+			clusters = self.alloc((len(data) + size - 1) // size)
+		if clusters:
+			self.link(clusters)
+		for i, c in enumerate(clusters):
+			chunk = data[i * size:(i + 1) * size]
+			self.img[self.offset(c):self.offset(c) + len(chunk)] = chunk
+		return clusters
+
+	def add(self, parent, name, short, attr, cluster=0, size=0, nt=0, long=True):
+		if long:
+			parent += lfn_entries(name, short)
+		parent.append(short_entry(short, attr, cluster, size, nt))
+
+	def file(self, parent, name, short, data, nt=0, long=True, clusters=None):
+		clusters = self.store(data, clusters)
+		self.add(parent, name, short, 0x20, clusters[0] if clusters else 0, len(data), nt, long)
+
+	def mkdir(self, parent, name, short, nt=0, long=False):
+		cluster = self.alloc(1)[0]
+		parent_cluster = next((c[0] for c, d in self.dirs if d is parent and c), 0)
+		entries = [short_entry(b".          ", 0x10, cluster), short_entry(b"..         ", 0x10, parent_cluster)]
+		self.dirs.append(([cluster], entries))
+		self.add(parent, name, short, 0x10, cluster, nt=nt, long=long)
+		return entries
+
+	def image(self):
+		for clusters, entries in self.dirs:
+			data = b"".join(entries)
+			if clusters is None and self.bits != 32:
+				off = (self.reserved + 2 * self.fat_sectors) * 512
+				self.img[off:off + len(data)] = data
+				continue
+			if clusters is None:
+				clusters = [self.root_cluster]
+			size = self.spc * 512
+			while len(clusters) * size < len(data):
+				clusters += self.alloc(1)
+			self.store(data, clusters)
+
+		img = self.img
+		img[0:512] = self.boot
+		if self.bits == 12:
+			fat = bytearray()
+			for i in range(0, len(self.fat) + 1, 2):
+				a = self.fat[i] if i < len(self.fat) else 0
+				b = self.fat[i + 1] if i + 1 < len(self.fat) else 0
+				fat += struct.pack("<I", a | b << 12)[:3]
+		else:
+			fat = b"".join(struct.pack("<H" if self.bits == 16 else "<I", v) for v in self.fat)
+		for i in range(2):
+			off = (self.reserved + i * self.fat_sectors) * 512
+			img[off:off + len(fat)] = fat
+		if self.bits == 32:
+			free = self.fat.count(0)
+			info = struct.pack("<I480xIII12xI", 0x41615252, 0x61417272, free, self.next, 0xAA550000)
+			img[512:1024] = info
+			img[6 * 512:9 * 512] = img[0:3 * 512]
+		return img
+
+
+def fat_image(bits, sectors, spc, reserved, root_entries, label):
+	fs = Fat(bits, sectors, spc, reserved, root_entries, label, 0x1234ABCD)
+	efi = fs.mkdir(fs.root, "EFI", b"EFI        ")
+	boot = fs.mkdir(efi, "BOOT", b"BOOT       ")
+	fs.file(boot, "BOOTX64.EFI", b"BOOTX64 EFI", pattern(3000, 1), long=False)
+
+	# a long name whose clusters are interleaved with another file's
+	size = spc * 512
+	long = pattern(2 * size + 100, 2)
+	clusters = fs.alloc(2)
+	fs.file(fs.root, "readme.txt", b"README  TXT", b"Hello from the ESP\n", nt=0x18, long=False)
+	clusters += fs.alloc(1)
+	fs.file(fs.root, "A long file name.txt", b"ALONGF~1TXT", long, clusters=clusters)
+
+	# a deleted file, its entries are skipped but their clusters stay allocated
+	deleted = [b"\xE5" + e[1:] for e in lfn_entries("deleted file.bin", b"DELETE~1BIN")]
+	fs.root += deleted + [b"\xE5" + short_entry(b"DELETE~1BIN", 0x20, 0, 0)[1:]]
+	fs.file(fs.root, "empty.dat", b"EMPTY   DAT", b"", nt=0x18, long=False)
+
+	many = fs.mkdir(fs.root, "many", b"MANY       ", nt=0x08)
+	for i in range(40):
+		fs.file(many, "file %02d.txt" % i, b"FILE%02d~1TXT" % i, b"%d\n" % i)
+	return fs.image()
+
+
+def tools(path, *names):
+	# the images of the real tools are only written where they are installed, the tests
+	# skip them otherwise
+	missing = [n for n in names if shutil.which(n) is None]
+	if missing:
+		print("skipping %s, %s not installed" % (path, ", ".join(missing)))
+	return not missing
+
+
+def mkfs_fat_image(path, bits, spc, kib):
+	# the same files as `fat_image`, formatted by `mkfs.fat` and copied by mtools
+	if not tools(path, "mkfs.fat", "mmd", "mcopy"):
+		return
+	with tempfile.TemporaryDirectory() as tmp:
+		img = os.path.join(tmp, "fat.img")
+		subprocess.run(["mkfs.fat", "-C", "-F", str(bits), "-s", str(spc), "-n", "TESTVOL", "-i", "1234ABCD", img,
+			str(kib)], stdout=subprocess.DEVNULL, check=True)
+		files = {
+			"EFI/BOOT/BOOTX64.EFI": pattern(3000, 1),
+			"readme.txt": b"Hello from the ESP\n",
+			"A long file name.txt": pattern(2 * spc * 512 + 100, 2),
+			"empty.dat": b""
+		}
+		files.update(("many/file %02d.txt" % i, b"%d\n" % i) for i in range(40))
+		env = dict(os.environ, MTOOLS_SKIP_CHECK="1")
+		for d in ["EFI", "EFI/BOOT", "many"]:
+			subprocess.run(["mmd", "-i", img, "::" + d], env=env, check=True)
+		for name, data in files.items():
+			src = os.path.join(tmp, "file")
+			with open(src, "wb") as f:
+				f.write(data)
+			subprocess.run(["mcopy", "-i", img, src, "::" + name], env=env, check=True)
+		with open(img, "rb") as f:
+			write(path, sparse(f.read()))
+
+
+def fat_images():
+	# equivalent to `mkfs.fat -F <bits> -s <spc> -r <root entries> -n TESTVOL -i 1234ABCD` followed by
+	# copying the files with a driver that writes Windows style short names
+	write("fat/fat12.img", sparse(fat_image(12, 2048, 4, 1, 512, b"TESTVOL")))
+	write("fat/fat16.img", sparse(fat_image(16, 5120, 1, 1, 512, b"TESTVOL")))
+	write("fat/fat32.img", sparse(fat_image(32, 69632, 1, 32, 0, b"TESTVOL")))
+	mkfs_fat_image("fat/mkfs-fat12.img", 12, 4, 1024)
+	mkfs_fat_image("fat/mkfs-fat16.img", 16, 1, 2560)
+	mkfs_fat_image("fat/mkfs-fat32.img", 32, 1, 34816)
+
+
+# ------------------------------------------------------------------------------------------------
+# Btrfs
+# ------------------------------------------------------------------------------------------------
+
+CRC32C_TABLE = []
+for i in range(256):
+	c = i
+	for _ in range(8):
+		c = c >> 1 ^ 0x82F63B78 if c & 1 else c >> 1
+	CRC32C_TABLE.append(c)
+
+
+def crc32c_raw(crc, data):
+	for b in data:
+		crc = CRC32C_TABLE[(crc ^ b) & 0xFF] ^ crc >> 8
+	return crc
+
+
+def crc32c(data):
+	return crc32c_raw(0xFFFFFFFF, data) ^ 0xFFFFFFFF
+
+
+BTRFS_NODE = 4096
+BTRFS_SECTOR = 4096
+BTRFS_GEN = 7
+BTRFS_SIZE = 20 << 20
+BTRFS_FSID = bytes(range(0x10, 0x20))
+BTRFS_DEV_UUID = bytes(range(0x20, 0x30))
+BTRFS_CHUNK_UUID = bytes(range(0x30, 0x40))
+# logical address, length, type and profile, device offsets of the stripes
+BTRFS_CHUNKS = [
+	(0x1500000, 1 << 20, 2 | 32, [1 << 20, 2 << 20]),
+	(0x1600000, 4 << 20, 4 | 32, [3 << 20, 7 << 20]),
+	(0x2000000, 8 << 20, 1, [11 << 20])
+]
+BTRFS_TIME = struct.pack("<QI", 1718627696, 123456789)
+
+
+def btrfs_key(objectid, ty, offset):
+	return struct.pack("<QBQ", objectid, ty, offset)
+
+
+def btrfs_inode(size, nbytes, mode, nlink=1, flags=0):
+	return struct.pack("<5Q4I3Q32x", BTRFS_GEN, BTRFS_GEN, size, nbytes, 0, nlink, 0, 0, mode, 0, flags, 1) \
+		+ BTRFS_TIME * 4
+
+
+def btrfs_root_item(bytenr, level, dirid=0):
+	item = btrfs_inode(3, BTRFS_NODE, 0o40755) + struct.pack("<7QI", BTRFS_GEN, dirid, bytenr, 0, BTRFS_NODE, 0, 0, 1)
+	item += btrfs_key(0, 0, 0) + struct.pack("<BBQ", 0, level, BTRFS_GEN) + bytes(48)
+	item += struct.pack("<4Q", BTRFS_GEN, BTRFS_GEN, 0, 0) + BTRFS_TIME * 4 + bytes(64)
+	assert len(item) == 439
+	return item
+
+
+def btrfs_dir_item(location, name, ty):
+	return btrfs_key(*location) + struct.pack("<QHHB", BTRFS_GEN, 0, len(name), ty) + name
+
+
+def btrfs_chunk_item(length, ty, stripes):
+	item = struct.pack("<4Q3I2H", length, 2, 0x10000, ty, BTRFS_SECTOR, BTRFS_SECTOR, BTRFS_SECTOR, len(stripes), 1)
+	for offset in stripes:
+		item += struct.pack("<QQ16s", 1, offset, BTRFS_DEV_UUID)
+	return item
+
+
+def btrfs_extent(disk_bytenr, disk_len, offset, num_bytes, ram_bytes, compression=0):
+	return struct.pack("<QQBBHB4Q", BTRFS_GEN, ram_bytes, compression, 0, 0, 1, disk_bytenr, disk_len, offset, num_bytes)
+
+
+def btrfs_inline(data, ram_bytes, compression=0):
+	return struct.pack("<QQBBHB", BTRFS_GEN, ram_bytes, compression, 0, 0, 0) + data
+
+
+def btrfs_node(bytenr, owner, level, entries):
+	# entries are (key, data) of a leaf or (key, address) of an internal node
+	node = bytearray(BTRFS_NODE)
+	end = BTRFS_NODE
+	for i, (key, value) in enumerate(entries):
+		if level == 0:
+			end -= len(value)
+			node[end:end + len(value)] = value
+			node[101 + i * 25:126 + i * 25] = btrfs_key(*key) + struct.pack("<II", end - 101, len(value))
+		else:
+			node[101 + i * 33:134 + i * 33] = btrfs_key(*key) + struct.pack("<QQ", value, BTRFS_GEN)
+	node[32:101] = struct.pack("<16sQQ16sQQIB", BTRFS_FSID, bytenr, 1 | 1 << 56, BTRFS_CHUNK_UUID, BTRFS_GEN, owner,
+		len(entries), level)
+	node[:4] = struct.pack("<I", crc32c(node[32:]))
+	return bytes(node)
+
+
+def btrfs_levels(items):
+	# packs sorted items into full leaves and the leaves into internal nodes, returns the
+	# levels from the leaves up, each as a list of nodes
+	levels = [[[]]]
+	used = 0
+	for key, data in items:
+		if used + 25 + len(data) > BTRFS_NODE - 101:
+			levels[0].append([])
+			used = 0
+		levels[0][-1].append((key, data))
+		used += 25 + len(data)
+	per_node = (BTRFS_NODE - 101) // 33
+	while len(levels[-1]) > 1:
+		children = levels[-1]
+		levels.append([list(range(i, min(i + per_node, len(children)))) for i in range(0, len(children), per_node)])
+	return levels
+
+
+class Btrfs:
+	# a single device file system with DUP metadata and SINGLE data, like
+	# `mkfs.btrfs -n 4096 -m dup -d single -O no-holes,free-space-tree`
+	def __init__(self):
+		self.disk = bytearray(BTRFS_SIZE)
+		self.fs = {}
+		self.data = []        # logical address, length, back reference
+		self.csums = {}       # logical address of a sector, checksum
+		self.next_data = BTRFS_CHUNKS[2][0]
+		self.next_inode = 257
+		self.dirs = {}        # inode, [next index, size]
+		self.inodes = {}
+		self.mkdir_root()
+
+	def mkdir_root(self):
+		self.dirs[256] = [2, 0]
+		self.fs[(256, 12, 256)] = struct.pack("<QH", 0, 2) + b".."
+
+	def link(self, parent, name, inode, ty):
+		index = self.dirs[parent][0]
+		self.dirs[parent][0] += 1
+		self.dirs[parent][1] += 2 * len(name)
+		item = btrfs_dir_item((inode, 1, 0), name, ty)
+		key = (parent, 84, crc32c_raw(0xFFFFFFFE, name))
+		self.fs[key] = self.fs.get(key, b"") + item
+		self.fs[(parent, 96, index)] = item
+		self.fs[(inode, 12, parent)] = struct.pack("<QH", index, len(name)) + name
+
+	def mkdir(self, parent, name):
+		inode = self.next_inode
+		self.next_inode += 1
+		self.dirs[inode] = [2, 0]
+		self.link(parent, name, inode, 2)
+		return inode
+
+	def file(self, parent, name, size, extents, flags=0):
+		# extents are (file offset, item) with the disk extent already written
+		inode = self.next_inode
+		self.next_inode += 1
+		self.link(parent, name, inode, 1)
+		nbytes = 0
+		for offset, item in extents:
+			self.fs[(inode, 108, offset)] = item
+			ram, compression, kind = struct.unpack_from("<8xQBxxxB", item)
+			if kind == 0:
+				nbytes += ram
+				continue
+			disk, disk_len, extent_offset, num_bytes = struct.unpack_from("<4Q", item, 21)
+			nbytes += num_bytes
+			self.data.append((disk, disk_len, (inode, offset - extent_offset)))
+		self.inodes[inode] = btrfs_inode(size, nbytes, 0o100644, flags=flags)
+		return inode
+
+	def write_data(self, data):
+		# writes a data extent padded to whole sectors, returns its address and length
+		data += bytes(-len(data) % BTRFS_SECTOR)
+		bytenr = self.next_data
+		self.next_data += len(data)
+		offset = BTRFS_CHUNKS[2][3][0] + bytenr - BTRFS_CHUNKS[2][0]
+		self.disk[offset:offset + len(data)] = data
+		for i in range(0, len(data), BTRFS_SECTOR):
+			self.csums[bytenr + i] = struct.pack("<I", crc32c(data[i:i + BTRFS_SECTOR]))
+		return bytenr, len(data)
+
+	def image(self, label):
+		for inode, (_, size) in self.dirs.items():
+			self.fs[(inode, 1, 0)] = btrfs_inode(size, 0, 0o40755)
+		for inode, item in self.inodes.items():
+			self.fs[(inode, 1, 0)] = item
+
+		chunk_items = [((1, 216, 1), struct.pack("<3Q3I3QIBB16s16s", 1, BTRFS_SIZE, sum(c[1] * len(c[3]) for c in BTRFS_CHUNKS),
+			BTRFS_SECTOR, BTRFS_SECTOR, BTRFS_SECTOR, 0, 0, 0, 0, 0, 0, BTRFS_DEV_UUID, BTRFS_FSID))]
+		chunk_items += [((256, 228, c[0]), btrfs_chunk_item(c[1], c[2], c[3])) for c in BTRFS_CHUNKS]
+		dev_items = sorted(((1, 204, offset), struct.pack("<4Q16s", 3, 256, c[0], c[1], BTRFS_CHUNK_UUID))
+			for c in BTRFS_CHUNKS for offset in c[3])
+		csum_items = []
+		sectors = sorted(self.csums)
+		for i in range(0, len(sectors), 64):
+			run = sectors[i:i + 64]
+			# a checksum item covers contiguous sectors
+			start = 0
+			for j in range(1, len(run) + 1):
+				if j == len(run) or run[j] != run[j - 1] + BTRFS_SECTOR:
+					csum_items.append(((2**64 - 10, 128, run[start]), b"".join(self.csums[s] for s in run[start:j])))
+					start = j
+		fs_items = sorted(self.fs.items())
+
+		# trees whose size doesn't depend on where the nodes are
+		trees = {3: chunk_items, 4: dev_items, 5: fs_items, 7: csum_items}
+		root_dir = [((6, 1, 0), btrfs_inode(0, 0, 0o40755)), ((6, 12, 6), struct.pack("<QH", 0, 2) + b".."),
+			((6, 84, crc32c_raw(0xFFFFFFFE, b"default")), btrfs_dir_item((5, 132, 2**64 - 1), b"default", 2))]
+		root_items = [((tree, 132, 0), btrfs_root_item(0, 0)) for tree in (2, 4, 5, 7, 10)] + root_dir
+		trees[1] = sorted(root_items)
+
+		# the extent and free space trees describe all nodes, including their own
+		counts = {2: 1, 10: 1}
+		while True:
+			addresses = {}
+			next_meta = {2 | 32: BTRFS_CHUNKS[0][0], 4 | 32: BTRFS_CHUNKS[1][0]}
+			for tree in (3, 1, 2, 4, 5, 7, 10):
+				n = counts[tree] if tree in (2, 10) else sum(len(level) for level in btrfs_levels(trees[tree]))
+				kind = 2 | 32 if tree == 3 else 4 | 32
+				addresses[tree] = [next_meta[kind] + i * BTRFS_NODE for i in range(n)]
+				next_meta[kind] += n * BTRFS_NODE
+
+			extent_items = []
+			used = {c[0]: 0 for c in BTRFS_CHUNKS}
+			for tree, nodes in addresses.items():
+				levels = btrfs_levels(trees[tree]) if tree in trees else [[0]] * 0
+				level_of = []
+				for level, level_nodes in enumerate(levels):
+					level_of += [level] * len(level_nodes)
+				level_of += [0] * (len(nodes) - len(level_of))
+				for bytenr, level in zip(nodes, level_of):
+					extent_items.append(((bytenr, 169, level), struct.pack("<3QBQ", 1, BTRFS_GEN, 2, 176, tree)))
+			for bytenr, length, (inode, offset) in self.data:
+				refs = [(b, l, r) for b, l, r in self.data if b == bytenr]
+				if refs[0][2] != (inode, offset):
+					continue
+				item = struct.pack("<3Q", len(refs), BTRFS_GEN, 1)
+				for r in sorted(set(r for _, _, r in refs), key=lambda r: -btrfs_data_ref_hash(5, *r)):
+					item += struct.pack("<BQQQI", 178, 5, r[0], r[1], sum(1 for _, _, x in refs if x == r))
+				extent_items.append(((bytenr, 168, length), item))
+			allocated = sorted([(k[0], BTRFS_NODE) for k, _ in extent_items if k[1] == 169]
+				+ [(k[0], k[2]) for k, _ in extent_items if k[1] == 168])
+			for start, length in allocated:
+				chunk = max(c for c in used if c <= start)
+				used[chunk] += length
+			for c in BTRFS_CHUNKS:
+				extent_items.append(((c[0], 192, c[1]), struct.pack("<3Q", used[c[0]], 256, c[2])))
+
+			fst_items = []
+			for c in BTRFS_CHUNKS:
+				free = []
+				pos = c[0]
+				for start, length in allocated:
+					if c[0] <= start < c[0] + c[1]:
+						if start > pos:
+							free.append((pos, start - pos))
+						pos = start + length
+				if pos < c[0] + c[1]:
+					free.append((pos, c[0] + c[1] - pos))
+				fst_items.append(((c[0], 198, c[1]), struct.pack("<II", len(free), 0)))
+				fst_items += [((start, 199, length), b"") for start, length in free]
+
+			trees[2] = sorted(extent_items)
+			trees[10] = sorted(fst_items)
+			new_counts = {tree: sum(len(level) for level in btrfs_levels(trees[tree])) for tree in (2, 10)}
+			if new_counts == counts:
+				break
+			counts = new_counts
+
+		roots = {}
+		for tree in (3, 2, 4, 5, 7, 10):
+			roots[tree] = self.write_tree(tree, trees[tree], addresses[tree])
+		trees[1] = sorted([((tree, 132, 0), btrfs_root_item(*roots[tree], dirid=256 if tree == 5 else 0))
+			for tree in (2, 4, 5, 7, 10)] + root_dir)
+		roots[1] = self.write_tree(1, trees[1], addresses[1])
+
+		sys_chunk = btrfs_key(256, 228, BTRFS_CHUNKS[0][0]) + btrfs_chunk_item(*BTRFS_CHUNKS[0][1:])
+		bytes_used = sum(length for _, length in allocated)
+		sb = struct.pack("<16sQQ8sQQQQQQQQQIIIIIQQQQHBBB", BTRFS_FSID, 0x10000, 0, b"_BHRfS_M", BTRFS_GEN,
+			roots[1][0], roots[3][0], 0, 0, BTRFS_SIZE, bytes_used, 6, 1, BTRFS_SECTOR, BTRFS_NODE, BTRFS_NODE,
+			BTRFS_SECTOR, len(sys_chunk), BTRFS_GEN, 0, 3, 1 | 16 | 64 | 256 | 512, 0, roots[1][1], roots[3][1], 0)
+		sb += chunk_items[0][1] + label.ljust(256, b"\0") + struct.pack("<QQ16sQ", 0, 0, bytes(16), 0) + bytes(27 * 8)
+		sb += sys_chunk.ljust(2048, b"\0")
+		sb = bytes(32) + sb
+		sb += bytes(4096 - len(sb))
+		assert len(sb) == 4096 and sb[0x32B:0x32B + 17] == sys_chunk[:17]
+		sb = struct.pack("<I", crc32c(sb[32:])) + sb[4:]
+		self.disk[0x10000:0x11000] = sb
+		return bytes(self.disk)
+
+	def write_tree(self, owner, items, addresses):
+		levels = btrfs_levels(items)
+		index = 0
+		level_addresses = []
+		for level, nodes in enumerate(levels):
+			level_addresses.append(addresses[index:index + len(nodes)])
+			index += len(nodes)
+		first_keys = [[node[0][0] if node else (0, 0, 0) for node in levels[0]]]
+		for level, nodes in enumerate(levels):
+			keys = []
+			for i, node in enumerate(nodes):
+				if level == 0:
+					entries = node
+				else:
+					entries = [(first_keys[level - 1][c], level_addresses[level - 1][c]) for c in node]
+				keys.append(entries[0][0] if entries else (0, 0, 0))
+				self.write_node(level_addresses[level][i], btrfs_node(level_addresses[level][i], owner, level, entries))
+			if level > 0:
+				first_keys.append(keys)
+		return level_addresses[-1][0], len(levels) - 1
+
+	def write_node(self, bytenr, node):
+		for logical, length, _, stripes in BTRFS_CHUNKS:
+			if logical <= bytenr < logical + length:
+				for offset in stripes:
+					self.disk[offset + bytenr - logical:offset + bytenr - logical + len(node)] = node
+
+
+def btrfs_data_ref_hash(root, inode, offset):
+	high = crc32c_raw(0xFFFFFFFF, struct.pack("<Q", root))
+	low = crc32c_raw(crc32c_raw(0xFFFFFFFF, struct.pack("<Q", inode)), struct.pack("<Q", offset))
+	return high << 31 ^ low
+
+
+def btrfs_image():
+	# equivalent to `mkfs.btrfs -n 4096 -m dup -d single -L fixture` followed by copying the files with
+	# `compress-force=zlib` or `zstd` where they are compressed
+	fs = Btrfs()
+	fs.file(256, b"hello.txt", 14, [(0, btrfs_inline(b"Hello, Btrfs!\n", 14))])
+	inline = text(3000, 7)
+	fs.file(256, b"inline.txt", 3000, [(0, btrfs_inline(zlib.compress(inline), 3000, 1))])
+
+	data = text(100000, 3) + bytes(2400)
+	bytenr, length = fs.write_data(zlib.compress(data))
+	fs.file(256, b"zlib.bin", 100000, [(0, btrfs_extent(bytenr, length, 0, len(data), len(data), 1))])
+
+	data = text(200000, 4) + bytes(4800)
+	extents = []
+	for offset in (0, 128 << 10):
+		part = data[offset:offset + (128 << 10)]
+		bytenr, length = fs.write_data(zstd(part, "-3", "--no-check"))
+		extents.append((offset, btrfs_extent(bytenr, length, 0, len(part), len(part), 3)))
+	fs.file(256, b"zstd.bin", 200000, extents)
+
+	# an extent, a hole and the middle of an extent whose other parts were overwritten
+	first = fs.write_data(pattern(16384, 5))
+	second = fs.write_data(pattern(16384, 9))
+	fs.file(256, b"plain.bin", 45000, [
+		(0, btrfs_extent(*first, 0, 16384, 16384)),
+		(32768, btrfs_extent(*second, 4096, 8192, 16384))
+	])
+
+	sub = fs.mkdir(fs.mkdir(256, b"dir"), b"sub")
+	fs.file(sub, b"nested.txt", 7, [(0, btrfs_inline(b"nested\n", 7))])
+	many = fs.mkdir(256, b"many")
+	for i in range(150):
+		content = b"%d\n" % i
+		fs.file(many, b"file-%03d.txt" % i, len(content), [(0, btrfs_inline(content, len(content)))])
+	write("btrfs/btrfs.img", sparse(fs.image(b"fixture")))
+	mkfs_btrfs_image("btrfs/mkfs-zlib.img", "zlib")
+	mkfs_btrfs_image("btrfs/mkfs-zstd.img", "zstd")
+
+
+def mkfs_btrfs_image(path, compress):
+	# formatted by `mkfs.btrfs` from a directory, `--compress` needs btrfs-progs 6.13
+	if not tools(path, "mkfs.btrfs"):
+		return
+	files = {
+		"hello.txt": b"Hello, Btrfs!\n",
+		"text.txt": text(1 << 20, 3),
+		"plain.bin": pattern(40000, 5),
+		"dir/sub/nested.txt": b"nested\n"
+	}
+	files.update(("many/file-%03d.txt" % i, b"%d\n" % i) for i in range(150))
+	with tempfile.TemporaryDirectory() as tmp:
+		root = os.path.join(tmp, "root")
+		for name, data in files.items():
+			os.makedirs(os.path.dirname(os.path.join(root, name)), exist_ok=True)
+			with open(os.path.join(root, name), "wb") as f:
+				f.write(data)
+		img = os.path.join(tmp, "btrfs.img")
+		with open(img, "wb") as f:
+			f.truncate(128 << 20)
+		subprocess.run(["mkfs.btrfs", "-q", "-L", "fixture", "-n", "4096", "--rootdir", root, "--compress", compress,
+			img], check=True)
+		with open(img, "rb") as f:
+			write(path, sparse(f.read()))
+
+
+# ------------------------------------------------------------------------------------------------
+# md RAID
+# ------------------------------------------------------------------------------------------------
+
+MD_DATA_OFFSET = 2048
+MD_SIZE = 64
+MD_CHUNK = 16
+MD_UUID = bytes(range(0x40, 0x50))
+
+
+def md_superblock(level, layout, chunk, raid_disks, number, roles, events=5):
+	# version 1.2 as `mdadm --create --metadata=1.2` writes it, without bitmap and bad block log
+	sb = struct.pack("<IIII16s32sQiIQIIIIQIIIIQQQQII16sBBHIQQQII32s", 0xA92B4EFC, 1, 0, 0, MD_UUID,
+		b"fixture", 1700000000, level, layout, MD_SIZE, chunk, raid_disks, 0, 0, 0, 0, 0, 0, 0,
+		MD_DATA_OFFSET, MD_SIZE, 8, 0, number, 0, bytes([number + 1] * 16), 0, 0, 0, 0,
+		1700000100, events, 0xFFFFFFFFFFFFFFFF, 0, len(roles), bytes(32))
+	sb += b"".join(struct.pack("<H", r) for r in roles)
+	words = struct.unpack("<%dI" % ((len(sb) + 3) // 4), align(sb, 4))
+	csum = sum(words)
+	csum = (csum & 0xFFFFFFFF) + (csum >> 32)
+	return sb[:0xD8] + struct.pack("<I", csum & 0xFFFFFFFF) + sb[0xDC:]
+
+
+def md_sector(n):
+	# every sector of the array holds its number, which the tests check without a copy of the data
+	return struct.pack("<Q", 0x6D640000_00000000 | n) * 64
+
+
+def gf_mul(a, b):
+	r = 0
+	while b:
+		if b & 1:
+			r ^= a
+		a <<= 1
+		if a & 0x100:
+			a ^= 0x11D
+		b >>= 1
+	return r
+
+
+def md_parity(blocks, q):
+	out = bytearray(len(blocks[0]))
+	for i, block in enumerate(blocks):
+		g = 1
+		for _ in range(i if q else 0):
+			g = gf_mul(g, 2)
+		for j, b in enumerate(block):
+			out[j] ^= gf_mul(g, b) if q else b
+	return bytes(out)
+
+
+def md_array(level, disks):
+	chunk = MD_CHUNK * 512
+	rows = MD_SIZE // MD_CHUNK
+	members = [[None] * rows for _ in range(disks)]
+	sector = 0
+
+	def data():
+		nonlocal sector
+		out = b"".join(md_sector(sector + i) for i in range(MD_CHUNK))
+		sector += MD_CHUNK
+		return out
+
+	if level == 10:
+		# near layout with two copies, chunk k is on disks 2k and 2k + 1 modulo the disk count
+		layout = 0x102
+		for k in range(rows * disks // 2):
+			block = data()
+			for j in range(2):
+				i = k * 2 + j
+				members[i % disks][i // disks] = block
+	else:
+		# left-symmetric, the parity rotates backwards and the data starts after it
+		layout = 2
+		parity = 1 if level == 5 else 2
+		for stripe in range(rows):
+			p = disks - 1 - stripe % disks
+			blocks = [data() for _ in range(disks - parity)]
+			members[p][stripe] = md_parity(blocks, False)
+			if level == 6:
+				members[(p + 1) % disks][stripe] = md_parity(blocks, True)
+			for i, block in enumerate(blocks):
+				members[(p + parity + i) % disks][stripe] = block
+
+	roles = list(range(disks))
+	for number in range(disks):
+		image = bytearray(MD_DATA_OFFSET * 512 + MD_SIZE * 512)
+		sb = md_superblock(level, layout, MD_CHUNK, disks, number, roles)
+		image[4096:4096 + len(sb)] = sb
+		image[MD_DATA_OFFSET * 512:] = b"".join(members[number])
+		write("raid/raid%d-%d.img" % (level, number), sparse(bytes(image)))
+
+
+def mdadm_array(level, disks):
+	# members of an array made by `mdadm --create`, which needs root and the kernel's md
+	# driver; the array is filled like `md_array` fills them
+	path = "raid/mdadm-raid%d-*.img" % level
+	if not tools(path, "mdadm", "losetup"):
+		return
+	if os.geteuid() != 0:
+		print("skipping %s, needs root" % path)
+		return
+	with tempfile.TemporaryDirectory() as tmp:
+		files = [os.path.join(tmp, "member%d.img" % i) for i in range(disks)]
+		loops = []
+		try:
+			for name in files:
+				with open(name, "wb") as f:
+					f.truncate(8 << 20)
+				loops.append(subprocess.run(["losetup", "-f", "--show", name], stdout=subprocess.PIPE, check=True)
+					.stdout.decode().strip())
+			md = "/dev/md/fixture"
+			subprocess.run(["mdadm", "--create", md, "--run", "--metadata=1.2", "--level=%d" % level,
+				"--raid-devices=%d" % disks, "--chunk=16", "--name=fixture", "--homehost=<none>", "--assume-clean",
+				*loops], check=True)
+			try:
+				with open(md, "r+b") as f:
+					size = f.seek(0, 2) // 512
+					f.seek(0)
+					for n in range(size):
+						f.write(md_sector(n))
+			finally:
+				subprocess.run(["mdadm", "--stop", md], check=True)
+		finally:
+			for loop in loops:
+				subprocess.run(["losetup", "-d", loop], check=True)
+		for i, name in enumerate(files):
+			with open(name, "rb") as f:
+				write("raid/mdadm-raid%d-%d.img" % (level, i), sparse(f.read()))
+
+
+def md_arrays():
+	md_array(5, 3)
+	md_array(6, 4)
+	md_array(10, 3)
+	mdadm_array(1, 2)
+	mdadm_array(5, 3)
+
+
+# ------------------------------------------------------------------------------------------------
+# SMBIOS & UEFI
+# ------------------------------------------------------------------------------------------------
+
+def smbios():
+	ep2 = bytearray(struct.pack("<4sBBBBHB5s5sBHIHB", b"_SM_", 0, 0x1F, 2, 8, 0x0052, 0, b"\0" * 5, b"_DMI_", 0,
+		0x0144, 0x000F0C00, 9, 0x28))
+	ep2[21] = checksum(ep2[16:31])
+	ep2[4] = checksum(ep2)
+	write("smbios/ep2.bin", bytes(ep2))
+
+	ep3 = bytearray(struct.pack("<5sBBBBBBBIQ", b"_SM3_", 0, 0x18, 3, 0, 0, 1, 0, 0x0000014A, 0x7F8F0000))
+	ep3[5] = checksum(ep3)
+	write("smbios/ep3.bin", bytes(ep3))
+
+
+def uefi_memory_map():
+	# OVMF reports 48 byte descriptors
+	layout = [
+		(3, 0x0000000000000000, 0x0A0),
+		(7, 0x00000000000A0000, 0x060),
+		(4, 0x0000000000100000, 0x700),
+		(7, 0x0000000000800000, 0x008),
+		(10, 0x0000000000808000, 0x008),
+		(1, 0x0000000000810000, 0x040),
+		(2, 0x0000000000850000, 0x010),
+		(7, 0x0000000000860000, 0x7F000),
+		(9, 0x000000007FB7E000, 0x00A),
+		(5, 0x000000007FB88000, 0x020),
+		(6, 0x000000007FBA8000, 0x030),
+		(0, 0x000000007FBD8000, 0x428),
+		(11, 0x00000000B0000000, 0x10000),
+	]
+	data = b""
+	for ty, start, pages in layout:
+		attr = 0xF | (1 << 63 if ty in (5, 6, 11) else 0)
+		data += struct.pack("<IIQQQQQ", ty, 0, start, 0, pages, attr, 0)
+	write("uefi/memory-map.bin", data)
+
+
+if __name__ == "__main__":
+	q35()
+	q35("acpi/q35-s3.bin", s3=True)
+	virt()
+	q35_numa()
+	write("acpi/interp.aml", interp_ssdt())
+	riscv_virt()
+	aarch64_virt()
+	riscv_virt_numa()
+	gpt_disk()
+	fat_images()
+	compressed()
+	btrfs_image()
+	md_arrays()
+	smbios()
+	uefi_memory_map()
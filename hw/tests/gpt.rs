@@ -0,0 +1,228 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+use hw::{block::{BlockDevice, RamDisk}, gpt::*};
+
+const SECTOR: usize = 512;
+
+fn header(disk: &[u8], lba: u64) -> Header {
+	common::read(disk, lba as usize * SECTOR)
+}
+
+fn entries(disk: &[u8], header: &Header) -> Vec<PartitionEntry> {
+	(0..header.number_of_partition_entries as usize)
+		.map(|i| common::read(disk, header.partition_entry_lba as usize * SECTOR + i * header.size_of_partition_entry as usize))
+		.collect()
+}
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<Header>(), 92);
+	assert_eq!(core::mem::size_of::<PartitionEntry>(), 128);
+}
+
+#[test]
+fn headers() {
+	let disk = common::load("gpt/disk.img");
+	let primary = header(disk, 1);
+	assert!(primary.is_valid());
+	assert_eq!({ primary.revision }, 0x10000);
+	assert_eq!({ primary.header_size }, 92);
+	assert_eq!({ primary.my_lba }, 1);
+	assert_eq!({ primary.alternate_lba }, 127);
+	assert_eq!({ primary.first_usable_lba }, 34);
+	assert_eq!({ primary.last_usable_lba }, 94);
+	assert_eq!({ primary.partition_entry_lba }, 2);
+	assert_eq!({ primary.number_of_partition_entries }, 128);
+	assert_eq!({ primary.size_of_partition_entry }, 128);
+
+	let backup = header(disk, primary.alternate_lba);
+	assert!(backup.is_valid());
+	assert_eq!({ backup.my_lba }, 127);
+	assert_eq!({ backup.alternate_lba }, 1);
+	assert_eq!({ backup.partition_entry_lba }, 95);
+	assert_eq!({ backup.disk_guid }, { primary.disk_guid });
+	assert_eq!({ backup.partition_entry_array_crc32 }, { primary.partition_entry_array_crc32 });
+
+	assert!(!header(disk, 0).is_valid());
+}
+
+#[test]
+fn partitions() {
+	let disk = common::load("gpt/disk.img");
+	let header = header(disk, 1);
+	let entries = entries(disk, &header);
+	let used = entries.iter().filter(|e| e.is_used()).collect::<Vec<_>>();
+	assert_eq!(used.len(), 2);
+
+	assert_eq!({ used[0].partition_type_guid }, EFI_SYSTEM_PARTITION_GUID);
+	assert_eq!({ used[0].unique_partition_guid }.to_le_bytes()[..4], [0x1A, 0x3E, 0x6F, 0x2B]);
+	assert_eq!(({ used[0].starting_lba }, { used[0].ending_lba }), (34, 63));
+	assert_eq!({ used[0].attributes }, 0);
+	assert_eq!(&used[0].partition_name[..6], &[b'E', 0, b'F', 0, b'I', 0]);
+
+	assert_eq!({ used[1].partition_type_guid }, 0xE47D_47D8_693D_798E_4772_8483_0FC6_3DAF);
+	assert_eq!(({ used[1].starting_lba }, { used[1].ending_lba }), (64, 94));
+	assert_eq!({ used[1].attributes } & PARTITION_ENTRY_LEGACY_BIOS_BOOTABLE, PARTITION_ENTRY_LEGACY_BIOS_BOOTABLE);
+
+	// the backup array is a copy of the primary one
+	let backup = self::entries(disk, &self::header(disk, 127));
+	assert!(entries.iter().zip(backup.iter()).all(|(a, b)| { a.starting_lba } == { b.starting_lba }
+		&& { a.partition_type_guid } == { b.partition_type_guid }));
+}
+
+#[test]
+fn corrupted_primary() {
+	let disk = common::load("gpt/disk-bad-primary.img");
+	let primary = header(disk, 1);
+	assert!(primary.is_valid());
+	assert_ne!({ primary.my_lba }, 1);
+
+	let backup = header(disk, 127);
+	assert!(backup.is_valid());
+	assert_eq!({ backup.my_lba }, 127);
+}
+
+fn ram_disk(name: &str) -> RamDisk {
+	RamDisk::from_vec(SECTOR, common::load(name).to_vec())
+}
+
+#[test]
+fn open() {
+	let gpt = Gpt::open(ram_disk("gpt/disk.img")).unwrap();
+	assert!(!gpt.needs_repair());
+	assert_eq!(gpt.len(), 128);
+	assert_eq!(({ gpt.header().first_usable_lba }, { gpt.header().last_usable_lba }), (34, 94));
+
+	let partitions = gpt.partitions().collect::<Vec<_>>();
+	assert_eq!(partitions.len(), 2);
+	assert_eq!(partitions[0].1.name(), "EFI system partition");
+	assert_eq!(({ partitions[1].1.starting_lba }, partitions[1].1.blocks()), (64, 31));
+
+	let guid = partitions[0].1.unique_partition_guid;
+	assert_eq!(gpt.find(guid).map(|(i, _)| i), Some(partitions[0].0));
+	assert!(gpt.find(guid + 1).is_none());
+
+	assert!(matches!(Gpt::open(RamDisk::new(SECTOR, 128)), Err(Error::NoTable)));
+}
+
+#[test]
+fn recover() {
+	let good = Gpt::open(ram_disk("gpt/disk.img")).unwrap().partitions().collect::<Vec<_>>();
+
+	let mut gpt = Gpt::open(ram_disk("gpt/disk-bad-primary.img")).unwrap();
+	assert!(gpt.needs_repair());
+	assert_eq!({ gpt.header().my_lba }, 1);
+	let recovered = gpt.partitions().collect::<Vec<_>>();
+	assert_eq!(recovered.len(), good.len());
+	assert!(recovered.iter().zip(&good).all(|((_, a), (_, b))| { a.unique_partition_guid } == { b.unique_partition_guid }
+		&& { a.starting_lba } == { b.starting_lba }));
+
+	gpt.repair().unwrap();
+	let mut disk = gpt.into_inner();
+	assert_eq!({ header(disk.as_slice(), 1).my_lba }, 1);
+	let gpt = Gpt::open(&mut disk).unwrap();
+	assert!(!gpt.needs_repair());
+
+	// a damaged entry array invalidates its header, a damaged backup is noticed as well
+	disk.as_mut_slice()[2 * SECTOR + 40] ^= 1;
+	let gpt = Gpt::open(&mut disk).unwrap();
+	assert!(gpt.needs_repair());
+	assert_eq!(gpt.partitions().count(), 2);
+
+	disk.as_mut_slice()[2 * SECTOR + 40] ^= 1;
+	disk.as_mut_slice()[127 * SECTOR + 24] ^= 1;
+	assert!(Gpt::open(&mut disk).unwrap().needs_repair());
+
+	disk.as_mut_slice()[2 * SECTOR + 40] ^= 1;
+	assert!(matches!(Gpt::open(&mut disk), Err(Error::NoTable)));
+}
+
+#[test]
+fn create() {
+	let mut disk = RamDisk::new(SECTOR, 2048);
+	disk.as_mut_slice()[..4].copy_from_slice(b"BOOT");
+	let mut gpt = Gpt::create(&mut disk, 0x1234).unwrap();
+	assert_eq!(({ gpt.header().first_usable_lba }, { gpt.header().last_usable_lba }), (34, 2014));
+	assert_eq!(gpt.partitions().count(), 0);
+
+	let esp = gpt.add(EFI_SYSTEM_PARTITION_GUID, 1, 34, 1033, "EFI system partition").unwrap();
+	assert_eq!(gpt.add(LEGACY_MBR_PARTITION_GUID, 2, 1000, 1100, "overlap"), Err(Error::InvalidRange));
+	assert_eq!(gpt.add(LEGACY_MBR_PARTITION_GUID, 2, 2000, 2015, "end"), Err(Error::InvalidRange));
+	assert_eq!(gpt.add(LEGACY_MBR_PARTITION_GUID, 1, 1100, 1200, "same"), Err(Error::InvalidGuid));
+	assert_eq!(gpt.find_free(100, 2048), None);
+	assert_eq!(gpt.find_free(100, 8), Some(1040));
+	let data = gpt.add(LEGACY_MBR_PARTITION_GUID, 2, 1040, 1139, "data").unwrap();
+	assert_ne!(esp, data);
+
+	assert_eq!(gpt.resize(1, 1040), Err(Error::InvalidRange));
+	gpt.resize(2, 2014).unwrap();
+	assert_eq!(gpt.remove(3).err(), Some(Error::NotFound));
+	assert_eq!(gpt.remove(1).unwrap().name(), "EFI system partition");
+	assert_eq!(gpt.find_free(16, 1), Some(34));
+
+	let disk = disk.as_slice();
+	assert_eq!(&disk[..4], b"BOOT");
+	assert_eq!(disk[446 + 4], 0xEE);
+	assert_eq!(common::read::<u32>(disk, 446 + 8), 1);
+	assert_eq!(common::read::<u32>(disk, 446 + 12), 2047);
+	assert_eq!(&disk[510..512], &[0x55, 0xAA]);
+
+	let (primary, backup) = (header(disk, 1), header(disk, 2047));
+	assert_eq!(({ primary.alternate_lba }, { backup.my_lba }, { backup.partition_entry_lba }), (2047, 2047, 2015));
+	assert_eq!({ primary.partition_entry_array_crc32 }, hw::crc::crc32(&disk[2 * SECTOR..34 * SECTOR]));
+	assert_eq!(&disk[2 * SECTOR..34 * SECTOR], &disk[2015 * SECTOR..2047 * SECTOR]);
+
+	let entries = entries(disk, &backup);
+	let used = entries.iter().filter(|e| e.is_used()).collect::<Vec<_>>();
+	assert_eq!(used.len(), 1);
+	assert_eq!(({ used[0].unique_partition_guid }, { used[0].ending_lba }), (2, 2014));
+
+	// the array of a 4 KiB sector disk is 4 sectors long
+	let gpt = Gpt::create(RamDisk::new(4096, 64), 1).unwrap();
+	assert_eq!(({ gpt.header().first_usable_lba }, { gpt.header().last_usable_lba }), (6, 58));
+	assert!(Gpt::open(gpt.into_inner()).is_ok());
+	assert_eq!(Gpt::create(RamDisk::new(SECTOR, 66), 1).err(), Some(Error::InvalidRange));
+}
+
+#[test]
+fn partition_device() {
+	let disk = Rc::new(RefCell::new(RamDisk::new(SECTOR, 256)));
+	let mut gpt = Gpt::create(disk.clone(), 7).unwrap();
+	gpt.add(EFI_SYSTEM_PARTITION_GUID, 0xAB, 40, 49, "").unwrap();
+
+	let mut part = gpt.partition(0xAB).unwrap();
+	assert!(gpt.partition(0xAC).is_none());
+	assert_eq!((part.blocks(), part.first_lba(), part.type_guid), (10, 40, EFI_SYSTEM_PARTITION_GUID));
+
+	part.write(9, &[0x5A; SECTOR]).unwrap();
+	assert_eq!(&disk.borrow().as_slice()[49 * SECTOR..50 * SECTOR], &[0x5A; SECTOR]);
+	assert!(part.write(10, &[0; SECTOR]).is_err());
+	assert!(part.read(9, &mut [0; 2 * SECTOR]).is_err());
+
+	let mut buf = [0; SECTOR];
+	part.read_vectored(9, &mut [&mut buf]).unwrap();
+	assert_eq!(buf, [0x5A; SECTOR]);
+}
@@ -0,0 +1,524 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{alloc::Layout, cell::RefCell, collections::BTreeMap, rc::Rc};
+use hw::{dma::Dma, hda::*};
+
+const INPUTS: usize = 4;
+const OUTPUTS: usize = 4;
+const FIFO: u16 = 63;
+const VENDOR_ID: u32 = 0x1AF4_0022;
+/// 16 bit at 44.1 and 48 kHz
+const PCM: u32 = PCM_BITS_16 | 1 << 5 | 1 << 6;
+
+/// A widget of the mock codec: widget caps, pin caps, configuration default and the
+/// encoded connection list.
+struct MockWidget {
+	caps:        u32,
+	pin_caps:    u32,
+	config:      u32,
+	connections: &'static [u8]
+}
+
+/// Nodes 2 to 8 of the codec: DAC 2 feeds mixer 3, which feeds the headphone pin 4, the
+/// unconnected line out pin 5 takes DAC 2 directly. ADC 6 selects between the unconnected
+/// line in pin 7 and the microphone pin 8, its list is the range 7 to 8.
+const WIDGETS: [MockWidget; 7] = [
+	MockWidget { caps: WCAP_STEREO | WCAP_OUT_AMP, pin_caps: 0, config: 0, connections: &[] },
+	MockWidget { caps: 0x2 << 20 | WCAP_STEREO | WCAP_IN_AMP | WCAP_CONN_LIST, pin_caps: 0, config: 0, connections: &[2] },
+	MockWidget { caps: 0x4 << 20 | WCAP_STEREO | WCAP_OUT_AMP | WCAP_CONN_LIST, pin_caps: PINCAP_OUTPUT | PINCAP_HEADPHONE | PINCAP_EAPD,
+		config: CONFIG_PORT_JACK | DEVICE_HEADPHONE << CONFIG_DEVICE_SHIFT, connections: &[3] },
+	MockWidget { caps: 0x4 << 20 | WCAP_STEREO | WCAP_CONN_LIST, pin_caps: PINCAP_OUTPUT,
+		config: CONFIG_PORT_NONE | DEVICE_LINE_OUT << CONFIG_DEVICE_SHIFT, connections: &[2] },
+	MockWidget { caps: 0x1 << 20 | WCAP_STEREO | WCAP_IN_AMP | WCAP_AMP_OVERRIDE | WCAP_CONN_LIST, pin_caps: 0, config: 0,
+		connections: &[7, 0x88] },
+	MockWidget { caps: 0x4 << 20 | WCAP_STEREO, pin_caps: PINCAP_INPUT,
+		config: CONFIG_PORT_NONE | DEVICE_LINE_IN << CONFIG_DEVICE_SHIFT, connections: &[] },
+	MockWidget { caps: 0x4 << 20 | WCAP_STEREO | WCAP_IN_AMP, pin_caps: PINCAP_INPUT,
+		config: CONFIG_PORT_JACK | DEVICE_MIC << CONFIG_DEVICE_SHIFT, connections: &[] }
+];
+
+/// Emulates a controller with a codec at address 0 when `codec` is set. Commands are
+/// executed whenever the driver stalls, streams only advance in `tick`.
+#[derive(Default)]
+struct State {
+	regs:      Vec<u32>,
+	allocs:    BTreeMap<usize, Layout>,
+	codec:     bool,
+	in_reset:  bool,
+	corb_read: usize,
+	rirb_write: usize,
+	/// Verbs the codec received as node, verb and payload
+	verbs:     Vec<(u8, u32, u32)>,
+	/// Bytes the output streams fetched
+	played:    Vec<u8>,
+	/// Next byte the input streams capture
+	captured:  u8
+}
+
+#[derive(Clone)]
+struct Mock(Rc<RefCell<State>>);
+
+impl Mock {
+	fn new(codec: bool) -> Self {
+		let mut state = State { regs: vec![0; 0x440 / 4], codec, in_reset: true, ..State::default() };
+		state.regs[0] = (OUTPUTS as u32) << 12 | (INPUTS as u32) << 8 | GCAP_64OK as u32 | 0x0100_0000;
+		state.write8(0x4E, RINGSIZE_CAP_256 | RINGSIZE_2 | RINGSIZE_16);
+		state.write8(0x5E, RINGSIZE_CAP_256 | RINGSIZE_CAP_16);
+		for i in 0..INPUTS + OUTPUTS {
+			state.write16(stream_reg(i, 0x10), FIFO);
+		}
+		Self(Rc::new(RefCell::new(state)))
+	}
+
+	fn regs(&self) -> *mut u8 {
+		self.0.borrow_mut().regs.as_mut_ptr() as *mut u8
+	}
+
+	/// Advances the running streams by `bytes`.
+	fn tick(&self, bytes: usize) {
+		self.0.borrow_mut().tick(bytes);
+	}
+
+	/// Sends an unsolicited response from the codec.
+	fn unsolicited(&self, response: u32) {
+		let mut state = self.0.borrow_mut();
+		state.respond(response, RIRB_EX_UNSOL);
+	}
+}
+
+fn stream_reg(i: usize, off: usize) -> usize {
+	0x80 + i * 0x20 + off
+}
+
+impl State {
+	fn ptr(&mut self, off: usize) -> *mut u8 {
+		unsafe { (self.regs.as_mut_ptr() as *mut u8).add(off) }
+	}
+
+	fn read8(&mut self, off: usize) -> u8 {
+		unsafe { self.ptr(off).read() }
+	}
+
+	fn write8(&mut self, off: usize, v: u8) {
+		unsafe { self.ptr(off).write(v) }
+	}
+
+	fn read16(&mut self, off: usize) -> u16 {
+		unsafe { (self.ptr(off) as *mut u16).read() }
+	}
+
+	fn write16(&mut self, off: usize, v: u16) {
+		unsafe { (self.ptr(off) as *mut u16).write(v) }
+	}
+
+	fn read32(&self, off: usize) -> u32 {
+		self.regs[off / 4]
+	}
+
+	fn base(&mut self, off: usize) -> u64 {
+		self.read32(off) as u64 | (self.read32(off + 4) as u64) << 32
+	}
+
+	fn process(&mut self) {
+		match (self.read32(0x08) & GCTL_CRST != 0, self.in_reset) {
+			(false, _) => {
+				self.in_reset = true;
+				self.write16(0x0E, 0);
+			}
+			(true, true) => {
+				self.in_reset = false;
+				self.write16(0x0E, self.codec as u16);
+			}
+			_ => ()
+		}
+
+		if self.read16(0x4A) & CORBRP_RST != 0 {
+			self.corb_read = 0;
+			self.write16(0x4A, 0);
+		}
+		if self.read16(0x58) & RIRBWP_RST != 0 {
+			self.rirb_write = 0;
+			self.write16(0x58, 0);
+		}
+
+		for i in 0..INPUTS + OUTPUTS {
+			let control = self.read16(stream_reg(i, 0));
+			if control & SDCTL_SRST != 0 {
+				self.regs[stream_reg(i, 0x04) / 4] = 0;
+			}
+		}
+
+		if self.read8(0x4C) & CORBCTL_RUN == 0 || self.read8(0x5C) & RIRBCTL_DMAEN == 0 {
+			return;
+		}
+		let write = self.read16(0x48) as usize;
+		while self.corb_read != write {
+			self.corb_read = (self.corb_read + 1) % 256;
+			let command = unsafe { (self.base(0x40) as *const u32).add(self.corb_read).read() };
+			let response = self.verb(command);
+			self.respond(response, 0);
+		}
+		self.write16(0x4A, self.corb_read as u16);
+	}
+
+	fn respond(&mut self, response: u32, extended: u32) {
+		self.rirb_write = (self.rirb_write + 1) % 256;
+		let entry = response as u64 | (extended as u64) << 32;
+		unsafe { (self.base(0x50) as *mut u64).add(self.rirb_write).write(entry) };
+		self.write16(0x58, self.rirb_write as u16);
+		self.write8(0x5D, RIRBSTS_RINTFL);
+		self.regs[0x24 / 4] |= INTSTS_GIS | INTSTS_CIS;
+	}
+
+	fn verb(&mut self, command: u32) -> u32 {
+		let (codec, node, verb, payload) = split_command(command);
+		assert_eq!(codec, 0);
+		self.verbs.push((node, verb, payload));
+		let widget = node.checked_sub(2).and_then(|i| WIDGETS.get(i as usize));
+
+		match (verb, node, payload as u8) {
+			(VERB_GET_PARAMETER, 0, PARAM_VENDOR_ID) => VENDOR_ID,
+			(VERB_GET_PARAMETER, 0, PARAM_REVISION_ID) => 0x0010_0101,
+			(VERB_GET_PARAMETER, 0, PARAM_NODE_COUNT) => 1 << 16 | 1,
+			(VERB_GET_PARAMETER, 1, PARAM_FUNCTION_GROUP) => FUNCTION_GROUP_AUDIO,
+			(VERB_GET_PARAMETER, 1, PARAM_NODE_COUNT) => 2 << 16 | WIDGETS.len() as u32,
+			(VERB_GET_PARAMETER, 1, PARAM_PCM) => PCM,
+			(VERB_GET_PARAMETER, 1, PARAM_IN_AMP_CAPS) => 0,
+			(VERB_GET_PARAMETER, 1, PARAM_OUT_AMP_CAPS) => AMPCAP_MUTE | 0x4A << AMPCAP_STEPS_SHIFT | 0x4A,
+			(VERB_GET_PARAMETER, 6, PARAM_IN_AMP_CAPS) => AMPCAP_MUTE | 0x7F << AMPCAP_STEPS_SHIFT | 0x20,
+			(VERB_GET_PARAMETER, _, PARAM_WIDGET_CAPS) => widget.unwrap().caps,
+			(VERB_GET_PARAMETER, _, PARAM_PIN_CAPS) => widget.unwrap().pin_caps,
+			(VERB_GET_PARAMETER, _, PARAM_CONNECTION_LENGTH) => widget.unwrap().connections.len() as u32,
+			(VERB_GET_PARAMETER, _, param) => panic!("parameter {:#x} of node {}", param, node),
+			(VERB_GET_CONFIG_DEFAULT, _, _) => widget.unwrap().config,
+			(VERB_GET_CONNECTION_LIST, _, offset) => widget.unwrap().connections.iter().skip(offset as usize).take(4)
+				.enumerate().fold(0, |r, (i, c)| r | (*c as u32) << (8 * i)),
+			_ => 0
+		}
+	}
+
+	fn tick(&mut self, bytes: usize) {
+		for i in 0..INPUTS + OUTPUTS {
+			if self.read16(stream_reg(i, 0)) & SDCTL_RUN == 0 {
+				continue;
+			}
+			let length = self.read32(stream_reg(i, 0x08)) as usize;
+			let last = self.read16(stream_reg(i, 0x0C)) as usize;
+			let bdl = self.base(stream_reg(i, 0x18)) as *const BufferDescriptor;
+			let entries = (0..=last).map(|e| unsafe { bdl.add(e).read() }).collect::<Vec<_>>();
+
+			let mut position = self.read32(stream_reg(i, 0x04)) as usize;
+			for _ in 0..bytes {
+				// the entry holding the position
+				let (mut entry, mut offset) = (0, position);
+				while offset >= entries[entry].length as usize {
+					offset -= entries[entry].length as usize;
+					entry += 1;
+				}
+				let byte = (entries[entry].address as usize + offset) as *mut u8;
+				match i < INPUTS {
+					true => {
+						unsafe { byte.write(self.captured) };
+						self.captured = self.captured.wrapping_add(1);
+					}
+					false => self.played.push(unsafe { byte.read() })
+				}
+
+				position = (position + 1) % length;
+				if offset + 1 == entries[entry].length as usize && entries[entry].flags & BDL_IOC != 0 {
+					let status = self.read8(stream_reg(i, 0x03));
+					self.write8(stream_reg(i, 0x03), status | SDSTS_BCIS);
+					self.regs[0x24 / 4] |= INTSTS_GIS | 1 << i;
+				}
+			}
+			self.regs[stream_reg(i, 0x04) / 4] = position as u32;
+		}
+	}
+}
+
+impl Dma for Mock {
+	fn alloc(&mut self, size: usize, align: usize) -> Option<(*mut u8, u64)> {
+		let layout = Layout::from_size_align(size, align).unwrap();
+		let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+		self.0.borrow_mut().allocs.insert(ptr as usize, layout);
+		Some((ptr, ptr as u64))
+	}
+
+	fn free(&mut self, virt: *mut u8, _size: usize) {
+		let layout = self.0.borrow_mut().allocs.remove(&(virt as usize)).expect("double free");
+		unsafe { std::alloc::dealloc(virt, layout) };
+	}
+
+	fn phys(&mut self, virt: *const u8) -> u64 {
+		virt as u64
+	}
+
+	fn stall(&mut self, _us: u64) {
+		self.0.borrow_mut().process();
+	}
+}
+
+fn controller() -> (Mock, Controller<Mock>) {
+	let mock = Mock::new(true);
+	let ctrl = unsafe { Controller::new(mock.regs(), mock.clone()) }.unwrap();
+	(mock, ctrl)
+}
+
+/// Advances the streams by `bytes` a period at a time, handling the interrupts in between
+/// like the driver's interrupt handler would.
+fn run(mock: &Mock, ctrl: &mut Controller<Mock>, bytes: usize, period: usize) {
+	for done in (0..bytes).step_by(period) {
+		mock.tick(period.min(bytes - done));
+		ctrl.interrupt();
+	}
+}
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<StreamDescriptor>(), 0x20);
+	assert_eq!(core::mem::size_of::<Registers>(), 0x80 + 30 * 0x20);
+	assert_eq!(core::mem::size_of::<BufferDescriptor>(), 16);
+
+	let regs = core::mem::MaybeUninit::<Registers>::uninit();
+	let base = regs.as_ptr() as usize;
+	let offset = |f: fn(*const Registers) -> usize| f(regs.as_ptr()) - base;
+	unsafe {
+		assert_eq!(offset(|r| core::ptr::addr_of!((*r).interrupt_control) as usize), 0x20);
+		assert_eq!(offset(|r| core::ptr::addr_of!((*r).corb_base) as usize), 0x40);
+		assert_eq!(offset(|r| core::ptr::addr_of!((*r).corb_size) as usize), 0x4E);
+		assert_eq!(offset(|r| core::ptr::addr_of!((*r).rirb_write_pointer) as usize), 0x58);
+		assert_eq!(offset(|r| core::ptr::addr_of!((*r).immediate_status) as usize), 0x68);
+		assert_eq!(offset(|r| core::ptr::addr_of!((*r).position_base) as usize), 0x70);
+		assert_eq!(offset(|r| core::ptr::addr_of!((*r).streams[1].bdl_base) as usize), 0xB8);
+	}
+}
+
+#[test]
+fn commands_and_formats() {
+	assert_eq!(command(2, 0x14, VERB_SET_PIN_CONTROL, 0x40), 0x2147_0740);
+	assert_eq!(command(0, 3, VERB_SET_AMP_GAIN_MUTE, 0xB04A), 0x0033_B04A);
+	assert_eq!(split_command(0x2147_0740), (2, 0x14, VERB_SET_PIN_CONTROL, 0x40));
+	assert_eq!(split_command(0x0033_B04A), (0, 3, VERB_SET_AMP_GAIN_MUTE, 0xB04A));
+	assert_eq!(split_command(command(1, 1, VERB_GET_PARAMETER, 0x0A)), (1, 1, VERB_GET_PARAMETER, 0x0A));
+
+	let format = |rate, bits, channels| Format { rate, bits, channels }.encode();
+	assert_eq!(Format::DEFAULT.encode(), Some(0x0011));
+	assert_eq!(format(44100, 16, 2), Some(0x4011));
+	assert_eq!(format(96000, 24, 2), Some(0x0831));
+	assert_eq!(format(8000, 16, 1), Some(0x0510));
+	assert_eq!(format(22050, 32, 8), Some(0x4147));
+	assert_eq!(format(12345, 16, 2), None);
+	assert_eq!(format(48000, 12, 2), None);
+	assert_eq!(format(48000, 16, 0), None);
+	assert_eq!(Format::DEFAULT.frame_size(), 4);
+	assert_eq!(Format { rate: 48000, bits: 24, channels: 6 }.frame_size(), 24);
+	assert!(Format::DEFAULT.supported_by(PCM));
+	assert!(!Format { rate: 96000, ..Format::DEFAULT }.supported_by(PCM));
+	assert!(!Format { bits: 24, ..Format::DEFAULT }.supported_by(PCM));
+}
+
+#[test]
+fn enumerate() {
+	let (mock, ctrl) = controller();
+	assert_eq!(ctrl.codecs().len(), 1);
+	let codec = &ctrl.codecs()[0];
+	assert_eq!((codec.address, codec.vendor_id, codec.afg), (0, VENDOR_ID, 1));
+	assert_eq!(codec.widgets.len(), 7);
+	assert_eq!(codec.widget(2).unwrap().kind(), WidgetType::Output);
+	assert_eq!(codec.widget(3).unwrap().kind(), WidgetType::Mixer);
+	assert_eq!(codec.widget(6).unwrap().kind(), WidgetType::Input);
+	assert_eq!(codec.widget(6).unwrap().connections, [7, 8]);
+	assert_eq!(codec.widget(6).unwrap().in_amp & AMPCAP_OFFSET_MASK, 0x20);
+	assert_eq!(codec.widget(2).unwrap().out_amp & AMPCAP_OFFSET_MASK, 0x4A);
+	assert_eq!(codec.widget(2).unwrap().pcm, PCM);
+	assert_eq!(codec.widget(4).unwrap().device(), DEVICE_HEADPHONE);
+	assert!(!codec.widget(5).unwrap().is_connected());
+
+	// the line out pin isn't connected, the headphones are behind the mixer
+	let output = codec.output.as_ref().unwrap();
+	assert_eq!(output.widgets, [2, 3, 4]);
+	assert_eq!((output.converter(), output.pin()), (2, 4));
+	let input = codec.input.as_ref().unwrap();
+	assert_eq!(input.widgets, [8, 6]);
+	assert_eq!((input.converter(), input.pin()), (6, 8));
+
+	let state = mock.0.borrow();
+	assert!(state.verbs.contains(&(1, VERB_SET_POWER_STATE, POWER_D0)));
+	assert_eq!(state.read32(0x20), INTCTL_GIE | INTCTL_CIE);
+	assert_ne!(state.read32(0x08) & GCTL_UNSOL, 0);
+}
+
+#[test]
+fn no_codec() {
+	let mock = Mock::new(false);
+	assert_eq!(unsafe { Controller::new(mock.regs(), mock.clone()) }.err(), Some(Error::NoCodec));
+	assert!(mock.0.borrow().allocs.is_empty());
+}
+
+#[test]
+fn paths() {
+	let (mock, mut ctrl) = controller();
+	mock.0.borrow_mut().verbs.clear();
+	let output = ctrl.open(0, Direction::Output, Format::DEFAULT).unwrap();
+	let input = ctrl.open(0, Direction::Input, Format { rate: 44100, ..Format::DEFAULT }).unwrap();
+	assert_eq!((output, input), (INPUTS, 0));
+
+	let verbs = mock.0.borrow().verbs.clone();
+	let amp = AMP_SET_LEFT | AMP_SET_RIGHT;
+	for verb in [
+		(2, VERB_SET_AMP_GAIN_MUTE, AMP_SET_OUTPUT | amp | 0x4A),
+		(3, VERB_SET_AMP_GAIN_MUTE, AMP_SET_INPUT | amp),
+		(4, VERB_SET_AMP_GAIN_MUTE, AMP_SET_OUTPUT | amp | 0x4A),
+		(4, VERB_SET_PIN_CONTROL, PINCTL_OUT_ENABLE | PINCTL_HP_ENABLE),
+		(4, VERB_SET_EAPD_BTL, EAPD_BTL_EAPD),
+		(2, VERB_SET_CONVERTER_FORMAT, 0x0011),
+		(2, VERB_SET_STREAM_CHANNEL, 1 << 4),
+		(8, VERB_SET_AMP_GAIN_MUTE, AMP_SET_INPUT | amp),
+		(8, VERB_SET_PIN_CONTROL, PINCTL_IN_ENABLE),
+		(6, VERB_SET_CONNECTION_SELECT, 1),
+		(6, VERB_SET_AMP_GAIN_MUTE, AMP_SET_INPUT | amp | 1 << AMP_SET_INDEX_SHIFT | 0x20),
+		(6, VERB_SET_CONVERTER_FORMAT, 0x4011),
+		(6, VERB_SET_STREAM_CHANNEL, 1 << 4)
+	] {
+		assert!(verbs.contains(&verb), "{:x?} not sent", verb);
+	}
+	// the mixer takes all inputs at once
+	assert!(!verbs.iter().any(|(node, verb, _)| (*node, *verb) == (3, VERB_SET_CONNECTION_SELECT)));
+
+	let mut state = mock.0.borrow_mut();
+	assert_eq!(state.read8(stream_reg(INPUTS, 0x02)), 1 << SDCTL2_STRM_SHIFT);
+	assert_eq!(state.read16(stream_reg(INPUTS, 0x12)), 0x0011);
+	assert_eq!(state.read16(stream_reg(INPUTS, 0x0C)), PERIODS as u16 - 1);
+	assert_eq!(state.read16(stream_reg(INPUTS, 0)) & SDCTL_RUN, SDCTL_RUN);
+	assert_eq!(state.read32(0x20) & (1 << INPUTS | 1), 1 << INPUTS | 1);
+}
+
+#[test]
+fn playback() {
+	let (mock, mut ctrl) = controller();
+	let stream = ctrl.open(0, Direction::Output, Format::DEFAULT).unwrap();
+	let period = ctrl.period(stream).unwrap();
+	// 20 ms of 48 kHz stereo
+	assert_eq!(period, 3840);
+	let size = PERIODS * period;
+	let guard = FIFO as usize + 1;
+
+	let data = (0..2 * size).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+	assert_eq!(ctrl.available(stream).unwrap(), size - guard);
+	let written = ctrl.write(stream, &data).unwrap();
+	assert_eq!(written, size - guard);
+	assert_eq!(ctrl.write(stream, &data[written..]).unwrap(), 0);
+	assert_eq!(ctrl.queued(stream).unwrap(), written);
+
+	// whole frames only
+	mock.tick(period);
+	assert_eq!(ctrl.available(stream).unwrap(), period);
+	assert_eq!(ctrl.write(stream, &data[written..written + 6]).unwrap(), 4);
+	let written = written + 4;
+	assert_eq!(ctrl.write(stream, &data[written..written + period]).unwrap(), period - 4);
+	let written = written + period - 4;
+
+	// everything is played after the silence before the first write, then silence again
+	run(&mock, &mut ctrl, 2 * size, period);
+	let played = mock.0.borrow().played.clone();
+	assert!(played[..guard].iter().all(|b| *b == 0));
+	assert_eq!(&played[guard..guard + written], &data[..written]);
+	assert!(played[guard + written..].iter().all(|b| *b == 0));
+
+	// after the underrun writes start ahead of the link position again
+	assert_eq!(ctrl.queued(stream).unwrap(), 0);
+	assert_eq!(ctrl.write(stream, &data[..period]).unwrap(), period);
+	mock.0.borrow_mut().played.clear();
+	mock.tick(guard + period);
+	let played = mock.0.borrow().played.clone();
+	assert!(played[..guard].iter().all(|b| *b == 0));
+	assert_eq!(&played[guard..], &data[..period]);
+
+	ctrl.close(stream).unwrap();
+	assert!(ctrl.period(stream).is_none());
+	let mut state = mock.0.borrow_mut();
+	assert_eq!(state.read16(stream_reg(stream, 0)) & SDCTL_RUN, 0);
+	assert_eq!(state.verbs.last(), Some(&(2, VERB_SET_STREAM_CHANNEL, 0)));
+}
+
+#[test]
+fn capture() {
+	let (mock, mut ctrl) = controller();
+	let stream = ctrl.open(0, Direction::Input, Format::DEFAULT).unwrap();
+	let period = ctrl.period(stream).unwrap();
+	let size = PERIODS * period;
+
+	let mut buf = vec![0; 2 * size];
+	assert_eq!(ctrl.read(stream, &mut buf).unwrap(), 0);
+	mock.tick(1002);
+	assert_eq!(ctrl.available(stream).unwrap(), 1002);
+	assert_eq!(ctrl.read(stream, &mut buf).unwrap(), 1000);
+	assert!(buf[..1000].iter().enumerate().all(|(i, b)| *b == i as u8));
+	assert_eq!(ctrl.read(stream, &mut buf).unwrap(), 0);
+
+	// the oldest period is dropped when the buffer overflows
+	run(&mock, &mut ctrl, size, period);
+	let len = ctrl.read(stream, &mut buf).unwrap();
+	assert_eq!(len, size - period);
+	let first = 1002 + size - len;
+	assert!(buf[..len].iter().enumerate().all(|(i, b)| *b == (first + i) as u8));
+	assert_eq!(ctrl.write(stream, &buf[..4]), Err(Error::InvalidArgument));
+}
+
+#[test]
+fn errors() {
+	let (mock, mut ctrl) = controller();
+	assert_eq!(ctrl.open(1, Direction::Output, Format::DEFAULT), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.open(0, Direction::Output, Format { rate: 96000, ..Format::DEFAULT }), Err(Error::Unsupported));
+	assert_eq!(ctrl.open(0, Direction::Output, Format { bits: 12, ..Format::DEFAULT }), Err(Error::Unsupported));
+	let stream = ctrl.open(0, Direction::Output, Format::DEFAULT).unwrap();
+	assert_eq!(ctrl.open(0, Direction::Output, Format::DEFAULT), Err(Error::Busy));
+	assert_eq!(ctrl.read(stream, &mut [0; 4]), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.close(stream + 1), Err(Error::InvalidArgument));
+	ctrl.close(stream).unwrap();
+	assert_eq!(ctrl.close(stream), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.open(0, Direction::Output, Format::DEFAULT), Ok(stream));
+
+	drop(ctrl);
+	assert!(mock.0.borrow().allocs.is_empty());
+}
+
+#[test]
+fn interrupts() {
+	let (mock, mut ctrl) = controller();
+	let output = ctrl.open(0, Direction::Output, Format::DEFAULT).unwrap();
+	let input = ctrl.open(0, Direction::Input, Format::DEFAULT).unwrap();
+	let period = ctrl.period(output).unwrap();
+
+	mock.tick(period - 4);
+	mock.0.borrow_mut().regs[0x24 / 4] = 0;
+	assert_eq!(ctrl.interrupt(), 0);
+	mock.tick(4);
+	assert_eq!(ctrl.interrupt(), 1 << output | 1 << input);
+	assert_eq!(ctrl.available(input).unwrap(), period);
+
+	mock.unsolicited(0x0400_0000);
+	ctrl.interrupt();
+	assert_eq!(ctrl.unsolicited().collect::<Vec<_>>(), [(0, 0x0400_0000)]);
+	assert_eq!(ctrl.unsolicited().count(), 0);
+}
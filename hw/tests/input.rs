@@ -0,0 +1,133 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use hw::input::*;
+
+fn chars(keyboard: &mut Keyboard, keys: &[(u16, bool)]) -> String {
+	keys.iter().filter_map(|&(code, pressed)| match keyboard.key(code, pressed) {
+		Event::Key { char, .. } => char,
+		_ => None
+	}).collect()
+}
+
+fn press(codes: &[u16]) -> Vec<(u16, bool)> {
+	codes.iter().flat_map(|&c| [(c, true), (c, false)]).collect()
+}
+
+#[test]
+fn layouts() {
+	assert_eq!(Layout::from_name("de"), Some(Layout::De));
+	assert_eq!(Layout::from_name("fr"), None);
+	assert_eq!(Layout::default().name(), "us");
+
+	// y, z, 2, -, the key next to the left shift
+	let keys = [21, 44, 3, 12, KEY_102ND];
+	let typed = |layout: Layout, modifiers| keys.iter().map(|&k| layout.char(k, modifiers).unwrap()).collect::<String>();
+	assert_eq!(typed(Layout::Us, 0), "yz2-\\");
+	assert_eq!(typed(Layout::Us, MOD_LEFT_SHIFT), "YZ@_|");
+	assert_eq!(typed(Layout::De, 0), "zy2ß<");
+	assert_eq!(typed(Layout::De, MOD_RIGHT_SHIFT), "ZY\"?>");
+	assert_eq!(typed(Layout::De, MOD_ALTGR), "zy²\\|");
+	assert_eq!(Layout::De.char(39, MOD_SHIFT), Some('Ö'));
+	assert_eq!(Layout::De.char(16, MOD_ALTGR), Some('@'));
+
+	assert_eq!(Layout::Us.char(KEY_A, MOD_CAPS_LOCK), Some('A'));
+	assert_eq!(Layout::Us.char(KEY_A, MOD_CAPS_LOCK | MOD_LEFT_SHIFT), Some('a'));
+	assert_eq!(Layout::Us.char(2, MOD_CAPS_LOCK), Some('1'));
+	assert_eq!(Layout::De.char(40, MOD_CAPS_LOCK), Some('Ä'));
+	assert_eq!(Layout::Us.char(46, MOD_LEFT_CTRL), Some('\x03'));
+	assert_eq!(Layout::Us.char(KEY_ENTER, 0), Some('\n'));
+	assert_eq!(Layout::De.char(79, 0), Some('1'));
+	assert_eq!(Layout::Us.char(KEY_UP, 0), None);
+}
+
+#[test]
+fn keyboard() {
+	let mut kb = Keyboard::new(Layout::Us);
+	assert_eq!(kb.key(KEY_LEFTSHIFT, true), Event::Key { code: KEY_LEFTSHIFT, pressed: true, char: None, modifiers: MOD_LEFT_SHIFT });
+	assert_eq!(kb.key(KEY_A, true), Event::Key { code: KEY_A, pressed: true, char: Some('A'), modifiers: MOD_LEFT_SHIFT });
+	// held down, the key repeats
+	assert_eq!(chars(&mut kb, &[(KEY_A, true), (KEY_A, false)]), "A");
+	kb.key(KEY_LEFTSHIFT, false);
+	assert_eq!(kb.modifiers(), 0);
+
+	// both shift keys, released one after the other
+	let mut keys = vec![(KEY_LEFTSHIFT, true), (KEY_RIGHTSHIFT, true), (KEY_LEFTSHIFT, false)];
+	keys.extend(press(&[KEY_A]));
+	keys.push((KEY_RIGHTSHIFT, false));
+	keys.extend(press(&[KEY_A]));
+	assert_eq!(chars(&mut kb, &keys), "Aa");
+
+	// caps lock toggles on presses
+	assert_eq!(chars(&mut kb, &press(&[KEY_CAPSLOCK, KEY_A, 2, KEY_CAPSLOCK, KEY_A])), "A1a");
+
+	kb.set_layout(Layout::De);
+	assert_eq!(kb.layout(), Layout::De);
+	let mut keys = vec![(KEY_RIGHTALT, true)];
+	keys.extend(press(&[16, 8, 11]));
+	keys.push((KEY_RIGHTALT, false));
+	keys.extend(press(&[21, KEY_SPACE]));
+	assert_eq!(chars(&mut kb, &keys), "@{}z ");
+	assert_eq!(kb.modifiers(), 0);
+}
+
+#[test]
+fn records() {
+	let events = [
+		Event::Key { code: 40, pressed: true, char: Some('ä'), modifiers: MOD_CAPS_LOCK },
+		Event::Key { code: KEY_UP, pressed: false, char: None, modifiers: 0 },
+		Event::Button { button: BTN_RIGHT, pressed: true },
+		Event::Motion { dx: -5, dy: 7 },
+		Event::Position { x: POSITION_MAX, y: 0 },
+		Event::Wheel { dx: 0, dy: -1 }
+	];
+	for e in events {
+		let bytes = e.to_bytes();
+		assert_eq!(bytes.len(), RECORD_LEN);
+		assert_eq!(Event::from_bytes(&bytes), Some(e));
+	}
+	assert_eq!(&Event::Motion { dx: -1, dy: 2 }.to_bytes(), &[3, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 2, 0, 0, 0, 0, 0, 0, 0]);
+	assert_eq!(Event::from_bytes(&[0; RECORD_LEN]), None);
+	assert_eq!(Event::from_bytes(&[1; RECORD_LEN - 1]), None);
+}
+
+#[test]
+fn hid_usages() {
+	assert_eq!(from_hid_usage(0x04), Some(KEY_A));
+	assert_eq!(from_hid_usage(0x1D), Some(44));
+	assert_eq!(from_hid_usage(0x27), Some(11));
+	assert_eq!(from_hid_usage(0x28), Some(KEY_ENTER));
+	assert_eq!(from_hid_usage(0x52), Some(KEY_UP));
+	assert_eq!(from_hid_usage(0x64), Some(KEY_102ND));
+	assert_eq!(from_hid_usage(0x65), Some(127));
+	assert_eq!(from_hid_usage(0xE1), Some(KEY_LEFTSHIFT));
+	assert_eq!(from_hid_usage(0xE6), Some(KEY_RIGHTALT));
+	assert_eq!((from_hid_usage(0x03), from_hid_usage(0x66), from_hid_usage(0xE8)), (None, None, None));
+
+	// a German USB keyboard types through the same layout
+	let mut kb = Keyboard::new(Layout::De);
+	let keys = [0x1C, 0x1D, 0x32, 0x33].iter().flat_map(|&u| {
+		let code = from_hid_usage(u).unwrap();
+		[(code, true), (code, false)]
+	}).collect::<Vec<_>>();
+	assert_eq!(chars(&mut kb, &keys), "zy#ö");
+}
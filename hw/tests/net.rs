@@ -0,0 +1,499 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+use hw::net::{*, ethernet::Header as EthernetHeader};
+
+/// Two ports connected by a wire, frames sent on one are received on the other.
+#[derive(Default)]
+struct Wire {
+	queues:  [VecDeque<Vec<u8>>; 2],
+	/// Frames sent by each port
+	log:     [Vec<Vec<u8>>; 2],
+	/// Frames of each port to lose, or to corrupt the last byte of
+	drop:    [usize; 2],
+	corrupt: [usize; 2]
+}
+
+struct Port {
+	wire:    Rc<RefCell<Wire>>,
+	side:    usize,
+	mac:     Mac,
+	/// The port completes checksums like a device with offloading
+	offload: bool
+}
+
+impl Device for Port {
+	fn mac(&self) -> Mac {
+		self.mac
+	}
+
+	fn checksum_offload(&self) -> bool {
+		self.offload
+	}
+
+	fn transmit(&mut self, frame: &[u8], checksum: Option<Checksum>) -> Result<()> {
+		let mut frame = frame.to_vec();
+		if let Some(c) = checksum {
+			assert!(self.offload, "checksum offload requested from a port without it");
+			let (start, offset) = (c.start as usize, c.start as usize + c.offset as usize);
+			let sum = hw::net::checksum(&frame[start..]);
+			frame[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+		}
+
+		let mut wire = self.wire.borrow_mut();
+		wire.log[self.side].push(frame.clone());
+		if wire.drop[self.side] > 0 {
+			wire.drop[self.side] -= 1;
+			return Ok(());
+		}
+		if wire.corrupt[self.side] > 0 {
+			wire.corrupt[self.side] -= 1;
+			*frame.last_mut().unwrap() ^= 0xFF;
+		}
+		wire.queues[1 - self.side].push_back(frame);
+		Ok(())
+	}
+
+	fn receive(&mut self) -> Option<Frame> {
+		let data = self.wire.borrow_mut().queues[self.side].pop_front()?;
+		Some(Frame { data, checksum_valid: false })
+	}
+}
+
+const A: [u8; 4] = [10, 0, 0, 1];
+const B: [u8; 4] = [10, 0, 0, 2];
+
+fn pair(offload: bool) -> (Rc<RefCell<Wire>>, Interface<Port>, Interface<Port>) {
+	let wire = Rc::new(RefCell::new(Wire::default()));
+	let port = |side: usize| Port { wire: wire.clone(), side, mac: [2, 0, 0, 0, 0, side as u8 + 1], offload };
+	let mut a = Interface::new(port(0), 1);
+	let mut b = Interface::new(port(1), 2);
+	a.set_ipv4(Some(Ipv4Config { addr: A, prefix: 24, gateway: None }));
+	b.set_ipv4(Some(Ipv4Config { addr: B, prefix: 24, gateway: None }));
+	(wire, a, b)
+}
+
+/// Polls both interfaces until no frames are in flight.
+fn run(a: &mut Interface<Port>, b: &mut Interface<Port>, now: u64) {
+	for _ in 0..1000 {
+		if !a.poll(now) & !b.poll(now) {
+			return;
+		}
+	}
+	panic!("interfaces didn't settle");
+}
+
+fn ep(addr: [u8; 4], port: u16) -> Endpoint {
+	Endpoint::new(IpAddr::V4(addr), port)
+}
+
+#[test]
+fn addresses() {
+	assert_eq!(IpAddr::parse("10.0.0.1"), Some(IpAddr::V4(A)));
+	assert_eq!(IpAddr::parse("10.0.0.256"), None);
+	let v6 = IpAddr::parse("fe80::1:2").unwrap();
+	assert_eq!(v6, IpAddr::V6([0xFE, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 2]));
+	assert_eq!(v6.to_string(), "fe80::1:2");
+	assert_eq!(IpAddr::parse("::").unwrap().to_string(), "::");
+	assert_eq!(IpAddr::parse("1:2:3:4:5:6:7:8").unwrap().to_string(), "1:2:3:4:5:6:7:8");
+	assert_eq!(Endpoint::parse("[fe80::1:2]:80"), Some(Endpoint::new(v6, 80)));
+	assert_eq!(Endpoint::parse("10.0.0.2:7").unwrap().to_string(), "10.0.0.2:7");
+	assert_eq!(Endpoint::parse("10.0.0.2"), None);
+	assert_eq!(ipv4::prefix([255, 255, 240, 0]), Some(20));
+	assert_eq!(ipv4::broadcast(A, 24), [10, 0, 0, 255]);
+}
+
+fn udp(offload: bool) {
+	let (wire, mut a, mut b) = pair(offload);
+	let server = b.udp_bind(ep([0; 4], 7), None).unwrap();
+	assert_eq!(b.udp_bind(ep([0; 4], 7), None).unwrap_err(), Error::AddressInUse);
+	let client = a.udp_bind(ep([0; 4], 0), Some(ep(B, 7))).unwrap();
+	let (local, _) = a.endpoints(client).unwrap();
+	assert!(local.port >= 49152);
+	let local = ep(A, local.port);
+
+	// the datagram waits for the address of the peer
+	a.udp_send(client, None, b"ping").unwrap();
+	let request = wire.borrow().log[0][0].clone();
+	let (eth, payload) = EthernetHeader::parse(&request).unwrap();
+	assert_eq!((eth.dst, eth.ty), (BROADCAST, ethernet::TYPE_ARP));
+	assert_eq!(arp::Packet::parse(payload).unwrap().target_ip, B);
+
+	run(&mut a, &mut b, 10);
+	let (src, data) = b.udp_recv(server).unwrap();
+	assert_eq!((src, &data[..]), (local, &b"ping"[..]));
+	assert_eq!(b.udp_recv(server).unwrap_err(), Error::WouldBlock);
+
+	b.udp_send(server, Some(src), b"pong").unwrap();
+	run(&mut a, &mut b, 20);
+	assert_eq!(a.udp_recv(client).unwrap(), (ep(B, 7), b"pong".to_vec()));
+
+	// a connected socket only receives from its peer
+	let other = b.udp_bind(ep([0; 4], 8), None).unwrap();
+	b.udp_send(other, Some(local), b"spam").unwrap();
+	run(&mut a, &mut b, 30);
+	assert_eq!(a.udp_recv(client).unwrap_err(), Error::WouldBlock);
+
+	// corrupted datagrams are dropped
+	wire.borrow_mut().corrupt[1] = 1;
+	b.udp_send(server, Some(local), b"pong").unwrap();
+	run(&mut a, &mut b, 40);
+	assert_eq!(a.udp_recv(client).unwrap_err(), Error::WouldBlock);
+
+	assert_eq!(a.udp_send(client, Some(ep([10, 0, 1, 1], 7)), b"x").unwrap_err(), Error::NoRoute);
+	assert_eq!(a.udp_send(client, None, &[0; 1500]).unwrap_err(), Error::InvalidArgument);
+	a.close(client).unwrap();
+	assert_eq!(a.udp_recv(client).unwrap_err(), Error::InvalidArgument);
+}
+
+#[test]
+fn udp_software_checksum() {
+	udp(false);
+}
+
+#[test]
+fn udp_offload() {
+	udp(true);
+}
+
+/// Connects `a` to a listener on `b`, returns both ends.
+fn connect(a: &mut Interface<Port>, b: &mut Interface<Port>, now: u64) -> (Handle, Handle, Handle) {
+	let listener = b.tcp_listen(ep([0; 4], 80), 4).unwrap();
+	let client = a.tcp_connect(ep(B, 80)).unwrap();
+	assert_eq!(a.tcp_state(client).unwrap(), tcp::State::SynSent);
+	assert_eq!(b.tcp_accept(listener).unwrap_err(), Error::WouldBlock);
+	run(a, b, now);
+	assert_eq!(a.tcp_state(client).unwrap(), tcp::State::Established);
+	let server = b.tcp_accept(listener).unwrap();
+	assert_eq!(b.tcp_state(server).unwrap(), tcp::State::Established);
+	(listener, client, server)
+}
+
+#[test]
+fn tcp_transfer() {
+	let (_, mut a, mut b) = pair(true);
+	let (_, client, server) = connect(&mut a, &mut b, 0);
+	assert_eq!(b.endpoints(server).unwrap().1, Some(a.endpoints(client).unwrap().0));
+
+	// more than fits into the buffers at once
+	let data = (0..300_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+	let (mut sent, mut received) = (0, Vec::new());
+	let mut buf = vec![0; 4096];
+	for now in 1.. {
+		if sent < data.len() {
+			sent += a.tcp_send(client, &data[sent..]).unwrap();
+		}
+		run(&mut a, &mut b, now);
+		while let Ok(n @ 1..) = b.tcp_recv(server, &mut buf) {
+			received.extend_from_slice(&buf[..n]);
+		}
+		if received.len() == data.len() {
+			break;
+		}
+		assert!(now < 1000, "transfer stalled at {} bytes", received.len());
+	}
+	assert!(received == data);
+
+	// a closes first and waits, b sees the end of the stream
+	a.tcp_shutdown(client).unwrap();
+	run(&mut a, &mut b, 2000);
+	assert!(b.tcp_can_recv(server).unwrap());
+	assert_eq!(b.tcp_recv(server, &mut buf).unwrap(), 0);
+	assert_eq!(b.tcp_state(server).unwrap(), tcp::State::CloseWait);
+	b.tcp_send(server, b"bye").unwrap();
+	b.close(server).unwrap();
+	run(&mut a, &mut b, 2001);
+	assert_eq!(a.tcp_recv(client, &mut buf).unwrap(), 3);
+	assert_eq!(a.tcp_recv(client, &mut buf).unwrap(), 0);
+	assert_eq!(a.tcp_state(client).unwrap(), tcp::State::TimeWait);
+	run(&mut a, &mut b, 2001 + tcp::TIME_WAIT_MS);
+	assert_eq!(a.tcp_state(client).unwrap(), tcp::State::Closed);
+	assert_eq!(a.tcp_error(client).unwrap(), None);
+}
+
+#[test]
+fn tcp_retransmit() {
+	let (wire, mut a, mut b) = pair(false);
+	let (_, client, server) = connect(&mut a, &mut b, 0);
+	let mut buf = [0; 16];
+
+	wire.borrow_mut().drop[0] = 1;
+	assert_eq!(a.tcp_send(client, b"lost").unwrap(), 4);
+	run(&mut a, &mut b, 10);
+	assert!(!b.tcp_can_recv(server).unwrap());
+	run(&mut a, &mut b, 10 + tcp::RTO_INITIAL_MS);
+	assert_eq!(b.tcp_recv(server, &mut buf).unwrap(), 4);
+	assert_eq!(&buf[..4], b"lost");
+
+	// lost acknowledgements make the data arrive twice, it is only received once
+	wire.borrow_mut().drop[1] = 1;
+	a.tcp_send(client, b"again").unwrap();
+	run(&mut a, &mut b, 2000);
+	run(&mut a, &mut b, 2000 + tcp::RTO_INITIAL_MS);
+	assert_eq!(b.tcp_recv(server, &mut buf).unwrap(), 5);
+	assert_eq!(b.tcp_recv(server, &mut buf).unwrap_err(), Error::WouldBlock);
+
+	// the connection times out once the peer is gone
+	wire.borrow_mut().drop[0] = usize::MAX;
+	a.tcp_send(client, b"void").unwrap();
+	let mut now = 4000;
+	while a.tcp_state(client).unwrap() != tcp::State::Closed {
+		now += 1000;
+		run(&mut a, &mut b, now);
+		assert!(now < 1_000_000, "connection didn't time out");
+	}
+	assert_eq!(a.tcp_error(client).unwrap(), Some(Error::Timeout));
+}
+
+#[test]
+fn tcp_refused_and_reset() {
+	let (_, mut a, mut b) = pair(false);
+	let client = a.tcp_connect(ep(B, 81)).unwrap();
+	run(&mut a, &mut b, 0);
+	assert_eq!(a.tcp_state(client).unwrap(), tcp::State::Closed);
+	assert_eq!(a.tcp_error(client).unwrap(), Some(Error::ConnectionRefused));
+	assert_eq!(a.tcp_send(client, b"x").unwrap_err(), Error::ConnectionRefused);
+
+	let (_, client, server) = connect(&mut a, &mut b, 10);
+	b.tcp_abort(server).unwrap();
+	run(&mut a, &mut b, 20);
+	assert_eq!(a.tcp_state(client).unwrap(), tcp::State::Closed);
+	assert_eq!(a.tcp_error(client).unwrap(), Some(Error::ConnectionReset));
+}
+
+#[test]
+fn tcp_backlog() {
+	let (_, mut a, mut b) = pair(false);
+	let listener = b.tcp_listen(ep(B, 80), 1).unwrap();
+	assert_eq!(b.tcp_listen(ep([0; 4], 80), 1).unwrap_err(), Error::AddressInUse);
+	let first = a.tcp_connect(ep(B, 80)).unwrap();
+	let second = a.tcp_connect(ep(B, 80)).unwrap();
+	run(&mut a, &mut b, 0);
+	assert_eq!(a.tcp_state(first).unwrap(), tcp::State::Established);
+	// the SYN of the second is ignored while the backlog is full
+	assert_eq!(a.tcp_state(second).unwrap(), tcp::State::SynSent);
+
+	let accepted = b.tcp_accept(listener).unwrap();
+	assert_eq!(b.tcp_accept(listener).unwrap_err(), Error::WouldBlock);
+	run(&mut a, &mut b, 1 + tcp::RTO_INITIAL_MS);
+	assert_eq!(a.tcp_state(second).unwrap(), tcp::State::Established);
+	let next = b.tcp_accept(listener).unwrap();
+	assert_ne!(next, accepted);
+
+	// closing the listener resets what wasn't accepted
+	let third = a.tcp_connect(ep(B, 80)).unwrap();
+	run(&mut a, &mut b, 2000);
+	assert_eq!(a.tcp_state(third).unwrap(), tcp::State::Established);
+	b.close(listener).unwrap();
+	run(&mut a, &mut b, 2001);
+	assert_eq!(a.tcp_error(third).unwrap(), Some(Error::ConnectionReset));
+	assert_eq!(a.tcp_state(first).unwrap(), tcp::State::Established);
+}
+
+#[test]
+fn ping() {
+	let (_, mut a, mut b) = pair(false);
+	let v4 = a.icmp_open(IpAddr::V4(B)).unwrap();
+	assert_eq!(a.icmp_send(v4, b"abc").unwrap(), 0);
+	assert_eq!(a.icmp_send(v4, b"def").unwrap(), 1);
+	run(&mut a, &mut b, 0);
+	assert_eq!(a.icmp_recv(v4).unwrap(), (0, b"abc".to_vec()));
+	assert_eq!(a.icmp_recv(v4).unwrap(), (1, b"def".to_vec()));
+	assert_eq!(a.icmp_recv(v4).unwrap_err(), Error::WouldBlock);
+
+	// link-local addresses, resolved with neighbor discovery
+	let target = b.ipv6()[0];
+	assert_eq!(target, ipv6::link_local(b.mac()));
+	let v6 = a.icmp_open(IpAddr::V6(target)).unwrap();
+	a.icmp_send(v6, b"ghi").unwrap();
+	run(&mut a, &mut b, 10);
+	assert_eq!(a.icmp_recv(v6).unwrap(), (0, b"ghi".to_vec()));
+
+	// an address nobody has is given up on
+	let nobody = a.icmp_open(IpAddr::V4([10, 0, 0, 3])).unwrap();
+	a.icmp_send(nobody, b"?").unwrap();
+	for i in 0..=RESOLVE_TRIES as u64 {
+		run(&mut a, &mut b, 100 + i * RESOLVE_INTERVAL_MS);
+	}
+	assert_eq!(a.icmp_recv(nobody).unwrap_err(), Error::WouldBlock);
+}
+
+#[test]
+fn tcp_v6() {
+	let (_, mut a, mut b) = pair(true);
+	let listener = b.tcp_listen(Endpoint::new(IpAddr::UNSPECIFIED_V6, 22), 1).unwrap();
+	let client = a.tcp_connect(Endpoint::new(IpAddr::V6(b.ipv6()[0]), 22)).unwrap();
+	run(&mut a, &mut b, 0);
+	let server = b.tcp_accept(listener).unwrap();
+	a.tcp_send(client, b"hello").unwrap();
+	run(&mut a, &mut b, 1);
+	let mut buf = [0; 8];
+	assert_eq!(b.tcp_recv(server, &mut buf).unwrap(), 5);
+	assert_eq!(b.endpoints(server).unwrap().1.unwrap().addr, IpAddr::V6(a.ipv6()[0]));
+}
+
+/// The frames `a` sent since the last call, without the Ethernet header.
+fn sent(wire: &Rc<RefCell<Wire>>, ty: u16) -> Vec<Vec<u8>> {
+	let mut wire = wire.borrow_mut();
+	wire.queues[1].clear();
+	std::mem::take(&mut wire.log[0]).iter()
+		.filter_map(|f| EthernetHeader::parse(f).filter(|(h, _)| h.ty == ty).map(|(_, p)| p.to_vec()))
+		.collect()
+}
+
+fn inject(wire: &Rc<RefCell<Wire>>, ty: u16, packet: &[u8]) {
+	let mut frame = Vec::new();
+	EthernetHeader { dst: [2, 0, 0, 0, 0, 1], src: [2, 0, 0, 0, 0, 0x99], ty }.write(&mut frame);
+	frame.extend_from_slice(packet);
+	wire.borrow_mut().queues[0].push_back(frame);
+}
+
+/// The DHCP messages `a` sent.
+fn dhcp_sent(wire: &Rc<RefCell<Wire>>) -> Vec<dhcp::Message> {
+	sent(wire, ethernet::TYPE_IPV4).iter().filter_map(|p| {
+		let (ip, datagram) = ipv4::Header::parse(p)?;
+		let (h, data) = udp::Header::parse(datagram, IpAddr::V4(ip.src), IpAddr::V4(ip.dst), false)?;
+		assert_eq!((h.src_port, h.dst_port), (dhcp::CLIENT_PORT, dhcp::SERVER_PORT));
+		dhcp::Message::parse(data)
+	}).collect()
+}
+
+fn dhcp_reply(wire: &Rc<RefCell<Wire>>, msg: dhcp::Message) {
+	let (src, dst) = ([10, 0, 2, 2], [255; 4]);
+	let mut datagram = Vec::new();
+	let data = msg.to_bytes();
+	udp::Header { src_port: dhcp::SERVER_PORT, dst_port: dhcp::CLIENT_PORT, len: data.len() as u16 }
+		.write(&mut datagram, &data, IpAddr::V4(src), IpAddr::V4(dst), false);
+	let mut packet = Vec::new();
+	ipv4::Header { src, dst, protocol: ipv4::PROTOCOL_UDP, ttl: 64, id: 1, len: datagram.len() as u16 }.write(&mut packet);
+	packet.extend_from_slice(&datagram);
+	inject(wire, ethernet::TYPE_IPV4, &packet);
+}
+
+#[test]
+fn dhcp() {
+	let wire = Rc::new(RefCell::new(Wire::default()));
+	let mut a = Interface::new(Port { wire: wire.clone(), side: 0, mac: [2, 0, 0, 0, 0, 1], offload: false }, 0);
+	a.start_dhcp();
+	a.poll(0);
+	let discover = dhcp_sent(&wire);
+	assert_eq!(discover.len(), 1);
+	assert_eq!((discover[0].ty, discover[0].chaddr), (dhcp::MSG_DISCOVER, a.mac()));
+	assert_eq!(a.dhcp_state(), Some(dhcp::State::Selecting));
+
+	// retransmitted until a server answers
+	a.poll(100);
+	assert!(dhcp_sent(&wire).is_empty());
+	a.poll(60_000);
+	let discover = dhcp_sent(&wire);
+	assert_eq!(discover.len(), 1);
+	let xid = discover[0].xid;
+
+	let (server, mac) = ([10, 0, 2, 2], a.mac());
+	let reply = |ty: u8| dhcp::Message {
+		op: dhcp::OP_REPLY, xid, yiaddr: [10, 0, 2, 15], chaddr: mac, ty, server: Some(server),
+		mask: Some([255, 255, 255, 0]), router: Some(server), dns: vec![[10, 0, 2, 3]],
+		lease_time: Some(86400), ..dhcp::Message::default()
+	};
+	// replies to other transactions are ignored
+	dhcp_reply(&wire, dhcp::Message { xid: xid ^ 1, ..reply(dhcp::MSG_OFFER) });
+	a.poll(60_001);
+	assert_eq!(a.dhcp_state(), Some(dhcp::State::Selecting));
+
+	dhcp_reply(&wire, reply(dhcp::MSG_OFFER));
+	a.poll(60_002);
+	let request = dhcp_sent(&wire);
+	assert_eq!(request.len(), 1);
+	assert_eq!((request[0].ty, request[0].requested, request[0].server), (dhcp::MSG_REQUEST, Some([10, 0, 2, 15]), Some(server)));
+
+	let renew = reply(dhcp::MSG_ACK);
+	dhcp_reply(&wire, renew.clone());
+	a.poll(60_003);
+	assert_eq!(a.dhcp_state(), Some(dhcp::State::Bound));
+	assert_eq!(a.ipv4(), Some(Ipv4Config { addr: [10, 0, 2, 15], prefix: 24, gateway: Some(server) }));
+	assert_eq!(a.dns(), [IpAddr::V4([10, 0, 2, 3])]);
+
+	// renewed with the server directly after half of the lease
+	a.poll(60_003 + 43_200_000);
+	assert_eq!(a.dhcp_state(), Some(dhcp::State::Renewing));
+	let arp = sent(&wire, ethernet::TYPE_ARP);
+	assert_eq!(arp::Packet::parse(&arp[0]).unwrap().target_ip, server);
+	inject(&wire, ethernet::TYPE_ARP, &arp::Packet {
+		op: arp::OP_REPLY, sender_mac: [2, 0, 0, 0, 0, 0x99], sender_ip: server, target_mac: a.mac(), target_ip: [10, 0, 2, 15]
+	}.to_bytes());
+	a.poll(60_004 + 43_200_000);
+	let request = dhcp_sent(&wire);
+	assert_eq!((request[0].ty, request[0].ciaddr), (dhcp::MSG_REQUEST, [10, 0, 2, 15]));
+	dhcp_reply(&wire, renew);
+	a.poll(60_005 + 43_200_000);
+	assert_eq!(a.dhcp_state(), Some(dhcp::State::Bound));
+
+	a.stop_dhcp();
+	let release = dhcp_sent(&wire);
+	assert_eq!((release[0].ty, release[0].server), (dhcp::MSG_RELEASE, Some(server)));
+	assert_eq!((a.ipv4(), a.dhcp_state()), (None, None));
+}
+
+#[test]
+fn router_advert() {
+	let wire = Rc::new(RefCell::new(Wire::default()));
+	let mut a = Interface::new(Port { wire: wire.clone(), side: 0, mac: [2, 0, 0, 0, 0, 1], offload: false }, 0);
+	a.poll(0);
+	let solicit = sent(&wire, ethernet::TYPE_IPV6);
+	let (ip, msg) = ipv6::Header::parse(&solicit[0]).unwrap();
+	assert_eq!((ip.dst, ip.hop_limit, msg[0]), (ipv6::ALL_ROUTERS, 255, icmp::V6_ROUTER_SOLICIT));
+
+	let router = ipv6::link_local([2, 0, 0, 0, 0, 0x99]);
+	let prefix = [0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+	let mut msg = icmp::RouterAdvert {
+		lifetime: 1800,
+		mac:      Some([2, 0, 0, 0, 0, 0x99]),
+		mtu:      None,
+		prefixes: vec![icmp::Prefix { prefix, len: 64, flags: icmp::PREFIX_ON_LINK | icmp::PREFIX_AUTONOMOUS, valid: 3600, preferred: 1800 }]
+	}.to_bytes();
+	icmp::finish_v6(&mut msg, router, ipv6::ALL_NODES);
+	let mut packet = Vec::new();
+	ipv6::Header { src: router, dst: ipv6::ALL_NODES, next: ipv6::NEXT_ICMPV6, hop_limit: 255, len: msg.len() as u16 }.write(&mut packet);
+	packet.extend_from_slice(&msg);
+
+	// forwarded advertisements are ignored
+	let mut forwarded = packet.clone();
+	forwarded[7] = 254;
+	inject(&wire, ethernet::TYPE_IPV6, &forwarded);
+	a.poll(1);
+	assert_eq!((a.ipv6().len(), a.router6()), (1, None));
+
+	inject(&wire, ethernet::TYPE_IPV6, &packet);
+	a.poll(2);
+	assert_eq!(a.ipv6(), [ipv6::link_local(a.mac()), ipv6::with_prefix(&[0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0], a.mac())]);
+	assert_eq!(a.router6(), Some(router));
+
+	// global destinations are sent to the router, which is known already
+	let socket = a.udp_bind(Endpoint::new(IpAddr::UNSPECIFIED_V6, 0), None).unwrap();
+	let dst = Endpoint::parse("[2001:db8:1::1]:53").unwrap();
+	a.udp_send(socket, Some(dst), b"query").unwrap();
+	let frame = wire.borrow().log[0].last().unwrap().clone();
+	let (eth, packet) = EthernetHeader::parse(&frame).unwrap();
+	assert_eq!(eth.dst, [2, 0, 0, 0, 0, 0x99]);
+	assert_eq!(ipv6::Header::parse(packet).unwrap().0.src, a.ipv6()[1]);
+}
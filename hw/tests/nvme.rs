@@ -0,0 +1,457 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{alloc::Layout, cell::RefCell, collections::BTreeMap, rc::Rc};
+use hw::{dma::{Dma, PAGE_SIZE}, nvme::*};
+
+const CAP: u64 = 1023 | 1 << 16 | 1 << 24 | 1 << 37;
+const MODEL: &[u8; 40] = b"QEMU NVMe Ctrl                          ";
+
+struct Sq { base: u64, size: u16, head: u16, cq: u16 }
+struct Cq { base: u64, size: u16, tail: u16, phase: bool, vector: Option<u16> }
+
+/// Emulates a controller with two namespaces, 512 and 4096 byte blocks. The device
+/// processes its queues whenever the driver stalls.
+#[derive(Default)]
+struct State {
+	regs:       Vec<u64>,
+	namespaces: Vec<(u32, u32, Vec<u8>)>,
+	enabled:    bool,
+	sqs:        BTreeMap<u16, Sq>,
+	cqs:        BTreeMap<u16, Cq>,
+	allocs:     BTreeMap<usize, Layout>,
+	/// Opcodes of the I/O commands in the order they were executed
+	io:         Vec<u8>,
+	trimmed:    Vec<(u64, u32)>,
+	interrupts: Vec<u16>,
+	/// Number of I/O queues granted
+	queues:     u32
+}
+
+#[derive(Clone)]
+struct Mock(Rc<RefCell<State>>);
+
+impl Mock {
+	fn new() -> Self {
+		let mut state = State {
+			regs:       vec![0; 0x2000 / 8],
+			namespaces: vec![(1, 512, vec![0; 512 * 2048]), (2, 4096, vec![0; 4096 * 64])],
+			queues:     2,
+			..State::default()
+		};
+		state.regs[0] = CAP;
+		state.regs[1] = 0x0001_0400;
+		Self(Rc::new(RefCell::new(state)))
+	}
+
+	fn regs(&self) -> *mut u8 {
+		self.0.borrow_mut().regs.as_mut_ptr() as *mut u8
+	}
+}
+
+fn reg32(regs: &[u64], off: usize) -> u32 {
+	unsafe { (regs.as_ptr() as *const u8).add(off).cast::<u32>().read() }
+}
+
+fn set_reg32(regs: &mut [u64], off: usize, v: u32) {
+	unsafe { (regs.as_mut_ptr() as *mut u8).add(off).cast::<u32>().write(v) }
+}
+
+/// The pages a command's PRPs describe, as `(address, length)`.
+fn prp_segments(cmd: &Command, len: usize) -> Vec<(u64, usize)> {
+	let first = (PAGE_SIZE - cmd.prp1 as usize % PAGE_SIZE).min(len);
+	let mut segments = vec![(cmd.prp1, first)];
+	let rest = len - first;
+	if rest == 0 {
+		return segments;
+	}
+
+	let pages = (rest + PAGE_SIZE - 1) / PAGE_SIZE;
+	let addrs = match pages {
+		1 => vec![cmd.prp2],
+		n => (0..n).map(|i| unsafe { (cmd.prp2 as *const u64).add(i).read() }).collect()
+	};
+	for (i, addr) in addrs.into_iter().enumerate() {
+		segments.push((addr, (rest - i * PAGE_SIZE).min(PAGE_SIZE)));
+	}
+	segments
+}
+
+impl State {
+	fn process(&mut self) {
+		let cc = reg32(&self.regs, 0x14);
+		if cc & 1 != 0 && !self.enabled {
+			let aqa = reg32(&self.regs, 0x24);
+			let (asq, acq) = (self.regs[0x28 / 8], self.regs[0x30 / 8]);
+			self.sqs.insert(0, Sq { base: asq, size: (aqa & 0xFFF) as u16 + 1, head: 0, cq: 0 });
+			self.cqs.insert(0, Cq { base: acq, size: (aqa >> 16) as u16 + 1, tail: 0, phase: true, vector: None });
+			self.enabled = true;
+		} else if cc & 1 == 0 && self.enabled {
+			self.sqs.clear();
+			self.cqs.clear();
+			self.enabled = false;
+		}
+
+		let mut csts = self.enabled as u32;
+		if cc & 0xC000 != 0 {
+			csts |= 2 << 2;
+		}
+		set_reg32(&mut self.regs, 0x1C, csts);
+
+		let ids = self.sqs.keys().copied().collect::<Vec<_>>();
+		for id in ids {
+			loop {
+				let tail = reg32(&self.regs, 0x1000 + 8 * id as usize) as u16;
+				let sq = match self.sqs.get_mut(&id) { Some(sq) if sq.head != tail => sq, _ => break };
+				let cmd = unsafe { (sq.base as *const Command).add(sq.head as usize).read() };
+				sq.head = (sq.head + 1) % sq.size;
+				let (head, cq) = (sq.head, sq.cq);
+
+				let (result, status) = match id {
+					0 => self.admin(&cmd),
+					_ => self.nvm(&cmd)
+				};
+				self.post(cq, Completion { result, sq_head: head, sq_id: id, command_id: cmd.command_id, status: status << 1, ..Completion::default() });
+			}
+		}
+	}
+
+	fn post(&mut self, cq: u16, mut entry: Completion) {
+		let cq = self.cqs.get_mut(&cq).unwrap();
+		entry.status |= cq.phase as u16;
+		unsafe { (cq.base as *mut Completion).add(cq.tail as usize).write(entry) };
+		cq.tail = (cq.tail + 1) % cq.size;
+		if cq.tail == 0 {
+			cq.phase = !cq.phase;
+		}
+		if let Some(v) = cq.vector {
+			self.interrupts.push(v);
+		}
+	}
+
+	fn admin(&mut self, cmd: &Command) -> (u32, u16) {
+		match cmd.opcode {
+			Command::ADMIN_IDENTIFY => {
+				let page = unsafe { std::slice::from_raw_parts_mut(cmd.prp1 as *mut u8, PAGE_SIZE) };
+				match cmd.cdw10 {
+					Command::IDENTIFY_CONTROLLER => {
+						page[0..2].copy_from_slice(&0x1B36u16.to_le_bytes());
+						page[4..12].copy_from_slice(b"deadbeef");
+						page[24..64].copy_from_slice(MODEL);
+						page[77] = 5;
+						page[516..520].copy_from_slice(&(self.namespaces.len() as u32).to_le_bytes());
+						page[520] = 1 << 2;
+						page[525] = 1;
+					}
+					Command::IDENTIFY_ACTIVE_NSIDS => for (i, (id, ..)) in self.namespaces.iter().enumerate() {
+						page[i * 4..i * 4 + 4].copy_from_slice(&id.to_le_bytes());
+					},
+					Command::IDENTIFY_NAMESPACE => match self.namespaces.iter().find(|ns| ns.0 == cmd.nsid) {
+						Some((_, bs, data)) => {
+							page[0..8].copy_from_slice(&((data.len() / *bs as usize) as u64).to_le_bytes());
+							page[130] = bs.trailing_zeros() as u8;
+						}
+						None => return (0, 0x0B)
+					},
+					_ => return (0, 0x02)
+				}
+				(0, 0)
+			}
+			Command::ADMIN_SET_FEATURES => {
+				let n = self.queues.min((cmd.cdw11 & 0xFFFF) + 1) - 1;
+				(n << 16 | n, 0)
+			}
+			Command::ADMIN_CREATE_CQ => {
+				let vector = (cmd.cdw11 & Command::QUEUE_IEN != 0).then_some((cmd.cdw11 >> 16) as u16);
+				self.cqs.insert(cmd.cdw10 as u16, Cq { base: cmd.prp1, size: (cmd.cdw10 >> 16) as u16 + 1, tail: 0, phase: true, vector });
+				(0, 0)
+			}
+			Command::ADMIN_CREATE_SQ => {
+				self.sqs.insert(cmd.cdw10 as u16, Sq { base: cmd.prp1, size: (cmd.cdw10 >> 16) as u16 + 1, head: 0, cq: (cmd.cdw11 >> 16) as u16 });
+				(0, 0)
+			}
+			Command::ADMIN_DELETE_SQ => (0, if self.sqs.remove(&(cmd.cdw10 as u16)).is_some() { 0 } else { 0x0C }),
+			Command::ADMIN_DELETE_CQ => (0, if self.cqs.remove(&(cmd.cdw10 as u16)).is_some() { 0 } else { 0x0C }),
+			_ => (0, 0x01)
+		}
+	}
+
+	fn nvm(&mut self, cmd: &Command) -> (u32, u16) {
+		self.io.push(cmd.opcode);
+		let (_, bs, data) = match self.namespaces.iter_mut().find(|ns| ns.0 == cmd.nsid) {
+			Some(ns) => ns,
+			None => return (0, 0x0B)
+		};
+		let bs = *bs as usize;
+		let lba = cmd.cdw10 as u64 | (cmd.cdw11 as u64) << 32;
+		let len = (cmd.cdw12 as usize & 0xFFFF) + 1;
+		let range = lba as usize * bs..(lba as usize + len) * bs;
+
+		match cmd.opcode {
+			Command::NVM_READ | Command::NVM_WRITE if range.end > data.len() => (0, 0x80),
+			Command::NVM_READ => {
+				let mut off = range.start;
+				for (addr, n) in prp_segments(cmd, len * bs) {
+					unsafe { std::ptr::copy_nonoverlapping(data.as_ptr().add(off), addr as *mut u8, n) };
+					off += n;
+				}
+				(0, 0)
+			}
+			Command::NVM_WRITE => {
+				let mut off = range.start;
+				for (addr, n) in prp_segments(cmd, len * bs) {
+					unsafe { std::ptr::copy_nonoverlapping(addr as *const u8, data.as_mut_ptr().add(off), n) };
+					off += n;
+				}
+				(0, 0)
+			}
+			Command::NVM_FLUSH => (0, 0),
+			Command::NVM_DATASET_MGMT => {
+				for i in 0..cmd.cdw10 as usize + 1 {
+					let r = unsafe { (cmd.prp1 as *const DsmRange).add(i).read() };
+					data[r.start_lba as usize * bs..(r.start_lba as usize + r.length as usize) * bs].fill(0);
+					self.trimmed.push((r.start_lba, r.length));
+				}
+				(0, 0)
+			}
+			_ => (0, 0x01)
+		}
+	}
+}
+
+impl Dma for Mock {
+	fn alloc(&mut self, size: usize, align: usize) -> Option<(*mut u8, u64)> {
+		let layout = Layout::from_size_align(size, align).unwrap();
+		let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+		self.0.borrow_mut().allocs.insert(ptr as usize, layout);
+		Some((ptr, ptr as u64))
+	}
+
+	fn free(&mut self, virt: *mut u8, _size: usize) {
+		let layout = self.0.borrow_mut().allocs.remove(&(virt as usize)).expect("double free");
+		unsafe { std::alloc::dealloc(virt, layout) };
+	}
+
+	fn phys(&mut self, virt: *const u8) -> u64 {
+		virt as u64
+	}
+
+	fn stall(&mut self, _us: u64) {
+		self.0.borrow_mut().process();
+	}
+}
+
+fn controller() -> (Mock, Controller<Mock>) {
+	let mock = Mock::new();
+	let ctrl = unsafe { Controller::new(mock.regs(), mock.clone()) }.unwrap();
+	(mock, ctrl)
+}
+
+/// A buffer starting `offset` bytes into a page, so transfers straddle pages.
+fn buffer(len: usize, offset: usize) -> (Vec<u8>, usize) {
+	let buf = vec![0u8; len + 2 * PAGE_SIZE];
+	let start = (PAGE_SIZE - buf.as_ptr() as usize % PAGE_SIZE) % PAGE_SIZE + offset;
+	(buf, start)
+}
+
+#[test]
+fn entries() {
+	assert_eq!(core::mem::size_of::<Command>(), 64);
+	assert_eq!(core::mem::size_of::<Completion>(), 16);
+	assert_eq!(core::mem::size_of::<DsmRange>(), 16);
+	assert_eq!(core::mem::size_of::<IdentifyController>(), 4096);
+	assert_eq!(core::mem::size_of::<IdentifyNamespace>(), 4096);
+	assert_eq!(core::mem::size_of::<Nvme>(), 0x1000);
+}
+
+#[test]
+fn identify() {
+	let (mock, mut ctrl) = controller();
+	assert_eq!(&ctrl.model_number, MODEL);
+	assert_eq!(&ctrl.serial_number[..8], b"deadbeef");
+	assert_eq!(ctrl.namespaces, 2);
+	assert_eq!(ctrl.max_transfer(), 128 * 1024);
+	assert!(ctrl.supports_trim());
+	assert!(ctrl.volatile_write_cache);
+
+	assert_eq!(ctrl.identify_namespaces(), Ok(vec![
+		Namespace { id: 1, blocks: 2048, block_size: 512 },
+		Namespace { id: 2, blocks: 64, block_size: 4096 }
+	]));
+
+	// the admin queue is set up before the controller is enabled
+	let state = mock.0.borrow();
+	assert_eq!(reg32(&state.regs, 0x14) & 0x00FF_0001, 0x0046_0001);
+	assert_eq!(reg32(&state.regs, 0x24), 0x001F_001F);
+}
+
+#[test]
+fn io_queues() {
+	let (mock, mut ctrl) = controller();
+	assert_eq!(ctrl.create_io_queues(&[Some(1), Some(2), Some(3)]), Ok(2));
+	assert_eq!(ctrl.io_queues(), 2);
+	assert_eq!(ctrl.create_io_queues(&[None]), Err(Error::InvalidArgument));
+
+	let ns = ctrl.identify_namespaces().unwrap()[0];
+	ctrl.flush(1, &ns).unwrap();
+	assert_eq!(mock.0.borrow().interrupts, [2]);
+	assert_eq!(ctrl.flush(2, &ns), Err(Error::InvalidArgument));
+}
+
+#[test]
+fn read_write() {
+	let (mock, mut ctrl) = controller();
+	ctrl.create_io_queues(&[None]).unwrap();
+	let ns = ctrl.identify_namespaces().unwrap();
+
+	// two pages straddled by the first transfer use PRP2, five need a PRP list
+	for (len, offset) in [(1024, PAGE_SIZE - 512), (5 * PAGE_SIZE, 512), (512, 0)] {
+		let (mut buf, start) = buffer(len, offset);
+		let data = &mut buf[start..start + len];
+		data.iter_mut().enumerate().for_each(|(i, b)| *b = (i * 7 + len) as u8);
+		ctrl.write(0, &ns[0], 100, data).unwrap();
+		assert_eq!(&mock.0.borrow().namespaces[0].2[100 * 512..100 * 512 + len], &*data);
+
+		let (mut out, out_start) = buffer(len, offset + 4);
+		ctrl.read(0, &ns[0], 100, &mut out[out_start..out_start + len]).unwrap();
+		assert_eq!(&out[out_start..out_start + len], &*data);
+	}
+
+	// 4 KiB blocks of the second namespace
+	let (mut buf, start) = buffer(8192, 0);
+	buf[start..start + 8192].fill(0xA5);
+	ctrl.write(0, &ns[1], 62, &buf[start..start + 8192]).unwrap();
+	assert!(mock.0.borrow().namespaces[1].2[62 * 4096..].iter().all(|b| *b == 0xA5));
+}
+
+#[test]
+fn split_transfers() {
+	let (mock, mut ctrl) = controller();
+	ctrl.create_io_queues(&[None]).unwrap();
+	let ns = ctrl.identify_namespaces().unwrap()[0];
+
+	let (mut buf, start) = buffer(300 * 1024, 0);
+	buf[start..start + 300 * 1024].iter_mut().enumerate().for_each(|(i, b)| *b = (i / 512) as u8);
+	ctrl.write(0, &ns, 0, &buf[start..start + 300 * 1024]).unwrap();
+	assert_eq!(mock.0.borrow().io, [Command::NVM_WRITE; 3]);
+	assert_eq!(&mock.0.borrow().namespaces[0].2[..300 * 1024], &buf[start..start + 300 * 1024]);
+
+	// a single command can't exceed the maximum transfer size
+	assert_eq!(ctrl.submit_write(0, &ns, 0, &buf[start..start + 256 * 1024]), Err(Error::InvalidArgument));
+}
+
+#[test]
+fn trim_flush() {
+	let (mock, mut ctrl) = controller();
+	ctrl.create_io_queues(&[None]).unwrap();
+	let ns = ctrl.identify_namespaces().unwrap()[0];
+	mock.0.borrow_mut().namespaces[0].2.fill(0xFF);
+
+	ctrl.trim(0, &ns, &[(8, 8), (100, 1)]).unwrap();
+	ctrl.flush(0, &ns).unwrap();
+
+	let state = mock.0.borrow();
+	assert_eq!(state.trimmed, [(8, 8), (100, 1)]);
+	assert_eq!(state.io, [Command::NVM_DATASET_MGMT, Command::NVM_FLUSH]);
+	let data = &state.namespaces[0].2;
+	assert!(data[8 * 512..16 * 512].iter().all(|b| *b == 0));
+	assert!(data[100 * 512..101 * 512].iter().all(|b| *b == 0));
+	assert_eq!(data[16 * 512], 0xFF);
+}
+
+#[test]
+fn errors() {
+	let (mock, mut ctrl) = controller();
+	ctrl.create_io_queues(&[None]).unwrap();
+	let ns = ctrl.identify_namespaces().unwrap()[0];
+	let (mut buf, start) = buffer(4096, 0);
+
+	assert_eq!(ctrl.read(0, &ns, 2047, &mut buf[start..start + 1024]), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.read(0, &ns, 0, &mut buf[start..start + 100]), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.read(0, &ns, 0, &mut buf[start + 1..start + 513]), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.trim(0, &ns, &[(2040, 16)]), Err(Error::InvalidArgument));
+	assert!(mock.0.borrow().io.is_empty());
+
+	let missing = Namespace { id: 9, ..ns };
+	assert_eq!(ctrl.read(0, &missing, 0, &mut buf[start..start + 512]),
+		Err(Error::Command { opcode: Command::NVM_READ, status: 0x0B }));
+}
+
+#[test]
+fn async_completion() {
+	let (mock, mut ctrl) = controller();
+	ctrl.create_io_queues(&[Some(5)]).unwrap();
+	let ns = ctrl.identify_namespaces().unwrap()[0];
+	mock.0.borrow_mut().namespaces[0].2[..1024].fill(0x11);
+
+	let (mut a, sa) = buffer(512, 0);
+	let (mut b, sb) = buffer(512, 0);
+	let ca = ctrl.submit_read(0, &ns, 0, &mut a[sa..sa + 512]).unwrap();
+	let cb = ctrl.submit_read(0, &ns, 1, &mut b[sb..sb + 512]).unwrap();
+	assert_ne!(ca, cb);
+	assert_eq!(ctrl.complete(0), None);
+
+	// the interrupt handler reaps the completions once the device posted them
+	mock.0.borrow_mut().process();
+	assert_eq!(mock.0.borrow().interrupts, [5, 5]);
+	let done = std::iter::from_fn(|| ctrl.complete(0)).map(|c| (c.command_id, c.code())).collect::<Vec<_>>();
+	assert_eq!(done, [(ca, 0), (cb, 0)]);
+	assert!(a[sa..sa + 512].iter().chain(&b[sb..sb + 512]).all(|v| *v == 0x11));
+}
+
+#[test]
+fn split_queues() {
+	let (mock, mut ctrl) = controller();
+	ctrl.create_io_queues(&[Some(1), Some(2)]).unwrap();
+	let ns = ctrl.identify_namespaces().unwrap()[0];
+	let mut queues = ctrl.take_io_queues();
+	assert_eq!(queues.iter().map(|q| q.id()).collect::<Vec<_>>(), [1, 2]);
+	assert_eq!(ctrl.io_queues(), 0);
+
+	// each queue completes on its own, through its vector
+	let (mut buf, start) = buffer(512, 0);
+	buf[start..start + 512].fill(0x5A);
+	queues[1].write(&ns, 3, &buf[start..start + 512]).unwrap();
+	buf[start..start + 512].fill(0);
+	queues[0].read(&ns, 3, &mut buf[start..start + 512]).unwrap();
+	assert!(buf[start..start + 512].iter().all(|b| *b == 0x5A));
+	assert_eq!(mock.0.borrow().interrupts, [2, 1]);
+
+	ctrl.return_io_queues(queues);
+	assert_eq!(ctrl.io_queues(), 2);
+	assert_eq!(ctrl.shutdown(), Ok(()));
+	drop(ctrl);
+	assert!(mock.0.borrow().allocs.is_empty());
+}
+
+#[test]
+fn shutdown() {
+	let (mock, mut ctrl) = controller();
+	ctrl.create_io_queues(&[None, None]).unwrap();
+	assert_eq!(ctrl.shutdown(), Ok(()));
+	assert_eq!(ctrl.io_queues(), 0);
+	assert_eq!(mock.0.borrow().sqs.len(), 1);
+	assert_eq!(reg32(&mock.0.borrow().regs, 0x1C) & 0xC, 0x8);
+
+	drop(ctrl);
+	assert!(mock.0.borrow().allocs.is_empty());
+}
@@ -0,0 +1,174 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+use common::p9::*;
+use hw::p9::*;
+
+fn client(msize: usize) -> (Rc<RefCell<Server>>, Client<Fake>) {
+	let server = Rc::new(RefCell::new(Server::new(msize)));
+	{
+		let mut s = server.borrow_mut();
+		s.add("etc", true, &[]);
+		s.add("etc/hosts", false, b"127.0.0.1 localhost\n");
+		s.add("readme", false, b"hello");
+	}
+	let client = Client::new(Fake(server.clone(), MAX_MSIZE), "root", "share", 0).unwrap();
+	(server, client)
+}
+
+#[test]
+fn attach() {
+	let (server, client) = client(4096);
+	assert_eq!(client.msize(), 4096);
+	assert_eq!(server.borrow().log, [TVERSION, TATTACH]);
+	assert_eq!(server.borrow().fids.keys().copied().collect::<Vec<_>>(), [ROOT]);
+	assert_eq!(client.fids(), 1);
+	drop(client);
+	assert!(server.borrow().fids.is_empty(), "root not clunked");
+
+	let server = Rc::new(RefCell::new(Server::new(MAX_MSIZE)));
+	server.borrow_mut().version = "9P2000.u";
+	assert_eq!(Client::new(Fake(server.clone(), MAX_MSIZE), "root", "", 0).err(), Some(Error::Unsupported));
+	server.borrow_mut().version = VERSION;
+	assert_eq!(Client::new(Fake(server.clone(), IO_HEADER_LEN), "root", "", 0).err(), Some(Error::InvalidArgument));
+	server.borrow_mut().bad_tag = true;
+	assert_eq!(Client::new(Fake(server, MAX_MSIZE), "root", "", 0).err(), Some(Error::Protocol));
+}
+
+#[test]
+fn walk() {
+	let (server, mut client) = client(MAX_MSIZE);
+	let attr = client.metadata("/etc/hosts").unwrap();
+	assert_eq!((attr.size, attr.is_dir(), attr.mode & S_IFMT), (20, false, S_IFREG));
+	assert!(client.metadata("etc").unwrap().is_dir());
+	assert!(client.metadata("/").unwrap().qid.is_dir());
+	assert_eq!(client.metadata("etc/passwd").err(), Some(Error::Remote(ENOENT)));
+	assert_eq!(client.metadata("etc/hosts/x").err(), Some(Error::Remote(ENOENT)));
+	assert_eq!(client.metadata("../etc").err(), Some(Error::InvalidArgument));
+	assert_eq!(client.fids(), 1);
+
+	// deep paths take several walks, a clone none
+	let mut path = String::new();
+	for i in 0..2 * MAXWELEM + 1 {
+		path = if path.is_empty() { i.to_string() } else { format!("{}/{}", path, i) };
+		server.borrow_mut().add(&path, true, &[]);
+	}
+	server.borrow_mut().log.clear();
+	assert!(client.metadata(&path).unwrap().is_dir());
+	assert_eq!(server.borrow().log, [TWALK, TWALK, TWALK, TGETATTR, TCLUNK]);
+	let fid = client.walk(ROOT, &[]).unwrap();
+	assert_eq!(server.borrow().fids[&fid].0, "");
+	client.clunk(fid).unwrap();
+	assert_eq!(client.walk(ROOT, &["etc", ""]).err(), Some(Error::InvalidArgument));
+	assert_eq!(client.fids(), 1);
+	assert_eq!(server.borrow().fids.len(), 1);
+}
+
+#[test]
+fn read_write() {
+	let (server, mut client) = client(1024);
+	let mut buf = [0; 64];
+	assert_eq!(client.read_at("readme", 0, &mut buf).unwrap(), 5);
+	assert_eq!(&buf[..5], b"hello");
+	assert_eq!(client.read_at("readme", 3, &mut buf).unwrap(), 2);
+	assert_eq!(client.read_at("readme", 9, &mut buf).unwrap(), 0);
+	assert_eq!(client.write_at("readme", 5, b" world").unwrap(), 6);
+	assert_eq!(server.borrow().nodes["readme"].data, b"hello world");
+	// the file stays open
+	assert_eq!(server.borrow().log.iter().filter(|t| **t == TLOPEN).count(), 1);
+
+	// larger than a message
+	let data = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+	assert_eq!(client.write_at("etc/hosts", 0, &data).unwrap(), data.len());
+	let mut buf = vec![0; 6000];
+	assert_eq!(client.read_at("etc/hosts", 0, &mut buf).unwrap(), data.len());
+	assert_eq!(buf[..data.len()], data);
+
+	// read-only files are opened for reading
+	server.borrow_mut().add("ro", false, b"ro");
+	server.borrow_mut().nodes.get_mut("ro").unwrap().readonly = true;
+	assert_eq!(client.read_at("ro", 0, &mut buf).unwrap(), 2);
+	assert_eq!(client.write_at("ro", 0, b"rw").err(), Some(Error::Remote(9)));
+	assert_eq!(client.read_at("etc", 0, &mut buf).err(), Some(Error::Remote(EISDIR)));
+	client.sync().unwrap();
+
+	// the least recently used file is closed
+	for i in 0..OPEN_FIDS + 4 {
+		server.borrow_mut().add(&i.to_string(), false, &[i as u8]);
+		assert_eq!(client.read_at(&i.to_string(), 0, &mut buf).unwrap(), 1);
+	}
+	assert_eq!(client.fids(), OPEN_FIDS + 1);
+	assert_eq!(server.borrow().fids.len(), OPEN_FIDS + 1);
+	drop(client);
+	assert!(server.borrow().fids.is_empty());
+}
+
+#[test]
+fn create_remove() {
+	let (server, mut client) = client(MAX_MSIZE);
+	client.create_path("etc/new", false).unwrap();
+	client.create_path("/var", true).unwrap();
+	client.create_path("var/log", true).unwrap();
+	assert_eq!(client.create_path("etc/new", false).err(), Some(Error::Remote(EEXIST)));
+	assert_eq!(client.create_path("missing/new", false).err(), Some(Error::Remote(ENOENT)));
+	assert_eq!(client.create_path("/", true).err(), Some(Error::InvalidArgument));
+	assert!(server.borrow().nodes["var/log"].dir);
+	assert!(!server.borrow().nodes["etc/new"].dir);
+
+	let names = |c: &mut Client<Fake>, path| c.list(path).unwrap().into_iter().map(|e| e.name).collect::<Vec<_>>();
+	assert_eq!(names(&mut client, "/"), ["etc", "readme", "var"]);
+	assert_eq!(names(&mut client, "etc"), ["hosts", "new"]);
+	assert!(client.list("var/log").unwrap().is_empty());
+	assert!(client.list("etc").unwrap()[0].qid.path == server.borrow().nodes["etc/hosts"].qid);
+
+	client.write_at("etc/new", 0, b"data").unwrap();
+	client.truncate("etc/new", 2).unwrap();
+	assert_eq!(client.metadata("etc/new").unwrap().size, 2);
+	assert_eq!(client.remove("var").err(), Some(Error::Remote(ENOTEMPTY)));
+	client.remove("var/log").unwrap();
+	client.remove("var").unwrap();
+	// removing a file closes it
+	client.remove("etc/new").unwrap();
+	assert_eq!(client.remove("etc/new").err(), Some(Error::Remote(ENOENT)));
+	assert_eq!(names(&mut client, "/"), ["etc", "readme"]);
+	assert_eq!(client.fids(), 1);
+	assert_eq!(server.borrow().fids.len(), 1);
+}
+
+#[test]
+fn readdir() {
+	// the entries take several responses
+	let (server, mut client) = client(256);
+	for i in 0..50 {
+		server.borrow_mut().add(&format!("etc/file{:02}", i), false, &[]);
+	}
+	server.borrow_mut().log.clear();
+	let entries = client.list("etc").unwrap();
+	assert_eq!(entries.len(), 51);
+	assert_eq!(entries[0].name, "file00");
+	assert_eq!(entries[50].name, "hosts");
+	assert!(server.borrow().log.iter().filter(|t| **t == TREADDIR).count() > 3);
+	assert_eq!(client.fids(), 1);
+}
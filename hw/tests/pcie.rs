@@ -0,0 +1,437 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use common::{AcpiWindow, MockHandler};
+use hw::{acpi::{Table, aml::Namespace}, pcie::*};
+
+/// A function in `MockBus`, bits set in `ro` keep their value in `cfg` on writes.
+struct Function {
+	/// Index of the bridge the function is behind
+	parent:   Option<usize>,
+	device:   u8,
+	function: u8,
+	cfg:      [u32; 1024],
+	ro:       [u32; 1024]
+}
+
+impl Function {
+	fn new(parent: Option<usize>, device: u8, function: u8, vendor: u16, id: u16, class: u32, header: u8) -> Self {
+		let mut f = Self { parent, device, function, cfg: [0; 1024], ro: [!0; 1024] };
+		f.cfg[0] = (id as u32) << 16 | vendor as u32;
+		f.cfg[1] = 0x0010_0000;
+		f.ro[1] = 0xFFFF_0000;
+		f.cfg[2] = class << 8 | 1;
+		f.cfg[3] = (header as u32) << 16;
+
+		if header & 0x7F == 1 {
+			for reg in [6, 7, 8, 9, 10, 11, 12] {
+				f.ro[reg] = 0;
+			}
+			// I/O window only decodes 4 KiB granules, the memory windows 1 MiB
+			f.ro[7] = 0xFFFF_0F0F;
+			f.ro[8] = 0x000F_000F;
+			f.ro[9] = 0x000F_000F;
+			f.cfg[9] = 0x0001_0001;
+		}
+		f
+	}
+
+	fn bar(mut self, i: usize, size: u32, flags: u32) -> Self {
+		self.cfg[4 + i] = flags;
+		self.ro[4 + i] = size - 1;
+		if flags & 0x6 == 0x4 {
+			self.ro[5 + i] = 0;
+		}
+		self
+	}
+
+	fn caps(mut self, caps: &[(u16, u32)]) -> Self {
+		let mut prev = None;
+		for (offset, header) in caps {
+			if *offset >= 0x100 {
+				if let Some(prev) = prev.filter(|v| *v >= 0x100) {
+					self.cfg[prev as usize / 4] |= (*offset as u32) << 20;
+				}
+			} else if let Some(prev) = prev {
+				self.cfg[prev as usize / 4] |= (*offset as u32) << 8;
+			} else {
+				self.cfg[13] = *offset as u32;
+			}
+			self.cfg[*offset as usize / 4] = *header;
+			prev = Some(*offset);
+		}
+		self
+	}
+}
+
+#[derive(Default)]
+struct MockBus(Vec<Function>);
+
+impl MockBus {
+	fn bus(&self, i: usize) -> Option<u8> {
+		match self.0[i].parent {
+			None => Some(0),
+			Some(p) => {
+				self.bus(p)?;
+				let secondary = (self.0[p].cfg[6] >> 8) as u8;
+				(secondary != 0).then_some(secondary)
+			}
+		}
+	}
+
+	fn find(&self, address: PciAddress) -> Option<usize> {
+		(0..self.0.len()).find(|i| self.0[*i].device == address.device
+			&& self.0[*i].function == address.function && self.bus(*i) == Some(address.bus))
+	}
+
+	fn reg(&self, address: PciAddress, offset: u16) -> u32 {
+		let i = self.find(address).unwrap();
+		self.0[i].cfg[offset as usize / 4]
+	}
+}
+
+impl ConfigSpace for MockBus {
+	fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+		self.find(address).map_or(!0, |i| self.0[i].cfg[offset as usize / 4])
+	}
+
+	fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+		if let Some(i) = self.find(address) {
+			let f = &mut self.0[i];
+			let reg = offset as usize / 4;
+			f.cfg[reg] = f.cfg[reg] & f.ro[reg] | value & !f.ro[reg];
+		}
+	}
+}
+
+/// Host bridge, VGA, a root port with a NIC, a multi-function AHCI/SMBus device and
+/// a root port with a PCIe-to-PCI bridge and a disk behind it.
+fn q35() -> MockBus {
+	MockBus(vec![
+		Function::new(None, 0, 0, 0x8086, 0x29C0, 0x060000, 0),
+		Function::new(None, 1, 0, 0x1234, 0x1111, 0x030000, 0)
+			.bar(0, 0x100_0000, 0x8)
+			.bar(2, 0x1000, 0x0),
+		Function::new(None, 2, 0, 0x1B36, 0x000C, 0x060400, 0x81)
+			.bar(0, 0x1000, 0x0)
+			.caps(&[(0x40, 0x0042_0010), (0x100, 0x0001_0001)]),
+		Function::new(Some(2), 0, 0, 0x1AF4, 0x1041, 0x020000, 0)
+			.bar(1, 0x1000, 0x0)
+			.bar(4, 0x4000, 0xC)
+			.caps(&[(0x84, 0x0000_0009), (0x98, 0x0003_0011)]),
+		Function::new(None, 3, 0, 0x8086, 0x2922, 0x010601, 0x80)
+			.bar(4, 0x20, 0x1)
+			.bar(5, 0x1000, 0x0),
+		Function::new(None, 3, 2, 0x8086, 0x2930, 0x0C0500, 0)
+			.bar(4, 0x40, 0x1),
+		Function::new(None, 4, 0, 0x1B36, 0x000C, 0x060400, 0x01),
+		Function::new(Some(6), 0, 0, 0x1B36, 0x000E, 0x060400, 0x01),
+		Function::new(Some(7), 1, 0, 0x1AF4, 0x1042, 0x010000, 0)
+			.bar(0, 0x40, 0x1)
+			.bar(4, 0x4000, 0xC),
+	])
+}
+
+fn find(devices: &[Device], bus: u8, device: u8, function: u8) -> &Device {
+	DeviceIter::new(devices)
+		.find(|d| d.address == PciAddress { segment: 0, bus, device, function })
+		.unwrap_or_else(|| panic!("no device at {:02x}:{:02x}.{}", bus, device, function))
+}
+
+fn resources() -> Resources {
+	let window = AcpiWindow::load("acpi/q35.bin");
+	let fadt = match window.rsdp().get_xsdt().unwrap() {
+		Table::Xsdt(xsdt) => xsdt.into_iter().find_map(|t| match t { Table::Fadt(v) => Some(v), _ => None }).unwrap(),
+		_ => unreachable!()
+	};
+
+	let mut handler = MockHandler::default();
+	let dsdt = fadt.dsdt().unwrap();
+	let mut ns = Namespace::new(dsdt.header.revision);
+	ns.load(dsdt.definition_block(), &mut handler).unwrap();
+	Resources::from_resources(&ns.resources("\\_SB.PCI0", &mut handler).unwrap())
+}
+
+#[test]
+fn headers() {
+	assert_eq!(core::mem::size_of::<ConfigurationSpaceHeaderCommon>(), 64);
+	assert_eq!(core::mem::size_of::<ConfigurationSpaceHeaderType0>(), 64);
+	assert_eq!(core::mem::size_of::<ConfigurationSpaceHeaderType1>(), 64);
+	assert_eq!(core::mem::size_of::<MsiXTableEntry>(), 16);
+}
+
+#[test]
+fn vendor_names() {
+	assert_eq!(vendors::get_name(0x8086), "Intel Corporation");
+	assert_eq!(vendors::get_name(0x1AF4), "Red Hat, Inc.");
+	assert_eq!(vendors::try_get_name(0x0014), Some("Loongson Technology Corporation Limited"));
+	assert_eq!(vendors::try_get_name(0x2646), Some("Kingston Technology Company "));
+	assert_eq!(vendors::try_get_name(0xFFFF), None);
+	assert_eq!(vendors::get_name(0xFFFF), "UNKNOWN VENDOR");
+}
+
+#[test]
+fn ecam() {
+	let mut mem = vec![0u32; 3 << 18];
+	let base = mem.as_mut_ptr() as *mut u8;
+	mem[(1 << 18) | (2 << 13) | (3 << 10)] = 0x1041_1AF4;
+
+	let mut ecam = unsafe { Ecam::new(base, 1, 1, 2) };
+	assert_eq!(ecam.read(PciAddress { segment: 1, bus: 1, device: 2, function: 3 }, 0), 0x1041_1AF4);
+	assert_eq!(ecam.read(PciAddress { segment: 1, bus: 1, device: 2, function: 3 }, 2), 0x1041_1AF4);
+	assert_eq!(ecam.read(PciAddress { segment: 1, bus: 0, device: 2, function: 3 }, 0), !0);
+	assert_eq!(ecam.read(PciAddress { segment: 0, bus: 1, device: 2, function: 3 }, 0), !0);
+	assert_eq!(ecam.read(PciAddress { segment: 1, bus: 3, device: 0, function: 0 }, 0), !0);
+
+	ecam.write(PciAddress { segment: 1, bus: 2, device: 31, function: 7 }, 0xFFC, 0x1234_5678);
+	assert_eq!(mem[(2 << 18) | (31 << 13) | (7 << 10) | 0x3FF], 0x1234_5678);
+}
+
+#[test]
+fn enumerate_q35() {
+	let mut bus = q35();
+	let devices = enumerate(&mut bus, 0, 0..=0xFF);
+
+	let root = devices.iter().map(|d| (d.address.device, d.address.function, d.vendor_id, d.device_id)).collect::<Vec<_>>();
+	assert_eq!(root, [(0, 0, 0x8086, 0x29C0), (1, 0, 0x1234, 0x1111), (2, 0, 0x1B36, 0x000C),
+		(3, 0, 0x8086, 0x2922), (3, 2, 0x8086, 0x2930), (4, 0, 0x1B36, 0x000C)]);
+	assert_eq!(DeviceIter::new(&devices).count(), 9);
+
+	let port = find(&devices, 0, 2, 0);
+	assert_eq!(port.vendor_name, "Red Hat, Inc.");
+	assert_eq!((port.class_code, port.subclass), (0x06, 0x04));
+	assert_eq!(port.bridge.map(|b| (b.secondary_bus, b.subordinate_bus)), Some((1, 1)));
+	assert_eq!(port.capability(CapabilityHeader::ID_PCIE), Some(0x40));
+	assert_eq!(port.ext_capability(ExtCapabilityHeader::ID_AER), Some(0x100));
+	assert_eq!(bus.reg(port.address, 0x18), 0x01_01_00);
+
+	let nic = find(&devices, 1, 0, 0);
+	assert_eq!(nic.vendor_name, "Red Hat, Inc.");
+	assert_eq!(nic.capabilities.iter().map(|c| (c.id, c.offset, c.extended)).collect::<Vec<_>>(),
+		[(0x09, 0x84, false), (0x11, 0x98, false)]);
+	assert_eq!(nic.capability(CapabilityHeader::ID_MSI), None);
+	assert_eq!(nic.bars[1], Some(Bar { kind: BarKind::Memory32, prefetchable: false, address: 0, size: 0x1000 }));
+	assert_eq!(nic.bars[4], Some(Bar { kind: BarKind::Memory64, prefetchable: true, address: 0, size: 0x4000 }));
+	assert_eq!(nic.bars[5], None);
+
+	let ahci = find(&devices, 0, 3, 0);
+	assert_eq!((ahci.class_code, ahci.subclass, ahci.prog_if), (0x01, 0x06, 0x01));
+	assert_eq!(ahci.bars[4], Some(Bar { kind: BarKind::Io, prefetchable: false, address: 0, size: 0x20 }));
+	assert!(ahci.capabilities.is_empty());
+
+	// nested bridges
+	let port = find(&devices, 0, 4, 0);
+	assert_eq!(port.bridge.map(|b| (b.secondary_bus, b.subordinate_bus)), Some((2, 3)));
+	let bridge = find(&devices, 2, 0, 0);
+	assert_eq!(bridge.bridge.map(|b| (b.secondary_bus, b.subordinate_bus)), Some((3, 3)));
+	assert_eq!(bus.reg(bridge.address, 0x18), 0x03_03_02);
+	assert_eq!(find(&devices, 3, 1, 0).device_id, 0x1042);
+
+	// sizing restored the BARs
+	assert_eq!(bus.reg(nic.address, 0x24), 0);
+	assert_eq!(bus.reg(nic.address, 0x20), 0xC);
+}
+
+#[test]
+fn host_bridge_resources() {
+	assert_eq!(resources(), Resources {
+		io:           Some(Window { base: 0xD00, limit: 0xFFFF }),
+		memory:       Some(Window { base: 0x8000_0000, limit: 0xAFFF_FFFF }),
+		prefetchable: Some(Window { base: 0x8_0000_0000, limit: 0xF_FFFF_FFFF })
+	});
+}
+
+#[test]
+fn assign_q35() {
+	let mut bus = q35();
+	let mut devices = enumerate(&mut bus, 0, 0..=0xFF);
+	assert_eq!(assign(&mut bus, &mut devices, &resources()), Ok(()));
+
+	// root port windows are 1 MiB (memory) and 4 KiB (I/O) aligned
+	let port = find(&devices, 0, 2, 0).bridge.unwrap();
+	assert_eq!(port.io, None);
+	assert_eq!(port.memory, Some(Window { base: 0x8000_0000, limit: 0x800F_FFFF }));
+	assert_eq!(port.prefetchable, Some(Window { base: 0x8_0000_0000, limit: 0x8_000F_FFFF }));
+
+	let port = find(&devices, 0, 4, 0).bridge.unwrap();
+	assert_eq!(port.io, Some(Window { base: 0x1000, limit: 0x1FFF }));
+	assert_eq!(port.memory, None);
+	assert_eq!(port.prefetchable, Some(Window { base: 0x8_0010_0000, limit: 0x8_001F_FFFF }));
+
+	let nic = find(&devices, 1, 0, 0);
+	assert_eq!(nic.bars[1].unwrap().address, 0x8000_0000);
+	assert_eq!(nic.bars[4].unwrap().address, 0x8_0000_0000);
+	assert_eq!(bus.reg(nic.address, 0x14), 0x8000_0000);
+	assert_eq!(bus.reg(nic.address, 0x20), 0xC);
+	assert_eq!(bus.reg(nic.address, 0x24), 0x8);
+	assert_eq!(bus.reg(nic.address, 0x04) & 0x7, COMMAND_MEMORY_SPACE as u32);
+
+	let disk = find(&devices, 3, 1, 0);
+	assert_eq!(disk.bars[0].unwrap().address, 0x1000);
+	assert_eq!(disk.bars[4].unwrap().address, 0x8_0010_0000);
+	assert_eq!(bus.reg(disk.address, 0x10), 0x1001);
+	assert_eq!(bus.reg(disk.address, 0x04) & 0x7, (COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE) as u32);
+
+	// the remaining root bus BARs follow the bridge windows, largest first, 32-bit
+	// BARs can't use the prefetchable window above 4 GiB
+	let vga = find(&devices, 0, 1, 0);
+	assert_eq!(vga.bars[0].unwrap().address, 0x8100_0000);
+	assert_eq!(vga.bars[2].unwrap().address, 0x8200_0000);
+	assert_eq!(find(&devices, 0, 2, 0).bars[0].unwrap().address, 0x8200_1000);
+	assert_eq!(find(&devices, 0, 3, 2).bars[4].unwrap().address, 0x2000);
+	assert_eq!(find(&devices, 0, 3, 0).bars[4].unwrap().address, 0x2040);
+
+	// bridges forward the windows and master on behalf of their children
+	let port = find(&devices, 0, 4, 0).address;
+	assert_eq!(bus.reg(port, 0x1C) & 0xFFFF, 0x1010);
+	assert_eq!(bus.reg(port, 0x20), 0x0000_FFF0);
+	assert_eq!(bus.reg(port, 0x24), 0x0011_0011);
+	assert_eq!(bus.reg(port, 0x28), 0x8);
+	assert_eq!(bus.reg(port, 0x2C), 0x8);
+	assert_eq!(bus.reg(port, 0x04) & 0x7, 0x7);
+}
+
+#[test]
+fn assign_exhausted() {
+	let mut bus = q35();
+	let mut devices = enumerate(&mut bus, 0, 0..=0xFF);
+	let resources = Resources {
+		io:           Some(Window { base: 0x1000, limit: 0x1FFF }),
+		memory:       Some(Window { base: 0x8000_0000, limit: 0x80FF_FFFF }),
+		prefetchable: None
+	};
+
+	// the 16 MiB VGA BAR doesn't fit after the root port windows, all others do
+	assert_eq!(assign(&mut bus, &mut devices, &resources),
+		Err(AssignError::Exhausted { address: PciAddress { segment: 0, bus: 0, device: 1, function: 0 }, bar: 0 }));
+	assert_eq!(find(&devices, 0, 1, 0).bars[0].unwrap().address, 0);
+	assert_ne!(find(&devices, 0, 1, 0).bars[2].unwrap().address, 0);
+	assert_eq!(find(&devices, 1, 0, 0).bars[4].unwrap().address, 0x8000_0000);
+}
+
+/// A function with a 64-bit, 4 vector MSI capability with per-vector masking and a
+/// 32-bit, single vector one.
+fn msi_bus() -> MockBus {
+	let mut f = Function::new(None, 5, 0, 0x8086, 0x10D3, 0x020000, 0)
+		.caps(&[(0x50, 0x0184_0005), (0x70, 0x0000_0005)]);
+	for reg in [0x54, 0x58, 0x5C, 0x60, 0x74, 0x78] {
+		f.ro[reg / 4] = 0;
+	}
+	// enable bit and MME are writable
+	f.ro[0x50 / 4] = 0xFF8E_FFFF;
+	f.ro[0x70 / 4] = 0xFF8E_FFFF;
+	MockBus(vec![f])
+}
+
+#[test]
+fn msi_messages() {
+	assert_eq!(MsiMessage::x2apic(3, 0x40), Some(MsiMessage { address: 0xFEE0_3000, data: 0x40 }));
+	assert_eq!(MsiMessage::x2apic(0x1FF, 0x41), Some(MsiMessage { address: 0xFEEF_F020, data: 0x41 }));
+	assert_eq!(MsiMessage::x2apic(0x7FFF, 0x41), Some(MsiMessage { address: 0xFEEF_FFE0, data: 0x41 }));
+	assert_eq!(MsiMessage::x2apic(0x8000, 0x40), None);
+	assert_eq!(MsiMessage::x2apic(0, 0x0F), None);
+	assert_eq!(MsiMessage::gic_its(0x0808_0000, 7), MsiMessage { address: 0x0809_0040, data: 7 });
+	assert_eq!(MsiMessage::imsic(0x2800_3000, 12), MsiMessage { address: 0x2800_3000, data: 12 });
+}
+
+#[test]
+fn msi() {
+	let mut bus = msi_bus();
+	let devices = enumerate(&mut bus, 0, 0..=0);
+	let dev = &devices[0];
+	let msi = Msi::new(dev).unwrap();
+	assert_eq!(msi.offset, 0x50);
+	assert_eq!(msi.vectors(&mut bus), 4);
+	assert!(msi.is_64bit(&mut bus));
+
+	// 3 vectors are rounded down, 8 are capped at what the function requests
+	let msg = MsiMessage { address: 0x1_FEE0_1000, data: 0x44 };
+	assert_eq!(msi.enable(&mut bus, msg, 3), 2);
+	assert_eq!(bus.reg(dev.address, 0x50) >> 16, 0x0195);
+	assert_eq!(msi.enable(&mut bus, msg, 8), 4);
+	assert_eq!(bus.reg(dev.address, 0x50) >> 16, 0x01A5);
+	assert_eq!(bus.reg(dev.address, 0x54), 0xFEE0_1000);
+	assert_eq!(bus.reg(dev.address, 0x58), 0x1);
+	assert_eq!(bus.reg(dev.address, 0x5C), 0x44);
+	assert_eq!(bus.reg(dev.address, 0x04) & COMMAND_INT_DISABLE as u32, COMMAND_INT_DISABLE as u32);
+
+	assert!(msi.mask(&mut bus, 2, true));
+	assert_eq!(bus.reg(dev.address, 0x60), 0x4);
+	assert!(msi.mask(&mut bus, 2, false));
+	assert_eq!(bus.reg(dev.address, 0x60), 0);
+
+	msi.disable(&mut bus);
+	assert_eq!(bus.reg(dev.address, 0x50) >> 16, 0x01A4);
+
+	// the second capability has 32-bit addresses and no masking
+	let msi = Msi { offset: 0x70, ..msi };
+	assert_eq!(msi.vectors(&mut bus), 1);
+	assert_eq!(msi.enable(&mut bus, msg, 1), 0);
+	assert_eq!(msi.enable(&mut bus, MsiMessage { address: 0xFEE0_0000, data: 0x30 }, 4), 1);
+	assert_eq!(bus.reg(dev.address, 0x70) >> 16, 0x0001);
+	assert_eq!(bus.reg(dev.address, 0x74), 0xFEE0_0000);
+	assert_eq!(bus.reg(dev.address, 0x78), 0x30);
+	assert!(!msi.mask(&mut bus, 0, true));
+}
+
+#[test]
+fn msix() {
+	let mut bus = q35();
+	let devices = enumerate(&mut bus, 0, 0..=0xFF);
+	let nic = find(&devices, 1, 0, 0);
+	let address = nic.address;
+	let i = bus.find(address).unwrap();
+	bus.0[i].cfg[0x9C / 4] = 0x2004;
+	bus.0[i].cfg[0xA0 / 4] = 0x3004;
+	bus.0[i].ro[0x98 / 4] = 0x3FFF_FFFF;
+
+	let msix = MsiX::new(&mut bus, nic).unwrap();
+	assert_eq!((msix.size, msix.table_bar, msix.table_offset, msix.pba_bar, msix.pba_offset), (4, 4, 0x2000, 4, 0x3000));
+
+	msix.enable(&mut bus);
+	assert_eq!(bus.reg(address, 0x98) >> 16, 0xC003);
+	msix.unmask_all(&mut bus);
+	assert_eq!(bus.reg(address, 0x98) >> 16, 0x8003);
+	assert_eq!(bus.reg(address, 0x04) & COMMAND_INT_DISABLE as u32, COMMAND_INT_DISABLE as u32);
+
+	let mut bar = vec![0u64; 0x4000 / 8];
+	bar[0x3000 / 8] = 0b100;
+	let base = bar.as_mut_ptr() as *mut u8;
+	let mut table = unsafe { msix.table(base, base) };
+	assert_eq!(table.len(), 4);
+
+	let msg = MsiMessage::x2apic(0x102, 0x51).unwrap();
+	table.set(1, msg);
+	assert_eq!(table.get(1), msg);
+	assert!(table.is_masked(1));
+	table.mask(1, false);
+	assert!(!table.is_masked(1));
+	assert_eq!(bar[0x2010 / 8], 0xFEE0_2020);
+	assert_eq!(bar[0x2018 / 8], 0x51);
+	assert!(table.is_pending(2));
+	assert!(!table.is_pending(1));
+
+	msix.disable(&mut bus);
+	assert_eq!(bus.reg(address, 0x98) >> 16, 0x0003);
+}
@@ -0,0 +1,129 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use common::{AcpiWindow, MockHandler};
+use hw::acpi::{*, aml::Namespace, power::*};
+
+fn load(name: &str) -> (&'static FADT, Namespace<'static>, MockHandler) {
+	let window = AcpiWindow::load(name);
+	let fadt = match window.rsdp().get_xsdt().expect("no XSDT") {
+		Table::Xsdt(xsdt) => xsdt.into_iter().find_map(|t| match t { Table::Fadt(v) => Some(v), _ => None }),
+		_ => None
+	}.expect("no FADT");
+
+	let mut handler = MockHandler::default();
+	let dsdt = fadt.dsdt().expect("no DSDT");
+	let mut ns = Namespace::new(dsdt.header.revision);
+	ns.load(dsdt.definition_block(), &mut handler).unwrap();
+	(fadt, ns, handler)
+}
+
+#[test]
+fn registers() {
+	let (fadt, _, _) = load("acpi/q35.bin");
+	let pm1a = fadt.pm1a_cnt_blk().expect("no PM1a control block");
+	assert_eq!(({ pm1a.address_space }, { pm1a.bit_width }, { pm1a.address }), (1, 16, 0x604));
+	assert!(fadt.pm1b_cnt_blk().is_none());
+	assert_eq!({ fadt.pm1a_evt_blk().unwrap().address }, 0x600);
+	assert_eq!({ fadt.reset_register().unwrap().address }, 0xCF9);
+	assert!(!fadt.is_hw_reduced());
+
+	let (fadt, _, _) = load("acpi/virt.bin");
+	assert!(fadt.is_hw_reduced());
+	assert!(fadt.pm1a_cnt_blk().is_none());
+	assert!(fadt.reset_register().is_none());
+}
+
+#[test]
+fn shutdown() {
+	let (fadt, mut ns, mut handler) = load("acpi/q35.bin");
+	handler.ports.insert(0x604, 0x0001);
+	Power::new(fadt).shutdown(&mut ns, &mut handler).unwrap();
+
+	// clear WAK_STS, then SLP_TYP = 0 and SLP_EN, preserving SCI_EN
+	assert_eq!(handler.io, [(0x600, 16, 0x8000), (0x604, 16, 0x0001), (0x604, 16, 0x2001)]);
+}
+
+#[test]
+fn suspend() {
+	let (fadt, mut ns, mut handler) = load("acpi/q35-s3.bin");
+	let power = Power::new(fadt);
+	power.suspend(0x8000, &mut ns, &mut handler).unwrap();
+	assert_eq!(handler.io, [(0x600, 16, 0x8000), (0x604, 16, 0x0400), (0x604, 16, 0x2400)]);
+
+	let facs = fadt.firmware_ctrl().expect("no FACS");
+	assert!(facs.is_valid());
+	assert_eq!({ facs.firmware_waking_vector }, 0x8000);
+	assert_eq!({ facs.x_firmware_waking_vector }, 0);
+
+	handler.io.clear();
+	handler.ports.insert(0x604, 0x2401);
+	power.wake(SleepState::S3, &mut ns, &mut handler).unwrap();
+	assert_eq!(handler.io, [(0x604, 16, 0x0001)]);
+
+	assert_eq!(power.sleep(SleepState::S4, &mut ns, &mut handler), Err(PowerError::Unsupported));
+}
+
+#[test]
+fn reset() {
+	let (fadt, _, mut handler) = load("acpi/q35.bin");
+	Power::new(fadt).reset(&mut handler).unwrap();
+	assert_eq!(handler.io, [(0xCF9, 8, 0x0F)]);
+
+	let (fadt, _, mut handler) = load("acpi/virt.bin");
+	assert_eq!(Power::new(fadt).reset(&mut handler), Err(PowerError::Unsupported));
+}
+
+#[test]
+fn enable() {
+	let (fadt, _, mut handler) = load("acpi/q35.bin");
+	handler.ports.insert(0x604, PM1_CNT_SCI_EN);
+	Power::new(fadt).enable(&mut handler).unwrap();
+	assert!(handler.io.is_empty());
+
+	// the mock never sets SCI_EN
+	handler.ports.clear();
+	assert_eq!(Power::new(fadt).enable(&mut handler), Err(PowerError::Timeout));
+	assert_eq!(handler.io, [(0xB2, 8, 0x02)]);
+}
+
+#[test]
+fn hw_reduced() {
+	let (fadt, mut ns, mut handler) = load("acpi/virt.bin");
+	Power::new(fadt).enable(&mut handler).unwrap();
+	assert_eq!(Power::new(fadt).shutdown(&mut ns, &mut handler), Err(PowerError::Unsupported));
+	assert!(handler.io.is_empty());
+}
+
+#[test]
+fn generic_address() {
+	let mut handler = MockHandler::default();
+	let reg = GenericAddress { address_space: 0, bit_width: 8, bit_offset: 4, access_size: 3, address: 0x1000 };
+	write_register(&reg, 0xAB, &mut handler).unwrap();
+	assert_eq!(hw::acpi::aml::Handler::read_memory(&mut handler, 0x1000, 32), 0xAB0);
+	assert_eq!(read_register(&reg, &mut handler), Ok(0xAB));
+
+	let reg = GenericAddress { address_space: 0x7F, bit_width: 8, bit_offset: 0, access_size: 1, address: 0 };
+	assert_eq!(read_register(&reg, &mut handler), Err(PowerError::InvalidRegister));
+}
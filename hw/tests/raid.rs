@@ -0,0 +1,391 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::Cell, rc::Rc};
+use hw::{block::{self, BlockDevice, RamDisk}, raid::*};
+
+const SECTOR: usize = 512;
+
+fn fixture(level: u32, disks: usize) -> Vec<RamDisk> {
+	(0..disks).map(|i| RamDisk::from_vec(SECTOR, common::load_sparse(&format!("raid/raid{}-{}.img", level, i)))).collect()
+}
+
+/// Checks that every sector of a fixture array holds its number.
+fn check_fixture<D: BlockDevice>(array: &mut Array<D>) {
+	let mut buf = vec![0; array.blocks() as usize * SECTOR];
+	array.read(0, &mut buf).unwrap();
+	for (n, sector) in buf.chunks(SECTOR).enumerate() {
+		let stamp = (0x6D64_0000_0000_0000 | n as u64).to_le_bytes();
+		assert!(sector.chunks(8).all(|c| c == stamp), "sector {}", n);
+	}
+}
+
+fn data(len: usize, seed: u32) -> Vec<u8> {
+	let mut x = seed;
+	(0..len).map(|_| {
+		x = x.wrapping_mul(1103515245).wrapping_add(12345);
+		(x >> 16) as u8
+	}).collect()
+}
+
+fn disks(n: usize, block_size: usize, blocks: usize) -> Vec<RamDisk> {
+	(0..n).map(|_| RamDisk::new(block_size, blocks)).collect()
+}
+
+fn config(level: Level) -> Config {
+	Config { chunk_size: 8192, data_offset: 16384, name: "test".into(), ..Config::new(level, 0x1234) }
+}
+
+fn finish<D: BlockDevice>(array: &mut Array<D>) {
+	while array.step(37).unwrap() {}
+	assert_eq!(array.action(), Action::Idle);
+}
+
+fn read_all<D: BlockDevice>(array: &mut Array<D>) -> Vec<u8> {
+	let mut buf = vec![0; array.blocks() as usize * array.block_size()];
+	array.read(0, &mut buf).unwrap();
+	buf
+}
+
+/// A new array filled with data, resynced and flushed.
+fn filled(level: Level, n: usize) -> (Array<RamDisk>, Vec<u8>) {
+	let mut array = Array::create(disks(n, SECTOR, 200), &config(level)).unwrap();
+	finish(&mut array);
+	let content = data(array.blocks() as usize * SECTOR, 7);
+	array.write(0, &content).unwrap();
+	array.flush().unwrap();
+	(array, content)
+}
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<Superblock>(), 256);
+}
+
+#[test]
+fn superblocks() {
+	let mut disk = fixture(6, 4).remove(2);
+	let (sb, roles) = probe(&mut disk).unwrap();
+	assert!(sb.is_valid());
+	assert_eq!(sb.name(), "fixture");
+	assert_eq!({ sb.level }, 6);
+	assert_eq!({ sb.layout }, LAYOUT_LEFT_SYMMETRIC);
+	assert_eq!({ sb.raid_disks }, 4);
+	assert_eq!({ sb.chunksize }, 16);
+	assert_eq!({ sb.data_offset }, 2048);
+	assert_eq!({ sb.size }, 64);
+	assert_eq!({ sb.dev_number }, 2);
+	assert_eq!({ sb.events }, 5);
+	assert_eq!({ sb.resync_offset }, MAX_SECTOR);
+	assert_eq!(roles, [0, 1, 2, 3]);
+
+	assert!(matches!(probe(&mut RamDisk::new(SECTOR, 64)), Err(Error::NoSuperblock)));
+	disk.as_mut_slice()[4096 + 100] ^= 1;
+	assert!(matches!(probe(&mut disk), Err(Error::Corrupted)));
+}
+
+#[test]
+fn assemble() {
+	for (level, n, blocks) in [(5, 3, 128), (6, 4, 128), (10, 3, 96)] {
+		let mut array = Array::assemble(fixture(level, n)).unwrap();
+		assert_eq!(array.level().md(), level);
+		assert_eq!(array.blocks(), blocks);
+		assert_eq!(array.chunk_size(), 8192);
+		assert_eq!(array.name(), "fixture");
+		assert_eq!(array.degraded(), 0);
+		assert!(array.is_clean());
+		assert_eq!(array.action(), Action::Idle);
+		check_fixture(&mut array);
+	}
+
+	let mut mixed = fixture(5, 3);
+	mixed[1] = fixture(6, 4).remove(1);
+	mixed[1].as_mut_slice()[4096 + 16] ^= 1;
+	assert!(Array::assemble(mixed).is_err());
+}
+
+#[test]
+fn degraded() {
+	for missing in 0..3 {
+		let mut disks = fixture(5, 3);
+		disks.remove(missing);
+		let mut array = Array::assemble(disks).unwrap();
+		assert_eq!(array.degraded(), 1);
+		assert_eq!(array.state(missing), Some(State::Missing));
+		check_fixture(&mut array);
+	}
+
+	// every pair of data, P and Q blocks
+	for a in 0..4 {
+		for b in a + 1..4 {
+			let disks = fixture(6, 4).into_iter().enumerate().filter(|(i, _)| *i != a && *i != b).map(|(_, d)| d).collect();
+			let mut array = Array::assemble(disks).unwrap();
+			assert_eq!(array.degraded(), 2);
+			check_fixture(&mut array);
+		}
+	}
+
+	for missing in 0..3 {
+		let mut disks = fixture(10, 3);
+		disks.remove(missing);
+		check_fixture(&mut Array::assemble(disks).unwrap());
+	}
+
+	assert!(matches!(Array::assemble(fixture(5, 3).split_off(1)[..1].to_vec()), Err(Error::Failed)));
+	assert!(matches!(Array::assemble(fixture(10, 3).split_off(1)[..1].to_vec()), Err(Error::Failed)));
+}
+
+#[test]
+fn create() {
+	for (level, n, block_size) in [(Level::Raid0, 3, 512), (Level::Raid1, 2, 4096), (Level::Raid5, 4, 512),
+		(Level::Raid6, 5, 4096), (Level::Raid10, 3, 512), (Level::Raid10, 4, 4096)] {
+		let blocks = 100 * 512 / block_size + 13;
+		let mut array = Array::create(disks(n, block_size, blocks), &config(level)).unwrap();
+		assert_eq!(array.action(), match level { Level::Raid0 => Action::Idle, _ => Action::Resync });
+		finish(&mut array);
+
+		let content = data(array.blocks() as usize * block_size, n as u32);
+		array.write(0, &content).unwrap();
+		// unaligned writes within and across chunks and stripes
+		let patch = data(5 * block_size, 99);
+		for lba in [1, 15, 33] {
+			if lba + 5 <= array.blocks() {
+				array.write(lba, &patch).unwrap();
+			}
+		}
+		let expected = read_all(&mut array);
+		array.flush().unwrap();
+		assert!(array.is_clean());
+
+		let mut disks = array.into_inner();
+		for disk in &mut disks {
+			let (sb, _) = probe(disk).unwrap();
+			assert_eq!({ sb.resync_offset }, MAX_SECTOR);
+			assert_eq!({ sb.level }, level.md());
+		}
+		let mut array = Array::assemble(disks).unwrap();
+		assert!(array.is_clean());
+		assert_eq!(read_all(&mut array), expected);
+		assert_ne!(expected, content);
+	}
+
+	assert!(matches!(Array::create(disks(3, SECTOR, 100), &config(Level::Raid6)), Err(Error::InvalidArgument)));
+	assert!(matches!(Array::create(disks(2, SECTOR, 20), &config(Level::Raid1)), Err(Error::InvalidArgument)));
+}
+
+#[test]
+fn rebuild() {
+	for (level, n, also_fail) in [(Level::Raid1, 2, &[1][..]), (Level::Raid5, 3, &[2]), (Level::Raid6, 4, &[1, 3]), (Level::Raid10, 4, &[1])] {
+		let (mut array, content) = filled(level, n);
+		array.fail(0).unwrap();
+		assert_eq!(array.state(0), Some(State::Faulty));
+		assert_eq!(read_all(&mut array), content);
+
+		// writes while degraded
+		let patch = data(20 * SECTOR, 3);
+		array.write(10, &patch).unwrap();
+		let mut content = content;
+		content[10 * SECTOR..30 * SECTOR].copy_from_slice(&patch);
+
+		assert!(array.remove(0).is_some());
+		assert_eq!(array.state(0), Some(State::Missing));
+		array.add(RamDisk::new(SECTOR, 200)).unwrap();
+		assert_eq!(array.state(0), Some(State::Rebuilding(0)));
+		assert_eq!(array.action(), Action::Rebuild);
+		array.step(10).unwrap();
+		assert_eq!(array.progress().0, 10);
+		finish(&mut array);
+		assert_eq!(array.state(0), Some(State::InSync));
+
+		for slot in also_fail {
+			array.fail(*slot).unwrap();
+		}
+		assert_eq!(read_all(&mut array), content, "{:?}", level);
+	}
+}
+
+#[test]
+fn spares() {
+	let (mut array, content) = filled(Level::Raid5, 3);
+	array.add(RamDisk::new(SECTOR, 200)).unwrap();
+	assert_eq!(array.spares(), 1);
+	assert!(matches!(array.start(Action::Check), Ok(())));
+
+	array.fail(1).unwrap();
+	assert_eq!(array.spares(), 0);
+	assert_eq!(array.state(1), Some(State::Rebuilding(0)));
+	// the rebuild takes priority over the check
+	assert_eq!(array.action(), Action::Rebuild);
+	assert!(matches!(array.start(Action::Idle), Err(Error::Busy)));
+
+	// the rebuild survives reassembly, from the last recorded progress
+	array.step(40).unwrap();
+	let mut array = Array::assemble(array.into_inner()).unwrap();
+	assert_eq!(array.state(1), Some(State::Rebuilding(0)));
+	finish(&mut array);
+	array.fail(0).unwrap();
+	assert_eq!(read_all(&mut array), content);
+}
+
+#[test]
+fn stale() {
+	let (mut array, content) = filled(Level::Raid5, 3);
+	array.fail(1).unwrap();
+	let patch = data(64 * SECTOR, 5);
+	array.write(0, &patch).unwrap();
+	array.flush().unwrap();
+
+	// the failed member comes back with an outdated superblock and is rebuilt
+	let mut array = Array::assemble(array.into_inner()).unwrap();
+	assert_eq!(array.state(1), Some(State::Rebuilding(0)));
+	finish(&mut array);
+	array.fail(2).unwrap();
+	let mut expected = content;
+	expected[..patch.len()].copy_from_slice(&patch);
+	assert_eq!(read_all(&mut array), expected);
+}
+
+#[test]
+fn resync() {
+	let (mut array, content) = filled(Level::Raid1, 2);
+	array.write(0, &content[..SECTOR]).unwrap();
+	assert!(!array.is_clean());
+
+	// not flushed, i.e. an unclean shutdown
+	let mut disks = array.into_inner();
+	assert_eq!({ probe(&mut disks[0]).unwrap().0.resync_offset }, 0);
+	let data_offset = config(Level::Raid1).data_offset as usize;
+	disks[1].as_mut_slice()[data_offset + 100] ^= 0xFF;
+
+	let mut array = Array::assemble(disks).unwrap();
+	assert_eq!(array.action(), Action::Resync);
+	finish(&mut array);
+	assert!(array.is_clean());
+	let mut disks = array.into_inner();
+	assert_eq!({ probe(&mut disks[1]).unwrap().0.resync_offset }, MAX_SECTOR);
+	assert_eq!(disks[0].as_slice()[data_offset..], disks[1].as_slice()[data_offset..]);
+}
+
+#[test]
+fn scrub() {
+	for (level, n) in [(Level::Raid1, 3), (Level::Raid6, 4), (Level::Raid10, 3)] {
+		let (array, content) = filled(level, n);
+		let mut disks = array.into_inner();
+		let data_offset = config(level).data_offset as usize;
+		disks[1].as_mut_slice()[data_offset + 3 * SECTOR + 7] ^= 0x5A;
+		let mut array = Array::assemble(disks).unwrap();
+
+		array.start(Action::Check).unwrap();
+		finish(&mut array);
+		assert!(array.mismatches() > 0, "{:?}", level);
+
+		array.start(Action::Repair).unwrap();
+		finish(&mut array);
+		array.start(Action::Check).unwrap();
+		finish(&mut array);
+		assert_eq!(array.mismatches(), 0);
+		// parity is recomputed from the data, mirrors are repaired from the first copy
+		if level != Level::Raid6 {
+			assert_eq!(read_all(&mut array), content);
+		}
+	}
+
+	let mut raid0 = Array::create(disks(2, SECTOR, 100), &config(Level::Raid0)).unwrap();
+	assert!(matches!(raid0.start(Action::Check), Err(Error::Unsupported)));
+	assert!(!raid0.step(10).unwrap());
+}
+
+/// A disk whose reads fail while `bad` is set
+struct Flaky {
+	disk: RamDisk,
+	bad:  Rc<Cell<bool>>
+}
+
+impl BlockDevice for Flaky {
+	fn block_size(&self) -> usize { self.disk.block_size() }
+	fn blocks(&self) -> u64 { self.disk.blocks() }
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		match self.bad.get() {
+			true  => Err(block::Error::Io),
+			false => self.disk.read(lba, buf)
+		}
+	}
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> { self.disk.write(lba, buf) }
+}
+
+#[test]
+fn read_errors() {
+	let bad = Rc::new(Cell::new(false));
+	let devices = (0..3).map(|_| Flaky { disk: RamDisk::new(SECTOR, 200), bad: Rc::new(Cell::new(false)) })
+		.enumerate()
+		.map(|(i, d)| match i { 0 => Flaky { bad: bad.clone(), ..d }, _ => d })
+		.collect();
+	let mut array = Array::create(devices, &config(Level::Raid5)).unwrap();
+	finish(&mut array);
+	let content = data(array.blocks() as usize * SECTOR, 11);
+	array.write(0, &content).unwrap();
+
+	// the blocks are reconstructed and rewritten, the member stays in the array
+	bad.set(true);
+	let mut buf = vec![0; 16 * SECTOR];
+	array.read(0, &mut buf).unwrap();
+	bad.set(false);
+	assert_eq!(buf, content[..buf.len()]);
+	assert_eq!(array.state(0), Some(State::InSync));
+	assert_eq!(read_all(&mut array), content);
+}
+
+/// Members of arrays made by `mdadm --create --metadata=1.2`, `generate.py` only writes
+/// them as root where mdadm is installed.
+#[test]
+#[ignore = "the members are not checked in, run generate.py as root where mdadm is installed"]
+fn mdadm_members() {
+	for (level, n) in [(1, 2), (5, 3)] {
+		let names = (0..n).map(|i| format!("raid/mdadm-raid{}-{}.img", level, i)).collect::<Vec<_>>();
+		let members = || names.iter().map(|name| RamDisk::from_vec(SECTOR, common::load_sparse(name))).collect::<Vec<_>>();
+
+		let mut disks = members();
+		let (sb, roles) = probe(&mut disks[1]).unwrap();
+		assert!(sb.is_valid());
+		assert_eq!(sb.name(), "fixture");
+		assert_eq!({ sb.level }, level);
+		assert_eq!({ sb.raid_disks } as usize, n);
+		assert_eq!({ sb.dev_number }, 1);
+		assert_eq!(roles, (0..n as u16).collect::<Vec<_>>());
+
+		let mut array = Array::assemble(disks).unwrap();
+		assert_eq!(array.level().md(), level);
+		assert_eq!(array.degraded(), 0);
+		assert!(array.is_clean());
+		check_fixture(&mut array);
+
+		for missing in 0..n {
+			let mut disks = members();
+			disks.remove(missing);
+			let mut array = Array::assemble(disks).unwrap();
+			assert_eq!(array.degraded(), 1);
+			check_fixture(&mut array);
+		}
+	}
+}
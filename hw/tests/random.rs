@@ -0,0 +1,81 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use hw::random::*;
+
+#[test]
+fn chacha20_rfc8439() {
+	// RFC 8439, 2.3.2
+	let key = [0x0302_0100, 0x0706_0504, 0x0b0a_0908, 0x0f0e_0d0c, 0x1312_1110, 0x1716_1514, 0x1b1a_1918, 0x1f1e_1d1c];
+	let nonce = [0x0900_0000, 0x4a00_0000, 0x0000_0000];
+	let expected = [
+		0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20, 0x71, 0xc4,
+		0xc7, 0xd1, 0xf4, 0xc7, 0x33, 0xc0, 0x68, 0x03, 0x04, 0x22, 0xaa, 0x9a, 0xc3, 0xd4, 0x6c, 0x4e,
+		0xd2, 0x82, 0x64, 0x46, 0x07, 0x9f, 0xaa, 0x09, 0x14, 0xc2, 0xd7, 0x05, 0xd9, 0x8b, 0x02, 0xa2,
+		0xb5, 0x12, 0x9c, 0xd1, 0xde, 0x16, 0x4e, 0xb9, 0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e
+	];
+	assert_eq!(chacha20_block(&key, 1, &nonce), expected);
+}
+
+#[test]
+fn pool() {
+	let mut pool = Pool::new();
+	assert!(!pool.is_seeded());
+	pool.add(&[1; 16], 1000);
+	assert_eq!(pool.entropy(), 128, "more than 8 bits per byte credited");
+	pool.add(&[2; 16], 0);
+	assert!(!pool.is_seeded());
+	pool.credit(128);
+	assert!(pool.is_seeded());
+	pool.credit(1000);
+	assert_eq!(pool.entropy(), SEED_BITS);
+
+	// the same input gives the same output, requests never repeat
+	let mut other = pool.clone();
+	let (mut a, mut b) = ([0; 100], [0; 100]);
+	pool.fill(&mut a);
+	other.fill(&mut b);
+	assert_eq!(a, b);
+	pool.fill(&mut b);
+	assert_ne!(a, b);
+	assert_ne!(a[..64], a[64..], "blocks repeat");
+
+	// mixed input changes the output
+	let mut other = pool.clone();
+	other.add(&[0], 0);
+	assert_ne!(pool.next_u64(), other.next_u64());
+
+	// requests larger than MAX_REQUEST are split without repeating
+	let mut big = vec![0; 2 * MAX_REQUEST];
+	pool.fill(&mut big);
+	assert_ne!(big[..64], big[MAX_REQUEST..MAX_REQUEST + 64]);
+	assert!(big.iter().any(|b| *b != 0));
+}
+
+#[test]
+fn jitter() {
+	assert_eq!(jitter_bits(&[]), 0);
+	assert_eq!(jitter_bits(&(0..100).map(|i| i * 10).collect::<Vec<_>>()), 0, "constant steps credited");
+	let samples = (0..34u64).map(|i| i * 10 + i * i % 7).collect::<Vec<_>>();
+	assert_eq!(jitter_bits(&samples), samples.windows(3).filter(|w| w[1] - w[0] != w[2] - w[1]).count() / 8);
+	assert!(jitter_bits(&samples) > 0);
+}
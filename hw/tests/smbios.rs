@@ -0,0 +1,58 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use hw::smbios::*;
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<SmBios2EntryPoint>(), 0x1F);
+	assert_eq!(core::mem::size_of::<SmBios3EntryPoint>(), 0x18);
+}
+
+#[test]
+fn entry_point_2() {
+	let buf = common::load("smbios/ep2.bin");
+	let ep = unsafe { (buf.as_ptr() as *const SmBios2EntryPoint).as_ref() }.unwrap();
+	assert!(ep.is_valid());
+	assert_eq!((ep.major_version, ep.minor_version), (2, 8));
+	assert_eq!({ ep.structure_table_length }, 0x144);
+	assert_eq!({ ep.structure_table_address }, 0xF0C00);
+	assert_eq!({ ep.number_of_structures }, 9);
+
+	buf[0x18] ^= 1;
+	assert!(!unsafe { (buf.as_ptr() as *const SmBios2EntryPoint).as_ref() }.unwrap().is_valid());
+}
+
+#[test]
+fn entry_point_3() {
+	let buf = common::load("smbios/ep3.bin");
+	let ep = unsafe { (buf.as_ptr() as *const SmBios3EntryPoint).as_ref() }.unwrap();
+	assert!(ep.is_valid());
+	assert_eq!((ep.major_version, ep.minor_version, ep.revision), (3, 0, 1));
+	assert_eq!({ ep.structure_table_max_size }, 0x14A);
+	assert_eq!({ ep.structure_table_address }, 0x7F8F0000);
+
+	buf[0] = b'X';
+	assert!(!unsafe { (buf.as_ptr() as *const SmBios3EntryPoint).as_ref() }.unwrap().is_valid());
+}
@@ -0,0 +1,67 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use hw::uefi::*;
+
+const DESCRIPTOR_SIZE: usize = 48;
+
+fn memory_map() -> MemoryMap<0x1000> {
+	let data = common::load("uefi/memory-map.bin");
+	let mut buf = [0u8; 0x1000];
+	buf[..data.len()].copy_from_slice(data);
+	MemoryMap::new(buf, data.len(), DESCRIPTOR_SIZE)
+}
+
+#[test]
+fn memory_map_stride() {
+	let map = memory_map();
+	let descs = map.into_iter().copied().collect::<Vec<_>>();
+	assert_eq!(descs.len(), 13);
+	assert_eq!(descs[0], MemoryDescriptor { r#type: MemoryType::BootServicesCode, physical_start: 0, virtual_start: 0, number_of_pages: 0xA0, attribute: 0xF });
+	assert_eq!(descs[7].r#type, MemoryType::ConventionalMemory);
+	assert_eq!(descs[7].physical_start, 0x860000);
+	assert_eq!(descs[7].number_of_pages, 0x7F000);
+	assert_eq!(descs[12].r#type, MemoryType::MemoryMappedIo);
+	assert_eq!(descs[12].attribute, 0xF | 1 << 63);
+}
+
+#[test]
+fn memory_map_totals() {
+	let map = memory_map();
+	let conventional = map.into_iter()
+		.filter(|d| d.r#type == MemoryType::ConventionalMemory)
+		.map(|d| d.number_of_pages)
+		.sum::<u64>();
+	assert_eq!(conventional, 0x60 + 0x8 + 0x7F000);
+
+	let runtime = map.into_iter().filter(|d| d.attribute & 1 << 63 != 0).count();
+	assert_eq!(runtime, 3);
+}
+
+#[test]
+fn guids() {
+	assert_eq!(ACPI_20_TABLE_GUID.to_le_bytes()[..4], [0x71, 0xE8, 0x68, 0x88]);
+	assert_eq!(<hw::acpi::RSDP as Table>::GUID, ACPI_20_TABLE_GUID);
+	assert_eq!(hw::gpt::EFI_SYSTEM_PARTITION_GUID.to_le_bytes()[..4], [0x28, 0x73, 0x2A, 0xC1]);
+}
@@ -0,0 +1,424 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::collections::BTreeMap;
+use common::virtio::*;
+use hw::{
+	devtree::FdtHeader,
+	dma::Region,
+	pcie::{Bar, BarKind, CapabilityRef, ConfigSpace, Device, PciAddress},
+	virtio::{*, mmio::{Mmio, Registers}, pci::{Capabilities, CommonConfig, Pci, Structure}}
+};
+
+macro_rules! offset_of {
+	($ty:ty, $field:ident) => {{
+		let v = std::mem::MaybeUninit::<$ty>::uninit();
+		let base = v.as_ptr();
+		unsafe { std::ptr::addr_of!((*base).$field) as usize - base as usize }
+	}};
+}
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<Descriptor>(), 16);
+	assert_eq!(core::mem::size_of::<PackedDescriptor>(), 16);
+	assert_eq!(core::mem::size_of::<UsedElem>(), 8);
+	assert_eq!(core::mem::size_of::<Registers>(), mmio::CONFIG_OFFSET);
+	assert_eq!(offset_of!(Registers, queue_notify), 0x50);
+	assert_eq!(offset_of!(Registers, status), 0x70);
+	assert_eq!(offset_of!(Registers, queue_device_low), 0xA0);
+	assert_eq!(offset_of!(Registers, queue_reset), 0xC0);
+	assert_eq!(offset_of!(Registers, config_generation), 0xFC);
+	assert_eq!(core::mem::size_of::<CommonConfig>(), 0x3C);
+	assert_eq!(offset_of!(CommonConfig, device_status), 0x14);
+	assert_eq!(offset_of!(CommonConfig, queue_notify_off), 0x1E);
+	assert_eq!(offset_of!(CommonConfig, queue_desc_low), 0x20);
+	assert_eq!(offset_of!(CommonConfig, queue_reset), 0x3A);
+}
+
+#[test]
+fn device_types() {
+	assert_eq!(DeviceType::from_id(1), Some(DeviceType::NetworkDevice));
+	assert_eq!(DeviceType::from_id(16), Some(DeviceType::Gpu));
+	assert_eq!(DeviceType::from_id(0), None);
+	assert_eq!(DeviceType::from_id(14), None);
+	assert_eq!(DeviceType::from_id(25), None);
+
+	for id in (1..=13).chain(16..=24) {
+		let ty = DeviceType::from_id(id).unwrap();
+		assert_eq!(ty as u32, id);
+	}
+	assert_eq!(DeviceType::_9pTransport.module(), "_9p_transport");
+	assert_eq!(DeviceType::EntropyDevice.module(), "entropy_device");
+}
+
+#[test]
+fn negotiation() {
+	let mock = Mock::new(DeviceType::EntropyDevice, FEATURE_VERSION_1 | FEATURE_RING_PACKED | 1 << 5 | 1 << 0);
+	let (mut t, mut dma) = (mock.clone(), mock.clone());
+	mock.0.borrow_mut().status = STATUS_DRIVER_OK;
+
+	assert_eq!(init(&mut t, &mut dma, 1 << 5 | FEATURE_EVENT_IDX), Ok(FEATURE_VERSION_1 | 1 << 5));
+	assert_eq!(mock.0.borrow().accepted, Some(FEATURE_VERSION_1 | 1 << 5));
+	assert_eq!(t.status(), STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+	t.driver_ok();
+	assert_eq!(t.status() & STATUS_DRIVER_OK, STATUS_DRIVER_OK);
+	assert!(!t.needs_reset());
+
+	// legacy devices don't offer VERSION_1
+	let mock = Mock::new(DeviceType::EntropyDevice, 1 << 0);
+	let (mut t, mut dma) = (mock.clone(), mock.clone());
+	assert_eq!(init(&mut t, &mut dma, !0), Err(Error::Unsupported));
+	assert_eq!(mock.0.borrow().accepted, None);
+	assert_ne!(t.status() & STATUS_FAILED, 0);
+
+	let mock = Mock::new(DeviceType::EntropyDevice, FEATURE_VERSION_1);
+	mock.0.borrow_mut().refuse = true;
+	let (mut t, mut dma) = (mock.clone(), mock.clone());
+	assert_eq!(init(&mut t, &mut dma, !0), Err(Error::Unsupported));
+	assert_ne!(t.status() & STATUS_FAILED, 0);
+
+	let mock = Mock::new(DeviceType::EntropyDevice, FEATURE_VERSION_1);
+	mock.0.borrow_mut().status = STATUS_ACKNOWLEDGE;
+	mock.0.borrow_mut().stuck = true;
+	let (mut t, mut dma) = (mock.clone(), mock.clone());
+	assert_eq!(init(&mut t, &mut dma, !0), Err(Error::Timeout));
+}
+
+#[test]
+fn config() {
+	let mut t = Mock::new(DeviceType::EntropyDevice, FEATURE_VERSION_1);
+	assert_eq!(t.read_config_u8(3), 3);
+	assert_eq!(t.read_config_u16(4), 0x0504);
+	assert_eq!(t.read_config_u32(8), 0x0B0A0908);
+	let mut buf = [0; 4];
+	t.read_config_bytes(16, &mut buf);
+	assert_eq!(buf, [16, 17, 18, 19]);
+	t.write_config(0, 2, 0xBEEF);
+	assert_eq!(&t.0.borrow().config[..3], [0xEF, 0xBE, 2]);
+
+	// the configuration changes between the halves, the value is read again
+	let reads = t.0.borrow().reads;
+	t.0.borrow_mut().changes = vec![reads + 1];
+	assert_eq!(t.read_config_u64(8), !0x0F0E0D0C_0B0A0908);
+	assert_eq!(t.config_generation(), 1);
+}
+
+fn reverse(data: &[u8]) -> Vec<u8> {
+	data.iter().rev().copied().collect()
+}
+
+fn queue(packed: bool) {
+	let mock = Mock::new(DeviceType::EntropyDevice, FEATURE_VERSION_1 | if packed { FEATURE_RING_PACKED } else { 0 });
+	let (mut t, mut dma) = (mock.clone(), mock.clone());
+	let features = init(&mut t, &mut dma, FEATURE_RING_PACKED).unwrap();
+	let mut queue = Virtqueue::new(&mut t, &mut dma, 0, 12, features & FEATURE_RING_PACKED != 0).unwrap();
+	assert_eq!(queue.is_packed(), packed);
+	// split queues are a power of two in size
+	assert_eq!(queue.size(), if packed { 12 } else { 8 });
+	assert_eq!(Virtqueue::new(&mut t, &mut dma, 0, 8, packed).unwrap_err(), Error::NoQueue);
+	assert_eq!(Virtqueue::new(&mut t, &mut dma, QUEUES, 8, packed).unwrap_err(), Error::NoQueue);
+	t.driver_ok();
+
+	// each chain reads a message and gets it back reversed in two buffers, the rings
+	// wrap several times
+	for round in 0..20 {
+		let messages = (0..1 + round % 2).map(|c| format!("round {} chain {}", round, c).into_bytes()).collect::<Vec<_>>();
+		let mut out = vec![[0u8; 24]; messages.len()];
+		let tokens = messages.iter().zip(out.iter_mut()).map(|(msg, out)| queue.push(&[
+			Buffer::read(msg.as_ptr() as u64, msg.len() as u32),
+			Buffer::write(out.as_mut_ptr() as u64, 12),
+			Buffer::write(out.as_mut_ptr() as u64 + 12, 12)
+		]).unwrap()).collect::<Vec<_>>();
+		queue.notify(&mut t);
+
+		assert_eq!(queue.pop(), None);
+		let reversed = round % 4 == 3;
+		assert_eq!(mock.process(0, reversed, |data, _| reverse(data)), messages.len());
+
+		let mut used = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+		if reversed {
+			used.reverse();
+		}
+		assert_eq!(used.iter().map(|(token, _)| *token).collect::<Vec<_>>(), tokens);
+		for (i, (_, len)) in used.iter().enumerate() {
+			assert_eq!(*len as usize, messages[i].len());
+			assert_eq!(out[i][..messages[i].len()], reverse(&messages[i]));
+		}
+		assert_eq!(queue.free_descriptors(), queue.size());
+	}
+	assert_eq!(mock.0.borrow().notified, [0; 20]);
+
+	let msg = [0u8; 4];
+	let (read, write) = (Buffer::read(msg.as_ptr() as u64, 4), Buffer::write(msg.as_ptr() as u64, 4));
+	assert_eq!(queue.push(&[]), Err(Error::InvalidArgument));
+	assert_eq!(queue.push(&[write, read]), Err(Error::InvalidArgument));
+	assert_eq!(queue.push(&vec![read; queue.size() as usize + 1]), Err(Error::InvalidArgument));
+
+	let mut pushed = 0;
+	while queue.push(&[read, write]) != Err(Error::NoMemory) {
+		pushed += 1;
+	}
+	assert_eq!(pushed, queue.size() / 2);
+
+	// the device doesn't want notifications
+	mock.suppress(0, true);
+	queue.notify(&mut t);
+	assert_eq!(mock.0.borrow().notified.len(), 20);
+	mock.suppress(0, false);
+
+	assert_eq!(mock.process(0, false, |_, _| Vec::new()), pushed as usize);
+	assert!(std::iter::from_fn(|| queue.pop()).all(|(_, len)| len == 0));
+	assert_eq!(queue.free_descriptors(), queue.size());
+
+	// the chain takes a single entry of the queue
+	let table = Region::alloc(&mut dma, 16 * 3, 16).unwrap();
+	let mut out = [0u8; 8];
+	let token = queue.push_indirect(&table, &[read, Buffer::read(msg.as_ptr() as u64, 2), Buffer::write(out.as_mut_ptr() as u64, 8)]).unwrap();
+	assert_eq!(queue.free_descriptors(), queue.size() - 1);
+	assert_eq!(queue.push_indirect(&table, &[read; 4]), Err(Error::InvalidArgument));
+	queue.notify(&mut t);
+	assert_eq!(mock.process(0, false, |data, len| { assert_eq!((data.len(), len), (6, 8)); vec![7; 3] }), 1);
+	assert_eq!(queue.pop(), Some((token, 3)));
+	assert_eq!(out, [7, 7, 7, 0, 0, 0, 0, 0]);
+	assert_eq!(mock.0.borrow().indirect, 1);
+	table.free(&mut dma);
+
+	queue.set_interrupts(false);
+	assert!(mock.interrupts_suppressed(0));
+	queue.set_interrupts(true);
+	assert!(!mock.interrupts_suppressed(0));
+
+	queue.free(&mut dma);
+	mock.check_freed();
+}
+
+#[test]
+fn split_queue() {
+	queue(false);
+}
+
+#[test]
+fn packed_queue() {
+	queue(true);
+}
+
+#[test]
+fn mmio() {
+	let mut mem = vec![0u32; 0x200 / 4];
+	let base = mem.as_mut_ptr() as *mut u8;
+	let reg = |offset: usize| unsafe { base.add(offset) as *mut u32 };
+	let (read, write) = (|offset| unsafe { reg(offset).read_volatile() }, |offset, v| unsafe { reg(offset).write_volatile(v) });
+
+	assert_eq!(unsafe { Mmio::new(base) }.unwrap_err(), Error::NoDevice);
+	write(0x00, mmio::MAGIC);
+	write(0x04, 1);
+	assert_eq!(unsafe { Mmio::new(base) }.unwrap_err(), Error::Unsupported);
+	write(0x04, mmio::VERSION);
+	// an empty slot
+	assert_eq!(unsafe { Mmio::new(base) }.unwrap_err(), Error::NoDevice);
+	write(0x08, DeviceType::BlockDevice as u32);
+	write(0x0C, 0x554D4551);
+
+	let mut t = unsafe { Mmio::new(base) }.unwrap();
+	assert_eq!(t.device_id(), 2);
+	assert_eq!(t.vendor_id(), 0x554D4551);
+
+	// the mock doesn't switch the halves, the driver reads both
+	write(0x10, 0x1234);
+	assert_eq!(t.device_features(), 0x1234_0000_1234);
+	assert_eq!(read(0x14), 1);
+	t.set_driver_features(FEATURE_VERSION_1 | 3);
+	assert_eq!((read(0x20), read(0x24)), (1, 1));
+
+	t.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+	assert_eq!(read(0x70), 3);
+	assert_eq!(t.status(), 3);
+
+	let mock = Mock::new(DeviceType::EntropyDevice, 0);
+	let mut dma = mock.clone();
+	assert_eq!(Virtqueue::new(&mut t, &mut dma, 1, 32, false).unwrap_err(), Error::NoQueue);
+	write(0x34, 64);
+	let queue = Virtqueue::new(&mut t, &mut dma, 1, 32, false).unwrap();
+	let desc = *mock.0.borrow().allocs.keys().next().unwrap() as u64;
+	assert_eq!(read(0x30), 1);
+	assert_eq!(read(0x38), 32);
+	assert_eq!(read(0x80) as u64 | (read(0x84) as u64) << 32, desc);
+	assert_eq!(read(0x90) as u64 | (read(0x94) as u64) << 32, desc + 32 * 16);
+	assert_eq!(read(0xA0) as u64 | (read(0xA4) as u64) << 32, desc + 32 * 16 + 72);
+	assert_eq!(read(0x44), 1);
+	assert_eq!(Virtqueue::new(&mut t, &mut dma, 1, 32, false).unwrap_err(), Error::NoQueue);
+
+	queue.notify(&mut t);
+	assert_eq!(read(0x50), 1);
+
+	write(0x60, INTERRUPT_QUEUE | INTERRUPT_CONFIG);
+	assert_eq!(t.ack_interrupt(), 3);
+	assert_eq!(read(0x64), 3);
+
+	write(0x100, 0x04030201);
+	write(0xFC, 7);
+	assert_eq!(t.read_config_u8(1), 2);
+	assert_eq!(t.read_config_u16(2), 0x0403);
+	assert_eq!(t.config_generation(), 7);
+	t.write_config(4, 2, 0xABCD);
+	assert_eq!(read(0x104), 0xABCD);
+
+	queue.free(&mut dma);
+	mock.check_freed();
+}
+
+#[test]
+fn mmio_devtree() {
+	let fdt = unsafe { (common::load("dtb/riscv64-virt.dtb").as_ptr() as *const FdtHeader).as_ref() }.unwrap();
+	let nodes = mmio::find(fdt);
+	assert_eq!(nodes.iter().map(|n| (n.base, n.size, n.interrupts.clone())).collect::<Vec<_>>(),
+		(1..=8).rev().map(|i| (0x10000000 + i * 0x1000, 0x1000, vec![i as u32])).collect::<Vec<_>>());
+
+	// the device nodes are at the root, with GIC interrupt specifiers
+	let fdt = unsafe { (common::load("dtb/aarch64-virt.dtb").as_ptr() as *const FdtHeader).as_ref() }.unwrap();
+	let nodes = mmio::find(fdt);
+	assert_eq!(nodes.len(), 4);
+	assert!(nodes.iter().all(|n| n.size == 0x200 && n.interrupts.len() == 3 && n.interrupts[0] == 0));
+}
+
+/// A configuration space with the given dwords, zero elsewhere.
+struct Config(BTreeMap<u16, u32>);
+
+impl ConfigSpace for Config {
+	fn read(&mut self, _address: PciAddress, offset: u16) -> u32 {
+		self.0.get(&offset).copied().unwrap_or(0)
+	}
+
+	fn write(&mut self, _address: PciAddress, offset: u16, value: u32) {
+		self.0.insert(offset, value);
+	}
+}
+
+fn vendor_cap(cfg: &mut Config, offset: u16, len: u8, cfg_type: u8, bar: u8, off: u32, length: u32) {
+	cfg.0.insert(offset, 0x09 | (len as u32) << 16 | (cfg_type as u32) << 24);
+	cfg.0.insert(offset + 4, bar as u32);
+	cfg.0.insert(offset + 8, off);
+	cfg.0.insert(offset + 12, length);
+}
+
+#[test]
+fn pci() {
+	let mut cfg = Config(BTreeMap::new());
+	vendor_cap(&mut cfg, 0x40, 16, pci::CAP_COMMON_CFG, 4, 0, 0x1000);
+	vendor_cap(&mut cfg, 0x50, 16, pci::CAP_ISR_CFG, 4, 0x1000, 0x1000);
+	vendor_cap(&mut cfg, 0x60, 16, pci::CAP_DEVICE_CFG, 4, 0x2000, 0x1000);
+	vendor_cap(&mut cfg, 0x70, 20, pci::CAP_NOTIFY_CFG, 4, 0x3000, 0x1000);
+	cfg.0.insert(0x80, 4);
+	vendor_cap(&mut cfg, 0x88, 20, pci::CAP_PCI_CFG, 0, 0, 0);
+	// only the first structure of a type is used
+	vendor_cap(&mut cfg, 0x9C, 16, pci::CAP_COMMON_CFG, 2, 0, 0x1000);
+
+	let mut device = Device {
+		address:          PciAddress { segment: 0, bus: 1, device: 0, function: 0 },
+		vendor_id:        pci::VENDOR_ID,
+		device_id:        pci::DEVICE_ID_BASE + 1,
+		vendor_name:      "Red Hat, Inc.",
+		class_code:       0x02,
+		subclass:         0x00,
+		prog_if:          0x00,
+		revision_id:      1,
+		header_type:      0,
+		subsys_vendor_id: pci::VENDOR_ID,
+		subsys_id:        0x1100,
+		interrupt_pin:    1,
+		bars:             [None, None, None, None, Some(Bar { kind: BarKind::Memory64, prefetchable: true,
+			address: 0xFE000000, size: 0x4000 }), None],
+		capabilities:     [0x40, 0x50, 0x60, 0x70, 0x88, 0x9C].iter()
+			.map(|&offset| CapabilityRef { id: 0x09, offset, extended: false })
+			.chain([CapabilityRef { id: 0x11, offset: 0xB0, extended: false }])
+			.collect(),
+		bridge:           None,
+		children:         Vec::new()
+	};
+
+	let caps = Capabilities::read(&mut cfg, &device);
+	assert_eq!(caps.common, Some(Structure { bar: 4, offset: 0, length: 0x1000 }));
+	assert_eq!(caps.notify, Some(Structure { bar: 4, offset: 0x3000, length: 0x1000 }));
+	assert_eq!(caps.notify_multiplier, 4);
+	assert_eq!(caps.bars(), [4]);
+
+	assert_eq!(pci::device_id(&device), Some(1));
+	device.device_id = 0x1000;
+	device.subsys_id = 1;
+	assert_eq!(pci::device_id(&device), Some(1));
+	device.device_id = 0x1100;
+	assert_eq!(pci::device_id(&device), None);
+	device.device_id = pci::DEVICE_ID_BASE + 1;
+
+	let mut mem = vec![0u32; 0x4000 / 4];
+	let base = mem.as_mut_ptr() as *mut u8;
+	let mut bars = [core::ptr::null_mut(); 6];
+	assert_eq!(unsafe { Pci::new(&device, &caps, &bars) }.unwrap_err(), Error::NoDevice);
+	bars[4] = base;
+	device.bars[4].as_mut().unwrap().size = 0x2000;
+	assert_eq!(unsafe { Pci::new(&device, &caps, &bars) }.unwrap_err(), Error::NoDevice);
+	device.bars[4].as_mut().unwrap().size = 0x4000;
+	let mut t = unsafe { Pci::new(&device, &caps, &bars) }.unwrap();
+	assert_eq!(t.device_id(), 1);
+
+	let common = base as *mut CommonConfig;
+	unsafe {
+		(*common).num_queues = QUEUES;
+		(*common).queue_size = 64;
+		(*common).queue_notify_off = 5;
+		(*common).device_feature = 0x20;
+	}
+	assert_eq!(t.device_features(), 0x20_0000_0020);
+	t.set_status(STATUS_ACKNOWLEDGE);
+	assert_eq!(unsafe { base.add(0x14).read() }, STATUS_ACKNOWLEDGE);
+	assert_eq!(t.max_queue_size(QUEUES), 0);
+
+	assert!(t.set_vectors(3));
+	assert_eq!(unsafe { (*common).config_msix_vector }, 0);
+
+	let mock = Mock::new(DeviceType::EntropyDevice, 0);
+	let mut dma = mock.clone();
+	let queue = Virtqueue::new(&mut t, &mut dma, 1, 16, true).unwrap();
+	let desc = *mock.0.borrow().allocs.keys().next().unwrap() as u64;
+	unsafe {
+		assert_eq!((*common).queue_select, 1);
+		assert_eq!((*common).queue_size, 16);
+		assert_eq!((*common).queue_msix_vector, 2);
+		assert_eq!((*common).queue_enable, 1);
+		assert_eq!((*common).queue_desc_low as u64 | ((*common).queue_desc_high as u64) << 32, desc);
+		assert_eq!((*common).queue_device_low as u64 | ((*common).queue_device_high as u64) << 32, desc + 16 * 16 + 4);
+	}
+
+	queue.notify(&mut t);
+	assert_eq!(unsafe { (base.add(0x3000 + 5 * 4) as *const u16).read() }, 1);
+
+	unsafe { base.add(0x1000).write(INTERRUPT_QUEUE as u8) };
+	assert_eq!(t.ack_interrupt(), INTERRUPT_QUEUE);
+	unsafe { (base.add(0x2000) as *mut u32).write(0xCAFE) };
+	assert_eq!(t.read_config_u32(0), 0xCAFE);
+
+	queue.free(&mut dma);
+	mock.check_freed();
+}
@@ -0,0 +1,107 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+use common::{p9::Server, virtio::*};
+use hw::{p9::{self, Channel, Client}, virtio::{*, _9p_transport::*}};
+
+/// A 9P device sharing `server` as `tag`.
+fn device(features: u64, tag: &str, server: Rc<RefCell<Server>>) -> Mock {
+	let mock = Mock::new(DeviceType::_9pTransport, FEATURE_VERSION_1 | FEATURE_MOUNT_TAG | features);
+	let mut s = mock.0.borrow_mut();
+	s.count = 1;
+	s.config[..2].copy_from_slice(&(tag.len() as u16).to_le_bytes());
+	s.config[2..2 + tag.len()].copy_from_slice(tag.as_bytes());
+	s.handler = Some(Rc::new(move |queue, data: &[u8], len| {
+		assert_eq!(queue, 0);
+		let response = server.borrow_mut().handle(data);
+		assert!(response.len() <= len, "response larger than the buffer");
+		response
+	}));
+	drop(s);
+	mock
+}
+
+#[test]
+fn init() {
+	let block = Mock::new(DeviceType::BlockDevice, FEATURE_VERSION_1);
+	assert!(matches!(Virtio9p::new(block.clone(), block), Err(Error::NoDevice)));
+
+	let mock = device(0, "share", Rc::new(RefCell::new(Server::new(p9::MAX_MSIZE))));
+	let dev = Virtio9p::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(dev.tag(), "share");
+	assert_ne!(dev.features() & FEATURE_MOUNT_TAG, 0);
+	assert_eq!(dev.max_size(), p9::MAX_MSIZE);
+	assert_ne!(mock.0.borrow().status & STATUS_DRIVER_OK, 0);
+	assert!(mock.interrupts_suppressed(0));
+	drop(dev);
+	assert_eq!(mock.0.borrow().status, 0, "device not reset");
+	mock.check_freed();
+}
+
+fn share(packed: bool) {
+	let server = Rc::new(RefCell::new(Server::new(p9::MAX_MSIZE)));
+	server.borrow_mut().add("file", false, b"hello");
+	let mock = device(if packed { FEATURE_RING_PACKED } else { 0 }, "host", server.clone());
+	let dev = Virtio9p::new(mock.clone(), mock.clone()).unwrap();
+	let mut client = Client::new(dev, "root", "", 0).unwrap();
+	assert_eq!(client.msize(), p9::MAX_MSIZE);
+
+	let mut buf = [0; 16];
+	assert_eq!(client.read_at("file", 0, &mut buf).unwrap(), 5);
+	assert_eq!(&buf[..5], b"hello");
+	// messages as large as negotiated, more requests than the queue has entries
+	let data = vec![0xA5; 3 * p9::MAX_MSIZE];
+	assert_eq!(client.write_at("file", 0, &data).unwrap(), data.len());
+	assert_eq!(server.borrow().nodes["file"].data, data);
+	client.create_path("dir", true).unwrap();
+	assert_eq!(client.list("/").unwrap().len(), 2);
+	drop(client);
+	assert!(server.borrow().fids.is_empty());
+	mock.check_freed();
+}
+
+#[test]
+fn share_split() {
+	share(false);
+}
+
+#[test]
+fn share_packed() {
+	share(true);
+}
+
+#[test]
+fn timeout() {
+	let mock = device(0, "", Rc::new(RefCell::new(Server::new(p9::MAX_MSIZE))));
+	mock.0.borrow_mut().handler = None;
+	let mut dev = Virtio9p::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(dev.tag(), "");
+	let request = p9::Writer::new(p9::TVERSION, p9::NOTAG).u32(4096).str(p9::VERSION).finish();
+	assert_eq!(dev.transfer(&request, &mut [0; 64]), Err(Error::Timeout));
+	assert_eq!(dev.request(&request, &mut [0; 64]), Err(p9::Error::Transport));
+	assert_eq!(dev.transfer(&[0; p9::MAX_MSIZE + 1], &mut [0; 64]), Err(Error::InvalidArgument));
+	drop(dev);
+	mock.check_freed();
+}
@@ -0,0 +1,134 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+use common::virtio::*;
+use hw::virtio::{*, memory_ballooning::*};
+
+type Log = Rc<RefCell<Vec<(u16, Vec<u8>, usize)>>>;
+
+/// A balloon that wants `target` pages, its handler serves all queues but the statistics
+/// queue and logs the requests.
+fn device(features: u64, target: u32) -> (Mock, Log) {
+	let mock = Mock::new(DeviceType::MemoryBallooning, FEATURE_VERSION_1 | features);
+	let log = Log::default();
+	let requests = log.clone();
+	let mut s = mock.0.borrow_mut();
+	s.count = 4;
+	s.config = vec![0; 16];
+	s.config[CONFIG_NUM_PAGES..CONFIG_NUM_PAGES + 4].copy_from_slice(&target.to_le_bytes());
+	s.serve = vec![0, 1, 3];
+	s.handler = Some(Rc::new(move |queue, data: &[u8], len| {
+		requests.borrow_mut().push((queue, data.to_vec(), len));
+		Vec::new()
+	}));
+	drop(s);
+	(mock, log)
+}
+
+fn pfns(data: &[u8]) -> Vec<u32> {
+	data.chunks_exact(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect()
+}
+
+fn actual(mock: &Mock) -> u32 {
+	u32::from_le_bytes(mock.0.borrow().config[CONFIG_ACTUAL..CONFIG_ACTUAL + 4].try_into().unwrap())
+}
+
+#[test]
+fn inflate_deflate() {
+	let block = Mock::new(DeviceType::BlockDevice, FEATURE_VERSION_1);
+	assert!(matches!(Balloon::new(block.clone(), block), Err(Error::NoDevice)));
+
+	let (mock, log) = device(FEATURE_MUST_TELL_HOST, 300);
+	let mut balloon = Balloon::new(mock.clone(), mock.clone()).unwrap();
+	assert!(balloon.must_tell_host() && !balloon.deflate_on_oom());
+	assert_eq!((balloon.target(), balloon.actual(), balloon.delta()), (300, 0, 300));
+	assert_eq!(balloon.report_capacity(), 0);
+	assert!(!balloon.stats_requested());
+	assert!(matches!(balloon.report(&[(0x1000, 4096)]), Err(Error::Unsupported)));
+
+	let pages = (100..356).collect::<Vec<u32>>();
+	balloon.inflate(&pages).unwrap();
+	balloon.inflate(&pages[..44]).unwrap();
+	assert!(matches!(balloon.inflate(&[0; PFNS_PER_REQUEST + 1]), Err(Error::InvalidArgument)));
+	assert_eq!((balloon.actual(), balloon.delta(), actual(&mock)), (300, 0, 300));
+
+	// the host wants some back
+	mock.0.borrow_mut().config[CONFIG_NUM_PAGES..CONFIG_NUM_PAGES + 4].copy_from_slice(&200u32.to_le_bytes());
+	assert_eq!(balloon.delta(), -100);
+	balloon.deflate(&pages[..100]).unwrap();
+	assert_eq!((balloon.actual(), actual(&mock)), (200, 200));
+
+	let log = log.borrow();
+	assert_eq!(log.iter().map(|(q, d, _)| (*q, d.len() / 4)).collect::<Vec<_>>(), [(0, 256), (0, 44), (1, 100)]);
+	assert_eq!(pfns(&log[0].1), pages);
+	assert_eq!(pfns(&log[2].1), pages[..100]);
+	drop(balloon);
+	mock.check_freed();
+}
+
+#[test]
+fn stats() {
+	let (mock, _) = device(FEATURE_STATS_VQ, 0);
+	let mut balloon = Balloon::new(mock.clone(), mock.clone()).unwrap();
+	assert!(balloon.stats_requested(), "the first statistics aren't sent unasked");
+	assert!(balloon.send_stats(&[(STAT_MEMFREE, 1 << 30), (STAT_MEMTOT, 1 << 32)]).unwrap());
+	assert!(!balloon.stats_requested());
+	assert!(!balloon.send_stats(&[]).unwrap(), "sent while the device holds the statistics");
+
+	let received = RefCell::new(Vec::new());
+	assert_eq!(mock.process(2, false, |data, _| {
+		received.borrow_mut().push(data.to_vec());
+		Vec::new()
+	}), 1);
+	let received = received.into_inner();
+	assert_eq!(received.len(), 1);
+	assert_eq!(received[0].len(), 2 * STAT_LEN);
+	assert_eq!(received[0][..2], STAT_MEMFREE.to_le_bytes());
+	assert_eq!(received[0][2..10], (1u64 << 30).to_le_bytes());
+	assert_eq!(received[0][10..12], STAT_MEMTOT.to_le_bytes());
+
+	assert!(balloon.stats_requested());
+	assert!(balloon.send_stats(&[(STAT_AVAIL, 1)]).unwrap());
+	assert!(matches!(balloon.send_stats(&[(0, 0); MAX_STATS + 1]), Err(Error::InvalidArgument)));
+}
+
+#[test]
+fn reporting() {
+	// without statistics the reporting queue is the third one
+	let (mock, log) = device(FEATURE_PAGE_REPORTING, 0);
+	mock.0.borrow_mut().serve = vec![2];
+	let mut balloon = Balloon::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(balloon.report_capacity(), 16);
+	balloon.report(&[(0x20_0000, 0x20_0000), (0x60_0000, 0x20_0000)]).unwrap();
+	assert!(matches!(balloon.report(&[(0, 0); 17]), Err(Error::InvalidArgument)));
+	assert_eq!(*log.borrow(), [(2, Vec::new(), 0x40_0000)]);
+	drop(balloon);
+	mock.check_freed();
+
+	let (mock, log) = device(FEATURE_STATS_VQ | FEATURE_PAGE_REPORTING, 0);
+	let mut balloon = Balloon::new(mock.clone(), mock.clone()).unwrap();
+	balloon.report(&[(0x20_0000, 0x20_0000)]).unwrap();
+	assert_eq!(log.borrow()[0].0, 3);
+}
@@ -0,0 +1,271 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+use common::virtio::*;
+use hw::virtio::{*, block_device::*};
+
+const SECTORS: u64 = 2048;
+
+/// A request the device received: type, sector, data length and the sectors of a
+/// discard or write zeroes segment with its flags.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Request {
+	ty:      u32,
+	sector:  u64,
+	len:     usize,
+	segment: Option<(u64, u32, u32)>
+}
+
+#[derive(Default)]
+struct Blk {
+	disk:     Vec<u8>,
+	requests: Vec<Request>,
+	/// Status to answer the next requests with instead of serving them
+	status:   Option<u8>
+}
+
+fn config(blk_size: u32, seg_max: u32, size_max: u32, queues: u16, max_discard: u32, max_write_zeroes: u32) -> Vec<u8> {
+	let mut config = vec![0; 64];
+	config[CONFIG_CAPACITY..CONFIG_CAPACITY + 8].copy_from_slice(&SECTORS.to_le_bytes());
+	config[CONFIG_SIZE_MAX..CONFIG_SIZE_MAX + 4].copy_from_slice(&size_max.to_le_bytes());
+	config[CONFIG_SEG_MAX..CONFIG_SEG_MAX + 4].copy_from_slice(&seg_max.to_le_bytes());
+	config[CONFIG_BLK_SIZE..CONFIG_BLK_SIZE + 4].copy_from_slice(&blk_size.to_le_bytes());
+	config[CONFIG_NUM_QUEUES..CONFIG_NUM_QUEUES + 2].copy_from_slice(&queues.to_le_bytes());
+	config[CONFIG_MAX_DISCARD_SECTORS..CONFIG_MAX_DISCARD_SECTORS + 4].copy_from_slice(&max_discard.to_le_bytes());
+	config[CONFIG_MAX_WRITE_ZEROES_SECTORS..CONFIG_MAX_WRITE_ZEROES_SECTORS + 4].copy_from_slice(&max_write_zeroes.to_le_bytes());
+	config
+}
+
+/// A block device with the given features and configuration, the disk is filled with
+/// the low byte of the sector number.
+fn device(features: u64, config: Vec<u8>) -> (Mock, Rc<RefCell<Blk>>) {
+	let mock = Mock::new(DeviceType::BlockDevice, FEATURE_VERSION_1 | features);
+	let blk = Rc::new(RefCell::new(Blk {
+		disk: (0..SECTORS as usize * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect(),
+		..Blk::default()
+	}));
+
+	let state = blk.clone();
+	let mut s = mock.0.borrow_mut();
+	s.config = config;
+	s.handler = Some(Rc::new(move |_, data: &[u8], len| {
+		let mut blk = state.borrow_mut();
+		let ty = u32::from_le_bytes(data[0..4].try_into().unwrap());
+		let sector = u64::from_le_bytes(data[8..16].try_into().unwrap());
+		let body = &data[16..];
+		let segment = match ty {
+			REQ_DISCARD | REQ_WRITE_ZEROES => Some((u64::from_le_bytes(body[0..8].try_into().unwrap()),
+				u32::from_le_bytes(body[8..12].try_into().unwrap()), u32::from_le_bytes(body[12..16].try_into().unwrap()))),
+			_ => None
+		};
+		blk.requests.push(Request { ty, sector, len: body.len().max(len - 1), segment });
+
+		if let Some(status) = blk.status {
+			let mut response = vec![0; len];
+			response[len - 1] = status;
+			return response;
+		}
+
+		let offset = sector as usize * SECTOR_SIZE;
+		match ty {
+			REQ_IN => [&blk.disk[offset..offset + len - 1], &[STATUS_OK]].concat(),
+			REQ_OUT => {
+				blk.disk[offset..offset + body.len()].copy_from_slice(body);
+				vec![STATUS_OK]
+			}
+			REQ_FLUSH => vec![STATUS_OK],
+			REQ_GET_ID => [&b"virtio-blk-1"[..], &[0; 8], &[STATUS_OK]].concat(),
+			REQ_DISCARD | REQ_WRITE_ZEROES => {
+				let (sector, sectors, _) = segment.unwrap();
+				let range = sector as usize * SECTOR_SIZE..(sector + sectors as u64) as usize * SECTOR_SIZE;
+				blk.disk[range].fill(if ty == REQ_DISCARD { 0xDD } else { 0 });
+				vec![STATUS_OK]
+			}
+			_ => vec![STATUS_UNSUPP]
+		}
+	}));
+	drop(s);
+	(mock, blk)
+}
+
+fn requests(blk: &Rc<RefCell<Blk>>) -> Vec<Request> {
+	std::mem::take(&mut blk.borrow_mut().requests)
+}
+
+fn check_freed<T: Transport, D: hw::dma::Dma>(mock: Mock, disk: Disk<T, D>) {
+	drop(disk);
+	assert_eq!(mock.0.borrow().status, 0, "device not reset");
+	mock.check_freed();
+}
+
+#[test]
+fn init() {
+	let features = FEATURE_MQ | FEATURE_BLK_SIZE | FEATURE_FLUSH | FEATURE_INDIRECT_DESC;
+	let (mock, _) = device(features, config(4096, 0, 0, 4, 0, 0));
+	let disk = Disk::new(mock.clone(), mock.clone(), 8).unwrap();
+	assert_eq!(disk.queues(), QUEUES as usize);
+	assert_eq!(disk.block_size(), 4096);
+	assert_eq!(disk.blocks(), SECTORS / 8);
+	assert!(disk.supports_flush() && !disk.supports_discard() && !disk.is_read_only());
+	assert_eq!(disk.features(), FEATURE_VERSION_1 | features);
+	assert_eq!(disk.max_transfer(), (MAX_SEGMENTS - 1) * 4096);
+	assert_ne!(mock.0.borrow().status & STATUS_DRIVER_OK, 0);
+	assert!(mock.interrupts_suppressed(0));
+	check_freed(mock, disk);
+
+	// a single queue without MQ, chains limited by the queue without indirect descriptors
+	let (mock, _) = device(0, config(4096, 0, 0, 4, 0, 0));
+	let disk = Disk::new(mock.clone(), mock.clone(), 8).unwrap();
+	assert_eq!((disk.queues(), disk.block_size()), (1, 512));
+	assert_eq!(disk.max_transfer(), 13 * 4096);
+	check_freed(mock, disk);
+
+	let (mock, _) = device(FEATURE_BLK_SIZE, config(1000, 0, 0, 1, 0, 0));
+	assert_eq!(Disk::new(mock.clone(), mock.clone(), 1).unwrap_err(), Error::Unsupported);
+	mock.check_freed();
+
+	let mock = Mock::new(DeviceType::NetworkDevice, FEATURE_VERSION_1);
+	assert_eq!(Disk::new(mock.clone(), mock.clone(), 1).unwrap_err(), Error::NoDevice);
+}
+
+fn read_write(packed: bool) {
+	let features = FEATURE_MQ | FEATURE_SEG_MAX | FEATURE_SIZE_MAX | FEATURE_INDIRECT_DESC
+		| if packed { FEATURE_RING_PACKED } else { 0 };
+	// segments end at page boundaries, requests have at most 8 of them
+	let (mock, blk) = device(features, config(0, 8, 4096, 2, 0, 0));
+	let mut disk = Disk::new(mock.clone(), mock.clone(), 2).unwrap();
+	assert_eq!(disk.max_transfer(), 7 * 4096);
+
+	let mut buf = vec![0; 2 * 4096];
+	disk.read(1, 8, &mut buf).unwrap();
+	assert!(buf.chunks(SECTOR_SIZE).enumerate().all(|(i, s)| s.iter().all(|&b| b as usize == 8 + i)));
+	assert_eq!(requests(&blk), [Request { ty: REQ_IN, sector: 8, len: 8192, segment: None }]);
+
+	// split into requests of at most 7 pages
+	let data = (0..16 * 4096).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+	disk.write(0, 100, &data).unwrap();
+	assert_eq!(requests(&blk).iter().map(|r| (r.ty, r.sector, r.len)).collect::<Vec<_>>(),
+		[(REQ_OUT, 100, 7 * 4096), (REQ_OUT, 156, 7 * 4096), (REQ_OUT, 212, 2 * 4096)]);
+	assert_eq!(blk.borrow().disk[100 * SECTOR_SIZE..100 * SECTOR_SIZE + data.len()], data);
+
+	let mut buf = vec![0; data.len()];
+	disk.read(0, 100, &mut buf).unwrap();
+	assert_eq!(buf, data);
+	assert_eq!(requests(&blk).len(), 3);
+
+	// the header, the pages of the buffer and the status don't fit into the queue directly
+	assert!(mock.0.borrow().indirect >= 6);
+	let indirect = mock.0.borrow().indirect;
+	disk.read(0, 0, &mut buf[..512]).unwrap();
+	assert_eq!(mock.0.borrow().indirect, indirect);
+	check_freed(mock, disk);
+}
+
+#[test]
+fn split() {
+	read_write(false);
+}
+
+#[test]
+fn packed() {
+	read_write(true);
+}
+
+#[test]
+fn direct() {
+	// without indirect descriptors, the chain of a request is limited by the queue
+	let (mock, blk) = device(FEATURE_SIZE_MAX, config(0, 0, 4096, 1, 0, 0));
+	mock.0.borrow_mut().max = 8;
+	let mut disk = Disk::new(mock.clone(), mock.clone(), 1).unwrap();
+	assert_eq!(disk.max_transfer(), 5 * 4096);
+
+	let data = vec![0xA5; 12 * 4096];
+	disk.write(0, 0, &data).unwrap();
+	assert_eq!(requests(&blk).iter().map(|r| r.len).collect::<Vec<_>>(), [5 * 4096, 5 * 4096, 2 * 4096]);
+	assert_eq!(mock.0.borrow().indirect, 0);
+	check_freed(mock, disk);
+}
+
+#[test]
+fn flush_discard_write_zeroes() {
+	let features = FEATURE_BLK_SIZE | FEATURE_FLUSH | FEATURE_DISCARD | FEATURE_WRITE_ZEROES;
+	let (mock, blk) = device(features, config(4096, 0, 0, 1, 64, 1 << 20));
+	let mut disk = Disk::new(mock.clone(), mock.clone(), 1).unwrap();
+
+	disk.flush(0).unwrap();
+	assert_eq!(requests(&blk), [Request { ty: REQ_FLUSH, sector: 0, len: 0, segment: None }]);
+
+	// 20 blocks of 8 sectors in pieces of at most 64 sectors
+	disk.discard(0, 10, 20).unwrap();
+	assert_eq!(requests(&blk).iter().map(|r| (r.ty, r.segment.unwrap())).collect::<Vec<_>>(),
+		[(REQ_DISCARD, (80, 64, 0)), (REQ_DISCARD, (144, 64, 0)), (REQ_DISCARD, (208, 32, 0))]);
+
+	disk.write_zeroes(0, 1, 2, true).unwrap();
+	assert_eq!(requests(&blk).iter().map(|r| (r.ty, r.segment.unwrap())).collect::<Vec<_>>(),
+		[(REQ_WRITE_ZEROES, (8, 16, SEGMENT_FLAG_UNMAP))]);
+	assert!(blk.borrow().disk[8 * SECTOR_SIZE..24 * SECTOR_SIZE].iter().all(|&b| b == 0));
+	assert_eq!(blk.borrow().disk[24 * SECTOR_SIZE], 24);
+
+	assert_eq!(disk.discard(0, 250, 10), Err(Error::InvalidArgument));
+	assert_eq!(disk.id(0).unwrap(), b"virtio-blk-1");
+	check_freed(mock, disk);
+
+	// discard is a hint, write zeroes and flush need the features
+	let (mock, blk) = device(0, config(0, 0, 0, 1, 64, 64));
+	let mut disk = Disk::new(mock.clone(), mock.clone(), 1).unwrap();
+	disk.flush(0).unwrap();
+	disk.discard(0, 0, 8).unwrap();
+	assert_eq!(disk.write_zeroes(0, 0, 8, false), Err(Error::Unsupported));
+	assert!(requests(&blk).is_empty());
+	check_freed(mock, disk);
+}
+
+#[test]
+fn errors() {
+	let (mock, blk) = device(FEATURE_RO, config(0, 0, 0, 1, 0, 0));
+	let mut disk = Disk::new(mock.clone(), mock.clone(), 1).unwrap();
+	assert!(disk.is_read_only());
+
+	let mut buf = vec![0; 1024];
+	assert_eq!(disk.write(0, 0, &buf), Err(Error::ReadOnly));
+	assert_eq!(disk.read(0, SECTORS - 1, &mut buf), Err(Error::InvalidArgument));
+	assert_eq!(disk.read(0, 0, &mut buf[..100]), Err(Error::InvalidArgument));
+	assert_eq!(disk.read(1, 0, &mut buf), Err(Error::InvalidArgument));
+	assert!(requests(&blk).is_empty());
+
+	blk.borrow_mut().status = Some(STATUS_IOERR);
+	assert_eq!(disk.read(0, 0, &mut buf), Err(Error::Device));
+	blk.borrow_mut().status = Some(STATUS_UNSUPP);
+	assert_eq!(disk.id(0), Err(Error::Unsupported));
+	blk.borrow_mut().status = None;
+	disk.read(0, 1, &mut buf).unwrap();
+	assert_eq!(buf[0], 1);
+
+	// the device stops answering, the queue isn't used anymore
+	mock.0.borrow_mut().handler = None;
+	assert_eq!(disk.read(0, 0, &mut buf), Err(Error::Timeout));
+	assert_eq!(disk.read(0, 0, &mut buf), Err(Error::Timeout));
+	check_freed(mock, disk);
+}
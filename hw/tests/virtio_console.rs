@@ -0,0 +1,171 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+use common::virtio::*;
+use hw::virtio::{*, console_device::*};
+
+fn config(cols: u16, rows: u16, max_ports: u32) -> Vec<u8> {
+	let mut config = vec![0; 12];
+	config[CONFIG_COLS..CONFIG_COLS + 2].copy_from_slice(&cols.to_le_bytes());
+	config[CONFIG_ROWS..CONFIG_ROWS + 2].copy_from_slice(&rows.to_le_bytes());
+	config[CONFIG_MAX_NR_PORTS..CONFIG_MAX_NR_PORTS + 4].copy_from_slice(&max_ports.to_le_bytes());
+	config
+}
+
+fn msg(port: u32, event: u16, value: u16, data: &[u8]) -> Vec<u8> {
+	[&port.to_le_bytes()[..], &event.to_le_bytes(), &value.to_le_bytes(), data].concat()
+}
+
+/// Sends messages on a receive queue, one per buffer.
+fn deliver(mock: &Mock, queue: u16, messages: &[Vec<u8>]) {
+	for m in messages {
+		let m = m.clone();
+		assert_eq!(mock.process_some(queue, false, 1, move |_, len| {
+			assert_eq!(len, BUFFER_SIZE);
+			m.clone()
+		}), 1);
+	}
+}
+
+/// Takes what the driver queued on a transmit queue.
+fn sent(mock: &Mock, queue: u16) -> Vec<Vec<u8>> {
+	let out = RefCell::new(Vec::new());
+	mock.process(queue, false, |data, _| {
+		out.borrow_mut().push(data.to_vec());
+		Vec::new()
+	});
+	out.into_inner()
+}
+
+#[test]
+fn single_port() {
+	let net = Mock::new(DeviceType::NetworkDevice, FEATURE_VERSION_1);
+	assert!(matches!(Console::new(net.clone(), net), Err(Error::NoDevice)));
+
+	let mock = Mock::new(DeviceType::ConsoleDevice, FEATURE_VERSION_1 | FEATURE_SIZE | FEATURE_EMERG_WRITE | FEATURE_MULTIPORT);
+	let written = Rc::new(RefCell::new(Vec::new()));
+	let log = written.clone();
+	{
+		let mut s = mock.0.borrow_mut();
+		// the device offers multiple ports, but has only one
+		s.config = config(80, 25, 1);
+		s.count = 4;
+		s.on_write = Some(Rc::new(move |config: &mut Vec<u8>, offset| {
+			if offset == CONFIG_EMERG_WR {
+				log.borrow_mut().push(config[offset]);
+			}
+		}));
+	}
+	let mut con = Console::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(con.size(), Some((80, 25)));
+	assert_eq!(sent(&mock, 3), [msg(0, CTRL_DEVICE_READY, 1, &[])]);
+	deliver(&mock, 2, &[msg(0, CTRL_DEVICE_ADD, 0, &[]), msg(0, CTRL_CONSOLE_PORT, 1, &[])]);
+	assert_eq!(con.poll(), [Event::Added(0), Event::Console(0)]);
+	assert_eq!(sent(&mock, 3), [msg(0, CTRL_PORT_READY, 1, &[]), msg(0, CTRL_PORT_OPEN, 1, &[])]);
+
+	assert_eq!(con.write(0, b"hello").unwrap(), 5);
+	assert_eq!(sent(&mock, 1), [b"hello".to_vec()]);
+	deliver(&mock, 0, &[b"ab".to_vec(), b"cde".to_vec()]);
+	assert!(con.poll().is_empty());
+	let mut buf = [0; 4];
+	assert_eq!(con.read(0, &mut buf).unwrap(), 4);
+	assert_eq!(&buf, b"abcd");
+	assert_eq!(con.read(0, &mut buf).unwrap(), 1);
+	assert_eq!(con.read(0, &mut buf).unwrap(), 0);
+
+	assert!(con.emergency_write(b"!?"));
+	assert_eq!(written.borrow().as_slice(), b"!?");
+
+	// the size is read again when the configuration changes
+	mock.0.borrow_mut().config[CONFIG_ROWS] = 50;
+	mock.0.borrow_mut().isr = INTERRUPT_CONFIG;
+	assert_eq!(con.interrupt(), INTERRUPT_CONFIG);
+	assert_eq!(con.size(), Some((80, 50)));
+	assert_eq!(con.poll(), [Event::Resized { port: 0, cols: 80, rows: 50 }]);
+
+	drop(con);
+	assert_eq!(mock.0.borrow().status, 0, "device not reset");
+	mock.check_freed();
+}
+
+#[test]
+fn legacy() {
+	let mock = Mock::new(DeviceType::ConsoleDevice, FEATURE_VERSION_1);
+	let mut con = Console::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(con.size(), None);
+	assert_eq!(con.ports().len(), 1);
+	assert!(con.port(0).unwrap().console && con.port(0).unwrap().host_open);
+	assert!(!con.emergency_write(b"x"));
+	assert!(matches!(con.set_open(0, true), Err(Error::Unsupported)));
+	assert!(matches!(con.write(1, b"x"), Err(Error::InvalidArgument)));
+	assert_eq!(con.write(0, b"x").unwrap(), 1);
+	drop(con);
+	mock.check_freed();
+}
+
+#[test]
+fn multiport() {
+	let mock = Mock::new(DeviceType::ConsoleDevice, FEATURE_VERSION_1 | FEATURE_MULTIPORT);
+	{
+		let mut s = mock.0.borrow_mut();
+		s.config = config(0, 0, 3);
+		s.count = 8;
+	}
+	let mut con = Console::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(sent(&mock, 3), [msg(0, CTRL_DEVICE_READY, 1, &[])]);
+	assert!(con.ports().is_empty());
+
+	deliver(&mock, 2, &[
+		msg(1, CTRL_DEVICE_ADD, 0, &[]),
+		msg(5, CTRL_DEVICE_ADD, 0, &[]),
+		msg(1, CTRL_PORT_NAME, 0, b"shell\0"),
+		msg(1, CTRL_PORT_OPEN, 1, &[]),
+		msg(2, CTRL_PORT_OPEN, 1, &[]),
+		msg(1, CTRL_RESIZE, 0, &[132, 0, 43, 0])
+	]);
+	assert_eq!(con.poll(), [Event::Added(1), Event::Named(1), Event::Opened(1), Event::Resized { port: 1, cols: 132, rows: 43 }]);
+	assert_eq!(sent(&mock, 3), [msg(1, CTRL_PORT_READY, 1, &[]), msg(5, CTRL_PORT_READY, 0, &[])]);
+	let port = con.port(1).unwrap();
+	assert_eq!((port.name.as_deref(), port.console, port.host_open), (Some("shell"), false, true));
+
+	// port 1 uses the queues 4 and 5, data is split into buffers until they run out
+	let data = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+	let slots = mock.0.borrow().queues[&5].size as usize;
+	assert_eq!(con.write(1, &data).unwrap(), slots * BUFFER_SIZE);
+	assert_eq!(sent(&mock, 5).concat(), &data[..slots * BUFFER_SIZE]);
+	assert_eq!(con.write(1, &data[slots * BUFFER_SIZE..]).unwrap(), 5000 - slots * BUFFER_SIZE);
+	assert!(sent(&mock, 1).is_empty());
+	assert!(matches!(con.write(2, b"x"), Err(Error::InvalidArgument)));
+
+	deliver(&mock, 4, &[b"ls\n".to_vec()]);
+	con.set_open(1, false).unwrap();
+	assert_eq!(sent(&mock, 3), [msg(1, CTRL_PORT_OPEN, 0, &[])]);
+	deliver(&mock, 2, &[msg(1, CTRL_PORT_OPEN, 0, &[]), msg(1, CTRL_DEVICE_REMOVE, 0, &[])]);
+	assert_eq!(con.poll(), [Event::Closed(1), Event::Removed(1)]);
+	assert!(matches!(con.read(1, &mut [0; 4]), Err(Error::InvalidArgument)));
+
+	drop(con);
+	mock.check_freed();
+}
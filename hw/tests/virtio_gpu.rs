@@ -0,0 +1,188 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::{Cell, RefCell}, rc::Rc};
+use common::virtio::*;
+use hw::{console::{PixelFormat, Rect}, virtio::{*, gpu::*}};
+
+fn word(data: &[u8], i: usize) -> u32 {
+	u32::from_le_bytes(data[4 * i..4 * i + 4].try_into().unwrap())
+}
+
+fn response(ty: u32) -> Vec<u8> {
+	let mut r = vec![0; HEADER_LEN];
+	r[..4].copy_from_slice(&ty.to_le_bytes());
+	r
+}
+
+/// A GPU with two scanouts, the first one enabled at 1024x768. Commands of the type
+/// `fail` are answered with `RESP_ERR_OUT_OF_MEMORY`, returns the commands it received.
+fn device(fail: Rc<Cell<u32>>) -> (Mock, Rc<RefCell<Vec<Vec<u8>>>>) {
+	let mock = Mock::new(DeviceType::Gpu, FEATURE_VERSION_1);
+	let commands = Rc::new(RefCell::new(Vec::new()));
+	let log = commands.clone();
+	let mut s = mock.0.borrow_mut();
+	s.config = vec![0; 16];
+	s.config[CONFIG_NUM_SCANOUTS] = 2;
+	s.handler = Some(Rc::new(move |queue, data: &[u8], len| {
+		assert_eq!(queue, 0, "command on the cursor queue");
+		log.borrow_mut().push(data.to_vec());
+		match word(data, 0) {
+			ty if ty == fail.get() => response(RESP_ERR_OUT_OF_MEMORY),
+			CMD_GET_DISPLAY_INFO => {
+				assert_eq!(len, DISPLAY_INFO_LEN);
+				let mut r = response(RESP_OK_DISPLAY_INFO);
+				r.resize(DISPLAY_INFO_LEN, 0);
+				for (i, w) in [0u32, 0, 1024, 768, 1, 0].iter().enumerate() {
+					r[HEADER_LEN + 4 * i..HEADER_LEN + 4 * i + 4].copy_from_slice(&w.to_le_bytes());
+				}
+				r
+			}
+			_ => response(RESP_OK_NODATA)
+		}
+	}));
+	drop(s);
+	(mock, commands)
+}
+
+fn types(commands: &RefCell<Vec<Vec<u8>>>) -> Vec<u32> {
+	commands.borrow_mut().drain(..).map(|c| word(&c, 0)).collect()
+}
+
+#[test]
+fn init() {
+	assert_eq!(DISPLAY_INFO_LEN, 408);
+	let block = Mock::new(DeviceType::BlockDevice, FEATURE_VERSION_1);
+	assert!(matches!(Gpu::new(block.clone(), block), Err(Error::NoDevice)));
+
+	let (mock, _) = device(Rc::new(Cell::new(0)));
+	let mut gpu = Gpu::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(gpu.scanouts(), 2);
+	assert_eq!(gpu.mode(), None);
+	assert!(gpu.framebuffer().is_none());
+	assert_eq!(gpu.format(), PixelFormat::Bgrx);
+	assert!(mock.interrupts_suppressed(0));
+	assert_eq!(gpu.displays().unwrap(), [
+		Display { scanout: 0, rect: Rect::new(0, 0, 1024, 768), enabled: true },
+		Display { scanout: 1, rect: Rect::new(0, 0, 0, 0), enabled: false }
+	]);
+
+	assert_eq!(gpu.events(), 0);
+	mock.0.borrow_mut().config[CONFIG_EVENTS_READ] = EVENT_DISPLAY as u8;
+	assert_eq!(gpu.events(), EVENT_DISPLAY);
+	assert_eq!(mock.0.borrow().config[CONFIG_EVENTS_CLEAR], EVENT_DISPLAY as u8);
+
+	drop(gpu);
+	assert_eq!(mock.0.borrow().status, 0, "device not reset");
+	mock.check_freed();
+}
+
+#[test]
+fn set_mode() {
+	let (mock, commands) = device(Rc::new(Cell::new(0)));
+	let mut gpu = Gpu::new(mock.clone(), mock.clone()).unwrap();
+	assert!(matches!(gpu.set_mode(2, 640, 480), Err(Error::InvalidArgument)));
+	assert!(matches!(gpu.set_mode(0, 0, 480), Err(Error::InvalidArgument)));
+	assert!(matches!(gpu.flush(Rect::new(0, 0, 1, 1)), Err(Error::InvalidArgument)));
+
+	gpu.set_mode(0, 640, 480).unwrap();
+	assert_eq!(gpu.mode(), Some((640, 480)));
+	let fb = gpu.framebuffer().unwrap();
+	assert_eq!(fb.len(), 640 * 480);
+	fb[0] = 0x123456;
+	let phys = fb.as_ptr() as u64;
+
+	let c = commands.borrow().clone();
+	assert_eq!(types(&commands), [CMD_RESOURCE_CREATE_2D, CMD_RESOURCE_ATTACH_BACKING, CMD_SET_SCANOUT,
+		CMD_TRANSFER_TO_HOST_2D, CMD_RESOURCE_FLUSH]);
+	let id = word(&c[0], 6);
+	assert_ne!(id, 0);
+	assert_eq!(&c[0][HEADER_LEN..], [id, FORMAT_B8G8R8X8_UNORM, 640, 480].map(u32::to_le_bytes).concat());
+	assert_eq!((word(&c[1], 6), word(&c[1], 7)), (id, 1));
+	assert_eq!(u64::from_le_bytes(c[1][32..40].try_into().unwrap()), phys);
+	assert_eq!(word(&c[1], 10), 640 * 480 * 4);
+	assert_eq!(&c[2][HEADER_LEN..], [0, 0, 640, 480, 0, id].map(u32::to_le_bytes).concat());
+	assert_eq!(&c[3][HEADER_LEN..HEADER_LEN + 16], [0, 0, 640, 480].map(u32::to_le_bytes).concat());
+	assert_eq!(&c[4][HEADER_LEN..], [0, 0, 640, 480, id, 0].map(u32::to_le_bytes).concat());
+
+	// a new mode releases the previous framebuffer first
+	gpu.set_mode(0, 800, 600).unwrap();
+	let c = commands.borrow().clone();
+	assert_eq!(types(&commands)[..5], [CMD_SET_SCANOUT, CMD_SET_SCANOUT, CMD_RESOURCE_DETACH_BACKING,
+		CMD_RESOURCE_UNREF, CMD_RESOURCE_CREATE_2D]);
+	assert_eq!(&c[0][HEADER_LEN..], [0, 0, 0, 0, 0, 0].map(u32::to_le_bytes).concat());
+	assert_eq!(word(&c[1], 10), 1);
+
+	drop(gpu);
+	assert_eq!(types(&commands), [CMD_SET_SCANOUT, CMD_SET_SCANOUT, CMD_RESOURCE_DETACH_BACKING, CMD_RESOURCE_UNREF]);
+	mock.check_freed();
+}
+
+#[test]
+fn flush() {
+	let (mock, commands) = device(Rc::new(Cell::new(0)));
+	let mut gpu = Gpu::new(mock.clone(), mock.clone()).unwrap();
+	gpu.set_mode(0, 100, 50).unwrap();
+	commands.borrow_mut().clear();
+
+	gpu.flush(Rect::new(10, 20, 200, 5)).unwrap();
+	let c = commands.borrow().clone();
+	assert_eq!(types(&commands), [CMD_TRANSFER_TO_HOST_2D, CMD_RESOURCE_FLUSH]);
+	assert_eq!(&c[0][HEADER_LEN..HEADER_LEN + 16], [10, 20, 90, 5].map(u32::to_le_bytes).concat());
+	assert_eq!(u64::from_le_bytes(c[0][40..48].try_into().unwrap()), (20 * 100 + 10) * 4);
+	assert_eq!(&c[1][HEADER_LEN..HEADER_LEN + 16], [10, 20, 90, 5].map(u32::to_le_bytes).concat());
+
+	// nothing is sent for areas outside of the framebuffer
+	gpu.flush(Rect::new(100, 0, 10, 10)).unwrap();
+	gpu.flush(Rect::new(0, 0, 10, 0)).unwrap();
+	assert!(commands.borrow().is_empty());
+
+	drop(gpu);
+	mock.check_freed();
+}
+
+#[test]
+fn errors() {
+	let fail = Rc::new(Cell::new(CMD_RESOURCE_CREATE_2D));
+	let (mock, commands) = device(fail.clone());
+	let mut gpu = Gpu::new(mock.clone(), mock.clone()).unwrap();
+	assert!(matches!(gpu.set_mode(0, 64, 64), Err(Error::NoMemory)));
+	assert_eq!(gpu.mode(), None);
+
+	fail.set(CMD_GET_DISPLAY_INFO);
+	assert!(matches!(gpu.displays(), Err(Error::Device)));
+
+	// a framebuffer the device didn't detach is freed after the reset
+	fail.set(0);
+	gpu.set_mode(0, 64, 64).unwrap();
+	fail.set(CMD_RESOURCE_DETACH_BACKING);
+	assert!(matches!(gpu.set_mode(0, 32, 32), Err(Error::NoMemory)));
+	assert_eq!(gpu.mode(), Some((64, 64)));
+	commands.borrow_mut().clear();
+
+	drop(gpu);
+	assert_eq!(types(&commands), [CMD_SET_SCANOUT, CMD_SET_SCANOUT, CMD_RESOURCE_DETACH_BACKING]);
+	assert_eq!(mock.0.borrow().status, 0, "device not reset");
+	mock.check_freed();
+}
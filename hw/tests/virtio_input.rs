@@ -0,0 +1,154 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::rc::Rc;
+use common::virtio::*;
+use hw::{input::{self, BTN_LEFT, KEY_A, POSITION_MAX}, virtio::{*, input::*}};
+
+/// A tablet with a few keys, answering configuration queries like a device.
+fn device() -> Mock {
+	let mock = Mock::new(DeviceType::Input, FEATURE_VERSION_1);
+	let mut s = mock.0.borrow_mut();
+	s.config = vec![0; CONFIG_DATA + CONFIG_DATA_LEN];
+	s.on_write = Some(Rc::new(|config: &mut Vec<u8>, offset| {
+		if offset > CONFIG_SUBSEL {
+			return;
+		}
+		let mut keys = vec![0; 0x24];
+		keys[KEY_A as usize / 8] |= 1 << (KEY_A % 8);
+		keys[BTN_LEFT as usize / 8] |= 1 << (BTN_LEFT % 8);
+		let abs = |max: i32| [0, max, 0, 0, 1].iter().flat_map(|w: &i32| w.to_le_bytes()).collect::<Vec<u8>>();
+
+		let data = match (config[CONFIG_SELECT], config[CONFIG_SUBSEL] as u16) {
+			(CFG_ID_NAME, 0) => b"QEMU Virtio Tablet".to_vec(),
+			(CFG_ID_DEVIDS, 0) => vec![6, 0, 0x27, 0x06, 0x03, 0, 1, 0],
+			(CFG_EV_BITS, EV_KEY) => keys,
+			(CFG_EV_BITS, EV_ABS) => vec![0b11],
+			(CFG_ABS_INFO, ABS_X) => abs(32767),
+			(CFG_ABS_INFO, ABS_Y) => abs(1000),
+			_ => Vec::new()
+		};
+		config[CONFIG_SIZE] = data.len() as u8;
+		config[CONFIG_DATA..CONFIG_DATA + data.len()].copy_from_slice(&data);
+	}));
+	drop(s);
+	mock
+}
+
+fn raw(ty: u16, code: u16, value: i32) -> RawEvent {
+	RawEvent { ty, code, value: value as u32 }
+}
+
+fn bytes(e: RawEvent) -> Vec<u8> {
+	[&e.ty.to_le_bytes()[..], &e.code.to_le_bytes(), &e.value.to_le_bytes()].concat()
+}
+
+#[test]
+fn init() {
+	let net = Mock::new(DeviceType::NetworkDevice, FEATURE_VERSION_1);
+	assert!(matches!(Input::new(net.clone(), net), Err(Error::NoDevice)));
+
+	let mock = device();
+	let mut dev = Input::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(dev.name(), "QEMU Virtio Tablet");
+	assert_eq!(dev.ids(), DevIds { bustype: 6, vendor: 0x0627, product: 3, version: 1 });
+	assert!(dev.supports(EV_KEY, KEY_A) && dev.supports(EV_KEY, BTN_LEFT) && dev.supports(EV_ABS, ABS_Y));
+	assert!(!dev.supports(EV_KEY, KEY_A + 1) && !dev.supports(EV_REL, REL_X) && !dev.supports(EV_KEY, 0x400));
+	assert_eq!(dev.abs_info(ABS_X), Some(AbsInfo { min: 0, max: 32767, fuzz: 0, flat: 0, res: 1 }));
+	assert_eq!(dev.abs_info(2), None);
+	assert_eq!(dev.config(CFG_ID_SERIAL, 0), []);
+	assert_eq!(mock.0.borrow().config[CONFIG_SELECT], CFG_UNSET);
+
+	// all buffers are posted
+	let size = mock.0.borrow().queues[&0].size;
+	assert_eq!(mock.process_some(0, false, usize::MAX, |_, len| {
+		assert_eq!(len, EVENT_LEN);
+		Vec::new()
+	}), size as usize);
+	assert!(mock.0.borrow().notified.contains(&0));
+
+	drop(dev);
+	assert_eq!(mock.0.borrow().status, 0, "device not reset");
+	mock.check_freed();
+}
+
+#[test]
+fn events() {
+	let mock = device();
+	let mut dev = Input::new(mock.clone(), mock.clone()).unwrap();
+	let size = mock.0.borrow().queues[&0].size as usize;
+	assert!(dev.poll().is_empty());
+
+	// more events than buffers, the buffers are reused
+	for round in 0..3 {
+		let events = [raw(EV_ABS, ABS_X, 32767), raw(EV_ABS, ABS_Y, 500), raw(EV_KEY, BTN_LEFT, 1), raw(EV_SYN, SYN_REPORT, 0)];
+		let n = mock.process_some(0, false, size, |_, _| bytes(events[0]));
+		assert_eq!(n, size, "round {}", round);
+		let received = dev.events();
+		assert_eq!(received.len(), size);
+		assert!(received.iter().all(|&e| e == events[0]));
+
+		let i = std::cell::Cell::new(0);
+		mock.process_some(0, false, events.len(), |_, _| {
+			i.set(i.get() + 1);
+			bytes(events[i.get() - 1])
+		});
+		assert_eq!(dev.poll(), [
+			input::Event::Button { button: BTN_LEFT, pressed: true },
+			input::Event::Position { x: POSITION_MAX, y: POSITION_MAX / 2 }
+		]);
+	}
+
+	drop(dev);
+	mock.check_freed();
+}
+
+#[test]
+fn translate() {
+	let mut t = Translator::new([None, Some(AbsInfo { min: -100, max: 100, ..AbsInfo::default() })]);
+	assert_eq!(t.translate(&[raw(EV_KEY, KEY_A, 1), raw(EV_KEY, KEY_A, 2), raw(EV_KEY, KEY_A, 0), raw(EV_MSC, 4, 0x70004)]), [
+		input::Event::Key { code: KEY_A, pressed: true, char: None, modifiers: 0 },
+		input::Event::Key { code: KEY_A, pressed: true, char: None, modifiers: 0 },
+		input::Event::Key { code: KEY_A, pressed: false, char: None, modifiers: 0 }
+	]);
+
+	// movements are summed up until the report
+	assert!(t.translate(&[raw(EV_REL, REL_X, 3), raw(EV_REL, REL_X, -5), raw(EV_REL, REL_Y, 2)]).is_empty());
+	assert_eq!(t.translate(&[raw(EV_REL, REL_WHEEL, -1), raw(EV_SYN, SYN_REPORT, 0)]), [
+		input::Event::Motion { dx: -2, dy: 2 },
+		input::Event::Wheel { dx: 0, dy: -1 }
+	]);
+	assert!(t.translate(&[raw(EV_SYN, SYN_REPORT, 0)]).is_empty());
+
+	// the other axis keeps its last position
+	assert_eq!(t.translate(&[raw(EV_ABS, ABS_X, 70000), raw(EV_ABS, ABS_Y, 0), raw(EV_SYN, SYN_REPORT, 0)]),
+		[input::Event::Position { x: POSITION_MAX, y: POSITION_MAX / 2 }]);
+	assert_eq!(t.translate(&[raw(EV_ABS, ABS_Y, -200), raw(EV_SYN, SYN_REPORT, 0)]),
+		[input::Event::Position { x: POSITION_MAX, y: 0 }]);
+
+	// everything up to the report after a drop is discarded
+	assert!(t.translate(&[raw(EV_REL, REL_X, 1), raw(EV_SYN, SYN_DROPPED, 0), raw(EV_KEY, KEY_A, 1), raw(EV_REL, REL_Y, 1),
+		raw(EV_SYN, SYN_REPORT, 0)]).is_empty());
+	assert_eq!(t.translate(&[raw(EV_REL, REL_Y, 1), raw(EV_SYN, SYN_REPORT, 0)]), [input::Event::Motion { dx: 0, dy: 1 }]);
+}
@@ -0,0 +1,219 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+use common::virtio::*;
+use hw::{net::{self, Checksum, Device}, virtio::{*, network_device::*}};
+
+const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+
+fn config(status: u16, pairs: u16, mtu: u16) -> Vec<u8> {
+	let mut config = vec![0; 32];
+	config[CONFIG_MAC..CONFIG_MAC + 6].copy_from_slice(&MAC);
+	config[CONFIG_STATUS..CONFIG_STATUS + 2].copy_from_slice(&status.to_le_bytes());
+	config[CONFIG_MAX_VQ_PAIRS..CONFIG_MAX_VQ_PAIRS + 2].copy_from_slice(&pairs.to_le_bytes());
+	config[CONFIG_MTU..CONFIG_MTU + 2].copy_from_slice(&mtu.to_le_bytes());
+	config
+}
+
+/// A network device with `pairs` queue pairs and a control queue answering with `ack`,
+/// returns the commands it received.
+fn device(features: u64, pairs: u16, ack: u8) -> (Mock, Rc<RefCell<Vec<Vec<u8>>>>) {
+	let mock = Mock::new(DeviceType::NetworkDevice, FEATURE_VERSION_1 | features);
+	let commands = Rc::new(RefCell::new(Vec::new()));
+	let log = commands.clone();
+	let mut s = mock.0.borrow_mut();
+	s.config = config(STATUS_LINK_UP, pairs, 9000);
+	s.count = 2 * pairs + 1;
+	s.serve = vec![2 * pairs];
+	s.handler = Some(Rc::new(move |_, data: &[u8], _| {
+		log.borrow_mut().push(data.to_vec());
+		vec![ack]
+	}));
+	drop(s);
+	(mock, commands)
+}
+
+fn header(bytes: &[u8]) -> Header {
+	unsafe { (bytes.as_ptr() as *const Header).read_unaligned() }
+}
+
+fn to_bytes(header: Header) -> Vec<u8> {
+	unsafe { std::slice::from_raw_parts(&header as *const Header as *const u8, HEADER_LEN) }.to_vec()
+}
+
+fn check_freed<T: Transport, D: hw::dma::Dma>(mock: Mock, net: Net<T, D>) {
+	drop(net);
+	assert_eq!(mock.0.borrow().status, 0, "device not reset");
+	mock.check_freed();
+}
+
+#[test]
+fn init() {
+	assert_eq!(HEADER_LEN, 12);
+	let features = FEATURE_MAC | FEATURE_MTU | FEATURE_STATUS | FEATURE_CTRL_VQ | FEATURE_MQ | FEATURE_CSUM;
+	let (mock, commands) = device(features, 2, CTRL_OK);
+	let net = Net::new(mock.clone(), mock.clone(), 4).unwrap();
+	assert_eq!(net.pairs(), 2);
+	assert_eq!(commands.borrow().as_slice(), [vec![CTRL_MQ, CTRL_MQ_VQ_PAIRS_SET, 2, 0]]);
+	assert_eq!((net.mac(), net.mtu()), (MAC, MAX_MTU));
+	assert!(Device::checksum_offload(&net));
+	assert!(net.link_up());
+	mock.0.borrow_mut().config[CONFIG_STATUS] = 0;
+	assert!(!net.link_up());
+	assert_ne!(mock.0.borrow().status & STATUS_DRIVER_OK, 0);
+	// all receive buffers are posted and the device notified
+	assert!(mock.0.borrow().notified.contains(&0) && mock.0.borrow().notified.contains(&2));
+	assert!(mock.interrupts_suppressed(0) && mock.interrupts_suppressed(3));
+	check_freed(mock, net);
+
+	// the device keeps using one pair if it refuses the command
+	let (mock, commands) = device(features, 2, 1);
+	let net = Net::new(mock.clone(), mock.clone(), 2).unwrap();
+	assert_eq!((net.pairs(), commands.borrow().len()), (1, 1));
+	check_freed(mock, net);
+
+	// without a MAC feature, a locally administered address is made up
+	let (mock, _) = device(FEATURE_MQ, 2, CTRL_OK);
+	let net = Net::new(mock.clone(), mock.clone(), 2).unwrap();
+	assert_eq!((net.pairs(), net.mtu()), (1, 1500));
+	assert_eq!(net.mac()[0], 0x02);
+	assert!(net.link_up() && !Device::checksum_offload(&net));
+	check_freed(mock, net);
+
+	let mock = Mock::new(DeviceType::BlockDevice, FEATURE_VERSION_1);
+	assert_eq!(Net::new(mock.clone(), mock.clone(), 1).unwrap_err(), Error::NoDevice);
+}
+
+fn transmit(packed: bool) {
+	let features = FEATURE_MAC | FEATURE_CSUM | if packed { FEATURE_RING_PACKED } else { 0 };
+	let (mock, _) = device(features, 1, CTRL_OK);
+	let mut net = Net::new(mock.clone(), mock.clone(), 1).unwrap();
+	let frame = (0..100).collect::<Vec<u8>>();
+	net.transmit(0, &frame, Some(Checksum { start: 34, offset: 6 })).unwrap();
+	net.transmit(0, &frame, None).unwrap();
+	assert_eq!(mock.0.borrow().notified.last(), Some(&1));
+
+	let sent = RefCell::new(Vec::new());
+	assert_eq!(mock.process(1, false, |data, len| {
+		assert_eq!(len, 0);
+		sent.borrow_mut().push(data.to_vec());
+		Vec::new()
+	}), 2);
+	let sent = sent.into_inner();
+	let h = header(&sent[0]);
+	assert_eq!((h.flags, h.gso_type, h.csum_start, h.csum_offset), (HDR_F_NEEDS_CSUM, HDR_GSO_NONE, 34, 6));
+	assert_eq!(&sent[0][HEADER_LEN..], frame);
+	assert_eq!(header(&sent[1]).flags, 0);
+
+	// the queue is full until the device used the buffers
+	let size = mock.0.borrow().queues[&1].size as usize;
+	for _ in 0..size {
+		net.transmit(0, &frame, None).unwrap();
+	}
+	assert_eq!(net.transmit(0, &frame, None).unwrap_err(), Error::NoMemory);
+	mock.process(1, false, |_, _| Vec::new());
+	net.transmit(0, &frame, None).unwrap();
+
+	assert_eq!(net.transmit(0, &[0; BUFFER_SIZE], None).unwrap_err(), Error::InvalidArgument);
+	assert_eq!(net.transmit(1, &frame, None).unwrap_err(), Error::InvalidArgument);
+	assert_eq!(net.transmit(0, &frame, Some(Checksum { start: 99, offset: 0 })).unwrap_err(), Error::InvalidArgument);
+	check_freed(mock, net);
+}
+
+#[test]
+fn transmit_split() {
+	transmit(false);
+}
+
+#[test]
+fn transmit_packed() {
+	transmit(true);
+}
+
+#[test]
+fn software_checksum() {
+	let (mock, _) = device(FEATURE_MAC, 1, CTRL_OK);
+	let mut net = Net::new(mock.clone(), mock.clone(), 1).unwrap();
+	// the field holds the sum of the pseudo header
+	let mut frame = (0..64).collect::<Vec<u8>>();
+	frame[20..22].copy_from_slice(&0x1234u16.to_be_bytes());
+	net.transmit(0, &frame, Some(Checksum { start: 14, offset: 6 })).unwrap();
+
+	let sent = RefCell::new(Vec::new());
+	mock.process(1, false, |data, _| {
+		sent.borrow_mut().extend_from_slice(data);
+		Vec::new()
+	});
+	let sent = sent.into_inner();
+	assert_eq!(header(&sent).flags, 0);
+	let sent = &sent[HEADER_LEN..];
+	assert_eq!(net::checksum_fold(net::checksum_add(0x1234, &sent[14..])), 0xFFFF);
+	assert_eq!(sent[..20], frame[..20]);
+	check_freed(mock, net);
+}
+
+fn receive(packed: bool) {
+	let features = FEATURE_MAC | FEATURE_MRG_RXBUF | FEATURE_GUEST_CSUM | FEATURE_CTRL_VQ | FEATURE_MQ
+		| if packed { FEATURE_RING_PACKED } else { 0 };
+	let (mock, _) = device(features, 2, CTRL_OK);
+	let mut net = Net::new(mock.clone(), mock.clone(), 2).unwrap();
+	assert_eq!(net.receive(0), None);
+
+	// a packet spanning two buffers
+	let packet = (0..3000).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+	let first = [to_bytes(Header { flags: HDR_F_DATA_VALID, num_buffers: 2, ..Header::default() }), packet[..BUFFER_SIZE - HEADER_LEN].to_vec()].concat();
+	assert_eq!(mock.process_some(0, false, 1, |_, len| { assert_eq!(len, BUFFER_SIZE); first.clone() }), 1);
+	assert_eq!(mock.process_some(0, false, 1, |_, _| packet[BUFFER_SIZE - HEADER_LEN..].to_vec()), 1);
+	// and one on the second pair
+	assert_eq!(mock.process_some(2, false, 1, |_, _| [to_bytes(Header { num_buffers: 1, ..Header::default() }), vec![7; 60]].concat()), 1);
+
+	mock.0.borrow_mut().notified.clear();
+	let frame = net.receive(0).unwrap();
+	assert_eq!((frame.data.len(), frame.checksum_valid), (packet.len(), true));
+	assert!(frame.data == packet);
+	// the buffers are posted again
+	assert_eq!(mock.0.borrow().notified, [0]);
+	assert_eq!(net.receive(0), None);
+	assert_eq!(Device::receive(&mut net), Some(net::Frame { data: vec![7; 60], checksum_valid: false }));
+	assert_eq!(Device::receive(&mut net), None);
+
+	// reposted buffers are used again, more often than the queue has entries
+	let size = mock.0.borrow().queues[&0].size as usize;
+	for i in 0..2 * size {
+		mock.process_some(0, false, 1, |_, _| [to_bytes(Header { num_buffers: 1, ..Header::default() }), vec![i as u8; 64]].concat());
+		assert_eq!(net.receive(0).unwrap().data, vec![i as u8; 64]);
+	}
+	check_freed(mock, net);
+}
+
+#[test]
+fn receive_split() {
+	receive(false);
+}
+
+#[test]
+fn receive_packed() {
+	receive(true);
+}
@@ -0,0 +1,75 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::rc::Rc;
+use common::virtio::*;
+use hw::virtio::{*, entropy_device::*};
+
+/// An entropy device that answers every request with at most `max` bytes counting up.
+fn device(max: usize) -> Mock {
+	let mock = Mock::new(DeviceType::EntropyDevice, FEATURE_VERSION_1);
+	mock.0.borrow_mut().handler = Some(Rc::new(move |queue, data: &[u8], len| {
+		assert_eq!(queue, 0);
+		assert!(data.is_empty(), "request with readable buffers");
+		(0..len.min(max)).map(|i| i as u8).collect()
+	}));
+	mock
+}
+
+#[test]
+fn read() {
+	let block = Mock::new(DeviceType::BlockDevice, FEATURE_VERSION_1);
+	assert!(matches!(Rng::new(block.clone(), block), Err(Error::NoDevice)));
+
+	let mock = device(usize::MAX);
+	let mut rng = Rng::new(mock.clone(), mock.clone()).unwrap();
+	assert!(mock.interrupts_suppressed(0));
+	let mut buf = [0xFF; 16];
+	assert_eq!(rng.read(&mut buf).unwrap(), 16);
+	assert_eq!(buf, core::array::from_fn(|i| i as u8));
+	assert_eq!(rng.read(&mut []).unwrap(), 0);
+
+	// requests are limited to a page
+	let mut buf = vec![0; 3 * 4096];
+	assert_eq!(rng.read(&mut buf).unwrap(), 4096);
+	drop(rng);
+	mock.check_freed();
+}
+
+#[test]
+fn fill() {
+	let mock = device(10);
+	let mut rng = Rng::new(mock.clone(), mock.clone()).unwrap();
+	let mut buf = [0xFF; 25];
+	assert_eq!(rng.read(&mut buf).unwrap(), 10);
+	rng.fill(&mut buf).unwrap();
+	assert_eq!(buf[..10], buf[10..20]);
+	assert_eq!(buf[20..], [0, 1, 2, 3, 4]);
+	drop(rng);
+
+	// a device that never has anything
+	let mock = device(0);
+	let mut rng = Rng::new(mock.clone(), mock.clone()).unwrap();
+	assert!(matches!(rng.fill(&mut [0; 4]), Err(Error::Timeout)));
+}
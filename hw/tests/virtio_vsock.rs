@@ -0,0 +1,138 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use std::cell::RefCell;
+use common::virtio::*;
+use hw::virtio::{*, socket::*};
+
+fn device(features: u64, cid: u64) -> Mock {
+	let mock = Mock::new(DeviceType::Socket, FEATURE_VERSION_1 | features);
+	let mut s = mock.0.borrow_mut();
+	s.count = 3;
+	s.config[CONFIG_GUEST_CID..CONFIG_GUEST_CID + 8].copy_from_slice(&cid.to_le_bytes());
+	drop(s);
+	mock
+}
+
+fn packet(op: u16, data: &[u8]) -> Vec<u8> {
+	let header = Header { src_cid: HOST_CID, dst_cid: 3, src_port: 1, dst_port: 2, len: data.len() as u32,
+		ty: TYPE_STREAM, op, ..Header::default() };
+	[&header.to_bytes()[..], data].concat()
+}
+
+#[test]
+fn init() {
+	let block = Mock::new(DeviceType::BlockDevice, FEATURE_VERSION_1);
+	assert!(matches!(Vsock::new(block.clone(), block), Err(Error::NoDevice)));
+
+	let mock = device(FEATURE_STREAM, 3);
+	let vsock = Vsock::new(mock.clone(), mock.clone()).unwrap();
+	assert_eq!(vsock.guest_cid(), 3);
+	assert_ne!(vsock.features() & FEATURE_STREAM, 0);
+	assert_ne!(mock.0.borrow().status & STATUS_DRIVER_OK, 0);
+	// the receive and event buffers are posted
+	assert!(mock.0.borrow().notified.contains(&0) && mock.0.borrow().notified.contains(&2));
+	assert!(mock.interrupts_suppressed(0) && mock.interrupts_suppressed(1) && mock.interrupts_suppressed(2));
+	drop(vsock);
+	assert_eq!(mock.0.borrow().status, 0, "device not reset");
+	mock.check_freed();
+}
+
+fn transfer(packed: bool) {
+	let mock = device(if packed { FEATURE_RING_PACKED } else { 0 }, 3);
+	let mut vsock = Vsock::new(mock.clone(), mock.clone()).unwrap();
+	let header = Header { src_cid: 3, dst_cid: HOST_CID, src_port: 2, dst_port: 1, len: 4, ty: TYPE_STREAM, op: OP_RW, ..Header::default() };
+	vsock.transmit(&header, b"ping").unwrap();
+	assert_eq!(vsock.transmit(&header, b"pin"), Err(Error::InvalidArgument));
+	assert_eq!(vsock.transmit(&Header { len: MAX_PAYLOAD as u32 + 1, ..header }, &[0; MAX_PAYLOAD + 1]), Err(Error::InvalidArgument));
+
+	let sent = RefCell::new(Vec::new());
+	assert_eq!(mock.process(1, false, |data, len| {
+		assert_eq!(len, 0);
+		sent.borrow_mut().push(data.to_vec());
+		Vec::new()
+	}), 1);
+	let sent = sent.into_inner();
+	assert_eq!(Header::parse(&sent[0]), Some(header));
+	assert_eq!(&sent[0][HEADER_LEN..], b"ping");
+
+	// the transmit queue is full until the device used the buffers
+	let size = mock.0.borrow().queues[&1].size as usize;
+	for _ in 0..size {
+		vsock.transmit(&header, b"ping").unwrap();
+	}
+	assert_eq!(vsock.transmit(&header, b"ping"), Err(Error::NoMemory));
+	mock.process(1, false, |_, _| Vec::new());
+	vsock.transmit(&header, b"ping").unwrap();
+
+	assert_eq!(vsock.receive(), None);
+	assert_eq!(mock.process_some(0, false, 1, |_, len| { assert_eq!(len, BUFFER_SIZE); packet(OP_RW, b"pong") }), 1);
+	// a packet shorter than its header says is dropped
+	assert_eq!(mock.process_some(0, false, 1, |_, _| packet(OP_RW, b"pong")[..HEADER_LEN + 2].to_vec()), 1);
+	assert_eq!(mock.process_some(0, false, 1, |_, _| packet(OP_SHUTDOWN, &[])), 1);
+	let p = vsock.receive().unwrap();
+	assert_eq!((p.header.op, p.header.src_cid, p.data.as_slice()), (OP_RW, HOST_CID, &b"pong"[..]));
+	assert_eq!(vsock.receive().unwrap().header.op, OP_SHUTDOWN);
+	assert_eq!(vsock.receive(), None);
+
+	// reposted buffers are used again, more often than the queue has entries
+	let size = mock.0.borrow().queues[&0].size as usize;
+	for i in 0..2 * size {
+		mock.process_some(0, false, 1, |_, _| packet(OP_RW, &[i as u8; 16]));
+		assert_eq!(vsock.receive().unwrap().data, [i as u8; 16]);
+	}
+	drop(vsock);
+	mock.check_freed();
+}
+
+#[test]
+fn transfer_split() {
+	transfer(false);
+}
+
+#[test]
+fn transfer_packed() {
+	transfer(true);
+}
+
+#[test]
+fn transport_reset() {
+	let mock = device(0, 3);
+	let mut vsock = Vsock::new(mock.clone(), mock.clone()).unwrap();
+	assert!(!Device::reset(&mut vsock));
+	// the device has a new context ID after a migration
+	mock.0.borrow_mut().config[0] = 7;
+	assert_eq!(mock.process_some(2, false, 1, |_, len| { assert_eq!(len, 4); EVENT_TRANSPORT_RESET.to_le_bytes().to_vec() }), 1);
+	mock.0.borrow_mut().notified.clear();
+	assert!(Device::reset(&mut vsock));
+	assert_eq!(mock.0.borrow().notified, [2]);
+	assert_eq!(vsock.guest_cid(), 7);
+	assert!(!Device::reset(&mut vsock));
+
+	// other events are ignored
+	mock.process_some(2, false, 1, |_, _| vec![9, 0, 0, 0]);
+	assert!(!vsock.poll_events());
+	drop(vsock);
+	mock.check_freed();
+}
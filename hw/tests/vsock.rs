@@ -0,0 +1,282 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+use hw::{net::Error, virtio::{self, socket::*}};
+
+/// Two devices connected by a wire, packets sent by one are received by the other.
+#[derive(Default)]
+struct Wire {
+	queues: [VecDeque<Packet>; 2],
+	/// Packets sent by each side
+	log:    [Vec<Header>; 2],
+	/// Drops the packets of each side
+	drop:   [bool; 2],
+	reset:  [bool; 2]
+}
+
+struct Side {
+	wire: Rc<RefCell<Wire>>,
+	side: usize,
+	cid:  u64
+}
+
+impl Device for Side {
+	fn guest_cid(&self) -> u64 {
+		self.cid
+	}
+
+	fn transmit(&mut self, header: &Header, data: &[u8]) -> Result<(), virtio::Error> {
+		assert_eq!(header.len as usize, data.len());
+		let mut wire = self.wire.borrow_mut();
+		wire.log[self.side].push(*header);
+		if !wire.drop[self.side] {
+			wire.queues[1 - self.side].push_back(Packet { header: *header, data: data.to_vec() });
+		}
+		Ok(())
+	}
+
+	fn receive(&mut self) -> Option<Packet> {
+		self.wire.borrow_mut().queues[self.side].pop_front()
+	}
+
+	fn reset(&mut self) -> bool {
+		std::mem::replace(&mut self.wire.borrow_mut().reset[self.side], false)
+	}
+}
+
+const GUEST: u64 = 3;
+
+fn pair() -> (Rc<RefCell<Wire>>, Streams<Side>, Streams<Side>) {
+	let wire = Rc::new(RefCell::new(Wire::default()));
+	let host = Streams::new(Side { wire: wire.clone(), side: 0, cid: HOST_CID }, 0);
+	let guest = Streams::new(Side { wire: wire.clone(), side: 1, cid: GUEST }, 1);
+	(wire, host, guest)
+}
+
+/// Polls both sides until no packets are in flight.
+fn run(a: &mut Streams<Side>, b: &mut Streams<Side>, now: u64) {
+	for _ in 0..1000 {
+		if !a.poll(now) & !b.poll(now) {
+			return;
+		}
+	}
+	panic!("sides didn't settle");
+}
+
+fn ops(wire: &Rc<RefCell<Wire>>, side: usize) -> Vec<u16> {
+	wire.borrow().log[side].iter().map(|h| h.op).collect()
+}
+
+/// A connection from the host to a listener of the guest.
+fn connect(host: &mut Streams<Side>, guest: &mut Streams<Side>) -> (Handle, Handle, Handle) {
+	let listener = guest.listen(1234, 4).unwrap();
+	let client = host.connect(GUEST, 1234).unwrap();
+	assert_eq!(host.state(client), Ok(State::Connecting));
+	assert_eq!(host.send(client, b"early"), Err(Error::WouldBlock));
+	run(host, guest, 0);
+	assert_eq!(host.state(client), Ok(State::Connected));
+	let server = guest.accept(listener).unwrap();
+	assert_eq!(guest.accept(listener), Err(Error::WouldBlock));
+	(listener, client, server)
+}
+
+#[test]
+fn header() {
+	let h = Header { src_cid: 3, dst_cid: 2, src_port: 0x1234, dst_port: 80, len: 5, ty: TYPE_STREAM,
+		op: OP_RW, flags: 0, buf_alloc: 0x40000, fwd_cnt: 7 };
+	let bytes = h.to_bytes();
+	assert_eq!(bytes[..8], 3u64.to_le_bytes());
+	assert_eq!(bytes[16..20], 0x1234u32.to_le_bytes());
+	assert_eq!(bytes[28..32], [1, 0, 5, 0]);
+	assert_eq!(bytes[40..44], 7u32.to_le_bytes());
+	assert_eq!(Header::parse(&bytes), Some(h));
+	assert_eq!(Header::parse(&bytes[..HEADER_LEN - 1]), None);
+}
+
+#[test]
+fn transfer() {
+	let (wire, mut host, mut guest) = pair();
+	let (_, client, server) = connect(&mut host, &mut guest);
+	assert_eq!(ops(&wire, 0), [OP_REQUEST]);
+	assert_eq!(ops(&wire, 1), [OP_RESPONSE]);
+	assert_eq!(host.endpoints(client).unwrap().1, Some((GUEST, 1234)));
+	let (port, peer) = guest.endpoints(server).unwrap();
+	assert_eq!((port, peer), (1234, Some((HOST_CID, host.endpoints(client).unwrap().0))));
+
+	assert_eq!(host.send(client, b"hello"), Ok(5));
+	let mut buf = [0; 64];
+	assert_eq!(guest.recv(server, &mut buf), Err(Error::WouldBlock));
+	run(&mut host, &mut guest, 0);
+	assert!(guest.can_recv(server).unwrap());
+	assert_eq!(guest.recv(server, &mut buf), Ok(5));
+	assert_eq!(&buf[..5], b"hello");
+	assert_eq!(guest.send(server, b"world"), Ok(5));
+	run(&mut host, &mut guest, 0);
+	assert_eq!(host.recv(client, &mut buf), Ok(5));
+	assert_eq!(&buf[..5], b"world");
+
+	// the packets are split to fit into the buffers
+	let data = (0..3 * MAX_PAYLOAD).map(|i| i as u8).collect::<Vec<_>>();
+	assert_eq!(host.send(client, &data), Ok(data.len()));
+	assert_eq!(wire.borrow().log[0].iter().filter(|h| h.op == OP_RW && h.len as usize == MAX_PAYLOAD).count(), 3);
+	run(&mut host, &mut guest, 0);
+	let mut received = vec![0; data.len() + 1];
+	assert_eq!(guest.recv(server, &mut received), Ok(data.len()));
+	assert!(received[..data.len()] == data);
+}
+
+#[test]
+fn flow_control() {
+	let (wire, mut host, mut guest) = pair();
+	let (_, client, server) = connect(&mut host, &mut guest);
+
+	// the host sends no more than the guest has room for and asks for credit
+	let data = vec![0x5A; BUF_ALLOC as usize];
+	assert_eq!(host.send(client, &data), Ok(data.len()));
+	assert_eq!(host.send(client, &data), Ok(data.len()));
+	assert_eq!(host.send(client, b"x"), Err(Error::WouldBlock));
+	run(&mut host, &mut guest, 0);
+	let sent = wire.borrow().log[0].iter().filter(|h| h.op == OP_RW).map(|h| h.len as usize).sum::<usize>();
+	assert_eq!(sent, BUF_ALLOC as usize);
+	assert_eq!(ops(&wire, 0).last(), Some(&OP_CREDIT_REQUEST));
+	assert_eq!(ops(&wire, 1).last(), Some(&OP_CREDIT_UPDATE));
+
+	// reading tells the host of the free space, which it fills again
+	let mut buf = vec![0; BUF_ALLOC as usize];
+	let mut total = 0;
+	while total < 2 * BUF_ALLOC as usize {
+		let n = guest.recv(server, &mut buf).unwrap_or(0);
+		assert!(buf[..n].iter().all(|b| *b == 0x5A));
+		total += n;
+		run(&mut host, &mut guest, 0);
+		assert!(n > 0 || guest.can_recv(server).unwrap(), "stalled after {} bytes", total);
+	}
+	assert_eq!(guest.recv(server, &mut buf), Err(Error::WouldBlock));
+	assert_eq!(host.send(client, b"x"), Ok(1));
+}
+
+#[test]
+fn close() {
+	let (wire, mut host, mut guest) = pair();
+	let (listener, client, server) = connect(&mut host, &mut guest);
+
+	// half-closed, the guest can still send
+	host.send(client, b"bye").unwrap();
+	host.shutdown(client).unwrap();
+	assert_eq!(host.send(client, b"more"), Err(Error::NotConnected));
+	run(&mut host, &mut guest, 0);
+	assert_eq!(ops(&wire, 0)[1..], [OP_RW, OP_SHUTDOWN]);
+	assert_eq!(wire.borrow().log[0].last().unwrap().flags, SHUTDOWN_SEND);
+	let mut buf = [0; 8];
+	assert_eq!(guest.recv(server, &mut buf), Ok(3));
+	assert_eq!(guest.recv(server, &mut buf), Ok(0));
+	guest.send(server, b"ok").unwrap();
+	run(&mut host, &mut guest, 0);
+	assert_eq!(host.recv(client, &mut buf), Ok(2));
+
+	// the host closes, the guest resets and the connection is gone on both sides
+	host.close(client).unwrap();
+	assert_eq!(host.state(client), Err(Error::InvalidArgument));
+	run(&mut host, &mut guest, 0);
+	assert_eq!(ops(&wire, 1).last(), Some(&OP_RST));
+	assert_eq!(guest.state(server), Ok(State::Closed));
+	assert_eq!(guest.error(server), Ok(None));
+	assert_eq!(guest.recv(server, &mut buf), Ok(0));
+	assert_eq!(guest.send(server, b"x"), Err(Error::NotConnected));
+	guest.close(server).unwrap();
+	guest.close(listener).unwrap();
+
+	// a peer that doesn't answer the shutdown is reset after the timeout
+	let (_, client, _) = connect(&mut host, &mut guest);
+	wire.borrow_mut().drop[1] = true;
+	host.close(client).unwrap();
+	run(&mut host, &mut guest, 0);
+	assert_eq!(ops(&wire, 0).last(), Some(&OP_SHUTDOWN));
+	run(&mut host, &mut guest, CLOSE_TIMEOUT_MS);
+	assert_eq!(ops(&wire, 0).last(), Some(&OP_RST));
+	// and a new connection gets the handle
+	wire.borrow_mut().drop[1] = false;
+	assert_eq!(host.connect(GUEST, 1234), Ok(client));
+}
+
+#[test]
+fn refused() {
+	let (wire, mut host, mut guest) = pair();
+	let client = host.connect(GUEST, 99).unwrap();
+	run(&mut host, &mut guest, 0);
+	assert_eq!(ops(&wire, 1), [OP_RST]);
+	assert_eq!(host.state(client), Ok(State::Closed));
+	assert_eq!(host.error(client), Ok(Some(Error::ConnectionRefused)));
+	let mut buf = [0; 4];
+	assert_eq!(host.recv(client, &mut buf), Err(Error::ConnectionRefused));
+
+	// connections beyond the backlog are refused
+	let listener = guest.listen(7, 1).unwrap();
+	assert_eq!(guest.listen(7, 1), Err(Error::AddressInUse));
+	let (a, b) = (host.connect(GUEST, 7).unwrap(), host.connect(GUEST, 7).unwrap());
+	run(&mut host, &mut guest, 0);
+	assert_eq!(host.state(a), Ok(State::Connected));
+	assert_eq!(host.error(b), Ok(Some(Error::ConnectionRefused)));
+
+	// closing the listener resets what it didn't accept
+	guest.close(listener).unwrap();
+	run(&mut host, &mut guest, 0);
+	assert_eq!(host.error(a), Ok(Some(Error::ConnectionReset)));
+
+	// packets for another context are reset, resets aren't answered
+	wire.borrow_mut().log[1].clear();
+	let h = Header { src_cid: HOST_CID, dst_cid: 42, src_port: 1, dst_port: 7, ty: TYPE_STREAM, op: OP_REQUEST, ..Header::default() };
+	wire.borrow_mut().queues[1].push_back(Packet { header: h, data: Vec::new() });
+	wire.borrow_mut().queues[1].push_back(Packet { header: Header { op: OP_RST, ..h }, data: Vec::new() });
+	guest.poll(0);
+	let log = wire.borrow().log[1].clone();
+	assert_eq!(log.len(), 1);
+	assert_eq!((log[0].op, log[0].dst_cid, log[0].dst_port, log[0].src_port), (OP_RST, HOST_CID, 1, 7));
+
+	assert_eq!(host.connect(HOST_CID, 1), Err(Error::InvalidArgument));
+}
+
+#[test]
+fn timeouts_and_reset() {
+	let (wire, mut host, mut guest) = pair();
+	guest.listen(1, 4).unwrap();
+	wire.borrow_mut().drop[0] = true;
+	let client = host.connect(GUEST, 1).unwrap();
+	run(&mut host, &mut guest, CONNECT_TIMEOUT_MS - 1);
+	assert_eq!(host.state(client), Ok(State::Connecting));
+	run(&mut host, &mut guest, CONNECT_TIMEOUT_MS);
+	assert_eq!(host.error(client), Ok(Some(Error::Timeout)));
+	host.close(client).unwrap();
+	wire.borrow_mut().drop[0] = false;
+
+	// a transport reset closes all connections, listeners stay
+	let (listener, client, server) = connect(&mut host, &mut guest);
+	wire.borrow_mut().reset[1] = true;
+	guest.poll(0);
+	assert_eq!(guest.error(server), Ok(Some(Error::ConnectionReset)));
+	assert_eq!(guest.state(listener), Ok(State::Listen));
+	// the host learns of it with its next packet
+	host.send(client, b"x").unwrap();
+	run(&mut host, &mut guest, 0);
+	assert_eq!(host.error(client), Ok(Some(Error::ConnectionReset)));
+}
@@ -0,0 +1,1061 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{alloc::Layout, cell::RefCell, collections::{BTreeMap, VecDeque}, rc::Rc};
+use hw::{block::{self, BlockDevice}, dma::Dma, xhci::{*, hid::{Key, Keyboard, MouseReport}, storage::*}};
+
+const PORTS: usize = 4;
+const SLOTS: usize = 8;
+const SCRATCHPADS: u32 = 2;
+const OP: usize = 0x40;
+const RUNTIME: usize = 0x2000;
+const DOORBELLS: usize = 0x3000;
+/// RsvdZ bit the mock sets in PORTSC, any write by the driver clears it
+const PORTSC_MARKER: u32 = 1 << 28;
+const COMPLETION_CONTEXT_STATE: u8 = 19;
+const BLOCK_SIZE: usize = 512;
+const DISK_BLOCKS: usize = 1024;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Kind {
+	Keyboard,
+	Mouse,
+	Storage
+}
+
+/// What a device answers to an IN transfer.
+enum In {
+	Nak,
+	Stall,
+	Data(Vec<u8>)
+}
+
+/// A USB device behind a root hub port.
+struct MockDevice {
+	kind:          Kind,
+	speed:         u8,
+	device:        Vec<u8>,
+	config:        Vec<u8>,
+	configuration: u8,
+	/// Control requests the device received
+	requests:      Vec<SetupPacket>,
+	protocol:      Option<u16>,
+	leds:          u8,
+	/// Interrupt IN reports not yet polled
+	reports:       VecDeque<Vec<u8>>,
+	/// Mass storage: the disk, data and status wrappers to send on bulk IN, the
+	/// pending write and whether the bulk endpoints are halted
+	disk:          Vec<u8>,
+	read_only:     bool,
+	bulk_in:       VecDeque<Vec<u8>>,
+	write:         Option<(usize, usize, u32)>,
+	halted_in:     bool,
+	halted_out:    bool,
+	sense:         (u8, u8, u8),
+	ready:         bool,
+	commands:      Vec<u8>
+}
+
+fn interface(number: u8, class: u8, subclass: u8, protocol: u8, endpoints: u8) -> Vec<u8> {
+	vec![9, DESCRIPTOR_INTERFACE, number, 0, endpoints, class, subclass, protocol, 0]
+}
+
+fn endpoint(address: u8, kind: u8, mps: u16, interval: u8) -> Vec<u8> {
+	let mps = mps.to_le_bytes();
+	vec![7, DESCRIPTOR_ENDPOINT, address, kind, mps[0], mps[1], interval]
+}
+
+impl MockDevice {
+	fn new(kind: Kind) -> Self {
+		let (speed, mps0, product, body) = match kind {
+			Kind::Keyboard => (SPEED_FULL, 8, 1, [
+				interface(0, CLASS_HID, hid::SUBCLASS_BOOT, hid::PROTOCOL_KEYBOARD, 1),
+				// the HID descriptor is skipped by the parser
+				vec![9, DESCRIPTOR_HID, 0x11, 0x01, 0, 1, 0x22, 63, 0],
+				endpoint(0x81, ENDPOINT_INTERRUPT, 8, 10)
+			].concat()),
+			// full speed with a 64 byte default endpoint, the driver assumes 8 bytes first
+			Kind::Mouse => (SPEED_FULL, 64, 2, [
+				interface(0, CLASS_HID, hid::SUBCLASS_BOOT, hid::PROTOCOL_MOUSE, 1),
+				endpoint(0x81, ENDPOINT_INTERRUPT, 4, 10)
+			].concat()),
+			// 2^9 byte default endpoint
+			Kind::Storage => (SPEED_SUPER, 9, 3, [
+				interface(0, CLASS_MASS_STORAGE, SUBCLASS_SCSI, PROTOCOL_BULK_ONLY, 2),
+				endpoint(0x81, ENDPOINT_BULK, 1024, 0),
+				endpoint(0x02, ENDPOINT_BULK, 1024, 0)
+			].concat())
+		};
+		let total = (9 + body.len()) as u16;
+		let config = [vec![9, DESCRIPTOR_CONFIGURATION, total as u8, (total >> 8) as u8, 1, 1, 0, 0x80, 50], body].concat();
+		let device = vec![18, DESCRIPTOR_DEVICE, 0x00, 0x02, 0, 0, 0, mps0, 0x27, 0x06, product, 0, 0, 1, 1, 2, 3, 1];
+		Self {
+			kind,
+			speed,
+			device,
+			config,
+			configuration: 0,
+			requests:      Vec::new(),
+			protocol:      None,
+			leds:          0,
+			reports:       VecDeque::new(),
+			disk:          (0..BLOCK_SIZE * DISK_BLOCKS).map(|i| (i / BLOCK_SIZE) as u8 ^ i as u8).collect(),
+			read_only:     false,
+			bulk_in:       VecDeque::new(),
+			write:         None,
+			halted_in:     false,
+			halted_out:    false,
+			sense:         (0, 0, 0),
+			ready:         false,
+			commands:      Vec::new()
+		}
+	}
+
+	/// Answers a control request, `None` stalls.
+	fn control(&mut self, setup: SetupPacket, data: &[u8]) -> Option<Vec<u8>> {
+		self.requests.push(setup);
+		match (setup.request_type, setup.request) {
+			(REQUEST_TYPE_IN, REQUEST_GET_DESCRIPTOR) => match (setup.value >> 8) as u8 {
+				DESCRIPTOR_DEVICE        => Some(self.device.clone()),
+				DESCRIPTOR_CONFIGURATION => Some(self.config.clone()),
+				_                        => None
+			},
+			(0, REQUEST_SET_CONFIGURATION) => {
+				self.configuration = setup.value as u8;
+				Some(Vec::new())
+			}
+			(REQUEST_TYPE_ENDPOINT, REQUEST_CLEAR_FEATURE) => {
+				match setup.index {
+					0x81 => self.halted_in = false,
+					0x02 => self.halted_out = false,
+					_ => ()
+				}
+				Some(Vec::new())
+			}
+			(0x21, hid::REQUEST_SET_PROTOCOL) if self.kind != Kind::Storage => {
+				self.protocol = Some(setup.value);
+				Some(Vec::new())
+			}
+			(0x21, hid::REQUEST_SET_IDLE) if self.kind == Kind::Keyboard => Some(Vec::new()),
+			(0x21, hid::REQUEST_SET_REPORT) if self.kind == Kind::Keyboard => {
+				self.leds = data[0];
+				Some(Vec::new())
+			}
+			(0xA1, REQUEST_GET_MAX_LUN) if self.kind == Kind::Storage => Some(vec![0]),
+			(0x21, REQUEST_RESET) if self.kind == Kind::Storage => {
+				self.bulk_in.clear();
+				self.write = None;
+				Some(Vec::new())
+			}
+			_ => None
+		}
+	}
+
+	fn bulk_in(&mut self, len: usize) -> In {
+		if self.halted_in {
+			return In::Stall;
+		}
+		match self.kind {
+			Kind::Storage => match self.bulk_in.pop_front() {
+				Some(mut data) => {
+					if data.len() > len {
+						self.bulk_in.push_front(data.split_off(len));
+					}
+					In::Data(data)
+				}
+				None => In::Nak
+			},
+			_ => match self.reports.pop_front() {
+				Some(report) => In::Data(report),
+				None => In::Nak
+			}
+		}
+	}
+
+	/// Takes a command block wrapper or the data of a write, returns whether the endpoint
+	/// stalls.
+	fn bulk_out(&mut self, data: &[u8]) -> bool {
+		if self.halted_out {
+			return true;
+		}
+		if let Some((off, len, tag)) = self.write.take() {
+			assert_eq!(data.len(), len);
+			self.disk[off..off + len].copy_from_slice(data);
+			self.status(tag, CSW_PASSED, 0);
+			return false;
+		}
+
+		assert_eq!(data.len(), CBW_SIZE);
+		assert_eq!(u32::from_le_bytes(data[0..4].try_into().unwrap()), CBW_SIGNATURE);
+		let tag = u32::from_le_bytes(data[4..8].try_into().unwrap());
+		let len = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+		let is_in = data[12] & CBW_FLAG_IN != 0;
+		assert_eq!(data[13], 0);
+		let cdb = &data[15..15 + data[14] as usize];
+		self.commands.push(cdb[0]);
+
+		let be32 = |b: &[u8]| u32::from_be_bytes(b.try_into().unwrap()) as usize;
+		let reply = match cdb[0] {
+			SCSI_INQUIRY => {
+				let mut inquiry = vec![0, 0x80, 5, 2, 31, 0, 0, 0];
+				inquiry.extend_from_slice(b"QEMU    QEMU HARDDISK   2.5+");
+				Ok(inquiry)
+			}
+			SCSI_TEST_UNIT_READY if !self.ready => {
+				// power on
+				self.ready = true;
+				Err((hw::xhci::storage::SENSE_UNIT_ATTENTION, 0x29, 0))
+			}
+			SCSI_TEST_UNIT_READY | SCSI_SYNCHRONIZE_CACHE_10 => Ok(Vec::new()),
+			SCSI_REQUEST_SENSE => {
+				let (key, asc, ascq) = std::mem::take(&mut self.sense);
+				Ok(vec![0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, ascq, 0, 0, 0, 0])
+			}
+			SCSI_READ_CAPACITY_10 => Ok([((DISK_BLOCKS - 1) as u32).to_be_bytes(), (BLOCK_SIZE as u32).to_be_bytes()].concat()),
+			SCSI_MODE_SENSE_6 => Ok(vec![3, 0, if self.read_only { 0x80 } else { 0 }, 0]),
+			SCSI_READ_10 | SCSI_WRITE_10 => {
+				let (lba, blocks) = (be32(&cdb[2..6]), u16::from_be_bytes([cdb[7], cdb[8]]) as usize);
+				assert_eq!(blocks * BLOCK_SIZE, len);
+				let off = lba * BLOCK_SIZE;
+				if lba + blocks > DISK_BLOCKS {
+					Err((SENSE_ILLEGAL_REQUEST, 0x21, 0))
+				} else if cdb[0] == SCSI_READ_10 {
+					Ok(self.disk[off..off + len].to_vec())
+				} else if self.read_only {
+					Err((SENSE_DATA_PROTECT, 0x27, 0))
+				} else {
+					assert!(!is_in);
+					self.write = Some((off, len, tag));
+					return false;
+				}
+			}
+			_ => Err((SENSE_ILLEGAL_REQUEST, 0x20, 0))
+		};
+
+		match reply {
+			Ok(mut data) => {
+				data.truncate(len);
+				let residue = len - data.len();
+				if !data.is_empty() {
+					self.bulk_in.push_back(data);
+				}
+				self.status(tag, CSW_PASSED, residue);
+			}
+			Err(sense) => {
+				self.sense = sense;
+				// the device stalls the data stage instead of transferring data
+				match is_in {
+					_ if len == 0 => (),
+					true  => self.halted_in = true,
+					false => self.halted_out = true
+				}
+				self.status(tag, CSW_FAILED, len);
+			}
+		}
+		false
+	}
+
+	fn status(&mut self, tag: u32, status: u8, residue: usize) {
+		let mut csw = Vec::new();
+		csw.extend_from_slice(&CSW_SIGNATURE.to_le_bytes());
+		csw.extend_from_slice(&tag.to_le_bytes());
+		csw.extend_from_slice(&(residue as u32).to_le_bytes());
+		csw.push(status);
+		self.bulk_in.push_back(csw);
+	}
+}
+
+/// A transfer ring of the mock, the controller's dequeue pointer and cycle state. Stopped
+/// endpoints wait for their doorbell.
+#[derive(Copy, Clone, Debug)]
+struct MockRing {
+	dequeue: u64,
+	cycle:   bool,
+	halted:  bool,
+	stopped: bool
+}
+
+impl MockRing {
+	fn new(pointer: u64) -> Self {
+		Self { dequeue: pointer & !0xF, cycle: pointer & 1 != 0, halted: false, stopped: false }
+	}
+
+	/// The next TRB and the ring after it, following link TRBs.
+	fn next(mut self) -> Option<(u64, Trb, Self)> {
+		loop {
+			let trb = unsafe { (self.dequeue as *const Trb).read_volatile() };
+			if trb.cycle() != self.cycle {
+				return None;
+			}
+			if trb.kind() == TRB_LINK {
+				self.dequeue = trb.parameter;
+				self.cycle ^= trb.control & TRB_TC != 0;
+				continue;
+			}
+			let addr = self.dequeue;
+			self.dequeue += 16;
+			return Some((addr, trb, self));
+		}
+	}
+}
+
+struct MockSlot {
+	port:  usize,
+	/// Transfer rings by DCI
+	rings: BTreeMap<u8, MockRing>,
+	/// Max packet size of the default endpoint
+	mps0:  u16
+}
+
+/// Emulates a controller with `PORTS` root hub ports. Commands and transfers are processed
+/// whenever the driver stalls, interrupt IN endpoints NAK until a report is queued.
+#[derive(Default)]
+struct State {
+	regs:      Vec<u32>,
+	allocs:    BTreeMap<usize, Layout>,
+	devices:   Vec<Option<MockDevice>>,
+	/// The actual PORTSC values, without the marker
+	ports:     Vec<u32>,
+	running:   bool,
+	commands:  Option<MockRing>,
+	events:    Option<(u64, usize, usize, bool)>,
+	slots:     BTreeMap<u8, MockSlot>,
+	/// Commands the controller ran
+	log:       Vec<u32>
+}
+
+#[derive(Clone)]
+struct Mock(Rc<RefCell<State>>);
+
+impl Mock {
+	fn new(devices: Vec<Option<Kind>>) -> Self {
+		let mut state = State { regs: vec![0; (DOORBELLS + 0x100) / 4], ..State::default() };
+		state.regs[0] = 0x0100_0000 | OP as u32;
+		state.regs[1] = (PORTS as u32) << 24 | 1 << 8 | SLOTS as u32;
+		state.regs[2] = SCRATCHPADS << HCSPARAMS2_SCRATCH_LO_SHIFT;
+		state.regs[4] = HCCPARAMS1_AC64 | HCCPARAMS1_PPC;
+		state.regs[5] = DOORBELLS as u32;
+		state.regs[6] = RUNTIME as u32;
+		state.regs[(OP + 4) / 4] = USBSTS_HCH;
+		state.ports = vec![0; PORTS];
+		state.devices = (0..PORTS).map(|_| None).collect();
+		for (i, kind) in devices.into_iter().enumerate() {
+			if let Some(kind) = kind {
+				state.devices[i] = Some(MockDevice::new(kind));
+			}
+		}
+		state.reset();
+		Self(Rc::new(RefCell::new(state)))
+	}
+
+	fn regs(&self) -> *mut u8 {
+		self.0.borrow_mut().regs.as_mut_ptr() as *mut u8
+	}
+
+	fn connect(&self, port: usize, kind: Kind) {
+		let mut state = self.0.borrow_mut();
+		state.devices[port - 1] = Some(MockDevice::new(kind));
+		state.connect(port);
+	}
+
+	fn disconnect(&self, port: usize) {
+		let mut state = self.0.borrow_mut();
+		state.devices[port - 1] = None;
+		state.ports[port - 1] &= !(PORTSC_CCS | PORTSC_PED | PORTSC_SPEED_MASK);
+		state.ports[port - 1] |= PORTSC_CSC;
+		state.port_event(port);
+	}
+
+	fn device<T>(&self, port: usize, f: impl FnOnce(&mut MockDevice) -> T) -> T {
+		f(self.0.borrow_mut().devices[port - 1].as_mut().unwrap())
+	}
+
+	fn process(&self) {
+		self.0.borrow_mut().process();
+	}
+}
+
+fn port_reg(port: usize) -> usize {
+	OP + 0x400 + (port - 1) * 0x10
+}
+
+impl State {
+	fn read32(&self, off: usize) -> u32 {
+		self.regs[off / 4]
+	}
+
+	fn write32(&mut self, off: usize, v: u32) {
+		self.regs[off / 4] = v;
+	}
+
+	fn read64(&self, off: usize) -> u64 {
+		self.read32(off) as u64 | (self.read32(off + 4) as u64) << 32
+	}
+
+	fn reset(&mut self) {
+		self.running = false;
+		self.commands = None;
+		self.events = None;
+		self.slots.clear();
+		for port in 1..=PORTS {
+			self.ports[port - 1] = 0;
+			if self.devices[port - 1].is_some() {
+				self.connect(port);
+			}
+			self.sync_port(port);
+		}
+	}
+
+	fn connect(&mut self, port: usize) {
+		let speed = self.devices[port - 1].as_ref().unwrap().speed as u32;
+		let p = &mut self.ports[port - 1];
+		*p = *p & !PORTSC_SPEED_MASK | PORTSC_CCS | PORTSC_CSC | speed << PORTSC_SPEED_SHIFT;
+		// USB 3 ports train the link and enable themselves
+		if speed == SPEED_SUPER as u32 {
+			*p |= PORTSC_PED;
+		}
+		self.port_event(port);
+	}
+
+	fn sync_port(&mut self, port: usize) {
+		let v = self.ports[port - 1];
+		self.write32(port_reg(port), v | PORTSC_MARKER);
+	}
+
+	fn port_event(&mut self, port: usize) {
+		self.sync_port(port);
+		if self.running {
+			self.event(Trb::new(TRB_PORT_STATUS_CHANGE, (port as u64) << 24, (COMPLETION_SUCCESS as u32) << 24, 0));
+		}
+	}
+
+	/// Writes an event to the event ring.
+	fn event(&mut self, mut trb: Trb) {
+		let (base, size, enqueue, cycle) = self.events.expect("no event ring");
+		let dequeue = (self.read64(RUNTIME + 0x38) & !0xF) as usize;
+		assert_ne!((enqueue + 1) % size, (dequeue - base as usize) / 16 % size, "event ring full");
+		trb.control = trb.control & !TRB_CYCLE | cycle as u32;
+		unsafe { ((base as usize + enqueue * 16) as *mut Trb).write_volatile(trb) };
+		self.events = Some((base, size, (enqueue + 1) % size, cycle ^ (enqueue + 1 == size)));
+		let iman = self.read32(RUNTIME + 0x20);
+		self.write32(RUNTIME + 0x20, iman | IMAN_IP);
+	}
+
+	fn completion(&mut self, trb: u64, code: u8, slot: u8) {
+		self.event(Trb::new(TRB_COMMAND_COMPLETION, trb, (code as u32) << 24, (slot as u32) << TRB_SLOT_SHIFT));
+	}
+
+	fn transfer_event(&mut self, trb: u64, code: u8, residual: usize, slot: u8, dci: u8) {
+		self.event(Trb::new(TRB_TRANSFER_EVENT, trb, (code as u32) << 24 | residual as u32,
+			(slot as u32) << TRB_SLOT_SHIFT | (dci as u32) << TRB_EP_SHIFT));
+	}
+
+	fn process(&mut self) {
+		let usbcmd = self.read32(OP);
+		if usbcmd & USBCMD_HCRST != 0 {
+			self.write32(OP, 0);
+			self.reset();
+			return;
+		}
+		if usbcmd & USBCMD_RS != 0 && !self.running {
+			self.running = true;
+			self.write32(OP + 4, 0);
+			let crcr = self.read64(OP + 0x18);
+			self.commands = Some(MockRing::new(crcr));
+			let erst = self.read64(RUNTIME + 0x30) as *const u64;
+			let (base, size) = unsafe { (erst.read(), (erst.add(1) as *const u32).read() as usize) };
+			assert_eq!(self.read32(RUNTIME + 0x28), 1);
+			self.events = Some((base, size, 0, true));
+		} else if usbcmd & USBCMD_RS == 0 && self.running {
+			self.running = false;
+			self.write32(OP + 4, USBSTS_HCH);
+		}
+
+		for port in 1..=PORTS {
+			let written = self.read32(port_reg(port));
+			if written & PORTSC_MARKER != 0 {
+				continue;
+			}
+			let p = &mut self.ports[port - 1];
+			assert_eq!(written & (PORTSC_CCS | PORTSC_SPEED_MASK), *p & (PORTSC_CCS | PORTSC_SPEED_MASK));
+			assert_eq!(written & PORTSC_PED, 0, "writing PED disables the port");
+			*p &= !(written & PORTSC_CHANGES);
+			*p = *p & !PORTSC_PP | written & PORTSC_PP;
+			if written & PORTSC_PR != 0 && *p & PORTSC_CCS != 0 {
+				*p |= PORTSC_PED | PORTSC_PRC;
+				self.port_event(port);
+			}
+			self.sync_port(port);
+		}
+
+		if !self.running {
+			return;
+		}
+		while let Some((addr, trb, next)) = self.commands.unwrap().next() {
+			self.commands = Some(next);
+			self.command(addr, trb);
+		}
+		for (&slot, s) in self.slots.iter_mut() {
+			let doorbell = DOORBELLS / 4 + slot as usize;
+			if let Some(ring) = s.rings.get_mut(&(self.regs[doorbell] as u8)) {
+				ring.stopped = false;
+			}
+			self.regs[doorbell] = 0;
+		}
+		let rings = self.slots.iter()
+			.flat_map(|(&slot, s)| s.rings.keys().map(move |&dci| (slot, dci)))
+			.collect::<Vec<_>>();
+		for (slot, dci) in rings {
+			while self.transfer(slot, dci) {}
+		}
+	}
+
+	fn dcbaa(&self, slot: u8) -> *mut u32 {
+		unsafe { (self.read64(OP + 0x30) as *const u64).add(slot as usize).read() as *mut u32 }
+	}
+
+	fn command(&mut self, addr: u64, trb: Trb) {
+		self.log.push(trb.kind());
+		let slot = trb.slot();
+		let dci = trb.endpoint();
+		let code = match trb.kind() {
+			TRB_ENABLE_SLOT => match (1..=SLOTS as u8).find(|s| !self.slots.contains_key(s)) {
+				Some(slot) => {
+					self.slots.insert(slot, MockSlot { port: 0, rings: BTreeMap::new(), mps0: 0 });
+					return self.completion(addr, COMPLETION_SUCCESS, slot);
+				}
+				None => COMPLETION_NO_SLOTS
+			},
+			TRB_DISABLE_SLOT => match self.slots.remove(&slot) {
+				Some(_) => COMPLETION_SUCCESS,
+				None => COMPLETION_TRB
+			},
+			TRB_ADDRESS_DEVICE => unsafe {
+				let input = trb.parameter as *const u32;
+				assert_eq!(input.add(1).read(), 0b11);
+				let slot_ctx = input.add(8);
+				let port = (slot_ctx.add(1).read() >> 16 & 0xFF) as usize;
+				let speed = (slot_ctx.read() >> 20 & 0xF) as u8;
+				assert_eq!(speed, self.devices[port - 1].as_ref().unwrap().speed);
+				assert_eq!(slot_ctx.read() >> 27, 1);
+				let ep0 = input.add(16);
+				assert_eq!(ep0.add(1).read() >> 3 & 7, EP_TYPE_CONTROL);
+				let pointer = ep0.add(2).read() as u64 | (ep0.add(3).read() as u64) << 32;
+				let output = self.dcbaa(slot);
+				assert!(!output.is_null());
+				output.write(slot_ctx.read());
+				output.add(1).write(slot_ctx.add(1).read());
+				output.add(3).write(2 << 27 | slot as u32);
+				let s = self.slots.get_mut(&slot).unwrap();
+				s.port = port;
+				s.mps0 = (ep0.add(1).read() >> 16) as u16;
+				s.rings.insert(1, MockRing::new(pointer));
+				COMPLETION_SUCCESS
+			},
+			TRB_EVALUATE_CONTEXT => unsafe {
+				let input = trb.parameter as *const u32;
+				assert_eq!(input.add(1).read(), 0b10);
+				self.slots.get_mut(&slot).unwrap().mps0 = (input.add(16 + 1).read() >> 16) as u16;
+				COMPLETION_SUCCESS
+			},
+			TRB_CONFIGURE_EP => unsafe {
+				let input = trb.parameter as *const u32;
+				let add = input.add(1).read();
+				assert_eq!(add & 0b11, 1);
+				let entries = input.add(8).read() >> 27;
+				assert_eq!(entries, 31 - add.leading_zeros());
+				assert_eq!(input.add(8 + 1).read() >> 16 & 0xFF, self.slots[&slot].port as u32);
+				for dci in (2..32).filter(|i| add & 1 << i != 0) {
+					let ctx = input.add((dci as usize + 1) * 8);
+					let pointer = ctx.add(2).read() as u64 | (ctx.add(3).read() as u64) << 32;
+					let kind = ctx.add(1).read() >> 3 & 7;
+					assert_eq!(kind >= 4, dci % 2 == 1, "direction of DCI {}", dci);
+					self.slots.get_mut(&slot).unwrap().rings.insert(dci, MockRing::new(pointer));
+				}
+				let output = self.dcbaa(slot);
+				output.add(3).write(3 << 27 | slot as u32);
+				COMPLETION_SUCCESS
+			},
+			TRB_RESET_EP => match self.slots.get_mut(&slot).and_then(|s| s.rings.get_mut(&dci)) {
+				Some(ring) if ring.halted => {
+					ring.halted = false;
+					ring.stopped = true;
+					COMPLETION_SUCCESS
+				}
+				Some(_) => COMPLETION_CONTEXT_STATE,
+				None => COMPLETION_TRB
+			},
+			TRB_SET_DEQUEUE => match self.slots.get_mut(&slot).and_then(|s| s.rings.get_mut(&dci)) {
+				Some(ring) if !ring.halted && ring.stopped => {
+					*ring = MockRing { stopped: true, ..MockRing::new(trb.parameter) };
+					COMPLETION_SUCCESS
+				}
+				Some(_) => COMPLETION_CONTEXT_STATE,
+				None => COMPLETION_TRB
+			},
+			TRB_STOP_EP => match self.slots.get_mut(&slot).and_then(|s| s.rings.get_mut(&dci)) {
+				Some(ring) if !ring.halted => {
+					ring.stopped = true;
+					COMPLETION_SUCCESS
+				}
+				Some(_) => COMPLETION_CONTEXT_STATE,
+				None => COMPLETION_TRB
+			},
+			_ => COMPLETION_TRB
+		};
+		self.completion(addr, code, slot);
+	}
+
+	/// Runs the next TD on a transfer ring, returns whether there was one.
+	fn transfer(&mut self, slot: u8, dci: u8) -> bool {
+		let Some(s) = self.slots.get(&slot) else { return false };
+		let (ring, port) = (s.rings[&dci], s.port);
+		if ring.halted || ring.stopped {
+			return false;
+		}
+
+		let mut tds = Vec::new();
+		let mut next = ring;
+		loop {
+			let Some((addr, trb, after)) = next.next() else { return false };
+			tds.push((addr, trb));
+			next = after;
+			let done = match dci {
+				1 => trb.kind() == TRB_STATUS,
+				_ => trb.control & TRB_CH == 0
+			};
+			if done {
+				break;
+			}
+		}
+
+		let Some(device) = self.devices[port - 1].as_mut() else {
+			// gone, the transfers never complete
+			return false;
+		};
+		let mut events = Vec::new();
+		let stalled = match dci {
+			1 => {
+				let (_, setup) = tds[0];
+				assert_eq!(setup.kind(), TRB_SETUP);
+				assert!(setup.control & TRB_IDT != 0);
+				let packet = SetupPacket::from_u64(setup.parameter);
+				let data = tds.iter().find(|(_, t)| t.kind() == TRB_DATA).copied();
+				let (status_addr, status) = *tds.last().unwrap();
+				assert_eq!(data.map_or(0, |(_, t)| t.status as usize), packet.length as usize);
+				assert_eq!(status.control & TRB_DIR_IN != 0, data.is_none() || !packet.is_in());
+
+				let out = match (data, packet.is_in()) {
+					(Some((_, t)), false) => unsafe { std::slice::from_raw_parts(t.parameter as *const u8, t.status as usize) }.to_vec(),
+					_ => Vec::new()
+				};
+				match device.control(packet, &out) {
+					None => {
+						events.push((data.map_or(status_addr, |(a, _)| a), COMPLETION_STALL, 0));
+						true
+					}
+					Some(mut reply) => {
+						if let (Some((addr, t)), true) = (data, packet.is_in()) {
+							reply.truncate(t.status as usize);
+							unsafe { std::ptr::copy_nonoverlapping(reply.as_ptr(), t.parameter as *mut u8, reply.len()) };
+							if reply.len() < t.status as usize {
+								assert!(t.control & TRB_ISP != 0);
+								events.push((addr, COMPLETION_SHORT_PACKET, t.status as usize - reply.len()));
+							}
+						}
+						events.push((status_addr, COMPLETION_SUCCESS, 0));
+						false
+					}
+				}
+			}
+			_ if dci % 2 == 1 => {
+				let total = tds.iter().map(|(_, t)| t.status as usize).sum::<usize>();
+				match device.bulk_in(total) {
+					In::Nak => return false,
+					In::Stall => {
+						events.push((tds[0].0, COMPLETION_STALL, 0));
+						true
+					}
+					In::Data(data) => {
+						let mut off = 0;
+						for (addr, t) in &tds {
+							let n = (data.len() - off).min(t.status as usize);
+							unsafe { std::ptr::copy_nonoverlapping(data[off..].as_ptr(), t.parameter as *mut u8, n) };
+							off += n;
+							if n < t.status as usize {
+								assert!(t.control & TRB_ISP != 0);
+								events.push((*addr, COMPLETION_SHORT_PACKET, t.status as usize - n));
+								break;
+							}
+							if t.control & TRB_IOC != 0 {
+								events.push((*addr, COMPLETION_SUCCESS, 0));
+							}
+						}
+						false
+					}
+				}
+			}
+			_ => {
+				let data = tds.iter()
+					.flat_map(|(_, t)| unsafe { std::slice::from_raw_parts(t.parameter as *const u8, t.status as usize) }.iter().copied())
+					.collect::<Vec<_>>();
+				match device.bulk_out(&data) {
+					true => {
+						events.push((tds[0].0, COMPLETION_STALL, 0));
+						true
+					}
+					false => {
+						let (addr, t) = tds.last().unwrap();
+						assert!(t.control & TRB_IOC != 0);
+						events.push((*addr, COMPLETION_SUCCESS, 0));
+						false
+					}
+				}
+			}
+		};
+
+		let ring = self.slots.get_mut(&slot).unwrap().rings.get_mut(&dci).unwrap();
+		match stalled {
+			true => ring.halted = true,
+			false => *ring = next
+		}
+		for (addr, code, residual) in events {
+			self.transfer_event(addr, code, residual, slot, dci);
+		}
+		true
+	}
+}
+
+impl Dma for Mock {
+	fn alloc(&mut self, size: usize, align: usize) -> Option<(*mut u8, u64)> {
+		let layout = Layout::from_size_align(size, align).unwrap();
+		let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+		self.0.borrow_mut().allocs.insert(ptr as usize, layout);
+		Some((ptr, ptr as u64))
+	}
+
+	fn free(&mut self, virt: *mut u8, _size: usize) {
+		let layout = self.0.borrow_mut().allocs.remove(&(virt as usize)).expect("double free");
+		unsafe { std::alloc::dealloc(virt, layout) };
+	}
+
+	fn phys(&mut self, virt: *const u8) -> u64 {
+		virt as u64
+	}
+
+	fn stall(&mut self, _us: u64) {
+		self.process();
+	}
+}
+
+fn controller(devices: Vec<Option<Kind>>) -> (Mock, Controller<Mock>) {
+	let mock = Mock::new(devices);
+	let ctrl = unsafe { Controller::new(mock.regs(), mock.clone()) }.unwrap();
+	(mock, ctrl)
+}
+
+fn slot_of(ctrl: &Controller<Mock>, port: u8) -> u8 {
+	ctrl.devices().find(|d| d.port == port).unwrap().slot
+}
+
+/// Lets the controller poll the endpoints, then handles the interrupt.
+fn poll(mock: &Mock, ctrl: &mut Controller<Mock>) -> Vec<Event> {
+	mock.process();
+	ctrl.interrupt()
+}
+
+fn check_freed(mock: Mock, ctrl: Controller<Mock>) {
+	drop(ctrl);
+	assert!(mock.0.borrow().allocs.is_empty(), "leaked DMA memory");
+}
+
+#[test]
+fn layout() {
+	assert_eq!(core::mem::size_of::<Trb>(), 16);
+	assert_eq!(core::mem::size_of::<Interrupter>(), 32);
+	assert_eq!(core::mem::size_of::<CapabilityRegisters>(), 0x20);
+
+	let regs = core::mem::MaybeUninit::<OperationalRegisters>::uninit();
+	let base = regs.as_ptr() as usize;
+	unsafe {
+		assert_eq!(core::ptr::addr_of!((*regs.as_ptr()).command_ring) as usize - base, 0x18);
+		assert_eq!(core::ptr::addr_of!((*regs.as_ptr()).dcbaa) as usize - base, 0x30);
+		assert_eq!(core::ptr::addr_of!((*regs.as_ptr()).config) as usize - base, 0x38);
+		assert_eq!(core::ptr::addr_of!((*regs.as_ptr()).ports[1]) as usize - base, 0x410);
+	}
+	let regs = core::mem::MaybeUninit::<RuntimeRegisters>::uninit();
+	let base = regs.as_ptr() as usize;
+	unsafe { assert_eq!(core::ptr::addr_of!((*regs.as_ptr()).interrupters[1].dequeue) as usize - base, 0x58) };
+}
+
+#[test]
+fn descriptors() {
+	let setup = SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, 9);
+	assert_eq!(setup.to_u64(), 0x0009_0000_0200_0680);
+	assert_eq!(SetupPacket::from_u64(setup.to_u64()), setup);
+	assert!(setup.is_in());
+
+	assert_eq!(dci(0), 1);
+	assert_eq!(dci(0x81), 3);
+	assert_eq!(dci(0x02), 4);
+	assert_eq!(dci(0x8F), 31);
+
+	let device = MockDevice::new(Kind::Keyboard);
+	let d = DeviceDescriptor::parse(&device.device).unwrap();
+	assert_eq!((d.usb_version, d.vendor_id, d.product_id, d.max_packet_size, d.configurations), (0x200, 0x0627, 1, 8, 1));
+	assert_eq!(DeviceDescriptor::parse(&device.device[..17]), None);
+
+	let config = ConfigurationDescriptor::parse(&device.config).unwrap();
+	assert_eq!((config.total_length as usize, config.value), (device.config.len(), 1));
+	assert_eq!(config.interfaces.len(), 1);
+	let interface = &config.interfaces[0];
+	assert_eq!((interface.class, interface.subclass, interface.protocol), (CLASS_HID, 1, 1));
+	assert_eq!(interface.endpoints, [EndpointDescriptor { address: 0x81, attributes: ENDPOINT_INTERRUPT, max_packet_size: 8, interval: 10 }]);
+	assert!(interface.endpoints[0].is_in());
+	assert_eq!(interface.endpoints[0].dci(), 3);
+
+	// truncated descriptors end the list
+	let config = ConfigurationDescriptor::parse(&device.config[..device.config.len() - 3]).unwrap();
+	assert!(config.interfaces[0].endpoints.is_empty());
+}
+
+#[test]
+fn enumerate() {
+	let (mock, ctrl) = controller(vec![Some(Kind::Keyboard), Some(Kind::Mouse), Some(Kind::Storage), None]);
+	assert_eq!(ctrl.ports, PORTS as u8);
+	assert_eq!(ctrl.devices().count(), 3);
+
+	let keyboard = ctrl.device(slot_of(&ctrl, 1)).unwrap();
+	assert_eq!((keyboard.speed, keyboard.descriptor.product_id), (SPEED_FULL, 1));
+	assert_eq!(hid::find(keyboard), [hid::Interface { kind: hid::Kind::Keyboard, interface: 0, endpoint: 0x81 }]);
+	assert_eq!(storage::find(keyboard), None);
+	let mouse = ctrl.device(slot_of(&ctrl, 2)).unwrap();
+	assert_eq!(hid::find(mouse)[0].kind, hid::Kind::Mouse);
+	let disk = ctrl.device(slot_of(&ctrl, 3)).unwrap();
+	assert_eq!(disk.speed, SPEED_SUPER);
+	assert_eq!(storage::find(disk), Some(storage::Interface { interface: 0, bulk_in: 0x81, bulk_out: 0x02 }));
+	assert!(hid::find(disk).is_empty());
+
+	{
+		let state = mock.0.borrow();
+		// powered, the full speed ports were reset, the USB 3 port enabled itself
+		assert!(state.ports.iter().all(|p| p & PORTSC_PP != 0));
+		assert!(state.ports[..3].iter().all(|p| p & (PORTSC_PED | PORTSC_CHANGES) == PORTSC_PED));
+		assert_eq!(state.ports[3] & PORTSC_CCS, 0);
+		assert_eq!(state.log.iter().filter(|&&c| c == TRB_ADDRESS_DEVICE).count(), 3);
+		// only the mouse's default endpoint was larger than assumed
+		assert_eq!(state.log.iter().filter(|&&c| c == TRB_EVALUATE_CONTEXT).count(), 1);
+		assert_eq!(state.slots.values().map(|s| (s.port, s.mps0)).collect::<Vec<_>>(), [(1, 8), (2, 64), (3, 512)]);
+		assert_eq!(state.slots.values().map(|s| s.rings.len()).collect::<Vec<_>>(), [2, 2, 3]);
+		assert!(state.devices.iter().flatten().all(|d| d.configuration == 1));
+
+		// the scratchpad buffer array is the first entry of the DCBAA
+		let array = state.dcbaa(0) as *const u64;
+		unsafe { assert!((0..SCRATCHPADS as usize).all(|i| array.add(i).read() != 0)) };
+	}
+	check_freed(mock, ctrl);
+}
+
+#[test]
+fn keyboard() {
+	let (mock, mut ctrl) = controller(vec![Some(Kind::Keyboard), None, None, None]);
+	let slot = slot_of(&ctrl, 1);
+	let interface = hid::find(ctrl.device(slot).unwrap())[0];
+	hid::start(&mut ctrl, slot, &interface).unwrap();
+	assert_eq!(mock.device(1, |d| d.protocol), Some(hid::PROTOCOL_BOOT));
+	assert!(poll(&mock, &mut ctrl).is_empty());
+
+	let mut keyboard = Keyboard::new();
+	let reports = [
+		[hid::MOD_LEFT_SHIFT, 0, 0x05, 0, 0, 0, 0, 0],
+		[hid::MOD_LEFT_SHIFT, 0, 0x05, 0x1E, 0, 0, 0, 0],
+		// rollover, ignored
+		[0, 0, 1, 1, 1, 1, 1, 1],
+		[0, 0, 0x1E, 0, 0, 0, 0, 0]
+	];
+	mock.device(1, |d| d.reports.extend(reports.iter().map(|r| r.to_vec())));
+	let mut keys = Vec::new();
+	for _ in 0..reports.len() {
+		for event in poll(&mock, &mut ctrl) {
+			match event {
+				Event::Report { slot: s, endpoint: 0x81, data } if s == slot => keys.extend(keyboard.report(&data)),
+				e => panic!("unexpected event {:?}", e)
+			}
+		}
+	}
+	let key = |usage, pressed| Key { usage, pressed };
+	assert_eq!(keys, [
+		key(0xE1, true), key(0x05, true),
+		key(0x1E, true),
+		key(0xE1, false), key(0x05, false)
+	]);
+	assert_eq!(keyboard.modifiers(), 0);
+	assert!(poll(&mock, &mut ctrl).is_empty());
+
+	assert_eq!(hid::to_char(0x05, true), Some('B'));
+	assert_eq!(hid::to_char(0x1E, false), Some('1'));
+	assert_eq!(hid::to_char(0x1E, true), Some('!'));
+	assert_eq!(hid::to_char(0x27, false), Some('0'));
+	assert_eq!(hid::to_char(0x38, true), Some('?'));
+	assert_eq!(hid::to_char(0x3A, false), None);
+
+	hid::set_leds(&mut ctrl, slot, &interface, hid::LED_CAPS_LOCK).unwrap();
+	assert_eq!(mock.device(1, |d| d.leds), hid::LED_CAPS_LOCK);
+	check_freed(mock, ctrl);
+}
+
+#[test]
+fn mouse() {
+	let (mock, mut ctrl) = controller(vec![None, Some(Kind::Mouse), None, None]);
+	let slot = slot_of(&ctrl, 2);
+	let interface = hid::find(ctrl.device(slot).unwrap())[0];
+	// the mouse stalls SET_IDLE
+	hid::start(&mut ctrl, slot, &interface).unwrap();
+	assert!(mock.device(2, |d| d.requests.iter().any(|r| r.request == hid::REQUEST_SET_IDLE)));
+
+	mock.device(2, |d| d.reports.push_back(vec![hid::BUTTON_LEFT, 5, 0xFD]));
+	let events = poll(&mock, &mut ctrl);
+	let [Event::Report { data, .. }] = &events[..] else { panic!("{:?}", events) };
+	assert_eq!(MouseReport::parse(data), Some(MouseReport { buttons: hid::BUTTON_LEFT, x: 5, y: -3, wheel: 0 }));
+	assert_eq!(MouseReport::parse(&[0, 1]), None);
+
+	// the default endpoint recovered from the stall
+	let mut buf = [0; 18];
+	assert_eq!(ctrl.control(slot, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 18), &mut buf), Ok(18));
+	check_freed(mock, ctrl);
+}
+
+#[test]
+fn storage() {
+	let (mock, mut ctrl) = controller(vec![None, None, Some(Kind::Storage), None]);
+	let slot = slot_of(&ctrl, 3);
+	let interface = storage::find(ctrl.device(slot).unwrap()).unwrap();
+	let mut transport = BulkOnly { ctrl: &mut ctrl, slot, interface };
+	assert_eq!(transport.max_lun(), Ok(0));
+
+	// the first TEST UNIT READY reports the power on
+	let mut disk = Disk::new(transport, 0).unwrap();
+	assert_eq!((disk.block_size(), disk.blocks(), disk.is_read_only()), (BLOCK_SIZE, DISK_BLOCKS as u64, false));
+	assert_eq!(&disk.vendor, b"QEMU    ");
+	assert_eq!(&disk.product, b"QEMU HARDDISK   ");
+	assert_eq!(mock.device(3, |d| d.commands.clone()), [SCSI_INQUIRY, SCSI_TEST_UNIT_READY, SCSI_REQUEST_SENSE,
+		SCSI_TEST_UNIT_READY, SCSI_READ_CAPACITY_10, SCSI_MODE_SENSE_6]);
+
+	let expected = mock.device(3, |d| d.disk.clone());
+	let mut buf = vec![0; 160 * 1024];
+	disk.read(10, &mut buf).unwrap();
+	assert_eq!(buf, expected[10 * BLOCK_SIZE..10 * BLOCK_SIZE + buf.len()]);
+	// 64 KiB per command
+	assert_eq!(mock.device(3, |d| d.commands.iter().filter(|&&c| c == SCSI_READ_10).count()), 3);
+
+	let data = (0..4 * BLOCK_SIZE).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+	disk.write(DISK_BLOCKS as u64 - 4, &data).unwrap();
+	disk.flush().unwrap();
+	let mut back = vec![0; data.len()];
+	disk.read(DISK_BLOCKS as u64 - 4, &mut back).unwrap();
+	assert_eq!(back, data);
+	assert_eq!(mock.device(3, |d| d.disk[(DISK_BLOCKS - 4) * BLOCK_SIZE..].to_vec()), data);
+
+	assert_eq!(disk.read(DISK_BLOCKS as u64 - 1, &mut back), Err(block::Error::InvalidArgument));
+	assert_eq!(disk.read(0, &mut back[..100]), Err(block::Error::InvalidArgument));
+	check_freed(mock, ctrl);
+}
+
+#[test]
+fn storage_errors() {
+	let (mock, mut ctrl) = controller(vec![None, None, Some(Kind::Storage), None]);
+	let slot = slot_of(&ctrl, 3);
+	let interface = storage::find(ctrl.device(slot).unwrap()).unwrap();
+	let mut disk = Disk::new(BulkOnly { ctrl: &mut ctrl, slot, interface }, 0).unwrap();
+
+	// the device stalls the data stage of a command it doesn't support
+	let mut buf = [0; 64];
+	assert_eq!(disk.command(&[0xC0, 0, 0, 0, 64, 0], Data::In(&mut buf)),
+		Err(Error::Sense { key: SENSE_ILLEGAL_REQUEST, asc: 0x20, ascq: 0 }));
+	assert!(!mock.device(3, |d| d.halted_in));
+	let mut block = vec![0; BLOCK_SIZE];
+	disk.read(0, &mut block).unwrap();
+	assert_eq!(block, mock.device(3, |d| d.disk[..BLOCK_SIZE].to_vec()));
+
+	mock.device(3, |d| d.read_only = true);
+	assert_eq!(disk.write(0, &block), Err(block::Error::ReadOnly));
+	assert!(!mock.device(3, |d| d.halted_out));
+	assert_eq!(block::Error::from(Error::Stall), block::Error::Io);
+
+	// a fresh disk sees the write protection
+	let disk = Disk::new(BulkOnly { ctrl: &mut ctrl, slot, interface }, 0).unwrap();
+	assert!(disk.is_read_only());
+	check_freed(mock, ctrl);
+}
+
+#[test]
+fn hotplug() {
+	let (mock, mut ctrl) = controller(vec![None; PORTS]);
+	assert_eq!(ctrl.devices().count(), 0);
+	let baseline = mock.0.borrow().allocs.len();
+
+	mock.connect(2, Kind::Keyboard);
+	let events = poll(&mock, &mut ctrl);
+	let [Event::Attached(slot)] = events[..] else { panic!("{:?}", events) };
+	assert_eq!(ctrl.device(slot).unwrap().port, 2);
+	assert!(poll(&mock, &mut ctrl).is_empty());
+
+	let interface = hid::find(ctrl.device(slot).unwrap())[0];
+	hid::start(&mut ctrl, slot, &interface).unwrap();
+	mock.disconnect(2);
+	assert_eq!(poll(&mock, &mut ctrl), [Event::Detached { slot, port: 2 }]);
+	assert_eq!(ctrl.devices().count(), 0);
+	assert_eq!(ctrl.control(slot, SetupPacket::default(), &mut []), Err(Error::NoDevice));
+	assert_eq!(mock.0.borrow().allocs.len(), baseline);
+	assert!(mock.0.borrow().slots.is_empty());
+
+	// the slot is reused
+	mock.connect(4, Kind::Storage);
+	assert_eq!(poll(&mock, &mut ctrl), [Event::Attached(slot)]);
+	check_freed(mock, ctrl);
+}
+
+#[test]
+fn errors() {
+	// a device that fails to enumerate leaves no slot behind
+	let mock = Mock::new(vec![Some(Kind::Keyboard), None, None, None]);
+	mock.device(1, |d| d.config[1] = 0);
+	let ctrl = unsafe { Controller::new(mock.regs(), mock.clone()) }.unwrap();
+	assert_eq!(ctrl.devices().count(), 0);
+	assert!(mock.0.borrow().slots.is_empty());
+	assert!(mock.0.borrow().log.contains(&TRB_DISABLE_SLOT));
+	check_freed(mock, ctrl);
+
+	let (mock, mut ctrl) = controller(vec![Some(Kind::Keyboard), None, None, None]);
+	let slot = slot_of(&ctrl, 1);
+	let mut buf = [0; 8];
+	assert_eq!(ctrl.bulk_in(slot, 0x82, &mut buf), Err(Error::InvalidArgument));
+	assert_eq!(ctrl.bulk_in(9, 0x81, &mut buf), Err(Error::NoDevice));
+	assert_eq!(ctrl.start_reports(slot, 0x00), Err(Error::InvalidArgument));
+	// unsupported requests stall, the endpoint keeps working
+	let setup = SetupPacket { request_type: REQUEST_TYPE_IN, request: 0x42, value: 0, index: 0, length: 8 };
+	assert_eq!(ctrl.control(slot, setup, &mut buf), Err(Error::Stall));
+	assert_eq!(ctrl.control(slot, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 8), &mut buf), Ok(8));
+	assert_eq!(ctrl.control(slot, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 8192), &mut vec![0; 8192]),
+		Err(Error::InvalidArgument));
+	check_freed(mock, ctrl);
+}
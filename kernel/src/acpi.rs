@@ -0,0 +1,69 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The ACPI tables the firmware handed over, read once during boot.
+//!
+//! `init` walks the XSDT, or the RSDT before ACPI 2.0, hands the SRAT and SLIT to the NUMA
+//! topology and the FADT with the SSDTs to the power management. Tables are read through
+//! the identity mapping of physical memory.
+
+use {
+	crate::{mem::numa::{self, Topology}, svc::power},
+	alloc::vec::Vec,
+	hw::acpi::{RSDP, Table}
+};
+
+/// Reads the tables, after the memory allocator is set up and before the harts are
+/// registered.
+pub unsafe fn init(rsdp: &'static RSDP) {
+	if !rsdp.is_valid() {
+		println!("ACPI: invalid RSDP");
+		return;
+	}
+
+	let tables: Vec<Table<'static>> = match (rsdp.get_xsdt(), rsdp.get_rsdt()) {
+		(Some(Table::Xsdt(xsdt)), _) => xsdt.into_iter().collect(),
+		(_, Some(rsdt))              => rsdt.into_iter().collect(),
+		_                            => Vec::new()
+	};
+
+	let (mut fadt, mut srat, mut slit, mut ssdts) = (None, None, None, Vec::new());
+	for table in tables {
+		match table {
+			Table::Fadt(v) => fadt = Some(v),
+			Table::Srat(v) => srat = Some(v),
+			Table::Slit(v) => slit = Some(v),
+			Table::Ssdt(v) => ssdts.push(v),
+			_              => ()
+		}
+	}
+
+	numa::init(match srat {
+		Some(srat) => Topology::from_acpi(srat, slit),
+		None       => Topology::single()
+	});
+
+	match fadt {
+		Some(fadt) => power::init(fadt, &ssdts),
+		None       => println!("ACPI: no FADT, power management is not available")
+	}
+}
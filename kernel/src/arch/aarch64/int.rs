@@ -22,7 +22,13 @@ fn aarch64_int_sync() {
 
 #[no_mangle]
 fn aarch64_int_irq() {
-
+    let intid = hw::arch::ICC_IAR1_EL1.read() as u32;
+    // LPIs are the message signaled interrupts translated by the ITS
+    if intid >= hw::arch::gic::GicIts::LPI_BASE && intid < 0xFF_FFFF {
+        let vector = (intid - hw::arch::gic::GicIts::LPI_BASE) as usize % crate::int::msi::VECTORS;
+        crate::int::msi::handle(crate::hart::current(), vector);
+    }
+    hw::arch::ICC_EOIR1_EL1.write(intid as u64);
 }
 
 #[no_mangle]
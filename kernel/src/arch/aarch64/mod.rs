@@ -12,6 +12,11 @@ impl Timer {
 		hw::arch::CNTP_TVAL_EL0.set(hw::arch::CNTFRQ_EL0.get() / (1000000000000 / ns));
         hw::arch::CNTP_CTL_EL0.set(1);
     }
+
+    /// A monotonic counter for accounting, not calibrated
+    pub fn ticks(&self) -> u64 {
+        hw::arch::CNTPCT_EL0.get()
+    }
 }
 
 pub struct Context {
@@ -3,7 +3,15 @@ unsafe extern fn _start() -> ! {
     // load tables
     hw::arch::lgdt(AMD64_GDT_BASE_LEN);
     hw::arch::lidt(AMD64_IDT_BASE_LEN);
+    init_msrs();
 
+    (*AP).status = crate::hart::Hart::STATUS_BOOT_COMPLETED;
+
+    hw::arch::sti();
+}
+
+/// Sets up syscalls and the x2APIC, again after resuming from S3.
+pub(super) unsafe fn init_msrs() {
     // setup syscalls
     hw::arch::Star.set(0);
     hw::arch::LStar.set(super::int::amd64_int_syscall as _);
@@ -21,10 +29,6 @@ unsafe extern fn _start() -> ! {
     hw::arch::x2APIC_LI1V.set(0x0002_0024);
     hw::arch::x2APIC_ERRV.set(0x0002_0025);
     hw::arch::x2APIC_SIV.set(0x0002_0026);
-
-    (*AP).status = crate::hart::Hart::STATUS_BOOT_COMPLETED;
-
-    hw::arch::sti();
 }
 
 #[link_section = "AMD64_AP_trampoline"]
@@ -234,3 +234,17 @@ extern "C" fn amd64_int_ipi_hart_up() {
 extern "C" fn amd64_int_ipi_hart_down() {
     crate::hart::handle_hart_down();
 }
+
+#[no_mangle]
+extern "C" fn amd64_int_msi() {
+    // the highest in-service vector is the one being handled
+    let isr = [
+        hw::arch::x2APIC_ISR0.get(), hw::arch::x2APIC_ISR1.get(), hw::arch::x2APIC_ISR2.get(), hw::arch::x2APIC_ISR3.get(),
+        hw::arch::x2APIC_ISR4.get(), hw::arch::x2APIC_ISR5.get(), hw::arch::x2APIC_ISR6.get(), hw::arch::x2APIC_ISR7.get()
+    ];
+    if let Some(i) = (0..8).rev().find(|i| isr[*i] as u32 != 0) {
+        let vector = i * 32 + 31 - (isr[i] as u32).leading_zeros() as usize;
+        crate::int::msi::handle(crate::hart::current(), vector);
+    }
+    hw::arch::x2APIC_EOI.set(0);
+}
@@ -1,6 +1,7 @@
 
 pub mod int;
 pub mod boot;
+pub mod sleep;
 
 pub struct Hart {
     timer_frq: u64,
@@ -20,6 +21,16 @@ impl Hart {
     pub fn send_ipi(vec: usize) {
 
     }
+
+    /// The x2APIC id
+    pub fn arch_id(&self) -> u64 {
+        self.arch_id
+    }
+}
+
+/// The `Hart` executing the caller, `GS` points to it while in the kernel.
+pub fn current_hart() -> *mut crate::hart::Hart {
+    hw::arch::GsBase.get() as _
 }
 
 pub struct Timer {
@@ -35,6 +46,11 @@ impl Timer {
     pub fn get(&self) -> u64 {
         hw::arch::x2APIC_TCCR.get()
     }
+
+    /// A monotonic counter for accounting, not calibrated
+    pub fn ticks(&self) -> u64 {
+        hw::arch::rdtsc64()
+    }
 }
 
 #[inline]
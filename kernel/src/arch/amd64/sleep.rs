@@ -0,0 +1,305 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Suspend to RAM, the hart state is saved before entering S3 and restored at the
+//! waking vector.
+//!
+//! The firmware resumes in real mode at the waking vector in the FACS, so a trampoline
+//! is copied to `WAKEUP_PAGE` below 1 MiB. It enables paging with the kernel's page
+//! table and long mode in one step, which relies on the identity mapping of physical
+//! memory, and jumps to `AMD64_sleep_resume`. That reloads the descriptor tables,
+//! control registers and segment bases and returns from `AMD64_sleep_save` a second
+//! time. The MSRs set up by the boot code are lost and set again.
+
+use {
+	core::{arch::global_asm, ptr::{addr_of, addr_of_mut}},
+	crate::svi::sys::{ERR_IO, ERR_NOT_IMPLEMENTED}
+};
+
+/// The page the trampoline is copied to, below 1 MiB and reserved like the page of the
+/// AP trampoline
+pub const WAKEUP_PAGE: usize = 0x7000;
+
+/// The state of the suspending hart, the offsets are used by the assembly below.
+#[repr(C)]
+struct State {
+	rsp:            u64,
+	rbx:            u64,
+	rbp:            u64,
+	r12:            u64,
+	r13:            u64,
+	r14:            u64,
+	r15:            u64,
+	rip:            u64,
+	rflags:         u64,
+	cr0:            u64,
+	cr3:            u64,
+	cr4:            u64,
+	efer:           u64,
+	fs_base:        u64,
+	gs_base:        u64,
+	kernel_gs_base: u64,
+	/// The limit and base as stored by `sgdt`
+	gdtr:           [u64; 2],
+	idtr:           [u64; 2]
+}
+
+static mut STATE: State = State {
+	rsp: 0, rbx: 0, rbp: 0, r12: 0, r13: 0, r14: 0, r15: 0, rip: 0, rflags: 0,
+	cr0: 0, cr3: 0, cr4: 0, efer: 0, fs_base: 0, gs_base: 0, kernel_gs_base: 0,
+	gdtr: [0; 2], idtr: [0; 2]
+};
+
+extern "C" {
+	/// Saves the hart state and returns 0, returns 1 once the hart resumed.
+	fn AMD64_sleep_save(state: *mut State) -> u64;
+	fn AMD64_sleep_resume();
+
+	static AMD64_wakeup_start:  u8;
+	static AMD64_wakeup_long:   u8;
+	static AMD64_wakeup_target: u8;
+	static AMD64_wakeup_gdt:    u8;
+	static AMD64_wakeup_gdtr:   u8;
+	static AMD64_wakeup_cr0:    u8;
+	static AMD64_wakeup_cr3:    u8;
+	static AMD64_wakeup_cr4:    u8;
+	static AMD64_wakeup_efer:   u8;
+	static AMD64_wakeup_state:  u8;
+	static AMD64_wakeup_entry:  u8;
+	static AMD64_wakeup_end:    u8;
+}
+
+/// Suspends the calling hart, `enter` enters S3 with the waking vector and only returns
+/// if the platform didn't go to sleep. Returns once the hart resumed, with interrupts
+/// enabled. All other harts have to be down.
+///
+/// # Safety
+///
+/// The caller must not hold any locks the resumed hart would need before returning.
+pub unsafe fn suspend(enter: &mut dyn FnMut(u32) -> Result<(), usize>) -> Result<(), usize> {
+	hw::arch::cli();
+	let state = &mut *addr_of_mut!(STATE);
+	// returns a second time at the waking vector, all callee-saved registers and the stack
+	// are restored then, nothing is kept in the others across the call
+	if AMD64_sleep_save(state) != 0 {
+		super::boot::init_msrs();
+		hw::arch::sti();
+		return Ok(());
+	}
+
+	// the trampoline loads the page table in 32 bit mode
+	if state.cr3 > u32::MAX as u64 {
+		hw::arch::sti();
+		return Err(ERR_NOT_IMPLEMENTED);
+	}
+	install(state);
+	// the caches are lost in S3
+	core::arch::asm!("wbinvd");
+
+	let result = enter(WAKEUP_PAGE as u32);
+	hw::arch::sti();
+	// the platform didn't go to sleep
+	result.and(Err(ERR_IO))
+}
+
+/// Copies the trampoline to `WAKEUP_PAGE` and fills in its data.
+unsafe fn install(state: &State) {
+	let start = addr_of!(AMD64_wakeup_start) as usize;
+	let len = addr_of!(AMD64_wakeup_end) as usize - start;
+	let page = WAKEUP_PAGE as *mut u8;
+	core::ptr::copy_nonoverlapping(start as *const u8, page, len);
+
+	let at = |sym: *const u8| page.add(sym as usize - start);
+	let phys = |sym: *const u8| (WAKEUP_PAGE + (sym as usize - start)) as u32;
+	(at(addr_of!(AMD64_wakeup_target)) as *mut u32).write_unaligned(phys(addr_of!(AMD64_wakeup_long)));
+	(at(addr_of!(AMD64_wakeup_gdtr)).add(2) as *mut u32).write_unaligned(phys(addr_of!(AMD64_wakeup_gdt)));
+	(at(addr_of!(AMD64_wakeup_cr0)) as *mut u32).write_unaligned(state.cr0 as u32);
+	(at(addr_of!(AMD64_wakeup_cr3)) as *mut u32).write_unaligned(state.cr3 as u32);
+	(at(addr_of!(AMD64_wakeup_cr4)) as *mut u32).write_unaligned(state.cr4 as u32);
+	(at(addr_of!(AMD64_wakeup_efer)) as *mut u32).write_unaligned(state.efer as u32);
+	(at(addr_of!(AMD64_wakeup_state)) as *mut u64).write_unaligned(state as *const State as u64);
+	(at(addr_of!(AMD64_wakeup_entry)) as *mut u64).write_unaligned(AMD64_sleep_resume as usize as u64);
+}
+
+global_asm!("
+.section .text
+.global AMD64_sleep_save
+AMD64_sleep_save:
+	mov [rdi + 8], rbx
+	mov [rdi + 16], rbp
+	mov [rdi + 24], r12
+	mov [rdi + 32], r13
+	mov [rdi + 40], r14
+	mov [rdi + 48], r15
+	mov rax, [rsp]
+	mov [rdi + 56], rax
+	lea rax, [rsp + 8]
+	mov [rdi], rax
+	pushfq
+	pop rax
+	mov [rdi + 64], rax
+	mov rax, cr0
+	mov [rdi + 72], rax
+	mov rax, cr3
+	mov [rdi + 80], rax
+	mov rax, cr4
+	mov [rdi + 88], rax
+
+	mov ecx, 0xC0000080
+	rdmsr
+	mov [rdi + 96], eax
+	mov [rdi + 100], edx
+	mov ecx, 0xC0000100
+	rdmsr
+	mov [rdi + 104], eax
+	mov [rdi + 108], edx
+	mov ecx, 0xC0000101
+	rdmsr
+	mov [rdi + 112], eax
+	mov [rdi + 116], edx
+	mov ecx, 0xC0000102
+	rdmsr
+	mov [rdi + 120], eax
+	mov [rdi + 124], edx
+
+	sgdt [rdi + 128]
+	sidt [rdi + 144]
+	xor eax, eax
+	ret
+
+// entered from the trampoline with the state in rdi
+.global AMD64_sleep_resume
+AMD64_sleep_resume:
+	lgdt [rdi + 128]
+	lidt [rdi + 144]
+	mov ax, 0x10
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+	xor eax, eax
+	mov fs, ax
+	mov gs, ax
+	// the kernel's code and data segments are at the same selectors as the trampoline's
+	lea rax, [rip + 2f]
+	push 0x08
+	push rax
+	retfq
+2:
+	mov rax, [rdi + 88]
+	mov cr4, rax
+	mov rax, [rdi + 80]
+	mov cr3, rax
+	mov rax, [rdi + 72]
+	mov cr0, rax
+
+	mov ecx, 0xC0000100
+	mov eax, [rdi + 104]
+	mov edx, [rdi + 108]
+	wrmsr
+	mov ecx, 0xC0000101
+	mov eax, [rdi + 112]
+	mov edx, [rdi + 116]
+	wrmsr
+	mov ecx, 0xC0000102
+	mov eax, [rdi + 120]
+	mov edx, [rdi + 124]
+	wrmsr
+
+	mov rbx, [rdi + 8]
+	mov rbp, [rdi + 16]
+	mov r12, [rdi + 24]
+	mov r13, [rdi + 32]
+	mov r14, [rdi + 40]
+	mov r15, [rdi + 48]
+	mov rsp, [rdi]
+	push qword ptr [rdi + 64]
+	popfq
+	mov eax, 1
+	jmp qword ptr [rdi + 56]
+
+// copied to WAKEUP_PAGE, the firmware enters it in real mode with cs:ip at the page
+.section .rodata
+.balign 16
+.global AMD64_wakeup_start
+AMD64_wakeup_start:
+.code16
+	cli
+	cld
+	mov ax, cs
+	mov ds, ax
+	lgdt [AMD64_wakeup_gdtr - AMD64_wakeup_start]
+	mov eax, dword ptr [AMD64_wakeup_cr4 - AMD64_wakeup_start]
+	mov cr4, eax
+	mov eax, dword ptr [AMD64_wakeup_cr3 - AMD64_wakeup_start]
+	mov cr3, eax
+	mov ecx, 0xC0000080
+	mov eax, dword ptr [AMD64_wakeup_efer - AMD64_wakeup_start]
+	xor edx, edx
+	wrmsr
+	// protection and paging at once, long mode is active from here on
+	mov eax, dword ptr [AMD64_wakeup_cr0 - AMD64_wakeup_start]
+	mov cr0, eax
+	// jmp far 0x08:AMD64_wakeup_long with a 32 bit offset
+	.byte 0x66, 0xEA
+.global AMD64_wakeup_target
+AMD64_wakeup_target:
+	.long 0
+	.word 0x08
+.code64
+.global AMD64_wakeup_long
+AMD64_wakeup_long:
+	mov rdi, [rip + AMD64_wakeup_state]
+	jmp qword ptr [rip + AMD64_wakeup_entry]
+
+.balign 8
+.global AMD64_wakeup_gdt
+AMD64_wakeup_gdt:
+	.quad 0x0000000000000000
+	.quad 0x00209A0000000000
+	.quad 0x0000920000000000
+.global AMD64_wakeup_gdtr
+AMD64_wakeup_gdtr:
+	.word 23
+	.long 0
+.balign 4
+.global AMD64_wakeup_cr0
+AMD64_wakeup_cr0:
+	.long 0
+.global AMD64_wakeup_cr3
+AMD64_wakeup_cr3:
+	.long 0
+.global AMD64_wakeup_cr4
+AMD64_wakeup_cr4:
+	.long 0
+.global AMD64_wakeup_efer
+AMD64_wakeup_efer:
+	.long 0
+.balign 8
+.global AMD64_wakeup_state
+AMD64_wakeup_state:
+	.quad 0
+.global AMD64_wakeup_entry
+AMD64_wakeup_entry:
+	.quad 0
+.global AMD64_wakeup_end
+AMD64_wakeup_end:
+.section .text
+");
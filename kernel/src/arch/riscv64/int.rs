@@ -1,4 +1,9 @@
 #[no_mangle]
 fn riscv64_int() {
-
+    // supervisor external interrupts are delivered by the IMSIC
+    if hw::arch::scause.read() == 1 << 63 | 9 {
+        while let Some(identity) = hw::arch::imsic::Imsic::claim() {
+            crate::int::msi::handle(crate::hart::current(), identity as usize);
+        }
+    }
 }
\ No newline at end of file
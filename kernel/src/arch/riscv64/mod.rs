@@ -2,6 +2,22 @@
 pub mod int;
 pub mod boot;
 
+pub struct Hart {
+    arch_id: u64,
+}
+
+impl Hart {
+    /// The hart id passed by the SBI
+    pub fn arch_id(&self) -> u64 {
+        self.arch_id
+    }
+}
+
+/// The `Hart` executing the caller, `tp` points to it while in the kernel.
+pub fn current_hart() -> *mut crate::hart::Hart {
+    hw::arch::tp.read() as _
+}
+
 pub struct Timer {
     frq:      u64,
     mtime:    *mut u64,
@@ -12,6 +28,11 @@ impl Timer {
     pub fn set(&self, ns: u32) {
 		unsafe { *self.mtimecmp = (*self.mtime) + self.frq / (1000000000000 / ns); }
     }
+
+    /// A monotonic counter for accounting, not calibrated
+    pub fn ticks(&self) -> u64 {
+		unsafe { self.mtime.read_volatile() }
+    }
 }
 
 pub struct Context {
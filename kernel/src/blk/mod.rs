@@ -0,0 +1,478 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Block devices, backs `sys_rd_*` on `/dev/<name>`.
+//!
+//! Drivers register their devices here, which adds them to the mount trie. Disks of drivers
+//! in user space are registered by `srv` when the driver publishes them. Reads and writes
+//! go through the page cache: each cached page is a `FLAGS_TYPE_USER_CACHED` page whose
+//! `node` is the device's mount node, dirty pages are written back on `sync`. Requests to the
+//! device go through its `Queue` and are charged to the context that caused them.
+//!
+//! The partitions of a GPT disk are registered as devices of their own, `/dev/<disk>p<n>`,
+//! and found by their unique GUID.
+//!
+//! File systems access the devices they are mounted on through a `Volume`, which shares
+//! the device's cache.
+//!
+//! md RAID arrays on registered devices are assembled by `raid::assemble` and registered as
+//! `/dev/md<n>`, their members are accessed through `Volume`s as well.
+
+pub mod raid;
+
+use {
+	crate::{*, ctx::Context, mem::{NodeDescriptor, PageDescriptor}},
+	crate::svi::sys::{ERR_INVALID_ARG, ERR_IO, ERR_NOT_READY, ERR_OUT_OF_KERNEL_MEMORY, ERR_PROTECTION},
+	alloc::{boxed::Box, collections::{BTreeMap, BTreeSet}, format, rc::Rc, string::String, vec::Vec},
+	core::cell::RefCell,
+	core::{ptr::null_mut, sync::atomic::Ordering},
+	hw::{block::{self, BlockDevice, Completion, Queue, Scheduler}, gpt::Gpt}
+};
+
+const PAGE_SIZE: usize = 4096;
+
+/// A page of the cache
+struct Page {
+	desc: *mut PageDescriptor,
+	node: *mut NodeDescriptor,
+	virt: *mut u8
+}
+
+pub struct Device {
+	pub name:  String,
+	/// The device's node in the mount trie
+	pub node:  *mut mnt::Node,
+	/// Unique GUID of the partition, zero for whole disks
+	pub partid: u128,
+	dev:       Box<dyn BlockDevice>,
+	queue:     Queue<Box<dyn Scheduler>>,
+	/// Cached pages by page index
+	pages:     BTreeMap<u64, Page>,
+	dirty:     BTreeSet<u64>
+}
+
+/// The registered devices, only changed while the mount trie is locked. Boxed, as the
+/// volumes of arrays and file systems point to them.
+pub static mut DEVICES: Vec<Box<Device>> = Vec::new();
+
+fn mount_trie() -> &'static mut TrieNode<mnt::Node> {
+	// SAFETY: the trie is locked by the callers
+	unsafe { &mut *(core::ptr::addr_of!(crate::KERNEL_DATA.mnt) as *mut TrieNode<mnt::Node>) }
+}
+
+/// Registers a device as `/dev/<name>`, its block size must divide the page size.
+pub fn register(name: &str, dev: Box<dyn BlockDevice>, scheduler: Box<dyn Scheduler>) -> Result<&'static mut Device, usize> {
+	add(name, dev, scheduler, 0)
+}
+
+/// Registers a disk and, if it has a GUID partition table, its partitions. Each device gets
+/// its own queue from `scheduler`. Returns the number of partitions.
+///
+/// The disk and its partitions are cached separately, writes through one aren't seen by
+/// cached pages of the other.
+pub fn register_disk(name: &str, dev: Box<dyn BlockDevice>, scheduler: fn() -> Box<dyn Scheduler>) -> Result<usize, usize> {
+	let dev = Rc::new(RefCell::new(dev));
+	add(name, Box::new(dev.clone()), scheduler(), 0)?;
+
+	let gpt = match Gpt::open(dev) {
+		Ok(gpt) => gpt,
+		Err(_)  => return Ok(0)
+	};
+	if gpt.needs_repair() {
+		println!("blk: {}: partition table is damaged, using the intact copy", name);
+	}
+
+	let mut count = 0;
+	for (index, entry) in gpt.partitions() {
+		let guid = entry.unique_partition_guid;
+		let part = gpt.partition(guid).ok_or(ERR_INVALID_ARG)?;
+		match add(&format!("{}p{}", name, index + 1), Box::new(part), scheduler(), guid) {
+			Ok(_)  => count += 1,
+			Err(e) => println!("blk: {}p{}: failed to register: {}", name, index + 1, e)
+		}
+	}
+	Ok(count)
+}
+
+/// The partition with the unique GUID, e.g. a `ctx::HvDrive::partid`.
+pub fn partition(partid: u128) -> Option<&'static mut Device> {
+	// SAFETY: see `DEVICES`
+	unsafe { (*core::ptr::addr_of_mut!(DEVICES)).iter_mut().find(|d| partid != 0 && d.partid == partid).map(|d| &mut **d) }
+}
+
+fn add(name: &str, dev: Box<dyn BlockDevice>, scheduler: Box<dyn Scheduler>, partid: u128) -> Result<&'static mut Device, usize> {
+	if dev.block_size() == 0 || PAGE_SIZE % dev.block_size() != 0 {
+		return Err(ERR_INVALID_ARG);
+	}
+
+	let path = format!("/dev/{}", name);
+	let trie = mount_trie();
+	if trie.get(&path).is_some() {
+		return Err(ERR_INVALID_ARG);
+	}
+
+	let flags = match dev.is_read_only() {
+		true  => mnt::Node::FLAG_READ,
+		false => mnt::Node::FLAG_READ | mnt::Node::FLAG_WRITE
+	};
+	trie.insert(&path, mnt::Node { parent: null_mut(), flags, refs: 1, pages: null_mut(), users: null_mut() });
+	let node = trie.get(&path).map_or(null_mut(), |n| n as *const _ as *mut mnt::Node);
+
+	// SAFETY: see `DEVICES`
+	let devices = unsafe { &mut *core::ptr::addr_of_mut!(DEVICES) };
+	devices.push(Box::new(Device {
+		name: name.into(),
+		node,
+		partid,
+		dev,
+		queue: Queue::new(scheduler),
+		pages: BTreeMap::new(),
+		dirty: BTreeSet::new()
+	}));
+	Ok(&mut **devices.last_mut().unwrap())
+}
+
+/// Writes back and drops the device's cache, then removes it from the mount trie. Fails
+/// if the device is still open.
+pub fn unregister(name: &str) -> Result<Box<dyn BlockDevice>, usize> {
+	// SAFETY: see `DEVICES`
+	let devices = unsafe { &mut *core::ptr::addr_of_mut!(DEVICES) };
+	let i = devices.iter().position(|d| d.name == name).ok_or(ERR_INVALID_ARG)?;
+
+	// SAFETY: the node lives as long as the device
+	if unsafe { !(*devices[i].node).users.is_null() } || crate::fs::is_mounted(name) || raid::is_member(name) {
+		return Err(ERR_NOT_READY);
+	}
+
+	let mut device = *devices.remove(i);
+	let result = device.sync(None, 0, !0);
+	for index in device.pages.keys().copied().collect::<Vec<_>>() {
+		device.evict(index);
+	}
+	mount_trie().remove(&format!("/dev/{}", name));
+	result.map(|_| device.dev)
+}
+
+/// Unregisters a disk registered with `register_disk` and its partitions, file systems on
+/// them are unmounted. Fails without removing any of them if one is still open, has open
+/// files or is a member of an array.
+pub fn unregister_disk(name: &str) -> Result<(), usize> {
+	// SAFETY: see `DEVICES`
+	let devices = unsafe { &*core::ptr::addr_of!(DEVICES) };
+	let names = devices.iter()
+		.filter(|d| d.name == name || d.name.strip_prefix(name)
+			.and_then(|s| s.strip_prefix('p'))
+			.map_or(false, |n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())))
+		.map(|d| (d.name.clone(), d.node))
+		.collect::<Vec<_>>();
+
+	for (n, _) in &names {
+		crate::fs::unmount_device(n)?;
+	}
+	// SAFETY: the nodes live as long as the devices
+	if names.iter().any(|(n, node)| unsafe { !(**node).users.is_null() } || crate::fs::is_mounted(n) || raid::is_member(n)) {
+		return Err(ERR_NOT_READY);
+	}
+
+	// partitions first, the disk is written back last
+	let mut result = Ok(());
+	for (n, _) in names.iter().rev() {
+		if let Err(e) = unregister(n) {
+			result = Err(e);
+		}
+	}
+	result
+}
+
+/// The device at the given mount node.
+pub fn lookup(node: *const mnt::Node) -> Option<&'static mut Device> {
+	// SAFETY: see `DEVICES`
+	unsafe { (*core::ptr::addr_of_mut!(DEVICES)).iter_mut().find(|d| d.node as *const _ == node).map(|d| &mut **d) }
+}
+
+/// The device mounted at `path`.
+pub fn resolve(path: &str) -> Option<&'static mut Device> {
+	mount_trie().get(path).and_then(|node| lookup(node))
+}
+
+/// The mount nodes of all devices.
+pub fn nodes() -> impl Iterator<Item = *mut mnt::Node> {
+	// SAFETY: see `DEVICES`
+	unsafe { (*core::ptr::addr_of!(DEVICES)).iter().map(|d| d.node) }
+}
+
+impl Device {
+	/// Size in bytes
+	pub fn size(&self) -> u64 {
+		self.dev.blocks() * self.dev.block_size() as u64
+	}
+
+	/// Reads through the cache. If `direct` is set, clean cached pages are dropped first so
+	/// the data comes from the device.
+	pub fn read(&mut self, mut ctx: Option<&mut Context>, offset: u64, buf: &mut [u8], direct: bool) -> Result<usize, usize> {
+		let len = (buf.len() as u64).min(self.size().saturating_sub(offset)) as usize;
+		if direct {
+			let range = offset / PAGE_SIZE as u64..=(offset + len as u64) / PAGE_SIZE as u64;
+			let clean = self.pages.range(range)
+				.map(|(i, _)| *i)
+				.filter(|i| !self.dirty.contains(i))
+				.collect::<Vec<_>>();
+			clean.into_iter().for_each(|i| self.evict(i));
+		}
+
+		let mut done = 0;
+		while done < len {
+			let pos = offset + done as u64;
+			let (index, start) = (pos / PAGE_SIZE as u64, pos as usize % PAGE_SIZE);
+			let n = (PAGE_SIZE - start).min(len - done);
+			let page = self.page(ctx.as_deref_mut(), index, true)?;
+			// SAFETY: cached pages are mapped and `start + n <= PAGE_SIZE`
+			buf[done..done + n].copy_from_slice(unsafe { core::slice::from_raw_parts(page.add(start), n) });
+			done += n;
+		}
+		Ok(len)
+	}
+
+	/// Writes into the cache, the pages are written back on `sync`. If `direct` is set, they
+	/// are written back before returning.
+	pub fn write(&mut self, mut ctx: Option<&mut Context>, offset: u64, buf: &[u8], direct: bool) -> Result<usize, usize> {
+		if self.dev.is_read_only() {
+			return Err(ERR_PROTECTION);
+		}
+
+		let len = (buf.len() as u64).min(self.size().saturating_sub(offset)) as usize;
+		let mut done = 0;
+		while done < len {
+			let pos = offset + done as u64;
+			let (index, start) = (pos / PAGE_SIZE as u64, pos as usize % PAGE_SIZE);
+			let n = (PAGE_SIZE - start).min(len - done);
+			// whole pages don't need to be read first
+			let page = self.page(ctx.as_deref_mut(), index, n != PAGE_SIZE)?;
+			// SAFETY: see `read`
+			unsafe { core::slice::from_raw_parts_mut(page.add(start), n) }.copy_from_slice(&buf[done..done + n]);
+			self.dirty.insert(index);
+			done += n;
+		}
+
+		if direct {
+			self.sync(ctx, offset, len as u64)?;
+		}
+		Ok(len)
+	}
+
+	/// Writes back the dirty pages in the byte range, then flushes the device.
+	pub fn sync(&mut self, ctx: Option<&mut Context>, offset: u64, len: u64) -> Result<(), usize> {
+		let (first, last) = (offset / PAGE_SIZE as u64, offset.saturating_add(len) / PAGE_SIZE as u64);
+		let dirty = self.dirty.range(first..=last).copied().collect::<Vec<_>>();
+		let owner = ctx.map_or(null_mut(), |c| c as *mut Context) as usize;
+		let now = hart::current().timer.ticks();
+
+		for &index in &dirty {
+			let (lba, blocks) = self.blocks_of(index);
+			let mut data = block::alloc_buffer(&*self.dev, blocks as usize).map_err(error)?;
+			// SAFETY: see `read`
+			data.copy_from_slice(unsafe { core::slice::from_raw_parts(self.pages[&index].virt, data.len()) });
+			self.queue.write(&*self.dev, lba, data, owner, now).map_err(error)?;
+		}
+		self.queue.flush(owner, now);
+
+		let mut result = Ok(());
+		for completion in self.run() {
+			if completion.result.is_err() {
+				result = Err(ERR_IO);
+			} else if completion.request.op == block::Op::Write {
+				self.dirty.remove(&(completion.request.lba * self.dev.block_size() as u64 / PAGE_SIZE as u64));
+			}
+		}
+		result
+	}
+
+	/// The cached page with the given index, allocated and, if `fill` is set, read from the
+	/// device if it isn't cached.
+	fn page(&mut self, ctx: Option<&mut Context>, index: u64, fill: bool) -> Result<*mut u8, usize> {
+		if let Some(page) = self.pages.get(&index) {
+			return Ok(page.virt);
+		}
+
+		let preferred = hart::current().preferred_node;
+		// SAFETY: nodes are never freed, the page is exclusively ours until it is freed
+		let page = unsafe {
+			let (node, desc) = (*preferred).alloc(0).ok_or(ERR_OUT_OF_KERNEL_MEMORY)?;
+			(*desc).flags.store(PageDescriptor::FLAGS_TYPE_USER_CACHED, Ordering::Relaxed);
+			(*desc).refs = 1;
+			(*desc).node = self.node;
+			let ppn = (*node).get_ppn(desc);
+			let virt = ((SvData::map_page(ppn as u32, 1) as usize) << 12) as *mut u8;
+			virt.write_bytes(0, PAGE_SIZE);
+			Page { desc, node, virt }
+		};
+		let virt = page.virt;
+		self.pages.insert(index, page);
+
+		if fill {
+			let (lba, blocks) = self.blocks_of(index);
+			let owner = ctx.map_or(null_mut(), |c| c as *mut Context) as usize;
+			let id = self.queue.read(&*self.dev, lba, blocks, owner, hart::current().timer.ticks()).map_err(error)?;
+
+			let mut result = Err(ERR_IO);
+			for completion in self.run() {
+				if completion.request.id == id && completion.result.is_ok() {
+					let data = &completion.request.data;
+					// SAFETY: the page is mapped and the data at most one page
+					unsafe { virt.copy_from_nonoverlapping(data.as_ptr(), data.len()) };
+					result = Ok(virt);
+				}
+			}
+			if result.is_err() {
+				self.evict(index);
+			}
+			return result;
+		}
+		Ok(virt)
+	}
+
+	/// Drops a page from the cache without writing it back.
+	fn evict(&mut self, index: u64) {
+		if let Some(page) = self.pages.remove(&index) {
+			self.dirty.remove(&index);
+			// SAFETY: the page was allocated by `page` and isn't referenced anymore
+			unsafe {
+				SvData::unmap_page((page.virt as usize >> 12) as u32, 1);
+				(*page.node).zone_normal.free(0, page.desc);
+			}
+		}
+	}
+
+	/// The blocks backing a page, pages past the end of the device are partially backed.
+	fn blocks_of(&self, index: u64) -> (u64, u64) {
+		let size = self.dev.block_size() as u64;
+		let lba = index * PAGE_SIZE as u64 / size;
+		(lba, (PAGE_SIZE as u64 / size).min(self.dev.blocks() - lba))
+	}
+
+	/// Dispatches the queued requests and charges each to its context.
+	fn run(&mut self) -> Vec<Completion> {
+		let mut completions = Vec::new();
+		while !self.queue.is_empty() {
+			let start = hart::current().timer.ticks();
+			let batch = self.queue.dispatch(&mut *self.dev, start);
+			let elapsed = hart::current().timer.ticks().saturating_sub(start);
+			let bytes = self.dev.block_size() as u64;
+
+			for completion in &batch {
+				let ctx = completion.request.owner as *mut Context;
+				// SAFETY: the owner is blocked in the syscall that submitted the request
+				if let Some(ctx) = unsafe { ctx.as_mut() } {
+					// each device command is charged once, to the first request it served
+					let time = match completion.request.id == batch[0].request.id {
+						true  => elapsed,
+						false => 0
+					};
+					charge(ctx, completion.request.blocks * bytes, time);
+				}
+			}
+			completions.extend(batch);
+		}
+		completions
+	}
+}
+
+/// A registered device as a `BlockDevice`, for the file systems mounted on it. I/O goes
+/// through the device's cache and isn't charged to any context.
+pub struct Volume(*mut Device);
+
+impl Volume {
+	pub fn new(device: &'static mut Device) -> Self {
+		Self(device)
+	}
+
+	fn device(&self) -> &'static mut Device {
+		// SAFETY: devices with file systems mounted on them aren't unregistered
+		unsafe { &mut *self.0 }
+	}
+}
+
+impl BlockDevice for Volume {
+	fn block_size(&self) -> usize {
+		self.device().dev.block_size()
+	}
+
+	fn blocks(&self) -> u64 {
+		self.device().dev.blocks()
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		let offset = lba * self.block_size() as u64;
+		self.device().read(None, offset, buf, false).map(drop).map_err(|_| block::Error::Io)
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		let offset = lba * self.block_size() as u64;
+		self.device().write(None, offset, buf, false).map(drop).map_err(|e| match e {
+			ERR_PROTECTION => block::Error::ReadOnly,
+			_              => block::Error::Io
+		})
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		self.device().sync(None, 0, !0).map_err(|_| block::Error::Io)
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.device().dev.is_read_only()
+	}
+}
+
+/// Whether the context may issue another request, `FLAG_CTX_LIM` contexts are limited to
+/// `lim_io_*_msm`.
+pub fn admit(ctx: &Context) -> Result<(), usize> {
+	let exceeded = ctx.flags & Context::FLAG_CTX_LIM != 0 && (
+		(ctx.lim_io_ops_msm != 0 && ctx.usg_io_ops_msm >= ctx.lim_io_ops_msm)
+			|| (ctx.lim_io_rw_msm != 0 && ctx.usg_io_rw_msm >= ctx.lim_io_rw_msm)
+			|| (ctx.lim_io_time_msm != 0 && ctx.usg_io_time_msm >= ctx.lim_io_time_msm));
+	match exceeded {
+		true  => Err(ERR_NOT_READY),
+		false => Ok(())
+	}
+}
+
+/// Records a request in the context's usage: one operation, the transferred KiB and the
+/// time the device took, in timer ticks.
+fn charge(ctx: &mut Context, bytes: u64, time: u64) {
+	if ctx.flags & (Context::FLAG_CTX_USG | Context::FLAG_CTX_LIM) == 0 {
+		return;
+	}
+	ctx.usg_io_ops_msm = ctx.usg_io_ops_msm.saturating_add(1);
+	ctx.usg_io_rw_msm = ctx.usg_io_rw_msm.saturating_add(u32::try_from((bytes + 1023) / 1024).unwrap_or(u32::MAX));
+	ctx.usg_io_time_msm = ctx.usg_io_time_msm.saturating_add(u32::try_from(time).unwrap_or(u32::MAX));
+}
+
+fn error(e: block::Error) -> usize {
+	match e {
+		block::Error::InvalidArgument => ERR_INVALID_ARG,
+		block::Error::ReadOnly        => ERR_PROTECTION,
+		block::Error::NoMemory        => ERR_OUT_OF_KERNEL_MEMORY,
+		_                             => ERR_IO
+	}
+}
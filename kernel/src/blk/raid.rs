@@ -0,0 +1,229 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! md RAID arrays, registered as `/dev/md<n>`.
+//!
+//! `assemble` groups the registered devices by the array their md superblock belongs to and
+//! registers a device for each array it can assemble. It runs whenever a disk is registered,
+//! so an array is assembled as soon as enough members are there and later members are added
+//! and rebuilt. Resyncs, rebuilds and scrubs advance
+//! by `STEP_BLOCKS` blocks after each request to an array and whenever `background` is
+//! called. The attributes `RD_ATTR_RAID_*` report and control them.
+
+use {
+	super::{Device, Volume, DEVICES},
+	crate::*,
+	crate::svi::sys::{
+		ERR_INVALID_ARG, ERR_IO, ERR_NOT_IMPLEMENTED, ERR_NOT_READY, ERR_PROTECTION,
+		RD_ATTR_RAID_LEVEL, RD_ATTR_RAID_DEGRADED, RD_ATTR_RAID_ACTION, RD_ATTR_RAID_COMPLETED,
+		RD_ATTR_RAID_TOTAL, RD_ATTR_RAID_MISMATCHES, RD_ATTR_RAID_FAIL,
+		RAID_ACTION_IDLE, RAID_ACTION_RESYNC, RAID_ACTION_REBUILD, RAID_ACTION_CHECK, RAID_ACTION_REPAIR
+	},
+	alloc::{boxed::Box, format, rc::Rc, string::String, vec::Vec},
+	core::cell::RefCell,
+	hw::{block::{self, BlockDevice, Noop}, raid::{self, Action, Array}}
+};
+
+/// Blocks of background work done after each request to an array
+const STEP_BLOCKS: u64 = 128;
+
+struct Raid {
+	/// Name of the array's device
+	name:    String,
+	/// Names of the member devices
+	members: Vec<String>,
+	array:   Rc<RefCell<Array<Volume>>>
+}
+
+/// The assembled arrays, only changed while the mount trie is locked
+static mut ARRAYS: Vec<Raid> = Vec::new();
+
+fn arrays() -> &'static mut Vec<Raid> {
+	// SAFETY: see `ARRAYS`
+	unsafe { &mut *core::ptr::addr_of_mut!(ARRAYS) }
+}
+
+/// An array as registered with `blk`, which does background work after each request.
+struct ArrayDevice(Rc<RefCell<Array<Volume>>>);
+
+impl ArrayDevice {
+	fn step(&self) {
+		if let Err(e) = self.0.borrow_mut().step(STEP_BLOCKS) {
+			println!("raid: background work failed: {:?}", e);
+		}
+	}
+}
+
+impl BlockDevice for ArrayDevice {
+	fn block_size(&self) -> usize {
+		self.0.borrow().block_size()
+	}
+
+	fn blocks(&self) -> u64 {
+		self.0.borrow().blocks()
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		let result = self.0.borrow_mut().read(lba, buf);
+		self.step();
+		result
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> {
+		let result = self.0.borrow_mut().write(lba, buf);
+		self.step();
+		result
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		self.0.borrow_mut().flush()
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.0.borrow().is_read_only()
+	}
+}
+
+/// Assembles the arrays whose members are registered and registers each as `/dev/md<n>`.
+/// Returns the number of arrays.
+pub fn assemble() -> usize {
+	// SAFETY: see `DEVICES`
+	let devices = unsafe { &mut *core::ptr::addr_of_mut!(DEVICES) };
+	let mut groups: Vec<(u128, Vec<usize>)> = Vec::new();
+	for (i, device) in devices.iter_mut().enumerate() {
+		if is_member(&device.name) || arrays().iter().any(|r| r.name == device.name) {
+			continue;
+		}
+		// SAFETY: the volume is dropped before the device is used again
+		let uuid = match raid::probe(&mut Volume::new(unsafe { &mut *(&mut **device as *mut Device) })) {
+			Ok((sb, _)) => sb.set_uuid,
+			Err(_)      => continue
+		};
+		// a member that shows up after its array was assembled degraded is rebuilt
+		if let Some(raid) = arrays().iter_mut().find(|r| r.array.borrow().uuid() == uuid) {
+			let name = device.name.clone();
+			// SAFETY: the device lives as long as it is a member
+			match raid.array.borrow_mut().add(Volume::new(unsafe { &mut *(&mut **device as *mut Device) })) {
+				Ok(())  => {
+					println!("raid: {}: added {}", raid.name, name);
+					raid.members.push(name);
+				}
+				Err(e) => println!("raid: {}: failed to add {}: {:?}", raid.name, name, e)
+			}
+			continue;
+		}
+		match groups.iter_mut().find(|(u, _)| *u == uuid) {
+			Some((_, members)) => members.push(i),
+			None               => groups.push((uuid, alloc::vec![i]))
+		}
+	}
+
+	let mut count = 0;
+	for (_, members) in groups {
+		let names = members.iter().map(|i| devices[*i].name.clone()).collect::<Vec<_>>();
+		// SAFETY: the devices are distinct and live as long as they are members
+		let volumes = members.iter().map(|i| Volume::new(unsafe { &mut *(&mut *devices[*i] as *mut Device) })).collect();
+		let name = format!("md{}", arrays().len());
+		let array = match Array::assemble(volumes) {
+			Ok(array) => Rc::new(RefCell::new(array)),
+			Err(e)    => {
+				println!("raid: {}: failed to assemble {:?}: {:?}", name, names, e);
+				continue;
+			}
+		};
+
+		{
+			let array = array.borrow();
+			println!("raid: {}: {:?} with {} of {} members, {} spares", name, array.level(), array.members() - array.degraded(),
+				array.members(), array.spares());
+		}
+		match super::register(&name, Box::new(ArrayDevice(array.clone())), Box::new(Noop)) {
+			Ok(_)  => {
+				arrays().push(Raid { name, members: names, array });
+				count += 1;
+			}
+			Err(e) => println!("raid: {}: failed to register: {}", name, e)
+		}
+	}
+	count
+}
+
+/// Advances the background work of all arrays, for harts with nothing else to do.
+pub fn background() {
+	for raid in arrays().iter() {
+		if let Err(e) = raid.array.borrow_mut().step(STEP_BLOCKS) {
+			println!("raid: {}: background work failed: {:?}", raid.name, e);
+		}
+	}
+}
+
+/// Whether the device is a member of an array.
+pub fn is_member(device: &str) -> bool {
+	arrays().iter().any(|r| r.members.iter().any(|m| m == device))
+}
+
+fn lookup(device: &Device) -> Result<&'static Raid, usize> {
+	arrays().iter().find(|r| r.name == device.name).ok_or(ERR_NOT_IMPLEMENTED)
+}
+
+/// Backs `sys_get_attr` on array devices.
+pub fn get_attr(device: &Device, key: usize) -> Result<usize, usize> {
+	let array = lookup(device)?.array.borrow();
+	let (done, total) = array.progress();
+	Ok(match u32::try_from(key).map_err(|_| ERR_INVALID_ARG)? {
+		RD_ATTR_RAID_LEVEL      => array.level().md() as usize,
+		RD_ATTR_RAID_DEGRADED   => array.degraded(),
+		RD_ATTR_RAID_ACTION     => match array.action() {
+			Action::Idle    => RAID_ACTION_IDLE,
+			Action::Resync  => RAID_ACTION_RESYNC,
+			Action::Rebuild => RAID_ACTION_REBUILD,
+			Action::Check   => RAID_ACTION_CHECK,
+			Action::Repair  => RAID_ACTION_REPAIR
+		},
+		RD_ATTR_RAID_COMPLETED  => done as usize,
+		RD_ATTR_RAID_TOTAL      => total as usize,
+		RD_ATTR_RAID_MISMATCHES => array.mismatches() as usize,
+		_                       => return Err(ERR_NOT_IMPLEMENTED)
+	})
+}
+
+/// Backs `sys_set_attr` on array devices.
+pub fn set_attr(device: &Device, key: usize, val: usize) -> Result<(), usize> {
+	let mut array = lookup(device)?.array.borrow_mut();
+	let result = match u32::try_from(key).map_err(|_| ERR_INVALID_ARG)? {
+		RD_ATTR_RAID_ACTION => array.start(match val {
+			RAID_ACTION_IDLE   => Action::Idle,
+			RAID_ACTION_CHECK  => Action::Check,
+			RAID_ACTION_REPAIR => Action::Repair,
+			_                  => return Err(ERR_INVALID_ARG)
+		}),
+		RD_ATTR_RAID_FAIL   => array.fail(val),
+		_                   => return Err(ERR_NOT_IMPLEMENTED)
+	};
+	result.map_err(|e| match e {
+		raid::Error::Busy                           => ERR_NOT_READY,
+		raid::Error::Block(block::Error::ReadOnly)  => ERR_PROTECTION,
+		raid::Error::InvalidArgument
+			| raid::Error::Unsupported              => ERR_INVALID_ARG,
+		_                                           => ERR_IO
+	})
+}
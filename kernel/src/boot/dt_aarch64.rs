@@ -5,5 +5,6 @@ use crate::arch::park;
 #[naked]
 #[no_mangle]
 pub extern fn _start(x0: usize, machine_type: usize, fdt: &FdtHeader) -> ! {
+	unsafe { kernel::mem::numa::init(kernel::mem::numa::Topology::from_devtree(fdt)); }
 	park()
 }
\ No newline at end of file
@@ -54,6 +54,12 @@ pub unsafe fn AMD64_BSC(data: &kernel_sv::SvData, madt: &hw::acpi::MADT) {
     AMD64_IDT[38] = entry(amd64_int_apic_spurious, 0x8E00_0000);
     AMD64_IDT[39] = entry(amd64_int_ipi_hart_up, 0x8E00_0000);
     AMD64_IDT[39] = entry(amd64_int_ipi_hart_down, 0x8E00_0000);
+	// message signaled interrupts share one handler, which looks up the vector in the ISR
+	for vector in 0x30..AMD64_IDT.len() {
+		if vector != INTERRUPT_VECTOR_SYSCALL as usize {
+			AMD64_IDT[vector] = entry(amd64_int_msi, 0x8E00_0000);
+		}
+	}
 
 	// get TSC frequency
     let tsc = unsafe { core::arch::x86_64::__cpuid(0x15) };
@@ -84,6 +90,7 @@ pub unsafe fn AMD64_BSC(data: &kernel_sv::SvData, madt: &hw::acpi::MADT) {
 
         let hart: &mut kernel_sv::hart::Hart = ();
 		hart.status = kernel_sv::hart::Hart::STATUS_PRE_BOOT;
+		kernel_sv::hart::register(hart);
         AP = hart;
 
         let dst = apic.acpi_processor_uid << 24;
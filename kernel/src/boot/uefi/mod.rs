@@ -83,18 +83,10 @@ pub extern fn efi_main(image: hw::uefi::Handle, system_table: &hw::uefi::SystemT
 
 		gop.set_mode(mode_number);
 
-        let fb = gop.mode.frame_buffer();
-        let writer = GopConsoleWriter {
-            info:        *gop.mode,
-			framebuffer: unsafe { core::slice::from_raw_parts_mut(fb.as_mut_ptr() as *mut u32, fb.len() / 4) },
-			x:           0,
-			y:           0,
-			c:           0xFFFFFFFF
-        };
-
-        fb.fill(0);
-        unsafe { GOP_WRITER = Some(writer) };
-        crate::set_out(GopConsoleWriter::static_write);
+        if let Some(writer) = GopConsoleWriter::new(gop.mode) {
+            unsafe { GOP_WRITER = Some(writer) };
+            crate::set_out(GopConsoleWriter::static_write);
+        }
 
         println!("[BOOT/S1:ENV] GOP mode: #{} {}x{} ({:?})\n", gop.mode.mode, gop.mode.info.horizontal_resolution,
 			 gop.mode.info.vertical_resolution, gop.mode.info.pixel_format);
@@ -104,7 +96,7 @@ pub extern fn efi_main(image: hw::uefi::Handle, system_table: &hw::uefi::SystemT
             height:   gop.mode.info.vertical_resolution,
             format:   gop.mode.info.pixel_format as _,
             scanline: gop.mode.info.pixels_per_scan_line,
-            ptr:      fb,
+            ptr:      core::ptr::slice_from_raw_parts_mut(gop.mode.frame_buffer_base, gop.mode.frame_buffer_size),
         })
     };
 
@@ -135,6 +127,23 @@ pub extern fn efi_main(image: hw::uefi::Handle, system_table: &hw::uefi::SystemT
 
     let memory_map = hw::uefi::MemoryMap::new(buf, size, descriptor_size);
 
+    kernel::random::init();
+
+    // prefer the ACPI 2.0 RSDP, only the ACPI 1.0 one lacks the XSDT
+    let rsdp = system_table.configuration_table().iter()
+        .fold(None, |rsdp, table| match (rsdp, hw::uefi::CfgTable::from(*table)) {
+            (_, hw::uefi::CfgTable::Acpi20(v)) | (None, hw::uefi::CfgTable::Acpi10(v)) => Some(v),
+            (rsdp, _) => rsdp
+        });
+
+    // without ACPI tables the NUMA topology stays a single node
+    if let Some(rsdp) = rsdp {
+        unsafe { kernel::acpi::init(rsdp); }
+    }
+
+    // the mount trie is usable now, drivers of entropy devices open `/dev/random`
+    kernel::random::register();
+
     arch::init(system_table, framebuffer, memory_map);
 
 
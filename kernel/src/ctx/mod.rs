@@ -34,6 +34,7 @@ pub struct HvMemory {
 
 pub struct HvDrive {
     pub drive:    *mut (),
+	/// Unique GUID of the GPT partition, see `blk::partition`
 	pub partid:   u128,
 	pub lim_ops:  u32,
 	pub lim_rw:   u32,
@@ -60,6 +61,8 @@ pub struct Context {
 	pub cid_counter:     AtomicU32,
 	pub cid_table:       Tree<Self>,
 	pub int_mask:        u128,
+	/// Interrupts raised but not yet delivered, indexed by `svi::Interrupt`
+	pub int_pending:     u128,
 	pub int_vector:      InterruptVector,
 	pub mem_table:       *mut [u64; 512],
 	pub mem_areas:       Tree<crate::mem::VirtMemoryArea>,
@@ -126,6 +129,16 @@ impl Context {
 	pub const FLAG_MNT_WRITE_THROUGH: u32 = 1 << 12;
 	/// If FLAG_CTX_MNT is set, writes of the parent context are visible
 	pub const FLAG_MNT_READ_THROUGH:  u32 = 1 << 13;
+
+	/// Whether the context may manage hardware, e.g. change the power state.
+	pub fn is_privileged(&self) -> bool {
+		self.flags & Self::FLAG_PRIVILEGED != 0
+	}
+
+	/// Whether the context handles its own interrupts, as device drivers do.
+	pub fn is_driver(&self) -> bool {
+		self.flags & Self::FLAG_CTX_INT != 0
+	}
 }
 
 pub union InterruptVector {
@@ -137,6 +150,10 @@ pub struct ResourceDescriptor {
     pub flags:  usize,
 	pub mapped: Tree<crate::mem::VirtMemoryArea>,
 	pub node:   *mut mnt::Node,
+	/// Path of the file relative to the mount point, empty if `node` is the resource itself
+	pub path:   alloc::string::String,
+	/// The service's handle of the resource, see `srv::Channel::open`
+	pub handle: usize,
 	pub ctx:    *mut Context,
 	pub next:   *mut Self,
 	pub prev:   *mut Self
@@ -0,0 +1,351 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! File systems, backs `sys_rd_*` on the files below a mount point.
+//!
+//! A file system is mounted on a device registered with `blk` and accesses it through a
+//! `blk::Volume`, i.e. through the device's cache. The mount point is a node of the mount
+//! trie, files below it are resolved by the file system. Descriptors of files are linked
+//! into the mount point's node and record their path relative to it. `mount_devices` mounts
+//! the file systems of newly registered devices at `/mnt/<device>`.
+//!
+//! Directories shared by the host over 9P are mounted with `mount_9p` when a driver
+//! publishes the share with `srv`, the client talks to the server over the service channel
+//! rather than through `blk`.
+
+use {
+	crate::{*, blk::Volume, svi::sys::{ERR_INVALID_ARG, ERR_IO, ERR_NOT_READY, ERR_PROTECTION, RD_OPEN_RESOURCE_EXISTS, RD_OPEN_RESOURCE_NO_EXISTS}},
+	alloc::{boxed::Box, format, string::String, vec::Vec},
+	core::ptr::null_mut,
+	hw::{block::BlockDevice, btrfs, fat32, p9}
+};
+
+/// A file system, paths are relative to the mount point.
+pub trait FileSystem {
+	/// Size in bytes and whether the path is a directory
+	fn metadata(&mut self, path: &str) -> Result<(u64, bool), usize>;
+
+	fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, usize>;
+
+	fn write(&mut self, path: &str, offset: u64, buf: &[u8]) -> Result<usize, usize>;
+
+	fn truncate(&mut self, path: &str, size: u64) -> Result<(), usize>;
+
+	fn create(&mut self, path: &str, dir: bool) -> Result<(), usize>;
+
+	fn remove(&mut self, path: &str) -> Result<(), usize>;
+
+	/// Writes all changes to the device
+	fn sync(&mut self) -> Result<(), usize>;
+}
+
+impl<D: BlockDevice> FileSystem for fat32::FileSystem<D> {
+	fn metadata(&mut self, path: &str) -> Result<(u64, bool), usize> {
+		let entry = fat32::FileSystem::metadata(self, path).map_err(fat_error)?;
+		Ok((entry.size as u64, entry.is_dir()))
+	}
+
+	fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, usize> {
+		fat32::FileSystem::read(self, path, offset, buf).map_err(fat_error)
+	}
+
+	fn write(&mut self, path: &str, offset: u64, buf: &[u8]) -> Result<usize, usize> {
+		fat32::FileSystem::write(self, path, offset, buf).map_err(fat_error)
+	}
+
+	fn truncate(&mut self, path: &str, size: u64) -> Result<(), usize> {
+		fat32::FileSystem::truncate(self, path, size).map_err(fat_error)
+	}
+
+	fn create(&mut self, path: &str, dir: bool) -> Result<(), usize> {
+		match dir {
+			true  => self.create_dir(path),
+			false => self.create_file(path)
+		}.map(drop).map_err(fat_error)
+	}
+
+	fn remove(&mut self, path: &str) -> Result<(), usize> {
+		fat32::FileSystem::remove(self, path).map_err(fat_error)
+	}
+
+	fn sync(&mut self) -> Result<(), usize> {
+		fat32::FileSystem::sync(self).map_err(fat_error)
+	}
+}
+
+fn fat_error(e: fat32::Error) -> usize {
+	match e {
+		fat32::Error::NotFound                              => RD_OPEN_RESOURCE_NO_EXISTS,
+		fat32::Error::Exists                                => RD_OPEN_RESOURCE_EXISTS,
+		fat32::Error::Block(hw::block::Error::ReadOnly)     => ERR_PROTECTION,
+		fat32::Error::Block(_) | fat32::Error::Corrupted    => ERR_IO,
+		_                                                   => ERR_INVALID_ARG
+	}
+}
+
+impl<D: BlockDevice> FileSystem for btrfs::FileSystem<D> {
+	fn metadata(&mut self, path: &str) -> Result<(u64, bool), usize> {
+		let entry = btrfs::FileSystem::metadata(self, path).map_err(btrfs_error)?;
+		Ok((entry.size, entry.is_dir()))
+	}
+
+	fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, usize> {
+		btrfs::FileSystem::read(self, path, offset, buf).map_err(btrfs_error)
+	}
+
+	fn write(&mut self, path: &str, offset: u64, buf: &[u8]) -> Result<usize, usize> {
+		btrfs::FileSystem::write(self, path, offset, buf).map_err(btrfs_error)
+	}
+
+	fn truncate(&mut self, path: &str, size: u64) -> Result<(), usize> {
+		btrfs::FileSystem::truncate(self, path, size).map_err(btrfs_error)
+	}
+
+	fn create(&mut self, path: &str, dir: bool) -> Result<(), usize> {
+		match dir {
+			true  => self.create_dir(path),
+			false => self.create_file(path)
+		}.map(drop).map_err(btrfs_error)
+	}
+
+	fn remove(&mut self, path: &str) -> Result<(), usize> {
+		btrfs::FileSystem::remove(self, path).map_err(btrfs_error)
+	}
+
+	fn sync(&mut self) -> Result<(), usize> {
+		btrfs::FileSystem::sync(self).map_err(btrfs_error)
+	}
+}
+
+fn btrfs_error(e: btrfs::Error) -> usize {
+	match e {
+		btrfs::Error::NotFound                              => RD_OPEN_RESOURCE_NO_EXISTS,
+		btrfs::Error::Exists                                => RD_OPEN_RESOURCE_EXISTS,
+		btrfs::Error::Block(hw::block::Error::ReadOnly)
+			| btrfs::Error::Unsupported                     => ERR_PROTECTION,
+		btrfs::Error::Block(_) | btrfs::Error::Corrupted    => ERR_IO,
+		_                                                   => ERR_INVALID_ARG
+	}
+}
+
+impl<C: p9::Channel> FileSystem for p9::Client<C> {
+	fn metadata(&mut self, path: &str) -> Result<(u64, bool), usize> {
+		let attr = p9::Client::metadata(self, path).map_err(p9_error)?;
+		Ok((attr.size, attr.is_dir()))
+	}
+
+	fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, usize> {
+		self.read_at(path, offset, buf).map_err(p9_error)
+	}
+
+	fn write(&mut self, path: &str, offset: u64, buf: &[u8]) -> Result<usize, usize> {
+		self.write_at(path, offset, buf).map_err(p9_error)
+	}
+
+	fn truncate(&mut self, path: &str, size: u64) -> Result<(), usize> {
+		p9::Client::truncate(self, path, size).map_err(p9_error)
+	}
+
+	fn create(&mut self, path: &str, dir: bool) -> Result<(), usize> {
+		self.create_path(path, dir).map_err(p9_error)
+	}
+
+	fn remove(&mut self, path: &str) -> Result<(), usize> {
+		p9::Client::remove(self, path).map_err(p9_error)
+	}
+
+	fn sync(&mut self) -> Result<(), usize> {
+		p9::Client::sync(self).map_err(p9_error)
+	}
+}
+
+fn p9_error(e: p9::Error) -> usize {
+	match e {
+		p9::Error::Remote(p9::ENOENT)                         => RD_OPEN_RESOURCE_NO_EXISTS,
+		p9::Error::Remote(p9::EEXIST)                         => RD_OPEN_RESOURCE_EXISTS,
+		p9::Error::Remote(p9::EPERM | p9::EACCES | p9::EROFS) => ERR_PROTECTION,
+		p9::Error::Remote(p9::EIO) | p9::Error::Transport
+			| p9::Error::Protocol                             => ERR_IO,
+		_                                                     => ERR_INVALID_ARG
+	}
+}
+
+pub struct Mount {
+	pub path:   String,
+	/// Name of the `blk` device, or `9p:` followed by the tag of a 9P share
+	pub device: String,
+	/// The mount point's node in the mount trie
+	pub node:   *mut mnt::Node,
+	pub fs:     Box<dyn FileSystem>
+}
+
+/// The mounted file systems, only changed while the mount trie is locked
+pub static mut MOUNTS: Vec<Mount> = Vec::new();
+
+fn mount_trie() -> &'static mut TrieNode<mnt::Node> {
+	// SAFETY: the trie is locked by the callers
+	unsafe { &mut *(core::ptr::addr_of!(crate::KERNEL_DATA.mnt) as *mut TrieNode<mnt::Node>) }
+}
+
+fn mounts() -> &'static mut Vec<Mount> {
+	// SAFETY: see `MOUNTS`
+	unsafe { &mut *core::ptr::addr_of_mut!(MOUNTS) }
+}
+
+/// Mounts the file system on the `blk` device `device` at `path`, which must not be in use.
+/// Btrfs and FAT are detected, Btrfs file systems with features that prevent writes are
+/// mounted read-only.
+pub fn mount(path: &str, device: &str) -> Result<&'static mut Mount, usize> {
+	let path = path.trim_end_matches('/');
+	let trie = mount_trie();
+	if !path.starts_with('/') || trie.get(path).is_some() {
+		return Err(ERR_INVALID_ARG);
+	}
+
+	if blk::raid::is_member(device) {
+		return Err(ERR_NOT_READY);
+	}
+
+	let resolve = || blk::resolve(&format!("/dev/{}", device)).ok_or(ERR_INVALID_ARG);
+	let volume = Volume::new(resolve()?);
+	let mut read_only = volume.is_read_only();
+	let fs: Box<dyn FileSystem> = match btrfs::FileSystem::open(volume) {
+		Ok(fs) => {
+			read_only |= !fs.is_writable();
+			Box::new(fs)
+		}
+		Err(btrfs::Error::NotBtrfs) => Box::new(fat32::FileSystem::open(Volume::new(resolve()?)).map_err(|e| match e {
+			fat32::Error::NotFat => ERR_INVALID_ARG,
+			e                    => fat_error(e)
+		})?),
+		Err(e) => return Err(btrfs_error(e))
+	};
+
+	let flags = match read_only {
+		true  => mnt::Node::FLAG_READ,
+		false => mnt::Node::FLAG_READ | mnt::Node::FLAG_WRITE
+	};
+	Ok(insert(path, device.into(), flags, fs))
+}
+
+/// Mounts the file systems on the registered devices that aren't mounted yet, each at
+/// `/mnt/<device>`. Members of arrays are skipped. Returns the number of mounts.
+pub fn mount_devices() -> usize {
+	// SAFETY: see `blk::DEVICES`
+	let names = unsafe { (*core::ptr::addr_of!(blk::DEVICES)).iter().map(|d| d.name.clone()).collect::<Vec<_>>() };
+	let mut count = 0;
+	for name in names {
+		let path = format!("/mnt/{}", name);
+		if is_mounted(&name) || mount_trie().get(&path).is_some() || blk::raid::is_member(&name) {
+			continue;
+		}
+		// a member of an array that isn't assembled yet may look like a file system
+		let is_member = blk::resolve(&format!("/dev/{}", name))
+			.map_or(true, |device| hw::raid::probe(&mut Volume::new(device)).is_ok());
+		if is_member {
+			continue;
+		}
+
+		match mount(&path, &name) {
+			Ok(_) => {
+				println!("fs: mounted {} at {}", name, path);
+				count += 1;
+			}
+			// no file system that is supported
+			Err(ERR_INVALID_ARG) => (),
+			Err(e) => println!("fs: {}: failed to mount: {}", name, e)
+		}
+	}
+	count
+}
+
+/// Mounts the 9P share `tag` at `path`, which must not be in use. The server decides
+/// which files may be written.
+pub fn mount_9p<C: p9::Channel + 'static>(path: &str, tag: &str, client: p9::Client<C>) -> Result<&'static mut Mount, usize> {
+	let path = path.trim_end_matches('/');
+	if !path.starts_with('/') || mount_trie().get(path).is_some() {
+		return Err(ERR_INVALID_ARG);
+	}
+	Ok(insert(path, format!("9p:{}", tag), mnt::Node::FLAG_READ | mnt::Node::FLAG_WRITE, Box::new(client)))
+}
+
+/// Links a mount point into the mount trie and records the mount.
+fn insert(path: &str, device: String, flags: u32, fs: Box<dyn FileSystem>) -> &'static mut Mount {
+	let trie = mount_trie();
+	trie.insert(path, mnt::Node { parent: null_mut(), flags, refs: 1, pages: null_mut(), users: null_mut() });
+	let node = trie.get(path).map_or(null_mut(), |n| n as *const _ as *mut mnt::Node);
+
+	let mounts = mounts();
+	mounts.push(Mount { path: path.into(), device, node, fs });
+	mounts.last_mut().unwrap()
+}
+
+/// Syncs and unmounts the file system at `path`. Fails if files are still open.
+pub fn unmount(path: &str) -> Result<(), usize> {
+	let path = path.trim_end_matches('/');
+	let mounts = mounts();
+	let i = mounts.iter().position(|m| m.path == path).ok_or(ERR_INVALID_ARG)?;
+
+	// SAFETY: the node lives as long as the mount
+	if unsafe { !(*mounts[i].node).users.is_null() } {
+		return Err(ERR_NOT_READY);
+	}
+
+	mounts[i].fs.sync()?;
+	mounts.remove(i);
+	mount_trie().remove(path);
+	Ok(())
+}
+
+/// Unmounts the file systems on the `blk` device `device`. Fails if files are still open.
+pub fn unmount_device(device: &str) -> Result<(), usize> {
+	let paths = mounts().iter().filter(|m| m.device == device).map(|m| m.path.clone()).collect::<Vec<_>>();
+	paths.iter().try_for_each(|path| unmount(path))
+}
+
+/// Whether a file system is mounted on the `blk` device `device`.
+pub fn is_mounted(device: &str) -> bool {
+	mounts().iter().any(|m| m.device == device)
+}
+
+/// The mount that contains `path` and the path relative to it.
+pub fn resolve(path: &str) -> Option<(&'static mut Mount, &str)> {
+	mounts().iter_mut()
+		.filter_map(|m| {
+			let rest = path.strip_prefix(m.path.as_str())?;
+			(rest.is_empty() || rest.starts_with('/')).then(|| (m.path.len(), rest))
+				.map(|(len, rest)| (len, m as *mut Mount, rest))
+		})
+		.max_by_key(|(len, ..)| *len)
+		// SAFETY: see `MOUNTS`
+		.map(|(_, m, rest)| (unsafe { &mut *m }, rest))
+}
+
+/// The mount at the given mount node.
+pub fn lookup(node: *const mnt::Node) -> Option<&'static mut Mount> {
+	mounts().iter_mut().find(|m| m.node as *const _ == node)
+}
+
+/// The mount nodes of all mounts.
+pub fn nodes() -> impl Iterator<Item = *mut mnt::Node> {
+	mounts().iter().map(|m| m.node)
+}
@@ -18,6 +18,27 @@ pub struct Hart {
 	pub min_latency:   u32,
 	pub min_granulity: u32,
     pub min_runtime:   u64,
+    /// Message signaled interrupt vectors of this hart
+    pub msi:           int::msi::Vectors,
+}
+
+/// All harts indexed by `Hart::id`, registered during boot
+pub static mut HARTS: [*mut Hart; mem::numa::MAX_HARTS] = [core::ptr::null_mut(); mem::numa::MAX_HARTS];
+
+/// Adds a hart to `HARTS` and sets its preferred node, called by the boot code for each
+/// hart it brings up.
+pub unsafe fn register(hart: *mut Hart) {
+    let Some(h) = hart.as_mut() else { return };
+    if let Some(slot) = HARTS.get_mut(h.id as usize) {
+        mem::numa::place(h);
+        *slot = hart;
+    }
+}
+
+/// The hart executing the caller.
+pub fn current() -> &'static mut Hart {
+    // SAFETY: set by the boot code of each hart before interrupts are enabled
+    unsafe { &mut *arch::current_hart() }
 }
 
 impl Hart {
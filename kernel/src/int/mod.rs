@@ -1,4 +1,6 @@
 
+pub mod msi;
+
 #[no_mangle]
 fn handle_page_fault() {
 
@@ -0,0 +1,218 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Message signaled interrupts.
+//!
+//! Each hart has its own vector space, a driver context allocates a block of vectors on
+//! one hart and programs the returned messages into the MSI capability or MSI-X table of
+//! its device. When a vector fires, the owning context receives an `IoReady` interrupt
+//! for the `Rd` returned by `sys_int_alloc`, with the index of the vector in the block as
+//! operation id. A driver thread blocked in `sys_int_wait` on the block is woken up and
+//! gets the indices of the vectors that fired.
+
+use {crate::{ctx::Context, hart::Hart, svc::Rd}, hw::pcie::MsiMessage};
+
+/// Vectors per hart, vector 0 is never handed out.
+pub const VECTORS: usize = 256;
+
+/// The vectors of a hart that can be handed out.
+#[cfg(target_arch = "x86_64")]
+const USABLE: core::ops::Range<usize> = 0x30..VECTORS;
+#[cfg(not(target_arch = "x86_64"))]
+const USABLE: core::ops::Range<usize> = 1..VECTORS;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Route {
+	pub ctx:     *mut Context,
+	pub rd:      Rd,
+	/// Index of the vector in its block
+	pub index:   u16,
+	/// PCI requester id of the device, the ITS translates events per device
+	pub device:  u32,
+	pub masked:  bool,
+	/// Interrupts received since the context last handled the vector
+	pub pending: u32,
+	/// The thread of `ctx` that last waited for the vector in `sys_int_wait`, if any
+	pub waiter:  *mut Context
+}
+
+impl Route {
+	/// The context woken up when the vector fires.
+	pub fn target(&self) -> *mut Context {
+		match self.waiter.is_null() {
+			true  => self.ctx,
+			false => self.waiter
+		}
+	}
+}
+
+/// The vector space of a hart, part of `Hart`.
+pub struct Vectors {
+	used:   [u64; VECTORS / 64],
+	routes: [Option<Route>; VECTORS],
+	count:  usize
+}
+
+impl Vectors {
+	pub const fn new() -> Self {
+		let mut used = [0u64; VECTORS / 64];
+		let mut i = 0;
+		while i < VECTORS {
+			if i < USABLE.start || i >= USABLE.end || is_reserved(i) {
+				used[i / 64] |= 1 << (i % 64);
+			}
+			i += 1;
+		}
+		Self { used, routes: [None; VECTORS], count: 0 }
+	}
+
+	/// Number of vectors handed out, used to spread allocations over the harts.
+	pub fn count(&self) -> usize {
+		self.count
+	}
+
+	fn is_used(&self, vector: usize) -> bool {
+		self.used[vector / 64] & 1 << (vector % 64) != 0
+	}
+
+	fn set_used(&mut self, vector: usize, used: bool) {
+		match used {
+			true  => self.used[vector / 64] |= 1 << (vector % 64),
+			false => self.used[vector / 64] &= !(1 << (vector % 64))
+		}
+	}
+
+	/// Allocates a naturally aligned block of `count` vectors routed to the descriptor
+	/// `rd` returns for the base vector. `count` must be a power of two, as multi-message
+	/// MSI sets the lower bits of the data to the vector index.
+	pub fn alloc(&mut self, count: usize, ctx: *mut Context, device: u32, rd: impl FnOnce(u16) -> Rd) -> Option<(u16, Rd)> {
+		if !count.is_power_of_two() || count > 32 {
+			return None;
+		}
+
+		let base = (0..VECTORS).step_by(count).find(|base| (*base..base + count).all(|v| !self.is_used(v)))?;
+		let rd = rd(base as u16);
+		for (index, vector) in (base..base + count).enumerate() {
+			self.set_used(vector, true);
+			self.routes[vector] = Some(Route { ctx, rd, index: index as u16, device, masked: false, pending: 0, waiter: core::ptr::null_mut() });
+		}
+		self.count += count;
+		Some((base as u16, rd))
+	}
+
+	/// Frees all vectors routed to `rd`.
+	pub fn free(&mut self, rd: Rd) -> usize {
+		let mut freed = 0;
+		for vector in 0..VECTORS {
+			if self.routes[vector].map_or(false, |r| r.rd == rd) {
+				self.routes[vector] = None;
+				self.set_used(vector, false);
+				freed += 1;
+			}
+		}
+		self.count -= freed;
+		freed
+	}
+
+	pub fn route(&mut self, vector: usize) -> Option<&mut Route> {
+		self.routes.get_mut(vector)?.as_mut()
+	}
+
+	pub fn routes(&mut self, rd: Rd) -> impl Iterator<Item = (usize, &mut Route)> + '_ {
+		self.routes.iter_mut().enumerate().filter_map(move |(v, r)| r.as_mut().filter(|r| r.rd == rd).map(|r| (v, r)))
+	}
+}
+
+/// The syscall vector and the vectors used by the local APIC and IPIs.
+#[cfg(target_arch = "x86_64")]
+const fn is_reserved(vector: usize) -> bool {
+	vector == crate::arch::int::INTERRUPT_VECTOR_SYSCALL as usize
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+const fn is_reserved(_: usize) -> bool {
+	false
+}
+
+/// Composes the message of a vector on a hart.
+#[cfg(target_arch = "x86_64")]
+pub fn message(hart: &Hart, vector: u16, _device: u32) -> Option<MsiMessage> {
+	MsiMessage::x2apic(hart.int.arch_id() as u32, vector as u8)
+}
+
+/// Composes the message of a vector on a hart. Each hart owns `VECTORS` LPIs and a
+/// collection with its id, the event id is the LPI's offset, so the ITT of a device
+/// has to be mapped with `EVENT_BITS` by the ITS driver.
+#[cfg(target_arch = "aarch64")]
+pub fn message(hart: &Hart, vector: u16, device: u32) -> Option<MsiMessage> {
+	let its = unsafe { ITS.as_mut()? };
+	let event = hart.id * VECTORS as u32 + vector as u32;
+	unsafe {
+		its.1.map_event(device, event, hw::arch::gic::GicIts::LPI_BASE + event, hart.id as u16);
+		its.1.sync(hart.int.arch_id());
+	}
+	Some(MsiMessage::gic_its(its.0, event))
+}
+
+/// Event id bits of a device's ITT, covers the vectors of all harts.
+#[cfg(target_arch = "aarch64")]
+pub const EVENT_BITS: u8 = 16;
+
+/// Composes the message of a vector on a hart, which is its identity in the hart's
+/// supervisor interrupt file.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", target_arch = "riscv128"))]
+pub fn message(hart: &Hart, vector: u16, _device: u32) -> Option<MsiMessage> {
+	let (base, stride) = unsafe { IMSIC? };
+	Some(MsiMessage::imsic(hw::arch::imsic::Imsic::interrupt_file(base, stride, hart.id as usize), vector as u32))
+}
+
+/// The ITS and the physical address of its control frame.
+#[cfg(target_arch = "aarch64")]
+pub static mut ITS: Option<(u64, hw::arch::gic::GicIts)> = None;
+
+/// Base address and per-hart stride of the IMSIC supervisor interrupt files.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", target_arch = "riscv128"))]
+pub static mut IMSIC: Option<(u64, u64)> = None;
+
+/// Delivers a vector that fired on `hart` to the context it is routed to.
+pub fn handle(hart: &mut Hart, vector: usize) {
+	let route = match hart.msi.route(vector) {
+		Some(route) => route,
+		None => return
+	};
+
+	route.pending = route.pending.saturating_add(1);
+	if !route.masked {
+		// SAFETY: routes are removed before their context is freed
+		unsafe { raise(&mut *route.target()) };
+	}
+}
+
+/// Marks `IoReady` pending on a context and wakes it up, the pending routes of the
+/// context tell which vectors fired.
+pub fn raise(ctx: &mut Context) {
+	ctx.int_pending |= 1 << crate::svi::Interrupt::IoReady as u32;
+	if ctx.sch_state == Context::STATE_BLOCKED && ctx.flags & Context::FLAG_INT_MASKED == 0 {
+		// SAFETY: a blocked context is owned by the hart it was scheduled on
+		unsafe { (*ctx.sch_hart).enqueue(ctx) };
+	}
+}
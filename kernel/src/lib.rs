@@ -14,7 +14,10 @@
 )]
 #![allow(incomplete_features)]
 
+pub mod acpi;
 pub mod arch;
+pub mod blk;
+pub mod fs;
 pub mod ctx;
 pub mod hart;
 pub mod int;
@@ -22,6 +25,8 @@ pub mod mem;
 pub mod misc;
 pub mod mnt;
 pub mod log;
+pub mod random;
+pub mod srv;
 pub mod svc;
 pub mod svi;
 pub mod hvc;
@@ -19,6 +19,8 @@ extern crate rlibc;
 
 pub use misc::std;
 
+pub mod blk;
+pub mod fs;
 pub mod ctx;
 pub mod int;
 pub mod mnt;
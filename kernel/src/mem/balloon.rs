@@ -0,0 +1,208 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Giving memory back to the host, backs the `sys_mem_balloon` syscall.
+//!
+//! The balloon driver inflates the balloon with pages taken from the zones, they are typed
+//! `FLAGS_TYPE_BALLOON` and counted in `balloon_pages` until the driver deflates it again.
+//!
+//! Free page reporting works on free blocks of `REPORT_ORDER`: the driver isolates blocks
+//! that weren't reported yet, so they can't be allocated while the host discards them,
+//! and puts them back afterwards with `FLAGS_REPORTED` set on the head. A block loses the
+//! flag once it is allocated, split or merged, so it is reported again after it was used.
+//!
+//! The statistics are taken from the zone counters.
+
+use {
+	super::{NodeDescriptor, PageDescriptor, ZoneDescriptor},
+	crate::{hart, svi::sys::{BALLOON_REPORT_ORDER, ERR_INVALID_ARG}},
+	core::{ptr::null_mut, sync::atomic::Ordering},
+	hw::virtio::memory_ballooning::{STAT_MEMFREE, STAT_MEMTOT, STAT_AVAIL}
+};
+
+/// Order of the reported blocks, 2 MiB with 4 KiB pages; blocks of a lower order would
+/// merge with their buddies while they are in the free lists
+pub const REPORT_ORDER: usize = BALLOON_REPORT_ORDER;
+const _: () = assert!(REPORT_ORDER == PageDescriptor::MAX_PAGE_ORDER as usize);
+
+const PAGE_SIZE: u64 = 4096;
+
+impl ZoneDescriptor {
+	/// Takes a page for the balloon, null if the zone has no free page.
+	pub unsafe fn balloon_take(&mut self) -> *mut PageDescriptor {
+		let page = self.alloc(0);
+		if let Some(p) = page.as_mut() {
+			p.flags.store(PageDescriptor::FLAGS_TYPE_BALLOON, Ordering::Relaxed);
+			self.balloon_pages.fetch_add(1, Ordering::SeqCst);
+		}
+		page
+	}
+
+	/// Returns a page taken by `balloon_take`, false if it isn't one.
+	pub unsafe fn balloon_return(&mut self, page: *mut PageDescriptor) -> bool {
+		if (*page).flags.load(Ordering::Relaxed) & PageDescriptor::FLAGS_TYPE_MASK != PageDescriptor::FLAGS_TYPE_BALLOON {
+			return false;
+		}
+		self.balloon_pages.fetch_sub(1, Ordering::SeqCst);
+		self.free(0, page);
+		true
+	}
+
+	/// Takes a free block of `REPORT_ORDER` that wasn't reported yet out of the free
+	/// lists, null if there is none.
+	pub unsafe fn isolate_unreported(&mut self) -> *mut PageDescriptor {
+		let area = &mut self.free_areas[REPORT_ORDER];
+		let page = match PageDescriptor::iter(area.next)
+			.find(|p| (**p).flags.load(Ordering::Relaxed) & PageDescriptor::FLAGS_REPORTED == 0)
+		{
+			Some(page) => page,
+			None       => return null_mut()
+		};
+		area.remove(page);
+		(*page).flags.fetch_or(PageDescriptor::FLAGS_ISOLATED, Ordering::Relaxed);
+		self.managed_pages.fetch_sub(1 << REPORT_ORDER, Ordering::SeqCst);
+		page
+	}
+
+	/// Puts a block from `isolate_unreported` back into the free lists, as reported. False
+	/// if `page` isn't the head of an isolated block.
+	pub unsafe fn putback_reported(&mut self, page: *mut PageDescriptor) -> bool {
+		if (*page).flags.load(Ordering::Relaxed) & PageDescriptor::FLAGS_ISOLATED == 0 {
+			return false;
+		}
+		self.add_compound_page(page, REPORT_ORDER);
+		(*page).flags.fetch_or(PageDescriptor::FLAGS_REPORTED, Ordering::Relaxed);
+		self.managed_pages.fetch_add(1 << REPORT_ORDER, Ordering::SeqCst);
+		self.reported_pages.fetch_add(1 << REPORT_ORDER, Ordering::SeqCst);
+		true
+	}
+
+	/// Called before the free block with the head `page` is allocated, split or merged.
+	pub(super) fn forget_reported(&mut self, page: &PageDescriptor, order: usize) {
+		if page.flags.load(Ordering::Relaxed) & PageDescriptor::FLAGS_REPORTED != 0 {
+			self.reported_pages.fetch_sub(1 << order, Ordering::SeqCst);
+		}
+	}
+}
+
+/// The preferred node of the calling hart and all others, by distance.
+fn nodes() -> impl Iterator<Item = &'static mut NodeDescriptor> {
+	let first = match hart::current().preferred_node {
+		node if node.is_null() => super::numa::first_node().map_or(null_mut(), |n| n as *mut _),
+		node                   => node
+	};
+	// SAFETY: nodes are set up during boot and never freed
+	let fallback = unsafe { first.as_ref() }.map_or([null_mut(); super::numa::MAX_NODES], |n| n.fallback);
+	core::iter::once(first)
+		.chain(fallback.into_iter())
+		.take_while(|node| !node.is_null())
+		.map(|node| unsafe { &mut *node })
+}
+
+/// The node and page descriptor of a page frame number.
+fn page(pfn: u32) -> Option<(&'static mut NodeDescriptor, *mut PageDescriptor)> {
+	nodes()
+		.find(|n| pfn >= n.first_page && pfn - n.first_page < n.spanned_pages)
+		.map(|n| {
+			let page = n.get_page(pfn as usize);
+			(n, page)
+		})
+}
+
+/// Takes up to `pfns.len()` pages for the balloon, nearest first, and stores their page
+/// frame numbers. Returns how many were taken.
+pub fn inflate(pfns: &mut [u32]) -> usize {
+	let mut taken = 0;
+	for node in nodes() {
+		while taken < pfns.len() {
+			// SAFETY: see `nodes`
+			let page = unsafe { node.zone_normal.balloon_take() };
+			if page.is_null() {
+				break;
+			}
+			pfns[taken] = node.get_ppn(page) as u32;
+			taken += 1;
+		}
+	}
+	taken
+}
+
+/// Returns pages of the balloon. Fails with `ERR_INVALID_ARG` at the first page that isn't
+/// in the balloon, the ones before it are returned.
+pub fn deflate(pfns: &[u32]) -> Result<(), usize> {
+	for pfn in pfns {
+		let (node, page) = page(*pfn).ok_or(ERR_INVALID_ARG)?;
+		// SAFETY: see `nodes`
+		if !unsafe { node.zone_normal.balloon_return(page) } {
+			return Err(ERR_INVALID_ARG);
+		}
+	}
+	Ok(())
+}
+
+/// Isolates up to `pfns.len()` unreported free blocks of `REPORT_ORDER` and stores the
+/// page frame numbers of their first pages. Returns how many were isolated.
+pub fn report(pfns: &mut [u32]) -> usize {
+	let mut isolated = 0;
+	for node in nodes() {
+		while isolated < pfns.len() {
+			// SAFETY: see `nodes`
+			let page = unsafe { node.zone_normal.isolate_unreported() };
+			if page.is_null() {
+				break;
+			}
+			pfns[isolated] = node.get_ppn(page) as u32;
+			isolated += 1;
+		}
+	}
+	isolated
+}
+
+/// Puts blocks isolated by `report` back, as reported. Fails with `ERR_INVALID_ARG` at the
+/// first page frame number that isn't the start of an isolated block, the ones before it
+/// are put back.
+pub fn reported(pfns: &[u32]) -> Result<(), usize> {
+	for pfn in pfns {
+		let (node, page) = page(*pfn).ok_or(ERR_INVALID_ARG)?;
+		// SAFETY: see `nodes`
+		if !unsafe { node.zone_normal.putback_reported(page) } {
+			return Err(ERR_INVALID_ARG);
+		}
+	}
+	Ok(())
+}
+
+/// The statistics for the host, `(STAT_*, value in bytes)`.
+pub fn stats() -> [(u16, u64); 3] {
+	let (mut present, mut free, mut balloon) = (0u64, 0u64, 0u64);
+	for node in nodes() {
+		let zone = &node.zone_normal;
+		present += zone.present_pages as u64;
+		free    += zone.managed_pages.load(Ordering::Relaxed) as u64;
+		balloon += zone.balloon_pages.load(Ordering::Relaxed) as u64;
+	}
+	[
+		(STAT_MEMTOT,  (present - balloon.min(present)) * PAGE_SIZE),
+		(STAT_MEMFREE, free * PAGE_SIZE),
+		(STAT_AVAIL,   free * PAGE_SIZE)
+	]
+}
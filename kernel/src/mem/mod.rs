@@ -22,6 +22,9 @@
 
 use core::{ptr::null_mut, sync::atomic::*};
 
+pub mod numa;
+pub mod balloon;
+
 const PAGE_SHIFT:      usize = 12;
 const MIN_CACHE_ORDER: usize = 3;
 const MAX_CACHE_ORDER: usize = 12;
@@ -32,6 +35,7 @@ const MAX_NODE_PAGES:  usize = (1 << 32) - 1;
 #[derive(Debug)]
 pub struct NodeDescriptor {
     pub flags:         u32,
+	pub id:            u32,
 	pub first_page:    u32,
 	pub spanned_pages: u32,
 	pub present_pages: u32,
@@ -40,6 +44,10 @@ pub struct NodeDescriptor {
 	#[cfg(target_arch = "x86_64")]
 	pub zone_dma32:    ZoneDescriptor,
 	pub zone_normal:   ZoneDescriptor,
+	/// Distance to each node as reported by the firmware, indexed by node id
+	pub distances:     [u8; numa::MAX_NODES],
+	/// The other nodes ordered by distance, terminated by a null pointer
+	pub fallback:      [*mut NodeDescriptor; numa::MAX_NODES],
 	pub pages:         [PageDescriptor; MAX_NODE_PAGES]
 }
 
@@ -51,6 +59,19 @@ impl NodeDescriptor {
 	pub fn get_page(&self, ppn: usize) -> *mut PageDescriptor {
         &self.pages[ppn - self.first_page] as *const PageDescriptor as _
     }
+
+	/// Allocates pages from this node or, if it is exhausted, from the nearest node
+	/// that has free pages. Returns the node the pages were taken from.
+	pub unsafe fn alloc(&mut self, order: usize) -> Option<(*mut NodeDescriptor, *mut PageDescriptor)> {
+        let fallback = self.fallback;
+
+        core::iter::once(self as *mut Self)
+			.chain(fallback.into_iter().take_while(|node| !node.is_null()))
+			.find_map(|node| {
+				let page = (*node).zone_normal.alloc(order);
+				(!page.is_null()).then_some((node, page))
+			})
+    }
 }
 
 
@@ -169,6 +190,10 @@ pub struct ZoneDescriptor {
 	pub alloc_pages:   [u32; PageDescriptor::MAX_PAGE_ORDER as usize + 1],
 	pub alloc_cache:   [AtomicPtr<CacheEntry>; MAX_CACHE_ORDER - MIN_CACHE_ORDER + 1],
 	pub alloc_heap:    AtomicPtr<HeapEntry>,
+	/// Pages given to the host by the balloon, not part of `managed_pages`
+	pub balloon_pages: AtomicU32,
+	/// Free pages in blocks the host was told about, part of `managed_pages`
+	pub reported_pages: AtomicU32,
 	pub node:          *mut NodeDescriptor
 }
 
@@ -223,6 +248,7 @@ impl ZoneDescriptor {
         while page < end_aligned {
             let pg = page.as_mut().unwrap();
             self.free_areas[pg.idx].remove(pg);
+            self.forget_reported(pg, pg.idx);
             page = page.add(1 << pg.idx);
         }
 
@@ -257,6 +283,7 @@ impl ZoneDescriptor {
 
         let pages = core::slice::from_raw_parts_mut(areas.pop_front(), 1 << i);
         self.managed_pages.fetch_sub(1 << order, Ordering::SeqCst);
+        self.forget_reported(&pages[0], i);
 
         // update page desc
 		pages[0] = PageDescriptor {
@@ -369,12 +396,18 @@ impl PageDescriptor {
     pub const FLAGS_TYPE_USER:           u32 = 0x4;
     pub const FLAGS_TYPE_USER_CACHED:    u32 = 0x5;
     pub const FLAGS_TYPE_FIRMWARE:       u32 = 0x6;
+    /// Given to the host by the balloon
+    pub const FLAGS_TYPE_BALLOON:        u32 = 0x7;
     pub const FLAGS_COMPOUND:            u32 = 0x8;
     pub const FLAGS_PINNED:              u32 = 0x10;
     pub const FLAGS_LOCKED:              u32 = 0x20;
     pub const FLAGS_LOCKED_EXCLUSIVE:    u32 = 0x40;
     pub const FLAGS_ORDER_MASK:          u32 = 0xF80;
     pub const FLAGS_ORDER_SHIFT:         u32 = 7;
+    /// The head of a free block the host was told about, see `balloon`
+    pub const FLAGS_REPORTED:            u32 = 0x1000;
+    /// The head of a free block taken out of the free lists to be reported
+    pub const FLAGS_ISOLATED:            u32 = 0x2000;
     pub const MAX_PAGE_ORDER:            u32 = 9;
 
     pub fn get_head(&mut self) -> *mut PageDescriptor {
@@ -477,8 +510,13 @@ pub fn handle_page_fault(ctx: &ctx::Context, addr: usize, attr: usize) {
             old_page.refs.store(1, Ordering::SeqCst);
             old_ppn as usize
         } else {
-            let page = unsafe { node.zone_normal.alloc(0).as_mut().unwrap() };
-            let ppn = node.get_ppn(page);
+            // harts without a preferred node, e.g. before the topology was applied, use the first node
+            let preferred = unsafe { ctx.sch_hart.as_ref() }
+                .and_then(|hart| unsafe { hart.preferred_node.as_mut() })
+                .or_else(numa::first_node)
+                .unwrap_or(node);
+            let (node, page) = unsafe { preferred.alloc(0).unwrap() };
+            let ppn = unsafe { (*node).get_ppn(page) };
             unsafe { ((old_ppn << 12) as *mut usize).copy_to_nonoverlapping(
                     (ppn << 12) as *mut usize, (1 << PAGE_SHIFT) / core::mem::size_of::<usize>()); }
 			ppn
@@ -0,0 +1,347 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! NUMA topology discovery from the ACPI SRAT/SLIT or the device tree.
+//!
+//! The topology is collected into fixed size tables before any memory can be allocated
+//! and then applied to the `NodeDescriptor`s and harts, one node per proximity domain.
+//! Without SRAT or `numa-node-id` properties all memory ends up in a single node.
+//!
+//! The boot code hands the topology to `init`, before the harts are registered. The
+//! node descriptors are placed by `set_nodes` once the memory map has been laid out,
+//! harts registered before that prefer no node and allocate from the first one.
+
+use {super::NodeDescriptor, crate::hart::{self, Hart}, core::ptr::null_mut};
+use hw::{acpi::{SRAT, SLIT, StaticResourceAllocation}, devtree::{FdtHeader, FdtStructureIter, FdtStructureToken, FdtValue}};
+
+pub const MAX_NODES:  usize = 64;
+pub const MAX_RANGES: usize = 128;
+pub const MAX_HARTS:  usize = 256;
+
+/// The distance of a node to itself.
+pub const LOCAL_DISTANCE:  u8 = 10;
+/// Assumed between different nodes if the firmware doesn't provide distances.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// The topology found during boot
+static mut TOPOLOGY: Topology = Topology::single();
+/// The node descriptors, indexed by node, and their number
+static mut NODES: (*mut NodeDescriptor, usize) = (null_mut(), 0);
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MemoryRange {
+	pub node:       u8,
+	pub first_page: u32,
+	pub pages:      u32
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HartAffinity {
+	/// APIC id, MPIDR or hart id, depending on the architecture
+	pub arch_id: u32,
+	pub node:    u8
+}
+
+pub struct Topology {
+	/// Proximity domain (ACPI) or `numa-node-id` (device tree) of each node
+	pub domains:   [u32; MAX_NODES],
+	pub nodes:     usize,
+	pub ranges:    [MemoryRange; MAX_RANGES],
+	pub range_len: usize,
+	pub harts:     [HartAffinity; MAX_HARTS],
+	pub hart_len:  usize,
+	/// Distance from one node to another, indexed by node
+	pub distances: [[u8; MAX_NODES]; MAX_NODES]
+}
+
+impl Topology {
+	pub const fn new() -> Self {
+		Self {
+			domains:   [0; MAX_NODES],
+			nodes:     0,
+			ranges:    [MemoryRange { node: 0, first_page: 0, pages: 0 }; MAX_RANGES],
+			range_len: 0,
+			harts:     [HartAffinity { arch_id: 0, node: 0 }; MAX_HARTS],
+			hart_len:  0,
+			distances: [[0; MAX_NODES]; MAX_NODES]
+		}
+	}
+
+	/// A single node that all memory and harts belong to, for firmware without SRAT or
+	/// `numa-node-id` properties.
+	pub const fn single() -> Self {
+		let mut topo = Self::new();
+		topo.nodes = 1;
+		topo.distances[0][0] = LOCAL_DISTANCE;
+		topo
+	}
+
+	/// Collects the topology from the SRAT, disabled entries are ignored. If there is
+	/// no SLIT, or it lacks a domain, the default distances are used.
+	pub fn from_acpi(srat: &SRAT, slit: Option<&SLIT>) -> Self {
+		let mut topo = Self::new();
+
+		for entry in srat {
+			if !entry.is_enabled() {
+				continue;
+			}
+
+			let node = match topo.node(entry.proximity_domain()) {
+				Some(v) => v,
+				None    => continue
+			};
+
+			match entry {
+				StaticResourceAllocation::ProcessorLocalApicSapicAffinity(v) =>
+					topo.add_hart(v.apic_id as _, node),
+				StaticResourceAllocation::ProcessorLocalX2ApicAffinity(v) =>
+					topo.add_hart(v.x2_apic_id, node),
+				StaticResourceAllocation::GiccAffinity(v) =>
+					topo.add_hart(v.acpi_processor_uid, node),
+				StaticResourceAllocation::MemoryAffinity(v) =>
+					topo.add_range(v.base_address, v.range_length, node),
+				StaticResourceAllocation::GicInterruptTranslationServiceAffinity(_) => ()
+			}
+		}
+
+		for from in 0..topo.nodes {
+			for to in 0..topo.nodes {
+				topo.distances[from][to] = slit
+					.and_then(|slit| slit.distance(topo.domains[from] as _, topo.domains[to] as _))
+					.unwrap_or_else(|| default_distance(from, to));
+			}
+		}
+
+		match topo.nodes {
+			0 => Self::single(),
+			_ => topo
+		}
+	}
+
+	/// Collects the topology from the `numa-node-id` properties of the memory and cpu
+	/// nodes and the optional `numa-distance-map-v1` distance map.
+	pub fn from_devtree(fdt: &FdtHeader) -> Self {
+		let mut topo = Self::new();
+
+		for token in fdt.get(&["", "memory"]) {
+			let tokens = match token {
+				FdtStructureToken::BeginNone { tokens, .. } => tokens,
+				_ => continue
+			};
+
+			let node = match topo.node(prop::<u32>(tokens, "numa-node-id").unwrap_or(0)) {
+				Some(v) => v,
+				None    => continue
+			};
+
+			// assumes #address-cells = #size-cells = 2, as on all supported platforms
+			let reg = match prop::<&[u8]>(tokens, "reg") {
+				Some(v) => v,
+				None    => continue
+			};
+
+			for v in reg.chunks_exact(16) {
+				let base = u64::from_be_bytes(v[..8].try_into().unwrap());
+				let len  = u64::from_be_bytes(v[8..].try_into().unwrap());
+				topo.add_range(base, len, node);
+			}
+		}
+
+		for token in fdt.get(&["", "cpus", "cpu"]) {
+			let tokens = match token {
+				FdtStructureToken::BeginNone { tokens, .. } => tokens,
+				_ => continue
+			};
+
+			let (id, node) = match (prop::<u32>(tokens, "reg"), topo.node(prop::<u32>(tokens, "numa-node-id").unwrap_or(0))) {
+				(Some(id), Some(node)) => (id, node),
+				_ => continue
+			};
+
+			topo.add_hart(id, node);
+		}
+
+		for from in 0..topo.nodes {
+			for to in 0..topo.nodes {
+				topo.distances[from][to] = default_distance(from, to);
+			}
+		}
+
+		let matrix = fdt.get(&["", "distance-map", "distance-matrix"]).find_map(|t| match t {
+			FdtStructureToken::Prop { value, .. } => Some(value),
+			_ => None
+		});
+
+		for v in matrix.iter().flat_map(|v| v.chunks_exact(12)) {
+			let cell = |i: usize| u32::from_be_bytes(v[i * 4..i * 4 + 4].try_into().unwrap());
+
+			if let (Some(from), Some(to)) = (topo.find(cell(0)), topo.find(cell(1))) {
+				topo.distances[from][to] = cell(2).min(u8::MAX as _) as _;
+			}
+		}
+
+		match topo.nodes {
+			0 => Self::single(),
+			_ => topo
+		}
+	}
+
+	/// Returns the node of the given proximity domain, adding it if it is new.
+	fn node(&mut self, domain: u32) -> Option<usize> {
+		if let Some(node) = self.find(domain) {
+			return Some(node);
+		}
+
+		if self.nodes == MAX_NODES {
+			return None;
+		}
+
+		self.domains[self.nodes] = domain;
+		self.nodes += 1;
+		Some(self.nodes - 1)
+	}
+
+	pub fn find(&self, domain: u32) -> Option<usize> {
+		self.domains[..self.nodes].iter().position(|v| *v == domain)
+	}
+
+	fn add_range(&mut self, base: u64, len: u64, node: usize) {
+		// partial pages can't be managed
+		let first = (base + 0xFFF) >> 12;
+		let last  = (base + len) >> 12;
+
+		if last <= first || self.range_len == MAX_RANGES {
+			return;
+		}
+
+		self.ranges[self.range_len] = MemoryRange { node: node as _, first_page: first as _, pages: (last - first) as _ };
+		self.range_len += 1;
+	}
+
+	fn add_hart(&mut self, arch_id: u32, node: usize) {
+		if self.hart_len < MAX_HARTS {
+			self.harts[self.hart_len] = HartAffinity { arch_id, node: node as _ };
+			self.hart_len += 1;
+		}
+	}
+
+	/// Returns the node a hart is closest to.
+	pub fn hart_node(&self, arch_id: u32) -> Option<usize> {
+		self.harts[..self.hart_len].iter().find(|v| v.arch_id == arch_id).map(|v| v.node as _)
+	}
+
+	/// Returns the other nodes ordered by their distance from `node`, unreachable
+	/// nodes are left out.
+	pub fn fallback(&self, node: usize) -> ([u8; MAX_NODES], usize) {
+		let mut order = [0u8; MAX_NODES];
+		let mut len   = 0;
+
+		for other in (0..self.nodes).filter(|v| *v != node && self.distances[node][*v] != SLIT::UNREACHABLE_DISTANCE) {
+			order[len] = other as _;
+			len += 1;
+		}
+
+		order[..len].sort_unstable_by_key(|v| (self.distances[node][*v as usize], *v));
+		(order, len)
+	}
+
+	/// Fills in the spans, distances and fallback lists of `nodes`, which are indexed by
+	/// node. Nodes without memory ranges, e.g. the only node of `single`, keep the span
+	/// the memory map gave them.
+	pub fn apply(&self, nodes: &mut [NodeDescriptor]) {
+		let base = nodes.as_mut_ptr();
+
+		for (i, desc) in nodes.iter_mut().enumerate().take(self.nodes) {
+			desc.id = i as _;
+			desc.distances[..self.nodes].copy_from_slice(&self.distances[i][..self.nodes]);
+
+			let ranges = self.ranges[..self.range_len].iter().filter(|v| v.node as usize == i);
+			if let (Some(first), Some(last)) = (ranges.clone().map(|v| v.first_page).min(), ranges.clone().map(|v| v.first_page + v.pages).max()) {
+				desc.first_page    = first;
+				desc.spanned_pages = last - first;
+				desc.present_pages = ranges.map(|v| v.pages).sum();
+			}
+
+			let (order, len) = self.fallback(i);
+			desc.fallback = [null_mut(); MAX_NODES];
+			for (slot, node) in desc.fallback.iter_mut().zip(&order[..len]) {
+				*slot = unsafe { base.add(*node as _) };
+			}
+		}
+	}
+
+	/// The node descriptor a hart prefers, harts without an affinity entry use the first
+	/// node.
+	pub fn preferred(&self, nodes: &mut [NodeDescriptor], hart: &Hart) -> *mut NodeDescriptor {
+		match nodes.len() {
+			0 => null_mut(),
+			len => unsafe { nodes.as_mut_ptr().add(self.hart_node(hart.id).unwrap_or(0).min(len - 1)) }
+		}
+	}
+}
+
+/// Records the topology found during boot. Called once, before the harts are registered.
+pub unsafe fn init(topo: Topology) {
+	crate::println!("numa: {} node(s), {} memory range(s), {} hart affinity entries", topo.nodes, topo.range_len, topo.hart_len);
+	TOPOLOGY = topo;
+}
+
+/// Applies the topology to the node descriptors, indexed by node, and updates the
+/// preferred node of the harts registered so far. Called once the memory map has been
+/// laid out.
+pub unsafe fn set_nodes(nodes: &'static mut [NodeDescriptor]) {
+	let topo = &*core::ptr::addr_of!(TOPOLOGY);
+	topo.apply(nodes);
+	NODES = (nodes.as_mut_ptr(), nodes.len().min(topo.nodes));
+
+	for hart in hart::HARTS.iter().filter_map(|h| h.as_mut()) {
+		place(hart);
+	}
+}
+
+/// Sets the preferred node of a hart, null until the node descriptors are set.
+pub fn place(hart: &mut Hart) {
+	// SAFETY: only changed during boot, see `init` and `set_nodes`
+	let (topo, (base, len)) = unsafe { (&*core::ptr::addr_of!(TOPOLOGY), NODES) };
+	hart.preferred_node = match base.is_null() {
+		true  => null_mut(),
+		false => topo.preferred(unsafe { core::slice::from_raw_parts_mut(base, len) }, hart)
+	};
+}
+
+/// The first node, which harts without a preferred node allocate from.
+pub fn first_node() -> Option<&'static mut NodeDescriptor> {
+	// SAFETY: see `place`
+	unsafe { NODES.0.as_mut() }
+}
+
+fn default_distance(from: usize, to: usize) -> u8 {
+	if from == to { LOCAL_DISTANCE } else { REMOTE_DISTANCE }
+}
+
+/// Returns the value of the property `name` among the direct properties of a node.
+fn prop<'a, T>(tokens: FdtStructureIter<'a>, name: &str) -> Option<T> where FdtValue<'a>: Into<Option<T>> {
+	tokens.take_while(|t| !matches!(t, FdtStructureToken::BeginNone { .. })).find_map(|t| match t {
+		FdtStructureToken::Prop { name: n, value } if n == name => value.into(),
+		_ => None
+	})
+}
@@ -0,0 +1,273 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The entropy pool, backs `sys_random` and reads of `/dev/random`.
+//!
+//! The pool is a `hw::random::Pool`. It is seeded during boot from the random number
+//! generator of the processor, if it has one (`rdseed`/`rdrand` on amd64, `RNDR` on
+//! aarch64, the Zkr `seed` CSR on RISC-V), and from the jitter of the timer. Drivers of
+//! entropy devices, e.g. virtio-rng, add to it with `add`. Every request mixes in fresh
+//! output of the processor's generator before the pool is read.
+//!
+//! Writes to `/dev/random` are mixed in without being credited. Privileged and driver
+//! contexts credit them afterwards by setting `RD_ATTR_RANDOM_ENTROPY`, at most 8 bits
+//! per byte written since the last credit.
+
+use {
+	crate::{hart, mnt, misc::trie::TrieNode, svi::sys::{ERR_INVALID_ARG, ERR_NOT_IMPLEMENTED, ERR_NOT_READY, ERR_PROTECTION, RD_ATTR_RANDOM_ENTROPY, RD_ATTR_RANDOM_SEEDED}},
+	core::{ptr::null_mut, sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering}},
+	hw::random::{self, Pool}
+};
+
+pub const PATH: &str = "/dev/random";
+
+/// Timer samples taken for the initial seed
+const JITTER_SAMPLES: usize = 512;
+
+static mut POOL: Pool = Pool::new();
+/// Protects `POOL`, which is used by all harts
+static LOCK: AtomicBool = AtomicBool::new(false);
+/// The usable instruction, one of `CPU_*`, set by `init`
+static CPU: AtomicU8 = AtomicU8::new(CPU_NONE);
+/// Bytes written to `/dev/random` since the last credit
+static UNCREDITED: AtomicUsize = AtomicUsize::new(0);
+/// The node of `/dev/random`
+static mut NODE: *mut mnt::Node = null_mut();
+
+const CPU_NONE:   u8 = 0;
+const CPU_RDSEED: u8 = 1;
+const CPU_RDRAND: u8 = 2;
+const CPU_RNDR:   u8 = 3;
+const CPU_SEED:   u8 = 4;
+
+fn mount_trie() -> &'static mut TrieNode<mnt::Node> {
+	// SAFETY: the trie is locked by the callers
+	unsafe { &mut *(core::ptr::addr_of!(crate::KERNEL_DATA.mnt) as *mut TrieNode<mnt::Node>) }
+}
+
+fn with_pool<R>(f: impl FnOnce(&mut Pool) -> R) -> R {
+	while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+		core::hint::spin_loop();
+	}
+	// SAFETY: `LOCK` is held
+	let r = f(unsafe { &mut *core::ptr::addr_of_mut!(POOL) });
+	LOCK.store(false, Ordering::Release);
+	r
+}
+
+/// A monotonic counter, uncalibrated; usable before the harts are set up.
+fn ticks() -> u64 {
+	#[cfg(target_arch = "x86_64")]
+	return hw::arch::rdtsc64();
+	#[cfg(target_arch = "aarch64")]
+	return hw::arch::CNTPCT_EL0.get();
+	#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", target_arch = "riscv128"))]
+	return hw::arch::rdtime();
+}
+
+/// 8 bytes from the processor's generator and the bits of entropy credited for them.
+/// `rdrand` and `RNDR` are DRBGs reseeded by the hardware, they are credited half.
+fn cpu() -> Option<(u64, usize)> {
+	match CPU.load(Ordering::Relaxed) {
+		#[cfg(target_arch = "x86_64")]
+		CPU_RDSEED => hw::arch::rdseed().map(|v| (v, 64)).or_else(|| hw::arch::rdrand().map(|v| (v, 32))),
+		#[cfg(target_arch = "x86_64")]
+		CPU_RDRAND => hw::arch::rdrand().map(|v| (v, 32)),
+		#[cfg(target_arch = "aarch64")]
+		CPU_RNDR => hw::arch::rndr().map(|v| (v, 32)),
+		#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", target_arch = "riscv128"))]
+		CPU_SEED => {
+			// SAFETY: `CPU_SEED` is only set by `enable_zkr`
+			let mut v = 0;
+			for _ in 0..4 {
+				v = v << 16 | unsafe { hw::arch::seed16()? } as u64;
+			}
+			Some((v, 64))
+		}
+		_ => None
+	}
+}
+
+/// Seeds the pool from the processor and the timer. Called once during boot, before
+/// anything allocates; on RISC-V after `enable_zkr` if the hart has Zkr.
+pub fn init() {
+	#[cfg(target_arch = "x86_64")]
+	{
+		let cpu = match (hw::arch::has_rdseed(), hw::arch::has_rdrand()) {
+			(true, _)      => CPU_RDSEED,
+			(false, true)  => CPU_RDRAND,
+			(false, false) => CPU_NONE
+		};
+		CPU.store(cpu, Ordering::Relaxed);
+	}
+	#[cfg(target_arch = "aarch64")]
+	if hw::arch::has_rndr() {
+		CPU.store(CPU_RNDR, Ordering::Relaxed);
+	}
+
+	let mut seeded = false;
+	for _ in 0..random::SEED_BITS / 32 {
+		if let Some((v, bits)) = cpu() {
+			add(&v.to_le_bytes(), bits);
+			seeded = true;
+		}
+	}
+	if !seeded {
+		println!("random: the processor has no random number generator");
+	}
+	add_jitter();
+
+	if !is_seeded() {
+		println!("random: {} of {} bits of entropy, waiting for an entropy device", entropy(), random::SEED_BITS);
+	}
+}
+
+/// Adds `/dev/random` to the mount trie, once it is set up.
+pub fn register() {
+	mount_trie().insert(PATH, mnt::Node {
+		parent: null_mut(),
+		flags:  mnt::Node::FLAG_READ | mnt::Node::FLAG_WRITE,
+		refs:   1,
+		pages:  null_mut(),
+		users:  null_mut()
+	});
+	// SAFETY: only set here, during boot
+	unsafe { NODE = mount_trie().get(PATH).map_or(null_mut(), |n| n as *const _ as *mut mnt::Node); }
+}
+
+/// Makes the `seed` CSR a source, the caller checked that the ISA string of the hart
+/// lists Zkr and that the machine mode allows access (`mseccfg.SSEED`).
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", target_arch = "riscv128"))]
+pub unsafe fn enable_zkr() {
+	CPU.store(CPU_SEED, Ordering::Relaxed);
+}
+
+/// Mixes in timer samples taken around work whose duration depends on the previous
+/// sample, credited by `hw::random::jitter_bits`.
+fn add_jitter() {
+	let mut samples = [0u64; JITTER_SAMPLES];
+	let mut key = [0u32; 8];
+	for (i, sample) in samples.iter_mut().enumerate() {
+		*sample = ticks();
+		key[i % 8] ^= *sample as u32;
+		for _ in 0..(*sample & 7) + 1 {
+			key[0] = key[0].wrapping_add(random::chacha20_block(&key, i as u32, &[0; 3])[0] as u32);
+		}
+	}
+	let mut bytes = [0u8; JITTER_SAMPLES * 8];
+	for (chunk, s) in bytes.chunks_exact_mut(8).zip(samples.iter()) {
+		chunk.copy_from_slice(&s.to_le_bytes());
+	}
+	add(&bytes, random::jitter_bits(&samples));
+}
+
+/// Mixes `data` into the pool and credits `bits` of entropy.
+pub fn add(data: &[u8], bits: usize) {
+	let became_seeded = with_pool(|pool| {
+		let was = pool.is_seeded();
+		pool.add(data, bits);
+		!was && pool.is_seeded()
+	});
+	if became_seeded {
+		println!("random: pool seeded");
+	}
+}
+
+/// Mixes in data written to `/dev/random`, without credit until `set_attr`.
+pub fn write(data: &[u8]) {
+	add(data, 0);
+	UNCREDITED.fetch_add(data.len(), Ordering::Relaxed);
+}
+
+/// Credits `bits` of entropy for data added before without credit.
+pub fn credit(bits: usize) {
+	let became_seeded = with_pool(|pool| {
+		let was = pool.is_seeded();
+		pool.credit(bits);
+		!was && pool.is_seeded()
+	});
+	if became_seeded {
+		println!("random: pool seeded");
+	}
+}
+
+pub fn is_seeded() -> bool {
+	with_pool(|pool| pool.is_seeded())
+}
+
+/// Bits of entropy credited so far, up to `hw::random::SEED_BITS`
+pub fn entropy() -> usize {
+	with_pool(|pool| pool.entropy())
+}
+
+/// Fills `buf` from the pool. Fails with `ERR_NOT_READY` while the pool is not seeded,
+/// unless `insecure` is set.
+pub fn fill(buf: &mut [u8], insecure: bool) -> Result<(), usize> {
+	let fresh = cpu();
+	with_pool(|pool| {
+		if let Some((v, _)) = fresh {
+			pool.add(&v.to_le_bytes(), 0);
+		}
+		if !pool.is_seeded() && !insecure {
+			return Err(ERR_NOT_READY);
+		}
+		pool.fill(buf);
+		Ok(())
+	})
+}
+
+/// Whether `node` is the node of `/dev/random`.
+pub fn is_node(node: *const mnt::Node) -> bool {
+	self::node().map_or(false, |own| own as *const _ == node)
+}
+
+/// The node of `/dev/random`, once registered.
+pub fn node() -> Option<*mut mnt::Node> {
+	// SAFETY: see `NODE`
+	let node = unsafe { NODE };
+	(!node.is_null()).then_some(node)
+}
+
+/// Backs `sys_get_attr` on `/dev/random`.
+pub fn get_attr(key: usize) -> Result<usize, usize> {
+	match u32::try_from(key).map_err(|_| ERR_INVALID_ARG)? {
+		RD_ATTR_RANDOM_ENTROPY => Ok(entropy()),
+		RD_ATTR_RANDOM_SEEDED  => Ok(is_seeded() as usize),
+		_                      => Err(ERR_NOT_IMPLEMENTED)
+	}
+}
+
+/// Backs `sys_set_attr` on `/dev/random`.
+pub fn set_attr(key: usize, val: usize) -> Result<(), usize> {
+	match u32::try_from(key).map_err(|_| ERR_INVALID_ARG)? {
+		RD_ATTR_RANDOM_ENTROPY => {
+			// SAFETY: the current context is set while a syscall runs
+			let ctx = unsafe { hart::current().current.as_ref() };
+			if !ctx.map_or(false, |ctx| ctx.is_privileged() || ctx.is_driver()) {
+				return Err(ERR_PROTECTION);
+			}
+			credit(val.min(UNCREDITED.swap(0, Ordering::Relaxed).saturating_mul(8)));
+			Ok(())
+		}
+		_                      => Err(ERR_NOT_IMPLEMENTED)
+	}
+}
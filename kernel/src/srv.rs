@@ -0,0 +1,586 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Services of user-space processes
+//!
+//! Drivers and services run in user space, a process registers a channel for each disk it
+//! attaches and each service it provides. Once published, the disk is registered with `blk` as `/dev/<name>`, so it is
+//! cached, its partitions are found and RAID arrays on it assembled like those of any other
+//! device, and the file systems on them are mounted at `/mnt/<device>`. Requests to the
+//! disk are queued on the channel and the calling context blocks until a thread of the
+//! process took the request with `sys_srv_receive` and answered it with `sys_srv_reply`.
+//!
+//! A service is published at `/<name>`, e.g. `/net`. Opening a path below it asks the
+//! process for a handle of the resource, reads, writes, syncs and closing the descriptor are
+//! passed on with the handle.
+//!
+//! A 9P share of a driver, e.g. of a virtio 9P device, is mounted at `/mnt/<name>`. The
+//! kernel's client sends its messages over the channel and the process passes them to the
+//! server.
+//!
+//! Data is copied through a buffer of the request, by the context that owns the memory:
+//! the client when it queues or gets back the request, the process when it receives or
+//! answers it. Requests carry at most `SRV_MAX_LEN` bytes.
+//!
+//! Reads and writes of `sys_rd_ops` are queued with `SRV_OP_FLAG_ASYNC` and don't block
+//! the client, `sys_rd_poll` takes their results with `poll`.
+
+use {
+	crate::{blk, ctx::Context, fs, hart, mnt, misc::trie::TrieNode, svi::{IoOpId, SrvRequest, sys::{
+		SRV_FLAG_DISK, SRV_FLAG_READ_ONLY, SRV_FLAG_9P, SRV_MAX_LEN, SRV_OP_DISCARD, SRV_OP_READ, SRV_OP_SYNC, SRV_OP_WRITE,
+		SRV_OP_OPEN, SRV_OP_CLOSE, SRV_OP_TRANSACT, SRV_OP_FLAG_ASYNC, ERR_INVALID_ARG, ERR_IO, ERR_NOT_IMPLEMENTED, ERR_NOT_READY, ERR_PROTECTION,
+		RD_IO_ERR_WOULD_BLOCK
+	}}},
+	alloc::{boxed::Box, collections::VecDeque, format, string::String, vec::Vec},
+	core::ptr::null_mut,
+	hw::{block::{self, BlockDevice, Noop}, p9}
+};
+
+pub struct Channel {
+	/// The registering context, threads of its task serve the channel
+	owner:      *mut Context,
+	/// Name of the disk in `/dev`, of the share in `/mnt` or of the service
+	name:       String,
+	/// The service's node in the mount trie, once published
+	node:       *mut mnt::Node,
+	flags:      usize,
+	block_size: usize,
+	blocks:     u64,
+	published:  bool,
+	/// Set by `unregister`, requests fail with `ERR_NOT_READY` from then on
+	closed:     bool,
+	next_id:    usize,
+	/// Requests not received yet
+	pending:    VecDeque<*mut Request>,
+	/// Requests received but not answered yet
+	active:     Vec<*mut Request>,
+	/// Threads blocked in `receive`
+	receivers:  Vec<*mut Context>
+}
+
+struct Request {
+	header: SrvRequest,
+	/// Written data on the way in, read data on the way out
+	data:   Vec<u8>,
+	result: Option<Result<usize, usize>>,
+	client: *mut Context
+}
+
+/// An operation of `sys_rd_ops` on a service, until `sys_rd_poll` took its result
+struct Op {
+	/// The task that queued it, ids are unique per task
+	task:    u32,
+	id:      IoOpId,
+	request: *mut Request
+}
+
+/// The registered channels, only changed while the mount trie is locked. Channels of
+/// disks that couldn't be removed from `blk` and of services with open descriptors stay
+/// here closed.
+static mut CHANNELS: Vec<Box<Channel>> = Vec::new();
+/// The queued operations, see `Op`. Their requests are freed by `poll` only.
+static mut OPS: Vec<Op> = Vec::new();
+
+fn mount_trie() -> &'static mut TrieNode<mnt::Node> {
+	// SAFETY: the trie is locked by the callers
+	unsafe { &mut *(core::ptr::addr_of!(crate::KERNEL_DATA.mnt) as *mut TrieNode<mnt::Node>) }
+}
+
+fn channels() -> &'static mut Vec<Box<Channel>> {
+	// SAFETY: see `CHANNELS`
+	unsafe { &mut *core::ptr::addr_of_mut!(CHANNELS) }
+}
+
+fn ops() -> &'static mut Vec<Op> {
+	// SAFETY: see `OPS`
+	unsafe { &mut *core::ptr::addr_of_mut!(OPS) }
+}
+
+/// The task of the running context.
+fn caller() -> u32 {
+	// SAFETY: the running context is live
+	unsafe { (*hart::current().current).id }
+}
+
+/// The channel of a descriptor, if the caller's task registered it.
+fn owned(rd: usize) -> Option<&'static mut Channel> {
+	let id = caller();
+	// SAFETY: the owners of channels are live
+	channels().iter_mut()
+		.find(|c| &***c as *const Channel as usize == rd && unsafe { (*c.owner).id } == id)
+		.map(|c| &mut **c)
+}
+
+/// Blocks the running context until `wake` enqueues it again.
+fn block() {
+	let current = hart::current();
+	// SAFETY: the context is the caller
+	let ctx = unsafe { &mut *current.current };
+	current.dequeue(ctx, Context::STATE_BLOCKED);
+}
+
+fn wake(ctx: *mut Context) {
+	// SAFETY: a blocked context waits in `block` and is live
+	if let Some(ctx) = unsafe { ctx.as_mut() } {
+		if ctx.sch_state == Context::STATE_BLOCKED {
+			// SAFETY: a blocked context is owned by the hart it was scheduled on
+			unsafe { (*ctx.sch_hart).enqueue(ctx) };
+		}
+	}
+}
+
+impl Channel {
+	/// Queues a request answered by a thread of the process, `client` is woken up once it
+	/// has a result. A closed channel fails it right away.
+	fn submit(&mut self, op: usize, handle: usize, offset: u64, len: usize, flags: usize, data: Vec<u8>, client: *mut Context) -> *mut Request {
+		self.next_id += 1;
+		let request = Box::into_raw(Box::new(Request {
+			header: SrvRequest { id: self.next_id, op, handle, offset, len, flags },
+			data,
+			result: None,
+			client
+		}));
+		if self.closed {
+			// SAFETY: the request was just allocated
+			unsafe { (*request).result = Some(Err(ERR_NOT_READY)) };
+			return request;
+		}
+
+		self.pending.push_back(request);
+		if let Some(receiver) = self.receivers.pop() {
+			wake(receiver);
+		}
+		request
+	}
+
+	/// Queues a request and blocks until it was answered, returns the result and the data
+	/// of the answer.
+	fn call(&mut self, op: usize, handle: usize, offset: u64, len: usize, flags: usize, data: Vec<u8>) -> (Result<usize, usize>, Vec<u8>) {
+		finish(self.submit(op, handle, offset, len, flags, data, hart::current().current))
+	}
+
+	/// Fails all requests and wakes up their clients and the receivers.
+	fn shutdown(&mut self) {
+		self.closed = true;
+		for request in self.pending.drain(..).chain(self.active.drain(..)) {
+			// SAFETY: the client frees the request once it has a result
+			unsafe {
+				(*request).result = Some(Err(ERR_NOT_READY));
+				wake((*request).client);
+			}
+		}
+		self.receivers.drain(..).for_each(wake);
+	}
+
+	/// The service's node in the mount trie.
+	pub fn node(&self) -> *mut mnt::Node {
+		self.node
+	}
+
+	/// Opens the resource at `path` below the service, returns its handle.
+	pub fn open(&mut self, path: &str, flags: usize) -> Result<usize, usize> {
+		self.call(SRV_OP_OPEN, 0, 0, path.len(), flags, path.into()).0
+	}
+
+	/// Reads at most `SRV_MAX_LEN` bytes.
+	pub fn read(&mut self, handle: usize, offset: u64, buf: &mut [u8]) -> Result<usize, usize> {
+		let len = buf.len().min(SRV_MAX_LEN);
+		let (result, data) = self.call(SRV_OP_READ, handle, offset, len, 0, Vec::new());
+		let n = result?.min(data.len());
+		buf[..n].copy_from_slice(&data[..n]);
+		Ok(n)
+	}
+
+	/// Writes at most `SRV_MAX_LEN` bytes.
+	pub fn write(&mut self, handle: usize, offset: u64, buf: &[u8]) -> Result<usize, usize> {
+		let buf = &buf[..buf.len().min(SRV_MAX_LEN)];
+		self.call(SRV_OP_WRITE, handle, offset, buf.len(), 0, buf.to_vec()).0
+	}
+
+	pub fn sync(&mut self, handle: usize) -> Result<(), usize> {
+		self.call(SRV_OP_SYNC, handle, 0, 0, 0, Vec::new()).0.map(drop)
+	}
+
+	pub fn close(&mut self, handle: usize) -> Result<(), usize> {
+		self.call(SRV_OP_CLOSE, handle, 0, 0, 0, Vec::new()).0.map(drop)
+	}
+
+	/// Queues a read of at most `SRV_MAX_LEN` bytes as the operation `id` of the calling
+	/// task, `poll` takes its result.
+	pub fn queue_read(&mut self, id: IoOpId, handle: usize, offset: u64, len: usize) -> Result<(), usize> {
+		self.queue(id, SRV_OP_READ, handle, offset, len.min(SRV_MAX_LEN), Vec::new())
+	}
+
+	/// Queues a write of at most `SRV_MAX_LEN` bytes as the operation `id` of the calling
+	/// task, `poll` takes its result.
+	pub fn queue_write(&mut self, id: IoOpId, handle: usize, offset: u64, data: Vec<u8>) -> Result<(), usize> {
+		if data.len() > SRV_MAX_LEN {
+			return Err(ERR_INVALID_ARG);
+		}
+		self.queue(id, SRV_OP_WRITE, handle, offset, data.len(), data)
+	}
+
+	fn queue(&mut self, id: IoOpId, op: usize, handle: usize, offset: u64, len: usize, data: Vec<u8>) -> Result<(), usize> {
+		if is_queued(id) {
+			return Err(ERR_INVALID_ARG);
+		}
+		// the client is set once it waits in `poll`
+		let request = self.submit(op, handle, offset, len, SRV_OP_FLAG_ASYNC, data, null_mut());
+		ops().push(Op { task: caller(), id, request });
+		Ok(())
+	}
+}
+
+/// Blocks until a request has a result, frees it and returns the result and the data of
+/// the answer.
+fn finish(request: *mut Request) -> (Result<usize, usize>, Vec<u8>) {
+	// SAFETY: the request is freed here only, after it was answered or failed
+	while unsafe { (*request).result.is_none() } {
+		block();
+	}
+	let request = unsafe { Box::from_raw(request) };
+	(request.result.unwrap(), request.data)
+}
+
+/// A disk of a driver process.
+struct Disk {
+	channel:    *mut Channel,
+	block_size: usize,
+	blocks:     u64,
+	read_only:  bool
+}
+
+impl Disk {
+	fn call(&mut self, op: usize, lba: u64, len: usize, data: Vec<u8>) -> block::Result<Vec<u8>> {
+		// SAFETY: channels of disks registered with `blk` are never freed
+		let (result, data) = unsafe { (*self.channel).call(op, 0, lba * self.block_size as u64, len, 0, data) };
+		match result {
+			Ok(_)                    => Ok(data),
+			Err(ERR_NOT_READY)       => Err(block::Error::NoDevice),
+			Err(ERR_PROTECTION)      => Err(block::Error::ReadOnly),
+			Err(ERR_NOT_IMPLEMENTED) => Err(block::Error::Unsupported),
+			Err(_)                   => Err(block::Error::Io)
+		}
+	}
+}
+
+impl BlockDevice for Disk {
+	fn block_size(&self) -> usize {
+		self.block_size
+	}
+
+	fn blocks(&self) -> u64 {
+		self.blocks
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		for (i, chunk) in buf.chunks_mut(SRV_MAX_LEN).enumerate() {
+			let lba = lba + (i * SRV_MAX_LEN / self.block_size) as u64;
+			let data = self.call(SRV_OP_READ, lba, chunk.len(), Vec::new())?;
+			if data.len() != chunk.len() {
+				return Err(block::Error::Io);
+			}
+			chunk.copy_from_slice(&data);
+		}
+		Ok(())
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		if self.read_only {
+			return Err(block::Error::ReadOnly);
+		}
+		for (i, chunk) in buf.chunks(SRV_MAX_LEN).enumerate() {
+			let lba = lba + (i * SRV_MAX_LEN / self.block_size) as u64;
+			self.call(SRV_OP_WRITE, lba, chunk.len(), chunk.to_vec())?;
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		self.call(SRV_OP_SYNC, 0, 0, Vec::new()).map(drop)
+	}
+
+	fn discard(&mut self, lba: u64, blocks: u64) -> block::Result<()> {
+		block::check_blocks(self, lba, blocks)?;
+		let len = usize::try_from(blocks * self.block_size as u64).map_err(|_| block::Error::InvalidArgument)?;
+		self.call(SRV_OP_DISCARD, lba, len, Vec::new()).map(drop)
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	fn max_blocks(&self) -> u64 {
+		(SRV_MAX_LEN / self.block_size) as u64
+	}
+}
+
+/// The transport of a share of a driver process.
+struct Share {
+	channel: *mut Channel
+}
+
+impl p9::Channel for Share {
+	fn max_size(&self) -> usize {
+		SRV_MAX_LEN
+	}
+
+	fn request(&mut self, request: &[u8], response: &mut [u8]) -> p9::Result<usize> {
+		let len = response.len().min(SRV_MAX_LEN);
+		// SAFETY: channels of mounted shares are never freed
+		let (result, data) = unsafe { (*self.channel).call(SRV_OP_TRANSACT, 0, 0, len, 0, request.to_vec()) };
+		let n = result.map_err(|_| p9::Error::Transport)?.min(data.len());
+		response[..n].copy_from_slice(&data[..n]);
+		Ok(n)
+	}
+}
+
+/// Registers a channel for the disk `/dev/<name>`, the share `/mnt/<name>` or the service
+/// `/<name>`, it isn't visible before `publish`. Returns the descriptor of the channel.
+pub fn register(path: &str, flags: usize, block_size: usize, blocks: u64) -> Result<usize, usize> {
+	// SAFETY: the context is the caller
+	let ctx = unsafe { &mut *hart::current().current };
+	if !ctx.is_driver() && !ctx.is_privileged() {
+		return Err(ERR_PROTECTION);
+	} else if flags & !(SRV_FLAG_DISK | SRV_FLAG_READ_ONLY | SRV_FLAG_9P) != 0
+		|| flags & (SRV_FLAG_DISK | SRV_FLAG_9P) == SRV_FLAG_DISK | SRV_FLAG_9P {
+		return Err(ERR_INVALID_ARG);
+	}
+
+	let disk = flags & SRV_FLAG_DISK != 0;
+	let kind = flags & (SRV_FLAG_DISK | SRV_FLAG_9P);
+	let name = match kind {
+		SRV_FLAG_DISK => path.strip_prefix("/dev/"),
+		SRV_FLAG_9P   => path.strip_prefix("/mnt/"),
+		_             => path.strip_prefix('/')
+	};
+	let name = match name {
+		Some(name) if !name.is_empty() && !name.contains('/') => name,
+		_ => return Err(ERR_INVALID_ARG)
+	};
+	if (disk && (block_size == 0 || blocks == 0)) || (!disk && (block_size != 0 || blocks != 0))
+		|| channels().iter().any(|c| !c.closed && c.name == name && c.flags & (SRV_FLAG_DISK | SRV_FLAG_9P) == kind) {
+		return Err(ERR_INVALID_ARG);
+	}
+
+	let channel = Box::new(Channel {
+		owner:      ctx,
+		name:       name.into(),
+		node:       null_mut(),
+		flags,
+		block_size,
+		blocks,
+		published:  false,
+		closed:     false,
+		next_id:    0,
+		pending:    VecDeque::new(),
+		active:     Vec::new(),
+		receivers:  Vec::new()
+	});
+	let rd = &*channel as *const Channel as usize;
+	channels().push(channel);
+	Ok(rd)
+}
+
+/// Registers the disk of a channel with `blk` or mounts its share, a thread of the caller
+/// has to serve the channel meanwhile as the partition table is read or the client attaches.
+/// A service is added to the mount trie.
+pub fn publish(rd: usize) -> Result<(), usize> {
+	let channel = match owned(rd) { Some(c) if !c.closed && !c.published => c, _ => return Err(ERR_INVALID_ARG) };
+	if channel.flags & SRV_FLAG_9P != 0 {
+		let name = channel.name.clone();
+		let client = p9::Client::new(Share { channel: channel as *mut Channel }, "root", "", 0).map_err(|e| {
+			println!("srv: {}: failed to attach: {:?}", name, e);
+			ERR_IO
+		})?;
+		fs::mount_9p(&format!("/mnt/{}", name), &name, client)?;
+		println!("srv: {}: mounted at /mnt/{}", name, name);
+		channel.published = true;
+		return Ok(());
+	} else if channel.flags & SRV_FLAG_DISK == 0 {
+		let path = format!("/{}", channel.name);
+		let trie = mount_trie();
+		if trie.get(&path).is_some() || resolve(&path).is_some() {
+			return Err(ERR_INVALID_ARG);
+		}
+		trie.insert(&path, mnt::Node {
+			parent: null_mut(),
+			flags:  mnt::Node::FLAG_READ | mnt::Node::FLAG_WRITE,
+			refs:   1,
+			pages:  null_mut(),
+			users:  null_mut()
+		});
+		channel.node = trie.get(&path).map_or(null_mut(), |n| n as *const _ as *mut mnt::Node);
+		channel.published = true;
+		return Ok(());
+	}
+	channel.published = true;
+
+	let disk = Disk {
+		channel:    channel as *mut Channel,
+		block_size: channel.block_size,
+		blocks:     channel.blocks,
+		read_only:  channel.flags & SRV_FLAG_READ_ONLY != 0
+	};
+	let name = channel.name.clone();
+	match blk::register_disk(&name, Box::new(disk), || Box::new(Noop)) {
+		Ok(partitions) => {
+			println!("srv: {}: {} blocks of {} bytes, {} partitions", name, channel.blocks, channel.block_size, partitions);
+			// the disk may complete an array
+			blk::raid::assemble();
+			fs::mount_devices();
+			Ok(())
+		}
+		Err(e) => {
+			channel.published = false;
+			Err(e)
+		}
+	}
+}
+
+/// Takes the next request of a channel, the data of a write is copied to `buf`. Blocks
+/// until there is a request unless `non_block` is set. Returns the number of bytes copied.
+pub fn receive(rd: usize, header: &mut SrvRequest, buf: &mut [u8], non_block: bool) -> Result<usize, usize> {
+	loop {
+		let channel = match owned(rd) { Some(c) if !c.closed => c, _ => return Err(ERR_INVALID_ARG) };
+		if let Some(request) = channel.pending.front().copied() {
+			// SAFETY: the request lives until it has a result
+			let request = unsafe { &mut *request };
+			let len = request.data.len();
+			if len > buf.len() {
+				return Err(ERR_INVALID_ARG);
+			}
+			*header = request.header;
+			buf[..len].copy_from_slice(&request.data);
+			channel.active.extend(channel.pending.pop_front());
+			return Ok(len);
+		} else if non_block {
+			return Err(RD_IO_ERR_WOULD_BLOCK);
+		}
+
+		channel.receivers.push(hart::current().current);
+		block();
+	}
+}
+
+/// Answers a request, `result` is the result or an error, `data` the data of a read or the
+/// response of a 9P message.
+pub fn reply(rd: usize, id: usize, result: Result<usize, usize>, data: &[u8]) -> Result<(), usize> {
+	let channel = owned(rd).ok_or(ERR_INVALID_ARG)?;
+	// SAFETY: see `receive`
+	let i = channel.active.iter().position(|r| unsafe { (**r).header.id } == id).ok_or(ERR_INVALID_ARG)?;
+	let request = unsafe { &mut *channel.active.swap_remove(i) };
+
+	let returns_data = matches!(request.header.op, SRV_OP_READ | SRV_OP_TRANSACT) && result.is_ok();
+	let too_long = returns_data && data.len() > request.header.len;
+	request.data = match returns_data && !too_long {
+		true  => data.to_vec(),
+		false => Vec::new()
+	};
+	request.result = Some(match too_long { true => Err(ERR_IO), false => result });
+	wake(request.client);
+	match too_long {
+		true  => Err(ERR_INVALID_ARG),
+		false => Ok(())
+	}
+}
+
+/// Removes the disk, share or service of a channel and fails its requests. A disk that is
+/// open, has open files or is a member of an array stays in `/dev` and fails all requests,
+/// as do a share with open files and the open descriptors of a service.
+pub fn unregister(rd: usize) -> Result<(), usize> {
+	let channel = match owned(rd) { Some(c) if !c.closed => c, _ => return Err(ERR_INVALID_ARG) };
+	// the cache is written back and the share's files are clunked while the channel is still served
+	let removed = match (channel.published, channel.flags & (SRV_FLAG_DISK | SRV_FLAG_9P)) {
+		(false, _)            => true,
+		(true, SRV_FLAG_DISK) => blk::unregister_disk(&channel.name).is_ok(),
+		(true, SRV_FLAG_9P)   => fs::unmount_device(&format!("9p:{}", channel.name)).is_ok(),
+		// SAFETY: the node lives as long as the channel
+		(true, _)             => unsafe { (*channel.node).users.is_null() }
+	};
+	channel.shutdown();
+	if removed && !channel.node.is_null() {
+		mount_trie().remove(&format!("/{}", channel.name));
+	}
+	if removed {
+		channels().retain(|c| &**c as *const Channel as usize != rd);
+	} else {
+		println!("srv: {}: still in use, failing its requests", channel.name);
+	}
+	Ok(())
+}
+
+/// Whether the calling task has queued an operation `id` that `poll` didn't take yet.
+pub fn is_queued(id: IoOpId) -> bool {
+	let task = caller();
+	ops().iter().any(|op| op.task == task && op.id == id)
+}
+
+/// Takes the result of the operation `id` of the calling task, the data of a read is
+/// copied to `buf`. Blocks until the service answered unless `non_block` is set.
+pub fn poll(id: IoOpId, buf: &mut [u8], non_block: bool) -> Result<usize, usize> {
+	let task = caller();
+	let request = ops().iter().find(|op| op.task == task && op.id == id).ok_or(ERR_INVALID_ARG)?.request;
+	// SAFETY: the request lives until it is taken from `OPS`
+	unsafe {
+		if (*request).result.is_none() {
+			if non_block {
+				return Err(RD_IO_ERR_WOULD_BLOCK);
+			}
+			(*request).client = hart::current().current;
+		}
+	}
+	ops().retain(|op| op.request != request);
+
+	// SAFETY: see above
+	let read = unsafe { (*request).header.op == SRV_OP_READ };
+	let (result, data) = finish(request);
+	let n = result?;
+	if !read {
+		return Ok(n);
+	}
+	let n = n.min(data.len()).min(buf.len());
+	buf[..n].copy_from_slice(&data[..n]);
+	Ok(n)
+}
+
+/// The published service that contains `path` and the path relative to it.
+pub fn resolve(path: &str) -> Option<(&'static mut Channel, &str)> {
+	channels().iter_mut()
+		.filter(|c| !c.node.is_null())
+		.find_map(|c| {
+			let rest = path.strip_prefix('/')?.strip_prefix(c.name.as_str())?;
+			(rest.is_empty() || rest.starts_with('/')).then(|| (&mut **c, rest))
+		})
+}
+
+/// The service at the given mount node.
+pub fn lookup(node: *const mnt::Node) -> Option<&'static mut Channel> {
+	channels().iter_mut().find(|c| !c.node.is_null() && c.node as *const _ == node).map(|c| &mut **c)
+}
+
+/// The mount nodes of all services.
+pub fn nodes() -> impl Iterator<Item = *mut mnt::Node> {
+	channels().iter().map(|c| c.node).filter(|n| !n.is_null())
+}
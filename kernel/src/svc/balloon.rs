@@ -0,0 +1,65 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Memory balloon, backs the `sys_mem_balloon` syscall
+
+use crate::{mem::balloon, svi::sys::{
+	BALLOON_OP_INFLATE, BALLOON_OP_DEFLATE, BALLOON_OP_REPORT, BALLOON_OP_REPORTED, BALLOON_OP_STATS,
+	BALLOON_MAX_PFNS, ERR_INVALID_ARG, ERR_INVALID_MEM_REF
+}};
+
+pub fn svc_mem_balloon(op: usize, buf: usize, len: usize, flags: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	if flags != 0 || len > BALLOON_MAX_PFNS {
+		return error(ERR_INVALID_ARG);
+	} else if buf == 0 && len != 0 {
+		return error(ERR_INVALID_MEM_REF);
+	} else if buf % core::mem::align_of::<u32>() != 0 || (op == BALLOON_OP_STATS && buf % core::mem::align_of::<u64>() != 0) {
+		return error(ERR_INVALID_ARG);
+	}
+
+	// SAFETY: the caller's buffer was validated by the syscall entry
+	let pfns = unsafe { core::slice::from_raw_parts_mut(buf as *mut u32, len) };
+	let result = match op {
+		BALLOON_OP_INFLATE  => Ok(balloon::inflate(pfns)),
+		BALLOON_OP_DEFLATE  => balloon::deflate(pfns).map(|_| len),
+		BALLOON_OP_REPORT   => Ok(balloon::report(pfns)),
+		BALLOON_OP_REPORTED => balloon::reported(pfns).map(|_| len),
+		BALLOON_OP_STATS    => {
+			// SAFETY: see above, the buffer holds `len` pairs of u64
+			let out = unsafe { core::slice::from_raw_parts_mut(buf as *mut [u64; 2], len) };
+			let stats = balloon::stats();
+			for (o, (tag, value)) in out.iter_mut().zip(stats.iter()) {
+				*o = [*tag as u64, *value];
+			}
+			Ok(stats.len().min(len))
+		}
+		_ => Err(ERR_INVALID_ARG)
+	};
+	match result {
+		Ok(n)  => (n, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+fn error(err: usize) -> (usize, usize, usize, usize) {
+	(-(err as isize) as usize, 0, 0, 0)
+}
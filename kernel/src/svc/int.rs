@@ -0,0 +1,177 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Message signaled interrupts, backs the `sys_int_alloc`/`free`/`mask`/`unmask`/`wait` syscalls
+
+use {crate::{ctx::Context, hart::{self, Hart}, int::msi, svi::Interrupt}, alloc::vec::Vec, hw::pcie::MsiMessage};
+use crate::svi::sys::{INT_ALLOC_LOCAL, INT_WAIT_NON_BLOCK, ERR_INVALID_ARG, ERR_NOT_IMPLEMENTED, ERR_OUT_OF_KERNEL_MEMORY, RD_IO_ERR_WOULD_BLOCK};
+
+/// The descriptor of a block of vectors encodes the hart and base vector, plus one so it
+/// is never zero.
+fn encode(hart: u32, base: u16) -> usize {
+	((hart as usize) << 16 | base as usize) + 1
+}
+
+fn decode(rd: usize) -> Option<&'static mut Hart> {
+	let hart = rd.checked_sub(1)? >> 16;
+	// SAFETY: harts are registered once during boot and never freed
+	unsafe { hart::HARTS.get(hart).copied().filter(|h| !h.is_null()).map(|h| &mut *h) }
+}
+
+pub fn svc_int_alloc(device: usize, messages: usize, count: usize, flags: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	if !count.is_power_of_two() || count > 32 || flags & !INT_ALLOC_LOCAL != 0 || messages == 0 {
+		return error(ERR_INVALID_ARG);
+	}
+
+	let current = hart::current();
+	let ctx = current.current;
+
+	// spread the vectors over the harts of the caller's node unless told otherwise
+	let target = match flags & INT_ALLOC_LOCAL != 0 {
+		true  => Some(current as *mut Hart),
+		false => unsafe { hart::HARTS.iter().copied()
+			.filter(|h| !h.is_null() && (**h).preferred_node == current.preferred_node)
+			.min_by_key(|h| (**h).msi.count()) }
+	};
+	// SAFETY: see `decode`
+	let target = match target { Some(h) => unsafe { &mut *h }, None => return error(ERR_NOT_IMPLEMENTED) };
+
+	let id = target.id;
+	let (base, rd) = match target.msi.alloc(count, ctx, device as u32, |base| encode(id, base)) {
+		Some(v) => v,
+		None => return error(ERR_OUT_OF_KERNEL_MEMORY)
+	};
+
+	// SAFETY: the caller's buffer was validated by the syscall entry
+	let messages = unsafe { core::slice::from_raw_parts_mut(messages as *mut MsiMessage, count) };
+	for (i, message) in messages.iter_mut().enumerate() {
+		match msi::message(target, base + i as u16, device as u32) {
+			Some(m) => *message = m,
+			None => {
+				target.msi.free(rd);
+				return error(ERR_NOT_IMPLEMENTED);
+			}
+		}
+	}
+	(rd, 0, 0, 0)
+}
+
+pub fn svc_int_free(rd: usize, _: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	match owned(rd) {
+		Some(hart) => {
+			let waiters = hart.msi.routes(rd).map(|(_, r)| r.waiter).filter(|w| !w.is_null()).collect::<Vec<_>>();
+			hart.msi.free(rd);
+			// a thread waiting for the vectors returns `ERR_INVALID_ARG`
+			for waiter in waiters {
+				// SAFETY: a waiter is a thread of the caller
+				msi::raise(unsafe { &mut *waiter });
+			}
+			(0, 0, 0, 0)
+		}
+		None => error(ERR_INVALID_ARG)
+	}
+}
+
+/// Returns the vectors of `rd` that fired since the last call as a mask of their indices,
+/// blocks until one fires unless `INT_WAIT_NON_BLOCK` is set. Masked vectors are left
+/// pending.
+pub fn svc_int_wait(rd: usize, flags: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	if flags & !INT_WAIT_NON_BLOCK != 0 {
+		return error(ERR_INVALID_ARG);
+	}
+
+	let io_ready = 1 << Interrupt::IoReady as u32;
+	loop {
+		let current = hart::current();
+		let waiter = current.current;
+		// SAFETY: the context is the caller
+		let ctx = unsafe { &mut *waiter };
+		ctx.int_pending &= !io_ready;
+
+		let hart = match owned(rd) {
+			Some(hart) => hart,
+			None => return error(ERR_INVALID_ARG)
+		};
+		let mut fired = 0;
+		for (_, route) in hart.msi.routes(rd) {
+			route.waiter = waiter;
+			if !route.masked && route.pending != 0 {
+				fired |= 1 << route.index;
+				route.pending = 0;
+			}
+		}
+
+		if fired != 0 {
+			return (fired, 0, 0, 0);
+		} else if flags & INT_WAIT_NON_BLOCK != 0 {
+			return error(RD_IO_ERR_WOULD_BLOCK);
+		}
+
+		// a vector firing on another hart since the scan raised `IoReady` again, otherwise
+		// `msi::raise` enqueues the context once one fires
+		if ctx.int_pending & io_ready == 0 {
+			current.dequeue(ctx, Context::STATE_BLOCKED);
+		}
+	}
+}
+
+pub fn svc_int_mask(rd: usize, _: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	set_masked(rd, true)
+}
+
+pub fn svc_int_unmask(rd: usize, _: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	set_masked(rd, false)
+}
+
+/// Vectors that fired while masked are raised once unmasked.
+fn set_masked(rd: usize, masked: bool) -> (usize, usize, usize, usize) {
+	let hart = match owned(rd) {
+		Some(hart) => hart,
+		None => return error(ERR_INVALID_ARG)
+	};
+
+	let mut pending = None;
+	for (_, route) in hart.msi.routes(rd) {
+		route.masked = masked;
+		if !masked && route.pending != 0 {
+			pending = Some(route.target());
+		}
+	}
+
+	if let Some(ctx) = pending {
+		// SAFETY: the context is the caller or a thread of it
+		msi::raise(unsafe { &mut *ctx });
+	}
+	(0, 0, 0, 0)
+}
+
+/// The hart of a descriptor, if the caller or another thread of its task owns the
+/// descriptor.
+fn owned(rd: usize) -> Option<&'static mut Hart> {
+	// SAFETY: the running context and the owners of routes are live
+	let id = unsafe { (*hart::current().current).id };
+	decode(rd).filter(|h| h.msi.routes(rd).next().map_or(false, |(_, r)| unsafe { (*r.ctx).id } == id))
+}
+
+fn error(err: usize) -> (usize, usize, usize, usize) {
+	(-(err as isize) as usize, 0, 0, 0)
+}
@@ -1,4 +1,11 @@
 
+pub mod balloon;
+pub mod int;
+pub mod rd;
+pub mod power;
+pub mod random;
+pub mod srv;
+
 pub type SvcId   = usize;
 pub type Status  = usize;
 pub type Flags   = usize;
@@ -6,7 +13,42 @@ pub type Rd      = usize;
 pub type IoOpId  = usize;
 pub type CtxId   = usize;
 
+pub const SVC_RD_OPEN:  SvcId = 0;
+pub const SVC_RD_CLOSE: SvcId = 1;
+pub const SVC_RD_READ:  SvcId = 2;
+pub const SVC_RD_WRITE: SvcId = 3;
+pub const SVC_RD_SYNC:  SvcId = 4;
+pub const SVC_RD_OPS:   SvcId = 13;
+pub const SVC_RD_POLL:  SvcId = 14;
+pub const SVC_SET_ATTR:   SvcId = 21;
+pub const SVC_GET_ATTR:   SvcId = 22;
+pub const SVC_POWER:      SvcId = 23;
+pub const SVC_INT_ALLOC:  SvcId = 24;
+pub const SVC_INT_FREE:   SvcId = 25;
+pub const SVC_INT_MASK:   SvcId = 26;
+pub const SVC_INT_UNMASK: SvcId = 27;
+pub const SVC_RANDOM:     SvcId = 28;
+pub const SVC_MEM_BALLOON: SvcId = 29;
+pub const SVC_INT_WAIT:   SvcId = 30;
+pub const SVC_SRV_REGISTER: SvcId = 31;
+pub const SVC_SRV_PUBLISH: SvcId = 32;
+pub const SVC_SRV_RECEIVE: SvcId = 33;
+pub const SVC_SRV_REPLY:  SvcId = 34;
+pub const SVC_SRV_UNREGISTER: SvcId = 35;
+
 #[no_mangle]
-pub static SVC_TABLE: [fn (usize, usize, usize, usize, usize, usize) -> (usize, usize, usize, usize);  0] = [
+pub static SVC_TABLE: [fn (usize, usize, usize, usize, usize, usize) -> (usize, usize, usize, usize);  36] = [
+	rd::svc_rd_open, rd::svc_rd_close, rd::svc_rd_read, rd::svc_rd_write,
+	rd::svc_rd_sync, svc_not_implemented, svc_not_implemented, svc_not_implemented,
+	svc_not_implemented, svc_not_implemented, svc_not_implemented, svc_not_implemented,
+	svc_not_implemented, rd::svc_rd_ops, rd::svc_rd_poll, svc_not_implemented,
+	svc_not_implemented, svc_not_implemented, svc_not_implemented, svc_not_implemented,
+	svc_not_implemented, rd::svc_set_attr, rd::svc_get_attr, power::svc_power,
+	int::svc_int_alloc, int::svc_int_free, int::svc_int_mask, int::svc_int_unmask,
+	random::svc_random, balloon::svc_mem_balloon, int::svc_int_wait, srv::svc_srv_register,
+	srv::svc_srv_publish, srv::svc_srv_receive, srv::svc_srv_reply, srv::svc_srv_unregister
+];
 
-];
\ No newline at end of file
+fn svc_not_implemented(_: usize, _: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	(-(crate::svi::sys::ERR_NOT_IMPLEMENTED as isize) as usize, 0, 0, 0)
+}
\ No newline at end of file
@@ -0,0 +1,222 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Power control, backs the `sys_power` syscall
+
+use hw::acpi::{FADT, SSDT, aml::{Handler, Namespace, PciAddress}, power::{Power, PowerError, SleepState}};
+use crate::{hart, svi::sys::{POWER_OP_OFF, POWER_OP_REBOOT, POWER_OP_SUSPEND, ERR_INVALID_ARG, ERR_IO, ERR_NOT_IMPLEMENTED, ERR_NOT_READY, ERR_PROTECTION}};
+
+/// The FADT and the ACPI namespace, only set on ACPI systems
+static mut ACPI: Option<(&'static FADT, Namespace<'static>)> = None;
+
+/// Called once during boot, loads the DSDT and `ssdts` into the namespace and switches
+/// the platform into ACPI mode.
+pub unsafe fn init(fadt: &'static FADT, ssdts: &[&'static SSDT]) {
+	let dsdt = match fadt.dsdt() {
+		Some(dsdt) => dsdt,
+		None       => {
+			println!("ACPI: no DSDT, power management is not available");
+			return;
+		}
+	};
+
+	let mut ns = Namespace::new(dsdt.header.revision);
+	for header in core::iter::once(&dsdt.header).chain(ssdts.iter().map(|&ssdt| &ssdt.header)) {
+		if let Err(e) = ns.load_table(header, &mut Platform) {
+			println!("ACPI: failed to load {}: {:?}", core::str::from_utf8(&header.signature.to_le_bytes()).unwrap_or("table"), e);
+		}
+	}
+
+	if let Err(e) = Power::new(fadt).enable(&mut Platform) {
+		println!("ACPI: failed to enable ACPI mode: {:?}", e);
+	}
+	ACPI = Some((fadt, ns));
+}
+
+pub fn svc_power(op: usize, flags: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	if flags != 0 {
+		return error(ERR_INVALID_ARG);
+	} else if !unsafe { hart::current().current.as_ref() }.map_or(false, |ctx| ctx.is_privileged()) {
+		return error(ERR_PROTECTION);
+	}
+
+	// SAFETY: the namespace is only used by this syscall, which runs on one hart at a time
+	let acpi = unsafe { ACPI.as_mut() };
+
+	match op {
+		POWER_OP_OFF => {
+			if let Some((fadt, ns)) = acpi {
+				let r = Power::new(fadt).shutdown(ns, &mut Platform);
+				println!("ACPI: shutdown failed: {:?}", r);
+			}
+			hw::arch::park();
+			(0, 0, 0, 0)
+		}
+		POWER_OP_REBOOT => {
+			if let Some((fadt, _)) = acpi {
+				let r = Power::new(fadt).reset(&mut Platform);
+				println!("ACPI: reset failed: {:?}", r);
+			}
+			#[cfg(target_arch = "x86_64")]
+			unsafe { hw::arch::outb(0x64, 0xFE); } // pulse the reset line through the keyboard controller
+			hw::arch::park();
+			(0, 0, 0, 0)
+		}
+		POWER_OP_SUSPEND => match acpi.ok_or(ERR_NOT_IMPLEMENTED).and_then(|(fadt, ns)| suspend(*fadt, ns)) {
+			Ok(())  => (0, 0, 0, 0),
+			Err(e) => error(e)
+		},
+		_ => error(ERR_INVALID_ARG)
+	}
+}
+
+/// Suspends to RAM and returns once the system resumed.
+#[cfg(target_arch = "x86_64")]
+fn suspend(fadt: &'static FADT, ns: &mut Namespace<'static>) -> Result<(), usize> {
+	// there is no way to take other harts down yet, they would be lost
+	let current = hart::current() as *mut hart::Hart;
+	// SAFETY: harts are registered during boot only
+	let others = unsafe { hart::HARTS.iter() }
+		.filter(|&&h| !h.is_null() && h != current)
+		.any(|&h| unsafe { (*h).status } as usize == hart::Hart::STATUS_UP);
+	if others {
+		return Err(ERR_NOT_READY);
+	}
+
+	let power = Power::new(fadt);
+	// SAFETY: the syscall holds no locks
+	unsafe {
+		crate::arch::sleep::suspend(&mut |vector| power.suspend(vector, ns, &mut Platform).map_err(power_error))?;
+	}
+	if let Err(e) = power.wake(SleepState::S3, ns, &mut Platform) {
+		println!("ACPI: wake failed: {:?}", e);
+	}
+	println!("ACPI: resumed from S3");
+	Ok(())
+}
+
+/// Only x86 resumes at a real mode waking vector.
+#[cfg(not(target_arch = "x86_64"))]
+fn suspend(_fadt: &'static FADT, _ns: &mut Namespace<'static>) -> Result<(), usize> {
+	Err(ERR_NOT_IMPLEMENTED)
+}
+
+fn power_error(e: PowerError) -> usize {
+	match e {
+		PowerError::Unsupported | PowerError::NoFacs => ERR_NOT_IMPLEMENTED,
+		_                                            => ERR_IO
+	}
+}
+
+fn error(err: usize) -> (usize, usize, usize, usize) {
+	(-(err as isize) as usize, 0, 0, 0)
+}
+
+/// Register and operation region accesses of the AML interpreter. ACPI tables and
+/// registers are accessed through the identity mapping of physical memory.
+struct Platform;
+
+impl Handler for Platform {
+	fn read_memory(&mut self, address: u64, width: u8) -> u64 {
+		unsafe {
+			match width {
+				8  => (address as usize as *const u8).read_volatile() as _,
+				16 => (address as usize as *const u16).read_volatile() as _,
+				32 => (address as usize as *const u32).read_volatile() as _,
+				_  => (address as usize as *const u64).read_volatile()
+			}
+		}
+	}
+
+	fn write_memory(&mut self, address: u64, width: u8, value: u64) {
+		unsafe {
+			match width {
+				8  => (address as usize as *mut u8).write_volatile(value as _),
+				16 => (address as usize as *mut u16).write_volatile(value as _),
+				32 => (address as usize as *mut u32).write_volatile(value as _),
+				_  => (address as usize as *mut u64).write_volatile(value)
+			}
+		}
+	}
+
+	#[cfg(target_arch = "x86_64")]
+	fn read_io(&mut self, port: u16, width: u8) -> u64 {
+		unsafe {
+			match width {
+				8  => hw::arch::inb(port) as _,
+				16 => hw::arch::inw(port) as _,
+				_  => hw::arch::inl(port) as _
+			}
+		}
+	}
+
+	#[cfg(not(target_arch = "x86_64"))]
+	fn read_io(&mut self, _port: u16, _width: u8) -> u64 {
+		!0
+	}
+
+	#[cfg(target_arch = "x86_64")]
+	fn write_io(&mut self, port: u16, width: u8, value: u64) {
+		unsafe {
+			match width {
+				8  => hw::arch::outb(port, value as _),
+				16 => hw::arch::outw(port, value as _),
+				_  => hw::arch::outl(port, value as _)
+			}
+		}
+	}
+
+	#[cfg(not(target_arch = "x86_64"))]
+	fn write_io(&mut self, _port: u16, _width: u8, _value: u64) {}
+
+	fn read_pci(&mut self, address: PciAddress, offset: u16, width: u8) -> u64 {
+		match pci_config_port(address, offset) {
+			Some(port) => {
+				let shift = (offset & 3) * 8;
+				(self.read_io(port, 32) >> shift) & (u64::MAX >> (64 - width))
+			}
+			None => u64::MAX >> (64 - width)
+		}
+	}
+
+	fn write_pci(&mut self, address: PciAddress, offset: u16, width: u8, value: u64) {
+		if let Some(port) = pci_config_port(address, offset) {
+			let shift = (offset & 3) * 8;
+			let mask = (u64::MAX >> (64 - width)) << shift;
+			let old = self.read_io(port, 32);
+			self.write_io(port, 32, (old & !mask) | ((value << shift) & mask));
+		}
+	}
+}
+
+/// Selects a dword of the configuration space through the legacy mechanism on
+/// ports `0xCF8`/`0xCFC` and returns the data port, only segment 0 is reachable.
+fn pci_config_port(address: PciAddress, offset: u16) -> Option<u16> {
+	if address.segment != 0 || offset >= 0x100 || cfg!(not(target_arch = "x86_64")) {
+		return None;
+	}
+
+	let select = 0x8000_0000 | (address.bus as u32) << 16 | (address.device as u32) << 11
+		| (address.function as u32) << 8 | (offset as u32 & 0xFC);
+	Platform.write_io(0xCF8, 32, select as _);
+	Some(0xCFC)
+}
@@ -0,0 +1,45 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Random numbers, backs the `sys_random` syscall
+
+use crate::{random, svi::sys::{RANDOM_FLAG_INSECURE, RANDOM_MAX_LEN, ERR_INVALID_ARG, ERR_INVALID_MEM_REF}};
+
+pub fn svc_random(buf: usize, len: usize, flags: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	if flags & !RANDOM_FLAG_INSECURE != 0 {
+		return error(ERR_INVALID_ARG);
+	} else if buf == 0 && len != 0 {
+		return error(ERR_INVALID_MEM_REF);
+	}
+
+	let len = len.min(RANDOM_MAX_LEN);
+	// SAFETY: the caller's buffer was validated by the syscall entry
+	let buf = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+	match random::fill(buf, flags & RANDOM_FLAG_INSECURE != 0) {
+		Ok(())  => (len, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+fn error(err: usize) -> (usize, usize, usize, usize) {
+	(-(err as isize) as usize, 0, 0, 0)
+}
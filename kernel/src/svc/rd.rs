@@ -0,0 +1,419 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Resource descriptors, backs `sys_rd_open`/`close`/`read`/`write`/`sync` for block devices,
+//! files of mounted file systems, resources of services in user space and `/dev/random`,
+//! `sys_rd_ops`/`sys_rd_poll` for the resources of services and `sys_set_attr`/`sys_get_attr`
+//! for RAID arrays and the entropy pool.
+//!
+//! A descriptor is the address of its `ResourceDescriptor`, which is linked into the
+//! `users` of the opened mount node, i.e. the device's, the file system's, the service's or
+//! the entropy pool's node.
+
+use {
+	crate::{blk, ctx::{Context, ResourceDescriptor}, fs, hart, misc::tree::Tree, mnt, random, srv},
+	crate::svi::sys::{
+		RD_OPEN_FLAG_READ, RD_OPEN_FLAG_WRITE, RD_OPEN_FLAG_EXEC, RD_OPEN_FLAG_RELATIVE, RD_OPEN_CREATE,
+		RD_OPEN_CREATE_NEW, RD_OPEN_RESOURCE_NO_EXISTS, RD_IO_FLAG_SYNC, RD_IO_FLAG_FILE_NAME, RD_IO_FLAGS,
+		RD_IO_FORMAT_REG, RD_IO_FORMAT_VEC, RD_IO_MODE_NON_BLOCK, RD_IO_OP_WRITE, RANDOM_MAX_LEN, SRV_MAX_LEN,
+		ERR_INVALID_ARG, ERR_INVALID_MEM_REF, ERR_NOT_IMPLEMENTED, ERR_PROTECTION
+	},
+	crate::svi::{IoOp, IoOpId},
+	alloc::{boxed::Box, string::String, vec::Vec},
+	core::ptr::null_mut
+};
+
+/// Longest path accepted by `sys_rd_open`
+const MAX_PATH: usize = 4096;
+
+pub fn svc_rd_open(path: usize, flags: usize, _dir: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	let access = RD_OPEN_FLAG_READ | RD_OPEN_FLAG_WRITE | RD_OPEN_FLAG_EXEC;
+	let create = RD_OPEN_CREATE | RD_OPEN_CREATE_NEW;
+	if flags & !(access | create | RD_OPEN_FLAG_RELATIVE) != 0 || flags & access == 0 {
+		return error(ERR_INVALID_ARG);
+	} else if flags & RD_OPEN_FLAG_RELATIVE != 0 || path == 0 {
+		return error(ERR_NOT_IMPLEMENTED);
+	}
+
+	// SAFETY: the caller's memory was validated by the syscall entry, the path is null-terminated,
+	// nothing after the terminator is read as it may be past the end of the mapping
+	let path = unsafe {
+		let len = match (0..MAX_PATH).find(|&i| (path as *const u8).add(i).read() == 0) {
+			Some(len) => len,
+			None      => return error(ERR_INVALID_ARG)
+		};
+		match core::str::from_utf8(core::slice::from_raw_parts(path as *const u8, len)) {
+			Ok(path) => path,
+			Err(_)   => return error(ERR_INVALID_ARG)
+		}
+	};
+
+	let (node, file) = match (blk::resolve(path), srv::resolve(path), fs::resolve(path)) {
+		_ if path == random::PATH => match random::node() {
+			Some(node) if flags & create == 0 => (node, String::new()),
+			_ => return error(ERR_INVALID_ARG)
+		},
+		(Some(device), ..) if flags & create == 0 => (device.node, String::new()),
+		(None, Some((service, file)), _) => (service.node(), String::from(file)),
+		(None, None, Some((mount, file))) => (mount.node, String::from(file)),
+		_ => return error(ERR_INVALID_ARG)
+	};
+
+	// SAFETY: the node lives as long as the device or mount
+	let node = unsafe { &mut *node };
+	if (flags & (RD_OPEN_FLAG_WRITE | create) != 0 && node.flags & mnt::Node::FLAG_WRITE == 0) || flags & RD_OPEN_FLAG_EXEC != 0 {
+		return error(ERR_PROTECTION);
+	}
+
+	if let Some(mount) = fs::lookup(node) {
+		let exists = match mount.fs.metadata(&file) {
+			Ok(_)                               => true,
+			Err(RD_OPEN_RESOURCE_NO_EXISTS)     => false,
+			Err(e)                              => return error(e)
+		};
+		let result = match (exists, flags & RD_OPEN_CREATE_NEW != 0, flags & create != 0) {
+			(false, _, true) | (true, true, _) => mount.fs.create(&file, false),
+			(false, _, false)                  => Err(RD_OPEN_RESOURCE_NO_EXISTS),
+			(true, false, _)                   => Ok(())
+		};
+		if let Err(e) = result {
+			return error(e);
+		}
+	}
+
+	// the service checks the access to its resources
+	let handle = match srv::lookup(node).map(|service| service.open(&file, flags)) {
+		Some(Ok(handle)) => handle,
+		Some(Err(e))     => return error(e),
+		None             => 0
+	};
+
+	let rd = Box::into_raw(Box::new(ResourceDescriptor {
+		flags,
+		mapped: Tree::new(),
+		node,
+		path:   file,
+		handle,
+		ctx:    hart::current().current,
+		next:   node.users,
+		prev:   null_mut()
+	}));
+	// SAFETY: the descriptors of a node are only changed with the mount trie locked
+	unsafe {
+		if let Some(next) = node.users.as_mut() {
+			next.prev = rd;
+		}
+	}
+	node.users = rd;
+	node.refs += 1;
+	(rd as usize, 0, 0, 0)
+}
+
+pub fn svc_rd_close(rd: usize, _: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	let desc = match owned(rd) { Some(desc) => desc, None => return error(ERR_INVALID_ARG) };
+	if let Some(service) = srv::lookup(desc.node) {
+		// the descriptor is gone either way
+		let _ = service.close(desc.handle);
+	}
+
+	// SAFETY: see `svc_rd_open`
+	unsafe {
+		let node = &mut *desc.node;
+		match desc.prev.as_mut() {
+			Some(prev) => prev.next = desc.next,
+			None       => node.users = desc.next
+		}
+		if let Some(next) = desc.next.as_mut() {
+			next.prev = desc.prev;
+		}
+		node.refs -= 1;
+		drop(Box::from_raw(desc as *mut ResourceDescriptor));
+	}
+	(0, 0, 0, 0)
+}
+
+pub fn svc_rd_read(rd: usize, flags: usize, buf: usize, len: usize, offset: usize, _op_id: usize) -> (usize, usize, usize, usize) {
+	let (desc, target, ctx) = match prepare(rd, flags, RD_OPEN_FLAG_READ) { Ok(v) => v, Err(e) => return error(e) };
+	if buf == 0 && len != 0 {
+		return error(ERR_INVALID_MEM_REF);
+	}
+
+	// SAFETY: the caller's buffer was validated by the syscall entry
+	let buf = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+	let result = match target {
+		Target::Device(device) => device.read(Some(ctx), offset as u64, buf, flags & RD_IO_FLAG_SYNC != 0),
+		Target::File(mount)    => mount.fs.read(&desc.path, offset as u64, buf),
+		Target::Service(service) => service.read(desc.handle, offset as u64, buf),
+		Target::Random         => {
+			let len = buf.len().min(RANDOM_MAX_LEN);
+			random::fill(&mut buf[..len], false).map(|_| len)
+		}
+	};
+	match result {
+		Ok(n)  => (n, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+pub fn svc_rd_write(rd: usize, flags: usize, buf: usize, len: usize, offset: usize, _op_id: usize) -> (usize, usize, usize, usize) {
+	let (desc, target, ctx) = match prepare(rd, flags, RD_OPEN_FLAG_WRITE) { Ok(v) => v, Err(e) => return error(e) };
+	if buf == 0 && len != 0 {
+		return error(ERR_INVALID_MEM_REF);
+	}
+
+	// SAFETY: see `svc_rd_read`
+	let buf = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+	let result = match target {
+		Target::Device(device) => device.write(Some(ctx), offset as u64, buf, flags & RD_IO_FLAG_SYNC != 0),
+		Target::File(mount)    => mount.fs.write(&desc.path, offset as u64, buf)
+			.and_then(|n| match flags & RD_IO_FLAG_SYNC != 0 {
+				true  => mount.fs.sync().map(|_| n),
+				false => Ok(n)
+			}),
+		Target::Service(service) => service.write(desc.handle, offset as u64, buf)
+			.and_then(|n| match flags & RD_IO_FLAG_SYNC != 0 {
+				true  => service.sync(desc.handle).map(|_| n),
+				false => Ok(n)
+			}),
+		Target::Random         => {
+			random::write(buf);
+			Ok(buf.len())
+		}
+	};
+	match result {
+		Ok(n)  => (n, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+pub fn svc_rd_sync(rd: usize, flags: usize, offset: usize, len: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	let (desc, target, ctx) = match prepare(rd, flags, 0) { Ok(v) => v, Err(e) => return error(e) };
+	let result = match target {
+		Target::Device(device) => device.sync(Some(ctx), offset as u64, len as u64),
+		Target::File(mount)    => mount.fs.sync(),
+		Target::Service(service) => service.sync(desc.handle),
+		Target::Random         => Ok(())
+	};
+	match result {
+		Ok(())  => (0, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+pub fn svc_rd_ops(ops: usize, len: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	if ops == 0 && len != 0 {
+		return error(ERR_INVALID_MEM_REF);
+	}
+	// SAFETY: see `svc_rd_read`, the buffers of the operations too
+	let ops = unsafe { core::slice::from_raw_parts(ops as *const IoOp, len) };
+
+	// nothing is queued if one of the operations is invalid
+	let mut batch = Vec::with_capacity(ops.len());
+	for op in ops {
+		match check_op(op, &batch) {
+			Ok(op) => batch.push(op),
+			Err(e) => return error(e)
+		}
+	}
+	// SAFETY: the caller is the running context
+	if let Err(e) = blk::admit(unsafe { &*hart::current().current }) {
+		return error(e);
+	}
+
+	for op in batch {
+		let result = match op.data {
+			Some(data) => op.service.queue_write(op.id, op.handle, op.offset, data),
+			None       => op.service.queue_read(op.id, op.handle, op.offset, op.len)
+		};
+		// the ids were checked above
+		debug_assert!(result.is_ok());
+	}
+	(0, 0, 0, 0)
+}
+
+pub fn svc_rd_poll(id: usize, flags: usize, buf: usize, len: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	if flags & !RD_IO_MODE_NON_BLOCK != 0 {
+		return error(ERR_INVALID_ARG);
+	} else if buf == 0 && len != 0 {
+		return error(ERR_INVALID_MEM_REF);
+	}
+
+	// SAFETY: see `svc_rd_read`
+	let buf = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+	match srv::poll(id, buf, flags & RD_IO_MODE_NON_BLOCK != 0) {
+		Ok(n)  => (n, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+pub fn svc_set_attr(rd: usize, key: usize, val: usize, _dir: usize, flags: usize, _: usize) -> (usize, usize, usize, usize) {
+	let desc = match owned(rd) { Some(desc) => desc, None => return error(ERR_INVALID_ARG) };
+	if flags != 0 {
+		return error(ERR_NOT_IMPLEMENTED);
+	} else if desc.flags & RD_OPEN_FLAG_WRITE == 0 {
+		return error(ERR_PROTECTION);
+	}
+
+	let result = match blk::lookup(desc.node) {
+		Some(device) => blk::raid::set_attr(device, key, val),
+		None if random::is_node(desc.node) => random::set_attr(key, val),
+		None         => Err(ERR_NOT_IMPLEMENTED)
+	};
+	match result {
+		Ok(())  => (0, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+pub fn svc_get_attr(rd: usize, key: usize, _dir: usize, flags: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	let desc = match owned(rd) { Some(desc) => desc, None => return error(ERR_INVALID_ARG) };
+	if flags != 0 {
+		return error(ERR_NOT_IMPLEMENTED);
+	}
+
+	let result = match blk::lookup(desc.node) {
+		Some(device) => blk::raid::get_attr(device, key),
+		None if random::is_node(desc.node) => random::get_attr(key),
+		None         => Err(ERR_NOT_IMPLEMENTED)
+	};
+	match result {
+		Ok(val) => (val, 0, 0, 0),
+		Err(e)  => error(e)
+	}
+}
+
+/// What a descriptor refers to
+enum Target {
+	Device(&'static mut blk::Device),
+	/// The file at the descriptor's path
+	File(&'static mut fs::Mount),
+	/// The resource of a service with the descriptor's handle
+	Service(&'static mut srv::Channel),
+	/// `/dev/random`, reads are served by the entropy pool, writes are mixed into it
+	Random
+}
+
+/// A checked operation of `sys_rd_ops`
+struct QueuedOp {
+	service: &'static mut srv::Channel,
+	handle:  usize,
+	id:      IoOpId,
+	offset:  u64,
+	/// The data of a write
+	data:    Option<Vec<u8>>,
+	/// The most bytes to read
+	len:     usize
+}
+
+/// Checks an operation of `sys_rd_ops`, its id must not be taken by the task or `batch`.
+fn check_op(op: &IoOp, batch: &[QueuedOp]) -> Result<QueuedOp, usize> {
+	let format = (op.flags >> 2) & 0b11;
+	if op.flags & !(RD_IO_FLAGS & !RD_IO_FLAG_SYNC) != 0 {
+		return Err(ERR_INVALID_ARG);
+	} else if op.flags & RD_IO_FLAG_FILE_NAME != 0 || format == RD_IO_FORMAT_REG {
+		return Err(ERR_NOT_IMPLEMENTED);
+	} else if srv::is_queued(op.id) || batch.iter().any(|o| o.id == op.id) {
+		return Err(ERR_INVALID_ARG);
+	}
+
+	// SAFETY: the union holds a descriptor without `RD_IO_FLAG_FILE_NAME`
+	let desc = owned(unsafe { op.rd.rd }).ok_or(ERR_INVALID_ARG)?;
+	let write = op.flags & RD_IO_OP_WRITE != 0;
+	let access = match write { true => RD_OPEN_FLAG_WRITE, false => RD_OPEN_FLAG_READ };
+	if desc.flags & access == 0 {
+		return Err(ERR_PROTECTION);
+	}
+	// only services complete operations in the background
+	let service = srv::lookup(desc.node).ok_or(ERR_NOT_IMPLEMENTED)?;
+
+	// SAFETY: the flags tell which field of the union is set, the buffers were validated by
+	// the syscall entry
+	let (data, len) = unsafe {
+		match (write, format == RD_IO_FORMAT_VEC) {
+			(true, true)   => (Some(op.buf.vec.unwrap_or(&[]).concat()), 0),
+			(true, false)  => (Some(op.buf.buf.unwrap_or(&[]).to_vec()), 0),
+			(false, false) => (None, op.buf.len),
+			(false, true)  => return Err(ERR_NOT_IMPLEMENTED)
+		}
+	};
+	if data.as_ref().map_or(false, |d| d.len() > SRV_MAX_LEN) {
+		return Err(ERR_INVALID_ARG);
+	}
+	Ok(QueuedOp { service, handle: desc.handle, id: op.id, offset: op.off as u64, data, len })
+}
+
+/// Checks the descriptor is open with `access` and the caller is within its I/O limits.
+fn prepare(rd: usize, flags: usize, access: usize) -> Result<(&'static mut ResourceDescriptor, Target, &'static mut Context), usize> {
+	if flags & !RD_IO_FLAG_SYNC != 0 {
+		return Err(ERR_NOT_IMPLEMENTED);
+	}
+
+	let desc = owned(rd).ok_or(ERR_INVALID_ARG)?;
+	if desc.flags & access != access {
+		return Err(ERR_PROTECTION);
+	}
+
+	let target = match (blk::lookup(desc.node), fs::lookup(desc.node), srv::lookup(desc.node)) {
+		(Some(device), ..)  => Target::Device(device),
+		(_, Some(mount), _) => Target::File(mount),
+		(.., Some(service)) => Target::Service(service),
+		_ if random::is_node(desc.node) => Target::Random,
+		_                   => return Err(ERR_INVALID_ARG)
+	};
+	// SAFETY: the caller is the running context
+	let ctx = unsafe { &mut *hart::current().current };
+	blk::admit(ctx)?;
+	Ok((desc, target, ctx))
+}
+
+/// The descriptor, if the caller opened it.
+fn owned(rd: usize) -> Option<&'static mut ResourceDescriptor> {
+	let ctx = hart::current().current;
+	// SAFETY: descriptors are only freed by `svc_rd_close` of their owner
+	blk::nodes()
+		.chain(fs::nodes())
+		.chain(srv::nodes())
+		.chain(random::node())
+		.flat_map(|node| ResourceDescriptors(unsafe { (*node).users }))
+		.find(|&desc| desc as usize == rd && unsafe { (*desc).ctx } == ctx)
+		.map(|desc| unsafe { &mut *desc })
+}
+
+/// Iterates the descriptors linked to a node.
+struct ResourceDescriptors(*mut ResourceDescriptor);
+
+impl Iterator for ResourceDescriptors {
+	type Item = *mut ResourceDescriptor;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		(!self.0.is_null()).then(|| {
+			let desc = self.0;
+			// SAFETY: see `owned`
+			self.0 = unsafe { (*desc).next };
+			desc
+		})
+	}
+}
+
+fn error(err: usize) -> (usize, usize, usize, usize) {
+	(-(err as isize) as usize, 0, 0, 0)
+}
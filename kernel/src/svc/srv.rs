@@ -0,0 +1,95 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Service channels of drivers, backs the `sys_srv_*` syscalls
+
+use crate::{srv, svi::{SrvRequest, sys::{SRV_MAX_LEN, SRV_RECEIVE_NON_BLOCK, ERR_INVALID_ARG, ERR_INVALID_MEM_REF}}};
+
+pub fn svc_srv_register(path: usize, len: usize, flags: usize, block_size: usize, blocks: usize, _: usize) -> (usize, usize, usize, usize) {
+	if path == 0 || len > 4096 {
+		return error(ERR_INVALID_MEM_REF);
+	}
+
+	// SAFETY: the caller's memory was validated by the syscall entry
+	let path = match core::str::from_utf8(unsafe { core::slice::from_raw_parts(path as *const u8, len) }) {
+		Ok(path) => path,
+		Err(_)   => return error(ERR_INVALID_ARG)
+	};
+	match srv::register(path, flags, block_size, blocks as u64) {
+		Ok(rd) => (rd, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+pub fn svc_srv_publish(rd: usize, _: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	match srv::publish(rd) {
+		Ok(())  => (0, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+pub fn svc_srv_receive(rd: usize, req: usize, buf: usize, len: usize, flags: usize, _: usize) -> (usize, usize, usize, usize) {
+	if flags & !SRV_RECEIVE_NON_BLOCK != 0 {
+		return error(ERR_INVALID_ARG);
+	} else if req == 0 || (buf == 0 && len != 0) {
+		return error(ERR_INVALID_MEM_REF);
+	}
+
+	// SAFETY: the caller's memory was validated by the syscall entry
+	let (req, buf) = unsafe {
+		(&mut *(req as *mut SrvRequest), core::slice::from_raw_parts_mut(buf as *mut u8, len.min(SRV_MAX_LEN)))
+	};
+	match srv::receive(rd, req, buf, flags & SRV_RECEIVE_NON_BLOCK != 0) {
+		Ok(len) => (len, 0, 0, 0),
+		Err(e)  => error(e)
+	}
+}
+
+pub fn svc_srv_reply(rd: usize, id: usize, result: usize, buf: usize, len: usize, _: usize) -> (usize, usize, usize, usize) {
+	if buf == 0 && len != 0 {
+		return error(ERR_INVALID_MEM_REF);
+	} else if len > SRV_MAX_LEN {
+		return error(ERR_INVALID_ARG);
+	}
+
+	let result = match (result as isize) < 0 {
+		true  => Err((result as isize).unsigned_abs()),
+		false => Ok(result)
+	};
+	// SAFETY: the caller's memory was validated by the syscall entry
+	let buf = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+	match srv::reply(rd, id, result, buf) {
+		Ok(())  => (0, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+pub fn svc_srv_unregister(rd: usize, _: usize, _: usize, _: usize, _: usize, _: usize) -> (usize, usize, usize, usize) {
+	match srv::unregister(rd) {
+		Ok(())  => (0, 0, 0, 0),
+		Err(e) => error(e)
+	}
+}
+
+fn error(err: usize) -> (usize, usize, usize, usize) {
+	(-(err as isize) as usize, 0, 0, 0)
+}
@@ -212,6 +212,24 @@ pub enum GroupAttr {
 	ParentGroup
 }
 
+/// A request taken from a service channel with `sys_srv_receive`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SrvRequest {
+	/// Passed back to `sys_srv_reply`
+	pub id:     usize,
+	/// One of `SRV_OP_*`
+	pub op:     usize,
+	/// The resource of a service, as returned for `SRV_OP_OPEN`
+	pub handle: usize,
+	/// Byte offset on the disk or in the resource
+	pub offset: u64,
+	/// Bytes to read, write or discard, or of the path to open
+	pub len:    usize,
+	/// The `RD_OPEN_*` flags of `SRV_OP_OPEN`
+	pub flags:  usize
+}
+
 /// test
 ///
 /// # Description
@@ -58,6 +58,8 @@ pub const RD_IO_FORMAT_REG:     usize = 0b10;
 pub const RD_IO_FLAG_SYNC:      usize = 0x10;
 /// A file name is passed instead of an RD
 pub const RD_IO_FLAG_FILE_NAME:       usize = 0x20;
+/// An operation of `sys_rd_ops` writes `buf`, otherwise it reads up to `buf.len` bytes
+pub const RD_IO_OP_WRITE:             usize = 0x40;
 
 pub const RD_IO_REG_RD_ARG0:      usize = 0x100;
 pub const RD_IO_REG_RD_ARG1:      usize = 0x101;
@@ -65,7 +67,7 @@ pub const RD_IO_REG_RD_ARG2:      usize = 0x102;
 pub const RD_IO_REG_RD_ARG3:      usize = 0x103;
 
 /// All possible flags
-pub const RD_IO_FLAGS:                usize = 0x3F;
+pub const RD_IO_FLAGS:                usize = 0x7F;
 pub const RD_IO_LEN_WHOLE_LEN:        usize = !0;
 /// The IO operation would block the task to complete
 pub const RD_IO_ERR_WOULD_BLOCK:      usize = 0x1000;
@@ -83,6 +85,8 @@ pub const MEM_MAP_FLAG_USAGE_STACK:   usize = 0x40;
 pub const MEM_MAP_FLAG_PHYSICAL_CONT: usize = 0x80;
 pub const MEM_MAP_FLAG_ADDRESS_HINT:  usize = 0x100;
 pub const MEM_MAP_FLAG_DISCARD_OLD:   usize = 0x200;
+/// Map the physical range at the passed address, e.g. the registers of a device
+pub const MEM_MAP_FLAG_PHYSICAL:      usize = 0x400;
 /// Map the whole file. If the rd is an anonymous region this will fail
 pub const MEM_MAP_LEN_WHOLE_LEN:      usize = !0;
 
@@ -115,6 +119,36 @@ pub const RD_ATTR_CTX_USAGE_IO_TIME:      u32 = 0x100D;
 pub const RD_ATTR_CTX_USAGE_IO_OPS:       u32 = 0x100E;
 pub const RD_ATTR_CTX_USAGE_IO_RW:        u32 = 0x100F;
 pub const RD_ATTR_INT_PHY_ID:             u32 = 0x2000;
+/// Physical address of an anonymous resource mapped with `MEM_MAP_FLAG_PHYSICAL_CONT`
+pub const RD_ATTR_PHYS_ADDR:              u32 = 0x2001;
+/// md level of a RAID array, i.e. of a `/dev/md<n>` device
+pub const RD_ATTR_RAID_LEVEL:             u32 = 0x2100;
+/// Number of members of a RAID array that are missing, failed or being rebuilt
+pub const RD_ATTR_RAID_DEGRADED:          u32 = 0x2101;
+/// Background work of a RAID array, one of `RAID_ACTION_*`. Setting `RAID_ACTION_CHECK` or
+/// `RAID_ACTION_REPAIR` starts a scrub, `RAID_ACTION_IDLE` stops it.
+pub const RD_ATTR_RAID_ACTION:            u32 = 0x2102;
+/// Blocks the background work of a RAID array has done
+pub const RD_ATTR_RAID_COMPLETED:         u32 = 0x2103;
+/// Blocks the background work of a RAID array goes through
+pub const RD_ATTR_RAID_TOTAL:             u32 = 0x2104;
+/// Blocks the last scrub of a RAID array found inconsistent
+pub const RD_ATTR_RAID_MISMATCHES:        u32 = 0x2105;
+/// Write-only, fails the member of a RAID array in the slot given as the value
+pub const RD_ATTR_RAID_FAIL:              u32 = 0x2106;
+/// Bits of entropy of the pool behind `/dev/random`, up to 256. Setting it credits the
+/// value as the entropy of the data written to `/dev/random` since the last credit, at
+/// most 8 bits per byte. Only entropy device drivers and privileged tasks may set it,
+/// others fail with `ERR_PROTECTION`.
+pub const RD_ATTR_RANDOM_ENTROPY:         u32 = 0x2200;
+/// Read-only, whether the pool behind `/dev/random` is seeded, 0 or 1
+pub const RD_ATTR_RANDOM_SEEDED:          u32 = 0x2201;
+
+pub const RAID_ACTION_IDLE:               usize = 0;
+pub const RAID_ACTION_RESYNC:             usize = 1;
+pub const RAID_ACTION_REBUILD:            usize = 2;
+pub const RAID_ACTION_CHECK:              usize = 3;
+pub const RAID_ACTION_REPAIR:             usize = 4;
 
 pub const CTX_STATE_RUNNING:              u32 = 0;
 pub const CTX_STATE_BLOCKED:              u32 = 1;
@@ -122,6 +156,69 @@ pub const CTX_STATE_SUSPENDED:            u32 = 2;
 pub const CTX_STATE_STOPPED:              u32 = 3;
 pub const CTX_STATE_ABORTED:              u32 = 4;
 
+/// Power off the system (ACPI S5)
+pub const POWER_OP_OFF:                   usize = 0;
+/// Reset the system
+pub const POWER_OP_REBOOT:                usize = 1;
+/// Suspend to RAM (ACPI S3), returns once the system resumed
+pub const POWER_OP_SUSPEND:               usize = 2;
+
+/// Allocate interrupt vectors on the calling hart
+pub const INT_ALLOC_LOCAL:                usize = 1;
+/// Return `RD_IO_ERR_WOULD_BLOCK` instead of waiting for a vector to fire
+pub const INT_WAIT_NON_BLOCK:             usize = 1;
+
+/// Return output even if the entropy pool is not seeded yet
+pub const RANDOM_FLAG_INSECURE:           usize = 1;
+/// The most bytes returned by one `sys_random` call
+pub const RANDOM_MAX_LEN:                 usize = 0x10000;
+
+/// Take pages for the balloon and return their page frame numbers
+pub const BALLOON_OP_INFLATE:             usize = 0;
+/// Return pages of the balloon
+pub const BALLOON_OP_DEFLATE:             usize = 1;
+/// Take free blocks that weren't reported to the host out of the allocator and return
+/// the page frame numbers of their first pages
+pub const BALLOON_OP_REPORT:              usize = 2;
+/// Put blocks taken by `BALLOON_OP_REPORT` back, they were reported
+pub const BALLOON_OP_REPORTED:            usize = 3;
+/// Return the memory statistics as pairs of a virtio balloon tag and a value in bytes
+pub const BALLOON_OP_STATS:               usize = 4;
+/// Pages of a block taken by `BALLOON_OP_REPORT`, as a power of two
+pub const BALLOON_REPORT_ORDER:           usize = 9;
+/// The most page frame numbers or statistics passed to one `sys_mem_balloon` call
+pub const BALLOON_MAX_PFNS:               usize = 256;
+
+/// The service is a disk, registered with the block layer as `/dev/<name>`
+pub const SRV_FLAG_DISK:                  usize = 1;
+/// The disk can't be written to
+pub const SRV_FLAG_READ_ONLY:             usize = 2;
+/// The service is the transport of a 9P share, mounted at `/mnt/<name>`
+pub const SRV_FLAG_9P:                    usize = 4;
+/// Return `RD_IO_ERR_WOULD_BLOCK` instead of waiting for a request
+pub const SRV_RECEIVE_NON_BLOCK:          usize = 1;
+/// Read `len` bytes at `offset` of the disk or resource `handle`, the reply carries the data
+pub const SRV_OP_READ:                    usize = 0;
+/// Write the data of the request at `offset` of the disk or resource `handle`
+pub const SRV_OP_WRITE:                   usize = 1;
+/// Write back the device's cache
+pub const SRV_OP_SYNC:                    usize = 2;
+/// Discard `len` bytes at `offset`
+pub const SRV_OP_DISCARD:                 usize = 3;
+/// Open the resource at the path in the data of the request, relative to the service, with
+/// the `RD_OPEN_*` flags in `flags`. The result is the handle of the resource.
+pub const SRV_OP_OPEN:                    usize = 4;
+/// Close the resource `handle`
+pub const SRV_OP_CLOSE:                   usize = 5;
+/// Send the 9P message in the data of the request, the reply carries the response of at
+/// most `len` bytes
+pub const SRV_OP_TRANSACT:                usize = 6;
+/// The most bytes of data carried by one request or reply
+pub const SRV_MAX_LEN:                    usize = 0x10000;
+/// Flag of a read or write queued with `sys_rd_ops`, the client polls for the result
+/// instead of waiting for it
+pub const SRV_OP_FLAG_ASYNC:              usize = 1;
+
 /// Opens a resource, identified by `filename`.
 ///
 /// # Description
@@ -317,7 +414,7 @@ pub fn sys_rd_read(rd: RdOrPath, flags: usize, buf: IoReadBuf, offset: u64, op_i
 /// | -4096 | `RD_IO_ERR_WOULD_BLOCK`    | The `RD_IO_FLAG_NON_BLOCK` flag was set, but the operation would block the task.
 #[inline(always)]
 pub fn sys_rd_write(rd: RdOrPath, flags: usize, buf: IoWriteBuf, offset: u64, op_id: IoOpId) -> Result<usize> {
-    arch_svc!(3, rd, buf, offset, op_id)
+    arch_svc!(3, rd, flags, buf, offset, op_id)
 }
 
 /// Commits the resource's cached data to disk.
@@ -480,6 +577,7 @@ pub fn sys_rd_unlock(rd: Rd, flags: Flags, offset: usize, len: usize) -> Result<
 /// |   9 | `MEM_MAP_FLAG_ADDRESS_HINT`  | The passed address is just a hint, the actual address of the mapping will be returned.
 /// |  10 | `MEM_MAP_FLAG_DISCARD_OLD`   | Discards mappings that lie in the specified range
 /// |  11 | `MEM_MAP_FLAG_ATTACH`        | The specified address rane is already mapped and contains data that should be attached to this resource
+/// |  11 | `MEM_MAP_FLAG_PHYSICAL`      | `addr` is a physical address to map uncached, `rd` must be `INVALID_RD`. Only drivers may set this flag.
 ///
 /// # Returns
 ///
@@ -580,33 +678,79 @@ pub fn sys_rd_mem_unlock(addr: *mut u8, len: usize, flags: Flags) -> Result<()>
     arch_svc!(12, addr, len, flags)
 }
 
+/// Queues a batch of reads and writes, their results are taken with `sys_rd_poll`.
+///
+/// # Description
+///
+/// Only the resources of services support it, see `sys_srv_register`. The operations are
+/// passed on to the service without blocking the task, those of one resource complete in
+/// the order they were queued. Nothing is queued if one of the operations is invalid.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `ops`    | The operations, see *Operations*.
+///
+/// # Operations
+///
+/// | Field   | Description
+/// |---------|------------
+/// | `rd`    | The resource descriptor, file names are not supported.
+/// | `id`    | The id to poll the operation with, unique among the task's queued operations.
+/// | `flags` | `RD_IO_OP_WRITE` for a write and the format, `RD_IO_FORMAT_BUF` or, for writes, `RD_IO_FORMAT_VEC`.
+/// | `buf`   | The data of a write, at most `SRV_MAX_LEN` bytes, or the most bytes to read.
+/// | `off`   | The offset into the resource, ignored by streams.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// Zero (0).
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -2 | `ERR_NOT_IMPLEMENTED`      | An operation is on a resource other than a service's, or a file name or `RD_IO_FORMAT_REG` was passed.
+/// |    -6 | `ERR_INVALID_ARG`          | An invalid argument was passed, one of:
+/// |       |                            | - `rd` is not open
+/// |       |                            | - `id` is already queued
+/// |       |                            | - `flags` had an unknown flag set
+/// |       |                            | - a write carries more than `SRV_MAX_LEN` bytes
+/// |    -7 | `ERR_INVALID_MEM_REF`      | `ops` or a buffer is outside of the task's accessible address space.
+/// |    -8 | `ERR_PROTECTION`           | The rd was not opened with `RD_OPEN_FLAG_READ` or `RD_OPEN_FLAG_WRITE`, as needed.
 #[inline(always)]
 pub fn sys_rd_ops(ops: &[IoOp]) -> Result<()> {
-    arch_svc!(13, ops)
+    arch_svc!(13, ops.as_ptr(), ops.len())
 }
 
 /// Completes (or polls) an IO operation.
 ///
 /// # Description
 ///
+/// Takes the result of an operation queued with `sys_rd_ops`, the id can be reused
+/// afterwards. The data of a read is copied to `buf`.
+///
 /// # Arguments
 ///
 /// | Argument | Description
 /// |----------|------------
 /// | `op_id`  | An IO OP ID
 /// | `flags`  | A bitfield, see *Flags*.
+/// | `buf`    | The buffer for the data of a read, ignored by writes.
 ///
 /// # Flags
 ///
 /// | Bit | Flag                   | Description
 /// |-----|------------------------|------------
-/// |     | `RD_IO_FLAG_NON_BLOCK` | If the operation needs to block the task to complete, `RD_IO_ERR_WOULD_BLOCK` is returned.
+/// |   1 | `RD_IO_MODE_NON_BLOCK` | If the operation needs to block the task to complete, `RD_IO_ERR_WOULD_BLOCK` is returned.
 ///
 /// # Returns
 ///
 /// ## On Success
 ///
-/// The number of transferred bytes.
+/// The number of transferred bytes, of a read at most the length of `buf`.
 ///
 /// ## On Failure
 ///
@@ -619,10 +763,10 @@ pub fn sys_rd_ops(ops: &[IoOp]) -> Result<()> {
 /// |    -6 | `ERR_INVALID_ARG`          | An invalid argument was passed, one of:
 /// |       |                            | - `op_id` was invalid
 /// |    -7 | `ERR_INVALID_MEM_REF`      | `buf` is outside of the task's accessible address space.
-/// | -4096 | `RD_IO_ERR_WOULD_BLOCK`    | The `RD_IO_FLAG_NON_BLOCK` flag was set, but the operation would block the task.
+/// | -4096 | `RD_IO_ERR_WOULD_BLOCK`    | The `RD_IO_MODE_NON_BLOCK` flag was set, but the operation would block the task.
 #[inline(always)]
-pub fn sys_rd_poll(id: IoOpId, flags: usize) -> Result<usize> {
-    arch_svc!(14, id, flags)
+pub fn sys_rd_poll(id: IoOpId, flags: usize, buf: &mut [u8]) -> Result<usize> {
+    arch_svc!(14, id, flags, buf.as_mut_ptr(), buf.len())
 }
 
 #[inline(always)]
@@ -667,12 +811,12 @@ pub fn sys_rd_move(rd: RdOrPath, path: &str, dir: Rd) -> Result<()> {
 
 #[inline(always)]
 pub fn sys_set_attr(rd: RdOrPath, key: usize, val: usize, dir: Rd, flags: u32) -> Result<()> {
-    arch_svc!(21, rd, val, dir)
+    arch_svc!(21, rd, key, val, dir, flags)
 }
 
 #[inline(always)]
 pub fn sys_get_attr(rd: RdOrPath, key: usize, dir: Rd, flags: u32) -> Result<usize> {
-    arch_svc!(22, rd, key, dir)
+    arch_svc!(22, rd, key, dir, flags)
 }
 
 #[inline(always)]
@@ -710,22 +854,420 @@ pub fn sys_ctx_unmount(rd: Rd, mnt: Rd) -> Result<()> {
 
 }
 
+/// Allocates message signaled interrupt vectors for a device.
+///
+/// # Description
+///
+/// Allocates `messages.len()` vectors on one hart and writes the address/data pair of each
+/// vector to `messages`, which the caller programs into the MSI capability or MSI-X table
+/// of the device. Whenever a vector fires, the task receives an `IoReady` interrupt.
+///
+/// # Arguments
+///
+/// | Argument   | Description
+/// |------------|------------
+/// | `device`   | The PCI requester id (bus, device, function) of the device.
+/// | `messages` | Receives the messages, its length must be a power of two up to 32.
+/// | `flags`    | A bitfield, see *Flags*.
+///
+/// # Flags
+///
+/// | Bit | Flag              | Description
+/// |-----|-------------------|------------
+/// |   1 | `INT_ALLOC_LOCAL` | Allocate the vectors on the calling hart instead of the least used hart of its node.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// A resource descriptor referencing the vectors.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -2 | `ERR_NOT_IMPLEMENTED`      | The interrupt controller does not support message signaled interrupts.
+/// |    -3 | `ERR_OUT_OF_KERNEL_MEMORY` | No hart has a free block of vectors.
+/// |    -6 | `ERR_INVALID_ARG`          | The length of `messages` is invalid or `flags` had an unknown flag set.
 #[inline(always)]
-pub fn sys_int_alloc() -> Result<Rd> {
-
+pub fn sys_int_alloc(device: u32, messages: &mut [hw::pcie::MsiMessage], flags: Flags) -> Result<Rd> {
+    arch_svc!(24, device, messages.as_mut_ptr(), messages.len(), flags)
 }
 
+/// Frees vectors allocated by `sys_int_alloc`, the device must no longer use them.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `rd` does not reference vectors owned by the task.
 #[inline(always)]
 pub fn sys_int_free(rd: Rd) -> Result<()> {
-
+    arch_svc!(25, rd)
 }
 
+/// Stops delivering the vectors referenced by `rd`, they are delivered once unmasked.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `rd` does not reference vectors owned by the task.
 #[inline(always)]
 pub fn sys_int_mask(rd: Rd) -> Result<()> {
-
+    arch_svc!(26, rd)
 }
 
+/// Resumes delivering the vectors referenced by `rd`.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `rd` does not reference vectors owned by the task.
 #[inline(always)]
 pub fn sys_int_unmask(rd: Rd) -> Result<()> {
+    arch_svc!(27, rd)
+}
+
+/// Waits for vectors allocated by `sys_int_alloc` to fire.
+///
+/// # Description
+///
+/// Returns the vectors of `rd` that fired since the last call, blocking the task until one
+/// does. Drivers run a thread per block of vectors that calls this in a loop, so interrupts
+/// are handled without an interrupt handler. Masked vectors are returned once unmasked.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `rd`     | The vectors to wait for.
+/// | `flags`  | A bitfield, see *Flags*.
+///
+/// # Flags
+///
+/// | Bit | Flag                 | Description
+/// |-----|----------------------|------------
+/// |   1 | `INT_WAIT_NON_BLOCK` | Return `RD_IO_ERR_WOULD_BLOCK` if no vector fired.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// A mask of the indices of the vectors in the block that fired.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `rd` does not reference vectors owned by the task, e.g. because
+/// |       |                            | they were freed while waiting, or `flags` had an unknown flag set.
+/// | -4096 | `RD_IO_ERR_WOULD_BLOCK`    | `INT_WAIT_NON_BLOCK` was set, but no vector fired.
+#[inline(always)]
+pub fn sys_int_wait(rd: Rd, flags: Flags) -> Result<usize> {
+    arch_svc!(30, rd, flags)
+}
+
+/// Changes the power state of the system.
+///
+/// # Description
+///
+/// Shutdown and reboot use the ACPI tables provided by the firmware, if they are not
+/// available or fail, all harts are parked instead. Suspending to RAM needs the ACPI
+/// tables, an FACS for the waking vector and, for now, all other harts to be down. The
+/// kernel saves the state of the calling hart and restores it when the firmware resumes
+/// at the waking vector, drivers have to save and restore the state of their devices.
+/// Only privileged tasks may change the power state.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `op`     | One of `POWER_OP_OFF`, `POWER_OP_REBOOT` or `POWER_OP_SUSPEND`.
+/// | `flags`  | Reserved, must be zero.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// Does not return, except for `POWER_OP_SUSPEND`, which returns zero (0) once the system
+/// resumed.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -2 | `ERR_NOT_IMPLEMENTED`      | The platform does not support the operation.
+/// |    -5 | `ERR_IO`                   | The firmware failed to enter S3.
+/// |    -6 | `ERR_INVALID_ARG`          | `op` is unknown or `flags` is not zero.
+/// |    -8 | `ERR_PROTECTION`           | The task does not have permission to change the power state.
+/// |    -9 | `ERR_NOT_READY`            | Other harts are still up, so the system can't be suspended.
+#[inline(always)]
+pub fn sys_power(op: usize, flags: Flags) -> Result<()> {
+    arch_svc!(23, op, flags)
+}
+
+/// Fills `buf` with cryptographically secure random bytes.
+///
+/// # Description
+///
+/// The bytes come from the kernel's entropy pool, a ChaCha20 generator seeded from the
+/// processor's random number generator, timer jitter and entropy devices. The same
+/// bytes can be read from `/dev/random`.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `buf`    | The buffer to fill, at most `RANDOM_MAX_LEN` bytes are written.
+/// | `flags`  | Zero or `RANDOM_FLAG_INSECURE`, which returns output before the pool is
+/// |          | seeded, e.g. for hash table keys during early boot.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// The number of bytes written, the smaller of the length of `buf` and `RANDOM_MAX_LEN`.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `flags` contains unknown flags.
+/// |    -7 | `ERR_INVALID_MEM_REF`      | `buf` is not accessible by the task.
+/// |    -9 | `ERR_NOT_READY`            | The pool is not seeded yet and `RANDOM_FLAG_INSECURE` is not set.
+#[inline(always)]
+pub fn sys_random(buf: &mut [u8], flags: Flags) -> Result<usize> {
+    arch_svc!(28, buf.as_mut_ptr(), buf.len(), flags)
+}
+
+/// Moves memory between the kernel's allocator and a memory balloon driver.
+///
+/// # Description
+///
+/// Inflating takes pages from the allocator, nearest node first, which the driver then
+/// gives to the host; deflating returns them once the host gave them back. Free page
+/// reporting takes free blocks of `2^BALLOON_REPORT_ORDER` pages out of the allocator
+/// while the driver reports them, blocks are only reported again after they were used.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `op`     | One of `BALLOON_OP_*`.
+/// | `pfns`   | The page frame numbers to return, or the buffer to store them in, at most
+/// |          | `BALLOON_MAX_PFNS`.
+/// | `flags`  | Reserved, must be zero.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// The number of page frame numbers stored or returned.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `op` is unknown, `flags` is not zero, `pfns` is too long or a page
+/// |       |                            | frame number wasn't taken by the balloon or `BALLOON_OP_REPORT`.
+/// |    -7 | `ERR_INVALID_MEM_REF`      | `pfns` is not accessible by the task.
+/// |    -8 | `ERR_PROTECTION`           | The task does not have permission to manage the balloon.
+#[inline(always)]
+pub fn sys_mem_balloon(op: usize, pfns: &mut [u32], flags: Flags) -> Result<usize> {
+    arch_svc!(29, op, pfns.as_mut_ptr(), pfns.len(), flags)
+}
+
+/// Returns memory statistics for a memory balloon driver, `BALLOON_OP_STATS` of
+/// `sys_mem_balloon`. Each pair is a virtio balloon statistics tag and a value in bytes.
+#[inline(always)]
+pub fn sys_mem_balloon_stats(stats: &mut [[u64; 2]]) -> Result<usize> {
+    arch_svc!(29, BALLOON_OP_STATS, stats.as_mut_ptr(), stats.len(), 0)
+}
+
+/// Registers a service channel, for a disk or 9P share a driver attached or a service of the task.
+///
+/// # Description
+///
+/// The channel is not visible until `sys_srv_publish` is called, so threads serving it can
+/// be started first. Requests are taken with `sys_srv_receive` and answered with
+/// `sys_srv_reply`, the task that registered the channel owns it. Only drivers and
+/// privileged tasks may register channels.
+///
+/// A service is published at `/<name>`. Opening a path below it sends `SRV_OP_OPEN`,
+/// reads, writes and syncs of the descriptor are sent with the handle the service
+/// returned, closing it sends `SRV_OP_CLOSE`. The kernel's 9P client sends the messages to
+/// a share with `SRV_OP_TRANSACT`.
+///
+/// # Arguments
+///
+/// | Argument     | Description
+/// |--------------|------------
+/// | `path`       | The path of the service, `/dev/<name>` for disks, `/mnt/<name>` for shares
+/// |              | and `/<name>` otherwise.
+/// | `flags`      | A bitfield, see *Flags*.
+/// | `block_size` | The logical block size of the disk, it must divide the page size. Zero
+/// |              | for other services.
+/// | `blocks`     | The number of blocks of the disk, zero for other services.
+///
+/// # Flags
+///
+/// | Bit | Flag                 | Description
+/// |-----|----------------------|------------
+/// |   1 | `SRV_FLAG_DISK`      | The service is a disk.
+/// |   2 | `SRV_FLAG_READ_ONLY` | The disk can't be written to.
+/// |   3 | `SRV_FLAG_9P`        | The service is the transport of a 9P share.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// The descriptor of the channel.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `path` is malformed or already registered, the disk is empty, a
+/// |       |                            | service has a size or `flags` had an unknown flag set.
+/// |    -7 | `ERR_INVALID_MEM_REF`      | `path` is not accessible by the task.
+/// |    -8 | `ERR_PROTECTION`           | The task is neither a driver nor privileged.
+#[inline(always)]
+pub fn sys_srv_register(path: &str, flags: Flags, block_size: usize, blocks: u64) -> Result<Rd> {
+    arch_svc!(31, path.as_ptr(), path.len(), flags, block_size, blocks as usize)
+}
+
+/// Makes the service of a channel visible, a disk is registered with the block layer, a
+/// share mounted at `/mnt/<name>` and a service added at `/<name>`.
+///
+/// # Description
+///
+/// The partition table of a disk is read, arrays it completes are assembled and the file
+/// systems on them mounted at `/mnt/<device>` before this returns, as is the share attached
+/// to. Another thread of the task has to serve the channel meanwhile.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `rd`     | The channel.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// Zero (0).
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -5 | `ERR_IO`                   | The 9P server didn't attach to the share.
+/// |    -6 | `ERR_INVALID_ARG`          | `rd` is not a channel of the task or it was published already,
+/// |       |                            | or the path of a service or share is in use.
+#[inline(always)]
+pub fn sys_srv_publish(rd: Rd) -> Result<()> {
+    arch_svc!(32, rd)
+}
 
-}
\ No newline at end of file
+/// Takes the next request of a channel.
+///
+/// # Description
+///
+/// Blocks until there is a request. The request is stored in `req`, the data of a write is
+/// copied to `buf`, which should hold `SRV_MAX_LEN` bytes.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `rd`     | The channel.
+/// | `req`    | Receives the request.
+/// | `buf`    | Receives the data of the request.
+/// | `flags`  | A bitfield, see *Flags*.
+///
+/// # Flags
+///
+/// | Bit | Flag                    | Description
+/// |-----|-------------------------|------------
+/// |   1 | `SRV_RECEIVE_NON_BLOCK` | Return `RD_IO_ERR_WOULD_BLOCK` if there is no request.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// The number of bytes copied to `buf`.
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `rd` is not a channel of the task, it was unregistered while
+/// |       |                            | waiting, `buf` is too short or `flags` had an unknown flag set.
+/// |    -7 | `ERR_INVALID_MEM_REF`      | `req` or `buf` is not accessible by the task.
+/// | -4096 | `RD_IO_ERR_WOULD_BLOCK`    | `SRV_RECEIVE_NON_BLOCK` was set, but there is no request.
+#[inline(always)]
+pub fn sys_srv_receive(rd: Rd, req: &mut SrvRequest, buf: &mut [u8], flags: Flags) -> Result<usize> {
+    arch_svc!(33, rd, req as *mut SrvRequest, buf.as_mut_ptr(), buf.len(), flags)
+}
+
+/// Answers a request taken with `sys_srv_receive`.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `rd`     | The channel.
+/// | `id`     | The `id` of the request.
+/// | `result` | The result of the request, or an error as a syscall returns it, e.g. `-ERR_IO`.
+/// | `buf`    | The data of a read, at most `len` bytes of the request.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// Zero (0).
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `rd` is not a channel of the task, `id` no request taken from it
+/// |       |                            | or `buf` is longer than requested. The request fails with `ERR_IO`.
+/// |    -7 | `ERR_INVALID_MEM_REF`      | `buf` is not accessible by the task.
+#[inline(always)]
+pub fn sys_srv_reply(rd: Rd, id: usize, result: isize, buf: &[u8]) -> Result<()> {
+    arch_svc!(34, rd, id, result, buf.as_ptr(), buf.len())
+}
+
+/// Removes a channel and fails its pending requests with `ERR_NOT_READY`.
+///
+/// # Description
+///
+/// File systems on a disk and a share are unmounted and the disk's cache is written back
+/// first, so the channel has to be served until this returns. A disk that is open, has open
+/// files or is a member of an array stays in `/dev` and fails all requests from then on, as
+/// do a share with open files and open descriptors of a service.
+///
+/// # Arguments
+///
+/// | Argument | Description
+/// |----------|------------
+/// | `rd`     | The channel.
+///
+/// # Returns
+///
+/// ## On Success
+///
+/// Zero (0).
+///
+/// ## On Failure
+///
+/// | Value | Error                      | Description
+/// |-------|----------------------------|------------
+/// |    -6 | `ERR_INVALID_ARG`          | `rd` is not a channel of the task.
+#[inline(always)]
+pub fn sys_srv_unregister(rd: Rd) -> Result<()> {
+    arch_svc!(35, rd)
+}
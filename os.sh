@@ -5,11 +5,24 @@ targets_binutils=(["amd64"]="x86_64" ["aarch64"]="aarch64" ["ppc64"]="powerpc64l
 targets_arch=(["amd64"]="i386:x86-64" ["aarch64"]="aarch64" ["ppc64"]="powerpc:common64" ["riscv32gc"]="rv32" ["riscv64gc"]="rv64" ["riscv128gc"]="rv128" )
 qemu_opts="-smp 4 -m 4G -no-reboot -serial mon:stdio
 	-drive if=none,format=raw,file=res/d0.img,id=d0
+    -device virtio-blk-device,drive=d0
     -device virtio-rng-device
+    -device virtio-balloon-device,free-page-reporting=on
+    -device vhost-vsock-device,guest-cid=3
+	-fsdev local,id=host,path=res,security_model=none
+    -device virtio-9p-device,fsdev=host,mount_tag=host
     -device virtio-gpu-device
-    -device virtio-net-device
+	-netdev user,id=n0
+    -device virtio-net-device,netdev=n0
     -device virtio-tablet-device
-    -device virtio-keyboard-device"
+    -device virtio-keyboard-device
+    -device virtio-serial-device,max_ports=4
+	-chardev file,id=log,path=target/log.txt
+    -device virtconsole,chardev=log,name=log
+	-chardev socket,id=shell,path=target/shell.sock,server=on,wait=off
+    -device virtserialport,chardev=shell,name=shell
+	-chardev socket,id=session,path=target/session.sock,server=on,wait=off
+    -device virtserialport,chardev=session,name=session"
 qemu_dbg="-d guest_errors,unimp,in_asm,int"
 
 check_args () {
@@ -0,0 +1,218 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Exposes the disks attached to AHCI controllers, following hot-plug events.
+//!
+//! Disks are registered with the kernel's block layer as `sd<letter>` when they show up and
+//! unregistered when they are pulled out. Hot-plug events are handled when the MSI of the
+//! controller fires, controllers without MSI are polled by a thread instead.
+
+use {
+	std::{sync::{Arc, Mutex}, time::Duration},
+	hw::{ahci::{Controller, Error, Event, MAX_TRANSFER}, block::{self, BlockDevice}, dma::{Region, PAGE_SIZE}, pcie::{Device, Msi}},
+	kernel::svi::Rd,
+	super::{pcie::{Driver, Match}, platform::Sys}
+};
+
+pub static DRIVER: Driver = Driver {
+	name:    "ahci",
+	matches: &[Match::class_if(0x01, 0x06, 0x01)],
+	probe
+};
+
+/// How often controllers without MSI are checked for hot-plug events.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The controllers, which keep their disks across hot-plug events.
+pub static CONTROLLERS: Mutex<Vec<Arc<Mutex<Ahci>>>> = Mutex::new(Vec::new());
+
+/// The names the disks were registered under, by controller and port.
+static NAMES: Mutex<Vec<(Arc<Mutex<Ahci>>, usize, String)>> = Mutex::new(Vec::new());
+
+pub struct Ahci {
+	controller: Controller<Sys>,
+	msi:        Option<(Msi, Rd)>,
+	/// Requests are copied through this buffer, callers' memory isn't DMA memory
+	bounce:     Region
+}
+
+// SAFETY: the registers and command lists are only accessed with the lock held
+unsafe impl Send for Ahci {}
+
+impl Drop for Ahci {
+	fn drop(&mut self) {
+		if let (Some((msi, rd)), Some(mut cfg)) = (self.msi.take(), Sys::config()) {
+			msi.disable(&mut cfg);
+			Sys::free_vectors(&[rd]);
+		}
+		core::mem::replace(&mut self.bounce, Region { virt: core::ptr::null_mut(), phys: 0, size: 0 }).free(&mut Sys);
+	}
+}
+
+#[derive(Clone)]
+pub struct Disk {
+	pub name:    String,
+	pub port:    usize,
+	pub sectors: u64,
+	pub sector_size: u32,
+	controller:  Arc<Mutex<Ahci>>
+}
+
+impl Disk {
+	/// Reads whole sectors starting at `lba`.
+	pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+		let mut ahci = self.controller.lock().unwrap();
+		let ahci = &mut *ahci;
+		let mut lba = lba;
+
+		for chunk in buf.chunks_mut(ahci.bounce.size) {
+			// SAFETY: the bounce buffer is at least as large as the chunk
+			let bounce = unsafe { core::slice::from_raw_parts_mut(ahci.bounce.virt, chunk.len()) };
+			ahci.controller.read(self.port, lba, bounce)?;
+			chunk.copy_from_slice(bounce);
+			lba += (chunk.len() / self.sector_size as usize) as u64;
+		}
+		Ok(())
+	}
+
+	/// Writes whole sectors starting at `lba`.
+	pub fn write(&self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+		let mut ahci = self.controller.lock().unwrap();
+		let ahci = &mut *ahci;
+		let mut lba = lba;
+
+		for chunk in buf.chunks(ahci.bounce.size) {
+			// SAFETY: see `read`
+			let bounce = unsafe { core::slice::from_raw_parts_mut(ahci.bounce.virt, chunk.len()) };
+			bounce.copy_from_slice(chunk);
+			ahci.controller.write(self.port, lba, bounce)?;
+			lba += (chunk.len() / self.sector_size as usize) as u64;
+		}
+		Ok(())
+	}
+
+	pub fn flush(&self) -> Result<(), Error> {
+		self.controller.lock().unwrap().controller.flush(self.port)
+	}
+}
+
+impl BlockDevice for Disk {
+	fn block_size(&self) -> usize {
+		self.sector_size as usize
+	}
+
+	fn blocks(&self) -> u64 {
+		self.sectors
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		Ok(Disk::read(self, lba, buf)?)
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> {
+		Ok(Disk::write(self, lba, buf)?)
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		Ok(Disk::flush(self)?)
+	}
+
+	fn max_blocks(&self) -> u64 {
+		(MAX_TRANSFER / self.sector_size as usize) as u64
+	}
+}
+
+fn probe(device: &Device) -> bool {
+	match attach(device) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("ahci: {}: {:?}", device.address, e);
+			false
+		}
+	}
+}
+
+fn attach(device: &Device) -> Result<(), Error> {
+	let regs = Sys::map_bar(device, 5).ok_or(Error::NoDevice)?;
+	// SAFETY: BAR5 holds the HBA's registers
+	let controller = unsafe { Controller::new(regs, Sys)? };
+	let bounce = Region::alloc(&mut Sys, MAX_TRANSFER, PAGE_SIZE).ok_or(Error::NoMemory)?;
+	// without MSI the disks are still usable, completions are polled
+	let msi = Sys::alloc_msi(device);
+	println!("ahci: {} {:?}", device.address, controller);
+
+	let vector = msi.as_ref().map(|(_, rd)| *rd);
+	let ahci = Arc::new(Mutex::new(Ahci { controller, msi, bounce }));
+	let handler = ahci.clone();
+	match vector {
+		Some(vector) => Sys::on_interrupt(vector, move |_| interrupt(&handler)),
+		None => {
+			std::thread::spawn(move || loop {
+				std::thread::sleep(POLL_INTERVAL);
+				interrupt(&handler);
+			});
+		}
+	}
+	let ports = ahci.lock().unwrap().controller.disks().map(|(port, _)| port).collect::<Vec<_>>();
+	for port in ports {
+		add(&ahci, port);
+	}
+	CONTROLLERS.lock().unwrap().push(ahci);
+	Ok(())
+}
+
+fn add(ahci: &Arc<Mutex<Ahci>>, port: usize) {
+	let (sectors, sector_size) = match ahci.lock().unwrap().controller.disk(port) {
+		Some(disk) => (disk.sectors, disk.sector_size),
+		None => return
+	};
+
+	let name = crate::dri::disk::name("sd");
+	println!("{}: {} sectors of {} bytes", name, sectors, sector_size);
+	let disk = Disk { name: name.clone(), port, sectors, sector_size, controller: ahci.clone() };
+	match crate::dri::disk::register(&name, Box::new(disk)) {
+		Ok(_)  => NAMES.lock().unwrap().push((ahci.clone(), port, name)),
+		Err(e) => println!("{}: failed to register: {}", name, e)
+	}
+}
+
+fn remove(ahci: &Arc<Mutex<Ahci>>, port: usize) {
+	let mut names = NAMES.lock().unwrap();
+	if let Some(i) = names.iter().position(|(a, p, _)| Arc::ptr_eq(a, ahci) && *p == port) {
+		let (_, _, name) = names.remove(i);
+		println!("{}: removed", name);
+		// the disk is gone, flushing it fails
+		let _ = crate::dri::disk::unregister(&name);
+	}
+}
+
+/// Handles the interrupt of a controller, disks that were plugged in or pulled out are
+/// registered or unregistered.
+pub fn interrupt(ahci: &Arc<Mutex<Ahci>>) {
+	let events = ahci.lock().unwrap().controller.interrupt();
+	for event in events {
+		match event {
+			Event::Attached(port) => add(ahci, port),
+			Event::Detached(port) => remove(ahci, port)
+		}
+	}
+}
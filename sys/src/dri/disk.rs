@@ -0,0 +1,134 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Disks of the drivers in this process, served to the kernel's block layer.
+//!
+//! Drivers `register` each disk they attach as `/dev/<name>` and `unregister` it when the
+//! device goes away. Each disk gets a service channel and a thread that answers the
+//! kernel's requests by calling the driver, so requests to a disk are serialized. The
+//! kernel caches the disks, reads their partition tables, assembles arrays on them and
+//! mounts their file systems at `/mnt/<device>`. Names are handed out by `name`, `sda` to
+//! `sdz`, then `sdaa` and so on.
+
+use {
+	std::{collections::BTreeMap, sync::Mutex, thread},
+	hw::block::{self, BlockDevice},
+	kernel::svi::{Rd, SrvRequest, sys::*}
+};
+
+pub type BoxedDevice = Box<dyn BlockDevice + Send>;
+
+/// The channels of the registered disks by name
+static DISKS: Mutex<BTreeMap<String, Rd>> = Mutex::new(BTreeMap::new());
+
+pub fn error(e: block::Error) -> usize {
+	match e {
+		block::Error::InvalidArgument => ERR_INVALID_ARG,
+		block::Error::ReadOnly        => ERR_PROTECTION,
+		block::Error::NoDevice        => ERR_NOT_READY,
+		block::Error::NoMemory        => ERR_OUT_OF_KERNEL_MEMORY,
+		block::Error::Unsupported     => ERR_NOT_IMPLEMENTED,
+		_                             => ERR_IO
+	}
+}
+
+/// The first free name of `prefix` followed by letters, e.g. `vda`, `vdz`, `vdaa`.
+pub fn name(prefix: &str) -> String {
+	let disks = DISKS.lock().unwrap();
+	(0..).map(|i| format!("{}{}", prefix, letters(i)))
+		.find(|name| !disks.contains_key(name))
+		.unwrap()
+}
+
+/// `a` to `z` for 0 to 25, then `aa`, `ab`, ...
+fn letters(mut i: usize) -> String {
+	let mut s = Vec::new();
+	loop {
+		s.push(b'a' + (i % 26) as u8);
+		if i < 26 {
+			break;
+		}
+		i = i / 26 - 1;
+	}
+	s.reverse();
+	String::from_utf8(s).unwrap()
+}
+
+/// Registers a disk as `/dev/<name>`. The kernel reads its partition table before this
+/// returns, so the disk is served first.
+pub fn register(name: &str, dev: BoxedDevice) -> Result<(), usize> {
+	let flags = match dev.is_read_only() {
+		true  => SRV_FLAG_DISK | SRV_FLAG_READ_ONLY,
+		false => SRV_FLAG_DISK
+	};
+	let rd = sys_srv_register(&format!("/dev/{}", name), flags, dev.block_size(), dev.blocks())?;
+	DISKS.lock().unwrap().insert(name.into(), rd);
+
+	let thread = name.to_string();
+	if let Err(e) = thread::Builder::new().name(thread).spawn(move || serve(rd, dev)) {
+		println!("{}: failed to start serving: {}", name, e);
+		let _ = unregister(name);
+		return Err(ERR_OUT_OF_KERNEL_MEMORY);
+	}
+
+	sys_srv_publish(rd).map_err(|e| {
+		let _ = unregister(name);
+		e
+	})
+}
+
+/// Removes a disk, the kernel unmounts its file systems and writes back its cache first.
+/// A disk that is still open or has open files stays in `/dev` and fails all requests.
+pub fn unregister(name: &str) -> Result<(), usize> {
+	let rd = DISKS.lock().unwrap().remove(name).ok_or(ERR_INVALID_ARG)?;
+	sys_srv_unregister(rd)
+}
+
+/// Answers the requests of a channel until it is unregistered.
+fn serve(rd: Rd, mut dev: BoxedDevice) {
+	let mut buf = vec![0u8; SRV_MAX_LEN];
+	let mut req = SrvRequest::default();
+	loop {
+		let len = match sys_srv_receive(rd, &mut req, &mut buf, 0) {
+			Ok(len) => len,
+			Err(ERR_INTERRUPTED) => continue,
+			Err(_) => break
+		};
+
+		let size = dev.block_size() as u64;
+		let lba = req.offset / size;
+		let result = match req.op {
+			_ if req.offset % size != 0 || req.len > buf.len() => Err(block::Error::InvalidArgument),
+			SRV_OP_READ    => dev.read(lba, &mut buf[..req.len]).map(|_| req.len),
+			SRV_OP_WRITE   => dev.write(lba, &buf[..len]).map(|_| len),
+			SRV_OP_SYNC    => dev.flush().map(|_| 0),
+			SRV_OP_DISCARD => dev.discard(lba, req.len as u64 / size).map(|_| 0),
+			_              => Err(block::Error::Unsupported)
+		};
+
+		let _ = match result {
+			Ok(n) if req.op == SRV_OP_READ => sys_srv_reply(rd, req.id, n as isize, &buf[..n]),
+			Ok(n)  => sys_srv_reply(rd, req.id, n as isize, &[]),
+			Err(e) => sys_srv_reply(rd, req.id, -(error(e) as isize), &[])
+		};
+	}
+}
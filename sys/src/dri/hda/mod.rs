@@ -0,0 +1,204 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Exposes the codecs of High Definition Audio controllers as PCM endpoints, `pcm<n>p` for
+//! playback and `pcm<n>c` for capture.
+//!
+//! Opening an endpoint starts a stream, which behaves like a pipe: applications write PCM
+//! into a playback stream and read PCM from a capture stream, both wait while the buffer is
+//! full or empty.
+
+use {
+	std::{io, sync::{Arc, Mutex}, time::Duration},
+	hw::{hda::{Controller, Direction, Error, Format}, pcie::{Device, Msi}},
+	kernel::svi::Rd,
+	super::{pcie::{Driver, Match}, platform::Sys}
+};
+
+pub static DRIVER: Driver = Driver {
+	name:    "hda",
+	matches: &[Match::class(0x04, 0x03)],
+	probe
+};
+
+pub static CONTROLLERS: Mutex<Vec<Arc<Mutex<Hda>>>> = Mutex::new(Vec::new());
+
+/// The endpoints of all codecs, numbered in the order the codecs were found.
+pub static ENDPOINTS: Mutex<Vec<Endpoint>> = Mutex::new(Vec::new());
+
+pub struct Hda {
+	controller: Controller<Sys>,
+	msi:        Option<(Msi, Rd)>
+}
+
+// SAFETY: the registers and rings are only accessed with the lock held
+unsafe impl Send for Hda {}
+
+impl Drop for Hda {
+	fn drop(&mut self) {
+		if let (Some((msi, rd)), Some(mut cfg)) = (self.msi.take(), Sys::config()) {
+			msi.disable(&mut cfg);
+			Sys::free_vectors(&[rd]);
+		}
+	}
+}
+
+/// The output or input path of a codec.
+#[derive(Clone)]
+pub struct Endpoint {
+	pub name:      String,
+	pub codec:     usize,
+	pub direction: Direction,
+	controller:    Arc<Mutex<Hda>>
+}
+
+impl Endpoint {
+	/// Starts a stream in `format`, fails with `Error::Busy` while the endpoint is open.
+	pub fn open(&self, format: Format) -> Result<Pcm, Error> {
+		let mut hda = self.controller.lock().unwrap();
+		let descriptor = hda.controller.open(self.codec, self.direction, format)?;
+		let period = hda.controller.period(descriptor).unwrap_or(0);
+		Ok(Pcm {
+			controller: self.controller.clone(),
+			descriptor,
+			direction:  self.direction,
+			// waiting a quarter period keeps the buffer filled without spinning
+			wait:       Duration::from_micros((period * 250_000 / format.bytes_per_second()) as u64)
+		})
+	}
+}
+
+/// An open stream.
+pub struct Pcm {
+	controller: Arc<Mutex<Hda>>,
+	descriptor: usize,
+	pub direction: Direction,
+	wait:       Duration
+}
+
+impl Pcm {
+	/// Writes all of `buf` to a playback stream, waiting while the buffer is full. Trailing
+	/// bytes that don't make up a whole frame are left out.
+	pub fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+		let mut done = 0;
+		loop {
+			let written = self.controller.lock().unwrap().controller.write(self.descriptor, &buf[done..])?;
+			done += written;
+			if written == 0 {
+				match self.controller.lock().unwrap().controller.available(self.descriptor)? {
+					0 => std::thread::sleep(self.wait),
+					_ => return Ok(done)
+				}
+			}
+		}
+	}
+
+	/// Reads from a capture stream, waiting until at least a frame was captured.
+	pub fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+		loop {
+			match self.controller.lock().unwrap().controller.read(self.descriptor, buf)? {
+				0 if !buf.is_empty() => std::thread::sleep(self.wait),
+				n => return Ok(n)
+			}
+		}
+	}
+
+	/// Waits until everything written to a playback stream was played.
+	pub fn drain(&self) -> Result<(), Error> {
+		while self.controller.lock().unwrap().controller.queued(self.descriptor)? > 0 {
+			std::thread::sleep(self.wait);
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Pcm {
+	fn drop(&mut self) {
+		let _ = self.controller.lock().unwrap().controller.close(self.descriptor);
+	}
+}
+
+fn io_error(e: Error) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, format!("hda: {:?}", e))
+}
+
+impl io::Write for Pcm {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		Pcm::write(self, buf).map_err(io_error)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.drain().map_err(io_error)
+	}
+}
+
+impl io::Read for Pcm {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		Pcm::read(self, buf).map_err(io_error)
+	}
+}
+
+fn probe(device: &Device) -> bool {
+	match attach(device) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("hda: {}: {:?}", device.address, e);
+			false
+		}
+	}
+}
+
+fn attach(device: &Device) -> Result<(), Error> {
+	let regs = Sys::map_bar(device, 0).ok_or(Error::NoCodec)?;
+	// SAFETY: BAR0 holds the controller's registers
+	let controller = unsafe { Controller::new(regs, Sys)? };
+	// without MSI streams still work, `Pcm` polls the link position
+	let msi = Sys::alloc_msi(device);
+	println!("hda: {} {:?}", device.address, controller);
+
+	let hda = Arc::new(Mutex::new(Hda { controller, msi }));
+	let mut controllers = CONTROLLERS.lock().unwrap();
+	let mut number = controllers.iter().map(|h| h.lock().unwrap().controller.codecs().len()).sum::<usize>();
+	let mut endpoints = ENDPOINTS.lock().unwrap();
+	for (i, codec) in hda.lock().unwrap().controller.codecs().iter().enumerate() {
+		for (direction, path, suffix) in [(Direction::Output, &codec.output, 'p'), (Direction::Input, &codec.input, 'c')] {
+			if let Some(path) = path {
+				let name = format!("pcm{}{}", number, suffix);
+				println!("{}: codec {:08x} at {}, converter {} pin {}", name, codec.vendor_id, codec.address, path.converter(), path.pin());
+				endpoints.push(Endpoint { name, codec: i, direction, controller: hda.clone() });
+			}
+		}
+		number += 1;
+	}
+	controllers.push(hda);
+	Ok(())
+}
+
+/// Handles the interrupt of a controller, advancing the streams that completed a period.
+/// Unsolicited responses, e.g. from jacks being plugged in, are logged.
+pub fn interrupt(hda: &Arc<Mutex<Hda>>) {
+	let mut hda = hda.lock().unwrap();
+	hda.controller.interrupt();
+	for (codec, response) in hda.controller.unsolicited() {
+		println!("hda: unsolicited response {:#010x} from codec {}", response, codec);
+	}
+}
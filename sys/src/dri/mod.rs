@@ -2,7 +2,44 @@
 mod uefi;
 mod acpi;
 mod pcie;
+mod platform;
 mod virtio;
 mod ahci;
 mod nvme;
+mod hda;
 mod usb;
+mod disk;
+
+use {
+	hw::{acpi::MCFG, devtree::FdtHeader, pcie::{self as pci, Device, Ecam}},
+	platform::Sys
+};
+
+/// Binds the drivers to the PCIe functions of the segment groups listed in the MCFG and to
+/// the `virtio,mmio` devices of the device tree. The first segment group is the one
+/// `Sys::config` hands to drivers.
+pub fn start(mcfg: Option<&MCFG>, fdt: Option<&FdtHeader>) {
+	for entry in mcfg.into_iter().flatten() {
+		let (segment, start, end) = (entry.pci_segment, entry.start_bus_number, entry.end_bus_number);
+		let offset = (start as usize) << 20;
+		let size = (end as usize - start as usize + 1) << 20;
+		let Some(window) = Sys::map_physical(entry.address.as_ptr() as u64 + offset as u64, size) else {
+			println!("pcie: segment {}: failed to map the configuration space", segment);
+			continue;
+		};
+
+		// `Ecam` takes the address bus 0 would be mapped at
+		let base = window.wrapping_sub(offset);
+		// SAFETY: the buses of the entry are mapped
+		let mut ecam = unsafe { Ecam::from_mcfg(entry, base) };
+		Sys::set_ecam(&ecam, base);
+		// drivers are bound for the lifetime of the process
+		let devices: &'static [Device] = Box::leak(pci::enumerate(&mut ecam, segment, start..=end).into_boxed_slice());
+		let bound = pcie::bind(devices, pcie::DRIVERS).len();
+		println!("pcie: segment {}: {} functions, {} bound", segment, pci::DeviceIter::new(devices).count(), bound);
+	}
+
+	if let Some(fdt) = fdt {
+		println!("virtio: {} mmio devices bound", virtio::probe_mmio(fdt));
+	}
+}
@@ -0,0 +1,354 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Exposes the namespaces of NVMe controllers as disks.
+//!
+//! A controller gets an I/O queue pair per hart, each completing through its own MSI-X
+//! vector the kernel spreads over the harts. Every queue pair has its own lock, so
+//! requests on different queues don't wait for each other. A request goes to the queue
+//! of the hart it is issued on, or the next idle one, and sleeps until the interrupt
+//! handler of the queue reaped its completion. A queue pair with a command that timed out
+//! is retired, the controller may still write to its buffers until it is shut down. The
+//! namespaces are registered with the kernel's block layer as `nvme<controller>n<namespace>`.
+
+use {
+	std::{collections::BTreeMap, sync::{Arc, Condvar, Mutex, MutexGuard, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}},
+	hw::{block::{self, BlockDevice}, dma::{Region, PAGE_SIZE}, nvme::{Command, Completion, Controller, Error, IoQueue, Namespace, COMMAND_TIMEOUT_MS}, pcie::{Device, MsiX, MsiXTable}},
+	kernel::svi::Rd,
+	super::{pcie::{Driver, Match}, platform::Sys}
+};
+
+pub static DRIVER: Driver = Driver {
+	name:    "nvme",
+	matches: &[Match::class_if(0x01, 0x08, 0x02)],
+	probe
+};
+
+/// Controllers attached so far, numbers the disks.
+static CONTROLLERS: Mutex<usize> = Mutex::new(0);
+
+/// How long a request sleeps before it looks for its completion itself, in case the
+/// interrupt got lost.
+const REAP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An I/O queue pair with the buffer its requests are copied through, callers' memory
+/// isn't DMA memory.
+struct QueuePair {
+	/// `None` once handed back to the controller
+	io:      Mutex<Option<IoQueue<Sys>>>,
+	/// Held for a whole request, locked before `io`
+	bounce:  Mutex<Region>,
+	/// Completions reaped by the interrupt handler, by command id
+	done:    Mutex<BTreeMap<u16, Completion>>,
+	reaped:  Condvar,
+	/// Set once a command timed out, the queue and its buffers are no longer used
+	retired: AtomicBool
+}
+
+// SAFETY: the queue and the buffer are only accessed with their lock held
+unsafe impl Send for QueuePair {}
+unsafe impl Sync for QueuePair {}
+
+impl QueuePair {
+	/// Moves the posted completions to `done` and wakes up the waiting requests.
+	fn interrupt(&self) {
+		let mut io = self.io.lock().unwrap();
+		let Some(io) = io.as_mut() else { return };
+		let mut done = self.done.lock().unwrap();
+		while let Some(completion) = io.complete() {
+			done.insert(completion.command_id, completion);
+		}
+		self.reaped.notify_all();
+	}
+
+	fn submit(&self, f: impl FnOnce(&mut IoQueue<Sys>) -> Result<u16, Error>) -> Result<u16, Error> {
+		f(self.io.lock().unwrap().as_mut().ok_or(Error::InvalidArgument)?)
+	}
+
+	fn is_retired(&self) -> bool {
+		self.retired.load(Ordering::Acquire)
+	}
+
+	/// Sleeps until the interrupt handler reaped the completion of `cid`. Retires the queue
+	/// if the command times out, as the controller may still access the bounce buffer and
+	/// the PRP list.
+	fn wait(&self, cid: u16, opcode: u8) -> Result<u32, Error> {
+		let deadline = Instant::now() + Duration::from_millis(COMMAND_TIMEOUT_MS);
+		let mut done = self.done.lock().unwrap();
+		loop {
+			if let Some(c) = done.remove(&cid) {
+				return match c.code() {
+					0 => Ok(c.result),
+					status => Err(Error::Command { opcode, status })
+				};
+			} else if Instant::now() >= deadline {
+				self.retired.store(true, Ordering::Release);
+				println!("nvme: command {} (opcode {:#x}) timed out, retiring its queue", cid, opcode);
+				return Err(Error::Timeout);
+			}
+
+			let (guard, timeout) = self.reaped.wait_timeout(done, REAP_INTERVAL).unwrap();
+			done = guard;
+			if timeout.timed_out() {
+				drop(done);
+				self.interrupt();
+				done = self.done.lock().unwrap();
+			}
+		}
+	}
+}
+
+pub struct Nvme {
+	/// Only used for the admin queue, during attach and shutdown
+	controller: Mutex<Controller<Sys>>,
+	queues:     Vec<Arc<QueuePair>>,
+	msix:       MsiX,
+	_table:     MsiXTable,
+	/// Vector 0 is the admin queue's, then one for each I/O queue
+	vectors:    Vec<Rd>,
+	trim:       bool,
+	max_blocks: usize
+}
+
+// SAFETY: the registers and queues are only accessed with their lock held
+unsafe impl Send for Nvme {}
+unsafe impl Sync for Nvme {}
+
+impl Drop for Nvme {
+	fn drop(&mut self) {
+		let mut controller = self.controller.lock().unwrap();
+		controller.return_io_queues(self.queues.iter().filter_map(|q| q.io.lock().unwrap().take()).collect());
+		let stopped = controller.shutdown().is_ok();
+		if let Some(mut cfg) = Sys::config() {
+			self.msix.disable(&mut cfg);
+		}
+		Sys::free_vectors(&self.vectors);
+		// a controller that didn't shut down may still write to the buffers of retired queues
+		for queue in self.queues.iter().filter(|q| stopped || !q.is_retired()) {
+			let mut bounce = queue.bounce.lock().unwrap();
+			core::mem::replace(&mut *bounce, Region { virt: core::ptr::null_mut(), phys: 0, size: 0 }).free(&mut Sys);
+		}
+	}
+}
+
+/// A namespace, as a `BlockDevice` it issues requests on the queue of `hart`.
+#[derive(Clone)]
+pub struct Disk {
+	pub name:  String,
+	pub ns:    Namespace,
+	pub hart:  usize,
+	controller: Arc<Nvme>
+}
+
+impl Disk {
+	/// A handle to the same namespace that uses the queue of `hart`.
+	pub fn on_hart(&self, hart: usize) -> Self {
+		Self { hart, ..self.clone() }
+	}
+
+	/// The queue of `hart` if it is idle, otherwise the next idle one. Waits for the first
+	/// of them if all are busy, retired queues are skipped. Fails once all are retired.
+	fn acquire(&self, hart: usize) -> Result<(&QueuePair, MutexGuard<'_, Region>), Error> {
+		let queues = &self.controller.queues;
+		let first = hart % queues.len();
+		let usable = || (0..queues.len())
+			.map(|i| &*queues[(first + i) % queues.len()])
+			.filter(|q| !q.is_retired());
+		loop {
+			let (queue, bounce) = usable()
+				.find_map(|q| q.bounce.try_lock().ok().map(|bounce| (q, bounce)))
+				.or_else(|| usable().next().map(|q| (q, q.bounce.lock().unwrap())))
+				.ok_or(Error::Timeout)?;
+			// the queue may have been retired while waiting for it
+			if !queue.is_retired() {
+				return Ok((queue, bounce));
+			}
+		}
+	}
+
+	/// Reads whole blocks starting at `lba`, `hart` is the hart the request is issued on.
+	pub fn read(&self, hart: usize, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+		let (queue, bounce) = self.acquire(hart)?;
+		let mut lba = lba;
+
+		for chunk in buf.chunks_mut(bounce.size) {
+			// SAFETY: the bounce buffer is at least as large as the chunk
+			let data = unsafe { core::slice::from_raw_parts_mut(bounce.virt, chunk.len()) };
+			let cid = queue.submit(|io| io.submit_read(&self.ns, lba, data))?;
+			queue.wait(cid, Command::NVM_READ)?;
+			chunk.copy_from_slice(data);
+			lba += (chunk.len() / self.ns.block_size as usize) as u64;
+		}
+		Ok(())
+	}
+
+	/// Writes whole blocks starting at `lba`, `hart` is the hart the request is issued on.
+	pub fn write(&self, hart: usize, lba: u64, buf: &[u8]) -> Result<(), Error> {
+		let (queue, bounce) = self.acquire(hart)?;
+		let mut lba = lba;
+
+		for chunk in buf.chunks(bounce.size) {
+			// SAFETY: see `read`
+			let data = unsafe { core::slice::from_raw_parts_mut(bounce.virt, chunk.len()) };
+			data.copy_from_slice(chunk);
+			let cid = queue.submit(|io| io.submit_write(&self.ns, lba, data))?;
+			queue.wait(cid, Command::NVM_WRITE)?;
+			lba += (chunk.len() / self.ns.block_size as usize) as u64;
+		}
+		Ok(())
+	}
+
+	pub fn flush(&self, hart: usize) -> Result<(), Error> {
+		let (queue, _bounce) = self.acquire(hart)?;
+		let cid = queue.submit(|io| io.submit_flush(&self.ns))?;
+		queue.wait(cid, Command::NVM_FLUSH).map(|_| ())
+	}
+
+	/// Deallocates `(lba, blocks)` ranges.
+	pub fn trim(&self, hart: usize, ranges: &[(u64, u32)]) -> Result<(), Error> {
+		let (queue, _bounce) = self.acquire(hart)?;
+		for chunk in ranges.chunks(256) {
+			let cid = queue.submit(|io| io.submit_trim(&self.ns, chunk))?;
+			queue.wait(cid, Command::NVM_DATASET_MGMT)?;
+		}
+		Ok(())
+	}
+}
+
+impl BlockDevice for Disk {
+	fn block_size(&self) -> usize {
+		self.ns.block_size as usize
+	}
+
+	fn blocks(&self) -> u64 {
+		self.ns.blocks
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		Ok(Disk::read(self, self.hart, lba, buf)?)
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> {
+		Ok(Disk::write(self, self.hart, lba, buf)?)
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		Ok(Disk::flush(self, self.hart)?)
+	}
+
+	fn discard(&mut self, lba: u64, blocks: u64) -> block::Result<()> {
+		block::check_blocks(self, lba, blocks)?;
+		// discarding is a hint, controllers without deallocate ignore it
+		if blocks == 0 || !self.controller.trim {
+			return Ok(());
+		}
+		let ranges = (0..blocks).step_by(u32::MAX as usize)
+			.map(|i| (lba + i, (blocks - i).min(u32::MAX as u64) as u32))
+			.collect::<Vec<_>>();
+		Ok(self.trim(self.hart, &ranges)?)
+	}
+
+	fn max_blocks(&self) -> u64 {
+		(self.controller.max_blocks / self.ns.block_size as usize) as u64
+	}
+}
+
+fn probe(device: &Device) -> bool {
+	match attach(device) {
+		Ok(n) => n > 0,
+		Err(e) => {
+			println!("nvme: {}: {:?}", device.address, e);
+			false
+		}
+	}
+}
+
+/// Initializes the controller and registers its namespaces, returns how many.
+fn attach(device: &Device) -> Result<usize, Error> {
+	let regs = Sys::map_bar(device, 0).ok_or(Error::Unsupported)?;
+	// SAFETY: BAR0 holds the controller's registers
+	let mut controller = unsafe { Controller::new(regs, Sys)? };
+
+	// vector 0 would also serve the admin queue, which is polled instead
+	let (msix, table, vectors) = Sys::alloc_vectors(device, Sys::harts() + 1).ok_or(Error::Unsupported)?;
+	let queues = (1..vectors.len()).map(|v| Some(v as u16)).collect::<Vec<_>>();
+	let created = controller.create_io_queues(&queues);
+	let size = controller.max_transfer().min(128 * PAGE_SIZE);
+	let bounces = (0..*created.as_ref().unwrap_or(&0))
+		.map_while(|_| Region::alloc(&mut Sys, size, PAGE_SIZE))
+		.collect::<Vec<_>>();
+
+	let mut cfg = Sys::config().ok_or(Error::Unsupported)?;
+	let created = match created {
+		Ok(n) if n > 0 && bounces.len() == n => n,
+		created => {
+			msix.disable(&mut cfg);
+			Sys::free_vectors(&vectors);
+			for bounce in bounces {
+				bounce.free(&mut Sys);
+			}
+			return Err(created.err().unwrap_or(Error::NoMemory));
+		}
+	};
+
+	let mut vectors = vectors;
+	let free = vectors.split_off(created + 1);
+	Sys::free_vectors(&free);
+
+	let namespaces = controller.identify_namespaces()?;
+	println!("nvme: {} {:?}, {} I/O queues", device.address, controller, created);
+
+	let queues = controller.take_io_queues().into_iter().zip(bounces)
+		.map(|(io, bounce)| Arc::new(QueuePair {
+			io:      Mutex::new(Some(io)),
+			bounce:  Mutex::new(bounce),
+			done:    Mutex::new(BTreeMap::new()),
+			reaped:  Condvar::new(),
+			retired: AtomicBool::new(false)
+		}))
+		.collect::<Vec<_>>();
+	for (queue, &vector) in queues.iter().zip(&vectors[1..]) {
+		let queue = queue.clone();
+		Sys::on_interrupt(vector, move |_| queue.interrupt());
+	}
+	msix.unmask_all(&mut cfg);
+
+	let trim = controller.supports_trim();
+	let nvme = Arc::new(Nvme { controller: Mutex::new(controller), queues, msix, _table: table, vectors, trim, max_blocks: size });
+	let index = {
+		let mut count = CONTROLLERS.lock().unwrap();
+		*count += 1;
+		*count - 1
+	};
+
+	let mut registered = 0;
+	for ns in &namespaces {
+		let name = format!("nvme{}n{}", index, ns.id);
+		println!("{}: {} blocks of {} bytes", name, ns.blocks, ns.block_size);
+		let disk = Disk { name: name.clone(), ns: *ns, hart: 0, controller: nvme.clone() };
+		match crate::dri::disk::register(&name, Box::new(disk)) {
+			Ok(_)  => registered += 1,
+			Err(e) => println!("{}: failed to register: {}", name, e)
+		}
+	}
+	Ok(registered)
+}
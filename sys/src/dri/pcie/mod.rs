@@ -0,0 +1,122 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Binds drivers to the functions found by `hw::pcie::enumerate`.
+//!
+//! A driver lists the functions it supports as `Match`es. If several drivers match a
+//! function, the one with the most specific match is tried first, a driver matching
+//! the vendor and device id wins over one matching the class. If its `probe` fails,
+//! the next driver is tried.
+
+use hw::pcie::{Device, DeviceIter};
+
+/// Matches functions by id and class, `None` fields match anything.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Match {
+	pub vendor_id:  Option<u16>,
+	pub device_id:  Option<u16>,
+	pub class_code: Option<u8>,
+	pub subclass:   Option<u8>,
+	pub prog_if:    Option<u8>
+}
+
+impl Match {
+	pub const fn id(vendor_id: u16, device_id: u16) -> Self {
+		Self { vendor_id: Some(vendor_id), device_id: Some(device_id), class_code: None, subclass: None, prog_if: None }
+	}
+
+	pub const fn vendor(vendor_id: u16) -> Self {
+		Self { vendor_id: Some(vendor_id), device_id: None, class_code: None, subclass: None, prog_if: None }
+	}
+
+	pub const fn class(class_code: u8, subclass: u8) -> Self {
+		Self { vendor_id: None, device_id: None, class_code: Some(class_code), subclass: Some(subclass), prog_if: None }
+	}
+
+	pub const fn class_if(class_code: u8, subclass: u8, prog_if: u8) -> Self {
+		Self { vendor_id: None, device_id: None, class_code: Some(class_code), subclass: Some(subclass), prog_if: Some(prog_if) }
+	}
+
+	pub fn matches(&self, device: &Device) -> bool {
+		self.vendor_id.map_or(true, |v| v == device.vendor_id)
+			&& self.device_id.map_or(true, |v| v == device.device_id)
+			&& self.class_code.map_or(true, |v| v == device.class_code)
+			&& self.subclass.map_or(true, |v| v == device.subclass)
+			&& self.prog_if.map_or(true, |v| v == device.prog_if)
+	}
+
+	/// Higher is more specific, ids count more than the class.
+	fn specificity(&self) -> u32 {
+		self.vendor_id.map_or(0, |_| 8) + self.device_id.map_or(0, |_| 16)
+			+ self.class_code.map_or(0, |_| 1) + self.subclass.map_or(0, |_| 2) + self.prog_if.map_or(0, |_| 4)
+	}
+}
+
+pub struct Driver {
+	pub name:    &'static str,
+	pub matches: &'static [Match],
+	/// Initializes the function, returns false if the driver can't handle it after all
+	pub probe:   fn(&Device) -> bool
+}
+
+impl core::fmt::Debug for Driver {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Driver").field("name", &self.name).finish()
+	}
+}
+
+#[derive(Debug)]
+pub struct Binding<'a> {
+	pub device: &'a Device,
+	pub driver: &'static Driver
+}
+
+/// The drivers that are tried for each function.
+pub static DRIVERS: &[&Driver] = &[&super::nvme::DRIVER, &super::ahci::DRIVER, &super::hda::DRIVER,
+	&super::usb::DRIVER, &super::virtio::DRIVER];
+
+/// Tries to bind a driver to each function in the tree, functions no driver accepted
+/// are left out.
+pub fn bind<'a>(devices: &'a [Device], drivers: &[&'static Driver]) -> Vec<Binding<'a>> {
+	let mut bindings = Vec::new();
+
+	for device in DeviceIter::new(devices) {
+		let mut candidates = drivers.iter()
+			.filter_map(|driver| driver.matches.iter()
+				.filter(|m| m.matches(device))
+				.map(Match::specificity)
+				.max()
+				.map(|specificity| (specificity, *driver)))
+			.collect::<Vec<_>>();
+
+		// stable, so equally specific drivers are tried in table order
+		candidates.sort_by_key(|(specificity, _)| core::cmp::Reverse(*specificity));
+
+		if let Some((_, driver)) = candidates.into_iter().find(|(_, driver)| (driver.probe)(device)) {
+			println!("pcie: {} {:04x}:{:04x} ({}) bound to {}",
+				device.address, device.vendor_id, device.device_id, device.vendor_name, driver.name);
+			bindings.push(Binding { device, driver });
+		}
+	}
+
+	bindings
+}
@@ -0,0 +1,212 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Hardware resources the kernel grants drivers: DMA memory, register mappings and
+//! interrupt vectors.
+
+use {
+	std::{collections::BTreeMap, sync::{Arc, Mutex, OnceLock}},
+	hw::{dma::{Dma, PAGE_SIZE}, pcie::{BarKind, Device, Ecam, Msi, MsiMessage, MsiX, MsiXTable}},
+	kernel::svi::{Rd, RdOrPath, INVALID_RD, sys::*}
+};
+
+/// DMA regions by address, with the resource backing them and their size.
+static REGIONS: Mutex<BTreeMap<usize, (Rd, usize, u64)>> = Mutex::new(BTreeMap::new());
+
+/// Interrupt handlers by the descriptor of their vectors.
+static HANDLERS: Mutex<BTreeMap<Rd, Arc<dyn Fn(usize) + Send + Sync>>> = Mutex::new(BTreeMap::new());
+
+/// Base, segment and bus range of the configuration space mapping.
+static ECAM: OnceLock<(usize, u16, u8, u8)> = OnceLock::new();
+
+/// Handle to the platform, drivers allocate DMA memory through it.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Sys;
+
+impl Sys {
+	/// Sets the configuration space mapping `enumerate` used, once.
+	pub fn set_ecam(ecam: &Ecam, base: *mut u8) {
+		let _ = ECAM.set((base as usize, ecam.segment(), *ecam.buses().start(), *ecam.buses().end()));
+	}
+
+	pub fn config() -> Option<Ecam> {
+		let &(base, segment, start, end) = ECAM.get()?;
+		// SAFETY: the mapping is never removed
+		Some(unsafe { Ecam::new(base as *mut u8, segment, start, end) })
+	}
+
+	/// Maps the memory BAR `index` of a function.
+	pub fn map_bar(device: &Device, index: usize) -> Option<*mut u8> {
+		let bar = device.bars.get(index).copied().flatten().filter(|bar| bar.kind != BarKind::Io && bar.address != 0)?;
+		Self::map_physical(bar.address, bar.size as usize)
+	}
+
+	/// Maps device registers outside of PCI, as listed in the device tree.
+	pub fn map_physical(address: u64, size: usize) -> Option<*mut u8> {
+		sys_rd_mem_map(address as *mut u8, size, INVALID_RD, MEM_MAP_FLAG_PROT_READ
+			| MEM_MAP_FLAG_PROT_WRITE | MEM_MAP_FLAG_PHYSICAL | MEM_MAP_FLAG_ADDRESS_HINT).ok()
+	}
+
+	pub fn unmap(virt: *mut u8, size: usize) {
+		let _ = sys_rd_unmap(virt, size);
+	}
+
+	pub fn harts() -> usize {
+		std::thread::available_parallelism().map_or(1, |n| n.get())
+	}
+
+	/// Sets up MSI-X with up to `count` vectors, the kernel places each on the least used
+	/// hart. Returns the descriptors of the vectors in order, the function mask stays set
+	/// until `unmask_all` is called.
+	pub fn alloc_vectors(device: &Device, count: usize) -> Option<(MsiX, MsiXTable, Vec<Rd>)> {
+		let mut cfg = Self::config()?;
+		let msix = MsiX::new(&mut cfg, device)?;
+		let table = Self::map_bar(device, msix.table_bar as usize)?;
+		let pba = Self::map_bar(device, msix.pba_bar as usize)?;
+		// SAFETY: both BARs are mapped
+		let mut table = unsafe { msix.table(table, pba) };
+		msix.enable(&mut cfg);
+
+		let mut rds = Vec::new();
+		for i in 0..count.min(table.len()) {
+			let mut message = [MsiMessage::default()];
+			match sys_int_alloc(requester(device), &mut message, 0) {
+				Ok(rd) => rds.push(rd),
+				Err(_) => break
+			}
+			table.set(i as u16, message[0]);
+		}
+
+		match rds.is_empty() {
+			true => {
+				msix.disable(&mut cfg);
+				None
+			}
+			false => Some((msix, table, rds))
+		}
+	}
+
+	/// Sets up MSI with a single vector, for functions without MSI-X.
+	pub fn alloc_msi(device: &Device) -> Option<(Msi, Rd)> {
+		let mut cfg = Self::config()?;
+		let msi = Msi::new(device)?;
+		let mut message = [MsiMessage::default()];
+		let rd = sys_int_alloc(requester(device), &mut message, 0).ok()?;
+
+		match msi.enable(&mut cfg, message[0], 1) {
+			0 => {
+				let _ = sys_int_free(rd);
+				None
+			}
+			_ => Some((msi, rd))
+		}
+	}
+
+	pub fn free_vectors(rds: &[Rd]) {
+		for rd in rds {
+			let _ = sys_int_free(*rd);
+			HANDLERS.lock().unwrap().remove(rd);
+		}
+	}
+
+	/// Calls `handler` whenever a vector of `rd` fires, with the index of the vector in
+	/// its block. Replaces an earlier handler, `free_vectors` removes it. The first handler
+	/// of a block starts the thread that waits for its vectors.
+	pub fn on_interrupt(rd: Rd, handler: impl Fn(usize) + Send + Sync + 'static) {
+		if HANDLERS.lock().unwrap().insert(rd, Arc::new(handler)).is_none() {
+			std::thread::spawn(move || wait(rd));
+		}
+	}
+
+	/// Runs the handler of `rd` for a vector that fired, `index` is its index in the block.
+	/// Called by the thread waiting for the block, returns false if `rd` has no handler.
+	pub fn dispatch(rd: Rd, index: usize) -> bool {
+		// the handler runs without the lock, it may free vectors
+		let handler = HANDLERS.lock().unwrap().get(&rd).cloned();
+		handler.map(|handler| handler(index)).is_some()
+	}
+}
+
+/// Dispatches the vectors of `rd` as they fire, until they are freed.
+fn wait(rd: Rd) {
+	loop {
+		match sys_int_wait(rd, 0) {
+			Ok(fired) => for index in (0..usize::BITS as usize).filter(|i| fired & 1 << i != 0) {
+				Sys::dispatch(rd, index);
+			},
+			Err(ERR_INTERRUPTED) => continue,
+			Err(_) => break
+		}
+	}
+}
+
+/// The PCI requester id, interrupt controllers translate messages per requester.
+fn requester(device: &Device) -> u32 {
+	(device.address.bus as u32) << 8 | (device.address.device as u32) << 3 | device.address.function as u32
+}
+
+impl Dma for Sys {
+	fn alloc(&mut self, size: usize, align: usize) -> Option<(*mut u8, u64)> {
+		// mappings are page aligned
+		if align > PAGE_SIZE {
+			return None;
+		}
+
+		let rd = sys_rd_open(None, RD_OPEN_FLAG_READ | RD_OPEN_FLAG_WRITE, INVALID_RD).ok()?;
+		let virt = sys_rd_mem_map(core::ptr::null_mut(), size, rd, MEM_MAP_FLAG_PROT_READ | MEM_MAP_FLAG_PROT_WRITE
+			| MEM_MAP_FLAG_PHYSICAL_CONT | MEM_MAP_FLAG_INIT_POPULATE | MEM_MAP_FLAG_ADDRESS_HINT);
+		let phys = sys_get_attr(RdOrPath { rd }, RD_ATTR_PHYS_ADDR as usize, INVALID_RD, 0);
+
+		match (virt, phys) {
+			(Ok(virt), Ok(phys)) => {
+				REGIONS.lock().unwrap().insert(virt as usize, (rd, size, phys as u64));
+				Some((virt, phys as u64))
+			}
+			(virt, _) => {
+				if let Ok(virt) = virt {
+					let _ = sys_rd_unmap(virt, size);
+				}
+				let _ = sys_rd_close(rd);
+				None
+			}
+		}
+	}
+
+	fn free(&mut self, virt: *mut u8, size: usize) {
+		if let Some((rd, _, _)) = REGIONS.lock().unwrap().remove(&(virt as usize)) {
+			let _ = sys_rd_unmap(virt, size);
+			let _ = sys_rd_close(rd);
+		}
+	}
+
+	/// Only memory from `alloc` has a known physical address, other buffers read as zero.
+	fn phys(&mut self, virt: *const u8) -> u64 {
+		let virt = virt as usize;
+		REGIONS.lock().unwrap().range(..=virt).next_back()
+			.filter(|(start, (_, size, _))| virt < *start + *size)
+			.map_or(0, |(start, (_, _, phys))| phys + (virt - start) as u64)
+	}
+
+	fn stall(&mut self, us: u64) {
+		std::thread::sleep(std::time::Duration::from_micros(us));
+	}
+}
@@ -0,0 +1,311 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Binds the devices on xHCI root hub ports: boot protocol keyboards and mice feed the
+//! input service, Bulk-Only mass storage devices are registered with the kernel's block layer
+//! as `ub<letter>` until they are unplugged.
+//!
+//! Reports and hot-plug events are handled when the MSI of the controller fires,
+//! controllers without MSI are polled by a thread instead.
+
+use {
+	std::{sync::{Arc, Mutex}, time::Duration},
+	hw::{
+		block::{self, BlockDevice},
+		input::{self, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT},
+		pcie::{Device, Msi},
+		xhci::{Controller, Error, Event, hid::{self, Keyboard, MouseReport}, storage::{self, BulkOnly, Transport}}
+	},
+	kernel::svi::Rd,
+	super::{pcie::{Driver, Match}, platform::Sys}
+};
+
+pub static DRIVER: Driver = Driver {
+	name:    "usb",
+	matches: &[Match::class_if(0x0C, 0x03, 0x30)],
+	probe
+};
+
+/// How often controllers without MSI are checked for reports and hot-plug events, about
+/// the interval keyboards and mice ask for.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub static CONTROLLERS: Mutex<Vec<Arc<Mutex<Usb>>>> = Mutex::new(Vec::new());
+
+/// The names the mass storage devices were registered under, by controller and slot.
+static NAMES: Mutex<Vec<(Arc<Mutex<Usb>>, u8, String)>> = Mutex::new(Vec::new());
+
+pub struct Usb {
+	controller: Controller<Sys>,
+	msi:        Option<(Msi, Rd)>,
+	/// Keyboards by slot and endpoint, with the last report
+	keyboards:  Vec<(u8, u8, Keyboard)>,
+	/// Mice by slot and endpoint, with the buttons of the last report
+	mice:       Vec<(u8, u8, u8)>
+}
+
+// SAFETY: the registers and rings are only accessed with the lock held
+unsafe impl Send for Usb {}
+
+impl Drop for Usb {
+	fn drop(&mut self) {
+		if let (Some((msi, rd)), Some(mut cfg)) = (self.msi.take(), Sys::config()) {
+			msi.disable(&mut cfg);
+			Sys::free_vectors(&[rd]);
+		}
+	}
+}
+
+/// The bulk endpoints of a mass storage device, each transfer takes the controller's
+/// lock.
+pub struct Pipe {
+	controller: Arc<Mutex<Usb>>,
+	slot:       u8,
+	interface:  storage::Interface
+}
+
+impl Pipe {
+	fn with<T>(&mut self, f: impl FnOnce(&mut BulkOnly<Sys>) -> T) -> T {
+		let mut usb = self.controller.lock().unwrap();
+		f(&mut BulkOnly { ctrl: &mut usb.controller, slot: self.slot, interface: self.interface })
+	}
+}
+
+impl Transport for Pipe {
+	fn bulk_out(&mut self, data: &[u8]) -> Result<usize, Error> {
+		self.with(|t| t.bulk_out(data))
+	}
+
+	fn bulk_in(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		self.with(|t| t.bulk_in(buf))
+	}
+
+	fn clear_halt(&mut self, is_in: bool) -> Result<(), Error> {
+		self.with(|t| t.clear_halt(is_in))
+	}
+
+	fn reset(&mut self) -> Result<(), Error> {
+		self.with(|t| t.reset())
+	}
+
+	fn stall(&mut self, us: u64) {
+		std::thread::sleep(Duration::from_micros(us));
+	}
+}
+
+#[derive(Clone)]
+pub struct Disk {
+	pub name:   String,
+	pub slot:   u8,
+	block_size: usize,
+	blocks:     u64,
+	read_only:  bool,
+	controller: Arc<Mutex<Usb>>,
+	lun:        Arc<Mutex<storage::Disk<Pipe>>>
+}
+
+impl BlockDevice for Disk {
+	fn block_size(&self) -> usize {
+		self.block_size
+	}
+
+	fn blocks(&self) -> u64 {
+		self.blocks
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		self.lun.lock().unwrap().read(lba, buf)
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> {
+		self.lun.lock().unwrap().write(lba, buf)
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		self.lun.lock().unwrap().flush()
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	fn max_blocks(&self) -> u64 {
+		self.lun.lock().unwrap().max_blocks()
+	}
+}
+
+fn probe(device: &Device) -> bool {
+	match attach(device) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("usb: {}: {:?}", device.address, e);
+			false
+		}
+	}
+}
+
+fn attach(device: &Device) -> Result<(), Error> {
+	let regs = Sys::map_bar(device, 0).ok_or(Error::NoDevice)?;
+	// SAFETY: BAR0 holds the controller's registers
+	let controller = unsafe { Controller::new(regs, Sys)? };
+	let msi = Sys::alloc_msi(device);
+	println!("usb: {} {:?}", device.address, controller);
+
+	let slots = controller.devices().map(|d| d.slot).collect::<Vec<_>>();
+	let vector = msi.as_ref().map(|(_, rd)| *rd);
+	let usb = Arc::new(Mutex::new(Usb { controller, msi, keyboards: Vec::new(), mice: Vec::new() }));
+	let handler = usb.clone();
+	match vector {
+		Some(vector) => Sys::on_interrupt(vector, move |_| interrupt(&handler)),
+		None => {
+			std::thread::spawn(move || loop {
+				std::thread::sleep(POLL_INTERVAL);
+				interrupt(&handler);
+			});
+		}
+	}
+	for slot in slots {
+		add(&usb, slot);
+	}
+	CONTROLLERS.lock().unwrap().push(usb);
+	Ok(())
+}
+
+/// Binds the interfaces of a device the classes here support, others are left alone.
+fn add(usb: &Arc<Mutex<Usb>>, slot: u8) {
+	let storage = {
+		let mut guard = usb.lock().unwrap();
+		let u = &mut *guard;
+		let Some(device) = u.controller.device(slot) else { return };
+		println!("usb: slot {} port {}: {:04x}:{:04x}", slot, device.port, device.descriptor.vendor_id,
+			device.descriptor.product_id);
+		let (interfaces, storage) = (hid::find(device), storage::find(device));
+		for interface in &interfaces {
+			if let Err(e) = hid::start(&mut u.controller, slot, interface) {
+				println!("usb: slot {}: {:?} interface {}: {:?}", slot, interface.kind, interface.interface, e);
+				continue;
+			}
+			match interface.kind {
+				hid::Kind::Keyboard => u.keyboards.push((slot, interface.endpoint, Keyboard::new())),
+				hid::Kind::Mouse    => u.mice.push((slot, interface.endpoint, 0))
+			}
+			crate::input::attach();
+		}
+		storage
+	};
+
+	// the disk takes the controller's lock for every command
+	if let Some(interface) = storage {
+		let pipe = Pipe { controller: usb.clone(), slot, interface };
+		let lun = match storage::Disk::new(pipe, 0) {
+			Ok(lun) => lun,
+			Err(e) => {
+				println!("usb: slot {}: mass storage: {:?}", slot, e);
+				return;
+			}
+		};
+		let name = crate::dri::disk::name("ub");
+		println!("{}: {:?}", name, lun);
+		let disk = Disk {
+			name:       name.clone(),
+			slot,
+			block_size: lun.block_size(),
+			blocks:     lun.blocks(),
+			read_only:  lun.is_read_only(),
+			controller: usb.clone(),
+			lun:        Arc::new(Mutex::new(lun))
+		};
+		match crate::dri::disk::register(&name, Box::new(disk)) {
+			Ok(_)  => NAMES.lock().unwrap().push((usb.clone(), slot, name)),
+			Err(e) => println!("{}: failed to register: {}", name, e)
+		}
+	}
+}
+
+fn remove(usb: &Arc<Mutex<Usb>>, slot: u8) {
+	{
+		let mut u = usb.lock().unwrap();
+		u.keyboards.retain(|(s, ..)| *s != slot);
+		u.mice.retain(|(s, ..)| *s != slot);
+	}
+	let mut names = NAMES.lock().unwrap();
+	if let Some(i) = names.iter().position(|(u, s, _)| Arc::ptr_eq(u, usb) && *s == slot) {
+		let (_, _, name) = names.remove(i);
+		println!("{}: removed", name);
+		// the device is gone, flushing it fails
+		let _ = crate::dri::disk::unregister(&name);
+	}
+}
+
+/// Handles the interrupt of a controller: reports become events of the input service,
+/// devices that were plugged in or pulled out are bound or removed.
+pub fn interrupt(usb: &Arc<Mutex<Usb>>) {
+	let mut changes = Vec::new();
+	{
+		let mut guard = usb.lock().unwrap();
+		let u = &mut *guard;
+		for event in u.controller.interrupt() {
+			match event {
+				Event::Report { slot, endpoint, data } => {
+					if let Some((.., keyboard)) = u.keyboards.iter_mut().find(|(s, e, _)| (*s, *e) == (slot, endpoint)) {
+						for key in keyboard.report(&data) {
+							if let Some(code) = input::from_hid_usage(key.usage) {
+								crate::input::push(input::Event::Key { code, pressed: key.pressed, char: None, modifiers: 0 });
+							}
+						}
+					} else if let Some((.., buttons)) = u.mice.iter_mut().find(|(s, e, _)| (*s, *e) == (slot, endpoint)) {
+						if let Some(report) = MouseReport::parse(&data) {
+							mouse(buttons, report);
+						}
+					}
+				}
+				e => changes.push(e)
+			}
+		}
+	}
+
+	// binding a device runs commands that take the controller's lock
+	for change in changes {
+		match change {
+			Event::Attached(slot) => add(usb, slot),
+			Event::Detached { slot, .. } => remove(usb, slot),
+			Event::Report { .. } => ()
+		}
+	}
+}
+
+/// Passes on the changes of a mouse report, `buttons` are those of the last one.
+fn mouse(buttons: &mut u8, report: MouseReport) {
+	for (bit, button) in [(hid::BUTTON_LEFT, BTN_LEFT), (hid::BUTTON_RIGHT, BTN_RIGHT), (hid::BUTTON_MIDDLE, BTN_MIDDLE)] {
+		if (*buttons ^ report.buttons) & bit != 0 {
+			crate::input::push(input::Event::Button { button, pressed: report.buttons & bit != 0 });
+		}
+	}
+	*buttons = report.buttons;
+	if (report.x, report.y) != (0, 0) {
+		crate::input::push(input::Event::Motion { dx: report.x as i32, dy: report.y as i32 });
+	}
+	// the boot report counts wheel steps up like evdev does
+	if report.wheel != 0 {
+		crate::input::push(input::Event::Wheel { dx: 0, dy: report.wheel as i32 });
+	}
+}
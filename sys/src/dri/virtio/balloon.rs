@@ -0,0 +1,151 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Gives memory back to the host through the virtio balloon.
+//!
+//! A thread per device moves the balloon towards the target of the host, taking pages
+//! from and returning them to the kernel's allocator with `sys_mem_balloon`. Deflated
+//! pages are only returned after the host was told. It also answers requests for
+//! statistics and reports free blocks every `REPORT_INTERVAL`. The kernel doesn't tell
+//! when it runs out of memory, so the balloon isn't deflated beyond the target.
+
+use {
+	std::time::{Duration, Instant},
+	hw::virtio::{DeviceType, Error, memory_ballooning::{Balloon, PFN_SHIFT, PFNS_PER_REQUEST, MAX_STATS}},
+	kernel::svi::sys::*,
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-balloon",
+	ty:    DeviceType::MemoryBallooning,
+	probe
+};
+
+const POLL_INTERVAL:   Duration = Duration::from_secs(1);
+const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+struct Device {
+	balloon:    Balloon<Transport, Sys>,
+	/// The pages in the balloon
+	pages:      Vec<u32>,
+	_interrupt: Interrupt
+}
+
+// SAFETY: the transport and queues are only accessed by the thread of the device
+unsafe impl Send for Device {}
+
+impl Device {
+	/// Moves the balloon towards the target, as far as the allocator allows.
+	fn adjust(&mut self) -> Result<(), Error> {
+		loop {
+			let delta = self.balloon.delta();
+			if delta > 0 {
+				let mut pfns = [0; PFNS_PER_REQUEST];
+				let len = (delta as usize).min(PFNS_PER_REQUEST);
+				let n = sys_mem_balloon(BALLOON_OP_INFLATE, &mut pfns[..len], 0).map_err(|_| Error::Device)?;
+				if n == 0 {
+					return Ok(());
+				}
+				if let Err(e) = self.balloon.inflate(&pfns[..n]) {
+					let _ = sys_mem_balloon(BALLOON_OP_DEFLATE, &mut pfns[..n], 0);
+					return Err(e);
+				}
+				self.pages.extend_from_slice(&pfns[..n]);
+			} else if delta < 0 && !self.pages.is_empty() {
+				let n = ((-delta) as usize).min(PFNS_PER_REQUEST).min(self.pages.len());
+				let mut pfns = self.pages.split_off(self.pages.len() - n);
+				if let Err(e) = self.balloon.deflate(&pfns) {
+					self.pages.append(&mut pfns);
+					return Err(e);
+				}
+				sys_mem_balloon(BALLOON_OP_DEFLATE, &mut pfns, 0).map_err(|_| Error::Device)?;
+			} else {
+				return Ok(());
+			}
+		}
+	}
+
+	fn stats(&mut self) -> Result<(), Error> {
+		if !self.balloon.stats_requested() {
+			return Ok(());
+		}
+		let mut stats = [[0u64; 2]; MAX_STATS];
+		let n = sys_mem_balloon_stats(&mut stats).map_err(|_| Error::Device)?;
+		let stats = stats[..n].iter().map(|[tag, value]| (*tag as u16, *value)).collect::<Vec<_>>();
+		self.balloon.send_stats(&stats).map(|_| ())
+	}
+
+	/// Reports the free blocks the kernel didn't report yet.
+	fn report(&mut self) -> Result<(), Error> {
+		let capacity = self.balloon.report_capacity();
+		if capacity == 0 {
+			return Ok(());
+		}
+		let mut pfns = vec![0; capacity];
+		loop {
+			let n = sys_mem_balloon(BALLOON_OP_REPORT, &mut pfns, 0).map_err(|_| Error::Device)?;
+			let blocks = pfns[..n].iter()
+				.map(|pfn| ((*pfn as u64) << PFN_SHIFT, 1 << (BALLOON_REPORT_ORDER as u32 + PFN_SHIFT)))
+				.collect::<Vec<_>>();
+			// the blocks go back either way, they are only reported again once used
+			let result = self.balloon.report(&blocks);
+			sys_mem_balloon(BALLOON_OP_REPORTED, &mut pfns[..n], 0).map_err(|_| Error::Device)?;
+			result?;
+			if n < capacity {
+				return Ok(());
+			}
+		}
+	}
+}
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("virtio-balloon: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<(), Error> {
+	let balloon = Balloon::new(transport, Sys)?;
+	println!("virtio-balloon: {:?}", balloon);
+	let mut device = Device { balloon, pages: Vec::new(), _interrupt: interrupt };
+	std::thread::spawn(move || {
+		let mut last_report = Instant::now();
+		loop {
+			device.balloon.interrupt();
+			let mut result = device.adjust().and_then(|_| device.stats());
+			if last_report.elapsed() >= REPORT_INTERVAL {
+				result = result.and_then(|_| device.report());
+				last_report = Instant::now();
+			}
+			if let Err(e) = result {
+				println!("virtio-balloon: {:?}", e);
+			}
+			std::thread::sleep(POLL_INTERVAL);
+		}
+	});
+	Ok(())
+}
@@ -0,0 +1,186 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Exposes virtio block devices as disks.
+//!
+//! A device gets a queue per hart if it supports multiple queues, requests issued on a
+//! hart use its queue. Requests are polled, the interrupt is only held on to. The disks
+//! are registered with the kernel's block layer as `vda` to `vdz`, then `vdaa` and so on.
+
+use {
+	std::sync::{Arc, Mutex},
+	hw::{block::{self, BlockDevice}, dma::{Region, PAGE_SIZE}, virtio::{DeviceType, Error, block_device}},
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-blk",
+	ty:    DeviceType::BlockDevice,
+	probe
+};
+
+pub struct Device {
+	disk:       block_device::Disk<Transport, Sys>,
+	_interrupt: Interrupt,
+	/// Requests are copied through this buffer, callers' memory isn't DMA memory
+	bounce:     Region
+}
+
+// SAFETY: the transport and queues are only accessed with the lock held
+unsafe impl Send for Device {}
+
+impl Drop for Device {
+	fn drop(&mut self) {
+		core::mem::replace(&mut self.bounce, Region { virt: core::ptr::null_mut(), phys: 0, size: 0 }).free(&mut Sys);
+	}
+}
+
+/// A block device, as a `BlockDevice` it issues requests on the queue of `hart`.
+#[derive(Clone)]
+pub struct Disk {
+	pub name:   String,
+	pub hart:   usize,
+	block_size: usize,
+	blocks:     u64,
+	read_only:  bool,
+	device:     Arc<Mutex<Device>>
+}
+
+impl Disk {
+	/// A handle to the same device that uses the queue of `hart`.
+	pub fn on_hart(&self, hart: usize) -> Self {
+		Self { hart, ..self.clone() }
+	}
+
+	/// Reads whole blocks starting at `lba`, `hart` is the hart the request is issued on.
+	pub fn read(&self, hart: usize, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+		let mut device = self.device.lock().unwrap();
+		let device = &mut *device;
+		let queue = hart % device.disk.queues();
+		let mut lba = lba;
+
+		for chunk in buf.chunks_mut(device.bounce.size) {
+			// SAFETY: the bounce buffer is at least as large as the chunk
+			let bounce = unsafe { core::slice::from_raw_parts_mut(device.bounce.virt, chunk.len()) };
+			device.disk.read(queue, lba, bounce)?;
+			chunk.copy_from_slice(bounce);
+			lba += (chunk.len() / self.block_size) as u64;
+		}
+		Ok(())
+	}
+
+	/// Writes whole blocks starting at `lba`, `hart` is the hart the request is issued on.
+	pub fn write(&self, hart: usize, lba: u64, buf: &[u8]) -> Result<(), Error> {
+		let mut device = self.device.lock().unwrap();
+		let device = &mut *device;
+		let queue = hart % device.disk.queues();
+		let mut lba = lba;
+
+		for chunk in buf.chunks(device.bounce.size) {
+			// SAFETY: see `read`
+			let bounce = unsafe { core::slice::from_raw_parts_mut(device.bounce.virt, chunk.len()) };
+			bounce.copy_from_slice(chunk);
+			device.disk.write(queue, lba, bounce)?;
+			lba += (chunk.len() / self.block_size) as u64;
+		}
+		Ok(())
+	}
+
+	pub fn flush(&self, hart: usize) -> Result<(), Error> {
+		let mut device = self.device.lock().unwrap();
+		let queue = hart % device.disk.queues();
+		device.disk.flush(queue)
+	}
+
+	/// Zeroes blocks, `unmap` allows the device to deallocate them.
+	pub fn write_zeroes(&self, hart: usize, lba: u64, blocks: u64, unmap: bool) -> Result<(), Error> {
+		let mut device = self.device.lock().unwrap();
+		let queue = hart % device.disk.queues();
+		device.disk.write_zeroes(queue, lba, blocks, unmap)
+	}
+}
+
+impl BlockDevice for Disk {
+	fn block_size(&self) -> usize {
+		self.block_size
+	}
+
+	fn blocks(&self) -> u64 {
+		self.blocks
+	}
+
+	fn read(&mut self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		Ok(Disk::read(self, self.hart, lba, buf)?)
+	}
+
+	fn write(&mut self, lba: u64, buf: &[u8]) -> block::Result<()> {
+		block::check_range(self, lba, buf.len())?;
+		Ok(Disk::write(self, self.hart, lba, buf)?)
+	}
+
+	fn flush(&mut self) -> block::Result<()> {
+		Ok(Disk::flush(self, self.hart)?)
+	}
+
+	fn discard(&mut self, lba: u64, blocks: u64) -> block::Result<()> {
+		block::check_blocks(self, lba, blocks)?;
+		let mut device = self.device.lock().unwrap();
+		let queue = self.hart % device.disk.queues();
+		Ok(device.disk.discard(queue, lba, blocks)?)
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	fn max_blocks(&self) -> u64 {
+		(self.device.lock().unwrap().bounce.size / self.block_size) as u64
+	}
+}
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("virtio-blk: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<(), Error> {
+	let disk = block_device::Disk::new(transport, Sys, Sys::harts() as u16)?;
+	let bounce = Region::alloc(&mut Sys, disk.max_transfer().min(128 * PAGE_SIZE), PAGE_SIZE)
+		.ok_or(Error::NoMemory)?;
+
+	let name = crate::dri::disk::name("vd");
+	println!("{}: {:?}, {} blocks of {} bytes", name, disk, disk.blocks(), disk.block_size());
+
+	let (block_size, blocks, read_only) = (disk.block_size() as usize, disk.blocks(), disk.is_read_only());
+	let device = Arc::new(Mutex::new(Device { disk, _interrupt: interrupt, bounce }));
+	let disk = Disk { name: name.clone(), hart: 0, block_size, blocks, read_only, device };
+	crate::dri::disk::register(&name, Box::new(disk)).map_err(|e| {
+		println!("{}: failed to register: {}", name, e);
+		Error::InvalidArgument
+	})
+}
@@ -0,0 +1,207 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Exposes the ports of virtio consoles as pipes, port 0 of the first console is the
+//! system console.
+//!
+//! Ports are pipe resources under `/console`, opened by the name the host gave them or
+//! by `<device>/<port>`, e.g. `console/log` or `console/0/1`. Reads return what the
+//! host sent since the last read, both reads and writes return `RD_IO_ERR_WOULD_BLOCK`
+//! instead of waiting. Opening a port tells the host the guest side is open, closing
+//! the last resource of it that it's closed again.
+//!
+//! A thread polls the devices, received data waits for reads in the driver. The system
+//! log goes to port 0 of the first console with `write_console`, through the
+//! configuration if the queue is full or a panic holds the lock.
+
+use {
+	std::{collections::BTreeMap, sync::{Arc, Mutex, OnceLock}, time::Duration},
+	hw::virtio::{DeviceType, Error, console_device::{self, Console}},
+	kernel::svi::{Rd, ResourceType, sys::*},
+	crate::res::{self, Service},
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-console",
+	ty:    DeviceType::ConsoleDevice,
+	probe
+};
+
+static SERVICE: Service = Service { ty: ResourceType::Pipe, open, read, write, close, ops: None, poll: None };
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct Device {
+	console:    Console<Transport, Sys>,
+	_interrupt: Interrupt
+}
+
+// SAFETY: the transport and queues are only accessed with the lock held
+unsafe impl Send for Device {}
+
+/// The consoles, in the order they were attached.
+pub static CONSOLES: Mutex<Vec<Arc<Mutex<Device>>>> = Mutex::new(Vec::new());
+
+/// Open ports by resource, locked after `CONSOLES`.
+static PIPES: Mutex<BTreeMap<Rd, (usize, u32)>> = Mutex::new(BTreeMap::new());
+static NEXT_RD: Mutex<Rd> = Mutex::new(1);
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("virtio-console: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<(), Error> {
+	let console = Console::new(transport, Sys)?;
+	println!("virtio-console: {:?}", console);
+	CONSOLES.lock().unwrap().push(Arc::new(Mutex::new(Device { console, _interrupt: interrupt })));
+
+	static POLL: OnceLock<()> = OnceLock::new();
+	POLL.get_or_init(|| {
+		let hook = std::panic::take_hook();
+		std::panic::set_hook(Box::new(move |info| {
+			write_console(&format!("{}\n", info));
+			hook(info);
+		}));
+		res::register("console", &SERVICE);
+		std::thread::spawn(|| loop {
+			poll();
+			std::thread::sleep(POLL_INTERVAL);
+		});
+	});
+	Ok(())
+}
+
+/// Polls all consoles, reports the ports that came and went.
+pub fn poll() {
+	let consoles = CONSOLES.lock().unwrap().clone();
+	let mut removed = Vec::new();
+	for (i, device) in consoles.iter().enumerate() {
+		let mut device = device.lock().unwrap();
+		device.console.interrupt();
+		for event in device.console.poll() {
+			match event {
+				console_device::Event::Named(port) => println!("virtio-console: {}/{}: {:?}", i, port,
+					device.console.port(port).and_then(|p| p.name.as_deref())),
+				console_device::Event::Removed(port) => {
+					println!("virtio-console: {}/{}: removed", i, port);
+					removed.push((i, port));
+				}
+				_ => ()
+			}
+		}
+	}
+	PIPES.lock().unwrap().retain(|_, p| !removed.contains(p));
+}
+
+/// Writes to port 0 of the first console.
+pub fn write_console(s: &str) {
+	let Some(device) = CONSOLES.try_lock().ok().and_then(|c| c.first().cloned()) else { return };
+	// the lock is poisoned if its holder panicked, the device is still usable
+	let mut device = match device.try_lock() {
+		Ok(device) => device,
+		Err(std::sync::TryLockError::Poisoned(e)) => e.into_inner(),
+		Err(std::sync::TryLockError::WouldBlock) => return
+	};
+	if device.console.port(0).is_none() {
+		device.console.emergency_write(s.as_bytes());
+		return;
+	}
+	let written = device.console.write(0, s.as_bytes()).unwrap_or(0);
+	if written < s.len() {
+		device.console.emergency_write(&s.as_bytes()[written..]);
+	}
+}
+
+/// Opens a port, `path` is relative to `/console`.
+pub fn open(path: &str, _flags: usize) -> Result<Rd, usize> {
+	let path = path.trim_start_matches('/');
+	let path = path.strip_prefix("console/").unwrap_or(path);
+	let consoles = CONSOLES.lock().unwrap();
+
+	let found = match path.split_once('/') {
+		Some((device, port)) => {
+			let i = device.parse::<usize>().map_err(|_| ERR_INVALID_ARG)?;
+			let port = port.parse::<u32>().map_err(|_| ERR_INVALID_ARG)?;
+			consoles.get(i).filter(|d| d.lock().unwrap().console.port(port).is_some()).map(|_| (i, port))
+		}
+		None => consoles.iter().enumerate().find_map(|(i, d)| d.lock().unwrap().console.ports().iter()
+			.find(|p| p.name.as_deref() == Some(path))
+			.map(|p| (i, p.id)))
+	};
+	let (i, port) = found.ok_or(RD_OPEN_RESOURCE_NO_EXISTS)?;
+	// a device with a single port has no control queue to tell it
+	match consoles[i].lock().unwrap().console.set_open(port, true) {
+		Ok(()) | Err(Error::Unsupported) => (),
+		Err(_) => return Err(ERR_IO)
+	}
+
+	let mut next = NEXT_RD.lock().unwrap();
+	let rd = *next;
+	*next += 1;
+	PIPES.lock().unwrap().insert(rd, (i, port));
+	Ok(rd)
+}
+
+fn with<T>(rd: Rd, f: impl FnOnce(&mut Console<Transport, Sys>, u32) -> Result<T, Error>) -> Result<T, usize> {
+	let consoles = CONSOLES.lock().unwrap();
+	let (i, port) = *PIPES.lock().unwrap().get(&rd).ok_or(ERR_INVALID_ARG)?;
+	let mut device = consoles[i].lock().unwrap();
+	f(&mut device.console, port).map_err(|e| match e {
+		Error::InvalidArgument => ERR_INVALID_ARG,
+		_ => ERR_IO
+	})
+}
+
+pub fn read(rd: Rd, buf: &mut [u8]) -> Result<usize, usize> {
+	match with(rd, |console, port| console.read(port, buf))? {
+		0 if !buf.is_empty() => Err(RD_IO_ERR_WOULD_BLOCK),
+		n => Ok(n)
+	}
+}
+
+pub fn write(rd: Rd, buf: &[u8]) -> Result<usize, usize> {
+	match with(rd, |console, port| console.write(port, buf))? {
+		0 if !buf.is_empty() => Err(RD_IO_ERR_WOULD_BLOCK),
+		n => Ok(n)
+	}
+}
+
+pub fn close(rd: Rd) -> Result<(), usize> {
+	let consoles = CONSOLES.lock().unwrap();
+	let last = {
+		let mut pipes = PIPES.lock().unwrap();
+		let (i, port) = pipes.remove(&rd).ok_or(ERR_INVALID_ARG)?;
+		pipes.values().all(|p| *p != (i, port)).then_some((i, port))
+	};
+	if let Some((i, port)) = last {
+		// the port may have been removed in the meantime
+		let _ = consoles[i].lock().unwrap().console.set_open(port, false);
+	}
+	Ok(())
+}
@@ -0,0 +1,106 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Shows a text console on the first virtio GPU.
+//!
+//! The framebuffer gets the size of the preferred display, or 1024x768 if the device
+//! reports none. The system log draws its text with `write`, the changed area is
+//! flushed right away.
+
+use {
+	std::sync::Mutex,
+	hw::{console::{Console, Framebuffer}, virtio::{DeviceType, Error, gpu::Gpu}},
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-gpu",
+	ty:    DeviceType::Gpu,
+	probe
+};
+
+const DEFAULT_MODE: (u32, u32) = (1024, 768);
+
+/// The console on the scanout, the only one even if there are more GPUs.
+static SCREEN: Mutex<Option<Screen>> = Mutex::new(None);
+
+struct Screen {
+	/// Borrows the framebuffer of `gpu`, it is dropped first
+	console:    Console<'static>,
+	gpu:        Gpu<Transport, Sys>,
+	_interrupt: Interrupt
+}
+
+// SAFETY: the transport, queue and framebuffer are only accessed with the lock held
+unsafe impl Send for Screen {}
+
+/// Draws text on the screen, if there is one. Called by the log, so it must not print.
+pub fn write(s: &str) {
+	// the lock is held while the screen is set up, which prints itself
+	let Ok(mut screen) = SCREEN.try_lock() else { return };
+	let Some(screen) = screen.as_mut() else { return };
+	screen.console.write(s);
+	if let Some(rect) = screen.console.take_dirty() {
+		if let Err(e) = screen.gpu.flush(rect) {
+			eprintln!("virtio-gpu: flush failed: {:?}", e);
+		}
+	}
+}
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(bound) => bound,
+		Err(e) => {
+			println!("virtio-gpu: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<bool, Error> {
+	let mut screen = SCREEN.lock().unwrap();
+	if screen.is_some() {
+		return Ok(false);
+	}
+
+	let mut gpu = Gpu::new(transport, Sys)?;
+	let display = gpu.displays()?.into_iter().find(|d| d.enabled && d.rect.width != 0 && d.rect.height != 0);
+	let (scanout, width, height) = match display {
+		Some(d) => (d.scanout, d.rect.width, d.rect.height),
+		None    => (0, DEFAULT_MODE.0, DEFAULT_MODE.1)
+	};
+	gpu.set_mode(scanout, width, height)?;
+
+	let format = gpu.format();
+	let pixels = gpu.framebuffer().ok_or(Error::Device)?;
+	// SAFETY: the framebuffer stays allocated until `gpu` is dropped, after the console
+	let pixels = unsafe { core::slice::from_raw_parts_mut(pixels.as_mut_ptr(), pixels.len()) };
+	let fb = Framebuffer::new(pixels, width as usize, height as usize, width as usize, format).ok_or(Error::Device)?;
+	let mut console = Console::new(fb);
+	if let Some(rect) = console.take_dirty() {
+		gpu.flush(rect)?;
+	}
+
+	println!("virtio-gpu: {:?}, scanout {}", gpu, scanout);
+	*screen = Some(Screen { console, gpu, _interrupt: interrupt });
+	Ok(true)
+}
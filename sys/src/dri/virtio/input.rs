@@ -0,0 +1,71 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Passes the events of virtio keyboards, mice and tablets to the input service.
+//!
+//! Each device is polled by a thread of its own, the interrupt is only held on to.
+
+use {
+	std::time::Duration,
+	hw::virtio::{DeviceType, Error, input::Input},
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-input",
+	ty:    DeviceType::Input,
+	probe
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+struct Device {
+	input:      Input<Transport, Sys>,
+	_interrupt: Interrupt
+}
+
+// SAFETY: the transport and queue are only accessed by the thread polling the device
+unsafe impl Send for Device {}
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("virtio-input: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<(), Error> {
+	let input = Input::new(transport, Sys)?;
+	println!("virtio-input: {:?}", input);
+	crate::input::attach();
+	let mut device = Device { input, _interrupt: interrupt };
+	std::thread::spawn(move || loop {
+		for event in device.input.poll() {
+			crate::input::push(event);
+		}
+		std::thread::sleep(POLL_INTERVAL);
+	});
+	Ok(())
+}
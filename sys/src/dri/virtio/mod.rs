@@ -0,0 +1,271 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Finds virtio devices, PCI functions of the virtio vendor and the `virtio,mmio` nodes
+//! of the device tree, and hands their transport to the driver of the device type.
+//!
+//! The drivers in `DEVICE_DRIVERS` negotiate features, set up their queues and keep
+//! the transport. A PCI function gets MSI-X vectors, vector 0 signals configuration
+//! changes and the queues share the others, see `Pci::set_vectors`.
+
+pub mod balloon;
+pub mod block;
+pub mod console;
+pub mod gpu;
+pub mod input;
+pub mod net;
+pub mod p9;
+pub mod rng;
+pub mod vsock;
+
+use {
+	std::sync::Mutex,
+	hw::{
+		devtree::FdtHeader,
+		pcie::{Device, MsiX, MsiXTable},
+		virtio::{self, DeviceType, Error, mmio::{self, Mmio}, pci::{self, Capabilities, Pci}}
+	},
+	kernel::svi::Rd,
+	super::{pcie::{Driver, Match}, platform::Sys}
+};
+
+pub static DRIVER: Driver = Driver {
+	name:    "virtio",
+	matches: &[Match::vendor(pci::VENDOR_ID)],
+	probe
+};
+
+/// The driver of a device type.
+pub struct DeviceDriver {
+	pub name:  &'static str,
+	pub ty:    DeviceType,
+	/// Initializes the device, returns false if the driver can't handle it after all
+	pub probe: fn(Transport, Interrupt) -> bool
+}
+
+impl core::fmt::Debug for DeviceDriver {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("DeviceDriver").field("name", &self.name).field("ty", &self.ty).finish()
+	}
+}
+
+/// The drivers devices are dispatched to by their type.
+pub static DEVICE_DRIVERS: &[&DeviceDriver] = &[&block::DRIVER, &net::DRIVER, &gpu::DRIVER, &input::DRIVER, &console::DRIVER, &rng::DRIVER, &balloon::DRIVER, &vsock::DRIVER, &p9::DRIVER];
+
+/// Where each bound device is, and the name of its driver.
+pub static DEVICES: Mutex<Vec<(String, &'static str)>> = Mutex::new(Vec::new());
+
+/// The transport of a device, whichever way it is attached.
+#[derive(Debug)]
+pub enum Transport {
+	Mmio(Mmio),
+	Pci(Pci)
+}
+
+// SAFETY: the registers are only accessed by the driver owning the transport
+unsafe impl Send for Transport {}
+
+macro_rules! delegate {
+	($self:ident, $t:ident => $e:expr) => {
+		match $self {
+			Self::Mmio($t) => $e,
+			Self::Pci($t)  => $e
+		}
+	};
+}
+
+impl virtio::Transport for Transport {
+	fn device_id(&self) -> u32 {
+		delegate!(self, t => t.device_id())
+	}
+
+	fn status(&self) -> u8 {
+		delegate!(self, t => t.status())
+	}
+
+	fn set_status(&mut self, status: u8) {
+		delegate!(self, t => t.set_status(status))
+	}
+
+	fn device_features(&mut self) -> u64 {
+		delegate!(self, t => t.device_features())
+	}
+
+	fn set_driver_features(&mut self, features: u64) {
+		delegate!(self, t => t.set_driver_features(features))
+	}
+
+	fn max_queue_size(&mut self, queue: u16) -> u16 {
+		delegate!(self, t => t.max_queue_size(queue))
+	}
+
+	fn queue_enabled(&mut self, queue: u16) -> bool {
+		delegate!(self, t => t.queue_enabled(queue))
+	}
+
+	fn enable_queue(&mut self, queue: u16, size: u16, desc: u64, driver: u64, device: u64) {
+		delegate!(self, t => t.enable_queue(queue, size, desc, driver, device))
+	}
+
+	fn notify(&mut self, queue: u16) {
+		delegate!(self, t => t.notify(queue))
+	}
+
+	fn ack_interrupt(&mut self) -> u32 {
+		delegate!(self, t => t.ack_interrupt())
+	}
+
+	fn config_generation(&self) -> u32 {
+		delegate!(self, t => t.config_generation())
+	}
+
+	fn read_config(&self, offset: usize, width: usize) -> u32 {
+		delegate!(self, t => t.read_config(offset, width))
+	}
+
+	fn write_config(&mut self, offset: usize, width: usize, value: u32) {
+		delegate!(self, t => t.write_config(offset, width, value))
+	}
+}
+
+/// MSI-X vectors of a PCI function, disabled and freed when dropped.
+pub struct Vectors {
+	msix:    MsiX,
+	_table:  MsiXTable,
+	pub rds: Vec<Rd>
+}
+
+// SAFETY: the table is only written while the vectors are set up
+unsafe impl Send for Vectors {}
+
+impl Drop for Vectors {
+	fn drop(&mut self) {
+		if let Some(mut cfg) = Sys::config() {
+			self.msix.disable(&mut cfg);
+		}
+		Sys::free_vectors(&self.rds);
+	}
+}
+
+/// How a device signals interrupts.
+pub enum Interrupt {
+	Msix(Vectors),
+	/// The interrupt specifier of a device tree node, in the format of its interrupt
+	/// parent. The driver polls until wired interrupts are routed to drivers.
+	Line(Vec<u32>),
+	/// The driver polls
+	None
+}
+
+fn probe(device: &Device) -> bool {
+	match attach(device) {
+		Ok(bound) => bound,
+		Err(e) => {
+			println!("virtio: {}: {:?}", device.address, e);
+			false
+		}
+	}
+}
+
+fn attach(device: &Device) -> Result<bool, Error> {
+	let id = pci::device_id(device).ok_or(Error::NoDevice)?;
+	let mut cfg = Sys::config().ok_or(Error::NoDevice)?;
+	let caps = Capabilities::read(&mut cfg, device);
+
+	let mut bars = [core::ptr::null_mut(); 6];
+	for bar in caps.bars() {
+		bars[bar as usize] = Sys::map_bar(device, bar as usize).ok_or(Error::NoDevice)?;
+	}
+	// SAFETY: the BARs holding the structures are mapped
+	let mut transport = unsafe { Pci::new(device, &caps, &bars)? };
+
+	// one vector per hart for the queues, the device only needs as many as it has queues
+	let vectors = (1 + Sys::harts()).min(1 + transport.num_queues() as usize);
+	let interrupt = match Sys::alloc_vectors(device, vectors) {
+		Some((msix, table, rds)) => {
+			let vectors = Vectors { msix, _table: table, rds };
+			match transport.set_vectors(vectors.rds.len() as u16) {
+				true => {
+					vectors.msix.unmask_all(&mut cfg);
+					Interrupt::Msix(vectors)
+				}
+				false => {
+					transport.set_vectors(0);
+					Interrupt::None
+				}
+			}
+		}
+		None => Interrupt::None
+	};
+
+	Ok(dispatch(device.address.to_string(), id, Transport::Pci(transport), interrupt))
+}
+
+/// Binds drivers to the `virtio,mmio` nodes of the device tree, returns how many devices
+/// were bound. Slots without a device are left unmapped.
+pub fn probe_mmio(fdt: &FdtHeader) -> usize {
+	let mut bound = 0;
+
+	for node in mmio::find(fdt) {
+		let Some(base) = Sys::map_physical(node.base, node.size as usize) else {
+			println!("virtio: mmio@{:x}: failed to map registers", node.base);
+			continue;
+		};
+
+		// SAFETY: the node lists the registers
+		let transport = match unsafe { Mmio::new(base) } {
+			Ok(transport) => transport,
+			Err(e) => {
+				if e != Error::NoDevice {
+					println!("virtio: mmio@{:x}: {:?}", node.base, e);
+				}
+				Sys::unmap(base, node.size as usize);
+				continue;
+			}
+		};
+
+		let id = virtio::Transport::device_id(&transport);
+		if dispatch(format!("mmio@{:x}", node.base), id, Transport::Mmio(transport), Interrupt::Line(node.interrupts)) {
+			bound += 1;
+		}
+	}
+
+	bound
+}
+
+/// Hands a device to the driver of its type, a device without one is reset.
+fn dispatch(location: String, id: u32, mut transport: Transport, interrupt: Interrupt) -> bool {
+	let driver = DeviceType::from_id(id).and_then(|ty| DEVICE_DRIVERS.iter().find(|d| d.ty == ty));
+	match driver {
+		Some(driver) if (driver.probe)(transport, interrupt) => {
+			println!("virtio: {} ({:?}) bound to {}", location, driver.ty, driver.name);
+			DEVICES.lock().unwrap().push((location, driver.name));
+			true
+		}
+		Some(_) => false,
+		None => {
+			println!("virtio: {}: no driver for device type {}", location, id);
+			let _ = virtio::reset(&mut transport, &mut Sys);
+			false
+		}
+	}
+}
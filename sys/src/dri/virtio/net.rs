@@ -0,0 +1,89 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Hands virtio network devices to the network service as interfaces.
+//!
+//! A device gets a queue pair per hart if it supports multiple pairs. The service polls
+//! the interfaces, the interrupt is only held on to.
+
+use {
+	hw::{net, virtio::{DeviceType, Error, network_device::Net}},
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-net",
+	ty:    DeviceType::NetworkDevice,
+	probe
+};
+
+pub struct Device {
+	net:        Net<Transport, Sys>,
+	_interrupt: Interrupt
+}
+
+// SAFETY: the transport and queues are only accessed by the interface owning the device
+unsafe impl Send for Device {}
+
+impl net::Device for Device {
+	fn mac(&self) -> net::Mac {
+		self.net.mac()
+	}
+
+	fn mtu(&self) -> usize {
+		self.net.mtu()
+	}
+
+	fn link_up(&mut self) -> bool {
+		self.net.link_up()
+	}
+
+	fn checksum_offload(&self) -> bool {
+		net::Device::checksum_offload(&self.net)
+	}
+
+	fn transmit(&mut self, frame: &[u8], checksum: Option<net::Checksum>) -> net::Result<()> {
+		net::Device::transmit(&mut self.net, frame, checksum)
+	}
+
+	fn receive(&mut self) -> Option<net::Frame> {
+		self.net.receive_any()
+	}
+}
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("virtio-net: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<(), Error> {
+	let net = Net::new(transport, Sys, Sys::harts() as u16)?;
+	let debug = format!("{:?}", net);
+	let name = crate::net::add_interface(Box::new(Device { net, _interrupt: interrupt }));
+	println!("{}: {}", name, debug);
+	Ok(())
+}
@@ -0,0 +1,111 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Hands the directories the host shares over virtio 9P to the kernel.
+//!
+//! Each device gets a service channel for its mount tag, or `9p<n>` if it has none, and
+//! the kernel mounts the share at `/mnt/<tag>` when the channel is published. A thread
+//! passes the kernel's 9P messages to the device and its responses back.
+
+use {
+	std::{sync::atomic::{AtomicUsize, Ordering}, thread},
+	hw::virtio::{DeviceType, Error, _9p_transport::Virtio9p},
+	kernel::svi::{Rd, SrvRequest, sys::*},
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-9p",
+	ty:    DeviceType::_9pTransport,
+	probe
+};
+
+/// Devices without a mount tag seen so far
+static UNNAMED: AtomicUsize = AtomicUsize::new(0);
+
+struct Device {
+	p9:         Virtio9p<Transport, Sys>,
+	_interrupt: Interrupt
+}
+
+// SAFETY: the transport and queue are only accessed by the thread serving the share
+unsafe impl Send for Device {}
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("virtio-9p: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<(), Error> {
+	let p9 = Virtio9p::new(transport, Sys)?;
+	println!("virtio-9p: {:?}", p9);
+	let tag = match p9.tag() {
+		""  => format!("9p{}", UNNAMED.fetch_add(1, Ordering::Relaxed)),
+		tag => tag.into()
+	};
+
+	let rd = sys_srv_register(&format!("/mnt/{}", tag), SRV_FLAG_9P, 0, 0).map_err(|e| {
+		println!("virtio-9p: {}: failed to register: {}", tag, e);
+		Error::Unsupported
+	})?;
+	let device = Device { p9, _interrupt: interrupt };
+	if thread::Builder::new().name(format!("9p-{}", tag)).spawn(move || serve(rd, device)).is_err() {
+		let _ = sys_srv_unregister(rd);
+		return Err(Error::NoMemory);
+	}
+
+	// the kernel attaches to the share before this returns, so it is served first
+	sys_srv_publish(rd).map_err(|e| {
+		println!("virtio-9p: {}: failed to mount: {}", tag, e);
+		let _ = sys_srv_unregister(rd);
+		Error::Unsupported
+	})
+}
+
+/// Passes the messages of a channel to the device until it is unregistered, the device is
+/// reset when dropped.
+fn serve(rd: Rd, mut device: Device) {
+	let mut buf = vec![0u8; SRV_MAX_LEN];
+	let mut response = vec![0u8; SRV_MAX_LEN];
+	let mut req = SrvRequest::default();
+	loop {
+		let len = match sys_srv_receive(rd, &mut req, &mut buf, 0) {
+			Ok(len) => len,
+			Err(ERR_INTERRUPTED) => continue,
+			Err(_) => break
+		};
+
+		let result = match req.op {
+			SRV_OP_TRANSACT => device.p9.transfer(&buf[..len], &mut response[..req.len.min(SRV_MAX_LEN)]).map_err(|_| ERR_IO),
+			_               => Err(ERR_NOT_IMPLEMENTED)
+		};
+
+		let _ = match result {
+			Ok(n)  => sys_srv_reply(rd, req.id, n as isize, &response[..n]),
+			Err(e) => sys_srv_reply(rd, req.id, -(e as isize), &[])
+		};
+	}
+}
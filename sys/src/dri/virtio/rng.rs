@@ -0,0 +1,96 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Feeds the kernel's entropy pool from virtio entropy devices.
+//!
+//! The bytes are written to `/dev/random` and then credited with `RD_ATTR_RANDOM_ENTROPY`,
+//! the host's generator is trusted in full. Each device seeds the pool once attached
+//! and adds to it again every `RESEED_INTERVAL` from a thread of its own.
+
+use {
+	std::time::Duration,
+	hw::virtio::{DeviceType, Error, entropy_device::Rng},
+	kernel::svi::{Rd, RdOrPath, IoWriteBuf, INVALID_RD, sys::*},
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-rng",
+	ty:    DeviceType::EntropyDevice,
+	probe
+};
+
+/// Null-terminated for `sys_rd_open`
+const PATH: &str = "/dev/random\0";
+/// Bytes added at a time, enough to seed the pool
+const SEED_LEN: usize = hw::random::SEED_BITS / 8;
+const RESEED_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Device {
+	rng:        Rng<Transport, Sys>,
+	pool:       Rd,
+	_interrupt: Interrupt
+}
+
+// SAFETY: the transport and queue are only accessed by the thread of the device
+unsafe impl Send for Device {}
+
+impl Device {
+	/// Reads `SEED_LEN` bytes from the device and hands them to the kernel.
+	fn feed(&mut self) -> Result<(), Error> {
+		let mut buf = [0; SEED_LEN];
+		self.rng.fill(&mut buf)?;
+		let result = sys_rd_write(RdOrPath { rd: self.pool }, 0, IoWriteBuf { buf: Some(&buf) }, 0, 0)
+			.and_then(|_| sys_set_attr(RdOrPath { rd: self.pool }, RD_ATTR_RANDOM_ENTROPY as usize, 8 * SEED_LEN, INVALID_RD, 0));
+		buf.fill(0);
+		result.map_err(|_| Error::Device)
+	}
+}
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("virtio-rng: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<(), Error> {
+	let rng = Rng::new(transport, Sys)?;
+	let pool = sys_rd_open(Some(PATH), RD_OPEN_FLAG_WRITE, INVALID_RD).map_err(|_| Error::Unsupported)?;
+	println!("virtio-rng: {:?}", rng);
+
+	let mut device = Device { rng, pool, _interrupt: interrupt };
+	if let Err(e) = device.feed() {
+		let _ = sys_rd_close(pool);
+		return Err(e);
+	}
+	std::thread::spawn(move || loop {
+		std::thread::sleep(RESEED_INTERVAL);
+		if let Err(e) = device.feed() {
+			println!("virtio-rng: {:?}", e);
+		}
+	});
+	Ok(())
+}
@@ -0,0 +1,82 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Hands the virtio socket device to the vsock service.
+//!
+//! The service polls the device, the interrupt is only held on to.
+
+use {
+	hw::virtio::{self, DeviceType, Error, socket::{self, Header, Packet, Vsock}},
+	super::{DeviceDriver, Interrupt, Transport, super::platform::Sys}
+};
+
+pub static DRIVER: DeviceDriver = DeviceDriver {
+	name:  "virtio-vsock",
+	ty:    DeviceType::Socket,
+	probe
+};
+
+pub struct Device {
+	vsock:      Vsock<Transport, Sys>,
+	_interrupt: Interrupt
+}
+
+// SAFETY: the transport and queues are only accessed by the service owning the device
+unsafe impl Send for Device {}
+
+impl socket::Device for Device {
+	fn guest_cid(&self) -> u64 {
+		self.vsock.guest_cid()
+	}
+
+	fn transmit(&mut self, header: &Header, data: &[u8]) -> Result<(), virtio::Error> {
+		self.vsock.transmit(header, data)
+	}
+
+	fn receive(&mut self) -> Option<Packet> {
+		self.vsock.receive()
+	}
+
+	fn reset(&mut self) -> bool {
+		socket::Device::reset(&mut self.vsock)
+	}
+}
+
+fn probe(transport: Transport, interrupt: Interrupt) -> bool {
+	match attach(transport, interrupt) {
+		Ok(()) => true,
+		Err(e) => {
+			println!("virtio-vsock: {:?}", e);
+			false
+		}
+	}
+}
+
+fn attach(transport: Transport, interrupt: Interrupt) -> Result<(), Error> {
+	let vsock = Vsock::new(transport, Sys)?;
+	println!("virtio-vsock: {:?}", vsock);
+	match crate::vsock::add_device(Box::new(Device { vsock, _interrupt: interrupt })) {
+		true  => Ok(()),
+		// the device is reset when dropped
+		false => Err(Error::Unsupported)
+	}
+}
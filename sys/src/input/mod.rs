@@ -0,0 +1,140 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The input service: key and pointer events of all keyboards, mice and tablets.
+//!
+//! Drivers call `attach` for each device and pass events in with `push`, keys go through
+//! one `Keyboard` shared by all devices, which adds the characters of the current layout.
+//! The service is registered with `res` once the first device is attached. Resources
+//! under `/input`:
+//!
+//! - `events` reads events as records of `input::RECORD_LEN` bytes, as many whole
+//!   records as fit into the buffer. Every open resource gets all events from the time
+//!   it was opened, reads return `RD_IO_ERR_WOULD_BLOCK` while there are none.
+//! - `layout` reads the name of the layout, writing `us` or `de` changes it.
+
+use {
+	std::{collections::{BTreeMap, VecDeque}, sync::{Mutex, OnceLock}},
+	hw::input::{Event, Keyboard, Layout, RECORD_LEN},
+	kernel::svi::{Rd, ResourceType, sys::*},
+	crate::res::{self, Service}
+};
+
+/// Events queued per reader, older ones are dropped beyond that.
+const QUEUE_LEN: usize = 256;
+
+static SERVICE: Service = Service { ty: ResourceType::Special, open, read, write, close, ops: None, poll: None };
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new(Layout::Us));
+
+/// Open resources by descriptor, readers of `events` have a queue.
+static RESOURCES: Mutex<BTreeMap<Rd, Resource>> = Mutex::new(BTreeMap::new());
+static NEXT_RD: Mutex<Rd> = Mutex::new(1);
+
+enum Resource {
+	Events(VecDeque<Event>),
+	Layout
+}
+
+/// Registers the service when the first device is attached.
+pub fn attach() {
+	static REGISTERED: OnceLock<()> = OnceLock::new();
+	REGISTERED.get_or_init(|| {
+		res::register("input", &SERVICE);
+	});
+}
+
+/// Hands an event to all readers, key events get their character and the modifiers.
+pub fn push(event: Event) {
+	let event = match event {
+		Event::Key { code, pressed, .. } => KEYBOARD.lock().unwrap().key(code, pressed),
+		e => e
+	};
+	for resource in RESOURCES.lock().unwrap().values_mut() {
+		if let Resource::Events(queue) = resource {
+			if queue.len() == QUEUE_LEN {
+				queue.pop_front();
+			}
+			queue.push_back(event);
+		}
+	}
+}
+
+pub fn layout() -> Layout {
+	KEYBOARD.lock().unwrap().layout()
+}
+
+pub fn set_layout(layout: Layout) {
+	KEYBOARD.lock().unwrap().set_layout(layout);
+}
+
+/// Opens a resource, `path` is relative to `/input`.
+pub fn open(path: &str, _flags: usize) -> Result<Rd, usize> {
+	let path = path.trim_start_matches('/');
+	let resource = match path.strip_prefix("input/").unwrap_or(path) {
+		"events" => Resource::Events(VecDeque::new()),
+		"layout" => Resource::Layout,
+		_ => return Err(RD_OPEN_RESOURCE_NO_EXISTS)
+	};
+
+	let mut next = NEXT_RD.lock().unwrap();
+	let rd = *next;
+	*next += 1;
+	RESOURCES.lock().unwrap().insert(rd, resource);
+	Ok(rd)
+}
+
+pub fn read(rd: Rd, buf: &mut [u8]) -> Result<usize, usize> {
+	let mut resources = RESOURCES.lock().unwrap();
+	match resources.get_mut(&rd).ok_or(ERR_INVALID_ARG)? {
+		Resource::Events(_) if buf.len() < RECORD_LEN => Err(ERR_INVALID_ARG),
+		Resource::Events(queue) if queue.is_empty() => Err(RD_IO_ERR_WOULD_BLOCK),
+		Resource::Events(queue) => {
+			let n = queue.len().min(buf.len() / RECORD_LEN);
+			for (record, event) in buf.chunks_exact_mut(RECORD_LEN).zip(queue.drain(..n)) {
+				record.copy_from_slice(&event.to_bytes());
+			}
+			Ok(n * RECORD_LEN)
+		}
+		Resource::Layout => {
+			let name = layout().name().as_bytes();
+			let n = name.len().min(buf.len());
+			buf[..n].copy_from_slice(&name[..n]);
+			Ok(n)
+		}
+	}
+}
+
+pub fn write(rd: Rd, buf: &[u8]) -> Result<usize, usize> {
+	match RESOURCES.lock().unwrap().get(&rd).ok_or(ERR_INVALID_ARG)? {
+		Resource::Events(_) => Err(ERR_INVALID_ARG),
+		Resource::Layout => {
+			let name = std::str::from_utf8(buf).map_err(|_| ERR_INVALID_ARG)?;
+			set_layout(Layout::from_name(name.trim()).ok_or(ERR_INVALID_ARG)?);
+			Ok(buf.len())
+		}
+	}
+}
+
+pub fn close(rd: Rd) -> Result<(), usize> {
+	RESOURCES.lock().unwrap().remove(&rd).map(|_| ()).ok_or(ERR_INVALID_ARG)
+}
@@ -0,0 +1,51 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The system log: everything the system service prints.
+//!
+//! `print!` and `println!` are replaced for the whole crate and go to `write`, which
+//! passes the text on to standard output and to the consoles of the drivers, the text
+//! console on the virtio GPU and port 0 of the virtio console. A console must not print
+//! from its own `write`, only `eprint!` still goes to standard output alone.
+
+use std::{io::Write, sync::Mutex};
+
+/// Keeps the lines of concurrent writers apart.
+static LOCK: Mutex<()> = Mutex::new(());
+
+macro_rules! print {
+	($($arg:tt)*) => ({ $crate::log::write(&format!($($arg)*)) });
+}
+
+macro_rules! println {
+	() => ({ $crate::log::write("\n") });
+	($($arg:tt)*) => ({ $crate::log::write(&format!("{}\n", format_args!($($arg)*))) });
+}
+
+/// Writes to all outputs of the log.
+pub fn write(s: &str) {
+	// a writer that panicked didn't leave anything half-done behind the lock
+	let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let _ = std::io::stdout().write_all(s.as_bytes());
+	crate::dri::virtio::gpu::write(s);
+	crate::dri::virtio::console::write_console(s);
+}
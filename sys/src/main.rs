@@ -1,12 +1,25 @@
 
+#[macro_use]
+mod log;
 mod auth;
 mod keys;
 mod net;
+mod vsock;
+mod input;
 mod ctx;
-mod log;
+mod res;
 mod dri;
 mod loader;
 
-fn main(ctx: *mut (), arg1: *mut (), arg2: *mut ()) {
-    println!("Hello, world!");
+/// The kernel maps the firmware tables it booted with into the process and passes them
+/// as `mcfg`, the ACPI MCFG, and `fdt`, the flattened device tree. Either may be null.
+fn main(_ctx: *mut (), mcfg: *mut (), fdt: *mut ()) {
+	// SAFETY: the tables stay mapped
+	let (mcfg, fdt) = unsafe { ((mcfg as *const hw::acpi::MCFG).as_ref(), (fdt as *const hw::devtree::FdtHeader).as_ref()) };
+	dri::start(mcfg, fdt);
+
+	// the drivers and services run on their own threads
+	loop {
+		std::thread::park();
+	}
 }
@@ -0,0 +1,263 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The network service: the interfaces of all network devices and the sockets
+//! applications opened on them.
+//!
+//! Sockets are resources under `/net`, opened by path:
+//!
+//! - `tcp/<host>:<port>` connects, writes return `RD_IO_ERR_WOULD_BLOCK` until the
+//!   connection is established.
+//! - `tcp/*:<port>` with `RD_OPEN_CREATE` listens, opening it without accepts a pending
+//!   connection or fails with `ERR_NOT_READY`.
+//! - `udp/<host>:<port>` is connected to the host, `udp/*:<port>` is bound to the port
+//!   and writes go to whoever sent the last datagram read.
+//! - `icmp/<host>` writes echo requests, reads return the data of the replies.
+//!
+//! Hosts are IPv4 addresses or bracketed IPv6 addresses. Reads and writes don't block.
+//! A thread polls the interfaces, interfaces configure IPv4 with DHCP. The service is
+//! registered with `res` once the first interface is added.
+
+use {
+	std::{collections::BTreeMap, sync::{Mutex, OnceLock}, time::{Duration, Instant}},
+	hw::net::{self, Device, Endpoint, Handle, Interface, IpAddr},
+	kernel::svi::{Rd, ResourceType, sys::*},
+	crate::res::{self, Service}
+};
+
+/// How often the interfaces are polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const BACKLOG: usize = 16;
+
+static SERVICE: Service = Service { ty: ResourceType::Special, open, read, write, close, ops: None, poll: None };
+
+pub type BoxedDevice = Box<dyn Device + Send>;
+
+pub struct Iface {
+	pub name:      String,
+	pub interface: Interface<BoxedDevice>
+}
+
+/// The interfaces, named `eth<n>` in the order their devices were attached.
+pub static INTERFACES: Mutex<Vec<Iface>> = Mutex::new(Vec::new());
+
+/// Sockets by resource, locked after `INTERFACES`.
+static SOCKETS: Mutex<BTreeMap<Rd, Socket>> = Mutex::new(BTreeMap::new());
+static NEXT_RD: Mutex<Rd> = Mutex::new(1);
+static START: OnceLock<Instant> = OnceLock::new();
+
+#[derive(Copy, Clone, Debug)]
+enum Kind {
+	Tcp,
+	Listener,
+	/// The sender of the last datagram read, writes of a bound socket go there
+	Udp(Option<Endpoint>),
+	Icmp
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Socket {
+	iface:  usize,
+	handle: Handle,
+	kind:   Kind
+}
+
+/// Milliseconds since the service started.
+fn now() -> u64 {
+	START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Adds an interface for a device and starts DHCP on it, returns its name.
+pub fn add_interface(device: BoxedDevice) -> String {
+	let mut interfaces = INTERFACES.lock().unwrap();
+	let name = format!("eth{}", interfaces.len());
+	let mut interface = Interface::new(device, now());
+	interface.start_dhcp();
+	interfaces.push(Iface { name: name.clone(), interface });
+
+	static POLL: OnceLock<()> = OnceLock::new();
+	POLL.get_or_init(|| {
+		res::register("net", &SERVICE);
+		std::thread::spawn(|| loop {
+			poll();
+			std::thread::sleep(POLL_INTERVAL);
+		});
+	});
+	name
+}
+
+/// Polls all interfaces, reports address changes.
+pub fn poll() {
+	let now = now();
+	for iface in INTERFACES.lock().unwrap().iter_mut() {
+		let before = iface.interface.ipv4();
+		iface.interface.poll(now);
+		match iface.interface.ipv4() {
+			after if after == before => (),
+			Some(c) => println!("net: {}: {}/{}, gateway {:?}", iface.name, IpAddr::V4(c.addr), c.prefix, c.gateway.map(IpAddr::V4)),
+			None => println!("net: {}: address released", iface.name)
+		}
+	}
+}
+
+/// The error code of a stack error, also used by the vsock service.
+pub fn error(e: net::Error) -> usize {
+	match e {
+		net::Error::WouldBlock      => RD_IO_ERR_WOULD_BLOCK,
+		net::Error::InvalidArgument => ERR_INVALID_ARG,
+		net::Error::AddressInUse    => RD_OPEN_RESOURCE_EXISTS,
+		net::Error::NoMemory        => ERR_OUT_OF_KERNEL_MEMORY,
+		_                           => ERR_IO
+	}
+}
+
+/// The interface to reach `addr` through, the first one with an address of its family.
+fn route(interfaces: &[Iface], addr: IpAddr) -> Result<usize, usize> {
+	interfaces.iter()
+		.position(|i| match addr {
+			IpAddr::V4(_) => i.interface.ipv4().is_some(),
+			IpAddr::V6(_) => true
+		})
+		.ok_or(ERR_NOT_READY)
+}
+
+/// `<host>:<port>` or `*:<port>`, the latter is the unspecified IPv4 address.
+fn endpoint(s: &str) -> Option<Endpoint> {
+	match s.strip_prefix("*:") {
+		Some(port) => Some(Endpoint::new(IpAddr::UNSPECIFIED_V4, port.parse().ok()?)),
+		None => Endpoint::parse(s)
+	}
+}
+
+fn insert(socket: Socket) -> Rd {
+	let mut next = NEXT_RD.lock().unwrap();
+	let rd = *next;
+	*next += 1;
+	SOCKETS.lock().unwrap().insert(rd, socket);
+	rd
+}
+
+/// Opens a socket, `path` is relative to `/net`.
+pub fn open(path: &str, flags: usize) -> Result<Rd, usize> {
+	let path = path.trim_start_matches('/');
+	let path = path.strip_prefix("net/").unwrap_or(path);
+	let (protocol, target) = path.split_once('/').ok_or(ERR_INVALID_ARG)?;
+	let mut interfaces = INTERFACES.lock().unwrap();
+
+	let socket = match protocol {
+		"tcp" => {
+			let ep = endpoint(target).ok_or(ERR_INVALID_ARG)?;
+			match ep.addr.is_unspecified() {
+				true if flags & RD_OPEN_CREATE != 0 => {
+					let iface = interfaces.first_mut().ok_or(ERR_NOT_READY)?;
+					let handle = iface.interface.tcp_listen(ep, BACKLOG).map_err(error)?;
+					Socket { iface: 0, handle, kind: Kind::Listener }
+				}
+				true => {
+					let sockets = SOCKETS.lock().unwrap();
+					let listener = sockets.values()
+						.find(|s| matches!(s.kind, Kind::Listener)
+							&& interfaces[s.iface].interface.endpoints(s.handle).map_or(false, |(l, _)| l.port == ep.port))
+						.copied()
+						.ok_or(RD_OPEN_RESOURCE_NO_EXISTS)?;
+					drop(sockets);
+					let handle = match interfaces[listener.iface].interface.tcp_accept(listener.handle) {
+						Err(net::Error::WouldBlock) => return Err(ERR_NOT_READY),
+						r => r.map_err(error)?
+					};
+					Socket { iface: listener.iface, handle, kind: Kind::Tcp }
+				}
+				false => {
+					let iface = route(&interfaces, ep.addr)?;
+					let handle = interfaces[iface].interface.tcp_connect(ep).map_err(error)?;
+					Socket { iface, handle, kind: Kind::Tcp }
+				}
+			}
+		}
+		"udp" => {
+			let ep = endpoint(target).ok_or(ERR_INVALID_ARG)?;
+			let (iface, local, remote) = match ep.addr.is_unspecified() {
+				true  => (0, ep, None),
+				false => (route(&interfaces, ep.addr)?, Endpoint::new(IpAddr::UNSPECIFIED_V4, 0), Some(ep))
+			};
+			let handle = interfaces.get_mut(iface).ok_or(ERR_NOT_READY)?.interface.udp_bind(local, remote).map_err(error)?;
+			Socket { iface, handle, kind: Kind::Udp(None) }
+		}
+		"icmp" => {
+			let addr = IpAddr::parse(target.trim_start_matches('[').trim_end_matches(']')).ok_or(ERR_INVALID_ARG)?;
+			let iface = route(&interfaces, addr)?;
+			let handle = interfaces[iface].interface.icmp_open(addr).map_err(error)?;
+			Socket { iface, handle, kind: Kind::Icmp }
+		}
+		_ => return Err(RD_OPEN_RESOURCE_NO_EXISTS)
+	};
+	Ok(insert(socket))
+}
+
+/// Reads received data: a part of the stream, a datagram or the data of an echo reply.
+/// Datagrams that don't fit into `buf` are truncated, reading from a TCP connection
+/// the peer closed returns zero.
+pub fn read(rd: Rd, buf: &mut [u8]) -> Result<usize, usize> {
+	let mut interfaces = INTERFACES.lock().unwrap();
+	let mut sockets = SOCKETS.lock().unwrap();
+	let socket = sockets.get_mut(&rd).ok_or(ERR_INVALID_ARG)?;
+	let interface = &mut interfaces[socket.iface].interface;
+
+	let data = match &mut socket.kind {
+		Kind::Tcp => return interface.tcp_recv(socket.handle, buf).map_err(error),
+		Kind::Listener => return Err(ERR_INVALID_ARG),
+		Kind::Udp(last) => {
+			let (src, data) = interface.udp_recv(socket.handle).map_err(error)?;
+			*last = Some(src);
+			data
+		}
+		Kind::Icmp => interface.icmp_recv(socket.handle).map_err(error)?.1
+	};
+	let n = data.len().min(buf.len());
+	buf[..n].copy_from_slice(&data[..n]);
+	Ok(n)
+}
+
+/// Sends data: queues it on a TCP connection, as one datagram or as an echo request.
+pub fn write(rd: Rd, buf: &[u8]) -> Result<usize, usize> {
+	let mut interfaces = INTERFACES.lock().unwrap();
+	let sockets = SOCKETS.lock().unwrap();
+	let socket = sockets.get(&rd).ok_or(ERR_INVALID_ARG)?;
+	let interface = &mut interfaces[socket.iface].interface;
+
+	match socket.kind {
+		Kind::Tcp => match interface.tcp_state(socket.handle).map_err(error)? {
+			net::tcp::State::SynSent | net::tcp::State::SynReceived => Err(RD_IO_ERR_WOULD_BLOCK),
+			_ => interface.tcp_send(socket.handle, buf).map_err(error)
+		},
+		Kind::Listener => Err(ERR_INVALID_ARG),
+		Kind::Udp(last) => interface.udp_send(socket.handle, last, buf).map(|_| buf.len()).map_err(error),
+		Kind::Icmp => interface.icmp_send(socket.handle, buf).map(|_| buf.len()).map_err(error)
+	}
+}
+
+/// Closes a socket, TCP connections are shut down gracefully in the background.
+pub fn close(rd: Rd) -> Result<(), usize> {
+	let mut interfaces = INTERFACES.lock().unwrap();
+	let socket = SOCKETS.lock().unwrap().remove(&rd).ok_or(ERR_INVALID_ARG)?;
+	interfaces[socket.iface].interface.close(socket.handle).map_err(error)
+}
@@ -0,0 +1,224 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Dispatches the resources of the system service by path.
+//!
+//! Services register under the first component of their paths, e.g. `console` for
+//! `/console/log`, with their resource type. `open` finds the service of a path and
+//! hands out an own `Rd` for the one of the service, `read`, `write` and `close` pass
+//! it on to the service that opened it.
+//!
+//! Each service is published to the kernel at `/<name>`, so other tasks reach it with
+//! `sys_rd_open`. `WORKERS` threads per service take the kernel's requests and answer them
+//! through the same functions, the `Rd`s handed out here are the handles of the requests.
+//!
+//! Reads and writes other tasks queue with `sys_rd_ops` are passed on to the `ops` of
+//! services that have them, a thread polls their results and answers the requests.
+
+use {
+	std::{collections::BTreeMap, sync::{Mutex, OnceLock}, thread, time::Duration},
+	kernel::svi::{IoBufOrLen, IoOp, IoOpId, Rd, RdOrPath, ResourceType, SrvRequest, sys::*}
+};
+
+/// Threads answering the kernel's requests to a service, one may block in a read
+const WORKERS: usize = 4;
+/// How often the results of queued operations are polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct Service {
+	pub ty:    ResourceType,
+	pub open:  fn(&str, usize) -> Result<Rd, usize>,
+	pub read:  fn(Rd, &mut [u8]) -> Result<usize, usize>,
+	pub write: fn(Rd, &[u8]) -> Result<usize, usize>,
+	pub close: fn(Rd) -> Result<(), usize>,
+	/// Queues a batch of reads and writes like `sys_rd_ops`
+	pub ops:   Option<fn(&[IoOp]) -> Result<(), usize>>,
+	/// Takes the result of a queued operation like `sys_rd_poll`, `RD_IO_ERR_WOULD_BLOCK`
+	/// until it completed
+	pub poll:  Option<fn(IoOpId, &mut [u8]) -> Result<usize, usize>>
+}
+
+/// An operation queued with a service's `ops`, until its request is answered.
+struct Queued {
+	channel: Rd,
+	request: usize,
+	poll:    fn(IoOpId, &mut [u8]) -> Result<usize, usize>,
+	id:      IoOpId,
+	/// The most bytes to read, 0 for writes
+	len:     usize
+}
+
+static SERVICES: Mutex<BTreeMap<&'static str, &'static Service>> = Mutex::new(BTreeMap::new());
+
+/// Open resources, with the service and its `Rd`.
+static OPEN: Mutex<BTreeMap<Rd, (&'static Service, Rd)>> = Mutex::new(BTreeMap::new());
+static NEXT_RD: Mutex<Rd> = Mutex::new(1);
+static QUEUED: Mutex<Vec<Queued>> = Mutex::new(Vec::new());
+static NEXT_OP: Mutex<IoOpId> = Mutex::new(1);
+
+/// Registers a service for the paths under `/<name>` and publishes it to the kernel, a
+/// second one for a name is refused.
+pub fn register(name: &'static str, service: &'static Service) -> bool {
+	{
+		let mut services = SERVICES.lock().unwrap();
+		if services.contains_key(name) {
+			return false;
+		}
+		services.insert(name, service);
+	}
+
+	if let Err(e) = publish(name) {
+		println!("res: /{}: failed to publish: {}", name, e);
+	}
+	true
+}
+
+fn publish(name: &'static str) -> Result<(), usize> {
+	let rd = sys_srv_register(&format!("/{}", name), 0, 0, 0)?;
+	for i in 0..WORKERS {
+		let result = thread::Builder::new().name(format!("{}{}", name, i)).spawn(move || serve(name, rd));
+		if result.is_err() {
+			let _ = sys_srv_unregister(rd);
+			return Err(ERR_OUT_OF_KERNEL_MEMORY);
+		}
+	}
+	sys_srv_publish(rd)
+}
+
+/// Answers the requests of a service's channel until it is unregistered.
+fn serve(name: &str, rd: Rd) {
+	let mut buf = vec![0u8; SRV_MAX_LEN];
+	let mut req = SrvRequest::default();
+	loop {
+		let len = match sys_srv_receive(rd, &mut req, &mut buf, 0) {
+			Ok(len) => len,
+			Err(ERR_INTERRUPTED) => continue,
+			Err(_) => break
+		};
+
+		let result = match req.op {
+			SRV_OP_READ | SRV_OP_WRITE if req.flags & SRV_OP_FLAG_ASYNC != 0 => match queue(rd, &req, &buf[..len]) {
+				// answered by `complete`
+				Ok(()) => continue,
+				Err(e) => Err(e)
+			},
+			SRV_OP_OPEN  => std::str::from_utf8(&buf[..len]).map_err(|_| ERR_INVALID_ARG)
+				.and_then(|path| open(&format!("/{}{}", name, path), req.flags)),
+			SRV_OP_READ  => read(req.handle, &mut buf[..req.len.min(SRV_MAX_LEN)]),
+			SRV_OP_WRITE => write(req.handle, &buf[..len]),
+			SRV_OP_SYNC  => get(req.handle).map(|_| 0),
+			SRV_OP_CLOSE => close(req.handle).map(|_| 0),
+			_            => Err(ERR_NOT_IMPLEMENTED)
+		};
+
+		let _ = match result {
+			Ok(n) if req.op == SRV_OP_READ => sys_srv_reply(rd, req.id, n as isize, &buf[..n]),
+			Ok(n)  => sys_srv_reply(rd, req.id, n as isize, &[]),
+			Err(e) => sys_srv_reply(rd, req.id, -(e as isize), &[])
+		};
+	}
+}
+
+/// Passes an operation of `sys_rd_ops` on to the service of its resource.
+fn queue(channel: Rd, req: &SrvRequest, data: &[u8]) -> Result<(), usize> {
+	let (service, inner) = get(req.handle)?;
+	let (ops, poll) = match (service.ops, service.poll) {
+		(Some(ops), Some(poll)) => (ops, poll),
+		_ => return Err(ERR_NOT_IMPLEMENTED)
+	};
+	let id = {
+		let mut next = NEXT_OP.lock().unwrap();
+		*next += 1;
+		*next
+	};
+	let write = req.op == SRV_OP_WRITE;
+	let op = IoOp {
+		rd:    RdOrPath { rd: inner },
+		id,
+		flags: match write { true => RD_IO_OP_WRITE, false => 0 },
+		buf:   match write { true => IoBufOrLen { buf: Some(data) }, false => IoBufOrLen { len: req.len } },
+		off:   req.offset as usize
+	};
+	ops(&[op])?;
+
+	let len = match write { true => 0, false => req.len.min(SRV_MAX_LEN) };
+	QUEUED.lock().unwrap().push(Queued { channel, request: req.id, poll, id, len });
+	static POLL: OnceLock<()> = OnceLock::new();
+	POLL.get_or_init(|| {
+		thread::spawn(|| loop {
+			complete();
+			thread::sleep(POLL_INTERVAL);
+		});
+	});
+	Ok(())
+}
+
+/// Answers the requests of the queued operations that completed.
+fn complete() {
+	let mut buf = vec![0u8; SRV_MAX_LEN];
+	QUEUED.lock().unwrap().retain(|op| {
+		let buf = &mut buf[..op.len];
+		let _ = match (op.poll)(op.id, buf) {
+			Err(RD_IO_ERR_WOULD_BLOCK) => return true,
+			Ok(n)  => sys_srv_reply(op.channel, op.request, n as isize, &buf[..n.min(op.len)]),
+			Err(e) => sys_srv_reply(op.channel, op.request, -(e as isize), &[])
+		};
+		false
+	});
+}
+
+/// Opens a resource of the service the path is under.
+pub fn open(path: &str, flags: usize) -> Result<Rd, usize> {
+	let path = path.trim_start_matches('/');
+	let name = path.split('/').next().unwrap_or(path);
+	let service = *SERVICES.lock().unwrap().get(name).ok_or(RD_OPEN_RESOURCE_NO_EXISTS)?;
+	let inner = (service.open)(path, flags)?;
+
+	let mut next = NEXT_RD.lock().unwrap();
+	let rd = *next;
+	*next += 1;
+	OPEN.lock().unwrap().insert(rd, (service, inner));
+	Ok(rd)
+}
+
+fn get(rd: Rd) -> Result<(&'static Service, Rd), usize> {
+	OPEN.lock().unwrap().get(&rd).copied().ok_or(ERR_INVALID_ARG)
+}
+
+pub fn ty(rd: Rd) -> Result<ResourceType, usize> {
+	get(rd).map(|(service, _)| service.ty)
+}
+
+pub fn read(rd: Rd, buf: &mut [u8]) -> Result<usize, usize> {
+	let (service, inner) = get(rd)?;
+	(service.read)(inner, buf)
+}
+
+pub fn write(rd: Rd, buf: &[u8]) -> Result<usize, usize> {
+	let (service, inner) = get(rd)?;
+	(service.write)(inner, buf)
+}
+
+pub fn close(rd: Rd) -> Result<(), usize> {
+	let (service, inner) = OPEN.lock().unwrap().remove(&rd).ok_or(ERR_INVALID_ARG)?;
+	(service.close)(inner)
+}
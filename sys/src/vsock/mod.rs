@@ -0,0 +1,271 @@
+// MIT License
+//
+// Copyright (c) 2019-2023 Tobias Pfeiffer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The vsock service: stream sockets between the guest and the host over a virtio
+//! socket device, to talk to the host without configuring a network.
+//!
+//! Sockets are resources under `/vsock`, opened by path:
+//!
+//! - `<cid>:<port>` connects to a port of a context, the host is `2`. Reads and writes
+//!   return `RD_IO_ERR_WOULD_BLOCK` until the connection is established.
+//! - `*:<port>` with `RD_OPEN_CREATE` listens, opening it without accepts a pending
+//!   connection or fails with `ERR_NOT_READY`.
+//!
+//! Reads and writes don't block, reading from a connection the host shut down returns
+//! zero. `ops` queues a batch of them like `sys_rd_ops`: the operations of a socket
+//! complete in order as the device is polled, writes once all of their data was sent,
+//! and `poll` takes the results like `sys_rd_poll`. Only the first device is used, a
+//! thread polls it.
+//!
+//! The service is published through `res` once the device is added, so other tasks open
+//! sockets at `/vsock/<cid>:<port>` and queue operations on them with `sys_rd_ops`.
+
+use {
+	std::{collections::BTreeMap, sync::{Mutex, OnceLock}, time::{Duration, Instant}},
+	hw::{net::Error, virtio::socket::{self, Handle, State, Streams}},
+	kernel::svi::{IoOp, IoOpId, Rd, ResourceType, sys::*},
+	crate::{net::error, res::{self, Service}}
+};
+
+/// How often the device is polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const BACKLOG: usize = 16;
+/// Largest read of an operation
+const MAX_READ: usize = socket::BUF_ALLOC as usize;
+
+pub type BoxedDevice = Box<dyn socket::Device + Send>;
+
+static SERVICE: Service = Service { ty: ResourceType::Special, open, read, write, close, ops: Some(ops), poll: Some(poll) };
+
+static STREAMS: Mutex<Option<Streams<BoxedDevice>>> = Mutex::new(None);
+/// Sockets by resource, locked after `STREAMS`.
+static SOCKETS: Mutex<BTreeMap<Rd, Handle>> = Mutex::new(BTreeMap::new());
+/// Operations queued by `ops` in the order they were queued, locked after `SOCKETS`.
+static OPS: Mutex<Vec<Op>> = Mutex::new(Vec::new());
+static NEXT_RD: Mutex<Rd> = Mutex::new(1);
+static START: OnceLock<Instant> = OnceLock::new();
+
+struct Op {
+	id:     IoOpId,
+	rd:     Rd,
+	write:  bool,
+	/// The data to write, or what was read
+	data:   Vec<u8>,
+	/// Bytes written so far, or the most to read
+	len:    usize,
+	result: Option<Result<usize, usize>>
+}
+
+/// Milliseconds since the service started.
+fn now() -> u64 {
+	START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Uses a device for the sockets, returns false if there already is one.
+pub fn add_device(device: BoxedDevice) -> bool {
+	let mut streams = STREAMS.lock().unwrap();
+	if streams.is_some() {
+		return false;
+	}
+	*streams = Some(Streams::new(device, now()));
+
+	static POLL: OnceLock<()> = OnceLock::new();
+	POLL.get_or_init(|| {
+		res::register("vsock", &SERVICE);
+		std::thread::spawn(|| loop {
+			poll_device();
+			std::thread::sleep(POLL_INTERVAL);
+		});
+	});
+	true
+}
+
+/// Polls the device and makes progress on the queued operations.
+fn poll_device() {
+	let mut streams = STREAMS.lock().unwrap();
+	let Some(streams) = streams.as_mut() else { return };
+	streams.poll(now());
+	progress(streams);
+}
+
+/// Continues the first pending operation of each socket, and those after it that
+/// complete.
+fn progress(streams: &mut Streams<BoxedDevice>) {
+	let sockets = SOCKETS.lock().unwrap();
+	let mut ops = OPS.lock().unwrap();
+	let mut blocked = Vec::new();
+	for op in ops.iter_mut().filter(|op| op.result.is_none()) {
+		if blocked.contains(&op.rd) {
+			continue;
+		}
+		let Some(&handle) = sockets.get(&op.rd) else {
+			// closed in the meantime
+			op.result = Some(Err(ERR_INVALID_ARG));
+			continue;
+		};
+		let result = match op.write {
+			true => match streams.send(handle, &op.data[op.len..]) {
+				Ok(n) => {
+					op.len += n;
+					(op.len == op.data.len()).then_some(Ok(op.len))
+				}
+				Err(Error::WouldBlock) => None,
+				Err(e) => Some(Err(error(e)))
+			},
+			false if !streams.can_recv(handle).unwrap_or(true) => None,
+			false => {
+				let mut buf = vec![0; op.len];
+				match streams.recv(handle, &mut buf) {
+					Ok(n) => {
+						buf.truncate(n);
+						op.data = buf;
+						Some(Ok(n))
+					}
+					Err(Error::WouldBlock) => None,
+					Err(e) => Some(Err(error(e)))
+				}
+			}
+		};
+		match result {
+			Some(result) => op.result = Some(result),
+			None => blocked.push(op.rd)
+		}
+	}
+}
+
+fn insert(handle: Handle) -> Rd {
+	let mut next = NEXT_RD.lock().unwrap();
+	let rd = *next;
+	*next += 1;
+	SOCKETS.lock().unwrap().insert(rd, handle);
+	rd
+}
+
+/// Opens a socket, `path` is relative to `/vsock`.
+pub fn open(path: &str, flags: usize) -> Result<Rd, usize> {
+	let path = path.trim_start_matches('/');
+	let path = path.strip_prefix("vsock/").unwrap_or(path);
+	let (cid, port) = path.split_once(':').ok_or(ERR_INVALID_ARG)?;
+	let port = port.parse::<u32>().map_err(|_| ERR_INVALID_ARG)?;
+	let mut streams = STREAMS.lock().unwrap();
+	let streams = streams.as_mut().ok_or(ERR_NOT_READY)?;
+
+	let handle = match cid {
+		"*" if flags & RD_OPEN_CREATE != 0 => streams.listen(port, BACKLOG).map_err(error)?,
+		"*" => {
+			let sockets = SOCKETS.lock().unwrap();
+			let listener = sockets.values()
+				.copied()
+				.find(|&h| streams.state(h) == Ok(State::Listen) && streams.endpoints(h).map_or(false, |(p, _)| p == port))
+				.ok_or(RD_OPEN_RESOURCE_NO_EXISTS)?;
+			match streams.accept(listener) {
+				Err(Error::WouldBlock) => return Err(ERR_NOT_READY),
+				r => r.map_err(error)?
+			}
+		}
+		cid => streams.connect(cid.parse().map_err(|_| ERR_INVALID_ARG)?, port).map_err(error)?
+	};
+	Ok(insert(handle))
+}
+
+fn with<T>(rd: Rd, f: impl FnOnce(&mut Streams<BoxedDevice>, Handle) -> hw::net::Result<T>) -> Result<T, usize> {
+	let mut streams = STREAMS.lock().unwrap();
+	let streams = streams.as_mut().ok_or(ERR_INVALID_ARG)?;
+	let handle = *SOCKETS.lock().unwrap().get(&rd).ok_or(ERR_INVALID_ARG)?;
+	f(streams, handle).map_err(error)
+}
+
+/// Reads received data, zero once the host won't send anymore.
+pub fn read(rd: Rd, buf: &mut [u8]) -> Result<usize, usize> {
+	with(rd, |streams, handle| streams.recv(handle, buf))
+}
+
+/// Queues data and sends what the host has room for, returns how much was queued.
+pub fn write(rd: Rd, buf: &[u8]) -> Result<usize, usize> {
+	with(rd, |streams, handle| streams.send(handle, buf))
+}
+
+/// Closes a socket, connections are shut down in the background. Queued operations of
+/// the socket fail with `ERR_INVALID_ARG`.
+pub fn close(rd: Rd) -> Result<(), usize> {
+	let mut streams = STREAMS.lock().unwrap();
+	let handle = SOCKETS.lock().unwrap().remove(&rd).ok_or(ERR_INVALID_ARG)?;
+	let streams = streams.as_mut().ok_or(ERR_INVALID_ARG)?;
+	streams.close(handle).map_err(error)?;
+	progress(streams);
+	Ok(())
+}
+
+/// Queues a batch of reads and writes, `RD_IO_OP_WRITE` tells them apart. Writes take
+/// a buffer or, with `RD_IO_FORMAT_VEC`, a vector of them, reads the most bytes to
+/// read. Nothing is queued if one of the operations is invalid.
+pub fn ops(batch: &[IoOp]) -> Result<(), usize> {
+	let mut streams = STREAMS.lock().unwrap();
+	let streams = streams.as_mut().ok_or(ERR_NOT_READY)?;
+	{
+		let sockets = SOCKETS.lock().unwrap();
+		let mut ops = OPS.lock().unwrap();
+		let mut queued = Vec::with_capacity(batch.len());
+		for op in batch {
+			if op.flags & RD_IO_FLAG_FILE_NAME != 0 {
+				return Err(ERR_NOT_IMPLEMENTED);
+			}
+			// SAFETY: the union holds a descriptor without `RD_IO_FLAG_FILE_NAME`
+			let rd = unsafe { op.rd.rd };
+			if !sockets.contains_key(&rd) || ops.iter().chain(&queued).any(|o: &Op| o.id == op.id) {
+				return Err(ERR_INVALID_ARG);
+			}
+			let write = op.flags & RD_IO_OP_WRITE != 0;
+			let vec = (op.flags >> 2) & 0b11 == RD_IO_FORMAT_VEC;
+			// SAFETY: the flags tell which field of the union is set
+			let (data, len) = unsafe {
+				match (write, vec) {
+					(true, true)   => (op.buf.vec.unwrap_or(&[]).concat(), 0),
+					(true, false)  => (op.buf.buf.unwrap_or(&[]).to_vec(), 0),
+					(false, false) => (Vec::new(), op.buf.len.min(MAX_READ)),
+					(false, true)  => return Err(ERR_NOT_IMPLEMENTED)
+				}
+			};
+			queued.push(Op { id: op.id, rd, write, data, len, result: None });
+		}
+		ops.extend(queued);
+	}
+	progress(streams);
+	Ok(())
+}
+
+/// The result of a queued operation, `RD_IO_ERR_WOULD_BLOCK` while it is pending. The
+/// data of a read is copied to `buf`, which should hold as many bytes as were to be
+/// read.
+pub fn poll(id: IoOpId, buf: &mut [u8]) -> Result<usize, usize> {
+	let mut ops = OPS.lock().unwrap();
+	let i = ops.iter().position(|op| op.id == id).ok_or(ERR_INVALID_ARG)?;
+	if ops[i].result.is_none() {
+		return Err(RD_IO_ERR_WOULD_BLOCK);
+	}
+	let op = ops.remove(i);
+	if !op.write {
+		let n = op.data.len().min(buf.len());
+		buf[..n].copy_from_slice(&op.data[..n]);
+	}
+	op.result.unwrap()
+}